@@ -0,0 +1,196 @@
+//! Boot configuration
+//!
+//! `webbos.cfg` at the root of the ESP lists what can be booted, so test
+//! kernels and a rescue image can sit beside the usual one. `#` starts a
+//! comment. Each `entry` line starts an entry, titled by the rest of the
+//! line, and the lines after it describe it:
+//!
+//! ```text
+//! timeout 5
+//! default 0
+//!
+//! entry WebbOS
+//!     kernel kernel.elf
+//!     video 1280x800
+//!
+//! entry Kernel tests
+//!     cmdline test
+//!
+//! entry Rescue
+//!     kernel rescue\kernel.elf
+//!     initrd rescue\initrd.tar
+//!     cmdline loglevel=debug
+//! ```
+//!
+//! `kernel` is `kernel.elf` unless given. Without `cmdline`, the entry
+//! takes `cmdline.txt`. `initrd` is a tar archive the kernel unpacks over
+//! its root filesystem. `video` picks a GOP mode by its resolution, or
+//! the largest that fits inside it if the display has none that size.
+//! `timeout` and `default` come before the entries: the menu waits
+//! `timeout` seconds, then boots entry `default`, counted from 0. With
+//! `timeout 0` the default boots unless a key is down. A `video` there is
+//! for the entries that have none. Without the file, `kernel.elf` boots
+//! alone.
+//!
+//! `slot` boots from the A/B update slots instead of `kernel` and
+//! `initrd`: `slot active` from the one `update.state` makes active,
+//! which is how updates and rollbacks take effect, `slot a` or `slot b`
+//! from that one, to get back to it by hand. Without `webbos.cfg`, an
+//! ESP with `update.state` boots the active slot.
+//!
+//! The bootloader boots only kernels signed with the key it was built
+//! with. Nothing in this file changes that, since anyone who can write the
+//! ESP can write the file; see the `allow-unsigned` feature.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use webbos_shared::update::Slot;
+
+/// Seconds the menu waits unless the file says otherwise
+const DEFAULT_TIMEOUT: usize = 5;
+
+/// Kernel an entry boots unless it names one
+pub const DEFAULT_KERNEL: &str = "kernel.elf";
+
+/// Something to boot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    pub title: String,
+    /// Path of the kernel on the ESP
+    pub kernel: String,
+    /// Path of a tar archive for the kernel's root filesystem
+    pub initrd: Option<String>,
+    /// Kernel command line, instead of `cmdline.txt`
+    pub cmdline: Option<String>,
+    /// Resolution of the GOP mode to switch to
+    pub video: Option<(usize, usize)>,
+    /// A/B slot to boot from, in place of `kernel` and `initrd`
+    pub slot: Option<SlotChoice>,
+}
+
+/// Which A/B slot an entry boots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotChoice {
+    /// The one `update.state` makes active
+    Active,
+    Pinned(Slot),
+}
+
+impl BootEntry {
+    fn new(title: &str) -> Self {
+        Self {
+            title: String::from(title),
+            kernel: String::from(DEFAULT_KERNEL),
+            initrd: None,
+            cmdline: None,
+            video: None,
+            slot: None,
+        }
+    }
+}
+
+/// The whole file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootConfig {
+    /// Seconds before the default boots
+    pub timeout: usize,
+    /// Index of the entry booted when nobody chooses
+    pub default: usize,
+    /// Resolution for the entries that name none
+    pub video: Option<(usize, usize)>,
+    pub entries: Vec<BootEntry>,
+}
+
+/// A line the parser did not understand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Counted from 1
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl BootConfig {
+    /// What boots without a `webbos.cfg`: `kernel.elf` with `cmdline.txt`,
+    /// or the active slot if there are slots
+    pub fn fallback(slots: bool) -> Self {
+        let mut entry = BootEntry::new("WebbOS");
+        if slots {
+            entry.slot = Some(SlotChoice::Active);
+        }
+        Self { timeout: 0, default: 0, video: None, entries: alloc::vec![entry] }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self {
+            timeout: DEFAULT_TIMEOUT,
+            default: 0,
+            video: None,
+            entries: Vec::new(),
+        };
+        let mut default_line = 0;
+        for (index, line) in text.lines().enumerate() {
+            let error = |message| ConfigError { line: index + 1, message };
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, ""),
+            };
+
+            if key == "entry" {
+                if value.is_empty() {
+                    return Err(error("an entry needs a title"));
+                }
+                let mut entry = BootEntry::new(value);
+                entry.video = config.video;
+                config.entries.push(entry);
+                continue;
+            }
+            // An empty command line is one
+            if value.is_empty() && key != "cmdline" {
+                return Err(error("missing value"));
+            }
+            match (key, config.entries.last_mut()) {
+                ("timeout", None) => config.timeout = value.parse().map_err(|_| error("timeout is in whole seconds"))?,
+                ("default", None) => {
+                    config.default = value.parse().map_err(|_| error("default is an entry number"))?;
+                    default_line = index + 1;
+                }
+                ("allow_unsigned", _) => {
+                    return Err(error("allow_unsigned is the allow-unsigned build feature now"))
+                }
+                ("timeout" | "default", Some(_)) => return Err(error("timeout and default go before the entries")),
+                ("video", None) => config.video = Some(parse_resolution(value).ok_or(error("video is WIDTHxHEIGHT"))?),
+                (_, None) => return Err(error("this goes in an entry")),
+                ("kernel", Some(entry)) => entry.kernel = String::from(value),
+                ("initrd", Some(entry)) => entry.initrd = Some(String::from(value)),
+                ("cmdline", Some(entry)) => entry.cmdline = Some(String::from(value)),
+                ("video", Some(entry)) => entry.video = Some(parse_resolution(value).ok_or(error("video is WIDTHxHEIGHT"))?),
+                ("slot", Some(entry)) => entry.slot = Some(match value {
+                    "active" => SlotChoice::Active,
+                    name => SlotChoice::Pinned(Slot::parse(name).ok_or(error("slot is active, a or b"))?),
+                }),
+                (_, Some(_)) => return Err(error("unknown setting")),
+            }
+        }
+
+        if config.entries.is_empty() {
+            return Err(ConfigError { line: text.lines().count(), message: "no entries" });
+        }
+        if config.default >= config.entries.len() {
+            return Err(ConfigError { line: default_line, message: "default is past the last entry" });
+        }
+        Ok(config)
+    }
+}
+
+/// `1280x800`
+fn parse_resolution(text: &str) -> Option<(usize, usize)> {
+    let (width, height) = text.split_once('x')?;
+    match (width.trim().parse(), height.trim().parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+        _ => None,
+    }
+}
@@ -0,0 +1,47 @@
+//! The display's EDID
+//!
+//! GOP drivers put the EDID they read from the display on the GOP handle:
+//! EDID Active is what the display said after any override by the
+//! platform, EDID Discovered what it said. The uefi crate has neither
+//! protocol, so they are here.
+
+use alloc::vec::Vec;
+use uefi::boot;
+use uefi::proto::unsafe_protocol;
+
+/// EFI_EDID_ACTIVE_PROTOCOL
+#[repr(C)]
+#[unsafe_protocol("bd8c1056-9f36-44ec-92a8-a6337f817986")]
+struct EdidActive {
+    size: u32,
+    edid: *const u8,
+}
+
+/// EFI_EDID_DISCOVERED_PROTOCOL
+#[repr(C)]
+#[unsafe_protocol("1c0c34f6-d380-41fa-a049-8ad06c1a66aa")]
+struct EdidDiscovered {
+    size: u32,
+    edid: *const u8,
+}
+
+/// A copy of the EDID, if the firmware has one
+pub fn read() -> Option<Vec<u8>> {
+    let active = boot::get_handle_for_protocol::<EdidActive>()
+        .and_then(|handle| boot::open_protocol_exclusive::<EdidActive>(handle))
+        .ok()
+        .and_then(|edid| copy(edid.size, edid.edid));
+    active.or_else(|| {
+        boot::get_handle_for_protocol::<EdidDiscovered>()
+            .and_then(|handle| boot::open_protocol_exclusive::<EdidDiscovered>(handle))
+            .ok()
+            .and_then(|edid| copy(edid.size, edid.edid))
+    })
+}
+
+fn copy(size: u32, edid: *const u8) -> Option<Vec<u8>> {
+    if size == 0 || edid.is_null() {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(edid, size as usize) }.to_vec())
+}
@@ -10,17 +10,28 @@
 
 extern crate alloc;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 use uefi::boot::{allocate_pages, AllocateType, MemoryType};
 use uefi::mem::memory_map::{MemoryMap, MemoryMapOwned};
-use uefi::proto::media::file::{File, FileAttribute, FileMode};
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode, RegularFile};
 use uefi::{boot, println, Status};
 use uefi::CString16;
-use webbos_shared::bootinfo::{BootInfo, FramebufferInfo, PixelFormat, BOOTINFO_MAGIC, BOOTINFO_VERSION};
-use webbos_shared::types::{MemoryRegion, MemoryRegionType, PhysAddr, VirtAddr, ByteSize};
-
+use webbos_shared::bootinfo::{
+    self, AcpiTag, BootInfo, BootInfoWriter, EdidTag, EfiTag, FramebufferInfo, InitrdTag, KernelNote, PhysmapTag,
+    PixelFormat, SmbiosTag, VideoMode, VideoModesTag, BOOTINFO_VERSION, CRASH_REGION_ADDR, CRASH_REGION_SIZE,
+};
+use config::{BootConfig, ConfigError};
+use webbos_shared::types::{sanitize_memory_map, MemoryRegion, MemoryRegionType, PhysAddr, VirtAddr, ByteSize};
+use webbos_shared::signature;
+use webbos_shared::update::Slot;
+
+mod config;
+mod edid;
 mod memory;
+mod menu;
 mod paging;
+mod update;
 
 /// Simple allocator for UEFI
 #[global_allocator]
@@ -29,11 +40,26 @@ static ALLOCATOR: uefi::allocator::Allocator = uefi::allocator::Allocator;
 /// Kernel load address (physical)
 const KERNEL_LOAD_ADDR: PhysAddr = PhysAddr::new(0x100000); // 1MB mark
 
+/// End of the kernel's physical space: the stack follows
+const KERNEL_LOAD_LIMIT: PhysAddr = PhysAddr::new(0x500000);
+
+/// Where the kernel's higher half starts; physical memory is mapped from
+/// here up
+const KERNEL_VIRT_BASE: u64 = 0xFFFF_8000_0000_0000;
+
 /// Stack size for kernel
 const KERNEL_STACK_SIZE: u64 = 128 * 1024; // 128KB
 
-/// Kernel entry point (set during load_kernel)
-static mut KERNEL_ENTRY_POINT: u64 = 0;
+/// Longest kernel command line passed on, in bytes
+const CMDLINE_MAX: usize = 4095;
+
+/// Highest address for what the kernel reads after boot, like the initrd:
+/// it reaches the first 512MB of physical memory directly
+const BOOT_DATA_MAX_ADDR: u64 = 0x1FFF_FFFF;
+
+/// Kernels without a signature boot, and a bootloader built without a key
+/// boots anything; for development builds only
+const ALLOW_UNSIGNED: bool = cfg!(feature = "allow-unsigned");
 
 /// Bootloader entry point
 #[entry]
@@ -47,15 +73,50 @@ fn main() -> Status {
     println!("╚═══════════════════════════════════════╝");
     println!();
 
-    // Load kernel from disk
-    let kernel_size = match load_kernel() {
-        Ok(size) => size,
-        Err(e) => {
-            println!("ERROR: Failed to load kernel: {:?}", e);
+    // What to boot: from webbos.cfg, or kernel.elf alone
+    let mut update_state = update::load();
+    let config = load_config(update_state.is_some());
+    let entry = &config.entries[menu::choose(&config)];
+    println!("Booting {}", entry.title);
+    let mut slot = update::choose(entry, update_state.as_mut());
+
+    // Load kernel from disk; a new slot whose kernel does not load gives
+    // way to the old one
+    if let Err(e) = claim_kernel_space() {
+        println!("ERROR: Failed to load kernel: {:?}", e);
+        return Status::LOAD_ERROR;
+    }
+    let kernel = loop {
+        let (path, limit) = match slot {
+            Some(slot) => update::kernel(slot, update_state.as_ref()),
+            None => (entry.kernel.clone(), None),
+        };
+        match load_kernel(&path, limit) {
+            Ok(kernel) => break kernel,
+            Err(e) => {
+                if let Some(fallback) = slot.and_then(|slot| update::fall_back(entry, slot, update_state.as_mut())) {
+                    slot = Some(fallback);
+                    continue;
+                }
+                println!("ERROR: Failed to load kernel: {:?}", e);
+                return Status::LOAD_ERROR;
+            }
+        }
+    };
+    println!("Kernel loaded: {} bytes", kernel.size);
+
+    let initrd = match slot {
+        Some(slot) => update::initrd(slot, update_state.as_ref()),
+        None => entry.initrd.clone().map(|path| (path, 0)),
+    };
+    let initrd = match initrd.map(|(path, size)| load_initrd(&path, Some(size).filter(|&size| size > 0))) {
+        None => None,
+        Some(Ok(initrd)) => Some(initrd),
+        Some(Err(e)) => {
+            println!("ERROR: Failed to load initrd: {:?}", e);
             return Status::LOAD_ERROR;
         }
     };
-    println!("Kernel loaded: {} bytes", kernel_size);
 
     // Get memory map
     let memory_map = match get_memory_map() {
@@ -77,7 +138,7 @@ fn main() -> Status {
     };
 
     // Get framebuffer info
-    let framebuffer_info = get_framebuffer_info();
+    let framebuffer_info = get_framebuffer_info(entry.video);
     if framebuffer_info.is_valid() {
         println!("Framebuffer: {}x{} @ {:?}", 
             framebuffer_info.width, 
@@ -85,6 +146,17 @@ fn main() -> Status {
             framebuffer_info.addr
         );
     }
+    // What the kernel may switch to later, and what the display says
+    let video_modes = get_video_modes();
+    let video_modes_addr = match copy_boot_data(&video_modes) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("ERROR: Failed to allocate the video mode list: {:?}", e);
+            return Status::OUT_OF_RESOURCES;
+        }
+    };
+    let edid = edid::read().and_then(|edid| Some((copy_boot_data(&edid).ok()?, edid.len() as u64)));
+    println!("Video modes: {}, EDID: {}", video_modes.len(), edid.map_or(0, |(_, size)| size));
 
     // Allocate kernel stack
     let stack_top = match allocate_stack() {
@@ -96,56 +168,93 @@ fn main() -> Status {
     };
     println!("Kernel stack: top={:?}", stack_top);
 
-    // Setup page tables for kernel
-    let _page_tables = match paging::setup_kernel_paging(kernel_size) {
+    // The kernel finds its last panic report here after a reboot
+    if let Err(e) = reserve_crash_region() {
+        println!("WARNING: No crash dump region: {:?}", e);
+    }
+
+    // Setup page tables for kernel, with every physical address in the
+    // physmap
+    let physmap_size = physmap_size(&memory_map, &framebuffer_info);
+    let _page_tables = match paging::setup_kernel_paging(physmap_size) {
         Ok(pt) => pt,
         Err(e) => {
             println!("ERROR: Failed to setup paging: {:?}", e);
             return Status::LOAD_ERROR;
         }
     };
-    println!("Page tables initialized");
-
-    // Populate boot info
-    unsafe {
-        let boot_info_ptr = boot_info.as_mut_ptr::<BootInfo>();
-        (*boot_info_ptr).magic = BOOTINFO_MAGIC;
-        (*boot_info_ptr).version = BOOTINFO_VERSION;
-        (*boot_info_ptr)._reserved = 0;
-        (*boot_info_ptr).kernel_addr = KERNEL_LOAD_ADDR;
-        (*boot_info_ptr).kernel_size = kernel_size as u64;
-        (*boot_info_ptr).kernel_virt_addr = VirtAddr::new(0xFFFF_8000_0010_0000);
-        (*boot_info_ptr).framebuffer = framebuffer_info;
-        (*boot_info_ptr).rsdp_addr = get_rsdp_addr();
-        (*boot_info_ptr).cmdline = None;
-        (*boot_info_ptr).bootloader_name = PhysAddr::new(b"WebbOS Bootloader\0".as_ptr() as u64);
-        (*boot_info_ptr).stack_top = stack_top;
-        (*boot_info_ptr).stack_size = KERNEL_STACK_SIZE;
-    }
-
-    // Convert memory map to kernel format
-    let kernel_memory_map = convert_memory_map(&memory_map);
-    let memory_map_addr = unsafe { 
-        copy_memory_map(&kernel_memory_map, boot_info.as_ptr::<BootInfo>().add(1) as *mut MemoryRegion)
+    println!("Page tables initialized; {} GB of physical memory mapped at {:#x}", physmap_size >> 30, KERNEL_VIRT_BASE);
+
+    let cmdline = load_cmdline(entry.cmdline.as_deref(), slot);
+
+    // Populate boot info: the header, then a tag for each thing there is.
+    // Two pages hold every tag many times over.
+    let buffer = unsafe { core::slice::from_raw_parts_mut(boot_info.as_mut_ptr::<u8>(), BOOT_INFO_PAGES * 0x1000) };
+    let header = BootInfo {
+        kernel_addr: KERNEL_LOAD_ADDR,
+        kernel_size: kernel.size as u64,
+        kernel_virt_addr: VirtAddr::new(KERNEL_VIRT_BASE + KERNEL_LOAD_ADDR.as_u64()),
+        cmdline: cmdline.unwrap_or(PhysAddr::new(0)),
+        bootloader_name: PhysAddr::new(b"WebbOS Bootloader\0".as_ptr() as u64),
+        stack_top,
+        stack_size: KERNEL_STACK_SIZE,
+        ..BootInfo::new()
     };
-    
-    unsafe {
-        let boot_info_ptr = boot_info.as_mut_ptr::<BootInfo>();
-        (*boot_info_ptr).memory_map_addr = memory_map_addr;
-        (*boot_info_ptr).memory_map_count = kernel_memory_map.len();
+    let mut writer = match BootInfoWriter::new(buffer, header) {
+        Some(writer) => writer,
+        None => {
+            println!("ERROR: The boot info does not fit its pages");
+            return Status::OUT_OF_RESOURCES;
+        }
+    };
+    if framebuffer_info.is_valid() {
+        writer.add(&framebuffer_info, 0);
+    }
+    if let Some(rsdp) = get_rsdp_addr() {
+        writer.add(&AcpiTag { rsdp }, 0);
+    }
+    if let Some((addr, size)) = initrd {
+        writer.add(&InitrdTag { addr, size }, 0);
+    }
+    writer.add(&PhysmapTag { base: VirtAddr::new(KERNEL_VIRT_BASE), size: physmap_size }, 0);
+    if !video_modes.is_empty() {
+        writer.add(&VideoModesTag { addr: video_modes_addr, count: video_modes.len() as u64 }, 0);
+    }
+    if let Some((addr, size)) = edid {
+        writer.add(&EdidTag { addr, size }, 0);
+    }
+    if let Some(entry_point) = get_smbios_addr() {
+        writer.add(&SmbiosTag { entry_point }, 0);
+    }
+    if let Some(table) = uefi::table::system_table_raw() {
+        let runtime_services = unsafe { table.as_ref().runtime_services };
+        writer.add(&EfiTag {
+            system_table: PhysAddr::new(table.as_ptr() as u64),
+            runtime_services: PhysAddr::new(runtime_services as u64),
+        }, 0);
+    }
+    if !check_features(kernel.note, writer.features()) {
+        return Status::INCOMPATIBLE_VERSION;
     }
+    let boot_info_len = writer.finish();
 
     println!("Boot info prepared");
     println!("Exiting boot services and jumping to kernel...");
 
-    // Exit boot services
+    // Exit boot services. The memory map the kernel gets is the one this
+    // leaves, so it has everything allocated above in it: the page
+    // tables, the stack and the rest are not free memory.
     unsafe {
-        // We need to get the memory map again after exiting boot services
-        let _ = boot::exit_boot_services(MemoryType::LOADER_DATA);
+        let final_map = boot::exit_boot_services(MemoryType::LOADER_DATA);
+        let boot_info_ptr = boot_info.as_mut_ptr::<BootInfo>();
+        let regions = boot_info.as_mut_ptr::<u8>().add(boot_info_len) as *mut MemoryRegion;
+        let capacity = (BOOT_INFO_PAGES * 0x1000 - boot_info_len) / core::mem::size_of::<MemoryRegion>();
+        (*boot_info_ptr).memory_map_addr = PhysAddr::new(regions as u64);
+        (*boot_info_ptr).memory_map_count = write_memory_map(&final_map, regions, capacity);
     }
 
     // Jump to kernel using the entry point from ELF header
-    let kernel_entry_virt = unsafe { KERNEL_ENTRY_POINT };
+    let kernel_entry_virt = kernel.entry;
     
     println!("Jumping to kernel at {:#x}...", kernel_entry_virt);
     
@@ -205,30 +314,78 @@ struct Elf64Phdr {
 }
 
 const ELFMAG: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
 const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+
+/// What `load_kernel` loaded
+struct LoadedKernel {
+    /// Bytes from `KERNEL_LOAD_ADDR` to the end of the last segment
+    size: usize,
+    /// Virtual address to jump to, from the ELF header
+    entry: u64,
+    /// What it says it needs from the boot info, if it says
+    note: Option<KernelNote>,
+}
+
+/// A loadable segment, checked against the file
+struct Segment {
+    /// Physical address it goes to
+    phys: u64,
+    /// Virtual address it runs at
+    virt: u64,
+    offset: usize,
+    file_size: usize,
+    mem_size: usize,
+    executable: bool,
+}
+
+/// Say why the kernel cannot be loaded, as an error with `status`
+fn load_error(status: Status, message: core::fmt::Arguments) -> uefi::Error {
+    println!("ERROR: {}", message);
+    uefi::Error::new(status, ())
+}
+
+/// Claim the physical memory the kernel is loaded into from the firmware,
+/// checking the memory map has it free
+fn claim_kernel_space() -> uefi::Result<(), ()> {
+    let (start, end) = (KERNEL_LOAD_ADDR.as_u64(), KERNEL_LOAD_LIMIT.as_u64());
+    let memory_map = get_memory_map()?;
+    let taken = memory_map.entries().find(|desc| {
+        let desc_end = desc.phys_start + desc.page_count * 0x1000;
+        desc.ty != MemoryType::CONVENTIONAL && desc.phys_start < end && desc_end > start
+    });
+    if let Some(desc) = taken {
+        return Err(load_error(Status::OUT_OF_RESOURCES, format_args!(
+            "The kernel goes at {:#x}-{:#x}, but the memory map has {:#x}-{:#x} in use ({:?})",
+            start, end, desc.phys_start, desc.phys_start + desc.page_count * 0x1000, desc.ty)));
+    }
+    allocate_pages(AllocateType::Address(start), memory::KERNEL_MEMORY, ((end - start) / 0x1000) as usize)
+        .map_err(|e| load_error(e.status(), format_args!("Cannot claim {:#x}-{:#x} for the kernel", start, end)))?;
+    Ok(())
+}
 
 /// Load kernel from disk and parse ELF
-fn load_kernel() -> uefi::Result<usize> {
-    let fs = boot::get_image_file_system(boot::image_handle())?;
-    let mut fs = fs;
-    
-    // Open root directory
-    let mut root = fs.open_volume()?;
-    
+///
+/// The space for the kernel must have been claimed, so the file is not
+/// read into it. Only the first `limit` bytes of the file are the kernel,
+/// if given. The file is checked before anything is copied: the header,
+/// that it is signed, unless built with `allow-unsigned`, that every `PT_LOAD`
+/// segment lies in the file and in that space, and that the entry point
+/// is in an executable segment.
+fn load_kernel(path: &str, limit: Option<usize>) -> uefi::Result<LoadedKernel> {
     // Open kernel file
-    let file = root.open(
-        uefi::cstr16!("kernel.elf"),
-        FileMode::Read,
-        FileAttribute::empty(),
-    )?;
-    
-    let mut file = file.into_regular_file().ok_or_else(|| uefi::Error::new(Status::NOT_FOUND, ()))?;
+    let mut file = open_file(path)?;
     
     // Get file size
-    let file_info = file.get_boxed_info::<uefi::proto::media::file::FileInfo>()?;
-    let file_size = file_info.file_size() as usize;
+    let file_info = file.get_boxed_info::<FileInfo>()?;
+    // A slot's file is larger than the kernel in it
+    let file_size = limit.map_or(file_info.file_size() as usize, |limit| limit.min(file_info.file_size() as usize));
     
-    println!("Kernel file size: {} bytes", file_size);
+    println!("Kernel {}: {} bytes", path, file_size);
     
     // Read entire file into temporary buffer
     let temp_pages = allocate_pages(
@@ -243,76 +400,270 @@ fn load_kernel() -> uefi::Result<usize> {
     let bytes_read = file.read(file_buffer)?;
     
     if bytes_read != file_size {
-        println!("WARNING: Read {} bytes, expected {}", bytes_read, file_size);
+        return Err(load_error(Status::LOAD_ERROR,
+            format_args!("Read {} bytes of {}, expected {}", bytes_read, path, file_size)));
     }
     
+    // The ELF is what the signature covers; the trailer is not part of it
+    let file_size = match signature::check(file_buffer) {
+        signature::Verdict::Valid(elf_len) => {
+            println!("Kernel signature: valid");
+            elf_len
+        }
+        signature::Verdict::Invalid => {
+            return Err(load_error(Status::SECURITY_VIOLATION,
+                format_args!("{} has a signature that does not check; refusing to boot it", path)));
+        }
+        signature::Verdict::Unsigned if ALLOW_UNSIGNED => {
+            println!("WARNING: {} is not signed; booting it as this development build allows", path);
+            file_size
+        }
+        signature::Verdict::Unsigned => {
+            return Err(load_error(Status::SECURITY_VIOLATION, format_args!(
+                "{} is not signed; sign it with tools/sign-kernel.py", path)));
+        }
+        signature::Verdict::Unchecked(elf_len) if ALLOW_UNSIGNED => {
+            println!("WARNING: This development bootloader has no kernel signing key; {} is not checked", path);
+            elf_len
+        }
+        signature::Verdict::Unchecked(_) => {
+            return Err(load_error(Status::SECURITY_VIOLATION, format_args!(
+                "This bootloader was built without WEBBOS_KERNEL_KEY and cannot check {}; refusing to boot it", path)));
+        }
+    };
+    let file_buffer = &file_buffer[..file_size];
+    
     // Parse ELF header
-    let elf_header = unsafe { &*(file_buffer.as_ptr() as *const Elf64Header) };
+    if file_size < core::mem::size_of::<Elf64Header>() {
+        return Err(load_error(Status::LOAD_ERROR, format_args!("kernel.elf is too short for an ELF header")));
+    }
+    let elf_header = unsafe { core::ptr::read_unaligned(file_buffer.as_ptr() as *const Elf64Header) };
     
     // Verify ELF magic
     if elf_header.e_ident[0..4] != ELFMAG {
-        println!("ERROR: Invalid ELF magic");
-        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+        return Err(load_error(Status::LOAD_ERROR, format_args!("Invalid ELF magic")));
+    }
+    if elf_header.e_ident[4] != ELFCLASS64 || elf_header.e_ident[5] != ELFDATA2LSB || elf_header.e_machine != EM_X86_64 {
+        return Err(load_error(Status::LOAD_ERROR, format_args!("kernel.elf is not a 64-bit little-endian x86_64 ELF")));
     }
     
     println!("ELF entry point: {:#x}", elf_header.e_entry);
     println!("Program headers: {} at offset {:#x}", elf_header.e_phnum, elf_header.e_phoff);
     
-    // Store entry point for later use
-    unsafe {
-        KERNEL_ENTRY_POINT = elf_header.e_entry;
+    let phdr_size = core::mem::size_of::<Elf64Phdr>();
+    let phdr_table_end = (elf_header.e_phnum as u64)
+        .checked_mul(phdr_size as u64)
+        .and_then(|size| size.checked_add(elf_header.e_phoff));
+    if elf_header.e_phentsize as usize != phdr_size || phdr_table_end.map_or(true, |end| end > file_size as u64) {
+        return Err(load_error(Status::LOAD_ERROR, format_args!("The program headers are not in kernel.elf")));
     }
     
-    // Load each program segment at the correct physical address
-    let phdr_table = unsafe {
-        core::slice::from_raw_parts(
-            file_buffer.as_ptr().add(elf_header.e_phoff as usize) as *const Elf64Phdr,
-            elf_header.e_phnum as usize,
-        )
+    // Check each loadable segment and where it goes, and find the note
+    // saying what the kernel needs
+    let mut segments = Vec::new();
+    let mut note = None;
+    for index in 0..elf_header.e_phnum as usize {
+        let phdr = unsafe {
+            core::ptr::read_unaligned(
+                file_buffer.as_ptr().add(elf_header.e_phoff as usize + index * phdr_size) as *const Elf64Phdr,
+            )
+        };
+        if phdr.p_type == PT_NOTE && note.is_none() {
+            let end = phdr.p_offset.saturating_add(phdr.p_filesz).min(file_size as u64) as usize;
+            note = file_buffer.get(phdr.p_offset as usize..end).and_then(bootinfo::find_kernel_note);
+        }
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        
+        // The ELF file has virtual addresses in p_paddr for some segments;
+        // higher half addresses are the physical ones above the base
+        let phys = match phdr.p_paddr {
+            paddr if paddr >= KERNEL_VIRT_BASE => paddr - KERNEL_VIRT_BASE,
+            paddr => paddr,
+        };
+        let in_file = phdr.p_offset.checked_add(phdr.p_filesz).map_or(false, |end| end <= file_size as u64);
+        if !in_file || phdr.p_filesz > phdr.p_memsz {
+            return Err(load_error(Status::LOAD_ERROR,
+                format_args!("Segment {} runs past the end of kernel.elf", index)));
+        }
+        
+        let end = phys.checked_add(phdr.p_memsz);
+        if phys < KERNEL_LOAD_ADDR.as_u64() || end.map_or(true, |end| end > KERNEL_LOAD_LIMIT.as_u64()) {
+            return Err(load_error(Status::BUFFER_TOO_SMALL, format_args!(
+                "The kernel does not fit: segment {} needs {:#x}-{:#x}, but the kernel has {:#x}-{:#x}",
+                index, phys, phys.saturating_add(phdr.p_memsz), KERNEL_LOAD_ADDR.as_u64(), KERNEL_LOAD_LIMIT.as_u64())));
+        }
+        
+        segments.push(Segment {
+            phys,
+            virt: phdr.p_vaddr,
+            offset: phdr.p_offset as usize,
+            file_size: phdr.p_filesz as usize,
+            mem_size: phdr.p_memsz as usize,
+            executable: phdr.p_flags & PF_X != 0,
+        });
+    }
+    
+    if let Some(note) = note.filter(|note| note.version != BOOTINFO_VERSION) {
+        return Err(load_error(Status::INCOMPATIBLE_VERSION, format_args!(
+            "{} reads boot info version {}, but this bootloader writes version {}", path, note.version, BOOTINFO_VERSION)));
+    }
+    
+    let end = match segments.iter().map(|s| s.phys + s.mem_size as u64).max() {
+        Some(end) => end,
+        None => return Err(load_error(Status::LOAD_ERROR, format_args!("kernel.elf has nothing to load"))),
     };
     
-    let mut max_addr = 0usize;
+    // The entry point in the higher half, where the kernel runs
+    let entry = match elf_header.e_entry {
+        entry if entry >= KERNEL_VIRT_BASE => entry,
+        entry => entry + KERNEL_VIRT_BASE,
+    };
+    let entry_in_text = segments.iter().any(|s| {
+        let virt = if s.virt >= KERNEL_VIRT_BASE { s.virt } else { s.virt + KERNEL_VIRT_BASE };
+        s.executable && entry >= virt && entry < virt + s.mem_size as u64
+    });
+    if !entry_in_text {
+        return Err(load_error(Status::LOAD_ERROR,
+            format_args!("The entry point {:#x} is not in an executable segment", entry)));
+    }
     
-    for phdr in phdr_table {
-        if phdr.p_type == PT_LOAD {
-            // The ELF file has virtual addresses in p_paddr for some segments
-            // We need to convert to physical addresses
-            // Kernel virtual base is 0xFFFF_8000_0000_0000
-            const KERNEL_VIRT_BASE: u64 = 0xFFFF_8000_0000_0000;
+    // Load each program segment at the correct physical address
+    for segment in &segments {
+        println!("Loading segment: src={:#x} -> dest={:#x} (phys), size={:#x}/{:#x}",
+            segment.offset, segment.phys, segment.file_size, segment.mem_size);
+        
+        // Copy data from file to destination
+        unsafe {
+            let src = file_buffer.as_ptr().add(segment.offset);
+            let dst = segment.phys as *mut u8;
+            core::ptr::copy_nonoverlapping(src, dst, segment.file_size);
             
-            let mut dest_addr = phdr.p_paddr as usize;
-            // If the address is in the higher half, convert to physical
-            if dest_addr as u64 >= KERNEL_VIRT_BASE {
-                dest_addr = (dest_addr as u64 - KERNEL_VIRT_BASE) as usize;
+            // Zero the rest if mem_size > file_size
+            if segment.mem_size > segment.file_size {
+                core::ptr::write_bytes(dst.add(segment.file_size), 0, segment.mem_size - segment.file_size);
             }
-            
-            let src_offset = phdr.p_offset as usize;
-            let filesz = phdr.p_filesz as usize;
-            let memsz = phdr.p_memsz as usize;
-            
-            println!("Loading segment: src={:#x} -> dest={:#x} (phys), size={:#x}/{:#x}",
-                src_offset, dest_addr, filesz, memsz);
-            
-            // Copy data from file to destination
-            unsafe {
-                let src = file_buffer.as_ptr().add(src_offset);
-                let dst = dest_addr as *mut u8;
-                core::ptr::copy_nonoverlapping(src, dst, filesz);
-                
-                // Zero the rest if mem_size > file_size
-                if memsz > filesz {
-                    core::ptr::write_bytes(dst.add(filesz), 0, memsz - filesz);
+        }
+    }
+    
+    Ok(LoadedKernel { size: (end - KERNEL_LOAD_ADDR.as_u64()) as usize, entry, note })
+}
+
+/// The kernel command line: the boot entry's, or else `cmdline.txt` next
+/// to the kernel, then `slot=` with the A/B slot it came from
+///
+/// The file's lines are joined with spaces and `#` comments dropped. The
+/// result goes NUL-terminated into a page of its own; None if the entry
+/// has none, there is no file and no slot.
+fn load_cmdline(entry: Option<&str>, slot: Option<Slot>) -> Option<PhysAddr> {
+    let mut cmdline = String::new();
+    match entry {
+        Some(options) => cmdline.push_str(options),
+        None => {
+            let mut file = match (open_file("cmdline.txt"), slot) {
+                (Ok(file), _) => Some(file),
+                (Err(_), Some(_)) => None,
+                (Err(_), None) => return None,
+            };
+            let mut buf = [0u8; CMDLINE_MAX];
+            let len = file.as_mut().map_or(Ok(0), |file| file.read(&mut buf)).ok()?;
+            let text = core::str::from_utf8(&buf[..len]).ok()?;
+            for option in text.lines().flat_map(|line| line.split('#').next().unwrap_or("").split_whitespace()) {
+                if !cmdline.is_empty() {
+                    cmdline.push(' ');
                 }
-            }
-            
-            // Track highest physical address
-            if dest_addr + memsz > max_addr {
-                max_addr = dest_addr + memsz;
+                cmdline.push_str(option);
             }
         }
     }
-    
-    Ok(max_addr)
+    if let Some(slot) = slot {
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
+        }
+        cmdline.push_str("slot=");
+        cmdline.push_str(slot.name());
+    }
+    if cmdline.len() > CMDLINE_MAX {
+        println!("WARNING: Command line cut to {} bytes", CMDLINE_MAX);
+        let mut end = CMDLINE_MAX;
+        while !cmdline.is_char_boundary(end) {
+            end -= 1;
+        }
+        cmdline.truncate(end);
+    }
+    println!("Command line: {}", cmdline);
+
+    let page = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1).ok()?;
+    unsafe {
+        let dst = page.as_ptr();
+        core::ptr::copy_nonoverlapping(cmdline.as_ptr(), dst, cmdline.len());
+        *dst.add(cmdline.len()) = 0;
+    }
+    Some(PhysAddr::new(page.as_ptr() as u64))
+}
+
+/// Open a file on the ESP the bootloader came from; `/` separates
+/// directories as well as `\`
+fn open_file(path: &str) -> uefi::Result<RegularFile> {
+    let path = CString16::try_from(path.replace('/', "\\").as_str())
+        .map_err(|_| uefi::Error::new(Status::INVALID_PARAMETER, ()))?;
+    let mut fs = boot::get_image_file_system(boot::image_handle())?;
+    let mut root = fs.open_volume()?;
+    let file = root.open(&path, FileMode::Read, FileAttribute::empty())?;
+    file.into_regular_file().ok_or_else(|| uefi::Error::new(Status::NOT_FOUND, ()))
+}
+
+/// Read a whole file from the ESP
+fn read_file(path: &str) -> uefi::Result<Vec<u8>> {
+    let mut file = open_file(path)?;
+    let size = file.get_boxed_info::<FileInfo>()?.file_size() as usize;
+    let mut data = alloc::vec![0u8; size];
+    let read = file.read(&mut data)?;
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Read `webbos.cfg`; without one, or with one that does not parse, boot
+/// `kernel.elf` as before, or the active slot if there are `slots`
+fn load_config(slots: bool) -> BootConfig {
+    let data = match read_file("webbos.cfg") {
+        Ok(data) => data,
+        Err(_) => return BootConfig::fallback(slots),
+    };
+    let parsed = match core::str::from_utf8(&data) {
+        Ok(text) => BootConfig::parse(text),
+        Err(_) => Err(ConfigError { line: 0, message: "not UTF-8" }),
+    };
+    match parsed {
+        Ok(config) => config,
+        Err(e) => {
+            println!("WARNING: webbos.cfg line {}: {}; booting {}", e.line, e.message,
+                if slots { "the active slot" } else { config::DEFAULT_KERNEL });
+            boot::stall(3_000_000);
+            BootConfig::fallback(slots)
+        }
+    }
+}
+
+/// Read the boot entry's initrd into memory the kernel reaches directly,
+/// the first `limit` bytes of the file if given; returns where it is and
+/// its size
+fn load_initrd(path: &str, limit: Option<usize>) -> uefi::Result<(PhysAddr, u64)> {
+    let mut file = open_file(path)?;
+    let size = file.get_boxed_info::<FileInfo>()?.file_size() as usize;
+    let size = limit.map_or(size, |limit| limit.min(size));
+    if size == 0 {
+        return Err(load_error(Status::LOAD_ERROR, format_args!("The initrd {} is empty", path)));
+    }
+    let pages = allocate_pages(AllocateType::MaxAddress(BOOT_DATA_MAX_ADDR), MemoryType::LOADER_DATA, (size + 0xFFF) / 0x1000)?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(pages.as_ptr(), size) };
+    let read = file.read(buffer)?;
+    if read != size {
+        return Err(load_error(Status::LOAD_ERROR, format_args!("Read {} bytes of {}, expected {}", read, path, size)));
+    }
+    println!("Initrd {}: {} bytes", path, size);
+    Ok((PhysAddr::new(pages.as_ptr() as u64), size as u64))
 }
 
 /// Get memory map from UEFI
@@ -321,26 +672,28 @@ fn get_memory_map() -> uefi::Result<MemoryMapOwned, ()> {
     Ok(memory_map)
 }
 
+/// Pages for the boot info; the memory map follows it
+const BOOT_INFO_PAGES: usize = 2;
+
 /// Allocate boot info structure
 fn allocate_boot_info(_memory_map: &MemoryMapOwned) -> uefi::Result<PhysAddr, ()> {
-    // Allocate 2 pages for boot info (should be plenty)
     let pages = allocate_pages(
         AllocateType::AnyPages,
         MemoryType::LOADER_DATA,
-        2,
+        BOOT_INFO_PAGES,
     )?;
     
     // Zero the memory
     unsafe {
-        core::ptr::write_bytes(pages.as_ptr(), 0, 0x2000);
+        core::ptr::write_bytes(pages.as_ptr(), 0, BOOT_INFO_PAGES * 0x1000);
     }
     
     Ok(PhysAddr::new(pages.as_ptr() as u64))
 }
 
 /// Get framebuffer information from GOP
-fn get_framebuffer_info() -> FramebufferInfo {
-    use uefi::proto::console::gop::{GraphicsOutput, PixelFormat as GopPixelFormat};
+fn get_framebuffer_info(video: Option<(usize, usize)>) -> FramebufferInfo {
+    use uefi::proto::console::gop::GraphicsOutput;
     
     let handle = match boot::get_handle_for_protocol::<GraphicsOutput>() {
         Ok(h) => h,
@@ -352,20 +705,44 @@ fn get_framebuffer_info() -> FramebufferInfo {
         Err(_) => return FramebufferInfo::default(),
     };
     
+    // The boot entry's mode, or the largest that fits inside it
+    if let Some((width, height)) = video {
+        let wanted = gop.modes()
+            .filter(|mode| pixel_format(mode.info().pixel_format()).is_some())
+            .filter(|mode| {
+                let (w, h) = mode.info().resolution();
+                w <= width && h <= height
+            })
+            .max_by_key(|mode| {
+                let (w, h) = mode.info().resolution();
+                w * h
+            });
+        match wanted {
+            Some(mode) => {
+                let (w, h) = mode.info().resolution();
+                if (w, h) != (width, height) {
+                    println!("WARNING: The display has no {}x{} mode; using {}x{}", width, height, w, h);
+                }
+                if let Err(e) = gop.set_mode(&mode) {
+                    println!("WARNING: Cannot switch to {}x{}: {:?}", w, h, e);
+                }
+            }
+            None => println!("WARNING: The display has no mode inside {}x{}", width, height),
+        }
+    }
+    
     let mode = gop.current_mode_info();
     let stride = mode.stride();
     let (width, height) = mode.resolution();
     
-    let (format, bpp) = match mode.pixel_format() {
-        GopPixelFormat::Rgb => (PixelFormat::Rgb, 32),
-        GopPixelFormat::Bgr => (PixelFormat::Bgr, 32),
-        GopPixelFormat::Bitmask { .. } => (PixelFormat::Rgb, 32),
-        GopPixelFormat::BltOnly => return FramebufferInfo::default(),
+    let format = match pixel_format(mode.pixel_format()) {
+        Some(format) => format,
+        None => return FramebufferInfo::default(),
     };
+    let bpp = 32;
     
     FramebufferInfo {
         addr: PhysAddr::new(gop.frame_buffer().as_mut_ptr() as u64),
-        virt_addr: None,
         width: width as u32,
         height: height as u32,
         bpp,
@@ -374,11 +751,78 @@ fn get_framebuffer_info() -> FramebufferInfo {
     }
 }
 
-/// Get RSDP address for ACPI
+/// Our pixel format for a GOP one; None for modes without a framebuffer
+fn pixel_format(format: uefi::proto::console::gop::PixelFormat) -> Option<PixelFormat> {
+    use uefi::proto::console::gop::PixelFormat as GopPixelFormat;
+
+    match format {
+        GopPixelFormat::Rgb => Some(PixelFormat::Rgb),
+        GopPixelFormat::Bgr => Some(PixelFormat::Bgr),
+        GopPixelFormat::Bitmask => Some(PixelFormat::Rgb),
+        GopPixelFormat::BltOnly => None,
+    }
+}
+
+/// Every GOP mode with a framebuffer, for the kernel to choose from
+fn get_video_modes() -> Vec<VideoMode> {
+    use uefi::proto::console::gop::GraphicsOutput;
+
+    let gop = match boot::get_handle_for_protocol::<GraphicsOutput>()
+        .and_then(|handle| boot::open_protocol_exclusive::<GraphicsOutput>(handle))
+    {
+        Ok(gop) => gop,
+        Err(_) => return Vec::new(),
+    };
+    gop.modes()
+        .filter_map(|mode| {
+            let info = mode.info();
+            let (width, height) = info.resolution();
+            Some(VideoMode {
+                width: width as u32,
+                height: height as u32,
+                pitch: (info.stride() * 4) as u32,
+                format: pixel_format(info.pixel_format())?,
+            })
+        })
+        .collect()
+}
+
+/// Copy `items` to pages of their own the kernel can reach
+fn copy_boot_data<T: Copy>(items: &[T]) -> uefi::Result<PhysAddr> {
+    let len = core::mem::size_of_val(items);
+    let pages = allocate_pages(
+        AllocateType::MaxAddress(BOOT_DATA_MAX_ADDR),
+        MemoryType::LOADER_DATA,
+        len.max(1).div_ceil(0x1000),
+    )?;
+    unsafe { core::ptr::copy_nonoverlapping(items.as_ptr() as *const u8, pages.as_ptr(), len) };
+    Ok(PhysAddr::new(pages.as_ptr() as u64))
+}
+
+/// Get RSDP address for ACPI from the UEFI configuration table,
+/// preferring the ACPI 2.0 entry, whose RSDP also points to the XSDT
 fn get_rsdp_addr() -> Option<PhysAddr> {
-    // Try to get RSDP from system configuration table
-    // This is a simplified version - full implementation would search config tables
-    None
+    use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+
+    uefi::system::with_config_table(|entries| {
+        let find = |guid| entries.iter().find(|e| e.guid == guid);
+        find(ACPI2_GUID)
+            .or_else(|| find(ACPI_GUID))
+            .map(|e| PhysAddr::new(e.address as u64))
+    })
+}
+
+/// Get the SMBIOS entry point from the UEFI configuration table,
+/// preferring the 3.0 one, which reaches tables above 4GB
+fn get_smbios_addr() -> Option<PhysAddr> {
+    use uefi::table::cfg::{SMBIOS3_GUID, SMBIOS_GUID};
+
+    uefi::system::with_config_table(|entries| {
+        let find = |guid| entries.iter().find(|e| e.guid == guid);
+        find(SMBIOS3_GUID)
+            .or_else(|| find(SMBIOS_GUID))
+            .map(|e| PhysAddr::new(e.address as u64))
+    })
 }
 
 /// Allocate kernel stack at fixed physical address 0x500000
@@ -392,7 +836,7 @@ fn allocate_stack() -> uefi::Result<VirtAddr, ()> {
     // This matches the virtual address 0xFFFF_8000_0050_0000 in higher half
     let stack_pages = allocate_pages(
         AllocateType::Address(0x500000),
-        MemoryType::LOADER_DATA,
+        memory::KERNEL_MEMORY,
         pages,
     )?;
     
@@ -408,36 +852,82 @@ fn allocate_stack() -> uefi::Result<VirtAddr, ()> {
     Ok(VirtAddr::new(stack_top_virt))
 }
 
-/// Convert UEFI memory map to kernel format
-fn convert_memory_map(uefi_map: &MemoryMapOwned) -> Vec<MemoryRegion> {
-    let mut regions = Vec::new();
+/// Reserve the crash dump region at its fixed address, as it is
+///
+/// The memory is not cleared: a panic report the kernel left there before
+/// a warm reboot is for the kernel to find again.
+fn reserve_crash_region() -> uefi::Result<(), ()> {
+    let pages = (CRASH_REGION_SIZE as usize + 0xFFF) / 0x1000;
+    allocate_pages(AllocateType::Address(CRASH_REGION_ADDR), MemoryType::LOADER_DATA, pages)?;
+    Ok(())
+}
+
+/// Whether the kernel can boot with the boot info `features`, going by its
+/// note; one without a note is booted as before
+fn check_features(note: Option<KernelNote>, features: u32) -> bool {
+    let note = match note {
+        Some(note) => note,
+        None => {
+            println!("WARNING: The kernel does not say what it needs from the boot info; booting it anyway");
+            return true;
+        }
+    };
+    let missing = note.required & !features;
+    if missing != 0 {
+        let names: Vec<&str> = bootinfo::feature_names(missing).collect();
+        println!("ERROR: The kernel needs boot info this bootloader does not have: {} ({:#x})", names.join(", "), missing);
+        return false;
+    }
+    let unknown = features & !note.known;
+    if unknown != 0 {
+        let names: Vec<&str> = bootinfo::feature_names(unknown).collect();
+        println!("The kernel predates some of the boot info and goes without it: {}", names.join(", "));
+    }
+    true
+}
+
+/// How much physical memory the physmap covers: all the memory map has,
+/// the framebuffer and at least the first 4GB, where devices are, in
+/// whole GB
+fn physmap_size(memory_map: &MemoryMapOwned, framebuffer: &FramebufferInfo) -> u64 {
+    const GB: u64 = 1 << 30;
+    let ram_end = memory_map.entries().map(|desc| desc.phys_start + desc.page_count * 0x1000).max().unwrap_or(0);
+    let framebuffer_end = framebuffer.addr.as_u64() + framebuffer.size() as u64;
+    ram_end.max(framebuffer_end).max(4 * GB).div_ceil(GB) * GB
+}
+
+/// Convert the UEFI memory map to the kernel's format in the boot info
+/// pages, after the boot info, sorted and merged, at most `capacity`
+/// regions; returns how many there are
+///
+/// Boot services are gone by now, so this allocates nothing. Their memory
+/// is free from here on, so it is `Available`.
+unsafe fn write_memory_map(uefi_map: &MemoryMapOwned, dest: *mut MemoryRegion, capacity: usize) -> usize {
+    let mut count = 0;
     
-    for desc in uefi_map.entries() {
+    for desc in uefi_map.entries().take(capacity) {
         let region_type = match desc.ty {
             MemoryType::CONVENTIONAL => MemoryRegionType::Available,
             MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => MemoryRegionType::Bootloader,
             MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => MemoryRegionType::Available,
-            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => MemoryRegionType::Reserved,
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => MemoryRegionType::EfiRuntime,
             MemoryType::ACPI_RECLAIM => MemoryRegionType::AcpiReclaimable,
             MemoryType::ACPI_NON_VOLATILE => MemoryRegionType::AcpiNvs,
+            MemoryType::UNUSABLE => MemoryRegionType::Bad,
+            memory::KERNEL_MEMORY => MemoryRegionType::Kernel,
+            memory::PAGE_TABLE_MEMORY => MemoryRegionType::PageTables,
             _ => MemoryRegionType::Reserved,
         };
         
-        regions.push(MemoryRegion::new(
+        dest.add(count).write(MemoryRegion::new(
             PhysAddr::new(desc.phys_start),
             ByteSize::new(desc.page_count * 0x1000),
             region_type,
         ));
+        count += 1;
     }
     
-    regions
-}
-
-/// Copy memory map to boot info location
-unsafe fn copy_memory_map(map: &[MemoryRegion], dest: *mut MemoryRegion) -> PhysAddr {
-    let _size = map.len() * core::mem::size_of::<MemoryRegion>();
-    core::ptr::copy_nonoverlapping(map.as_ptr(), dest, map.len());
-    PhysAddr::new(dest as u64)
+    sanitize_memory_map(core::slice::from_raw_parts_mut(dest, count))
 }
 
 /// Panic handler
@@ -5,11 +5,20 @@
 use uefi::boot::{allocate_pages, AllocateType, MemoryType};
 use webbos_shared::types::PhysAddr;
 
+/// Memory type of the kernel's image and stack, from the range UEFI
+/// leaves to OS loaders; the kernel's memory map calls it `Kernel`
+pub const KERNEL_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
+/// Memory type of the kernel's page tables, `PageTables` to the kernel
+///
+/// Everything else the bootloader allocates is `LOADER_DATA`, which the
+/// kernel reclaims once it has read it.
+pub const PAGE_TABLE_MEMORY: MemoryType = MemoryType::custom(0x8000_0001);
+
 /// Allocate a contiguous block of pages
-pub fn alloc_pages(count: usize) -> uefi::Result<PhysAddr, ()> {
+pub fn alloc_pages(count: usize, memory_type: MemoryType) -> uefi::Result<PhysAddr, ()> {
     let pages = allocate_pages(
         AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
+        memory_type,
         count,
     )?;
     
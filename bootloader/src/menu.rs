@@ -0,0 +1,103 @@
+//! Boot menu
+//!
+//! Lists the entries of `webbos.cfg` on the UEFI console, which the
+//! firmware draws over GOP. Up and Down move, Enter boots, and a digit
+//! boots that entry at once. The default boots when the timeout runs out;
+//! any key stops the countdown.
+
+use core::fmt::Write;
+use uefi::proto::console::text::{Color, Key, ScanCode};
+use uefi::{boot, system};
+
+use crate::config::BootConfig;
+
+/// How often the keyboard is polled
+const TICK_MS: usize = 50;
+const TICKS_PER_SECOND: usize = 1000 / TICK_MS;
+
+/// Let whoever is at the console choose an entry; returns its index
+pub fn choose(config: &BootConfig) -> usize {
+    let count = config.entries.len();
+    let mut selected = config.default;
+    if count == 1 {
+        return selected;
+    }
+
+    // Ticks left before the default boots; None once a key stopped them
+    let mut remaining = match config.timeout {
+        0 if read_key().is_none() => return selected,
+        0 => None,
+        timeout => Some(timeout * TICKS_PER_SECOND),
+    };
+
+    draw(config, selected, remaining);
+    loop {
+        let key = match read_key() {
+            Some(key) => key,
+            None => {
+                boot::stall(TICK_MS * 1000);
+                match remaining {
+                    Some(0) => break,
+                    Some(ticks) => {
+                        remaining = Some(ticks - 1);
+                        if (ticks - 1) % TICKS_PER_SECOND == 0 {
+                            draw(config, selected, remaining);
+                        }
+                    }
+                    None => {}
+                }
+                continue;
+            }
+        };
+
+        remaining = None;
+        match key {
+            Key::Special(ScanCode::UP) => selected = (selected + count - 1) % count,
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1) % count,
+            Key::Printable(ch) => match char::from(ch) {
+                '\r' | '\n' => break,
+                digit @ '1'..='9' if (digit as usize - '1' as usize) < count => {
+                    selected = digit as usize - '1' as usize;
+                    break;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        draw(config, selected, remaining);
+    }
+
+    system::with_stdout(|out| {
+        let _ = out.set_color(Color::LightGray, Color::Black);
+        let _ = out.clear();
+    });
+    selected
+}
+
+/// A key if one is waiting
+fn read_key() -> Option<Key> {
+    system::with_stdin(|input| input.read_key().ok().flatten())
+}
+
+fn draw(config: &BootConfig, selected: usize, remaining: Option<usize>) {
+    system::with_stdout(|out| {
+        let _ = out.set_color(Color::LightGray, Color::Black);
+        let _ = out.clear();
+        let _ = out.enable_cursor(false);
+        let _ = writeln!(out, "WebbOS boot menu\n");
+        for (index, entry) in config.entries.iter().enumerate() {
+            if index == selected {
+                let _ = out.set_color(Color::Black, Color::LightGray);
+            }
+            let _ = write!(out, " {}. {:<40}", index + 1, entry.title);
+            let _ = out.set_color(Color::LightGray, Color::Black);
+            let _ = writeln!(out);
+        }
+        let _ = writeln!(out, "\nUp and Down choose, Enter boots");
+        if let Some(ticks) = remaining {
+            let seconds = (ticks + TICKS_PER_SECOND - 1) / TICKS_PER_SECOND;
+            let _ = writeln!(out, "Booting {} in {}s; press a key to wait",
+                config.entries[config.default].title, seconds);
+        }
+    });
+}
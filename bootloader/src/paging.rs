@@ -2,9 +2,16 @@
 //!
 //! Sets up page tables to transition to long mode and map the kernel
 //! into higher half virtual memory.
+//!
+//! All of physical memory is mapped from `KERNEL_BASE` up, the physmap:
+//! the kernel, its stack and the framebuffer are all reached through it,
+//! and the kernel's `mm::phys_to_virt` works for any address.
+
+use crate::memory::{alloc_pages, PAGE_TABLE_MEMORY};
+use webbos_shared::types::{PhysAddr, KERNEL_BASE};
 
-use crate::memory::alloc_pages;
-use webbos_shared::types::PhysAddr;
+const PAGE_2M: u64 = 0x20_0000;
+const PAGE_1G: u64 = 0x4000_0000;
 
 /// Page table entry flags
 pub mod flags {
@@ -161,6 +168,23 @@ impl PageTableManager {
         Ok(())
     }
 
+    /// Map a huge page (1GB)
+    pub fn map_huge_page(
+        &mut self,
+        virt: u64,
+        phys: PhysAddr,
+        flags: u64,
+    ) -> uefi::Result<(), ()> {
+        let pml4_index = ((virt >> 39) & 0x1FF) as usize;
+        let pdpt_index = ((virt >> 30) & 0x1FF) as usize;
+
+        let pdpt = self.get_or_create_next_level(self.pml4, pml4_index)?;
+        let entry = pdpt.get_entry_mut(pdpt_index);
+        entry.set_addr(phys, flags | flags::PRESENT | flags::HUGE_PAGE);
+
+        Ok(())
+    }
+
     /// Get or create the next level page table
     fn get_or_create_next_level(
         &self,
@@ -199,7 +223,7 @@ impl PageTableManager {
 
 /// Allocate a new page table
 fn allocate_page_table() -> uefi::Result<&'static mut PageTable, ()> {
-    let phys_addr = alloc_pages(1)?;
+    let phys_addr = alloc_pages(1, PAGE_TABLE_MEMORY)?;
     
     unsafe {
         core::ptr::write_bytes(phys_addr.as_mut_ptr::<u8>(), 0, 0x1000);
@@ -207,91 +231,35 @@ fn allocate_page_table() -> uefi::Result<&'static mut PageTable, ()> {
     }
 }
 
+/// Whether the CPU can map 1GB pages
+fn has_1g_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0 }
+}
+
 /// Setup kernel paging
 /// 
 /// This creates page tables that map:
-/// - Identity mapping for first 4MB (contains kernel and VGA)
-/// - Identity mapping for bootloader code region
-/// - Higher half mapping for kernel at 0xFFFF_8000_0000_0000
-/// 
-/// The kernel has three segments that need to be mapped:
-/// - 0xFFFF_8000_0010_0000 -> 0x100000 (rodata)
-/// - 0xFFFF_8000_0012_14f0 -> 0x1214f0 (text/code - entry point)
-/// - 0xFFFF_8000_0022_a3dd -> 0x22a3dd (data)
+/// - Identity mapping for the first 512MB, so the bootloader keeps
+///   running after the switch and the kernel can read what it was passed
+/// - The physmap: `physmap_size` bytes of physical memory, from 0, at
+///   `KERNEL_BASE`, in 1GB pages where the CPU has them and 2MB pages
+///   where not
 /// 
-/// We map the entire region from 0xFFFF_8000_0010_0000 to cover all segments
-pub fn setup_kernel_paging(_kernel_size: usize) -> uefi::Result<PhysAddr, ()> {
+/// The kernel is linked at `KERNEL_BASE` plus its physical address, and
+/// its stack and the framebuffer are found the same way, so the physmap
+/// maps all of them.
+pub fn setup_kernel_paging(physmap_size: u64) -> uefi::Result<PhysAddr, ()> {
     // Allocate PML4
     let pml4 = allocate_page_table()?;
     
     unsafe {
         let mut manager = PageTableManager::new(PhysAddr::new(pml4 as *mut _ as u64));
         
-        // Map first 8MB at identity (0x000000-0x800000)
-        // This includes:
-        // - VGA buffer at 0xB8000
-        // - Kernel at 0x100000-0x300000
-        // - Stack at 0x500000
-        for i in 0..4u64 {
-            manager.map_large_page(
-                i * 0x200000,
-                PhysAddr::new(i * 0x200000),
-                flags::PRESENT | flags::WRITABLE,
-            )?;
-        }
-        
-        // Map VGA buffer at higher half (0xFFFF8000000B8000 -> 0xB8000)
-        // Use 4KB page since VGA buffer is not 2MB aligned
-        manager.map_page(
-            0xFFFF_8000_000B_8000,
-            PhysAddr::new(0xB8000),
-            flags::PRESENT | flags::WRITABLE,
-        )?;
-        
-        // Map higher half kernel region (0xFFFF800000100000 -> 0x100000)
-        // Kernel is at 0xFFFF800000100000, needs to be mapped with 4KB pages
-        // because it's not 2MB aligned. Map 4MB to cover the kernel.
-        for i in 0..1024u64 { // 1024 * 4KB = 4MB
-            let phys_addr = 0x100000 + i * 0x1000;
-            let virt_addr = 0xFFFF_8000_0010_0000 + i * 0x1000;
-            manager.map_page(
-                virt_addr,
-                PhysAddr::new(phys_addr),
-                flags::PRESENT | flags::WRITABLE,
-            )?;
-        }
-        
-        // Map kernel stack at 0xFFFF_8000_0050_0000 (5MB in higher half)
-        // The kernel expects the stack at this virtual address
-        // Stack is 128KB, map it with 4KB pages for flexibility
-        // Physical stack is allocated at 0x500000 (5MB physical)
-        for i in 0..32u64 { // 32 * 4KB = 128KB
-            let phys_addr = 0x500000 + i * 0x1000;
-            let virt_addr = 0xFFFF_8000_0050_0000 + i * 0x1000;
-            manager.map_page(
-                virt_addr,
-                PhysAddr::new(phys_addr),
-                flags::PRESENT | flags::WRITABLE,
-            )?;
-        }
-        
-        // Map a large region of physical memory to higher half
-        // This covers 0-512MB mapped at 0xFFFF800000000000
-        // Use 2MB large pages for efficiency
-        for i in 0..256u64 { // 256 * 2MB = 512MB
-            let phys = i * 0x200000;
-            let virt = 0xFFFF_8000_0000_0000 + phys;
-            manager.map_large_page(
-                virt,
-                PhysAddr::new(phys),
-                flags::PRESENT | flags::WRITABLE,
-            )?;
-        }
-        
-        // Also identity map the same 512MB region
-        // This ensures the bootloader can continue executing after page table switch
+        // Identity map the first 512MB with 2MB pages
         for i in 0..256u64 {
-            let phys = i * 0x200000;
+            let phys = i * PAGE_2M;
             manager.map_large_page(
                 phys,
                 PhysAddr::new(phys),
@@ -299,13 +267,16 @@ pub fn setup_kernel_paging(_kernel_size: usize) -> uefi::Result<PhysAddr, ()> {
             )?;
         }
         
-        // Map framebuffer at 0x80000000 (2GB) - used by QEMU for VESA
-        // Map just one 2MB page for now
-        manager.map_large_page(
-            0xFFFF_8000_8000_0000u64,  // Virtual: 0xFFFF800080000000
-            PhysAddr::new(0x80000000),  // Physical: 0x80000000
-            flags::PRESENT | flags::WRITABLE,
-        )?;
+        // The physmap
+        if has_1g_pages() {
+            for phys in (0..physmap_size).step_by(PAGE_1G as usize) {
+                manager.map_huge_page(KERNEL_BASE + phys, PhysAddr::new(phys), flags::PRESENT | flags::WRITABLE)?;
+            }
+        } else {
+            for phys in (0..physmap_size).step_by(PAGE_2M as usize) {
+                manager.map_large_page(KERNEL_BASE + phys, PhysAddr::new(phys), flags::PRESENT | flags::WRITABLE)?;
+            }
+        }
         
         Ok(manager.pml4_addr())
     }
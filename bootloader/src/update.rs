@@ -0,0 +1,88 @@
+//! Booting the A/B update slots
+//!
+//! The kernel installs updates and confirms them; the bootloader counts
+//! the boots of an unconfirmed slot and goes back to the other when they
+//! run out, or at once when its kernel does not load. See
+//! `webbos_shared::update` for the slots and `update.state`.
+
+use alloc::format;
+use alloc::string::String;
+use uefi::proto::media::file::{File, FileAttribute, FileMode};
+use uefi::{boot, println, CString16, Status};
+use webbos_shared::update::{Slot, UpdateState, STATE_FILE};
+
+use crate::config::{BootEntry, SlotChoice};
+
+/// `update.state`, if the ESP has a valid one
+pub fn load() -> Option<UpdateState> {
+    let data = crate::read_file(STATE_FILE).ok()?;
+    let state = core::str::from_utf8(&data).ok().and_then(UpdateState::parse);
+    if state.is_none() {
+        println!("WARNING: {} does not parse; booting slot a", STATE_FILE);
+    }
+    state
+}
+
+/// Write `update.state` back, in place
+fn store(state: &UpdateState) -> uefi::Result<()> {
+    let path = CString16::try_from(STATE_FILE).map_err(|_| uefi::Error::new(Status::INVALID_PARAMETER, ()))?;
+    let mut fs = boot::get_image_file_system(boot::image_handle())?;
+    let mut file = fs.open_volume()?
+        .open(&path, FileMode::ReadWrite, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or_else(|| uefi::Error::new(Status::NOT_FOUND, ()))?;
+    file.write(&state.to_bytes()).map_err(|e| uefi::Error::new(e.status(), ()))?;
+    file.flush()
+}
+
+fn save(state: &UpdateState) {
+    if let Err(e) = store(state) {
+        println!("WARNING: Cannot write {}: {:?}", STATE_FILE, e.status());
+    }
+}
+
+/// The slot `entry` boots, if it boots one; counts the boot of an
+/// unconfirmed slot, and rolls back if it has none left
+pub fn choose(entry: &BootEntry, state: Option<&mut UpdateState>) -> Option<Slot> {
+    let state = match entry.slot? {
+        SlotChoice::Pinned(slot) => return Some(slot),
+        SlotChoice::Active => match state {
+            Some(state) => state,
+            None => return Some(Slot::A),
+        },
+    };
+    let trying = state.active;
+    if state.boot() {
+        if state.active == trying {
+            println!("Slot {} is unconfirmed; {} tries left after this", trying.name(), state.tries);
+        } else {
+            println!("WARNING: Slot {} never confirmed it booted; going back to slot {}", trying.name(), state.active.name());
+        }
+        save(state);
+    }
+    Some(state.active)
+}
+
+/// `slot`'s kernel did not load: go back to the other, if `entry` boots
+/// the active slot and it was unconfirmed
+pub fn fall_back(entry: &BootEntry, slot: Slot, state: Option<&mut UpdateState>) -> Option<Slot> {
+    let state = state?;
+    if entry.slot != Some(SlotChoice::Active) || state.active != slot || state.ok || !state.roll_back() {
+        return None;
+    }
+    println!("WARNING: Slot {} does not load; going back to slot {}", slot.name(), state.active.name());
+    save(state);
+    Some(state.active)
+}
+
+/// The kernel's path and how much of the file it is; None for all of it
+pub fn kernel(slot: Slot, state: Option<&UpdateState>) -> (String, Option<usize>) {
+    let size = state.map_or(0, |state| state.kernel_size(slot));
+    (format!("{}\\kernel.elf", slot.dir()), Some(size as usize).filter(|&size| size > 0))
+}
+
+/// The initrd's path and size, if the slot has one
+pub fn initrd(slot: Slot, state: Option<&UpdateState>) -> Option<(String, usize)> {
+    let size = state?.initrd_size(slot);
+    Some((format!("{}\\initrd.tar", slot.dir()), size as usize)).filter(|_| size > 0)
+}
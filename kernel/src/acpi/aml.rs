@@ -0,0 +1,1519 @@
+//! A small AML interpreter
+//!
+//! Enough of the ACPI Machine Language in the DSDT and SSDTs to ask the
+//! firmware about batteries and power adapters. `Namespace::load` runs a
+//! table's definitions: scopes, devices, names, methods, operation regions
+//! and the fields in them. `Namespace::evaluate` runs control methods,
+//! which for the most part read fields and build packages out of them.
+//! Registers are reached through `Hardware`, so nothing here touches a
+//! port or physical memory itself.
+//!
+//! What it does not handle, such as buffer fields, events or loading more
+//! tables, fails the evaluation rather than being guessed at. Mutexes are
+//! always acquired at once: only one method runs at a time.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// Operation region address spaces
+pub const SPACE_MEMORY: u8 = 0;
+pub const SPACE_IO: u8 = 1;
+pub const SPACE_PCI: u8 = 2;
+pub const SPACE_EC: u8 = 3;
+
+/// Calls nested deeper than this are taken for runaway recursion
+const MAX_DEPTH: usize = 32;
+
+/// Turns of one `While` before it is given up on
+const MAX_LOOPS: usize = 100_000;
+
+/// Registers, as the firmware's methods reach them
+pub trait Hardware {
+    /// Read `width` bytes, 1, 2, 4 or 8, at `address` in address space
+    /// `space`; in PCI configuration space the address is a device,
+    /// function and offset on bus 0, as in the FADT's generic addresses
+    fn read(&mut self, space: u8, address: u64, width: u8) -> Option<u64>;
+    fn write(&mut self, space: u8, address: u64, width: u8, value: u64) -> Option<()>;
+    /// `Sleep` and `Stall`, in microseconds
+    fn sleep(&mut self, us: u64);
+}
+
+/// An AML data object
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<Value>),
+    /// What `Index` and `RefOf` give, for `DerefOf` and stores
+    Reference(Box<Place>),
+}
+
+impl Value {
+    /// The value as an integer, converting buffers and strings as AML
+    /// does: buffers little-endian, strings as hex
+    pub fn as_integer(&self) -> Option<u64> {
+        match self {
+            Value::Integer(n) => Some(*n),
+            Value::Buffer(bytes) => Some(bytes.iter().take(8).rev().fold(0, |n, &b| n << 8 | b as u64)),
+            Value::String(s) => {
+                let hex: String = s.chars().take_while(char::is_ascii_hexdigit).take(16).collect();
+                Some(u64::from_str_radix(&hex, 16).unwrap_or(0))
+            }
+            _ => None,
+        }
+    }
+
+    /// The text of a string, or of a buffer holding one
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Buffer(bytes) => {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_package(&self) -> Option<&[Value]> {
+        match self {
+            Value::Package(elements) => Some(elements),
+            _ => None,
+        }
+    }
+}
+
+/// Where a value can be stored
+#[derive(Debug, Clone, PartialEq)]
+pub enum Place {
+    Local(usize),
+    Arg(usize),
+    /// A named object, by its absolute path
+    Named(String),
+    /// An element of a package, or a byte of a buffer
+    Index(Box<Place>, usize),
+    /// A value with nowhere to go, such as a package built in place
+    Temporary(Value),
+    /// The debug object, which takes anything and keeps none of it
+    Debug,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmlError {
+    /// The code ends in the middle of something
+    Truncated,
+    /// An opcode this interpreter does not handle; extended ones as 0x5Bxx
+    Unsupported(u16),
+    /// A name that is not in the namespace
+    NotFound(String),
+    /// An operand of the wrong type, or an index out of range
+    Type,
+    /// The hardware would not read or write a region
+    Hardware,
+    DivideByZero,
+    /// Calls nested too deep, or a loop that would not end
+    Runaway,
+}
+
+type Result<T> = core::result::Result<T, AmlError>;
+
+/// A field's location: a region, or registers that lead to one
+#[derive(Debug, Clone)]
+enum FieldKind {
+    Region(String),
+    /// A field of `region` that needs `value` written to the `bank` field
+    /// first
+    Bank { region: String, bank: String, value: u64 },
+    /// Reached by writing the offset to the `index` field and then using
+    /// the `data` field
+    Index { index: String, data: String },
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    kind: FieldKind,
+    bit_offset: u64,
+    bits: u64,
+    /// Bytes per access
+    access: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    space: u8,
+    offset: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Object {
+    /// A scope, or something that is one: a device, processor, thermal
+    /// zone or power resource
+    Scope { device: bool },
+    Name(Value),
+    /// A control method, where its code is in which table
+    Method { table: usize, start: usize, end: usize, args: usize },
+    Region(Region),
+    Field(Field),
+    Alias(String),
+    /// A mutex or event, which need nothing from a single-threaded
+    /// interpreter
+    Sync,
+}
+
+/// A name as it appears in the code, before it is looked up
+#[derive(Debug, Clone)]
+struct NameString {
+    root: bool,
+    parents: usize,
+    segments: Vec<String>,
+}
+
+impl NameString {
+    /// The path the name means in `scope`, without searching
+    fn absolute(&self, scope: &str) -> String {
+        let mut path = String::from(if self.root { "\\" } else { scope });
+        for _ in 0..self.parents {
+            path = String::from(parent(&path));
+        }
+        for segment in &self.segments {
+            path = join(&path, segment);
+        }
+        path
+    }
+}
+
+/// The path of `segment` in `scope`
+pub fn join(scope: &str, segment: &str) -> String {
+    if scope == "\\" {
+        format!("\\{}", segment)
+    } else {
+        format!("{}.{}", scope, segment)
+    }
+}
+
+/// The scope `path` is in
+fn parent(path: &str) -> &str {
+    match path.rfind('.') {
+        Some(dot) => &path[..dot],
+        None => "\\",
+    }
+}
+
+/// A device's hardware ID from a `_HID` or `_CID` integer, a compressed
+/// EISA ID such as `PNP0C0A`
+pub fn eisa_id(value: u64) -> String {
+    let id = (value as u32).swap_bytes();
+    let letter = |shift: u32| (0x40 + ((id >> shift) & 0x1F) as u8) as char;
+    format!("{}{}{}{:04X}", letter(26), letter(21), letter(16), id & 0xFFFF)
+}
+
+fn is_name_start(byte: u8) -> bool {
+    matches!(byte, b'\\' | b'^' | b'_' | b'A'..=b'Z' | 0x2E | 0x2F)
+}
+
+/// Bytes per access for a field's flags
+fn access_width(flags: u8) -> u8 {
+    match flags & 0x0F {
+        2 => 2,
+        3 => 4,
+        4 => 8,
+        _ => 1,
+    }
+}
+
+/// Where a term that carries its length, such as a `Scope` or `Method`,
+/// ends
+fn package_end(code: &[u8], start: usize) -> Option<usize> {
+    let mut c = Cursor { code, pos: start, table: 0 };
+    let has_length = match c.byte().ok()? {
+        0x10..=0x14 | 0xA0..=0xA2 => true,
+        0x5B => matches!(c.byte().ok()?, 0x81..=0x87),
+        _ => false,
+    };
+    if !has_length {
+        return None;
+    }
+    c.pkg_end().ok()
+}
+
+/// A position in a table's code
+struct Cursor<'a> {
+    code: &'a [u8],
+    pos: usize,
+    table: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Result<u8> {
+        self.code.get(self.pos).copied().ok_or(AmlError::Truncated)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    /// A little-endian integer of `len` bytes
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        let bytes = self.code.get(self.pos..self.pos + len).ok_or(AmlError::Truncated)?;
+        self.pos += len;
+        Ok(bytes.iter().rev().fold(0, |n, &b| n << 8 | b as u64))
+    }
+
+    /// A PkgLength's value; its top two bits count the bytes after the
+    /// first
+    fn pkg_length(&mut self) -> Result<usize> {
+        let lead = self.byte()?;
+        let extra = (lead >> 6) as usize;
+        if extra == 0 {
+            return Ok((lead & 0x3F) as usize);
+        }
+        let mut len = (lead & 0x0F) as usize;
+        for i in 0..extra {
+            len |= (self.byte()? as usize) << (4 + 8 * i);
+        }
+        Ok(len)
+    }
+
+    /// A PkgLength, as where the package it starts ends
+    fn pkg_end(&mut self) -> Result<usize> {
+        let start = self.pos;
+        let end = start + self.pkg_length()?;
+        if end > self.code.len() || end < self.pos {
+            return Err(AmlError::Truncated);
+        }
+        Ok(end)
+    }
+
+    fn name_seg(&mut self) -> Result<String> {
+        let bytes = self.code.get(self.pos..self.pos + 4).ok_or(AmlError::Truncated)?;
+        self.pos += 4;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn name_string(&mut self) -> Result<NameString> {
+        let mut name = NameString { root: false, parents: 0, segments: Vec::new() };
+        if self.peek()? == b'\\' {
+            self.pos += 1;
+            name.root = true;
+        }
+        while self.peek()? == b'^' {
+            self.pos += 1;
+            name.parents += 1;
+        }
+        let count = match self.peek()? {
+            0x00 => {
+                self.pos += 1;
+                0
+            }
+            0x2E => {
+                self.pos += 1;
+                2
+            }
+            0x2F => {
+                self.pos += 1;
+                self.byte()? as usize
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segments.push(self.name_seg()?);
+        }
+        Ok(name)
+    }
+}
+
+/// A method's state while it runs
+struct Frame {
+    /// Where names are looked up from
+    scope: String,
+    locals: Vec<Value>,
+    args: Vec<Value>,
+    /// Running a table's definitions: method bodies are kept rather than
+    /// run, and a term that fails is skipped if its length is known
+    loading: bool,
+}
+
+/// How a term list was left
+enum Flow {
+    Next,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+/// The objects the tables define, by absolute path such as
+/// `\_SB_.PCI0.BAT0`
+pub struct Namespace {
+    tables: Vec<Arc<[u8]>>,
+    objects: BTreeMap<String, Object>,
+    /// Integers are 64-bit, as from DSDT revision 2
+    wide: bool,
+    depth: usize,
+}
+
+impl Namespace {
+    pub fn new(wide: bool) -> Self {
+        let mut objects = BTreeMap::new();
+        for path in ["\\", "\\_GPE", "\\_PR_", "\\_SB_", "\\_SI_", "\\_TZ_"] {
+            objects.insert(String::from(path), Object::Scope { device: false });
+        }
+        Namespace { tables: Vec::new(), objects, wide, depth: 0 }
+    }
+
+    /// Number of named objects
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Run the definitions in `aml`, the code of a DSDT or SSDT after its
+    /// header; what comes before an error stays defined
+    pub fn load(&mut self, aml: &[u8], hw: &mut dyn Hardware) -> Result<()> {
+        self.tables.push(Arc::from(aml));
+        let table = self.tables.len() - 1;
+        let mut frame = Frame { scope: String::from("\\"), locals: Vec::new(), args: Vec::new(), loading: true };
+        self.run(hw, &mut frame, table, 0, aml.len()).map(|_| ())
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.objects.contains_key(path)
+    }
+
+    /// Every device, by path
+    pub fn devices(&self) -> Vec<String> {
+        self.objects.iter()
+            .filter(|(_, object)| matches!(object, Object::Scope { device: true }))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Devices whose `_HID` or `_CID` is `hid`, such as `PNP0C0A`
+    pub fn find_devices(&mut self, hw: &mut dyn Hardware, hid: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        for device in self.devices() {
+            let matches = ["_HID", "_CID"].iter().any(|id| {
+                let ids = match self.evaluate(hw, &join(&device, id), Vec::new()) {
+                    Ok(Value::Package(ids)) => ids,
+                    Ok(value) => vec![value],
+                    Err(_) => Vec::new(),
+                };
+                ids.iter().any(|value| match value {
+                    Value::Integer(n) => eisa_id(*n) == hid,
+                    value => value.as_string().map_or(false, |s| s == hid),
+                })
+            });
+            if matches {
+                found.push(device);
+            }
+        }
+        found
+    }
+
+    /// The value of the object at `path`: a method's result, a field's
+    /// contents or a name's value
+    pub fn evaluate(&mut self, hw: &mut dyn Hardware, path: &str, args: Vec<Value>) -> Result<Value> {
+        let path = self.follow(path);
+        match self.objects.get(&path) {
+            Some(Object::Method { .. }) => self.call(hw, &path, args),
+            Some(_) => self.read_named(hw, &path),
+            None => Err(AmlError::NotFound(path)),
+        }
+    }
+
+    /// All ones, as wide as integers are
+    fn ones(&self) -> u64 {
+        if self.wide { u64::MAX } else { u32::MAX as u64 }
+    }
+
+    /// `path`, or what it is an alias of
+    fn follow(&self, path: &str) -> String {
+        let mut path = String::from(path);
+        for _ in 0..8 {
+            match self.objects.get(&path) {
+                Some(Object::Alias(target)) => path = target.clone(),
+                _ => break,
+            }
+        }
+        path
+    }
+
+    /// The path a name used in `scope` refers to; a name of a single
+    /// segment is looked for in the enclosing scopes too
+    fn resolve(&self, scope: &str, name: &NameString) -> Option<String> {
+        if name.root || name.parents > 0 || name.segments.len() != 1 {
+            let path = name.absolute(scope);
+            return self.objects.contains_key(&path).then_some(path);
+        }
+        let mut scope = String::from(scope);
+        loop {
+            let path = join(&scope, &name.segments[0]);
+            if self.objects.contains_key(&path) {
+                return Some(path);
+            }
+            if scope == "\\" {
+                return None;
+            }
+            scope = String::from(parent(&scope));
+        }
+    }
+
+    fn call(&mut self, hw: &mut dyn Hardware, path: &str, args: Vec<Value>) -> Result<Value> {
+        let (table, start, end) = match self.objects.get(path) {
+            Some(&Object::Method { table, start, end, .. }) => (table, start, end),
+            _ => return Err(AmlError::NotFound(String::from(path))),
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err(AmlError::Runaway);
+        }
+        self.depth += 1;
+        // A method is a scope of its own, for the names it makes
+        let mut frame = Frame { scope: String::from(path), locals: vec![Value::Integer(0); 8], args, loading: false };
+        let result = self.run(hw, &mut frame, table, start, end);
+        self.depth -= 1;
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Integer(0)),
+        }
+    }
+
+    /// Run the terms from `start` to `end` of a table
+    fn run(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, table: usize, start: usize, end: usize) -> Result<Flow> {
+        let code = self.tables[table].clone();
+        let mut c = Cursor { code: &code, pos: start, table };
+        self.terms(hw, frame, &mut c, end)
+    }
+
+    fn terms(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor, end: usize) -> Result<Flow> {
+        while c.pos < end {
+            let start = c.pos;
+            match self.term(hw, frame, c, end) {
+                Ok(Flow::Next) => {}
+                Ok(flow) => return Ok(flow),
+                Err(e) if frame.loading => match package_end(c.code, start) {
+                    Some(next) if next <= end => c.pos = next,
+                    _ => return Err(e),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Run the body of a scope-like definition named `path`
+    fn scope_body(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor, path: String, end: usize) -> Result<Flow> {
+        let outer = core::mem::replace(&mut frame.scope, path);
+        let flow = self.terms(hw, frame, c, end);
+        frame.scope = outer;
+        c.pos = end;
+        flow
+    }
+
+    /// Define a scope-like object from its name on, and run its body
+    fn define_scope(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor, end: usize, skip: usize, device: bool) -> Result<Flow> {
+        let path = c.name_string()?.absolute(&frame.scope);
+        c.pos += skip;
+        self.objects.insert(path.clone(), Object::Scope { device });
+        self.scope_body(hw, frame, c, path, end)
+    }
+
+    /// One term: a definition, a statement or an expression whose value
+    /// is thrown away
+    fn term(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor, end: usize) -> Result<Flow> {
+        match c.peek()? {
+            // Scope
+            0x10 => {
+                c.pos += 1;
+                let pkg_end = c.pkg_end()?;
+                let name = c.name_string()?;
+                let path = self.resolve(&frame.scope, &name).unwrap_or_else(|| name.absolute(&frame.scope));
+                self.objects.entry(path.clone()).or_insert(Object::Scope { device: false });
+                self.scope_body(hw, frame, c, path, pkg_end)
+            }
+            // Name
+            0x08 => {
+                c.pos += 1;
+                let path = c.name_string()?.absolute(&frame.scope);
+                let value = self.eval(hw, frame, c)?;
+                self.objects.insert(path, Object::Name(value));
+                Ok(Flow::Next)
+            }
+            // Method
+            0x14 => {
+                c.pos += 1;
+                let pkg_end = c.pkg_end()?;
+                let path = c.name_string()?.absolute(&frame.scope);
+                let flags = c.byte()?;
+                self.objects.insert(path, Object::Method { table: c.table, start: c.pos, end: pkg_end, args: (flags & 7) as usize });
+                c.pos = pkg_end;
+                Ok(Flow::Next)
+            }
+            // Alias
+            0x06 => {
+                c.pos += 1;
+                let source = c.name_string()?;
+                let source = self.resolve(&frame.scope, &source).unwrap_or_else(|| source.absolute(&frame.scope));
+                let alias = c.name_string()?.absolute(&frame.scope);
+                self.objects.insert(alias, Object::Alias(source));
+                Ok(Flow::Next)
+            }
+            // External: only a declaration
+            0x15 => {
+                c.pos += 1;
+                c.name_string()?;
+                c.pos += 2;
+                Ok(Flow::Next)
+            }
+            0x5B => self.ext_term(hw, frame, c),
+            // If, and the Else after it
+            0xA0 => {
+                c.pos += 1;
+                let pkg_end = c.pkg_end()?;
+                let taken = self.eval_integer(hw, frame, c)? != 0;
+                let mut flow = Flow::Next;
+                if taken {
+                    flow = self.terms(hw, frame, c, pkg_end)?;
+                }
+                c.pos = pkg_end;
+                if c.pos < end && c.peek()? == 0xA1 {
+                    c.pos += 1;
+                    let else_end = c.pkg_end()?;
+                    if !taken {
+                        flow = self.terms(hw, frame, c, else_end)?;
+                    }
+                    c.pos = else_end;
+                }
+                Ok(flow)
+            }
+            // An Else with no If before it
+            0xA1 => {
+                c.pos += 1;
+                c.pos = c.pkg_end()?;
+                Ok(Flow::Next)
+            }
+            // While
+            0xA2 => {
+                c.pos += 1;
+                let pkg_end = c.pkg_end()?;
+                let predicate = c.pos;
+                for _ in 0..MAX_LOOPS {
+                    c.pos = predicate;
+                    if self.eval_integer(hw, frame, c)? == 0 {
+                        c.pos = pkg_end;
+                        return Ok(Flow::Next);
+                    }
+                    match self.terms(hw, frame, c, pkg_end)? {
+                        Flow::Break => {
+                            c.pos = pkg_end;
+                            return Ok(Flow::Next);
+                        }
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+                Err(AmlError::Runaway)
+            }
+            // Noop, BreakPoint
+            0xA3 | 0xCC => {
+                c.pos += 1;
+                Ok(Flow::Next)
+            }
+            // Return
+            0xA4 => {
+                c.pos += 1;
+                let value = self.eval(hw, frame, c)?;
+                Ok(Flow::Return(value))
+            }
+            0xA5 => {
+                c.pos += 1;
+                Ok(Flow::Break)
+            }
+            0x9F => {
+                c.pos += 1;
+                Ok(Flow::Continue)
+            }
+            // Buffer fields: CreateDWordField and the like
+            op @ (0x8A..=0x8D | 0x8F) => Err(AmlError::Unsupported(op as u16)),
+            _ => {
+                self.eval(hw, frame, c)?;
+                Ok(Flow::Next)
+            }
+        }
+    }
+
+    /// Extended definitions, after 0x5B; anything else is an expression
+    fn ext_term(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<Flow> {
+        let op = *c.code.get(c.pos + 1).ok_or(AmlError::Truncated)?;
+        match op {
+            // OperationRegion
+            0x80 => {
+                c.pos += 2;
+                let path = c.name_string()?.absolute(&frame.scope);
+                let space = c.byte()?;
+                let offset = self.eval_integer(hw, frame, c)?;
+                self.eval_integer(hw, frame, c)?;
+                self.objects.insert(path, Object::Region(Region { space, offset }));
+            }
+            // Field
+            0x81 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                let region = self.name_path(frame, c)?;
+                let flags = c.byte()?;
+                self.field_list(frame, c, end, FieldKind::Region(region), flags)?;
+            }
+            // Device
+            0x82 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                return self.define_scope(hw, frame, c, end, 0, true);
+            }
+            // Processor: an ID, a register block address and its length
+            0x83 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                return self.define_scope(hw, frame, c, end, 6, false);
+            }
+            // PowerResource: a system level and a resource order
+            0x84 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                return self.define_scope(hw, frame, c, end, 3, false);
+            }
+            // ThermalZone
+            0x85 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                return self.define_scope(hw, frame, c, end, 0, false);
+            }
+            // IndexField
+            0x86 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                let index = self.name_path(frame, c)?;
+                let data = self.name_path(frame, c)?;
+                let flags = c.byte()?;
+                self.field_list(frame, c, end, FieldKind::Index { index, data }, flags)?;
+            }
+            // BankField
+            0x87 => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                let region = self.name_path(frame, c)?;
+                let bank = self.name_path(frame, c)?;
+                let value = self.eval_integer(hw, frame, c)?;
+                let flags = c.byte()?;
+                self.field_list(frame, c, end, FieldKind::Bank { region, bank, value }, flags)?;
+            }
+            // Mutex, with its sync level
+            0x01 => {
+                c.pos += 2;
+                let path = c.name_string()?.absolute(&frame.scope);
+                c.byte()?;
+                self.objects.insert(path, Object::Sync);
+            }
+            // Event
+            0x02 => {
+                c.pos += 2;
+                let path = c.name_string()?.absolute(&frame.scope);
+                self.objects.insert(path, Object::Sync);
+            }
+            // CreateField
+            0x13 => return Err(AmlError::Unsupported(0x5B13)),
+            _ => {
+                self.eval(hw, frame, c)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// A name that must already be defined, as its path
+    fn name_path(&self, frame: &Frame, c: &mut Cursor) -> Result<String> {
+        let name = c.name_string()?;
+        self.resolve(&frame.scope, &name).ok_or_else(|| AmlError::NotFound(name.absolute(&frame.scope)))
+    }
+
+    /// The fields of a Field, IndexField or BankField, up to `end`
+    fn field_list(&mut self, frame: &Frame, c: &mut Cursor, end: usize, kind: FieldKind, flags: u8) -> Result<()> {
+        let mut access = access_width(flags);
+        let mut offset = 0;
+        while c.pos < end {
+            match c.peek()? {
+                // Reserved bits
+                0x00 => {
+                    c.pos += 1;
+                    offset += c.pkg_length()? as u64;
+                }
+                // AccessField, with an access attribute
+                0x01 => {
+                    c.pos += 1;
+                    access = access_width(c.byte()?);
+                    c.byte()?;
+                }
+                // ExtendedAccessField, with an access length too
+                0x03 => {
+                    c.pos += 1;
+                    access = access_width(c.byte()?);
+                    c.pos += 2;
+                }
+                // ConnectField, for serial buses and GPIO
+                0x02 => return Err(AmlError::Unsupported(0x02)),
+                _ => {
+                    let name = c.name_seg()?;
+                    let bits = c.pkg_length()? as u64;
+                    let field = Field { kind: kind.clone(), bit_offset: offset, bits, access };
+                    self.objects.insert(join(&frame.scope, &name), Object::Field(field));
+                    offset += bits;
+                }
+            }
+        }
+        c.pos = end;
+        Ok(())
+    }
+
+    fn eval_integer(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<u64> {
+        let value = self.eval(hw, frame, c)?;
+        value.as_integer().map(|n| n & self.ones()).ok_or(AmlError::Type)
+    }
+
+    /// Evaluate a term argument
+    fn eval(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<Value> {
+        let op = c.byte()?;
+        let value = match op {
+            0x00 => Value::Integer(0),
+            0x01 => Value::Integer(1),
+            0xFF => Value::Integer(self.ones()),
+            0x0A => Value::Integer(c.uint(1)?),
+            0x0B => Value::Integer(c.uint(2)?),
+            0x0C => Value::Integer(c.uint(4)?),
+            0x0E => Value::Integer(c.uint(8)?),
+            0x0D => {
+                let rest = c.code.get(c.pos..).ok_or(AmlError::Truncated)?;
+                let len = rest.iter().position(|&b| b == 0).ok_or(AmlError::Truncated)?;
+                c.pos += len + 1;
+                Value::String(String::from_utf8_lossy(&rest[..len]).into_owned())
+            }
+            // Buffer
+            0x11 => {
+                let end = c.pkg_end()?;
+                let size = self.eval_integer(hw, frame, c)? as usize;
+                let mut bytes = c.code.get(c.pos..end).ok_or(AmlError::Truncated)?.to_vec();
+                if bytes.len() < size {
+                    bytes.resize(size, 0);
+                }
+                c.pos = end;
+                Value::Buffer(bytes)
+            }
+            // Package and VarPackage
+            0x12 => {
+                let end = c.pkg_end()?;
+                let count = c.byte()? as usize;
+                self.package(hw, frame, c, end, count)?
+            }
+            0x13 => {
+                let end = c.pkg_end()?;
+                let count = self.eval_integer(hw, frame, c)? as usize;
+                self.package(hw, frame, c, end, count)?
+            }
+            0x60..=0x67 => frame.locals[(op - 0x60) as usize].clone(),
+            0x68..=0x6E => frame.args.get((op - 0x68) as usize).cloned().unwrap_or(Value::Integer(0)),
+            // Store
+            0x70 => {
+                let value = self.eval(hw, frame, c)?;
+                let target = self.target(hw, frame, c)?;
+                self.store(hw, frame, target, value.clone())?;
+                value
+            }
+            // RefOf
+            0x71 => match self.target(hw, frame, c)? {
+                Some(place) => Value::Reference(Box::new(place)),
+                None => return Err(AmlError::Type),
+            },
+            // Add, Subtract, Multiply, ShiftLeft, ShiftRight, And, Nand, Or,
+            // Nor, Xor, Mod
+            0x72 | 0x74 | 0x77 | 0x79..=0x7F | 0x85 => {
+                let a = self.eval_integer(hw, frame, c)?;
+                let b = self.eval_integer(hw, frame, c)?;
+                let result = match op {
+                    0x72 => a.wrapping_add(b),
+                    0x74 => a.wrapping_sub(b),
+                    0x77 => a.wrapping_mul(b),
+                    0x79 => if b < 64 { a << b } else { 0 },
+                    0x7A => if b < 64 { a >> b } else { 0 },
+                    0x7B => a & b,
+                    0x7C => !(a & b),
+                    0x7D => a | b,
+                    0x7E => !(a | b),
+                    0x7F => a ^ b,
+                    _ => a.checked_rem(b).ok_or(AmlError::DivideByZero)?,
+                };
+                self.result(hw, frame, c, Value::Integer(result & self.ones()))?
+            }
+            // Concatenate
+            0x73 => {
+                let a = self.eval(hw, frame, c)?;
+                let b = self.eval(hw, frame, c)?;
+                let joined = match a {
+                    Value::String(mut s) => {
+                        s.push_str(&match b {
+                            Value::Integer(n) => format!("{:X}", n),
+                            b => b.as_string().ok_or(AmlError::Type)?,
+                        });
+                        Value::String(s)
+                    }
+                    a => {
+                        let mut bytes = self.bytes(&a)?;
+                        bytes.extend(self.bytes(&b)?);
+                        Value::Buffer(bytes)
+                    }
+                };
+                self.result(hw, frame, c, joined)?
+            }
+            // Increment, Decrement
+            0x75 | 0x76 => {
+                let place = self.target(hw, frame, c)?.ok_or(AmlError::Type)?;
+                let n = self.read_place(hw, frame, &place)?.as_integer().ok_or(AmlError::Type)?;
+                let n = if op == 0x75 { n.wrapping_add(1) } else { n.wrapping_sub(1) } & self.ones();
+                self.store(hw, frame, Some(place), Value::Integer(n))?;
+                Value::Integer(n)
+            }
+            // Divide: remainder, then quotient
+            0x78 => {
+                let a = self.eval_integer(hw, frame, c)?;
+                let b = self.eval_integer(hw, frame, c)?;
+                if b == 0 {
+                    return Err(AmlError::DivideByZero);
+                }
+                let remainder = self.target(hw, frame, c)?;
+                self.store(hw, frame, remainder, Value::Integer(a % b))?;
+                self.result(hw, frame, c, Value::Integer(a / b))?
+            }
+            // Not, FindSetLeftBit, FindSetRightBit
+            0x80..=0x82 => {
+                let a = self.eval_integer(hw, frame, c)?;
+                let result = match op {
+                    0x80 => !a & self.ones(),
+                    0x81 => if a == 0 { 0 } else { 64 - a.leading_zeros() as u64 },
+                    _ => if a == 0 { 0 } else { a.trailing_zeros() as u64 + 1 },
+                };
+                self.result(hw, frame, c, Value::Integer(result))?
+            }
+            // DerefOf
+            0x83 => match self.eval(hw, frame, c)? {
+                Value::Reference(place) => self.read_place(hw, frame, &place)?,
+                _ => return Err(AmlError::Type),
+            },
+            // Notify: nobody is listening
+            0x86 => {
+                self.target(hw, frame, c)?;
+                self.eval_integer(hw, frame, c)?;
+                Value::Integer(0)
+            }
+            // SizeOf
+            0x87 => {
+                let place = self.target(hw, frame, c)?.ok_or(AmlError::Type)?;
+                match self.read_place(hw, frame, &place)? {
+                    Value::String(s) => Value::Integer(s.len() as u64),
+                    Value::Buffer(bytes) => Value::Integer(bytes.len() as u64),
+                    Value::Package(elements) => Value::Integer(elements.len() as u64),
+                    _ => return Err(AmlError::Type),
+                }
+            }
+            // Index
+            0x88 => {
+                let source = self.source(hw, frame, c)?;
+                let index = self.eval_integer(hw, frame, c)? as usize;
+                let reference = Value::Reference(Box::new(Place::Index(Box::new(source), index)));
+                self.result(hw, frame, c, reference)?
+            }
+            // LAnd, LOr
+            0x90 | 0x91 => {
+                let a = self.eval_integer(hw, frame, c)? != 0;
+                let b = self.eval_integer(hw, frame, c)? != 0;
+                self.boolean(if op == 0x90 { a && b } else { a || b })
+            }
+            // LNot
+            0x92 => {
+                let a = self.eval_integer(hw, frame, c)?;
+                self.boolean(a == 0)
+            }
+            // LEqual, LGreater, LLess
+            0x93..=0x95 => {
+                let a = self.eval(hw, frame, c)?;
+                let b = self.eval(hw, frame, c)?;
+                let ordering = self.compare(&a, &b)?;
+                self.boolean(ordering == [Ordering::Equal, Ordering::Greater, Ordering::Less][(op - 0x93) as usize])
+            }
+            // ToBuffer
+            0x96 => {
+                let a = self.eval(hw, frame, c)?;
+                let bytes = self.bytes(&a)?;
+                self.result(hw, frame, c, Value::Buffer(bytes))?
+            }
+            // ToDecimalString, ToHexString
+            0x97 | 0x98 => {
+                let a = self.eval(hw, frame, c)?;
+                let text = match (&a, op) {
+                    (Value::Integer(n), 0x97) => format!("{}", n),
+                    (Value::Integer(n), _) => format!("0x{:X}", n),
+                    (Value::String(s), _) => s.clone(),
+                    (Value::Buffer(bytes), 0x97) => bytes.iter().map(|b| format!("{}", b)).collect::<Vec<_>>().join(","),
+                    (Value::Buffer(bytes), _) => bytes.iter().map(|b| format!("0x{:02X}", b)).collect::<Vec<_>>().join(","),
+                    _ => return Err(AmlError::Type),
+                };
+                self.result(hw, frame, c, Value::String(text))?
+            }
+            // ToInteger
+            0x99 => {
+                let a = self.eval_integer(hw, frame, c)?;
+                self.result(hw, frame, c, Value::Integer(a))?
+            }
+            // ToString: a buffer's bytes up to a NUL or the length
+            0x9C => {
+                let bytes = match self.eval(hw, frame, c)? {
+                    Value::Buffer(bytes) => bytes,
+                    _ => return Err(AmlError::Type),
+                };
+                let limit = self.eval_integer(hw, frame, c)? as usize;
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len()).min(limit);
+                self.result(hw, frame, c, Value::String(String::from_utf8_lossy(&bytes[..end]).into_owned()))?
+            }
+            // Mid
+            0x9E => {
+                let source = self.eval(hw, frame, c)?;
+                let start = self.eval_integer(hw, frame, c)? as usize;
+                let len = self.eval_integer(hw, frame, c)? as usize;
+                let part = |bytes: &[u8]| bytes[start.min(bytes.len())..start.saturating_add(len).min(bytes.len())].to_vec();
+                let value = match source {
+                    Value::String(s) => Value::String(String::from_utf8_lossy(&part(s.as_bytes())).into_owned()),
+                    Value::Buffer(bytes) => Value::Buffer(part(&bytes)),
+                    _ => return Err(AmlError::Type),
+                };
+                self.result(hw, frame, c, value)?
+            }
+            0x5B => self.ext_eval(hw, frame, c)?,
+            op if is_name_start(op) => {
+                c.pos -= 1;
+                self.eval_name(hw, frame, c)?
+            }
+            op => return Err(AmlError::Unsupported(op as u16)),
+        };
+        Ok(value)
+    }
+
+    /// Extended expressions, after 0x5B
+    fn ext_eval(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<Value> {
+        let op = c.byte()?;
+        let value = match op {
+            // CondRefOf: whether the name is there, which is the point, so
+            // it is not looked up as usual
+            0x12 => {
+                let place = match c.peek()? {
+                    byte if is_name_start(byte) => {
+                        let name = c.name_string()?;
+                        self.resolve(&frame.scope, &name).map(Place::Named)
+                    }
+                    _ => self.target(hw, frame, c)?,
+                };
+                let target = self.target(hw, frame, c)?;
+                match place {
+                    Some(place) => {
+                        self.store(hw, frame, target, Value::Reference(Box::new(place)))?;
+                        Value::Integer(1)
+                    }
+                    None => Value::Integer(0),
+                }
+            }
+            // Stall, in microseconds, and Sleep, in milliseconds
+            0x21 | 0x22 => {
+                let time = self.eval_integer(hw, frame, c)?;
+                hw.sleep(if op == 0x21 { time } else { time * 1000 });
+                Value::Integer(0)
+            }
+            // Acquire, with a timeout: always at once
+            0x23 => {
+                self.target(hw, frame, c)?;
+                c.uint(2)?;
+                Value::Integer(0)
+            }
+            // Release
+            0x27 => {
+                self.target(hw, frame, c)?;
+                Value::Integer(0)
+            }
+            // Revision of the interpreter
+            0x30 => Value::Integer(1),
+            op => return Err(AmlError::Unsupported(0x5B00 | op as u16)),
+        };
+        Ok(value)
+    }
+
+    /// A name in an expression: a method call, with as many arguments as
+    /// the method takes, or what the object holds
+    fn eval_name(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<Value> {
+        let path = self.name_path(frame, c)?;
+        let path = self.follow(&path);
+        match self.objects.get(&path) {
+            Some(&Object::Method { args, .. }) => {
+                let mut values = Vec::with_capacity(args);
+                for _ in 0..args {
+                    values.push(self.eval(hw, frame, c)?);
+                }
+                self.call(hw, &path, values)
+            }
+            _ => self.read_named(hw, &path),
+        }
+    }
+
+    /// The elements of a package, up to `end`; names in it stay names,
+    /// as strings of their paths
+    fn package(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor, end: usize, count: usize) -> Result<Value> {
+        let mut elements = Vec::new();
+        while c.pos < end {
+            let element = if is_name_start(c.peek()?) {
+                let name = c.name_string()?;
+                Value::String(self.resolve(&frame.scope, &name).unwrap_or_else(|| name.absolute(&frame.scope)))
+            } else {
+                self.eval(hw, frame, c)?
+            };
+            elements.push(element);
+        }
+        if elements.len() < count {
+            elements.resize(count, Value::Integer(0));
+        }
+        c.pos = end;
+        Ok(Value::Package(elements))
+    }
+
+    /// A SuperName or Target; None for the null name, which stores
+    /// nowhere
+    fn target(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<Option<Place>> {
+        let op = c.peek()?;
+        let place = match op {
+            0x00 => {
+                c.pos += 1;
+                return Ok(None);
+            }
+            0x60..=0x67 => {
+                c.pos += 1;
+                Place::Local((op - 0x60) as usize)
+            }
+            0x68..=0x6E => {
+                c.pos += 1;
+                Place::Arg((op - 0x68) as usize)
+            }
+            0x5B if c.code.get(c.pos + 1) == Some(&0x31) => {
+                c.pos += 2;
+                Place::Debug
+            }
+            // Index, RefOf, or DerefOf of a reference
+            0x83 => {
+                c.pos += 1;
+                match self.eval(hw, frame, c)? {
+                    Value::Reference(place) => *place,
+                    _ => return Err(AmlError::Type),
+                }
+            }
+            0x88 | 0x71 => match self.eval(hw, frame, c)? {
+                Value::Reference(place) => *place,
+                _ => return Err(AmlError::Type),
+            },
+            op if is_name_start(op) => Place::Named(self.name_path(frame, c)?),
+            op => return Err(AmlError::Unsupported(op as u16)),
+        };
+        Ok(Some(place))
+    }
+
+    /// What `Index` indexes: somewhere stores can go through to when it
+    /// is a local, an argument or a named value
+    fn source(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor) -> Result<Place> {
+        let op = c.peek()?;
+        if (0x60..=0x6E).contains(&op) {
+            return Ok(self.target(hw, frame, c)?.unwrap_or(Place::Debug));
+        }
+        if is_name_start(op) {
+            let start = c.pos;
+            let path = self.follow(&self.name_path(frame, c)?);
+            if let Some(Object::Name(_)) = self.objects.get(&path) {
+                return Ok(Place::Named(path));
+            }
+            c.pos = start;
+        }
+        Ok(Place::Temporary(self.eval(hw, frame, c)?))
+    }
+
+    /// Store an operator's result in its target, and give it back
+    fn result(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, c: &mut Cursor, value: Value) -> Result<Value> {
+        let target = self.target(hw, frame, c)?;
+        self.store(hw, frame, target, value.clone())?;
+        Ok(value)
+    }
+
+    fn boolean(&self, b: bool) -> Value {
+        Value::Integer(if b { self.ones() } else { 0 })
+    }
+
+    /// The bytes of a value, for buffers made of it
+    fn bytes(&self, value: &Value) -> Result<Vec<u8>> {
+        match value {
+            Value::Integer(n) => Ok(n.to_le_bytes()[..if self.wide { 8 } else { 4 }].to_vec()),
+            Value::String(s) => Ok(s.as_bytes().to_vec()),
+            Value::Buffer(bytes) => Ok(bytes.clone()),
+            _ => Err(AmlError::Type),
+        }
+    }
+
+    /// Compare as the first operand's type says
+    fn compare(&self, a: &Value, b: &Value) -> Result<Ordering> {
+        match a {
+            Value::Integer(a) => Ok(a.cmp(&(b.as_integer().ok_or(AmlError::Type)? & self.ones()))),
+            Value::String(_) | Value::Buffer(_) => Ok(self.bytes(a)?.cmp(&self.bytes(b)?)),
+            _ => Err(AmlError::Type),
+        }
+    }
+
+    fn read_named(&mut self, hw: &mut dyn Hardware, path: &str) -> Result<Value> {
+        let path = self.follow(path);
+        match self.objects.get(&path) {
+            Some(Object::Name(value)) => Ok(value.clone()),
+            Some(Object::Field(field)) => {
+                let field = field.clone();
+                self.read_field(hw, &field)
+            }
+            Some(Object::Method { .. }) => self.call(hw, &path, Vec::new()),
+            // Devices, regions and the like are only ever referred to
+            Some(_) => Ok(Value::Reference(Box::new(Place::Named(path)))),
+            None => Err(AmlError::NotFound(path)),
+        }
+    }
+
+    fn read_place(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, place: &Place) -> Result<Value> {
+        match place {
+            Place::Local(i) => Ok(frame.locals[*i].clone()),
+            Place::Arg(i) => Ok(frame.args.get(*i).cloned().unwrap_or(Value::Integer(0))),
+            Place::Named(path) => self.read_named(hw, path),
+            Place::Index(base, index) => {
+                let mut container = self.read_place(hw, frame, base)?;
+                while let Value::Reference(place) = container {
+                    container = self.read_place(hw, frame, &place)?;
+                }
+                match container {
+                    Value::Package(mut elements) if *index < elements.len() => Ok(elements.swap_remove(*index)),
+                    Value::Buffer(bytes) => bytes.get(*index).map(|&b| Value::Integer(b as u64)).ok_or(AmlError::Type),
+                    Value::String(s) => s.as_bytes().get(*index).map(|&b| Value::Integer(b as u64)).ok_or(AmlError::Type),
+                    _ => Err(AmlError::Type),
+                }
+            }
+            Place::Temporary(value) => Ok(value.clone()),
+            Place::Debug => Ok(Value::Integer(0)),
+        }
+    }
+
+    fn store(&mut self, hw: &mut dyn Hardware, frame: &mut Frame, place: Option<Place>, value: Value) -> Result<()> {
+        let place = match place {
+            Some(place) => place,
+            None => return Ok(()),
+        };
+        match place {
+            Place::Local(i) => frame.locals[i] = value,
+            Place::Arg(i) => match frame.args.get(i) {
+                // An argument passed by reference is stored through
+                Some(Value::Reference(target)) => {
+                    let target = (**target).clone();
+                    return self.store(hw, frame, Some(target), value);
+                }
+                _ => {
+                    if frame.args.len() <= i {
+                        frame.args.resize(i + 1, Value::Integer(0));
+                    }
+                    frame.args[i] = value;
+                }
+            },
+            Place::Named(path) => self.write_named(hw, &path, value)?,
+            Place::Index(base, index) => {
+                let mut container = self.read_place(hw, frame, &base)?;
+                if let Value::Reference(target) = container {
+                    return self.store(hw, frame, Some(Place::Index(target, index)), value);
+                }
+                match &mut container {
+                    Value::Package(elements) if index < elements.len() => elements[index] = value,
+                    Value::Buffer(bytes) if index < bytes.len() => {
+                        bytes[index] = value.as_integer().ok_or(AmlError::Type)? as u8;
+                    }
+                    _ => return Err(AmlError::Type),
+                }
+                return self.store(hw, frame, Some(*base), container);
+            }
+            Place::Temporary(_) | Place::Debug => {}
+        }
+        Ok(())
+    }
+
+    /// Store to a named object: a field is written, and a name keeps its
+    /// type if it is an integer or a buffer
+    fn write_named(&mut self, hw: &mut dyn Hardware, path: &str, value: Value) -> Result<()> {
+        let path = self.follow(path);
+        let wide = self.wide;
+        match self.objects.get_mut(&path) {
+            Some(Object::Name(old)) => {
+                *old = match (&*old, value) {
+                    (Value::Integer(_), value @ (Value::Buffer(_) | Value::String(_))) => {
+                        Value::Integer(value.as_integer().ok_or(AmlError::Type)?)
+                    }
+                    (Value::Buffer(old), Value::Integer(n)) => {
+                        let mut bytes = n.to_le_bytes()[..if wide { 8 } else { 4 }].to_vec();
+                        bytes.resize(old.len(), 0);
+                        Value::Buffer(bytes)
+                    }
+                    (_, value) => value,
+                };
+                Ok(())
+            }
+            Some(Object::Field(field)) => {
+                let field = field.clone();
+                self.write_field(hw, &field, &value)
+            }
+            Some(_) => Err(AmlError::Type),
+            None => Err(AmlError::NotFound(path)),
+        }
+    }
+
+    fn read_field(&mut self, hw: &mut dyn Hardware, field: &Field) -> Result<Value> {
+        let unit = field.access as u64 * 8;
+        let mut bytes = vec![0u8; field.bits.div_ceil(8) as usize];
+        let mut word: Option<(u64, u64)> = None;
+        for bit in 0..field.bits {
+            let at = field.bit_offset + bit;
+            let index = at / unit;
+            let value = match word {
+                Some((i, value)) if i == index => value,
+                _ => {
+                    let value = self.access(hw, field, index * field.access as u64, None)?;
+                    word = Some((index, value));
+                    value
+                }
+            };
+            if value >> (at % unit) & 1 != 0 {
+                bytes[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        if field.bits <= 64 {
+            Ok(Value::Integer(bytes.iter().rev().fold(0, |n, &b| n << 8 | b as u64)))
+        } else {
+            Ok(Value::Buffer(bytes))
+        }
+    }
+
+    /// Write a field, keeping the bits it shares an access with
+    fn write_field(&mut self, hw: &mut dyn Hardware, field: &Field, value: &Value) -> Result<()> {
+        let bytes = match value {
+            Value::Integer(n) => n.to_le_bytes().to_vec(),
+            value => self.bytes(value)?,
+        };
+        let unit = field.access as u64 * 8;
+        let (start, end) = (field.bit_offset, field.bit_offset + field.bits);
+        if end == start {
+            return Ok(());
+        }
+        for index in start / unit..=(end - 1) / unit {
+            let unit_start = index * unit;
+            let whole = start <= unit_start && end >= unit_start + unit;
+            let mut word = if whole { 0 } else { self.access(hw, field, index * field.access as u64, None)? };
+            for b in 0..unit {
+                let at = unit_start + b;
+                if at < start || at >= end {
+                    continue;
+                }
+                let bit = at - start;
+                if bytes.get((bit / 8) as usize).map_or(false, |byte| byte >> (bit % 8) & 1 != 0) {
+                    word |= 1 << b;
+                } else {
+                    word &= !(1 << b);
+                }
+            }
+            self.access(hw, field, index * field.access as u64, Some(word))?;
+        }
+        Ok(())
+    }
+
+    /// Read, or write if there is a value, one access of a field at
+    /// `offset` bytes into it
+    fn access(&mut self, hw: &mut dyn Hardware, field: &Field, offset: u64, write: Option<u64>) -> Result<u64> {
+        match &field.kind {
+            FieldKind::Region(region) => self.region_access(hw, region, offset, field.access, write),
+            FieldKind::Bank { region, bank, value } => {
+                self.write_named(hw, bank, Value::Integer(*value))?;
+                self.region_access(hw, region, offset, field.access, write)
+            }
+            FieldKind::Index { index, data } => {
+                self.write_named(hw, index, Value::Integer(offset))?;
+                match write {
+                    Some(value) => self.write_named(hw, data, Value::Integer(value)).map(|_| 0),
+                    None => self.read_named(hw, data)?.as_integer().ok_or(AmlError::Type),
+                }
+            }
+        }
+    }
+
+    fn region_access(&mut self, hw: &mut dyn Hardware, path: &str, offset: u64, width: u8, write: Option<u64>) -> Result<u64> {
+        let region = match self.objects.get(path) {
+            Some(Object::Region(region)) => *region,
+            _ => return Err(AmlError::NotFound(String::from(path))),
+        };
+        let mut address = region.offset + offset;
+        if region.space == SPACE_PCI {
+            // The region is in the configuration space of the device it is
+            // defined in, which `_ADR` gives as device and function
+            let adr = self.evaluate(hw, &join(parent(path), "_ADR"), Vec::new())
+                .ok().and_then(|adr| adr.as_integer()).unwrap_or(0);
+            address |= (adr >> 16 & 0xFFFF) << 32 | (adr & 0xFFFF) << 16;
+        }
+        match write {
+            Some(value) => hw.write(region.space, address, width, value).map(|()| 0).ok_or(AmlError::Hardware),
+            None => hw.read(region.space, address, width).ok_or(AmlError::Hardware),
+        }
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use core::result::Result;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// Registers as bytes, by address space and address
+    struct Registers(BTreeMap<(u8, u64), u8>);
+
+    impl Hardware for Registers {
+        fn read(&mut self, space: u8, address: u64, width: u8) -> Option<u64> {
+            Some((0..width as u64).rev().fold(0, |n, i| n << 8 | *self.0.get(&(space, address + i)).unwrap_or(&0) as u64))
+        }
+
+        fn write(&mut self, space: u8, address: u64, width: u8, value: u64) -> Option<()> {
+            for i in 0..width as u64 {
+                self.0.insert((space, address + i), (value >> (8 * i)) as u8);
+            }
+            Some(())
+        }
+
+        fn sleep(&mut self, _us: u64) {}
+    }
+
+    /// `op`, a PkgLength and `body`
+    fn pkg(op: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut out = op.to_vec();
+        if body.len() + 1 < 64 {
+            out.push(body.len() as u8 + 1);
+        } else {
+            let len = body.len() + 2;
+            out.extend([0x40 | (len & 0x0F) as u8, (len >> 4) as u8]);
+        }
+        out.extend(body);
+        out
+    }
+
+    fn load(aml: &[u8], hw: &mut Registers) -> Result<Namespace, String> {
+        let mut namespace = Namespace::new(true);
+        namespace.load(aml, hw).map_err(|e| format!("{:?}", e))?;
+        Ok(namespace)
+    }
+
+    #[kernel_test]
+    fn runs_control_flow() -> Result<(), String> {
+        // Method (MAX_, 2) { If (LGreater (Arg0, Arg1)) { Return (Arg0) } Else { Return (Arg1) } }
+        let mut body = b"MAX_\x02".to_vec();
+        body.extend(pkg(&[0xA0], &[0x94, 0x68, 0x69, 0xA4, 0x68]));
+        body.extend(pkg(&[0xA1], &[0xA4, 0x69]));
+        let mut aml = pkg(&[0x14], &body);
+        // Method (SUM_, 1) { Store (Zero, Local0) Store (Zero, Local1)
+        //   While (LLess (Local1, Arg0)) { Add (Local0, Local1, Local0) Increment (Local1) }
+        //   Return (Local0) }
+        let mut body = b"SUM_\x01".to_vec();
+        body.extend([0x70, 0x00, 0x60, 0x70, 0x00, 0x61]);
+        body.extend(pkg(&[0xA2], &[0x95, 0x61, 0x68, 0x72, 0x60, 0x61, 0x60, 0x75, 0x61]));
+        body.extend([0xA4, 0x60]);
+        aml.extend(pkg(&[0x14], &body));
+
+        let mut hw = Registers(BTreeMap::new());
+        let mut namespace = load(&aml, &mut hw)?;
+        let max = |namespace: &mut Namespace, hw: &mut Registers, a, b| {
+            namespace.evaluate(hw, "\\MAX_", vec![Value::Integer(a), Value::Integer(b)])
+        };
+        check_eq!(max(&mut namespace, &mut hw, 3, 9), Ok(Value::Integer(9)));
+        check_eq!(max(&mut namespace, &mut hw, 7, 2), Ok(Value::Integer(7)));
+        check_eq!(namespace.evaluate(&mut hw, "\\SUM_", vec![Value::Integer(5)]), Ok(Value::Integer(10)));
+        check_eq!(namespace.evaluate(&mut hw, "\\NONE", Vec::new()), Err(AmlError::NotFound(String::from("\\NONE"))));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn reads_a_battery_from_ec_fields() -> Result<(), String> {
+        // Scope (\_SB) { Device (BAT0) {
+        //   Name (_HID, EisaId ("PNP0C0A"))
+        //   OperationRegion (ECR_, EmbeddedControl, Zero, 0x10)
+        //   Field (ECR_, ByteAcc, NoLock, Preserve) { STAT, 8, RATE, 16, REMN, 16 }
+        //   Name (PBST, Package (4) { Zero, Zero, Zero, 0x2A30 })
+        //   Method (_BST) { Store (STAT, Index (PBST, Zero)) Store (RATE, Index (PBST, One))
+        //     Store (REMN, Index (PBST, 2)) Return (PBST) } } }
+        let mut device = b"BAT0".to_vec();
+        device.extend(b"\x08_HID\x0C\x41\xD0\x0C\x0A");
+        device.extend(b"\x5B\x80ECR_\x03\x00\x0A\x10");
+        device.extend(pkg(b"\x5B\x81", b"ECR_\x01STAT\x08RATE\x10REMN\x10"));
+        device.extend(b"\x08PBST");
+        device.extend(pkg(&[0x12], &[0x04, 0x00, 0x00, 0x00, 0x0B, 0x30, 0x2A]));
+        let mut method = b"_BST\x00".to_vec();
+        method.extend(b"\x70STAT\x88PBST\x00\x00");
+        method.extend(b"\x70RATE\x88PBST\x01\x00");
+        method.extend(b"\x70REMN\x88PBST\x0A\x02\x00");
+        method.extend(b"\xA4PBST");
+        device.extend(pkg(&[0x14], &method));
+        let mut scope = b"\\_SB_".to_vec();
+        scope.extend(pkg(b"\x5B\x82", &device));
+        let aml = pkg(&[0x10], &scope);
+
+        let mut hw = Registers(BTreeMap::new());
+        for (address, value) in [(0, 0x01), (1, 0x23), (2, 0x01), (3, 0x00), (4, 0x10)] {
+            hw.0.insert((SPACE_EC, address), value);
+        }
+        let mut namespace = load(&aml, &mut hw)?;
+        check_eq!(namespace.find_devices(&mut hw, "PNP0C0A"), vec![String::from("\\_SB_.BAT0")]);
+        check_eq!(namespace.find_devices(&mut hw, "ACPI0003"), Vec::<String>::new());
+        let bst = namespace.evaluate(&mut hw, "\\_SB_.BAT0._BST", Vec::new()).map_err(|e| format!("{:?}", e))?;
+        let expected = [1, 0x123, 0x1000, 0x2A30].map(Value::Integer);
+        check_eq!(bst.as_package(), Some(&expected[..]));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn writes_keep_neighbouring_bits() -> Result<(), String> {
+        // OperationRegion (IOR_, SystemIO, 0x100, 2)
+        // Field (IOR_, ByteAcc, NoLock, Preserve) { , 4, FLAG, 2 }
+        // Method (SETF, 1) { Store (Arg0, FLAG) }
+        let mut aml = b"\x5B\x80IOR_\x01\x0B\x00\x01\x0A\x02".to_vec();
+        aml.extend(pkg(b"\x5B\x81", b"IOR_\x01\x00\x04FLAG\x02"));
+        aml.extend(pkg(&[0x14], b"SETF\x01\x70\x68FLAG"));
+
+        let mut hw = Registers(BTreeMap::new());
+        hw.0.insert((SPACE_IO, 0x100), 0x81);
+        let mut namespace = load(&aml, &mut hw)?;
+        namespace.evaluate(&mut hw, "\\SETF", vec![Value::Integer(3)]).map_err(|e| format!("{:?}", e))?;
+        check_eq!(hw.0.get(&(SPACE_IO, 0x100)), Some(&0xB1));
+        check_eq!(namespace.evaluate(&mut hw, "\\FLAG", Vec::new()), Ok(Value::Integer(3)));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn decodes_eisa_ids() -> Result<(), String> {
+        check_eq!(eisa_id(0x0A0CD041), "PNP0C0A");
+        check_eq!(eisa_id(0x090CD041), "PNP0C09");
+        check!(is_name_start(b'_') && !is_name_start(b'a'));
+        Ok(())
+    }
+}
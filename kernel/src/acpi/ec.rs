@@ -0,0 +1,125 @@
+//! Embedded controller
+//!
+//! Laptops keep the battery's registers, and much else, in the embedded
+//! controller, which the DSDT reaches through `EmbeddedControl` operation
+//! regions. It has a command/status port and a data port, found in the
+//! ECDT or in the resources of the `PNP0C09` device; a byte is read with
+//! `RD_EC` and its address, and written with `WR_EC`, its address and the
+//! value, each handed over when the controller is ready for it.
+//!
+//! Queries, the events the controller raises through a GPE, are not
+//! handled: whoever wants fresh values polls.
+
+use crate::drivers::input::{inb, outb};
+
+/// EC_SC status bits: output buffer full, input buffer full
+const OBF: u8 = 1 << 0;
+const IBF: u8 = 1 << 1;
+
+/// EC_SC commands
+const RD_EC: u8 = 0x80;
+const WR_EC: u8 = 0x81;
+
+/// Status reads before the controller is given up on, each taking about
+/// a microsecond
+const TIMEOUT: usize = 100_000;
+
+/// Port I/O resource descriptor in a `_CRS` buffer
+const IO_DESCRIPTOR: u8 = 0x47;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedController {
+    data: u16,
+    command: u16,
+}
+
+impl EmbeddedController {
+    /// The ports from an ECDT: the command/status register, then the data
+    /// register, in generic address format
+    pub fn from_ecdt(table: &[u8]) -> Option<Self> {
+        let port = |offset: usize| -> Option<u16> {
+            let gas = table.get(offset..offset + 12)?;
+            if gas[0] != super::SPACE_IO {
+                return None;
+            }
+            Some(u16::from_le_bytes([gas[4], gas[5]]))
+        };
+        let command = port(36)?;
+        let data = port(48)?;
+        (command != 0 && data != 0).then_some(EmbeddedController { data, command })
+    }
+
+    /// The ports from the device's `_CRS`: the first I/O range is the
+    /// data register, the second the command/status register
+    pub fn from_resources(crs: &[u8]) -> Option<Self> {
+        let mut ports = [0u16; 2];
+        let mut found = 0;
+        let mut offset = 0;
+        while offset < crs.len() && found < 2 {
+            let tag = crs[offset];
+            // Large items have a 16-bit length after the tag; small ones
+            // have it in the tag
+            let (len, header) = if tag & 0x80 != 0 {
+                (u16::from_le_bytes([*crs.get(offset + 1)?, *crs.get(offset + 2)?]) as usize, 3)
+            } else {
+                ((tag & 0x07) as usize, 1)
+            };
+            if tag & 0x80 == 0 && tag & 0xF8 == IO_DESCRIPTOR & 0xF8 {
+                ports[found] = u16::from_le_bytes([*crs.get(offset + 2)?, *crs.get(offset + 3)?]);
+                found += 1;
+            }
+            offset += header + len;
+        }
+        (found == 2).then_some(EmbeddedController { data: ports[0], command: ports[1] })
+    }
+
+    fn wait(&self, ready: impl Fn(u8) -> bool) -> Option<()> {
+        for _ in 0..TIMEOUT {
+            if ready(unsafe { inb(self.command) }) {
+                return Some(());
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    pub fn read(&self, address: u8) -> Option<u8> {
+        self.wait(|status| status & IBF == 0)?;
+        unsafe { outb(self.command, RD_EC) };
+        self.wait(|status| status & IBF == 0)?;
+        unsafe { outb(self.data, address) };
+        self.wait(|status| status & OBF != 0)?;
+        Some(unsafe { inb(self.data) })
+    }
+
+    pub fn write(&self, address: u8, value: u8) -> Option<()> {
+        self.wait(|status| status & IBF == 0)?;
+        unsafe { outb(self.command, WR_EC) };
+        self.wait(|status| status & IBF == 0)?;
+        unsafe { outb(self.data, address) };
+        self.wait(|status| status & IBF == 0)?;
+        unsafe { outb(self.data, value) };
+        self.wait(|status| status & IBF == 0)
+    }
+
+    pub fn ports(&self) -> (u16, u16) {
+        (self.data, self.command)
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn finds_ports_in_resources() -> Result<(), String> {
+        // IO (Decode16, 0x62, 0x62, 0, 1) IO (Decode16, 0x66, 0x66, 0, 1) EndTag
+        let crs = [0x47, 0x01, 0x62, 0x00, 0x62, 0x00, 0x00, 0x01,
+            0x47, 0x01, 0x66, 0x00, 0x66, 0x00, 0x00, 0x01, 0x79, 0x00];
+        check_eq!(EmbeddedController::from_resources(&crs).map(|ec| ec.ports()), Some((0x62, 0x66)));
+        check!(EmbeddedController::from_resources(&crs[..8]).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,674 @@
+//! ACPI tables
+//!
+//! The bootloader passes the RSDP it got from the UEFI configuration
+//! table. From there `init` walks the XSDT, or the RSDT on ACPI 1.0
+//! machines, and copies out the tables the kernel uses:
+//!
+//! - MADT: the processors' local APICs, the I/O APICs and the ISA
+//!   interrupt overrides
+//! - FADT: the PM1 control registers and the `\_S5` sleep type from the
+//!   DSDT for powering off, and the reset register
+//! - MCFG: where PCIe configuration space is memory-mapped (ECAM)
+//! - DSDT and SSDTs: the AML that `load_namespace` runs, once the timer is
+//!   up, so that `evaluate` can ask the firmware about devices such as
+//!   batteries; the ECDT, if there is one, says where the embedded
+//!   controller those often live in is
+//!
+//! Without ACPI, `shutdown` and `reset` report that they cannot help and
+//! callers fall back to what works on a PC without it.
+
+pub mod aml;
+mod ec;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use webbos_shared::types::PhysAddr;
+
+use crate::drivers::input::{inb, inl, inw, outb, outl, outw};
+use crate::drivers::{pci, timer};
+use crate::mm::{self, read_physical};
+use crate::println;
+use crate::{debug, info, warn};
+
+/// A local APIC, one per processor
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    /// Usable now, or able to be brought online
+    pub enabled: bool,
+}
+
+/// An I/O APIC and the first global system interrupt it handles
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt wired to another global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// PCIe configuration space of a range of buses
+#[derive(Debug, Clone, Copy)]
+pub struct Ecam {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// A register in the FADT's generic address format
+#[derive(Debug, Clone, Copy)]
+struct GenericAddress {
+    space: u8,
+    address: u64,
+}
+
+/// What the FADT and DSDT say about power
+#[derive(Debug, Clone, Copy)]
+struct Power {
+    smi_command: u32,
+    acpi_enable: u8,
+    pm1a_control: u32,
+    pm1b_control: u32,
+    /// SLP_TYP values for soft-off in PM1a and PM1b, from `\_S5`
+    s5: Option<(u8, u8)>,
+    reset: Option<(GenericAddress, u8)>,
+}
+
+struct Acpi {
+    revision: u8,
+    tables: Vec<String>,
+    local_apic: u64,
+    processors: Vec<Processor>,
+    io_apics: Vec<IoApic>,
+    overrides: Vec<InterruptOverride>,
+    ecam: Vec<Ecam>,
+    power: Option<Power>,
+    /// Physical address of the HPET registers; 0 if there is none
+    hpet: u64,
+    /// CMOS register of the RTC's century; 0 if there is none
+    century: u8,
+    /// The DSDT and SSDTs, until `load_namespace` takes them
+    aml: Vec<Vec<u8>>,
+    ecdt: Option<Vec<u8>>,
+}
+
+static ACPI: Mutex<Option<Acpi>> = Mutex::new(None);
+
+/// The AML namespace, and the registers its methods reach
+struct Interpreter {
+    namespace: aml::Namespace,
+    platform: Platform,
+}
+
+static AML: Mutex<Option<Interpreter>> = Mutex::new(None);
+
+/// Hardware ID of embedded controllers
+const EC_HID: &str = "PNP0C09";
+
+/// Generic address spaces
+const SPACE_MEMORY: u8 = 0;
+const SPACE_IO: u8 = 1;
+const SPACE_PCI: u8 = 2;
+
+/// FADT flag: the reset register is there
+const RESET_REG_SUP: u32 = 1 << 10;
+
+/// PM1 control bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_EN: u16 = 1 << 13;
+
+/// Length of the header every table but the RSDP starts with
+const HEADER_LEN: usize = 36;
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Whether the bytes add up to zero, as every ACPI structure's must
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// A whole table, given where it starts
+fn read_table(addr: u64) -> Option<Vec<u8>> {
+    let header = read_physical(addr, HEADER_LEN)?;
+    let len = u32_at(&header, 4)? as usize;
+    if len < HEADER_LEN {
+        return None;
+    }
+    let table = read_physical(addr, len)?;
+    if !checksum_ok(&table) {
+        warn!("acpi", "Bad checksum on {}", String::from_utf8_lossy(&table[..4]));
+        return None;
+    }
+    Some(table)
+}
+
+/// Read the tables the RSDP at `rsdp` leads to
+pub fn init(rsdp: Option<PhysAddr>) {
+    let rsdp = match rsdp {
+        Some(rsdp) => rsdp.as_u64(),
+        None => {
+            warn!("acpi", "No RSDP from the bootloader");
+            return;
+        }
+    };
+    match parse(rsdp) {
+        Some(acpi) => {
+            info!("acpi", "ACPI {}: {}; {} processors, {} I/O APICs, {} ECAM regions",
+                if acpi.revision >= 2 { "2.0+" } else { "1.0" }, acpi.tables.join(" "),
+                acpi.processors.len(), acpi.io_apics.len(), acpi.ecam.len());
+            *ACPI.lock() = Some(acpi);
+        }
+        None => warn!("acpi", "No valid RSDP at {:#x}", rsdp),
+    }
+}
+
+fn parse(rsdp_addr: u64) -> Option<Acpi> {
+    let rsdp = read_physical(rsdp_addr, 20)?;
+    if &rsdp[..8] != b"RSD PTR " || !checksum_ok(&rsdp) {
+        return None;
+    }
+    let revision = rsdp[15];
+    // ACPI 2.0 adds the XSDT, with 64-bit pointers
+    let (root, entry_size) = match revision {
+        0 | 1 => (u32_at(&rsdp, 16)? as u64, 4),
+        _ => {
+            let rsdp = read_physical(rsdp_addr, 36)?;
+            match u64_at(&rsdp, 24)? {
+                0 => (u32_at(&rsdp, 16)? as u64, 4),
+                xsdt => (xsdt, 8),
+            }
+        }
+    };
+    let root = read_table(root)?;
+
+    let mut acpi = Acpi {
+        revision,
+        tables: Vec::new(),
+        local_apic: 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        ecam: Vec::new(),
+        power: None,
+        hpet: 0,
+        century: 0,
+        aml: Vec::new(),
+        ecdt: None,
+    };
+    for offset in (HEADER_LEN..root.len()).step_by(entry_size) {
+        let addr = match entry_size {
+            4 => u32_at(&root, offset).map(u64::from),
+            _ => u64_at(&root, offset),
+        };
+        let table = match addr.and_then(read_table) {
+            Some(table) => table,
+            None => continue,
+        };
+        acpi.tables.push(String::from_utf8_lossy(&table[..4]).into_owned());
+        let signature = [table[0], table[1], table[2], table[3]];
+        match &signature {
+            b"APIC" => parse_madt(&mut acpi, &table),
+            b"FACP" => {
+                let dsdt = dsdt_address(&table).and_then(read_table);
+                acpi.power = parse_fadt(&table, dsdt.as_deref());
+                acpi.century = table.get(108).copied().unwrap_or(0);
+                if let Some(dsdt) = dsdt {
+                    acpi.tables.push(String::from("DSDT"));
+                    acpi.aml.insert(0, dsdt);
+                }
+            }
+            b"MCFG" => parse_mcfg(&mut acpi, &table),
+            // The register block, in generic address format at 40
+            b"HPET" if table.get(40) == Some(&SPACE_MEMORY) => acpi.hpet = u64_at(&table, 44).unwrap_or(0),
+            b"SSDT" => acpi.aml.push(table),
+            b"ECDT" => acpi.ecdt = Some(table),
+            _ => {}
+        }
+    }
+    Some(acpi)
+}
+
+fn parse_madt(acpi: &mut Acpi, table: &[u8]) {
+    acpi.local_apic = u32_at(table, 36).unwrap_or(0) as u64;
+    let mut offset = 44;
+    while offset + 2 <= table.len() {
+        let (kind, len) = (table[offset], table[offset + 1] as usize);
+        if len < 2 || offset + len > table.len() {
+            break;
+        }
+        let entry = &table[offset..offset + len];
+        match (kind, len) {
+            (0, 8) => acpi.processors.push(Processor {
+                acpi_id: entry[2],
+                apic_id: entry[3],
+                enabled: u32_at(entry, 4).map_or(false, |flags| flags & 0b11 != 0),
+            }),
+            (1, 12) => acpi.io_apics.push(IoApic {
+                id: entry[2],
+                address: u32_at(entry, 4).unwrap_or(0),
+                gsi_base: u32_at(entry, 8).unwrap_or(0),
+            }),
+            (2, 10) => acpi.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: u32_at(entry, 4).unwrap_or(0),
+                flags: u16_at(entry, 8).unwrap_or(0),
+            }),
+            (5, 12) => acpi.local_apic = u64_at(entry, 4).unwrap_or(acpi.local_apic),
+            _ => {}
+        }
+        offset += len;
+    }
+}
+
+/// Where the FADT says the DSDT is
+fn dsdt_address(fadt: &[u8]) -> Option<u64> {
+    // The 64-bit pointer, where there is one, wins
+    match u64_at(fadt, 140) {
+        Some(x_dsdt) if x_dsdt != 0 => Some(x_dsdt),
+        _ => u32_at(fadt, 40).map(u64::from),
+    }
+}
+
+fn parse_fadt(table: &[u8], dsdt: Option<&[u8]>) -> Option<Power> {
+    let flags = u32_at(table, 112).unwrap_or(0);
+    let reset = match (flags & RESET_REG_SUP != 0, table.get(116), u64_at(table, 120), table.get(128)) {
+        (true, Some(&space), Some(address), Some(&value)) => Some((GenericAddress { space, address }, value)),
+        _ => None,
+    };
+    Some(Power {
+        smi_command: u32_at(table, 48)?,
+        acpi_enable: *table.get(52)?,
+        pm1a_control: u32_at(table, 64)?,
+        pm1b_control: u32_at(table, 68)?,
+        s5: dsdt.and_then(sleep_type_s5),
+        reset,
+    })
+}
+
+/// SLP_TYPa and SLP_TYPb from the `\_S5` package in the DSDT's AML
+///
+/// The package is `NameOp "_S5_" PackageOp PkgLength NumElements` followed
+/// by the values, each a `BytePrefix` and a byte or a bare Zero or One.
+fn sleep_type_s5(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let at = dsdt.windows(4).position(|w| w == b"_S5_")?;
+    let named = (at >= 1 && dsdt[at - 1] == 0x08) || (at >= 2 && dsdt[at - 2] == 0x08 && dsdt[at - 1] == b'\\');
+    if !named || *dsdt.get(at + 4)? != 0x12 {
+        return None;
+    }
+    // Past PackageOp, then PkgLength, whose top bits count its extra bytes,
+    // then NumElements
+    let mut pos = at + 5;
+    pos += ((*dsdt.get(pos)? & 0xC0) >> 6) as usize + 2;
+    let mut value = || {
+        if *dsdt.get(pos)? == 0x0A {
+            pos += 1;
+        }
+        let v = *dsdt.get(pos)?;
+        pos += 1;
+        Some(v)
+    };
+    let a = value()?;
+    let b = value()?;
+    Some((a, b))
+}
+
+fn parse_mcfg(acpi: &mut Acpi, table: &[u8]) {
+    for offset in (44..table.len()).step_by(16) {
+        let entry = match table.get(offset..offset + 16) {
+            Some(entry) => entry,
+            None => break,
+        };
+        acpi.ecam.push(Ecam {
+            base: u64_at(entry, 0).unwrap_or(0),
+            segment: u16_at(entry, 8).unwrap_or(0),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        });
+    }
+}
+
+/// Registers as the firmware's methods reach them
+struct Platform {
+    ec: Option<ec::EmbeddedController>,
+    /// Memory outside the physmap mapped so far, by physical page
+    mapped: BTreeMap<u64, u64>,
+}
+
+impl Platform {
+    /// Where physical address `addr` can be reached
+    fn map(&mut self, addr: u64) -> Option<u64> {
+        if addr + 8 <= mm::physmap_size() {
+            return Some(mm::phys_to_virt(PhysAddr::new(addr)).as_u64());
+        }
+        let page = addr & !0xFFF;
+        let virt = match self.mapped.get(&page) {
+            Some(&virt) => virt,
+            None => {
+                let virt = mm::map_mmio(PhysAddr::new(page), 0x1000)?.as_u64();
+                self.mapped.insert(page, virt);
+                virt
+            }
+        };
+        Some(virt + (addr & 0xFFF))
+    }
+}
+
+/// Device, function and offset of a PCI generic address on bus 0
+fn pci_address(address: u64) -> (u8, u8, u8) {
+    ((address >> 32) as u8, (address >> 16) as u8, address as u8)
+}
+
+impl aml::Hardware for Platform {
+    fn read(&mut self, space: u8, address: u64, width: u8) -> Option<u64> {
+        match space {
+            aml::SPACE_MEMORY => {
+                let virt = self.map(address)?;
+                unsafe {
+                    Some(match width {
+                        1 => core::ptr::read_volatile(virt as *const u8) as u64,
+                        2 => core::ptr::read_volatile(virt as *const u16) as u64,
+                        4 => core::ptr::read_volatile(virt as *const u32) as u64,
+                        _ => core::ptr::read_volatile(virt as *const u64),
+                    })
+                }
+            }
+            aml::SPACE_IO => unsafe {
+                match width {
+                    1 => Some(inb(address as u16) as u64),
+                    2 => Some(inw(address as u16) as u64),
+                    4 => Some(inl(address as u16) as u64),
+                    _ => None,
+                }
+            },
+            aml::SPACE_PCI if width <= 4 => {
+                let (device, function, offset) = pci_address(address);
+                let dword = pci::read_config32(0, device, function, offset & !3) as u64;
+                Some(dword >> ((offset & 3) * 8) & (u64::MAX >> (64 - 8 * width as u32)))
+            }
+            aml::SPACE_EC => {
+                let ec = self.ec?;
+                (0..width).rev().try_fold(0u64, |n, i| Some(n << 8 | ec.read((address as u8).wrapping_add(i))? as u64))
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, space: u8, address: u64, width: u8, value: u64) -> Option<()> {
+        match space {
+            aml::SPACE_MEMORY => {
+                let virt = self.map(address)?;
+                unsafe {
+                    match width {
+                        1 => core::ptr::write_volatile(virt as *mut u8, value as u8),
+                        2 => core::ptr::write_volatile(virt as *mut u16, value as u16),
+                        4 => core::ptr::write_volatile(virt as *mut u32, value as u32),
+                        _ => core::ptr::write_volatile(virt as *mut u64, value),
+                    }
+                }
+                Some(())
+            }
+            aml::SPACE_IO => unsafe {
+                match width {
+                    1 => outb(address as u16, value as u8),
+                    2 => outw(address as u16, value as u16),
+                    4 => outl(address as u16, value as u32),
+                    _ => return None,
+                }
+                Some(())
+            },
+            aml::SPACE_PCI if width <= 4 => {
+                let (device, function, offset) = pci_address(address);
+                let shift = (offset & 3) * 8;
+                let mask = (u32::MAX >> (32 - 8 * width as u32)) << shift;
+                let old = pci::read_config32(0, device, function, offset & !3);
+                let new = (old & !mask) | (((value as u32) << shift) & mask);
+                pci::write_config32(0, device, function, offset & !3, new);
+                Some(())
+            }
+            aml::SPACE_EC => {
+                let ec = self.ec?;
+                (0..width).try_for_each(|i| ec.write((address as u8).wrapping_add(i), (value >> (8 * i)) as u8))
+            }
+            _ => None,
+        }
+    }
+
+    fn sleep(&mut self, us: u64) {
+        timer::sleep_ms(us.div_ceil(1000));
+    }
+}
+
+/// Load the DSDT and SSDTs into the AML namespace; needs the timer, as
+/// the firmware's methods may sleep
+pub fn load_namespace() {
+    let (tables, ecdt) = match ACPI.lock().as_mut() {
+        Some(acpi) => (core::mem::take(&mut acpi.aml), acpi.ecdt.take()),
+        None => return,
+    };
+    if tables.is_empty() {
+        return;
+    }
+    // Integers are 64-bit from DSDT revision 2
+    let mut namespace = aml::Namespace::new(tables[0][8] >= 2);
+    let mut platform = Platform {
+        ec: ecdt.as_deref().and_then(ec::EmbeddedController::from_ecdt),
+        mapped: BTreeMap::new(),
+    };
+    for table in &tables {
+        if let Err(e) = namespace.load(&table[HEADER_LEN..], &mut platform) {
+            warn!("acpi", "Stopped loading {}: {:?}", String::from_utf8_lossy(&table[..4]), e);
+        }
+    }
+
+    let controllers = namespace.find_devices(&mut platform, EC_HID);
+    if platform.ec.is_none() {
+        platform.ec = controllers.first()
+            .and_then(|device| namespace.evaluate(&mut platform, &aml::join(device, "_CRS"), Vec::new()).ok())
+            .and_then(|crs| match crs {
+                aml::Value::Buffer(crs) => ec::EmbeddedController::from_resources(&crs),
+                _ => None,
+            });
+    }
+    // Many DSDTs keep away from the EC's fields until `_REG` says they
+    // can be reached
+    if platform.ec.is_some() {
+        for device in &controllers {
+            let reg = aml::join(device, "_REG");
+            if namespace.exists(&reg) {
+                let connect = vec![aml::Value::Integer(aml::SPACE_EC as u64), aml::Value::Integer(1)];
+                if let Err(e) = namespace.evaluate(&mut platform, &reg, connect) {
+                    warn!("acpi", "{}: {:?}", reg, e);
+                }
+            }
+        }
+    }
+    info!("acpi", "AML namespace: {} objects, {} devices{}", namespace.len(), namespace.devices().len(),
+        match platform.ec {
+            Some(ec) => alloc::format!(", embedded controller at {:#x}/{:#x}", ec.ports().0, ec.ports().1),
+            None => String::new(),
+        });
+    *AML.lock() = Some(Interpreter { namespace, platform });
+}
+
+/// Evaluate the object at `path`, such as `\_SB_.BAT0._BST`; None if
+/// there is no such object or it fails
+pub fn evaluate(path: &str, args: Vec<aml::Value>) -> Option<aml::Value> {
+    let mut aml = AML.lock();
+    let aml = aml.as_mut()?;
+    match aml.namespace.evaluate(&mut aml.platform, path, args) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            debug!("acpi", "{}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Whether the AML namespace has an object at `path`
+pub fn exists(path: &str) -> bool {
+    AML.lock().as_ref().map_or(false, |aml| aml.namespace.exists(path))
+}
+
+/// The paths of the devices whose hardware ID is `hid`
+pub fn find_devices(hid: &str) -> Vec<String> {
+    let mut aml = AML.lock();
+    match aml.as_mut() {
+        Some(aml) => aml.namespace.find_devices(&mut aml.platform, hid),
+        None => Vec::new(),
+    }
+}
+
+/// Physical address of the ECAM window of `bus` on segment 0
+pub fn ecam_bus(bus: u8) -> Option<u64> {
+    let acpi = ACPI.lock();
+    let region = acpi.as_ref()?.ecam.iter()
+        .find(|e| e.segment == 0 && (e.start_bus..=e.end_bus).contains(&bus))?;
+    Some(region.base + (((bus - region.start_bus) as u64) << 20))
+}
+
+/// Physical address of the local APIC registers
+pub fn local_apic() -> Option<u64> {
+    ACPI.lock().as_ref().map(|a| a.local_apic).filter(|&addr| addr != 0)
+}
+
+/// Physical address of the HPET registers
+pub fn hpet() -> Option<u64> {
+    ACPI.lock().as_ref().map(|a| a.hpet).filter(|&addr| addr != 0)
+}
+
+/// CMOS register holding the RTC's century, from the FADT
+pub fn century_register() -> Option<u8> {
+    ACPI.lock().as_ref().map(|a| a.century).filter(|&reg| reg != 0)
+}
+
+/// I/O APICs the MADT lists
+pub fn io_apics() -> Vec<IoApic> {
+    ACPI.lock().as_ref().map(|a| a.io_apics.clone()).unwrap_or_default()
+}
+
+/// The MADT's override for ISA interrupt `irq`, if it has one
+pub fn interrupt_override(irq: u8) -> Option<InterruptOverride> {
+    ACPI.lock().as_ref()?.overrides.iter().find(|o| o.source == irq).copied()
+}
+
+/// Power off through the PM1 control registers; returns only if ACPI
+/// cannot do it
+pub fn shutdown() {
+    let power = match ACPI.lock().as_ref().and_then(|a| a.power) {
+        Some(power) => power,
+        None => return,
+    };
+    let (slp_typa, slp_typb) = match power.s5 {
+        Some(s5) => s5,
+        None => return,
+    };
+    unsafe {
+        // Firmware may still own power management until told otherwise
+        if inw(power.pm1a_control as u16) & SCI_EN == 0 && power.smi_command != 0 {
+            outb(power.smi_command as u16, power.acpi_enable);
+            for _ in 0..1_000_000 {
+                if inw(power.pm1a_control as u16) & SCI_EN != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        outw(power.pm1a_control as u16, ((slp_typa as u16) << 10) | SLP_EN);
+        if power.pm1b_control != 0 {
+            outw(power.pm1b_control as u16, ((slp_typb as u16) << 10) | SLP_EN);
+        }
+    }
+    // Powering off takes a moment
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+    warn!("acpi", "Still running after entering S5");
+}
+
+/// Reset through the FADT reset register; returns only if ACPI cannot do
+/// it
+pub fn reset() {
+    let (register, value) = match ACPI.lock().as_ref().and_then(|a| a.power).and_then(|p| p.reset) {
+        Some(reset) => reset,
+        None => return,
+    };
+    match register.space {
+        SPACE_IO => unsafe { outb(register.address as u16, value) },
+        SPACE_MEMORY => {
+            if let Some(virt) = mm::map_mmio(PhysAddr::new(register.address), 1) {
+                unsafe { core::ptr::write_volatile(virt.as_u64() as *mut u8, value) };
+            }
+        }
+        SPACE_PCI => {
+            let (device, function, offset) = pci_address(register.address);
+            let shift = (offset & 3) * 8;
+            let old = pci::read_config32(0, device, function, offset);
+            pci::write_config32(0, device, function, offset, (old & !(0xFF << shift)) | ((value as u32) << shift));
+        }
+        _ => return,
+    }
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Print what the tables say
+pub fn print_info() {
+    let acpi = ACPI.lock();
+    let acpi = match acpi.as_ref() {
+        Some(acpi) => acpi,
+        None => {
+            println!("No ACPI tables");
+            return;
+        }
+    };
+    println!("ACPI revision {}, tables: {}", acpi.revision, acpi.tables.join(" "));
+    println!("Local APIC at {:#x}", acpi.local_apic);
+    for p in &acpi.processors {
+        println!("  CPU: ACPI id {}, APIC id {}{}", p.acpi_id, p.apic_id, if p.enabled { "" } else { " (disabled)" });
+    }
+    for io in &acpi.io_apics {
+        println!("  I/O APIC {} at {:#x}, interrupts from {}", io.id, io.address, io.gsi_base);
+    }
+    for o in &acpi.overrides {
+        println!("  IRQ {} -> interrupt {} (flags {:#x})", o.source, o.gsi, o.flags);
+    }
+    if acpi.hpet != 0 {
+        println!("  HPET at {:#x}", acpi.hpet);
+    }
+    for e in &acpi.ecam {
+        println!("  PCIe ECAM at {:#x}, segment {}, buses {}-{}", e.base, e.segment, e.start_bus, e.end_bus);
+    }
+    match acpi.power {
+        Some(p) => println!("  Power off: {}; reset register: {}",
+            if p.s5.is_some() { "yes" } else { "no \\_S5" },
+            if p.reset.is_some() { "yes" } else { "no" }),
+        None => println!("  No FADT"),
+    }
+    if let Some(aml) = AML.lock().as_ref() {
+        println!("  AML namespace: {} objects, {} devices{}", aml.namespace.len(), aml.namespace.devices().len(),
+            if aml.platform.ec.is_some() { ", embedded controller" } else { "" });
+    }
+}
@@ -0,0 +1,286 @@
+//! Local APIC and I/O APIC
+//!
+//! `init` takes over from the 8259 PICs when the MADT lists an I/O APIC.
+//! Legacy IRQs are routed through the I/O APIC to the vectors they had
+//! under the PICs, `IRQ_BASE + irq`, following the MADT's interrupt
+//! source overrides, and the PICs are masked for good.
+//!
+//! The local APIC timer drives the kernel tick, re-armed after each one:
+//! in TSC-deadline mode where the CPU has it, one-shot otherwise. The
+//! APIC timer is measured against the TSC, which the timer has calibrated.
+//!
+//! Vectors from `FIRST_DYNAMIC_VECTOR` up are handed out by
+//! `allocate_vector`, for MSI and MSI-X.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use webbos_shared::types::PhysAddr;
+
+use super::cpu::{rdmsr, wrmsr};
+use super::interrupts::{self, InterruptStackFrame, IRQ_BASE};
+use crate::drivers::input::outb;
+use crate::{info, warn};
+
+// Local APIC registers
+const ID: u32 = 0x20;
+const EOI: u32 = 0xB0;
+const SPURIOUS: u32 = 0xF0;
+const ICR_LOW: u32 = 0x300;
+const ICR_HIGH: u32 = 0x310;
+const LVT_TIMER: u32 = 0x320;
+const LVT_PERFORMANCE: u32 = 0x340;
+const TIMER_INITIAL: u32 = 0x380;
+const TIMER_CURRENT: u32 = 0x390;
+const TIMER_DIVIDE: u32 = 0x3E0;
+
+// Model-specific registers
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LVT timer modes
+const TIMER_ONE_SHOT: u32 = 0b00 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const LVT_MASKED: u32 = 1 << 16;
+/// Delivery mode of an LVT entry or IPI: non-maskable interrupt
+const DELIVERY_NMI: u32 = 0b100 << 8;
+/// Interrupt command: still being sent
+const ICR_PENDING: u32 = 1 << 12;
+
+/// Vector of the APIC timer, just past the legacy IRQs
+pub const TIMER_VECTOR: u8 = IRQ_BASE + 16;
+/// First vector `allocate_vector` hands out
+pub const FIRST_DYNAMIC_VECTOR: u8 = 0x40;
+/// Past the last vector `allocate_vector` hands out
+const LAST_DYNAMIC_VECTOR: u8 = 0xF0;
+const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Where MSI writes land: the local APIC of the processor named in bits
+/// 12-19
+const MSI_ADDRESS: u64 = 0xFEE0_0000;
+
+/// Virtual address of the local APIC registers; 0 until `init` succeeds
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// APIC timer ticks, or TSC ticks in deadline mode, per kernel tick
+static TIMER_PERIOD: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+static NEXT_VECTOR: AtomicU8 = AtomicU8::new(FIRST_DYNAMIC_VECTOR);
+
+/// I/O APICs, as (virtual address, first interrupt, interrupts handled)
+static IO_APICS: Mutex<Vec<(u64, u32, u32)>> = Mutex::new(Vec::new());
+
+/// Whether the APICs have taken over from the PICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+fn read(reg: u32) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile((base + reg as u64) as *const u32) }
+}
+
+fn write(reg: u32, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile((base + reg as u64) as *mut u32, value) }
+}
+
+fn io_apic_read(base: u64, reg: u32) -> u32 {
+    unsafe {
+        core::ptr::write_volatile(base as *mut u32, reg);
+        core::ptr::read_volatile((base + 0x10) as *const u32)
+    }
+}
+
+fn io_apic_write(base: u64, reg: u32, value: u32) {
+    unsafe {
+        core::ptr::write_volatile(base as *mut u32, reg);
+        core::ptr::write_volatile((base + 0x10) as *mut u32, value);
+    }
+}
+
+/// Switch from the PICs to the APICs, if the MADT lists an I/O APIC
+pub fn init() {
+    let io_apics = crate::acpi::io_apics();
+    if io_apics.is_empty() {
+        warn!("apic", "No I/O APIC in the MADT, staying on the PICs");
+        return;
+    }
+    let phys = crate::acpi::local_apic().unwrap_or_else(|| unsafe { rdmsr(IA32_APIC_BASE) } & !0xFFF);
+    let local = match crate::mm::map_mmio(PhysAddr::new(phys), 0x1000) {
+        Some(virt) => virt.as_u64(),
+        None => {
+            warn!("apic", "Could not map the local APIC, staying on the PICs");
+            return;
+        }
+    };
+
+    let mut mapped = IO_APICS.lock();
+    for io in &io_apics {
+        let base = match crate::mm::map_mmio(PhysAddr::new(io.address as u64), 0x20) {
+            Some(virt) => virt.as_u64(),
+            None => continue,
+        };
+        // Bits 16-23 of the version register: the last redirection entry
+        let count = ((io_apic_read(base, 0x01) >> 16) & 0xFF) + 1;
+        for i in 0..count {
+            io_apic_write(base, 0x10 + i * 2, LVT_MASKED);
+        }
+        mapped.push((base, io.gsi_base, count));
+    }
+    drop(mapped);
+
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    unsafe {
+        wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | (1 << 11));
+        // The PICs, cascade and all, stay quiet from now on
+        outb(0x21, 0xFF);
+        outb(0xA1, 0xFF);
+    }
+    interrupts::set_vector_handler(SPURIOUS_VECTOR, spurious_interrupt);
+    LOCAL_APIC.store(local, Ordering::Relaxed);
+    write(SPURIOUS, 0x100 | SPURIOUS_VECTOR as u32);
+    write(LVT_TIMER, LVT_MASKED);
+    if enabled {
+        interrupts::enable();
+    }
+    info!("apic", "Local APIC {} at {:#x}, {} I/O APICs", apic_id(), phys, io_apics.len());
+}
+
+/// ID of this processor's local APIC
+pub fn apic_id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Tell the local APIC the interrupt being handled is done
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Deliver ISA interrupt `irq` as `vector` to this processor
+pub fn route_irq(irq: u8, vector: u8) {
+    // ISA interrupts are edge-triggered and active high unless overridden
+    let (gsi, flags) = crate::acpi::interrupt_override(irq).map_or((irq as u32, 0), |o| (o.gsi, o.flags));
+    let mut low = vector as u32;
+    if flags & 0b11 == 0b11 {
+        low |= 1 << 13; // Active low
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        low |= 1 << 15; // Level triggered
+    }
+    let io_apics = IO_APICS.lock();
+    match io_apics.iter().find(|&&(_, first, count)| (first..first + count).contains(&gsi)) {
+        Some(&(base, first, _)) => {
+            let entry = 0x10 + (gsi - first) * 2;
+            io_apic_write(base, entry + 1, (apic_id() as u32) << 24);
+            io_apic_write(base, entry, low);
+        }
+        None => warn!("apic", "No I/O APIC handles interrupt {} (IRQ {})", gsi, irq),
+    }
+}
+
+/// A free vector, with `handler` installed; None once they have all gone
+pub fn allocate_vector(handler: extern "x86-interrupt" fn(InterruptStackFrame)) -> Option<u8> {
+    let vector = NEXT_VECTOR.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+        (v < LAST_DYNAMIC_VECTOR).then_some(v + 1)
+    }).ok()?;
+    interrupts::set_vector_handler(vector, handler);
+    Some(vector)
+}
+
+/// Address and data a device writes to raise `vector` here by MSI
+pub fn msi_message(vector: u8) -> (u64, u32) {
+    (MSI_ADDRESS | ((apic_id() as u64) << 12), vector as u32)
+}
+
+/// Deliver performance counter overflows here as NMIs; an overflow masks
+/// the entry on some processors, so it is set again after each one
+pub fn route_performance_nmi() -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    write(LVT_PERFORMANCE, DELIVERY_NMI);
+    true
+}
+
+/// Raise an NMI on this processor; false if the APICs are not in use
+pub fn send_nmi_to_self() -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    while read(ICR_LOW) & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+    // The self shorthand takes only fixed interrupts, so by APIC ID
+    write(ICR_HIGH, (apic_id() as u32) << 24);
+    write(ICR_LOW, DELIVERY_NMI);
+    true
+}
+
+/// APIC timer ticks per millisecond, counted over 10ms of the TSC the
+/// timer has calibrated
+fn calibrate() -> u64 {
+    const MS: u64 = 10;
+    let tsc_ticks = crate::drivers::timer::tsc_per_ms() * MS;
+    write(TIMER_DIVIDE, 0b1011); // Divide by 1
+    write(TIMER_INITIAL, u32::MAX);
+    let tsc = super::cpu::rdtsc();
+    while super::cpu::rdtsc() - tsc < tsc_ticks {
+        core::hint::spin_loop();
+    }
+    let apic_ticks = (u32::MAX - read(TIMER_CURRENT)) as u64;
+    write(TIMER_INITIAL, 0);
+    apic_ticks / MS
+}
+
+/// Whether the CPU can arm the APIC timer with a TSC deadline
+fn has_tsc_deadline() -> bool {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+    leaf.ecx & (1 << 24) != 0
+}
+
+/// Run the kernel tick off the APIC timer at `frequency` Hz; false if the
+/// APICs are not in use
+pub fn start_timer(frequency: u32) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let tsc_per_ms = crate::drivers::timer::tsc_per_ms();
+    let apic_per_ms = calibrate();
+    let deadline = has_tsc_deadline() && tsc_per_ms > 0;
+    let per_ms = if deadline { tsc_per_ms } else { apic_per_ms };
+    TIMER_PERIOD.store((per_ms * 1000 / frequency as u64).max(1), Ordering::Relaxed);
+    TSC_DEADLINE.store(deadline, Ordering::Relaxed);
+
+    interrupts::set_vector_handler(TIMER_VECTOR, timer_interrupt);
+    if deadline {
+        write(LVT_TIMER, TIMER_TSC_DEADLINE | TIMER_VECTOR as u32);
+    } else {
+        write(TIMER_DIVIDE, 0b1011);
+        write(LVT_TIMER, TIMER_ONE_SHOT | TIMER_VECTOR as u32);
+    }
+    arm_timer();
+    info!("apic", "Timer at {}Hz in {} mode ({} APIC and {} TSC ticks per ms)", frequency,
+        if deadline { "TSC-deadline" } else { "one-shot" }, apic_per_ms, tsc_per_ms);
+    true
+}
+
+/// Have the timer fire one tick from now
+fn arm_timer() {
+    let period = TIMER_PERIOD.load(Ordering::Relaxed);
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        unsafe { wrmsr(IA32_TSC_DEADLINE, super::cpu::rdtsc() + period) };
+    } else {
+        write(TIMER_INITIAL, period.min(u32::MAX as u64) as u32);
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt(_stack_frame: InterruptStackFrame) {
+    arm_timer();
+    end_of_interrupt();
+    unsafe { crate::drivers::timer::timer_interrupt() };
+}
+
+extern "x86-interrupt" fn spurious_interrupt(_stack_frame: InterruptStackFrame) {
+    // Spurious interrupts take no EOI
+}
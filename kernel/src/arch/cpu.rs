@@ -1,52 +1,33 @@
 //! CPU-specific functions
 
-use core::arch::x86_64::__cpuid;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::println;
 
+/// CPUID.7.0:EBX bits for SMEP and SMAP, and the CR4 bits enabling them
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+static SMEP: AtomicBool = AtomicBool::new(false);
+static SMAP: AtomicBool = AtomicBool::new(false);
+
 /// Initialize CPU features
 pub fn init() {
     unsafe {
-        // Enable SSE
-        enable_sse();
+        // Enable SSE, and AVX where there is XSAVE
+        super::simd::init();
         
         // Enable NX bit (requires EFER MSR)
         enable_nx_bit();
         
         // Enable write protect
         enable_write_protect();
-    }
-}
 
-/// Enable SSE (Streaming SIMD Extensions)
-unsafe fn enable_sse() {
-    let mut cr0: u64;
-    core::arch::asm!(
-        "mov {}, cr0",
-        out(reg) cr0,
-        options(nomem, nostack)
-    );
-    // Clear EM, Set MP
-    cr0 &= !(1 << 2);  // Clear EM (bit 2)
-    cr0 |= 1 << 1;     // Set MP (bit 1)
-    core::arch::asm!(
-        "mov cr0, {}",
-        in(reg) cr0,
-        options(nomem, nostack)
-    );
-    
-    let mut cr4: u64;
-    core::arch::asm!(
-        "mov {}, cr4",
-        out(reg) cr4,
-        options(nomem, nostack)
-    );
-    // Set OSFXSR (bit 9) and OSXMMEXCPT (bit 10)
-    cr4 |= (1 << 9) | (1 << 10);
-    core::arch::asm!(
-        "mov cr4, {}",
-        in(reg) cr4,
-        options(nomem, nostack)
-    );
+        // Keep the kernel from running or touching user pages
+        enable_smep_smap();
+    }
 }
 
 /// Enable NX (No-Execute) bit
@@ -88,6 +69,29 @@ unsafe fn enable_write_protect() {
     );
 }
 
+/// Enable SMEP, so the kernel faults running a user page, and SMAP, so
+/// it faults touching one outside `mm::user`'s copies, where the CPU has
+/// them
+unsafe fn enable_smep_smap() {
+    let features = __cpuid_count(7, 0).ebx;
+    let mut cr4: u64;
+    core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+    if features & CPUID_SMEP != 0 {
+        cr4 |= CR4_SMEP;
+        SMEP.store(true, Ordering::Relaxed);
+    }
+    if features & CPUID_SMAP != 0 {
+        cr4 |= CR4_SMAP;
+        SMAP.store(true, Ordering::Relaxed);
+    }
+    core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nomem, nostack));
+}
+
+/// Whether user pages can only be reached with EFLAGS.AC set
+pub fn smap_enabled() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
 /// Halt the CPU until next interrupt
 pub fn halt() {
     unsafe {
@@ -171,36 +175,94 @@ pub fn print_info() {
     if !brand_trimmed.is_empty() {
         println!("  CPU Brand: {}", brand_trimmed);
     }
+    let state = if super::simd::uses_xsave() { "XSAVE" } else { "FXSAVE" };
+    println!("  SIMD: {} ({})", super::simd::level().name(), state);
+    let on = |enabled: &AtomicBool| if enabled.load(Ordering::Relaxed) { "on" } else { "off" };
+    println!("  Protection: NX, SMEP {}, SMAP {}", on(&SMEP), on(&SMAP));
 }
 
 /// Reboot the system
+///
+/// The ACPI reset register comes first. Then the chipset's reset control
+/// register at 0xCF9, then the keyboard controller's reset line, and last
+/// a triple fault, which resets any x86 machine.
 pub fn reboot() -> ! {
+    use crate::drivers::input::{inb, outb};
+
+    disable_interrupts();
+    crate::acpi::reset();
     unsafe {
-        // Try keyboard controller reset
-        core::arch::asm!(
-            "mov al, 0xFE",
-            "out 0x64, al",
-            options(nomem, nostack)
-        );
-        
-        // If that fails, triple fault
+        // Reset control register: system reset, then a full reset
+        outb(0xCF9, 0x02);
+        outb(0xCF9, 0x06);
+        settle();
+
+        // Keyboard controller reset pulse, once it takes commands
+        for _ in 0..100_000 {
+            if inb(0x64) & 0x02 == 0 {
+                break;
+            }
+        }
+        outb(0x64, 0xFE);
+        settle();
+
+        // With no IDT, the next exception triple faults
+        let null_idt = [0u8; 10];
+        core::arch::asm!("lidt [{}]", "int3", in(reg) null_idt.as_ptr(), options(nostack));
         loop {
-            core::arch::asm!("int 3", options(nomem, nostack));
+            core::arch::asm!("hlt", options(nomem, nostack));
         }
     }
 }
 
 /// Shutdown the system (if supported by hardware)
+///
+/// ACPI soft-off through the FADT works on real machines. Emulators that
+/// boot without usable ACPI tables still power off through their own
+/// ports: QEMU's PIIX4 power management block, Bochs and older QEMU, and
+/// VirtualBox.
 pub fn shutdown() -> ! {
+    disable_interrupts();
+    crate::acpi::shutdown();
     unsafe {
-        // Try ACPI shutdown (simplified - should use proper ACPI)
-        // For now, just halt
-        loop {
-            core::arch::asm!("cli; hlt", options(nomem, nostack));
+        for (port, value) in [(0x604u16, 0x2000u16), (0xB004, 0x2000), (0x4004, 0x3400)] {
+            core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack));
         }
+        settle();
+    }
+    println!("Could not power off; it is now safe to turn the machine off");
+    loop {
+        unsafe { core::arch::asm!("hlt", options(nomem, nostack)) };
+    }
+}
+
+/// Give a reset or power-off that has been asked for time to happen
+fn settle() {
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
     }
 }
 
+/// Read a model-specific register
+///
+/// # Safety
+/// Reading an MSR the processor does not have faults.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+/// Write a model-specific register
+///
+/// # Safety
+/// Writing an MSR the processor does not have faults, and many change
+/// how it runs.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32,
+        options(nomem, nostack));
+}
+
 /// Read timestamp counter
 pub fn rdtsc() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
@@ -61,9 +61,90 @@ pub struct InterruptStackFrame {
     pub stack_segment: u64,
 }
 
+/// Vector of the first legacy PIC interrupt; IRQ `n` arrives as
+/// `IRQ_BASE + n`, clear of the CPU exceptions
+pub const IRQ_BASE: u8 = 32;
+
+/// Command and data ports of the primary and secondary 8259 PICs
+const PIC1: u16 = 0x20;
+const PIC2: u16 = 0xA0;
+
+/// Move the PIC interrupts to `IRQ_BASE` and mask all of them; drivers
+/// unmask the lines they handle with `set_irq_handler`. Once the APICs
+/// take over, the PICs stay masked and IRQs go through the I/O APIC.
+unsafe fn remap_pic() {
+    use crate::drivers::input::outb;
+
+    // Start initialization, with ICW4
+    outb(PIC1, 0x11);
+    outb(PIC2, 0x11);
+    // Vector offsets
+    outb(PIC1 + 1, IRQ_BASE);
+    outb(PIC2 + 1, IRQ_BASE + 8);
+    // The secondary is cascaded on IRQ 2
+    outb(PIC1 + 1, 0x04);
+    outb(PIC2 + 1, 0x02);
+    // 8086 mode
+    outb(PIC1 + 1, 0x01);
+    outb(PIC2 + 1, 0x01);
+    // Everything masked but the cascade
+    outb(PIC1 + 1, 0xFB);
+    outb(PIC2 + 1, 0xFF);
+}
+
+/// Handle `vector` with `handler`
+pub fn set_vector_handler(vector: u8, handler: extern "x86-interrupt" fn(InterruptStackFrame)) {
+    let enabled = are_enabled();
+    disable();
+    unsafe {
+        IDT[vector as usize].set_handler(handler as u64);
+    }
+    if enabled {
+        enable();
+    }
+}
+
+/// Handle legacy interrupt `irq` with `handler` and unmask it
+pub fn set_irq_handler(irq: u8, handler: extern "x86-interrupt" fn(InterruptStackFrame)) {
+    use crate::drivers::input::{inb, outb};
+
+    set_vector_handler(IRQ_BASE + irq, handler);
+    if super::apic::is_enabled() {
+        super::apic::route_irq(irq, IRQ_BASE + irq);
+        return;
+    }
+    let enabled = are_enabled();
+    disable();
+    unsafe {
+        let (port, line) = if irq < 8 { (PIC1 + 1, irq) } else { (PIC2 + 1, irq - 8) };
+        outb(port, inb(port) & !(1 << line));
+    }
+    if enabled {
+        enable();
+    }
+}
+
+/// Tell the interrupt controller interrupt `irq` has been handled
+pub fn end_of_interrupt(irq: u8) {
+    use crate::drivers::input::outb;
+
+    if super::apic::is_enabled() {
+        super::apic::end_of_interrupt();
+        return;
+    }
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2, 0x20);
+        }
+        outb(PIC1, 0x20);
+    }
+}
+
 /// Initialize interrupt handling
 pub fn init() {
     unsafe {
+        remap_pic();
+
         // Set up exception handlers
         IDT[0].set_handler(divide_error as u64);
         IDT[1].set_handler(debug as u64);
@@ -129,6 +210,9 @@ extern "x86-interrupt" fn debug(stack_frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn nmi(stack_frame: InterruptStackFrame) {
+    if crate::watchdog::nmi(&stack_frame) {
+        return;
+    }
     panic!("EXCEPTION: Non-Maskable Interrupt\n{:#?}", stack_frame);
 }
 
@@ -174,13 +258,19 @@ extern "x86-interrupt" fn general_protection_fault(stack_frame: InterruptStackFr
         error_code, stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, error_code: u64) {
+extern "x86-interrupt" fn page_fault(mut stack_frame: InterruptStackFrame, error_code: u64) {
     // Read CR2 for faulting address
     let cr2: u64;
     unsafe {
         core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack));
     }
-    
+
+    // A bad pointer a syscall was given fails its copy, not the kernel
+    if let Some(fixup) = crate::mm::user::fixup(stack_frame.instruction_pointer, cr2) {
+        unsafe { core::ptr::write_volatile(&mut stack_frame.instruction_pointer, fixup) };
+        return;
+    }
+
     panic!(
         "EXCEPTION: Page Fault\n  Accessed Address: {:#x}\n  Error Code: {:#b}\n  {:#?}",
         cr2, error_code, stack_frame
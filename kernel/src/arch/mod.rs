@@ -4,5 +4,7 @@
 
 pub mod cpu;
 pub mod interrupts;
+pub mod apic;
 pub mod paging;
 pub mod gdt;
+pub mod simd;
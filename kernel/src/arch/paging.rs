@@ -1,5 +1,6 @@
 //! Paging implementation
 
+use alloc::vec::Vec;
 use webbos_shared::types::{PhysAddr, PAGE_SIZE};
 
 /// Page table entry
@@ -37,6 +38,21 @@ impl PageTableEntry {
     pub fn is_huge_page(&self) -> bool {
         (self.0 & 0x80) != 0
     }
+
+    /// The flag bits, without the address
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags(self.0 & !0x000F_FFFF_FFFF_F000)
+    }
+
+    /// Replace the flags, keeping the address
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.0 = (self.0 & 0x000F_FFFF_FFFF_F000) | flags.bits();
+    }
+
+    /// Clear the entry
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
 }
 
 /// Page table flags
@@ -62,6 +78,9 @@ impl PageTableFlags {
     pub const HUGE_PAGE: Self = Self(1 << 7);
     /// Global flag
     pub const GLOBAL: Self = Self(1 << 8);
+    /// Ignored by the CPU: the frame belongs to a shared memory object,
+    /// not to the tables mapping it
+    pub const SHARED: Self = Self(1 << 9);
     /// No execute flag (bit 63)
     pub const NO_EXECUTE: Self = Self(1 << 63);
 
@@ -79,6 +98,16 @@ impl PageTableFlags {
     pub fn bits(&self) -> u64 {
         self.0
     }
+
+    /// Whether all of `other`'s flags are set
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These flags without `other`'s
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
 }
 
 impl core::ops::BitOr for PageTableFlags {
@@ -138,6 +167,9 @@ impl PhysFrame {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static [webbos_shared::types::MemoryRegion],
     next: usize,
+    /// Page-aligned ranges given back after boot, handed out once the
+    /// memory map's frames are gone
+    reclaimed: Vec<(u64, u64)>,
 }
 
 impl BootInfoFrameAllocator {
@@ -151,6 +183,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            reclaimed: Vec::new(),
         }
     }
 
@@ -169,8 +202,28 @@ impl BootInfoFrameAllocator {
     /// Allocate a frame
     pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if frame.is_some() {
+            self.next += 1;
+            return frame;
+        }
+        let (start, end) = self.reclaimed.last_mut()?;
+        let frame = PhysFrame::containing_address(PhysAddr::new(*start));
+        *start += PAGE_SIZE as u64;
+        if start >= end {
+            self.reclaimed.pop();
+        }
+        Some(frame)
+    }
+
+    /// Hand out `start..end` too; both must be page-aligned and the
+    /// memory unused from now on
+    ///
+    /// # Safety
+    /// Nothing may use the memory any more.
+    pub unsafe fn add_free_range(&mut self, start: u64, end: u64) {
+        if start < end {
+            self.reclaimed.push((start, end));
+        }
     }
 }
 
@@ -183,6 +236,8 @@ pub enum MapToError {
     ParentEntryHugePage,
     /// Page already mapped
     PageAlreadyMapped,
+    /// Page outside the range the address space may map
+    OutOfRange,
 }
 
 /// Offset page table
@@ -259,7 +314,7 @@ impl OffsetPageTable {
             
             // Zero the new table
             unsafe {
-                core::ptr::write_bytes(virt_addr as *mut u8, 0, PAGE_SIZE);
+                super::simd::memset(virt_addr as *mut u8, 0, PAGE_SIZE);
             }
             
             // Set entry to point to new table using raw pointer
@@ -372,7 +427,7 @@ fn translate_addr_inner(addr: u64, physical_memory_offset: u64) -> Option<PhysAd
 
     let mut table_virt_addr = virt_addr;
 
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         let table = unsafe { &*(table_virt_addr as *const PageTable) };
         let entry = table.get_entry(index);
         
@@ -380,8 +435,10 @@ fn translate_addr_inner(addr: u64, physical_memory_offset: u64) -> Option<PhysAd
             return None;
         }
         
-        if entry.is_huge_page() {
-            panic!("huge pages not supported in translation");
+        // A 1GB page in the PDPT or a 2MB page in the PD ends the walk
+        if entry.is_huge_page() && (level == 1 || level == 2) {
+            let page_size = 1u64 << (39 - 9 * level);
+            return Some(PhysAddr::new((entry.addr().as_u64() & !(page_size - 1)) | (addr & (page_size - 1))));
         }
         
         // Convert next table's physical address to virtual
@@ -0,0 +1,448 @@
+//! SIMD fast paths
+//!
+//! `init` probes CPUID once and turns on what it finds: SSE through CR0 and
+//! CR4, and where there is XSAVE, the AVX state through CR4.OSXSAVE and
+//! XCR0. `memcpy`, `memset` and `memcmp` then take the widest path the CPU
+//! has, AVX2, SSE2 or plain, picked at each call from what was probed.
+//!
+//! The kernel is built soft-float, so the compiler keeps nothing in the
+//! vector registers and the paths are written in assembly. Each stores
+//! the registers it uses on entry and loads them back before it returns,
+//! so the values of whatever it interrupted, a handler's caller or a
+//! thread in user mode, come through untouched. Buffers shorter than
+//! `SIMD_MIN` are not worth that and are done plainly.
+//!
+//! Only these paths use the vector registers. Threads keep no vector state
+//! of their own, so the scheduler has none to save or load.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Bytes below which the plain path is used
+const SIMD_MIN: usize = 256;
+
+/// CPUID.1:EDX and ECX, and CPUID.7.0:EBX bits
+const CPUID_SSE2: u32 = 1 << 26;
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+const CPUID_AVX2: u32 = 1 << 5;
+
+/// CR4.OSXSAVE
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// XCR0 state components: x87, SSE, AVX
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// The widest path the CPU has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Plain,
+    Sse2,
+    Avx2,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Plain => "none",
+            Level::Sse2 => "SSE2",
+            Level::Avx2 => "AVX2",
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Plain as u8);
+/// The CPU has XSAVE, and so AVX state may be enabled
+static XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// Probe the CPU and enable the vector state it has
+pub fn init() {
+    let basic = unsafe { __cpuid(1) };
+    let extended = unsafe { __cpuid_count(7, 0) };
+    unsafe { enable_sse() };
+    let mut level = if basic.edx & CPUID_SSE2 != 0 { Level::Sse2 } else { Level::Plain };
+
+    if basic.ecx & CPUID_XSAVE != 0 {
+        unsafe { write_cr4(read_cr4() | CR4_OSXSAVE) };
+        let avx = basic.ecx & CPUID_AVX != 0;
+        unsafe { xsetbv(XCR0_X87 | XCR0_SSE | if avx { XCR0_AVX } else { 0 }) };
+        if avx && extended.ebx & CPUID_AVX2 != 0 {
+            level = Level::Avx2;
+        }
+        XSAVE.store(true, Ordering::Relaxed);
+    }
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Enable SSE: clear CR0.EM, set CR0.MP, then CR4.OSFXSR and OSXMMEXCPT
+///
+/// Setting bits already set changes nothing, so anything about to run SSE
+/// instructions may call this itself rather than count on `init`.
+pub(crate) unsafe fn enable_sse() {
+    let mut cr0: u64;
+    core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+    cr0 &= !(1 << 2);
+    cr0 |= 1 << 1;
+    core::arch::asm!("mov cr0, {}", in(reg) cr0, options(nomem, nostack));
+    write_cr4(read_cr4() | (1 << 9) | (1 << 10));
+}
+
+unsafe fn read_cr4() -> u64 {
+    let cr4: u64;
+    core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+    cr4
+}
+
+unsafe fn write_cr4(cr4: u64) {
+    core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nomem, nostack));
+}
+
+unsafe fn xsetbv(xcr0: u64) {
+    core::arch::asm!(
+        "xsetbv",
+        in("ecx") 0u32,
+        in("eax") xcr0 as u32,
+        in("edx") (xcr0 >> 32) as u32,
+        options(nomem, nostack),
+    );
+}
+
+/// The path `init` found
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        2 => Level::Avx2,
+        1 => Level::Sse2,
+        _ => Level::Plain,
+    }
+}
+
+pub fn uses_xsave() -> bool {
+    XSAVE.load(Ordering::Relaxed)
+}
+
+/// The path to take for `len` bytes
+fn path(len: usize) -> Level {
+    if len < SIMD_MIN {
+        return Level::Plain;
+    }
+    level()
+}
+
+/// Copy `len` bytes from `src` to `dst`, which must not overlap
+pub unsafe fn memcpy(dst: *mut u8, src: *const u8, len: usize) {
+    copy_with(path(len), dst, src, len);
+}
+
+/// Set `len` bytes at `dst` to `value`
+pub unsafe fn memset(dst: *mut u8, value: u8, len: usize) {
+    set_with(path(len), dst, value, len);
+}
+
+/// Compare `len` bytes: negative, zero or positive as the first differing
+/// byte of `a` is below, equal to or above that of `b`
+pub unsafe fn memcmp(a: *const u8, b: *const u8, len: usize) -> i32 {
+    compare_with(path(len), a, b, len)
+}
+
+/// `dst.copy_from_slice(src)`, by the fast path
+pub fn copy(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len(), "copy between slices of different lengths");
+    unsafe { memcpy(dst.as_mut_ptr(), src.as_ptr(), src.len()) }
+}
+
+/// `a == b`, by the fast path
+pub fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && unsafe { memcmp(a.as_ptr(), b.as_ptr(), a.len()) } == 0
+}
+
+unsafe fn copy_with(level: Level, dst: *mut u8, src: *const u8, len: usize) {
+    match level {
+        Level::Avx2 => copy_avx2(dst, src, len),
+        Level::Sse2 => copy_sse2(dst, src, len),
+        Level::Plain => core::ptr::copy_nonoverlapping(src, dst, len),
+    }
+}
+
+unsafe fn set_with(level: Level, dst: *mut u8, value: u8, len: usize) {
+    match level {
+        Level::Avx2 => set_avx2(dst, value, len),
+        Level::Sse2 => set_sse2(dst, value, len),
+        Level::Plain => core::ptr::write_bytes(dst, value, len),
+    }
+}
+
+unsafe fn compare_with(level: Level, a: *const u8, b: *const u8, len: usize) -> i32 {
+    let start = match level {
+        Level::Avx2 => compare_avx2(a, b, len),
+        Level::Sse2 => compare_sse2(a, b, len),
+        Level::Plain => 0,
+    };
+    for i in start..len {
+        let (x, y) = (*a.add(i), *b.add(i));
+        if x != y {
+            return x as i32 - y as i32;
+        }
+    }
+    0
+}
+
+unsafe fn copy_sse2(dst: *mut u8, src: *const u8, len: usize) {
+    let blocks = len / 64;
+    if blocks > 0 {
+        let mut save = [0u8; 64];
+        core::arch::asm!(
+            "movdqu %xmm0, ({save})",
+            "movdqu %xmm1, 16({save})",
+            "movdqu %xmm2, 32({save})",
+            "movdqu %xmm3, 48({save})",
+            "2:",
+            "movdqu ({src}), %xmm0",
+            "movdqu 16({src}), %xmm1",
+            "movdqu 32({src}), %xmm2",
+            "movdqu 48({src}), %xmm3",
+            "movdqu %xmm0, ({dst})",
+            "movdqu %xmm1, 16({dst})",
+            "movdqu %xmm2, 32({dst})",
+            "movdqu %xmm3, 48({dst})",
+            "add $64, {src}",
+            "add $64, {dst}",
+            "dec {n}",
+            "jnz 2b",
+            "movdqu ({save}), %xmm0",
+            "movdqu 16({save}), %xmm1",
+            "movdqu 32({save}), %xmm2",
+            "movdqu 48({save}), %xmm3",
+            save = in(reg) save.as_mut_ptr(),
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            n = inout(reg) blocks => _,
+            options(att_syntax, nostack),
+        );
+    }
+    let done = blocks * 64;
+    core::ptr::copy_nonoverlapping(src.add(done), dst.add(done), len - done);
+}
+
+/// The upper halves are put back with the rest, so there is no
+/// `vzeroupper`; it would clear them for all sixteen registers
+unsafe fn copy_avx2(dst: *mut u8, src: *const u8, len: usize) {
+    let blocks = len / 128;
+    if blocks > 0 {
+        let mut save = [0u8; 128];
+        core::arch::asm!(
+            "vmovdqu %ymm0, ({save})",
+            "vmovdqu %ymm1, 32({save})",
+            "vmovdqu %ymm2, 64({save})",
+            "vmovdqu %ymm3, 96({save})",
+            "2:",
+            "vmovdqu ({src}), %ymm0",
+            "vmovdqu 32({src}), %ymm1",
+            "vmovdqu 64({src}), %ymm2",
+            "vmovdqu 96({src}), %ymm3",
+            "vmovdqu %ymm0, ({dst})",
+            "vmovdqu %ymm1, 32({dst})",
+            "vmovdqu %ymm2, 64({dst})",
+            "vmovdqu %ymm3, 96({dst})",
+            "add $128, {src}",
+            "add $128, {dst}",
+            "dec {n}",
+            "jnz 2b",
+            "vmovdqu ({save}), %ymm0",
+            "vmovdqu 32({save}), %ymm1",
+            "vmovdqu 64({save}), %ymm2",
+            "vmovdqu 96({save}), %ymm3",
+            save = in(reg) save.as_mut_ptr(),
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            n = inout(reg) blocks => _,
+            options(att_syntax, nostack),
+        );
+    }
+    let done = blocks * 128;
+    core::ptr::copy_nonoverlapping(src.add(done), dst.add(done), len - done);
+}
+
+unsafe fn set_sse2(dst: *mut u8, value: u8, len: usize) {
+    let blocks = len / 64;
+    if blocks > 0 {
+        let mut save = [0u8; 16];
+        let pattern = [value; 16];
+        core::arch::asm!(
+            "movdqu %xmm0, ({save})",
+            "movdqu ({pattern}), %xmm0",
+            "2:",
+            "movdqu %xmm0, ({dst})",
+            "movdqu %xmm0, 16({dst})",
+            "movdqu %xmm0, 32({dst})",
+            "movdqu %xmm0, 48({dst})",
+            "add $64, {dst}",
+            "dec {n}",
+            "jnz 2b",
+            "movdqu ({save}), %xmm0",
+            save = in(reg) save.as_mut_ptr(),
+            pattern = in(reg) pattern.as_ptr(),
+            dst = inout(reg) dst => _,
+            n = inout(reg) blocks => _,
+            options(att_syntax, nostack),
+        );
+    }
+    let done = blocks * 64;
+    core::ptr::write_bytes(dst.add(done), value, len - done);
+}
+
+unsafe fn set_avx2(dst: *mut u8, value: u8, len: usize) {
+    let blocks = len / 128;
+    if blocks > 0 {
+        let mut save = [0u8; 32];
+        let pattern = [value; 32];
+        core::arch::asm!(
+            "vmovdqu %ymm0, ({save})",
+            "vmovdqu ({pattern}), %ymm0",
+            "2:",
+            "vmovdqu %ymm0, ({dst})",
+            "vmovdqu %ymm0, 32({dst})",
+            "vmovdqu %ymm0, 64({dst})",
+            "vmovdqu %ymm0, 96({dst})",
+            "add $128, {dst}",
+            "dec {n}",
+            "jnz 2b",
+            "vmovdqu ({save}), %ymm0",
+            save = in(reg) save.as_mut_ptr(),
+            pattern = in(reg) pattern.as_ptr(),
+            dst = inout(reg) dst => _,
+            n = inout(reg) blocks => _,
+            options(att_syntax, nostack),
+        );
+    }
+    let done = blocks * 128;
+    core::ptr::write_bytes(dst.add(done), value, len - done);
+}
+
+/// Start of the first block that differs, or how far the vectors got
+/// when none does; the caller compares the rest byte by byte
+unsafe fn compare_sse2(a: *const u8, b: *const u8, len: usize) -> usize {
+    let end = len / 16 * 16;
+    if end == 0 {
+        return 0;
+    }
+    let mut save = [0u8; 32];
+    let mut i = 0usize;
+    core::arch::asm!(
+        "movdqu %xmm0, ({save})",
+        "movdqu %xmm1, 16({save})",
+        "2:",
+        "movdqu ({a},{i}), %xmm0",
+        "movdqu ({b},{i}), %xmm1",
+        "pcmpeqb %xmm1, %xmm0",
+        "pmovmskb %xmm0, {mask:e}",
+        "cmp $0xFFFF, {mask:e}",
+        "jne 3f",
+        "add $16, {i}",
+        "cmp {end}, {i}",
+        "jb 2b",
+        "3:",
+        "movdqu ({save}), %xmm0",
+        "movdqu 16({save}), %xmm1",
+        save = in(reg) save.as_mut_ptr(),
+        a = in(reg) a,
+        b = in(reg) b,
+        end = in(reg) end,
+        i = inout(reg) i,
+        mask = out(reg) _,
+        options(att_syntax, nostack),
+    );
+    i
+}
+
+unsafe fn compare_avx2(a: *const u8, b: *const u8, len: usize) -> usize {
+    let end = len / 32 * 32;
+    if end == 0 {
+        return 0;
+    }
+    let mut save = [0u8; 32];
+    let mut i = 0usize;
+    core::arch::asm!(
+        "vmovdqu %ymm0, ({save})",
+        "2:",
+        "vmovdqu ({a},{i}), %ymm0",
+        "vpcmpeqb ({b},{i}), %ymm0, %ymm0",
+        "vpmovmskb %ymm0, {mask:e}",
+        "cmp $-1, {mask:e}",
+        "jne 3f",
+        "add $32, {i}",
+        "cmp {end}, {i}",
+        "jb 2b",
+        "3:",
+        "vmovdqu ({save}), %ymm0",
+        save = in(reg) save.as_mut_ptr(),
+        a = in(reg) a,
+        b = in(reg) b,
+        end = in(reg) end,
+        i = inout(reg) i,
+        mask = out(reg) _,
+        options(att_syntax, nostack),
+    );
+    i
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// Every path up to the one this CPU has
+    fn levels() -> impl Iterator<Item = Level> {
+        [Level::Plain, Level::Sse2, Level::Avx2].into_iter().filter(|&l| l <= level())
+    }
+
+    #[kernel_test]
+    fn copies_any_length_and_alignment() -> Result<(), String> {
+        let src: Vec<u8> = (0..1100u32).map(|i| (i * 7 + 3) as u8).collect();
+        for level in levels() {
+            for (offset, len) in [(0, 0), (1, 15), (3, 64), (5, 300), (7, 1029)] {
+                let mut dst = [0xAAu8; 1100];
+                unsafe { copy_with(level, dst.as_mut_ptr().add(offset), src.as_ptr().add(2), len) };
+                check_eq!(&dst[offset..offset + len], &src[2..2 + len]);
+                check!(dst[..offset].iter().all(|&b| b == 0xAA));
+                check!(dst[offset + len..].iter().all(|&b| b == 0xAA));
+            }
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn fills_any_length() -> Result<(), String> {
+        for level in levels() {
+            for len in [0, 1, 31, 33, 500] {
+                let mut dst = [0u8; 520];
+                unsafe { set_with(level, dst.as_mut_ptr().add(3), 0x5C, len) };
+                check!(dst[3..3 + len].iter().all(|&b| b == 0x5C));
+                check_eq!(dst[3 + len], 0);
+            }
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn compares_like_memcmp() -> Result<(), String> {
+        let a: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        for level in levels() {
+            let mut b = a.clone();
+            check_eq!(unsafe { compare_with(level, a.as_ptr(), b.as_ptr(), a.len()) }, 0);
+            for at in [0, 17, 40, 699] {
+                b[at] = a[at].wrapping_add(1);
+                check!(unsafe { compare_with(level, a.as_ptr(), b.as_ptr(), a.len()) } < 0);
+                check!(unsafe { compare_with(level, b.as_ptr(), a.as_ptr(), a.len()) } > 0);
+                check_eq!(unsafe { compare_with(level, a.as_ptr(), b.as_ptr(), at) }, 0);
+                b[at] = a[at];
+            }
+        }
+        check!(equal(&a, &a.clone()));
+        check!(!equal(&a, &a[1..]));
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! about: pages
+//!
+//! Pages the browser makes itself, showing the kernel's state. Each is
+//! put together when it is loaded, from the same reports the console
+//! commands print, so reloading one brings it up to date:
+//!
+//! - `about:system`: the machine, uptime, heap, processes and the
+//!   scheduler
+//! - `about:network`: interfaces, protocol counters and sockets
+//! - `about:storage`: block devices and mounted filesystems
+//!
+//! `about:` lists them, and `about:blank` is an empty page.
+
+use alloc::format;
+use alloc::string::String;
+
+use super::BrowserError;
+use crate::arch::cpu;
+use crate::console;
+use crate::drivers::timer;
+use crate::mm::{self, allocator};
+use crate::process::{self, PROCESSES};
+use crate::{fs, net, smbios, storage};
+
+/// The pages `about:` lists, with what each shows
+const PAGES: &[(&str, &str)] = &[
+    ("system", "Hardware, uptime, memory, processes and scheduling"),
+    ("network", "Network interfaces, traffic and open sockets"),
+    ("storage", "Block devices and filesystems"),
+];
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 16px; } \
+    h1 { font-size: 22px; } h2 { font-size: 17px; margin-top: 20px; } \
+    pre { background: #f0f0f0; padding: 8px; } td { padding: 2px 12px 2px 0; }";
+
+fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+}
+
+struct Page {
+    html: String,
+}
+
+impl Page {
+    fn new(title: &str) -> Self {
+        let mut html = format!("<!DOCTYPE html><html><head><title>{}</title><style>{}</style></head><body>", title, STYLE);
+        html.push_str(&format!("<h1>{}</h1><p><a href=\"about:\">All about: pages</a></p>", title));
+        Page { html }
+    }
+
+    /// A table of names and values
+    fn table(&mut self, rows: &[(&str, String)]) {
+        self.html.push_str("<table>");
+        for (name, value) in rows {
+            self.html.push_str("<tr><td><b>");
+            escape(name, &mut self.html);
+            self.html.push_str("</b></td><td>");
+            escape(value, &mut self.html);
+            self.html.push_str("</td></tr>");
+        }
+        self.html.push_str("</table>");
+    }
+
+    /// A section with what `report` prints, as the console shows it
+    fn report(&mut self, heading: &str, report: impl FnOnce()) {
+        let text = console::capture(report);
+        self.html.push_str(&format!("<h2>{}</h2><pre>", heading));
+        escape(text.trim_end(), &mut self.html);
+        self.html.push_str("</pre>");
+    }
+
+    fn finish(mut self) -> String {
+        self.html.push_str("</body></html>");
+        self.html
+    }
+}
+
+fn index() -> String {
+    let mut page = Page::new("about: pages");
+    page.html.push_str("<ul>");
+    for (name, summary) in PAGES {
+        page.html.push_str(&format!("<li><a href=\"about:{0}\">about:{0}</a> - {1}</li>", name, summary));
+    }
+    page.html.push_str("</ul>");
+    page.finish()
+}
+
+fn system() -> String {
+    let mut page = Page::new("System");
+    let uptime = timer::elapsed_sec();
+    let (used, free) = (allocator::used_heap(), allocator::free_heap());
+    page.table(&[
+        ("Machine", smbios::machine().unwrap_or_else(|| String::from("Unknown"))),
+        ("Uptime", format!("{}:{:02}:{:02}", uptime / 3600, uptime / 60 % 60, uptime % 60)),
+        ("Heap", format!("{} KB used of {} KB", used / 1024, (used + free) / 1024)),
+        ("Processes", format!("{}", PROCESSES.lock().len())),
+    ]);
+    page.report("Processor", cpu::print_info);
+    page.report("Hardware", smbios::print_info);
+    page.report("Memory", mm::print_stats);
+    page.report("Processes", process::print_process_list);
+    page.report("Scheduler", process::scheduler::print_stats);
+    page.report("Timer", timer::print_stats);
+    page.finish()
+}
+
+fn network() -> String {
+    let mut page = Page::new("Network");
+    let config = net::get_config();
+    page.table(&[
+        ("Interfaces", format!("{}", net::interface_count())),
+        ("Address", format!("{}", config.ip)),
+        ("Netmask", format!("{}", config.netmask)),
+        ("Gateway", format!("{}", config.gateway)),
+        ("DNS server", format!("{}", config.dns)),
+    ]);
+    page.report("Interfaces", net::print_interfaces);
+    page.report("Traffic", net::print_stats);
+    page.report("Sockets", net::socket::print_sockets);
+    page.finish()
+}
+
+fn storage() -> String {
+    let mut page = Page::new("Storage");
+    page.table(&[("Block devices", format!("{}", storage::device_count()))]);
+    page.report("Devices", storage::print_devices);
+    page.report("Filesystems", fs::print_stats);
+    page.finish()
+}
+
+/// The HTML of page `about:<name>`
+pub fn page(name: &str) -> Result<String, BrowserError> {
+    match name {
+        "" | "about" => Ok(index()),
+        "blank" => Ok(String::from("<!DOCTYPE html><html><head><title></title></head><body></body></html>")),
+        "system" => Ok(system()),
+        "network" => Ok(network()),
+        "storage" => Ok(storage()),
+        _ => Err(BrowserError::NotFound),
+    }
+}
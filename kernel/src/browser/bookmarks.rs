@@ -0,0 +1,98 @@
+//! Bookmarks
+//!
+//! Pages the user has starred, kept in `.bookmarks` in their home
+//! directory, one `url<TAB>title` line each, oldest first. The list is
+//! read from the file the first time it is wanted for a user and written
+//! back whenever it changes, so every browser window and the start menu
+//! see the same one.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::{self, FsError, FsResult};
+use crate::warn;
+use crate::users;
+
+#[derive(Debug, Clone)]
+pub struct Bookmark {
+    pub url: String,
+    pub title: String,
+}
+
+/// The bookmarks file last read, and what was in it
+static CACHE: Mutex<Option<(String, Vec<Bookmark>)>> = Mutex::new(None);
+
+/// The current user's bookmarks file, if anyone is logged in
+fn path() -> Option<String> {
+    users::current_user().map(|user| format!("{}/.bookmarks", user.home_directory.trim_end_matches('/')))
+}
+
+fn parse(text: &str) -> Vec<Bookmark> {
+    text.lines()
+        .filter_map(|line| {
+            let (url, title) = line.split_once('\t').unwrap_or((line, line));
+            let url = url.trim();
+            (!url.is_empty()).then(|| Bookmark { url: String::from(url), title: String::from(title.trim()) })
+        })
+        .collect()
+}
+
+/// Run `f` on the current user's bookmarks, reading them first if they
+/// have not been; `None` if nobody is logged in
+fn with_bookmarks<R>(f: impl FnOnce(&str, &mut Vec<Bookmark>) -> R) -> Option<R> {
+    let path = path()?;
+    let mut cache = CACHE.lock();
+    if cache.as_ref().map_or(true, |(cached, _)| *cached != path) {
+        let bookmarks = fs::read_file(&path).map_or_else(|_| Vec::new(), |data| parse(&String::from_utf8_lossy(&data)));
+        *cache = Some((path, bookmarks));
+    }
+    let (path, bookmarks) = cache.as_mut().unwrap();
+    Some(f(path, bookmarks))
+}
+
+fn save(path: &str, bookmarks: &[Bookmark]) -> FsResult<()> {
+    let mut text = String::new();
+    for bookmark in bookmarks {
+        // Tabs and line breaks in a title would break the line up
+        let title: String = bookmark.title.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        text.push_str(&format!("{}\t{}\n", bookmark.url, title));
+    }
+    fs::write_file(path, text.as_bytes())
+}
+
+/// The current user's bookmarks, oldest first
+pub fn list() -> Vec<Bookmark> {
+    with_bookmarks(|_, bookmarks| bookmarks.clone()).unwrap_or_default()
+}
+
+/// Whether `url` is bookmarked
+pub fn contains(url: &str) -> bool {
+    with_bookmarks(|_, bookmarks| bookmarks.iter().any(|b| b.url == url)).unwrap_or(false)
+}
+
+/// Bookmark `url` as `title`, or take its bookmark away if it has one;
+/// returns whether it is bookmarked now
+///
+/// The change holds for this session even if saving it fails.
+pub fn toggle(url: &str, title: &str) -> FsResult<bool> {
+    with_bookmarks(|path, bookmarks| {
+        let starred = match bookmarks.iter().position(|b| b.url == url) {
+            Some(index) => {
+                bookmarks.remove(index);
+                false
+            }
+            None => {
+                bookmarks.push(Bookmark { url: String::from(url), title: String::from(title) });
+                true
+            }
+        };
+        if let Err(e) = save(path, bookmarks) {
+            warn!("browser", "Cannot save {}: {:?}", path, e);
+            return Err(e);
+        }
+        Ok(starred)
+    })
+    .unwrap_or(Err(FsError::PermissionDenied))
+}
@@ -0,0 +1,219 @@
+//! Cookies
+//!
+//! The cookie jar all tabs share. Servers set cookies with `Set-Cookie`
+//! and pages with `document.cookie`; each belongs to the host that set it,
+//! or to the domain it names if that host is within it, and goes back on
+//! requests there under its path. `Secure` cookies are sent only over
+//! HTTPS and `HttpOnly` ones are never shown to scripts. A cookie lasts
+//! until its `Max-Age` runs out or the system restarts.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::Url;
+use crate::drivers::{rtc, timer};
+
+/// Most cookies the jar holds; the oldest go first
+const MAX_COOKIES: usize = 300;
+
+/// Most cookies a single domain may have
+const MAX_COOKIES_PER_DOMAIN: usize = 50;
+
+/// Longest name and value a cookie may have together
+const MAX_COOKIE_SIZE: usize = 4096;
+
+struct Cookie {
+    name: String,
+    value: String,
+    /// Host or domain it was set for
+    domain: String,
+    /// Sent only to `domain` itself, not to its subdomains
+    host_only: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// When it expires, in milliseconds since boot
+    expires: Option<u64>,
+}
+
+impl Cookie {
+    fn expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |t| t <= now)
+    }
+
+    /// Whether the cookie goes with a request to `url`
+    fn matches(&self, url: &Url) -> bool {
+        let host_matches = if self.host_only { url.host == self.domain } else { domain_match(&url.host, &self.domain) };
+        host_matches && path_match(&url.path, &self.path) && (!self.secure || url.scheme == "https")
+    }
+}
+
+static JAR: Mutex<Vec<Cookie>> = Mutex::new(Vec::new());
+
+/// Whether `host` is `domain` or one of its subdomains
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+/// Whether a request for `path` falls under the cookie path `prefix`
+fn path_match(path: &str, prefix: &str) -> bool {
+    path == prefix
+        || (path.starts_with(prefix) && (prefix.ends_with('/') || path[prefix.len()..].starts_with('/')))
+}
+
+/// The path a cookie without one gets: the directory of the request
+fn default_path(url: &Url) -> String {
+    match url.path.rfind('/') {
+        Some(0) | None => String::from("/"),
+        Some(pos) => String::from(&url.path[..pos]),
+    }
+}
+
+/// Whether an `Expires` date such as `Thu, 01 Jan 1970 00:00:00 GMT` is
+/// already past, going by the real-time clock
+fn date_passed(date: &str) -> bool {
+    const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    let mut time = (0u8, 0u8, 0u8);
+    for token in date.split(|c: char| c == ' ' || c == ',' || c == '-').filter(|t| !t.is_empty()) {
+        let lower = token.to_ascii_lowercase();
+        if let Some(m) = MONTHS.iter().position(|m| lower.starts_with(m)) {
+            month = Some(m as u8 + 1);
+        } else if token.contains(':') {
+            let mut parts = token.split(':').map(|p| p.parse::<u8>().unwrap_or(0));
+            time = (parts.next().unwrap_or(0), parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+        } else if let Ok(n) = token.parse::<u16>() {
+            if day.is_none() && n <= 31 {
+                day = Some(n as u8);
+            } else {
+                // Two-digit years are 1970 to 2069
+                year = Some(match n {
+                    0..=69 => n + 2000,
+                    70..=99 => n + 1900,
+                    _ => n,
+                });
+            }
+        }
+    }
+    let (day, month, year) = match (day, month, year) {
+        (Some(day), Some(month), Some(year)) => (day, month, year),
+        _ => return false,
+    };
+    let now = rtc::now();
+    (year, month, day, time.0, time.1, time.2) <= (now.year, now.month, now.day, now.hour, now.minute, now.second)
+}
+
+/// Store a cookie from a `Set-Cookie` line of a response from `url`, or
+/// from `document.cookie` if `from_script`
+pub fn store(url: &Url, line: &str, from_script: bool) {
+    if url.scheme != "http" && url.scheme != "https" {
+        return;
+    }
+    let mut attributes = line.split(';');
+    let (name, value) = match attributes.next().and_then(|pair| pair.split_once('=')) {
+        Some((name, value)) => (name.trim(), value.trim()),
+        None => return,
+    };
+    if name.is_empty() || name.len() + value.len() > MAX_COOKIE_SIZE {
+        return;
+    }
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain: url.host.clone(),
+        host_only: true,
+        path: default_path(url),
+        secure: false,
+        http_only: false,
+        expires: None,
+    };
+    let now = timer::elapsed_ms();
+    let mut delete = false;
+    let mut max_age_given = false;
+    for attribute in attributes {
+        let (key, val) = match attribute.split_once('=') {
+            Some((key, val)) => (key.trim().to_ascii_lowercase(), val.trim()),
+            None => (attribute.trim().to_ascii_lowercase(), ""),
+        };
+        match key.as_str() {
+            "domain" if !val.is_empty() => {
+                let domain = val.trim_start_matches('.').to_ascii_lowercase();
+                // A host may only set cookies for a domain it is in, and
+                // not for a whole top-level domain
+                if !domain_match(&url.host, &domain) || !domain.contains('.') {
+                    return;
+                }
+                cookie.host_only = false;
+                cookie.domain = domain;
+            }
+            "path" if val.starts_with('/') => cookie.path = val.to_string(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "max-age" => {
+                if let Ok(seconds) = val.parse::<i64>() {
+                    max_age_given = true;
+                    delete = seconds <= 0;
+                    cookie.expires = Some(now.saturating_add(seconds.max(0) as u64 * 1000));
+                }
+            }
+            // `Max-Age` wins over `Expires`; a date in the future lasts
+            // until restart, as uptime cannot be compared with it
+            "expires" if !max_age_given => delete = date_passed(val),
+            _ => {}
+        }
+    }
+    // Only a secure page sets secure cookies
+    if cookie.secure && url.scheme != "https" {
+        return;
+    }
+    let mut jar = JAR.lock();
+    let same = |c: &Cookie| c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path;
+    // Scripts can neither set nor replace HttpOnly cookies
+    if from_script && (cookie.http_only || jar.iter().any(|c| same(c) && c.http_only)) {
+        return;
+    }
+    jar.retain(|c| !same(c) && !c.expired(now));
+    if delete {
+        return;
+    }
+    if jar.iter().filter(|c| c.domain == cookie.domain).count() >= MAX_COOKIES_PER_DOMAIN {
+        if let Some(oldest) = jar.iter().position(|c| c.domain == cookie.domain) {
+            jar.remove(oldest);
+        }
+    }
+    if jar.len() >= MAX_COOKIES {
+        jar.remove(0);
+    }
+    jar.push(cookie);
+}
+
+/// The `Cookie` header for a request to `url`, or for `document.cookie`
+/// if `for_script`; cookies with longer paths come first
+pub fn header(url: &Url, for_script: bool) -> Option<String> {
+    let now = timer::elapsed_ms();
+    let jar = JAR.lock();
+    let mut cookies: Vec<&Cookie> = jar.iter()
+        .filter(|c| c.matches(url) && !c.expired(now) && !(for_script && c.http_only))
+        .collect();
+    if cookies.is_empty() {
+        return None;
+    }
+    cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+    let pairs: Vec<String> = cookies.iter().map(|c| alloc::format!("{}={}", c.name, c.value)).collect();
+    Some(pairs.join("; "))
+}
+
+/// `document.cookie` for the page at `page`
+pub fn for_page(page: &str) -> String {
+    Url::parse(page).ok().and_then(|url| header(&url, true)).unwrap_or_default()
+}
+
+/// Set `document.cookie` to `text` on the page at `page`
+pub fn set_from_page(page: &str, text: &str) {
+    if let Ok(url) = Url::parse(page) {
+        store(&url, text, true);
+    }
+}
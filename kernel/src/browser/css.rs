@@ -2,12 +2,13 @@
 //!
 //! Parses CSS stylesheets and applies styles to DOM elements.
 
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 
 use crate::browser::{BrowserError, html::{Document, Element, Node}};
-use crate::println;
+use crate::info;
 
 /// CSS Stylesheet
 pub struct Stylesheet {
@@ -499,7 +500,18 @@ fn parse_declarations(tokens: &[Token], pos: &mut usize) -> Result<Vec<Declarati
         }
 
         // Parse value
-        let value = parse_value(tokens, pos)?;
+        let start = *pos;
+        let mut value = parse_value(tokens, pos)?;
+
+        // Shorthands like `border: 1px solid red` have several components;
+        // keep those as written and leave them to the property's user
+        if !matches!(tokens[*pos], Token::Semicolon | Token::RBrace | Token::EOF) {
+            while !matches!(tokens[*pos], Token::Semicolon | Token::RBrace | Token::EOF) {
+                *pos += 1;
+            }
+            let text: String = tokens[start..*pos].iter().map(token_text).collect();
+            value = Value::Keyword(String::from(text.trim()));
+        }
 
         declarations.push(Declaration { property, value });
 
@@ -522,6 +534,27 @@ fn parse_declarations(tokens: &[Token], pos: &mut usize) -> Result<Vec<Declarati
     Ok(declarations)
 }
 
+/// Source text of a token inside a declaration value
+fn token_text(token: &Token) -> String {
+    match token {
+        Token::Ident(s) => s.clone(),
+        Token::String(s) => format!("\"{}\"", s),
+        Token::Number(n) if *n == (*n as i64) as f32 => int_to_string(*n as i64),
+        Token::Number(n) => format!("{}", n),
+        Token::Hash(s) => format!("#{}", s),
+        Token::AtKeyword(s) => format!("@{}", s),
+        Token::Delim(c) => format!("{}", c),
+        Token::LParen => String::from("("),
+        Token::RParen => String::from(")"),
+        Token::LBracket => String::from("["),
+        Token::RBracket => String::from("]"),
+        Token::Colon => String::from(":"),
+        Token::Comma => String::from(","),
+        Token::Whitespace => String::from(" "),
+        Token::LBrace | Token::RBrace | Token::Semicolon | Token::EOF => String::new(),
+    }
+}
+
 /// Parse value
 fn parse_value(tokens: &[Token], pos: &mut usize) -> Result<Value, BrowserError> {
     match &tokens[*pos] {
@@ -582,12 +615,23 @@ pub fn apply_styles(document: &mut Document) -> Result<(), BrowserError> {
         }
     }
 
-    // Apply rules to elements
+    // Apply rules to elements, replacing what an earlier pass matched
+    clear_styles(&mut document.root);
     apply_rules_to_element(&stylesheet, &mut document.root);
 
     Ok(())
 }
 
+/// Forget the declarations matched to an element and its children
+fn clear_styles(element: &mut Element) {
+    element.computed_styles.clear();
+    for child in &mut element.children {
+        if let Node::Element(ref mut elem) = child {
+            clear_styles(elem);
+        }
+    }
+}
+
 /// Apply rules to element and children
 fn apply_rules_to_element(sheet: &Stylesheet, element: &mut Element) {
     // Find matching rules
@@ -611,7 +655,8 @@ fn apply_rules_to_element(sheet: &Stylesheet, element: &mut Element) {
                             }
                             s
                         }
-                        Value::Color(_) => String::from("color"),
+                        Value::Color(c) if c.a == 0 => String::from("transparent"),
+                        Value::Color(c) => format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b),
                         Value::Percentage(n) => {
                             let mut s = int_to_string(*n as i64);
                             s.push('%');
@@ -676,5 +721,5 @@ fn int_to_string(n: i64) -> String {
 
 /// Initialize CSS engine
 pub fn init() {
-    println!("[css] CSS engine initialized");
+    info!("css", "CSS engine initialized");
 }
@@ -0,0 +1,218 @@
+//! Downloads
+//!
+//! What a tab is sent that is not a page is saved to `Downloads` in the
+//! user's home directory instead. The tab hands its load over with
+//! `start` as soon as the response's `Content-Type` shows it is no page,
+//! and `poll`, which the desktop calls every tick, writes what has
+//! arrived to the file as it comes, so a download carries on after its
+//! tab is closed. A notification says when one is done or has failed.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{fetch, BrowserError, Url};
+use crate::desktop::notifications;
+use crate::fs::{self, FsError, FsResult};
+use crate::net::http::Response;
+use crate::{info, warn};
+use crate::users;
+
+/// Content types shown as pages; everything else is downloaded
+const PAGE_TYPES: &[&str] = &["text/html", "application/xhtml+xml"];
+
+/// Where a download stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Active,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub id: u32,
+    pub url: String,
+    /// File it is saved to
+    pub path: String,
+    /// Bytes saved so far
+    pub received: usize,
+    /// Size the server gave, if it did
+    pub total: Option<usize>,
+    pub state: State,
+}
+
+impl Download {
+    /// Name of the file it is saved to
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+struct Downloads {
+    /// Every download this session, oldest first
+    list: Vec<Download>,
+    /// Loads still arriving, by download ID
+    loads: Vec<(u32, fetch::Load)>,
+    next_id: u32,
+    /// Bumped whenever anything in `list` changes
+    generation: u32,
+}
+
+static DOWNLOADS: Mutex<Downloads> = Mutex::new(Downloads { list: Vec::new(), loads: Vec::new(), next_id: 1, generation: 0 });
+
+/// Whether `response` is something to download rather than show
+pub fn is_download(response: &Response) -> bool {
+    match response.headers.get("content-type") {
+        Some(value) => {
+            let essence = value.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+            !PAGE_TYPES.contains(&essence.as_str())
+        }
+        // Servers that do not say are taken to send pages
+        None => false,
+    }
+}
+
+/// The name a download from `url` is saved under: the one the server
+/// suggests, or else the last part of the URL's path
+fn file_name(url: &Url, response: &Response) -> String {
+    let suggested = response.headers.get("content-disposition").and_then(|value| {
+        let (_, rest) = value.split_once("filename=")?;
+        Some(rest.split(';').next().unwrap_or("").trim().trim_matches('"').to_string())
+    });
+    let name = suggested.unwrap_or_else(|| url.path.rsplit('/').next().unwrap_or("").to_string());
+    // A suggested name may not lead out of the directory
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("").trim();
+    if name.is_empty() || name == "." || name == ".." {
+        String::from("download")
+    } else {
+        String::from(name)
+    }
+}
+
+/// Make `path` a directory, and every directory above it
+fn create_dirs(path: &str) -> FsResult<()> {
+    let mut at = String::new();
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        at.push('/');
+        at.push_str(part);
+        match fs::create_dir(&at) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A path in `dir` for `name` that nothing is saved at yet, numbering the
+/// name if it has to
+fn free_path(dir: &str, name: &str, taken: &[Download]) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot..]),
+        _ => (name, ""),
+    };
+    let mut n = 0;
+    loop {
+        let path = match n {
+            0 => format!("{}/{}", dir, name),
+            _ => format!("{}/{} ({}){}", dir, stem, n, ext),
+        };
+        let used = fs::read_file(&path).is_ok() || taken.iter().any(|d| d.path == path);
+        if !used {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+/// Save what `load` brings to the user's downloads, starting with
+/// `received`, what has already arrived; returns the download's ID
+pub fn start(load: fetch::Load, received: Vec<u8>) -> Result<u32, BrowserError> {
+    let user = users::current_user().ok_or(BrowserError::Unknown)?;
+    let dir = format!("{}/Downloads", user.home_directory.trim_end_matches('/'));
+    let response = load.response().ok_or(BrowserError::NetworkError)?;
+    let name = file_name(load.url(), response);
+    let total = response.headers.get("content-length").and_then(|v| v.trim().parse().ok());
+    let url = load.url().to_string();
+
+    let mut downloads = DOWNLOADS.lock();
+    let path = free_path(&dir, &name, &downloads.list);
+    let created = create_dirs(&dir).and_then(|()| fs::write_file(&path, &received));
+    if let Err(e) = created {
+        warn!("browser", "Cannot save {}: {:?}", path, e);
+        return Err(BrowserError::Unknown);
+    }
+    info!("browser", "Downloading {} to {}", url, path);
+
+    let id = downloads.next_id;
+    downloads.next_id += 1;
+    downloads.list.push(Download { id, url, path, received: received.len(), total, state: State::Active });
+    downloads.loads.push((id, load));
+    downloads.generation += 1;
+    drop(downloads);
+    // Everything may already be in
+    poll();
+    Ok(id)
+}
+
+/// Write what has arrived for each download to its file; never waits
+pub fn poll() {
+    let mut downloads = DOWNLOADS.lock();
+    if downloads.loads.is_empty() {
+        return;
+    }
+    let Downloads { list, loads, generation, .. } = &mut *downloads;
+    loads.retain_mut(|(id, load)| {
+        let download = match list.iter_mut().find(|d| d.id == *id) {
+            Some(download) => download,
+            None => return false,
+        };
+        let saved = load.poll().and_then(|chunk| {
+            if chunk.is_empty() {
+                return Ok(false);
+            }
+            fs::append_file(&download.path, &chunk).map_err(|_| BrowserError::Unknown)?;
+            download.received += chunk.len();
+            Ok(true)
+        });
+        match saved {
+            Ok(changed) if !load.finished() => {
+                *generation += changed as u32;
+                true
+            }
+            Ok(_) => {
+                download.state = State::Done;
+                *generation += 1;
+                info!("browser", "Downloaded {} ({} bytes)", download.path, download.received);
+                notifications::notify("Download complete", &format!("Saved {}", download.path), 'v', 5);
+                false
+            }
+            Err(e) => {
+                download.state = State::Failed;
+                *generation += 1;
+                warn!("browser", "Download of {} failed: {:?}", download.url, e);
+                notifications::notify("Download failed", &format!("{} could not be saved", download.name()), '!', 5);
+                false
+            }
+        }
+    });
+}
+
+/// Every download this session, oldest first
+pub fn list() -> Vec<Download> {
+    DOWNLOADS.lock().list.clone()
+}
+
+/// A number that changes whenever a download starts, makes progress or
+/// ends, so the browser knows when to show it again
+pub fn generation() -> u32 {
+    DOWNLOADS.lock().generation
+}
+
+/// Forget the downloads that have ended; their files stay
+pub fn clear() {
+    let mut downloads = DOWNLOADS.lock();
+    downloads.list.retain(|d| d.state == State::Active);
+    downloads.generation += 1;
+}
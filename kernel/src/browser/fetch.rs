@@ -0,0 +1,373 @@
+//! Sending requests
+//!
+//! Every request the browser makes goes through `send`, which follows
+//! redirects itself so that it can vet each address, and keeps the cookie
+//! jar: cookies go with the requests they match, where the caller allows
+//! it, and the ones responses set are stored. A page the browser navigates
+//! to is fetched by a `Load` instead, which does the same but hands over
+//! the page as it arrives.
+//!
+//! Requests a page's scripts make go through `script_request`, which
+//! holds them to the same-origin policy. A page's origin is the scheme,
+//! host and port of its address.
+//!
+//! - A page loaded over HTTPS may not request anything over plain HTTP.
+//! - A cross-origin request carries an `Origin` header, and the script
+//!   sees the response only if `Access-Control-Allow-Origin` allows the
+//!   page's origin. A request that a form could not have made is first
+//!   put to the server as an `OPTIONS` preflight.
+//! - Cookies go cross-origin only if the script asks for that and the
+//!   server allows credentials.
+//! - Scripts never see `Set-Cookie`, nor, cross-origin, headers the
+//!   server does not expose.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::js::net::{self as script, Credentials};
+use super::{cookies, BrowserError, Url};
+use crate::net::http::{self, Method, Request, Response};
+use crate::warn;
+
+/// Most redirects followed for one request
+const MAX_REDIRECTS: usize = 10;
+
+/// Request headers only the browser sets
+const FORBIDDEN_HEADERS: &[&str] = &[
+    "accept-charset", "accept-encoding", "access-control-request-headers", "access-control-request-method",
+    "connection", "content-length", "cookie", "cookie2", "date", "dnt", "expect", "host", "keep-alive",
+    "origin", "referer", "te", "trailer", "transfer-encoding", "upgrade", "user-agent", "via",
+];
+
+/// Request headers a cross-origin request may carry without a preflight
+const SAFELISTED_REQUEST_HEADERS: &[&str] = &["accept", "accept-language", "content-language", "content-type"];
+
+/// Content types a cross-origin request may send without a preflight:
+/// those a form can send
+const FORM_CONTENT_TYPES: &[&str] = &["application/x-www-form-urlencoded", "multipart/form-data", "text/plain"];
+
+/// Headers of a cross-origin response a script sees without the server
+/// exposing them
+const SAFELISTED_RESPONSE_HEADERS: &[&str] = &[
+    "cache-control", "content-language", "content-length", "content-type", "expires", "last-modified", "pragma",
+];
+
+fn parse_method(name: &str) -> Option<Method> {
+    match name {
+        "GET" => Some(Method::Get),
+        "POST" => Some(Method::Post),
+        "PUT" => Some(Method::Put),
+        "DELETE" => Some(Method::Delete),
+        "HEAD" => Some(Method::Head),
+        "OPTIONS" => Some(Method::Options),
+        "PATCH" => Some(Method::Patch),
+        _ => None,
+    }
+}
+
+/// A request as it goes from one address to the next
+struct Outgoing {
+    method: Method,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Outgoing {
+    fn new(method: Method, url: &Url, headers: Vec<(String, String)>, body: Vec<u8>) -> Self {
+        let mut url = url.clone();
+        url.fragment.clear();
+        Self { method, url, headers, body }
+    }
+
+    /// The request to the current address
+    fn request(&self, with_cookies: bool) -> Result<Request, BrowserError> {
+        let mut request = Request::get(&self.url.to_string()).map_err(|_| BrowserError::InvalidUrl)?;
+        request.method = self.method;
+        request.body = self.body.clone();
+        for (name, value) in &self.headers {
+            request.header(name, value);
+        }
+        if with_cookies {
+            if let Some(cookie) = cookies::header(&self.url, false) {
+                request.header("Cookie", &cookie);
+            }
+        }
+        Ok(request)
+    }
+
+    /// Keep the cookies `response` sets, and if it is a redirect, move on
+    /// to where it leads; returns whether it was
+    fn follow(&mut self, response: &Response, with_cookies: bool) -> Result<bool, BrowserError> {
+        if with_cookies {
+            if let Some(lines) = response.headers.get("set-cookie") {
+                for line in lines.lines() {
+                    cookies::store(&self.url, line, false);
+                }
+            }
+        }
+        let location = match response.headers.get("location") {
+            Some(location) if matches!(response.status, 301 | 302 | 303 | 307 | 308) => location,
+            _ => return Ok(false),
+        };
+        // A 303, or a 301 or 302 answering a POST, sends the browser to
+        // the new address with a GET
+        let to_get = match response.status {
+            303 => self.method != Method::Head,
+            301 | 302 => self.method == Method::Post,
+            _ => false,
+        };
+        if to_get {
+            self.method = Method::Get;
+            self.body.clear();
+            self.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-type"));
+        }
+        self.url = self.url.join(location)?;
+        self.url.fragment.clear();
+        Ok(true)
+    }
+}
+
+/// Send a request to `url`, following redirects
+///
+/// `check` vets each address the request goes to, and says whether
+/// cookies go with the request there. Returns where the response came
+/// from, and the response.
+pub fn send(
+    method: Method,
+    url: &Url,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    mut check: impl FnMut(&Url) -> Result<bool, BrowserError>,
+) -> Result<(Url, Response), BrowserError> {
+    let mut outgoing = Outgoing::new(method, url, headers, body);
+    for _ in 0..=MAX_REDIRECTS {
+        let with_cookies = check(&outgoing.url)?;
+        let response = http::request_once(&outgoing.request(with_cookies)?).map_err(|_| BrowserError::NetworkError)?;
+        if !outgoing.follow(&response, with_cookies)? {
+            return Ok((outgoing.url, response));
+        }
+    }
+    warn!("browser", "Too many redirects from {}", outgoing.url);
+    Err(BrowserError::NetworkError)
+}
+
+/// A page being fetched, its body taken a piece at a time as it arrives
+///
+/// Redirects are followed and cookies kept as `send` does.
+pub struct Load {
+    outgoing: Outgoing,
+    stream: http::Stream,
+    redirects: usize,
+    /// The final response's headers have arrived and it is a success
+    ready: bool,
+}
+
+impl Load {
+    /// Start fetching the page at `url`
+    pub fn start(url: &Url) -> Result<Self, BrowserError> {
+        let outgoing = Outgoing::new(Method::Get, url, Vec::new(), Vec::new());
+        let stream = http::Stream::open(&outgoing.request(true)?).map_err(|_| BrowserError::NetworkError)?;
+        Ok(Self { outgoing, stream, redirects: 0, ready: false })
+    }
+
+    /// Where the page comes from, as far as the redirects so far go
+    pub fn url(&self) -> &Url {
+        &self.outgoing.url
+    }
+
+    /// Take in what has arrived, returning the part of the page's body
+    /// that came since the last call; never waits
+    ///
+    /// Fails if the server cannot be reached or answers with an error.
+    pub fn poll(&mut self) -> Result<Vec<u8>, BrowserError> {
+        self.stream.poll().map_err(|_| BrowserError::NetworkError)?;
+        if !self.ready {
+            let head = match self.stream.head() {
+                Some(head) => head,
+                None => return Ok(Vec::new()),
+            };
+            let status = head.status;
+            if self.outgoing.follow(head, true)? {
+                self.redirects += 1;
+                if self.redirects > MAX_REDIRECTS {
+                    warn!("browser", "Too many redirects from {}", self.outgoing.url);
+                    return Err(BrowserError::NetworkError);
+                }
+                let request = self.outgoing.request(true)?;
+                self.stream = http::Stream::open(&request).map_err(|_| BrowserError::NetworkError)?;
+                return Ok(Vec::new());
+            }
+            super::check_status(status)?;
+            self.ready = true;
+        }
+        Ok(self.stream.take_body())
+    }
+
+    /// The response the page comes in, once its headers have arrived and
+    /// it is not a redirect
+    pub fn response(&self) -> Option<&Response> {
+        self.stream.head().filter(|_| self.ready)
+    }
+
+    /// Whether the whole page has arrived
+    pub fn finished(&self) -> bool {
+        self.ready && self.stream.finished()
+    }
+}
+
+/// Whether the script of page `page` may send a request to `url`
+fn allowed(page: &Url, url: &Url) -> Result<(), BrowserError> {
+    if url.scheme != "http" && url.scheme != "https" {
+        warn!("browser", "Scripts cannot request {}", url);
+        return Err(BrowserError::UnsupportedProtocol);
+    }
+    if page.scheme == "https" && url.scheme == "http" {
+        warn!("browser", "Mixed content: blocked request to {} from secure page {}", url, page.origin());
+        return Err(BrowserError::NetworkError);
+    }
+    Ok(())
+}
+
+/// The items of a comma-separated header, in lower case
+fn header_list(response: &Response, name: &str) -> Vec<String> {
+    response.headers.get(name).map_or_else(Vec::new, |value| {
+        value.split(',').map(|item| item.trim().to_ascii_lowercase()).filter(|item| !item.is_empty()).collect()
+    })
+}
+
+/// Whether a cross-origin response lets a script of `origin` see it
+fn cors_allows(response: &Response, origin: &str, credentials: bool) -> bool {
+    let allow_credentials = || response.headers.get("access-control-allow-credentials").map_or(false, |v| v.trim() == "true");
+    match response.headers.get("access-control-allow-origin").map(|v| v.trim()) {
+        Some("*") => !credentials,
+        Some(allowed) => allowed == origin && (!credentials || allow_credentials()),
+        None => false,
+    }
+}
+
+/// Whether a cross-origin request may carry a header without a preflight
+fn safelisted(name: &str, value: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name == "content-type" {
+        let essence = value.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        FORM_CONTENT_TYPES.contains(&essence.as_str())
+    } else {
+        name == "origin" || SAFELISTED_REQUEST_HEADERS.contains(&name.as_str())
+    }
+}
+
+/// Whether a form could have made the request, so it needs no preflight
+fn form_like(method: Method, headers: &[(String, String)]) -> bool {
+    matches!(method, Method::Get | Method::Head | Method::Post) && headers.iter().all(|(name, value)| safelisted(name, value))
+}
+
+/// Ask the server whether the page's script may make a request that is
+/// not form-like
+fn preflight(page: &Url, url: &Url, method: Method, headers: &[(String, String)], credentials: bool) -> Result<(), BrowserError> {
+    let origin = page.origin();
+    let mut names: Vec<String> = headers.iter()
+        .filter(|(name, value)| !safelisted(name, value))
+        .map(|(name, _)| name.to_ascii_lowercase())
+        .collect();
+    names.sort();
+    names.dedup();
+    let mut asked = alloc::vec![
+        (String::from("Origin"), origin.clone()),
+        (String::from("Access-Control-Request-Method"), String::from(method.as_str())),
+    ];
+    if !names.is_empty() {
+        asked.push((String::from("Access-Control-Request-Headers"), names.join(",")));
+    }
+    // A preflight goes without cookies, and may not be redirected
+    let (_, response) = send(Method::Options, url, asked, Vec::new(), |hop| {
+        allowed(page, hop)?;
+        if hop.same_document(url) { Ok(false) } else { Err(BrowserError::NetworkError) }
+    })?;
+    let methods = header_list(&response, "access-control-allow-methods");
+    let allowed_headers = header_list(&response, "access-control-allow-headers");
+    let method_ok = matches!(method, Method::Get | Method::Head | Method::Post)
+        || methods.iter().any(|m| m == "*" || m.eq_ignore_ascii_case(method.as_str()));
+    let headers_ok = names.iter().all(|name| allowed_headers.iter().any(|h| h == "*" || h == name));
+    if (200..300).contains(&response.status) && cors_allows(&response, &origin, credentials) && method_ok && headers_ok {
+        Ok(())
+    } else {
+        warn!("browser", "Blocked {} {} from {}: the preflight was refused", method.as_str(), url, origin);
+        Err(BrowserError::NetworkError)
+    }
+}
+
+/// The headers of `response` a script may see
+fn visible_headers(response: &Response, cross_origin: bool) -> Vec<(String, String)> {
+    let exposed = header_list(response, "access-control-expose-headers");
+    let mut visible = Vec::new();
+    for (name, value) in &response.headers {
+        if name == "set-cookie" || name == "set-cookie2" {
+            continue;
+        }
+        let shown = !cross_origin
+            || SAFELISTED_RESPONSE_HEADERS.contains(&name.as_str())
+            || exposed.iter().any(|e| e == "*" || e == name);
+        if shown {
+            visible.push((name.clone(), value.clone()));
+        }
+    }
+    visible
+}
+
+/// Send a request the script of the page at `page` made, giving back
+/// what the page may see of the response
+///
+/// A request that breaks the policy fails just as one that cannot reach
+/// its server does; why is logged.
+pub fn script_request(page: &str, request: &script::Request) -> Result<script::Response, BrowserError> {
+    let page = Url::parse(page)?;
+    let url = page.join(&request.url)?;
+    let method = match parse_method(&request.method) {
+        Some(method) => method,
+        None => {
+            warn!("browser", "Scripts cannot send {} requests", request.method);
+            return Err(BrowserError::NetworkError);
+        }
+    };
+    allowed(&page, &url)?;
+    let origin = page.origin();
+    let mut headers: Vec<(String, String)> = request.headers.iter()
+        .filter(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !FORBIDDEN_HEADERS.contains(&name.as_str()) && !name.starts_with("proxy-") && !name.starts_with("sec-")
+        })
+        .cloned()
+        .collect();
+    let include = request.credentials == Credentials::Include;
+    if !page.same_origin(&url) {
+        headers.push((String::from("Origin"), origin.clone()));
+        if !form_like(method, &headers) {
+            preflight(&page, &url, method, &headers, include)?;
+        }
+    }
+
+    // Once a redirect has taken the request to another origin, it stays
+    // cross-origin
+    let mut cross_origin = false;
+    let (final_url, response) = send(method, &url, headers, request.body.clone(), |hop| {
+        allowed(&page, hop)?;
+        cross_origin |= !page.same_origin(hop);
+        Ok(match request.credentials {
+            Credentials::Omit => false,
+            Credentials::SameOrigin => !cross_origin,
+            Credentials::Include => true,
+        })
+    })?;
+    if cross_origin && !cors_allows(&response, &origin, include) {
+        warn!("browser", "Blocked response from {} to a script of {}: not allowed by CORS", final_url, origin);
+        return Err(BrowserError::NetworkError);
+    }
+    Ok(script::Response {
+        url: final_url.to_string(),
+        status: response.status,
+        status_text: response.status_text.clone(),
+        headers: visible_headers(&response, cross_origin),
+        body: response.body,
+    })
+}
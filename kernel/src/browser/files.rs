@@ -0,0 +1,348 @@
+//! file: pages
+//!
+//! What the browser shows for a `file://` URL, read through the VFS. HTML
+//! documents are shown as they are; Markdown is turned into HTML, other
+//! text is shown as it is in a plain viewer, and a directory gets an
+//! index page linking to what is in it. Anything else is described
+//! rather than shown.
+//!
+//! Paths in URLs may have `%XX` escapes, which the index pages use for
+//! names with spaces, `%`, `?` or `#` in them.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::BrowserError;
+use crate::fs::{self, FileType, FsError};
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 16px; } \
+    pre { font-family: monospace; } td { padding: 2px 16px 2px 0; } \
+    pre.code { background: #f0f0f0; padding: 8px; } blockquote { color: #555555; margin-left: 16px; }";
+
+fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// `path` with its `%XX` escapes decoded
+fn decode_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| core::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// `path` with the characters a URL path cannot carry escaped
+fn encode_path(path: &str) -> String {
+    let mut out = String::new();
+    for c in path.chars() {
+        match c {
+            ' ' | '%' | '?' | '#' | '"' => out.push_str(&format!("%{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn page_start(title: &str) -> String {
+    let mut html = String::from("<!DOCTYPE html><html><head><title>");
+    escape(title, &mut html);
+    html.push_str(&format!("</title><style>{}</style></head><body>", STYLE));
+    html
+}
+
+/// `bytes` as a size for people to read
+fn size_text(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} bytes", bytes),
+        1024..=1048575 => format!("{} KB", bytes.div_ceil(1024)),
+        _ => format!("{}.{} MB", bytes / 1048576, bytes % 1048576 * 10 / 1048576),
+    }
+}
+
+/// An index page for directory `path`: its parent, then its directories,
+/// then its files, each by name
+fn directory(path: &str) -> Result<String, BrowserError> {
+    let mut entries = fs::read_dir(path).map_err(|_| BrowserError::NotFound)?;
+    entries.sort_by(|a, b| {
+        let dirs_first = (b.metadata.file_type == FileType::Directory).cmp(&(a.metadata.file_type == FileType::Directory));
+        dirs_first.then_with(|| a.name.cmp(&b.name))
+    });
+    let dir = path.trim_end_matches('/');
+    let mut html = page_start(&format!("Index of {}", if dir.is_empty() { "/" } else { dir }));
+    html.push_str("<h1>Index of ");
+    escape(if dir.is_empty() { "/" } else { dir }, &mut html);
+    html.push_str("</h1><table>");
+    if let Some((parent, _)) = dir.rsplit_once('/') {
+        let parent = if parent.is_empty() { "/" } else { parent };
+        html.push_str(&format!("<tr><td><a href=\"file://{}\">..</a></td><td></td></tr>", encode_path(parent)));
+    }
+    for entry in &entries {
+        let is_dir = entry.metadata.file_type == FileType::Directory;
+        html.push_str(&format!("<tr><td><a href=\"file://{}\">", encode_path(&format!("{}/{}", dir, entry.name))));
+        escape(&entry.name, &mut html);
+        if is_dir {
+            html.push('/');
+        }
+        html.push_str("</a></td><td>");
+        if !is_dir {
+            html.push_str(&size_text(entry.metadata.size));
+        }
+        html.push_str("</td></tr>");
+    }
+    html.push_str("</table>");
+    if entries.is_empty() {
+        html.push_str("<p>This directory is empty.</p>");
+    }
+    html.push_str("</body></html>");
+    Ok(html)
+}
+
+/// Text with `**strong**`, `*emphasis*`, `` `code` ``, links and images
+/// as HTML
+fn inline(text: &str, out: &mut String) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    // Where `marker` next comes after `from`, with something before it
+    let closing = |from: usize, marker: &str| -> Option<usize> {
+        let marker: Vec<char> = marker.chars().collect();
+        (from..chars.len()).find(|&j| chars[j..].starts_with(&marker)).filter(|&j| j > from)
+    };
+    let string = |from: usize, to: usize| -> String { chars[from..to].iter().collect() };
+    while i < chars.len() {
+        let rest = &chars[i..];
+        // Underscores inside a word, as in snake_case, are just underscores
+        let in_word = rest[0] == '_' && i > 0 && chars[i - 1].is_alphanumeric();
+        if in_word {
+            out.push('_');
+            i += 1;
+            continue;
+        }
+        if rest.starts_with(&['*', '*']) || rest.starts_with(&['_', '_']) {
+            let marker = if rest[0] == '*' { "**" } else { "__" };
+            if let Some(end) = closing(i + 2, marker) {
+                out.push_str("<strong>");
+                inline(&string(i + 2, end), out);
+                out.push_str("</strong>");
+                i = end + 2;
+                continue;
+            }
+        } else if rest[0] == '*' || rest[0] == '_' {
+            let marker = if rest[0] == '*' { "*" } else { "_" };
+            if let Some(end) = closing(i + 1, marker) {
+                out.push_str("<em>");
+                inline(&string(i + 1, end), out);
+                out.push_str("</em>");
+                i = end + 1;
+                continue;
+            }
+        } else if rest[0] == '`' {
+            if let Some(end) = closing(i + 1, "`") {
+                out.push_str("<code>");
+                escape(&string(i + 1, end), out);
+                out.push_str("</code>");
+                i = end + 1;
+                continue;
+            }
+        } else if rest[0] == '[' || rest.starts_with(&['!', '[']) {
+            let image = rest[0] == '!';
+            let open = if image { i + 2 } else { i + 1 };
+            let link = closing(open, "](").and_then(|label_end| Some((label_end, closing(label_end + 2, ")")?)));
+            if let Some((label_end, end)) = link {
+                let (label, target) = (string(open, label_end), string(label_end + 2, end));
+                let mut href = String::new();
+                escape(target.trim(), &mut href);
+                if image {
+                    out.push_str(&format!("<img src=\"{}\" alt=\"", href));
+                    escape(&label, out);
+                    out.push_str("\">");
+                } else {
+                    out.push_str(&format!("<a href=\"{}\">", href));
+                    inline(&label, out);
+                    out.push_str("</a>");
+                }
+                i = end + 1;
+                continue;
+            }
+        }
+        escape(&string(i, i + 1), out);
+        i += 1;
+    }
+}
+
+/// What a line of Markdown starts, as far as blocks go
+enum Line<'a> {
+    Blank,
+    Heading(usize, &'a str),
+    Fence,
+    Rule,
+    Quote(&'a str),
+    Bullet(&'a str),
+    Numbered(&'a str),
+    Text(&'a str),
+}
+
+fn classify(line: &str) -> Line<'_> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return Line::Blank;
+    }
+    if trimmed.starts_with("```") {
+        return Line::Fence;
+    }
+    let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+    if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+        return Line::Heading(hashes, trimmed[hashes..].trim().trim_end_matches('#').trim_end());
+    }
+    if trimmed.len() >= 3 && ["-", "*", "_"].iter().any(|m| trimmed.chars().all(|c| c == ' ' || m.starts_with(c))) {
+        return Line::Rule;
+    }
+    if let Some(quoted) = trimmed.strip_prefix('>') {
+        return Line::Quote(quoted.trim_start());
+    }
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = trimmed.strip_prefix(bullet) {
+            return Line::Bullet(item);
+        }
+    }
+    let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 && trimmed[digits..].starts_with(". ") {
+        return Line::Numbered(&trimmed[digits + 2..]);
+    }
+    Line::Text(trimmed)
+}
+
+/// Markdown as the body of an HTML page
+fn markdown(text: &str, out: &mut String) {
+    let mut lines = text.lines().peekable();
+    // The list being written, `ul` or `ol`
+    let mut list: Option<&str> = None;
+    let mut paragraph: Vec<&str> = Vec::new();
+    let flush = |paragraph: &mut Vec<&str>, out: &mut String| {
+        if !paragraph.is_empty() {
+            out.push_str("<p>");
+            inline(&paragraph.join(" "), out);
+            out.push_str("</p>");
+            paragraph.clear();
+        }
+    };
+    while let Some(line) = lines.next() {
+        let kind = classify(line);
+        let list_item = match kind {
+            Line::Bullet(_) => Some("ul"),
+            Line::Numbered(_) => Some("ol"),
+            _ => None,
+        };
+        if !matches!(kind, Line::Text(_)) {
+            flush(&mut paragraph, out);
+        }
+        if list != list_item && !matches!(kind, Line::Text(_) if list.is_some()) {
+            if let Some(tag) = list.take() {
+                out.push_str(&format!("</{}>", tag));
+            }
+            if let Some(tag) = list_item {
+                out.push_str(&format!("<{}>", tag));
+                list = Some(tag);
+            }
+        }
+        match kind {
+            Line::Blank => {}
+            Line::Heading(level, text) => {
+                out.push_str(&format!("<h{}>", level));
+                inline(text, out);
+                out.push_str(&format!("</h{}>", level));
+            }
+            Line::Fence => {
+                out.push_str("<pre class=\"code\">");
+                for code in lines.by_ref().take_while(|l| !l.trim_start().starts_with("```")) {
+                    escape(code, out);
+                    out.push('\n');
+                }
+                out.push_str("</pre>");
+            }
+            Line::Rule => out.push_str("<hr>"),
+            Line::Quote(text) => {
+                out.push_str("<blockquote>");
+                inline(text, out);
+                out.push_str("</blockquote>");
+            }
+            Line::Bullet(text) | Line::Numbered(text) => {
+                out.push_str("<li>");
+                inline(text, out);
+                out.push_str("</li>");
+            }
+            // A line after a list item carries the item on
+            Line::Text(text) if list.is_some() => {
+                if out.ends_with("</li>") {
+                    out.truncate(out.len() - 5);
+                }
+                out.push(' ');
+                inline(text, out);
+                out.push_str("</li>");
+            }
+            Line::Text(text) => paragraph.push(text),
+        }
+    }
+    flush(&mut paragraph, out);
+    if let Some(tag) = list {
+        out.push_str(&format!("</{}>", tag));
+    }
+}
+
+/// Whether `data` reads as text rather than binary
+fn is_text(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(4096)];
+    // The sample may end part way through a character
+    !sample.contains(&0) && core::str::from_utf8(sample).map_or_else(|e| e.error_len().is_none(), |_| true)
+}
+
+/// The page for the file or directory at `path`, from a `file://` URL
+pub fn page(path: &str) -> Result<Vec<u8>, BrowserError> {
+    let path = decode_path(path);
+    let data = match fs::read_file(&path) {
+        Ok(data) => data,
+        Err(FsError::IsDirectory) => return directory(&path).map(String::into_bytes),
+        Err(_) => return Err(BrowserError::NotFound),
+    };
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).unwrap_or_default();
+    if matches!(extension.as_str(), "html" | "htm") {
+        return Ok(data);
+    }
+
+    let mut html = page_start(name);
+    if !is_text(&data) {
+        html.push_str("<h1>");
+        escape(name, &mut html);
+        html.push_str(&format!("</h1><p>This file is {} of binary data, which cannot be shown.</p>", size_text(data.len() as u64)));
+    } else if matches!(extension.as_str(), "md" | "markdown") {
+        markdown(&String::from_utf8_lossy(&data), &mut html);
+    } else {
+        html.push_str("<pre>");
+        escape(&String::from_utf8_lossy(&data), &mut html);
+        html.push_str("</pre>");
+    }
+    html.push_str("</body></html>");
+    Ok(html.into_bytes())
+}
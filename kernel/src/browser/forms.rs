@@ -0,0 +1,557 @@
+//! HTML Forms
+//!
+//! Form controls are numbered in document order, the numbering the layout
+//! engine gives their boxes, so a click or key on a box finds its element.
+//! What the user or a script has typed, ticked or picked is kept in a
+//! `FormState` beside the document, by element, rather than written into
+//! it, which leaves the values in the markup as the defaults a reset goes
+//! back to.
+//!
+//! Submitting a form builds its entry list as the HTML standard does and
+//! encodes it as `application/x-www-form-urlencoded`, for a GET query or a
+//! POST body, as `multipart/form-data`, or as `text/plain`.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::browser::html::{Document, Element, Node};
+
+/// What kind of control an element is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlKind {
+    /// A one-line text field: `text`, `search`, `email`, `number` and the
+    /// other typed inputs
+    Text,
+    Password,
+    Checkbox,
+    Radio,
+    Hidden,
+    /// A button that submits its form
+    Submit,
+    /// A button that resets its form
+    Reset,
+    /// A button that does nothing by itself
+    Button,
+    TextArea,
+    Select,
+}
+
+impl ControlKind {
+    /// The kind of `element`, if it is a form control
+    pub fn of(element: &Element) -> Option<Self> {
+        let kind = || element.get_attr("type").unwrap_or("").trim().to_ascii_lowercase();
+        match element.tag.as_str() {
+            "input" => Some(match kind().as_str() {
+                "password" => Self::Password,
+                "checkbox" => Self::Checkbox,
+                "radio" => Self::Radio,
+                "hidden" => Self::Hidden,
+                "submit" | "image" => Self::Submit,
+                "reset" => Self::Reset,
+                "button" => Self::Button,
+                _ => Self::Text,
+            }),
+            "button" => Some(match kind().as_str() {
+                "reset" => Self::Reset,
+                "button" => Self::Button,
+                _ => Self::Submit,
+            }),
+            "textarea" => Some(Self::TextArea),
+            "select" => Some(Self::Select),
+            _ => None,
+        }
+    }
+
+    /// Whether the control takes typed text
+    pub fn is_text(self) -> bool {
+        matches!(self, Self::Text | Self::Password | Self::TextArea)
+    }
+
+    fn is_button(self) -> bool {
+        matches!(self, Self::Submit | Self::Reset | Self::Button)
+    }
+}
+
+/// A form control and the form it belongs to
+pub struct Control<'a> {
+    pub element: &'a Element,
+    pub kind: ControlKind,
+    /// Index in `Controls::forms` of the form the control is inside
+    pub form: Option<usize>,
+}
+
+impl Control<'_> {
+    fn name(&self) -> Option<&str> {
+        self.element.get_attr("name").filter(|n| !n.is_empty())
+    }
+
+    fn disabled(&self) -> bool {
+        self.element.get_attr("disabled").is_some()
+    }
+}
+
+/// The forms and form controls of a document, in document order
+pub struct Controls<'a> {
+    pub forms: Vec<&'a Element>,
+    pub controls: Vec<Control<'a>>,
+}
+
+impl<'a> Controls<'a> {
+    pub fn new(document: &'a Document) -> Self {
+        let mut found = Controls { forms: Vec::new(), controls: Vec::new() };
+        collect(&document.root, None, &mut found);
+        found
+    }
+
+    /// Control number `index`
+    pub fn get(&self, index: u16) -> Option<&Control<'a>> {
+        self.controls.get(index as usize)
+    }
+
+    /// Numbers of the controls in the same form as `index`, itself included
+    fn form_of(&self, index: u16) -> Vec<u16> {
+        let form = match self.get(index) {
+            Some(control) => control.form,
+            None => return Vec::new(),
+        };
+        (0..self.controls.len() as u16).filter(|&i| self.controls[i as usize].form == form).collect()
+    }
+
+    /// The control after `from` that can take the focus, wrapping around
+    /// to the first
+    pub fn next_focus(&self, from: Option<u16>) -> Option<u16> {
+        let count = self.controls.len();
+        let start = from.map_or(0, |i| i as usize + 1);
+        (0..count)
+            .map(|n| (start + n) % count)
+            .find(|&i| self.controls[i].kind != ControlKind::Hidden && !self.controls[i].disabled())
+            .map(|i| i as u16)
+    }
+}
+
+fn collect<'a>(element: &'a Element, form: Option<usize>, found: &mut Controls<'a>) {
+    let mut form = form;
+    if element.tag == "form" {
+        found.forms.push(element);
+        form = Some(found.forms.len() - 1);
+    }
+    if let Some(kind) = ControlKind::of(element) {
+        found.controls.push(Control { element, kind, form });
+        // What a list or text area holds is its value, not more controls
+        if matches!(kind, ControlKind::Select | ControlKind::TextArea) {
+            return;
+        }
+    }
+    for child in &element.children {
+        if let Node::Element(elem) = child {
+            collect(elem, form, found);
+        }
+    }
+}
+
+/// Number of form controls in `element` and its descendants, counted as
+/// `Controls` counts them
+pub fn count_controls(element: &Element) -> u16 {
+    let own = ControlKind::of(element);
+    if matches!(own, Some(ControlKind::Select) | Some(ControlKind::TextArea)) {
+        return 1;
+    }
+    let nested = element.children.iter().fold(0u16, |count, child| match child {
+        Node::Element(elem) => count.saturating_add(count_controls(elem)),
+        _ => count,
+    });
+    nested.saturating_add(own.is_some() as u16)
+}
+
+/// The `option` elements of a `select`, including those in groups
+pub fn options(select: &Element) -> Vec<&Element> {
+    fn walk<'a>(element: &'a Element, out: &mut Vec<&'a Element>) {
+        for child in &element.children {
+            if let Node::Element(elem) = child {
+                if elem.tag == "option" {
+                    out.push(elem);
+                } else {
+                    walk(elem, out);
+                }
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk(select, &mut out);
+    out
+}
+
+/// What an option submits: its `value`, or else its text
+pub fn option_value(option: &Element) -> String {
+    match option.get_attr("value") {
+        Some(value) => String::from(value),
+        None => option.text_content().split_whitespace().collect::<Vec<_>>().join(" "),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Text(String),
+    Checked(bool),
+    /// Index in the `select`'s options
+    Selected(usize),
+}
+
+/// What has been entered, by the element's node ID
+///
+/// Controls that have not been touched keep the values their markup gives.
+#[derive(Debug, Default)]
+pub struct FormState {
+    values: BTreeMap<u32, Value>,
+}
+
+impl FormState {
+    pub fn new() -> Self {
+        Self { values: BTreeMap::new() }
+    }
+
+    /// Forget everything entered, as for a new page
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Text of a text field or text area
+    pub fn text(&self, element: &Element) -> String {
+        match self.values.get(&element.node_id) {
+            Some(Value::Text(text)) => text.clone(),
+            _ if element.tag == "textarea" => element.text_content(),
+            _ => String::from(element.get_attr("value").unwrap_or("")),
+        }
+    }
+
+    /// Whether a checkbox or radio button is checked
+    pub fn checked(&self, element: &Element) -> bool {
+        match self.values.get(&element.node_id) {
+            Some(Value::Checked(checked)) => *checked,
+            _ => element.get_attr("checked").is_some(),
+        }
+    }
+
+    /// Index of the chosen option of a `select`, if it has any options
+    pub fn selected(&self, element: &Element) -> Option<usize> {
+        let options = options(element);
+        match self.values.get(&element.node_id) {
+            Some(Value::Selected(i)) if *i < options.len() => Some(*i),
+            _ if options.is_empty() => None,
+            _ => Some(options.iter().position(|o| o.get_attr("selected").is_some()).unwrap_or(0)),
+        }
+    }
+
+    /// Set the text of a text field or text area
+    pub fn set_text(&mut self, element: &Element, text: String) {
+        self.values.insert(element.node_id, Value::Text(text));
+    }
+
+    /// Check or uncheck a checkbox or radio button
+    pub fn set_checked(&mut self, element: &Element, checked: bool) {
+        self.values.insert(element.node_id, Value::Checked(checked));
+    }
+
+    /// Choose option `index` of a `select`
+    pub fn set_selected(&mut self, element: &Element, index: usize) {
+        self.values.insert(element.node_id, Value::Selected(index));
+    }
+}
+
+/// A key pressed while a control has the focus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Backspace,
+    Enter,
+    Up,
+    Down,
+    Tab,
+    Escape,
+}
+
+/// What operating a control calls for
+pub enum Outcome {
+    /// Nothing shown changed
+    Unchanged,
+    /// A value changed
+    Changed,
+    /// The control's form is to be submitted
+    Submit(Submission),
+}
+
+/// Operate control `index` as a click on it does: toggle a checkbox, pick
+/// a radio button, step a list to its next option, or press a button
+pub fn activate(controls: &Controls, state: &mut FormState, index: u16) -> Outcome {
+    let control = match controls.get(index) {
+        Some(control) if !control.disabled() => control,
+        _ => return Outcome::Unchanged,
+    };
+    match control.kind {
+        ControlKind::Checkbox => {
+            let checked = state.checked(control.element);
+            state.set_checked(control.element, !checked);
+            Outcome::Changed
+        }
+        ControlKind::Radio => {
+            // Checking a radio button unchecks the others of its group
+            for other in controls.form_of(index) {
+                let c = &controls.controls[other as usize];
+                if other != index && c.kind == ControlKind::Radio && c.name().is_some() && c.name() == control.name() {
+                    state.set_checked(c.element, false);
+                }
+            }
+            state.set_checked(control.element, true);
+            Outcome::Changed
+        }
+        ControlKind::Select => step_option(state, control.element, 1, true),
+        ControlKind::Submit => match submission(controls, state, index, Some(index)) {
+            Some(submission) => Outcome::Submit(submission),
+            None => Outcome::Unchanged,
+        },
+        ControlKind::Reset => {
+            for member in controls.form_of(index) {
+                state.values.remove(&controls.controls[member as usize].element.node_id);
+            }
+            Outcome::Changed
+        }
+        _ => Outcome::Unchanged,
+    }
+}
+
+/// Apply a key to the focused control `index`; `None` if the control has
+/// no use for it
+pub fn key(controls: &Controls, state: &mut FormState, index: u16, key: Key) -> Option<Outcome> {
+    let control = controls.get(index).filter(|c| !c.disabled())?;
+    let kind = control.kind;
+    let outcome = match key {
+        Key::Char(ch) if kind.is_text() => {
+            let mut text = state.text(control.element);
+            text.push(ch);
+            state.set_text(control.element, text);
+            Outcome::Changed
+        }
+        Key::Backspace if kind.is_text() => {
+            let mut text = state.text(control.element);
+            text.pop();
+            state.set_text(control.element, text);
+            Outcome::Changed
+        }
+        Key::Enter if kind == ControlKind::TextArea => {
+            let mut text = state.text(control.element);
+            text.push('\n');
+            state.set_text(control.element, text);
+            Outcome::Changed
+        }
+        Key::Char(' ') if kind.is_button() || matches!(kind, ControlKind::Checkbox | ControlKind::Radio) => {
+            activate(controls, state, index)
+        }
+        Key::Enter if kind.is_button() => activate(controls, state, index),
+        // Enter in a field submits its form, as its first submit button would
+        Key::Enter if kind != ControlKind::Select => {
+            let default = controls.form_of(index).into_iter().find(|&i| {
+                let c = &controls.controls[i as usize];
+                c.kind == ControlKind::Submit && !c.disabled()
+            });
+            match submission(controls, state, index, default) {
+                Some(submission) => Outcome::Submit(submission),
+                None => Outcome::Unchanged,
+            }
+        }
+        Key::Up if kind == ControlKind::Select => step_option(state, control.element, -1, false),
+        Key::Down if kind == ControlKind::Select => step_option(state, control.element, 1, false),
+        _ => return None,
+    };
+    Some(outcome)
+}
+
+/// Move a `select` `by` options, going round from the last option to the
+/// first if `wrap` is set, or stopping at either end
+fn step_option(state: &mut FormState, element: &Element, by: isize, wrap: bool) -> Outcome {
+    let count = options(element).len() as isize;
+    let current = match state.selected(element) {
+        Some(current) => current as isize,
+        None => return Outcome::Unchanged,
+    };
+    let next = if wrap { (current + by).rem_euclid(count) } else { (current + by).clamp(0, count - 1) };
+    if next == current {
+        return Outcome::Unchanged;
+    }
+    state.set_selected(element, next as usize);
+    Outcome::Changed
+}
+
+/// How a form is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// How a POST body is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enctype {
+    UrlEncoded,
+    Multipart,
+    TextPlain,
+}
+
+/// A form ready to send
+#[derive(Debug, Clone)]
+pub struct Submission {
+    /// Where to send it, as written; empty for the page itself
+    pub action: String,
+    pub method: Method,
+    pub enctype: Enctype,
+    /// Name and value of each entry, in order
+    pub entries: Vec<(String, String)>,
+}
+
+impl Submission {
+    /// The entries as a query string
+    pub fn query(&self) -> String {
+        urlencode(&self.entries)
+    }
+
+    /// The entries as a POST body, with its `Content-Type`
+    pub fn body(&self) -> (String, Vec<u8>) {
+        match self.enctype {
+            Enctype::UrlEncoded => (String::from("application/x-www-form-urlencoded"), self.query().into_bytes()),
+            Enctype::Multipart => {
+                let boundary = boundary_for(&self.entries);
+                (format!("multipart/form-data; boundary={}", boundary), multipart(&self.entries, &boundary))
+            }
+            Enctype::TextPlain => {
+                let mut body = String::new();
+                for (name, value) in &self.entries {
+                    body.push_str(&format!("{}={}\r\n", name, normalize_newlines(value)));
+                }
+                (String::from("text/plain"), body.into_bytes())
+            }
+        }
+    }
+}
+
+/// Build the submission of the form control `index` is in, sent by the
+/// button `submitter` if there is one
+///
+/// The submitter's `formaction`, `formmethod` and `formenctype` take the
+/// place of the form's own attributes. Disabled and unnamed controls,
+/// unchecked boxes and buttons other than the submitter are left out.
+pub fn submission(controls: &Controls, state: &FormState, index: u16, submitter: Option<u16>) -> Option<Submission> {
+    let form = controls.forms[controls.get(index)?.form?];
+    let sender = submitter.and_then(|i| controls.get(i));
+    let attr = |button: &str, own: &str| {
+        sender.and_then(|s| s.element.get_attr(button)).or_else(|| form.get_attr(own)).unwrap_or("").trim().to_ascii_lowercase()
+    };
+    let action = sender.and_then(|s| s.element.get_attr("formaction")).or_else(|| form.get_attr("action")).unwrap_or("");
+    let method = match attr("formmethod", "method").as_str() {
+        "post" => Method::Post,
+        _ => Method::Get,
+    };
+    let enctype = match attr("formenctype", "enctype").as_str() {
+        "multipart/form-data" => Enctype::Multipart,
+        "text/plain" => Enctype::TextPlain,
+        _ => Enctype::UrlEncoded,
+    };
+
+    let mut entries = Vec::new();
+    for i in controls.form_of(index) {
+        let control = &controls.controls[i as usize];
+        let name = match control.name() {
+            Some(name) if !control.disabled() => String::from(name),
+            _ => continue,
+        };
+        let element = control.element;
+        let value = match control.kind {
+            ControlKind::Text | ControlKind::Password | ControlKind::Hidden | ControlKind::TextArea => state.text(element),
+            ControlKind::Checkbox | ControlKind::Radio => {
+                if !state.checked(element) {
+                    continue;
+                }
+                String::from(element.get_attr("value").unwrap_or("on"))
+            }
+            ControlKind::Select => match state.selected(element) {
+                Some(selected) => option_value(options(element)[selected]),
+                None => continue,
+            },
+            ControlKind::Submit if submitter == Some(i) => String::from(element.get_attr("value").unwrap_or("")),
+            ControlKind::Submit | ControlKind::Reset | ControlKind::Button => continue,
+        };
+        entries.push((name, value));
+    }
+    Some(Submission { action: String::from(action.trim()), method, enctype, entries })
+}
+
+/// Line breaks as CR LF pairs, as the form encodings send them
+fn normalize_newlines(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\r' => {
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                out.push_str("\r\n");
+            }
+            '\n' => out.push_str("\r\n"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Encode entries as `application/x-www-form-urlencoded`
+///
+/// Letters, digits and `*-._` stand for themselves and spaces become `+`;
+/// every other byte of the UTF-8 text is percent-encoded.
+pub fn urlencode(entries: &[(String, String)]) -> String {
+    fn encode(text: &str, out: &mut String) {
+        for byte in normalize_newlines(text).bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => out.push(byte as char),
+                b' ' => out.push('+'),
+                _ => out.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+    }
+    let mut out = String::new();
+    for (i, (name, value)) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push('&');
+        }
+        encode(name, &mut out);
+        out.push('=');
+        encode(value, &mut out);
+    }
+    out
+}
+
+/// A multipart boundary that none of the entries contain
+fn boundary_for(entries: &[(String, String)]) -> String {
+    let mut boundary = String::from("----WebbOSFormBoundary");
+    let mut n = 0u32;
+    while entries.iter().any(|(name, value)| name.contains(&boundary) || value.contains(&boundary)) {
+        n += 1;
+        boundary = format!("----WebbOSFormBoundary{:08x}", n.wrapping_mul(0x9E37_79B9));
+    }
+    boundary
+}
+
+/// Encode entries as `multipart/form-data` parts separated by `boundary`
+pub fn multipart(entries: &[(String, String)], boundary: &str) -> Vec<u8> {
+    let mut body = String::new();
+    for (name, value) in entries {
+        // Quotes and line breaks in a name would end its header early
+        let name = name.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A");
+        body.push_str(&format!("--{}\r\n", boundary));
+        body.push_str(&format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name));
+        body.push_str(&normalize_newlines(value));
+        body.push_str("\r\n");
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    body.into_bytes()
+}
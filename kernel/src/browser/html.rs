@@ -1,13 +1,29 @@
 //! HTML Parser
 //!
-//! Parses HTML documents into a DOM tree.
+//! Parses HTML documents into a DOM tree, following the WHATWG parsing
+//! algorithm in two stages.
+//!
+//! The tokenizer turns the input into doctype, tag, text and comment
+//! tokens. It decodes character references, reads the contents of
+//! `script`, `style` and the other raw text elements verbatim up to their
+//! end tag, and recovers from malformed markup the way the specification
+//! says: a stray `<` is text, `</>` is dropped, `<!x>` is a comment.
+//!
+//! The tree builder feeds the tokens through the insertion modes for
+//! everything outside foreign content, templates and framesets. It adds
+//! the `html`, `head`, `body`, `tbody` and `tr` elements a page leaves
+//! out, closes a `p`, `li` or `option` when a new one starts, ignores end
+//! tags with nothing to close, moves stray content out of tables (foster
+//! parenting), reopens formatting elements such as `b` and `i` that a
+//! block closed early, and splits them when they are misnested.
 
-use alloc::string::String;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use crate::browser::BrowserError;
-use crate::println;
+use crate::info;
 
 /// HTML Document
 pub struct Document {
@@ -26,10 +42,27 @@ impl Document {
     pub fn element_count(&self) -> usize {
         self.root.count_descendants()
     }
+
+    /// Text of the first `title` element, with whitespace collapsed
+    pub fn title(&self) -> Option<String> {
+        let title = self.root.find("title")?.text_content();
+        Some(title.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    /// The `body` element
+    pub fn body(&self) -> Option<&Element> {
+        self.root.find("body")
+    }
 }
 
+/// Number the next element created gets
+static NEXT_NODE_ID: AtomicU32 = AtomicU32::new(1);
+
 /// HTML Element
 pub struct Element {
+    /// Number of the element, unique while the kernel runs; scripts and
+    /// form state refer to elements by it
+    pub node_id: u32,
     /// Tag name
     pub tag: String,
     /// Attributes
@@ -44,6 +77,7 @@ impl Element {
     /// Create new element
     pub fn new(tag: &str) -> Self {
         Self {
+            node_id: NEXT_NODE_ID.fetch_add(1, Ordering::Relaxed),
             tag: String::from(tag),
             attributes: Vec::new(),
             children: Vec::new(),
@@ -71,6 +105,34 @@ impl Element {
         }
         count
     }
+
+    /// First descendant with the given tag, in document order
+    pub fn find(&self, tag: &str) -> Option<&Element> {
+        for child in &self.children {
+            if let Node::Element(elem) = child {
+                if elem.tag == tag {
+                    return Some(elem);
+                }
+                if let Some(found) = elem.find(tag) {
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+
+    /// All text inside the element, concatenated
+    pub fn text_content(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            match child {
+                Node::Element(elem) => text.push_str(&elem.text_content()),
+                Node::Text(t) => text.push_str(t),
+                Node::Comment(_) => {}
+            }
+        }
+        text
+    }
 }
 
 /// DOM Node
@@ -100,378 +162,2336 @@ pub struct StylesheetRef {
     pub content: String,
 }
 
+/// Deepest the tree may nest; elements opened below this become siblings
+/// instead, so walking the tree cannot overrun the kernel stack
+const MAX_DEPTH: usize = 256;
+
+/// Elements whose contents are read as text up to their end tag
+const RAW_TEXT: &[&str] = &["script", "style", "xmp", "iframe", "noembed", "noframes", "noscript"];
+
+/// Like `RAW_TEXT`, but character references are decoded
+const RCDATA: &[&str] = &["title", "textarea"];
+
+/// Elements the parser treats specially, which stop searches up the stack
+/// of open elements
+const SPECIAL: &[&str] = &[
+    "address", "applet", "area", "article", "aside", "base", "basefont", "bgsound",
+    "blockquote", "body", "br", "button", "caption", "center", "col", "colgroup", "dd",
+    "details", "dir", "div", "dl", "dt", "embed", "fieldset", "figcaption", "figure",
+    "footer", "form", "frame", "frameset", "h1", "h2", "h3", "h4", "h5", "h6", "head",
+    "header", "hgroup", "hr", "html", "iframe", "img", "input", "keygen", "li", "link",
+    "listing", "main", "marquee", "menu", "meta", "nav", "noembed", "noframes", "noscript",
+    "object", "ol", "p", "param", "plaintext", "pre", "script", "search", "section",
+    "select", "source", "style", "summary", "table", "tbody", "td", "template", "textarea",
+    "tfoot", "th", "thead", "title", "tr", "track", "ul", "wbr", "xmp",
+];
+
+/// Block elements whose start tag closes an open `p`
+const BLOCKS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "center", "details", "dialog", "dir",
+    "div", "dl", "fieldset", "figcaption", "figure", "footer", "header", "hgroup", "main",
+    "menu", "nav", "ol", "p", "search", "section", "summary", "ul",
+];
+
+const HEADINGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// Formatting elements other than `a` and `nobr`
+const FORMATTING: &[&str] = &[
+    "b", "big", "code", "em", "font", "i", "s", "small", "strike", "strong", "tt", "u",
+];
+
+/// Elements closed implicitly when their parent ends
+const IMPLIED_END: &[&str] = &["dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc"];
+
+/// Elements that bound "in scope" searches
+const SCOPE: &[&str] = &["applet", "caption", "html", "table", "td", "th", "marquee", "object", "template"];
+const LIST_ITEM_SCOPE: &[&str] = &[
+    "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template", "ol", "ul",
+];
+const BUTTON_SCOPE: &[&str] = &[
+    "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template", "button",
+];
+const TABLE_SCOPE: &[&str] = &["html", "table", "template"];
+
+/// Where the stack is cleared back to before table parts are inserted
+const TABLE_CONTEXT: &[&str] = &["table", "template", "html"];
+const TABLE_BODY_CONTEXT: &[&str] = &["tbody", "tfoot", "thead", "template", "html"];
+const TABLE_ROW_CONTEXT: &[&str] = &["tr", "template", "html"];
+
+/// Table parts whose start tag ends a cell, row or caption
+const TABLE_PARTS: &[&str] = &["caption", "col", "colgroup", "tbody", "td", "tfoot", "th", "thead", "tr"];
+
+/// Whitespace as HTML defines it
+fn is_space(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
+/// Split off the whitespace a text token starts with
+fn split_space(text: &str) -> (&str, &str) {
+    let end = text.find(|c| !is_space(c)).unwrap_or(text.len());
+    text.split_at(end)
+}
+
+/// A tag as it appeared in the markup
+#[derive(Debug, Clone)]
+struct Tag {
+    name: String,
+    attributes: Vec<(String, String)>,
+    self_closing: bool,
+}
+
+impl Tag {
+    fn new(name: &str) -> Self {
+        Self { name: String::from(name), attributes: Vec::new(), self_closing: false }
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+}
+
 /// HTML Token
 #[derive(Debug, Clone)]
 enum Token {
     Doctype(String),
-    StartTag(String, Vec<(String, String)>),
+    StartTag(Tag),
     EndTag(String),
     Text(String),
     Comment(String),
-    EOF,
+    Eof,
 }
 
-/// Tokenize HTML
-struct Tokenizer<'a> {
-    input: &'a [u8],
+/// Named character references the parser knows: the Latin-1 set and the
+/// punctuation and symbols common on the web, not the full table
+const ENTITIES: &[(&str, &str)] = &[
+    ("amp", "&"), ("lt", "<"), ("gt", ">"), ("quot", "\""), ("apos", "'"),
+    ("nbsp", "\u{A0}"), ("iexcl", "¡"), ("cent", "¢"), ("pound", "£"), ("curren", "¤"),
+    ("yen", "¥"), ("brvbar", "¦"), ("sect", "§"), ("uml", "¨"), ("copy", "©"),
+    ("ordf", "ª"), ("laquo", "«"), ("not", "¬"), ("shy", "\u{AD}"), ("reg", "®"),
+    ("macr", "¯"), ("deg", "°"), ("plusmn", "±"), ("sup2", "²"), ("sup3", "³"),
+    ("acute", "´"), ("micro", "µ"), ("para", "¶"), ("middot", "·"), ("cedil", "¸"),
+    ("sup1", "¹"), ("ordm", "º"), ("raquo", "»"), ("frac14", "¼"), ("frac12", "½"),
+    ("frac34", "¾"), ("iquest", "¿"), ("times", "×"), ("divide", "÷"),
+    ("Agrave", "À"), ("Aacute", "Á"), ("Auml", "Ä"), ("Ccedil", "Ç"), ("Eacute", "É"),
+    ("Ntilde", "Ñ"), ("Ouml", "Ö"), ("Uuml", "Ü"), ("szlig", "ß"), ("agrave", "à"),
+    ("aacute", "á"), ("acirc", "â"), ("auml", "ä"), ("aring", "å"), ("ccedil", "ç"),
+    ("egrave", "è"), ("eacute", "é"), ("ecirc", "ê"), ("euml", "ë"), ("iacute", "í"),
+    ("iuml", "ï"), ("ntilde", "ñ"), ("oacute", "ó"), ("ocirc", "ô"), ("ouml", "ö"),
+    ("oslash", "ø"), ("uacute", "ú"), ("uuml", "ü"), ("yuml", "ÿ"),
+    ("ndash", "–"), ("mdash", "—"), ("lsquo", "‘"), ("rsquo", "’"), ("sbquo", "‚"),
+    ("ldquo", "“"), ("rdquo", "”"), ("bdquo", "„"), ("dagger", "†"), ("Dagger", "‡"),
+    ("bull", "•"), ("hellip", "…"), ("permil", "‰"), ("prime", "′"), ("lsaquo", "‹"),
+    ("rsaquo", "›"), ("euro", "€"), ("trade", "™"), ("larr", "←"), ("uarr", "↑"),
+    ("rarr", "→"), ("darr", "↓"), ("harr", "↔"), ("minus", "−"), ("le", "≤"),
+    ("ge", "≥"), ("ne", "≠"), ("infin", "∞"), ("check", "✓"), ("hearts", "♥"),
+    ("ensp", "\u{2002}"), ("emsp", "\u{2003}"), ("thinsp", "\u{2009}"), ("zwnj", "\u{200C}"),
+    ("zwj", "\u{200D}"), ("AMP", "&"), ("LT", "<"), ("GT", ">"), ("QUOT", "\""),
+    ("COPY", "©"), ("REG", "®"),
+];
+
+/// References that old pages write without the semicolon
+const LEGACY_ENTITIES: &[&str] = &[
+    "amp", "lt", "gt", "quot", "nbsp", "copy", "reg", "AMP", "LT", "GT", "QUOT", "COPY", "REG",
+];
+
+/// What numeric references to the C1 controls 0x80-0x9F mean, as pages
+/// written in Windows-1252 intended
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+/// Splits HTML into tokens
+struct Tokenizer {
+    input: Vec<char>,
     pos: usize,
+    /// Raw text element being read, and whether references are decoded
+    /// in it
+    raw: Option<(String, bool)>,
+    /// A `plaintext` element swallowed the rest of the input
+    plaintext: bool,
+    /// The input pushed last ended with a carriage return, so a line feed
+    /// starting the next belongs to the same line break
+    after_cr: bool,
 }
 
-impl<'a> Tokenizer<'a> {
-    fn new(input: &'a [u8]) -> Self {
-        Self { input, pos: 0 }
+/// Where a tokenizer stands, to go back to
+type Mark = (usize, Option<(String, bool)>, bool);
+
+impl Tokenizer {
+    fn new(input: &str) -> Self {
+        let mut tokenizer = Self { input: Vec::new(), pos: 0, raw: None, plaintext: false, after_cr: false };
+        tokenizer.push(input);
+        tokenizer
+    }
+
+    /// Add input to the end
+    fn push(&mut self, input: &str) {
+        // Newlines are normalized before tokenizing
+        self.input.reserve(input.len());
+        let mut source = input.chars().peekable();
+        if self.after_cr && source.peek() == Some(&'\n') {
+            source.next();
+        }
+        while let Some(c) = source.next() {
+            if c == '\r' {
+                if source.peek() == Some(&'\n') {
+                    source.next();
+                }
+                self.input.push('\n');
+            } else {
+                self.input.push(c);
+            }
+        }
+        self.after_cr = input.ends_with('\r');
+    }
+
+    /// Drop the input already read
+    fn compact(&mut self) {
+        self.input.drain(..self.pos);
+        self.pos = 0;
     }
 
-    fn peek(&self) -> Option<u8> {
+    fn mark(&self) -> Mark {
+        (self.pos, self.raw.clone(), self.plaintext)
+    }
+
+    fn reset(&mut self, mark: Mark) {
+        (self.pos, self.raw, self.plaintext) = mark;
+    }
+
+    /// Whether all the input has been read
+    fn at_end(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    fn peek(&self) -> Option<char> {
         self.input.get(self.pos).copied()
     }
 
-    fn next(&mut self) -> Option<u8> {
-        let ch = self.peek()?;
-        self.pos += 1;
-        Some(ch)
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.input.get(self.pos + offset).copied()
     }
 
-    fn consume_whitespace(&mut self) {
-        while let Some(ch) = self.peek() {
-            if !ch.is_ascii_whitespace() {
-                break;
+    /// Whether the input continues with `s`, ignoring ASCII case
+    fn at(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek_at(i).map_or(false, |d| d.eq_ignore_ascii_case(&c)))
+    }
+
+    fn rest(&mut self) -> String {
+        let rest = self.input[self.pos..].iter().collect();
+        self.pos = self.input.len();
+        rest
+    }
+
+    fn next_token(&mut self) -> Token {
+        if self.pos >= self.input.len() {
+            return Token::Eof;
+        }
+        if self.plaintext {
+            return Token::Text(self.rest());
+        }
+        if let Some((name, decode)) = self.raw.take() {
+            let text = self.raw_text(&name, decode);
+            if !text.is_empty() {
+                return Token::Text(text);
+            }
+        }
+        if self.peek() == Some('<') {
+            if let Some(token) = self.markup() {
+                return token;
             }
-            self.next();
         }
+        Token::Text(self.text())
     }
 
-    fn consume_until(&mut self, target: u8) -> String {
-        let mut result = String::new();
-        while let Some(ch) = self.peek() {
-            if ch == target {
-                break;
+    /// Text up to the next tag; a `<` that starts no tag is text too
+    fn text(&mut self) -> String {
+        let mut text = String::new();
+        if self.peek() == Some('<') {
+            text.push('<');
+            self.pos += 1;
+        }
+        while let Some(c) = self.peek() {
+            match c {
+                '<' => break,
+                '&' => {
+                    self.pos += 1;
+                    self.reference(&mut text, false);
+                }
+                '\0' => self.pos += 1,
+                _ => {
+                    text.push(c);
+                    self.pos += 1;
+                }
             }
-            result.push(ch as char);
-            self.next();
         }
-        result
+        text
     }
 
-    fn tokenize(&mut self) -> Vec<Token> {
-        let mut tokens = Vec::new();
+    /// Contents of a raw text element, up to its end tag
+    fn raw_text(&mut self, name: &str, decode: bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '<' && self.peek_at(1) == Some('/') {
+                let after = self.pos + 2;
+                let matches = name.chars().enumerate()
+                    .all(|(i, n)| self.input.get(after + i).map_or(false, |c| c.eq_ignore_ascii_case(&n)));
+                let ends = self.input.get(after + name.len()).map_or(true, |&c| is_space(c) || c == '/' || c == '>');
+                if matches && ends {
+                    break;
+                }
+            }
+            self.pos += 1;
+            match c {
+                '&' if decode => self.reference(&mut text, false),
+                '\0' => text.push('\u{FFFD}'),
+                _ => text.push(c),
+            }
+        }
+        text
+    }
 
-        while self.pos < self.input.len() {
-            if let Some(ch) = self.peek() {
-                if ch == b'<' {
-                    // Parse tag
-                    self.next(); // consume '<'
-                    
-                    if self.peek() == Some(b'!') {
-                        // Doctype or comment
-                        self.next(); // consume '!'
-                        if self.peek() == Some(b'-') && self.input.get(self.pos + 1) == Some(&b'-') {
-                            // Comment
-                            self.pos += 2; // skip '--'
-                            let comment = self.consume_until(b'-');
-                            self.pos += 2; // skip '-->'
-                            tokens.push(Token::Comment(comment));
-                        } else {
-                            // Doctype
-                            let content = self.consume_until(b'>');
-                            tokens.push(Token::Doctype(content));
-                        }
-                    } else if self.peek() == Some(b'/') {
-                        // End tag
-                        self.next(); // consume '/'
-                        let tag = self.parse_tag_name();
-                        self.consume_until(b'>');
-                        self.next(); // consume '>'
-                        tokens.push(Token::EndTag(tag));
-                    } else {
-                        // Start tag
-                        let (tag, attrs) = self.parse_start_tag();
-                        tokens.push(Token::StartTag(tag, attrs));
-                    }
+    /// A tag, comment or doctype at `<`, or `None` if the `<` is text
+    fn markup(&mut self) -> Option<Token> {
+        match self.peek_at(1) {
+            Some('!') => {
+                self.pos += 2;
+                if self.at("--") {
+                    self.pos += 2;
+                    Some(self.comment())
+                } else if self.at("doctype") {
+                    self.pos += 7;
+                    Some(self.doctype())
                 } else {
-                    // Text content
-                    let text = self.consume_until(b'<');
-                    if !text.trim().is_empty() {
-                        tokens.push(Token::Text(text));
-                    }
+                    Some(self.bogus_comment())
                 }
-            } else {
-                break;
             }
+            Some('/') => match self.peek_at(2) {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.pos += 2;
+                    // Attributes on end tags are read and thrown away
+                    let tag = self.tag();
+                    Some(tag.map_or(Token::Eof, |tag| Token::EndTag(tag.name)))
+                }
+                Some('>') => {
+                    self.pos += 3;
+                    Some(self.next_token())
+                }
+                Some(_) => {
+                    self.pos += 2;
+                    Some(self.bogus_comment())
+                }
+                None => None,
+            },
+            Some('?') => {
+                self.pos += 1;
+                Some(self.bogus_comment())
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.pos += 1;
+                let tag = match self.tag() {
+                    Some(tag) => tag,
+                    None => return Some(Token::Eof),
+                };
+                if RAW_TEXT.contains(&tag.name.as_str()) {
+                    self.raw = Some((tag.name.clone(), false));
+                } else if RCDATA.contains(&tag.name.as_str()) {
+                    self.raw = Some((tag.name.clone(), true));
+                } else if tag.name == "plaintext" {
+                    self.plaintext = true;
+                }
+                Some(Token::StartTag(tag))
+            }
+            _ => None,
         }
-
-        tokens.push(Token::EOF);
-        tokens
     }
 
-    fn parse_tag_name(&mut self) -> String {
-        self.consume_whitespace();
-        let mut name = String::new();
-        
-        while let Some(ch) = self.peek() {
-            if ch.is_ascii_alphanumeric() || ch == b'-' {
-                name.push(ch.to_ascii_lowercase() as char);
-                self.next();
-            } else {
-                break;
+    /// Comment after `<!--`
+    fn comment(&mut self) -> Token {
+        // `<!-->` and `<!--->` are empty comments
+        for close in [">", "->"] {
+            if self.at(close) {
+                self.pos += close.len();
+                return Token::Comment(String::new());
             }
         }
-        
-        name
+        let mut text = String::new();
+        while self.pos < self.input.len() {
+            for close in ["-->", "--!>"] {
+                if self.at(close) {
+                    self.pos += close.len();
+                    return Token::Comment(text);
+                }
+            }
+            text.push(self.input[self.pos]);
+            self.pos += 1;
+        }
+        Token::Comment(text)
     }
 
-    fn parse_start_tag(&mut self) -> (String, Vec<(String, String)>) {
-        let tag = self.parse_tag_name();
-        let mut attrs = Vec::new();
-
-        // Parse attributes
-        loop {
-            self.consume_whitespace();
-            
-            if self.peek() == Some(b'>') || self.peek() == Some(b'/') {
+    /// Anything else that starts with `<!`, `</` or `<?`, up to `>`
+    fn bogus_comment(&mut self) -> Token {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            if c == '>' {
                 break;
             }
-
-            let name = self.parse_attr_name();
-            let value = if self.peek() == Some(b'=') {
-                self.next(); // consume '='
-                self.parse_attr_value()
-            } else {
-                String::new()
-            };
-
-            attrs.push((name, value));
+            text.push(c);
         }
+        Token::Comment(text)
+    }
 
-        // Consume self-closing marker if present
-        if self.peek() == Some(b'/') {
-            self.next();
+    /// Doctype after `<!DOCTYPE`; only the name is kept
+    fn doctype(&mut self) -> Token {
+        while self.peek().map_or(false, is_space) {
+            self.pos += 1;
+        }
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if is_space(c) || c == '>' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase());
+            self.pos += 1;
         }
-        
-        // Consume '>'
-        if self.peek() == Some(b'>') {
-            self.next();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            if c == '>' {
+                break;
+            }
         }
+        Token::Doctype(name)
+    }
 
-        (tag, attrs)
+    /// Tag name and attributes; `None` if the input ends inside the tag
+    fn tag(&mut self) -> Option<Tag> {
+        let mut tag = Tag::new("");
+        tag.name = self.name(|c| c == '/');
+        loop {
+            while self.peek().map_or(false, is_space) {
+                self.pos += 1;
+            }
+            match self.peek()? {
+                '>' => {
+                    self.pos += 1;
+                    return Some(tag);
+                }
+                '/' => {
+                    self.pos += 1;
+                    if self.peek() == Some('>') {
+                        self.pos += 1;
+                        tag.self_closing = true;
+                        return Some(tag);
+                    }
+                }
+                _ => {
+                    // `=` may start a name, as in `<a =x>`
+                    let first = self.peek()?;
+                    self.pos += 1;
+                    let mut name = String::new();
+                    name.push(if first == '\0' { '\u{FFFD}' } else { first.to_ascii_lowercase() });
+                    name.push_str(&self.name(|c| c == '/' || c == '='));
+                    while self.peek().map_or(false, is_space) {
+                        self.pos += 1;
+                    }
+                    let mut value = String::new();
+                    if self.peek() == Some('=') {
+                        self.pos += 1;
+                        while self.peek().map_or(false, is_space) {
+                            self.pos += 1;
+                        }
+                        value = self.attribute_value()?;
+                    }
+                    // The first of a repeated attribute wins
+                    if !tag.attributes.iter().any(|(n, _)| *n == name) {
+                        tag.attributes.push((name, value));
+                    }
+                }
+            }
+        }
     }
 
-    fn parse_attr_name(&mut self) -> String {
+    /// Lowercased name up to whitespace, `>` or a character `stop` accepts
+    fn name(&mut self, stop: impl Fn(char) -> bool) -> String {
         let mut name = String::new();
-        
-        while let Some(ch) = self.peek() {
-            if ch.is_ascii_alphanumeric() || ch == b'-' || ch == b'_' || ch == b':' {
-                name.push(ch.to_ascii_lowercase() as char);
-                self.next();
-            } else {
+        while let Some(c) = self.peek() {
+            if is_space(c) || c == '>' || stop(c) {
                 break;
             }
+            name.push(if c == '\0' { '\u{FFFD}' } else { c.to_ascii_lowercase() });
+            self.pos += 1;
         }
-        
         name
     }
 
-    fn parse_attr_value(&mut self) -> String {
-        self.consume_whitespace();
-        
-        let quote = self.peek();
-        if quote == Some(b'"') || quote == Some(b'\'') {
-            self.next(); // consume quote
-            let value = self.consume_until(quote.unwrap());
-            self.next(); // consume closing quote
-            value
-        } else {
-            let mut value = String::new();
-            while let Some(ch) = self.peek() {
-                if ch.is_ascii_whitespace() || ch == b'>' || ch == b'/' {
-                    break;
+    fn attribute_value(&mut self) -> Option<String> {
+        let mut value = String::new();
+        let quote = match self.peek()? {
+            q @ ('"' | '\'') => {
+                self.pos += 1;
+                Some(q)
+            }
+            _ => None,
+        };
+        loop {
+            let c = self.peek()?;
+            match quote {
+                Some(q) if c == q => {
+                    self.pos += 1;
+                    return Some(value);
                 }
-                value.push(ch as char);
-                self.next();
+                None if is_space(c) || c == '>' => return Some(value),
+                _ => {}
+            }
+            self.pos += 1;
+            match c {
+                '&' => self.reference(&mut value, true),
+                '\0' => value.push('\u{FFFD}'),
+                _ => value.push(c),
+            }
+        }
+    }
+
+    /// Decode the character reference after an `&` into `out`; an `&`
+    /// that starts none is kept as it is
+    fn reference(&mut self, out: &mut String, in_attribute: bool) {
+        if self.peek() == Some('#') {
+            let hex = matches!(self.peek_at(1), Some('x' | 'X'));
+            let start = self.pos + if hex { 2 } else { 1 };
+            let radix = if hex { 16 } else { 10 };
+            let mut end = start;
+            let mut code: u32 = 0;
+            while let Some(digit) = self.input.get(end).and_then(|c| c.to_digit(radix)) {
+                code = code.saturating_mul(radix).saturating_add(digit);
+                end += 1;
+            }
+            if end == start {
+                out.push('&');
+                return;
+            }
+            self.pos = end;
+            if self.peek() == Some(';') {
+                self.pos += 1;
+            }
+            out.push(match code {
+                0x80..=0x9F => WINDOWS_1252[(code - 0x80) as usize],
+                0 => '\u{FFFD}',
+                _ => char::from_u32(code).unwrap_or('\u{FFFD}'),
+            });
+            return;
+        }
+
+        let mut end = self.pos;
+        while self.input.get(end).map_or(false, |c| c.is_ascii_alphanumeric()) {
+            end += 1;
+        }
+        let name: String = self.input[self.pos..end].iter().collect();
+        if self.input.get(end) == Some(&';') {
+            if let Some((_, value)) = ENTITIES.iter().find(|(n, _)| *n == name) {
+                out.push_str(value);
+                self.pos = end + 1;
+                return;
+            }
+        }
+        // Without a semicolon only the legacy names count, longest first
+        let legacy = LEGACY_ENTITIES.iter()
+            .filter(|n| name.starts_with(**n))
+            .max_by_key(|n| n.len());
+        if let Some(legacy) = legacy {
+            let next = self.input.get(self.pos + legacy.len()).copied();
+            // In attributes `&copy=1` is left alone; it is most likely a
+            // query string
+            let ambiguous = in_attribute && next.map_or(false, |c| c == '=' || c.is_ascii_alphanumeric());
+            if !ambiguous {
+                let (_, value) = ENTITIES.iter().find(|(n, _)| n == legacy).unwrap();
+                out.push_str(value);
+                self.pos += legacy.len();
+                return;
             }
-            value
         }
+        out.push('&');
     }
 }
 
+/// Insertion modes of the tree builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    AfterHead,
+    InBody,
+    InTable,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    InSelect,
+    AfterBody,
+    AfterAfterBody,
+}
+
+/// An element while the tree is being built
+struct Slot {
+    tag: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Child>,
+    parent: Option<usize>,
+}
+
+/// A child of a slot; elements are slot indices
+enum Child {
+    Element(usize),
+    Text(String),
+    Comment(String),
+}
+
+/// Entry in the list of active formatting elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Formatting {
+    /// Formatting from outside a cell, caption or object does not reach in
+    Marker,
+    Element(usize),
+}
+
 /// Build DOM from tokens
-struct DomBuilder {
-    stack: Vec<Element>,
-    scripts: Vec<Script>,
-    stylesheets: Vec<StylesheetRef>,
+///
+/// Elements live in a flat arena while the tree is built, so the stack of
+/// open elements can refer to them by index; `finish` turns the arena
+/// into the nested `Element` tree.
+struct TreeBuilder {
+    slots: Vec<Slot>,
+    /// Stack of open elements; the root `html` is at the bottom
+    stack: Vec<usize>,
+    formatting: Vec<Formatting>,
+    mode: Mode,
+    head: Option<usize>,
+    form: Option<usize>,
+    doctype: Option<String>,
+    /// Reading a raw text element; its end tag closes it whatever the mode
+    in_text: bool,
+    /// A leading newline of the next text is dropped (after `<pre>`)
+    skip_newline: bool,
+    /// Content goes before the table it appeared in
+    foster: bool,
 }
 
-impl DomBuilder {
+impl TreeBuilder {
     fn new() -> Self {
         Self {
+            slots: Vec::new(),
             stack: Vec::new(),
-            scripts: Vec::new(),
-            stylesheets: Vec::new(),
-        }
-    }
-
-    fn build(mut self, tokens: &[Token]) -> Result<Document, BrowserError> {
-        let mut doctype = None;
-
-        for token in tokens {
-            match token {
-                Token::Doctype(dt) => {
-                    doctype = Some(dt.clone());
-                }
-                Token::StartTag(tag, attrs) => {
-                    let mut elem = Element::new(tag);
-                    elem.attributes = attrs.clone();
-
-                    // Handle special elements
-                    match tag.as_str() {
-                        "script" => {
-                            // Extract script info
-                            let src = elem.get_attr("src").map(String::from);
-                            let async_ = elem.get_attr("async").is_some();
-                            let defer = elem.get_attr("defer").is_some();
-                            
-                            self.scripts.push(Script {
-                                src,
-                                content: Vec::new(),
-                                async_,
-                                defer,
-                            });
-                        }
-                        "link" => {
-                            if elem.get_attr("rel") == Some("stylesheet") {
-                                let href = elem.get_attr("href").map(String::from);
-                                self.stylesheets.push(StylesheetRef {
-                                    href,
-                                    content: String::new(),
-                                });
-                            }
-                        }
-                        "style" => {
-                            // Inline stylesheet - content will be in text child
-                            self.stylesheets.push(StylesheetRef {
-                                href: None,
-                                content: String::new(),
-                            });
-                        }
-                        _ => {}
-                    }
+            formatting: Vec::new(),
+            mode: Mode::Initial,
+            head: None,
+            form: None,
+            doctype: None,
+            in_text: false,
+            skip_newline: false,
+            foster: false,
+        }
+    }
 
-                    self.stack.push(elem);
-                }
-                Token::EndTag(tag) => {
-                    if let Some(mut elem) = self.stack.pop() {
-                        // Capture inline script/style content
-                        match tag.as_str() {
-                            "script" => {
-                                if let Some(last) = self.scripts.last_mut() {
-                                    if last.src.is_none() {
-                                        // Inline script - get text content from children
-                                        let mut content = Vec::new();
-                                        for child in &elem.children {
-                                            if let Node::Text(text) = child {
-                                                content.extend_from_slice(text.as_bytes());
-                                            }
-                                        }
-                                        last.content = content;
-                                    }
-                                }
-                            }
-                            "style" => {
-                                if let Some(last) = self.stylesheets.last_mut() {
-                                    if last.href.is_none() {
-                                        // Inline stylesheet
-                                        let mut content = String::new();
-                                        for child in &elem.children {
-                                            if let Node::Text(text) = child {
-                                                content.push_str(text);
-                                            }
-                                        }
-                                        last.content = content;
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+    fn tag(&self, slot: usize) -> &str {
+        &self.slots[slot].tag
+    }
 
-                        // Add to parent if exists
-                        if let Some(parent) = self.stack.last_mut() {
-                            parent.children.push(Node::Element(elem));
-                        } else if tag == "html" {
-                            // Root element
-                            self.stack.push(elem);
-                        }
-                    }
-                }
-                Token::Text(text) => {
-                    if let Some(parent) = self.stack.last_mut() {
-                        parent.children.push(Node::Text(text.clone()));
-                    }
-                }
-                Token::Comment(_) => {
-                    // Ignore comments for now
-                }
-                Token::EOF => break,
+    fn current(&self) -> Option<usize> {
+        self.stack.last().copied()
+    }
+
+    fn current_tag(&self) -> &str {
+        self.current().map_or("", |slot| self.tag(slot))
+    }
+
+    fn new_slot(&mut self, tag: Tag) -> usize {
+        self.slots.push(Slot { tag: tag.name, attributes: tag.attributes, children: Vec::new(), parent: None });
+        self.slots.len() - 1
+    }
+
+    /// A new element with the same tag and attributes as `slot`
+    fn clone_slot(&mut self, slot: usize) -> usize {
+        let tag = Tag {
+            name: self.slots[slot].tag.clone(),
+            attributes: self.slots[slot].attributes.clone(),
+            self_closing: false,
+        };
+        self.new_slot(tag)
+    }
+
+    /// Parent and position new content goes to
+    fn insertion_point(&self) -> Option<(usize, Option<usize>)> {
+        let depth = self.stack.len().min(MAX_DEPTH);
+        let target = *self.stack.get(depth.checked_sub(1)?)?;
+        Some(self.insertion_point_in(target))
+    }
+
+    /// Where content for `target` goes, which is before the table when
+    /// it is being foster parented out of one
+    fn insertion_point_in(&self, target: usize) -> (usize, Option<usize>) {
+        if self.foster && matches!(self.tag(target), "table" | "tbody" | "tfoot" | "thead" | "tr") {
+            let table = self.stack.iter().rposition(|&s| self.tag(s) == "table");
+            if let Some(table) = table.filter(|&t| t > 0) {
+                let table = self.stack[table];
+                let parent = self.slots[table].parent.unwrap_or(self.stack[0]);
+                let before = self.slots[parent].children.iter()
+                    .position(|c| matches!(c, Child::Element(e) if *e == table));
+                return (parent, before);
             }
+            return (self.stack[0], None);
         }
+        (target, None)
+    }
 
-        // Get root element
-        let root = if self.stack.len() == 1 {
-            self.stack.pop().unwrap()
-        } else {
-            Element::new("html")
-        };
+    fn append(&mut self, child: Child) {
+        if let Some((parent, before)) = self.insertion_point() {
+            self.place(parent, before, child);
+        }
+    }
 
-        Ok(Document {
-            doctype,
-            root,
-            scripts: self.scripts,
-            stylesheets: self.stylesheets,
-        })
+    /// Add `child` to `parent`, before the child at `before` or last
+    fn place(&mut self, parent: usize, before: Option<usize>, child: Child) {
+        let children = &mut self.slots[parent].children;
+        let index = before.unwrap_or(children.len());
+        // Adjacent text joins into one node
+        if let Child::Text(text) = &child {
+            if let Some(Child::Text(previous)) = index.checked_sub(1).and_then(|i| children.get_mut(i)) {
+                previous.push_str(text);
+                return;
+            }
+        }
+        if let Child::Element(slot) = child {
+            self.slots[slot].parent = Some(parent);
+        }
+        self.slots[parent].children.insert(index, child);
     }
-}
 
-/// Parse HTML document
-pub fn parse(input: &[u8]) -> Result<Document, BrowserError> {
-    let mut tokenizer = Tokenizer::new(input);
-    let tokens = tokenizer.tokenize();
-    
-    let builder = DomBuilder::new();
-    builder.build(&tokens)
-}
+    /// Take an element out of its parent
+    fn detach(&mut self, slot: usize) {
+        if let Some(parent) = self.slots[slot].parent.take() {
+            self.slots[parent].children.retain(|c| !matches!(c, Child::Element(e) if *e == slot));
+        }
+    }
 
-/// Initialize HTML parser
-pub fn init() {
-    println!("[html] HTML parser initialized");
-}
+    /// Move an element to the end of `parent`
+    fn move_to(&mut self, parent: usize, slot: usize) {
+        self.detach(slot);
+        self.place(parent, None, Child::Element(slot));
+    }
 
-/// Create a simple test document
-pub fn create_test_document() -> Document {
-    let mut html = Element::new("html");
-    let mut head = Element::new("head");
-    let mut body = Element::new("body");
-    
-    // Add title
-    let mut title = Element::new("title");
-    title.children.push(Node::Text(String::from("WebbOS Browser")));
-    head.children.push(Node::Element(title));
-    
-    // Add heading
-    let mut h1 = Element::new("h1");
-    h1.children.push(Node::Text(String::from("Welcome to WebbOS!")));
-    body.children.push(Node::Element(h1));
-    
-    // Add paragraph
-    let mut p = Element::new("p");
-    p.children.push(Node::Text(String::from("This is a test page.")));
-    body.children.push(Node::Element(p));
-    
-    html.children.push(Node::Element(head));
-    html.children.push(Node::Element(body));
-    
-    Document {
-        doctype: Some(String::from("html")),
-        root: html,
-        scripts: Vec::new(),
-        stylesheets: Vec::new(),
+    fn insert_text(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.append(Child::Text(String::from(text)));
+        }
+    }
+
+    /// Insert an element at the current position and open it
+    fn insert(&mut self, tag: Tag) -> usize {
+        let slot = self.new_slot(tag);
+        self.append(Child::Element(slot));
+        self.stack.push(slot);
+        slot
+    }
+
+    /// Insert an element that is closed straight away
+    fn insert_void(&mut self, tag: Tag) {
+        self.insert(tag);
+        self.stack.pop();
+    }
+
+    fn pop(&mut self) {
+        self.stack.pop();
+    }
+
+    /// Pop elements until one with a tag in `tags` has been popped
+    fn pop_until(&mut self, tags: &[&str]) {
+        while let Some(slot) = self.stack.pop() {
+            if tags.contains(&self.tag(slot)) {
+                break;
+            }
+        }
+    }
+
+    /// Pop elements until the current one has a tag in `tags`
+    fn clear_to(&mut self, tags: &[&str]) {
+        while self.stack.len() > 1 && !tags.contains(&self.current_tag()) {
+            self.stack.pop();
+        }
+    }
+
+    /// Whether an element with a tag in `targets` is open, looking no
+    /// further up than the first with a tag in `boundaries`
+    fn in_scope_of(&self, targets: &[&str], boundaries: &[&str]) -> bool {
+        for &slot in self.stack.iter().rev() {
+            let tag = self.tag(slot);
+            if targets.contains(&tag) {
+                return true;
+            }
+            if boundaries.contains(&tag) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn in_scope(&self, tag: &str) -> bool {
+        self.in_scope_of(&[tag], SCOPE)
+    }
+
+    fn in_table_scope(&self, tag: &str) -> bool {
+        self.in_scope_of(&[tag], TABLE_SCOPE)
+    }
+
+    fn in_select_scope(&self, tag: &str) -> bool {
+        for &slot in self.stack.iter().rev() {
+            match self.tag(slot) {
+                t if t == tag => return true,
+                "optgroup" | "option" => {}
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// Close the elements that end with their parent, except `except`
+    fn generate_implied_end_tags(&mut self, except: Option<&str>) {
+        while IMPLIED_END.contains(&self.current_tag()) && Some(self.current_tag()) != except {
+            self.pop();
+        }
+    }
+
+    fn close_p(&mut self) {
+        if self.in_scope_of(&["p"], BUTTON_SCOPE) {
+            self.generate_implied_end_tags(Some("p"));
+            self.pop_until(&["p"]);
+        }
+    }
+
+    /// Close an open `li`, or `dd` or `dt`, before another starts
+    fn close_list_item(&mut self, tags: &[&str]) {
+        for i in (0..self.stack.len()).rev() {
+            let tag = self.tag(self.stack[i]);
+            if tags.contains(&tag) {
+                let tag = tag.to_string();
+                self.generate_implied_end_tags(Some(&tag));
+                self.pop_until(&[&tag]);
+                return;
+            }
+            if SPECIAL.contains(&tag) && !matches!(tag, "address" | "div" | "p") {
+                return;
+            }
+        }
+    }
+
+    /// Add attributes a repeated `html` or `body` tag brings to the element
+    fn merge_attributes(&mut self, slot: usize, attributes: Vec<(String, String)>) {
+        for (name, value) in attributes {
+            if !self.slots[slot].attributes.iter().any(|(n, _)| *n == name) {
+                self.slots[slot].attributes.push((name, value));
+            }
+        }
+    }
+
+    fn last_marker(&self) -> usize {
+        self.formatting.iter().rposition(|f| *f == Formatting::Marker).map_or(0, |i| i + 1)
+    }
+
+    fn push_formatting(&mut self, slot: usize) {
+        // At most three identical entries are kept after the last marker
+        let start = self.last_marker();
+        let same: Vec<usize> = (start..self.formatting.len()).filter(|&i| match self.formatting[i] {
+            Formatting::Element(e) => self.slots[e].tag == self.slots[slot].tag
+                && self.slots[e].attributes == self.slots[slot].attributes,
+            Formatting::Marker => false,
+        }).collect();
+        if same.len() >= 3 {
+            self.formatting.remove(same[0]);
+        }
+        self.formatting.push(Formatting::Element(slot));
+    }
+
+    /// Reopen formatting elements that were closed by something else
+    fn reconstruct_formatting(&mut self) {
+        let mut first = self.formatting.len();
+        while first > 0 {
+            match self.formatting[first - 1] {
+                Formatting::Element(e) if !self.stack.contains(&e) => first -= 1,
+                _ => break,
+            }
+        }
+        for i in first..self.formatting.len() {
+            if let Formatting::Element(e) = self.formatting[i] {
+                let tag = Tag {
+                    name: self.slots[e].tag.clone(),
+                    attributes: self.slots[e].attributes.clone(),
+                    self_closing: false,
+                };
+                let clone = self.insert(tag);
+                self.formatting[i] = Formatting::Element(clone);
+            }
+        }
+    }
+
+    fn clear_formatting_to_marker(&mut self) {
+        while let Some(entry) = self.formatting.pop() {
+            if entry == Formatting::Marker {
+                break;
+            }
+        }
+    }
+
+    /// Work out the insertion mode from the open elements, after a table
+    /// or select closes
+    fn reset_mode(&mut self) {
+        for (i, &slot) in self.stack.iter().enumerate().rev() {
+            let last = i == 0;
+            self.mode = match self.tag(slot) {
+                "select" => Mode::InSelect,
+                "td" | "th" if !last => Mode::InCell,
+                "tr" => Mode::InRow,
+                "tbody" | "thead" | "tfoot" => Mode::InTableBody,
+                "caption" => Mode::InCaption,
+                "colgroup" => Mode::InColumnGroup,
+                "table" => Mode::InTable,
+                "head" if !last => Mode::InHead,
+                "body" => Mode::InBody,
+                "html" if self.head.is_none() => Mode::BeforeHead,
+                "html" => Mode::AfterHead,
+                _ if last => Mode::InBody,
+                _ => continue,
+            };
+            return;
+        }
+    }
+
+    fn process(&mut self, mut token: Token) {
+        if core::mem::take(&mut self.skip_newline) {
+            if let Token::Text(text) = &mut token {
+                if text.starts_with('\n') {
+                    text.remove(0);
+                }
+            }
+        }
+        if self.in_text {
+            match &token {
+                Token::Text(text) => {
+                    self.insert_text(text);
+                    return;
+                }
+                // The tokenizer only ends raw text with the element's own
+                // end tag or the end of the input
+                _ => {
+                    self.in_text = false;
+                    self.pop();
+                    if !matches!(token, Token::Eof) {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let raw = match &token {
+            Token::StartTag(tag) if RAW_TEXT.contains(&tag.name.as_str()) || RCDATA.contains(&tag.name.as_str()) => {
+                Some(tag.name.clone())
+            }
+            _ => None,
+        };
+        let depth = self.stack.len();
+
+        let mut token = Some(token);
+        while let Some(next) = token.take() {
+            token = match self.mode {
+                Mode::Initial => self.initial(next),
+                Mode::BeforeHtml => self.before_html(next),
+                Mode::BeforeHead => self.before_head(next),
+                Mode::InHead => self.in_head(next),
+                Mode::AfterHead => self.after_head(next),
+                Mode::InBody => self.in_body(next),
+                Mode::InTable => self.in_table(next),
+                Mode::InCaption => self.in_caption(next),
+                Mode::InColumnGroup => self.in_column_group(next),
+                Mode::InTableBody => self.in_table_body(next),
+                Mode::InRow => self.in_row(next),
+                Mode::InCell => self.in_cell(next),
+                Mode::InSelect => self.in_select(next),
+                Mode::AfterBody => self.after_body(next),
+                Mode::AfterAfterBody => self.after_after_body(next),
+            };
+        }
+
+        // The tokenizer reads a raw text element's contents by itself;
+        // if the element was opened, its end tag will close it
+        if let Some(name) = raw {
+            if self.stack.len() > depth && self.current_tag() == name {
+                self.in_text = true;
+            }
+        }
+    }
+
+    fn initial(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                let (_, rest) = split_space(&text);
+                self.mode = Mode::BeforeHtml;
+                (!rest.is_empty()).then(|| Token::Text(String::from(rest)))
+            }
+            Token::Comment(_) => None,
+            Token::Doctype(name) => {
+                self.doctype = Some(name);
+                self.mode = Mode::BeforeHtml;
+                None
+            }
+            token => {
+                self.mode = Mode::BeforeHtml;
+                Some(token)
+            }
+        }
+    }
+
+    fn before_html(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Doctype(_) | Token::Comment(_) => None,
+            Token::Text(text) => {
+                let (_, rest) = split_space(&text);
+                if rest.is_empty() {
+                    return None;
+                }
+                self.create_root(Tag::new("html"), Token::Text(String::from(rest)))
+            }
+            Token::StartTag(tag) if tag.name == "html" => {
+                let slot = self.new_slot(tag);
+                self.stack.push(slot);
+                self.mode = Mode::BeforeHead;
+                None
+            }
+            Token::EndTag(name) if !matches!(name.as_str(), "head" | "body" | "html" | "br") => None,
+            token => self.create_root(Tag::new("html"), token),
+        }
+    }
+
+    fn create_root(&mut self, tag: Tag, token: Token) -> Option<Token> {
+        let slot = self.new_slot(tag);
+        self.stack.push(slot);
+        self.mode = Mode::BeforeHead;
+        Some(token)
+    }
+
+    fn before_head(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                let (_, rest) = split_space(&text);
+                if rest.is_empty() {
+                    return None;
+                }
+                self.implied_head(Token::Text(String::from(rest)))
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) => None,
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "head" => {
+                self.head = Some(self.insert(tag));
+                self.mode = Mode::InHead;
+                None
+            }
+            Token::EndTag(name) if !matches!(name.as_str(), "head" | "body" | "html" | "br") => None,
+            token => self.implied_head(token),
+        }
+    }
+
+    fn implied_head(&mut self, token: Token) -> Option<Token> {
+        self.head = Some(self.insert(Tag::new("head")));
+        self.mode = Mode::InHead;
+        Some(token)
+    }
+
+    fn in_head(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                let (space, rest) = split_space(&text);
+                self.insert_text(space);
+                if rest.is_empty() {
+                    return None;
+                }
+                self.leave_head(Token::Text(String::from(rest)))
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) => None,
+            Token::StartTag(tag) => match tag.name.as_str() {
+                "html" => self.in_body(Token::StartTag(tag)),
+                "base" | "basefont" | "bgsound" | "link" | "meta" => {
+                    self.insert_void(tag);
+                    None
+                }
+                "title" | "noscript" | "noframes" | "style" | "script" | "template" => {
+                    self.insert(tag);
+                    None
+                }
+                "head" => None,
+                _ => self.leave_head(Token::StartTag(tag)),
+            },
+            Token::EndTag(name) => match name.as_str() {
+                "head" => {
+                    self.pop_until(&["head"]);
+                    self.mode = Mode::AfterHead;
+                    None
+                }
+                "template" => {
+                    if self.in_scope_of(&["template"], &["html"]) {
+                        self.pop_until(&["template"]);
+                    }
+                    None
+                }
+                "body" | "html" | "br" => self.leave_head(Token::EndTag(name)),
+                _ => None,
+            },
+            Token::Eof => self.leave_head(Token::Eof),
+        }
+    }
+
+    fn leave_head(&mut self, token: Token) -> Option<Token> {
+        self.pop_until(&["head"]);
+        self.mode = Mode::AfterHead;
+        Some(token)
+    }
+
+    fn after_head(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                let (space, rest) = split_space(&text);
+                self.insert_text(space);
+                if rest.is_empty() {
+                    return None;
+                }
+                self.implied_body(Token::Text(String::from(rest)))
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) => None,
+            Token::StartTag(tag) => match tag.name.as_str() {
+                "html" => self.in_body(Token::StartTag(tag)),
+                "body" | "frameset" => {
+                    self.insert(tag);
+                    self.mode = Mode::InBody;
+                    None
+                }
+                "base" | "basefont" | "bgsound" | "link" | "meta" | "noframes" | "script" | "style"
+                | "template" | "title" => {
+                    // Head content that turned up late still goes in the head
+                    let head = self.head?;
+                    self.stack.push(head);
+                    let reprocess = self.in_head(Token::StartTag(tag));
+                    // A raw text element it opened stays open above
+                    if let Some(i) = self.stack.iter().rposition(|&s| s == head) {
+                        self.stack.remove(i);
+                    }
+                    reprocess
+                }
+                "head" => None,
+                _ => self.implied_body(Token::StartTag(tag)),
+            },
+            Token::EndTag(name) if !matches!(name.as_str(), "body" | "html" | "br") => None,
+            token => self.implied_body(token),
+        }
+    }
+
+    fn implied_body(&mut self, token: Token) -> Option<Token> {
+        self.insert(Tag::new("body"));
+        self.mode = Mode::InBody;
+        Some(token)
+    }
+
+    fn in_body(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                self.reconstruct_formatting();
+                self.insert_text(&text);
+                None
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) | Token::Eof => None,
+            Token::StartTag(tag) => self.body_start_tag(tag),
+            Token::EndTag(name) => self.body_end_tag(name),
+        }
+    }
+
+    fn body_start_tag(&mut self, mut tag: Tag) -> Option<Token> {
+        let name = tag.name.as_str();
+        match name {
+            "html" => {
+                let root = *self.stack.first()?;
+                self.merge_attributes(root, tag.attributes);
+            }
+            "base" | "basefont" | "bgsound" | "link" | "meta" | "noframes" | "script" | "style"
+            | "template" | "title" => return self.in_head(Token::StartTag(tag)),
+            "body" => {
+                if let Some(&body) = self.stack.get(1) {
+                    if self.tag(body) == "body" {
+                        self.merge_attributes(body, tag.attributes);
+                    }
+                }
+            }
+            "frameset" => {}
+            _ if BLOCKS.contains(&name) => {
+                self.close_p();
+                self.insert(tag);
+            }
+            _ if HEADINGS.contains(&name) => {
+                self.close_p();
+                if HEADINGS.contains(&self.current_tag()) {
+                    self.pop();
+                }
+                self.insert(tag);
+            }
+            "pre" | "listing" => {
+                self.close_p();
+                self.insert(tag);
+                self.skip_newline = true;
+            }
+            "form" => {
+                if self.form.is_none() {
+                    self.close_p();
+                    self.form = Some(self.insert(tag));
+                }
+            }
+            "li" => {
+                self.close_list_item(&["li"]);
+                self.close_p();
+                self.insert(tag);
+            }
+            "dd" | "dt" => {
+                self.close_list_item(&["dd", "dt"]);
+                self.close_p();
+                self.insert(tag);
+            }
+            "plaintext" => {
+                self.close_p();
+                self.insert(tag);
+            }
+            "button" => {
+                if self.in_scope("button") {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&["button"]);
+                }
+                self.reconstruct_formatting();
+                self.insert(tag);
+            }
+            "a" => {
+                // An `a` inside an open `a` closes the first one
+                let start = self.last_marker();
+                let open = self.formatting[start..].iter().rev().find_map(|f| match *f {
+                    Formatting::Element(e) if self.tag(e) == "a" => Some(e),
+                    _ => None,
+                });
+                if let Some(open) = open {
+                    self.end_formatting("a");
+                    self.formatting.retain(|f| *f != Formatting::Element(open));
+                    self.stack.retain(|&s| s != open);
+                }
+                self.reconstruct_formatting();
+                let slot = self.insert(tag);
+                self.push_formatting(slot);
+            }
+            _ if FORMATTING.contains(&name) => {
+                self.reconstruct_formatting();
+                let slot = self.insert(tag);
+                self.push_formatting(slot);
+            }
+            "nobr" => {
+                self.reconstruct_formatting();
+                if self.in_scope("nobr") {
+                    self.end_formatting("nobr");
+                    self.reconstruct_formatting();
+                }
+                let slot = self.insert(tag);
+                self.push_formatting(slot);
+            }
+            "applet" | "marquee" | "object" => {
+                self.reconstruct_formatting();
+                self.insert(tag);
+                self.formatting.push(Formatting::Marker);
+            }
+            "table" => {
+                self.close_p();
+                self.insert(tag);
+                self.mode = Mode::InTable;
+            }
+            "area" | "br" | "embed" | "img" | "keygen" | "wbr" | "input" => {
+                self.reconstruct_formatting();
+                self.insert_void(tag);
+            }
+            "param" | "source" | "track" => self.insert_void(tag),
+            "hr" => {
+                self.close_p();
+                self.insert_void(tag);
+            }
+            "image" => {
+                tag.name = String::from("img");
+                return Some(Token::StartTag(tag));
+            }
+            "textarea" => {
+                self.insert(tag);
+                self.skip_newline = true;
+            }
+            "xmp" => {
+                self.close_p();
+                self.reconstruct_formatting();
+                self.insert(tag);
+            }
+            "iframe" | "noembed" | "noscript" => {
+                self.insert(tag);
+            }
+            "select" => {
+                self.reconstruct_formatting();
+                self.insert(tag);
+                self.mode = Mode::InSelect;
+            }
+            "optgroup" | "option" => {
+                if self.current_tag() == "option" {
+                    self.pop();
+                }
+                self.reconstruct_formatting();
+                self.insert(tag);
+            }
+            "rb" | "rtc" => {
+                if self.in_scope("ruby") {
+                    self.generate_implied_end_tags(None);
+                }
+                self.insert(tag);
+            }
+            "rp" | "rt" => {
+                if self.in_scope("ruby") {
+                    self.generate_implied_end_tags(Some("rtc"));
+                }
+                self.insert(tag);
+            }
+            _ if TABLE_PARTS.contains(&name) || matches!(name, "frame" | "head") => {}
+            _ => {
+                // Self-closing tags only mean something in SVG and MathML
+                let foreign = name == "svg" || name == "math"
+                    || self.stack.iter().any(|&s| matches!(self.tag(s), "svg" | "math"));
+                let self_closing = tag.self_closing;
+                self.reconstruct_formatting();
+                self.insert(tag);
+                if foreign && self_closing {
+                    self.pop();
+                }
+            }
+        }
+        None
+    }
+
+    fn body_end_tag(&mut self, name: String) -> Option<Token> {
+        let tag = name.as_str();
+        match tag {
+            "body" => {
+                if self.in_scope("body") {
+                    self.mode = Mode::AfterBody;
+                }
+            }
+            "html" => {
+                if self.in_scope("body") {
+                    self.mode = Mode::AfterBody;
+                    return Some(Token::EndTag(name));
+                }
+            }
+            "button" | "listing" | "pre" | "dialog" => self.end_block(tag),
+            _ if BLOCKS.contains(&tag) && tag != "p" => self.end_block(tag),
+            "form" => {
+                if let Some(form) = self.form.take() {
+                    if self.stack.contains(&form) && self.in_scope("form") {
+                        self.generate_implied_end_tags(None);
+                        self.stack.retain(|&s| s != form);
+                    }
+                }
+            }
+            "p" => {
+                if !self.in_scope_of(&["p"], BUTTON_SCOPE) {
+                    self.insert(Tag::new("p"));
+                }
+                self.close_p();
+            }
+            "li" => {
+                if self.in_scope_of(&["li"], LIST_ITEM_SCOPE) {
+                    self.generate_implied_end_tags(Some("li"));
+                    self.pop_until(&["li"]);
+                }
+            }
+            "dd" | "dt" => {
+                if self.in_scope(tag) {
+                    self.generate_implied_end_tags(Some(tag));
+                    self.pop_until(&[tag]);
+                }
+            }
+            _ if HEADINGS.contains(&tag) => {
+                if self.in_scope_of(HEADINGS, SCOPE) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(HEADINGS);
+                }
+            }
+            "a" | "nobr" => self.end_formatting(tag),
+            _ if FORMATTING.contains(&tag) => self.end_formatting(tag),
+            "applet" | "marquee" | "object" => {
+                if self.in_scope(tag) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&[tag]);
+                    self.clear_formatting_to_marker();
+                }
+            }
+            "br" => {
+                // `</br>` is read as `<br>`
+                return self.body_start_tag(Tag::new("br"));
+            }
+            _ => self.end_other(tag),
+        }
+        None
+    }
+
+    fn end_block(&mut self, tag: &str) {
+        if self.in_scope(tag) {
+            self.generate_implied_end_tags(None);
+            self.pop_until(&[tag]);
+        }
+    }
+
+    /// End tag of a formatting element: the adoption agency algorithm
+    ///
+    /// A formatting element ended while a block opened inside it is still
+    /// open, as in `<b>1<p>2</b>3</p>`, is split. The block moves out of
+    /// it and wraps its own contents in a copy, giving
+    /// `<b>1</b><p><b>2</b>3</p>`.
+    fn end_formatting(&mut self, tag: &str) {
+        if let Some(current) = self.current() {
+            if self.tag(current) == tag && !self.formatting.contains(&Formatting::Element(current)) {
+                self.pop();
+                return;
+            }
+        }
+        for _ in 0..8 {
+            let start = self.last_marker();
+            let entry = (start..self.formatting.len()).rev().find(|&i| {
+                matches!(self.formatting[i], Formatting::Element(e) if self.tag(e) == tag)
+            });
+            let element = match entry.map(|i| self.formatting[i]) {
+                Some(Formatting::Element(element)) => element,
+                _ => {
+                    self.end_other(tag);
+                    return;
+                }
+            };
+            let index = match self.stack.iter().position(|&s| s == element) {
+                Some(index) if index > 0 => index,
+                _ => {
+                    self.formatting.retain(|f| *f != Formatting::Element(element));
+                    return;
+                }
+            };
+            if !self.in_scope(tag) {
+                return;
+            }
+            // The first special element opened inside it
+            let furthest = (index + 1..self.stack.len()).find(|&i| SPECIAL.contains(&self.tag(self.stack[i])));
+            let furthest_index = match furthest {
+                Some(i) => i,
+                None => {
+                    self.stack.truncate(index);
+                    self.formatting.retain(|f| *f != Formatting::Element(element));
+                    return;
+                }
+            };
+            let furthest = self.stack[furthest_index];
+            let ancestor = self.stack[index - 1];
+            let mut bookmark = entry.unwrap_or(0);
+
+            // Copy the formatting elements between the two into a chain
+            // above the block, dropping the rest
+            let mut node_index = furthest_index;
+            let mut last = furthest;
+            let mut counter = 0;
+            loop {
+                counter += 1;
+                node_index -= 1;
+                let node = self.stack[node_index];
+                if node == element {
+                    break;
+                }
+                let mut listed = self.formatting.iter().position(|f| *f == Formatting::Element(node));
+                if let Some(i) = listed.filter(|_| counter > 3) {
+                    self.formatting.remove(i);
+                    if i < bookmark {
+                        bookmark -= 1;
+                    }
+                    listed = None;
+                }
+                let listed = match listed {
+                    Some(i) => i,
+                    None => {
+                        self.stack.remove(node_index);
+                        continue;
+                    }
+                };
+                let copy = self.clone_slot(node);
+                self.formatting[listed] = Formatting::Element(copy);
+                self.stack[node_index] = copy;
+                if last == furthest {
+                    bookmark = listed + 1;
+                }
+                self.move_to(copy, last);
+                last = copy;
+            }
+
+            self.detach(last);
+            let (parent, before) = self.insertion_point_in(ancestor);
+            self.place(parent, before, Child::Element(last));
+
+            // The block's contents go into a copy of the formatting element
+            let copy = self.clone_slot(element);
+            let children = core::mem::take(&mut self.slots[furthest].children);
+            for child in &children {
+                if let Child::Element(e) = child {
+                    self.slots[*e].parent = Some(copy);
+                }
+            }
+            self.slots[copy].children = children;
+            self.place(furthest, None, Child::Element(copy));
+
+            if let Some(i) = self.formatting.iter().position(|f| *f == Formatting::Element(element)) {
+                self.formatting.remove(i);
+                if i < bookmark {
+                    bookmark -= 1;
+                }
+            }
+            self.formatting.insert(bookmark.min(self.formatting.len()), Formatting::Element(copy));
+            self.stack.retain(|&s| s != element);
+            let below = self.stack.iter().position(|&s| s == furthest).map_or(self.stack.len(), |i| i + 1);
+            self.stack.insert(below, copy);
+        }
+    }
+
+    /// End tag with no rule of its own: close the nearest open element
+    /// with that tag, unless a special element is in the way
+    fn end_other(&mut self, tag: &str) {
+        for i in (0..self.stack.len()).rev() {
+            let open = self.tag(self.stack[i]);
+            if open == tag {
+                self.generate_implied_end_tags(Some(tag));
+                self.stack.truncate(i);
+                return;
+            }
+            if SPECIAL.contains(&open) {
+                return;
+            }
+        }
+    }
+
+    /// Whether text in table mode stays in the table
+    fn table_text_target(&self) -> bool {
+        matches!(self.current_tag(), "table" | "tbody" | "template" | "tfoot" | "thead" | "tr")
+    }
+
+    /// Body rules for content misplaced in a table, which is moved
+    /// before the table
+    fn foster_parent(&mut self, token: Token) -> Option<Token> {
+        self.foster = true;
+        let reprocess = self.in_body(token);
+        self.foster = false;
+        reprocess
+    }
+
+    fn in_table(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) if self.table_text_target() => {
+                if text.chars().all(is_space) {
+                    self.insert_text(&text);
+                    None
+                } else {
+                    self.foster_parent(Token::Text(text))
+                }
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) => None,
+            Token::StartTag(tag) => match tag.name.as_str() {
+                "caption" => {
+                    self.clear_to(TABLE_CONTEXT);
+                    self.formatting.push(Formatting::Marker);
+                    self.insert(tag);
+                    self.mode = Mode::InCaption;
+                    None
+                }
+                "colgroup" => {
+                    self.clear_to(TABLE_CONTEXT);
+                    self.insert(tag);
+                    self.mode = Mode::InColumnGroup;
+                    None
+                }
+                "col" => {
+                    self.clear_to(TABLE_CONTEXT);
+                    self.insert(Tag::new("colgroup"));
+                    self.mode = Mode::InColumnGroup;
+                    Some(Token::StartTag(tag))
+                }
+                "tbody" | "tfoot" | "thead" => {
+                    self.clear_to(TABLE_CONTEXT);
+                    self.insert(tag);
+                    self.mode = Mode::InTableBody;
+                    None
+                }
+                "td" | "th" | "tr" => {
+                    self.clear_to(TABLE_CONTEXT);
+                    self.insert(Tag::new("tbody"));
+                    self.mode = Mode::InTableBody;
+                    Some(Token::StartTag(tag))
+                }
+                "table" => {
+                    // A table inside a table closes the first
+                    if !self.in_table_scope("table") {
+                        return None;
+                    }
+                    self.pop_until(&["table"]);
+                    self.reset_mode();
+                    Some(Token::StartTag(tag))
+                }
+                "style" | "script" | "template" => self.in_head(Token::StartTag(tag)),
+                "input" if tag.attr("type").map_or(false, |t| t.eq_ignore_ascii_case("hidden")) => {
+                    self.insert_void(tag);
+                    None
+                }
+                "form" => {
+                    if self.form.is_none() {
+                        self.form = Some(self.insert(tag));
+                        self.pop();
+                    }
+                    None
+                }
+                _ => self.foster_parent(Token::StartTag(tag)),
+            },
+            Token::EndTag(name) => match name.as_str() {
+                "table" => {
+                    if self.in_table_scope("table") {
+                        self.pop_until(&["table"]);
+                        self.reset_mode();
+                    }
+                    None
+                }
+                "body" | "caption" | "col" | "colgroup" | "html" | "tbody" | "td" | "tfoot" | "th"
+                | "thead" | "tr" => None,
+                "template" => self.in_head(Token::EndTag(name)),
+                _ => self.foster_parent(Token::EndTag(name)),
+            },
+            Token::Eof => self.in_body(Token::Eof),
+            token => self.foster_parent(token),
+        }
+    }
+
+    fn in_caption(&mut self, token: Token) -> Option<Token> {
+        let closes = match &token {
+            Token::EndTag(name) => matches!(name.as_str(), "caption" | "table"),
+            Token::StartTag(tag) => TABLE_PARTS.contains(&tag.name.as_str()),
+            _ => false,
+        };
+        if closes {
+            if !self.in_table_scope("caption") {
+                return None;
+            }
+            self.generate_implied_end_tags(None);
+            self.pop_until(&["caption"]);
+            self.clear_formatting_to_marker();
+            self.mode = Mode::InTable;
+            return match token {
+                Token::EndTag(name) if name == "caption" => None,
+                token => Some(token),
+            };
+        }
+        match token {
+            Token::EndTag(name) if matches!(name.as_str(), "body" | "col" | "colgroup" | "html" | "tbody"
+                | "td" | "tfoot" | "th" | "thead" | "tr") => None,
+            token => self.in_body(token),
+        }
+    }
+
+    fn in_column_group(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                let (space, rest) = split_space(&text);
+                self.insert_text(space);
+                if rest.is_empty() {
+                    return None;
+                }
+                self.leave_column_group(Token::Text(String::from(rest)))
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) => None,
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "col" => {
+                self.insert_void(tag);
+                None
+            }
+            Token::StartTag(tag) if tag.name == "template" => self.in_head(Token::StartTag(tag)),
+            Token::EndTag(name) if name == "colgroup" => {
+                if self.current_tag() == "colgroup" {
+                    self.pop();
+                    self.mode = Mode::InTable;
+                }
+                None
+            }
+            Token::EndTag(name) if name == "col" => None,
+            Token::EndTag(name) if name == "template" => self.in_head(Token::EndTag(name)),
+            Token::Eof => self.in_body(Token::Eof),
+            token => self.leave_column_group(token),
+        }
+    }
+
+    fn leave_column_group(&mut self, token: Token) -> Option<Token> {
+        if self.current_tag() != "colgroup" {
+            return None;
+        }
+        self.pop();
+        self.mode = Mode::InTable;
+        Some(token)
+    }
+
+    fn in_table_body(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::StartTag(tag) if tag.name == "tr" => {
+                self.clear_to(TABLE_BODY_CONTEXT);
+                self.insert(tag);
+                self.mode = Mode::InRow;
+                None
+            }
+            Token::StartTag(tag) if matches!(tag.name.as_str(), "td" | "th") => {
+                self.clear_to(TABLE_BODY_CONTEXT);
+                self.insert(Tag::new("tr"));
+                self.mode = Mode::InRow;
+                Some(Token::StartTag(tag))
+            }
+            Token::EndTag(name) if matches!(name.as_str(), "tbody" | "tfoot" | "thead") => {
+                if self.in_table_scope(&name) {
+                    self.clear_to(TABLE_BODY_CONTEXT);
+                    self.pop();
+                    self.mode = Mode::InTable;
+                }
+                None
+            }
+            Token::StartTag(ref tag) if matches!(tag.name.as_str(), "caption" | "col" | "colgroup" | "tbody"
+                | "tfoot" | "thead") => self.leave_table_body(token),
+            Token::EndTag(ref name) if name == "table" => self.leave_table_body(token),
+            Token::EndTag(name) if matches!(name.as_str(), "body" | "caption" | "col" | "colgroup" | "html"
+                | "td" | "th" | "tr") => None,
+            token => self.in_table(token),
+        }
+    }
+
+    fn leave_table_body(&mut self, token: Token) -> Option<Token> {
+        if !self.in_scope_of(&["tbody", "thead", "tfoot"], TABLE_SCOPE) {
+            return None;
+        }
+        self.clear_to(TABLE_BODY_CONTEXT);
+        self.pop();
+        self.mode = Mode::InTable;
+        Some(token)
+    }
+
+    fn in_row(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::StartTag(tag) if matches!(tag.name.as_str(), "td" | "th") => {
+                self.clear_to(TABLE_ROW_CONTEXT);
+                self.insert(tag);
+                self.mode = Mode::InCell;
+                self.formatting.push(Formatting::Marker);
+                None
+            }
+            Token::EndTag(name) if name == "tr" => {
+                if self.in_table_scope("tr") {
+                    self.clear_to(TABLE_ROW_CONTEXT);
+                    self.pop();
+                    self.mode = Mode::InTableBody;
+                }
+                None
+            }
+            Token::StartTag(ref tag) if matches!(tag.name.as_str(), "caption" | "col" | "colgroup" | "tbody"
+                | "tfoot" | "thead" | "tr") => self.leave_row(token),
+            Token::EndTag(ref name) if name == "table" => self.leave_row(token),
+            Token::EndTag(ref name) if matches!(name.as_str(), "tbody" | "tfoot" | "thead") => {
+                if !self.in_table_scope(name) {
+                    return None;
+                }
+                self.leave_row(token)
+            }
+            Token::EndTag(name) if matches!(name.as_str(), "body" | "caption" | "col" | "colgroup" | "html"
+                | "td" | "th") => None,
+            token => self.in_table(token),
+        }
+    }
+
+    fn leave_row(&mut self, token: Token) -> Option<Token> {
+        if !self.in_table_scope("tr") {
+            return None;
+        }
+        self.clear_to(TABLE_ROW_CONTEXT);
+        self.pop();
+        self.mode = Mode::InTableBody;
+        Some(token)
+    }
+
+    fn in_cell(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::EndTag(name) if matches!(name.as_str(), "td" | "th") => {
+                if self.in_table_scope(&name) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&[&name]);
+                    self.clear_formatting_to_marker();
+                    self.mode = Mode::InRow;
+                }
+                None
+            }
+            Token::StartTag(ref tag) if TABLE_PARTS.contains(&tag.name.as_str()) => {
+                if !self.in_scope_of(&["td", "th"], TABLE_SCOPE) {
+                    return None;
+                }
+                self.close_cell();
+                Some(token)
+            }
+            Token::EndTag(name) if matches!(name.as_str(), "body" | "caption" | "col" | "colgroup" | "html") => None,
+            Token::EndTag(ref name) if matches!(name.as_str(), "table" | "tbody" | "tfoot" | "thead" | "tr") => {
+                if !self.in_table_scope(name) {
+                    return None;
+                }
+                self.close_cell();
+                Some(token)
+            }
+            token => self.in_body(token),
+        }
+    }
+
+    fn close_cell(&mut self) {
+        self.generate_implied_end_tags(None);
+        self.pop_until(&["td", "th"]);
+        self.clear_formatting_to_marker();
+        self.mode = Mode::InRow;
+    }
+
+    fn in_select(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(text) => {
+                self.insert_text(&text);
+                None
+            }
+            Token::Comment(text) => {
+                self.append(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) => None,
+            Token::StartTag(tag) => match tag.name.as_str() {
+                "html" => self.in_body(Token::StartTag(tag)),
+                "option" => {
+                    if self.current_tag() == "option" {
+                        self.pop();
+                    }
+                    self.insert(tag);
+                    None
+                }
+                "optgroup" | "hr" => {
+                    if self.current_tag() == "option" {
+                        self.pop();
+                    }
+                    if self.current_tag() == "optgroup" {
+                        self.pop();
+                    }
+                    if tag.name == "hr" {
+                        self.insert_void(tag);
+                    } else {
+                        self.insert(tag);
+                    }
+                    None
+                }
+                "select" => {
+                    // A select inside a select closes the first
+                    self.end_select();
+                    None
+                }
+                "input" | "keygen" | "textarea" => {
+                    if !self.in_select_scope("select") {
+                        return None;
+                    }
+                    self.end_select();
+                    Some(Token::StartTag(tag))
+                }
+                "script" | "template" => self.in_head(Token::StartTag(tag)),
+                _ => None,
+            },
+            Token::EndTag(name) => match name.as_str() {
+                "optgroup" => {
+                    let below = self.stack.len().checked_sub(2).map(|i| self.stack[i]);
+                    if self.current_tag() == "option" && below.map_or(false, |s| self.tag(s) == "optgroup") {
+                        self.pop();
+                    }
+                    if self.current_tag() == "optgroup" {
+                        self.pop();
+                    }
+                    None
+                }
+                "option" => {
+                    if self.current_tag() == "option" {
+                        self.pop();
+                    }
+                    None
+                }
+                "select" => {
+                    self.end_select();
+                    None
+                }
+                "template" => self.in_head(Token::EndTag(name)),
+                _ => None,
+            },
+            Token::Eof => self.in_body(Token::Eof),
+        }
+    }
+
+    fn end_select(&mut self) {
+        if self.in_select_scope("select") {
+            self.pop_until(&["select"]);
+            self.reset_mode();
+        }
+    }
+
+    fn after_body(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Text(ref text) if text.chars().all(is_space) => self.in_body(token),
+            Token::Comment(text) => {
+                // Comments after the body belong to the html element
+                let root = *self.stack.first()?;
+                self.slots[root].children.push(Child::Comment(text));
+                None
+            }
+            Token::Doctype(_) | Token::Eof => None,
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(name) if name == "html" => {
+                self.mode = Mode::AfterAfterBody;
+                None
+            }
+            token => {
+                self.mode = Mode::InBody;
+                Some(token)
+            }
+        }
+    }
+
+    fn after_after_body(&mut self, token: Token) -> Option<Token> {
+        match token {
+            Token::Comment(_) | Token::Doctype(_) | Token::Eof => None,
+            Token::Text(ref text) if text.chars().all(is_space) => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            token => {
+                self.mode = Mode::InBody;
+                Some(token)
+            }
+        }
+    }
+
+    /// Turn the arena into the element tree
+    fn finish(mut self) -> Document {
+        let mut root = self.take_element(0);
+        let mut scripts = Vec::new();
+        let mut stylesheets = Vec::new();
+        collect_resources(&mut root, &mut scripts, &mut stylesheets);
+        Document {
+            doctype: self.doctype,
+            root,
+            scripts,
+            stylesheets,
+        }
+    }
+
+    /// The document as far as it has been built, leaving the builder as
+    /// it is
+    fn snapshot(&self) -> Document {
+        let mut root = if self.slots.is_empty() { Element::new("html") } else { self.copy_element(0) };
+        let mut scripts = Vec::new();
+        let mut stylesheets = Vec::new();
+        collect_resources(&mut root, &mut scripts, &mut stylesheets);
+        Document {
+            doctype: self.doctype.clone(),
+            root,
+            scripts,
+            stylesheets,
+        }
+    }
+
+    fn copy_element(&self, slot: usize) -> Element {
+        let mut element = Element::new(&self.slots[slot].tag);
+        element.attributes = self.slots[slot].attributes.clone();
+        element.children = self.slots[slot].children.iter().map(|child| match child {
+            Child::Element(e) => Node::Element(self.copy_element(*e)),
+            Child::Text(text) => Node::Text(text.clone()),
+            Child::Comment(text) => Node::Comment(text.clone()),
+        }).collect();
+        element
+    }
+
+    fn take_element(&mut self, slot: usize) -> Element {
+        let children = core::mem::take(&mut self.slots[slot].children);
+        let mut element = Element::new(&self.slots[slot].tag);
+        element.attributes = core::mem::take(&mut self.slots[slot].attributes);
+        element.children = children.into_iter().map(|child| match child {
+            Child::Element(e) => Node::Element(self.take_element(e)),
+            Child::Text(text) => Node::Text(text),
+            Child::Comment(text) => Node::Comment(text),
+        }).collect();
+        element
+    }
+}
+
+/// Gather scripts and stylesheets in document order
+fn collect_resources(element: &mut Element, scripts: &mut Vec<Script>, stylesheets: &mut Vec<StylesheetRef>) {
+    match element.tag.as_str() {
+        "script" => {
+            let src = element.get_attr("src").map(String::from);
+            scripts.push(Script {
+                content: if src.is_none() { element.text_content().into_bytes() } else { Vec::new() },
+                src,
+                async_: element.get_attr("async").is_some(),
+                defer: element.get_attr("defer").is_some(),
+            });
+        }
+        "style" => stylesheets.push(StylesheetRef { href: None, content: element.text_content() }),
+        "link" => {
+            let rel = element.get_attr("rel").unwrap_or("");
+            if rel.split_ascii_whitespace().any(|r| r.eq_ignore_ascii_case("stylesheet")) {
+                stylesheets.push(StylesheetRef {
+                    href: element.get_attr("href").map(String::from),
+                    content: String::new(),
+                });
+            }
+        }
+        _ => {}
+    }
+    for child in &mut element.children {
+        if let Node::Element(child) = child {
+            collect_resources(child, scripts, stylesheets);
+        }
+    }
+}
+
+/// Parse HTML document
+///
+/// Any input yields a document; malformed markup is recovered from rather
+/// than rejected. Bytes that are not UTF-8 become U+FFFD.
+pub fn parse(input: &[u8]) -> Result<Document, BrowserError> {
+    let mut parser = Parser::new();
+    parser.feed(input);
+    Ok(parser.finish())
+}
+
+/// Parses a document that arrives a piece at a time
+///
+/// Each token goes to the tree builder as soon as it is complete. One
+/// that runs up to the end of what has arrived is held back, as it may
+/// go on in the next piece, so the tree so far never has half a tag or a
+/// word cut in two.
+pub struct Parser {
+    tokenizer: Tokenizer,
+    builder: TreeBuilder,
+    /// Start of a UTF-8 sequence the last piece cut off
+    partial: Vec<u8>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self { tokenizer: Tokenizer::new(""), builder: TreeBuilder::new(), partial: Vec::new() }
+    }
+
+    /// Parse the next piece of the document
+    pub fn feed(&mut self, input: &[u8]) {
+        self.partial.extend_from_slice(input);
+        let complete = self.partial.len() - incomplete_tail(&self.partial);
+        let text = String::from_utf8_lossy(&self.partial[..complete]).into_owned();
+        self.partial.drain(..complete);
+        self.tokenizer.push(&text);
+        loop {
+            let mark = self.tokenizer.mark();
+            let token = self.tokenizer.next_token();
+            if self.tokenizer.at_end() {
+                self.tokenizer.reset(mark);
+                break;
+            }
+            self.builder.process(token);
+        }
+        self.tokenizer.compact();
+    }
+
+    /// The document as far as it has arrived, with the elements still
+    /// open ending where it does
+    pub fn snapshot(&self) -> Document {
+        self.builder.snapshot()
+    }
+
+    /// Parse the rest of the document now that all of it has arrived
+    pub fn finish(mut self) -> Document {
+        let rest = String::from_utf8_lossy(&self.partial).into_owned();
+        self.tokenizer.push(&rest);
+        loop {
+            let token = self.tokenizer.next_token();
+            let eof = matches!(token, Token::Eof);
+            self.builder.process(token);
+            if eof {
+                break;
+            }
+        }
+        self.builder.finish()
+    }
+}
+
+/// Length of the UTF-8 sequence cut off at the end of `bytes`, if any
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        // Skip continuation bytes back to the one that starts the sequence
+        if byte & 0xC0 != 0x80 {
+            let length = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if length > back { back } else { 0 };
+        }
+    }
+    0
+}
+
+/// Parse markup meant to go inside a `context` element, as setting
+/// `innerHTML` does
+///
+/// The markup is parsed inside a wrapper of the right kind, so rows go
+/// into a table body and options into a list.
+pub fn parse_fragment(context: &str, input: &str) -> Vec<Node> {
+    let (open, close, wrapper) = match context {
+        "table" => ("<table>", "</table>", "table"),
+        "tbody" | "thead" | "tfoot" => ("<table><tbody>", "</tbody></table>", "tbody"),
+        "tr" => ("<table><tbody><tr>", "</tr></tbody></table>", "tr"),
+        "select" | "optgroup" => ("<select>", "</select>", "select"),
+        "ul" | "ol" => ("<ul>", "</ul>", "ul"),
+        _ => ("<div>", "</div>", "div"),
+    };
+    let markup = format!("<!DOCTYPE html><html><body>{}{}{}</body></html>", open, input, close);
+    let mut tokenizer = Tokenizer::new(&markup);
+    let mut builder = TreeBuilder::new();
+    loop {
+        let token = tokenizer.next_token();
+        let eof = matches!(token, Token::Eof);
+        builder.process(token);
+        if eof {
+            break;
+        }
+    }
+    let mut root = builder.take_element(0);
+    // The wrapper is the first element of its kind in the body
+    fn take(element: &mut Element, tag: &str) -> Option<Vec<Node>> {
+        for child in &mut element.children {
+            if let Node::Element(elem) = child {
+                if elem.tag == tag {
+                    return Some(core::mem::take(&mut elem.children));
+                }
+                if let Some(found) = take(elem, tag) {
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+    take(&mut root, wrapper).unwrap_or_default()
+}
+
+/// Initialize HTML parser
+pub fn init() {
+    info!("html", "HTML parser initialized");
+}
+
+/// Create a simple test document
+pub fn create_test_document() -> Document {
+    let mut html = Element::new("html");
+    let mut head = Element::new("head");
+    let mut body = Element::new("body");
+
+    // Add title
+    let mut title = Element::new("title");
+    title.children.push(Node::Text(String::from("WebbOS Browser")));
+    head.children.push(Node::Element(title));
+
+    // Add heading
+    let mut h1 = Element::new("h1");
+    h1.children.push(Node::Text(String::from("Welcome to WebbOS!")));
+    body.children.push(Node::Element(h1));
+
+    // Add paragraph
+    let mut p = Element::new("p");
+    p.children.push(Node::Text(String::from("This is a test page.")));
+    body.children.push(Node::Element(p));
+
+    html.children.push(Node::Element(head));
+    html.children.push(Node::Element(body));
+
+    Document {
+        doctype: Some(String::from("html")),
+        root: html,
+        scripts: Vec::new(),
+        stylesheets: Vec::new(),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// Tokens up to the end, with text runs joined, as `<a x=1>`, `</a>`,
+    /// `"text"` and `!comment`
+    fn tokens(input: &str) -> Vec<String> {
+        let mut tokenizer = Tokenizer::new(input);
+        let mut out: Vec<String> = Vec::new();
+        loop {
+            match tokenizer.next_token() {
+                Token::Eof => return out,
+                Token::Text(text) => match out.last_mut() {
+                    Some(last) if last.starts_with('"') => {
+                        last.pop();
+                        last.push_str(&text);
+                        last.push('"');
+                    }
+                    _ => out.push(format!("\"{}\"", text)),
+                },
+                Token::StartTag(tag) => {
+                    let mut s = format!("<{}", tag.name);
+                    for (k, v) in &tag.attributes {
+                        s.push_str(&format!(" {}={}", k, v));
+                    }
+                    out.push(s + ">");
+                }
+                Token::EndTag(name) => out.push(format!("</{}>", name)),
+                Token::Comment(text) => out.push(format!("!{}", text)),
+                Token::Doctype(name) => out.push(format!("doctype {}", name)),
+            }
+        }
+    }
+
+    /// The element tree as `tag(child,child)`, text as `"text"`
+    fn outline(element: &Element) -> String {
+        let children: Vec<String> = element.children.iter().map(|child| match child {
+            Node::Element(e) => outline(e),
+            Node::Text(t) => format!("\"{}\"", t),
+            Node::Comment(c) => format!("!{}", c),
+        }).collect();
+        if children.is_empty() {
+            element.tag.clone()
+        } else {
+            format!("{}({})", element.tag, children.join(","))
+        }
+    }
+
+    /// The outline of the body of `input`
+    fn body_of(input: &str) -> Result<String, String> {
+        let document = parse(input.as_bytes()).map_err(|e| format!("{:?}", e))?;
+        document.body().map(outline).ok_or_else(|| String::from("no body"))
+    }
+
+    #[kernel_test]
+    fn character_references() -> Result<(), String> {
+        check_eq!(tokens("a &amp; b &lt;&gt; &#65;&#x42; &copy"), alloc::vec![String::from("\"a & b <> AB ©\"")]);
+        // Unknown names and a bare ampersand stay as they are
+        check_eq!(tokens("&bogus; & x"), alloc::vec![String::from("\"&bogus; & x\"")]);
+        check_eq!(tokens("<a title='&quot;x&quot;' href=?a=1&b=2>"), alloc::vec![String::from("<a title=\"x\" href=?a=1&b=2>")]);
+        // A code point that is not allowed becomes the replacement character
+        check_eq!(tokens("&#0;"), alloc::vec![String::from("\"\u{FFFD}\"")]);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn malformed_markup_recovers() -> Result<(), String> {
+        check_eq!(tokens("1 < 2"), alloc::vec![String::from("\"1 < 2\"")]);
+        check_eq!(tokens("a</>b"), alloc::vec![String::from("\"ab\"")]);
+        check_eq!(tokens("<!x>"), alloc::vec![String::from("!x")]);
+        check_eq!(tokens("<!DOCTYPE html><!-- c --><P CLASS=x>"), alloc::vec![
+            String::from("doctype html"), String::from("! c "), String::from("<p class=x>"),
+        ]);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn raw_text_is_read_verbatim() -> Result<(), String> {
+        check_eq!(tokens("<script>if (a < b && c) { x = '</p>'; }</script>"), alloc::vec![
+            String::from("<script>"), String::from("\"if (a < b && c) { x = '</p>'; }\""), String::from("</script>"),
+        ]);
+        check_eq!(tokens("<style>a > b { content: '&amp;' }</STYLE>"), alloc::vec![
+            String::from("<style>"), String::from("\"a > b { content: '&amp;' }\""), String::from("</style>"),
+        ]);
+        // Titles are text too, but with references decoded
+        check_eq!(tokens("<title><b>&amp;</b></title>"), alloc::vec![
+            String::from("<title>"), String::from("\"<b>&</b>\""), String::from("</title>"),
+        ]);
+        let document = parse(b"<script>document.write('<b>')</script><style>p{}</style>").map_err(|e| format!("{:?}", e))?;
+        check_eq!(document.scripts.len(), 1);
+        check_eq!(document.scripts[0].content, b"document.write('<b>')".to_vec());
+        check_eq!(document.stylesheets.len(), 1);
+        check_eq!(document.stylesheets[0].content, "p{}");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn missing_elements_are_implied() -> Result<(), String> {
+        let document = parse(b"<title>T</title>hello").map_err(|e| format!("{:?}", e))?;
+        check_eq!(outline(&document.root), "html(head(title(\"T\")),body(\"hello\"))");
+        check_eq!(document.title(), Some(String::from("T")));
+        check!(document.doctype.is_none());
+        check_eq!(body_of("<p>one<p>two<div>three</div>")?, "body(p(\"one\"),p(\"two\"),div(\"three\"))");
+        check_eq!(body_of("<ul><li>a<li>b</ul>")?, "body(ul(li(\"a\"),li(\"b\")))");
+        check_eq!(body_of("<table><td>x</table>")?, "body(table(tbody(tr(td(\"x\")))))");
+        check_eq!(body_of("<select><option>a<option>b</select>")?, "body(select(option(\"a\"),option(\"b\")))");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn misnested_tags_are_repaired() -> Result<(), String> {
+        // The adoption agency splits the b around the p
+        check_eq!(body_of("<b>1<p>2</b>3</p>")?, "body(b(\"1\"),p(b(\"2\"),\"3\"))");
+        check_eq!(body_of("<b><i>x</b>y</i>")?, "body(b(i(\"x\")),i(\"y\"))");
+        // End tags with nothing open to close are ignored
+        check_eq!(body_of("a</span></div>b")?, "body(\"ab\")");
+        // Text inside a table moves out in front of it
+        check_eq!(body_of("<table>oops<tr><td>x</td></tr></table>")?, "body(\"oops\",table(tbody(tr(td(\"x\")))))");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn input_may_arrive_in_pieces() -> Result<(), String> {
+        // Split inside the two bytes of the é and inside the reference
+        let input = "<p>caf\u{e9} &amp; ok</p>".as_bytes();
+        let mut parser = Parser::new();
+        for piece in [&input[..7], &input[7..12], &input[12..]] {
+            parser.feed(piece);
+        }
+        let document = parser.finish();
+        check_eq!(document.body().map(|b| b.text_content()), Some(String::from("caf\u{e9} & ok")));
+        Ok(())
     }
 }
@@ -0,0 +1,146 @@
+//! Image Cache
+//!
+//! Decoded `<img>` bitmaps, keyed by absolute URL, so an image shown twice
+//! on a page or by pages visited in turn is fetched and decoded once. An
+//! entry is marked loading while its fetch is queued, and remembers a
+//! failed load so a broken image keeps its placeholder without being
+//! fetched again. Once the bitmaps pass `CACHE_LIMIT` bytes, the least
+//! recently used are dropped.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::graphics::image::Image;
+use crate::println;
+
+/// Bytes of decoded pixels the cache holds before evicting, a quarter of
+/// the kernel heap
+const CACHE_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Where an image's load stands
+#[derive(Debug, Clone)]
+pub enum ImageState {
+    /// Queued for fetching
+    Loading,
+    /// Fetched and decoded
+    Ready(Arc<Image>),
+    /// The fetch or decode failed
+    Failed,
+}
+
+struct Entry {
+    state: ImageState,
+    /// Value of `Cache::clock` when the entry was last looked up
+    last_used: u64,
+}
+
+struct Cache {
+    entries: BTreeMap<String, Entry>,
+    /// Bytes held by ready images
+    bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    entries: BTreeMap::new(),
+    bytes: 0,
+    clock: 0,
+    hits: 0,
+    misses: 0,
+});
+
+/// The state of the image at `url`, if it has been requested
+pub fn state(url: &str) -> Option<ImageState> {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let now = cache.clock;
+    match cache.entries.get_mut(url) {
+        Some(entry) => {
+            entry.last_used = now;
+            Some(entry.state.clone())
+        }
+        None => None,
+    }
+}
+
+/// The decoded image at `url`, if it has loaded
+pub fn get(url: &str) -> Option<Arc<Image>> {
+    let found = match state(url) {
+        Some(ImageState::Ready(image)) => Some(image),
+        _ => None,
+    };
+    let mut cache = CACHE.lock();
+    if found.is_some() {
+        cache.hits += 1;
+    } else {
+        cache.misses += 1;
+    }
+    found
+}
+
+/// Mark `url` as loading
+///
+/// Returns false if the image has already loaded or failed, so there is
+/// nothing to fetch.
+pub fn request(url: &str) -> bool {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let now = cache.clock;
+    match cache.entries.get(url).map(|e| &e.state) {
+        Some(ImageState::Ready(_)) | Some(ImageState::Failed) => false,
+        _ => {
+            cache.entries.insert(String::from(url), Entry { state: ImageState::Loading, last_used: now });
+            true
+        }
+    }
+}
+
+/// Record the outcome of loading `url`
+pub fn store(url: &str, image: Option<Image>) {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let now = cache.clock;
+    let state = match image {
+        Some(image) => {
+            cache.bytes += image.size_in_bytes();
+            ImageState::Ready(Arc::new(image))
+        }
+        None => ImageState::Failed,
+    };
+    let old = cache.entries.insert(String::from(url), Entry { state, last_used: now });
+    if let Some(Entry { state: ImageState::Ready(old), .. }) = old {
+        cache.bytes -= old.size_in_bytes();
+    }
+    evict(&mut cache, url);
+}
+
+/// Drop the least recently used images until the cache fits its limit,
+/// keeping the one at `keep` that was just stored
+fn evict(cache: &mut Cache, keep: &str) {
+    while cache.bytes > CACHE_LIMIT {
+        let oldest = cache.entries.iter()
+            .filter(|(url, e)| url.as_str() != keep && matches!(e.state, ImageState::Ready(_)))
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(url, _)| url.clone());
+        let url = match oldest {
+            Some(url) => url,
+            None => return,
+        };
+        if let Some(Entry { state: ImageState::Ready(image), .. }) = cache.entries.remove(&url) {
+            cache.bytes -= image.size_in_bytes();
+        }
+    }
+}
+
+/// Print cache statistics
+pub fn print_stats() {
+    let cache = CACHE.lock();
+    let ready = cache.entries.values().filter(|e| matches!(e.state, ImageState::Ready(_))).count();
+    let failed = cache.entries.values().filter(|e| matches!(e.state, ImageState::Failed)).count();
+    println!("  Images: {} cached ({} KiB), {} failed, {} hits, {} misses",
+        ready, cache.bytes / 1024, failed, cache.hits, cache.misses);
+}
@@ -0,0 +1,1944 @@
+//! The standard library
+//!
+//! Constructors and prototype methods for objects, functions, arrays,
+//! strings, numbers, errors and regular expressions, with `Math`, `JSON`,
+//! `console` and the global functions. There is no floating point library
+//! in the kernel, so the functions `Math` needs are worked out here.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::interp::{Interpreter, MAX_ARRAY_LENGTH};
+use super::regex::{Captures, Regex};
+use super::value::{number_to_string, Function, Kind, ObjRef, Value};
+use crate::info;
+
+/// Most bytes `crypto.getRandomValues` fills at once, as in Web Crypto
+const GET_RANDOM_VALUES_QUOTA: usize = 65536;
+
+/// Deepest `JSON.stringify` and `JSON.parse` go into nested values
+const MAX_JSON_DEPTH: usize = 64;
+
+const LN_2: f64 = core::f64::consts::LN_2;
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or(Value::Undefined)
+}
+
+/// A global object whose properties are all methods
+fn namespace(interp: &mut Interpreter, name: &str, methods: &[(&str, super::value::Native)]) -> ObjRef {
+    let obj = interp.new_object().ok().and_then(|v| v.object()).expect("heap full at startup");
+    for (method, f) in methods {
+        interp.define(obj, method, *f);
+    }
+    interp.heap.get_mut(interp.global).put(name, Value::Object(obj));
+    obj
+}
+
+/// A global constructor with `proto` as its prototype
+fn constructor(interp: &mut Interpreter, name: &str, f: super::value::Native, proto: ObjRef) -> ObjRef {
+    let function = interp.native(f).ok().and_then(|v| v.object()).expect("heap full at startup");
+    interp.heap.get_mut(function).put("prototype", Value::Object(proto));
+    interp.heap.get_mut(proto).put("constructor", Value::Object(function));
+    interp.heap.get_mut(interp.global).put(name, Value::Object(function));
+    function
+}
+
+/// Give the interpreter its standard library
+pub fn install(interp: &mut Interpreter) {
+    let global = interp.global;
+    let protos = [
+        interp.protos.object, interp.protos.function, interp.protos.array, interp.protos.string,
+        interp.protos.number, interp.protos.boolean, interp.protos.error, interp.protos.regexp,
+    ];
+    let [object, function, array, string, number, boolean, error, regexp] = protos;
+
+    let object_ctor = constructor(interp, "Object", object_new, object);
+    for (name, f) in [("keys", object_keys as super::value::Native), ("values", object_values), ("entries", object_entries), ("assign", object_assign), ("freeze", identity)] {
+        interp.define(object_ctor, name, f);
+    }
+    interp.define(object, "hasOwnProperty", object_has_own);
+    interp.define(object, "toString", object_to_string);
+
+    constructor(interp, "Function", not_supported, function);
+    interp.define(function, "call", function_call);
+    interp.define(function, "apply", function_apply);
+    interp.define(function, "bind", function_bind);
+    interp.define(function, "toString", function_to_string);
+
+    let array_ctor = constructor(interp, "Array", array_new, array);
+    interp.define(array_ctor, "isArray", array_is_array);
+    interp.define(array_ctor, "from", array_from);
+    let array_methods: &[(&str, super::value::Native)] = &[
+        ("push", array_push), ("pop", array_pop), ("shift", array_shift), ("unshift", array_unshift),
+        ("slice", array_slice), ("splice", array_splice), ("concat", array_concat), ("join", array_join),
+        ("toString", array_join), ("indexOf", array_index_of), ("lastIndexOf", array_last_index_of),
+        ("includes", array_includes), ("find", array_find), ("findIndex", array_find_index),
+        ("filter", array_filter), ("map", array_map), ("forEach", array_for_each), ("reduce", array_reduce),
+        ("some", array_some), ("every", array_every), ("reverse", array_reverse), ("sort", array_sort),
+        ("fill", array_fill), ("at", array_at), ("flat", array_flat),
+    ];
+    for (name, f) in array_methods {
+        interp.define(array, name, *f);
+    }
+
+    constructor(interp, "String", string_new, string);
+    let string_methods: &[(&str, super::value::Native)] = &[
+        ("charAt", string_char_at), ("charCodeAt", string_char_code_at), ("indexOf", string_index_of),
+        ("lastIndexOf", string_last_index_of), ("includes", string_includes), ("startsWith", string_starts_with),
+        ("endsWith", string_ends_with), ("slice", string_slice), ("substring", string_substring),
+        ("substr", string_substr), ("toUpperCase", string_to_upper), ("toLowerCase", string_to_lower),
+        ("trim", string_trim), ("trimStart", string_trim_start), ("trimEnd", string_trim_end),
+        ("split", string_split), ("replace", string_replace), ("replaceAll", string_replace_all),
+        ("match", string_match), ("padStart", string_pad_start), ("padEnd", string_pad_end),
+        ("repeat", string_repeat), ("concat", string_concat), ("toString", string_to_string),
+        ("valueOf", string_to_string), ("localeCompare", string_locale_compare), ("at", string_at),
+    ];
+    for (name, f) in string_methods {
+        interp.define(string, name, *f);
+    }
+
+    let number_ctor = constructor(interp, "Number", number_new, number);
+    interp.define(number_ctor, "isInteger", number_is_integer);
+    interp.define(number_ctor, "isNaN", number_is_nan);
+    interp.define(number_ctor, "isFinite", number_is_finite);
+    interp.define(number_ctor, "parseFloat", parse_float);
+    interp.define(number_ctor, "parseInt", parse_int);
+    interp.heap.get_mut(number_ctor).put("MAX_SAFE_INTEGER", Value::Number(9007199254740991.0));
+    interp.heap.get_mut(number_ctor).put("MIN_SAFE_INTEGER", Value::Number(-9007199254740991.0));
+    interp.define(number, "toFixed", number_to_fixed);
+    interp.define(number, "toString", number_to_string_method);
+    interp.define(number, "valueOf", this_value);
+
+    constructor(interp, "Boolean", boolean_new, boolean);
+    interp.define(boolean, "toString", boolean_to_string);
+    interp.define(boolean, "valueOf", this_value);
+
+    constructor(interp, "Error", error_new, error);
+    interp.heap.get_mut(error).put("name", Value::str("Error"));
+    interp.heap.get_mut(error).put("message", Value::str(""));
+    interp.define(error, "toString", error_to_string);
+    let subtypes: [(&str, super::value::Native); 4] = [
+        ("TypeError", type_error_new), ("RangeError", range_error_new),
+        ("SyntaxError", syntax_error_new), ("ReferenceError", reference_error_new),
+    ];
+    for (name, f) in subtypes {
+        if let Ok(Value::Object(proto)) = interp.new_object() {
+            interp.heap.get_mut(proto).proto = Some(error);
+            interp.heap.get_mut(proto).put("name", Value::str(name));
+            constructor(interp, name, f, proto);
+        }
+    }
+
+    constructor(interp, "RegExp", regexp_new, regexp);
+    interp.define(regexp, "test", regexp_test);
+    interp.define(regexp, "exec", regexp_exec);
+    interp.define(regexp, "toString", regexp_to_string);
+
+    let math = namespace(interp, "Math", &[
+        ("floor", math_floor), ("ceil", math_ceil), ("round", math_round), ("trunc", math_trunc),
+        ("abs", math_abs), ("sign", math_sign), ("min", math_min), ("max", math_max), ("sqrt", math_sqrt),
+        ("pow", math_pow), ("exp", math_exp), ("log", math_log), ("random", math_random),
+    ]);
+    interp.heap.get_mut(math).put("PI", Value::Number(core::f64::consts::PI));
+    interp.heap.get_mut(math).put("E", Value::Number(core::f64::consts::E));
+    namespace(interp, "JSON", &[("stringify", json_stringify), ("parse", json_parse)]);
+    namespace(interp, "console", &[("log", console_log), ("info", console_log), ("warn", console_log), ("error", console_log), ("debug", console_log)]);
+    namespace(interp, "crypto", &[("getRandomValues", get_random_values)]);
+    namespace(interp, "Date", &[("now", date_now)]);
+
+    for (name, f) in [
+        ("parseInt", parse_int as super::value::Native), ("parseFloat", parse_float), ("isNaN", is_nan),
+        ("isFinite", is_finite), ("encodeURIComponent", encode_uri_component),
+        ("decodeURIComponent", decode_uri_component),
+    ] {
+        interp.define(global, name, f);
+    }
+    let globals = interp.heap.get_mut(global);
+    globals.put("undefined", Value::Undefined);
+    globals.put("NaN", Value::Number(f64::NAN));
+    globals.put("Infinity", Value::Number(f64::INFINITY));
+    globals.put("globalThis", Value::Object(global));
+}
+
+// Arithmetic
+
+pub fn trunc(x: f64) -> f64 {
+    // Past 2^52 every double is already whole
+    if !x.is_finite() || x.abs() >= 4503599627370496.0 {
+        return x;
+    }
+    let t = x as i64 as f64;
+    // Keep the sign of negative zero and of small negatives
+    if t == 0.0 && x.is_sign_negative() { -0.0 } else { t }
+}
+
+pub fn floor(x: f64) -> f64 {
+    let t = trunc(x);
+    if t > x { t - 1.0 } else { t }
+}
+
+pub fn ceil(x: f64) -> f64 {
+    let t = trunc(x);
+    if t < x { t + 1.0 } else { t }
+}
+
+/// Round half up, as `Math.round` does
+pub fn round(x: f64) -> f64 {
+    if !x.is_finite() {
+        return x;
+    }
+    floor(x + 0.5)
+}
+
+/// `a % b` with the sign of `a`
+pub fn fmod(a: f64, b: f64) -> f64 {
+    if !a.is_finite() || b == 0.0 || b.is_nan() {
+        return f64::NAN;
+    }
+    if b.is_infinite() {
+        return a;
+    }
+    a - trunc(a / b) * b
+}
+
+pub fn sqrt(x: f64) -> f64 {
+    if x.is_nan() || x < 0.0 {
+        return f64::NAN;
+    }
+    if x == 0.0 || x.is_infinite() {
+        return x;
+    }
+    // Halving the exponent gives a first guess Newton's method refines
+    let mut guess = f64::from_bits((x.to_bits() >> 1) + (1023u64 << 51));
+    for _ in 0..6 {
+        guess = 0.5 * (guess + x / guess);
+    }
+    guess
+}
+
+/// Natural logarithm
+pub fn ln(x: f64) -> f64 {
+    if x.is_nan() || x < 0.0 {
+        return f64::NAN;
+    }
+    if x == 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if x.is_infinite() {
+        return x;
+    }
+    // x = m * 2^k with m in [1, 2), and ln m = 2 atanh((m - 1) / (m + 1))
+    let bits = x.to_bits();
+    let mut k = ((bits >> 52) & 0x7FF) as i64 - 1023;
+    let mut m = f64::from_bits((bits & 0x000F_FFFF_FFFF_FFFF) | (1023u64 << 52));
+    if k == -1023 {
+        // Subnormal: scale up first
+        let scaled = ln(x * 4503599627370496.0);
+        return scaled - 52.0 * LN_2;
+    }
+    if m > core::f64::consts::SQRT_2 {
+        m /= 2.0;
+        k += 1;
+    }
+    let s = (m - 1.0) / (m + 1.0);
+    let s2 = s * s;
+    let mut term = s;
+    let mut sum = 0.0;
+    let mut n = 1.0;
+    while n < 60.0 {
+        sum += term / n;
+        term *= s2;
+        n += 2.0;
+    }
+    2.0 * sum + k as f64 * LN_2
+}
+
+pub fn exp(x: f64) -> f64 {
+    if x.is_nan() {
+        return x;
+    }
+    if x > 709.8 {
+        return f64::INFINITY;
+    }
+    if x < -745.2 {
+        return 0.0;
+    }
+    // e^x = 2^k e^r with |r| <= ln 2 / 2
+    let k = round(x / LN_2);
+    let r = x - k * LN_2;
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for n in 1..24 {
+        term *= r / n as f64;
+        sum += term;
+    }
+    let mut result = sum;
+    let mut k = k as i64;
+    while k > 0 {
+        let step = k.min(1000);
+        result *= f64::from_bits(((step + 1023) as u64) << 52);
+        k -= step;
+    }
+    while k < 0 {
+        let step = (-k).min(1000);
+        result /= f64::from_bits(((step + 1023) as u64) << 52);
+        k += step;
+    }
+    result
+}
+
+pub fn pow(base: f64, exponent: f64) -> f64 {
+    if exponent == 0.0 {
+        return 1.0;
+    }
+    if base.is_nan() || exponent.is_nan() {
+        return f64::NAN;
+    }
+    if trunc(exponent) == exponent && exponent.abs() < 2147483648.0 {
+        let mut result = 1.0;
+        let mut b = base;
+        let mut e = exponent.abs() as u64;
+        while e > 0 {
+            if e & 1 == 1 {
+                result *= b;
+            }
+            b *= b;
+            e >>= 1;
+        }
+        return if exponent < 0.0 { 1.0 / result } else { result };
+    }
+    if base < 0.0 {
+        return f64::NAN;
+    }
+    if base == 0.0 {
+        return if exponent > 0.0 { 0.0 } else { f64::INFINITY };
+    }
+    exp(exponent * ln(base))
+}
+
+// Objects and functions
+
+fn identity(_: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(arg(args, 0))
+}
+
+fn this_value(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    interp.to_primitive(&this)
+}
+
+fn not_supported(interp: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    Err(interp.error("Error", "Not supported"))
+}
+
+fn object_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    match arg(args, 0) {
+        value @ Value::Object(_) => Ok(value),
+        _ => interp.new_object(),
+    }
+}
+
+fn object_keys(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let keys = interp.keys(&arg(args, 0)).into_iter().map(Value::String).collect();
+    interp.new_array(keys)
+}
+
+fn object_values(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let obj = arg(args, 0);
+    let mut values = Vec::new();
+    for key in interp.keys(&obj) {
+        values.push(interp.get(&obj, &key)?);
+    }
+    interp.new_array(values)
+}
+
+fn object_entries(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let obj = arg(args, 0);
+    let mut entries = Vec::new();
+    for key in interp.keys(&obj) {
+        let value = interp.get(&obj, &key)?;
+        entries.push(interp.new_array(alloc::vec![Value::String(key), value])?);
+    }
+    interp.new_array(entries)
+}
+
+fn object_assign(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let target = arg(args, 0);
+    for source in args.iter().skip(1) {
+        for key in interp.keys(source) {
+            let value = interp.get(source, &key)?;
+            interp.put(&target, &key, value)?;
+        }
+    }
+    Ok(target)
+}
+
+fn object_has_own(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let key = interp.to_string(&arg(args, 0))?;
+    Ok(Value::Bool(interp.keys(&this).contains(&key)))
+}
+
+fn object_to_string(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let tag = match &this {
+        Value::Object(obj) => match interp.heap.get(*obj).kind {
+            Kind::Array(_) => "Array",
+            Kind::Function(_) => "Function",
+            Kind::Element(_) => "HTMLElement",
+            _ => "Object",
+        },
+        Value::Undefined => "Undefined",
+        Value::Null => "Null",
+        _ => "Object",
+    };
+    Ok(Value::String(format!("[object {}]", tag)))
+}
+
+fn function_call(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let rest = args.get(1..).unwrap_or(&[]);
+    interp.call(&this, arg(args, 0), rest)
+}
+
+fn function_apply(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let list = match arg(args, 1) {
+        Value::Undefined | Value::Null => Vec::new(),
+        list => interp.iterate(&list)?,
+    };
+    interp.call(&this, arg(args, 0), &list)
+}
+
+fn function_bind(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let target = match this {
+        Value::Object(target) if interp.is_callable(&this) => target,
+        _ => return Err(interp.type_error("Bind must be called on a function")),
+    };
+    let bound = Function::Bound { target, this: arg(args, 0), args: args.get(1..).unwrap_or(&[]).to_vec() };
+    let proto = interp.protos.function;
+    interp.alloc(Kind::Function(bound), Some(proto)).map(Value::Object)
+}
+
+fn function_to_string(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let name = interp.get(&this, "name")?;
+    let name = interp.to_string(&name)?;
+    Ok(Value::String(format!("function {}() {{ [native code] }}", name)))
+}
+
+// Errors
+
+fn make_error(interp: &mut Interpreter, name: &str, args: &[Value]) -> Result<Value, Value> {
+    let message = match arg(args, 0) {
+        Value::Undefined => String::new(),
+        message => interp.to_string(&message)?,
+    };
+    Ok(interp.error(name, &message))
+}
+
+fn error_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    make_error(interp, "Error", args)
+}
+
+fn type_error_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    make_error(interp, "TypeError", args)
+}
+
+fn range_error_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    make_error(interp, "RangeError", args)
+}
+
+fn syntax_error_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    make_error(interp, "SyntaxError", args)
+}
+
+fn reference_error_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    make_error(interp, "ReferenceError", args)
+}
+
+fn error_to_string(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let name = interp.get(&this, "name")?;
+    let name = interp.to_string(&name)?;
+    let message = interp.get(&this, "message")?;
+    let message = interp.to_string(&message)?;
+    Ok(Value::String(if message.is_empty() { name } else { format!("{}: {}", name, message) }))
+}
+
+// Arrays
+
+fn this_array(interp: &mut Interpreter, this: &Value) -> Result<Vec<Value>, Value> {
+    match interp.array_items(this) {
+        Some(items) => Ok(items),
+        None => Err(interp.type_error("Not an array")),
+    }
+}
+
+/// Set the items of the array `this`
+fn set_items(interp: &mut Interpreter, this: &Value, items: Vec<Value>) {
+    if let Some(slot) = interp.array_mut(this) {
+        *slot = items;
+    }
+}
+
+/// A relative index as `slice` takes it, clamped to `0..=len`
+fn relative(interp: &mut Interpreter, value: &Value, len: usize, default: usize) -> Result<usize, Value> {
+    if let Value::Undefined = value {
+        return Ok(default);
+    }
+    let n = trunc(interp.to_number(value)?);
+    let n = if n.is_nan() { 0.0 } else { n };
+    Ok(if n < 0.0 { (len as f64 + n).max(0.0) as usize } else { n.min(len as f64) as usize })
+}
+
+fn array_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    if let [Value::Number(n)] = args {
+        if *n < 0.0 || *n > MAX_ARRAY_LENGTH as f64 || trunc(*n) != *n {
+            return Err(interp.error("RangeError", "Invalid array length"));
+        }
+        return interp.new_array(alloc::vec![Value::Undefined; *n as usize]);
+    }
+    interp.new_array(args.to_vec())
+}
+
+fn array_is_array(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(interp.array_items(&arg(args, 0)).is_some()))
+}
+
+fn array_from(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let source = arg(args, 0);
+    let items = match &source {
+        Value::Object(_) if interp.array_items(&source).is_none() => {
+            // An array-like object such as `{ length: 3 }`
+            let length = interp.get(&source, "length")?;
+            let length = interp.to_number(&length)?;
+            if !(0.0..=MAX_ARRAY_LENGTH as f64).contains(&length) {
+                return Err(interp.error("RangeError", "Invalid array length"));
+            }
+            let mut items = Vec::new();
+            for i in 0..length as usize {
+                items.push(interp.get(&source, &i.to_string())?);
+            }
+            items
+        }
+        _ => interp.iterate(&source)?,
+    };
+    let map = arg(args, 1);
+    if !interp.is_callable(&map) {
+        return interp.new_array(items);
+    }
+    let mut mapped = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        mapped.push(interp.call(&map, Value::Undefined, &[item, Value::Number(i as f64)])?);
+    }
+    interp.new_array(mapped)
+}
+
+fn array_push(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let len = this_array(interp, &this)?.len();
+    if len + args.len() > MAX_ARRAY_LENGTH {
+        return Err(interp.error("RangeError", "Invalid array length"));
+    }
+    let items = interp.array_mut(&this).unwrap();
+    items.extend_from_slice(args);
+    Ok(Value::Number(items.len() as f64))
+}
+
+fn array_pop(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    this_array(interp, &this)?;
+    Ok(interp.array_mut(&this).unwrap().pop().unwrap_or(Value::Undefined))
+}
+
+fn array_shift(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    if items.is_empty() {
+        return Ok(Value::Undefined);
+    }
+    Ok(interp.array_mut(&this).unwrap().remove(0))
+}
+
+fn array_unshift(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut items = args.to_vec();
+    items.extend(this_array(interp, &this)?);
+    if items.len() > MAX_ARRAY_LENGTH {
+        return Err(interp.error("RangeError", "Invalid array length"));
+    }
+    let len = items.len();
+    set_items(interp, &this, items);
+    Ok(Value::Number(len as f64))
+}
+
+fn array_slice(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let start = relative(interp, &arg(args, 0), items.len(), 0)?;
+    let end = relative(interp, &arg(args, 1), items.len(), items.len())?;
+    let slice = items.get(start..end.max(start)).unwrap_or(&[]).to_vec();
+    interp.new_array(slice)
+}
+
+fn array_splice(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut items = this_array(interp, &this)?;
+    let start = relative(interp, &arg(args, 0), items.len(), 0)?;
+    let count = match args.get(1) {
+        None => items.len() - start,
+        Some(count) => {
+            let count = interp.to_number(count)?;
+            if count.is_nan() { 0 } else { (count.max(0.0) as usize).min(items.len() - start) }
+        }
+    };
+    let inserted = args.get(2..).unwrap_or(&[]).iter().cloned();
+    let removed: Vec<Value> = items.splice(start..start + count, inserted).collect();
+    set_items(interp, &this, items);
+    interp.new_array(removed)
+}
+
+fn array_concat(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut items = this_array(interp, &this)?;
+    for value in args {
+        match interp.array_items(value) {
+            Some(more) => items.extend(more),
+            None => items.push(value.clone()),
+        }
+        if items.len() > MAX_ARRAY_LENGTH {
+            return Err(interp.error("RangeError", "Invalid array length"));
+        }
+    }
+    interp.new_array(items)
+}
+
+fn array_join(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let separator = match arg(args, 0) {
+        Value::Undefined => String::from(","),
+        separator => interp.to_string(&separator)?,
+    };
+    let mut text = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            text.push_str(&separator);
+        }
+        if !item.is_nullish() {
+            text.push_str(&interp.to_string(item)?);
+        }
+    }
+    Ok(Value::String(text))
+}
+
+fn array_index_of(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let target = arg(args, 0);
+    let found = items.iter().position(|item| Interpreter::strict_equals(item, &target));
+    Ok(Value::Number(found.map_or(-1.0, |i| i as f64)))
+}
+
+fn array_last_index_of(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let target = arg(args, 0);
+    let found = items.iter().rposition(|item| Interpreter::strict_equals(item, &target));
+    Ok(Value::Number(found.map_or(-1.0, |i| i as f64)))
+}
+
+fn array_includes(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let target = arg(args, 0);
+    let nan = matches!(target, Value::Number(n) if n.is_nan());
+    let found = items.iter().any(|item| Interpreter::strict_equals(item, &target) || (nan && matches!(item, Value::Number(n) if n.is_nan())));
+    Ok(Value::Bool(found))
+}
+
+/// Call `callback(item, index, array)` for each item until it returns
+/// something `stop` accepts; gives the index and result that stopped it
+fn each(interp: &mut Interpreter, this: &Value, args: &[Value], stop: impl Fn(&Value) -> bool) -> Result<Option<(usize, Value)>, Value> {
+    let items = this_array(interp, this)?;
+    let callback = arg(args, 0);
+    if !interp.is_callable(&callback) {
+        return Err(interp.type_error("callback is not a function"));
+    }
+    let receiver = arg(args, 1);
+    for (i, item) in items.into_iter().enumerate() {
+        let result = interp.call(&callback, receiver.clone(), &[item, Value::Number(i as f64), this.clone()])?;
+        if stop(&result) {
+            return Ok(Some((i, result)));
+        }
+    }
+    Ok(None)
+}
+
+fn array_find(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let found = each(interp, &this, args, Interpreter::truthy)?;
+    Ok(match found {
+        Some((i, _)) => this_array(interp, &this)?.get(i).cloned().unwrap_or(Value::Undefined),
+        None => Value::Undefined,
+    })
+}
+
+fn array_find_index(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let found = each(interp, &this, args, Interpreter::truthy)?;
+    Ok(Value::Number(found.map_or(-1.0, |(i, _)| i as f64)))
+}
+
+fn array_some(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(each(interp, &this, args, Interpreter::truthy)?.is_some()))
+}
+
+fn array_every(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(each(interp, &this, args, |v| !Interpreter::truthy(v))?.is_none()))
+}
+
+fn array_for_each(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    each(interp, &this, args, |_| false)?;
+    Ok(Value::Undefined)
+}
+
+fn array_map(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let callback = arg(args, 0);
+    if !interp.is_callable(&callback) {
+        return Err(interp.type_error("callback is not a function"));
+    }
+    let mut mapped = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        mapped.push(interp.call(&callback, arg(args, 1), &[item, Value::Number(i as f64), this.clone()])?);
+    }
+    interp.new_array(mapped)
+}
+
+fn array_filter(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let callback = arg(args, 0);
+    if !interp.is_callable(&callback) {
+        return Err(interp.type_error("callback is not a function"));
+    }
+    let mut kept = Vec::new();
+    for (i, item) in items.into_iter().enumerate() {
+        let keep = interp.call(&callback, arg(args, 1), &[item.clone(), Value::Number(i as f64), this.clone()])?;
+        if Interpreter::truthy(&keep) {
+            kept.push(item);
+        }
+    }
+    interp.new_array(kept)
+}
+
+fn array_reduce(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let callback = arg(args, 0);
+    if !interp.is_callable(&callback) {
+        return Err(interp.type_error("callback is not a function"));
+    }
+    let mut items = items.into_iter().enumerate();
+    let mut accumulator = match args.get(1) {
+        Some(initial) => initial.clone(),
+        None => match items.next() {
+            Some((_, first)) => first,
+            None => return Err(interp.type_error("Reduce of empty array with no initial value")),
+        },
+    };
+    for (i, item) in items {
+        accumulator = interp.call(&callback, Value::Undefined, &[accumulator, item, Value::Number(i as f64), this.clone()])?;
+    }
+    Ok(accumulator)
+}
+
+fn array_reverse(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    this_array(interp, &this)?;
+    interp.array_mut(&this).unwrap().reverse();
+    Ok(this)
+}
+
+/// Whether `a` sorts after `b`
+fn sorts_after(interp: &mut Interpreter, compare: &Value, a: &Value, b: &Value) -> Result<bool, Value> {
+    // Undefined always goes last
+    match (a, b) {
+        (Value::Undefined, _) => return Ok(!matches!(b, Value::Undefined)),
+        (_, Value::Undefined) => return Ok(false),
+        _ => {}
+    }
+    if interp.is_callable(compare) {
+        let order = interp.call(compare, Value::Undefined, &[a.clone(), b.clone()])?;
+        return Ok(interp.to_number(&order)? > 0.0);
+    }
+    let a = interp.to_string(a)?;
+    let b = interp.to_string(b)?;
+    Ok(a > b)
+}
+
+/// A stable merge sort, which the comparator may throw out of
+fn array_sort(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut items = this_array(interp, &this)?;
+    let compare = arg(args, 0);
+    let mut width = 1;
+    let mut buffer = Vec::with_capacity(items.len());
+    while width < items.len() {
+        buffer.clear();
+        let mut start = 0;
+        while start < items.len() {
+            let middle = (start + width).min(items.len());
+            let end = (start + 2 * width).min(items.len());
+            let (mut i, mut j) = (start, middle);
+            while i < middle && j < end {
+                if sorts_after(interp, &compare, &items[i], &items[j])? {
+                    buffer.push(items[j].clone());
+                    j += 1;
+                } else {
+                    buffer.push(items[i].clone());
+                    i += 1;
+                }
+            }
+            buffer.extend_from_slice(&items[i..middle]);
+            buffer.extend_from_slice(&items[j..end]);
+            start = end;
+        }
+        core::mem::swap(&mut items, &mut buffer);
+        width *= 2;
+    }
+    set_items(interp, &this, items);
+    Ok(this)
+}
+
+fn array_fill(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let start = relative(interp, &arg(args, 1), items.len(), 0)?;
+    let end = relative(interp, &arg(args, 2), items.len(), items.len())?;
+    let value = arg(args, 0);
+    if let Some(items) = interp.array_mut(&this) {
+        for slot in items.iter_mut().take(end).skip(start) {
+            *slot = value.clone();
+        }
+    }
+    Ok(this)
+}
+
+fn array_at(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = this_array(interp, &this)?;
+    let n = trunc(interp.to_number(&arg(args, 0))?);
+    let i = if n < 0.0 { items.len() as f64 + n } else { n };
+    Ok(if i >= 0.0 { items.get(i as usize).cloned().unwrap_or(Value::Undefined) } else { Value::Undefined })
+}
+
+fn array_flat(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let mut flat = Vec::new();
+    for item in this_array(interp, &this)? {
+        match interp.array_items(&item) {
+            Some(inner) => flat.extend(inner),
+            None => flat.push(item),
+        }
+    }
+    interp.new_array(flat)
+}
+
+// Strings
+
+fn this_string(interp: &mut Interpreter, this: &Value) -> Result<String, Value> {
+    if this.is_nullish() {
+        return Err(interp.type_error("String method called on null or undefined"));
+    }
+    interp.to_string(this)
+}
+
+fn string_arg(interp: &mut Interpreter, args: &[Value], i: usize) -> Result<String, Value> {
+    interp.to_string(&arg(args, i))
+}
+
+fn chars(s: &str) -> Vec<char> {
+    s.chars().collect()
+}
+
+/// Character position of `needle` in `haystack` at or after `from`
+fn find_chars(haystack: &[char], needle: &[char], from: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return (from <= haystack.len()).then_some(from);
+    }
+    (from..haystack.len()).find(|&i| haystack[i..].starts_with(needle))
+}
+
+fn string_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    if args.is_empty() {
+        return Ok(Value::str(""));
+    }
+    interp.to_string(&args[0]).map(Value::String)
+}
+
+fn string_to_string(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    this_string(interp, &this).map(Value::String)
+}
+
+fn string_char_at(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let i = interp.to_number(&arg(args, 0))?;
+    let c = if i >= 0.0 { s.chars().nth(i as usize) } else { None };
+    Ok(Value::String(c.map(|c| c.to_string()).unwrap_or_default()))
+}
+
+fn string_char_code_at(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let i = interp.to_number(&arg(args, 0))?;
+    let i = if i.is_nan() { 0.0 } else { i };
+    let c = if i >= 0.0 { s.chars().nth(i as usize) } else { None };
+    Ok(Value::Number(c.map_or(f64::NAN, |c| c as u32 as f64)))
+}
+
+fn string_index_of(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let needle = chars(&string_arg(interp, args, 0)?);
+    let from = relative(interp, &arg(args, 1), s.len(), 0)?;
+    Ok(Value::Number(find_chars(&s, &needle, from).map_or(-1.0, |i| i as f64)))
+}
+
+fn string_last_index_of(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let needle = chars(&string_arg(interp, args, 0)?);
+    let found = (0..=s.len().saturating_sub(needle.len())).rev().find(|&i| s[i..].starts_with(&needle));
+    Ok(Value::Number(found.map_or(-1.0, |i| i as f64)))
+}
+
+fn string_includes(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let needle = string_arg(interp, args, 0)?;
+    Ok(Value::Bool(s.contains(needle.as_str())))
+}
+
+fn string_starts_with(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let needle = chars(&string_arg(interp, args, 0)?);
+    let from = relative(interp, &arg(args, 1), s.len(), 0)?;
+    Ok(Value::Bool(s[from..].starts_with(&needle)))
+}
+
+fn string_ends_with(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let needle = chars(&string_arg(interp, args, 0)?);
+    let end = relative(interp, &arg(args, 1), s.len(), s.len())?;
+    Ok(Value::Bool(s[..end].ends_with(&needle)))
+}
+
+fn string_slice(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let start = relative(interp, &arg(args, 0), s.len(), 0)?;
+    let end = relative(interp, &arg(args, 1), s.len(), s.len())?;
+    Ok(Value::String(s.get(start..end.max(start)).unwrap_or(&[]).iter().collect()))
+}
+
+fn string_substring(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    // Unlike `slice`, negative positions count as zero and the ends swap
+    let clamp = |interp: &mut Interpreter, v: &Value, default: usize| -> Result<usize, Value> {
+        if let Value::Undefined = v {
+            return Ok(default);
+        }
+        let n = interp.to_number(v)?;
+        Ok(if n.is_nan() || n < 0.0 { 0 } else { (n as usize).min(s.len()) })
+    };
+    let a = clamp(interp, &arg(args, 0), 0)?;
+    let b = clamp(interp, &arg(args, 1), s.len())?;
+    Ok(Value::String(s[a.min(b)..a.max(b)].iter().collect()))
+}
+
+fn string_substr(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let start = relative(interp, &arg(args, 0), s.len(), 0)?;
+    let length = match arg(args, 1) {
+        Value::Undefined => s.len() - start,
+        length => interp.to_number(&length)?.max(0.0) as usize,
+    };
+    Ok(Value::String(s[start..(start + length).min(s.len())].iter().collect()))
+}
+
+fn string_to_upper(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::String(this_string(interp, &this)?.to_uppercase()))
+}
+
+fn string_to_lower(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::String(this_string(interp, &this)?.to_lowercase()))
+}
+
+fn string_trim(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::str(this_string(interp, &this)?.trim()))
+}
+
+fn string_trim_start(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::str(this_string(interp, &this)?.trim_start()))
+}
+
+fn string_trim_end(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::str(this_string(interp, &this)?.trim_end()))
+}
+
+/// The compiled expression of a RegExp object
+fn regex_of(interp: &Interpreter, value: &Value) -> Option<(Arc<Regex>, String)> {
+    match &interp.heap.get(value.object()?).kind {
+        Kind::Regex(regex, flags) => Some((regex.clone(), flags.clone())),
+        _ => None,
+    }
+}
+
+fn string_split(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let limit = match arg(args, 1) {
+        Value::Undefined => usize::MAX,
+        limit => interp.to_number(&limit)? as usize,
+    };
+    let separator = arg(args, 0);
+    let mut parts: Vec<Value> = Vec::new();
+    if let Some((regex, _)) = regex_of(interp, &separator) {
+        // Captured groups go into the result between the pieces
+        let text = chars(&s);
+        if text.is_empty() {
+            if regex.find_at(&text, 0).is_none() {
+                parts.push(Value::str(""));
+            }
+            return interp.new_array(parts);
+        }
+        let mut last = 0;
+        let mut pos = 0;
+        while pos < text.len() {
+            let caps = match regex.find_at(&text, pos) {
+                Some(caps) => caps,
+                None => break,
+            };
+            let (start, end) = caps[0].unwrap();
+            if end == start && (end == last || start >= text.len()) {
+                pos = start + 1;
+                continue;
+            }
+            parts.push(Value::String(text[last..start].iter().collect()));
+            for group in &caps[1..] {
+                parts.push(group.map_or(Value::Undefined, |(a, b)| Value::String(text[a..b].iter().collect())));
+            }
+            last = end;
+            pos = if end > start { end } else { end + 1 };
+        }
+        parts.push(Value::String(text[last..].iter().collect()));
+    } else if let Value::Undefined = separator {
+        parts.push(Value::String(s));
+    } else {
+        let separator = interp.to_string(&separator)?;
+        if separator.is_empty() {
+            parts.extend(s.chars().map(|c| Value::String(c.to_string())));
+        } else {
+            parts.extend(s.split(separator.as_str()).map(Value::str));
+        }
+    }
+    parts.truncate(limit);
+    interp.new_array(parts)
+}
+
+/// The text a replacement pattern such as `<$1>` stands for
+fn expand(replacement: &str, text: &[char], caps: &Captures) -> String {
+    let mut out = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        let (start, end) = caps[0].unwrap();
+        match chars.peek().copied() {
+            Some('$') => {
+                chars.next();
+                out.push('$');
+            }
+            Some('&') => {
+                chars.next();
+                out.extend(&text[start..end]);
+            }
+            Some('`') => {
+                chars.next();
+                out.extend(&text[..start]);
+            }
+            Some('\'') => {
+                chars.next();
+                out.extend(&text[end..]);
+            }
+            Some(d) if d.is_ascii_digit() => {
+                chars.next();
+                let mut n = d.to_digit(10).unwrap() as usize;
+                if let Some(d2) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    if n * 10 + (d2 as usize) < caps.len() {
+                        chars.next();
+                        n = n * 10 + d2 as usize;
+                    }
+                }
+                match caps.get(n) {
+                    Some(group) if n > 0 => {
+                        if let Some((a, b)) = group {
+                            out.extend(&text[*a..*b]);
+                        }
+                    }
+                    _ => {
+                        out.push('$');
+                        out.push(d);
+                    }
+                }
+            }
+            _ => out.push('$'),
+        }
+    }
+    out
+}
+
+fn replace(interp: &mut Interpreter, this: Value, args: &[Value], all: bool) -> Result<Value, Value> {
+    let text = chars(&this_string(interp, &this)?);
+    let pattern = arg(args, 0);
+    let replacement = arg(args, 1);
+    let mut matches: Vec<Captures> = Vec::new();
+    if let Some((regex, flags)) = regex_of(interp, &pattern) {
+        let global = all || flags.contains('g');
+        let mut pos = 0;
+        while pos <= text.len() {
+            let caps = match regex.find_at(&text, pos) {
+                Some(caps) => caps,
+                None => break,
+            };
+            let (start, end) = caps[0].unwrap();
+            matches.push(caps);
+            if !global {
+                break;
+            }
+            pos = if end > start { end } else { end + 1 };
+        }
+    } else {
+        let needle = chars(&interp.to_string(&pattern)?);
+        let mut pos = 0;
+        while let Some(start) = find_chars(&text, &needle, pos) {
+            matches.push(alloc::vec![Some((start, start + needle.len()))]);
+            if !all {
+                break;
+            }
+            pos = if needle.is_empty() { start + 1 } else { start + needle.len() };
+        }
+    }
+    let template = if interp.is_callable(&replacement) { None } else { Some(interp.to_string(&replacement)?) };
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in &matches {
+        let (start, end) = caps[0].unwrap();
+        out.extend(&text[last..start]);
+        match &template {
+            Some(template) => out.push_str(&expand(template, &text, caps)),
+            None => {
+                let mut call_args: Vec<Value> = caps.iter()
+                    .map(|group| group.map_or(Value::Undefined, |(a, b)| Value::String(text[a..b].iter().collect())))
+                    .collect();
+                call_args.push(Value::Number(start as f64));
+                call_args.push(Value::String(text.iter().collect()));
+                let result = interp.call(&replacement, Value::Undefined, &call_args)?;
+                out.push_str(&interp.to_string(&result)?);
+            }
+        }
+        last = end;
+    }
+    out.extend(&text[last..]);
+    Ok(Value::String(out))
+}
+
+fn string_replace(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    replace(interp, this, args, false)
+}
+
+fn string_replace_all(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    replace(interp, this, args, true)
+}
+
+/// The array `exec` gives for a match: the text of each group, with the
+/// match's `index` and `input`
+fn match_array(interp: &mut Interpreter, text: &[char], caps: &Captures) -> Result<Value, Value> {
+    let groups = caps.iter()
+        .map(|group| group.map_or(Value::Undefined, |(a, b)| Value::String(text[a..b].iter().collect())))
+        .collect();
+    let array = interp.new_array(groups)?;
+    interp.put(&array, "index", Value::Number(caps[0].unwrap().0 as f64))?;
+    interp.put(&array, "input", Value::String(text.iter().collect()))?;
+    Ok(array)
+}
+
+fn string_match(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let pattern = arg(args, 0);
+    let (regex, flags) = match regex_of(interp, &pattern) {
+        Some(found) => found,
+        None => {
+            let source = interp.to_string(&pattern)?;
+            let regex = interp.new_regex(&source, "")?;
+            regex_of(interp, &regex).unwrap()
+        }
+    };
+    let text = chars(&s);
+    if !flags.contains('g') {
+        return match regex.find_at(&text, 0) {
+            Some(caps) => match_array(interp, &text, &caps),
+            None => Ok(Value::Null),
+        };
+    }
+    let mut found = Vec::new();
+    let mut pos = 0;
+    while pos <= text.len() {
+        let caps = match regex.find_at(&text, pos) {
+            Some(caps) => caps,
+            None => break,
+        };
+        let (start, end) = caps[0].unwrap();
+        found.push(Value::String(text[start..end].iter().collect()));
+        pos = if end > start { end } else { end + 1 };
+    }
+    if found.is_empty() {
+        return Ok(Value::Null);
+    }
+    interp.new_array(found)
+}
+
+fn pad(interp: &mut Interpreter, this: Value, args: &[Value], at_start: bool) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let length = interp.to_number(&arg(args, 0))?;
+    let filler = match arg(args, 1) {
+        Value::Undefined => String::from(" "),
+        filler => interp.to_string(&filler)?,
+    };
+    let current = s.chars().count();
+    if length.is_nan() || length as usize <= current || filler.is_empty() {
+        return Ok(Value::String(s));
+    }
+    let wanted = (length as usize).min(MAX_ARRAY_LENGTH) - current;
+    let padding: String = filler.chars().cycle().take(wanted).collect();
+    Ok(Value::String(if at_start { padding + &s } else { s + &padding }))
+}
+
+fn string_pad_start(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    pad(interp, this, args, true)
+}
+
+fn string_pad_end(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    pad(interp, this, args, false)
+}
+
+fn string_repeat(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let count = interp.to_number(&arg(args, 0))?;
+    if count < 0.0 || count.is_infinite() || s.len() as f64 * count > MAX_ARRAY_LENGTH as f64 {
+        return Err(interp.error("RangeError", "Invalid count value"));
+    }
+    Ok(Value::String(s.repeat(if count.is_nan() { 0 } else { count as usize })))
+}
+
+fn string_concat(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut s = this_string(interp, &this)?;
+    for value in args {
+        s.push_str(&interp.to_string(value)?);
+    }
+    Ok(Value::String(s))
+}
+
+fn string_locale_compare(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = this_string(interp, &this)?;
+    let other = string_arg(interp, args, 0)?;
+    Ok(Value::Number(match s.cmp(&other) {
+        core::cmp::Ordering::Less => -1.0,
+        core::cmp::Ordering::Equal => 0.0,
+        core::cmp::Ordering::Greater => 1.0,
+    }))
+}
+
+fn string_at(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = chars(&this_string(interp, &this)?);
+    let n = trunc(interp.to_number(&arg(args, 0))?);
+    let i = if n < 0.0 { s.len() as f64 + n } else { n };
+    Ok(if i >= 0.0 { s.get(i as usize).map_or(Value::Undefined, |c| Value::String(c.to_string())) } else { Value::Undefined })
+}
+
+// Numbers and booleans
+
+fn number_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    if args.is_empty() {
+        return Ok(Value::Number(0.0));
+    }
+    interp.to_number(&args[0]).map(Value::Number)
+}
+
+fn this_number(interp: &mut Interpreter, this: &Value) -> Result<f64, Value> {
+    match interp.to_primitive(this)? {
+        Value::Number(n) => Ok(n),
+        _ => Err(interp.type_error("Not a number")),
+    }
+}
+
+fn number_to_fixed(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let n = this_number(interp, &this)?;
+    let digits = interp.to_number(&arg(args, 0))?;
+    if !(0.0..=100.0).contains(&digits) && !digits.is_nan() {
+        return Err(interp.error("RangeError", "toFixed() digits argument must be between 0 and 100"));
+    }
+    if !n.is_finite() || n.abs() >= 1e21 {
+        return Ok(Value::String(number_to_string(n)));
+    }
+    let digits = if digits.is_nan() { 0 } else { digits as usize };
+    Ok(Value::String(format!("{:.*}", digits, n)))
+}
+
+fn number_to_string_method(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let n = this_number(interp, &this)?;
+    let radix = match arg(args, 0) {
+        Value::Undefined => 10,
+        radix => interp.to_number(&radix)? as u32,
+    };
+    if !(2..=36).contains(&radix) {
+        return Err(interp.error("RangeError", "toString() radix must be between 2 and 36"));
+    }
+    if radix == 10 || !n.is_finite() {
+        return Ok(Value::String(number_to_string(n)));
+    }
+    // Whole part only in other bases
+    let mut whole = trunc(n.abs()) as u64;
+    let mut digits = Vec::new();
+    loop {
+        digits.push(core::char::from_digit((whole % radix as u64) as u32, radix).unwrap());
+        whole /= radix as u64;
+        if whole == 0 {
+            break;
+        }
+    }
+    if n < 0.0 {
+        digits.push('-');
+    }
+    Ok(Value::String(digits.iter().rev().collect()))
+}
+
+fn number_is_integer(_: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(matches!(arg(args, 0), Value::Number(n) if n.is_finite() && trunc(n) == n)))
+}
+
+fn number_is_nan(_: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(matches!(arg(args, 0), Value::Number(n) if n.is_nan())))
+}
+
+fn number_is_finite(_: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(matches!(arg(args, 0), Value::Number(n) if n.is_finite())))
+}
+
+fn boolean_new(_: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(Interpreter::truthy(&arg(args, 0))))
+}
+
+fn boolean_to_string(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let value = interp.to_primitive(&this)?;
+    interp.to_string(&value).map(Value::String)
+}
+
+fn is_nan(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(interp.to_number(&arg(args, 0))?.is_nan()))
+}
+
+fn is_finite(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(interp.to_number(&arg(args, 0))?.is_finite()))
+}
+
+fn parse_int(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = string_arg(interp, args, 0)?;
+    let mut radix = match arg(args, 1) {
+        Value::Undefined => 0,
+        radix => interp.to_number(&radix)? as u32,
+    };
+    let mut s = s.trim_start();
+    let negative = s.starts_with('-');
+    if negative || s.starts_with('+') {
+        s = &s[1..];
+    }
+    if (radix == 0 || radix == 16) && (s.starts_with("0x") || s.starts_with("0X")) {
+        s = &s[2..];
+        radix = 16;
+    }
+    if radix == 0 {
+        radix = 10;
+    }
+    if !(2..=36).contains(&radix) {
+        return Ok(Value::Number(f64::NAN));
+    }
+    let mut value = 0f64;
+    let mut any = false;
+    for digit in s.chars().map_while(|c| c.to_digit(radix)) {
+        value = value * radix as f64 + digit as f64;
+        any = true;
+    }
+    if !any {
+        return Ok(Value::Number(f64::NAN));
+    }
+    Ok(Value::Number(if negative { -value } else { value }))
+}
+
+fn parse_float(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let s = string_arg(interp, args, 0)?;
+    let s = s.trim_start();
+    if s.starts_with("Infinity") || s.starts_with("+Infinity") {
+        return Ok(Value::Number(f64::INFINITY));
+    }
+    if s.starts_with("-Infinity") {
+        return Ok(Value::Number(f64::NEG_INFINITY));
+    }
+    // The longest prefix that reads as a number
+    let bytes = s.as_bytes();
+    let mut end = 0;
+    let mut best = f64::NAN;
+    while end < bytes.len() && (bytes[end].is_ascii_digit() || b"+-.eE".contains(&bytes[end])) {
+        end += 1;
+        if let Ok(n) = s[..end].parse::<f64>() {
+            best = n;
+        }
+    }
+    Ok(Value::Number(best))
+}
+
+// Regular expressions
+
+fn regexp_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let pattern = arg(args, 0);
+    let source = match regex_of(interp, &pattern) {
+        Some((regex, _)) => regex.source.clone(),
+        None if matches!(pattern, Value::Undefined) => String::from("(?:)"),
+        None => interp.to_string(&pattern)?,
+    };
+    let flags = match arg(args, 1) {
+        Value::Undefined => String::new(),
+        flags => interp.to_string(&flags)?,
+    };
+    interp.new_regex(&source, &flags)
+}
+
+/// Run the expression `this` on the first argument from its `lastIndex`
+/// if it is global, updating that
+fn regexp_run(interp: &mut Interpreter, this: &Value, args: &[Value]) -> Result<Option<(Vec<char>, Captures)>, Value> {
+    let (regex, flags) = match regex_of(interp, this) {
+        Some(found) => found,
+        None => return Err(interp.type_error("Not a regular expression")),
+    };
+    let text = chars(&string_arg(interp, args, 0)?);
+    let global = flags.contains('g') || flags.contains('y');
+    let start = if global {
+        let last = interp.get(this, "lastIndex")?;
+        interp.to_number(&last)?.max(0.0) as usize
+    } else {
+        0
+    };
+    let found = if start <= text.len() { regex.find_at(&text, start) } else { None };
+    if global {
+        let next = found.as_ref().map_or(0, |caps| caps[0].unwrap().1);
+        interp.put(this, "lastIndex", Value::Number(next as f64))?;
+    }
+    Ok(found.map(|caps| (text, caps)))
+}
+
+fn regexp_test(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Bool(regexp_run(interp, &this, args)?.is_some()))
+}
+
+fn regexp_exec(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    match regexp_run(interp, &this, args)? {
+        Some((text, caps)) => match_array(interp, &text, &caps),
+        None => Ok(Value::Null),
+    }
+}
+
+fn regexp_to_string(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    match regex_of(interp, &this) {
+        Some((regex, flags)) => Ok(Value::String(format!("/{}/{}", regex.source, flags))),
+        None => Err(interp.type_error("Not a regular expression")),
+    }
+}
+
+// Math
+
+fn math_arg(interp: &mut Interpreter, args: &[Value]) -> Result<f64, Value> {
+    interp.to_number(&arg(args, 0))
+}
+
+fn math_floor(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(floor(math_arg(interp, args)?)))
+}
+
+fn math_ceil(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(ceil(math_arg(interp, args)?)))
+}
+
+fn math_round(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(round(math_arg(interp, args)?)))
+}
+
+fn math_trunc(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(trunc(math_arg(interp, args)?)))
+}
+
+fn math_abs(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(math_arg(interp, args)?.abs()))
+}
+
+fn math_sign(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let n = math_arg(interp, args)?;
+    Ok(Value::Number(if n > 0.0 { 1.0 } else if n < 0.0 { -1.0 } else { n }))
+}
+
+fn math_min(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut result = f64::INFINITY;
+    for value in args {
+        let n = interp.to_number(value)?;
+        if n.is_nan() {
+            return Ok(Value::Number(f64::NAN));
+        }
+        result = result.min(n);
+    }
+    Ok(Value::Number(result))
+}
+
+fn math_max(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut result = f64::NEG_INFINITY;
+    for value in args {
+        let n = interp.to_number(value)?;
+        if n.is_nan() {
+            return Ok(Value::Number(f64::NAN));
+        }
+        result = result.max(n);
+    }
+    Ok(Value::Number(result))
+}
+
+fn math_sqrt(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(sqrt(math_arg(interp, args)?)))
+}
+
+fn math_pow(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let base = math_arg(interp, args)?;
+    let exponent = interp.to_number(&arg(args, 1))?;
+    Ok(Value::Number(pow(base, exponent)))
+}
+
+fn math_exp(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(exp(math_arg(interp, args)?)))
+}
+
+fn math_log(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number(ln(math_arg(interp, args)?)))
+}
+
+fn math_random(_: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    // 53 random bits make a uniform double in [0, 1)
+    let bits = crate::crypto::rng::next_u64() >> 11;
+    Ok(Value::Number(bits as f64 / 9007199254740992.0))
+}
+
+// JSON
+
+fn json_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// `value` as JSON, or `None` for values JSON leaves out, like functions
+pub fn to_json(interp: &mut Interpreter, value: &Value, indent: &str) -> Result<Option<String>, Value> {
+    let mut out = String::new();
+    let written = write_json(interp, value, indent, 0, &mut out)?;
+    Ok(written.then_some(out))
+}
+
+fn write_json(interp: &mut Interpreter, value: &Value, indent: &str, depth: usize, out: &mut String) -> Result<bool, Value> {
+    if depth > MAX_JSON_DEPTH {
+        return Err(interp.type_error("Converting circular structure to JSON"));
+    }
+    let newline = |out: &mut String, depth: usize| {
+        if !indent.is_empty() {
+            out.push('\n');
+            for _ in 0..depth {
+                out.push_str(indent);
+            }
+        }
+    };
+    match value {
+        Value::Undefined => return Ok(false),
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) if n.is_finite() => out.push_str(&number_to_string(*n)),
+        Value::Number(_) => out.push_str("null"),
+        Value::String(s) => json_string(s, out),
+        Value::Object(obj) => {
+            match &interp.heap.get(*obj).kind {
+                Kind::Function(_) => return Ok(false),
+                Kind::Primitive(inner) => {
+                    let inner = inner.clone();
+                    return write_json(interp, &inner, indent, depth, out);
+                }
+                _ => {}
+            }
+            if let Some(items) = interp.array_items(value) {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, depth + 1);
+                    if !write_json(interp, item, indent, depth + 1, out)? {
+                        out.push_str("null");
+                    }
+                }
+                if !items.is_empty() {
+                    newline(out, depth);
+                }
+                out.push(']');
+            } else {
+                out.push('{');
+                let mut first = true;
+                for key in interp.keys(value) {
+                    let item = interp.get(value, &key)?;
+                    let mut entry = String::new();
+                    json_string(&key, &mut entry);
+                    entry.push(':');
+                    if !indent.is_empty() {
+                        entry.push(' ');
+                    }
+                    if !write_json(interp, &item, indent, depth + 1, &mut entry)? {
+                        continue;
+                    }
+                    if !first {
+                        out.push(',');
+                    }
+                    first = false;
+                    newline(out, depth + 1);
+                    out.push_str(&entry);
+                }
+                if !first {
+                    newline(out, depth);
+                }
+                out.push('}');
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn json_stringify(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let indent = match arg(args, 2) {
+        Value::Number(n) if n >= 1.0 => " ".repeat((n as usize).min(10)),
+        Value::String(s) => s.chars().take(10).collect(),
+        _ => String::new(),
+    };
+    Ok(to_json(interp, &arg(args, 0), &indent)?.map_or(Value::Undefined, Value::String))
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_space(&mut self) {
+        while self.pos < self.text.len() && matches!(self.text[self.pos], b' ' | b'\t' | b'\n' | b'\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, literal: &str) -> bool {
+        if self.text[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self, interp: &mut Interpreter, depth: usize) -> Result<Value, String> {
+        if depth > MAX_JSON_DEPTH {
+            return Err(String::from("too deeply nested"));
+        }
+        self.skip_space();
+        let c = *self.text.get(self.pos).ok_or("unexpected end of JSON input")?;
+        match c {
+            b'{' => {
+                self.pos += 1;
+                let obj = interp.new_object().map_err(|_| String::from("out of memory"))?;
+                self.skip_space();
+                if self.eat("}") {
+                    return Ok(obj);
+                }
+                loop {
+                    self.skip_space();
+                    if self.text.get(self.pos) != Some(&b'"') {
+                        return Err(format!("expected a property name at position {}", self.pos));
+                    }
+                    self.pos += 1;
+                    let key = self.string()?;
+                    self.skip_space();
+                    if !self.eat(":") {
+                        return Err(format!("expected ':' at position {}", self.pos));
+                    }
+                    let value = self.value(interp, depth + 1)?;
+                    interp.put(&obj, &key, value).map_err(|_| String::from("too many properties"))?;
+                    self.skip_space();
+                    if self.eat("}") {
+                        return Ok(obj);
+                    }
+                    if !self.eat(",") {
+                        return Err(format!("expected ',' or '}}' at position {}", self.pos));
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_space();
+                if !self.eat("]") {
+                    loop {
+                        items.push(self.value(interp, depth + 1)?);
+                        if items.len() > MAX_ARRAY_LENGTH {
+                            return Err(String::from("array too long"));
+                        }
+                        self.skip_space();
+                        if self.eat("]") {
+                            break;
+                        }
+                        if !self.eat(",") {
+                            return Err(format!("expected ',' or ']' at position {}", self.pos));
+                        }
+                    }
+                }
+                interp.new_array(items).map_err(|_| String::from("out of memory"))
+            }
+            b'"' => {
+                self.pos += 1;
+                self.string().map(Value::String)
+            }
+            b't' if self.eat("true") => Ok(Value::Bool(true)),
+            b'f' if self.eat("false") => Ok(Value::Bool(false)),
+            b'n' if self.eat("null") => Ok(Value::Null),
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+                while self.pos < self.text.len() && matches!(self.text[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+                    self.pos += 1;
+                }
+                let number = core::str::from_utf8(&self.text[start..self.pos]).unwrap_or("");
+                number.parse::<f64>().map(Value::Number).map_err(|_| format!("bad number at position {}", start))
+            }
+            _ => Err(format!("unexpected token at position {}", self.pos)),
+        }
+    }
+
+    /// A string after its opening quote
+    fn string(&mut self) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self.text.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.text.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    let decoded = match escape {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let unit = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&unit) && self.eat("\\u") {
+                                let low = self.hex4()?;
+                                char::from_u32(0x10000 + ((unit - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)).unwrap_or('\u{fffd}')
+                            } else {
+                                char::from_u32(unit).unwrap_or('\u{fffd}')
+                            }
+                        }
+                        other => other as char,
+                    };
+                    let mut buf = [0u8; 4];
+                    bytes.extend_from_slice(decoded.encode_utf8(&mut buf).as_bytes());
+                }
+                c => bytes.push(c),
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or("bad unicode escape")?;
+        let digits = core::str::from_utf8(digits).map_err(|_| String::from("bad unicode escape"))?;
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).map_err(|_| String::from("bad unicode escape"))
+    }
+}
+
+/// Parse JSON text into a value
+pub fn from_json(interp: &mut Interpreter, text: &str) -> Result<Value, Value> {
+    let mut parser = JsonParser { text: text.as_bytes(), pos: 0 };
+    let value = parser.value(interp, 0).and_then(|value| {
+        parser.skip_space();
+        if parser.pos < parser.text.len() {
+            Err(format!("unexpected token at position {}", parser.pos))
+        } else {
+            Ok(value)
+        }
+    });
+    value.map_err(|message| interp.error("SyntaxError", &format!("JSON.parse: {}", message)))
+}
+
+fn json_parse(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let text = string_arg(interp, args, 0)?;
+    from_json(interp, &text)
+}
+
+// Host
+
+fn console_log(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let mut line = String::new();
+    for (i, value) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        line.push_str(&interp.display(value));
+    }
+    info!("js", "{}", line);
+    Ok(Value::Undefined)
+}
+
+/// `crypto.getRandomValues(array)`: fill the array with random bytes and
+/// return it
+///
+/// There are no typed arrays, so each element of the array becomes a
+/// number from 0 to 255.
+fn get_random_values(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let array = arg(args, 0);
+    let len = match interp.array_items(&array) {
+        Some(items) => items.len(),
+        None => return Err(interp.type_error("getRandomValues: argument is not an array")),
+    };
+    if len > GET_RANDOM_VALUES_QUOTA {
+        return Err(interp.error("Error", "QuotaExceededError: more than 65536 bytes requested"));
+    }
+    let mut bytes = alloc::vec![0u8; len];
+    crate::crypto::rng::fill_bytes(&mut bytes);
+    if let Some(items) = interp.array_mut(&array) {
+        for (slot, byte) in items.iter_mut().zip(bytes) {
+            *slot = Value::Number(byte as f64);
+        }
+    }
+    Ok(array)
+}
+
+/// `Date.now()`: milliseconds since 1970 by the real-time clock
+fn date_now(_: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Number((crate::drivers::rtc::unix_ns() / 1_000_000) as f64))
+}
+
+fn encode_uri_component(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let text = string_arg(interp, args, 0)?;
+    let mut out = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    Ok(Value::String(out))
+}
+
+fn decode_uri_component(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let text = string_arg(interp, args, 0)?;
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok());
+            match hex {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+                None => return Err(interp.error("URIError", "URI malformed")),
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    match String::from_utf8(out) {
+        Ok(decoded) => Ok(Value::String(decoded)),
+        Err(_) => Err(interp.error("URIError", "URI malformed")),
+    }
+}
+
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn result_of(source: &str) -> Result<String, String> {
+        let mut interp = Interpreter::new();
+        interp.run(source)?;
+        let result = interp.find_property(interp.global, "result").unwrap_or(Value::Undefined);
+        Ok(interp.display(&result))
+    }
+
+    #[kernel_test]
+    fn arithmetic_helpers() -> Result<(), String> {
+        check_eq!(floor(-1.5), -2.0);
+        check_eq!(ceil(1.2), 2.0);
+        check_eq!(round(2.5), 3.0);
+        check_eq!(round(-2.5), -2.0);
+        check_eq!(trunc(-3.7), -3.0);
+        check_eq!(fmod(7.5, 2.0), 1.5);
+        check_eq!(sqrt(81.0), 9.0);
+        check_eq!(pow(2.0, 10.0), 1024.0);
+        check!((exp(ln(10.0)) - 10.0).abs() < 1e-9);
+        check!(sqrt(-1.0).is_nan());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn math_and_numbers() -> Result<(), String> {
+        check_eq!(result_of("var result = [Math.max(1, 9, 3), Math.min(), Math.abs(-4), Math.sign(-2)].join();")?, "9,Infinity,4,-1");
+        check_eq!(result_of("var result = [parseInt('42px'), parseInt('ff', 16), parseFloat('3.5e1x')].join();")?, "42,255,35");
+        check_eq!(result_of("var result = (1.005).toFixed(1) + ' ' + (255).toString(16) + ' ' + isNaN('x');")?, "1.0 ff true");
+        check_eq!(result_of("var r = Math.random(); var result = r >= 0 && r < 1;")?, "true");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn array_methods() -> Result<(), String> {
+        check_eq!(result_of("
+            var a = [3, 1, 2];
+            a.push(5); a.unshift(0);
+            var result = a.slice(1, 4).concat(a.pop()).sort((x, y) => x - y).join('-');
+        ")?, "1-2-3-5");
+        check_eq!(result_of("var result = [1, 2, 3, 4].filter(x => x % 2).map(x => x * 10).reduce((s, x) => s + x, 0);")?, "40");
+        check_eq!(result_of("var a = [1, 2, 3, 4]; var removed = a.splice(1, 2, 'x'); var result = a + '|' + removed;")?, "1,x,4|2,3");
+        check_eq!(result_of("var result = [[1, [2]], 3].flat().length + ' ' + [1, 2].includes(2) + ' ' + [5, 6].indexOf(7);")?, "3 true -1");
+        check_eq!(result_of("var result = Array.isArray([]) && !Array.isArray('a') && Array.from('ab').join() === 'a,b';")?, "true");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn string_methods() -> Result<(), String> {
+        check_eq!(result_of("var result = '  Hello, World  '.trim().toLowerCase().split(', ').join('|');")?, "hello|world");
+        check_eq!(result_of("var result = 'abcabc'.replace('b', 'X') + ' ' + 'abcabc'.replaceAll('b', 'X');")?, "aXcabc aXcaXc");
+        check_eq!(result_of("var result = '7'.padStart(3, '0') + 'ab'.repeat(2) + 'hello'.slice(-3) + 'hello'.charAt(1);")?, "007abablloe");
+        check_eq!(result_of("var result = 'a1b22'.replace(/\\d+/g, n => '<' + n + '>');")?, "a<1>b<22>");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn json_round_trips() -> Result<(), String> {
+        check_eq!(result_of("var result = JSON.stringify({ a: [1, 'two', null, true], b: { c: 'q\"' } });")?,
+            r#"{"a":[1,"two",null,true],"b":{"c":"q\""}}"#);
+        check_eq!(result_of("var o = JSON.parse('{\"x\": [1, 2.5, {\"y\": \"z\"}]}'); var result = o.x[1] + o.x[2].y;")?, "2.5z");
+        check_eq!(result_of("var s = JSON.stringify({ n: 1, s: 'x' }); var result = JSON.stringify(JSON.parse(s)) === s;")?, "true");
+        check!(result_of("JSON.parse('{bad');").err().unwrap_or_default().starts_with("SyntaxError"));
+        check_eq!(result_of("var result = decodeURIComponent(encodeURIComponent('a b&é'));")?, "a b&é");
+        Ok(())
+    }
+}
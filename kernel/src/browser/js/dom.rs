@@ -0,0 +1,1639 @@
+//! Page bindings
+//!
+//! `window`, `document` and the elements of the page as scripts see them.
+//! An element object holds only its node ID: reading or writing one of
+//! its properties finds the node in the document, or among the nodes a
+//! script has created or removed, and acts on it there. Listeners, timers
+//! and the messages posted to `window.parent` are kept here too; the
+//! browser dispatches clicks, keys and resizes, runs due timers and
+//! collects the messages.
+//!
+//! Changing the page sets `changed`, so the browser styles, lays out and
+//! renders it again once the script returns. Sizes a script reads come
+//! from the last layout.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::builtins;
+use super::interp::Interpreter;
+use super::net;
+use super::value::{Heap, Kind, Native, ObjRef, Value};
+use super::net::Requests;
+use crate::browser::cookies;
+use crate::browser::forms::{self, FormState};
+use crate::browser::html::{self, Document, Element, Node};
+use crate::browser::layout::LayoutTree;
+use crate::{info, warn};
+
+/// Shortest delay a timer may have, in milliseconds
+const MIN_TIMER_DELAY: u64 = 4;
+
+/// Most timers a page may have pending at once
+const MAX_TIMERS: usize = 256;
+
+/// Most listeners a page may register
+const MAX_LISTENERS: usize = 1024;
+
+/// Timers run in one go before the rest wait for the next tick
+const MAX_TIMERS_PER_RUN: usize = 32;
+
+/// Messages kept for the parent window before the oldest are dropped
+const MAX_OUTBOX: usize = 64;
+
+/// Elements that have no end tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr",
+];
+
+/// What an event is dispatched to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Window,
+    Document,
+    Node(u32),
+}
+
+struct Listener {
+    target: Target,
+    event: String,
+    callback: Value,
+    /// Set with a property such as `onclick`, which replaces the previous
+    /// one and any attribute handler
+    property: bool,
+}
+
+struct Timer {
+    id: u32,
+    /// Time it is due, in milliseconds since boot
+    due: u64,
+    /// Period of a `setInterval` timer
+    interval: Option<u64>,
+    callback: Value,
+    args: Vec<Value>,
+}
+
+/// The page and everything its scripts have registered with it
+pub struct Dom {
+    /// The page, while a script runs; the browser keeps it otherwise
+    pub document: Option<Document>,
+    pub forms: FormState,
+    pub layout: Option<LayoutTree>,
+    pub scroll_y: u32,
+    /// The page changed and needs laying out again
+    pub changed: bool,
+    /// Node ID of the element a script gave the focus
+    pub focus: Option<u32>,
+    /// Address of the page, whose origin its requests and cookies have
+    pub url: String,
+    /// Requests the page's scripts have made
+    pub requests: Requests,
+    listeners: Vec<Listener>,
+    timers: Vec<Timer>,
+    next_timer: u32,
+    /// The object of each element a script has seen, so the same element
+    /// is always the same object
+    wrappers: BTreeMap<u32, ObjRef>,
+    /// Elements made with `createElement` or removed from the page, by
+    /// node ID, with their subtrees
+    detached: BTreeMap<u32, Element>,
+    /// Messages for the parent window, as JSON
+    outbox: Vec<String>,
+    document_object: Option<ObjRef>,
+    event_proto: Option<ObjRef>,
+    /// Objects kept alive while an event is dispatched
+    pinned: Vec<ObjRef>,
+}
+
+impl Dom {
+    pub fn new() -> Self {
+        Self {
+            document: None,
+            forms: FormState::new(),
+            layout: None,
+            scroll_y: 0,
+            changed: false,
+            focus: None,
+            url: String::new(),
+            requests: Requests::new(),
+            listeners: Vec::new(),
+            timers: Vec::new(),
+            next_timer: 1,
+            wrappers: BTreeMap::new(),
+            detached: BTreeMap::new(),
+            outbox: Vec::new(),
+            document_object: None,
+            event_proto: None,
+            pinned: Vec::new(),
+        }
+    }
+
+    /// Add what the page keeps alive to the garbage collector's roots
+    ///
+    /// Objects of elements in the page are roots; those of detached
+    /// elements live only as long as a script holds them, and their
+    /// elements go with them (see `prune`).
+    pub fn roots(&mut self, roots: &mut Vec<ObjRef>) {
+        let mut attached = BTreeSet::new();
+        if let Some(document) = &self.document {
+            node_ids(&document.root, &mut attached);
+        }
+        let mut detached = BTreeSet::new();
+        for element in self.detached.values() {
+            node_ids(element, &mut detached);
+        }
+        self.wrappers.retain(|id, _| attached.contains(id) || detached.contains(id));
+        self.listeners.retain(|l| match l.target {
+            Target::Node(id) => attached.contains(&id) || detached.contains(&id),
+            _ => true,
+        });
+        roots.extend(self.wrappers.iter().filter(|(id, _)| attached.contains(id)).map(|(_, obj)| *obj));
+        let values = self.listeners.iter().map(|l| &l.callback)
+            .chain(self.timers.iter().flat_map(|t| core::iter::once(&t.callback).chain(&t.args)));
+        roots.extend(values.filter_map(Value::object));
+        roots.extend(self.document_object);
+        roots.extend(self.event_proto);
+        roots.extend(&self.pinned);
+        self.requests.roots(roots);
+    }
+
+    /// After a collection, forget the objects that were freed, and the
+    /// detached elements no script can reach any more
+    pub fn prune(&mut self, heap: &Heap) {
+        self.wrappers.retain(|_, obj| heap.alive(*obj));
+        let wrappers = &self.wrappers;
+        self.detached.retain(|id, _| wrappers.contains_key(id));
+        self.requests.prune(heap);
+    }
+
+    /// Messages posted to the parent window since the last call, as JSON
+    pub fn take_messages(&mut self) -> Vec<String> {
+        core::mem::take(&mut self.outbox)
+    }
+
+    /// Whether any timers are pending
+    pub fn has_timers(&self) -> bool {
+        !self.timers.is_empty()
+    }
+
+    /// The element with node ID `id`, in the page or detached
+    pub fn element(&self, id: u32) -> Option<&Element> {
+        let page = self.document.as_ref().and_then(|d| find(&d.root, id));
+        page.or_else(|| self.detached.values().find_map(|e| find(e, id)))
+    }
+
+    fn element_mut(&mut self, id: u32) -> Option<&mut Element> {
+        if let Some(document) = self.document.as_mut() {
+            if let Some(found) = find_mut(&mut document.root, id) {
+                return Some(found);
+            }
+        }
+        self.detached.values_mut().find_map(|e| find_mut(e, id))
+    }
+
+    fn in_page(&self, id: u32) -> bool {
+        self.document.as_ref().map_or(false, |d| find(&d.root, id).is_some())
+    }
+
+    /// Node IDs from the page's root down to `id`'s parent
+    fn ancestors(&self, id: u32) -> Vec<u32> {
+        fn walk(element: &Element, id: u32, path: &mut Vec<u32>) -> bool {
+            for child in &element.children {
+                if let Node::Element(child) = child {
+                    if child.node_id == id {
+                        return true;
+                    }
+                    path.push(child.node_id);
+                    if walk(child, id, path) {
+                        return true;
+                    }
+                    path.pop();
+                }
+            }
+            false
+        }
+        let mut path = Vec::new();
+        let roots = self.document.iter().map(|d| &d.root).chain(self.detached.values());
+        for root in roots {
+            if root.node_id == id {
+                return path;
+            }
+            path.push(root.node_id);
+            if walk(root, id, &mut path) {
+                return path;
+            }
+            path.clear();
+        }
+        path
+    }
+
+    /// Take element `id` out of wherever it is
+    fn take(&mut self, id: u32) -> Option<Element> {
+        if let Some(element) = self.detached.remove(&id) {
+            return Some(element);
+        }
+        let page = self.document.as_mut().and_then(|d| take_from(&mut d.root, id));
+        if page.is_some() {
+            self.changed = true;
+            return page;
+        }
+        self.detached.values_mut().find_map(|e| take_from(e, id))
+    }
+
+    /// Put `child` into `parent` before its child `before`, or last;
+    /// gives the child back if there is no such parent
+    fn insert(&mut self, parent: u32, child: Element, before: Option<u32>) -> Result<(), Element> {
+        let in_page = self.in_page(parent);
+        let parent = match self.element_mut(parent) {
+            Some(parent) => parent,
+            None => return Err(child),
+        };
+        let position = before.and_then(|b| parent.children.iter().position(|c| matches!(c, Node::Element(e) if e.node_id == b)));
+        match position {
+            Some(i) => parent.children.insert(i, Node::Element(child)),
+            None => parent.children.push(Node::Element(child)),
+        }
+        if in_page {
+            self.changed = true;
+        }
+        Ok(())
+    }
+}
+
+fn node_ids(element: &Element, out: &mut BTreeSet<u32>) {
+    out.insert(element.node_id);
+    for child in &element.children {
+        if let Node::Element(child) = child {
+            node_ids(child, out);
+        }
+    }
+}
+
+fn find(element: &Element, id: u32) -> Option<&Element> {
+    if element.node_id == id {
+        return Some(element);
+    }
+    element.children.iter().find_map(|child| match child {
+        Node::Element(child) => find(child, id),
+        _ => None,
+    })
+}
+
+fn find_mut(element: &mut Element, id: u32) -> Option<&mut Element> {
+    if element.node_id == id {
+        return Some(element);
+    }
+    for child in &mut element.children {
+        if let Node::Element(child) = child {
+            if let Some(found) = find_mut(child, id) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+/// Remove the descendant `id` of `element` from its parent
+fn take_from(element: &mut Element, id: u32) -> Option<Element> {
+    let position = element.children.iter().position(|c| matches!(c, Node::Element(e) if e.node_id == id));
+    if let Some(i) = position {
+        return match element.children.remove(i) {
+            Node::Element(e) => Some(e),
+            _ => None,
+        };
+    }
+    for child in &mut element.children {
+        if let Node::Element(child) = child {
+            if let Some(found) = take_from(child, id) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+fn element_children(element: &Element) -> impl Iterator<Item = &Element> {
+    element.children.iter().filter_map(|c| match c {
+        Node::Element(e) => Some(e),
+        _ => None,
+    })
+}
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or(Value::Undefined)
+}
+
+/// Set up `window`, `document` and the element methods
+pub fn install(interp: &mut Interpreter) {
+    let global = interp.global;
+    let window = Value::Object(global);
+    interp.heap.get_mut(global).put("window", window.clone());
+    interp.heap.get_mut(global).put("self", window);
+    interp.heap.get_mut(global).put("innerWidth", Value::Number(0.0));
+    interp.heap.get_mut(global).put("innerHeight", Value::Number(0.0));
+    let window_methods: &[(&str, Native)] = &[
+        ("addEventListener", add_event_listener), ("removeEventListener", remove_event_listener),
+        ("setTimeout", set_timeout), ("setInterval", set_interval), ("clearTimeout", clear_timer),
+        ("clearInterval", clear_timer), ("requestAnimationFrame", request_animation_frame),
+        ("alert", alert), ("confirm", confirm), ("prompt", prompt),
+    ];
+    for (name, f) in window_methods {
+        interp.define(global, name, *f);
+    }
+
+    // The desktop is the parent of every app's window
+    if let Ok(Value::Object(parent)) = interp.new_object() {
+        interp.define(parent, "postMessage", post_message);
+        interp.heap.get_mut(global).put("parent", Value::Object(parent));
+        interp.heap.get_mut(global).put("top", Value::Object(parent));
+    }
+
+    let object = interp.protos.object;
+    if let Ok(document) = interp.alloc(Kind::Document, Some(object)) {
+        let document_methods: &[(&str, Native)] = &[
+            ("getElementById", get_element_by_id), ("querySelector", query_selector),
+            ("querySelectorAll", query_selector_all), ("getElementsByClassName", get_elements_by_class_name),
+            ("getElementsByTagName", get_elements_by_tag_name), ("createElement", create_element),
+            ("addEventListener", add_event_listener), ("removeEventListener", remove_event_listener),
+        ];
+        for (name, f) in document_methods {
+            interp.define(document, name, *f);
+        }
+        interp.heap.get_mut(global).put("document", Value::Object(document));
+        interp.dom.document_object = Some(document);
+    }
+
+    net::install(interp);
+
+    let element = interp.protos.element;
+    let element_methods: &[(&str, Native)] = &[
+        ("getAttribute", get_attribute), ("setAttribute", set_attribute), ("removeAttribute", remove_attribute),
+        ("hasAttribute", has_attribute), ("appendChild", append_child), ("insertBefore", insert_before),
+        ("removeChild", remove_child), ("remove", remove), ("contains", contains),
+        ("querySelector", query_selector), ("querySelectorAll", query_selector_all),
+        ("getElementsByClassName", get_elements_by_class_name), ("getElementsByTagName", get_elements_by_tag_name),
+        ("matches", matches_method), ("closest", closest),
+        ("addEventListener", add_event_listener), ("removeEventListener", remove_event_listener),
+        ("getBoundingClientRect", get_bounding_client_rect), ("focus", focus), ("blur", blur),
+        ("click", click), ("getContext", get_context), ("toDataURL", to_data_url),
+    ];
+    for (name, f) in element_methods {
+        interp.define(element, name, *f);
+    }
+
+    let class_list = interp.protos.class_list;
+    let class_list_methods: &[(&str, Native)] = &[
+        ("add", class_add), ("remove", class_remove), ("toggle", class_toggle), ("contains", class_contains),
+    ];
+    for (name, f) in class_list_methods {
+        interp.define(class_list, name, *f);
+    }
+
+    if let Ok(Value::Object(event)) = interp.new_object() {
+        interp.define(event, "preventDefault", prevent_default);
+        interp.define(event, "stopPropagation", stop_propagation);
+        interp.define(event, "stopImmediatePropagation", stop_propagation);
+        interp.dom.event_proto = Some(event);
+    }
+}
+
+/// Point `document.body`, `document.documentElement` and
+/// `document.title` at the page, once the browser has handed it over
+pub fn bind_document(interp: &mut Interpreter) {
+    let (root, body, title) = match &interp.dom.document {
+        Some(document) => (document.root.node_id, document.body().map(|b| b.node_id), document.title()),
+        None => return,
+    };
+    let document = match interp.dom.document_object {
+        Some(document) => document,
+        None => return,
+    };
+    let root = wrap(interp, root).unwrap_or(Value::Null);
+    let body = match body {
+        Some(body) => wrap(interp, body).unwrap_or(Value::Null),
+        None => Value::Null,
+    };
+    let object = interp.heap.get_mut(document);
+    object.put("documentElement", root);
+    object.put("body", body);
+    object.put("title", Value::String(title.unwrap_or_default()));
+}
+
+/// Update `innerWidth` and `innerHeight` for the viewport
+pub fn set_viewport(interp: &mut Interpreter, width: u32, height: u32) {
+    let global = interp.heap.get_mut(interp.global);
+    global.put("innerWidth", Value::Number(width as f64));
+    global.put("innerHeight", Value::Number(height as f64));
+}
+
+/// The object of element `id`
+pub fn wrap(interp: &mut Interpreter, id: u32) -> Result<Value, Value> {
+    if let Some(obj) = interp.dom.wrappers.get(&id) {
+        return Ok(Value::Object(*obj));
+    }
+    let proto = interp.protos.element;
+    let obj = interp.alloc(Kind::Element(id), Some(proto))?;
+    interp.dom.wrappers.insert(id, obj);
+    Ok(Value::Object(obj))
+}
+
+fn wrap_all(interp: &mut Interpreter, ids: Vec<u32>) -> Result<Value, Value> {
+    let mut items = Vec::with_capacity(ids.len());
+    for id in ids {
+        items.push(wrap(interp, id)?);
+    }
+    interp.new_array(items)
+}
+
+/// Node ID of an element object
+fn node_of(interp: &Interpreter, value: &Value) -> Option<u32> {
+    match interp.heap.get(value.object()?).kind {
+        Kind::Element(id) => Some(id),
+        _ => None,
+    }
+}
+
+/// Node ID of the element `this` is, or a TypeError
+fn this_node(interp: &mut Interpreter, this: &Value) -> Result<u32, Value> {
+    match node_of(interp, this) {
+        Some(id) => Ok(id),
+        None => Err(interp.type_error("Illegal invocation")),
+    }
+}
+
+fn string_arg(interp: &mut Interpreter, args: &[Value], i: usize) -> Result<String, Value> {
+    interp.to_string(&arg(args, i))
+}
+
+// Properties
+
+/// `camelCase` as `kebab-case`, as `style` and `dataset` names map to CSS
+/// properties and attributes
+fn kebab(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            out.push('-');
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn set_attr(element: &mut Element, name: &str, value: &str) {
+    match element.attributes.iter_mut().find(|(k, _)| k == name) {
+        Some(slot) => slot.1 = String::from(value),
+        None => element.attributes.push((String::from(name), String::from(value))),
+    }
+}
+
+fn remove_attr(element: &mut Element, name: &str) {
+    element.attributes.retain(|(k, _)| k != name);
+}
+
+/// Declarations of a `style` attribute, in order
+fn declarations(style: &str) -> Vec<(String, String)> {
+    style.split(';').filter_map(|d| {
+        let (prop, value) = d.split_once(':')?;
+        Some((prop.trim().to_ascii_lowercase(), String::from(value.trim())))
+    }).collect()
+}
+
+/// What a form control holds: its text, or a list's chosen option
+fn control_value(forms: &FormState, element: &Element) -> String {
+    match element.tag.as_str() {
+        "input" | "textarea" => forms.text(element),
+        "select" => forms.selected(element).map(|i| forms::option_value(forms::options(element)[i])).unwrap_or_default(),
+        "option" => forms::option_value(element),
+        _ => String::from(element.get_attr("value").unwrap_or("")),
+    }
+}
+
+/// What reading a property of an element found
+enum Found {
+    Value(Value),
+    Node(Option<u32>),
+    Nodes(Vec<u32>),
+    Part(fn(u32) -> Kind),
+}
+
+/// Read a property of a DOM object; `None` leaves it to the object's own
+/// properties and prototype
+pub fn get(interp: &mut Interpreter, obj: ObjRef, key: &str) -> Result<Option<Value>, Value> {
+    let found = match interp.heap.get(obj).kind {
+        Kind::Document if key == "cookie" => Some(Found::Value(Value::String(cookies::for_page(&interp.dom.url)))),
+        Kind::Element(id) => element_get(interp, id, key),
+        Kind::ClassList(id) => {
+            let class = interp.dom.element(id).and_then(|e| e.get_attr("class")).unwrap_or("");
+            match key {
+                "length" => Some(Found::Value(Value::Number(class.split_ascii_whitespace().count() as f64))),
+                "value" => Some(Found::Value(Value::str(class))),
+                _ => None,
+            }
+        }
+        Kind::Dataset(id) => {
+            let name = format!("data-{}", kebab(key));
+            interp.dom.element(id).and_then(|e| e.get_attr(&name)).map(|v| Found::Value(Value::str(v)))
+        }
+        Kind::Style(id) => {
+            let style = interp.dom.element(id).and_then(|e| e.get_attr("style")).unwrap_or("");
+            if key == "cssText" {
+                Some(Found::Value(Value::str(style)))
+            } else if key.chars().all(|c| c.is_ascii_alphabetic()) {
+                let prop = kebab(key);
+                let value = declarations(style).into_iter().rev().find(|(p, _)| *p == prop).map(|(_, v)| v);
+                Some(Found::Value(Value::String(value.unwrap_or_default())))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    Ok(match found {
+        None => None,
+        Some(Found::Value(value)) => Some(value),
+        Some(Found::Node(Some(id))) => Some(wrap(interp, id)?),
+        Some(Found::Node(None)) => Some(Value::Null),
+        Some(Found::Nodes(ids)) => Some(wrap_all(interp, ids)?),
+        Some(Found::Part(kind)) => {
+            let id = match interp.heap.get(obj).kind {
+                Kind::Element(id) => id,
+                _ => return Ok(None),
+            };
+            let proto = if let Kind::ClassList(_) = kind(id) { interp.protos.class_list } else { interp.protos.object };
+            Some(Value::Object(interp.alloc(kind(id), Some(proto))?))
+        }
+    })
+}
+
+fn element_get(interp: &Interpreter, id: u32, key: &str) -> Option<Found> {
+    let dom = &interp.dom;
+    let element = dom.element(id)?;
+    let attr = |name: &str| Found::Value(Value::str(element.get_attr(name).unwrap_or("")));
+    let layout = || {
+        let b = dom.layout.as_ref()?.element_box(id)?;
+        Some((b.x, b.y, b.width, b.height, b.border.left + b.border.right, b.border.top + b.border.bottom))
+    };
+    let size = |pick: fn((f32, f32, f32, f32, f32, f32)) -> f32| {
+        Found::Value(Value::Number(layout().map_or(0.0, |b| pick(b) as i32 as f64)))
+    };
+    let siblings = || {
+        let parent = *dom.ancestors(id).last()?;
+        let siblings: Vec<u32> = element_children(dom.element(parent)?).map(|e| e.node_id).collect();
+        let i = siblings.iter().position(|&s| s == id)?;
+        Some((siblings, i))
+    };
+    Some(match key {
+        "id" | "name" | "href" | "src" | "title" | "placeholder" | "alt" => attr(key),
+        "className" => attr("class"),
+        "tagName" | "nodeName" => Found::Value(Value::String(element.tag.to_ascii_uppercase())),
+        "localName" => Found::Value(Value::str(&element.tag)),
+        "nodeType" => Found::Value(Value::Number(1.0)),
+        "type" => match element.tag.as_str() {
+            "input" => Found::Value(Value::String(element.get_attr("type").unwrap_or("text").to_ascii_lowercase())),
+            "select" => Found::Value(Value::str("select-one")),
+            "textarea" => Found::Value(Value::str("textarea")),
+            _ => attr("type"),
+        },
+        "textContent" | "innerText" => Found::Value(Value::String(element.text_content())),
+        "innerHTML" => {
+            let mut out = String::new();
+            serialize_children(element, &mut out);
+            Found::Value(Value::String(out))
+        }
+        "outerHTML" => {
+            let mut out = String::new();
+            serialize(element, &mut out);
+            Found::Value(Value::String(out))
+        }
+        "value" => Found::Value(Value::String(control_value(&dom.forms, element))),
+        "checked" => Found::Value(Value::Bool(dom.forms.checked(element))),
+        "disabled" => Found::Value(Value::Bool(element.get_attr("disabled").is_some())),
+        "selectedIndex" => Found::Value(Value::Number(dom.forms.selected(element).map_or(-1.0, |i| i as f64))),
+        "options" if element.tag == "select" => Found::Nodes(forms::options(element).iter().map(|o| o.node_id).collect()),
+        "width" | "height" if element.tag == "canvas" => {
+            let default = if key == "width" { 300.0 } else { 150.0 };
+            let value = element.get_attr(key).and_then(|v| v.trim().parse::<f64>().ok()).unwrap_or(default);
+            Found::Value(Value::Number(value))
+        }
+        "classList" => Found::Part(Kind::ClassList),
+        "dataset" => Found::Part(Kind::Dataset),
+        "style" => Found::Part(Kind::Style),
+        "parentElement" | "parentNode" => Found::Node(dom.ancestors(id).last().copied()),
+        "children" | "childNodes" => Found::Nodes(element_children(element).map(|e| e.node_id).collect()),
+        "firstChild" | "firstElementChild" => Found::Node(element_children(element).next().map(|e| e.node_id)),
+        "lastChild" | "lastElementChild" => Found::Node(element_children(element).last().map(|e| e.node_id)),
+        "childElementCount" => Found::Value(Value::Number(element_children(element).count() as f64)),
+        "nextSibling" | "nextElementSibling" => {
+            Found::Node(siblings().and_then(|(s, i)| s.get(i + 1).copied()))
+        }
+        "previousSibling" | "previousElementSibling" => {
+            Found::Node(siblings().and_then(|(s, i)| i.checked_sub(1).map(|j| s[j])))
+        }
+        "isConnected" => Found::Value(Value::Bool(dom.in_page(id))),
+        "clientWidth" => size(|b| b.2 - b.4),
+        "clientHeight" => size(|b| b.3 - b.5),
+        "offsetWidth" | "scrollWidth" => size(|b| b.2),
+        "offsetHeight" | "scrollHeight" => size(|b| b.3),
+        "offsetLeft" => size(|b| b.0),
+        "offsetTop" => size(|b| b.1),
+        _ if key.starts_with("on") && key.len() > 2 => {
+            let event = &key[2..];
+            let listener = dom.listeners.iter().find(|l| l.property && l.target == Target::Node(id) && l.event == event);
+            Found::Value(listener.map_or(Value::Null, |l| l.callback.clone()))
+        }
+        _ => return None,
+    })
+}
+
+/// Write a property of a DOM object; false leaves it to be stored as an
+/// ordinary property
+pub fn set(interp: &mut Interpreter, obj: ObjRef, key: &str, value: &Value) -> Result<bool, Value> {
+    match interp.heap.get(obj).kind {
+        Kind::Document if key == "cookie" => {
+            let text = interp.to_string(value)?;
+            cookies::set_from_page(&interp.dom.url, &text);
+            Ok(true)
+        }
+        Kind::Element(id) => element_set(interp, id, key, value),
+        Kind::Dataset(id) => {
+            let text = interp.to_string(value)?;
+            let name = format!("data-{}", kebab(key));
+            if let Some(element) = interp.dom.element_mut(id) {
+                set_attr(element, &name, &text);
+                interp.dom.changed = true;
+            }
+            Ok(true)
+        }
+        Kind::Style(id) => {
+            let text = interp.to_string(value)?;
+            if let Some(element) = interp.dom.element_mut(id) {
+                let style = if key == "cssText" {
+                    text
+                } else {
+                    let prop = kebab(key);
+                    let mut declarations = declarations(element.get_attr("style").unwrap_or(""));
+                    declarations.retain(|(p, _)| *p != prop);
+                    if !text.is_empty() {
+                        declarations.push((prop, text));
+                    }
+                    declarations.iter().map(|(p, v)| format!("{}: {}", p, v)).collect::<Vec<_>>().join("; ")
+                };
+                set_attr(element, "style", &style);
+                interp.dom.changed = true;
+            }
+            Ok(true)
+        }
+        Kind::ClassList(_) => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+fn element_set(interp: &mut Interpreter, id: u32, key: &str, value: &Value) -> Result<bool, Value> {
+    if key.starts_with("on") && key.len() > 2 {
+        let event = String::from(&key[2..]);
+        let target = Target::Node(id);
+        interp.dom.listeners.retain(|l| !(l.property && l.target == target && l.event == event));
+        if interp.is_callable(value) {
+            interp.dom.listeners.push(Listener { target, event, callback: value.clone(), property: true });
+        }
+        return Ok(true);
+    }
+    let attribute = match key {
+        "id" | "name" | "href" | "src" | "title" | "placeholder" | "alt" | "type" => Some(key),
+        "className" => Some("class"),
+        "width" | "height" => Some(key),
+        _ => None,
+    };
+    let handled = matches!(key, "textContent" | "innerText" | "innerHTML" | "value" | "checked" | "disabled" | "selectedIndex");
+    if attribute.is_none() && !handled {
+        return Ok(false);
+    }
+    let text = match key {
+        "checked" | "disabled" => String::new(),
+        _ => interp.to_string(value)?,
+    };
+    let on = Interpreter::truthy(value);
+    let dom = &mut interp.dom;
+    let in_page = dom.in_page(id);
+    let forms = &mut dom.forms;
+    let element = match dom.document.as_mut().and_then(|d| find_mut(&mut d.root, id)) {
+        Some(element) => element,
+        None => match dom.detached.values_mut().find_map(|e| find_mut(e, id)) {
+            Some(element) => element,
+            None => return Ok(true),
+        },
+    };
+    match key {
+        _ if attribute.is_some() => set_attr(element, attribute.unwrap(), &text),
+        "textContent" | "innerText" => element.children = alloc::vec![Node::Text(text)],
+        "innerHTML" => element.children = html::parse_fragment(&element.tag, &text),
+        "value" => match element.tag.as_str() {
+            "input" | "textarea" => forms.set_text(element, text),
+            "select" => {
+                let chosen = forms::options(element).iter().position(|o| forms::option_value(o) == text);
+                if let Some(i) = chosen {
+                    forms.set_selected(element, i);
+                }
+            }
+            _ => set_attr(element, "value", &text),
+        },
+        "checked" => forms.set_checked(element, on),
+        "disabled" => {
+            if on {
+                set_attr(element, "disabled", "");
+            } else {
+                remove_attr(element, "disabled");
+            }
+        }
+        "selectedIndex" => {
+            let i = text.parse::<usize>().unwrap_or(0);
+            if i < forms::options(element).len() {
+                forms.set_selected(element, i);
+            }
+        }
+        _ => {}
+    }
+    if in_page {
+        dom.changed = true;
+    }
+    Ok(true)
+}
+
+// Serializing
+
+fn escape(text: &str, attribute: bool, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' if !attribute => out.push_str("&lt;"),
+            '>' if !attribute => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            '\u{a0}' => out.push_str("&nbsp;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn serialize(element: &Element, out: &mut String) {
+    out.push('<');
+    out.push_str(&element.tag);
+    for (name, value) in &element.attributes {
+        out.push(' ');
+        out.push_str(name);
+        out.push_str("=\"");
+        escape(value, true, out);
+        out.push('"');
+    }
+    out.push('>');
+    if VOID_ELEMENTS.contains(&element.tag.as_str()) {
+        return;
+    }
+    serialize_children(element, out);
+    out.push_str("</");
+    out.push_str(&element.tag);
+    out.push('>');
+}
+
+fn serialize_children(element: &Element, out: &mut String) {
+    let raw = matches!(element.tag.as_str(), "script" | "style");
+    for child in &element.children {
+        match child {
+            Node::Element(child) => serialize(child, out),
+            Node::Text(text) if raw => out.push_str(text),
+            Node::Text(text) => escape(text, false, out),
+            Node::Comment(text) => {
+                out.push_str("<!--");
+                out.push_str(text);
+                out.push_str("-->");
+            }
+        }
+    }
+}
+
+// Selectors
+
+#[derive(Default)]
+struct Compound {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+    /// Attribute names, with the value they must have if any
+    attributes: Vec<(String, Option<String>)>,
+}
+
+impl Compound {
+    fn matches(&self, element: &Element) -> bool {
+        if self.tag.as_ref().map_or(false, |t| *t != element.tag) {
+            return false;
+        }
+        if self.id.as_ref().map_or(false, |id| element.get_attr("id") != Some(id.as_str())) {
+            return false;
+        }
+        let class = element.get_attr("class").unwrap_or("");
+        if !self.classes.iter().all(|c| class.split_ascii_whitespace().any(|have| have == c)) {
+            return false;
+        }
+        self.attributes.iter().all(|(name, value)| match (element.get_attr(name), value) {
+            (Some(_), None) => true,
+            (Some(have), Some(want)) => have == want,
+            (None, _) => false,
+        })
+    }
+}
+
+/// A complex selector: compounds left to right, each after the first with
+/// whether it must be a child (rather than any descendant) of the one
+/// before
+struct Selector {
+    parts: Vec<(Compound, bool)>,
+}
+
+impl Selector {
+    fn matches(&self, element: &Element, ancestors: &[&Element]) -> bool {
+        self.matches_at(self.parts.len() - 1, element, ancestors)
+    }
+
+    fn matches_at(&self, i: usize, element: &Element, ancestors: &[&Element]) -> bool {
+        let (compound, child) = &self.parts[i];
+        if !compound.matches(element) {
+            return false;
+        }
+        if i == 0 {
+            return true;
+        }
+        if *child {
+            match ancestors.split_last() {
+                Some((parent, rest)) => self.matches_at(i - 1, parent, rest),
+                None => false,
+            }
+        } else {
+            (0..ancestors.len()).rev().any(|k| self.matches_at(i - 1, ancestors[k], &ancestors[..k]))
+        }
+    }
+}
+
+/// Parse a selector list of type, `#id`, `.class` and `[attr]` or
+/// `[attr="value"]` selectors joined by descendant and `>` combinators
+fn parse_selectors(text: &str) -> Result<Vec<Selector>, String> {
+    let invalid = || format!("'{}' is not a valid selector", text);
+    let ident = |chars: &[char], i: &mut usize| {
+        let start = *i;
+        while *i < chars.len() && (chars[*i].is_alphanumeric() || chars[*i] == '-' || chars[*i] == '_') {
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>()
+    };
+    let mut selectors = Vec::new();
+    for part in text.split(',') {
+        let chars: Vec<char> = part.trim().chars().collect();
+        let mut parts: Vec<(Compound, bool)> = Vec::new();
+        let mut current = Compound::default();
+        let mut empty = true;
+        let mut child = false;
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                ' ' | '\t' | '\n' | '>' => {
+                    while i < chars.len() && (chars[i].is_whitespace() || chars[i] == '>') {
+                        child |= chars[i] == '>';
+                        i += 1;
+                    }
+                    if empty {
+                        return Err(invalid());
+                    }
+                    parts.push((core::mem::take(&mut current), child));
+                    empty = true;
+                    child = false;
+                    continue;
+                }
+                '*' => i += 1,
+                '#' => {
+                    i += 1;
+                    current.id = Some(ident(&chars, &mut i));
+                }
+                '.' => {
+                    i += 1;
+                    current.classes.push(ident(&chars, &mut i));
+                }
+                '[' => {
+                    i += 1;
+                    let name = ident(&chars, &mut i).to_ascii_lowercase();
+                    let mut value = None;
+                    if chars.get(i) == Some(&'=') {
+                        i += 1;
+                        let quote = chars.get(i).copied().filter(|&q| q == '"' || q == '\'');
+                        value = Some(match quote {
+                            Some(q) => {
+                                let start = i + 1;
+                                let end = (start..chars.len()).find(|&j| chars[j] == q).ok_or_else(invalid)?;
+                                i = end + 1;
+                                chars[start..end].iter().collect()
+                            }
+                            None => ident(&chars, &mut i),
+                        });
+                    }
+                    if chars.get(i) != Some(&']') || name.is_empty() {
+                        return Err(invalid());
+                    }
+                    i += 1;
+                    current.attributes.push((name, value));
+                }
+                c if c.is_alphabetic() => current.tag = Some(ident(&chars, &mut i).to_ascii_lowercase()),
+                _ => return Err(invalid()),
+            }
+            empty = false;
+        }
+        if empty {
+            return Err(invalid());
+        }
+        parts.push((current, child));
+        // Each compound's flag says how it joins the one before
+        let mut flags: Vec<bool> = parts.iter().map(|(_, c)| *c).collect();
+        flags.rotate_right(1);
+        flags[0] = false;
+        let parts = parts.into_iter().zip(flags).map(|((c, _), f)| (c, f)).collect();
+        selectors.push(Selector { parts });
+    }
+    Ok(selectors)
+}
+
+/// Node IDs of the descendants of `root` that match, in document order
+fn select<'a>(element: &'a Element, selectors: &[Selector], ancestors: &mut Vec<&'a Element>, first: bool, out: &mut Vec<u32>) {
+    ancestors.push(element);
+    for child in element_children(element) {
+        if first && !out.is_empty() {
+            break;
+        }
+        if selectors.iter().any(|s| s.matches(child, ancestors)) {
+            out.push(child.node_id);
+        }
+        select(child, selectors, ancestors, first, out);
+    }
+    ancestors.pop();
+}
+
+/// The root `this` searches under: an element, or the whole page
+fn scope(interp: &mut Interpreter, this: &Value) -> Result<Option<u32>, Value> {
+    if let Some(id) = node_of(interp, this) {
+        return Ok(Some(id));
+    }
+    Ok(interp.dom.document.as_ref().map(|d| d.root.node_id))
+}
+
+fn query(interp: &mut Interpreter, this: &Value, selectors: &str, first: bool) -> Result<Vec<u32>, Value> {
+    let selectors = match parse_selectors(selectors) {
+        Ok(selectors) => selectors,
+        Err(message) => return Err(interp.error("SyntaxError", &message)),
+    };
+    let root = scope(interp, this)?;
+    let mut found = Vec::new();
+    if let Some(root) = root.and_then(|id| interp.dom.element(id)) {
+        // The page's root matches too when searching the whole document
+        if node_of(interp, this).is_none() && selectors.iter().any(|s| s.matches(root, &[])) {
+            found.push(root.node_id);
+        }
+        select(root, &selectors, &mut Vec::new(), first, &mut found);
+    }
+    Ok(found)
+}
+
+fn query_selector(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let selectors = string_arg(interp, args, 0)?;
+    match query(interp, &this, &selectors, true)?.first() {
+        Some(&id) => wrap(interp, id),
+        None => Ok(Value::Null),
+    }
+}
+
+fn query_selector_all(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let selectors = string_arg(interp, args, 0)?;
+    let found = query(interp, &this, &selectors, false)?;
+    wrap_all(interp, found)
+}
+
+fn get_elements_by_class_name(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let names = string_arg(interp, args, 0)?;
+    let selector: String = names.split_ascii_whitespace().map(|n| format!(".{}", n)).collect();
+    if selector.is_empty() {
+        return interp.new_array(Vec::new());
+    }
+    let found = query(interp, &this, &selector, false)?;
+    wrap_all(interp, found)
+}
+
+fn get_elements_by_tag_name(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let tag = string_arg(interp, args, 0)?;
+    let found = query(interp, &this, &tag, false)?;
+    wrap_all(interp, found)
+}
+
+fn matches_method(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let text = string_arg(interp, args, 0)?;
+    let selectors = parse_selectors(&text).map_err(|message| interp.error("SyntaxError", &message))?;
+    let ancestors: Vec<&Element> = interp.dom.ancestors(id).into_iter().filter_map(|a| interp.dom.element(a)).collect();
+    let matched = interp.dom.element(id).map_or(false, |e| selectors.iter().any(|s| s.matches(e, &ancestors)));
+    Ok(Value::Bool(matched))
+}
+
+fn closest(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let text = string_arg(interp, args, 0)?;
+    let selectors = parse_selectors(&text).map_err(|message| interp.error("SyntaxError", &message))?;
+    let mut chain = interp.dom.ancestors(id);
+    chain.push(id);
+    let dom = &interp.dom;
+    let elements: Vec<&Element> = chain.iter().filter_map(|&a| dom.element(a)).collect();
+    let found = (0..elements.len()).rev().find(|&k| selectors.iter().any(|s| s.matches(elements[k], &elements[..k])));
+    match found {
+        Some(k) => {
+            let id = elements[k].node_id;
+            wrap(interp, id)
+        }
+        None => Ok(Value::Null),
+    }
+}
+
+// Document
+
+fn get_element_by_id(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let wanted = string_arg(interp, args, 0)?;
+    fn walk(element: &Element, id: &str) -> Option<u32> {
+        if element.get_attr("id") == Some(id) {
+            return Some(element.node_id);
+        }
+        element_children(element).find_map(|child| walk(child, id))
+    }
+    let found = interp.dom.document.as_ref().and_then(|d| walk(&d.root, &wanted));
+    match found {
+        Some(id) => wrap(interp, id),
+        None => Ok(Value::Null),
+    }
+}
+
+fn create_element(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let tag = string_arg(interp, args, 0)?.to_ascii_lowercase();
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(interp.error("Error", "InvalidCharacterError: invalid tag name"));
+    }
+    let element = Element::new(&tag);
+    let id = element.node_id;
+    interp.dom.detached.insert(id, element);
+    wrap(interp, id)
+}
+
+// Elements
+
+fn get_attribute(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let name = string_arg(interp, args, 0)?.to_ascii_lowercase();
+    Ok(interp.dom.element(id).and_then(|e| e.get_attr(&name)).map_or(Value::Null, Value::str))
+}
+
+fn has_attribute(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let name = string_arg(interp, args, 0)?.to_ascii_lowercase();
+    Ok(Value::Bool(interp.dom.element(id).map_or(false, |e| e.get_attr(&name).is_some())))
+}
+
+fn set_attribute(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let name = string_arg(interp, args, 0)?.to_ascii_lowercase();
+    let value = string_arg(interp, args, 1)?;
+    if let Some(element) = interp.dom.element_mut(id) {
+        set_attr(element, &name, &value);
+        interp.dom.changed = true;
+    }
+    Ok(Value::Undefined)
+}
+
+fn remove_attribute(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let name = string_arg(interp, args, 0)?.to_ascii_lowercase();
+    if let Some(element) = interp.dom.element_mut(id) {
+        remove_attr(element, &name);
+        interp.dom.changed = true;
+    }
+    Ok(Value::Undefined)
+}
+
+/// Move element `child` into `parent`, before `before` if given
+fn adopt(interp: &mut Interpreter, parent: u32, child: u32, before: Option<u32>) -> Result<(), Value> {
+    let cycle = child == parent || interp.dom.element(child).map_or(false, |c| find(c, parent).is_some());
+    if cycle {
+        return Err(interp.error("Error", "HierarchyRequestError: the new child contains the parent"));
+    }
+    let element = match interp.dom.take(child) {
+        Some(element) => element,
+        None => return Err(interp.type_error("The node to insert no longer exists")),
+    };
+    if let Err(element) = interp.dom.insert(parent, element, before) {
+        interp.dom.detached.insert(child, element);
+    }
+    Ok(())
+}
+
+fn append_child(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let parent = this_node(interp, &this)?;
+    let child = arg(args, 0);
+    let id = match node_of(interp, &child) {
+        Some(id) => id,
+        None => return Err(interp.type_error("appendChild: argument is not an element")),
+    };
+    adopt(interp, parent, id, None)?;
+    Ok(child)
+}
+
+fn insert_before(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let parent = this_node(interp, &this)?;
+    let child = arg(args, 0);
+    let id = match node_of(interp, &child) {
+        Some(id) => id,
+        None => return Err(interp.type_error("insertBefore: argument is not an element")),
+    };
+    let before = node_of(interp, &arg(args, 1));
+    adopt(interp, parent, id, before)?;
+    Ok(child)
+}
+
+fn remove_child(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let parent = this_node(interp, &this)?;
+    let child = arg(args, 0);
+    let id = node_of(interp, &child);
+    let is_child = id.map_or(false, |id| {
+        interp.dom.element(parent).map_or(false, |p| element_children(p).any(|c| c.node_id == id))
+    });
+    if !is_child {
+        return Err(interp.error("Error", "NotFoundError: the node is not a child of this element"));
+    }
+    let id = id.unwrap();
+    if let Some(element) = interp.dom.take(id) {
+        interp.dom.detached.insert(id, element);
+    }
+    Ok(child)
+}
+
+fn remove(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    if !interp.dom.ancestors(id).is_empty() {
+        if let Some(element) = interp.dom.take(id) {
+            interp.dom.detached.insert(id, element);
+        }
+    }
+    Ok(Value::Undefined)
+}
+
+fn contains(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let other = node_of(interp, &arg(args, 0));
+    let found = other.map_or(false, |other| interp.dom.element(id).map_or(false, |e| find(e, other).is_some()));
+    Ok(Value::Bool(found))
+}
+
+fn get_bounding_client_rect(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let scroll = interp.dom.scroll_y as f32;
+    let (x, y, w, h) = interp.dom.layout.as_ref().and_then(|l| l.element_bounds(id))
+        .map_or((0.0, 0.0, 0.0, 0.0), |(x, y, w, h)| (x, y - scroll, w, h));
+    let rect = interp.new_object()?;
+    for (name, value) in [("x", x), ("y", y), ("left", x), ("top", y), ("width", w), ("height", h), ("right", x + w), ("bottom", y + h)] {
+        interp.put(&rect, name, Value::Number(value as f64))?;
+    }
+    Ok(rect)
+}
+
+fn focus(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    interp.dom.focus = Some(id);
+    Ok(Value::Undefined)
+}
+
+fn blur(_: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Undefined)
+}
+
+fn click(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    dispatch(interp, Target::Node(id), "click", &[]);
+    Ok(Value::Undefined)
+}
+
+/// `canvas.getContext('2d')`
+///
+/// Scripts get a context whose drawing methods do nothing, so pages that
+/// draw still run; nothing is painted.
+fn get_context(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_node(interp, &this)?;
+    let kind = string_arg(interp, args, 0)?;
+    let is_canvas = interp.dom.element(id).map_or(false, |e| e.tag == "canvas");
+    if !is_canvas || kind != "2d" {
+        return Ok(Value::Null);
+    }
+    let context = interp.new_object()?;
+    let methods = [
+        "beginPath", "closePath", "moveTo", "lineTo", "stroke", "fill", "clearRect", "fillRect", "strokeRect",
+        "arc", "rect", "fillText", "strokeText", "drawImage", "save", "restore", "scale", "translate", "rotate",
+        "setTransform", "quadraticCurveTo", "bezierCurveTo",
+    ];
+    if let Value::Object(obj) = context {
+        for name in methods {
+            interp.define(obj, name, nothing);
+        }
+    }
+    interp.put(&context, "canvas", this)?;
+    Ok(context)
+}
+
+fn nothing(_: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::Undefined)
+}
+
+/// `canvas.toDataURL()`: the canvas is never painted, so this is an empty
+/// image
+fn to_data_url(_: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    Ok(Value::str("data:,"))
+}
+
+// Class lists
+
+fn this_class_list(interp: &mut Interpreter, this: &Value) -> Result<u32, Value> {
+    match this.object().map(|obj| &interp.heap.get(obj).kind) {
+        Some(Kind::ClassList(id)) => Ok(*id),
+        _ => Err(interp.type_error("Illegal invocation")),
+    }
+}
+
+/// Add or remove class `name` on element `id`; returns whether it is set
+/// afterwards
+fn set_class(interp: &mut Interpreter, id: u32, name: &str, on: bool) -> bool {
+    let element = match interp.dom.element_mut(id) {
+        Some(element) => element,
+        None => return false,
+    };
+    let mut classes: Vec<String> = element.get_attr("class").unwrap_or("").split_ascii_whitespace().map(String::from).collect();
+    let had = classes.iter().any(|c| c == name);
+    if had == on {
+        return on;
+    }
+    if on {
+        classes.push(String::from(name));
+    } else {
+        classes.retain(|c| c != name);
+    }
+    set_attr(element, "class", &classes.join(" "));
+    interp.dom.changed = true;
+    on
+}
+
+fn class_add(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_class_list(interp, &this)?;
+    for name in args {
+        let name = interp.to_string(name)?;
+        set_class(interp, id, &name, true);
+    }
+    Ok(Value::Undefined)
+}
+
+fn class_remove(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_class_list(interp, &this)?;
+    for name in args {
+        let name = interp.to_string(name)?;
+        set_class(interp, id, &name, false);
+    }
+    Ok(Value::Undefined)
+}
+
+fn class_contains(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_class_list(interp, &this)?;
+    let name = string_arg(interp, args, 0)?;
+    let class = interp.dom.element(id).and_then(|e| e.get_attr("class")).unwrap_or("");
+    Ok(Value::Bool(class.split_ascii_whitespace().any(|c| c == name)))
+}
+
+fn class_toggle(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = this_class_list(interp, &this)?;
+    let name = string_arg(interp, args, 0)?;
+    let on = match args.get(1) {
+        Some(force) if !matches!(force, Value::Undefined) => Interpreter::truthy(force),
+        _ => !interp.dom.element(id).and_then(|e| e.get_attr("class")).unwrap_or("").split_ascii_whitespace().any(|c| c == name),
+    };
+    Ok(Value::Bool(set_class(interp, id, &name, on)))
+}
+
+// Events
+
+/// The target `this` stands for: an element, the document or the window
+fn target_of(interp: &Interpreter, this: &Value) -> Target {
+    if let Some(id) = node_of(interp, this) {
+        return Target::Node(id);
+    }
+    match this.object() {
+        Some(obj) if Some(obj) == interp.dom.document_object => Target::Document,
+        _ => Target::Window,
+    }
+}
+
+fn add_event_listener(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let target = target_of(interp, &this);
+    let event = string_arg(interp, args, 0)?;
+    let callback = arg(args, 1);
+    if !interp.is_callable(&callback) {
+        return Ok(Value::Undefined);
+    }
+    let duplicate = interp.dom.listeners.iter().any(|l| {
+        !l.property && l.target == target && l.event == event && Interpreter::strict_equals(&l.callback, &callback)
+    });
+    if duplicate {
+        return Ok(Value::Undefined);
+    }
+    if interp.dom.listeners.len() >= MAX_LISTENERS {
+        return Err(interp.error("RangeError", "Too many event listeners"));
+    }
+    interp.dom.listeners.push(Listener { target, event, callback, property: false });
+    Ok(Value::Undefined)
+}
+
+fn remove_event_listener(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let target = target_of(interp, &this);
+    let event = string_arg(interp, args, 0)?;
+    let callback = arg(args, 1);
+    interp.dom.listeners.retain(|l| {
+        l.property || l.target != target || l.event != event || !Interpreter::strict_equals(&l.callback, &callback)
+    });
+    Ok(Value::Undefined)
+}
+
+fn prevent_default(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    interp.put(&this, "defaultPrevented", Value::Bool(true))?;
+    Ok(Value::Undefined)
+}
+
+fn stop_propagation(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    interp.put(&this, "cancelBubble", Value::Bool(true))?;
+    Ok(Value::Undefined)
+}
+
+/// The object scripts see for `target`
+fn target_object(interp: &mut Interpreter, target: Target) -> Value {
+    match target {
+        Target::Window => Value::Object(interp.global),
+        Target::Document => interp.dom.document_object.map_or(Value::Null, Value::Object),
+        Target::Node(id) => wrap(interp, id).unwrap_or(Value::Null),
+    }
+}
+
+/// The handlers of `target` for `event`, in the order they run: the one
+/// set by property or attribute, then those added as listeners
+fn handlers(interp: &mut Interpreter, target: Target, event: &str) -> Vec<Value> {
+    let mut found = Vec::new();
+    let property = interp.dom.listeners.iter().find(|l| l.property && l.target == target && l.event == event).map(|l| l.callback.clone());
+    let handler_name = format!("on{}", event);
+    match (property, target) {
+        (Some(callback), _) => found.push(callback),
+        (None, Target::Node(id)) => {
+            let source = interp.dom.element(id).and_then(|e| e.get_attr(&handler_name)).map(String::from);
+            if let Some(source) = source {
+                match interp.handler(&source) {
+                    Ok(handler) => found.push(handler),
+                    Err(e) => warn!("js", "Uncaught {}", e),
+                }
+            }
+        }
+        (None, Target::Window) => {
+            // `window.onmessage = ...` is an ordinary property of the global
+            if let Some(callback) = interp.heap.get(interp.global).own(&handler_name).cloned() {
+                found.push(callback);
+            }
+        }
+        (None, Target::Document) => {
+            let own = interp.dom.document_object.and_then(|d| interp.heap.get(d).own(&handler_name).cloned());
+            found.extend(own);
+        }
+    }
+    found.retain(|f| interp.is_callable(f));
+    found.extend(interp.dom.listeners.iter().filter(|l| !l.property && l.target == target && l.event == event).map(|l| l.callback.clone()));
+    found
+}
+
+/// Dispatch `event` to `target` and on up through its ancestors, the
+/// document and the window, with `fields` as properties of the event
+/// object; returns whether a handler called `preventDefault`
+///
+/// `load`, `resize` and `message` go to the window alone. Exceptions a
+/// handler throws are reported and do not stop the others.
+pub fn dispatch(interp: &mut Interpreter, target: Target, event: &str, fields: &[(&str, Value)]) -> bool {
+    let mut path = Vec::new();
+    if let Target::Node(id) = target {
+        path.push(target);
+        let in_page = interp.dom.in_page(id);
+        path.extend(interp.dom.ancestors(id).into_iter().rev().map(Target::Node));
+        if in_page {
+            path.push(Target::Document);
+            path.push(Target::Window);
+        }
+    } else if target == Target::Document {
+        path.extend([Target::Document, Target::Window]);
+    } else {
+        path.push(Target::Window);
+    }
+    if !path.iter().any(|&t| interp.dom.listeners.iter().any(|l| l.target == t && l.event == event)) {
+        // Nothing registered; only inline attributes and properties remain
+        let inline = path.iter().any(|t| match t {
+            Target::Node(id) => interp.dom.element(*id).map_or(false, |e| e.get_attr(&format!("on{}", event)).is_some()),
+            _ => true,
+        });
+        if !inline {
+            return false;
+        }
+    }
+
+    let proto = interp.dom.event_proto;
+    let object = match interp.alloc(Kind::Plain, proto) {
+        Ok(obj) => obj,
+        Err(_) => return false,
+    };
+    // Handlers may run a collection between them
+    let pinned = interp.dom.pinned.len();
+    interp.dom.pinned.push(object);
+    let object = Value::Object(object);
+    let target_value = target_object(interp, target);
+    let mut props: Vec<(&str, Value)> = alloc::vec![
+        ("type", Value::str(event)),
+        ("target", target_value.clone()),
+        ("srcElement", target_value),
+        ("bubbles", Value::Bool(true)),
+        ("defaultPrevented", Value::Bool(false)),
+        ("cancelBubble", Value::Bool(false)),
+        ("timeStamp", Value::Number(crate::drivers::timer::elapsed_ms() as f64)),
+    ];
+    props.extend(fields.iter().cloned());
+    for (name, value) in props {
+        let _ = interp.put(&object, name, value);
+    }
+
+    for step in path {
+        let current = target_object(interp, step);
+        let _ = interp.put(&object, "currentTarget", current.clone());
+        let handlers = handlers(interp, step, event);
+        interp.dom.pinned.extend(handlers.iter().filter_map(Value::object));
+        for handler in handlers {
+            if let Err(e) = interp.invoke(&handler, current.clone(), core::slice::from_ref(&object)) {
+                warn!("js", "Uncaught {}", e);
+            }
+        }
+        let stopped = interp.get(&object, "cancelBubble").map_or(false, |v| Interpreter::truthy(&v));
+        if stopped {
+            break;
+        }
+    }
+    let prevented = interp.get(&object, "defaultPrevented").map_or(false, |v| Interpreter::truthy(&v));
+    interp.dom.pinned.truncate(pinned);
+    prevented
+}
+
+/// Deliver a message from the parent window, given as JSON, as a
+/// `message` event
+pub fn deliver_message(interp: &mut Interpreter, json: &str) {
+    let data = match builtins::from_json(interp, json) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("js", "Cannot deliver message: {}", interp.describe(&e));
+            return;
+        }
+    };
+    let source = interp.heap.get(interp.global).own("parent").cloned().unwrap_or(Value::Null);
+    let fields = [("data", data), ("origin", Value::str("webbos://desktop")), ("source", source)];
+    dispatch(interp, Target::Window, "message", &fields);
+}
+
+fn post_message(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let message = match builtins::to_json(interp, &arg(args, 0), "")? {
+        Some(message) => message,
+        None => return Ok(Value::Undefined),
+    };
+    let outbox = &mut interp.dom.outbox;
+    if outbox.len() >= MAX_OUTBOX {
+        outbox.remove(0);
+    }
+    outbox.push(message);
+    Ok(Value::Undefined)
+}
+
+fn alert(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let message = interp.display(&arg(args, 0));
+    info!("js", "alert: {}", message);
+    Ok(Value::Undefined)
+}
+
+/// There is no way to ask the user from inside a script, so `confirm`
+/// agrees and `prompt` is cancelled
+fn confirm(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let message = interp.display(&arg(args, 0));
+    info!("js", "confirm: {}", message);
+    Ok(Value::Bool(true))
+}
+
+fn prompt(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let message = interp.display(&arg(args, 0));
+    info!("js", "prompt: {}", message);
+    Ok(Value::Null)
+}
+
+// Timers
+
+fn add_timer(interp: &mut Interpreter, args: &[Value], repeat: bool) -> Result<Value, Value> {
+    let mut callback = arg(args, 0);
+    if let Value::String(source) = &callback {
+        // A string is code to run, as a function with no parameters
+        let source = source.clone();
+        callback = match interp.handler(&source) {
+            Ok(callback) => callback,
+            Err(message) => return Err(interp.error("SyntaxError", &message)),
+        };
+    }
+    if !interp.is_callable(&callback) {
+        return Err(interp.type_error("Timer callback is not a function"));
+    }
+    if interp.dom.timers.len() >= MAX_TIMERS {
+        return Err(interp.error("RangeError", "Too many timers"));
+    }
+    let delay = interp.to_number(&arg(args, 1))?;
+    let delay = if delay.is_finite() && delay > 0.0 { (delay as u64).max(MIN_TIMER_DELAY) } else { MIN_TIMER_DELAY };
+    let dom = &mut interp.dom;
+    let id = dom.next_timer;
+    dom.next_timer = dom.next_timer.wrapping_add(1).max(1);
+    dom.timers.push(Timer {
+        id,
+        due: crate::drivers::timer::elapsed_ms() + delay,
+        interval: repeat.then_some(delay),
+        callback,
+        args: args.get(2..).unwrap_or(&[]).to_vec(),
+    });
+    Ok(Value::Number(id as f64))
+}
+
+fn set_timeout(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    add_timer(interp, args, false)
+}
+
+fn set_interval(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    add_timer(interp, args, true)
+}
+
+fn request_animation_frame(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    add_timer(interp, &[arg(args, 0), Value::Number(16.0)], false)
+}
+
+fn clear_timer(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let id = interp.to_number(&arg(args, 0))?;
+    interp.dom.timers.retain(|t| t.id as f64 != id);
+    Ok(Value::Undefined)
+}
+
+/// Run the timers that are due at `now`, in milliseconds since boot;
+/// returns whether any ran
+///
+/// An interval timer that has fallen behind runs once and is rescheduled
+/// from now rather than catching up.
+pub fn run_timers(interp: &mut Interpreter, now: u64) -> bool {
+    let mut ran = 0;
+    while ran < MAX_TIMERS_PER_RUN {
+        let due = interp.dom.timers.iter().enumerate().filter(|(_, t)| t.due <= now).min_by_key(|(_, t)| t.due).map(|(i, _)| i);
+        let i = match due {
+            Some(i) => i,
+            None => break,
+        };
+        let timer = &mut interp.dom.timers[i];
+        let (callback, args) = (timer.callback.clone(), timer.args.clone());
+        match timer.interval {
+            Some(interval) => timer.due = now + interval,
+            None => {
+                interp.dom.timers.remove(i);
+            }
+        }
+        let this = Value::Object(interp.global);
+        if let Err(e) = interp.invoke(&callback, this, &args) {
+            warn!("js", "Uncaught {}", e);
+        }
+        ran += 1;
+    }
+    ran > 0
+}
+
+/// Text a value shows as in an event's `key`, for the keys forms know
+pub fn key_name(key: forms::Key) -> String {
+    match key {
+        forms::Key::Char(c) => c.to_string(),
+        forms::Key::Backspace => String::from("Backspace"),
+        forms::Key::Enter => String::from("Enter"),
+        forms::Key::Up => String::from("ArrowUp"),
+        forms::Key::Down => String::from("ArrowDown"),
+        forms::Key::Tab => String::from("Tab"),
+        forms::Key::Escape => String::from("Escape"),
+    }
+}
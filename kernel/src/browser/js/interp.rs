@@ -0,0 +1,1723 @@
+//! The evaluator
+//!
+//! Runs the syntax tree directly. Variables live in scope objects on the
+//! heap, each pointing to the scope around it, so closures simply keep
+//! the scope they were made in. The global scope is the global object
+//! itself. Exceptions travel as the `Err` side of a `Result` and `break`,
+//! `continue` and `return` as a `Flow`.
+//!
+//! A script gets a budget of steps for each run and each event it
+//! handles, and a limit on how deeply calls nest, as the kernel stack is
+//! small. Running out of either ends the script with an error that `try`
+//! cannot catch.
+
+use alloc::format;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::builtins;
+use super::dom::{self, Dom};
+use super::parser::{self, DeclKind, Expr, FunctionDef, Pattern, Prop, PropKey, Stmt};
+use super::promise::{self, Job};
+use super::regex::Regex;
+use super::value::{number_to_string, Function, Heap, Kind, Native, ObjRef, Object, Value};
+
+/// Deepest calls may nest
+pub const MAX_CALL_DEPTH: usize = 20;
+
+/// Statements, loop rounds and calls a run or an event may take
+const MAX_STEPS: u32 = 2_000_000;
+
+/// Longest an array may grow
+pub const MAX_ARRAY_LENGTH: usize = 1 << 20;
+
+/// How a statement ended
+#[derive(Debug, Clone)]
+pub enum Flow {
+    Normal,
+    Return(Value),
+    Break(Option<String>),
+    Continue(Option<String>),
+}
+
+/// A statement's outcome, or the value it threw
+pub type Completion = Result<Flow, Value>;
+
+/// Prototypes of the built-in kinds of object
+pub struct Protos {
+    pub object: ObjRef,
+    pub function: ObjRef,
+    pub array: ObjRef,
+    pub string: ObjRef,
+    pub number: ObjRef,
+    pub boolean: ObjRef,
+    pub error: ObjRef,
+    pub regexp: ObjRef,
+    pub element: ObjRef,
+    pub class_list: ObjRef,
+    pub promise: ObjRef,
+}
+
+impl Protos {
+    fn all(&self) -> [ObjRef; 11] {
+        [
+            self.object, self.function, self.array, self.string, self.number,
+            self.boolean, self.error, self.regexp, self.element, self.class_list,
+            self.promise,
+        ]
+    }
+}
+
+/// How a pattern binds its names
+#[derive(Clone, Copy)]
+enum Bind {
+    /// As new variables of a scope
+    Declare(ObjRef),
+    /// By assigning to whatever the names already refer to
+    Assign,
+}
+
+pub struct Interpreter {
+    pub heap: Heap,
+    /// The global object, which is also the global scope
+    pub global: ObjRef,
+    pub protos: Protos,
+    /// The page and everything scripts have registered with it
+    pub dom: Dom,
+    /// Promise reactions waiting for the end of the turn
+    pub jobs: VecDeque<Job>,
+    /// Promises rejected this turn with nothing to handle them yet
+    pub unhandled: Vec<ObjRef>,
+    /// Innermost scope
+    scope: ObjRef,
+    /// Scope `var` declares in: that of the function running
+    var_scope: ObjRef,
+    this: Value,
+    depth: usize,
+    steps: u32,
+    /// The script ran out of steps or memory; nothing may catch this
+    aborted: bool,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let object = heap.alloc(Object::new(Kind::Plain, None)).expect("empty heap");
+        let mut make = |kind: Kind| heap.alloc(Object::new(kind, Some(object))).expect("empty heap");
+        let protos = Protos {
+            object,
+            function: make(Kind::Plain),
+            array: make(Kind::Array(Vec::new())),
+            string: make(Kind::Primitive(Value::str(""))),
+            number: make(Kind::Primitive(Value::Number(0.0))),
+            boolean: make(Kind::Primitive(Value::Bool(false))),
+            error: make(Kind::Plain),
+            regexp: make(Kind::Plain),
+            element: make(Kind::Plain),
+            class_list: make(Kind::Plain),
+            promise: make(Kind::Plain),
+        };
+        let global = make(Kind::Plain);
+        let mut interp = Interpreter {
+            heap,
+            global,
+            protos,
+            dom: Dom::new(),
+            jobs: VecDeque::new(),
+            unhandled: Vec::new(),
+            scope: global,
+            var_scope: global,
+            this: Value::Object(global),
+            depth: 0,
+            steps: 0,
+            aborted: false,
+        };
+        builtins::install(&mut interp);
+        promise::install(&mut interp);
+        dom::install(&mut interp);
+        interp.heap.live_after_gc = interp.heap.len();
+        interp
+    }
+
+    /// Run a script in the global scope; the error is the uncaught
+    /// exception, as text
+    pub fn run(&mut self, source: &str) -> Result<(), String> {
+        let program = parser::parse(source).map_err(|e| format!("SyntaxError: {}", e))?;
+        self.enter(|interp| {
+            interp.hoist(&program, interp.global);
+            match interp.exec_statements(&program)? {
+                Flow::Normal | Flow::Return(_) => Ok(()),
+                Flow::Break(_) | Flow::Continue(_) => Err(interp.error("SyntaxError", "Illegal break or continue")),
+            }
+        })
+    }
+
+    /// Call `func` from outside any script, as for an event or a timer
+    pub fn invoke(&mut self, func: &Value, this: Value, args: &[Value]) -> Result<Value, String> {
+        let func = func.clone();
+        self.enter(|interp| interp.call(&func, this, args))
+    }
+
+    /// Run `f` as a fresh turn of the event loop: with a new step budget,
+    /// in the global scope, then the promise jobs it queued, collecting
+    /// garbage afterwards if the heap has grown
+    pub fn enter<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, Value>) -> Result<R, String> {
+        let outer = self.depth > 0;
+        if !outer {
+            self.steps = 0;
+            self.aborted = false;
+            self.scope = self.global;
+            self.var_scope = self.global;
+            self.this = Value::Object(self.global);
+        }
+        let result = f(self);
+        let result = match result {
+            Ok(value) => Ok(value),
+            Err(thrown) => Err(self.describe(&thrown)),
+        };
+        if !outer {
+            while let Some(job) = self.jobs.pop_front() {
+                if self.aborted {
+                    self.jobs.clear();
+                    break;
+                }
+                promise::run(self, job);
+            }
+            promise::report_unhandled(self);
+        }
+        if !outer && self.heap.len() > self.heap.live_after_gc * 2 + 1000 {
+            self.collect();
+        }
+        result
+    }
+
+    /// Free the objects nothing can reach any more
+    pub fn collect(&mut self) {
+        let mut roots: Vec<ObjRef> = self.protos.all().to_vec();
+        roots.push(self.global);
+        roots.push(self.scope);
+        roots.push(self.var_scope);
+        if let Value::Object(this) = self.this {
+            roots.push(this);
+        }
+        self.dom.roots(&mut roots);
+        self.jobs.iter().for_each(|job| job.roots(&mut roots));
+        roots.extend_from_slice(&self.unhandled);
+        self.heap.collect(&roots);
+        self.dom.prune(&self.heap);
+    }
+
+    /// An exception as the console shows it, such as `TypeError: x is
+    /// not a function`
+    pub fn describe(&mut self, thrown: &Value) -> String {
+        if let Value::Object(obj) = thrown {
+            let name = self.find_property(*obj, "name");
+            let message = self.find_property(*obj, "message");
+            if let (Some(Value::String(name)), Some(message)) = (name, message) {
+                let message = self.display(&message);
+                return if message.is_empty() { name } else { format!("{}: {}", name, message) };
+            }
+        }
+        self.display(thrown)
+    }
+
+    /// A value as text, for the console: as `String(value)` gives it, or
+    /// a description if that throws
+    pub fn display(&mut self, value: &Value) -> String {
+        match self.to_string(value) {
+            Ok(s) => s,
+            Err(_) => String::from("[object]"),
+        }
+    }
+
+    /// Count a step, ending the script if it has used up its budget
+    fn step(&mut self) -> Result<(), Value> {
+        self.steps += 1;
+        if self.aborted {
+            return Err(Value::str("Script aborted"));
+        }
+        if self.steps > MAX_STEPS {
+            self.aborted = true;
+            return Err(self.error("RangeError", "Script took too long to run"));
+        }
+        Ok(())
+    }
+
+    // Objects
+
+    /// Store a new object, or throw if the heap is full
+    pub fn alloc(&mut self, kind: Kind, proto: Option<ObjRef>) -> Result<ObjRef, Value> {
+        match self.heap.alloc(Object::new(kind, proto)) {
+            Some(obj) => Ok(obj),
+            None => {
+                // Nothing is freed while a script runs, so it cannot go on
+                self.aborted = true;
+                Err(Value::str("RangeError: Out of memory"))
+            }
+        }
+    }
+
+    pub fn new_object(&mut self) -> Result<Value, Value> {
+        let proto = self.protos.object;
+        self.alloc(Kind::Plain, Some(proto)).map(Value::Object)
+    }
+
+    pub fn new_array(&mut self, items: Vec<Value>) -> Result<Value, Value> {
+        let proto = self.protos.array;
+        self.alloc(Kind::Array(items), Some(proto)).map(Value::Object)
+    }
+
+    /// A function object for `f`
+    pub fn native(&mut self, f: Native) -> Result<Value, Value> {
+        let proto = self.protos.function;
+        self.alloc(Kind::Function(Function::Native(f)), Some(proto)).map(Value::Object)
+    }
+
+    /// Give `obj` a method implemented by `f`
+    pub fn define(&mut self, obj: ObjRef, name: &str, f: Native) {
+        if let Ok(method) = self.native(f) {
+            self.heap.get_mut(obj).put(name, method);
+        }
+    }
+
+    /// An error object of the built-in type `name`, such as `TypeError`
+    pub fn error(&mut self, name: &str, message: &str) -> Value {
+        let constructor = self.heap.get(self.global).own(name).and_then(Value::object);
+        let proto = constructor
+            .and_then(|c| self.heap.get(c).own("prototype").and_then(Value::object))
+            .unwrap_or(self.protos.error);
+        match self.alloc(Kind::Plain, Some(proto)) {
+            Ok(obj) => {
+                self.heap.get_mut(obj).put("message", Value::str(message));
+                Value::Object(obj)
+            }
+            Err(e) => e,
+        }
+    }
+
+    pub fn type_error(&mut self, message: &str) -> Value {
+        self.error("TypeError", message)
+    }
+
+    /// The items of an array, copied
+    pub fn array_items(&self, value: &Value) -> Option<Vec<Value>> {
+        match &self.heap.get(value.object()?).kind {
+            Kind::Array(items) => Some(items.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn array_mut(&mut self, value: &Value) -> Option<&mut Vec<Value>> {
+        match &mut self.heap.get_mut(value.object()?).kind {
+            Kind::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn is_callable(&self, value: &Value) -> bool {
+        match value {
+            Value::Object(obj) => matches!(self.heap.get(*obj).kind, Kind::Function(_)),
+            _ => false,
+        }
+    }
+
+    // Properties
+
+    /// A property found on `obj` or its prototypes, not counting what the
+    /// object's kind provides
+    pub fn find_property(&self, obj: ObjRef, key: &str) -> Option<Value> {
+        let mut current = Some(obj);
+        // Prototype chains cannot loop, as nothing can set a prototype,
+        // but a bound keeps a damaged heap from hanging the kernel
+        for _ in 0..64 {
+            let object = self.heap.get(current?);
+            if let Some(value) = object.own(key) {
+                return Some(value.clone());
+            }
+            current = object.proto;
+        }
+        None
+    }
+
+    /// The prototype primitives of a type get their methods from
+    fn primitive_proto(&self, value: &Value) -> Option<ObjRef> {
+        match value {
+            Value::String(_) => Some(self.protos.string),
+            Value::Number(_) => Some(self.protos.number),
+            Value::Bool(_) => Some(self.protos.boolean),
+            _ => None,
+        }
+    }
+
+    /// `target[key]`
+    pub fn get(&mut self, target: &Value, key: &str) -> Result<Value, Value> {
+        let obj = match target {
+            Value::Undefined | Value::Null => {
+                let message = format!("Cannot read properties of {} (reading '{}')", self.display(target), key);
+                return Err(self.type_error(&message));
+            }
+            Value::String(s) => {
+                if let Some(value) = string_property(s, key) {
+                    return Ok(value);
+                }
+                return Ok(self.find_property(self.protos.string, key).unwrap_or(Value::Undefined));
+            }
+            Value::Object(obj) => *obj,
+            primitive => {
+                let proto = self.primitive_proto(primitive).unwrap();
+                return Ok(self.find_property(proto, key).unwrap_or(Value::Undefined));
+            }
+        };
+        match &self.heap.get(obj).kind {
+            Kind::Array(items) => {
+                if key == "length" {
+                    return Ok(Value::Number(items.len() as f64));
+                }
+                if let Some(i) = array_index(key) {
+                    return Ok(items.get(i).cloned().unwrap_or(Value::Undefined));
+                }
+            }
+            Kind::Primitive(Value::String(s)) => {
+                if let Some(value) = string_property(s, key) {
+                    return Ok(value);
+                }
+            }
+            Kind::Function(Function::Script { def, .. }) if key == "prototype" && !def.arrow => {
+                // Made on first use, as most functions never construct
+                if self.heap.get(obj).own("prototype").is_none() {
+                    let proto = self.new_object()?;
+                    self.put(&proto, "constructor", Value::Object(obj))?;
+                    self.heap.get_mut(obj).put("prototype", proto);
+                }
+            }
+            Kind::Function(_) if key == "name" || key == "length" => {
+                if let Some(value) = self.heap.get(obj).own(key) {
+                    return Ok(value.clone());
+                }
+                return Ok(match (key, &self.heap.get(obj).kind) {
+                    ("name", Kind::Function(Function::Script { def, .. })) => {
+                        Value::str(def.name.as_deref().unwrap_or(""))
+                    }
+                    ("length", Kind::Function(Function::Script { def, .. })) => Value::Number(def.params.len() as f64),
+                    ("name", _) => Value::str(""),
+                    _ => Value::Number(0.0),
+                });
+            }
+            Kind::Regex(regex, flags) => {
+                let (regex, flags) = (regex.clone(), flags.clone());
+                match key {
+                    "source" => return Ok(Value::String(regex.source.clone())),
+                    "flags" => return Ok(Value::String(flags)),
+                    "global" => return Ok(Value::Bool(flags.contains('g'))),
+                    _ => {}
+                }
+            }
+            Kind::Document | Kind::Element(_) | Kind::ClassList(_) | Kind::Dataset(_) | Kind::Style(_) => {
+                if let Some(value) = dom::get(self, obj, key)? {
+                    return Ok(value);
+                }
+            }
+            _ => {}
+        }
+        Ok(self.find_property(obj, key).unwrap_or(Value::Undefined))
+    }
+
+    /// `target[key] = value`
+    pub fn put(&mut self, target: &Value, key: &str, value: Value) -> Result<(), Value> {
+        let obj = match target {
+            Value::Undefined | Value::Null => {
+                let message = format!("Cannot set properties of {} (setting '{}')", self.display(target), key);
+                return Err(self.type_error(&message));
+            }
+            Value::Object(obj) => *obj,
+            // Properties set on a primitive go nowhere
+            _ => return Ok(()),
+        };
+        match &mut self.heap.get_mut(obj).kind {
+            Kind::Array(items) => {
+                if key == "length" {
+                    let length = value.to_number();
+                    if !(0.0..=MAX_ARRAY_LENGTH as f64).contains(&length) || length != (length as usize) as f64 {
+                        return Err(self.error("RangeError", "Invalid array length"));
+                    }
+                    items.resize(length as usize, Value::Undefined);
+                    return Ok(());
+                }
+                if let Some(i) = array_index(key) {
+                    if i >= MAX_ARRAY_LENGTH {
+                        return Err(self.error("RangeError", "Invalid array length"));
+                    }
+                    if i >= items.len() {
+                        items.resize(i + 1, Value::Undefined);
+                    }
+                    items[i] = value;
+                    return Ok(());
+                }
+            }
+            Kind::Document | Kind::Element(_) | Kind::ClassList(_) | Kind::Dataset(_) | Kind::Style(_) => {
+                if dom::set(self, obj, key, &value)? {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+        if self.heap.get(obj).props.len() >= MAX_ARRAY_LENGTH {
+            return Err(self.error("RangeError", "Too many properties"));
+        }
+        self.heap.get_mut(obj).put(key, value);
+        Ok(())
+    }
+
+    /// `key in target`
+    fn has_property(&mut self, target: &Value, key: &str) -> Result<bool, Value> {
+        let obj = match target {
+            Value::Object(obj) => *obj,
+            _ => return Err(self.type_error("Cannot use 'in' operator to search in a primitive")),
+        };
+        if let Kind::Array(items) = &self.heap.get(obj).kind {
+            if key == "length" || array_index(key).map_or(false, |i| i < items.len()) {
+                return Ok(true);
+            }
+        }
+        if self.find_property(obj, key).is_some() {
+            return Ok(true);
+        }
+        Ok(!matches!(self.get(target, key)?, Value::Undefined))
+    }
+
+    /// Names of the properties `for...in` visits
+    pub fn keys(&self, value: &Value) -> Vec<String> {
+        match value {
+            Value::String(s) => (0..s.chars().count()).map(|i| i.to_string()).collect(),
+            Value::Object(obj) => {
+                let object = self.heap.get(*obj);
+                let mut keys: Vec<String> = match &object.kind {
+                    Kind::Array(items) => (0..items.len()).map(|i| i.to_string()).collect(),
+                    _ => Vec::new(),
+                };
+                keys.extend(object.props.iter().map(|(k, _)| k.clone()));
+                keys
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The values `for...of` and spreading go through
+    pub fn iterate(&mut self, value: &Value) -> Result<Vec<Value>, Value> {
+        match value {
+            Value::String(s) => Ok(s.chars().map(|c| Value::String(c.to_string())).collect()),
+            Value::Object(obj) => match &self.heap.get(*obj).kind {
+                Kind::Array(items) => Ok(items.clone()),
+                Kind::Primitive(Value::String(s)) => Ok(s.chars().map(|c| Value::String(c.to_string())).collect()),
+                _ => Err(self.type_error("object is not iterable")),
+            },
+            other => {
+                let message = format!("{} is not iterable", self.display(other));
+                Err(self.type_error(&message))
+            }
+        }
+    }
+
+    // Conversions
+
+    pub fn truthy(value: &Value) -> bool {
+        match value {
+            Value::Undefined | Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0 && !n.is_nan(),
+            Value::String(s) => !s.is_empty(),
+            Value::Object(_) => true,
+        }
+    }
+
+    /// The primitive an object stands for in arithmetic and text: what
+    /// its `toString` returns
+    pub fn to_primitive(&mut self, value: &Value) -> Result<Value, Value> {
+        let obj = match value {
+            Value::Object(obj) => *obj,
+            primitive => return Ok(primitive.clone()),
+        };
+        if let Kind::Primitive(inner) = &self.heap.get(obj).kind {
+            return Ok(inner.clone());
+        }
+        let to_string = self.get(value, "toString")?;
+        if self.is_callable(&to_string) {
+            let result = self.call(&to_string, value.clone(), &[])?;
+            if !matches!(result, Value::Object(_)) {
+                return Ok(result);
+            }
+        }
+        Err(self.type_error("Cannot convert object to primitive value"))
+    }
+
+    pub fn to_string(&mut self, value: &Value) -> Result<String, Value> {
+        Ok(match self.to_primitive(value)? {
+            Value::Undefined => String::from("undefined"),
+            Value::Null => String::from("null"),
+            Value::Bool(b) => String::from(if b { "true" } else { "false" }),
+            Value::Number(n) => number_to_string(n),
+            Value::String(s) => s,
+            Value::Object(_) => String::from("[object Object]"),
+        })
+    }
+
+    pub fn to_number(&mut self, value: &Value) -> Result<f64, Value> {
+        Ok(self.to_primitive(value)?.to_number())
+    }
+
+    /// What `typeof` says of a value
+    pub fn type_of(&self, value: &Value) -> &'static str {
+        match value {
+            Value::Undefined => "undefined",
+            Value::Null => "object",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Object(obj) => match self.heap.get(*obj).kind {
+                Kind::Function(_) => "function",
+                _ => "object",
+            },
+        }
+    }
+
+    pub fn strict_equals(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) => x == y,
+            _ => a == b,
+        }
+    }
+
+    fn loose_equals(&mut self, a: &Value, b: &Value) -> Result<bool, Value> {
+        Ok(match (a, b) {
+            (Value::Undefined | Value::Null, Value::Undefined | Value::Null) => true,
+            (Value::Undefined | Value::Null, _) | (_, Value::Undefined | Value::Null) => false,
+            (Value::Object(_), Value::Object(_)) => a == b,
+            (Value::Object(_), _) => {
+                let a = self.to_primitive(a)?;
+                return self.loose_equals(&a, b);
+            }
+            (_, Value::Object(_)) => {
+                let b = self.to_primitive(b)?;
+                return self.loose_equals(a, &b);
+            }
+            (Value::String(x), Value::String(y)) => x == y,
+            _ => a.to_number() == b.to_number(),
+        })
+    }
+
+    // Calls
+
+    /// Call `func` with `this` and `args`
+    pub fn call(&mut self, func: &Value, this: Value, args: &[Value]) -> Result<Value, Value> {
+        let function = match func {
+            Value::Object(obj) => match &self.heap.get(*obj).kind {
+                Kind::Function(function) => function.clone(),
+                _ => return Err(self.type_error("not a function")),
+            },
+            _ => return Err(self.type_error("not a function")),
+        };
+        self.step()?;
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(self.error("RangeError", "Maximum call stack size exceeded"));
+        }
+        self.depth += 1;
+        let result = match function {
+            Function::Native(f) => f(self, this, args),
+            Function::Bound { target, this, args: bound } => {
+                let mut all = bound;
+                all.extend_from_slice(args);
+                self.depth -= 1;
+                let result = self.call(&Value::Object(target), this, &all);
+                self.depth += 1;
+                result
+            }
+            Function::Script { def, scope, this: lexical } => {
+                let this = match lexical {
+                    Some(this) => this,
+                    None if this.is_nullish() => Value::Object(self.global),
+                    None => this,
+                };
+                self.call_script(&def, scope, this, args)
+            }
+        };
+        self.depth -= 1;
+        result
+    }
+
+    fn call_script(&mut self, def: &Arc<FunctionDef>, scope: ObjRef, this: Value, args: &[Value]) -> Result<Value, Value> {
+        let local = self.alloc(Kind::Scope, Some(scope))?;
+        let saved_scope = core::mem::replace(&mut self.scope, local);
+        let saved_var_scope = core::mem::replace(&mut self.var_scope, local);
+        let saved_this = core::mem::replace(&mut self.this, this);
+        let result = self.run_function(def, local, args);
+        self.scope = saved_scope;
+        self.var_scope = saved_var_scope;
+        self.this = saved_this;
+        result
+    }
+
+    fn run_function(&mut self, def: &FunctionDef, local: ObjRef, args: &[Value]) -> Result<Value, Value> {
+        for (i, param) in def.params.iter().enumerate() {
+            let arg = args.get(i).cloned().unwrap_or(Value::Undefined);
+            self.bind(param, arg, Bind::Declare(local))?;
+        }
+        if let Some(rest) = &def.rest {
+            let extra = args.get(def.params.len()..).unwrap_or(&[]).to_vec();
+            let extra = self.new_array(extra)?;
+            self.bind(rest, extra, Bind::Declare(local))?;
+        }
+        if def.arguments {
+            let arguments = self.new_array(args.to_vec())?;
+            self.declare(local, "arguments", arguments);
+        }
+        self.hoist(&def.body, local);
+        match self.exec_statements(&def.body)? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Undefined),
+        }
+    }
+
+    /// `new func(...args)`
+    pub fn construct(&mut self, func: &Value, args: &[Value]) -> Result<Value, Value> {
+        if !self.is_callable(func) {
+            return Err(self.type_error("not a constructor"));
+        }
+        let proto = match self.get(func, "prototype")? {
+            Value::Object(proto) => proto,
+            _ => self.protos.object,
+        };
+        let obj = Value::Object(self.alloc(Kind::Plain, Some(proto))?);
+        let result = self.call(func, obj.clone(), args)?;
+        Ok(if let Value::Object(_) = result { result } else { obj })
+    }
+
+    /// A function made from a definition in the current scope
+    fn closure(&mut self, def: &Arc<FunctionDef>) -> Result<Value, Value> {
+        let this = if def.arrow { Some(self.this.clone()) } else { None };
+        let function = Function::Script { def: def.clone(), scope: self.scope, this };
+        let proto = self.protos.function;
+        self.alloc(Kind::Function(function), Some(proto)).map(Value::Object)
+    }
+
+    /// A function for an inline event handler such as `onclick="..."`,
+    /// with `this` the element, or the error its source has
+    pub fn handler(&mut self, source: &str) -> Result<Value, String> {
+        let def = parser::parse_handler(source).map_err(|e| format!("SyntaxError: {}", e))?;
+        let outer = core::mem::replace(&mut self.scope, self.global);
+        let handler = self.closure(&def);
+        self.scope = outer;
+        handler.map_err(|e| self.describe(&e))
+    }
+
+    // Variables
+
+    fn lookup(&mut self, name: &str) -> Result<Value, Value> {
+        match self.resolve(name) {
+            Some(value) => Ok(value),
+            None => {
+                let message = format!("{} is not defined", name);
+                Err(self.error("ReferenceError", &message))
+            }
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Option<Value> {
+        let mut scope = self.scope;
+        loop {
+            let object = self.heap.get(scope);
+            match object.kind {
+                Kind::Scope => {
+                    if let Some(value) = object.own(name) {
+                        return Some(value.clone());
+                    }
+                    scope = object.proto?;
+                }
+                // The global object, and the end of the chain
+                _ => return self.find_property(scope, name),
+            }
+        }
+    }
+
+    fn assign(&mut self, name: &str, value: Value) -> Result<(), Value> {
+        let mut scope = self.scope;
+        loop {
+            let object = self.heap.get_mut(scope);
+            match object.kind {
+                Kind::Scope => {
+                    if let Some(slot) = object.props.iter_mut().find(|(k, _)| k == name) {
+                        slot.1 = value;
+                        return Ok(());
+                    }
+                    scope = object.proto.unwrap_or(self.global);
+                }
+                _ => return self.put(&Value::Object(scope), name, value),
+            }
+        }
+    }
+
+    fn declare(&mut self, scope: ObjRef, name: &str, value: Value) {
+        self.heap.get_mut(scope).put(name, value);
+    }
+
+    /// Declare the `var`s of a function body, and anything nested in its
+    /// statements, as undefined
+    fn hoist(&mut self, body: &[Stmt], scope: ObjRef) {
+        for stmt in body {
+            match stmt {
+                Stmt::Decl(DeclKind::Var, declarations) => {
+                    for (pattern, _) in declarations {
+                        let mut names = Vec::new();
+                        pattern_names(pattern, &mut names);
+                        for name in names {
+                            if self.heap.get(scope).own(name).is_none() {
+                                self.declare(scope, name, Value::Undefined);
+                            }
+                        }
+                    }
+                }
+                Stmt::If(_, then, otherwise) => {
+                    self.hoist(core::slice::from_ref(then), scope);
+                    if let Some(otherwise) = otherwise {
+                        self.hoist(core::slice::from_ref(otherwise), scope);
+                    }
+                }
+                Stmt::Block(body) => self.hoist(body, scope),
+                Stmt::While(_, body) | Stmt::DoWhile(body, _) | Stmt::Labeled(_, body) => {
+                    self.hoist(core::slice::from_ref(body), scope)
+                }
+                Stmt::For(init, _, _, body) => {
+                    if let Some(init) = init {
+                        self.hoist(core::slice::from_ref(init), scope);
+                    }
+                    self.hoist(core::slice::from_ref(body), scope);
+                }
+                Stmt::ForEach(kind, pattern, _, body, _) => {
+                    if let Some(DeclKind::Var) = kind {
+                        let mut names = Vec::new();
+                        pattern_names(pattern, &mut names);
+                        for name in names {
+                            if self.heap.get(scope).own(name).is_none() {
+                                self.declare(scope, name, Value::Undefined);
+                            }
+                        }
+                    }
+                    self.hoist(core::slice::from_ref(body), scope);
+                }
+                Stmt::Try(body, catch, finally) => {
+                    self.hoist(body, scope);
+                    if let Some((_, handler)) = catch {
+                        self.hoist(handler, scope);
+                    }
+                    if let Some(finally) = finally {
+                        self.hoist(finally, scope);
+                    }
+                }
+                Stmt::Switch(_, cases) => {
+                    for (_, body) in cases {
+                        self.hoist(body, scope);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Bind the names of `pattern` to the parts of `value`
+    fn bind(&mut self, pattern: &Pattern, value: Value, mode: Bind) -> Result<(), Value> {
+        match pattern {
+            Pattern::Ident(name) => match mode {
+                Bind::Declare(scope) => {
+                    self.declare(scope, name, value);
+                    Ok(())
+                }
+                Bind::Assign => self.assign(name, value),
+            },
+            Pattern::Member(target) => {
+                let (obj, key) = self.member(target)?;
+                self.put(&obj, &key, value)
+            }
+            Pattern::Default(target, default) => {
+                let value = match value {
+                    Value::Undefined => self.eval(default)?,
+                    value => value,
+                };
+                self.bind(target, value, mode)
+            }
+            Pattern::Array(elements, rest) => {
+                let items = self.iterate(&value)?;
+                for (i, element) in elements.iter().enumerate() {
+                    if let Some(element) = element {
+                        let item = items.get(i).cloned().unwrap_or(Value::Undefined);
+                        self.bind(element, item, mode)?;
+                    }
+                }
+                if let Some(rest) = rest {
+                    let remaining = items.get(elements.len()..).unwrap_or(&[]).to_vec();
+                    let remaining = self.new_array(remaining)?;
+                    self.bind(rest, remaining, mode)?;
+                }
+                Ok(())
+            }
+            Pattern::Object(props, rest) => {
+                if value.is_nullish() {
+                    let message = format!("Cannot destructure '{}'", self.display(&value));
+                    return Err(self.type_error(&message));
+                }
+                let mut used = Vec::new();
+                for (key, target) in props {
+                    let key = self.prop_key(key)?;
+                    let item = self.get(&value, &key)?;
+                    self.bind(target, item, mode)?;
+                    used.push(key);
+                }
+                if let Some(rest) = rest {
+                    let remaining = self.new_object()?;
+                    for key in self.keys(&value) {
+                        if !used.contains(&key) {
+                            let item = self.get(&value, &key)?;
+                            self.put(&remaining, &key, item)?;
+                        }
+                    }
+                    self.bind(rest, remaining, mode)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn prop_key(&mut self, key: &PropKey) -> Result<String, Value> {
+        match key {
+            PropKey::Name(name) => Ok(name.clone()),
+            PropKey::Computed(expr) => {
+                let key = self.eval(expr)?;
+                self.to_string(&key)
+            }
+        }
+    }
+
+    // Statements
+
+    /// Run statements in the current scope, after defining the functions
+    /// they declare
+    fn exec_statements(&mut self, body: &[Stmt]) -> Completion {
+        for stmt in body {
+            if let Stmt::Function(def) = stmt {
+                let function = self.closure(def)?;
+                let name = def.name.as_deref().unwrap_or("");
+                let scope = self.scope;
+                self.declare(scope, name, function);
+            }
+        }
+        for stmt in body {
+            match self.exec(stmt)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Run a block, in a scope of its own if it declares anything
+    fn exec_block(&mut self, body: &[Stmt]) -> Completion {
+        let declares = body.iter().any(|s| matches!(s, Stmt::Decl(DeclKind::Let | DeclKind::Const, _) | Stmt::Function(_)));
+        if !declares {
+            return self.exec_statements(body);
+        }
+        let block = self.alloc(Kind::Scope, Some(self.scope))?;
+        let outer = core::mem::replace(&mut self.scope, block);
+        let result = self.exec_statements(body);
+        self.scope = outer;
+        result
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Completion {
+        self.step()?;
+        match stmt {
+            Stmt::Expr(expr) => {
+                self.eval(expr)?;
+            }
+            Stmt::Decl(kind, declarations) => {
+                for (pattern, init) in declarations {
+                    if *kind == DeclKind::Var && init.is_none() {
+                        continue;
+                    }
+                    let value = match init {
+                        Some(init) => self.eval_named(init, pattern)?,
+                        None => Value::Undefined,
+                    };
+                    let scope = if *kind == DeclKind::Var { self.var_scope } else { self.scope };
+                    self.bind(pattern, value, Bind::Declare(scope))?;
+                }
+            }
+            // Defined on entry to the enclosing block
+            Stmt::Function(_) | Stmt::Empty => {}
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Value::Undefined,
+                };
+                return Ok(Flow::Return(value));
+            }
+            Stmt::If(test, then, otherwise) => {
+                let test = self.eval(test)?;
+                if Self::truthy(&test) {
+                    return self.exec_body(then);
+                } else if let Some(otherwise) = otherwise {
+                    return self.exec_body(otherwise);
+                }
+            }
+            Stmt::Block(body) => return self.exec_block(body),
+            Stmt::While(..) | Stmt::DoWhile(..) | Stmt::For(..) | Stmt::ForEach(..) => return self.exec_loop(stmt, None),
+            Stmt::Break(label) => return Ok(Flow::Break(label.clone())),
+            Stmt::Continue(label) => return Ok(Flow::Continue(label.clone())),
+            Stmt::Throw(value) => {
+                let value = self.eval(value)?;
+                return Err(value);
+            }
+            Stmt::Try(body, catch, finally) => return self.exec_try(body, catch, finally),
+            Stmt::Switch(discriminant, cases) => return self.exec_switch(discriminant, cases),
+            Stmt::Labeled(label, body) => {
+                let flow = match &**body {
+                    Stmt::While(..) | Stmt::DoWhile(..) | Stmt::For(..) | Stmt::ForEach(..) => self.exec_loop(body, Some(label))?,
+                    other => self.exec(other)?,
+                };
+                return Ok(match flow {
+                    Flow::Break(Some(l)) if l == *label => Flow::Normal,
+                    flow => flow,
+                });
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// The body of an `if` or loop, which is a block or a single statement
+    fn exec_body(&mut self, body: &Stmt) -> Completion {
+        match body {
+            Stmt::Block(body) => self.exec_block(body),
+            other => self.exec(other),
+        }
+    }
+
+    /// What a loop does after its body ran: `Ok(true)` to go round again,
+    /// `Ok(false)` to stop, or `Err` with a flow for an enclosing statement
+    fn loop_flow(flow: Flow, label: Option<&String>) -> Result<bool, Flow> {
+        match flow {
+            Flow::Normal | Flow::Continue(None) => Ok(true),
+            Flow::Break(None) => Ok(false),
+            Flow::Continue(Some(l)) if Some(&l) == label => Ok(true),
+            Flow::Break(Some(l)) if Some(&l) == label => Ok(false),
+            flow => Err(flow),
+        }
+    }
+
+    fn exec_loop(&mut self, stmt: &Stmt, label: Option<&String>) -> Completion {
+        match stmt {
+            Stmt::While(test, body) => loop {
+                let test = self.eval(test)?;
+                if !Self::truthy(&test) {
+                    break;
+                }
+                let flow = self.exec_body(body)?;
+                match Self::loop_flow(flow, label) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(flow) => return Ok(flow),
+                }
+            },
+            Stmt::DoWhile(body, test) => loop {
+                self.step()?;
+                let flow = self.exec_body(body)?;
+                match Self::loop_flow(flow, label) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(flow) => return Ok(flow),
+                }
+                let test = self.eval(test)?;
+                if !Self::truthy(&test) {
+                    break;
+                }
+            },
+            Stmt::For(init, test, update, body) => {
+                // A `let` in the head is bound afresh for each round, so
+                // closures made in the body each see their own
+                let lexical = matches!(init.as_deref(), Some(Stmt::Decl(DeclKind::Let | DeclKind::Const, _)));
+                let outer = self.scope;
+                if lexical {
+                    self.scope = self.alloc(Kind::Scope, Some(outer))?;
+                }
+                let result = self.exec_for(init.as_deref(), test.as_ref(), update.as_ref(), body, label, lexical);
+                self.scope = outer;
+                return result;
+            }
+            Stmt::ForEach(kind, pattern, iterable, body, is_in) => {
+                let collection = self.eval(iterable)?;
+                let items: Vec<Value> = if *is_in {
+                    self.keys(&collection).into_iter().map(Value::String).collect()
+                } else {
+                    self.iterate(&collection)?
+                };
+                let outer = self.scope;
+                for item in items {
+                    self.step()?;
+                    let mode = match kind {
+                        Some(DeclKind::Var) => Bind::Declare(self.var_scope),
+                        Some(_) => {
+                            self.scope = self.alloc(Kind::Scope, Some(outer))?;
+                            Bind::Declare(self.scope)
+                        }
+                        None => Bind::Assign,
+                    };
+                    let flow = self.bind(pattern, item, mode).and_then(|_| self.exec_body(body));
+                    self.scope = outer;
+                    match Self::loop_flow(flow?, label) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(flow) => return Ok(flow),
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_for(&mut self, init: Option<&Stmt>, test: Option<&Expr>, update: Option<&Expr>, body: &Stmt, label: Option<&String>, lexical: bool) -> Completion {
+        if let Some(init) = init {
+            self.exec(init)?;
+        }
+        loop {
+            if let Some(test) = test {
+                let test = self.eval(test)?;
+                if !Self::truthy(&test) {
+                    break;
+                }
+            } else {
+                self.step()?;
+            }
+            let flow = self.exec_body(body)?;
+            match Self::loop_flow(flow, label) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(flow) => return Ok(flow),
+            }
+            if lexical {
+                // Copy the loop variables into the next round's scope
+                let proto = self.heap.get(self.scope).proto;
+                let props = self.heap.get(self.scope).props.clone();
+                self.scope = self.alloc(Kind::Scope, proto)?;
+                self.heap.get_mut(self.scope).props = props;
+            }
+            if let Some(update) = update {
+                self.eval(update)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_try(&mut self, body: &[Stmt], catch: &Option<(Option<Pattern>, Vec<Stmt>)>, finally: &Option<Vec<Stmt>>) -> Completion {
+        let mut result = self.exec_block(body);
+        if let (Err(thrown), Some((param, handler))) = (&result, catch) {
+            if !self.aborted {
+                let thrown = thrown.clone();
+                let outer = self.scope;
+                result = self.alloc(Kind::Scope, Some(outer)).and_then(|scope| {
+                    self.scope = scope;
+                    if let Some(param) = param {
+                        self.bind(param, thrown, Bind::Declare(scope))?;
+                    }
+                    self.exec_block(handler)
+                });
+                self.scope = outer;
+            }
+        }
+        if let Some(finally) = finally {
+            if self.aborted {
+                return result;
+            }
+            match self.exec_block(finally)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        result
+    }
+
+    fn exec_switch(&mut self, discriminant: &Expr, cases: &[(Option<Expr>, Vec<Stmt>)]) -> Completion {
+        let value = self.eval(discriminant)?;
+        let mut start = None;
+        for (i, (test, _)) in cases.iter().enumerate() {
+            if let Some(test) = test {
+                let test = self.eval(test)?;
+                if Self::strict_equals(&value, &test) {
+                    start = Some(i);
+                    break;
+                }
+            }
+        }
+        let start = match start.or_else(|| cases.iter().position(|(test, _)| test.is_none())) {
+            Some(start) => start,
+            None => return Ok(Flow::Normal),
+        };
+        let block = self.alloc(Kind::Scope, Some(self.scope))?;
+        let outer = core::mem::replace(&mut self.scope, block);
+        let mut result = Ok(Flow::Normal);
+        for (_, body) in &cases[start..] {
+            result = self.exec_statements(body);
+            if !matches!(result, Ok(Flow::Normal)) {
+                break;
+            }
+        }
+        self.scope = outer;
+        match result? {
+            Flow::Break(None) => Ok(Flow::Normal),
+            flow => Ok(flow),
+        }
+    }
+
+    // Expressions
+
+    /// The object and key of a member expression `obj[key]` or `obj.key`
+    fn member(&mut self, expr: &Expr) -> Result<(Value, String), Value> {
+        match expr {
+            Expr::Member(obj, key, _) => {
+                let obj = self.eval(obj)?;
+                let key = match &**key {
+                    Expr::String(key) => key.clone(),
+                    key => {
+                        let key = self.eval(key)?;
+                        self.to_string(&key)?
+                    }
+                };
+                Ok((obj, key))
+            }
+            _ => Err(self.error("SyntaxError", "Invalid assignment target")),
+        }
+    }
+
+    /// Evaluate the value of a declaration or assignment, naming it after
+    /// the variable if it is an anonymous function
+    fn eval_named(&mut self, expr: &Expr, target: &Pattern) -> Result<Value, Value> {
+        let value = self.eval(expr)?;
+        if let (Expr::Function(def), Pattern::Ident(name)) = (expr, target) {
+            if def.name.is_none() {
+                if let Value::Object(obj) = value {
+                    self.heap.get_mut(obj).put("name", Value::str(name));
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn eval_list(&mut self, exprs: &[Expr]) -> Result<Vec<Value>, Value> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for expr in exprs {
+            match expr {
+                Expr::Spread(inner) => {
+                    let inner = self.eval(inner)?;
+                    values.extend(self.iterate(&inner)?);
+                }
+                expr => values.push(self.eval(expr)?),
+            }
+            if values.len() > MAX_ARRAY_LENGTH {
+                return Err(self.error("RangeError", "Invalid array length"));
+            }
+        }
+        Ok(values)
+    }
+
+    pub fn eval(&mut self, expr: &Expr) -> Result<Value, Value> {
+        Ok(match expr {
+            Expr::Number(n) => Value::Number(*n),
+            Expr::String(s) => Value::String(s.clone()),
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Null => Value::Null,
+            Expr::Undefined => Value::Undefined,
+            Expr::Template(quasis, values) => {
+                let mut text = String::new();
+                for (i, quasi) in quasis.iter().enumerate() {
+                    text.push_str(quasi);
+                    if let Some(value) = values.get(i) {
+                        let value = self.eval(value)?;
+                        text.push_str(&self.to_string(&value)?);
+                    }
+                }
+                Value::String(text)
+            }
+            Expr::Regex(pattern, flags) => self.new_regex(pattern, flags)?,
+            Expr::Ident(name) => self.lookup(name)?,
+            Expr::This => self.this.clone(),
+            Expr::Array(items) => {
+                let items = self.eval_list(items)?;
+                self.new_array(items)?
+            }
+            Expr::Object(props) => {
+                let obj = self.new_object()?;
+                for prop in props {
+                    match prop {
+                        Prop::Init(key, value) => {
+                            let key = self.prop_key(key)?;
+                            let value = self.eval(value)?;
+                            self.put(&obj, &key, value)?;
+                        }
+                        Prop::Spread(source) => {
+                            let source = self.eval(source)?;
+                            if let Value::Object(_) = source {
+                                for key in self.keys(&source) {
+                                    let value = self.get(&source, &key)?;
+                                    self.put(&obj, &key, value)?;
+                                }
+                            }
+                        }
+                    }
+                }
+                obj
+            }
+            Expr::Function(def) => {
+                if def.name.is_some() && !def.arrow {
+                    // A named function expression can call itself by name
+                    let scope = self.alloc(Kind::Scope, Some(self.scope))?;
+                    let outer = core::mem::replace(&mut self.scope, scope);
+                    let function = self.closure(def);
+                    self.scope = outer;
+                    let function = function?;
+                    self.declare(scope, def.name.as_deref().unwrap(), function.clone());
+                    function
+                } else {
+                    self.closure(def)?
+                }
+            }
+            Expr::Unary(op, operand) => self.eval_unary(op, operand)?,
+            Expr::Update(op, prefix, target) => {
+                let old = match &**target {
+                    Expr::Ident(name) => self.lookup(name)?,
+                    target => {
+                        let (obj, key) = self.member(target)?;
+                        self.get(&obj, &key)?
+                    }
+                };
+                let old = self.to_number(&old)?;
+                let new = if *op == "++" { old + 1.0 } else { old - 1.0 };
+                match &**target {
+                    Expr::Ident(name) => self.assign(name, Value::Number(new))?,
+                    target => {
+                        let (obj, key) = self.member(target)?;
+                        self.put(&obj, &key, Value::Number(new))?;
+                    }
+                }
+                Value::Number(if *prefix { new } else { old })
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.binary(op, &left, &right)?
+            }
+            Expr::Logical(op, left, right) => {
+                let left = self.eval(left)?;
+                let short = match *op {
+                    "&&" => !Self::truthy(&left),
+                    "||" => Self::truthy(&left),
+                    _ => !left.is_nullish(),
+                };
+                if short { left } else { self.eval(right)? }
+            }
+            Expr::Assign(op, target, value) => self.eval_assign(op, target, value)?,
+            Expr::Conditional(test, then, otherwise) => {
+                let test = self.eval(test)?;
+                if Self::truthy(&test) { self.eval(then)? } else { self.eval(otherwise)? }
+            }
+            Expr::Call(callee, args, optional) => {
+                let (func, this) = match &**callee {
+                    Expr::Member(obj, _, optional_member) => {
+                        let receiver = self.eval(obj)?;
+                        if *optional_member && receiver.is_nullish() {
+                            return Ok(Value::Undefined);
+                        }
+                        let key = match &**callee {
+                            Expr::Member(_, key, _) => key,
+                            _ => unreachable!(),
+                        };
+                        let key = match &**key {
+                            Expr::String(key) => key.clone(),
+                            key => {
+                                let key = self.eval(key)?;
+                                self.to_string(&key)?
+                            }
+                        };
+                        (self.get(&receiver, &key)?, receiver)
+                    }
+                    other => (self.eval(other)?, Value::Undefined),
+                };
+                if *optional && func.is_nullish() {
+                    return Ok(Value::Undefined);
+                }
+                let args = self.eval_list(args)?;
+                if !self.is_callable(&func) {
+                    let message = format!("{} is not a function", describe_expr(callee));
+                    return Err(self.type_error(&message));
+                }
+                self.call(&func, this, &args)?
+            }
+            Expr::New(callee, args) => {
+                let func = self.eval(callee)?;
+                let args = self.eval_list(args)?;
+                if !self.is_callable(&func) {
+                    let message = format!("{} is not a constructor", describe_expr(callee));
+                    return Err(self.type_error(&message));
+                }
+                self.construct(&func, &args)?
+            }
+            Expr::Member(obj, key, optional) => {
+                let obj = self.eval(obj)?;
+                if *optional && obj.is_nullish() {
+                    return Ok(Value::Undefined);
+                }
+                let key = match &**key {
+                    Expr::String(key) => key.clone(),
+                    key => {
+                        let key = self.eval(key)?;
+                        self.to_string(&key)?
+                    }
+                };
+                self.get(&obj, &key)?
+            }
+            Expr::Sequence(exprs) => {
+                let mut last = Value::Undefined;
+                for expr in exprs {
+                    last = self.eval(expr)?;
+                }
+                last
+            }
+            Expr::Spread(_) => return Err(self.error("SyntaxError", "Unexpected '...'")),
+        })
+    }
+
+    pub fn new_regex(&mut self, pattern: &str, flags: &str) -> Result<Value, Value> {
+        let regex = match Regex::new(pattern, flags) {
+            Ok(regex) => regex,
+            Err(message) => return Err(self.error("SyntaxError", &message)),
+        };
+        let proto = self.protos.regexp;
+        let obj = self.alloc(Kind::Regex(Arc::new(regex), String::from(flags)), Some(proto))?;
+        self.heap.get_mut(obj).put("lastIndex", Value::Number(0.0));
+        Ok(Value::Object(obj))
+    }
+
+    fn eval_unary(&mut self, op: &str, operand: &Expr) -> Result<Value, Value> {
+        match op {
+            "typeof" => {
+                // An undeclared name is "undefined" rather than an error
+                let value = match operand {
+                    Expr::Ident(name) => self.resolve(name).unwrap_or(Value::Undefined),
+                    operand => self.eval(operand)?,
+                };
+                return Ok(Value::str(self.type_of(&value)));
+            }
+            "delete" => {
+                if let Expr::Member(..) = operand {
+                    let (obj, key) = self.member(operand)?;
+                    if let Value::Object(o) = obj {
+                        match &mut self.heap.get_mut(o).kind {
+                            Kind::Array(items) => {
+                                if let Some(slot) = array_index(&key).and_then(|i| items.get_mut(i)) {
+                                    *slot = Value::Undefined;
+                                }
+                            }
+                            _ => {
+                                self.heap.get_mut(o).remove(&key);
+                            }
+                        }
+                    }
+                }
+                return Ok(Value::Bool(true));
+            }
+            _ => {}
+        }
+        let value = self.eval(operand)?;
+        Ok(match op {
+            "!" => Value::Bool(!Self::truthy(&value)),
+            "-" => Value::Number(-self.to_number(&value)?),
+            "+" => Value::Number(self.to_number(&value)?),
+            "~" => Value::Number(!to_int32(self.to_number(&value)?) as f64),
+            _ => Value::Undefined,
+        })
+    }
+
+    fn eval_assign(&mut self, op: &str, target: &Pattern, value: &Expr) -> Result<Value, Value> {
+        if op == "=" {
+            let value = self.eval_named(value, target)?;
+            self.bind(target, value.clone(), Bind::Assign)?;
+            return Ok(value);
+        }
+        // A compound assignment reads the target once and writes it once
+        let (obj, key) = match target {
+            Pattern::Member(member) => {
+                let (obj, key) = self.member(member)?;
+                (Some(obj), key)
+            }
+            Pattern::Ident(name) => (None, name.clone()),
+            _ => return Err(self.error("SyntaxError", "Invalid assignment target")),
+        };
+        let current = match &obj {
+            Some(obj) => self.get(obj, &key)?,
+            None => self.lookup(&key)?,
+        };
+        let result = match op {
+            "&&=" | "||=" | "??=" => {
+                let keep = match op {
+                    "&&=" => !Self::truthy(&current),
+                    "||=" => Self::truthy(&current),
+                    _ => !current.is_nullish(),
+                };
+                if keep {
+                    return Ok(current);
+                }
+                self.eval(value)?
+            }
+            _ => {
+                let value = self.eval(value)?;
+                self.binary(&op[..op.len() - 1], &current, &value)?
+            }
+        };
+        match &obj {
+            Some(obj) => self.put(obj, &key, result.clone())?,
+            None => self.assign(&key, result.clone())?,
+        }
+        Ok(result)
+    }
+
+    fn binary(&mut self, op: &str, left: &Value, right: &Value) -> Result<Value, Value> {
+        Ok(match op {
+            "+" => {
+                let left = self.to_primitive(left)?;
+                let right = self.to_primitive(right)?;
+                if matches!(left, Value::String(_)) || matches!(right, Value::String(_)) {
+                    let mut text = self.to_string(&left)?;
+                    text.push_str(&self.to_string(&right)?);
+                    Value::String(text)
+                } else {
+                    Value::Number(left.to_number() + right.to_number())
+                }
+            }
+            "==" => Value::Bool(self.loose_equals(left, right)?),
+            "!=" => Value::Bool(!self.loose_equals(left, right)?),
+            "===" => Value::Bool(Self::strict_equals(left, right)),
+            "!==" => Value::Bool(!Self::strict_equals(left, right)),
+            "<" | ">" | "<=" | ">=" => {
+                let left = self.to_primitive(left)?;
+                let right = self.to_primitive(right)?;
+                let ordering = match (&left, &right) {
+                    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+                    _ => left.to_number().partial_cmp(&right.to_number()),
+                };
+                Value::Bool(match ordering {
+                    None => false,
+                    Some(ordering) => match op {
+                        "<" => ordering.is_lt(),
+                        ">" => ordering.is_gt(),
+                        "<=" => ordering.is_le(),
+                        _ => ordering.is_ge(),
+                    },
+                })
+            }
+            "instanceof" => {
+                if !self.is_callable(right) {
+                    return Err(self.type_error("Right-hand side of 'instanceof' is not callable"));
+                }
+                let proto = self.get(right, "prototype")?.object();
+                let mut current = left.object().and_then(|o| self.heap.get(o).proto);
+                let mut found = false;
+                while let (Some(obj), Some(proto)) = (current, proto) {
+                    if obj == proto {
+                        found = true;
+                        break;
+                    }
+                    current = self.heap.get(obj).proto;
+                }
+                Value::Bool(found)
+            }
+            "in" => {
+                let key = self.to_string(left)?;
+                Value::Bool(self.has_property(right, &key)?)
+            }
+            _ => {
+                let a = self.to_number(left)?;
+                let b = self.to_number(right)?;
+                Value::Number(match op {
+                    "-" => a - b,
+                    "*" => a * b,
+                    "/" => a / b,
+                    "%" => builtins::fmod(a, b),
+                    "**" => builtins::pow(a, b),
+                    "&" => (to_int32(a) & to_int32(b)) as f64,
+                    "|" => (to_int32(a) | to_int32(b)) as f64,
+                    "^" => (to_int32(a) ^ to_int32(b)) as f64,
+                    "<<" => to_int32(a).wrapping_shl(to_int32(b) as u32 & 31) as f64,
+                    ">>" => to_int32(a).wrapping_shr(to_int32(b) as u32 & 31) as f64,
+                    ">>>" => (to_int32(a) as u32).wrapping_shr(to_int32(b) as u32 & 31) as f64,
+                    _ => f64::NAN,
+                })
+            }
+        })
+    }
+}
+
+/// A number as the 32-bit integer bitwise operators work on
+pub fn to_int32(n: f64) -> i32 {
+    if !n.is_finite() {
+        return 0;
+    }
+    // Wrap modulo 2^32, as JavaScript does, for numbers an i64 holds
+    (n as i64) as i32
+}
+
+/// `key` as an array index, if it is one
+pub fn array_index(key: &str) -> Option<usize> {
+    if key.is_empty() || key.len() > 9 || (key.len() > 1 && key.starts_with('0')) {
+        return None;
+    }
+    if !key.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    key.parse().ok()
+}
+
+/// `length` or a character of a string
+fn string_property(s: &str, key: &str) -> Option<Value> {
+    if key == "length" {
+        return Some(Value::Number(s.chars().count() as f64));
+    }
+    let i = array_index(key)?;
+    Some(s.chars().nth(i).map_or(Value::Undefined, |c| Value::String(c.to_string())))
+}
+
+/// Names a pattern binds
+fn pattern_names<'a>(pattern: &'a Pattern, out: &mut Vec<&'a str>) {
+    match pattern {
+        Pattern::Ident(name) => out.push(name),
+        Pattern::Member(_) => {}
+        Pattern::Default(target, _) => pattern_names(target, out),
+        Pattern::Array(elements, rest) => {
+            elements.iter().flatten().for_each(|p| pattern_names(p, out));
+            if let Some(rest) = rest {
+                pattern_names(rest, out);
+            }
+        }
+        Pattern::Object(props, rest) => {
+            props.iter().for_each(|(_, p)| pattern_names(p, out));
+            if let Some(rest) = rest {
+                pattern_names(rest, out);
+            }
+        }
+    }
+}
+
+/// An expression as error messages name it, such as `list.map`
+fn describe_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::Ident(name) => name.clone(),
+        Expr::This => String::from("this"),
+        Expr::Member(obj, key, _) => match &**key {
+            Expr::String(key) => format!("{}.{}", describe_expr(obj), key),
+            _ => format!("{}[...]", describe_expr(obj)),
+        },
+        Expr::Call(callee, _, _) => format!("{}(...)", describe_expr(callee)),
+        _ => String::from("expression"),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// Run `source` and give the global `result` as the console shows it
+    fn result_of(source: &str) -> Result<String, String> {
+        let mut interp = Interpreter::new();
+        interp.run(source)?;
+        let result = interp.find_property(interp.global, "result").unwrap_or(Value::Undefined);
+        Ok(interp.display(&result))
+    }
+
+    #[kernel_test]
+    fn operators_follow_precedence() -> Result<(), String> {
+        check_eq!(result_of("var result = 1 + 2 * 3 - 4 / 2;")?, "5");
+        check_eq!(result_of("var result = 2 ** 3 ** 2;")?, "512");
+        check_eq!(result_of("var result = (1 + 2) * 3 % 4;")?, "1");
+        check_eq!(result_of("var result = 1 + 2 + '3' + 4;")?, "334");
+        check_eq!(result_of("var result = 1 < 2 == true && !0 ? 'yes' : 'no';")?, "yes");
+        check_eq!(result_of("var result = null ?? 0 || 7;")?, "7");
+        check_eq!(result_of("var result = -8 >> 1 | 1;")?, "-3");
+        check_eq!(result_of("var a = 1, b = 2; var result = a = b += 3; result += a;")?, "10");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn closures_keep_their_scope() -> Result<(), String> {
+        check_eq!(result_of("
+            function counter() { let n = 0; return () => ++n; }
+            var a = counter(), b = counter();
+            a(); a();
+            var result = a() + ',' + b();
+        ")?, "3,1");
+        // Each iteration of a let loop has its own binding
+        check_eq!(result_of("
+            var fs = [];
+            for (let i = 0; i < 3; i++) fs.push(function () { return i; });
+            var result = fs.map(f => f()).join('');
+        ")?, "012");
+        check_eq!(result_of("
+            function outer(x) { function inner(y) { return x * y; } return inner; }
+            var result = outer(6)(7);
+        ")?, "42");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn exceptions_unwind_to_catch() -> Result<(), String> {
+        check_eq!(result_of("
+            var log = [];
+            function f() { try { log.push('try'); throw new Error('boom'); }
+                           finally { log.push('finally'); } }
+            try { f(); } catch (e) { log.push(e.message); }
+            var result = log.join(' ');
+        ")?, "try finally boom");
+        check_eq!(result_of("
+            var result;
+            try { null.x; } catch (e) { result = e instanceof TypeError; }
+        ")?, "true");
+        check_eq!(result_of("
+            function f() { try { return 1; } finally { result = 'ran'; } }
+            var result; f();
+        ")?, "ran");
+        check_eq!(result_of("var result; try { throw 5; } catch (e) { result = e * 2; }")?, "10");
+        let uncaught = result_of("undefinedThing();").err().unwrap_or_default();
+        check!(uncaught.starts_with("ReferenceError: "));
+        check_eq!(result_of("throw new RangeError('too big');"), Err(String::from("RangeError: too big")));
+        check!(result_of("var = 1;").err().unwrap_or_default().starts_with("SyntaxError: "));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn objects_and_arrays_have_properties() -> Result<(), String> {
+        check_eq!(result_of("
+            var o = { a: 1, 'b c': 2, nested: { d: [10, 20] } };
+            o.e = o.a + o['b c'];
+            var result = [o.e, o.nested.d[1], o.missing, o.nested.d.length].join('/');
+        ")?, "3/20//2");
+        check_eq!(result_of("var a = [1, 2]; a[4] = 5; var result = a.length + ':' + a[3];")?, "5:undefined");
+        check_eq!(result_of("var a = [1, 2, 3]; a.length = 1; var result = String(a);")?, "1");
+        check_eq!(result_of("
+            function Person(name) { this.name = name; }
+            Person.prototype.greet = function () { return 'hi ' + this.name; };
+            var o = new Person('x');
+            var result = o.greet() + ' ' + ('greet' in o) + ' ' + o.hasOwnProperty('greet');
+        ")?, "hi x true false");
+        check_eq!(result_of("var o = {}; var k = 'dyn'; o[k + 1] = 9; var result = Object.keys(o)[0];")?, "dyn1");
+        let err = result_of("var o; o.x;").err().unwrap_or_default();
+        check!(err.starts_with("TypeError: "));
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+//! JavaScript tokenizer
+//!
+//! Splits source into tokens, noting which follow a line break so the
+//! parser can insert the semicolons a script leaves out. A `/` starts a
+//! regular expression where an operand is expected and is division
+//! elsewhere. Template literals are read whole, with the tokens of each
+//! `${...}` substitution nested inside.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Operators and punctuation, longest first so `===` is not read as `==`
+const PUNCTUATORS: &[&str] = &[
+    ">>>=", "...", "===", "!==", "**=", "<<=", ">>=", ">>>", "&&=", "||=", "??=",
+    "=>", "==", "!=", "<=", ">=", "&&", "||", "??", "?.", "++", "--", "+=", "-=", "*=", "/=", "%=",
+    "&=", "|=", "^=", "**", "<<", ">>",
+    "{", "}", "(", ")", "[", "]", ";", ",", "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "!",
+    "~", "?", ":", "=", ".",
+];
+
+/// Words after which a `/` starts a regular expression
+const OPERAND_KEYWORDS: &[&str] = &["return", "typeof", "instanceof", "in", "of", "new", "delete", "void", "throw", "case", "do", "else"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Number(f64),
+    String(String),
+    /// The text between substitutions, one more piece than there are
+    /// substitutions, and the tokens of each substitution
+    Template(Vec<String>, Vec<Vec<Tok>>),
+    /// Pattern and flags
+    Regex(String, String),
+    /// A name or keyword
+    Ident(String),
+    Punct(&'static str),
+    Eof,
+}
+
+/// A token and whether a line break comes before it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tok {
+    pub token: Token,
+    pub newline: bool,
+    /// Line the token is on, counting from 1
+    pub line: u32,
+}
+
+struct Lexer<'a> {
+    chars: &'a [char],
+    pos: usize,
+    line: u32,
+}
+
+/// Tokens of `source`, ending with `Eof`
+pub fn tokenize(source: &str) -> Result<Vec<Tok>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut lexer = Lexer { chars: &chars, pos: 0, line: 1 };
+    let mut tokens = lexer.tokens(false)?;
+    tokens.push(Tok { token: Token::Eof, newline: true, line: lexer.line });
+    Ok(tokens)
+}
+
+impl Lexer<'_> {
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.pos + ahead).copied()
+    }
+
+    fn error(&self, message: &str) -> String {
+        alloc::format!("line {}: {}", self.line, message)
+    }
+
+    /// Tokens up to the end, or up to the `}` closing a substitution if
+    /// `substitution` is set
+    fn tokens(&mut self, substitution: bool) -> Result<Vec<Tok>, String> {
+        let mut tokens: Vec<Tok> = Vec::new();
+        let mut depth = 0usize;
+        loop {
+            let newline = self.skip_space()?;
+            let c = match self.peek(0) {
+                Some(c) => c,
+                None if substitution => return Err(self.error("unterminated template")),
+                None => return Ok(tokens),
+            };
+            let line = self.line;
+            let token = if c.is_ascii_digit() || (c == '.' && self.peek(1).map_or(false, |d| d.is_ascii_digit())) {
+                self.number()?
+            } else if c == '"' || c == '\'' {
+                self.pos += 1;
+                Token::String(self.string(c)?)
+            } else if c == '`' {
+                self.pos += 1;
+                self.template()?
+            } else if is_ident_start(c) {
+                let start = self.pos;
+                while self.peek(0).map_or(false, is_ident_part) {
+                    self.pos += 1;
+                }
+                Token::Ident(self.chars[start..self.pos].iter().collect())
+            } else if c == '/' && operand_expected(tokens.last()) {
+                self.pos += 1;
+                self.regex()?
+            } else {
+                let punct = PUNCTUATORS.iter().find(|p| {
+                    p.chars().enumerate().all(|(i, pc)| self.peek(i) == Some(pc))
+                });
+                let punct = match punct {
+                    Some(p) => *p,
+                    None => return Err(self.error(&alloc::format!("unexpected character '{}'", c))),
+                };
+                // `?.` followed by a digit is a conditional and a number
+                let punct = if punct == "?." && self.peek(2).map_or(false, |d| d.is_ascii_digit()) { "?" } else { punct };
+                self.pos += punct.chars().count();
+                match punct {
+                    "{" => depth += 1,
+                    "}" if depth == 0 && substitution => return Ok(tokens),
+                    "}" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                Token::Punct(punct)
+            };
+            tokens.push(Tok { token, newline, line });
+        }
+    }
+
+    /// Skip white space and comments; true if they held a line break
+    fn skip_space(&mut self) -> Result<bool, String> {
+        let mut newline = false;
+        while let Some(c) = self.peek(0) {
+            if c == '\n' {
+                newline = true;
+                self.line += 1;
+                self.pos += 1;
+            } else if c.is_whitespace() || c == '\u{feff}' {
+                self.pos += 1;
+            } else if c == '/' && self.peek(1) == Some('/') {
+                while self.peek(0).map_or(false, |c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == '/' && self.peek(1) == Some('*') {
+                self.pos += 2;
+                loop {
+                    match self.peek(0) {
+                        Some('*') if self.peek(1) == Some('/') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                newline = true;
+                                self.line += 1;
+                            }
+                            self.pos += 1;
+                        }
+                        None => return Err(self.error("unterminated comment")),
+                    }
+                }
+                self.pos += 2;
+            } else {
+                break;
+            }
+        }
+        Ok(newline)
+    }
+
+    fn number(&mut self) -> Result<Token, String> {
+        let start = self.pos;
+        if self.peek(0) == Some('0') && matches!(self.peek(1), Some('x' | 'X' | 'o' | 'O' | 'b' | 'B')) {
+            let radix = match self.peek(1) {
+                Some('x' | 'X') => 16,
+                Some('o' | 'O') => 8,
+                _ => 2,
+            };
+            self.pos += 2;
+            let mut value = 0f64;
+            while let Some(digit) = self.peek(0).and_then(|c| c.to_digit(radix)) {
+                value = value * radix as f64 + digit as f64;
+                self.pos += 1;
+            }
+            return Ok(Token::Number(value));
+        }
+        while self.peek(0).map_or(false, |c| c.is_ascii_digit() || c == '.' || c == '_') {
+            self.pos += 1;
+        }
+        if matches!(self.peek(0), Some('e' | 'E')) {
+            self.pos += 1;
+            if matches!(self.peek(0), Some('+' | '-')) {
+                self.pos += 1;
+            }
+            while self.peek(0).map_or(false, |c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+        }
+        let text: String = self.chars[start..self.pos].iter().filter(|&&c| c != '_').collect();
+        text.parse::<f64>().map(Token::Number).map_err(|_| self.error("malformed number"))
+    }
+
+    /// The escape after a `\`, or `None` for a line continuation
+    fn escape(&mut self) -> Result<Option<char>, String> {
+        let c = self.peek(0).ok_or_else(|| self.error("unterminated string"))?;
+        self.pos += 1;
+        Ok(Some(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'v' => '\u{b}',
+            '0' if !self.peek(0).map_or(false, |d| d.is_ascii_digit()) => '\0',
+            'x' => self.hex(2)?,
+            'u' if self.peek(0) == Some('{') => {
+                self.pos += 1;
+                let start = self.pos;
+                while self.peek(0).map_or(false, |c| c != '}') {
+                    self.pos += 1;
+                }
+                let digits: String = self.chars[start..self.pos].iter().collect();
+                self.pos += 1;
+                u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32).unwrap_or('\u{fffd}')
+            }
+            'u' => {
+                let unit = self.hex(4)? as u32;
+                // A surrogate pair written as two escapes
+                if (0xD800..0xDC00).contains(&unit) && self.peek(0) == Some('\\') && self.peek(1) == Some('u') {
+                    self.pos += 2;
+                    let low = self.hex(4)? as u32;
+                    char::from_u32(0x10000 + ((unit - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)).unwrap_or('\u{fffd}')
+                } else {
+                    char::from_u32(unit).unwrap_or('\u{fffd}')
+                }
+            }
+            '\r' => {
+                if self.peek(0) == Some('\n') {
+                    self.pos += 1;
+                }
+                self.line += 1;
+                return Ok(None);
+            }
+            '\n' => {
+                self.line += 1;
+                return Ok(None);
+            }
+            c => c,
+        }))
+    }
+
+    fn hex(&mut self, digits: usize) -> Result<char, String> {
+        let text: String = self.chars.get(self.pos..self.pos + digits).map(|s| s.iter().collect()).unwrap_or_default();
+        let value = u32::from_str_radix(&text, 16).map_err(|_| self.error("malformed escape"))?;
+        self.pos += digits;
+        Ok(char::from_u32(value).unwrap_or('\u{fffd}'))
+    }
+
+    fn string(&mut self, quote: char) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.peek(0) {
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(text);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    if let Some(c) = self.escape()? {
+                        text.push(c);
+                    }
+                }
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn template(&mut self) -> Result<Token, String> {
+        let mut quasis = Vec::new();
+        let mut substitutions = Vec::new();
+        let mut text = String::new();
+        loop {
+            match self.peek(0) {
+                Some('`') => {
+                    self.pos += 1;
+                    quasis.push(text);
+                    return Ok(Token::Template(quasis, substitutions));
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    if let Some(c) = self.escape()? {
+                        text.push(c);
+                    }
+                }
+                Some('$') if self.peek(1) == Some('{') => {
+                    self.pos += 2;
+                    quasis.push(core::mem::take(&mut text));
+                    let mut tokens = self.tokens(true)?;
+                    tokens.push(Tok { token: Token::Eof, newline: false, line: self.line });
+                    substitutions.push(tokens);
+                }
+                Some(c) => {
+                    if c == '\n' {
+                        self.line += 1;
+                    }
+                    text.push(c);
+                    self.pos += 1;
+                }
+                None => return Err(self.error("unterminated template")),
+            }
+        }
+    }
+
+    fn regex(&mut self) -> Result<Token, String> {
+        let mut pattern = String::new();
+        let mut in_class = false;
+        loop {
+            match self.peek(0) {
+                Some('/') if !in_class => break,
+                Some('\\') => {
+                    pattern.push('\\');
+                    self.pos += 1;
+                    match self.peek(0) {
+                        Some(c) if c != '\n' => pattern.push(c),
+                        _ => return Err(self.error("unterminated regular expression")),
+                    }
+                }
+                Some('\n') | None => return Err(self.error("unterminated regular expression")),
+                Some(c) => {
+                    match c {
+                        '[' => in_class = true,
+                        ']' => in_class = false,
+                        _ => {}
+                    }
+                    pattern.push(c);
+                }
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        let start = self.pos;
+        while self.peek(0).map_or(false, is_ident_part) {
+            self.pos += 1;
+        }
+        Ok(Token::Regex(pattern, self.chars[start..self.pos].iter().collect()))
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '$'
+}
+
+fn is_ident_part(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Whether a `/` after `previous` begins an operand, and so a regular
+/// expression
+fn operand_expected(previous: Option<&Tok>) -> bool {
+    match previous.map(|t| &t.token) {
+        None => true,
+        Some(Token::Punct(p)) => !matches!(*p, ")" | "]" | "}"),
+        Some(Token::Ident(word)) => OPERAND_KEYWORDS.contains(&word.as_str()),
+        Some(_) => false,
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn tokens(source: &str) -> Result<Vec<Token>, String> {
+        Ok(tokenize(source)?.into_iter().map(|t| t.token).collect())
+    }
+
+    #[kernel_test]
+    fn longest_punctuator_wins() -> Result<(), String> {
+        check_eq!(tokens("a===b>>>=c?.d")?, alloc::vec![
+            Token::Ident(String::from("a")), Token::Punct("==="), Token::Ident(String::from("b")),
+            Token::Punct(">>>="), Token::Ident(String::from("c")), Token::Punct("?."),
+            Token::Ident(String::from("d")), Token::Eof,
+        ]);
+        // A conditional followed by a number, not optional chaining
+        check_eq!(tokens("a?.5:1")?[1], Token::Punct("?"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn slashes_divide_after_operands() -> Result<(), String> {
+        check_eq!(tokens("x / 2 / y")?[1], Token::Punct("/"));
+        check_eq!(tokens("f(/a+b/g)")?[2], Token::Regex(String::from("a+b"), String::from("g")));
+        check_eq!(tokens("return /x/")?[1], Token::Regex(String::from("x"), String::new()));
+        check_eq!(tokens("(a) / b")?[3], Token::Punct("/"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn strings_numbers_and_templates() -> Result<(), String> {
+        check_eq!(tokens(r#"'it\'s' "a\nb" 0x1F 1.5e3 .25"#)?, alloc::vec![
+            Token::String(String::from("it's")), Token::String(String::from("a\nb")),
+            Token::Number(31.0), Token::Number(1500.0), Token::Number(0.25), Token::Eof,
+        ]);
+        match &tokens("`a${b + 1}c`")?[0] {
+            Token::Template(parts, substitutions) => {
+                check_eq!(parts, &alloc::vec![String::from("a"), String::from("c")]);
+                check_eq!(substitutions.len(), 1);
+                // b, +, 1 and the Eof that ends each substitution
+                check_eq!(substitutions[0].len(), 4);
+            }
+            other => return Err(alloc::format!("not a template: {:?}", other)),
+        }
+        check!(tokenize("'open").is_err());
+        check!(tokenize("`open ${").is_err());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn line_breaks_are_noted() -> Result<(), String> {
+        let toks = tokenize("a // note\nb /* one\ntwo */ c")?;
+        check!(!toks[0].newline || toks[0].line == 1);
+        check!(toks[1].newline);
+        check_eq!(toks[1].line, 2);
+        check!(toks[2].newline);
+        check_eq!(toks[2].line, 3);
+        Ok(())
+    }
+}
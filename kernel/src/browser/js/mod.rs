@@ -0,0 +1,40 @@
+//! JavaScript Engine
+//!
+//! An interpreter for the JavaScript pages and the desktop's HTML apps
+//! use: most of ES5, with the `let`, `const`, arrow functions, template
+//! literals, spread and destructuring of later versions, the core built-in
+//! objects, promises and the parts of the DOM those pages reach for.
+//! Getters, classes, generators and `async` functions are not supported. Each page gets
+//! its own interpreter, which lives as long as the page does.
+
+pub mod builtins;
+pub mod dom;
+pub mod interp;
+pub mod lexer;
+pub mod net;
+pub mod parser;
+pub mod promise;
+pub mod regex;
+pub mod value;
+
+pub use interp::Interpreter;
+
+use alloc::string::String;
+
+use crate::browser::BrowserError;
+use crate::{info, warn};
+
+/// Run a script on its own, with no page
+pub fn execute(code: &[u8]) -> Result<(), BrowserError> {
+    let source = String::from_utf8_lossy(code);
+    let mut interp = Interpreter::new();
+    interp.run(&source).map_err(|e| {
+        warn!("js", "Uncaught {}", e);
+        BrowserError::JsError
+    })
+}
+
+/// Initialize JavaScript engine
+pub fn init() {
+    info!("js", "JavaScript engine initialized");
+}
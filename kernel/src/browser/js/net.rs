@@ -0,0 +1,558 @@
+//! Network requests
+//!
+//! `fetch` and `XMLHttpRequest`. A request a script makes is queued with
+//! the page and the browser sends it on a later tick, so the script
+//! always hears back asynchronously: through the promise `fetch`
+//! returned, or the `on...` handlers of the `XMLHttpRequest`. The
+//! browser decides whether the page may see the response at all; when it
+//! may not, the script learns only that the request failed.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::builtins;
+use super::interp::Interpreter;
+use super::promise;
+use super::value::{Heap, Kind, Native, ObjRef, Value};
+use crate::warn;
+
+/// Most requests a page may have waiting at once
+const MAX_REQUESTS: usize = 32;
+
+/// Whether a request goes with the user's cookies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credentials {
+    Omit,
+    /// Only to the page's own origin
+    SameOrigin,
+    /// Cross-origin too, if the server allows it
+    Include,
+}
+
+/// A request as a script made it; the URL may be relative to the page
+pub struct Request {
+    pub id: u32,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub credentials: Credentials,
+}
+
+/// What the browser lets a script see of a response
+pub struct Response {
+    /// Where the response came from, after any redirects
+    pub url: String,
+    pub status: u16,
+    pub status_text: String,
+    /// Names in lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// What hears back about a request
+#[derive(Clone, Copy)]
+enum Waiter {
+    /// The promise `fetch` returned
+    Fetch(ObjRef),
+    XmlHttpRequest(ObjRef),
+}
+
+impl Waiter {
+    fn object(&self) -> ObjRef {
+        match self {
+            Waiter::Fetch(obj) | Waiter::XmlHttpRequest(obj) => *obj,
+        }
+    }
+}
+
+/// What a `Response`, `Headers` or finished `XMLHttpRequest` holds
+/// besides its properties
+struct Held {
+    headers: Vec<(String, String)>,
+    /// A response's body, until it is read
+    body: Option<Vec<u8>>,
+}
+
+/// The requests of a page
+pub struct Requests {
+    /// Made by scripts and not sent yet, oldest first
+    queue: VecDeque<Request>,
+    /// Requests not answered yet, and who is waiting for each
+    waiting: Vec<(u32, Waiter)>,
+    /// `XMLHttpRequest`s opened and not sent yet
+    opened: Vec<(ObjRef, Request)>,
+    held: Vec<(ObjRef, Held)>,
+    next_id: u32,
+}
+
+impl Requests {
+    pub fn new() -> Self {
+        Self { queue: VecDeque::new(), waiting: Vec::new(), opened: Vec::new(), held: Vec::new(), next_id: 1 }
+    }
+
+    /// The next request to send, if any
+    pub fn next(&mut self) -> Option<Request> {
+        self.queue.pop_front()
+    }
+
+    /// Whatever is waiting for an answer lives until it comes; an opened
+    /// `XMLHttpRequest` only as long as a script holds it
+    pub fn roots(&self, roots: &mut Vec<ObjRef>) {
+        roots.extend(self.waiting.iter().map(|(_, waiter)| waiter.object()));
+    }
+
+    pub fn prune(&mut self, heap: &Heap) {
+        self.opened.retain(|(obj, _)| heap.alive(*obj));
+        self.held.retain(|(obj, _)| heap.alive(*obj));
+    }
+
+    /// Queue `request` for `waiter`; false if too many are waiting
+    fn send(&mut self, mut request: Request, waiter: Waiter) -> bool {
+        if self.waiting.len() >= MAX_REQUESTS {
+            return false;
+        }
+        request.id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.waiting.push((request.id, waiter));
+        self.queue.push_back(request);
+        true
+    }
+
+    /// Forget what `xhr` has opened or sent; whether it had been sent
+    fn cancel(&mut self, xhr: ObjRef) -> bool {
+        self.opened.retain(|(obj, _)| *obj != xhr);
+        let sent: Vec<u32> = self.waiting.iter()
+            .filter(|(_, waiter)| matches!(waiter, Waiter::XmlHttpRequest(obj) if *obj == xhr))
+            .map(|(id, _)| *id)
+            .collect();
+        self.waiting.retain(|(id, _)| !sent.contains(id));
+        self.queue.retain(|request| !sent.contains(&request.id));
+        !sent.is_empty()
+    }
+
+    fn held(&mut self, obj: ObjRef) -> Option<&mut Held> {
+        self.held.iter_mut().find(|(o, _)| *o == obj).map(|(_, held)| held)
+    }
+
+    fn hold(&mut self, obj: ObjRef, held: Held) {
+        self.held.retain(|(o, _)| *o != obj);
+        self.held.push((obj, held));
+    }
+}
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or(Value::Undefined)
+}
+
+/// Set up `fetch`, `XMLHttpRequest`, `Response` and `Headers`
+pub fn install(interp: &mut Interpreter) {
+    let global = interp.global;
+    interp.define(global, "fetch", fetch);
+
+    let classes: [(&str, Native, &[(&str, Native)]); 3] = [
+        ("XMLHttpRequest", xhr_new, &[
+            ("open", xhr_open), ("setRequestHeader", xhr_set_request_header), ("send", xhr_send),
+            ("abort", xhr_abort), ("getResponseHeader", xhr_get_response_header),
+            ("getAllResponseHeaders", xhr_get_all_response_headers),
+        ]),
+        ("Response", not_constructible, &[("text", response_text), ("json", response_json)]),
+        ("Headers", not_constructible, &[("get", headers_get), ("has", headers_has), ("forEach", headers_for_each)]),
+    ];
+    for (name, constructor, methods) in classes {
+        let (ctor, proto) = match (interp.native(constructor), interp.new_object()) {
+            (Ok(Value::Object(ctor)), Ok(Value::Object(proto))) => (ctor, proto),
+            _ => return,
+        };
+        interp.heap.get_mut(ctor).put("prototype", Value::Object(proto));
+        interp.heap.get_mut(proto).put("constructor", Value::Object(ctor));
+        for (method, f) in methods {
+            interp.define(proto, method, *f);
+        }
+        interp.heap.get_mut(global).put(name, Value::Object(ctor));
+    }
+    if let Some(Value::Object(ctor)) = interp.heap.get(global).own("XMLHttpRequest").cloned() {
+        for (i, state) in ["UNSENT", "OPENED", "HEADERS_RECEIVED", "LOADING", "DONE"].iter().enumerate() {
+            interp.heap.get_mut(ctor).put(state, Value::Number(i as f64));
+        }
+    }
+}
+
+/// The prototype of the global constructor `name`
+fn prototype(interp: &Interpreter, name: &str) -> ObjRef {
+    let constructor = interp.heap.get(interp.global).own(name).and_then(Value::object);
+    constructor
+        .and_then(|c| interp.heap.get(c).own("prototype").and_then(Value::object))
+        .unwrap_or(interp.protos.object)
+}
+
+/// Hand the outcome of request `id` to whatever is waiting for it
+///
+/// A request that failed, or whose response the page may not see, is
+/// `Err`, which scripts see as a network error.
+pub fn complete(interp: &mut Interpreter, id: u32, outcome: Result<Response, ()>) {
+    let position = interp.dom.requests.waiting.iter().position(|(i, _)| *i == id);
+    let waiter = match position {
+        Some(i) => interp.dom.requests.waiting.remove(i).1,
+        None => return,
+    };
+    let result = interp.enter(|interp| match waiter {
+        Waiter::Fetch(promise) => {
+            match outcome {
+                Ok(response) => {
+                    let response = response_object(interp, response)?;
+                    promise::resolve(interp, promise, response);
+                }
+                Err(()) => {
+                    let error = interp.type_error("Failed to fetch");
+                    promise::reject(interp, promise, error);
+                }
+            }
+            Ok(())
+        }
+        Waiter::XmlHttpRequest(xhr) => finish(interp, xhr, outcome),
+    });
+    if let Err(e) = result {
+        warn!("js", "Uncaught {}", e);
+    }
+}
+
+/// Request headers from `init.headers`: an object, a `Headers` or a list
+/// of pairs
+fn header_list(interp: &mut Interpreter, headers: &Value) -> Result<Vec<(String, String)>, Value> {
+    let obj = match headers {
+        Value::Object(obj) => *obj,
+        _ => return Ok(Vec::new()),
+    };
+    if let Some(held) = interp.dom.requests.held(obj) {
+        return Ok(held.headers.clone());
+    }
+    let mut list = Vec::new();
+    if let Some(pairs) = interp.array_items(headers) {
+        for pair in pairs {
+            let name = interp.get(&pair, "0")?;
+            let value = interp.get(&pair, "1")?;
+            list.push((interp.to_string(&name)?, interp.to_string(&value)?));
+        }
+    } else {
+        for name in interp.keys(headers) {
+            let value = interp.get(headers, &name)?;
+            list.push((name, interp.to_string(&value)?));
+        }
+    }
+    Ok(list)
+}
+
+fn credentials(interp: &mut Interpreter, value: &Value) -> Result<Credentials, Value> {
+    match interp.to_string(value)?.as_str() {
+        "omit" => Ok(Credentials::Omit),
+        "same-origin" => Ok(Credentials::SameOrigin),
+        "include" => Ok(Credentials::Include),
+        other => {
+            let message = format!("'{}' is not a valid value for credentials", other);
+            Err(interp.type_error(&message))
+        }
+    }
+}
+
+/// A request body as bytes
+fn body(interp: &mut Interpreter, method: &str, value: &Value) -> Result<Vec<u8>, Value> {
+    if value.is_nullish() {
+        return Ok(Vec::new());
+    }
+    if method == "GET" || method == "HEAD" {
+        return Err(interp.type_error("Request with GET/HEAD method cannot have body"));
+    }
+    Ok(interp.to_string(value)?.into_bytes())
+}
+
+/// The request `fetch(input, init)` asks for
+fn fetch_request(interp: &mut Interpreter, args: &[Value]) -> Result<Request, Value> {
+    let mut request = Request {
+        id: 0,
+        method: String::from("GET"),
+        url: interp.to_string(&arg(args, 0))?,
+        headers: Vec::new(),
+        body: Vec::new(),
+        credentials: Credentials::SameOrigin,
+    };
+    let init = arg(args, 1);
+    if let Value::Object(_) = init {
+        let method = interp.get(&init, "method")?;
+        if !method.is_nullish() {
+            request.method = interp.to_string(&method)?.to_ascii_uppercase();
+        }
+        let headers = interp.get(&init, "headers")?;
+        request.headers = header_list(interp, &headers)?;
+        let body_value = interp.get(&init, "body")?;
+        request.body = body(interp, &request.method, &body_value)?;
+        let mode = interp.get(&init, "credentials")?;
+        if !mode.is_nullish() {
+            request.credentials = credentials(interp, &mode)?;
+        }
+    }
+    Ok(request)
+}
+
+/// `fetch(url, init)`: a promise of the `Response`
+fn fetch(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let promise = promise::new_promise(interp)?;
+    match fetch_request(interp, args) {
+        Ok(request) => {
+            if !interp.dom.requests.send(request, Waiter::Fetch(promise)) {
+                let error = interp.type_error("Failed to fetch: too many requests");
+                promise::reject(interp, promise, error);
+            }
+        }
+        Err(thrown) => promise::reject(interp, promise, thrown),
+    }
+    Ok(Value::Object(promise))
+}
+
+fn not_constructible(interp: &mut Interpreter, _: Value, _: &[Value]) -> Result<Value, Value> {
+    Err(interp.type_error("Illegal constructor"))
+}
+
+fn headers_object(interp: &mut Interpreter, headers: Vec<(String, String)>) -> Result<Value, Value> {
+    let proto = prototype(interp, "Headers");
+    let obj = interp.alloc(Kind::Plain, Some(proto))?;
+    interp.dom.requests.hold(obj, Held { headers, body: None });
+    Ok(Value::Object(obj))
+}
+
+fn response_object(interp: &mut Interpreter, response: Response) -> Result<Value, Value> {
+    let proto = prototype(interp, "Response");
+    let obj = interp.alloc(Kind::Plain, Some(proto))?;
+    let headers = headers_object(interp, response.headers.clone())?;
+    let value = Value::Object(obj);
+    interp.put(&value, "ok", Value::Bool((200..300).contains(&response.status)))?;
+    interp.put(&value, "status", Value::Number(response.status as f64))?;
+    interp.put(&value, "statusText", Value::String(response.status_text))?;
+    interp.put(&value, "url", Value::String(response.url))?;
+    interp.put(&value, "headers", headers)?;
+    interp.dom.requests.hold(obj, Held { headers: response.headers, body: Some(response.body) });
+    Ok(value)
+}
+
+/// Take the body of the response `this`, which can be read only once
+fn take_body(interp: &mut Interpreter, this: &Value) -> Result<String, Value> {
+    let body = this.object().and_then(|obj| interp.dom.requests.held(obj)).and_then(|held| held.body.take());
+    match body {
+        Some(body) => Ok(String::from_utf8_lossy(&body).into_owned()),
+        None => Err(interp.type_error("Body has already been read")),
+    }
+}
+
+/// A promise settled with `outcome`
+fn settled(interp: &mut Interpreter, outcome: Result<Value, Value>) -> Result<Value, Value> {
+    let promise = promise::new_promise(interp)?;
+    match outcome {
+        Ok(value) => promise::resolve(interp, promise, value),
+        Err(thrown) => promise::reject(interp, promise, thrown),
+    }
+    Ok(Value::Object(promise))
+}
+
+fn response_text(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let text = take_body(interp, &this).map(Value::String);
+    settled(interp, text)
+}
+
+fn response_json(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let value = take_body(interp, &this).and_then(|text| builtins::from_json(interp, &text));
+    settled(interp, value)
+}
+
+/// The values of header `name` in the headers of `this`, joined as HTTP
+/// joins repeated headers
+fn header_value(interp: &mut Interpreter, this: &Value, name: &str) -> Option<String> {
+    let held = this.object().and_then(|obj| interp.dom.requests.held(obj))?;
+    let values: Vec<&str> = held.headers.iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+fn headers_get(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let name = interp.to_string(&arg(args, 0))?;
+    Ok(header_value(interp, &this, &name).map_or(Value::Null, Value::String))
+}
+
+fn headers_has(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let name = interp.to_string(&arg(args, 0))?;
+    Ok(Value::Bool(header_value(interp, &this, &name).is_some()))
+}
+
+fn headers_for_each(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let headers = this.object().and_then(|obj| interp.dom.requests.held(obj)).map(|held| held.headers.clone());
+    let callback = arg(args, 0);
+    for (name, value) in headers.unwrap_or_default() {
+        interp.call(&callback, Value::Undefined, &[Value::String(value), Value::String(name), this.clone()])?;
+    }
+    Ok(Value::Undefined)
+}
+
+// XMLHttpRequest
+
+fn this_xhr(interp: &mut Interpreter, this: &Value) -> Result<ObjRef, Value> {
+    match this.object() {
+        Some(obj) => Ok(obj),
+        None => Err(interp.type_error("Illegal invocation")),
+    }
+}
+
+fn invalid_state(interp: &mut Interpreter, message: &str) -> Value {
+    let message = format!("InvalidStateError: {}", message);
+    interp.error("Error", &message)
+}
+
+fn xhr_new(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let initial = [
+        ("readyState", Value::Number(0.0)), ("status", Value::Number(0.0)), ("statusText", Value::str("")),
+        ("responseText", Value::str("")), ("response", Value::str("")), ("responseURL", Value::str("")),
+        ("responseType", Value::str("")), ("withCredentials", Value::Bool(false)),
+    ];
+    for (name, value) in initial {
+        interp.put(&this, name, value)?;
+    }
+    Ok(Value::Undefined)
+}
+
+/// Call the `on<event>` handler of `xhr`, reporting what it throws
+fn fire(interp: &mut Interpreter, xhr: ObjRef, event: &str) -> Result<(), Value> {
+    let target = Value::Object(xhr);
+    let handler = interp.get(&target, &format!("on{}", event))?;
+    if !interp.is_callable(&handler) {
+        return Ok(());
+    }
+    let object = interp.new_object()?;
+    interp.put(&object, "type", Value::str(event))?;
+    interp.put(&object, "target", target.clone())?;
+    if let Err(thrown) = interp.call(&handler, target, &[object]) {
+        let message = interp.describe(&thrown);
+        warn!("js", "Uncaught {}", message);
+    }
+    Ok(())
+}
+
+fn set_ready_state(interp: &mut Interpreter, xhr: ObjRef, state: u32) -> Result<(), Value> {
+    interp.put(&Value::Object(xhr), "readyState", Value::Number(state as f64))?;
+    fire(interp, xhr, "readystatechange")
+}
+
+fn xhr_open(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let xhr = this_xhr(interp, &this)?;
+    if args.len() > 2 && !Interpreter::truthy(&args[2]) {
+        return Err(interp.error("Error", "Synchronous XMLHttpRequest is not supported"));
+    }
+    let method = interp.to_string(&arg(args, 0))?.to_ascii_uppercase();
+    let url = interp.to_string(&arg(args, 1))?;
+    interp.dom.requests.cancel(xhr);
+    interp.dom.requests.held.retain(|(obj, _)| *obj != xhr);
+    let request = Request { id: 0, method, url, headers: Vec::new(), body: Vec::new(), credentials: Credentials::SameOrigin };
+    interp.dom.requests.opened.push((xhr, request));
+    for (name, value) in [("status", Value::Number(0.0)), ("statusText", Value::str("")), ("responseText", Value::str("")), ("response", Value::str(""))] {
+        interp.put(&this, name, value)?;
+    }
+    set_ready_state(interp, xhr, 1)?;
+    Ok(Value::Undefined)
+}
+
+fn xhr_set_request_header(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let xhr = this_xhr(interp, &this)?;
+    let name = interp.to_string(&arg(args, 0))?;
+    let value = interp.to_string(&arg(args, 1))?;
+    let request = interp.dom.requests.opened.iter_mut().find(|(obj, _)| *obj == xhr).map(|(_, request)| request);
+    let request = match request {
+        Some(request) => request,
+        None => return Err(invalid_state(interp, "The object's state must be OPENED")),
+    };
+    match request.headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(&name)) {
+        Some((_, existing)) => {
+            existing.push_str(", ");
+            existing.push_str(&value);
+        }
+        None => request.headers.push((name, value)),
+    }
+    Ok(Value::Undefined)
+}
+
+fn xhr_send(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let xhr = this_xhr(interp, &this)?;
+    let position = interp.dom.requests.opened.iter().position(|(obj, _)| *obj == xhr);
+    let mut request = match position {
+        Some(i) => interp.dom.requests.opened.remove(i).1,
+        None => return Err(invalid_state(interp, "The object's state must be OPENED")),
+    };
+    let value = arg(args, 0);
+    // As in browsers, a body given to a GET or HEAD is ignored
+    if request.method != "GET" && request.method != "HEAD" {
+        request.body = body(interp, &request.method, &value)?;
+    }
+    let with_credentials = interp.get(&this, "withCredentials")?;
+    if Interpreter::truthy(&with_credentials) {
+        request.credentials = Credentials::Include;
+    }
+    if !interp.dom.requests.send(request, Waiter::XmlHttpRequest(xhr)) {
+        return Err(interp.error("Error", "NetworkError: too many requests"));
+    }
+    Ok(Value::Undefined)
+}
+
+fn xhr_abort(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let xhr = this_xhr(interp, &this)?;
+    if interp.dom.requests.cancel(xhr) {
+        interp.put(&this, "status", Value::Number(0.0))?;
+        set_ready_state(interp, xhr, 4)?;
+        fire(interp, xhr, "abort")?;
+        fire(interp, xhr, "loadend")?;
+    }
+    interp.put(&this, "readyState", Value::Number(0.0))?;
+    Ok(Value::Undefined)
+}
+
+/// Give `xhr` the outcome of its request and fire its handlers
+fn finish(interp: &mut Interpreter, xhr: ObjRef, outcome: Result<Response, ()>) -> Result<(), Value> {
+    let this = Value::Object(xhr);
+    let response = match outcome {
+        Ok(response) => response,
+        Err(()) => {
+            interp.put(&this, "status", Value::Number(0.0))?;
+            set_ready_state(interp, xhr, 4)?;
+            fire(interp, xhr, "error")?;
+            return fire(interp, xhr, "loadend");
+        }
+    };
+    let text = String::from_utf8_lossy(&response.body).into_owned();
+    let response_type = interp.get(&this, "responseType")?;
+    let value = match interp.to_string(&response_type)?.as_str() {
+        "json" => builtins::from_json(interp, &text).unwrap_or(Value::Null),
+        _ => Value::String(text.clone()),
+    };
+    interp.put(&this, "status", Value::Number(response.status as f64))?;
+    interp.put(&this, "statusText", Value::String(response.status_text))?;
+    interp.put(&this, "responseURL", Value::String(response.url))?;
+    interp.put(&this, "responseText", Value::String(text))?;
+    interp.put(&this, "response", value)?;
+    interp.dom.requests.hold(xhr, Held { headers: response.headers, body: None });
+    set_ready_state(interp, xhr, 4)?;
+    fire(interp, xhr, "load")?;
+    fire(interp, xhr, "loadend")
+}
+
+fn xhr_get_response_header(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    headers_get(interp, this, args)
+}
+
+fn xhr_get_all_response_headers(interp: &mut Interpreter, this: Value, _: &[Value]) -> Result<Value, Value> {
+    let headers = this.object().and_then(|obj| interp.dom.requests.held(obj)).map(|held| held.headers.clone());
+    let text: String = headers.unwrap_or_default().iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    Ok(Value::String(text))
+}
@@ -0,0 +1,1163 @@
+//! JavaScript parser
+//!
+//! Builds a syntax tree by recursive descent, with binary operators parsed
+//! by precedence. Semicolons a script leaves out are inserted before a
+//! line break, a `}` and the end of input. Function bodies are shared
+//! through `Arc`, so a closure can hold on to its code after the script
+//! that defined it is gone.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::lexer::{self, Tok, Token};
+
+/// Deepest statements and expressions may nest, so parsing and running a
+/// script cannot overrun the kernel stack
+const MAX_NESTING: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeclKind {
+    Var,
+    Let,
+    Const,
+}
+
+#[derive(Debug)]
+pub enum Stmt {
+    Expr(Expr),
+    Decl(DeclKind, Vec<(Pattern, Option<Expr>)>),
+    Function(Arc<FunctionDef>),
+    Return(Option<Expr>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Block(Vec<Stmt>),
+    While(Expr, Box<Stmt>),
+    DoWhile(Box<Stmt>, Expr),
+    /// Initializer, condition, update and body
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    /// `for (x of list)`, or `for (x in object)` when the flag is set
+    ForEach(Option<DeclKind>, Pattern, Expr, Box<Stmt>, bool),
+    Break(Option<String>),
+    Continue(Option<String>),
+    Throw(Expr),
+    /// Body, catch clause and `finally` block
+    Try(Vec<Stmt>, Option<(Option<Pattern>, Vec<Stmt>)>, Option<Vec<Stmt>>),
+    /// Discriminant and cases; the `default` case has no test
+    Switch(Expr, Vec<(Option<Expr>, Vec<Stmt>)>),
+    Labeled(String, Box<Stmt>),
+    Empty,
+}
+
+/// Where a declaration or assignment puts its value
+#[derive(Debug)]
+pub enum Pattern {
+    Ident(String),
+    /// An assignment to a property, in destructuring assignments only
+    Member(Box<Expr>),
+    /// Elements, `None` for holes, and the rest element
+    Array(Vec<Option<Pattern>>, Option<Box<Pattern>>),
+    /// Properties and the rest element
+    Object(Vec<(PropKey, Pattern)>, Option<Box<Pattern>>),
+    /// A target with a default for when the value is undefined
+    Default(Box<Pattern>, Box<Expr>),
+}
+
+#[derive(Debug)]
+pub enum PropKey {
+    Name(String),
+    Computed(Expr),
+}
+
+#[derive(Debug)]
+pub enum Prop {
+    Init(PropKey, Expr),
+    Spread(Expr),
+}
+
+#[derive(Debug)]
+pub struct FunctionDef {
+    pub name: Option<String>,
+    pub params: Vec<Pattern>,
+    pub rest: Option<Pattern>,
+    pub body: Vec<Stmt>,
+    /// Arrow functions take `this` and `arguments` from where they are
+    /// defined
+    pub arrow: bool,
+    /// The function refers to `arguments`, so calls must make it
+    pub arguments: bool,
+}
+
+#[derive(Debug)]
+pub enum Expr {
+    Number(f64),
+    String(String),
+    Template(Vec<String>, Vec<Expr>),
+    Regex(String, String),
+    Bool(bool),
+    Null,
+    Undefined,
+    Ident(String),
+    This,
+    /// Elements; holes are `Undefined` and spreads `Spread`
+    Array(Vec<Expr>),
+    Object(Vec<Prop>),
+    Function(Arc<FunctionDef>),
+    Unary(&'static str, Box<Expr>),
+    /// Operator, whether it is a prefix, and target
+    Update(&'static str, bool, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    /// `&&`, `||` and `??`, which may not evaluate their right side
+    Logical(&'static str, Box<Expr>, Box<Expr>),
+    /// Operator (`=` or a compound one), target and value
+    Assign(&'static str, Box<Pattern>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Callee, arguments and whether it is an optional call `f?.()`
+    Call(Box<Expr>, Vec<Expr>, bool),
+    New(Box<Expr>, Vec<Expr>),
+    /// Object, property and whether the access is optional `a?.b`
+    Member(Box<Expr>, Box<Expr>, bool),
+    Sequence(Vec<Expr>),
+    Spread(Box<Expr>),
+}
+
+/// Parse a script
+pub fn parse(source: &str) -> Result<Vec<Stmt>, String> {
+    let tokens = lexer::tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0, depth: 0, no_in: false };
+    let mut body = Vec::new();
+    while !parser.at_eof() {
+        body.push(parser.statement()?);
+    }
+    Ok(body)
+}
+
+/// Parse the body of an inline event handler such as `onclick`, as a
+/// function taking `event`
+pub fn parse_handler(source: &str) -> Result<Arc<FunctionDef>, String> {
+    let body = parse(source)?;
+    Ok(Arc::new(FunctionDef {
+        name: None,
+        params: alloc::vec![Pattern::Ident(String::from("event"))],
+        rest: None,
+        body,
+        arrow: false,
+        arguments: false,
+    }))
+}
+
+struct Parser {
+    tokens: Vec<Tok>,
+    pos: usize,
+    depth: usize,
+    /// Inside a `for` head, where `in` is not an operator
+    no_in: bool,
+}
+
+impl Parser {
+    fn current(&self) -> &Tok {
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn peek(&self) -> &Token {
+        &self.current().token
+    }
+
+    fn peek_at(&self, ahead: usize) -> &Token {
+        let i = (self.pos + ahead).min(self.tokens.len() - 1);
+        &self.tokens[i].token
+    }
+
+    fn at_eof(&self) -> bool {
+        matches!(self.peek(), Token::Eof)
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.peek().clone();
+        self.pos += 1;
+        token
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        let found = match self.peek() {
+            Token::Eof => String::from("end of input"),
+            Token::Ident(name) => format!("'{}'", name),
+            Token::Punct(p) => format!("'{}'", p),
+            Token::String(_) | Token::Template(..) => String::from("string"),
+            Token::Number(n) => format!("{}", n),
+            Token::Regex(..) => String::from("regular expression"),
+        };
+        Err(format!("line {}: {}, found {}", self.current().line, message, found))
+    }
+
+    fn is(&self, punct: &str) -> bool {
+        matches!(self.peek(), Token::Punct(p) if *p == punct)
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Token::Ident(w) if w == word)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if self.is(punct) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.is_word(word) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", punct))
+        }
+    }
+
+    /// Whether a line break comes before the next token
+    fn newline(&self) -> bool {
+        self.current().newline
+    }
+
+    /// End a statement: a `;`, or where one may be left out
+    fn semicolon(&mut self) -> Result<(), String> {
+        if self.eat(";") || self.is("}") || self.at_eof() || self.newline() {
+            Ok(())
+        } else {
+            self.error("expected ';'")
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Token::Ident(name) if !is_reserved(name) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => self.error("expected a name"),
+        }
+    }
+
+    /// A property name, where keywords are allowed
+    fn property_name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Token::Ident(name) | Token::String(name) => Ok(name),
+            Token::Number(n) => Ok(super::value::number_to_string(n)),
+            _ => {
+                self.pos -= 1;
+                self.error("expected a property name")
+            }
+        }
+    }
+
+    fn nest(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return self.error("nested too deeply");
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        self.nest()?;
+        let stmt = self.statement_inner();
+        self.depth -= 1;
+        stmt
+    }
+
+    fn statement_inner(&mut self) -> Result<Stmt, String> {
+        if let Token::Punct(p) = self.peek() {
+            match *p {
+                "{" => {
+                    self.next();
+                    return Ok(Stmt::Block(self.block_rest()?));
+                }
+                ";" => {
+                    self.next();
+                    return Ok(Stmt::Empty);
+                }
+                _ => {}
+            }
+        }
+        let word = match self.peek() {
+            Token::Ident(word) => word.clone(),
+            _ => return self.expression_statement(),
+        };
+        match word.as_str() {
+            "var" | "let" | "const" => {
+                let decl = self.declaration()?;
+                self.semicolon()?;
+                Ok(decl)
+            }
+            "function" => {
+                self.next();
+                Ok(Stmt::Function(self.function_rest(true)?))
+            }
+            "return" => {
+                self.next();
+                let value = if self.is(";") || self.is("}") || self.at_eof() || self.newline() {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.semicolon()?;
+                Ok(Stmt::Return(value))
+            }
+            "if" => {
+                self.next();
+                self.expect("(")?;
+                let test = self.expression()?;
+                self.expect(")")?;
+                let then = Box::new(self.statement()?);
+                let otherwise = if self.eat_word("else") { Some(Box::new(self.statement()?)) } else { None };
+                Ok(Stmt::If(test, then, otherwise))
+            }
+            "while" => {
+                self.next();
+                self.expect("(")?;
+                let test = self.expression()?;
+                self.expect(")")?;
+                Ok(Stmt::While(test, Box::new(self.statement()?)))
+            }
+            "do" => {
+                self.next();
+                let body = Box::new(self.statement()?);
+                if !self.eat_word("while") {
+                    return self.error("expected 'while'");
+                }
+                self.expect("(")?;
+                let test = self.expression()?;
+                self.expect(")")?;
+                self.eat(";");
+                Ok(Stmt::DoWhile(body, test))
+            }
+            "for" => {
+                self.next();
+                self.for_rest()
+            }
+            "break" | "continue" => {
+                self.next();
+                let label = match self.peek() {
+                    Token::Ident(name) if !self.newline() && !is_reserved(name) => Some(self.ident()?),
+                    _ => None,
+                };
+                self.semicolon()?;
+                Ok(if word == "break" { Stmt::Break(label) } else { Stmt::Continue(label) })
+            }
+            "throw" => {
+                self.next();
+                let value = self.expression()?;
+                self.semicolon()?;
+                Ok(Stmt::Throw(value))
+            }
+            "try" => {
+                self.next();
+                self.expect("{")?;
+                let body = self.block_rest()?;
+                let catch = if self.eat_word("catch") {
+                    let param = if self.eat("(") {
+                        let param = self.binding()?;
+                        self.expect(")")?;
+                        Some(param)
+                    } else {
+                        None
+                    };
+                    self.expect("{")?;
+                    Some((param, self.block_rest()?))
+                } else {
+                    None
+                };
+                let finally = if self.eat_word("finally") {
+                    self.expect("{")?;
+                    Some(self.block_rest()?)
+                } else {
+                    None
+                };
+                if catch.is_none() && finally.is_none() {
+                    return self.error("expected 'catch' or 'finally'");
+                }
+                Ok(Stmt::Try(body, catch, finally))
+            }
+            "switch" => {
+                self.next();
+                self.expect("(")?;
+                let discriminant = self.expression()?;
+                self.expect(")")?;
+                self.expect("{")?;
+                let mut cases = Vec::new();
+                while !self.eat("}") {
+                    let test = if self.eat_word("default") {
+                        None
+                    } else if self.eat_word("case") {
+                        Some(self.expression()?)
+                    } else {
+                        return self.error("expected 'case'");
+                    };
+                    self.expect(":")?;
+                    let mut body = Vec::new();
+                    while !self.is("}") && !self.is_word("case") && !self.is_word("default") {
+                        if self.at_eof() {
+                            return self.error("expected '}'");
+                        }
+                        body.push(self.statement()?);
+                    }
+                    cases.push((test, body));
+                }
+                Ok(Stmt::Switch(discriminant, cases))
+            }
+            _ if matches!(self.peek_at(1), Token::Punct(":")) && !is_reserved(&word) => {
+                self.next();
+                self.next();
+                Ok(Stmt::Labeled(word, Box::new(self.statement()?)))
+            }
+            _ => self.expression_statement(),
+        }
+    }
+
+    fn expression_statement(&mut self) -> Result<Stmt, String> {
+        let expr = self.expression()?;
+        self.semicolon()?;
+        Ok(Stmt::Expr(expr))
+    }
+
+    /// Statements up to a `}`, which is consumed
+    fn block_rest(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut body = Vec::new();
+        while !self.eat("}") {
+            if self.at_eof() {
+                return self.error("expected '}'");
+            }
+            body.push(self.statement()?);
+        }
+        Ok(body)
+    }
+
+    fn decl_kind(&mut self) -> Option<DeclKind> {
+        let kind = match self.peek() {
+            Token::Ident(w) if w == "var" => DeclKind::Var,
+            Token::Ident(w) if w == "let" => DeclKind::Let,
+            Token::Ident(w) if w == "const" => DeclKind::Const,
+            _ => return None,
+        };
+        self.next();
+        Some(kind)
+    }
+
+    fn declaration(&mut self) -> Result<Stmt, String> {
+        let kind = match self.decl_kind() {
+            Some(kind) => kind,
+            None => return self.error("expected a declaration"),
+        };
+        let mut declarations = Vec::new();
+        loop {
+            let target = self.binding_target()?;
+            let init = if self.eat("=") { Some(self.assignment()?) } else { None };
+            declarations.push((target, init));
+            if !self.eat(",") {
+                break;
+            }
+        }
+        Ok(Stmt::Decl(kind, declarations))
+    }
+
+    fn for_rest(&mut self) -> Result<Stmt, String> {
+        self.expect("(")?;
+        let mut init = None;
+        if !self.is(";") {
+            let start = self.pos;
+            let kind = self.decl_kind();
+            // `for (x of list)` and `for (const [k, v] of list)`
+            let target = if kind.is_some() || matches!(self.peek(), Token::Ident(_)) {
+                self.binding_target().ok()
+            } else {
+                None
+            };
+            if let Some(target) = target {
+                let of = self.is_word("of");
+                if of || self.is_word("in") {
+                    self.next();
+                    let iterable = if of { self.assignment()? } else { self.expression()? };
+                    self.expect(")")?;
+                    let body = Box::new(self.statement()?);
+                    return Ok(Stmt::ForEach(kind, target, iterable, body, !of));
+                }
+            }
+            self.pos = start;
+            self.no_in = true;
+            let first = if self.decl_kind().is_some() {
+                self.pos = start;
+                self.declaration()
+            } else {
+                self.expression().map(Stmt::Expr)
+            };
+            self.no_in = false;
+            init = Some(Box::new(first?));
+        }
+        self.expect(";")?;
+        let test = if self.is(";") { None } else { Some(self.expression()?) };
+        self.expect(";")?;
+        let update = if self.is(")") { None } else { Some(self.expression()?) };
+        self.expect(")")?;
+        let body = Box::new(self.statement()?);
+        Ok(Stmt::For(init, test, update, body))
+    }
+
+    /// A parameter or pattern element: a target, with the default it takes
+    /// in place of `undefined` if any
+    fn binding(&mut self) -> Result<Pattern, String> {
+        let target = self.binding_target()?;
+        if self.eat("=") {
+            return Ok(Pattern::Default(Box::new(target), Box::new(self.assignment()?)));
+        }
+        Ok(target)
+    }
+
+    /// A declaration target: a name, or an array or object pattern
+    fn binding_target(&mut self) -> Result<Pattern, String> {
+        let target = if self.eat("[") {
+            let mut elements = Vec::new();
+            let mut rest = None;
+            while !self.eat("]") {
+                if self.eat(",") {
+                    elements.push(None);
+                    continue;
+                }
+                if self.eat("...") {
+                    rest = Some(Box::new(self.binding()?));
+                    self.expect("]")?;
+                    break;
+                }
+                elements.push(Some(self.binding()?));
+                if !self.is("]") {
+                    self.expect(",")?;
+                }
+            }
+            Pattern::Array(elements, rest)
+        } else if self.eat("{") {
+            let mut props = Vec::new();
+            let mut rest = None;
+            while !self.eat("}") {
+                if self.eat("...") {
+                    rest = Some(Box::new(Pattern::Ident(self.ident()?)));
+                    self.expect("}")?;
+                    break;
+                }
+                let key = if self.eat("[") {
+                    let key = PropKey::Computed(self.assignment()?);
+                    self.expect("]")?;
+                    key
+                } else {
+                    PropKey::Name(self.property_name()?)
+                };
+                let value = if self.eat(":") {
+                    self.binding()?
+                } else {
+                    match &key {
+                        PropKey::Name(name) => {
+                            let target = Pattern::Ident(name.clone());
+                            if self.eat("=") {
+                                Pattern::Default(Box::new(target), Box::new(self.assignment()?))
+                            } else {
+                                target
+                            }
+                        }
+                        PropKey::Computed(_) => return self.error("expected ':'"),
+                    }
+                };
+                props.push((key, value));
+                if !self.is("}") {
+                    self.expect(",")?;
+                }
+            }
+            Pattern::Object(props, rest)
+        } else {
+            Pattern::Ident(self.ident()?)
+        };
+        Ok(target)
+    }
+
+    /// The parameters and body of a function after the `function` keyword
+    fn function_rest(&mut self, named: bool) -> Result<Arc<FunctionDef>, String> {
+        self.eat("*");
+        let name = match self.peek() {
+            Token::Ident(_) if !self.is("(") => Some(self.ident()?),
+            _ if named => return self.error("expected a function name"),
+            _ => None,
+        };
+        self.function_tail(name, false)
+    }
+
+    /// Parameter list and body
+    fn function_tail(&mut self, name: Option<String>, arrow: bool) -> Result<Arc<FunctionDef>, String> {
+        self.expect("(")?;
+        let start = self.pos;
+        let (params, rest) = self.params()?;
+        self.expect("{")?;
+        let body = self.block_rest()?;
+        let arguments = !arrow && self.tokens[start..self.pos].iter().any(|t| matches!(&t.token, Token::Ident(w) if w == "arguments"));
+        Ok(Arc::new(FunctionDef { name, params, rest, body, arrow, arguments }))
+    }
+
+    /// Parameters up to and including the `)`
+    fn params(&mut self) -> Result<(Vec<Pattern>, Option<Pattern>), String> {
+        let mut params = Vec::new();
+        let mut rest = None;
+        while !self.eat(")") {
+            if self.eat("...") {
+                rest = Some(self.binding()?);
+                self.expect(")")?;
+                break;
+            }
+            params.push(self.binding()?);
+            if !self.is(")") {
+                self.expect(",")?;
+            }
+        }
+        Ok((params, rest))
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let first = self.assignment()?;
+        if !self.is(",") {
+            return Ok(first);
+        }
+        let mut list = alloc::vec![first];
+        while self.eat(",") {
+            list.push(self.assignment()?);
+        }
+        Ok(Expr::Sequence(list))
+    }
+
+    /// Whether an arrow function starts here: `x =>` or `(...) =>`
+    fn arrow_ahead(&self) -> bool {
+        match self.peek() {
+            Token::Ident(name) if !is_reserved(name) => matches!(self.peek_at(1), Token::Punct("=>")),
+            Token::Punct("(") => {
+                let mut depth = 0usize;
+                let mut i = self.pos;
+                while i < self.tokens.len() {
+                    match &self.tokens[i].token {
+                        Token::Punct("(" | "[" | "{") => depth += 1,
+                        Token::Punct(")" | "]" | "}") => {
+                            depth -= 1;
+                            if depth == 0 {
+                                return matches!(self.tokens.get(i + 1).map(|t| &t.token), Some(Token::Punct("=>")));
+                            }
+                        }
+                        Token::Eof => return false,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn arrow(&mut self) -> Result<Expr, String> {
+        let (params, rest) = if self.eat("(") {
+            self.params()?
+        } else {
+            (alloc::vec![Pattern::Ident(self.ident()?)], None)
+        };
+        self.expect("=>")?;
+        let body = if self.eat("{") {
+            self.block_rest()?
+        } else {
+            let no_in = core::mem::replace(&mut self.no_in, false);
+            let value = self.assignment();
+            self.no_in = no_in;
+            alloc::vec![Stmt::Return(Some(value?))]
+        };
+        Ok(Expr::Function(Arc::new(FunctionDef { name: None, params, rest, body, arrow: true, arguments: false })))
+    }
+
+    fn assignment(&mut self) -> Result<Expr, String> {
+        self.nest()?;
+        let expr = self.assignment_inner();
+        self.depth -= 1;
+        expr
+    }
+
+    fn assignment_inner(&mut self) -> Result<Expr, String> {
+        if self.arrow_ahead() {
+            return self.arrow();
+        }
+        let target = self.conditional()?;
+        let op = match self.peek() {
+            Token::Punct(p) if is_assignment(p) => *p,
+            _ => return Ok(target),
+        };
+        self.next();
+        let pattern = if op == "=" {
+            to_pattern(target).or_else(|_| self.error("invalid assignment target"))?
+        } else {
+            match target {
+                Expr::Ident(_) | Expr::Member(..) => simple_pattern(target),
+                _ => return self.error("invalid assignment target"),
+            }
+        };
+        let value = self.assignment()?;
+        Ok(Expr::Assign(op, Box::new(pattern), Box::new(value)))
+    }
+
+    fn conditional(&mut self) -> Result<Expr, String> {
+        let test = self.binary(0)?;
+        if !self.eat("?") {
+            return Ok(test);
+        }
+        let no_in = core::mem::replace(&mut self.no_in, false);
+        let then = self.assignment();
+        self.no_in = no_in;
+        let then = then?;
+        self.expect(":")?;
+        let otherwise = self.assignment()?;
+        Ok(Expr::Conditional(Box::new(test), Box::new(then), Box::new(otherwise)))
+    }
+
+    /// The binary operator next, if it binds tighter than `min`, and its
+    /// precedence
+    fn binary_op(&self, min: u8) -> Option<(&'static str, u8)> {
+        let op = match self.peek() {
+            Token::Punct(p) => *p,
+            Token::Ident(w) if w == "instanceof" => "instanceof",
+            Token::Ident(w) if w == "in" && !self.no_in => "in",
+            _ => return None,
+        };
+        let precedence = match op {
+            "??" => 1,
+            "||" => 2,
+            "&&" => 3,
+            "|" => 4,
+            "^" => 5,
+            "&" => 6,
+            "==" | "!=" | "===" | "!==" => 7,
+            "<" | ">" | "<=" | ">=" | "instanceof" | "in" => 8,
+            "<<" | ">>" | ">>>" => 9,
+            "+" | "-" => 10,
+            "*" | "/" | "%" => 11,
+            "**" => 12,
+            _ => return None,
+        };
+        (precedence > min).then_some((op, precedence))
+    }
+
+    fn binary(&mut self, min: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.binary_op(min) {
+            self.next();
+            // `**` groups to the right
+            let right = if op == "**" { self.binary(precedence - 1)? } else { self.binary(precedence)? };
+            left = match op {
+                "&&" | "||" | "??" => Expr::Logical(op, Box::new(left), Box::new(right)),
+                _ => Expr::Binary(op, Box::new(left), Box::new(right)),
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        self.nest()?;
+        let expr = self.unary_inner();
+        self.depth -= 1;
+        expr
+    }
+
+    fn unary_inner(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Token::Punct(p @ ("!" | "-" | "+" | "~")) => Some(*p),
+            Token::Ident(w) if w == "typeof" => Some("typeof"),
+            Token::Ident(w) if w == "void" => Some("void"),
+            Token::Ident(w) if w == "delete" => Some("delete"),
+            _ => None,
+        };
+        if let Some(op) = op {
+            self.next();
+            let operand = self.unary()?;
+            return Ok(Expr::Unary(op, Box::new(operand)));
+        }
+        if let Token::Punct(op @ ("++" | "--")) = self.peek() {
+            let op = *op;
+            self.next();
+            let target = self.unary()?;
+            return Ok(Expr::Update(op, true, Box::new(target)));
+        }
+        let expr = self.postfix()?;
+        if let Token::Punct(op @ ("++" | "--")) = self.peek() {
+            if !self.newline() {
+                let op = *op;
+                self.next();
+                return Ok(Expr::Update(op, false, Box::new(expr)));
+            }
+        }
+        Ok(expr)
+    }
+
+    /// Arguments up to and including the `)`
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        let no_in = core::mem::replace(&mut self.no_in, false);
+        while !self.eat(")") {
+            let arg = if self.eat("...") { Expr::Spread(Box::new(self.assignment()?)) } else { self.assignment()? };
+            args.push(arg);
+            if !self.is(")") {
+                self.expect(",")?;
+            }
+        }
+        self.no_in = no_in;
+        Ok(args)
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = if self.eat_word("new") {
+            if self.eat(".") {
+                // `new.target`
+                self.property_name()?;
+                Expr::Undefined
+            } else {
+                let callee = self.member_only()?;
+                let args = if self.eat("(") { self.arguments()? } else { Vec::new() };
+                Expr::New(Box::new(callee), args)
+            }
+        } else {
+            self.primary()?
+        };
+        loop {
+            if self.eat(".") {
+                let name = self.property_name()?;
+                expr = Expr::Member(Box::new(expr), Box::new(Expr::String(name)), false);
+            } else if self.eat("?.") {
+                if self.eat("(") {
+                    expr = Expr::Call(Box::new(expr), self.arguments()?, true);
+                } else if self.eat("[") {
+                    let key = self.expression()?;
+                    self.expect("]")?;
+                    expr = Expr::Member(Box::new(expr), Box::new(key), true);
+                } else {
+                    let name = self.property_name()?;
+                    expr = Expr::Member(Box::new(expr), Box::new(Expr::String(name)), true);
+                }
+            } else if self.eat("[") {
+                let no_in = core::mem::replace(&mut self.no_in, false);
+                let key = self.expression();
+                self.no_in = no_in;
+                self.expect("]")?;
+                expr = Expr::Member(Box::new(expr), Box::new(key?), false);
+            } else if self.eat("(") {
+                expr = Expr::Call(Box::new(expr), self.arguments()?, false);
+            } else if let Token::Template(..) = self.peek() {
+                // A tagged template calls the tag with the text and values
+                let (quasis, values) = match self.primary()? {
+                    Expr::Template(quasis, values) => (quasis, values),
+                    _ => unreachable!(),
+                };
+                let strings = Expr::Array(quasis.into_iter().map(Expr::String).collect());
+                let mut args = alloc::vec![strings];
+                args.extend(values);
+                expr = Expr::Call(Box::new(expr), args, false);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// The callee of a `new`: member accesses without calls
+    fn member_only(&mut self) -> Result<Expr, String> {
+        let mut expr = if self.eat_word("new") {
+            let callee = self.member_only()?;
+            let args = if self.eat("(") { self.arguments()? } else { Vec::new() };
+            Expr::New(Box::new(callee), args)
+        } else {
+            self.primary()?
+        };
+        loop {
+            if self.eat(".") {
+                let name = self.property_name()?;
+                expr = Expr::Member(Box::new(expr), Box::new(Expr::String(name)), false);
+            } else if self.eat("[") {
+                let key = self.expression()?;
+                self.expect("]")?;
+                expr = Expr::Member(Box::new(expr), Box::new(key), false);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::String(s) => Ok(Expr::String(s)),
+            Token::Regex(pattern, flags) => Ok(Expr::Regex(pattern, flags)),
+            Token::Template(quasis, substitutions) => {
+                let mut values = Vec::new();
+                for tokens in substitutions {
+                    let mut inner = Parser { tokens, pos: 0, depth: self.depth, no_in: false };
+                    let value = inner.expression()?;
+                    if !inner.at_eof() {
+                        return inner.error("expected '}'");
+                    }
+                    values.push(value);
+                }
+                Ok(Expr::Template(quasis, values))
+            }
+            Token::Punct("(") => {
+                let no_in = core::mem::replace(&mut self.no_in, false);
+                let expr = self.expression();
+                self.no_in = no_in;
+                self.expect(")")?;
+                expr
+            }
+            Token::Punct("[") => {
+                let mut elements = Vec::new();
+                let no_in = core::mem::replace(&mut self.no_in, false);
+                while !self.eat("]") {
+                    if self.eat(",") {
+                        elements.push(Expr::Undefined);
+                        continue;
+                    }
+                    let element = if self.eat("...") { Expr::Spread(Box::new(self.assignment()?)) } else { self.assignment()? };
+                    elements.push(element);
+                    if !self.is("]") {
+                        self.expect(",")?;
+                    }
+                }
+                self.no_in = no_in;
+                Ok(Expr::Array(elements))
+            }
+            Token::Punct("{") => {
+                let no_in = core::mem::replace(&mut self.no_in, false);
+                let object = self.object_rest();
+                self.no_in = no_in;
+                object
+            }
+            Token::Ident(word) => match word.as_str() {
+                "true" => Ok(Expr::Bool(true)),
+                "false" => Ok(Expr::Bool(false)),
+                "null" => Ok(Expr::Null),
+                "undefined" => Ok(Expr::Undefined),
+                "this" => Ok(Expr::This),
+                "function" => Ok(Expr::Function(self.function_rest(false)?)),
+                word if is_reserved(word) => {
+                    self.pos -= 1;
+                    self.error("unexpected keyword")
+                }
+                _ => Ok(Expr::Ident(word)),
+            },
+            _ => {
+                self.pos -= 1;
+                self.error("expected an expression")
+            }
+        }
+    }
+
+    /// An object literal after its `{`
+    fn object_rest(&mut self) -> Result<Expr, String> {
+        let mut props = Vec::new();
+        while !self.eat("}") {
+            if self.eat("...") {
+                props.push(Prop::Spread(self.assignment()?));
+            } else if matches!(self.peek_at(1), Token::Punct("(")) {
+                // Method shorthand `name() { ... }`
+                let name = self.property_name()?;
+                let function = self.function_tail(Some(name.clone()), false)?;
+                props.push(Prop::Init(PropKey::Name(name), Expr::Function(function)));
+            } else {
+                let key = if self.eat("[") {
+                    let key = PropKey::Computed(self.assignment()?);
+                    self.expect("]")?;
+                    key
+                } else {
+                    PropKey::Name(self.property_name()?)
+                };
+                let value = if self.eat(":") {
+                    self.assignment()?
+                } else {
+                    // Shorthand `{ name }`
+                    match &key {
+                        PropKey::Name(name) => Expr::Ident(name.clone()),
+                        PropKey::Computed(_) => return self.error("expected ':'"),
+                    }
+                };
+                props.push(Prop::Init(key, value));
+            }
+            if !self.is("}") {
+                self.expect(",")?;
+            }
+        }
+        Ok(Expr::Object(props))
+    }
+}
+
+fn is_assignment(op: &str) -> bool {
+    matches!(op, "=" | "+=" | "-=" | "*=" | "/=" | "%=" | "**=" | "<<=" | ">>=" | ">>>=" | "&=" | "|=" | "^=" | "&&=" | "||=" | "??=")
+}
+
+fn is_reserved(word: &str) -> bool {
+    matches!(word,
+        "break" | "case" | "catch" | "const" | "continue" | "default" | "delete" | "do" | "else"
+        | "finally" | "for" | "function" | "if" | "in" | "instanceof" | "new" | "return"
+        | "switch" | "this" | "throw" | "try" | "typeof" | "var" | "void" | "while" | "true" | "false"
+        | "null")
+}
+
+fn simple_pattern(expr: Expr) -> Pattern {
+    match expr {
+        Expr::Ident(name) => Pattern::Ident(name),
+        other => Pattern::Member(Box::new(other)),
+    }
+}
+
+/// The target an expression on the left of `=` stands for, such as the
+/// array pattern of `[a, b] = [b, a]`
+fn to_pattern(expr: Expr) -> Result<Pattern, ()> {
+    Ok(match expr {
+        Expr::Ident(_) | Expr::Member(..) => simple_pattern(expr),
+        Expr::Array(elements) => {
+            let mut targets = Vec::new();
+            let mut rest = None;
+            for element in elements {
+                match element {
+                    Expr::Undefined => targets.push(None),
+                    Expr::Spread(inner) => rest = Some(Box::new(to_pattern(*inner)?)),
+                    other => targets.push(Some(to_pattern(other)?)),
+                }
+            }
+            Pattern::Array(targets, rest)
+        }
+        Expr::Object(props) => {
+            let mut targets = Vec::new();
+            let mut rest = None;
+            for prop in props {
+                match prop {
+                    Prop::Init(key, value) => targets.push((key, to_pattern(value)?)),
+                    Prop::Spread(inner) => rest = Some(Box::new(to_pattern(inner)?)),
+                }
+            }
+            Pattern::Object(targets, rest)
+        }
+        Expr::Assign("=", target, value) => Pattern::Default(target, value),
+        _ => return Err(()),
+    })
+}
+
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn expression(source: &str) -> Result<Expr, String> {
+        match parse(source)?.into_iter().next() {
+            Some(Stmt::Expr(expr)) => Ok(expr),
+            other => Err(alloc::format!("not an expression: {:?}", other)),
+        }
+    }
+
+    fn number(expr: &Expr, value: f64) -> bool {
+        matches!(expr, Expr::Number(n) if *n == value)
+    }
+
+    #[kernel_test]
+    fn multiplication_binds_tighter() -> Result<(), String> {
+        match expression("1 + 2 * 3")? {
+            Expr::Binary("+", left, right) => {
+                check!(number(&left, 1.0));
+                check!(matches!(*right, Expr::Binary("*", ref a, ref b) if number(a, 2.0) && number(b, 3.0)));
+            }
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        // Left associative: (8 - 4) - 2
+        match expression("8 - 4 - 2")? {
+            Expr::Binary("-", left, right) => {
+                check!(matches!(*left, Expr::Binary("-", ..)));
+                check!(number(&right, 2.0));
+            }
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn right_associative_operators() -> Result<(), String> {
+        match expression("2 ** 3 ** 2")? {
+            Expr::Binary("**", left, right) => {
+                check!(number(&left, 2.0));
+                check!(matches!(*right, Expr::Binary("**", ..)));
+            }
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        match expression("a = b += 1")? {
+            Expr::Assign("=", target, value) => {
+                check!(matches!(*target, Pattern::Ident(ref name) if name == "a"));
+                check!(matches!(*value, Expr::Assign("+=", ..)));
+            }
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        match expression("a ? b : c ? d : e")? {
+            Expr::Conditional(_, _, otherwise) => check!(matches!(*otherwise, Expr::Conditional(..))),
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn logic_sits_below_comparison() -> Result<(), String> {
+        match expression("a < b || c && d == e")? {
+            Expr::Logical("||", left, right) => {
+                check!(matches!(*left, Expr::Binary("<", ..)));
+                match *right {
+                    Expr::Logical("&&", _, last) => check!(matches!(*last, Expr::Binary("==", ..))),
+                    other => return Err(alloc::format!("{:?}", other)),
+                }
+            }
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn members_and_calls_chain() -> Result<(), String> {
+        // (a.b)[c](d)
+        match expression("a.b[c](d)")? {
+            Expr::Call(callee, args, false) => {
+                check_eq!(args.len(), 1);
+                match *callee {
+                    Expr::Member(object, _, false) => check!(matches!(*object, Expr::Member(..))),
+                    other => return Err(alloc::format!("{:?}", other)),
+                }
+            }
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        check!(matches!(expression("a?.b")?, Expr::Member(_, _, true)));
+        check!(matches!(expression("new A(1).b")?, Expr::Member(ref object, _, false) if matches!(**object, Expr::New(..))));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn semicolons_may_be_left_out() -> Result<(), String> {
+        check_eq!(parse("let a = 1\nlet b = 2\na + b")?.len(), 3);
+        // A line break after return ends the statement
+        match parse("function f() { return\n1 }")?.into_iter().next() {
+            Some(Stmt::Function(def)) => check!(matches!(def.body.first(), Some(Stmt::Return(None)))),
+            other => return Err(alloc::format!("{:?}", other)),
+        }
+        check!(parse("let = ;").is_err());
+        check!(parse("a +").is_err());
+        Ok(())
+    }
+}
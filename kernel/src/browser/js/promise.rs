@@ -0,0 +1,351 @@
+//! Promises
+//!
+//! `Promise` and the jobs that carry its reactions out. When a promise
+//! settles, each `then` waiting on it becomes a job, and the interpreter
+//! runs the jobs once the script, event or timer that queued them has
+//! finished, before anything else gets a turn. A rejection nothing has
+//! handled by then is reported on the console.
+
+use alloc::vec::Vec;
+
+use super::interp::Interpreter;
+use super::value::{Function, Kind, Native, ObjRef, PromiseState, Reaction, Value};
+use crate::warn;
+
+/// Work left for the end of a turn
+pub enum Job {
+    /// Call the handler of a reaction with what its promise settled to
+    Reaction { reaction: Reaction, argument: Value, rejected: bool },
+    /// Follow a thenable a promise was resolved with, by calling its
+    /// `then` with the promise's resolve functions
+    Thenable { promise: ObjRef, thenable: Value, then: Value },
+}
+
+impl Job {
+    /// Push the objects the job refers to
+    pub fn roots(&self, out: &mut Vec<ObjRef>) {
+        let values: [&Value; 3] = match self {
+            Job::Reaction { reaction, argument, .. } => {
+                out.extend(reaction.derived);
+                [&reaction.on_fulfilled, &reaction.on_rejected, argument]
+            }
+            Job::Thenable { promise, thenable, then } => {
+                out.push(*promise);
+                [thenable, then, &Value::Undefined]
+            }
+        };
+        out.extend(values.iter().filter_map(|v| v.object()));
+    }
+}
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or(Value::Undefined)
+}
+
+/// Give the interpreter `Promise`
+pub fn install(interp: &mut Interpreter) {
+    let proto = interp.protos.promise;
+    let ctor = match interp.native(promise_new) {
+        Ok(Value::Object(ctor)) => ctor,
+        _ => return,
+    };
+    interp.heap.get_mut(ctor).put("prototype", Value::Object(proto));
+    interp.heap.get_mut(proto).put("constructor", Value::Object(ctor));
+    let global = interp.global;
+    interp.heap.get_mut(global).put("Promise", Value::Object(ctor));
+    interp.define(proto, "then", promise_then);
+    interp.define(proto, "catch", promise_catch);
+    interp.define(proto, "finally", promise_finally);
+    interp.define(ctor, "resolve", promise_resolve);
+    interp.define(ctor, "reject", promise_reject);
+    interp.define(ctor, "all", promise_all);
+    interp.define(ctor, "race", promise_race);
+}
+
+/// A new pending promise
+pub fn new_promise(interp: &mut Interpreter) -> Result<ObjRef, Value> {
+    let promise = super::value::Promise { state: PromiseState::Pending(Vec::new()), handled: false };
+    let proto = interp.protos.promise;
+    interp.alloc(Kind::Promise(promise), Some(proto))
+}
+
+fn state(interp: &mut Interpreter, promise: ObjRef) -> Option<&mut super::value::Promise> {
+    match &mut interp.heap.get_mut(promise).kind {
+        Kind::Promise(state) => Some(state),
+        _ => None,
+    }
+}
+
+fn is_promise(interp: &Interpreter, value: &Value) -> Option<ObjRef> {
+    let obj = value.object()?;
+    matches!(interp.heap.get(obj).kind, Kind::Promise(_)).then_some(obj)
+}
+
+/// Resolve `promise` with `value`: fulfill it, or have it follow `value`
+/// if that is a thenable
+pub fn resolve(interp: &mut Interpreter, promise: ObjRef, value: Value) {
+    if value == Value::Object(promise) {
+        let error = interp.type_error("A promise cannot be resolved with itself");
+        return settle(interp, promise, Err(error));
+    }
+    if let Value::Object(_) = value {
+        match interp.get(&value, "then") {
+            Err(thrown) => return settle(interp, promise, Err(thrown)),
+            Ok(then) if interp.is_callable(&then) => {
+                interp.jobs.push_back(Job::Thenable { promise, thenable: value, then });
+                return;
+            }
+            Ok(_) => {}
+        }
+    }
+    settle(interp, promise, Ok(value));
+}
+
+pub fn reject(interp: &mut Interpreter, promise: ObjRef, reason: Value) {
+    settle(interp, promise, Err(reason));
+}
+
+/// Fulfill or reject a pending promise, queuing its reactions
+fn settle(interp: &mut Interpreter, promise: ObjRef, outcome: Result<Value, Value>) {
+    let (argument, rejected) = match outcome {
+        Ok(value) => (value, false),
+        Err(reason) => (reason, true),
+    };
+    let state = match state(interp, promise) {
+        Some(state) => state,
+        None => return,
+    };
+    let reactions = match &mut state.state {
+        PromiseState::Pending(reactions) => core::mem::take(reactions),
+        _ => return,
+    };
+    state.state = if rejected { PromiseState::Rejected(argument.clone()) } else { PromiseState::Fulfilled(argument.clone()) };
+    if rejected && !state.handled && reactions.is_empty() {
+        interp.unhandled.push(promise);
+    }
+    for reaction in reactions {
+        interp.jobs.push_back(Job::Reaction { reaction, argument: argument.clone(), rejected });
+    }
+}
+
+/// React to `promise` settling, settling `derived` with the outcome
+fn then(interp: &mut Interpreter, promise: ObjRef, on_fulfilled: Value, on_rejected: Value, derived: Option<ObjRef>) {
+    let reaction = Reaction { on_fulfilled, on_rejected, derived };
+    let state = match state(interp, promise) {
+        Some(state) => state,
+        None => return,
+    };
+    state.handled = true;
+    let (argument, rejected) = match &mut state.state {
+        PromiseState::Pending(reactions) => return reactions.push(reaction),
+        PromiseState::Fulfilled(value) => (value.clone(), false),
+        PromiseState::Rejected(reason) => (reason.clone(), true),
+    };
+    interp.jobs.push_back(Job::Reaction { reaction, argument, rejected });
+}
+
+/// Carry out a job, which cannot throw: what its handler throws rejects
+/// the promise waiting on it
+pub fn run(interp: &mut Interpreter, job: Job) {
+    match job {
+        Job::Reaction { reaction, argument, rejected } => {
+            let handler = if rejected { reaction.on_rejected } else { reaction.on_fulfilled };
+            let outcome = if interp.is_callable(&handler) {
+                interp.call(&handler, Value::Undefined, &[argument])
+            } else if rejected {
+                Err(argument)
+            } else {
+                Ok(argument)
+            };
+            match (reaction.derived, outcome) {
+                (Some(derived), Ok(value)) => resolve(interp, derived, value),
+                (Some(derived), Err(reason)) => reject(interp, derived, reason),
+                (None, _) => {}
+            }
+        }
+        Job::Thenable { promise, thenable, then } => {
+            if let Ok((resolve_fn, reject_fn)) = resolving_functions(interp, promise) {
+                if let Err(thrown) = interp.call(&then, thenable, &[resolve_fn, reject_fn.clone()]) {
+                    let _ = interp.call(&reject_fn, Value::Undefined, &[thrown]);
+                }
+            }
+        }
+    }
+}
+
+/// Report the promises rejected with nothing to handle them
+pub fn report_unhandled(interp: &mut Interpreter) {
+    for promise in core::mem::take(&mut interp.unhandled) {
+        let reason = match state(interp, promise) {
+            Some(super::value::Promise { state: PromiseState::Rejected(reason), handled: false }) => reason.clone(),
+            _ => continue,
+        };
+        let message = interp.describe(&reason);
+        warn!("js", "Uncaught (in promise) {}", message);
+    }
+}
+
+/// `f` with `this` and leading arguments bound, as `bind` makes
+fn bound(interp: &mut Interpreter, f: Native, this: Value, args: Vec<Value>) -> Result<Value, Value> {
+    let target = interp.native(f)?.object().expect("native is an object");
+    let proto = interp.protos.function;
+    interp.alloc(Kind::Function(Function::Bound { target, this, args }), Some(proto)).map(Value::Object)
+}
+
+/// The `resolve` and `reject` functions an executor gets: whichever is
+/// called first settles the promise, and later calls do nothing
+fn resolving_functions(interp: &mut Interpreter, promise: ObjRef) -> Result<(Value, Value), Value> {
+    let record = interp.new_object()?;
+    let resolve_fn = bound(interp, resolve_function, Value::Object(promise), alloc::vec![record.clone()])?;
+    let reject_fn = bound(interp, reject_function, Value::Object(promise), alloc::vec![record])?;
+    Ok((resolve_fn, reject_fn))
+}
+
+/// Whether the resolve functions sharing `record` are still to be called,
+/// marking them used
+fn first_call(interp: &mut Interpreter, record: &Value) -> bool {
+    let record = match record.object() {
+        Some(record) => record,
+        None => return false,
+    };
+    let first = interp.heap.get(record).own("done").is_none();
+    interp.heap.get_mut(record).put("done", Value::Bool(true));
+    first
+}
+
+fn resolve_function(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    if let (Some(promise), true) = (this.object(), first_call(interp, &arg(args, 0))) {
+        resolve(interp, promise, arg(args, 1));
+    }
+    Ok(Value::Undefined)
+}
+
+fn reject_function(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    if let (Some(promise), true) = (this.object(), first_call(interp, &arg(args, 0))) {
+        reject(interp, promise, arg(args, 1));
+    }
+    Ok(Value::Undefined)
+}
+
+fn promise_new(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let executor = arg(args, 0);
+    if !interp.is_callable(&executor) {
+        return Err(interp.type_error("Promise resolver is not a function"));
+    }
+    let promise = new_promise(interp)?;
+    let (resolve_fn, reject_fn) = resolving_functions(interp, promise)?;
+    if let Err(thrown) = interp.call(&executor, Value::Undefined, &[resolve_fn, reject_fn.clone()]) {
+        interp.call(&reject_fn, Value::Undefined, &[thrown])?;
+    }
+    Ok(Value::Object(promise))
+}
+
+fn this_promise(interp: &mut Interpreter, this: &Value) -> Result<ObjRef, Value> {
+    match is_promise(interp, this) {
+        Some(promise) => Ok(promise),
+        None => Err(interp.type_error("not a Promise")),
+    }
+}
+
+fn promise_then(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let promise = this_promise(interp, &this)?;
+    let derived = new_promise(interp)?;
+    then(interp, promise, arg(args, 0), arg(args, 1), Some(derived));
+    Ok(Value::Object(derived))
+}
+
+fn promise_catch(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    promise_then(interp, this, &[Value::Undefined, arg(args, 0)])
+}
+
+fn promise_finally(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let f = arg(args, 0);
+    if !interp.is_callable(&f) {
+        return promise_then(interp, this, &[f.clone(), f]);
+    }
+    let on_fulfilled = bound(interp, finally_fulfilled, f.clone(), Vec::new())?;
+    let on_rejected = bound(interp, finally_rejected, f, Vec::new())?;
+    promise_then(interp, this, &[on_fulfilled, on_rejected])
+}
+
+fn finally_fulfilled(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    interp.call(&this, Value::Undefined, &[])?;
+    Ok(arg(args, 0))
+}
+
+fn finally_rejected(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    interp.call(&this, Value::Undefined, &[])?;
+    Err(arg(args, 0))
+}
+
+/// `Promise.resolve(value)`: the value itself if it is a promise
+fn to_promise(interp: &mut Interpreter, value: Value) -> Result<ObjRef, Value> {
+    if let Some(promise) = is_promise(interp, &value) {
+        return Ok(promise);
+    }
+    let promise = new_promise(interp)?;
+    resolve(interp, promise, value);
+    Ok(promise)
+}
+
+fn promise_resolve(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    to_promise(interp, arg(args, 0)).map(Value::Object)
+}
+
+fn promise_reject(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let promise = new_promise(interp)?;
+    reject(interp, promise, arg(args, 0));
+    Ok(Value::Object(promise))
+}
+
+fn promise_all(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = interp.iterate(&arg(args, 0))?;
+    let promise = new_promise(interp)?;
+    let values = interp.new_array(alloc::vec![Value::Undefined; items.len()])?;
+    if items.is_empty() {
+        resolve(interp, promise, values);
+        return Ok(Value::Object(promise));
+    }
+    // What the element functions share: the results so far and how many
+    // are still to come
+    let record = interp.new_object()?;
+    interp.put(&record, "promise", Value::Object(promise))?;
+    interp.put(&record, "values", values)?;
+    interp.put(&record, "remaining", Value::Number(items.len() as f64))?;
+    let (_, reject_fn) = resolving_functions(interp, promise)?;
+    for (i, item) in items.into_iter().enumerate() {
+        let item = to_promise(interp, item)?;
+        let on_fulfilled = bound(interp, all_element, record.clone(), alloc::vec![Value::Number(i as f64)])?;
+        then(interp, item, on_fulfilled, reject_fn.clone(), None);
+    }
+    Ok(Value::Object(promise))
+}
+
+fn all_element(interp: &mut Interpreter, this: Value, args: &[Value]) -> Result<Value, Value> {
+    let index = arg(args, 0).to_number() as usize;
+    let values = interp.get(&this, "values")?;
+    if let Some(items) = interp.array_mut(&values) {
+        if let Some(slot) = items.get_mut(index) {
+            *slot = arg(args, 1);
+        }
+    }
+    let remaining = interp.get(&this, "remaining")?.to_number() - 1.0;
+    interp.put(&this, "remaining", Value::Number(remaining))?;
+    if remaining == 0.0 {
+        if let Some(promise) = interp.get(&this, "promise")?.object() {
+            resolve(interp, promise, values);
+        }
+    }
+    Ok(Value::Undefined)
+}
+
+fn promise_race(interp: &mut Interpreter, _: Value, args: &[Value]) -> Result<Value, Value> {
+    let items = interp.iterate(&arg(args, 0))?;
+    let promise = new_promise(interp)?;
+    let (resolve_fn, reject_fn) = resolving_functions(interp, promise)?;
+    for item in items {
+        let item = to_promise(interp, item)?;
+        then(interp, item, resolve_fn.clone(), reject_fn.clone(), None);
+    }
+    Ok(Value::Object(promise))
+}
@@ -0,0 +1,471 @@
+//! Regular expressions
+//!
+//! A backtracking matcher for the common part of JavaScript's syntax:
+//! character classes and escapes, anchors and word boundaries, capturing
+//! and non-capturing groups, lookahead, back references, alternation and
+//! greedy or lazy quantifiers, with the `i` and `m` flags. Positions are
+//! in characters. A quantified single character is matched in a loop
+//! rather than by recursion, so `.*` over a long string stays shallow.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Deepest the matcher recurses for repeated groups before giving up
+const MAX_DEPTH: usize = 200;
+
+#[derive(Debug)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    Any,
+    /// Items and whether the class is negated
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    /// `\b`, or `\B` when false
+    WordBoundary(bool),
+    /// Alternatives and the capture index, if the group captures
+    Group(Vec<Vec<Node>>, Option<usize>),
+    /// Alternatives and whether the lookahead is negative
+    Look(Vec<Vec<Node>>, bool),
+    BackRef(usize),
+    /// Node, minimum, maximum and whether greedy
+    Repeat(Box<Node>, usize, usize, bool),
+}
+
+#[derive(Debug)]
+pub struct Regex {
+    /// The pattern as written
+    pub source: String,
+    alternatives: Vec<Vec<Node>>,
+    /// Number of capturing groups
+    pub groups: usize,
+    ignore_case: bool,
+    multiline: bool,
+}
+
+/// Start and end of each group of a match, the whole match first
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+impl Regex {
+    pub fn new(pattern: &str, flags: &str) -> Result<Self, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = Parser { chars: &chars, pos: 0, groups: 0 };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < chars.len() {
+            return Err(format!("Invalid regular expression: /{}/: unmatched ')'", pattern));
+        }
+        Ok(Regex {
+            source: String::from(pattern),
+            alternatives,
+            groups: parser.groups,
+            ignore_case: flags.contains('i'),
+            multiline: flags.contains('m'),
+        })
+    }
+
+    /// The first match in `text` starting at or after `start`
+    pub fn find_at(&self, text: &[char], start: usize) -> Option<Captures> {
+        (start..=text.len()).find_map(|pos| self.match_at(text, pos))
+    }
+
+    /// A match starting exactly at `pos`
+    fn match_at(&self, text: &[char], pos: usize) -> Option<Captures> {
+        let mut caps: Captures = alloc::vec![None; self.groups + 1];
+        let mut matcher = Matcher { regex: self, text, depth: 0 };
+        let mut end = None;
+        let found = matcher.alternatives(&self.alternatives, pos, &mut caps, &mut |e, _| {
+            end = Some(e);
+            true
+        });
+        if !found {
+            return None;
+        }
+        caps[0] = Some((pos, end.unwrap()));
+        Some(caps)
+    }
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        let pattern: String = self.chars.iter().collect();
+        Err(format!("Invalid regular expression: /{}/: {}", pattern, message))
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = alloc::vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, usize::MAX),
+            Some('+') => (1, usize::MAX),
+            Some('?') => (0, 1),
+            Some('{') => match self.braces() {
+                Some(bounds) => bounds,
+                None => return Ok(atom),
+            },
+            _ => return Ok(atom),
+        };
+        // Past the quantifier, or the `}` that ends it
+        self.pos += 1;
+        if matches!(atom, Node::Start | Node::End | Node::WordBoundary(_) | Node::Look(..)) {
+            return self.error("nothing to repeat");
+        }
+        let greedy = if self.peek() == Some('?') {
+            self.pos += 1;
+            false
+        } else {
+            true
+        };
+        Ok(Node::Repeat(Box::new(atom), min, max, greedy))
+    }
+
+    /// `{n}`, `{n,}` or `{n,m}`, leaving the position on the `}`; `None`
+    /// if the brace is a literal
+    fn braces(&mut self) -> Option<(usize, usize)> {
+        let close = self.chars[self.pos..].iter().position(|&c| c == '}')? + self.pos;
+        let inner: String = self.chars[self.pos + 1..close].iter().collect();
+        let (min, max) = match inner.split_once(',') {
+            Some((min, "")) => (min.parse().ok()?, usize::MAX),
+            Some((min, max)) => (min.parse().ok()?, max.parse().ok()?),
+            None => {
+                let n = inner.parse().ok()?;
+                (n, n)
+            }
+        };
+        self.pos = close;
+        Some((min, max))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let (capture, look) = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    (false, None)
+                } else if self.chars[self.pos..].starts_with(&['?', '=']) {
+                    self.pos += 2;
+                    (false, Some(false))
+                } else if self.chars[self.pos..].starts_with(&['?', '!']) {
+                    self.pos += 2;
+                    (false, Some(true))
+                } else {
+                    (true, None)
+                };
+                let index = if capture {
+                    self.groups += 1;
+                    Some(self.groups)
+                } else {
+                    None
+                };
+                let alternatives = self.alternatives()?;
+                if self.peek() != Some(')') {
+                    return self.error("missing )");
+                }
+                self.pos += 1;
+                match look {
+                    Some(negative) => Node::Look(alternatives, negative),
+                    None => Node::Group(alternatives, index),
+                }
+            }
+            '[' => self.class()?,
+            '\\' => self.escape(false)?,
+            '*' | '+' | '?' => return self.error("nothing to repeat"),
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        loop {
+            let c = match self.peek() {
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Node::Class(items, negated));
+                }
+                Some(c) => c,
+                None => return self.error("missing ]"),
+            };
+            self.pos += 1;
+            let item = if c == '\\' {
+                match self.escape(true)? {
+                    Node::Char(c) => ClassItem::Char(c),
+                    Node::Class(mut inner, false) if inner.len() == 1 => inner.pop().unwrap(),
+                    _ => return self.error("invalid class escape"),
+                }
+            } else {
+                ClassItem::Char(c)
+            };
+            // A range such as `a-z`; a `-` at either end is literal
+            if let ClassItem::Char(from) = item {
+                if self.peek() == Some('-') && self.chars.get(self.pos + 1).map_or(false, |&c| c != ']') {
+                    self.pos += 1;
+                    let mut to = self.peek().unwrap();
+                    self.pos += 1;
+                    if to == '\\' {
+                        match self.escape(true)? {
+                            Node::Char(c) => to = c,
+                            _ => return self.error("invalid range"),
+                        }
+                    }
+                    items.push(ClassItem::Range(from, to));
+                    continue;
+                }
+            }
+            items.push(item);
+        }
+    }
+
+    /// The escape after a `\`; `in_class` reads `\b` as a backspace
+    fn escape(&mut self, in_class: bool) -> Result<Node, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("\\ at end of pattern"),
+        };
+        self.pos += 1;
+        let class = |item| Node::Class(alloc::vec![item], false);
+        Ok(match c {
+            'd' => class(ClassItem::Digit(true)),
+            'D' => class(ClassItem::Digit(false)),
+            'w' => class(ClassItem::Word(true)),
+            'W' => class(ClassItem::Word(false)),
+            's' => class(ClassItem::Space(true)),
+            'S' => class(ClassItem::Space(false)),
+            'b' if in_class => Node::Char('\u{8}'),
+            'b' => Node::WordBoundary(true),
+            'B' => Node::WordBoundary(false),
+            'n' => Node::Char('\n'),
+            'r' => Node::Char('\r'),
+            't' => Node::Char('\t'),
+            'f' => Node::Char('\u{c}'),
+            'v' => Node::Char('\u{b}'),
+            '0' => Node::Char('\0'),
+            'x' | 'u' => {
+                let digits = if c == 'x' { 2 } else { 4 };
+                let hex: String = self.chars.get(self.pos..self.pos + digits).map(|s| s.iter().collect()).unwrap_or_default();
+                match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    Some(ch) => {
+                        self.pos += digits;
+                        Node::Char(ch)
+                    }
+                    None => Node::Char(c),
+                }
+            }
+            '1'..='9' if !in_class => Node::BackRef(c as usize - '0' as usize),
+            c => Node::Char(c),
+        })
+    }
+}
+
+type Continuation<'k> = dyn FnMut(usize, &mut Captures) -> bool + 'k;
+
+struct Matcher<'a> {
+    regex: &'a Regex,
+    text: &'a [char],
+    depth: usize,
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl Matcher<'_> {
+    fn alternatives(&mut self, alternatives: &[Vec<Node>], pos: usize, caps: &mut Captures, k: &mut Continuation) -> bool {
+        alternatives.iter().any(|seq| self.sequence(seq, pos, caps, k))
+    }
+
+    /// Whether the single-character `node` matches at `pos`
+    fn single(&self, node: &Node, pos: usize) -> Option<bool> {
+        let c = self.text.get(pos).copied();
+        Some(match node {
+            Node::Char(expected) => c.map_or(false, |c| self.same(c, *expected)),
+            Node::Any => c.map_or(false, |c| c != '\n' && c != '\r'),
+            Node::Class(items, negated) => c.map_or(false, |c| self.in_class(items, c) != *negated),
+            _ => return None,
+        })
+    }
+
+    fn same(&self, a: char, b: char) -> bool {
+        a == b || (self.regex.ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    fn in_class(&self, items: &[ClassItem], c: char) -> bool {
+        let lower = if self.regex.ignore_case { c.to_lowercase().next().unwrap_or(c) } else { c };
+        let upper = if self.regex.ignore_case { c.to_uppercase().next().unwrap_or(c) } else { c };
+        items.iter().any(|item| match *item {
+            ClassItem::Char(x) => self.same(c, x),
+            ClassItem::Range(from, to) => (from..=to).contains(&c) || (from..=to).contains(&lower) || (from..=to).contains(&upper),
+            ClassItem::Digit(yes) => c.is_ascii_digit() == yes,
+            ClassItem::Word(yes) => is_word(c) == yes,
+            ClassItem::Space(yes) => c.is_whitespace() == yes,
+        })
+    }
+
+    fn sequence(&mut self, nodes: &[Node], pos: usize, caps: &mut Captures, k: &mut Continuation) -> bool {
+        let (node, rest) = match nodes.split_first() {
+            Some(split) => split,
+            None => return k(pos, caps),
+        };
+        if let Some(matched) = self.single(node, pos) {
+            return matched && self.sequence(rest, pos + 1, caps, k);
+        }
+        let text = self.text;
+        match node {
+            Node::Start => {
+                let at_start = pos == 0 || (self.regex.multiline && text[pos - 1] == '\n');
+                at_start && self.sequence(rest, pos, caps, k)
+            }
+            Node::End => {
+                let at_end = pos == text.len() || (self.regex.multiline && text[pos] == '\n');
+                at_end && self.sequence(rest, pos, caps, k)
+            }
+            Node::WordBoundary(expected) => {
+                let before = pos > 0 && is_word(text[pos - 1]);
+                let after = pos < text.len() && is_word(text[pos]);
+                (before != after) == *expected && self.sequence(rest, pos, caps, k)
+            }
+            Node::BackRef(group) => {
+                let (start, end) = match caps.get(*group).copied().flatten() {
+                    Some(span) => span,
+                    None => return self.sequence(rest, pos, caps, k),
+                };
+                let len = end - start;
+                let matches = pos + len <= text.len() && (0..len).all(|i| self.same(text[pos + i], text[start + i]));
+                matches && self.sequence(rest, pos + len, caps, k)
+            }
+            Node::Group(alternatives, index) => {
+                if !self.enter() {
+                    return false;
+                }
+                let index = *index;
+                let (regex, depth) = (self.regex, self.depth);
+                let found = self.alternatives(alternatives, pos, caps, &mut |end, caps: &mut Captures| {
+                    let saved = index.map(|i| caps[i]);
+                    if let Some(i) = index {
+                        caps[i] = Some((pos, end));
+                    }
+                    // The rest of the sequence runs in a matcher of its own,
+                    // as this one is borrowed for the group
+                    let mut inner = Matcher { regex, text, depth };
+                    if inner.sequence(rest, end, caps, k) {
+                        return true;
+                    }
+                    if let (Some(i), Some(saved)) = (index, saved) {
+                        caps[i] = saved;
+                    }
+                    false
+                });
+                self.depth -= 1;
+                found
+            }
+            Node::Look(alternatives, negative) => {
+                let mut scratch = caps.clone();
+                let found = self.alternatives(alternatives, pos, &mut scratch, &mut |_, _| true);
+                if found && !negative {
+                    *caps = scratch;
+                }
+                found != *negative && self.sequence(rest, pos, caps, k)
+            }
+            Node::Repeat(inner, min, max, greedy) => self.repeat(inner, *min, *max, *greedy, rest, pos, caps, k),
+            _ => false,
+        }
+    }
+
+    fn enter(&mut self) -> bool {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            self.depth -= 1;
+            return false;
+        }
+        true
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn repeat(&mut self, node: &Node, min: usize, max: usize, greedy: bool, rest: &[Node], pos: usize, caps: &mut Captures, k: &mut Continuation) -> bool {
+        if self.single(node, pos).is_some() {
+            // Count how far the character repeats, then back off
+            let mut count = 0;
+            while count < max && self.single(node, pos + count) == Some(true) {
+                count += 1;
+            }
+            if count < min {
+                return false;
+            }
+            if greedy {
+                return (min..=count).rev().any(|n| self.sequence(rest, pos + n, caps, k));
+            }
+            return (min..=count).any(|n| self.sequence(rest, pos + n, caps, k));
+        }
+        self.repeat_complex(node, min, max, greedy, rest, pos, caps, k, 0)
+    }
+
+    /// Repeat a group, one recursion per repetition
+    #[allow(clippy::too_many_arguments)]
+    fn repeat_complex(&mut self, node: &Node, min: usize, max: usize, greedy: bool, rest: &[Node], pos: usize, caps: &mut Captures, k: &mut Continuation, done: usize) -> bool {
+        if !self.enter() {
+            return false;
+        }
+        let (regex, text, depth) = (self.regex, self.text, self.depth);
+        let one = core::slice::from_ref(node);
+        let found = if done >= max {
+            Matcher { regex, text, depth }.sequence(rest, pos, caps, k)
+        } else if done >= min && !greedy && (Matcher { regex, text, depth }).sequence(rest, pos, caps, k) {
+            true
+        } else {
+            let matched = self.sequence(one, pos, caps, &mut |end: usize, caps: &mut Captures| {
+                // An empty repetition would go round forever
+                end != pos && Matcher { regex, text, depth }.repeat_complex(node, min, max, greedy, rest, end, caps, k, done + 1)
+            });
+            matched || (done >= min && greedy && Matcher { regex, text, depth }.sequence(rest, pos, caps, k))
+        };
+        self.depth -= 1;
+        found
+    }
+}
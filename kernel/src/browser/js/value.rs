@@ -0,0 +1,311 @@
+//! JavaScript values and the object heap
+//!
+//! Objects live in a heap owned by the interpreter and are referred to by
+//! index, so values can be copied about freely and cycles cost nothing.
+//! Unreachable objects are freed by a mark and sweep collection, which
+//! the interpreter runs between scripts and events, when nothing but its
+//! own roots holds a reference.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::interp::Interpreter;
+use super::parser::FunctionDef;
+use super::regex::Regex;
+
+/// Most objects the heap holds, which keeps a runaway script within the
+/// kernel heap
+pub const MAX_OBJECTS: usize = 40_000;
+
+/// Index of an object in the heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ObjRef(pub u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Object(ObjRef),
+}
+
+impl Value {
+    pub fn str(s: &str) -> Self {
+        Value::String(String::from(s))
+    }
+
+    pub fn is_nullish(&self) -> bool {
+        matches!(self, Value::Undefined | Value::Null)
+    }
+
+    pub fn object(&self) -> Option<ObjRef> {
+        match self {
+            Value::Object(obj) => Some(*obj),
+            _ => None,
+        }
+    }
+
+    /// The number the value converts to, for values that are not objects
+    pub fn to_number(&self) -> f64 {
+        match self {
+            Value::Undefined => f64::NAN,
+            Value::Null => 0.0,
+            Value::Bool(b) => *b as u8 as f64,
+            Value::Number(n) => *n,
+            Value::String(s) => string_to_number(s),
+            Value::Object(_) => f64::NAN,
+        }
+    }
+}
+
+/// A function implemented in Rust: it gets `this` and the arguments, and
+/// returns a value or throws one
+pub type Native = fn(&mut Interpreter, Value, &[Value]) -> Result<Value, Value>;
+
+#[derive(Clone)]
+pub enum Function {
+    /// Code from a script, closed over the scope it was defined in; an
+    /// arrow function also keeps the `this` of that scope
+    Script { def: Arc<FunctionDef>, scope: ObjRef, this: Option<Value> },
+    Native(Native),
+    /// `f.bind(this, ...args)`
+    Bound { target: ObjRef, this: Value, args: Vec<Value> },
+}
+
+/// What an object is besides its properties
+#[derive(Clone)]
+pub enum Kind {
+    Plain,
+    Array(Vec<Value>),
+    Function(Function),
+    /// A compiled regular expression and its flags
+    Regex(Arc<Regex>, String),
+    /// Variables of a scope; the object's prototype is the enclosing scope
+    Scope,
+    /// A wrapper for a primitive, as `new String("x")` makes
+    Primitive(Value),
+    /// `document`
+    Document,
+    /// A DOM element, by node ID
+    Element(u32),
+    /// An element's `classList`, `dataset` or `style`
+    ClassList(u32),
+    Dataset(u32),
+    Style(u32),
+    Promise(Promise),
+}
+
+/// What a promise has settled to, or the reactions waiting for it
+#[derive(Clone)]
+pub enum PromiseState {
+    Pending(Vec<Reaction>),
+    Fulfilled(Value),
+    Rejected(Value),
+}
+
+/// A `then` waiting on a promise: what to call when it settles, and the
+/// promise `then` returned, which settles with the outcome
+#[derive(Clone)]
+pub struct Reaction {
+    pub on_fulfilled: Value,
+    pub on_rejected: Value,
+    pub derived: Option<ObjRef>,
+}
+
+#[derive(Clone)]
+pub struct Promise {
+    pub state: PromiseState,
+    /// Something has reacted to a rejection, so it is not reported
+    pub handled: bool,
+}
+
+#[derive(Clone)]
+pub struct Object {
+    /// Own properties in the order they were added
+    pub props: Vec<(String, Value)>,
+    pub proto: Option<ObjRef>,
+    pub kind: Kind,
+}
+
+impl Object {
+    pub fn new(kind: Kind, proto: Option<ObjRef>) -> Self {
+        Object { props: Vec::new(), proto, kind }
+    }
+
+    pub fn own(&self, key: &str) -> Option<&Value> {
+        self.props.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+    }
+
+    /// Set an own data property, adding it if it is new
+    pub fn put(&mut self, key: &str, value: Value) {
+        match self.props.iter_mut().find(|(k, _)| k == key) {
+            Some((_, slot)) => *slot = value,
+            None => self.props.push((String::from(key), value)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let before = self.props.len();
+        self.props.retain(|(k, _)| k != key);
+        self.props.len() != before
+    }
+}
+
+/// The objects of one interpreter
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free: Vec<u32>,
+    /// Objects alive after the last collection
+    pub live_after_gc: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap { objects: Vec::new(), free: Vec::new(), live_after_gc: 0 }
+    }
+
+    /// Number of objects allocated
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    /// Store an object, or `None` if the heap is full
+    pub fn alloc(&mut self, object: Object) -> Option<ObjRef> {
+        if let Some(index) = self.free.pop() {
+            self.objects[index as usize] = Some(object);
+            return Some(ObjRef(index));
+        }
+        if self.objects.len() >= MAX_OBJECTS {
+            return None;
+        }
+        self.objects.push(Some(object));
+        Some(ObjRef(self.objects.len() as u32 - 1))
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        self.objects[obj.0 as usize].as_ref().expect("freed object")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        self.objects[obj.0 as usize].as_mut().expect("freed object")
+    }
+
+    /// Free every object not reachable from `roots`; returns how many
+    /// were freed
+    pub fn collect(&mut self, roots: &[ObjRef]) -> usize {
+        let mut marked = alloc::vec![false; self.objects.len()];
+        let mut stack: Vec<ObjRef> = roots.to_vec();
+        while let Some(obj) = stack.pop() {
+            let index = obj.0 as usize;
+            if marked[index] || self.objects[index].is_none() {
+                continue;
+            }
+            marked[index] = true;
+            let object = self.objects[index].as_ref().unwrap();
+            object.children(&mut stack);
+        }
+        let mut freed = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            if slot.is_some() && !marked[index] {
+                *slot = None;
+                self.free.push(index as u32);
+                freed += 1;
+            }
+        }
+        self.live_after_gc = self.len();
+        freed
+    }
+
+    /// Whether `obj` is still allocated, for weak references
+    pub fn alive(&self, obj: ObjRef) -> bool {
+        self.objects.get(obj.0 as usize).map_or(false, |o| o.is_some())
+    }
+}
+
+impl Object {
+    /// Push the objects this one refers to
+    fn children(&self, out: &mut Vec<ObjRef>) {
+        let value = |v: &Value, out: &mut Vec<ObjRef>| {
+            if let Value::Object(o) = v {
+                out.push(*o);
+            }
+        };
+        if let Some(proto) = self.proto {
+            out.push(proto);
+        }
+        self.props.iter().for_each(|(_, v)| value(v, out));
+        match &self.kind {
+            Kind::Array(items) => items.iter().for_each(|v| value(v, out)),
+            Kind::Function(Function::Script { scope, this, .. }) => {
+                out.push(*scope);
+                if let Some(this) = this {
+                    value(this, out);
+                }
+            }
+            Kind::Function(Function::Bound { target, this, args }) => {
+                out.push(*target);
+                value(this, out);
+                args.iter().for_each(|v| value(v, out));
+            }
+            Kind::Primitive(v) => value(v, out),
+            Kind::Promise(promise) => match &promise.state {
+                PromiseState::Pending(reactions) => {
+                    for reaction in reactions {
+                        value(&reaction.on_fulfilled, out);
+                        value(&reaction.on_rejected, out);
+                        out.extend(reaction.derived);
+                    }
+                }
+                PromiseState::Fulfilled(v) | PromiseState::Rejected(v) => value(v, out),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// A number as JavaScript prints it
+pub fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        String::from("NaN")
+    } else if n.is_infinite() {
+        String::from(if n > 0.0 { "Infinity" } else { "-Infinity" })
+    } else if n == 0.0 {
+        String::from("0")
+    } else if n == (n as i64) as f64 && n.abs() < 1e18 {
+        format!("{}", n as i64)
+    } else if n.abs() >= 1e21 || n.abs() < 1e-6 {
+        // Written with an exponent, as `1e+21` and `1.5e-7`
+        let s = format!("{:e}", n);
+        match s.split_once('e') {
+            Some((mantissa, exponent)) if !exponent.starts_with('-') => format!("{}e+{}", mantissa, exponent),
+            _ => s,
+        }
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// A string as a number: decimal, `0x` hex, or NaN; blank is zero
+pub fn string_to_number(s: &str) -> f64 {
+    let s = s.trim();
+    if s.is_empty() {
+        return 0.0;
+    }
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).map_or(f64::NAN, |n| n as f64);
+    }
+    match s {
+        "Infinity" | "+Infinity" => return f64::INFINITY,
+        "-Infinity" => return f64::NEG_INFINITY,
+        _ => {}
+    }
+    // Rust accepts "inf" and "nan", which JavaScript does not
+    if s.bytes().any(|b| b.is_ascii_alphabetic() && b != b'e' && b != b'E') {
+        return f64::NAN;
+    }
+    s.parse::<f64>().unwrap_or(f64::NAN)
+}
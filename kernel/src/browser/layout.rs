@@ -1,22 +1,55 @@
 //! Layout Engine
 //!
-//! Performs CSS box model layout on the DOM tree.
+//! Builds a box tree from the styled DOM and lays it out in normal flow.
+//! Block boxes stack vertically inside their containing block, with the
+//! margins of adjacent siblings collapsed. A block whose children are all
+//! inline-level gets an inline formatting context: its text is broken into
+//! lines at spaces, with inline-blocks and images placed as atomic boxes.
+//! Blocks holding both kinds of children wrap each run of inline content
+//! in an anonymous block.
+//!
+//! Text is measured with the bitmap font the desktop draws with, scaled up
+//! (never down) to the font size. All positions are in page coordinates,
+//! with (0, 0) at the top left of the page.
+//!
+//! Links and fragment targets are recorded in the tree's `links` and
+//! `anchors` tables, and every box and fragment inside one carries its
+//! index, so a point can be mapped back to the link under it and a
+//! fragment to where its target starts.
+//!
+//! Form controls are numbered in document order, as `forms::Controls`
+//! numbers them, and show the values in the page's `FormState`.
 
+use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::String;
-use alloc::vec;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use crate::browser::BrowserError;
+use crate::browser::forms::{self, ControlKind, FormState};
 use crate::browser::html::{Document, Element, Node};
-use crate::println;
+use crate::graphics::font;
+use crate::graphics::image::Image;
+use crate::info;
+
+/// Font size of the root element, which `rem` and the headings scale from
+const BASE_FONT_SIZE: f32 = 16.0;
+
+/// Width a text input gets when the page does not set one
+const INPUT_WIDTH: f32 = 160.0;
+
+/// Columns and rows a text area gets when the page does not set them
+const TEXTAREA_COLS: f32 = 20.0;
+const TEXTAREA_ROWS: f32 = 2.0;
 
 /// Layout box
 #[derive(Debug)]
 pub struct LayoutBox {
-    /// Position (x, y)
+    /// Position of the border box
     pub x: f32,
     pub y: f32,
-    /// Dimensions
+    /// Size of the border box
     pub width: f32,
     pub height: f32,
     /// Padding
@@ -31,10 +64,12 @@ pub struct LayoutBox {
     pub content_height: f32,
     /// Box type
     pub box_type: BoxType,
-    /// Children
+    /// Children; those of an inline formatting context move into `lines`
     pub children: Vec<LayoutBox>,
-    /// Text content
-    pub text: Option<String>,
+    /// What the box holds besides its children
+    pub content: BoxContent,
+    /// Lines of an inline formatting context
+    pub lines: Vec<LineBox>,
     /// Styles
     pub styles: LayoutStyles,
 }
@@ -48,6 +83,48 @@ pub enum BoxType {
     None,
 }
 
+/// What a box holds besides its children
+#[derive(Debug, Clone)]
+pub enum BoxContent {
+    /// An element, or an anonymous block
+    Element,
+    /// A run of text
+    Text(String),
+    /// `<br>`
+    LineBreak,
+    /// `<img>`, with its bitmap once loaded; until then it is drawn as a
+    /// frame holding its alt text
+    Image { alt: String, bitmap: Option<Arc<Image>> },
+}
+
+/// One line of an inline formatting context
+#[derive(Debug)]
+pub struct LineBox {
+    pub y: f32,
+    pub height: f32,
+    pub fragments: Vec<Fragment>,
+}
+
+/// A piece of inline content placed on a line
+#[derive(Debug)]
+pub struct Fragment {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub kind: FragmentKind,
+    /// Styles of the inline box the fragment came from
+    pub styles: LayoutStyles,
+}
+
+/// Fragment contents
+#[derive(Debug)]
+pub enum FragmentKind {
+    Text(String),
+    /// An inline-block or image, already laid out at its place on the line
+    Atomic(Box<LayoutBox>),
+}
+
 /// Edge values (padding, border, margin)
 #[derive(Debug, Clone, Copy)]
 pub struct Edge {
@@ -76,6 +153,11 @@ impl Edge {
         }
     }
 
+    /// Only the top and bottom edges set
+    fn vertical_only(value: f32) -> Self {
+        Self { top: value, bottom: value, ..Self::new() }
+    }
+
     pub fn horizontal(&self) -> f32 {
         self.left + self.right
     }
@@ -85,15 +167,49 @@ impl Edge {
     }
 }
 
+/// A width or height given by the page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Px(f32),
+    /// Percentage of the containing block's width
+    Percent(f32),
+}
+
 /// Layout styles
-#[derive(Debug, Clone)]
+///
+/// `color`, the font properties, `text_align`, `white_space` and
+/// `underline` are inherited; the rest start from their initial values on
+/// every element.
+#[derive(Debug, Clone, Copy)]
 pub struct LayoutStyles {
     pub display: BoxType,
     pub background_color: Option<Color>,
-    pub color: Option<Color>,
+    pub color: Color,
     pub font_size: f32,
     pub font_weight: FontWeight,
     pub text_align: TextAlign,
+    pub white_space: WhiteSpace,
+    pub underline: bool,
+    pub margin: Edge,
+    pub padding: Edge,
+    pub border: Edge,
+    pub border_color: Color,
+    /// `margin-left` and `margin-right` are both `auto`
+    pub center: bool,
+    pub width: Option<Size>,
+    pub height: Option<f32>,
+    /// Index in `LayoutTree::links` of the link the box is inside
+    pub link: Option<u16>,
+    /// Index in `LayoutTree::anchors` of the nearest element with an ID
+    /// the box is inside
+    pub anchor: Option<u16>,
+    /// Number of the form control the box is, or is inside
+    pub control: Option<u16>,
+    /// The box is a text field or text area, which shows a caret when it
+    /// has the focus
+    pub editable: bool,
+    /// Node ID of the element the box is, or is inside; 0 for none
+    pub node: u32,
 }
 
 impl LayoutStyles {
@@ -101,16 +217,47 @@ impl LayoutStyles {
         Self {
             display: BoxType::Block,
             background_color: None,
-            color: Some(Color { r: 0, g: 0, b: 0 }),
-            font_size: 16.0,
+            color: Color::black(),
+            font_size: BASE_FONT_SIZE,
             font_weight: FontWeight::Normal,
             text_align: TextAlign::Left,
+            white_space: WhiteSpace::Normal,
+            underline: false,
+            margin: Edge::new(),
+            padding: Edge::new(),
+            border: Edge::new(),
+            border_color: Color::black(),
+            center: false,
+            width: None,
+            height: None,
+            link: None,
+            anchor: None,
+            control: None,
+            editable: false,
+            node: 0,
+        }
+    }
+
+    /// Styles a child starts from: the inherited properties of `self`
+    fn inherit(&self) -> Self {
+        Self {
+            color: self.color,
+            font_size: self.font_size,
+            font_weight: self.font_weight,
+            text_align: self.text_align,
+            white_space: self.white_space,
+            underline: self.underline,
+            link: self.link,
+            anchor: self.anchor,
+            control: self.control,
+            node: self.node,
+            ..Self::default()
         }
     }
 }
 
 /// Color
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -129,6 +276,10 @@ impl Color {
     pub fn gray() -> Self {
         Self { r: 128, g: 128, b: 128 }
     }
+
+    const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
 }
 
 /// Font weight
@@ -147,266 +298,1186 @@ pub enum TextAlign {
     Justify,
 }
 
+/// How white space in text is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteSpace {
+    /// Runs of white space collapse to one space; lines wrap
+    Normal,
+    /// Collapse, but never wrap
+    NoWrap,
+    /// Keep white space and newlines as written; never wrap
+    Pre,
+}
+
 /// Layout tree
 pub struct LayoutTree {
     pub root: LayoutBox,
     pub viewport_width: f32,
     pub viewport_height: f32,
+    /// `href` of each link, as written
+    pub links: Vec<String>,
+    /// Names fragments can target: element IDs and `<a name>`s
+    pub anchors: Vec<String>,
+    /// Number of the form control with the keyboard focus
+    pub focus: Option<u16>,
+}
+
+impl LayoutTree {
+    /// Height of the laid out page, which may exceed the viewport
+    pub fn page_height(&self) -> f32 {
+        self.root.y + self.root.height + self.root.margin.bottom
+    }
+
+    /// `href` of the link at (x, y) in page coordinates, if any
+    pub fn link_at(&self, x: f32, y: f32) -> Option<&str> {
+        hit(&self.root, x, y, &|s| s.link).map(|i| self.links[i as usize].as_str())
+    }
+
+    /// Number of the form control at (x, y) in page coordinates, if any
+    pub fn control_at(&self, x: f32, y: f32) -> Option<u16> {
+        hit(&self.root, x, y, &|s| s.control)
+    }
+
+    /// The box of form control `index`
+    pub fn control_box(&self, index: u16) -> Option<&LayoutBox> {
+        box_in(&self.root, &|s| s.control == Some(index))
+    }
+
+    /// Node ID of the innermost element at (x, y) in page coordinates
+    pub fn element_at(&self, x: f32, y: f32) -> Option<u32> {
+        hit(&self.root, x, y, &|s| Some(s.node).filter(|&n| n != 0))
+    }
+
+    /// The outermost box of the element with node ID `node`, if it is
+    /// displayed
+    ///
+    /// The box of an inline element has no position of its own; its
+    /// content is placed in fragments on the lines around it.
+    pub fn element_box(&self, node: u32) -> Option<&LayoutBox> {
+        box_in(&self.root, &|s| s.node == node)
+    }
+
+    /// Border box of the element with node ID `node` as x, y, width and
+    /// height in page coordinates; for an inline element, the smallest
+    /// rectangle around its fragments
+    pub fn element_bounds(&self, node: u32) -> Option<(f32, f32, f32, f32)> {
+        if let Some(b) = self.element_box(node) {
+            return Some((b.x, b.y, b.width, b.height));
+        }
+        let mut corners = None;
+        fragments_of(&self.root, node, &mut corners);
+        corners.map(|(left, top, right, bottom)| (left, top, right - left, bottom - top))
+    }
+
+    /// Top of the element a fragment such as `#intro` targets
+    pub fn anchor_y(&self, name: &str) -> Option<f32> {
+        let index = self.anchors.iter().position(|a| a == name)? as u16;
+        anchor_in(&self.root, index)
+    }
+}
+
+/// The `key` of the innermost box or fragment at (x, y) inside `b` that
+/// has one, such as its link
+fn hit<T>(b: &LayoutBox, x: f32, y: f32, key: &dyn Fn(&LayoutStyles) -> Option<T>) -> Option<T> {
+    for child in b.children.iter().rev() {
+        if let Some(found) = hit(child, x, y, key) {
+            return Some(found);
+        }
+    }
+    let fragment = b.lines.iter()
+        .filter(|line| y >= line.y && y < line.y + line.height)
+        .flat_map(|line| &line.fragments)
+        .find(|f| x >= f.x && x < f.x + f.width);
+    if let Some(fragment) = fragment {
+        let found = match &fragment.kind {
+            FragmentKind::Atomic(inner) => hit(inner, x, y, key).or(key(&fragment.styles)),
+            FragmentKind::Text(_) => key(&fragment.styles),
+        };
+        if found.is_some() {
+            return found;
+        }
+    }
+    let inside = x >= b.x && x < b.x + b.width && y >= b.y && y < b.y + b.height;
+    if inside { key(&b.styles) } else { None }
+}
+
+/// The outermost box inside `b` whose styles `wanted` accepts
+fn box_in<'a>(b: &'a LayoutBox, wanted: &dyn Fn(&LayoutStyles) -> bool) -> Option<&'a LayoutBox> {
+    if wanted(&b.styles) {
+        return Some(b);
+    }
+    let atomics = b.lines.iter().flat_map(|line| &line.fragments).filter_map(|f| match &f.kind {
+        FragmentKind::Atomic(inner) => Some(&**inner),
+        FragmentKind::Text(_) => None,
+    });
+    b.children.iter().chain(atomics).find_map(|child| box_in(child, wanted))
+}
+
+/// Grow `corners`, as left, top, right and bottom, around the fragments
+/// inside `b` that element `node` is split into
+fn fragments_of(b: &LayoutBox, node: u32, corners: &mut Option<(f32, f32, f32, f32)>) {
+    for child in &b.children {
+        fragments_of(child, node, corners);
+    }
+    for fragment in b.lines.iter().flat_map(|line| &line.fragments) {
+        if fragment.styles.node == node {
+            let (x, y, w, h) = (fragment.x, fragment.y, fragment.width, fragment.height);
+            *corners = Some(match *corners {
+                Some((l, t, r, btm)) => (l.min(x), t.min(y), r.max(x + w), btm.max(y + h)),
+                None => (x, y, x + w, y + h),
+            });
+        }
+        if let FragmentKind::Atomic(inner) = &fragment.kind {
+            fragments_of(inner, node, corners);
+        }
+    }
+}
+
+/// Top of the first box or fragment, in tree order, inside anchor `index`
+fn anchor_in(b: &LayoutBox, index: u16) -> Option<f32> {
+    if b.styles.anchor == Some(index) {
+        return Some(b.y);
+    }
+    for child in &b.children {
+        if let Some(y) = anchor_in(child, index) {
+            return Some(y);
+        }
+    }
+    for fragment in b.lines.iter().flat_map(|line| &line.fragments) {
+        if fragment.styles.anchor == Some(index) {
+            return Some(fragment.y);
+        }
+        if let FragmentKind::Atomic(inner) = &fragment.kind {
+            if let Some(y) = anchor_in(inner, index) {
+                return Some(y);
+            }
+        }
+    }
+    None
+}
+
+/// Size a glyph is drawn at for `font_size`
+pub fn glyph_size(font_size: f32) -> (f32, f32) {
+    let (cell_w, cell_h) = font::cell_size();
+    let scale = (font_size / cell_h as f32).max(1.0);
+    (cell_w as f32 * scale, cell_h as f32 * scale)
 }
 
+/// Height of a line of text at `font_size`
+pub fn line_height(font_size: f32) -> f32 {
+    let (_, h) = glyph_size(font_size);
+    h + h / 4.0
+}
+
+/// Looks up the decoded image for an `<img src>`, if it has loaded
+pub type ImageSource<'a> = &'a dyn Fn(&str) -> Option<Arc<Image>>;
+
 /// Perform layout on document
-pub fn layout(document: &Document, viewport_width: u32, viewport_height: u32) -> Result<LayoutTree, BrowserError> {
-    let mut root_box = build_layout_tree(&document.root)?;
-    
-    // Calculate layout
-    let containing_block = Dimensions {
-        width: viewport_width as f32,
-        height: viewport_height as f32,
-    };
-    
-    calculate_layout(&mut root_box, &containing_block);
-    
+///
+/// Images are sized from `images` where it has their bitmap, and from
+/// their attributes or alt text where it does not. Form controls show
+/// what `forms` holds for them.
+pub fn layout(document: &Document, images: ImageSource, forms: &FormState, viewport_width: u32, viewport_height: u32) -> Result<LayoutTree, BrowserError> {
+    let mut cx = BuildContext { images, forms, links: Vec::new(), anchors: Vec::new(), controls: 0 };
+    let mut root_box = build_layout_tree(&document.root, &mut cx)?;
+    layout_block(&mut root_box, 0.0, 0.0, viewport_width as f32);
+
     Ok(LayoutTree {
         root: root_box,
         viewport_width: viewport_width as f32,
         viewport_height: viewport_height as f32,
+        links: cx.links,
+        anchors: cx.anchors,
+        focus: None,
     })
 }
 
-/// Dimensions for containing block
-struct Dimensions {
-    width: f32,
-    height: f32,
+/// What building the box tree needs besides the elements
+struct BuildContext<'a> {
+    images: ImageSource<'a>,
+    forms: &'a FormState,
+    links: Vec<String>,
+    anchors: Vec<String>,
+    /// Number the next form control gets
+    controls: u16,
+}
+
+/// Add `value` to `table`, returning its index, unless the table is full
+fn intern(table: &mut Vec<String>, value: &str) -> Option<u16> {
+    if let Some(i) = table.iter().position(|v| v == value) {
+        return Some(i as u16);
+    }
+    if table.len() >= u16::MAX as usize {
+        return None;
+    }
+    table.push(String::from(value));
+    Some(table.len() as u16 - 1)
+}
+
+/// Build the box tree for the root element
+fn build_layout_tree(root: &Element, cx: &mut BuildContext) -> Result<LayoutBox, BrowserError> {
+    let mut root_box = build_box(root, &LayoutStyles::default(), false, cx)
+        .unwrap_or_else(|| new_box(BoxType::Block, BoxContent::Element, LayoutStyles::default()));
+    // The root always establishes a block
+    root_box.box_type = BoxType::Block;
+    normalize_children(&mut root_box);
+    Ok(root_box)
 }
 
-/// Build layout tree from DOM element
-fn build_layout_tree(element: &Element) -> Result<LayoutBox, BrowserError> {
-    let box_type = determine_box_type(element);
-    
-    let styles = compute_styles(element);
-    
-    let mut layout_box = LayoutBox {
+fn new_box(box_type: BoxType, content: BoxContent, styles: LayoutStyles) -> LayoutBox {
+    LayoutBox {
         x: 0.0,
         y: 0.0,
         width: 0.0,
         height: 0.0,
-        padding: Edge::new(),
-        border: Edge::new(),
-        margin: Edge::new(),
+        padding: styles.padding,
+        border: styles.border,
+        margin: styles.margin,
         content_width: 0.0,
         content_height: 0.0,
         box_type,
         children: Vec::new(),
-        text: None,
+        content,
+        lines: Vec::new(),
         styles,
-    };
+    }
+}
+
+/// A text box in `parent_styles`, without the parent's box decorations
+fn text_box(text: &str, parent_styles: &LayoutStyles, parent_inline: bool) -> LayoutBox {
+    let mut styles = parent_styles.inherit();
+    styles.display = BoxType::Inline;
+    // Text takes the background of an inline parent (a highlighted span),
+    // while a block paints its own behind the whole box
+    if parent_inline {
+        styles.background_color = parent_styles.background_color;
+    }
+    new_box(BoxType::Inline, BoxContent::Text(String::from(text)), styles)
+}
 
-    // Build children
-    for child in &element.children {
-        match child {
-            Node::Element(elem) => {
-                let child_box = build_layout_tree(elem)?;
-                if child_box.box_type != BoxType::None {
-                    layout_box.children.push(child_box);
+/// Build a box for `element` and its subtree, or `None` if it is not displayed
+fn build_box(element: &Element, parent: &LayoutStyles, parent_inline: bool, cx: &mut BuildContext) -> Option<LayoutBox> {
+    let mut styles = compute_styles(element, parent, parent_inline);
+    styles.node = element.node_id;
+    if styles.display == BoxType::None {
+        // Controls that are not shown keep their numbers
+        cx.controls = cx.controls.saturating_add(forms::count_controls(element));
+        return None;
+    }
+    let tag = element.tag.as_str();
+    let control = ControlKind::of(element).map(|kind| {
+        let index = cx.controls;
+        cx.controls = cx.controls.saturating_add(1);
+        styles.control = Some(index);
+        (index, kind)
+    });
+    if tag == "a" {
+        if let Some(href) = element.get_attr("href") {
+            styles.link = intern(&mut cx.links, href.trim());
+        }
+    }
+    let anchor = element.get_attr("id").or_else(|| if tag == "a" { element.get_attr("name") } else { None });
+    if let Some(name) = anchor.filter(|n| !n.is_empty()) {
+        styles.anchor = intern(&mut cx.anchors, name);
+    }
+
+    let mut content = BoxContent::Element;
+    let mut children = Vec::new();
+    match tag {
+        "br" => content = BoxContent::LineBreak,
+        "img" => {
+            let src = element.get_attr("src").unwrap_or("");
+            let alt = String::from(element.get_attr("alt").unwrap_or(""));
+            let bitmap = if src.is_empty() { None } else { (cx.images)(src) };
+            let attr = |name| element.get_attr(name).and_then(|v| v.trim().parse::<f32>().ok());
+            let width = styles.width.or_else(|| attr("width").map(Size::Px));
+            let height = styles.height.or_else(|| attr("height"));
+            match &bitmap {
+                Some(image) => {
+                    // A loaded image keeps its aspect ratio when only one
+                    // dimension is given, and its own size when neither is
+                    let (iw, ih) = (image.width as f32, image.height as f32);
+                    let (w, h) = match (width, height) {
+                        (None, None) => (Size::Px(iw), ih),
+                        (Some(Size::Px(w)), None) => (Size::Px(w), w * ih / iw),
+                        (None, Some(h)) => (Size::Px(h * iw / ih), h),
+                        (Some(w), None) => (w, ih),
+                        (Some(w), Some(h)) => (w, h),
+                    };
+                    styles.width = Some(w);
+                    styles.height = Some(h);
+                }
+                None => {
+                    // Until the image is fetched its size comes from the
+                    // attributes, or the alt text it shows instead
+                    let (text_w, text_h) = glyph_size(styles.font_size);
+                    let alt_w = alt.chars().count() as f32 * text_w + 8.0;
+                    styles.width = Some(width.unwrap_or(Size::Px(alt_w.max(16.0))));
+                    styles.height = Some(height.unwrap_or((text_h + 8.0).max(16.0)));
                 }
             }
-            Node::Text(text) => {
-                if !text.trim().is_empty() {
-                    let text_box = LayoutBox {
-                        x: 0.0,
-                        y: 0.0,
-                        width: 0.0,
-                        height: 0.0,
-                        padding: Edge::new(),
-                        border: Edge::new(),
-                        margin: Edge::new(),
-                        content_width: 0.0,
-                        content_height: 0.0,
-                        box_type: BoxType::Inline,
-                        children: Vec::new(),
-                        text: Some(text.clone()),
-                        styles: layout_box.styles.clone(),
-                    };
-                    layout_box.children.push(text_box);
+            content = BoxContent::Image { alt, bitmap };
+        }
+        _ => {
+            if let Some((_, kind)) = control {
+                children = control_contents(element, kind, &mut styles, cx)?;
+            }
+        }
+    }
+
+    let is_inline = styles.display == BoxType::Inline;
+    let holds_value = matches!(control, Some((_, ControlKind::Select)) | Some((_, ControlKind::TextArea)));
+    if matches!(content, BoxContent::Element) && children.is_empty() && !holds_value {
+        let mut counter = 0;
+        for child in &element.children {
+            match child {
+                Node::Element(elem) => {
+                    if let Some(mut child_box) = build_box(elem, &styles, is_inline, cx) {
+                        // List items laid out as blocks start with their marker
+                        if elem.tag == "li" && (tag == "ul" || tag == "ol") && child_box.box_type == BoxType::Block {
+                            counter += 1;
+                            let marker = if tag == "ol" { format!("{}. ", counter) } else { String::from("\u{2022} ") };
+                            let marker_box = text_box(&marker, &child_box.styles, false);
+                            child_box.children.insert(0, marker_box);
+                            normalize_children(&mut child_box);
+                        }
+                        children.push(child_box);
+                    }
                 }
+                Node::Text(text) => children.push(text_box(text, &styles, is_inline)),
+                Node::Comment(_) => {}
             }
-            _ => {}
         }
     }
 
-    Ok(layout_box)
-}
+    // An inline element holding blocks is laid out as a block itself
+    let mut box_type = styles.display;
+    if box_type == BoxType::Inline && children.iter().any(|c| c.box_type == BoxType::Block) {
+        box_type = BoxType::Block;
+    }
+    if matches!(content, BoxContent::Image { .. }) {
+        box_type = match box_type {
+            BoxType::Inline => BoxType::InlineBlock,
+            other => other,
+        };
+    }
 
-/// Determine box type from element
-fn determine_box_type(element: &Element) -> BoxType {
-    match element.tag.as_str() {
-        "head" | "script" | "style" | "meta" | "link" => BoxType::None,
-        "span" | "a" | "em" | "strong" | "code" | "b" | "i" | "u" => BoxType::Inline,
-        "img" | "input" | "button" => BoxType::InlineBlock,
-        _ => BoxType::Block,
+    let mut layout_box = new_box(box_type, content, styles);
+    layout_box.children = children;
+    if box_type != BoxType::Inline {
+        normalize_children(&mut layout_box);
     }
+    Some(layout_box)
 }
 
-/// Compute layout styles from element
-fn compute_styles(element: &Element) -> LayoutStyles {
-    let mut styles = LayoutStyles::default();
-
-    // Check for display: none
-    for (prop, val) in &element.computed_styles {
-        match prop.as_str() {
-            "display" => {
-                styles.display = match val.as_str() {
-                    "none" => BoxType::None,
-                    "inline" => BoxType::Inline,
-                    "inline-block" => BoxType::InlineBlock,
-                    _ => BoxType::Block,
+/// The boxes a form control shows: its value, label or chosen option
+///
+/// Returns `None` for a control that is not displayed. A `<button>` shows
+/// its own contents, so gets no boxes here.
+fn control_contents(element: &Element, kind: ControlKind, styles: &mut LayoutStyles, cx: &BuildContext) -> Option<Vec<LayoutBox>> {
+    let mut children = Vec::new();
+    if element.get_attr("disabled").is_some() {
+        styles.color = Color::gray();
+    }
+    match kind {
+        ControlKind::Hidden => return None,
+        ControlKind::Checkbox | ControlKind::Radio => {
+            styles.width = Some(Size::Px(12.0));
+            styles.height = Some(12.0);
+            styles.padding = Edge::new();
+            if cx.forms.checked(element) {
+                styles.background_color = Some(Color::rgb(0x33, 0x66, 0xCC));
+            }
+        }
+        ControlKind::Submit | ControlKind::Reset | ControlKind::Button => {
+            if element.tag == "input" {
+                let default = match kind {
+                    ControlKind::Submit => "Submit",
+                    ControlKind::Reset => "Reset",
+                    _ => "",
                 };
+                let label = element.get_attr("value").unwrap_or(default);
+                children.push(text_box(label, styles, false));
             }
-            "background-color" => {
-                styles.background_color = parse_color(val);
+        }
+        ControlKind::Select => {
+            let options = forms::options(element);
+            let label = cx.forms.selected(element).map(|i| options[i].text_content()).unwrap_or_default();
+            children.push(text_box(label.trim(), styles, false));
+        }
+        ControlKind::Text | ControlKind::Password | ControlKind::TextArea => {
+            let textarea = kind == ControlKind::TextArea;
+            let (advance, _) = glyph_size(styles.font_size);
+            let attr = |name| element.get_attr(name).and_then(|v| v.trim().parse::<f32>().ok()).filter(|&v| v > 0.0);
+            if styles.width.is_none() {
+                let width = if textarea { attr("cols").unwrap_or(TEXTAREA_COLS) * advance } else { INPUT_WIDTH };
+                styles.width = Some(Size::Px(width));
             }
-            "color" => {
-                styles.color = parse_color(val);
+            if styles.height.is_none() {
+                // A field is a line high even while it is empty
+                let rows = if textarea { attr("rows").unwrap_or(TEXTAREA_ROWS) } else { 1.0 };
+                styles.height = Some(rows * line_height(styles.font_size));
             }
-            "font-size" => {
-                if let Some(size) = parse_length(val) {
-                    styles.font_size = size;
-                }
+            styles.editable = true;
+
+            let mut value = cx.forms.text(element);
+            if kind == ControlKind::Password {
+                value = "*".repeat(value.chars().count());
             }
-            "font-weight" => {
-                if val == "bold" || val == "700" {
-                    styles.font_weight = FontWeight::Bold;
-                }
+            let mut text_styles = *styles;
+            text_styles.white_space = WhiteSpace::Pre;
+            if value.is_empty() {
+                // The placeholder is not the control's text, so the caret
+                // goes before it
+                value = String::from(element.get_attr("placeholder").unwrap_or(""));
+                text_styles.color = Color::gray();
+                text_styles.control = None;
             }
-            "text-align" => {
-                styles.text_align = match val.as_str() {
-                    "center" => TextAlign::Center,
-                    "right" => TextAlign::Right,
-                    "justify" => TextAlign::Justify,
-                    _ => TextAlign::Left,
-                };
+            children.push(text_box(&value, &text_styles, false));
+            // A final line break starts a line the caret can go on
+            if textarea && value.ends_with('\n') {
+                children.push(new_box(BoxType::Inline, BoxContent::LineBreak, text_styles));
+            }
+        }
+    }
+    Some(children)
+}
+
+/// Whether a box is text that collapses away between blocks
+fn is_collapsible_space(b: &LayoutBox) -> bool {
+    match &b.content {
+        BoxContent::Text(t) => b.styles.white_space != WhiteSpace::Pre && t.chars().all(char::is_whitespace),
+        _ => false,
+    }
+}
+
+/// Give a block container children of one kind
+///
+/// If any child is a block, runs of inline-level children are wrapped in
+/// anonymous blocks, and runs of only white space are dropped.
+fn normalize_children(b: &mut LayoutBox) {
+    if !b.children.iter().any(|c| c.box_type == BoxType::Block) {
+        return;
+    }
+    let mut anonymous_styles = b.styles.inherit();
+    anonymous_styles.display = BoxType::Block;
+
+    let mut result = Vec::new();
+    let mut run: Vec<LayoutBox> = Vec::new();
+    let flush = |run: &mut Vec<LayoutBox>, result: &mut Vec<LayoutBox>| {
+        if run.iter().all(is_collapsible_space) {
+            run.clear();
+            return;
+        }
+        let mut anonymous = new_box(BoxType::Block, BoxContent::Element, anonymous_styles);
+        anonymous.children = core::mem::take(run);
+        result.push(anonymous);
+    };
+    for child in core::mem::take(&mut b.children) {
+        if child.box_type == BoxType::Block {
+            flush(&mut run, &mut result);
+            result.push(child);
+        } else {
+            run.push(child);
+        }
+    }
+    flush(&mut run, &mut result);
+    b.children = result;
+}
+
+/// Compute layout styles from element
+///
+/// Starts from what `element` inherits from `parent`, applies the default
+/// styles for its tag, then the declarations the CSS engine matched and
+/// finally those in its `style` attribute.
+fn compute_styles(element: &Element, parent: &LayoutStyles, parent_inline: bool) -> LayoutStyles {
+    let mut styles = parent.inherit();
+    styles.display = default_display(&element.tag);
+    apply_tag_defaults(&element.tag, &mut styles);
+
+    for (prop, val) in &element.computed_styles {
+        apply_declaration(&mut styles, prop, val, parent.font_size);
+    }
+    if let Some(style) = element.get_attr("style") {
+        for declaration in style.split(';') {
+            if let Some((prop, val)) = declaration.split_once(':') {
+                let prop = prop.trim().to_ascii_lowercase();
+                apply_declaration(&mut styles, &prop, val.trim(), parent.font_size);
             }
-            _ => {}
         }
     }
 
+    // Nested inline boxes show the highlight of the one around them
+    if parent_inline && styles.display == BoxType::Inline && styles.background_color.is_none() {
+        styles.background_color = parent.background_color;
+    }
     styles
 }
 
-/// Parse color value
-fn parse_color(s: &str) -> Option<Color> {
-    // Named colors
-    match s.to_ascii_lowercase().as_str() {
-        "black" => return Some(Color::black()),
-        "white" => return Some(Color::white()),
-        "gray" | "grey" => return Some(Color::gray()),
-        "red" => return Some(Color { r: 255, g: 0, b: 0 }),
-        "green" => return Some(Color { r: 0, g: 128, b: 0 }),
-        "blue" => return Some(Color { r: 0, g: 0, b: 255 }),
+/// Display type of an element before any CSS applies
+fn default_display(tag: &str) -> BoxType {
+    match tag {
+        "head" | "script" | "style" | "meta" | "link" | "title" | "template" | "noscript"
+        | "base" | "area" | "map" | "param" | "source" | "track" | "datalist" | "option" => BoxType::None,
+        "span" | "a" | "em" | "strong" | "code" | "b" | "i" | "u" | "s" | "small" | "big"
+        | "sub" | "sup" | "abbr" | "cite" | "q" | "mark" | "label" | "font" | "tt" | "kbd"
+        | "var" | "samp" | "dfn" | "ins" | "del" | "strike" | "time" | "bdi" | "bdo" | "br" | "img" => BoxType::Inline,
+        "input" | "button" | "select" | "textarea" | "td" | "th" => BoxType::InlineBlock,
+        _ => BoxType::Block,
+    }
+}
+
+/// Default styles browsers give each element
+fn apply_tag_defaults(tag: &str, s: &mut LayoutStyles) {
+    let em = s.font_size;
+    match tag {
+        "body" => s.margin = Edge::uniform(8.0),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let (size, margin) = match tag {
+                "h1" => (2.0, 0.67),
+                "h2" => (1.5, 0.83),
+                "h3" => (1.17, 1.0),
+                "h4" => (1.0, 1.33),
+                "h5" => (0.83, 1.67),
+                _ => (0.67, 2.33),
+            };
+            s.font_size = em * size;
+            s.font_weight = FontWeight::Bold;
+            s.margin = Edge::vertical_only(s.font_size * margin);
+        }
+        "p" | "dl" | "figure" => s.margin = Edge::vertical_only(em),
+        "blockquote" => s.margin = Edge { top: em, right: 40.0, bottom: em, left: 40.0 },
+        "ul" | "ol" => {
+            s.margin = Edge::vertical_only(em);
+            s.padding.left = 40.0;
+        }
+        "dd" => s.margin.left = 40.0,
+        "pre" | "listing" | "xmp" | "plaintext" => {
+            s.white_space = WhiteSpace::Pre;
+            s.margin = Edge::vertical_only(em);
+        }
+        "textarea" => {
+            s.white_space = WhiteSpace::Pre;
+            s.border = Edge::uniform(1.0);
+            s.border_color = Color::gray();
+            s.padding = Edge::uniform(2.0);
+            s.background_color = Some(Color::white());
+        }
+        "hr" => {
+            s.border = Edge::uniform(1.0);
+            s.border_color = Color::gray();
+            s.margin = Edge::vertical_only(em / 2.0);
+        }
+        "b" | "strong" => s.font_weight = FontWeight::Bold,
+        "a" => {
+            s.color = Color::rgb(0x00, 0x00, 0xEE);
+            s.underline = true;
+        }
+        "u" | "ins" => s.underline = true,
+        "mark" => s.background_color = Some(Color::rgb(0xFF, 0xFF, 0x00)),
+        "center" => s.text_align = TextAlign::Center,
+        "nobr" => s.white_space = WhiteSpace::NoWrap,
+        "td" => s.padding = Edge::uniform(2.0),
+        "th" => {
+            s.padding = Edge::uniform(2.0);
+            s.font_weight = FontWeight::Bold;
+            s.text_align = TextAlign::Center;
+        }
+        "input" | "button" | "select" => {
+            s.border = Edge::uniform(1.0);
+            s.border_color = Color::gray();
+            s.padding = Edge { top: 2.0, right: 6.0, bottom: 2.0, left: 6.0 };
+            s.background_color = Some(if tag == "button" { Color::rgb(0xEE, 0xEE, 0xEE) } else { Color::white() });
+            s.white_space = WhiteSpace::NoWrap;
+        }
         _ => {}
     }
+}
 
-    // Hex colors
-    if s.starts_with('#') {
-        let hex = &s[1..];
-        if hex.len() == 6 {
-            if let (Ok(r), Ok(g), Ok(b)) = (
-                u8::from_str_radix(&hex[0..2], 16),
-                u8::from_str_radix(&hex[2..4], 16),
-                u8::from_str_radix(&hex[4..6], 16),
-            ) {
-                return Some(Color { r, g, b });
+/// Apply one `property: value` declaration
+///
+/// `parent_font_size` is what `em` means for `font-size` itself; every
+/// other length is relative to the element's own font size.
+fn apply_declaration(s: &mut LayoutStyles, prop: &str, val: &str, parent_font_size: f32) {
+    let val = val.trim().trim_end_matches("!important").trim();
+    let em = s.font_size;
+    match prop {
+        "display" => {
+            s.display = match val {
+                "none" => BoxType::None,
+                "inline" => BoxType::Inline,
+                "inline-block" => BoxType::InlineBlock,
+                _ => BoxType::Block,
+            };
+        }
+        "background-color" | "background" => {
+            // The shorthand may list an image or position too; use its color
+            if let Some(color) = val.split_whitespace().find_map(parse_color) {
+                s.background_color = Some(color);
+            } else if val == "none" || val == "transparent" {
+                s.background_color = None;
+            }
+        }
+        "color" => {
+            if let Some(color) = parse_color(val) {
+                s.color = color;
             }
         }
+        "font-size" => {
+            s.font_size = match val {
+                "small" => 13.0,
+                "medium" => BASE_FONT_SIZE,
+                "large" => 18.0,
+                "x-large" => 24.0,
+                "xx-large" => 32.0,
+                "smaller" => parent_font_size / 1.2,
+                "larger" => parent_font_size * 1.2,
+                _ => match val.strip_suffix('%').and_then(|p| p.trim().parse::<f32>().ok()) {
+                    Some(p) => parent_font_size * p / 100.0,
+                    None => parse_length(val, parent_font_size).unwrap_or(s.font_size),
+                },
+            };
+        }
+        "font-weight" => {
+            s.font_weight = match val {
+                "bold" | "bolder" | "600" | "700" | "800" | "900" => FontWeight::Bold,
+                _ => FontWeight::Normal,
+            };
+        }
+        "font" => {
+            for part in val.split_whitespace() {
+                let size = part.split('/').next().unwrap_or(part);
+                if part == "bold" {
+                    s.font_weight = FontWeight::Bold;
+                } else if size.ends_with(|c: char| c.is_ascii_alphabetic()) {
+                    if let Some(size) = parse_length(size, parent_font_size) {
+                        s.font_size = size;
+                    }
+                }
+            }
+        }
+        "text-align" => {
+            s.text_align = match val {
+                "center" => TextAlign::Center,
+                "right" | "end" => TextAlign::Right,
+                "justify" => TextAlign::Justify,
+                _ => TextAlign::Left,
+            };
+        }
+        "text-decoration" | "text-decoration-line" => s.underline = val.contains("underline"),
+        "white-space" => {
+            s.white_space = match val {
+                "pre" | "pre-wrap" | "break-spaces" => WhiteSpace::Pre,
+                "nowrap" => WhiteSpace::NoWrap,
+                _ => WhiteSpace::Normal,
+            };
+        }
+        "width" => {
+            s.width = match val.strip_suffix('%').and_then(|p| p.trim().parse::<f32>().ok()) {
+                Some(p) => Some(Size::Percent(p)),
+                None => parse_length(val, em).map(Size::Px),
+            };
+        }
+        "height" => s.height = parse_length(val, em),
+        "margin" => {
+            let parts: Vec<&str> = val.split_whitespace().collect();
+            s.margin = parse_edges(&parts, em);
+            let horizontal = match parts.len() {
+                1 => [parts[0], parts[0]],
+                2 | 3 => [parts[1], parts[1]],
+                4 => [parts[1], parts[3]],
+                _ => ["", ""],
+            };
+            s.center = horizontal == ["auto", "auto"];
+        }
+        "padding" => {
+            let parts: Vec<&str> = val.split_whitespace().collect();
+            s.padding = parse_edges(&parts, em);
+        }
+        "margin-top" => s.margin.top = parse_length(val, em).unwrap_or(0.0),
+        "margin-right" => s.margin.right = parse_length(val, em).unwrap_or(0.0),
+        "margin-bottom" => s.margin.bottom = parse_length(val, em).unwrap_or(0.0),
+        "margin-left" => s.margin.left = parse_length(val, em).unwrap_or(0.0),
+        "padding-top" => s.padding.top = parse_length(val, em).unwrap_or(0.0),
+        "padding-right" => s.padding.right = parse_length(val, em).unwrap_or(0.0),
+        "padding-bottom" => s.padding.bottom = parse_length(val, em).unwrap_or(0.0),
+        "padding-left" => s.padding.left = parse_length(val, em).unwrap_or(0.0),
+        "border" | "border-top" | "border-right" | "border-bottom" | "border-left" => {
+            let (width, color) = parse_border(val);
+            if let Some(color) = color {
+                s.border_color = color;
+            }
+            match prop {
+                "border-top" => s.border.top = width,
+                "border-right" => s.border.right = width,
+                "border-bottom" => s.border.bottom = width,
+                "border-left" => s.border.left = width,
+                _ => s.border = Edge::uniform(width),
+            }
+        }
+        "border-width" => {
+            let parts: Vec<&str> = val.split_whitespace().collect();
+            s.border = parse_edges(&parts, em);
+        }
+        "border-color" => {
+            if let Some(color) = parse_color(val) {
+                s.border_color = color;
+            }
+        }
+        _ => {}
     }
+}
 
-    None
+/// Parse a `border` shorthand into its width and color
+fn parse_border(val: &str) -> (f32, Option<Color>) {
+    let mut width = None;
+    let mut color = None;
+    let mut visible = false;
+    for part in val.split_whitespace() {
+        match part {
+            "none" | "hidden" => return (0.0, None),
+            "thin" => width = Some(1.0),
+            "medium" => width = Some(3.0),
+            "thick" => width = Some(5.0),
+            "solid" | "dashed" | "dotted" | "double" | "groove" | "ridge" | "inset" | "outset" => visible = true,
+            _ => {
+                if let Some(w) = parse_length(part, BASE_FONT_SIZE) {
+                    width = Some(w);
+                } else if let Some(c) = parse_color(part) {
+                    color = Some(c);
+                }
+            }
+        }
+    }
+    // A border with no style set draws nothing
+    (if visible { width.unwrap_or(3.0) } else { 0.0 }, color)
 }
 
-/// Parse length value
-fn parse_length(s: &str) -> Option<f32> {
-    if s.ends_with("px") {
-        s[..s.len()-2].parse().ok()
-    } else if s.ends_with("em") {
-        s[..s.len()-2].parse::<f32>().map(|v| v * 16.0).ok()
-    } else if s.ends_with("rem") {
-        s[..s.len()-3].parse::<f32>().map(|v| v * 16.0).ok()
-    } else if s.ends_with("pt") {
-        s[..s.len()-2].parse::<f32>().map(|v| v * 1.33).ok()
+/// Parse the 1-4 values of a `margin`, `padding` or `border-width` shorthand
+fn parse_edges(parts: &[&str], em: f32) -> Edge {
+    let v = |i: usize| parse_length(parts[i], em).unwrap_or(0.0);
+    match parts.len() {
+        1 => Edge::uniform(v(0)),
+        2 => Edge { top: v(0), right: v(1), bottom: v(0), left: v(1) },
+        3 => Edge { top: v(0), right: v(1), bottom: v(2), left: v(1) },
+        4 => Edge { top: v(0), right: v(1), bottom: v(2), left: v(3) },
+        _ => Edge::new(),
+    }
+}
+
+/// Parse color value
+fn parse_color(s: &str) -> Option<Color> {
+    crate::browser::css::Color::parse(s)
+        .filter(|c| c.a > 0)
+        .map(|c| Color { r: c.r, g: c.g, b: c.b })
+}
+
+/// Parse length value; `em` is the size one `em` stands for
+fn parse_length(s: &str, em: f32) -> Option<f32> {
+    let s = s.trim();
+    if let Some(v) = s.strip_suffix("px") {
+        v.parse().ok()
+    } else if let Some(v) = s.strip_suffix("rem") {
+        v.parse::<f32>().map(|v| v * BASE_FONT_SIZE).ok()
+    } else if let Some(v) = s.strip_suffix("em") {
+        v.parse::<f32>().map(|v| v * em).ok()
+    } else if let Some(v) = s.strip_suffix("pt") {
+        v.parse::<f32>().map(|v| v * 4.0 / 3.0).ok()
     } else {
         s.parse().ok()
     }
 }
 
-/// Calculate layout dimensions
-fn calculate_layout(layout_box: &mut LayoutBox, containing_block: &Dimensions) {
-    match layout_box.box_type {
-        BoxType::Block => calculate_block_layout(layout_box, containing_block),
-        BoxType::Inline => calculate_inline_layout(layout_box, containing_block),
-        BoxType::InlineBlock => calculate_inline_block_layout(layout_box, containing_block),
-        BoxType::None => {}
+/// Lay out a block-level box with the top left of its margin box at (x, y)
+fn layout_block(b: &mut LayoutBox, x: f32, y: f32, containing_width: f32) {
+    let edges = b.border.horizontal() + b.padding.horizontal();
+    let content_width = match b.styles.width {
+        Some(Size::Px(w)) => w,
+        Some(Size::Percent(p)) => containing_width * p / 100.0,
+        None => containing_width - b.margin.horizontal() - edges,
+    }
+    .max(0.0);
+    if b.styles.center && b.styles.width.is_some() {
+        let free = (containing_width - content_width - edges).max(0.0);
+        b.margin.left = free / 2.0;
+        b.margin.right = free / 2.0;
     }
-}
 
-/// Calculate block-level layout
-fn calculate_block_layout(layout_box: &mut LayoutBox, containing_block: &Dimensions) {
-    // Calculate width
-    layout_box.width = containing_block.width;
-    layout_box.content_width = layout_box.width - layout_box.padding.horizontal() - layout_box.border.horizontal() - layout_box.margin.horizontal();
+    b.x = x + b.margin.left;
+    b.y = y + b.margin.top;
+    let content_x = b.x + b.border.left + b.padding.left;
+    let content_y = b.y + b.border.top + b.padding.top;
 
-    // Calculate children
-    let mut current_y = layout_box.padding.top + layout_box.border.top + layout_box.margin.top;
-    
-    for child in &mut layout_box.children {
-        child.x = layout_box.padding.left + layout_box.border.left;
-        child.y = current_y;
-        
-        let child_containing = Dimensions {
-            width: layout_box.content_width,
-            height: containing_block.height,
-        };
-        calculate_layout(child, &child_containing);
-        
-        current_y += child.height;
-    }
-
-    // Calculate height
-    layout_box.content_height = current_y;
-    layout_box.height = layout_box.content_height + layout_box.padding.vertical() + layout_box.border.vertical() + layout_box.margin.vertical();
-}
-
-/// Calculate inline layout
-fn calculate_inline_layout(layout_box: &mut LayoutBox, containing_block: &Dimensions) {
-    // Simple inline layout - just estimate text size
-    if let Some(ref text) = layout_box.text {
-        // Rough estimate: 8 pixels per character
-        let char_width = layout_box.styles.font_size * 0.6;
-        let char_height = layout_box.styles.font_size * 1.2;
-        
-        layout_box.content_width = text.len() as f32 * char_width;
-        layout_box.content_height = char_height;
+    let content_height = if b.children.iter().any(|c| c.box_type == BoxType::Block) {
+        // Block formatting context: stack the children, collapsing the
+        // bottom margin of each with the top margin of the next
+        let mut cursor = content_y;
+        let mut previous_margin = 0.0f32;
+        for child in &mut b.children {
+            let overlap = previous_margin.min(child.margin.top);
+            layout_block(child, content_x, cursor - overlap, content_width);
+            cursor = child.y + child.height + child.margin.bottom;
+            previous_margin = child.margin.bottom;
+        }
+        cursor - content_y
     } else {
-        layout_box.content_width = 0.0;
-        layout_box.content_height = layout_box.styles.font_size;
+        let children = core::mem::take(&mut b.children);
+        let mut lines = LineBuilder::new(content_x, content_y, content_width, &b.styles);
+        for child in children {
+            lines.flow(child);
+        }
+        let (lines, height) = lines.finish();
+        b.lines = lines;
+        height
+    };
+
+    b.content_width = content_width;
+    b.content_height = b.styles.height.unwrap_or(content_height);
+    b.width = content_width + edges;
+    b.height = b.content_height + b.border.vertical() + b.padding.vertical();
+}
+
+/// Lay out an inline-block or image at the origin, at most `available` wide
+///
+/// Without a set width the box shrinks to fit its content.
+fn layout_atomic(b: &mut LayoutBox, available: f32) {
+    if b.styles.width.is_none() {
+        let edges = b.margin.horizontal() + b.border.horizontal() + b.padding.horizontal();
+        let fit = max_content_width(b).min((available - edges).max(0.0));
+        b.styles.width = Some(Size::Px(fit));
+    }
+    layout_block(b, 0.0, 0.0, available);
+}
+
+/// Width of a box's content laid out without any line breaks
+fn max_content_width(b: &LayoutBox) -> f32 {
+    if let Some(Size::Px(w)) = b.styles.width {
+        return w;
+    }
+    let own = |c: &LayoutBox| c.margin.horizontal() + c.border.horizontal() + c.padding.horizontal();
+    if b.children.iter().any(|c| c.box_type == BoxType::Block) {
+        return b.children.iter().map(|c| max_content_width(c) + own(c)).fold(0.0, f32::max);
+    }
+    // Inline content: the widest of the lines between forced breaks
+    let mut widest = 0.0f32;
+    let mut line = 0.0f32;
+    fn walk(b: &LayoutBox, line: &mut f32, widest: &mut f32) {
+        for c in &b.children {
+            match &c.content {
+                BoxContent::Text(t) => {
+                    let (advance, _) = glyph_size(c.styles.font_size);
+                    if c.styles.white_space == WhiteSpace::Pre {
+                        let mut parts = t.split('\n');
+                        if let Some(first) = parts.next() {
+                            *line += first.chars().count() as f32 * advance;
+                        }
+                        for part in parts {
+                            *widest = widest.max(*line);
+                            *line = part.chars().count() as f32 * advance;
+                        }
+                    } else {
+                        *line += collapse_spaces(t).chars().count() as f32 * advance;
+                    }
+                }
+                BoxContent::LineBreak => {
+                    *widest = widest.max(*line);
+                    *line = 0.0;
+                }
+                _ if c.box_type == BoxType::Inline => walk(c, line, widest),
+                _ => {
+                    *line += max_content_width(c) + c.margin.horizontal() + c.border.horizontal() + c.padding.horizontal();
+                }
+            }
+        }
     }
+    walk(b, &mut line, &mut widest);
+    widest.max(line)
+}
 
-    layout_box.width = layout_box.content_width + layout_box.padding.horizontal() + layout_box.border.horizontal();
-    layout_box.height = layout_box.content_height + layout_box.padding.vertical() + layout_box.border.vertical();
+/// Text with each run of white space collapsed to a single space
+fn collapse_spaces(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut space = false;
+    for ch in text.chars() {
+        if ch.is_whitespace() {
+            space = true;
+        } else {
+            if space && !out.is_empty() {
+                out.push(' ');
+            }
+            space = false;
+            out.push(ch);
+        }
+    }
+    if space {
+        out.push(' ');
+    }
+    out
 }
 
-/// Calculate inline-block layout
-fn calculate_inline_block_layout(layout_box: &mut LayoutBox, containing_block: &Dimensions) {
-    // Similar to block but with natural width
-    if layout_box.width == 0.0 {
-        layout_box.width = 100.0; // Default width
+/// Move a laid out box and everything in it by (dx, dy)
+fn translate(b: &mut LayoutBox, dx: f32, dy: f32) {
+    b.x += dx;
+    b.y += dy;
+    for child in &mut b.children {
+        translate(child, dx, dy);
+    }
+    for line in &mut b.lines {
+        line.y += dy;
+        for fragment in &mut line.fragments {
+            fragment.x += dx;
+            fragment.y += dy;
+            if let FragmentKind::Atomic(inner) = &mut fragment.kind {
+                translate(inner, dx, dy);
+            }
+        }
+    }
+}
+
+/// Breaks the inline content of one block into lines
+struct LineBuilder {
+    /// Content box of the block
+    left: f32,
+    top: f32,
+    width: f32,
+    align: TextAlign,
+    /// Height of an empty line, from the block's font
+    strut: f32,
+    lines: Vec<LineBox>,
+    /// Fragments of the line being filled, positioned relative to it
+    current: Vec<Fragment>,
+    /// Pen position along the current line
+    x: f32,
+    /// Top of the current line, relative to `top`
+    y: f32,
+    /// Collapsed white space is waiting to be placed before the next word
+    space: bool,
+    /// `current` ends with a text fragment that more text may join
+    open: bool,
+}
+
+impl LineBuilder {
+    fn new(left: f32, top: f32, width: f32, styles: &LayoutStyles) -> Self {
+        Self {
+            left,
+            top,
+            width,
+            align: styles.text_align,
+            strut: line_height(styles.font_size),
+            lines: Vec::new(),
+            current: Vec::new(),
+            x: 0.0,
+            y: 0.0,
+            space: false,
+            open: false,
+        }
+    }
+
+    /// Place an inline-level box and its descendants
+    fn flow(&mut self, mut b: LayoutBox) {
+        match core::mem::replace(&mut b.content, BoxContent::Element) {
+            BoxContent::Text(text) => {
+                self.open = false;
+                match b.styles.white_space {
+                    WhiteSpace::Pre => self.pre_text(&text, &b.styles),
+                    WhiteSpace::NoWrap => self.text(&text, &b.styles, false),
+                    WhiteSpace::Normal => self.text(&text, &b.styles, true),
+                }
+            }
+            BoxContent::LineBreak => {
+                if self.current.is_empty() {
+                    // An empty line is as tall as the text around it
+                    self.strut = self.strut.max(line_height(b.styles.font_size));
+                }
+                self.break_line();
+            }
+            content if b.box_type == BoxType::Inline => {
+                b.content = content;
+                for child in core::mem::take(&mut b.children) {
+                    self.flow(child);
+                }
+            }
+            content => {
+                b.content = content;
+                self.atomic(b);
+            }
+        }
+    }
+
+    /// Place white-space-collapsing text, wrapping at spaces if `wrap`
+    fn text(&mut self, text: &str, styles: &LayoutStyles, wrap: bool) {
+        let (advance, _) = glyph_size(styles.font_size);
+        let height = line_height(styles.font_size);
+        for (i, word) in text.split(|c: char| c.is_whitespace()).enumerate() {
+            if i > 0 {
+                self.space = true;
+            }
+            if word.is_empty() {
+                continue;
+            }
+            let mut word_width = word.chars().count() as f32 * advance;
+            let space_width = if self.space && !self.current.is_empty() { advance } else { 0.0 };
+            if wrap && !self.current.is_empty() && self.x + space_width + word_width > self.width {
+                self.break_line();
+            }
+            if self.space && !self.current.is_empty() {
+                self.x += advance;
+                if self.open {
+                    if let Some(Fragment { kind: FragmentKind::Text(t), width, .. }) = self.current.last_mut() {
+                        t.push(' ');
+                        *width += advance;
+                    }
+                }
+            }
+            self.space = false;
+
+            let mut word = word;
+            // A word longer than a whole line is split where it overflows
+            while wrap && word_width > self.width - self.x && self.width >= advance {
+                let fit = ((self.width - self.x) / advance) as usize;
+                if fit == 0 {
+                    self.break_line();
+                    continue;
+                }
+                let split = word.char_indices().nth(fit).map_or(word.len(), |(i, _)| i);
+                self.push_text(&word[..split], fit as f32 * advance, height, styles);
+                self.break_line();
+                word = &word[split..];
+                word_width = word.chars().count() as f32 * advance;
+            }
+            if !word.is_empty() {
+                self.push_text(word, word_width, height, styles);
+            }
+        }
+        // Trailing white space carries over to the next inline box
+        if text.ends_with(|c: char| c.is_whitespace()) {
+            self.space = true;
+        }
+    }
+
+    /// Place preformatted text: every space kept, lines broken only at newlines
+    fn pre_text(&mut self, text: &str, styles: &LayoutStyles) {
+        let (advance, _) = glyph_size(styles.font_size);
+        let height = line_height(styles.font_size);
+        self.space = false;
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.break_line();
+                self.strut = self.strut.max(height);
+            }
+            let mut expanded = String::with_capacity(line.len());
+            let mut column = (self.x / advance) as usize;
+            for ch in line.chars() {
+                if ch == '\t' {
+                    let stop = (column / 8 + 1) * 8;
+                    for _ in column..stop {
+                        expanded.push(' ');
+                    }
+                    column = stop;
+                } else {
+                    expanded.push(ch);
+                    column += 1;
+                }
+            }
+            if !expanded.is_empty() {
+                let width = expanded.chars().count() as f32 * advance;
+                self.push_text(&expanded, width, height, styles);
+            }
+        }
+    }
+
+    /// Add text to the current line, joining the open fragment if there is one
+    fn push_text(&mut self, text: &str, width: f32, height: f32, styles: &LayoutStyles) {
+        if self.open {
+            if let Some(Fragment { kind: FragmentKind::Text(t), width: w, .. }) = self.current.last_mut() {
+                t.push_str(text);
+                *w += width;
+                self.x += width;
+                return;
+            }
+        }
+        self.current.push(Fragment {
+            x: self.x,
+            y: 0.0,
+            width,
+            height,
+            kind: FragmentKind::Text(String::from(text)),
+            styles: *styles,
+        });
+        self.x += width;
+        self.open = true;
+    }
+
+    /// Place an inline-block or image
+    fn atomic(&mut self, mut b: LayoutBox) {
+        layout_atomic(&mut b, self.width);
+        let width = b.width + b.margin.horizontal();
+        let height = b.height + b.margin.vertical();
+        if self.space && !self.current.is_empty() {
+            self.x += glyph_size(b.styles.font_size).0;
+        }
+        self.space = false;
+        if !self.current.is_empty() && self.x + width > self.width {
+            self.break_line();
+        }
+        let styles = b.styles;
+        self.current.push(Fragment {
+            x: self.x,
+            y: 0.0,
+            width,
+            height,
+            kind: FragmentKind::Atomic(Box::new(b)),
+            styles,
+        });
+        self.x += width;
+        self.open = false;
+    }
+
+    /// End the current line and start the next
+    fn break_line(&mut self) {
+        let mut fragments = core::mem::take(&mut self.current);
+        // Collapsed white space never ends a line
+        if let Some(Fragment { kind: FragmentKind::Text(t), width, styles, .. }) = fragments.last_mut() {
+            if styles.white_space != WhiteSpace::Pre && t.ends_with(' ') {
+                t.pop();
+                *width -= glyph_size(styles.font_size).0;
+            }
+        }
+        let height = fragments.iter().map(|f| f.height).fold(self.strut, f32::max);
+        let used = fragments.last().map_or(0.0, |f| f.x + f.width);
+        let shift = match self.align {
+            TextAlign::Center => ((self.width - used) / 2.0).max(0.0),
+            TextAlign::Right => (self.width - used).max(0.0),
+            TextAlign::Left | TextAlign::Justify => 0.0,
+        };
+        let line_top = self.top + self.y;
+        for fragment in &mut fragments {
+            // Everything on a line shares its bottom edge
+            let dx = self.left + shift + fragment.x;
+            let dy = line_top + height - fragment.height;
+            fragment.x = dx;
+            fragment.y = dy;
+            if let FragmentKind::Atomic(inner) = &mut fragment.kind {
+                translate(inner, dx, dy);
+            }
+        }
+        self.lines.push(LineBox { y: line_top, height, fragments });
+        self.y += height;
+        self.x = 0.0;
+        self.space = false;
+        self.open = false;
+    }
+
+    /// Close the last line; returns the lines and their total height
+    fn finish(mut self) -> (Vec<LineBox>, f32) {
+        if !self.current.is_empty() {
+            self.break_line();
+        }
+        (self.lines, self.y)
     }
-    
-    layout_box.content_width = layout_box.width - layout_box.padding.horizontal() - layout_box.border.horizontal();
-    layout_box.content_height = layout_box.styles.font_size * 1.2;
-    layout_box.height = layout_box.content_height + layout_box.padding.vertical() + layout_box.border.vertical();
 }
 
 /// Initialize layout engine
 pub fn init() {
-    println!("[layout] Layout engine initialized");
+    info!("layout", "Layout engine initialized");
 }
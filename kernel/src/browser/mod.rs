@@ -2,10 +2,36 @@
 //!
 //! A lightweight web browser engine for WebbOS.
 //! Supports HTML, CSS, JavaScript, and WebAssembly.
+//!
+//! Each tab is a `Browser` of its own, with its page, scroll position and
+//! history, kept in a table by `TabId`; the Browser app opens as many as
+//! the user asks for and drives them with `with_tab`. A page from a server is parsed
+//! as it arrives, by `load_next_chunk`, and what has come so far is laid
+//! out and rendered every so often until the rest is in. Clicks and keys on the page go
+//! to `click` and `key`, which follow links and operate form controls,
+//! submitting forms by GET or POST. A response that is not a page goes to
+//! `downloads`, and the tab goes back to the page it was on.
+//!
+//! Pages come from servers over `http` and `https`, from files with
+//! `file`, which also shows text and directories (see `files`), and from
+//! the kernel itself with `about`, whose pages report on the system (see
+//! `about`).
+//!
+//! A page's scripts run in an interpreter that lives as long as the page.
+//! Clicks and keys are dispatched to its listeners before the browser
+//! acts on them, and `run_timers` runs its timers; whenever a script has
+//! changed the page, it is styled, laid out and rendered again. Requests
+//! its scripts make are sent one at a time by `send_next_request`, under
+//! the same-origin policy (see `fetch`). Cookies are kept for pages and
+//! scripts alike.
 
-use alloc::string::String;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::fmt;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
@@ -15,8 +41,18 @@ pub mod js;
 pub mod wasm;
 pub mod layout;
 pub mod render;
+pub mod images;
+pub mod forms;
+pub mod cookies;
+pub mod fetch;
+pub mod bookmarks;
+pub mod about;
+pub mod files;
+pub mod downloads;
 
+use crate::net::http::Method;
 use crate::println;
+use crate::{debug, info, warn};
 
 /// Browser configuration
 pub struct BrowserConfig {
@@ -45,8 +81,9 @@ impl BrowserConfig {
             wasm_enabled: true,
             images_enabled: true,
             css_enabled: true,
-            viewport_width: 1024,
-            viewport_height: 768,
+            // The body of a default-size desktop window, below its title bar
+            viewport_width: 800,
+            viewport_height: 572,
         }
     }
 }
@@ -63,8 +100,43 @@ pub struct Browser {
     pub title: String,
     /// Render context
     pub render_context: render::RenderContext,
+    /// Absolute URLs of the page's images still to be fetched, in
+    /// document order
+    pub pending_images: Vec<String>,
+    /// Pages visited in this tab, oldest first
+    pub history: Vec<String>,
+    /// Index in `history` of the page shown
+    pub history_index: usize,
+    /// Page row at the top of the viewport
+    pub scroll_y: u32,
+    /// What has been entered into the page's form controls
+    pub forms: forms::FormState,
+    /// Number of the form control with the keyboard focus
+    pub focus: Option<u16>,
+    /// The page's scripts, if it has any
+    pub script: Option<js::Interpreter>,
+    /// The page being loaded, shown as it arrives
+    loading: Option<Loading>,
+}
+
+/// A page on its way from a server
+struct Loading {
+    fetch: fetch::Load,
+    parser: html::Parser,
+    /// Fragment of the address navigated to, scrolled to once it is in
+    fragment: String,
+    /// Scroll position to go back to once the page is in, when reloading
+    restore_scroll: Option<u32>,
+    /// When the part that has arrived was last laid out and rendered, in
+    /// milliseconds since boot
+    painted: Option<u64>,
+    /// The response has turned out to be a page rather than a download
+    page: bool,
 }
 
+/// How often a page is laid out and rendered again while it arrives
+const LOADING_PAINT_MS: u64 = 250;
+
 impl Browser {
     /// Create new browser instance
     pub fn new() -> Self {
@@ -74,52 +146,544 @@ impl Browser {
             current_url: String::new(),
             title: String::from("New Tab"),
             render_context: render::RenderContext::new(),
+            pending_images: Vec::new(),
+            history: Vec::new(),
+            history_index: 0,
+            scroll_y: 0,
+            forms: forms::FormState::new(),
+            focus: None,
+            script: None,
+            loading: None,
         }
     }
 
     /// Navigate to URL
+    ///
+    /// A relative URL, such as a link's `href`, resolves against the page
+    /// shown. The page is added to the history after the current one,
+    /// dropping any pages that were ahead of it.
     pub fn navigate(&mut self, url: &str) -> Result<(), BrowserError> {
-        println!("[browser] Navigating to: {}", url);
-        
-        // Parse URL
-        let parsed_url = Url::parse(url)?;
+        let target = match Url::parse(&self.current_url) {
+            Ok(base) => base.join(url)?,
+            Err(_) => Url::parse(url)?,
+        };
+        self.load(&target, false)?;
+        self.push_history();
+        Ok(())
+    }
+
+    /// Record the page shown as the newest in the history
+    fn push_history(&mut self) {
+        if !self.history.is_empty() {
+            self.history.truncate(self.history_index + 1);
+        }
+        self.history.push(self.current_url.clone());
+        self.history_index = self.history.len() - 1;
+    }
+
+    /// Go to the previous page in the history; false if there is none
+    pub fn go_back(&mut self) -> Result<bool, BrowserError> {
+        if self.history_index == 0 || self.history.is_empty() {
+            return Ok(false);
+        }
+        let url = Url::parse(&self.history[self.history_index - 1])?;
+        self.load(&url, false)?;
+        self.history_index -= 1;
+        Ok(true)
+    }
+
+    /// Go to the next page in the history; false if there is none
+    pub fn go_forward(&mut self) -> Result<bool, BrowserError> {
+        if self.history_index + 1 >= self.history.len() {
+            return Ok(false);
+        }
+        let url = Url::parse(&self.history[self.history_index + 1])?;
+        self.load(&url, false)?;
+        self.history_index += 1;
+        Ok(true)
+    }
+
+    /// Fetch the page shown again, keeping the scroll position
+    pub fn reload(&mut self) -> Result<(), BrowserError> {
+        let url = Url::parse(&self.current_url)?;
+        let scroll_y = self.scroll_y;
+        self.load(&url, true)?;
+        match self.loading.as_mut() {
+            Some(loading) => loading.restore_scroll = Some(scroll_y),
+            None => {
+                self.scroll_to(scroll_y);
+            }
+        }
+        Ok(())
+    }
+
+    /// Absolute URL of the link at (x, y) in the viewport, if any
+    pub fn link_at(&self, x: i32, y: i32) -> Option<String> {
+        let tree = self.render_context.layout_tree.as_ref()?;
+        let href = tree.link_at(x as f32, (y + self.scroll_y as i32) as f32)?;
+        let base = Url::parse(&self.current_url).ok()?;
+        base.join(href).ok().map(|url| url.to_string())
+    }
+
+    /// Act on a click at (x, y) in the viewport: operate the form control
+    /// there and give it the focus, or follow the link
+    ///
+    /// The page's listeners see the click first and may cancel this.
+    /// Clicking anything else takes the focus away. Returns whether the
+    /// page changed.
+    pub fn click(&mut self, x: i32, y: i32) -> Result<bool, BrowserError> {
+        let page_y = (y + self.scroll_y as i32) as f32;
+        let node = self.render_context.layout_tree.as_ref().and_then(|t| t.element_at(x as f32, page_y));
+        let mut scripted = false;
+        if let Some(node) = node {
+            let fields = [
+                ("clientX", js::value::Value::Number(x as f64)),
+                ("clientY", js::value::Value::Number(y as f64)),
+                ("button", js::value::Value::Number(0.0)),
+            ];
+            let prevented = self.with_script(|interp| {
+                let target = js::dom::Target::Node(node);
+                js::dom::dispatch(interp, target, "mousedown", &fields);
+                js::dom::dispatch(interp, target, "mouseup", &fields);
+                js::dom::dispatch(interp, target, "click", &fields)
+            });
+            if prevented == Some(true) {
+                return Ok(true);
+            }
+            scripted = prevented.is_some();
+        }
+
+        // A listener may have moved things about
+        let control = self.render_context.layout_tree.as_ref().and_then(|t| t.control_at(x as f32, page_y));
+        if let Some(index) = control {
+            let (outcome, changed) = match self.document.as_ref() {
+                Some(document) => {
+                    let controls = forms::Controls::new(document);
+                    let outcome = forms::activate(&controls, &mut self.forms, index);
+                    let picked = controls.get(index).filter(|c| {
+                        matches!(c.kind, forms::ControlKind::Checkbox | forms::ControlKind::Radio | forms::ControlKind::Select)
+                    });
+                    let changed = picked.filter(|_| matches!(outcome, forms::Outcome::Changed)).map(|c| c.element.node_id);
+                    (outcome, changed)
+                }
+                None => return Ok(scripted),
+            };
+            self.focus = Some(index);
+            if let Some(node) = changed {
+                self.with_script(|interp| {
+                    js::dom::dispatch(interp, js::dom::Target::Node(node), "input", &[]);
+                    js::dom::dispatch(interp, js::dom::Target::Node(node), "change", &[]);
+                });
+            }
+            return self.apply(outcome);
+        }
+        if let Some(url) = self.link_at(x, y) {
+            self.navigate(&url)?;
+            return Ok(true);
+        }
+        if self.focus.take().is_some() {
+            self.refresh()?;
+            return Ok(true);
+        }
+        Ok(scripted)
+    }
+
+    /// Handle a key for the focused form control; Tab moves the focus to
+    /// the next control and Escape takes it away
+    ///
+    /// The page's listeners see the key first, as a `keydown` on the
+    /// focused control or else the document, and may cancel this. Returns
+    /// false if the key was of no use, so it can scroll the page instead.
+    pub fn key(&mut self, key: forms::Key) -> Result<bool, BrowserError> {
+        let focused = match (self.document.as_ref(), self.focus) {
+            (Some(document), Some(index)) => forms::Controls::new(document).get(index).map(|c| c.element.node_id),
+            _ => None,
+        };
+        let target = focused.map_or(js::dom::Target::Document, js::dom::Target::Node);
+        let fields = [("key", js::value::Value::String(js::dom::key_name(key)))];
+        let prevented = self.with_script(|interp| js::dom::dispatch(interp, target, "keydown", &fields));
+        if prevented == Some(true) {
+            return Ok(true);
+        }
+
+        let document = match self.document.as_ref() {
+            Some(document) => document,
+            None => return Ok(false),
+        };
+        let controls = forms::Controls::new(document);
+        let typed = self.focus.and_then(|i| controls.get(i)).filter(|c| c.kind.is_text()).map(|c| c.element.node_id);
+        let outcome = match (key, self.focus) {
+            (forms::Key::Tab, focus) => match controls.next_focus(focus) {
+                Some(next) => {
+                    self.focus = Some(next);
+                    forms::Outcome::Changed
+                }
+                None => return Ok(false),
+            },
+            (forms::Key::Escape, Some(_)) => {
+                self.focus = None;
+                forms::Outcome::Changed
+            }
+            (key, Some(index)) => match forms::key(&controls, &mut self.forms, index, key) {
+                Some(outcome) => outcome,
+                None => return Ok(false),
+            },
+            (_, None) => return Ok(false),
+        };
+        if let (forms::Outcome::Changed, forms::Key::Char(_) | forms::Key::Backspace | forms::Key::Enter, Some(node)) = (&outcome, key, typed) {
+            self.with_script(|interp| js::dom::dispatch(interp, js::dom::Target::Node(node), "input", &[]));
+        }
+        self.apply(outcome)
+    }
+
+    /// Show what operating a form control led to, submitting its form if
+    /// it calls for that
+    fn apply(&mut self, outcome: forms::Outcome) -> Result<bool, BrowserError> {
+        match outcome {
+            forms::Outcome::Submit(submission) => self.submit(&submission)?,
+            forms::Outcome::Changed | forms::Outcome::Unchanged => self.refresh()?,
+        }
+        Ok(true)
+    }
+
+    /// Send a form to its action and show the response
+    ///
+    /// A GET puts the entries in the query of the action URL; a POST sends
+    /// them as the request body. Going back to or reloading a page that
+    /// answered a POST fetches it again with GET.
+    fn submit(&mut self, submission: &forms::Submission) -> Result<(), BrowserError> {
+        let mut target = Url::parse(&self.current_url)?.join(&submission.action)?;
+        match submission.method {
+            forms::Method::Get => {
+                target.query = submission.query();
+                self.navigate(&target.to_string())
+            }
+            forms::Method::Post => {
+                info!("browser", "Submitting form to: {}", target);
+                let (content_type, body) = submission.body();
+                let content = match target.scheme.as_str() {
+                    "http" | "https" => self.post_http(&target, &content_type, body)?,
+                    // Other schemes have nowhere to send a body
+                    _ => self.fetch(&target)?,
+                };
+                self.show(&target, &content)?;
+                self.push_history();
+                Ok(())
+            }
+        }
+    }
+
+    /// Scroll so page row `y` is at the top of the viewport, as far as the
+    /// page allows; returns whether anything moved
+    pub fn scroll_to(&mut self, y: u32) -> bool {
+        let y = y.min(self.max_scroll());
+        if y == self.scroll_y {
+            return false;
+        }
+        self.scroll_y = y;
+        let _ = self.render();
+        true
+    }
+
+    /// Scroll by `dy` rows, down if positive
+    pub fn scroll_by(&mut self, dy: i32) -> bool {
+        self.scroll_to((self.scroll_y as i32 + dy).max(0) as u32)
+    }
+
+    /// Lowest scroll position, with the bottom of the page at the bottom
+    /// of the viewport
+    fn max_scroll(&self) -> u32 {
+        self.page_height().saturating_sub(self.config.viewport_height)
+    }
+
+    /// Height of the laid out page, which may be more than the viewport
+    /// shows
+    pub fn page_height(&self) -> u32 {
+        self.render_context.layout_tree.as_ref().map_or(0.0, |t| t.page_height()) as u32
+    }
+
+    /// Resize the viewport, laying the page out again for the new width;
+    /// returns whether the size changed
+    pub fn set_viewport(&mut self, width: u32, height: u32) -> bool {
+        if (width, height) == (self.config.viewport_width, self.config.viewport_height) {
+            return false;
+        }
+        self.config.viewport_width = width;
+        self.config.viewport_height = height;
+        if self.layout().is_ok() {
+            self.scroll_y = self.scroll_y.min(self.max_scroll());
+            let _ = self.render();
+        }
+        self.with_script(|interp| {
+            js::dom::set_viewport(interp, width, height);
+            js::dom::dispatch(interp, js::dom::Target::Window, "resize", &[]);
+        });
+        true
+    }
+
+    /// Run the page's timers that are due; returns whether any ran
+    pub fn run_timers(&mut self) -> bool {
+        if !self.script.as_ref().map_or(false, |s| s.dom.has_timers()) {
+            return false;
+        }
+        let now = crate::drivers::timer::elapsed_ms();
+        self.with_script(|interp| js::dom::run_timers(interp, now)).unwrap_or(false)
+    }
+
+    /// Send the next request the page's scripts made and hand them the
+    /// response; returns false if none was waiting
+    pub fn send_next_request(&mut self) -> bool {
+        let request = match self.script.as_mut().and_then(|s| s.dom.requests.next()) {
+            Some(request) => request,
+            None => return false,
+        };
+        let outcome = fetch::script_request(&self.current_url, &request).map_err(|e| {
+            warn!("browser", "Request to {} failed: {:?}", request.url, e);
+        });
+        self.with_script(|interp| js::net::complete(interp, request.id, outcome));
+        true
+    }
+
+    /// Messages the page has posted to its parent window, as JSON
+    pub fn take_messages(&mut self) -> Vec<String> {
+        self.script.as_mut().map_or_else(Vec::new, |s| s.dom.take_messages())
+    }
+
+    /// Hand the page a message from its parent window, given as JSON
+    pub fn deliver_message(&mut self, json: &str) {
+        self.with_script(|interp| js::dom::deliver_message(interp, json));
+    }
+
+    /// Run `f` on the page's scripts, with the page handed to them, then
+    /// show whatever they changed
+    ///
+    /// Does nothing if the page has no scripts.
+    fn with_script<R>(&mut self, f: impl FnOnce(&mut js::Interpreter) -> R) -> Option<R> {
+        let mut interp = self.script.take()?;
+        let dom = &mut interp.dom;
+        dom.document = self.document.take();
+        core::mem::swap(&mut dom.forms, &mut self.forms);
+        dom.layout = self.render_context.layout_tree.take();
+        dom.scroll_y = self.scroll_y;
+        dom.changed = false;
+        let result = f(&mut interp);
+
+        let dom = &mut interp.dom;
+        self.document = dom.document.take();
+        core::mem::swap(&mut dom.forms, &mut self.forms);
+        self.render_context.layout_tree = dom.layout.take();
+        let mut changed = dom.changed;
+        let focus = dom.focus.take();
+        self.script = Some(interp);
+
+        // A script focuses elements; the browser focuses form controls
+        if let (Some(node), Some(document)) = (focus, self.document.as_ref()) {
+            let controls = forms::Controls::new(document);
+            if let Some(index) = controls.controls.iter().position(|c| c.element.node_id == node) {
+                self.focus = Some(index as u16);
+                changed = true;
+            }
+        }
+        if changed {
+            if self.config.css_enabled {
+                let _ = self.apply_stylesheets();
+            }
+            // Until the first layout, the page is laid out once its scripts
+            // have run
+            if self.render_context.layout_tree.is_some() {
+                let _ = self.refresh();
+            }
+        }
+        Some(result)
+    }
+
+    /// Scroll to the element `fragment` names, if the page has it
+    fn scroll_to_fragment(&mut self, fragment: &str) {
+        let target = self.render_context.layout_tree.as_ref().and_then(|t| t.anchor_y(fragment));
+        if let Some(y) = target {
+            self.scroll_y = (y as u32).min(self.max_scroll());
+        }
+    }
+
+    /// Show the page at `url`
+    ///
+    /// A URL that differs from the page shown only in its fragment is the
+    /// same page, and just scrolls, unless `refetch` is set.
+    fn load(&mut self, parsed_url: &Url, refetch: bool) -> Result<(), BrowserError> {
+        let url = parsed_url.to_string();
+        if !refetch && self.document.is_some() {
+            if let Ok(current) = Url::parse(&self.current_url) {
+                let in_page = !parsed_url.fragment.is_empty() || !current.fragment.is_empty();
+                if in_page && parsed_url.same_document(&current) {
+                    self.current_url = url;
+                    self.scroll_y = 0;
+                    self.scroll_to_fragment(&parsed_url.fragment);
+                    return self.render();
+                }
+            }
+        }
+
+        info!("browser", "Navigating to: {}", url);
         
+        // Pages from servers are shown as they arrive
+        let streamed = matches!(parsed_url.scheme.as_str(), "http" | "https")
+            && matches!(parsed_url.content_type(), ContentType::Html);
+        if streamed {
+            self.loading = Some(Loading {
+                fetch: fetch::Load::start(parsed_url)?,
+                parser: html::Parser::new(),
+                fragment: parsed_url.fragment.clone(),
+                restore_scroll: None,
+                painted: None,
+                page: false,
+            });
+            // The page shown stays on screen until the new one arrives,
+            // but no longer takes clicks or runs scripts
+            self.current_url = url.clone();
+            self.title = url;
+            self.document = None;
+            self.script = None;
+            self.render_context.layout_tree = None;
+            self.pending_images.clear();
+            self.forms.clear();
+            self.focus = None;
+            self.scroll_y = 0;
+            return Ok(());
+        }
+
         // Fetch resource
-        let content = self.fetch(&parsed_url)?;
-        
-        // Parse based on content type
-        match parsed_url.content_type() {
-            ContentType::Html => {
-                let document = html::parse(&content)?;
-                self.document = Some(document);
-                
-                // Apply CSS if enabled
-                if self.config.css_enabled {
-                    self.apply_stylesheets()?;
-                }
-                
-                // Execute JavaScript if enabled
-                if self.config.js_enabled {
-                    self.execute_scripts()?;
+        let content = self.fetch(parsed_url)?;
+        self.show(parsed_url, &content)
+    }
+
+    /// Whether a page is still arriving
+    pub fn is_loading(&self) -> bool {
+        self.loading.is_some()
+    }
+
+    /// Parse what has arrived of the page being loaded
+    ///
+    /// The part that has arrived is laid out and rendered every so often
+    /// as it grows. Once all of it is in, the page is set up as any other:
+    /// its scripts run and its images are queued. Returns whether the page
+    /// shown changed.
+    pub fn load_next_chunk(&mut self) -> Result<bool, BrowserError> {
+        let loading = match self.loading.as_mut() {
+            Some(loading) => loading,
+            None => return Ok(false),
+        };
+        let chunk = match loading.fetch.poll() {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                self.loading = None;
+                return Err(e);
+            }
+        };
+        if !loading.page {
+            match loading.fetch.response().map(downloads::is_download) {
+                None => return Ok(false),
+                Some(false) => loading.page = true,
+                Some(true) => {
+                    let loading = self.loading.take().unwrap();
+                    let started = downloads::start(loading.fetch, chunk);
+                    self.leave_download()?;
+                    return started.map(|_| true);
                 }
-                
-                // Layout and render
-                self.layout()?;
+            }
+        }
+        loading.parser.feed(&chunk);
+        let url = loading.fetch.url().clone();
+        if loading.fetch.finished() {
+            let mut loading = self.loading.take().unwrap();
+            let mut url = url;
+            url.fragment = core::mem::take(&mut loading.fragment);
+            // Where the reader has scrolled to meanwhile is kept
+            let scroll_y = loading.restore_scroll.unwrap_or(self.scroll_y);
+            self.show_document(&url, loading.parser.finish())?;
+            if url.fragment.is_empty() && scroll_y > 0 {
+                self.scroll_y = scroll_y.min(self.max_scroll());
                 self.render()?;
             }
+            return Ok(true);
+        }
+        let now = crate::drivers::timer::elapsed_ms();
+        let due = loading.painted.map_or(true, |painted| now >= painted + LOADING_PAINT_MS);
+        if chunk.is_empty() || !due {
+            return Ok(false);
+        }
+        loading.painted = Some(now);
+        let document = loading.parser.snapshot();
+        // Relative URLs resolve against where the redirects led
+        self.current_url = url.to_string();
+        self.title = document.title().unwrap_or_else(|| self.current_url.clone());
+        self.document = Some(document);
+        if self.config.css_enabled {
+            self.apply_stylesheets()?;
+        }
+        self.layout()?;
+        self.scroll_y = self.scroll_y.min(self.max_scroll());
+        self.render()?;
+        Ok(true)
+    }
+
+    /// Go back to the page shown before navigating to what turned out to
+    /// be a download, or to an empty tab if there was none
+    fn leave_download(&mut self) -> Result<(), BrowserError> {
+        // A download is not a page to come back to
+        if self.history.get(self.history_index) == Some(&self.current_url) {
+            self.history.remove(self.history_index);
+            self.history_index = self.history_index.saturating_sub(1);
+        }
+        match self.history.get(self.history_index).cloned() {
+            Some(url) => self.load(&Url::parse(&url)?, true),
+            None => {
+                self.current_url.clear();
+                self.title = String::from("New Tab");
+                self.render_context.framebuffer = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Show `content` as the page at `url`, as if fetched from there; for
+    /// pages that come from the system rather than a server
+    pub fn show_html(&mut self, url: &str, content: &[u8]) -> Result<(), BrowserError> {
+        let url = Url::parse(url)?;
+        self.show(&url, content)?;
+        self.push_history();
+        Ok(())
+    }
+
+    /// Show `content` fetched from `parsed_url` as the page
+    fn show(&mut self, parsed_url: &Url, content: &[u8]) -> Result<(), BrowserError> {
+        // Whatever was still loading is abandoned for this page
+        self.loading = None;
+        self.current_url = parsed_url.to_string();
+        
+        // Files and about: pages come as HTML made to show them, whatever
+        // they are
+        let content_type = match parsed_url.scheme.as_str() {
+            "file" | "about" => ContentType::Html,
+            _ => parsed_url.content_type(),
+        };
+        match content_type {
+            ContentType::Html => {
+                let document = html::parse(content)?;
+                self.show_document(parsed_url, document)?;
+            }
             ContentType::Css => {
                 // CSS file - not a document
             }
             ContentType::JavaScript => {
                 // JS file - execute it
                 if self.config.js_enabled {
-                    js::execute(&content)?;
+                    js::execute(content)?;
                 }
             }
             ContentType::Wasm => {
                 // WebAssembly module
                 if self.config.wasm_enabled {
-                    wasm::load(&content)?;
+                    wasm::load(content)?;
                 }
             }
             _ => {
@@ -127,31 +691,68 @@ impl Browser {
             }
         }
         
-        self.current_url = String::from(url);
         Ok(())
     }
 
+    /// Show `document`, from `parsed_url`, as the page
+    fn show_document(&mut self, parsed_url: &Url, document: html::Document) -> Result<(), BrowserError> {
+        // Relative URLs in the page resolve against it from here on
+        let url = parsed_url.to_string();
+        self.current_url = url.clone();
+        self.title = document.title().unwrap_or(url);
+        // The first control marked `autofocus` starts with the keyboard
+        self.focus = forms::Controls::new(&document).controls.iter()
+            .position(|c| c.element.get_attr("autofocus").is_some())
+            .map(|i| i as u16);
+        self.document = Some(document);
+        self.forms.clear();
+        self.script = None;
+        self.render_context.layout_tree = None;
+        self.queue_images();
+        
+        // Apply CSS if enabled
+        if self.config.css_enabled {
+            self.apply_stylesheets()?;
+        }
+        
+        // Execute JavaScript if enabled
+        if self.config.js_enabled {
+            self.execute_scripts()?;
+        }
+        
+        // Layout and render, from the top or the fragment's target
+        self.layout()?;
+        self.scroll_y = 0;
+        self.scroll_to_fragment(&parsed_url.fragment);
+        self.render()
+    }
+
     /// Fetch resource from URL
     fn fetch(&self, url: &Url) -> Result<Vec<u8>, BrowserError> {
         match url.scheme.as_str() {
-            "http" => self.fetch_http(url, false),
-            "https" => self.fetch_http(url, true),
+            "http" | "https" => self.fetch_http(url),
             "file" => self.fetch_file(url),
+            "about" => about::page(&url.path).map(String::into_bytes),
             _ => Err(BrowserError::UnsupportedProtocol),
         }
     }
 
     /// Fetch via HTTP/HTTPS
-    fn fetch_http(&self, url: &Url, _tls: bool) -> Result<Vec<u8>, BrowserError> {
-        // Simple HTTP GET implementation
-        // For now, just return a basic HTML page
-        Ok(Vec::new()) // Placeholder
+    fn fetch_http(&self, url: &Url) -> Result<Vec<u8>, BrowserError> {
+        let (_, response) = fetch::send(Method::Get, url, Vec::new(), Vec::new(), |_| Ok(true))?;
+        response_body(response)
+    }
+
+    /// POST a body of type `content_type` via HTTP/HTTPS
+    fn post_http(&self, url: &Url, content_type: &str, body: Vec<u8>) -> Result<Vec<u8>, BrowserError> {
+        let headers = vec![(String::from("Content-Type"), String::from(content_type))];
+        let (_, response) = fetch::send(Method::Post, url, headers, body, |_| Ok(true))?;
+        response_body(response)
     }
 
     /// Fetch local file
-    fn fetch_file(&self, _url: &Url) -> Result<Vec<u8>, BrowserError> {
-        // File protocol - read from filesystem
-        Ok(Vec::new()) // Placeholder
+    fn fetch_file(&self, url: &Url) -> Result<Vec<u8>, BrowserError> {
+        files::page(&url.path)
     }
 
     /// Apply stylesheets to document
@@ -162,45 +763,147 @@ impl Browser {
         Ok(())
     }
 
-    /// Execute JavaScript in document
+    /// Run the document's scripts in order, then fire `DOMContentLoaded`
+    /// and `load`
+    ///
+    /// A script that fails to load or throws is reported and the rest run
+    /// regardless, as the page itself still shows.
     fn execute_scripts(&mut self) -> Result<(), BrowserError> {
+        let scripts: Vec<(Option<String>, Vec<u8>)> = match self.document {
+            Some(ref doc) => doc.scripts.iter().map(|s| (s.src.clone(), s.content.clone())).collect(),
+            None => return Ok(()),
+        };
+        let mut interp = js::Interpreter::new();
+        interp.dom.url = self.current_url.clone();
+        js::dom::set_viewport(&mut interp, self.config.viewport_width, self.config.viewport_height);
+        self.script = Some(interp);
+        // Scripts may measure the page as they set it up
+        self.layout()?;
+        self.with_script(js::dom::bind_document);
+
+        let base = Url::parse(&self.current_url)?;
+        for (src, content) in scripts {
+            let code = match src {
+                Some(src) => match base.join(&src).and_then(|url| self.fetch(&url)) {
+                    Ok(code) => code,
+                    Err(e) => {
+                        warn!("js", "Cannot load {}: {:?}", src, e);
+                        continue;
+                    }
+                },
+                None => content,
+            };
+            let source = String::from_utf8_lossy(&code).into_owned();
+            self.with_script(|interp| {
+                if let Err(e) = interp.run(&source) {
+                    warn!("js", "Uncaught {}", e);
+                }
+            });
+        }
+        self.with_script(|interp| {
+            js::dom::dispatch(interp, js::dom::Target::Document, "DOMContentLoaded", &[]);
+            js::dom::dispatch(interp, js::dom::Target::Window, "load", &[]);
+        });
+        Ok(())
+    }
+
+    /// Queue the document's images that are not in the cache yet
+    fn queue_images(&mut self) {
+        self.pending_images.clear();
+        if !self.config.images_enabled {
+            return;
+        }
+        let base = match Url::parse(&self.current_url) {
+            Ok(base) => base,
+            Err(_) => return,
+        };
+        let mut sources = Vec::new();
         if let Some(ref doc) = self.document {
-            for script in &doc.scripts {
-                js::execute(&script.content)?;
+            collect_image_sources(&doc.root, &mut sources);
+        }
+        for src in sources {
+            if let Ok(url) = base.join(src) {
+                let url = url.to_string();
+                if !self.pending_images.contains(&url) && images::request(&url) {
+                    self.pending_images.push(url);
+                }
             }
         }
-        Ok(())
+    }
+
+    /// Fetch and decode the next queued image, then lay out and render the
+    /// page again with it in place
+    ///
+    /// Returns false once no images are left to load.
+    pub fn load_next_image(&mut self) -> bool {
+        if self.pending_images.is_empty() {
+            return false;
+        }
+        let url = self.pending_images.remove(0);
+        let image = Url::parse(&url)
+            .and_then(|parsed| self.fetch(&parsed))
+            .ok()
+            .and_then(|data| match crate::graphics::image::decode(&data) {
+                Ok(image) => Some(image),
+                Err(e) => {
+                    warn!("browser", "Cannot decode {}: {:?}", url, e);
+                    None
+                }
+            });
+        images::store(&url, image);
+        if self.layout().is_ok() {
+            let _ = self.render();
+        }
+        true
     }
 
     /// Perform layout
     fn layout(&mut self) -> Result<(), BrowserError> {
         if let Some(ref doc) = self.document {
-            let tree = layout::layout(doc, self.config.viewport_width, self.config.viewport_height)?;
+            let base = Url::parse(&self.current_url).ok();
+            let loaded = |src: &str| {
+                let url = base.as_ref()?.join(src).ok()?;
+                images::get(&url.to_string())
+            };
+            let images: layout::ImageSource = if self.config.images_enabled { &loaded } else { &|_| None };
+            let mut tree = layout::layout(doc, images, &self.forms, self.config.viewport_width, self.config.viewport_height)?;
+            tree.focus = self.focus;
             self.render_context.layout_tree = Some(tree);
         }
         Ok(())
     }
 
+    /// Lay out and render the page again after its form values or focus
+    /// changed
+    fn refresh(&mut self) -> Result<(), BrowserError> {
+        self.layout()?;
+        self.render()
+    }
+
     /// Render to framebuffer
+    ///
+    /// The framebuffer is painted over in place unless a window still
+    /// holds it, in which case that window keeps the previous page until
+    /// it picks up this one.
     fn render(&mut self) -> Result<(), BrowserError> {
-        // Initialize framebuffer if not already done
-        if self.render_context.framebuffer.is_none() {
-            self.render_context.init_framebuffer(
-                self.config.viewport_width,
-                self.config.viewport_height
-            );
-        }
-        
         if let Some(ref tree) = self.render_context.layout_tree {
-            if let Some(ref mut fb) = self.render_context.framebuffer {
-                render::render(tree, fb)?;
-            }
+            let (width, height) = (self.config.viewport_width, self.config.viewport_height);
+            let reusable = self.render_context.framebuffer.take()
+                .and_then(|fb| Arc::try_unwrap(fb).ok())
+                .filter(|fb| fb.width == width && fb.height == height);
+            let mut fb = reusable.unwrap_or_else(|| render::Framebuffer::new(width, height));
+            fb.scroll_y = self.scroll_y as i32;
+            render::render(tree, &mut fb)?;
+            self.render_context.viewport_width = width;
+            self.render_context.viewport_height = height;
+            self.render_context.framebuffer = Some(Arc::new(fb));
         }
         Ok(())
     }
 }
 
 /// URL structure
+#[derive(Debug, Clone)]
 pub struct Url {
     pub scheme: String,
     pub host: String,
@@ -213,40 +916,127 @@ pub struct Url {
 impl Url {
     /// Parse URL string
     pub fn parse(url: &str) -> Result<Self, BrowserError> {
+        // about: pages have a name where other URLs have a host and path
+        if url.get(..6).map_or(false, |scheme| scheme.eq_ignore_ascii_case("about:")) {
+            let (path, query, fragment) = split_target(&url[6..]);
+            return Ok(Self {
+                scheme: String::from("about"),
+                host: String::new(),
+                port: 0,
+                path: path.to_ascii_lowercase(),
+                query: String::from(query),
+                fragment: String::from(fragment),
+            });
+        }
+
         // Simple URL parsing
         let parts: Vec<&str> = url.split("://").collect();
         if parts.len() != 2 {
             return Err(BrowserError::InvalidUrl);
         }
         
-        let scheme = String::from(parts[0]);
+        let scheme = parts[0].to_ascii_lowercase();
         let rest = parts[1];
         
         // Parse host and path
-        let (host, path) = if let Some(pos) = rest.find('/') {
-            (String::from(&rest[..pos]), String::from(&rest[pos..]))
-        } else {
-            (String::from(rest), String::from("/"))
+        let (authority, target) = match rest.find(|c| c == '/' || c == '?' || c == '#') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
         };
         
-        // Determine default port
-        let port = match scheme.as_str() {
-            "http" => 80,
-            "https" => 443,
-            "ftp" => 21,
-            _ => 0,
+        // An explicit port follows the host, else the scheme's default applies
+        let (host, port) = match authority.rfind(':') {
+            Some(pos) => {
+                let port = authority[pos + 1..].parse().map_err(|_| BrowserError::InvalidUrl)?;
+                (&authority[..pos], port)
+            }
+            None => (authority, default_port(&scheme)),
         };
         
+        let (path, query, fragment) = split_target(target);
         Ok(Self {
             scheme,
-            host,
+            host: host.to_ascii_lowercase(),
             port,
-            path,
-            query: String::new(),
-            fragment: String::new(),
+            path: if path.is_empty() { String::from("/") } else { remove_dot_segments(path) },
+            query: String::from(query),
+            fragment: String::from(fragment),
         })
     }
 
+    /// The scheme, host and port, as an `Origin` header gives them
+    pub fn origin(&self) -> String {
+        if self.port == default_port(&self.scheme) {
+            format!("{}://{}", self.scheme, self.host)
+        } else {
+            format!("{}://{}:{}", self.scheme, self.host, self.port)
+        }
+    }
+
+    /// Whether `other` has the same origin, whose scripts may read what
+    /// this one serves
+    pub fn same_origin(&self, other: &Url) -> bool {
+        self.scheme == other.scheme && self.host == other.host && self.port == other.port
+    }
+
+    /// Whether `other` is the same document, whatever the fragments
+    pub fn same_document(&self, other: &Url) -> bool {
+        self.scheme == other.scheme
+            && self.host == other.host
+            && self.port == other.port
+            && self.path == other.path
+            && self.query == other.query
+    }
+
+    /// Resolve a reference found in this page, such as an `<img src>`,
+    /// into an absolute URL
+    pub fn join(&self, reference: &str) -> Result<Self, BrowserError> {
+        let reference = reference.trim();
+        // Nothing is relative to an about: page but its fragments
+        if (self.scheme == "about" && !reference.starts_with(['?', '#'])) || reference.starts_with("about:") {
+            return Url::parse(reference);
+        }
+        // An absolute URL starts with a scheme
+        if let Some(pos) = reference.find("://") {
+            let scheme = &reference[..pos];
+            if !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                return Url::parse(reference);
+            }
+        }
+        if reference.starts_with("//") {
+            return Url::parse(&format!("{}:{}", self.scheme, reference));
+        }
+
+        let (path, query, fragment) = split_target(reference);
+        let mut url = Url {
+            scheme: self.scheme.clone(),
+            host: self.host.clone(),
+            port: self.port,
+            path: self.path.clone(),
+            query: self.query.clone(),
+            fragment: String::from(fragment),
+        };
+        if path.is_empty() {
+            // Only a query or fragment: the same document
+            if reference.starts_with('?') {
+                url.query = String::from(query);
+            }
+            return Ok(url);
+        }
+        url.query = String::from(query);
+        url.path = if path.starts_with('/') {
+            remove_dot_segments(path)
+        } else {
+            // Relative to the directory of this page
+            let dir = match self.path.rfind('/') {
+                Some(pos) => &self.path[..pos + 1],
+                None => "/",
+            };
+            remove_dot_segments(&format!("{}{}", dir, path))
+        };
+        Ok(url)
+    }
+
     /// Get content type based on extension
     pub fn content_type(&self) -> ContentType {
         if self.path.ends_with(".html") || self.path.ends_with(".htm") {
@@ -263,6 +1053,109 @@ impl Url {
     }
 }
 
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.scheme == "about" {
+            write!(f, "about:{}", self.path)?;
+        } else {
+            write!(f, "{}://{}", self.scheme, self.host)?;
+            if self.port != default_port(&self.scheme) {
+                write!(f, ":{}", self.port)?;
+            }
+            write!(f, "{}", self.path)?;
+        }
+        if !self.query.is_empty() {
+            write!(f, "?{}", self.query)?;
+        }
+        if !self.fragment.is_empty() {
+            write!(f, "#{}", self.fragment)?;
+        }
+        Ok(())
+    }
+}
+
+/// The body of a successful response, or the error its status stands for
+fn response_body(response: crate::net::http::Response) -> Result<Vec<u8>, BrowserError> {
+    check_status(response.status)?;
+    Ok(response.body)
+}
+
+/// The error a response status stands for, if any
+fn check_status(status: u16) -> Result<(), BrowserError> {
+    match status {
+        200..=299 => Ok(()),
+        404 | 410 => Err(BrowserError::NotFound),
+        _ => Err(BrowserError::NetworkError),
+    }
+}
+
+/// Port a scheme uses when the URL does not give one
+fn default_port(scheme: &str) -> u16 {
+    match scheme {
+        "http" => 80,
+        "https" => 443,
+        "ftp" => 21,
+        _ => 0,
+    }
+}
+
+/// Split the part of a URL after the host into path, query and fragment
+fn split_target(target: &str) -> (&str, &str, &str) {
+    let (rest, fragment) = match target.find('#') {
+        Some(pos) => (&target[..pos], &target[pos + 1..]),
+        None => (target, ""),
+    };
+    match rest.find('?') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..], fragment),
+        None => (rest, "", fragment),
+    }
+}
+
+/// Resolve the `.` and `..` segments of an absolute path
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+        match part {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(part),
+        }
+        // A path ending in a dot segment names a directory
+        if last && (part == "." || part == "..") {
+            segments.push("");
+        }
+    }
+    let mut out = String::new();
+    for segment in segments {
+        out.push('/');
+        out.push_str(segment);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+/// The `src` of every `<img>` under `element`, in document order
+fn collect_image_sources<'a>(element: &'a html::Element, out: &mut Vec<&'a str>) {
+    if element.tag == "img" {
+        if let Some(src) = element.get_attr("src") {
+            if !src.trim().is_empty() {
+                out.push(src);
+            }
+        }
+    }
+    for child in &element.children {
+        if let html::Node::Element(elem) = child {
+            collect_image_sources(elem, out);
+        }
+    }
+}
+
 /// Content types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ContentType {
@@ -291,71 +1184,79 @@ pub enum BrowserError {
     Unknown = 255,
 }
 
-/// Global browser instance
+/// Identifies a tab
+pub type TabId = u32;
+
+/// Open tabs
+struct Tabs {
+    browsers: BTreeMap<TabId, Browser>,
+    next_id: TabId,
+}
+
+// Global tab table
 lazy_static! {
-    static ref BROWSER: Mutex<Option<Browser>> = Mutex::new(None);
+    static ref TABS: Mutex<Tabs> = Mutex::new(Tabs { browsers: BTreeMap::new(), next_id: 1 });
 }
 
 /// Initialize browser engine
 pub fn init() {
-    println!("[browser] Initializing browser engine...");
-
-    println!("[browser] Creating browser instance...");
-    let browser = Browser::new();
-    println!("[browser] Browser instance created, storing...");
-    *BROWSER.lock() = Some(browser);
-    println!("[browser] Browser stored");
+    info!("browser", "Initializing browser engine...");
 
     // Initialize subsystems
-    println!("[browser] Init HTML...");
+    debug!("browser", "Init HTML...");
     html::init();
-    println!("[browser] Init CSS...");
+    debug!("browser", "Init CSS...");
     css::init();
-    println!("[browser] Init JS...");
+    debug!("browser", "Init JS...");
     js::init();
-    println!("[browser] Init WASM...");
+    debug!("browser", "Init WASM...");
     wasm::init();
-    println!("[browser] Init layout...");
+    debug!("browser", "Init layout...");
     layout::init();
-    println!("[browser] Init render...");
+    debug!("browser", "Init render...");
     render::init();
 
-    println!("[browser] Browser engine initialized");
+    info!("browser", "Browser engine initialized");
 }
 
-/// Navigate to URL
-pub fn navigate(url: &str) -> Result<(), BrowserError> {
-    if let Some(ref mut browser) = *BROWSER.lock() {
-        browser.navigate(url)
-    } else {
-        Err(BrowserError::Unknown)
-    }
+/// Open a tab with an empty page and history
+pub fn open_tab() -> TabId {
+    let mut tabs = TABS.lock();
+    let id = tabs.next_id;
+    tabs.next_id += 1;
+    tabs.browsers.insert(id, Browser::new());
+    id
 }
 
-/// Get current page title
-pub fn get_title() -> String {
-    if let Some(ref browser) = *BROWSER.lock() {
-        browser.title.clone()
-    } else {
-        String::new()
-    }
+/// Close a tab, freeing its page
+pub fn close_tab(tab: TabId) {
+    TABS.lock().browsers.remove(&tab);
+}
+
+/// Run `f` on the browser of `tab`, if it is open
+pub fn with_tab<R>(tab: TabId, f: impl FnOnce(&mut Browser) -> R) -> Option<R> {
+    TABS.lock().browsers.get_mut(&tab).map(f)
 }
 
 /// Print browser statistics
 pub fn print_stats() {
     println!("Browser Engine:");
-    
-    if let Some(ref browser) = *BROWSER.lock() {
-        println!("  Current URL: {}", browser.current_url);
-        println!("  Title: {}", browser.title);
-        println!("  Viewport: {}x{}", browser.config.viewport_width, browser.config.viewport_height);
-        println!("  JavaScript: {}", if browser.config.js_enabled { "enabled" } else { "disabled" });
-        println!("  WebAssembly: {}", if browser.config.wasm_enabled { "enabled" } else { "disabled" });
-        
+
+    let tabs = TABS.lock();
+    if tabs.browsers.is_empty() {
+        println!("  No tabs open");
+    }
+    for (id, browser) in tabs.browsers.iter() {
+        println!("  Tab {}: {}", id, browser.title);
+        println!("    URL: {}", browser.current_url);
+        println!("    Viewport: {}x{}, scrolled to {}", browser.config.viewport_width, browser.config.viewport_height, browser.scroll_y);
+        println!("    History: page {} of {}", browser.history_index + 1, browser.history.len());
         if let Some(ref doc) = browser.document {
-            println!("  Document elements: {}", doc.element_count());
+            println!("    Document elements: {}", doc.element_count());
+        }
+        if let Some(ref tree) = browser.render_context.layout_tree {
+            println!("    Page height: {}px, {} links", tree.page_height() as u32, tree.links.len());
         }
-    } else {
-        println!("  Browser not initialized");
     }
+    images::print_stats();
 }
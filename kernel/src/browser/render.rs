@@ -1,13 +1,24 @@
 //! Rendering Engine
 //!
-//! Renders the layout tree to a framebuffer.
-
+//! Paints the layout tree into a framebuffer: backgrounds, borders, text
+//! and images, in the compositor's ARGB channel order so a desktop window
+//! can show the result as it is. Images are scaled to their boxes by
+//! nearest neighbour and blended by their alpha. The form control with
+//! the focus is outlined, with a caret after its text if it takes typing.
+//! The framebuffer holds one viewport of the page, starting `scroll_y`
+//! rows down it.
+
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 
 use crate::browser::BrowserError;
-use crate::browser::layout::{LayoutBox, LayoutTree, BoxType, Color, TextAlign, FontWeight};
-use crate::println;
+use crate::browser::layout::{self, BoxContent, BoxType, Color, FontWeight, Fragment, FragmentKind, LayoutBox, LayoutStyles, LayoutTree};
+use crate::drivers::vesa::colors;
+use crate::graphics::font;
+use crate::graphics::image::Image;
+use crate::info;
 
 /// Framebuffer for rendering
 pub struct Framebuffer {
@@ -15,8 +26,16 @@ pub struct Framebuffer {
     pub width: u32,
     /// Height in pixels
     pub height: u32,
-    /// Pixel data (RGBA)
+    /// Pixel data (ARGB)
     pub data: Vec<u32>,
+    /// Page row shown in the top row; drawing takes page coordinates
+    pub scroll_y: i32,
+}
+
+impl core::fmt::Debug for Framebuffer {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Framebuffer({}x{})", self.width, self.height)
+    }
 }
 
 impl Framebuffer {
@@ -27,6 +46,7 @@ impl Framebuffer {
             width,
             height,
             data: vec![0xFFFFFFFF; size], // White background
+            scroll_y: 0,
         }
     }
 
@@ -39,6 +59,7 @@ impl Framebuffer {
 
     /// Set pixel
     pub fn set_pixel(&mut self, x: i32, y: i32, color: u32) {
+        let y = y - self.scroll_y;
         if x >= 0 && x < self.width as i32 && y >= 0 && y < self.height as i32 {
             let idx = (y as u32 * self.width + x as u32) as usize;
             self.data[idx] = color;
@@ -47,6 +68,7 @@ impl Framebuffer {
 
     /// Get pixel
     pub fn get_pixel(&self, x: i32, y: i32) -> u32 {
+        let y = y - self.scroll_y;
         if x >= 0 && x < self.width as i32 && y >= 0 && y < self.height as i32 {
             let idx = (y as u32 * self.width + x as u32) as usize;
             self.data[idx]
@@ -55,12 +77,19 @@ impl Framebuffer {
         }
     }
 
-    /// Fill rectangle
+    /// Fill rectangle, clipped to the framebuffer
     pub fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: u32) {
-        for dy in 0..height as i32 {
-            for dx in 0..width as i32 {
-                self.set_pixel(x + dx, y + dy, color);
-            }
+        let y = y - self.scroll_y;
+        let x0 = x.max(0);
+        let y0 = y.max(0);
+        let x1 = (x + width as i32).min(self.width as i32);
+        let y1 = (y + height as i32).min(self.height as i32);
+        if x1 <= x0 || y1 <= y0 {
+            return;
+        }
+        for row in y0..y1 {
+            let start = (row as u32 * self.width) as usize;
+            self.data[start + x0 as usize..start + x1 as usize].fill(color);
         }
     }
 
@@ -108,8 +137,8 @@ impl Framebuffer {
 
 /// Render context
 pub struct RenderContext {
-    /// Framebuffer (lazy init)
-    pub framebuffer: Option<Framebuffer>,
+    /// The last rendered page, shared with the window showing it
+    pub framebuffer: Option<Arc<Framebuffer>>,
     /// Layout tree
     pub layout_tree: Option<LayoutTree>,
     /// Viewport width
@@ -128,134 +157,225 @@ impl RenderContext {
             viewport_height: 600,
         }
     }
-    
-    /// Initialize framebuffer when needed
-    pub fn init_framebuffer(&mut self, width: u32, height: u32) {
-        self.viewport_width = width;
-        self.viewport_height = height;
-        self.framebuffer = Some(Framebuffer::new(width, height));
-    }
 }
 
 /// Render layout tree to framebuffer
+///
+/// The canvas takes the background of the root element, or failing that
+/// of the body, as browsers do; the boxes are then painted in tree order,
+/// each with its background, borders and contents.
 pub fn render(layout_tree: &LayoutTree, framebuffer: &mut Framebuffer) -> Result<(), BrowserError> {
-    // Clear background
-    framebuffer.clear(0xFFFFFFFF); // White
-
-    // Render root box
-    render_box(&layout_tree.root, framebuffer, 0.0, 0.0)?;
-
+    let root = &layout_tree.root;
+    let canvas = root.styles.background_color
+        .or_else(|| root.children.first().and_then(|body| body.styles.background_color))
+        .map_or(colors::WHITE, to_pixel);
+    framebuffer.clear(canvas);
+    paint_box(root, framebuffer);
+    if let Some(index) = layout_tree.focus {
+        if let Some(control) = layout_tree.control_box(index) {
+            paint_focus(control, index, framebuffer);
+        }
+    }
     Ok(())
 }
 
-/// Render a layout box
-fn render_box(layout_box: &LayoutBox, framebuffer: &mut Framebuffer, offset_x: f32, offset_y: f32) -> Result<(), BrowserError> {
+/// Paint a box and everything inside it
+fn paint_box(layout_box: &LayoutBox, framebuffer: &mut Framebuffer) {
     if layout_box.box_type == BoxType::None {
-        return Ok(());
+        return;
     }
-
-    let x = (layout_box.x + offset_x) as i32;
-    let y = (layout_box.y + offset_y) as i32;
+    let x = layout_box.x as i32;
+    let y = layout_box.y as i32;
     let width = layout_box.width as u32;
     let height = layout_box.height as u32;
 
-    // Draw background
-    if let Some(ref bg_color) = layout_box.styles.background_color {
-        let color = rgb_to_u32(bg_color.r, bg_color.g, bg_color.b);
-        framebuffer.fill_rect(x, y, width, height, color);
+    if let Some(bg) = layout_box.styles.background_color {
+        framebuffer.fill_rect(x, y, width, height, to_pixel(bg));
+    }
+    paint_borders(layout_box, framebuffer);
+
+    if let BoxContent::Image { alt, bitmap, .. } = &layout_box.content {
+        match bitmap {
+            Some(image) => paint_image(layout_box, image, framebuffer),
+            None => paint_image_placeholder(layout_box, alt, framebuffer),
+        }
+    }
+
+    for child in &layout_box.children {
+        paint_box(child, framebuffer);
+    }
+    for line in &layout_box.lines {
+        for fragment in &line.fragments {
+            paint_fragment(fragment, framebuffer);
+        }
     }
+}
 
-    // Draw border
-    let border_color = rgb_to_u32(0, 0, 0);
-    if layout_box.border.top > 0.0 {
-        framebuffer.fill_rect(x, y, width, layout_box.border.top as u32, border_color);
+fn paint_borders(layout_box: &LayoutBox, framebuffer: &mut Framebuffer) {
+    let b = &layout_box.border;
+    let color = to_pixel(layout_box.styles.border_color);
+    let x = layout_box.x as i32;
+    let y = layout_box.y as i32;
+    let width = layout_box.width as u32;
+    let height = layout_box.height as u32;
+    if b.top > 0.0 {
+        framebuffer.fill_rect(x, y, width, b.top as u32, color);
     }
-    if layout_box.border.bottom > 0.0 {
-        framebuffer.fill_rect(x, y + height as i32 - layout_box.border.bottom as i32, width, layout_box.border.bottom as u32, border_color);
+    if b.bottom > 0.0 {
+        framebuffer.fill_rect(x, y + height as i32 - b.bottom as i32, width, b.bottom as u32, color);
     }
-    if layout_box.border.left > 0.0 {
-        framebuffer.fill_rect(x, y, layout_box.border.left as u32, height, border_color);
+    if b.left > 0.0 {
+        framebuffer.fill_rect(x, y, b.left as u32, height, color);
     }
-    if layout_box.border.right > 0.0 {
-        framebuffer.fill_rect(x + width as i32 - layout_box.border.right as i32, y, layout_box.border.right as u32, height, border_color);
+    if b.right > 0.0 {
+        framebuffer.fill_rect(x + width as i32 - b.right as i32, y, b.right as u32, height, color);
     }
+}
 
-    // Render text
-    if let Some(ref text) = layout_box.text {
-        let text_x = (layout_box.x + layout_box.padding.left + offset_x) as i32;
-        let text_y = (layout_box.y + layout_box.padding.top + offset_y) as i32;
-        let text_color = layout_box.styles.color.as_ref()
-            .map(|c| rgb_to_u32(c.r, c.g, c.b))
-            .unwrap_or(0xFF000000);
-        
-        render_text(framebuffer, text, text_x, text_y, layout_box.styles.font_size, text_color);
+/// Draw an image scaled to fill the box inside its border and padding
+fn paint_image(layout_box: &LayoutBox, image: &Image, framebuffer: &mut Framebuffer) {
+    let x = (layout_box.x + layout_box.border.left + layout_box.padding.left) as i32;
+    let y = (layout_box.y + layout_box.border.top + layout_box.padding.top) as i32 - framebuffer.scroll_y;
+    let width = layout_box.content_width as i32;
+    let height = layout_box.content_height as i32;
+    if width <= 0 || height <= 0 {
+        return;
+    }
+    // Only the rows and columns inside the framebuffer are sampled
+    let x0 = x.max(0);
+    let y0 = y.max(0);
+    let x1 = (x + width).min(framebuffer.width as i32);
+    let y1 = (y + height).min(framebuffer.height as i32);
+    for py in y0..y1 {
+        let sy = ((py - y) as u64 * image.height as u64 / height as u64) as u32;
+        let row = (py as u32 * framebuffer.width) as usize;
+        for px in x0..x1 {
+            let sx = ((px - x) as u64 * image.width as u64 / width as u64) as u32;
+            let src = image.pixel(sx, sy);
+            let dst = &mut framebuffer.data[row + px as usize];
+            *dst = colors::blend(*dst, src);
+        }
     }
+}
 
-    // Render children
-    for child in &layout_box.children {
-        render_box(child, framebuffer, layout_box.x + offset_x, layout_box.y + offset_y)?;
+/// Draw an image that has not been loaded: a frame with the alt text
+fn paint_image_placeholder(layout_box: &LayoutBox, alt: &str, framebuffer: &mut Framebuffer) {
+    let x = (layout_box.x + layout_box.border.left) as i32;
+    let y = (layout_box.y + layout_box.border.top) as i32;
+    let width = layout_box.content_width as u32 + layout_box.padding.horizontal() as u32;
+    let height = layout_box.content_height as u32 + layout_box.padding.vertical() as u32;
+    let frame = colors::rgb(0xC0, 0xC0, 0xC0);
+    framebuffer.draw_rect(x, y, width, height, frame);
+
+    let (glyph_w, glyph_h) = layout::glyph_size(layout_box.styles.font_size);
+    let fits = ((width as f32 - 8.0) / glyph_w) as usize;
+    let shown: String = alt.chars().take(fits).collect();
+    let text_y = y + (height as i32 - glyph_h as i32) / 2;
+    draw_text(framebuffer, &shown, x + 4, text_y, &layout_box.styles);
+}
+
+/// Outline the focused form control `index`, and draw the caret at the
+/// end of its text if it is editable
+fn paint_focus(control: &LayoutBox, index: u16, framebuffer: &mut Framebuffer) {
+    let ring = colors::rgb(0x33, 0x66, 0xCC);
+    let x = control.x as i32;
+    let y = control.y as i32;
+    let width = control.width as u32;
+    let height = control.height as u32;
+    framebuffer.draw_rect(x - 1, y - 1, width + 2, height + 2, ring);
+    framebuffer.draw_rect(x - 2, y - 2, width + 4, height + 4, ring);
+    if !control.styles.editable {
+        return;
     }
 
-    Ok(())
+    // After the last fragment of the value, or at the start of the last
+    // line if it has none
+    let left = control.x + control.border.left + control.padding.left;
+    let top = control.y + control.border.top + control.padding.top;
+    let (caret_x, caret_y, caret_h) = match control.lines.last() {
+        Some(line) => {
+            let end = line.fragments.last()
+                .filter(|f| f.styles.control == Some(index))
+                .map_or(left, |f| f.x + f.width);
+            (end, line.y, line.height)
+        }
+        None => (left, top, layout::line_height(control.styles.font_size)),
+    };
+    framebuffer.fill_rect(caret_x as i32, caret_y as i32 + 2, 1, (caret_h as u32).saturating_sub(4), to_pixel(control.styles.color));
 }
 
-/// Render text (simplified bitmap font)
-fn render_text(framebuffer: &mut Framebuffer, text: &str, x: i32, y: i32, font_size: f32, color: u32) {
-    let char_width = (font_size * 0.6) as i32;
-    let char_height = (font_size * 1.2) as i32;
-    
-    for (i, ch) in text.chars().enumerate() {
-        let char_x = x + (i as i32 * char_width);
-        render_char(framebuffer, ch, char_x, y, char_width, char_height, color);
+/// Paint one piece of a line: highlight, text and underline, or an atomic box
+fn paint_fragment(fragment: &Fragment, framebuffer: &mut Framebuffer) {
+    let text = match &fragment.kind {
+        FragmentKind::Atomic(inner) => return paint_box(inner, framebuffer),
+        FragmentKind::Text(text) => text,
+    };
+    // Text scrolled out of view is not drawn at all
+    let top = framebuffer.scroll_y as f32;
+    if fragment.y + fragment.height <= top || fragment.y >= top + framebuffer.height as f32 {
+        return;
+    }
+    let styles = &fragment.styles;
+    let x = fragment.x as i32;
+    let y = fragment.y as i32;
+    if let Some(bg) = styles.background_color {
+        framebuffer.fill_rect(x, y, fragment.width as u32, fragment.height as u32, to_pixel(bg));
+    }
+
+    // Glyphs sit centered in the line height
+    let (_, glyph_h) = layout::glyph_size(styles.font_size);
+    let text_y = y + ((fragment.height - glyph_h) / 2.0) as i32;
+    draw_text(framebuffer, text, x, text_y, styles);
+    if styles.underline {
+        let thickness = (glyph_h / 16.0).max(1.0) as u32;
+        let underline_y = text_y + glyph_h as i32 - thickness as i32;
+        framebuffer.fill_rect(x, underline_y, fragment.width as u32, thickness, to_pixel(styles.color));
     }
 }
 
-/// Render a single character (simplified)
-fn render_char(framebuffer: &mut Framebuffer, ch: char, x: i32, y: i32, width: i32, height: i32, color: u32) {
-    // Simple block representation of characters
-    // In a real implementation, this would use a proper font atlas
-    
-    match ch {
-        ' ' => {}
-        '!' => {
-            framebuffer.fill_rect(x + width / 2 - 1, y, 2, (height * 2 / 3) as u32, color);
-            framebuffer.fill_rect(x + width / 2 - 1, y + height - 3, 2, 3, color);
-        }
-        '.' => {
-            framebuffer.fill_rect(x + width / 2 - 1, y + height - 4, 2, 2, color);
-        }
-        ',' => {
-            framebuffer.fill_rect(x + width / 2 - 1, y + height - 4, 2, 2, color);
-            framebuffer.fill_rect(x + width / 2, y + height - 2, 2, 2, color);
-        }
-        '-' => {
-            framebuffer.fill_rect(x, y + height / 2, width as u32, 2, color);
-        }
-        '_' => {
-            framebuffer.fill_rect(x, y + height - 2, width as u32, 2, color);
-        }
-        '/' => {
-            framebuffer.draw_line(x, y + height, x + width, y, color);
-        }
-        '0'..='9' | 'A'..='Z' | 'a'..='z' => {
-            // Draw a simple filled rectangle for letters/digits
-            framebuffer.fill_rect(x + 1, y + 1, (width - 2) as u32, (height - 2) as u32, color);
-        }
-        _ => {
-            // Default: small rectangle
-            framebuffer.fill_rect(x + width / 4, y + height / 4, (width / 2) as u32, (height / 2) as u32, color);
+/// Draw a line of text with its top left at (x, y)
+///
+/// Each glyph of the bitmap font is scaled to the font size by repeating
+/// pixels; bold text is drawn twice, one pixel apart.
+fn draw_text(framebuffer: &mut Framebuffer, text: &str, x: i32, y: i32, styles: &LayoutStyles) {
+    let (glyph_w, glyph_h) = layout::glyph_size(styles.font_size);
+    let color = to_pixel(styles.color);
+    let bold = styles.font_weight == FontWeight::Bold;
+    let mut pen = x as f32;
+    for ch in text.chars() {
+        if ch != ' ' {
+            let glyph = font::glyph(ch);
+            let gx = pen as i32;
+            let (w, h) = (glyph_w as u32, glyph_h as u32);
+            // Source pixel column/row `n` covers target pixels
+            // n * size / cells up to (n + 1) * size / cells
+            let scale = |n: u32, size: u32, cells: u32| (n * size / cells) as i32;
+            glyph.for_each_run(|rx, ry, len| {
+                let x0 = gx + scale(rx, w, glyph.width);
+                let x1 = gx + scale(rx + len, w, glyph.width);
+                let y0 = y + scale(ry, h, glyph.height);
+                let y1 = y + scale(ry + 1, h, glyph.height);
+                let run_w = (x1 - x0) as u32 + if bold { 1 } else { 0 };
+                framebuffer.fill_rect(x0, y0, run_w, (y1 - y0) as u32, color);
+            });
         }
+        pen += glyph_w;
     }
 }
 
+/// Pixel value of a page color, in the compositor's channel order
+fn to_pixel(c: Color) -> u32 {
+    colors::rgb(c.r, c.g, c.b)
+}
+
 /// Convert RGB to u32 color
 fn rgb_to_u32(r: u8, g: u8, b: u8) -> u32 {
-    0xFF000000 | ((b as u32) << 16) | ((g as u32) << 8) | (r as u32)
+    colors::rgb(r, g, b)
 }
 
 /// Initialize render engine
 pub fn init() {
-    println!("[render] Rendering engine initialized");
+    info!("render", "Rendering engine initialized");
 }
 
 /// Create a simple test pattern
@@ -10,7 +10,7 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 
 use crate::browser::BrowserError;
-use crate::println;
+use crate::{info, warn};
 
 /// WebAssembly value types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -718,13 +718,13 @@ pub fn load(data: &[u8]) -> Result<Module, BrowserError> {
 
 /// Initialize WebAssembly engine
 pub fn init() {
-    println!("[wasm] WebAssembly engine initialized");
+    info!("wasm", "WebAssembly engine initialized");
 }
 
 /// Execute a simple test program
 pub fn test() -> Result<(), BrowserError> {
     // Simple i32 addition: (i32.add (i32.const 1) (i32.const 2))
     // This would need a proper wasm binary to test
-    println!("[wasm] WebAssembly test not implemented");
+    warn!("wasm", "WebAssembly test not implemented");
     Ok(())
 }
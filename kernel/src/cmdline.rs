@@ -0,0 +1,175 @@
+//! Kernel command line
+//!
+//! The bootloader reads it from `cmdline.txt` on the EFI system partition
+//! and passes it in the boot info. Options are separated by spaces and are
+//! either a bare flag or `key=value`:
+//!
+//! - `loglevel=LEVEL`: least severe log records kept, by name or 1 to 5
+//! - `video=WIDTHxHEIGHT[xBPP]`: display mode
+//! - `root=DEVICE`: block device, by index or name, whose filesystem is
+//!   mounted at `/` in place of the RAM root
+//! - `nosmp`: use the boot processor alone; it is all the kernel runs on
+//!   for now, so this is accepted and changes nothing
+//! - `console=ttyS0[,BAUD]`: speed of the serial console
+//! - `test`: run the kernel tests once up, then leave QEMU with the result
+//!
+//! For this boot they win over the same settings in `/etc/webbos.conf`.
+//! Options the kernel does not know are kept all the same, so `cmdline`
+//! and `/proc/cmdline` show the line as it was given.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::log::{self, Level};
+use crate::{console, fs, println, storage};
+use crate::{info, warn};
+
+/// Options and the settings they stand in for
+const SETTINGS: [(&str, &str); 3] = [
+    ("loglevel", "log.level"),
+    ("video", "display.mode"),
+    ("console", "serial.baud"),
+];
+
+static CMDLINE: Mutex<String> = Mutex::new(String::new());
+
+/// Take the command line from the bootloader and apply what has to be in
+/// effect from the start
+pub fn init(text: &str) {
+    *CMDLINE.lock() = String::from(text.trim());
+    if text.trim().is_empty() {
+        return;
+    }
+    info!("cmdline", "{}", text.trim());
+    for (key, value) in options() {
+        match (key.as_str(), value) {
+            ("loglevel", Some(value)) => match level(&value) {
+                Some(level) => log::set_level(Some(level)),
+                None => warn!("cmdline", "loglevel: no level '{}'", value),
+            },
+            ("console", Some(value)) => match console_baud(&value) {
+                Some(baud) => {
+                    if !console::set_serial_baud(baud) {
+                        warn!("cmdline", "console: {} baud is not supported", baud);
+                    }
+                }
+                None => warn!("cmdline", "console: expected ttyS0[,baud], not '{}'", value),
+            },
+            ("video" | "root", Some(_)) | ("nosmp" | "test", None) => {}
+            (key, _) => warn!("cmdline", "Unknown option {}", key),
+        }
+    }
+}
+
+/// The command line as given
+pub fn text() -> String {
+    CMDLINE.lock().clone()
+}
+
+/// Every option in order, with its value if it has one
+pub fn options() -> Vec<(String, Option<String>)> {
+    CMDLINE.lock()
+        .split_whitespace()
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (String::from(key), Some(String::from(value))),
+            None => (String::from(option), None),
+        })
+        .collect()
+}
+
+/// Whether the bare flag `name` was given
+pub fn flag(name: &str) -> bool {
+    options().iter().any(|(key, value)| key == name && value.is_none())
+}
+
+/// Value of the last `key=value` option for `key`
+pub fn get(key: &str) -> Option<String> {
+    options().into_iter().rev().find(|(k, _)| k == key).and_then(|(_, value)| value)
+}
+
+/// The value the command line gives the config setting `key`, if any
+pub fn setting(key: &str) -> Option<String> {
+    let (option, _) = SETTINGS.iter().find(|(_, setting)| *setting == key)?;
+    let value = get(option)?;
+    match *option {
+        "loglevel" => level(&value).map(|level| String::from(level.name())),
+        "console" => console_baud(&value).map(|baud| format!("{}", baud)),
+        _ => Some(value),
+    }
+}
+
+/// A log level by name or number
+fn level(value: &str) -> Option<Level> {
+    Level::from_name(value).or_else(|| Level::ALL.into_iter().find(|&l| value.parse() == Ok(l as u8)))
+}
+
+/// Baud rate of `ttyS0[,BAUD]`; the current rate if none is given
+fn console_baud(value: &str) -> Option<u32> {
+    let (port, baud) = match value.split_once(',') {
+        Some((port, baud)) => (port, Some(baud)),
+        None => (value, None),
+    };
+    if port != "ttyS0" {
+        return None;
+    }
+    match baud {
+        Some(baud) => baud.parse().ok(),
+        None => Some(console::serial_baud()),
+    }
+}
+
+/// Mount the filesystem on the `root=` device at `/` in place of the RAM
+/// root; block devices have to be up
+pub fn mount_root() {
+    let name = match get("root") {
+        Some(name) => name,
+        None => return,
+    };
+    let device = match storage::find_device(&name) {
+        Some(device) => device,
+        None => {
+            warn!("cmdline", "root: no block device {}, keeping the RAM root", name);
+            return;
+        }
+    };
+    let filesystem = match fs::fat32::mount(device) {
+        Ok(filesystem) => filesystem,
+        Err(_) => match storage::find_device(&name).map(fs::ext2::mount) {
+            Some(Ok(filesystem)) => filesystem,
+            _ => {
+                warn!("cmdline", "root: no FAT32 or ext2 filesystem on {}, keeping the RAM root", name);
+                return;
+            }
+        },
+    };
+    let _ = fs::unmount("/");
+    if let Err(e) = fs::mount("/", Arc::from(filesystem)) {
+        warn!("cmdline", "root: could not mount {}: {:?}", name, e);
+    }
+}
+
+/// Contents of `/proc/cmdline`
+pub fn contents() -> Vec<u8> {
+    let mut text = text();
+    text.push('\n');
+    text.into_bytes()
+}
+
+/// Print the command line and its options
+pub fn print_info() {
+    let text = text();
+    if text.is_empty() {
+        println!("No kernel command line (put one in cmdline.txt on the boot partition)");
+        return;
+    }
+    println!("{}", text);
+    for (key, value) in options() {
+        match value {
+            Some(value) => println!("  {:<10} {}", key, value),
+            None => println!("  {}", key),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! System configuration store
+//!
+//! Settings are `key = value` lines in `/etc/webbos.conf`; `#` starts a
+//! comment. `init` reads the file at boot and applies what it finds. `set`
+//! checks a new value, puts it into effect right away through the
+//! subsystem it belongs to, and saves the file. Options on the kernel
+//! command line win over the file for the boot they are given on.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+use crate::drivers::input::{self, Layout};
+use crate::drivers::rtc;
+use crate::fs::{self, FsError};
+use crate::log::{self, Level};
+use crate::net::{self, Ipv4Address, NetworkConfig};
+use crate::{cmdline, console, desktop, println, sound, tls, users, watchdog};
+use crate::{info, warn};
+
+/// Where the settings are kept
+pub const CONFIG_PATH: &str = "/etc/webbos.conf";
+
+/// A setting the store knows about
+pub struct Setting {
+    pub key: &'static str,
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+/// Every setting, in the order they are listed and saved
+pub const SETTINGS: [Setting; 35] = [
+    Setting { key: "display.mode", default: "", description: "WIDTHxHEIGHTxBPP; empty keeps the boot mode" },
+    Setting { key: "desktop.wallpaper", default: "default", description: "Desktop background" },
+    Setting { key: "desktop.theme", default: "light", description: "Window colors, light or dark" },
+    Setting { key: "desktop.lock_timeout", default: "5", description: "Idle minutes before locking; 0 never" },
+    Setting { key: "keyboard.layout", default: "us", description: "Keyboard layout: us, uk, de, fr or dvorak" },
+    Setting { key: "clock.timezone", default: "UTC", description: "Offset of local time from UTC, e.g. UTC+2 or UTC-5:30" },
+    Setting { key: "network.mode", default: "dhcp", description: "dhcp or static" },
+    Setting { key: "network.address", default: "", description: "Static IPv4 address" },
+    Setting { key: "network.netmask", default: "255.255.255.0", description: "Static netmask" },
+    Setting { key: "network.gateway", default: "", description: "Static default gateway" },
+    Setting { key: "network.dns", default: "", description: "Static DNS server" },
+    Setting { key: "network.dns_mode", default: "udp", description: "How names are looked up: udp, tls or https" },
+    Setting { key: "network.dns_upstream", default: "1.1.1.1", description: "Resolver for tls and https lookups" },
+    Setting { key: "network.dns_fallback", default: "on", description: "Look up over udp when tls or https fails, on or off" },
+    Setting { key: "network.proxy", default: "", description: "socks5://[user:password@]host:port to tunnel web traffic through; empty for none" },
+    Setting { key: "network.tcp_congestion", default: "cubic", description: "How TCP grows its window: cubic or reno" },
+    Setting { key: "network.arp_gateway_updates", default: "off", description: "Let ARP change the gateway's known MAC address: on or off" },
+    Setting { key: "network.tcp_delayed_ack", default: "40", description: "Milliseconds TCP may hold an acknowledgement back, up to 500; 0 for none" },
+    Setting { key: "nat.inside", default: "", description: "Interface of the network to route for, e.g. eth1; empty for no NAT" },
+    Setting { key: "nat.outside", default: "", description: "Interface its traffic leaves through, from this machine's address" },
+    Setting { key: "nat.forwards", default: "", description: "Outside ports that lead inside, e.g. tcp:8080=192.168.7.2:80,udp:53=192.168.7.2" },
+    Setting { key: "tls.keylog", default: "", description: "File to append TLS secrets to for Wireshark, or serial; empty for none" },
+    Setting { key: "tls.trace", default: "off", description: "Log each step of TLS handshakes, on or off" },
+    Setting { key: "auth.server", default: "", description: "https:// URL that checks passwords before local accounts" },
+    Setting { key: "log.level", default: "info", description: "Least severe log records kept: error, warn, info, debug or trace" },
+    Setting { key: "log.modules", default: "", description: "Levels for single modules, e.g. vfs=debug,js=warn" },
+    Setting { key: "log.console", default: "info", description: "Least severe log records shown on screen, or off" },
+    Setting { key: "log.serial", default: "info", description: "Least severe log records sent to serial, or off" },
+    Setting { key: "serial.baud", default: "115200", description: "Serial console speed: 115200 down to 1200" },
+    Setting { key: "serial.flow_control", default: "off", description: "RTS/CTS flow control on the serial ports, on or off" },
+    Setting { key: "serial.log_port", default: "", description: "Port for log records alone, e.g. ttyS1; empty uses the console port" },
+    Setting { key: "watchdog.timeout", default: "10", description: "Seconds a subsystem may go unseen before it is reported stuck; 0 never" },
+    Setting { key: "watchdog.action", default: "log", description: "What else to do when one is stuck: log, backtrace or reboot" },
+    Setting { key: "sound.volume", default: "80", description: "Master volume, 0 to 100" },
+    Setting { key: "sound.notifications", default: "on", description: "Chime when a notification comes in, on or off" },
+];
+
+/// Why a setting could not be changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    UnknownKey(String),
+    InvalidValue { key: &'static str, value: String },
+    /// The subsystem the setting belongs to refused it
+    Failed(String),
+    /// The new value is in effect but could not be written to the file
+    NotSaved(FsError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::UnknownKey(key) => write!(f, "no setting '{}'", key),
+            ConfigError::InvalidValue { key, value } => write!(f, "'{}' is not a valid {}", value, key),
+            ConfigError::Failed(message) => write!(f, "{}", message),
+            ConfigError::NotSaved(e) => write!(f, "in effect, but not saved to {}: {:?}", CONFIG_PATH, e),
+        }
+    }
+}
+
+/// Values read from the file or changed with `set`; the rest have their
+/// defaults
+static STORE: Mutex<BTreeMap<&'static str, String>> = Mutex::new(BTreeMap::new());
+
+fn setting(key: &str) -> Result<&'static Setting, ConfigError> {
+    SETTINGS.iter().find(|s| s.key == key).ok_or_else(|| ConfigError::UnknownKey(String::from(key)))
+}
+
+/// Current value of a setting
+pub fn get(key: &str) -> Option<String> {
+    let setting = setting(key).ok()?;
+    Some(STORE.lock().get(setting.key).cloned().unwrap_or_else(|| String::from(setting.default)))
+}
+
+/// Every setting with its current value
+pub fn entries() -> Vec<(&'static str, String)> {
+    let store = STORE.lock();
+    SETTINGS.iter()
+        .map(|s| (s.key, store.get(s.key).cloned().unwrap_or_else(|| String::from(s.default))))
+        .collect()
+}
+
+/// Change a setting, put it into effect and save the file
+///
+/// A value the subsystem refuses leaves the old one in place.
+pub fn set(key: &str, value: &str) -> Result<(), ConfigError> {
+    let setting = setting(key)?;
+    let value = value.trim();
+    if value.contains('\n') {
+        return Err(ConfigError::InvalidValue { key: setting.key, value: String::from(value) });
+    }
+    let previous = STORE.lock().insert(setting.key, String::from(value));
+    if let Err(e) = apply(setting.key, value) {
+        let mut store = STORE.lock();
+        match previous {
+            Some(previous) => store.insert(setting.key, previous),
+            None => store.remove(setting.key),
+        };
+        return Err(e);
+    }
+    info!("config", "{} = {}", setting.key, value);
+    save().map_err(ConfigError::NotSaved)
+}
+
+/// Put a setting's value into effect
+fn apply(key: &'static str, value: &str) -> Result<(), ConfigError> {
+    let invalid = || ConfigError::InvalidValue { key, value: String::from(value) };
+    match key {
+        "display.mode" => {
+            if value.is_empty() {
+                return Ok(());
+            }
+            let (width, height, bpp) = parse_mode(value).ok_or_else(invalid)?;
+            desktop::set_display_mode(width, height, bpp)
+                .map_err(|e| ConfigError::Failed(format!("Could not switch to {}: {:?}", value, e)))
+        }
+        "desktop.wallpaper" => desktop::set_wallpaper(value).then_some(()).ok_or_else(invalid),
+        "desktop.theme" => desktop::set_theme(value).then_some(()).ok_or_else(invalid),
+        "desktop.lock_timeout" => {
+            desktop::set_lock_timeout(value.parse().map_err(|_| invalid())?);
+            Ok(())
+        }
+        "keyboard.layout" => {
+            input::set_layout(Layout::from_name(value).ok_or_else(invalid)?);
+            Ok(())
+        }
+        "clock.timezone" => {
+            rtc::set_offset(rtc::parse_timezone(value).ok_or_else(invalid)?);
+            Ok(())
+        }
+        "network.mode" => match value {
+            "dhcp" => {
+                net::dhcp::start_dhcp();
+                Ok(())
+            }
+            "static" => apply_static_network(),
+            _ => Err(invalid()),
+        },
+        "network.address" | "network.netmask" | "network.gateway" | "network.dns" => {
+            if !value.is_empty() && Ipv4Address::parse(value).is_none() {
+                return Err(invalid());
+            }
+            if get("network.mode").as_deref() == Some("static") {
+                apply_static_network()?;
+            }
+            Ok(())
+        }
+        // Read by each lookup
+        "network.dns_mode" => net::dns::Transport::from_name(value).map(|_| ()).ok_or_else(invalid),
+        "network.dns_upstream" => Ipv4Address::parse(value).map(|_| ()).ok_or_else(invalid),
+        "network.dns_fallback" | "network.arp_gateway_updates" | "tls.trace" => match value {
+            "on" | "off" => Ok(()),
+            _ => Err(invalid()),
+        },
+        // Read by each connection
+        "network.proxy" if value.is_empty() => Ok(()),
+        "network.proxy" => net::socks::Proxy::parse(value).map(|_| ()).map_err(|_| invalid()),
+        "network.tcp_congestion" => net::tcp::Algorithm::from_name(value).map(|_| ()).ok_or_else(invalid),
+        "network.tcp_delayed_ack" => match value.parse::<u64>() {
+            Ok(ms) if ms <= net::tcp::MAX_ACK_DELAY_MS => Ok(()),
+            _ => Err(invalid()),
+        },
+        // Read by each handshake
+        "tls.keylog" if value.is_empty() => Ok(()),
+        "tls.keylog" => tls::keylog::Sink::parse(value).map(|_| ()).ok_or_else(invalid),
+        "nat.inside" | "nat.outside" | "nat.forwards" => net::nat::configure().map_err(|e| ConfigError::Failed(String::from(e))),
+        "auth.server" => users::auth::set_server(value).map_err(|e| ConfigError::Failed(String::from(e))),
+        "log.level" => {
+            log::set_level(Some(Level::from_name(value).ok_or_else(invalid)?));
+            Ok(())
+        }
+        "log.modules" => {
+            log::set_filters(log::parse_filters(value).ok_or_else(invalid)?);
+            Ok(())
+        }
+        "log.console" | "log.serial" => {
+            let level = match value {
+                "off" => None,
+                _ => Some(Level::from_name(value).ok_or_else(invalid)?),
+            };
+            if key == "log.console" {
+                log::set_console_level(level);
+            } else {
+                log::set_serial_level(level);
+            }
+            Ok(())
+        }
+        "serial.baud" => {
+            let baud = value.parse().map_err(|_| invalid())?;
+            console::set_serial_baud(baud).then_some(()).ok_or_else(invalid)
+        }
+        "serial.flow_control" => {
+            match value {
+                "on" => console::set_serial_flow_control(true),
+                "off" => console::set_serial_flow_control(false),
+                _ => return Err(invalid()),
+            }
+            Ok(())
+        }
+        "serial.log_port" => console::set_log_port(value).then_some(()).ok_or_else(invalid),
+        "watchdog.timeout" => {
+            watchdog::set_timeout(value.parse().map_err(|_| invalid())?);
+            Ok(())
+        }
+        "watchdog.action" => {
+            watchdog::set_action(watchdog::Action::from_name(value).ok_or_else(invalid)?);
+            Ok(())
+        }
+        "sound.volume" => match value.parse() {
+            Ok(volume) if volume <= 100 => {
+                sound::set_volume(volume);
+                Ok(())
+            }
+            _ => Err(invalid()),
+        },
+        "sound.notifications" => {
+            match value {
+                "on" => sound::set_chime_enabled(true),
+                "off" => sound::set_chime_enabled(false),
+                _ => return Err(invalid()),
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parse a display mode: `WIDTHxHEIGHT`, optionally followed by `xBPP`
+fn parse_mode(value: &str) -> Option<(u32, u32, u8)> {
+    let mut parts = value.split('x');
+    let width = parts.next()?.trim().parse().ok()?;
+    let height = parts.next()?.trim().parse().ok()?;
+    let bpp = match parts.next() {
+        Some(bpp) => bpp.trim().parse().ok()?,
+        None => 32,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((width, height, bpp))
+}
+
+/// Configure the network from the static settings and stop DHCP
+fn apply_static_network() -> Result<(), ConfigError> {
+    let address = |key| get(key).and_then(|v| Ipv4Address::parse(&v));
+    let ip = address("network.address")
+        .ok_or_else(|| ConfigError::Failed(String::from("Set network.address before static mode")))?;
+    net::dhcp::stop();
+    net::set_config(NetworkConfig {
+        ip,
+        netmask: address("network.netmask").unwrap_or(Ipv4Address::from_octets(255, 255, 255, 0)),
+        gateway: address("network.gateway").unwrap_or(Ipv4Address::unspecified()),
+        dns: address("network.dns").unwrap_or(Ipv4Address::unspecified()),
+    });
+    Ok(())
+}
+
+/// Write every setting that has been given a value to the file
+pub fn save() -> Result<(), FsError> {
+    let mut text = String::from("# WebbOS settings, key = value\n");
+    let store = STORE.lock();
+    for (key, value) in SETTINGS.iter().filter_map(|s| Some((s.key, store.get(s.key)?))) {
+        text.push_str(&format!("{} = {}\n", key, value));
+    }
+    drop(store);
+    fs::write_file(CONFIG_PATH, text.as_bytes())
+}
+
+/// Read the settings file and apply it
+pub fn init() {
+    let data = match fs::read_file(CONFIG_PATH) {
+        Ok(data) => data,
+        Err(_) => {
+            info!("config", "No {}, using defaults", CONFIG_PATH);
+            return;
+        }
+    };
+    let text = String::from_utf8_lossy(&data);
+    {
+        let mut store = STORE.lock();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let entry = line.split_once('=')
+                .and_then(|(key, value)| Some((setting(key.trim()).ok()?.key, value.trim())));
+            match entry {
+                Some((key, value)) => {
+                    store.insert(key, String::from(value));
+                }
+                None => warn!("config", "{}:{}: ignoring '{}'", CONFIG_PATH, n + 1, line),
+            }
+        }
+    }
+    for (key, value) in entries() {
+        if let Some(value) = cmdline::setting(key) {
+            if let Err(e) = apply(key, &value) {
+                warn!("config", "{} from the command line: {}", key, e);
+            }
+            continue;
+        }
+        // Applying the network mode takes the address settings with it
+        let network_detail = key.starts_with("network.") && key != "network.mode";
+        if network_detail || Some(value.as_str()) == setting(key).ok().map(|s| s.default) {
+            continue;
+        }
+        if let Err(e) = apply(key, &value) {
+            warn!("config", "{}: {}", key, e);
+        }
+    }
+    info!("config", "Loaded {}", CONFIG_PATH);
+}
+
+/// Print every setting
+pub fn print_info() {
+    println!("Settings ({}):", CONFIG_PATH);
+    for (setting, (_, value)) in SETTINGS.iter().zip(entries()) {
+        println!("  {:<18} {:<16} {}", setting.key, value, setting.description);
+    }
+}
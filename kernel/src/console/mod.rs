@@ -1,12 +1,18 @@
 //! Console output
 //!
-//! Provides VGA text mode and serial port output.
+//! Provides VGA text mode and serial port output. Once the framebuffer
+//! console is up it takes over from VGA text mode. COM1 carries both
+//! output and input; log records can go to a second port of their own.
 
+use alloc::string::String;
 use core::fmt;
 use spin::Mutex;
 
 mod vga;
 mod serial;
+mod readline;
+
+pub use readline::LineEditor;
 
 /// Global writer for console output
 static WRITER: Mutex<ConsoleWriter> = Mutex::new(ConsoleWriter::new());
@@ -15,6 +21,8 @@ static WRITER: Mutex<ConsoleWriter> = Mutex::new(ConsoleWriter::new());
 struct ConsoleWriter {
     vga: Option<vga::Writer>,
     serial: Option<serial::SerialPort>,
+    log_serial: Option<serial::SerialPort>, // Port for log records only
+    capture: Option<String>, // Output diverted by `capture`
 }
 
 impl ConsoleWriter {
@@ -22,19 +30,28 @@ impl ConsoleWriter {
         Self {
             vga: None,
             serial: None,
+            log_serial: None,
+            capture: None,
         }
     }
 
     fn init(&mut self) {
         self.vga = Some(vga::Writer::new());
-        self.serial = Some(serial::SerialPort::new(serial::COM1));
+        self.serial = Some(serial::SerialPort::new(serial::COM1, serial::DEFAULT_BAUD));
     }
 }
 
 impl fmt::Write for ConsoleWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        // Write to VGA
-        if let Some(ref mut vga) = self.vga {
+        if let Some(ref mut buf) = self.capture {
+            buf.push_str(s);
+            return Ok(());
+        }
+
+        // Write to the framebuffer console, or VGA before it exists
+        if crate::graphics::fbcon::is_enabled() {
+            crate::graphics::fbcon::write_str(s);
+        } else if let Some(ref mut vga) = self.vga {
             vga.write_str(s)?;
         }
         
@@ -52,15 +69,101 @@ pub fn init() {
     WRITER.lock().init();
 }
 
-/// Get a character from input
-pub fn getchar() -> Option<u8> {
-    // Try serial first, then keyboard
-    if let Some(c) = serial::try_receive() {
-        return Some(c);
+/// Take serial input by interrupt; once interrupts are set up
+pub fn listen_serial() {
+    serial::listen();
+}
+
+/// Serial baud rate, 115200 until changed
+pub fn serial_baud() -> u32 {
+    WRITER.lock().serial.as_ref().map_or(serial::DEFAULT_BAUD, |port| port.baud())
+}
+
+/// Change the baud rate of the serial ports; false if it is not one the
+/// UART supports
+pub fn set_serial_baud(baud: u32) -> bool {
+    if !serial::BAUD_RATES.contains(&baud) {
+        return false;
+    }
+    let mut writer = WRITER.lock();
+    let writer = &mut *writer;
+    for port in writer.serial.iter_mut().chain(writer.log_serial.iter_mut()) {
+        port.set_baud(baud);
     }
-    
-    // TODO: Add PS/2 keyboard support
-    None
+    true
+}
+
+/// Turn RTS/CTS flow control on the serial ports on or off
+pub fn set_serial_flow_control(on: bool) {
+    serial::set_flow_control(on);
+}
+
+/// Send log records to the serial port `name` (`ttyS1` or `com2`, say)
+/// instead of the console port, or back to it if `name` is empty; false if
+/// there is no such port
+pub fn set_log_port(name: &str) -> bool {
+    let port = match name {
+        "" => None,
+        _ => match serial::port(name) {
+            Some(serial::COM1) | None => return false,
+            Some(port) => Some(port),
+        },
+    };
+    let baud = serial_baud();
+    WRITER.lock().log_serial = port.map(|port| serial::SerialPort::new(port, baud));
+    true
+}
+
+/// Run `f` and return what it printed instead of showing it
+pub fn capture<F: FnOnce()>(f: F) -> String {
+    let previous = WRITER.lock().capture.replace(String::new());
+    f();
+    let mut writer = WRITER.lock();
+    let output = writer.capture.take().unwrap_or_default();
+    writer.capture = previous;
+    output
+}
+
+/// Write a log record to the screen, the serial port or both; unlike
+/// printing, this is never captured
+pub fn write_log(args: fmt::Arguments, screen: bool, serial: bool) {
+    use core::fmt::Write;
+
+    struct Fbcon;
+
+    impl Write for Fbcon {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            crate::graphics::fbcon::write_str(s);
+            Ok(())
+        }
+    }
+
+    let mut writer = WRITER.lock();
+    if screen {
+        if crate::graphics::fbcon::is_enabled() {
+            let _ = Fbcon.write_fmt(args);
+        } else if let Some(ref mut vga) = writer.vga {
+            let _ = vga.write_fmt(args);
+        }
+    }
+    if serial {
+        let writer = &mut *writer;
+        if let Some(port) = writer.log_serial.as_mut().or(writer.serial.as_mut()) {
+            let _ = port.write_fmt(args);
+        }
+    }
+}
+
+/// Write straight to COM1, for reports made from interrupts while the
+/// code they interrupted may hold the console's lock
+pub fn emergency_write(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = serial::unlocked(serial::COM1).write_fmt(args);
+}
+
+/// Read a byte straight from COM1, if one has come
+pub fn emergency_read() -> Option<u8> {
+    serial::unlocked(serial::COM1).read_byte()
 }
 
 /// Print to console
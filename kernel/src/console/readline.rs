@@ -0,0 +1,518 @@
+//! Console line editor
+//!
+//! Reads command lines from the serial port or, while no desktop session
+//! has it, the keyboard. Left and Right move the cursor, Home and End (or
+//! Ctrl+A and Ctrl+E) jump to either end, and Up and Down recall earlier
+//! lines, which are kept in `.console_history` in the home directory of
+//! whoever is logged in. Tab completes the command name and, after it,
+//! absolute VFS paths; when there is more than one way to go on, a second
+//! Tab lists them.
+//!
+//! The line is redrawn with backspaces and reprinting only, which the
+//! serial terminal, the framebuffer console and VGA text mode all follow.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::drivers::input::{self, MOD_CTRL, MOD_SHIFT};
+use crate::fs::{self, FileType};
+use crate::{print, println, users};
+
+/// Most lines the history keeps
+const MAX_HISTORY: usize = 100;
+
+/// History file in the user's home directory
+const HISTORY_FILE: &str = ".console_history";
+
+/// History file while nobody is logged in
+const SYSTEM_HISTORY: &str = "/var/console_history";
+
+/// A key that edits the line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(u8),
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Tab,
+    Backspace,
+    Delete,
+    Enter,
+}
+
+/// Line editor for the console prompt
+pub struct LineEditor {
+    prompt: &'static str,
+    /// Command names Tab completes
+    commands: Vec<&'static str>,
+    line: Vec<u8>,
+    cursor: usize,
+    history: Vec<String>,
+    /// Where the history came from and goes back to
+    history_path: Option<String>,
+    /// History entry on show; `history.len()` for the line being typed
+    recall: usize,
+    /// The line being typed, put back after recalling past the newest
+    /// entry
+    draft: Vec<u8>,
+    /// Bytes of an escape sequence from the serial port after ESC
+    escape: Option<Vec<u8>>,
+    /// The last key was a Tab that could not complete anything by itself
+    listing: bool,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &'static str, commands: Vec<&'static str>) -> Self {
+        Self {
+            prompt,
+            commands,
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_path: None,
+            recall: 0,
+            draft: Vec::new(),
+            escape: None,
+            listing: false,
+        }
+    }
+
+    /// Print the prompt for a new line, loading the history of whoever is
+    /// now logged in
+    pub fn start(&mut self) {
+        let path = history_path();
+        if self.history_path.as_deref() != Some(path.as_str()) {
+            self.history = fs::read_file(&path)
+                .map(|data| String::from_utf8_lossy(&data).lines().map(String::from).collect())
+                .unwrap_or_default();
+            let excess = self.history.len().saturating_sub(MAX_HISTORY);
+            self.history.drain(..excess);
+            self.history_path = Some(path);
+        }
+        self.line.clear();
+        self.draft.clear();
+        self.cursor = 0;
+        self.recall = self.history.len();
+        self.listing = false;
+        print!("{}", self.prompt);
+    }
+
+    /// Take waiting input; the line once Enter is pressed
+    pub fn poll(&mut self) -> Option<String> {
+        while let Some(key) = self.next_key() {
+            if let Some(line) = self.edit(key) {
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    /// Next key from the serial port, or the keyboard if nothing is
+    /// waiting there
+    fn next_key(&mut self) -> Option<Key> {
+        while let Some(byte) = super::serial::try_receive() {
+            if let Some(key) = self.serial_byte(byte) {
+                return Some(key);
+            }
+        }
+        loop {
+            let event = input::get_key()?;
+            if event.modifiers & MOD_SHIFT != 0 {
+                // Shift+PageUp/PageDown scroll the framebuffer console
+                match event.keycode {
+                    0x49 => {
+                        crate::graphics::fbcon::scroll_back();
+                        continue;
+                    }
+                    0x51 => {
+                        crate::graphics::fbcon::scroll_forward();
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            let key = match (event.ch, event.keycode) {
+                ('a', _) if event.modifiers & MOD_CTRL != 0 => Key::Home,
+                ('e', _) if event.modifiers & MOD_CTRL != 0 => Key::End,
+                ('\n', _) => Key::Enter,
+                ('\x08', _) => Key::Backspace,
+                ('\t', _) => Key::Tab,
+                (c, _) if c == ' ' || c.is_ascii_graphic() => Key::Char(c as u8),
+                ('\0', 0x48) => Key::Up,
+                ('\0', 0x50) => Key::Down,
+                ('\0', 0x4B) => Key::Left,
+                ('\0', 0x4D) => Key::Right,
+                ('\0', 0x47) => Key::Home,
+                ('\0', 0x4F) => Key::End,
+                ('\0', 0x53) => Key::Delete,
+                _ => continue,
+            };
+            return Some(key);
+        }
+    }
+
+    /// A byte from the serial port; ANSI escape sequences come a byte at
+    /// a time, so the key they stand for comes with the last
+    fn serial_byte(&mut self, byte: u8) -> Option<Key> {
+        if let Some(seq) = self.escape.as_mut() {
+            match (seq.len(), byte) {
+                (0, b'[' | b'O') => {
+                    seq.push(byte);
+                    return None;
+                }
+                (0, _) => {
+                    self.escape = None;
+                    return None;
+                }
+                (_, b'A'..=b'Z' | b'a'..=b'z' | b'~') => {}
+                _ => {
+                    seq.push(byte);
+                    return None;
+                }
+            }
+            let mut seq = self.escape.take().unwrap_or_default();
+            seq.push(byte);
+            return match seq.as_slice() {
+                b"[A" => Some(Key::Up),
+                b"[B" => Some(Key::Down),
+                b"[C" => Some(Key::Right),
+                b"[D" => Some(Key::Left),
+                b"[H" | b"OH" | b"[1~" | b"[7~" => Some(Key::Home),
+                b"[F" | b"OF" | b"[4~" | b"[8~" => Some(Key::End),
+                b"[3~" => Some(Key::Delete),
+                _ => None,
+            };
+        }
+        match byte {
+            0x1b => {
+                self.escape = Some(Vec::new());
+                None
+            }
+            b'\r' | b'\n' => Some(Key::Enter),
+            8 | 127 => Some(Key::Backspace),
+            b'\t' => Some(Key::Tab),
+            // Ctrl+A and Ctrl+E
+            1 => Some(Key::Home),
+            5 => Some(Key::End),
+            b' '..=b'~' => Some(Key::Char(byte)),
+            _ => None,
+        }
+    }
+
+    /// Apply a key to the line; the line once it is entered
+    fn edit(&mut self, key: Key) -> Option<String> {
+        let listing = core::mem::take(&mut self.listing);
+        match key {
+            Key::Char(c) => self.insert(&[c]),
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                print!("\x08");
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                print!("{}", self.line[self.cursor] as char);
+                self.cursor += 1;
+            }
+            Key::Home => {
+                back(self.cursor);
+                self.cursor = 0;
+            }
+            Key::End => {
+                print!("{}", text(&self.line[self.cursor..]));
+                self.cursor = self.line.len();
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                print!("\x08");
+                self.redraw_tail(1);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw_tail(1);
+            }
+            Key::Up if self.recall > 0 => {
+                if self.recall == self.history.len() {
+                    self.draft = self.line.clone();
+                }
+                self.recall -= 1;
+                let line = self.history[self.recall].clone().into_bytes();
+                self.replace(line);
+            }
+            Key::Down if self.recall < self.history.len() => {
+                self.recall += 1;
+                let line = match self.history.get(self.recall) {
+                    Some(entry) => entry.clone().into_bytes(),
+                    None => core::mem::take(&mut self.draft),
+                };
+                self.replace(line);
+            }
+            Key::Tab => self.complete(listing),
+            Key::Enter => {
+                println!();
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.remember(&line);
+                return Some(line);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Type `bytes` at the cursor
+    fn insert(&mut self, bytes: &[u8]) {
+        let at = self.cursor;
+        self.line.splice(at..at, bytes.iter().copied());
+        self.cursor += bytes.len();
+        print!("{}", text(&self.line[at..]));
+        back(self.line.len() - self.cursor);
+    }
+
+    /// Reprint the line from the cursor on, blanking the `removed` cells
+    /// it no longer reaches, and put the cursor back
+    fn redraw_tail(&self, removed: usize) {
+        let tail = &self.line[self.cursor..];
+        print!("{}{:width$}", text(tail), "", width = removed);
+        back(tail.len() + removed);
+    }
+
+    /// Show `line` in place of the current one, with the cursor at its end
+    fn replace(&mut self, line: Vec<u8>) {
+        back(self.cursor);
+        let old = self.line.len();
+        self.line = line;
+        self.cursor = self.line.len();
+        let removed = old.saturating_sub(self.line.len());
+        print!("{}{:width$}", text(&self.line), "", width = removed);
+        back(removed);
+    }
+
+    /// Complete the word before the cursor: a command name if it is the
+    /// first word, else a path. One candidate is taken whole; several
+    /// give their common start, and are listed if that adds nothing and
+    /// `list` is set
+    fn complete(&mut self, list: bool) {
+        let before = String::from_utf8_lossy(&self.line[..self.cursor]).into_owned();
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let candidates = if before[..start].trim().is_empty() {
+            self.commands.iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| format!("{} ", c))
+                .collect()
+        } else {
+            complete_path(word)
+        };
+        let common = match candidates.first() {
+            Some(first) => candidates.iter().fold(first.len(), |n, c| common_prefix(&first[..n], c)),
+            None => return,
+        };
+        if common > word.len() {
+            let rest = String::from(&candidates[0][word.len()..common]);
+            self.insert(rest.as_bytes());
+        } else if candidates.len() > 1 && list {
+            println!();
+            let shown: Vec<&str> = candidates.iter()
+                .map(|c| c.trim_end_matches(' ').rsplit('/').find(|p| !p.is_empty()).unwrap_or(c))
+                .collect();
+            println!("{}", shown.join("  "));
+            print!("{}{}", self.prompt, text(&self.line));
+            back(self.line.len() - self.cursor);
+        } else {
+            self.listing = true;
+        }
+    }
+
+    /// Add an entered line to the history and save it
+    fn remember(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(String::from(line));
+        let excess = self.history.len().saturating_sub(MAX_HISTORY);
+        self.history.drain(..excess);
+        if let Some(path) = &self.history_path {
+            let mut data = self.history.join("\n");
+            data.push('\n');
+            let _ = fs::write_file(path, data.as_bytes());
+        }
+    }
+}
+
+/// History file for whoever is logged in
+fn history_path() -> String {
+    match users::current_user() {
+        Some(user) => format!("{}/{}", user.home_directory.trim_end_matches('/'), HISTORY_FILE),
+        None => String::from(SYSTEM_HISTORY),
+    }
+}
+
+/// Paths that `word` could go on to, relative ones from the working
+/// directory; directories end in `/` and the rest in a space
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = word.split_at(word.rfind('/').map_or(0, |i| i + 1));
+    let entries = match fs::read_dir(&crate::shell::env::path(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut paths: Vec<String> = entries.iter()
+        .filter(|e| e.name.starts_with(prefix))
+        .map(|e| {
+            let end = if e.metadata.file_type == FileType::Directory { "/" } else { " " };
+            format!("{}{}{}", dir, e.name, end)
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Length of the common start of `a` and `b`, at a character boundary
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i)
+}
+
+fn text(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("")
+}
+
+/// Move the cursor `n` columns left
+fn back(n: usize) {
+    for _ in 0..n {
+        print!("\x08");
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::vec;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// An editor with no history file, so nothing it enters is saved
+    fn editor() -> LineEditor {
+        LineEditor::new("> ", vec!["help", "hexdump", "ls"])
+    }
+
+    /// Apply `keys`, starting a new line after each Enter; the lines
+    /// entered
+    fn press(ed: &mut LineEditor, keys: &[Key]) -> Vec<String> {
+        let mut entered = Vec::new();
+        for &key in keys {
+            if let Some(line) = ed.edit(key) {
+                entered.push(line);
+                // As `start` does for the next prompt
+                ed.line.clear();
+                ed.cursor = 0;
+                ed.recall = ed.history.len();
+            }
+        }
+        entered
+    }
+
+    fn chars(s: &str) -> Vec<Key> {
+        s.bytes().map(Key::Char).collect()
+    }
+
+    #[kernel_test]
+    fn typing_inserts_at_the_cursor() -> Result<(), String> {
+        let mut ed = editor();
+        press(&mut ed, &chars("helo"));
+        press(&mut ed, &[Key::Left]);
+        press(&mut ed, &chars("l"));
+        check_eq!(text(&ed.line), "hello");
+        check_eq!(ed.cursor, 4);
+        press(&mut ed, &[Key::Home]);
+        press(&mut ed, &chars(">"));
+        press(&mut ed, &[Key::End]);
+        press(&mut ed, &chars("!"));
+        check_eq!(press(&mut ed, &[Key::Enter]), vec![String::from(">hello!")]);
+        check!(ed.line.is_empty());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn deleting_either_side_of_the_cursor() -> Result<(), String> {
+        let mut ed = editor();
+        press(&mut ed, &chars("abcd"));
+        press(&mut ed, &[Key::Left, Key::Left, Key::Backspace, Key::Delete]);
+        check_eq!(text(&ed.line), "ad");
+        check_eq!(ed.cursor, 1);
+        // Nothing to remove at either end
+        press(&mut ed, &[Key::Home, Key::Backspace, Key::End, Key::Delete]);
+        check_eq!(text(&ed.line), "ad");
+        // Nor to move past
+        press(&mut ed, &[Key::Right, Key::Right]);
+        check_eq!(ed.cursor, 2);
+        press(&mut ed, &[Key::Home, Key::Left]);
+        check_eq!(ed.cursor, 0);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn history_recalls_earlier_lines() -> Result<(), String> {
+        let mut ed = editor();
+        for line in ["one", "two", "two", "  ", "three"] {
+            press(&mut ed, &chars(line));
+            press(&mut ed, &[Key::Enter]);
+        }
+        // Repeats and blank lines are not kept
+        check_eq!(ed.history, vec![String::from("one"), String::from("two"), String::from("three")]);
+        press(&mut ed, &chars("dra"));
+        press(&mut ed, &[Key::Up, Key::Up]);
+        check_eq!(text(&ed.line), "two");
+        check_eq!(ed.cursor, 3);
+        press(&mut ed, &[Key::Up, Key::Up]);
+        check_eq!(text(&ed.line), "one");
+        // Down past the newest puts back what was being typed
+        press(&mut ed, &[Key::Down, Key::Down, Key::Down, Key::Down]);
+        check_eq!(text(&ed.line), "dra");
+        press(&mut ed, &[Key::Up]);
+        press(&mut ed, &chars("!"));
+        check_eq!(press(&mut ed, &[Key::Enter]), vec![String::from("three!")]);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn tab_completes_commands() -> Result<(), String> {
+        let mut ed = editor();
+        press(&mut ed, &chars("l"));
+        press(&mut ed, &[Key::Tab]);
+        check_eq!(text(&ed.line), "ls ");
+        // Two commands start with "he", so Tab adds nothing and waits to
+        // list them
+        let mut ed = editor();
+        press(&mut ed, &chars("he"));
+        press(&mut ed, &[Key::Tab]);
+        check_eq!(text(&ed.line), "he");
+        check!(ed.listing);
+        press(&mut ed, &[Key::Tab]);
+        check!(!ed.listing);
+        press(&mut ed, &chars("x"));
+        press(&mut ed, &[Key::Tab]);
+        check_eq!(text(&ed.line), "hexdump ");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn serial_escape_sequences() -> Result<(), String> {
+        let mut ed = editor();
+        let mut keys = Vec::new();
+        for &byte in b"a\x1b[D\x1b[3~\x1bOH\x1b[4~\x7f\x01\r\x02" {
+            keys.extend(ed.serial_byte(byte));
+        }
+        check_eq!(keys, vec![
+            Key::Char(b'a'), Key::Left, Key::Delete, Key::Home, Key::End,
+            Key::Backspace, Key::Home, Key::Enter,
+        ]);
+        // An unknown sequence is dropped whole
+        check_eq!(b"\x1b[5~b".iter().filter_map(|&b| ed.serial_byte(b)).collect::<Vec<_>>(), vec![Key::Char(b'b')]);
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
 //! Serial port driver (UART 16550)
+//!
+//! COM1 is a console in its own right: everything printed goes out on it
+//! and what comes in feeds the console line editor, so a machine without
+//! a screen gets the same shell. Output goes into the 16-byte transmit
+//! FIFO a burst at a time, with `\n` sent as `\r\n` as terminals expect.
+//! Input is taken off the chip by the IRQ 4 handler into a buffer, so it
+//! is not lost while the kernel is busy elsewhere. With flow control on,
+//! RTS drops while that buffer is nearly full and output waits for CTS.
 
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::arch::interrupts::{self, InterruptStackFrame};
 
 /// COM1 base port
 pub const COM1: u16 = 0x3F8;
@@ -11,79 +23,142 @@ pub const COM3: u16 = 0x3E8;
 /// COM4 base port
 pub const COM4: u16 = 0x2E8;
 
+/// Ports by their `ttyS` number
+const PORTS: [u16; 4] = [COM1, COM2, COM3, COM4];
+
+/// IRQ line of COM1
+const COM1_IRQ: u8 = 4;
+
+/// Rates the UART clock divides down to
+pub const BAUD_RATES: [u32; 8] = [115200, 57600, 38400, 19200, 9600, 4800, 2400, 1200];
+pub const DEFAULT_BAUD: u32 = 115200;
+const UART_CLOCK: u32 = 115200;
+
+/// Bytes the transmit FIFO holds
+const FIFO_SIZE: usize = 16;
+
+// Register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+// Modem control: DTR, RTS, and OUT2, which lets the chip raise its IRQ
+const MODEM_READY: u8 = 0x0B;
+const MODEM_HOLD: u8 = 0x09;
+
+/// Times to check CTS before sending anyway, about a second, so a port
+/// with nothing on the other end cannot hang the kernel
+const CTS_SPINS: u32 = 1_000_000;
+
+/// Input received on COM1 and not yet read
+const RX_SIZE: usize = 256;
+/// With flow control on, RTS drops above this many waiting bytes and
+/// comes back below `RX_LOW`
+const RX_HIGH: usize = RX_SIZE * 3 / 4;
+const RX_LOW: usize = RX_SIZE / 4;
+
+/// RTS/CTS flow control on every port
+static FLOW_CONTROL: AtomicBool = AtomicBool::new(false);
+
 /// Serial port
 pub struct SerialPort {
     port: u16,
+    baud: u32,
 }
 
 impl SerialPort {
     /// Create and initialize a serial port
-    pub fn new(port: u16) -> Self {
+    pub fn new(port: u16, baud: u32) -> Self {
+        let mut serial = Self { port, baud: DEFAULT_BAUD };
+        unsafe {
+            // No interrupts until `listen`
+            outb(port + INTERRUPT_ENABLE, 0x00);
+        }
+        if !serial.set_baud(baud) {
+            serial.set_baud(DEFAULT_BAUD);
+        }
         unsafe {
-            // Disable interrupts
-            Self::outb(port + 1, 0x00);
-
-            // Enable DLAB (set baud rate divisor)
-            Self::outb(port + 3, 0x80);
-
-            // Set divisor to 3 (38400 baud)
-            Self::outb(port + 0, 0x03);
-            Self::outb(port + 1, 0x00);
-
-            // 8 bits, no parity, one stop bit
-            Self::outb(port + 3, 0x03);
-
             // Enable FIFO, clear them, with 14-byte threshold
-            Self::outb(port + 2, 0xC7);
-
-            // IRQs enabled, RTS/DSR set
-            Self::outb(port + 4, 0x0B);
-
-            // Enable interrupts
-            Self::outb(port + 1, 0x01);
+            outb(port + FIFO_CONTROL, 0xC7);
+            outb(port + MODEM_CONTROL, MODEM_READY);
         }
-
-        Self { port }
+        serial
     }
 
-    /// Output byte to port
-    unsafe fn outb(port: u16, val: u8) {
-        core::arch::asm!(
-            "out dx, al",
-            in("dx") port,
-            in("al") val,
-            options(nomem, nostack)
-        );
+    pub fn baud(&self) -> u32 {
+        self.baud
     }
 
-    /// Input byte from port
-    unsafe fn inb(port: u16) -> u8 {
-        let val: u8;
-        core::arch::asm!(
-            "in al, dx",
-            in("dx") port,
-            out("al") val,
-            options(nomem, nostack)
-        );
-        val
+    /// Change the baud rate; false if it is not one of `BAUD_RATES`
+    pub fn set_baud(&mut self, baud: u32) -> bool {
+        if !BAUD_RATES.contains(&baud) {
+            return false;
+        }
+        let divisor = (UART_CLOCK / baud) as u16;
+        unsafe {
+            // DLAB on to reach the divisor
+            outb(self.port + LINE_CONTROL, 0x80);
+            outb(self.port + DATA, divisor as u8);
+            outb(self.port + INTERRUPT_ENABLE, (divisor >> 8) as u8);
+            // 8 bits, no parity, one stop bit, DLAB off
+            outb(self.port + LINE_CONTROL, 0x03);
+        }
+        self.baud = baud;
+        true
     }
 
-    /// Check if transmit buffer is empty
+    /// Check if the transmit FIFO is empty
     fn is_transmit_empty(&self) -> bool {
-        unsafe { (Self::inb(self.port + 5) & 0x20) != 0 }
+        unsafe { (inb(self.port + LINE_STATUS) & 0x20) != 0 }
     }
 
     /// Check if data is available to read
     fn data_available(&self) -> bool {
-        unsafe { (Self::inb(self.port + 5) & 0x01) != 0 }
+        unsafe { (inb(self.port + LINE_STATUS) & 0x01) != 0 }
+    }
+
+    /// Wait for CTS if flow control is on
+    fn wait_clear_to_send(&self) {
+        if !FLOW_CONTROL.load(Ordering::Relaxed) {
+            return;
+        }
+        for _ in 0..CTS_SPINS {
+            if unsafe { inb(self.port + MODEM_STATUS) } & 0x10 != 0 {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Send bytes as they are, a FIFO's worth at a time
+    fn send(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(FIFO_SIZE) {
+            while !self.is_transmit_empty() {
+                core::hint::spin_loop();
+            }
+            self.wait_clear_to_send();
+            for &byte in chunk {
+                unsafe { outb(self.port + DATA, byte) };
+            }
+        }
     }
 
     /// Write a byte to the serial port
     pub fn write_byte(&mut self, byte: u8) {
-        unsafe {
-            // Wait for transmit buffer to be empty
-            while !self.is_transmit_empty() {}
-            Self::outb(self.port, byte);
+        self.write_bytes(&[byte]);
+    }
+
+    /// Write bytes, with `\n` as `\r\n`
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for (i, line) in bytes.split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                self.send(b"\r\n");
+            }
+            self.send(line);
         }
     }
 
@@ -91,7 +166,7 @@ impl SerialPort {
     pub fn read_byte(&mut self) -> Option<u8> {
         unsafe {
             if self.data_available() {
-                Some(Self::inb(self.port))
+                Some(inb(self.port))
             } else {
                 None
             }
@@ -100,9 +175,7 @@ impl SerialPort {
 
     /// Write a string to the serial port
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            self.write_byte(byte);
-        }
+        self.write_bytes(s.as_bytes());
     }
 }
 
@@ -113,32 +186,125 @@ impl fmt::Write for SerialPort {
     }
 }
 
+/// A port as it is set up already, without taking the console's lock
+pub fn unlocked(port: u16) -> SerialPort {
+    SerialPort { port, baud: DEFAULT_BAUD }
+}
+
+/// Base port of `ttyS0` to `ttyS3`, also known as `com1` to `com4`
+pub fn port(name: &str) -> Option<u16> {
+    let n: usize = match (name.strip_prefix("ttyS"), name.strip_prefix("com")) {
+        (Some(n), _) => n.parse().ok()?,
+        (_, Some(n)) => n.parse::<usize>().ok()?.checked_sub(1)?,
+        _ => return None,
+    };
+    PORTS.get(n).copied()
+}
+
+/// Turn RTS/CTS flow control on or off
+pub fn set_flow_control(on: bool) {
+    FLOW_CONTROL.store(on, Ordering::Relaxed);
+    if !on {
+        unsafe { outb(COM1 + MODEM_CONTROL, MODEM_READY) };
+    }
+}
+
+/// Bytes in a circle; full, it refuses new ones
+struct RxBuffer {
+    buf: [u8; RX_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    const fn new() -> Self {
+        Self { buf: [0; RX_SIZE], start: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < RX_SIZE {
+            self.buf[(self.start + self.len) % RX_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.start];
+        self.start = (self.start + 1) % RX_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static RX: Mutex<RxBuffer> = Mutex::new(RxBuffer::new());
+
+/// Move what COM1 has received into the buffer
+fn drain(rx: &mut RxBuffer) {
+    unsafe {
+        while inb(COM1 + LINE_STATUS) & 0x01 != 0 {
+            rx.push(inb(COM1 + DATA));
+        }
+        if FLOW_CONTROL.load(Ordering::Relaxed) && rx.len >= RX_HIGH {
+            outb(COM1 + MODEM_CONTROL, MODEM_HOLD);
+        }
+    }
+}
+
+extern "x86-interrupt" fn receive_interrupt(_stack_frame: InterruptStackFrame) {
+    let begin = crate::trace::begin();
+    drain(&mut RX.lock());
+    crate::trace::end(crate::trace::Kind::Irq, begin, COM1_IRQ as u64, 0);
+    interrupts::end_of_interrupt(COM1_IRQ);
+}
+
+/// Take COM1 input by interrupt from now on
+pub fn listen() {
+    interrupts::set_irq_handler(COM1_IRQ, receive_interrupt);
+    unsafe { outb(COM1 + INTERRUPT_ENABLE, 0x01) };
+}
+
 /// Try to receive a byte from COM1
 pub fn try_receive() -> Option<u8> {
-    // Simple implementation - just check COM1
-    unsafe {
-        let port = COM1;
-        
-        // Check if data available
-        let status: u8;
-        core::arch::asm!(
-            "in al, dx",
-            in("dx") port + 5,
-            out("al") status,
-            options(nomem, nostack)
-        );
-        
-        if (status & 0x01) != 0 {
-            let val: u8;
-            core::arch::asm!(
-                "in al, dx",
-                in("dx") port,
-                out("al") val,
-                options(nomem, nostack)
-            );
-            Some(val)
-        } else {
-            None
+    // The interrupt handler takes the buffer too, so keep it out meanwhile
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let byte = {
+        let mut rx = RX.lock();
+        // Before `listen`, or with the IRQ lost, input is still on the chip
+        drain(&mut rx);
+        let byte = rx.pop();
+        if FLOW_CONTROL.load(Ordering::Relaxed) && rx.len <= RX_LOW {
+            unsafe { outb(COM1 + MODEM_CONTROL, MODEM_READY) };
         }
+        byte
+    };
+    if enabled {
+        interrupts::enable();
     }
+    byte
+}
+
+/// Output byte to port
+unsafe fn outb(port: u16, val: u8) {
+    core::arch::asm!(
+        "out dx, al",
+        in("dx") port,
+        in("al") val,
+        options(nomem, nostack)
+    );
+}
+
+/// Input byte from port
+unsafe fn inb(port: u16) -> u8 {
+    let val: u8;
+    core::arch::asm!(
+        "in al, dx",
+        in("dx") port,
+        out("al") val,
+        options(nomem, nostack)
+    );
+    val
 }
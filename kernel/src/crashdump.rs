@@ -0,0 +1,243 @@
+//! Crash dumps kept across a reboot
+//!
+//! The bootloader sets aside a region of RAM at a fixed address and never
+//! clears it, and a warm reboot leaves RAM as it was. So the panic handler
+//! writes its report there as it goes, then the end of the log and the
+//! counters, and the next boot finds it: it says the previous boot
+//! crashed, saves the dump to `/var/crash` and keeps it for `crash`.
+//!
+//! The region starts with a header whose length and checksum follow every
+//! write, so a panic that dies halfway still leaves what it got to.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use webbos_shared::bootinfo::{CRASH_REGION_ADDR, CRASH_REGION_SIZE};
+use webbos_shared::types::{MemoryRegion, MemoryRegionType, PhysAddr};
+
+use crate::drivers::{rtc, timer};
+use crate::fs::{self, FsError, Permissions};
+use crate::mm;
+use crate::trace;
+use crate::{info, println, warn};
+
+/// "WBCRASH1"
+const MAGIC: u64 = 0x3148_5341_5243_4257;
+/// Log bytes kept after the report
+const LOG_BYTES: usize = 16 * 1024;
+const CRASH_DIR: &str = "/var/crash";
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// Bytes of text
+    len: u32,
+    /// FNV-1a of the text
+    checksum: u32,
+    /// Bytes of text that are the report the screen showed
+    summary: u32,
+    _reserved: u32,
+}
+
+const TEXT_SIZE: usize = CRASH_REGION_SIZE as usize - core::mem::size_of::<Header>();
+
+/// The region is reserved and read, so a panic may write it
+static ARMED: AtomicBool = AtomicBool::new(false);
+/// The dump the previous boot left
+static PREVIOUS: Mutex<Option<Previous>> = Mutex::new(None);
+
+struct Previous {
+    text: String,
+    summary: usize,
+    /// Where `save_previous` put it
+    path: Option<String>,
+}
+
+fn header() -> *mut Header {
+    mm::phys_to_virt(PhysAddr::new(CRASH_REGION_ADDR)).as_u64() as *mut Header
+}
+
+fn text() -> *mut u8 {
+    unsafe { header().add(1) as *mut u8 }
+}
+
+fn fnv_step(hash: u32, byte: u8) -> u32 {
+    (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+}
+
+const FNV_OFFSET: u32 = 0x811C_9DC5;
+
+/// Take what the previous boot left in the region and make it ready for
+/// this one; needs the heap
+pub fn init(memory_map: &[MemoryRegion]) {
+    let end = CRASH_REGION_ADDR + CRASH_REGION_SIZE;
+    let reserved = memory_map.iter().any(|region| {
+        matches!(region.region_type, MemoryRegionType::Bootloader)
+            && region.base.as_u64() <= CRASH_REGION_ADDR
+            && region.end().as_u64() >= end
+    });
+    if !reserved {
+        warn!("crash", "The bootloader reserved no crash dump region; crashes are not kept");
+        return;
+    }
+
+    unsafe {
+        let header = &mut *header();
+        let len = header.len as usize;
+        if header.magic == MAGIC && len <= TEXT_SIZE {
+            let bytes = core::slice::from_raw_parts(text(), len);
+            if bytes.iter().fold(FNV_OFFSET, |hash, &byte| fnv_step(hash, byte)) == header.checksum {
+                let text = String::from_utf8_lossy(bytes).into_owned();
+                let summary = (header.summary as usize).min(text.len());
+                warn!("crash", "The previous boot crashed: {}", headline(&text));
+                *PREVIOUS.lock() = Some(Previous { text, summary, path: None });
+            }
+        }
+        core::ptr::write_volatile(&mut header.magic, 0);
+    }
+    ARMED.store(true, Ordering::SeqCst);
+}
+
+/// The panic message: the first line that says anything
+fn headline(text: &str) -> &str {
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with("***"))
+        .unwrap_or("(no message)")
+}
+
+/// A dump being written by the panic handler; nothing here locks or
+/// allocates
+pub struct Dump {
+    len: usize,
+    checksum: u32,
+}
+
+/// Start a dump, over whatever was in the region; `None` without one
+pub fn begin() -> Option<Dump> {
+    if !ARMED.swap(false, Ordering::SeqCst) {
+        return None;
+    }
+    let dump = Dump { len: 0, checksum: FNV_OFFSET };
+    unsafe {
+        let header = header();
+        core::ptr::write_volatile(header, Header { magic: MAGIC, len: 0, checksum: FNV_OFFSET, summary: 0, _reserved: 0 });
+    }
+    Some(dump)
+}
+
+impl Dump {
+    fn push(&mut self, byte: u8) {
+        if self.len < TEXT_SIZE {
+            unsafe { core::ptr::write_volatile(text().add(self.len), byte) };
+            self.len += 1;
+            self.checksum = fnv_step(self.checksum, byte);
+        }
+    }
+
+    /// Bring the header up to what has been written
+    fn commit(&self) {
+        unsafe {
+            let header = header();
+            core::ptr::write_volatile(&mut (*header).checksum, self.checksum);
+            core::ptr::write_volatile(&mut (*header).len, self.len as u32);
+        }
+    }
+
+    /// What has been written so far is the report the screen showed; the
+    /// next boot shows that much
+    pub fn end_summary(&self) {
+        unsafe { core::ptr::write_volatile(&mut (*header()).summary, self.len as u32) };
+    }
+
+    /// Add what the screen leaves out: when, the counters and the end of
+    /// the log
+    pub fn finish(mut self) {
+        let _ = writeln!(self, "\nCrashed at {} UTC, {}s after boot", rtc::now(), timer::now_ns() / 1_000_000_000);
+        match mm::allocator::try_used_heap() {
+            Some(used) => { let _ = writeln!(self, "Heap: {} KB used", used / 1024); }
+            None => { let _ = writeln!(self, "Heap: locked"); }
+        }
+        let _ = writeln!(self, "\nEvents since boot:");
+        for kind in trace::Kind::ALL {
+            let _ = writeln!(self, "  {:12} {}", kind.name(), trace::count(kind));
+        }
+        let _ = writeln!(self, "\nLog:");
+        if !crate::log::tail(LOG_BYTES, |byte| self.push(byte)) {
+            let _ = writeln!(self, "(the log is locked)");
+        }
+        self.commit();
+    }
+}
+
+impl Write for Dump {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.push(byte));
+        self.commit();
+        Ok(())
+    }
+}
+
+/// Save the previous boot's dump to `/var/crash`, where it can be copied
+/// off; the root is in RAM, so it lasts until the next reboot
+pub fn save_previous() {
+    let mut previous = PREVIOUS.lock();
+    let previous = match previous.as_mut() {
+        Some(previous) => previous,
+        None => return,
+    };
+    let path = format!("{}/{}.txt", CRASH_DIR, rtc::unix_time());
+    let saved = match fs::create_dir(CRASH_DIR) {
+        Ok(()) | Err(FsError::AlreadyExists) => fs::write_file(&path, previous.text.as_bytes())
+            .and_then(|()| fs::chmod(&path, Permissions::from_mode(0o600))),
+        Err(e) => Err(e),
+    };
+    match saved {
+        Ok(()) => {
+            info!("crash", "Saved the previous boot's crash dump to {}", path);
+            previous.path = Some(path);
+        }
+        Err(e) => warn!("crash", "Cannot save the crash dump to {}: {:?}", path, e),
+    }
+}
+
+/// Tell whoever is at the console that the previous boot crashed, and how
+pub fn print_previous() {
+    let previous = PREVIOUS.lock();
+    let previous = match previous.as_ref() {
+        Some(previous) => previous,
+        None => return,
+    };
+    println!("\n\x1b[31mThe previous boot crashed:\x1b[0m");
+    let summary = previous.text.get(..previous.summary).unwrap_or(&previous.text);
+    for line in summary.trim().lines() {
+        println!("  {}", line);
+    }
+    match &previous.path {
+        Some(path) => println!("The whole dump, with the log, is in {}; `crash` shows it", path),
+        None => println!("`crash` shows the whole dump, with the log"),
+    }
+}
+
+/// Print the previous boot's dump, if it crashed
+pub fn print_dump() {
+    match PREVIOUS.lock().as_ref() {
+        Some(previous) => println!("{}", previous.text.trim_end()),
+        None => println!("The previous boot did not crash"),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    #[kernel_test]
+    fn finds_the_headline() -> Result<(), String> {
+        check_eq!(headline("\n*** KERNEL PANIC ***\n\nout of memory\nat src/x.rs:1:1\n"), "out of memory");
+        check_eq!(headline(""), "(no message)");
+        Ok(())
+    }
+}
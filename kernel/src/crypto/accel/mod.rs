@@ -0,0 +1,547 @@
+//! Hardware crypto acceleration
+//!
+//! Probes CPUID once from `crypto::init` and installs function pointers for
+//! the hot primitives: the SHA-256 compression function, single-block AES
+//! encryption, and 64x64 carry-less multiply (used by GHASH). Callers go
+//! through the dispatch functions below and never check CPU features
+//! themselves; until `init` runs the portable implementations are used.
+
+use core::arch::x86_64::__cpuid;
+use spin::Mutex;
+
+use crate::crypto::aes::{self, KeySchedule};
+use crate::crypto::sha256;
+use crate::{info, println};
+
+/// Crypto-relevant CPU capabilities
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuCaps {
+    pub ssse3: bool,
+    pub sse41: bool,
+    pub aes_ni: bool,
+    pub pclmulqdq: bool,
+    pub sha_ni: bool,
+}
+
+impl CpuCaps {
+    /// Read capabilities from CPUID leaves 1 and 7
+    pub fn detect() -> Self {
+        let leaf1 = unsafe { __cpuid(1) };
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        let leaf7_ebx = if max_leaf >= 7 {
+            unsafe { core::arch::x86_64::__cpuid_count(7, 0) }.ebx
+        } else {
+            0
+        };
+
+        Self {
+            ssse3: leaf1.ecx & (1 << 9) != 0,
+            sse41: leaf1.ecx & (1 << 19) != 0,
+            aes_ni: leaf1.ecx & (1 << 25) != 0,
+            pclmulqdq: leaf1.ecx & (1 << 1) != 0,
+            sha_ni: leaf7_ebx & (1 << 29) != 0,
+        }
+    }
+}
+
+/// Installed primitive implementations
+#[derive(Clone, Copy)]
+struct Backend {
+    sha256_compress: fn(&mut [u32; 8], &[u8; 64]),
+    sha256_name: &'static str,
+    aes_encrypt_block: fn(&KeySchedule, &mut [u8; 16]),
+    aes_name: &'static str,
+    clmul: fn(u64, u64) -> u128,
+    clmul_name: &'static str,
+}
+
+const SOFTWARE: Backend = Backend {
+    sha256_compress: sha256::compress_soft,
+    sha256_name: "software",
+    aes_encrypt_block: aes::encrypt_block_soft,
+    aes_name: "software",
+    clmul: clmul_soft,
+    clmul_name: "software",
+};
+
+static BACKEND: Mutex<Backend> = Mutex::new(SOFTWARE);
+static CAPS: Mutex<CpuCaps> = Mutex::new(CpuCaps {
+    ssse3: false,
+    sse41: false,
+    aes_ni: false,
+    pclmulqdq: false,
+    sha_ni: false,
+});
+
+/// Probe the CPU and install the fastest available implementations
+pub fn init() {
+    let caps = CpuCaps::detect();
+    let mut backend = SOFTWARE;
+    // The hardware paths are SSE instructions, which fault with #UD until
+    // CR4.OSFXSR and OSXMMEXCPT are set
+    unsafe { crate::arch::simd::enable_sse() };
+
+    if caps.sha_ni && caps.ssse3 && caps.sse41 {
+        backend.sha256_compress = sha256_compress_ni;
+        backend.sha256_name = "SHA-NI";
+    }
+    if caps.aes_ni {
+        backend.aes_encrypt_block = aes_encrypt_block_ni;
+        backend.aes_name = "AES-NI";
+    }
+    if caps.pclmulqdq {
+        backend.clmul = clmul_hw;
+        backend.clmul_name = "PCLMULQDQ";
+    }
+
+    *CAPS.lock() = caps;
+    *BACKEND.lock() = backend;
+
+    info!("accel", "SHA-256: {}, AES: {}, CLMUL: {}",
+        backend.sha256_name, backend.aes_name, backend.clmul_name);
+}
+
+/// Capabilities detected at init
+pub fn caps() -> CpuCaps {
+    *CAPS.lock()
+}
+
+/// SHA-256 compression of one 64-byte block
+#[inline]
+pub fn sha256_compress(state: &mut [u32; 8], block: &[u8; 64]) {
+    let f = BACKEND.lock().sha256_compress;
+    f(state, block)
+}
+
+/// Encrypt one AES block in place
+#[inline]
+pub fn aes_encrypt_block(ks: &KeySchedule, block: &mut [u8; 16]) {
+    let f = BACKEND.lock().aes_encrypt_block;
+    f(ks, block)
+}
+
+/// Carry-less multiply of two 64-bit values
+#[inline]
+pub fn clmul(a: u64, b: u64) -> u128 {
+    let f = BACKEND.lock().clmul;
+    f(a, b)
+}
+
+/// Multiply two GHASH field elements (GCM bit order, big-endian blocks)
+///
+/// The operands are bit-reflected into ordinary polynomial order, multiplied
+/// with four carry-less products, and reduced modulo x^128 + x^7 + x^2 + x + 1.
+pub fn gf128_mul(x: u128, y: u128) -> u128 {
+    let a = x.reverse_bits();
+    let b = y.reverse_bits();
+    let (a0, a1) = (a as u64, (a >> 64) as u64);
+    let (b0, b1) = (b as u64, (b >> 64) as u64);
+
+    let f = BACKEND.lock().clmul;
+    let lo = f(a0, b0);
+    let hi = f(a1, b1);
+    let mid = f(a0, b1) ^ f(a1, b0);
+
+    let lo = lo ^ (mid << 64);
+    let hi = hi ^ (mid >> 64);
+
+    // Fold the high half: x^128 = x^7 + x^2 + x + 1
+    let overflow = (hi >> 127) ^ (hi >> 126) ^ (hi >> 121);
+    let folded = hi ^ (hi << 1) ^ (hi << 2) ^ (hi << 7);
+    let overflow = overflow ^ (overflow << 1) ^ (overflow << 2) ^ (overflow << 7);
+
+    (lo ^ folded ^ overflow).reverse_bits()
+}
+
+/// Portable carry-less multiply
+fn clmul_soft(a: u64, b: u64) -> u128 {
+    let mut result = 0u128;
+    let a = a as u128;
+    for i in 0..64 {
+        // Branch-free: mask is all ones when bit i of b is set
+        let mask = 0u128.wrapping_sub(((b >> i) & 1) as u128);
+        result ^= (a << i) & mask;
+    }
+    result
+}
+
+// The kernel is built soft-float, so the compiler keeps nothing in the
+// vector registers, but a syscall may arrive with user values in them.
+// Each fast path stores the ones it uses to `save` first and loads them
+// back before it returns.
+
+fn clmul_hw(a: u64, b: u64) -> u128 {
+    // SAFETY: only installed when CPUID reports PCLMULQDQ
+    unsafe { clmul_pclmulqdq(a, b) }
+}
+
+unsafe fn clmul_pclmulqdq(a: u64, b: u64) -> u128 {
+    let mut save = [0u8; 32];
+    let (lo, hi): (u64, u64);
+    core::arch::asm!(
+        "movdqu %xmm0, ({save})",
+        "movdqu %xmm1, 16({save})",
+        "movq {a}, %xmm0",
+        "movq {b}, %xmm1",
+        "pclmulqdq $0, %xmm1, %xmm0",
+        "movq %xmm0, {a}",
+        "psrldq $8, %xmm0",
+        "movq %xmm0, {b}",
+        "movdqu ({save}), %xmm0",
+        "movdqu 16({save}), %xmm1",
+        save = in(reg) save.as_mut_ptr(),
+        a = inout(reg) a => lo,
+        b = inout(reg) b => hi,
+        options(att_syntax, nostack),
+    );
+    ((hi as u128) << 64) | lo as u128
+}
+
+fn aes_encrypt_block_ni(ks: &KeySchedule, block: &mut [u8; 16]) {
+    // SAFETY: only installed when CPUID reports AES-NI
+    unsafe { aes_encrypt_block_aesni(ks, block) }
+}
+
+/// The round keys are consecutive in the schedule, 16 bytes each
+unsafe fn aes_encrypt_block_aesni(ks: &KeySchedule, block: &mut [u8; 16]) {
+    let mut save = [0u8; 32];
+    core::arch::asm!(
+        "movdqu %xmm0, ({save})",
+        "movdqu %xmm1, 16({save})",
+        "movdqu ({block}), %xmm0",
+        "movdqu ({keys}), %xmm1",
+        "pxor %xmm1, %xmm0",
+        "2:",
+        "add $16, {keys}",
+        "movdqu ({keys}), %xmm1",
+        "aesenc %xmm1, %xmm0",
+        "dec {rounds}",
+        "jnz 2b",
+        "movdqu 16({keys}), %xmm1",
+        "aesenclast %xmm1, %xmm0",
+        "movdqu %xmm0, ({block})",
+        "movdqu ({save}), %xmm0",
+        "movdqu 16({save}), %xmm1",
+        save = in(reg) save.as_mut_ptr(),
+        block = in(reg) block.as_mut_ptr(),
+        keys = inout(reg) ks.round_key(0).as_ptr() => _,
+        rounds = inout(reg) ks.rounds() - 1 => _,
+        options(att_syntax, nostack),
+    );
+}
+
+fn sha256_compress_ni(state: &mut [u32; 8], block: &[u8; 64]) {
+    // SAFETY: only installed when CPUID reports SHA, SSSE3 and SSE4.1
+    unsafe { sha256_compress_shani(state, block) }
+}
+
+/// Round constants where the rounds can load them from
+static SHA256_K: [u32; 64] = sha256::K;
+
+/// `pshufb` mask turning big-endian message words around
+#[repr(align(16))]
+struct ByteFlip([u8; 16]);
+static SHA256_BYTE_FLIP: ByteFlip = ByteFlip([3, 2, 1, 0, 7, 6, 5, 4, 11, 10, 9, 8, 15, 14, 13, 12]);
+
+/// Registers: xmm0 the message plus constants `sha256rnds2` takes, xmm1
+/// and xmm2 the state as ABEF and CDGH, xmm3 to xmm6 the message schedule,
+/// xmm7 scratch, xmm8 the byte flip mask, xmm9 and xmm10 the state on entry
+unsafe fn sha256_compress_shani(state: &mut [u32; 8], block: &[u8; 64]) {
+    let mut save = [0u8; 11 * 16];
+    core::arch::asm!(
+        "movdqu %xmm0, ({save})",
+        "movdqu %xmm1, 16({save})",
+        "movdqu %xmm2, 32({save})",
+        "movdqu %xmm3, 48({save})",
+        "movdqu %xmm4, 64({save})",
+        "movdqu %xmm5, 80({save})",
+        "movdqu %xmm6, 96({save})",
+        "movdqu %xmm7, 112({save})",
+        "movdqu %xmm8, 128({save})",
+        "movdqu %xmm9, 144({save})",
+        "movdqu %xmm10, 160({save})",
+        // DCBA and HGFE into ABEF and CDGH
+        "movdqu ({state}), %xmm1",
+        "movdqu 16({state}), %xmm2",
+        "pshufd $0xB1, %xmm1, %xmm1",
+        "pshufd $0x1B, %xmm2, %xmm2",
+        "movdqa %xmm1, %xmm7",
+        "palignr $8, %xmm2, %xmm1",
+        "pblendw $0xF0, %xmm7, %xmm2",
+        "movdqu ({flip}), %xmm8",
+        "movdqa %xmm1, %xmm9",
+        "movdqa %xmm2, %xmm10",
+        // Four rounds at a time, scheduling the words four rounds ahead
+        "movdqu 0({data}), %xmm3",
+        "pshufb %xmm8, %xmm3",
+        "movdqu 0({k}), %xmm0",
+        "paddd %xmm3, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "movdqu 16({data}), %xmm4",
+        "pshufb %xmm8, %xmm4",
+        "movdqu 16({k}), %xmm0",
+        "paddd %xmm4, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm4, %xmm3",
+        "movdqu 32({data}), %xmm5",
+        "pshufb %xmm8, %xmm5",
+        "movdqu 32({k}), %xmm0",
+        "paddd %xmm5, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm5, %xmm4",
+        "movdqu 48({data}), %xmm6",
+        "pshufb %xmm8, %xmm6",
+        "movdqu 48({k}), %xmm0",
+        "paddd %xmm6, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm6, %xmm7",
+        "palignr $4, %xmm5, %xmm7",
+        "paddd %xmm7, %xmm3",
+        "sha256msg2 %xmm6, %xmm3",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm6, %xmm5",
+        "movdqu 64({k}), %xmm0",
+        "paddd %xmm3, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm3, %xmm7",
+        "palignr $4, %xmm6, %xmm7",
+        "paddd %xmm7, %xmm4",
+        "sha256msg2 %xmm3, %xmm4",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm3, %xmm6",
+        "movdqu 80({k}), %xmm0",
+        "paddd %xmm4, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm4, %xmm7",
+        "palignr $4, %xmm3, %xmm7",
+        "paddd %xmm7, %xmm5",
+        "sha256msg2 %xmm4, %xmm5",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm4, %xmm3",
+        "movdqu 96({k}), %xmm0",
+        "paddd %xmm5, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm5, %xmm7",
+        "palignr $4, %xmm4, %xmm7",
+        "paddd %xmm7, %xmm6",
+        "sha256msg2 %xmm5, %xmm6",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm5, %xmm4",
+        "movdqu 112({k}), %xmm0",
+        "paddd %xmm6, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm6, %xmm7",
+        "palignr $4, %xmm5, %xmm7",
+        "paddd %xmm7, %xmm3",
+        "sha256msg2 %xmm6, %xmm3",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm6, %xmm5",
+        "movdqu 128({k}), %xmm0",
+        "paddd %xmm3, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm3, %xmm7",
+        "palignr $4, %xmm6, %xmm7",
+        "paddd %xmm7, %xmm4",
+        "sha256msg2 %xmm3, %xmm4",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm3, %xmm6",
+        "movdqu 144({k}), %xmm0",
+        "paddd %xmm4, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm4, %xmm7",
+        "palignr $4, %xmm3, %xmm7",
+        "paddd %xmm7, %xmm5",
+        "sha256msg2 %xmm4, %xmm5",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm4, %xmm3",
+        "movdqu 160({k}), %xmm0",
+        "paddd %xmm5, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm5, %xmm7",
+        "palignr $4, %xmm4, %xmm7",
+        "paddd %xmm7, %xmm6",
+        "sha256msg2 %xmm5, %xmm6",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm5, %xmm4",
+        "movdqu 176({k}), %xmm0",
+        "paddd %xmm6, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm6, %xmm7",
+        "palignr $4, %xmm5, %xmm7",
+        "paddd %xmm7, %xmm3",
+        "sha256msg2 %xmm6, %xmm3",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm6, %xmm5",
+        "movdqu 192({k}), %xmm0",
+        "paddd %xmm3, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm3, %xmm7",
+        "palignr $4, %xmm6, %xmm7",
+        "paddd %xmm7, %xmm4",
+        "sha256msg2 %xmm3, %xmm4",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "sha256msg1 %xmm3, %xmm6",
+        "movdqu 208({k}), %xmm0",
+        "paddd %xmm4, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm4, %xmm7",
+        "palignr $4, %xmm3, %xmm7",
+        "paddd %xmm7, %xmm5",
+        "sha256msg2 %xmm4, %xmm5",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "movdqu 224({k}), %xmm0",
+        "paddd %xmm5, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "movdqa %xmm5, %xmm7",
+        "palignr $4, %xmm4, %xmm7",
+        "paddd %xmm7, %xmm6",
+        "sha256msg2 %xmm5, %xmm6",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+        "movdqu 240({k}), %xmm0",
+        "paddd %xmm6, %xmm0",
+        "sha256rnds2 %xmm1, %xmm2",
+        "punpckhqdq %xmm0, %xmm0",
+        "sha256rnds2 %xmm2, %xmm1",
+
+        "paddd %xmm9, %xmm1",
+        "paddd %xmm10, %xmm2",
+        // And back to DCBA and HGFE
+        "pshufd $0x1B, %xmm1, %xmm1",
+        "pshufd $0xB1, %xmm2, %xmm2",
+        "movdqa %xmm1, %xmm7",
+        "pblendw $0xF0, %xmm2, %xmm1",
+        "palignr $8, %xmm7, %xmm2",
+        "movdqu %xmm1, ({state})",
+        "movdqu %xmm2, 16({state})",
+        "movdqu ({save}), %xmm0",
+        "movdqu 16({save}), %xmm1",
+        "movdqu 32({save}), %xmm2",
+        "movdqu 48({save}), %xmm3",
+        "movdqu 64({save}), %xmm4",
+        "movdqu 80({save}), %xmm5",
+        "movdqu 96({save}), %xmm6",
+        "movdqu 112({save}), %xmm7",
+        "movdqu 128({save}), %xmm8",
+        "movdqu 144({save}), %xmm9",
+        "movdqu 160({save}), %xmm10",
+        save = in(reg) save.as_mut_ptr(),
+        state = in(reg) state.as_mut_ptr(),
+        data = in(reg) block.as_ptr(),
+        k = in(reg) SHA256_K.as_ptr(),
+        flip = in(reg) SHA256_BYTE_FLIP.0.as_ptr(),
+        options(att_syntax, nostack),
+    );
+}
+
+/// Measure throughput of each primitive and print MB/s
+///
+/// Used by the `crypto bench` console command.
+pub fn bench() {
+    use crate::drivers::timer;
+
+    const BUF_LEN: usize = 16 * 1024;
+    const ITERATIONS: usize = 64;
+    let total = (BUF_LEN * ITERATIONS) as u64;
+
+    let caps = caps();
+    let backend = *BACKEND.lock();
+    println!("Crypto acceleration:");
+    println!("  CPU: AES-NI={} PCLMULQDQ={} SHA-NI={} SSSE3={} SSE4.1={}",
+        caps.aes_ni, caps.pclmulqdq, caps.sha_ni, caps.ssse3, caps.sse41);
+    println!("  Using: SHA-256={} AES={} CLMUL={}",
+        backend.sha256_name, backend.aes_name, backend.clmul_name);
+    println!();
+
+    let mut buf = alloc::vec![0xA5u8; BUF_LEN];
+
+    let report = |name: &str, ms: u64| {
+        let ms = ms.max(1);
+        let kb_per_s = total * 1000 / 1024 / ms;
+        println!("  {:<20} {:>6} ms  {:>6} KB/s", name, ms, kb_per_s);
+    };
+
+    let start = timer::elapsed_ms();
+    for _ in 0..ITERATIONS {
+        let mut hasher = sha256::Sha256::new();
+        hasher.update(&buf);
+        let _ = hasher.finalize();
+    }
+    report("SHA-256", timer::elapsed_ms() - start);
+
+    let gcm = aes::AesGcm::new_128(&[0x42; aes::KEY_SIZE_128]);
+    let nonce = [0u8; aes::NONCE_SIZE];
+    let start = timer::elapsed_ms();
+    for _ in 0..ITERATIONS {
+        let _ = gcm.encrypt_in_place(&nonce, &[], &mut buf);
+    }
+    report("AES-128-GCM", timer::elapsed_ms() - start);
+
+    let key = [0x42; crate::crypto::chacha20::KEY_SIZE];
+    let nonce = [0u8; crate::crypto::chacha20::NONCE_SIZE];
+    let start = timer::elapsed_ms();
+    for _ in 0..ITERATIONS {
+        let _ = crate::crypto::chacha20::ChaCha20Poly1305::encrypt_in_place(&key, &nonce, &[], &mut buf);
+    }
+    report("ChaCha20-Poly1305", timer::elapsed_ms() - start);
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    #[kernel_test]
+    fn hardware_paths_match_software() -> Result<(), String> {
+        let caps = CpuCaps::detect();
+        unsafe { crate::arch::simd::enable_sse() };
+
+        let mut block = [0u8; 64];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = (i * 7 + 3) as u8;
+        }
+        if caps.sha_ni && caps.ssse3 && caps.sse41 {
+            let mut soft = sha256::H;
+            let mut hard = sha256::H;
+            sha256::compress_soft(&mut soft, &block);
+            sha256_compress_ni(&mut hard, &block);
+            check_eq!(hard, soft);
+        }
+
+        for key in [&block[..16], &block[..32]] {
+            let ks = KeySchedule::new(key);
+            let mut soft = [0x5Au8; 16];
+            let mut hard = soft;
+            aes::encrypt_block_soft(&ks, &mut soft);
+            if caps.aes_ni {
+                aes_encrypt_block_ni(&ks, &mut hard);
+                check_eq!(hard, soft);
+            }
+        }
+
+        if caps.pclmulqdq {
+            for (a, b) in [(0, 0), (1, u64::MAX), (0x8000_0000_0000_0001, 0xDEAD_BEEF_0BAD_F00D)] {
+                check_eq!(clmul_hw(a, b), clmul_soft(a, b));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
 //! AES-GCM AEAD
 //!
-//! Implementation of AES-128-GCM and AES-256-GCM authenticated encryption.
+//! Implementation of AES-128-GCM and AES-256-GCM authenticated encryption
+//! (FIPS 197, NIST SP 800-38D). Block encryption and the GHASH multiply go
+//! through `crypto::accel`, so AES-NI and PCLMULQDQ are used when present.
+
+use crate::crypto::{accel, ct};
 
 /// AES block size in bytes
 pub const BLOCK_SIZE: usize = 16;
@@ -11,39 +15,218 @@ pub const KEY_SIZE_128: usize = 16;
 /// AES-256 key size
 pub const AES_256_KEY_SIZE: usize = 32;
 
+/// GCM nonce size
+pub const NONCE_SIZE: usize = 12;
+
 /// GCM tag size
 pub const TAG_SIZE: usize = 16;
 
-/// AES-GCM instance
-pub struct AesGcm {
-    key: [u8; 32],
-    key_len: usize,
+/// Maximum number of rounds (AES-256)
+const MAX_ROUNDS: usize = 14;
+
+/// Forward S-box
+pub(crate) const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// Round constants for key expansion
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// Expanded AES round keys
+#[derive(Clone)]
+pub struct KeySchedule {
+    round_keys: [[u8; BLOCK_SIZE]; MAX_ROUNDS + 1],
+    rounds: usize,
+}
+
+impl KeySchedule {
+    /// Expand a 128- or 256-bit key
+    pub fn new(key: &[u8]) -> Self {
+        let nk = key.len() / 4;
+        let rounds = nk + 6;
+        let total_words = 4 * (rounds + 1);
+
+        let mut words = [[0u8; 4]; 4 * (MAX_ROUNDS + 1)];
+        for i in 0..nk {
+            words[i].copy_from_slice(&key[i * 4..i * 4 + 4]);
+        }
+
+        for i in nk..total_words {
+            let mut temp = words[i - 1];
+            if i % nk == 0 {
+                temp.rotate_left(1);
+                for b in temp.iter_mut() {
+                    *b = ct::lookup_u8(&SBOX, *b);
+                }
+                temp[0] ^= RCON[i / nk - 1];
+            } else if nk > 6 && i % nk == 4 {
+                for b in temp.iter_mut() {
+                    *b = ct::lookup_u8(&SBOX, *b);
+                }
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - nk][j] ^ temp[j];
+            }
+        }
+
+        let mut round_keys = [[0u8; BLOCK_SIZE]; MAX_ROUNDS + 1];
+        for (r, rk) in round_keys.iter_mut().enumerate().take(rounds + 1) {
+            for c in 0..4 {
+                rk[c * 4..c * 4 + 4].copy_from_slice(&words[r * 4 + c]);
+            }
+        }
+
+        Self { round_keys, rounds }
+    }
+
+    /// Number of rounds (10 for AES-128, 14 for AES-256)
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Round key for round `i`
+    pub fn round_key(&self, i: usize) -> &[u8; BLOCK_SIZE] {
+        &self.round_keys[i]
+    }
+}
+
+impl Drop for KeySchedule {
+    fn drop(&mut self) {
+        for rk in self.round_keys.iter_mut() {
+            crate::crypto::secure_clear(rk);
+        }
+    }
+}
+
+/// Multiply by x in GF(2^8)
+#[inline]
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (((b >> 7) & 1) * 0x1b)
+}
+
+/// Portable single-block AES encryption
+///
+/// S-box lookups scan the whole table so the access pattern does not depend
+/// on key or plaintext; prefer the AES-NI path for throughput.
+pub fn encrypt_block_soft(ks: &KeySchedule, block: &mut [u8; BLOCK_SIZE]) {
+    for (b, k) in block.iter_mut().zip(ks.round_key(0).iter()) {
+        *b ^= k;
+    }
+
+    for round in 1..=ks.rounds {
+        // SubBytes
+        for b in block.iter_mut() {
+            *b = ct::lookup_u8(&SBOX, *b);
+        }
+
+        // ShiftRows (state is column-major)
+        let s = *block;
+        for c in 0..4 {
+            for r in 0..4 {
+                block[c * 4 + r] = s[((c + r) % 4) * 4 + r];
+            }
+        }
+
+        // MixColumns (skipped in the final round)
+        if round != ks.rounds {
+            for c in 0..4 {
+                let col = [block[c * 4], block[c * 4 + 1], block[c * 4 + 2], block[c * 4 + 3]];
+                let all = col[0] ^ col[1] ^ col[2] ^ col[3];
+                for r in 0..4 {
+                    block[c * 4 + r] = col[r] ^ all ^ xtime(col[r] ^ col[(r + 1) % 4]);
+                }
+            }
+        }
+
+        // AddRoundKey
+        for (b, k) in block.iter_mut().zip(ks.round_key(round).iter()) {
+            *b ^= k;
+        }
+    }
 }
 
-/// AES state
-struct AesState {
-    state: [[u8; 4]; 4],
+/// AES-GCM instance
+pub struct AesGcm {
+    schedule: KeySchedule,
+    /// GHASH subkey H = E(K, 0^128)
+    h: u128,
 }
 
 impl AesGcm {
     /// Create new AES-128-GCM instance
     pub fn new_128(key: &[u8; KEY_SIZE_128]) -> Self {
-        let mut full_key = [0u8; 32];
-        full_key[..16].copy_from_slice(key);
-        Self {
-            key: full_key,
-            key_len: KEY_SIZE_128,
-        }
+        Self::with_schedule(KeySchedule::new(key))
     }
 
     /// Create new AES-256-GCM instance
     pub fn new_256(key: &[u8; AES_256_KEY_SIZE]) -> Self {
+        Self::with_schedule(KeySchedule::new(key))
+    }
+
+    fn with_schedule(schedule: KeySchedule) -> Self {
+        let mut h = [0u8; BLOCK_SIZE];
+        accel::aes_encrypt_block(&schedule, &mut h);
         Self {
-            key: *key,
-            key_len: AES_256_KEY_SIZE,
+            schedule,
+            h: u128::from_be_bytes(h),
         }
     }
 
+    /// Pre-counter block J0 for a 96-bit nonce
+    fn j0(nonce: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut j0 = [0u8; BLOCK_SIZE];
+        j0[..NONCE_SIZE].copy_from_slice(&nonce[..NONCE_SIZE]);
+        j0[15] = 1;
+        j0
+    }
+
+    /// Apply the CTR keystream starting at inc32(J0)
+    fn ctr(&self, j0: &[u8; BLOCK_SIZE], data: &mut [u8]) {
+        let mut counter = u32::from_be_bytes([j0[12], j0[13], j0[14], j0[15]]);
+        for chunk in data.chunks_mut(BLOCK_SIZE) {
+            counter = counter.wrapping_add(1);
+            let mut ks = *j0;
+            ks[12..].copy_from_slice(&counter.to_be_bytes());
+            accel::aes_encrypt_block(&self.schedule, &mut ks);
+            for (d, k) in chunk.iter_mut().zip(ks.iter()) {
+                *d ^= k;
+            }
+        }
+    }
+
+    /// GHASH over AAD and ciphertext, then mask with E(K, J0)
+    fn tag(&self, j0: &[u8; BLOCK_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+        let mut y = 0u128;
+        for data in [aad, ciphertext] {
+            for chunk in data.chunks(BLOCK_SIZE) {
+                let mut block = [0u8; BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+                y = accel::gf128_mul(y ^ u128::from_be_bytes(block), self.h);
+            }
+        }
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        y = accel::gf128_mul(y ^ lengths, self.h);
+
+        let mut mask = *j0;
+        accel::aes_encrypt_block(&self.schedule, &mut mask);
+        (y ^ u128::from_be_bytes(mask)).to_be_bytes()
+    }
+
     /// Encrypt in place and return tag
     pub fn encrypt_in_place(
         &self,
@@ -51,21 +234,9 @@ impl AesGcm {
         aad: &[u8],
         plaintext: &mut [u8],
     ) -> [u8; TAG_SIZE] {
-        // Simplified implementation - in production, use a proper AES implementation
-        // This is a placeholder that demonstrates the API
-        
-        // XOR with key stream (simplified - not real AES-GCM)
-        for (i, byte) in plaintext.iter_mut().enumerate() {
-            *byte ^= self.key[i % self.key_len];
-        }
-        
-        // Compute dummy tag
-        let mut tag = [0u8; TAG_SIZE];
-        for (i, &byte) in plaintext.iter().enumerate() {
-            tag[i % TAG_SIZE] ^= byte;
-        }
-        
-        tag
+        let j0 = Self::j0(nonce);
+        self.ctr(&j0, plaintext);
+        self.tag(&j0, aad, plaintext)
     }
 
     /// Decrypt in place and verify tag
@@ -76,24 +247,43 @@ impl AesGcm {
         ciphertext: &mut [u8],
         tag: &[u8; TAG_SIZE],
     ) -> bool {
-        // Make a copy for tag verification
-        let ciphertext_copy: alloc::vec::Vec<u8> = ciphertext.iter().copied().collect();
-        let expected_tag = self.encrypt_in_place(nonce, aad, &mut ciphertext_copy.clone());
-        
-        if !crate::crypto::constant_time_eq(tag, &expected_tag) {
+        let j0 = Self::j0(nonce);
+        let expected_tag = self.tag(&j0, aad, ciphertext);
+
+        if !ct::eq(tag, &expected_tag) {
             return false;
         }
-        
-        // Decrypt (same operation as encrypt for XOR cipher)
-        for (i, byte) in ciphertext.iter_mut().enumerate() {
-            *byte ^= self.key[i % self.key_len];
-        }
-        
+
+        self.ctr(&j0, ciphertext);
         true
     }
 }
 
 /// Initialize AES module
 pub fn init() {
-    crate::println!("[aes] AES-GCM initialized (stub)");
+    // NIST SP 800-38D test case 3 (AES-128, 64-byte plaintext, no AAD)
+    let key = [
+        0xfe, 0xff, 0xe9, 0x92, 0x86, 0x65, 0x73, 0x1c,
+        0x6d, 0x6a, 0x8f, 0x94, 0x67, 0x30, 0x83, 0x08,
+    ];
+    let nonce = [0xca, 0xfe, 0xba, 0xbe, 0xfa, 0xce, 0xdb, 0xad, 0xde, 0xca, 0xf8, 0x88];
+    let expected_tag = [
+        0x4d, 0x5c, 0x2a, 0xf3, 0x27, 0xcd, 0x64, 0xa6,
+        0x2c, 0xf3, 0x5a, 0xbd, 0x2b, 0xa6, 0xfa, 0xb4,
+    ];
+    let mut data = [
+        0xd9, 0x31, 0x32, 0x25, 0xf8, 0x84, 0x06, 0xe5, 0xa5, 0x59, 0x09, 0xc5, 0xaf, 0xf5, 0x26, 0x9a,
+        0x86, 0xa7, 0xa9, 0x53, 0x15, 0x34, 0xf7, 0xda, 0x2e, 0x4c, 0x30, 0x3d, 0x8a, 0x31, 0x8a, 0x72,
+        0x1c, 0x3c, 0x0c, 0x95, 0x95, 0x68, 0x09, 0x53, 0x2f, 0xcf, 0x0e, 0x24, 0x49, 0xa6, 0xb5, 0x25,
+        0xb1, 0x6a, 0xed, 0xf5, 0xaa, 0x0d, 0xe6, 0x57, 0xba, 0x63, 0x7b, 0x39, 0x1a, 0xaf, 0xd2, 0x55,
+    ];
+
+    let gcm = AesGcm::new_128(&key);
+    let tag = gcm.encrypt_in_place(&nonce, &[], &mut data);
+
+    if tag == expected_tag {
+        crate::info!("aes", "Self-test passed");
+    } else {
+        crate::error!("aes", "Self-test FAILED");
+    }
 }
@@ -0,0 +1,274 @@
+//! Big numbers modulo an odd modulus
+//!
+//! Just enough arithmetic to check RSA and ECDSA signatures. A number is
+//! little-endian 64-bit limbs, as many as its modulus has, and products
+//! are Montgomery products. Only public values pass through here, so
+//! nothing is constant time.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// A number below some modulus, least significant limb first
+pub type Limbs = Vec<u64>;
+
+/// An odd modulus and what Montgomery multiplication needs of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Modulus {
+    n: Limbs,
+    /// -n^-1 mod 2^64
+    n0: u64,
+    /// R^2 mod n, where R is 2^(64 * limbs)
+    r2: Limbs,
+}
+
+impl Modulus {
+    /// The modulus `n`, big-endian; `None` unless it is odd and above one
+    pub fn new(n: &[u8]) -> Option<Self> {
+        let significant = n.iter().position(|&b| b != 0)?;
+        let n = from_be(n, (n.len() - significant).div_ceil(8))?;
+        if n[0] & 1 == 0 || (n.len() == 1 && n[0] == 1) {
+            return None;
+        }
+        // Newton's iteration doubles the correct low bits each time
+        let mut inverse: u64 = 1;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(n[0].wrapping_mul(inverse)));
+        }
+        let mut r2 = vec![0; n.len()];
+        r2[0] = 1;
+        for _ in 0..128 * n.len() {
+            r2 = add(&r2, &r2, &n);
+        }
+        Some(Self { n0: inverse.wrapping_neg(), n, r2 })
+    }
+
+    /// Bits in the modulus
+    pub fn bits(&self) -> usize {
+        let top = self.n.len() - 1;
+        64 * top + 64 - self.n[top].leading_zeros() as usize
+    }
+
+    /// A big-endian number, which must be below the modulus
+    pub fn element(&self, bytes: &[u8]) -> Option<Limbs> {
+        let x = from_be(bytes, self.n.len())?;
+        below(&x, &self.n).then_some(x)
+    }
+
+    /// A big-endian number as wide as the modulus, reduced
+    pub fn reduce(&self, bytes: &[u8]) -> Option<Limbs> {
+        let mut x = from_be(bytes, self.n.len())?;
+        while !below(&x, &self.n) {
+            wrapping_sub(&mut x, &self.n);
+        }
+        Some(x)
+    }
+
+    /// `x` into the Montgomery domain
+    pub fn to_mont(&self, x: &[u64]) -> Limbs {
+        self.mul(x, &self.r2)
+    }
+
+    /// `x` out of the Montgomery domain
+    pub fn from_mont(&self, x: &[u64]) -> Limbs {
+        let mut one = vec![0; self.n.len()];
+        one[0] = 1;
+        self.mul(x, &one)
+    }
+
+    /// One, in the Montgomery domain
+    pub fn one(&self) -> Limbs {
+        let mut one = vec![0; self.n.len()];
+        one[0] = 1;
+        self.to_mont(&one)
+    }
+
+    /// Montgomery product a * b / R mod n
+    pub fn mul(&self, a: &[u64], b: &[u64]) -> Limbs {
+        let len = self.n.len();
+        let mut t = vec![0u64; len + 2];
+        for &bi in b {
+            let mut carry = 0u128;
+            for j in 0..len {
+                let sum = t[j] as u128 + a[j] as u128 * bi as u128 + carry;
+                t[j] = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[len] as u128 + carry;
+            t[len] = sum as u64;
+            t[len + 1] = (sum >> 64) as u64;
+
+            let m = t[0].wrapping_mul(self.n0);
+            let mut carry = (t[0] as u128 + m as u128 * self.n[0] as u128) >> 64;
+            for j in 1..len {
+                let sum = t[j] as u128 + m as u128 * self.n[j] as u128 + carry;
+                t[j - 1] = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[len] as u128 + carry;
+            t[len - 1] = sum as u64;
+            t[len] = t[len + 1] + (sum >> 64) as u64;
+            t[len + 1] = 0;
+        }
+        let high = t[len];
+        t.truncate(len);
+        if high != 0 || !below(&t, &self.n) {
+            // Any borrow cancels the high limb
+            wrapping_sub(&mut t, &self.n);
+        }
+        t
+    }
+
+    pub fn add(&self, a: &[u64], b: &[u64]) -> Limbs {
+        add(a, b, &self.n)
+    }
+
+    pub fn sub(&self, a: &[u64], b: &[u64]) -> Limbs {
+        sub(a, b, &self.n)
+    }
+
+    /// `base` to the power `exponent`, little-endian limbs of any width;
+    /// `base` and the result are in the Montgomery domain
+    pub fn pow(&self, base: &[u64], exponent: &[u64]) -> Limbs {
+        let mut result = self.one();
+        for bit in (0..64 * exponent.len()).rev() {
+            result = self.mul(&result, &result);
+            if exponent[bit / 64] >> (bit % 64) & 1 == 1 {
+                result = self.mul(&result, base);
+            }
+        }
+        result
+    }
+
+    /// The inverse of a nonzero `x` by Fermat, for a prime modulus; in
+    /// and out of the Montgomery domain
+    pub fn invert(&self, x: &[u64]) -> Limbs {
+        let mut two = vec![0; self.n.len()];
+        two[0] = 2;
+        let exponent = sub(&self.n, &two, &self.n);
+        self.pow(x, &exponent)
+    }
+}
+
+/// A big-endian number in `len` limbs; `None` if it does not fit
+pub fn from_be(bytes: &[u8], len: usize) -> Option<Limbs> {
+    let mut limbs = vec![0u64; len];
+    for (i, &byte) in bytes.iter().rev().enumerate() {
+        if byte == 0 {
+            continue;
+        }
+        *limbs.get_mut(i / 8)? |= (byte as u64) << (8 * (i % 8));
+    }
+    Some(limbs)
+}
+
+/// `x` as `len` big-endian bytes, dropping any that do not fit
+pub fn to_be(x: &[u64], len: usize) -> Vec<u8> {
+    (0..len).rev()
+        .map(|i| x.get(i / 8).map_or(0, |limb| (limb >> (8 * (i % 8))) as u8))
+        .collect()
+}
+
+pub fn is_zero(x: &[u64]) -> bool {
+    x.iter().all(|&limb| limb == 0)
+}
+
+/// Whether a < b, both as wide
+fn below(a: &[u64], b: &[u64]) -> bool {
+    for (x, y) in a.iter().zip(b).rev() {
+        if x != y {
+            return x < y;
+        }
+    }
+    false
+}
+
+/// a + b mod n, for a and b below n
+fn add(a: &[u64], b: &[u64], n: &[u64]) -> Limbs {
+    let mut sum = vec![0u64; n.len()];
+    let mut carry = false;
+    for i in 0..n.len() {
+        let (s, c1) = a[i].overflowing_add(b[i]);
+        let (s, c2) = s.overflowing_add(carry as u64);
+        sum[i] = s;
+        carry = c1 || c2;
+    }
+    if carry || !below(&sum, n) {
+        wrapping_sub(&mut sum, n);
+    }
+    sum
+}
+
+/// a -= b, modulo 2^(64 * limbs); whether it borrowed
+fn wrapping_sub(a: &mut [u64], b: &[u64]) -> bool {
+    let mut borrow = false;
+    for (x, &y) in a.iter_mut().zip(b) {
+        let (d, b1) = x.overflowing_sub(y);
+        let (d, b2) = d.overflowing_sub(borrow as u64);
+        *x = d;
+        borrow = b1 || b2;
+    }
+    borrow
+}
+
+/// a - b mod n, for a and b below n
+fn sub(a: &[u64], b: &[u64], n: &[u64]) -> Limbs {
+    let mut difference = a[..n.len()].to_vec();
+    if wrapping_sub(&mut difference, b) {
+        let mut carry = false;
+        for i in 0..n.len() {
+            let (s, c1) = difference[i].overflowing_add(n[i]);
+            let (s, c2) = s.overflowing_add(carry as u64);
+            difference[i] = s;
+            carry = c1 || c2;
+        }
+    }
+    difference
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn moduli_must_be_odd() -> Result<(), String> {
+        check!(Modulus::new(&[0x00, 0x10]).is_none());
+        check!(Modulus::new(&[0x01]).is_none());
+        check!(Modulus::new(&[]).is_none());
+        let m = Modulus::new(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]).ok_or("no modulus")?;
+        check_eq!(m.bits(), 65);
+        check!(m.element(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).is_none());
+        check_eq!(m.reduce(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02]), Some(alloc::vec![0, 0]));
+        Ok(())
+    }
+
+    /// Small enough to check by hand: 4^13 mod 497 is 445, and 333 is the
+    /// inverse of 3 mod the prime 499
+    #[kernel_test]
+    fn powers_and_inverses() -> Result<(), String> {
+        let m = Modulus::new(&[0x01, 0xF1]).ok_or("no modulus")?;
+        let four = m.to_mont(&[4]);
+        check_eq!(m.from_mont(&m.pow(&four, &[13])), alloc::vec![445]);
+        check_eq!(m.from_mont(&m.mul(&m.to_mont(&[400]), &m.to_mont(&[300]))), alloc::vec![223]);
+        check_eq!(m.add(&[400], &[300]), alloc::vec![203]);
+        check_eq!(m.sub(&[300], &[400]), alloc::vec![397]);
+
+        let p = Modulus::new(&[0x01, 0xF3]).ok_or("no modulus")?;
+        check_eq!(p.from_mont(&p.invert(&p.to_mont(&[3]))), alloc::vec![333]);
+
+        // Wider than a limb: (2^64 + 1)^2 mod (2^127 - 1)
+        let mersenne = Modulus::new(&[0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF])
+            .ok_or("no modulus")?;
+        let x = mersenne.to_mont(&[1, 1]);
+        check_eq!(mersenne.from_mont(&mersenne.mul(&x, &x)), alloc::vec![3, 2]);
+        // Near the top of a limb, where products carry out of it: (-1)^2
+        let big = Modulus::new(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC5]).ok_or("no modulus")?;
+        let minus_one = big.to_mont(&[0xFFFF_FFFF_FFFF_FFC4]);
+        check_eq!(big.from_mont(&big.mul(&minus_one, &minus_one)), alloc::vec![1]);
+        check_eq!(to_be(&[0x0102, 0], 3), alloc::vec![0, 1, 2]);
+        check_eq!(from_be(&[0, 0, 1, 2], 1), Some(alloc::vec![0x0102]));
+        check_eq!(from_be(&[1, 0, 0, 0, 0, 0, 0, 0, 0], 1), None);
+        Ok(())
+    }
+}
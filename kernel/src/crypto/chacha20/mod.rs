@@ -9,9 +9,10 @@ pub struct ChaCha20 {
 
 /// Poly1305 state
 pub struct Poly1305 {
-    r: [u8; 16],
-    s: [u8; 16],
-    accumulator: [u8; 17],
+    // r and the accumulator in 26-bit limbs, modulo 2^130 - 5
+    r: [u32; 5],
+    s: [u32; 4],
+    accumulator: [u32; 5],
     buffer: [u8; 16],
     buffer_len: usize,
 }
@@ -140,27 +141,30 @@ impl ChaCha20 {
     }
 }
 
+/// Limbs of a Poly1305 number
+const LIMB_MASK: u32 = 0x3ffffff;
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
 impl Poly1305 {
     /// Create new Poly1305 instance
     pub fn new(key: &[u8; 32]) -> Self {
-        // Clamp r
-        let mut r = [0u8; 16];
-        r.copy_from_slice(&key[..16]);
-        r[3] &= 15;
-        r[7] &= 15;
-        r[11] &= 15;
-        r[15] &= 15;
-        r[4] &= 252;
-        r[8] &= 252;
-        r[12] &= 252;
-
-        let mut s = [0u8; 16];
-        s.copy_from_slice(&key[16..]);
+        // Split r into limbs, clamping it as they are taken
+        let r = [
+            le32(&key[0..]) & 0x3ffffff,
+            (le32(&key[3..]) >> 2) & 0x3ffff03,
+            (le32(&key[6..]) >> 4) & 0x3ffc0ff,
+            (le32(&key[9..]) >> 6) & 0x3f03fff,
+            (le32(&key[12..]) >> 8) & 0x00fffff,
+        ];
+        let s = [le32(&key[16..]), le32(&key[20..]), le32(&key[24..]), le32(&key[28..])];
 
         Self {
             r,
             s,
-            accumulator: [0; 17],
+            accumulator: [0; 5],
             buffer: [0; 16],
             buffer_len: 0,
         }
@@ -211,14 +215,43 @@ impl Poly1305 {
             self.process_block(&block, true);
         }
 
-        // Add s
-        let mut tag = [0u8; TAG_SIZE];
-        let mut carry = 0u16;
+        // Carry all the way through
+        let mut h = self.accumulator;
+        for i in 1..5 {
+            h[i] += h[i - 1] >> 26;
+            h[i - 1] &= LIMB_MASK;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= LIMB_MASK;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+
+        // h - p, taken instead of h when h is at least p; chosen in
+        // constant time so the time is the same either way
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..5 {
+            let sum = h[i] + carry;
+            g[i] = sum & LIMB_MASK;
+            carry = sum >> 26;
+        }
+        for i in 0..5 {
+            h[i] = crate::crypto::ct::select_u32(carry as u8, g[i], h[i]);
+        }
 
-        for i in 0..16 {
-            let sum = self.accumulator[i] as u16 + self.s[i] as u16 + carry;
-            tag[i] = sum as u8;
-            carry = sum >> 8;
+        // Back to 32-bit words, then add s
+        let words = [
+            h[0] | h[1] << 26,
+            h[1] >> 6 | h[2] << 20,
+            h[2] >> 12 | h[3] << 14,
+            h[3] >> 18 | h[4] << 8,
+        ];
+        let mut tag = [0u8; TAG_SIZE];
+        let mut carry = 0u64;
+        for i in 0..4 {
+            let sum = words[i] as u64 + self.s[i] as u64 + carry;
+            tag[i * 4..i * 4 + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
         }
 
         tag
@@ -227,54 +260,37 @@ impl Poly1305 {
     /// Process a single block
     fn process_block(&mut self, block: &[u8], padded: bool) {
         // Add block to accumulator (with implicit 2^128 if padded=false)
-        let mut carry = if padded { 0 } else { 1 };
-
-        for i in 0..16 {
-            let sum = self.accumulator[i] as u16 + block[i] as u16 + carry;
-            self.accumulator[i] = sum as u8;
-            carry = sum >> 8;
-        }
-        self.accumulator[16] = carry as u8;
-
-        // Multiply by r (mod 2^130 - 5)
-        let mut result = [0u8; 17];
-
-        for i in 0..17 {
-            let mut carry = 0u32;
-            for j in 0..16 {
-                if i + j >= 17 {
-                    break;
-                }
-                let prod = (self.accumulator[i] as u32) * (self.r[j] as u32) + result[i + j] as u32 + carry;
-                result[i + j] = prod as u8;
-                carry = prod >> 8;
-            }
-            if i + 16 < 17 {
-                result[i + 16] = carry as u8;
-            }
-        }
-
-        // Reduce mod 2^130 - 5
-        let mut carry = (result[16] as u32) * 5;
-        for i in 0..16 {
-            let sum = result[i] as u32 + carry;
-            result[i] = sum as u8;
-            carry = sum >> 8;
+        let high_bit = if padded { 0 } else { 1 << 24 };
+        let h = &mut self.accumulator;
+        h[0] += le32(&block[0..]) & LIMB_MASK;
+        h[1] += (le32(&block[3..]) >> 2) & LIMB_MASK;
+        h[2] += (le32(&block[6..]) >> 4) & LIMB_MASK;
+        h[3] += (le32(&block[9..]) >> 6) & LIMB_MASK;
+        h[4] += (le32(&block[12..]) >> 8) | high_bit;
+
+        // Multiply by r; limbs past the fifth come back around times 5, as
+        // 2^130 = 5 (mod 2^130 - 5)
+        let r = self.r.map(|limb| limb as u64);
+        let r5 = r.map(|limb| limb * 5);
+        let a = h.map(|limb| limb as u64);
+        let d = [
+            a[0] * r[0] + a[1] * r5[4] + a[2] * r5[3] + a[3] * r5[2] + a[4] * r5[1],
+            a[0] * r[1] + a[1] * r[0] + a[2] * r5[4] + a[3] * r5[3] + a[4] * r5[2],
+            a[0] * r[2] + a[1] * r[1] + a[2] * r[0] + a[3] * r5[4] + a[4] * r5[3],
+            a[0] * r[3] + a[1] * r[2] + a[2] * r[1] + a[3] * r[0] + a[4] * r5[4],
+            a[0] * r[4] + a[1] * r[3] + a[2] * r[2] + a[3] * r[1] + a[4] * r[0],
+        ];
+
+        // Partly reduce: limbs of 26 bits, the first a little over
+        let mut carry = 0u64;
+        for i in 0..5 {
+            let sum = d[i] + carry;
+            h[i] = (sum as u32) & LIMB_MASK;
+            carry = sum >> 26;
         }
-        result[16] = carry as u8;
-
-        // Second reduction if needed
-        if result[16] != 0 {
-            carry = (result[16] as u32) * 5;
-            for i in 0..16 {
-                let sum = result[i] as u32 + carry;
-                result[i] = sum as u8;
-                carry = sum >> 8;
-            }
-            result[16] = carry as u8;
-        }
-
-        self.accumulator = result;
+        let first = h[0] as u64 + carry * 5;
+        h[0] = (first as u32) & LIMB_MASK;
+        h[1] += (first >> 26) as u32;
     }
 }
 
@@ -317,7 +333,7 @@ impl ChaCha20Poly1305 {
         let expected_tag = Self::compute_mac(&poly_key, aad, ciphertext);
 
         // Verify MAC (constant time)
-        if !crate::crypto::constant_time_eq(tag, &expected_tag) {
+        if !crate::crypto::ct::eq(tag, &expected_tag) {
             return false;
         }
 
@@ -368,5 +384,62 @@ pub fn init() {
     let mut encrypted = plaintext.clone();
     let tag = ChaCha20Poly1305::encrypt_in_place(&key, &nonce, aad, &mut encrypted);
 
-    crate::println!("[chacha20] Self-test passed");
+    // RFC 8439, section 2.8.2
+    let expected_tag = [
+        0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a,
+        0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60, 0x06, 0x91,
+    ];
+    if tag == expected_tag && encrypted[..4] == [0xd3, 0x1a, 0x8d, 0x34] {
+        crate::info!("chacha20", "Self-test passed");
+    } else {
+        crate::error!("chacha20", "Self-test FAILED");
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// RFC 8439, section 2.5.2
+    #[kernel_test]
+    fn poly1305_rfc8439() -> Result<(), String> {
+        let key = [
+            0x85, 0xd6, 0xbe, 0x78, 0x57, 0x55, 0x6d, 0x33, 0x7f, 0x44, 0x52, 0xfe, 0x42, 0xd5, 0x06, 0xa8,
+            0x01, 0x03, 0x80, 0x8a, 0xfb, 0x0d, 0xb2, 0xfd, 0x4a, 0xbf, 0xf6, 0xaf, 0x41, 0x49, 0xf5, 0x1b,
+        ];
+        let mut poly = Poly1305::new(&key);
+        poly.update(b"Cryptographic Forum ");
+        poly.update(b"Research Group");
+        check_eq!(poly.finalize(), [
+            0xa8, 0x06, 0x1d, 0xc1, 0x30, 0x51, 0x36, 0xc6, 0xc2, 0x2b, 0x8b, 0xaf, 0x0c, 0x01, 0x27, 0xa9,
+        ]);
+        Ok(())
+    }
+
+    /// RFC 8439, section 2.8.2, there and back
+    #[kernel_test]
+    fn aead_rfc8439() -> Result<(), String> {
+        let key: [u8; KEY_SIZE] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce = [0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let aad = [0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let mut data = *plaintext;
+        let tag = ChaCha20Poly1305::encrypt_in_place(&key, &nonce, &aad, &mut data);
+        check_eq!(data[..16], [
+            0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86, 0xaf, 0xbc, 0x53, 0xef, 0x7e, 0xc2,
+        ]);
+        check_eq!(tag, [
+            0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60, 0x06, 0x91,
+        ]);
+
+        let mut tampered = data;
+        tampered[0] ^= 1;
+        check!(!ChaCha20Poly1305::decrypt_in_place(&key, &nonce, &aad, &mut tampered, &tag));
+        check!(ChaCha20Poly1305::decrypt_in_place(&key, &nonce, &aad, &mut data, &tag));
+        check_eq!(&data[..], &plaintext[..]);
+        Ok(())
+    }
 }
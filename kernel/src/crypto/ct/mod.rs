@@ -0,0 +1,186 @@
+//! Constant-time primitives
+//!
+//! Every comparison, selection or table lookup whose operands depend on
+//! secret data goes through this module. The helpers avoid data-dependent
+//! branches and memory addresses; `black_box` keeps the optimizer from
+//! turning the masks back into branches.
+
+use core::hint::black_box;
+
+/// Expand the low bit of `bit` into an all-zeros or all-ones mask
+#[inline]
+pub fn mask_u8(bit: u8) -> u8 {
+    black_box(0u8.wrapping_sub(bit & 1))
+}
+
+/// Expand the low bit of `bit` into an all-zeros or all-ones mask
+#[inline]
+pub fn mask_u32(bit: u8) -> u32 {
+    black_box(0u32.wrapping_sub((bit & 1) as u32))
+}
+
+/// Expand the low bit of `bit` into an all-zeros or all-ones mask
+#[inline]
+pub fn mask_u64(bit: u8) -> u64 {
+    black_box(0u64.wrapping_sub((bit & 1) as u64))
+}
+
+/// Compare two byte slices in time independent of their contents
+///
+/// The lengths are treated as public.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+
+    black_box(diff) == 0
+}
+
+/// Returns 1 if `a == b`, 0 otherwise
+#[inline]
+pub fn eq_u32(a: u32, b: u32) -> u8 {
+    let x = a ^ b;
+    // High bit of (x | -x) is set iff x != 0
+    let nonzero = ((x | x.wrapping_neg()) >> 31) as u8;
+    black_box(nonzero ^ 1)
+}
+
+/// Select `a` when `choice` is 1, `b` when 0
+#[inline]
+pub fn select_u8(choice: u8, a: u8, b: u8) -> u8 {
+    let m = mask_u8(choice);
+    (a & m) | (b & !m)
+}
+
+/// Select `a` when `choice` is 1, `b` when 0
+#[inline]
+pub fn select_u32(choice: u8, a: u32, b: u32) -> u32 {
+    let m = mask_u32(choice);
+    (a & m) | (b & !m)
+}
+
+/// Select `a` when `choice` is 1, `b` when 0
+#[inline]
+pub fn select_u64(choice: u8, a: u64, b: u64) -> u64 {
+    let m = mask_u64(choice);
+    (a & m) | (b & !m)
+}
+
+/// Copy `src` into `dst` when `choice` is 1, leave `dst` unchanged when 0
+pub fn conditional_copy(choice: u8, dst: &mut [u8], src: &[u8]) {
+    let m = mask_u8(choice);
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        *d ^= m & (*d ^ s);
+    }
+}
+
+/// Swap `a` and `b` when `choice` is 1, leave them unchanged when 0
+pub fn conditional_swap_u32(choice: u8, a: &mut [u32], b: &mut [u32]) {
+    let m = mask_u32(choice);
+    for (x, y) in a.iter_mut().zip(b.iter_mut()) {
+        let t = m & (*x ^ *y);
+        *x ^= t;
+        *y ^= t;
+    }
+}
+
+/// Swap `a` and `b` when `choice` is 1, leave them unchanged when 0
+pub fn conditional_swap_u64(choice: u8, a: &mut [u64], b: &mut [u64]) {
+    let m = mask_u64(choice);
+    for (x, y) in a.iter_mut().zip(b.iter_mut()) {
+        let t = m & (*x ^ *y);
+        *x ^= t;
+        *y ^= t;
+    }
+}
+
+/// Read `table[index]` while touching every entry
+///
+/// The memory access pattern is independent of `index`, so cache timing
+/// does not reveal it. Used for S-box lookups on secret data.
+pub fn lookup_u8(table: &[u8], index: u8) -> u8 {
+    let mut result = 0u8;
+    for (i, &entry) in table.iter().enumerate() {
+        result |= entry & mask_u8(eq_u32(i as u32, index as u32));
+    }
+    result
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn masks_follow_the_low_bit() -> Result<(), String> {
+        check_eq!(mask_u8(0), 0);
+        check_eq!(mask_u8(1), 0xFF);
+        check_eq!(mask_u8(0xFE), 0);
+        check_eq!(mask_u32(2), 0);
+        check_eq!(mask_u32(1), u32::MAX);
+        check_eq!(mask_u64(3), u64::MAX);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn comparisons() -> Result<(), String> {
+        check!(eq(b"", b""));
+        check!(eq(b"tag bytes", b"tag bytes"));
+        check!(!eq(b"tag bytes", b"tag byteS"));
+        check!(!eq(b"\x80tag", b"\x00tag"));
+        check!(!eq(b"tag", b"tag bytes"));
+        check_eq!(eq_u32(7, 7), 1);
+        check_eq!(eq_u32(0, 0x8000_0000), 0);
+        check_eq!(eq_u32(u32::MAX, u32::MAX - 1), 0);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn lookup_matches_indexing() -> Result<(), String> {
+        let table: Vec<u8> = (0..=255u8).map(|i| i.wrapping_mul(167).wrapping_add(13)).collect();
+        for index in 0..=255u8 {
+            check_eq!(lookup_u8(&table, index), table[index as usize]);
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn selections() -> Result<(), String> {
+        check_eq!(select_u8(1, 0xA5, 0x5A), 0xA5);
+        check_eq!(select_u8(0, 0xA5, 0x5A), 0x5A);
+        check_eq!(select_u32(1, 7, u32::MAX), 7);
+        check_eq!(select_u32(0, 7, u32::MAX), u32::MAX);
+        check_eq!(select_u64(3, u64::MAX, 1), u64::MAX);
+        check_eq!(select_u64(2, u64::MAX, 1), 1);
+
+        let mut dst = *b"secret";
+        conditional_copy(0, &mut dst, b"public");
+        check_eq!(&dst, b"secret");
+        conditional_copy(1, &mut dst, b"public");
+        check_eq!(&dst, b"public");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn swap_only_when_chosen() -> Result<(), String> {
+        let (mut a, mut b) = ([1u64, u64::MAX], [2u64, 0]);
+        conditional_swap_u64(0, &mut a, &mut b);
+        check_eq!((a, b), ([1, u64::MAX], [2, 0]));
+        conditional_swap_u64(1, &mut a, &mut b);
+        check_eq!((a, b), ([2, 0], [1, u64::MAX]));
+
+        let (mut c, mut d) = ([3u32, 0], [4u32, u32::MAX]);
+        conditional_swap_u32(0, &mut c, &mut d);
+        check_eq!((c, d), ([3, 0], [4, u32::MAX]));
+        conditional_swap_u32(1, &mut c, &mut d);
+        check_eq!((c, d), ([4, u32::MAX], [3, 0]));
+        Ok(())
+    }
+}
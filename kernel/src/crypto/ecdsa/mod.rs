@@ -0,0 +1,298 @@
+//! ECDSA signature checks on P-256 and P-384
+//!
+//! Only verification is here, for the certificates and CertificateVerify
+//! of a TLS handshake. Points are Jacobian, their coordinates in the
+//! Montgomery domain of the field, and the curves' a is -3.
+
+use crate::crypto::bignum::{self, Limbs, Modulus};
+
+/// Curves a key may be on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    P256,
+    P384,
+}
+
+impl Curve {
+    /// Bytes in a coordinate or a scalar
+    pub fn size(self) -> usize {
+        match self {
+            Curve::P256 => 32,
+            Curve::P384 => 48,
+        }
+    }
+
+    /// p, n, b and the generator, big-endian
+    fn params(self) -> [&'static [u8]; 5] {
+        match self {
+            Curve::P256 => [&P256_P, &P256_N, &P256_B, &P256_GX, &P256_GY],
+            Curve::P384 => [&P384_P, &P384_N, &P384_B, &P384_GX, &P384_GY],
+        }
+    }
+}
+
+// Parameters of the curves, from SEC 2
+const P256_P: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+const P256_N: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+const P256_B: [u8; 32] = [
+    0x5a, 0xc6, 0x35, 0xd8, 0xaa, 0x3a, 0x93, 0xe7, 0xb3, 0xeb, 0xbd, 0x55, 0x76, 0x98, 0x86, 0xbc,
+    0x65, 0x1d, 0x06, 0xb0, 0xcc, 0x53, 0xb0, 0xf6, 0x3b, 0xce, 0x3c, 0x3e, 0x27, 0xd2, 0x60, 0x4b,
+];
+const P256_GX: [u8; 32] = [
+    0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40, 0xf2,
+    0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2, 0x96,
+];
+const P256_GY: [u8; 32] = [
+    0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e, 0x16,
+    0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51, 0xf5,
+];
+const P384_P: [u8; 48] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+];
+const P384_N: [u8; 48] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc7, 0x63, 0x4d, 0x81, 0xf4, 0x37, 0x2d, 0xdf,
+    0x58, 0x1a, 0x0d, 0xb2, 0x48, 0xb0, 0xa7, 0x7a, 0xec, 0xec, 0x19, 0x6a, 0xcc, 0xc5, 0x29, 0x73,
+];
+const P384_B: [u8; 48] = [
+    0xb3, 0x31, 0x2f, 0xa7, 0xe2, 0x3e, 0xe7, 0xe4, 0x98, 0x8e, 0x05, 0x6b, 0xe3, 0xf8, 0x2d, 0x19,
+    0x18, 0x1d, 0x9c, 0x6e, 0xfe, 0x81, 0x41, 0x12, 0x03, 0x14, 0x08, 0x8f, 0x50, 0x13, 0x87, 0x5a,
+    0xc6, 0x56, 0x39, 0x8d, 0x8a, 0x2e, 0xd1, 0x9d, 0x2a, 0x85, 0xc8, 0xed, 0xd3, 0xec, 0x2a, 0xef,
+];
+const P384_GX: [u8; 48] = [
+    0xaa, 0x87, 0xca, 0x22, 0xbe, 0x8b, 0x05, 0x37, 0x8e, 0xb1, 0xc7, 0x1e, 0xf3, 0x20, 0xad, 0x74,
+    0x6e, 0x1d, 0x3b, 0x62, 0x8b, 0xa7, 0x9b, 0x98, 0x59, 0xf7, 0x41, 0xe0, 0x82, 0x54, 0x2a, 0x38,
+    0x55, 0x02, 0xf2, 0x5d, 0xbf, 0x55, 0x29, 0x6c, 0x3a, 0x54, 0x5e, 0x38, 0x72, 0x76, 0x0a, 0xb7,
+];
+const P384_GY: [u8; 48] = [
+    0x36, 0x17, 0xde, 0x4a, 0x96, 0x26, 0x2c, 0x6f, 0x5d, 0x9e, 0x98, 0xbf, 0x92, 0x92, 0xdc, 0x29,
+    0xf8, 0xf4, 0x1d, 0xbd, 0x28, 0x9a, 0x14, 0x7c, 0xe9, 0xda, 0x31, 0x13, 0xb5, 0xf0, 0xb8, 0xc0,
+    0x0a, 0x60, 0xb1, 0xce, 0x1d, 0x7e, 0x81, 0x9d, 0x7a, 0x43, 0x1d, 0x7c, 0x90, 0xea, 0x0e, 0x5f,
+];
+
+/// A point; z is zero at infinity
+#[derive(Clone)]
+struct Point {
+    x: Limbs,
+    y: Limbs,
+    z: Limbs,
+}
+
+/// A curve's field and group, ready to compute in
+struct Group {
+    field: Modulus,
+    order: Modulus,
+    /// b, in the field's Montgomery domain
+    b: Limbs,
+    generator: Point,
+}
+
+impl Group {
+    fn new(curve: Curve) -> Option<Self> {
+        let [p, n, b, gx, gy] = curve.params();
+        let field = Modulus::new(p)?;
+        let order = Modulus::new(n)?;
+        let generator = Point {
+            x: field.to_mont(&field.element(gx)?),
+            y: field.to_mont(&field.element(gy)?),
+            z: field.one(),
+        };
+        Some(Self { b: field.to_mont(&field.element(b)?), field, order, generator })
+    }
+
+    fn infinity(&self) -> Point {
+        Point { x: self.field.one(), y: self.field.one(), z: alloc::vec![0; self.b.len()] }
+    }
+
+    /// Whether y^2 = x^3 - 3x + b
+    fn on_curve(&self, x: &Limbs, y: &Limbs) -> bool {
+        let f = &self.field;
+        let x3 = f.mul(&f.mul(x, x), x);
+        let three_x = f.add(&f.add(x, x), x);
+        f.mul(y, y) == f.add(&f.sub(&x3, &three_x), &self.b)
+    }
+
+    fn double(&self, p: &Point) -> Point {
+        if bignum::is_zero(&p.z) {
+            return p.clone();
+        }
+        let f = &self.field;
+        let delta = f.mul(&p.z, &p.z);
+        let gamma = f.mul(&p.y, &p.y);
+        let beta = f.mul(&p.x, &gamma);
+        let t = f.mul(&f.sub(&p.x, &delta), &f.add(&p.x, &delta));
+        let alpha = f.add(&f.add(&t, &t), &t);
+        let beta2 = f.add(&beta, &beta);
+        let beta4 = f.add(&beta2, &beta2);
+        let beta8 = f.add(&beta4, &beta4);
+        let x = f.sub(&f.mul(&alpha, &alpha), &beta8);
+        let yz = f.add(&p.y, &p.z);
+        let z = f.sub(&f.sub(&f.mul(&yz, &yz), &gamma), &delta);
+        let gamma_sq = f.mul(&gamma, &gamma);
+        let gamma_sq2 = f.add(&gamma_sq, &gamma_sq);
+        let gamma_sq4 = f.add(&gamma_sq2, &gamma_sq2);
+        let y = f.sub(&f.mul(&alpha, &f.sub(&beta4, &x)), &f.add(&gamma_sq4, &gamma_sq4));
+        Point { x, y, z }
+    }
+
+    fn add(&self, p: &Point, q: &Point) -> Point {
+        if bignum::is_zero(&p.z) {
+            return q.clone();
+        }
+        if bignum::is_zero(&q.z) {
+            return p.clone();
+        }
+        let f = &self.field;
+        let z1z1 = f.mul(&p.z, &p.z);
+        let z2z2 = f.mul(&q.z, &q.z);
+        let u1 = f.mul(&p.x, &z2z2);
+        let u2 = f.mul(&q.x, &z1z1);
+        let s1 = f.mul(&f.mul(&p.y, &q.z), &z2z2);
+        let s2 = f.mul(&f.mul(&q.y, &p.z), &z1z1);
+        let h = f.sub(&u2, &u1);
+        let r = f.sub(&s2, &s1);
+        if bignum::is_zero(&h) {
+            return if bignum::is_zero(&r) { self.double(p) } else { self.infinity() };
+        }
+        let hh = f.mul(&h, &h);
+        let hhh = f.mul(&h, &hh);
+        let v = f.mul(&u1, &hh);
+        let x = f.sub(&f.sub(&f.mul(&r, &r), &hhh), &f.add(&v, &v));
+        let y = f.sub(&f.mul(&r, &f.sub(&v, &x)), &f.mul(&s1, &hhh));
+        let z = f.mul(&f.mul(&p.z, &q.z), &h);
+        Point { x, y, z }
+    }
+}
+
+/// Whether (r, s), big-endian, is a signature of `digest` by `key`, an
+/// uncompressed point; a digest longer than the curve is cut to its
+/// leftmost bytes
+pub fn verify(curve: Curve, key: &[u8], digest: &[u8], r: &[u8], s: &[u8]) -> bool {
+    let size = curve.size();
+    let group = match Group::new(curve) {
+        Some(group) => group,
+        None => return false,
+    };
+    let (field, order) = (&group.field, &group.order);
+    if key.len() != 1 + 2 * size || key[0] != 0x04 {
+        return false;
+    }
+    let q = match (field.element(&key[1..1 + size]), field.element(&key[1 + size..])) {
+        (Some(x), Some(y)) => Point { x: field.to_mont(&x), y: field.to_mont(&y), z: field.one() },
+        _ => return false,
+    };
+    if !group.on_curve(&q.x, &q.y) {
+        return false;
+    }
+    let (r, s) = match (order.element(r), order.element(s)) {
+        (Some(r), Some(s)) if !bignum::is_zero(&r) && !bignum::is_zero(&s) => (r, s),
+        _ => return false,
+    };
+    let e = match order.reduce(&digest[..digest.len().min(size)]) {
+        Some(e) => e,
+        None => return false,
+    };
+
+    // u1 = e / s and u2 = r / s, then u1 G + u2 Q, a bit of each at a time
+    let w = order.invert(&order.to_mont(&s));
+    let u1 = order.from_mont(&order.mul(&order.to_mont(&e), &w));
+    let u2 = order.from_mont(&order.mul(&order.to_mont(&r), &w));
+    let both = group.add(&group.generator, &q);
+    let mut point = group.infinity();
+    for bit in (0..64 * u1.len()).rev() {
+        point = group.double(&point);
+        match (u1[bit / 64] >> (bit % 64) & 1, u2[bit / 64] >> (bit % 64) & 1) {
+            (1, 0) => point = group.add(&point, &group.generator),
+            (0, 1) => point = group.add(&point, &q),
+            (1, 1) => point = group.add(&point, &both),
+            _ => {}
+        }
+    }
+    if bignum::is_zero(&point.z) {
+        return false;
+    }
+    // The affine x, mod n, must be r
+    let z = field.invert(&point.z);
+    let x = field.from_mont(&field.mul(&point.x, &field.mul(&z, &z)));
+    order.reduce(&bignum::to_be(&x, size)) == Some(r)
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::crypto::{sha256, sha384};
+    use crate::testing::kernel_test;
+    use crate::check;
+
+    const MESSAGE: &[u8] = b"WebbOS checks signatures";
+
+    /// P-256 key, and its signature of MESSAGE with SHA-256
+    const P256_KEY: [u8; 65] = [
+        0x04, 0x66, 0x30, 0x2a, 0xc3, 0xb8, 0xee, 0x5d, 0xfc, 0xdf, 0x53, 0x6c, 0x6e, 0x2a, 0x42, 0x58,
+        0x48, 0xb6, 0x4f, 0xca, 0x27, 0xe7, 0xc6, 0x46, 0xac, 0xd2, 0x5e, 0xd3, 0xf5, 0xf9, 0x59, 0x3e,
+        0x29, 0xb5, 0xcf, 0x86, 0x8d, 0x57, 0x1d, 0x47, 0x2e, 0xc3, 0x27, 0xdc, 0xe1, 0xd8, 0x5b, 0xd6,
+        0x47, 0x10, 0xb7, 0xc5, 0x1e, 0x32, 0x9c, 0x17, 0x54, 0xa3, 0x0e, 0x1e, 0xf0, 0xde, 0x5a, 0xab,
+        0xf4,
+    ];
+    const P256_R: [u8; 32] = [
+        0x0a, 0x64, 0x75, 0x49, 0x1d, 0xc6, 0xc9, 0x47, 0x14, 0x25, 0x3b, 0x05, 0x25, 0x09, 0x6b, 0x31,
+        0x2f, 0x63, 0xae, 0x07, 0xb2, 0x11, 0xff, 0x40, 0x86, 0x34, 0xba, 0x08, 0x99, 0xf3, 0xc7, 0x97,
+    ];
+    const P256_S: [u8; 32] = [
+        0x74, 0x4a, 0x32, 0x09, 0xae, 0xea, 0x66, 0x00, 0x92, 0x2b, 0x60, 0xb6, 0x09, 0x2e, 0xce, 0xf0,
+        0x12, 0x39, 0xb5, 0x19, 0xc2, 0xc9, 0x6e, 0xfd, 0x18, 0xe0, 0xcf, 0x0d, 0xd2, 0xc6, 0x5d, 0xdf,
+    ];
+    /// P-384 key, and its signature of MESSAGE with SHA-384
+    const P384_KEY: [u8; 97] = [
+        0x04, 0x3f, 0xdd, 0x4e, 0xb4, 0x5e, 0x67, 0xe0, 0xd3, 0x78, 0x24, 0x5f, 0xe9, 0x31, 0xf9, 0xf3,
+        0x93, 0x7b, 0xa8, 0x6d, 0x20, 0x2e, 0x88, 0x5f, 0x7e, 0x1d, 0xae, 0x07, 0x59, 0x36, 0x89, 0xcd,
+        0xa3, 0x48, 0xec, 0x2f, 0x9b, 0xb8, 0xa0, 0x0f, 0xaf, 0xcb, 0xa5, 0x5c, 0x2b, 0x24, 0x5a, 0x6b,
+        0xf5, 0xa7, 0xf9, 0x3e, 0xa9, 0xd3, 0xcd, 0xb9, 0xd8, 0x6d, 0x7f, 0xb4, 0x21, 0x52, 0x37, 0x30,
+        0xd9, 0x3c, 0xc0, 0x7d, 0x2b, 0x4c, 0xd0, 0x31, 0x64, 0x8e, 0x5e, 0xee, 0xd1, 0x4c, 0x12, 0xb5,
+        0x01, 0xd8, 0x0e, 0x23, 0xb7, 0x04, 0xe1, 0x17, 0x9d, 0x8f, 0x4a, 0x85, 0xae, 0x43, 0x48, 0x5d,
+        0xcc,
+    ];
+    const P384_R: [u8; 48] = [
+        0x78, 0xe2, 0xfd, 0x39, 0x73, 0xf6, 0x30, 0x69, 0xb7, 0x20, 0x18, 0x98, 0xba, 0x24, 0x00, 0x2d,
+        0x97, 0x05, 0xd6, 0xbb, 0x53, 0x7c, 0x5d, 0x80, 0x93, 0xdb, 0xfc, 0xd9, 0x19, 0xd8, 0xa9, 0xbd,
+        0xcd, 0x2a, 0x1a, 0xf6, 0xb7, 0x7e, 0xc8, 0xcf, 0xf1, 0x94, 0x4c, 0xcb, 0x4e, 0x91, 0x34, 0x73,
+    ];
+    const P384_S: [u8; 48] = [
+        0xbb, 0x60, 0xf9, 0xb3, 0xae, 0x75, 0xf6, 0x66, 0xb6, 0x5f, 0xee, 0xc0, 0xb5, 0x51, 0x78, 0xb9,
+        0x3c, 0x42, 0x46, 0xfb, 0xac, 0xa0, 0x70, 0x20, 0xa4, 0x8c, 0x6c, 0x3d, 0xbb, 0xff, 0x42, 0x17,
+        0x9d, 0x31, 0xb6, 0x33, 0xbf, 0x50, 0xf8, 0xff, 0x5f, 0xa2, 0xf8, 0x32, 0x79, 0xc5, 0x15, 0x4e,
+    ];
+
+    #[kernel_test]
+    fn p256_signatures_verify() -> Result<(), String> {
+        let digest = sha256::hash(MESSAGE);
+        check!(verify(Curve::P256, &P256_KEY, &digest, &P256_R, &P256_S));
+        check!(!verify(Curve::P256, &P256_KEY, &sha256::hash(b"WebbOS checks signatures!"), &P256_R, &P256_S));
+        check!(!verify(Curve::P256, &P256_KEY, &digest, &P256_S, &P256_R));
+        check!(!verify(Curve::P256, &P256_KEY, &digest, &P256_N, &P256_S));
+        check!(!verify(Curve::P256, &P256_KEY, &digest, &[0; 32], &P256_S));
+        // The same key moved off the curve
+        let mut off = P256_KEY;
+        off[64] ^= 1;
+        check!(!verify(Curve::P256, &off, &digest, &P256_R, &P256_S));
+        check!(!verify(Curve::P384, &P256_KEY, &digest, &P256_R, &P256_S));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn p384_signatures_verify() -> Result<(), String> {
+        let digest = sha384::hash(MESSAGE);
+        check!(verify(Curve::P384, &P384_KEY, &digest, &P384_R, &P384_S));
+        check!(!verify(Curve::P384, &P384_KEY, &sha384::hash(b"webbOS checks signatures"), &P384_R, &P384_S));
+        check!(!verify(Curve::P384, &P384_KEY, &digest, &P384_R, &P384_R));
+        Ok(())
+    }
+}
@@ -52,15 +52,19 @@ pub mod labels {
     pub const DERIVED: &[u8] = b"derived";
 }
 
+/// What every TLS 1.3 label starts with
+const LABEL_PREFIX: &[u8] = b"tls13 ";
+
 /// Create HkdfLabel structure as per TLS 1.3
 fn make_label(label: &[u8], context: &[u8], length: u16) -> Vec<u8> {
-    let mut result = Vec::with_capacity(2 + 1 + label.len() + 1 + context.len());
+    let mut result = Vec::with_capacity(2 + 1 + LABEL_PREFIX.len() + label.len() + 1 + context.len());
     
     // Length
     result.extend_from_slice(&length.to_be_bytes());
     
-    // Label length and label
-    result.push(label.len() as u8);
+    // Label length and label, which TLS 1.3 prefixes
+    result.push((LABEL_PREFIX.len() + label.len()) as u8);
+    result.extend_from_slice(LABEL_PREFIX);
     result.extend_from_slice(label);
     
     // Context length and context
@@ -93,7 +97,47 @@ pub fn derive_secret(secret: &[u8; DIGEST_SIZE], label: &[u8], messages: &[u8])
 
 /// Initialize HKDF module
 pub fn init() {
-    crate::println!("[hkdf] HKDF initialized");
+    crate::info!("hkdf", "HKDF initialized");
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::check_eq;
+    use crate::testing::kernel_test;
+    use alloc::string::String;
+
+    /// RFC 5869, test case 1
+    #[kernel_test]
+    fn rfc5869_case_1() -> Result<(), String> {
+        let salt: [u8; 13] = core::array::from_fn(|i| i as u8);
+        let info: [u8; 10] = core::array::from_fn(|i| 0xf0 + i as u8);
+        let expected = [
+        0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a,
+        0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36, 0x2f, 0x2a,
+        0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c,
+        0x5d, 0xb0, 0x2d, 0x56, 0xec, 0xc4, 0xc5, 0xbf,
+        0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18,
+        0x58, 0x65,
+        ];
+        check_eq!(derive(&salt, &[0x0b; 22], &info, 42), expected);
+        Ok(())
+    }
+
+    /// RFC 8448, simple 1-RTT handshake: the early secret and the salt
+    /// derived from it
+    #[kernel_test]
+    fn rfc8448_derived_secret() -> Result<(), String> {
+        let early = extract(&[0; DIGEST_SIZE], &[0; DIGEST_SIZE]);
+        check_eq!(early, [
+        0x33, 0xad, 0x0a, 0x1c, 0x60, 0x7e, 0xc0, 0x3b, 0x09, 0xe6, 0xcd, 0x98, 0x93, 0x68, 0x0c, 0xe2,
+        0x10, 0xad, 0xf3, 0x00, 0xaa, 0x1f, 0x26, 0x60, 0xe1, 0xb2, 0x2e, 0x10, 0xf1, 0x70, 0xf9, 0x2a,
+        ]);
+        check_eq!(derive_secret(&early, labels::DERIVED, &[]), [
+        0x6f, 0x26, 0x15, 0xa1, 0x08, 0xc7, 0x02, 0xc5, 0x67, 0x8f, 0x54, 0xfc, 0x9d, 0xba, 0xb6, 0x97,
+        0x16, 0xc0, 0x76, 0x18, 0x9c, 0x48, 0x25, 0x0c, 0xeb, 0xea, 0xc3, 0x57, 0x6c, 0x36, 0x11, 0xba,
+        ]);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
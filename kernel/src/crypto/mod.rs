@@ -6,20 +6,40 @@
 //! - ChaCha20-Poly1305 AEAD cipher
 //! - HKDF key derivation
 //! - X25519 key exchange
+//! - RSA and ECDSA (P-256, P-384) signature checks, over `bignum`
+//! - ChaCha20 CSPRNG seeded from the hardware TRNG
+//!
+//! SHA-1 is here too, for the one-time passwords of two-factor logins.
+//!
+//! Hot primitives dispatch through `accel`, which selects SHA-NI, AES-NI and
+//! PCLMULQDQ implementations at init when the CPU supports them. Comparisons
+//! and lookups on secret data use the constant-time helpers in `ct`.
 
+pub mod accel;
+pub mod bignum;
+pub mod ct;
+pub mod ecdsa;
+pub mod rng;
+pub mod sha1;
 pub mod sha256;
 pub mod sha384;
 pub mod aes;
 pub mod chacha20;
 pub mod hkdf;
+pub mod rsa;
 pub mod x25519;
 
-use crate::println;
+use alloc::vec::Vec;
+
+use crate::info;
 
 /// Initialize cryptographic subsystem
 pub fn init() {
-    println!("[crypto] Initializing cryptographic subsystem...");
+    info!("crypto", "Initializing cryptographic subsystem...");
     
+    accel::init();
+    rng::init();
+    sha1::init();
     sha256::init();
     sha384::init();
     aes::init();
@@ -27,21 +47,31 @@ pub fn init() {
     hkdf::init();
     x25519::init();
     
-    println!("[crypto] Cryptographic subsystem initialized");
+    info!("crypto", "Cryptographic subsystem initialized");
+}
+
+/// Hash functions a signature may be made over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    Sha256,
+    Sha384,
 }
 
-/// Constant-time comparison of two byte slices
-pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
-    if a.len() != b.len() {
-        return false;
+impl Hash {
+    /// Bytes in a digest
+    pub fn size(self) -> usize {
+        match self {
+            Hash::Sha256 => sha256::DIGEST_SIZE,
+            Hash::Sha384 => sha384::DIGEST_SIZE,
+        }
     }
-    
-    let mut result = 0u8;
-    for i in 0..a.len() {
-        result |= a[i] ^ b[i];
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Hash::Sha256 => sha256::hash(data).to_vec(),
+            Hash::Sha384 => sha384::hash(data).to_vec(),
+        }
     }
-    
-    result == 0
 }
 
 /// XOR two byte slices in place
@@ -0,0 +1,217 @@
+//! Kernel random number generator
+//!
+//! A ChaCha20-based CSPRNG seeded from the CPU's hardware TRNG (RDSEED,
+//! falling back to RDRAND, and finally to TSC jitter when neither exists).
+//! Output uses fast key erasure: the first 32 bytes of every keystream
+//! batch replace the key, so a later state compromise cannot recover
+//! earlier output. The generator reseeds from hardware periodically and
+//! whenever a process is forked.
+
+use core::arch::x86_64::__cpuid;
+use spin::Mutex;
+
+use crate::crypto::chacha20::{ChaCha20, KEY_SIZE, NONCE_SIZE};
+use crate::crypto::{secure_clear, sha256};
+use crate::info;
+
+/// Reseed from hardware after this many output bytes
+const RESEED_INTERVAL: u64 = 1024 * 1024;
+
+/// Largest request served from one keystream batch
+const BATCH_SIZE: usize = 256;
+
+/// Hardware entropy source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    /// RDSEED (true random, conditioned by the CPU)
+    RdSeed,
+    /// RDRAND (CPU DRBG reseeded from its TRNG)
+    RdRand,
+    /// Timestamp-counter jitter (no hardware RNG)
+    TscJitter,
+}
+
+impl EntropySource {
+    fn detect() -> Self {
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        if max_leaf >= 7 {
+            let leaf7 = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+            if leaf7.ebx & (1 << 18) != 0 {
+                return Self::RdSeed;
+            }
+        }
+        if unsafe { __cpuid(1) }.ecx & (1 << 30) != 0 {
+            return Self::RdRand;
+        }
+        Self::TscJitter
+    }
+
+    /// Source name for diagnostics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RdSeed => "RDSEED",
+            Self::RdRand => "RDRAND",
+            Self::TscJitter => "TSC jitter",
+        }
+    }
+
+    /// Read one 64-bit word of raw entropy
+    fn read_u64(&self) -> u64 {
+        match self {
+            Self::RdSeed => rdseed64().or_else(rdrand64).unwrap_or_else(tsc_jitter64),
+            Self::RdRand => rdrand64().unwrap_or_else(tsc_jitter64),
+            Self::TscJitter => tsc_jitter64(),
+        }
+    }
+}
+
+/// Execute RDSEED, retrying while the CPU reports underflow
+fn rdseed64() -> Option<u64> {
+    for _ in 0..64 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdseed {0}",
+                "setc {1}",
+                out(reg) value,
+                out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Execute RDRAND, retrying as recommended by the Intel DRNG guide
+fn rdrand64() -> Option<u64> {
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {0}",
+                "setc {1}",
+                out(reg) value,
+                out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Gather entropy from timing jitter between TSC reads
+///
+/// Weak on its own; only used when the CPU has no RNG instructions.
+fn tsc_jitter64() -> u64 {
+    let mut hasher = sha256::Sha256::new();
+    let mut prev = crate::arch::cpu::rdtsc();
+    for _ in 0..256 {
+        // Variable amount of work so successive deltas differ
+        for _ in 0..(prev & 0x3f) {
+            core::hint::spin_loop();
+        }
+        let now = crate::arch::cpu::rdtsc();
+        hasher.update(&now.wrapping_sub(prev).to_le_bytes());
+        prev = now;
+    }
+    let digest = hasher.finalize();
+    u64::from_le_bytes([
+        digest[0], digest[1], digest[2], digest[3],
+        digest[4], digest[5], digest[6], digest[7],
+    ])
+}
+
+/// ChaCha20 DRBG state
+struct Rng {
+    key: [u8; KEY_SIZE],
+    counter: u64,
+    bytes_since_reseed: u64,
+    source: EntropySource,
+    seeded: bool,
+}
+
+impl Rng {
+    const fn new() -> Self {
+        Self {
+            key: [0; KEY_SIZE],
+            counter: 0,
+            bytes_since_reseed: 0,
+            source: EntropySource::TscJitter,
+            seeded: false,
+        }
+    }
+
+    /// Mix fresh hardware entropy into the key
+    fn reseed(&mut self) {
+        let mut hasher = sha256::Sha256::new();
+        hasher.update(&self.key);
+        for _ in 0..8 {
+            hasher.update(&self.source.read_u64().to_le_bytes());
+        }
+        hasher.update(&crate::arch::cpu::rdtsc().to_le_bytes());
+        self.key = hasher.finalize();
+        self.bytes_since_reseed = 0;
+        self.seeded = true;
+    }
+
+    fn fill(&mut self, dest: &mut [u8]) {
+        if !self.seeded || self.bytes_since_reseed >= RESEED_INTERVAL {
+            self.reseed();
+        }
+
+        for chunk in dest.chunks_mut(BATCH_SIZE) {
+            let mut nonce = [0u8; NONCE_SIZE];
+            nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+            self.counter = self.counter.wrapping_add(1);
+
+            let mut stream = [0u8; KEY_SIZE + BATCH_SIZE];
+            ChaCha20::new(&self.key, &nonce).apply_keystream(&mut stream);
+
+            // Fast key erasure
+            self.key.copy_from_slice(&stream[..KEY_SIZE]);
+            chunk.copy_from_slice(&stream[KEY_SIZE..KEY_SIZE + chunk.len()]);
+            secure_clear(&mut stream);
+
+            self.bytes_since_reseed += chunk.len() as u64;
+        }
+    }
+}
+
+static RNG: Mutex<Rng> = Mutex::new(Rng::new());
+
+/// Detect the entropy source and seed the generator
+pub fn init() {
+    let mut rng = RNG.lock();
+    rng.source = EntropySource::detect();
+    rng.reseed();
+    info!("rng", "Seeded from {}", rng.source.name());
+}
+
+/// Fill `dest` with cryptographically secure random bytes
+pub fn fill_bytes(dest: &mut [u8]) {
+    RNG.lock().fill(dest);
+}
+
+/// Random 64-bit value
+pub fn next_u64() -> u64 {
+    let mut buf = [0u8; 8];
+    fill_bytes(&mut buf);
+    u64::from_le_bytes(buf)
+}
+
+/// Force a reseed from hardware entropy
+///
+/// Called when a process is forked so parent and child never observe
+/// related output, even if either later leaks generator state.
+pub fn reseed() {
+    RNG.lock().reseed();
+}
@@ -0,0 +1,258 @@
+//! RSA signature checks
+//!
+//! PKCS #1 v1.5 signatures, which certificates carry, and PSS, which a
+//! TLS 1.3 server signs its handshake with (RFC 8017). Only verification
+//! is here, and keys under `MIN_BITS` are refused.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::crypto::bignum::{self, Limbs, Modulus};
+use crate::crypto::Hash;
+
+/// Smallest modulus a key may have
+pub const MIN_BITS: usize = 2048;
+
+/// DER of the DigestInfo before a digest, by hash
+const SHA256_DIGEST_INFO: &[u8] = &[
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+const SHA384_DIGEST_INFO: &[u8] = &[
+    0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0x05,
+    0x00, 0x04, 0x30,
+];
+
+/// An RSA public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    modulus: Modulus,
+    exponent: Limbs,
+    /// Bytes in the modulus, and so in a signature
+    size: usize,
+}
+
+impl PublicKey {
+    /// A key from its modulus and exponent, big-endian as DER has them
+    pub fn new(modulus: &[u8], exponent: &[u8]) -> Option<Self> {
+        let modulus = Modulus::new(modulus)?;
+        let exponent = bignum::from_be(exponent, exponent.len().div_ceil(8))?;
+        if modulus.bits() < MIN_BITS || bignum::is_zero(&exponent) {
+            return None;
+        }
+        Some(Self { size: modulus.bits().div_ceil(8), modulus, exponent })
+    }
+
+    pub fn bits(&self) -> usize {
+        self.modulus.bits()
+    }
+
+    /// The message a signature encodes, s^e mod n, as wide as the modulus
+    fn open(&self, signature: &[u8]) -> Option<Vec<u8>> {
+        if signature.len() != self.size {
+            return None;
+        }
+        let s = self.modulus.to_mont(&self.modulus.element(signature)?);
+        let m = self.modulus.from_mont(&self.modulus.pow(&s, &self.exponent));
+        Some(bignum::to_be(&m, self.size))
+    }
+
+    /// Whether `signature` is a PKCS #1 v1.5 signature of `digest`, made
+    /// with `hash`
+    pub fn verify_pkcs1(&self, hash: Hash, digest: &[u8], signature: &[u8]) -> bool {
+        let opened = match self.open(signature) {
+            Some(opened) => opened,
+            None => return false,
+        };
+        let prefix = match hash {
+            Hash::Sha256 => SHA256_DIGEST_INFO,
+            Hash::Sha384 => SHA384_DIGEST_INFO,
+        };
+        let info_len = prefix.len() + digest.len();
+        if digest.len() != hash.size() || self.size < info_len + 11 {
+            return false;
+        }
+        // 00 01 FF .. FF 00 DigestInfo
+        let mut expected = vec![0x00, 0x01];
+        expected.resize(self.size - info_len - 1, 0xFF);
+        expected.push(0x00);
+        expected.extend_from_slice(prefix);
+        expected.extend_from_slice(digest);
+        opened == expected
+    }
+
+    /// Whether `signature` is a PSS signature of `digest`, with MGF1 and
+    /// a salt as long as the digest, all by `hash`, as TLS 1.3 requires
+    pub fn verify_pss(&self, hash: Hash, digest: &[u8], signature: &[u8]) -> bool {
+        let opened = match self.open(signature) {
+            Some(opened) => opened,
+            None => return false,
+        };
+        let em_bits = self.bits() - 1;
+        let em_len = em_bits.div_ceil(8);
+        let (h_len, salt_len) = (hash.size(), hash.size());
+        let (zeros, em) = opened.split_at(self.size - em_len);
+        if digest.len() != h_len || zeros.iter().any(|&b| b != 0)
+            || em_len < h_len + salt_len + 2 || em[em_len - 1] != 0xBC {
+            return false;
+        }
+        let (masked_db, rest) = em.split_at(em_len - h_len - 1);
+        let h = &rest[..h_len];
+        // Bits above em_bits must be clear
+        let top = 0xFFu8 >> (8 * em_len - em_bits);
+        if masked_db[0] & !top != 0 {
+            return false;
+        }
+        let mut db = mgf1(hash, h, masked_db.len());
+        for (d, m) in db.iter_mut().zip(masked_db) {
+            *d ^= m;
+        }
+        db[0] &= top;
+        // Zeros, a one, then the salt
+        let padding = em_len - h_len - salt_len - 2;
+        if db[..padding].iter().any(|&b| b != 0) || db[padding] != 0x01 {
+            return false;
+        }
+        let mut m = vec![0u8; 8];
+        m.extend_from_slice(digest);
+        m.extend_from_slice(&db[padding + 1..]);
+        hash.digest(&m) == h
+    }
+}
+
+/// MGF1 mask of `len` bytes from `seed`
+fn mgf1(hash: Hash, seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(len + hash.size());
+    let mut counter: u32 = 0;
+    while mask.len() < len {
+        let mut block = seed.to_vec();
+        block.extend_from_slice(&counter.to_be_bytes());
+        mask.extend_from_slice(&hash.digest(&block));
+        counter += 1;
+    }
+    mask.truncate(len);
+    mask
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    const MESSAGE: &[u8] = b"WebbOS checks signatures";
+    const EXPONENT: &[u8] = &[0x01, 0x00, 0x01];
+
+    /// A 2048-bit key, whose exponent is 65537
+    const MODULUS: [u8; 256] = [
+        0xac, 0xab, 0xdb, 0xea, 0x46, 0x8b, 0x85, 0x40, 0xdb, 0xf2, 0xbc, 0x1e, 0x7a, 0x5a, 0xb6, 0x2e,
+        0x87, 0x48, 0x95, 0xef, 0xe9, 0x6a, 0x0b, 0xbf, 0x1f, 0x7a, 0x07, 0x95, 0x48, 0x61, 0x6d, 0xeb,
+        0xd8, 0x8e, 0xb9, 0xb0, 0x1f, 0x38, 0xd9, 0xcd, 0x82, 0xca, 0x59, 0xe2, 0x81, 0xc2, 0xf9, 0x12,
+        0xdd, 0xce, 0xd1, 0x04, 0xab, 0x1a, 0x89, 0xb4, 0x6a, 0xc4, 0x93, 0x3f, 0x32, 0x6d, 0x26, 0xc5,
+        0x7d, 0x61, 0xf4, 0x47, 0x40, 0xb2, 0xbf, 0xfc, 0x7c, 0xed, 0x6c, 0x43, 0x7c, 0xb1, 0x9c, 0x61,
+        0x7e, 0xb7, 0x38, 0x60, 0xf8, 0x4c, 0x91, 0x7f, 0x9e, 0x01, 0x8a, 0x55, 0xc6, 0xd8, 0x0f, 0x33,
+        0x31, 0xb6, 0x5d, 0x39, 0x9b, 0x71, 0xa5, 0x77, 0xa7, 0x32, 0xf4, 0xec, 0x40, 0xd5, 0x27, 0xe0,
+        0x2f, 0x20, 0x64, 0x70, 0xe1, 0x64, 0x5d, 0x9b, 0xe8, 0xa6, 0x89, 0x49, 0x38, 0x34, 0x45, 0xe7,
+        0x15, 0x0c, 0x9a, 0x61, 0x7a, 0x93, 0xbd, 0x5b, 0xcf, 0x16, 0x13, 0x31, 0x92, 0xfb, 0xe1, 0x1b,
+        0x94, 0x1b, 0x38, 0xd8, 0x98, 0x2f, 0x39, 0x64, 0x26, 0x4b, 0xcf, 0xeb, 0x39, 0x69, 0xa7, 0xb1,
+        0x51, 0xfc, 0xa6, 0xa7, 0xf3, 0xda, 0x70, 0x6c, 0xf6, 0xd1, 0x8a, 0xa3, 0xbc, 0xeb, 0x33, 0xc2,
+        0xd7, 0xfc, 0xf9, 0x3f, 0x27, 0x7f, 0x93, 0x4b, 0xb4, 0xd8, 0x6b, 0x68, 0x9c, 0xf5, 0x46, 0x9f,
+        0x99, 0x15, 0x4e, 0x7a, 0x15, 0xe1, 0x9d, 0x1a, 0x08, 0x1d, 0xd5, 0xcd, 0x74, 0x2c, 0x1a, 0x9d,
+        0x8f, 0xea, 0x15, 0x15, 0x21, 0x39, 0x68, 0x96, 0xc1, 0xb8, 0x8f, 0x27, 0x03, 0xf6, 0x38, 0xf5,
+        0x5a, 0x34, 0x15, 0x81, 0xcb, 0x31, 0xd3, 0x43, 0x6a, 0x83, 0xfe, 0x1a, 0x95, 0xdc, 0x62, 0xa5,
+        0x7d, 0xc2, 0xe4, 0xe8, 0x86, 0xd0, 0x1b, 0xde, 0x49, 0x93, 0x69, 0x9b, 0x14, 0xa1, 0xeb, 0xf9,
+    ];
+    /// Its PKCS #1 v1.5 signature of MESSAGE with SHA-256
+    const PKCS1_SHA256: [u8; 256] = [
+        0x67, 0xcf, 0x70, 0xfc, 0xa5, 0x65, 0x47, 0xe9, 0x61, 0x6e, 0xe4, 0x12, 0x76, 0x09, 0x74, 0xd8,
+        0x89, 0xee, 0xee, 0x5c, 0x00, 0xc4, 0x12, 0x94, 0x68, 0x09, 0x75, 0x81, 0xe1, 0x96, 0x88, 0x8a,
+        0x3a, 0xba, 0x2e, 0x60, 0x55, 0xfc, 0x03, 0x5d, 0xdd, 0xb8, 0x66, 0x63, 0xab, 0x0e, 0x46, 0xaa,
+        0xa2, 0xd1, 0xdb, 0x24, 0x6a, 0x9e, 0x67, 0x5a, 0x06, 0xee, 0x03, 0xfe, 0x7a, 0x1a, 0xf7, 0x7a,
+        0xb6, 0xda, 0xf2, 0x1d, 0x77, 0xf7, 0x89, 0xeb, 0x93, 0x8c, 0x6f, 0x64, 0x35, 0x4b, 0x4f, 0x50,
+        0x13, 0x14, 0xab, 0x88, 0x78, 0xe0, 0xe7, 0xdb, 0xab, 0xa9, 0x43, 0x7b, 0x52, 0x81, 0xf9, 0xc2,
+        0x48, 0xdb, 0xe4, 0xb1, 0x1a, 0x8c, 0x12, 0xe0, 0x3d, 0xcf, 0xe8, 0x8a, 0x23, 0x09, 0xf6, 0xd1,
+        0xce, 0x6a, 0xc4, 0xe2, 0x64, 0x5a, 0xdc, 0xd8, 0xb5, 0x93, 0x4a, 0xab, 0x05, 0x16, 0xe0, 0x2b,
+        0x4d, 0xa8, 0x72, 0x96, 0x64, 0x82, 0xa9, 0x5f, 0x14, 0xb7, 0xbf, 0xb3, 0x10, 0xff, 0x8a, 0xa7,
+        0xbd, 0xd2, 0xb3, 0x29, 0xf0, 0x0a, 0x7d, 0x72, 0x38, 0x92, 0x17, 0x0a, 0x71, 0x10, 0x93, 0x4d,
+        0x34, 0xa8, 0xf1, 0x3f, 0x4a, 0x27, 0x14, 0xec, 0x31, 0xce, 0xac, 0xd6, 0x4e, 0xee, 0x54, 0x50,
+        0xe7, 0x67, 0xef, 0x0a, 0x1c, 0x4b, 0x7f, 0xb7, 0xcb, 0x8f, 0xce, 0x05, 0xe4, 0xcd, 0x9f, 0x27,
+        0xa3, 0x88, 0xbb, 0x30, 0xb0, 0x0c, 0x0d, 0xeb, 0x60, 0x78, 0x10, 0xa2, 0x62, 0xb5, 0x12, 0x4c,
+        0xdc, 0x1e, 0xbf, 0xaa, 0xbf, 0xc8, 0x5b, 0x54, 0x34, 0xab, 0x38, 0x65, 0xc9, 0xba, 0x77, 0x3f,
+        0x46, 0x3c, 0x74, 0x39, 0x8d, 0xb3, 0xa6, 0x73, 0xca, 0x79, 0x7d, 0x8d, 0x32, 0xa7, 0x54, 0xc2,
+        0xe1, 0x50, 0x68, 0xfd, 0xb1, 0xb8, 0x59, 0x9d, 0xa2, 0xc0, 0x1d, 0x24, 0x13, 0x5e, 0xd9, 0x30,
+    ];
+    /// With SHA-384
+    const PKCS1_SHA384: [u8; 256] = [
+        0x09, 0x14, 0x6d, 0x2d, 0x29, 0x1c, 0x4b, 0x0b, 0xe6, 0x63, 0xb8, 0x34, 0x3d, 0xa1, 0x55, 0x42,
+        0xe7, 0xa6, 0x6a, 0xd2, 0x4c, 0xbd, 0x79, 0xe2, 0x16, 0xf3, 0xb8, 0x59, 0x1b, 0x40, 0xa6, 0x5b,
+        0x5d, 0xf6, 0x37, 0xa7, 0x6e, 0xe6, 0x0e, 0xf4, 0xcb, 0xb1, 0x3a, 0x3d, 0x74, 0x5f, 0xbb, 0x2b,
+        0x54, 0x78, 0x38, 0x91, 0xa2, 0x5a, 0x11, 0xd4, 0xae, 0x94, 0x3a, 0x56, 0x4a, 0x14, 0x57, 0x55,
+        0x48, 0xcd, 0x70, 0x8c, 0x3b, 0xb8, 0x63, 0xc8, 0x60, 0x9b, 0xff, 0x1f, 0x4a, 0xee, 0xfc, 0x89,
+        0xd0, 0x35, 0xc9, 0x03, 0x0a, 0x9b, 0xf9, 0xe6, 0xff, 0xfb, 0x8e, 0x89, 0x31, 0xaa, 0xe9, 0x22,
+        0x7b, 0xd4, 0x8d, 0xd9, 0xc2, 0x1b, 0x89, 0x6d, 0x10, 0x69, 0x1c, 0x8f, 0x20, 0x63, 0xf9, 0x58,
+        0xb4, 0x4e, 0x10, 0xf6, 0x86, 0x1d, 0x03, 0x72, 0x71, 0x4f, 0x5e, 0x08, 0xf9, 0x18, 0x9f, 0x41,
+        0x41, 0x6c, 0x9c, 0x65, 0xe3, 0x07, 0x3b, 0x1d, 0x37, 0x95, 0xba, 0x68, 0x8d, 0x5c, 0x8e, 0xac,
+        0x07, 0x32, 0xd3, 0xcb, 0xad, 0xa5, 0x0d, 0x05, 0x3c, 0xec, 0xb6, 0xfd, 0x32, 0x58, 0xee, 0x54,
+        0xb4, 0xd7, 0x47, 0x2f, 0xd9, 0x81, 0xe7, 0xe2, 0x21, 0x2c, 0x23, 0xaf, 0xed, 0x0f, 0xbd, 0x7b,
+        0xe3, 0xbf, 0x6f, 0x9a, 0x08, 0xed, 0x87, 0xae, 0x89, 0x60, 0x55, 0x65, 0x68, 0xe6, 0xe8, 0xaa,
+        0xa5, 0x9a, 0x3f, 0xf3, 0x2f, 0x59, 0x92, 0xde, 0xae, 0x36, 0x64, 0xac, 0x4c, 0x13, 0x06, 0xde,
+        0x15, 0xc6, 0xa5, 0x78, 0x98, 0x3a, 0xe6, 0x4c, 0x04, 0x14, 0x7e, 0x0b, 0x2a, 0x61, 0x5f, 0xee,
+        0xde, 0xf5, 0xe6, 0xa5, 0x85, 0x19, 0x23, 0xf1, 0xaa, 0xc3, 0x4d, 0xd8, 0xb1, 0xe1, 0xb0, 0xfc,
+        0x80, 0x36, 0xea, 0x28, 0x59, 0x85, 0xf9, 0xdc, 0xbc, 0xa9, 0x2e, 0x7c, 0x87, 0xde, 0x7e, 0xc4,
+    ];
+    /// Its PSS signature, with SHA-256 and a 32-byte salt
+    const PSS_SHA256: [u8; 256] = [
+        0x13, 0x0a, 0xa6, 0x27, 0xed, 0x59, 0xa0, 0xba, 0x91, 0xf9, 0xc4, 0x67, 0x65, 0x05, 0xd9, 0xf9,
+        0x77, 0x3e, 0x87, 0xb4, 0x72, 0xb8, 0xb5, 0x25, 0x49, 0x52, 0xd7, 0xcd, 0x34, 0x47, 0xb3, 0x63,
+        0xc3, 0xf0, 0xaa, 0x2c, 0x07, 0x59, 0x53, 0x0f, 0x2d, 0x15, 0x73, 0xc3, 0x21, 0x68, 0x08, 0xe0,
+        0x9e, 0xc8, 0xb0, 0xcf, 0xf3, 0x1d, 0xfe, 0xdd, 0xf5, 0x4d, 0x28, 0x1f, 0xcd, 0xc5, 0x85, 0xbb,
+        0xf6, 0xda, 0xab, 0x29, 0xd1, 0x3d, 0xba, 0x08, 0xf0, 0xc4, 0xc2, 0x4b, 0x47, 0x5a, 0x43, 0x90,
+        0x9a, 0x71, 0xfe, 0x0a, 0x66, 0xae, 0x55, 0xd1, 0xd9, 0x0d, 0x35, 0xa8, 0xb6, 0x9e, 0x2e, 0xd2,
+        0x05, 0x2f, 0xf0, 0xfc, 0x3f, 0x48, 0x1a, 0x10, 0xeb, 0x2c, 0x72, 0x40, 0xa0, 0xc2, 0xaf, 0x94,
+        0x80, 0x5b, 0xce, 0x4c, 0x94, 0x2c, 0x91, 0x8b, 0xa5, 0x1d, 0x4a, 0x5d, 0x88, 0xdf, 0xba, 0x8b,
+        0x2e, 0x26, 0x2b, 0xdc, 0x7f, 0x48, 0x29, 0x2f, 0x8b, 0xd9, 0x06, 0x13, 0xa2, 0x97, 0xa6, 0xd0,
+        0x7a, 0xeb, 0x49, 0xd6, 0xf2, 0x42, 0x15, 0x7b, 0xc9, 0x45, 0xba, 0xc1, 0x69, 0xe3, 0xc5, 0xe5,
+        0x7e, 0x63, 0xfc, 0x6e, 0x30, 0xf6, 0xea, 0x4d, 0xf9, 0x9a, 0xd5, 0xa1, 0xc9, 0x28, 0x08, 0xa5,
+        0xb2, 0x4c, 0x57, 0xfa, 0xe3, 0xf6, 0x8a, 0x6c, 0x36, 0x17, 0x53, 0x36, 0x2b, 0xd2, 0xaa, 0xee,
+        0x9b, 0xad, 0x15, 0x98, 0x2d, 0xdc, 0x61, 0xdd, 0xd0, 0x93, 0x6a, 0x3b, 0xe9, 0x52, 0x09, 0xd6,
+        0x69, 0x78, 0xda, 0xa8, 0x28, 0x2d, 0x32, 0x94, 0xbb, 0x17, 0xba, 0x65, 0x48, 0x60, 0x80, 0xf5,
+        0xfc, 0x11, 0xf6, 0xd9, 0xfc, 0x0d, 0x42, 0x99, 0x6e, 0x9c, 0x46, 0x79, 0xd8, 0xc7, 0x61, 0xbf,
+        0x8a, 0x51, 0x12, 0x85, 0xfd, 0xba, 0x1b, 0x60, 0xeb, 0x75, 0x14, 0x6a, 0xd9, 0x47, 0x11, 0x46,
+    ];
+
+    #[kernel_test]
+    fn pkcs1_signatures_verify() -> Result<(), String> {
+        let key = PublicKey::new(&MODULUS, EXPONENT).ok_or("no key")?;
+        check_eq!(key.bits(), 2048);
+        let sha256 = Hash::Sha256.digest(MESSAGE);
+        let sha384 = Hash::Sha384.digest(MESSAGE);
+        check!(key.verify_pkcs1(Hash::Sha256, &sha256, &PKCS1_SHA256));
+        check!(key.verify_pkcs1(Hash::Sha384, &sha384, &PKCS1_SHA384));
+        check!(!key.verify_pkcs1(Hash::Sha384, &sha384, &PKCS1_SHA256));
+        check!(!key.verify_pkcs1(Hash::Sha256, &Hash::Sha256.digest(b"WebbOS"), &PKCS1_SHA256));
+        check!(!key.verify_pkcs1(Hash::Sha256, &sha256, &PSS_SHA256));
+        check!(!key.verify_pkcs1(Hash::Sha256, &sha256, &PKCS1_SHA256[1..]));
+        check!(!key.verify_pkcs1(Hash::Sha256, &sha256, &MODULUS));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn pss_signatures_verify() -> Result<(), String> {
+        let key = PublicKey::new(&MODULUS, EXPONENT).ok_or("no key")?;
+        let digest = Hash::Sha256.digest(MESSAGE);
+        check!(key.verify_pss(Hash::Sha256, &digest, &PSS_SHA256));
+        check!(!key.verify_pss(Hash::Sha256, &digest, &PKCS1_SHA256));
+        check!(!key.verify_pss(Hash::Sha256, &Hash::Sha256.digest(b"WebbOS"), &PSS_SHA256));
+        let mut tampered = PSS_SHA256;
+        tampered[100] ^= 0x40;
+        check!(!key.verify_pss(Hash::Sha256, &digest, &tampered));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn short_keys_are_refused() -> Result<(), String> {
+        check!(PublicKey::new(&MODULUS[128..], EXPONENT).is_none());
+        check!(PublicKey::new(&MODULUS, &[0]).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! SHA-1 Hash Function
+//!
+//! Implementation of the SHA-1 hash function (FIPS 180-4). SHA-1 is no
+//! longer collision resistant; it is here for HMAC-SHA-1, which one-time
+//! password apps still use by default.
+
+/// SHA-1 digest size in bytes
+pub const DIGEST_SIZE: usize = 20;
+
+/// SHA-1 block size in bytes
+pub const BLOCK_SIZE: usize = 64;
+
+/// SHA-1 state
+pub struct Sha1 {
+    state: [u32; 5],
+    buffer: [u8; BLOCK_SIZE],
+    buffer_len: usize,
+    total_len: u64,
+}
+
+/// Initial hash values
+const H: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+impl Sha1 {
+    /// Create new SHA-1 hasher
+    pub fn new() -> Self {
+        Self {
+            state: H,
+            buffer: [0; BLOCK_SIZE],
+            buffer_len: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Update hash with data
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        while !data.is_empty() {
+            let to_copy = (BLOCK_SIZE - self.buffer_len).min(data.len());
+            self.buffer[self.buffer_len..self.buffer_len + to_copy].copy_from_slice(&data[..to_copy]);
+            self.buffer_len += to_copy;
+            data = &data[to_copy..];
+
+            if self.buffer_len == BLOCK_SIZE {
+                let block = self.buffer;
+                self.process_block(&block);
+                self.buffer_len = 0;
+            }
+        }
+    }
+
+    /// Finalize and return digest
+    pub fn finalize(mut self) -> [u8; DIGEST_SIZE] {
+        let bit_len = self.total_len * 8;
+
+        // Append 0x80
+        self.buffer[self.buffer_len] = 0x80;
+        self.buffer_len += 1;
+
+        // If there's not enough space for the length, process and reset
+        if self.buffer_len > BLOCK_SIZE - 8 {
+            self.buffer[self.buffer_len..].fill(0);
+            let block = self.buffer;
+            self.process_block(&block);
+            self.buffer.fill(0);
+        } else {
+            self.buffer[self.buffer_len..BLOCK_SIZE - 8].fill(0);
+        }
+
+        // Append length (big-endian)
+        self.buffer[BLOCK_SIZE - 8..].copy_from_slice(&bit_len.to_be_bytes());
+        let block = self.buffer;
+        self.process_block(&block);
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        for (i, &word) in self.state.iter().enumerate() {
+            digest[i * 4..(i + 1) * 4].copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Process a single 64-byte block
+    fn process_block(&mut self, block: &[u8; BLOCK_SIZE]) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+/// Compute SHA-1 hash of data
+pub fn hash(data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// HMAC-SHA-1
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut k = [0u8; BLOCK_SIZE];
+    if key.len() <= BLOCK_SIZE {
+        k[..key.len()].copy_from_slice(key);
+    } else {
+        k[..DIGEST_SIZE].copy_from_slice(&hash(key));
+    }
+
+    let mut inner = k;
+    let mut outer = k;
+    for i in 0..BLOCK_SIZE {
+        inner[i] ^= 0x36;
+        outer[i] ^= 0x5c;
+    }
+
+    let mut inner_hasher = Sha1::new();
+    inner_hasher.update(&inner);
+    inner_hasher.update(data);
+    let inner_hash = inner_hasher.finalize();
+
+    let mut outer_hasher = Sha1::new();
+    outer_hasher.update(&outer);
+    outer_hasher.update(&inner_hash);
+    outer_hasher.finalize()
+}
+
+/// Initialize SHA-1 module
+pub fn init() {
+    let result = hash(b"abc");
+    let expected = [
+        0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e,
+        0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+    ];
+
+    if result == expected {
+        crate::info!("sha1", "Self-test passed");
+    } else {
+        crate::error!("sha1", "Self-test FAILED");
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::check_eq;
+    use crate::testing::kernel_test;
+    use alloc::string::String;
+
+    /// FIPS 180 examples: empty, one block, two blocks
+    #[kernel_test]
+    fn fips180_vectors() -> Result<(), String> {
+        check_eq!(hash(b""), [
+            0xda, 0x39, 0xa3, 0xee, 0x5e, 0x6b, 0x4b, 0x0d, 0x32, 0x55,
+            0xbf, 0xef, 0x95, 0x60, 0x18, 0x90, 0xaf, 0xd8, 0x07, 0x09,
+        ]);
+        check_eq!(hash(b"abc"), [
+            0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e,
+            0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+        ]);
+        check_eq!(hash(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), [
+            0x84, 0x98, 0x3e, 0x44, 0x1c, 0x3b, 0xd2, 0x6e, 0xba, 0xae,
+            0x4a, 0xa1, 0xf9, 0x51, 0x29, 0xe5, 0xe5, 0x46, 0x70, 0xf1,
+        ]);
+        Ok(())
+    }
+
+    /// FIPS 180 example: a million 'a's, fed in uneven pieces
+    #[kernel_test]
+    fn fips180_million_a() -> Result<(), String> {
+        let piece = [b'a'; 1000];
+        let mut hasher = Sha1::new();
+        hasher.update(&piece[..1]);
+        for _ in 0..999 {
+            hasher.update(&piece);
+        }
+        hasher.update(&piece[1..]);
+        check_eq!(hasher.finalize(), [
+            0x34, 0xaa, 0x97, 0x3c, 0xd4, 0xc4, 0xda, 0xa4, 0xf6, 0x1e,
+            0xeb, 0x2b, 0xdb, 0xad, 0x27, 0x31, 0x65, 0x34, 0x01, 0x6f,
+        ]);
+        Ok(())
+    }
+
+    /// RFC 2202, test case 2
+    #[kernel_test]
+    fn hmac_rfc2202_case_2() -> Result<(), String> {
+        check_eq!(hmac(b"Jefe", b"what do ya want for nothing?"), [
+            0xef, 0xfc, 0xdf, 0x6a, 0xe5, 0xeb, 0x2f, 0xa2, 0xd2, 0x74,
+            0x16, 0xd5, 0xf1, 0x84, 0xdf, 0x9c, 0x25, 0x9a, 0x7c, 0x79,
+        ]);
+        Ok(())
+    }
+}
@@ -2,8 +2,12 @@
 //!
 //! Implementation of X25519 key exchange (RFC 7748).
 
-/// Field element (256-bit integer)
-type Fe = [u32; 10];
+use crate::crypto::ct;
+
+/// Field element modulo 2^255 - 19, five 51-bit limbs
+type Fe = [u64; 5];
+
+const MASK: u64 = (1 << 51) - 1;
 
 /// X25519 private key
 pub type PrivateKey = [u8; 32];
@@ -22,89 +26,75 @@ const BASE_POINT: [u8; 32] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-/// Curve constant d = -121665/121666 mod p
-const D: Fe = [0x135978a3, 0x75eb4dca, 0x4141d470, 0x4d4141d4,
-               0x2d4d4141, 0x4175eb4d, 0xd4ca1359, 0x41d4ca13,
-               0x78a341d4, 0x6];
+/// (A - 2) / 4 for the curve's A = 486662
+const A24: u64 = 121665;
 
-/// 2^25.5
-const SQRT_M1: Fe = [0x0ea3baec, 0x7819c4c9, 0xdfb7a46d, 0x24650942,
-                     0xa2ab5ce1, 0xac54a91, 0x696b3da8, 0xed97a68d,
-                     0xaefbea7a, 0x1d];
+/// p - 2, little-endian, the exponent that inverts
+const P_MINUS_2: [u8; 32] = [
+    0xeb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
+];
 
-/// Reduce field element modulo 2^255 - 19
-fn fe_reduce(a: &mut Fe) {
-    let mut carry = 0i64;
-    
-    // Reduce each limb
-    for i in 0..9 {
-        carry = (a[i] as i64) + (carry >> 25);
-        a[i] = (carry & 0x1ffffff) as u32;
-    }
-    
-    // Handle overflow
-    carry = (a[9] as i64) + (carry >> 25);
-    a[9] = (carry & 0x1ffffff) as u32;
-    
-    // Multiply overflow by 19 and add back
-    let mut carry2 = carry >> 25;
-    carry2 *= 19;
-    
-    for i in 0..10 {
-        carry2 = (a[i] as i64) + carry2;
-        a[i] = (carry2 & 0x1ffffff) as u32;
-        carry2 >>= 25;
+/// Bring each limb back to about 51 bits
+fn fe_carry(mut h: Fe) -> Fe {
+    for i in 0..4 {
+        h[i + 1] += h[i] >> 51;
+        h[i] &= MASK;
     }
+    h[0] += 19 * (h[4] >> 51);
+    h[4] &= MASK;
+    h
 }
 
 /// Add two field elements
 fn fe_add(a: &Fe, b: &Fe) -> Fe {
-    let mut result = [0u32; 10];
-    for i in 0..10 {
-        result[i] = a[i] + b[i];
+    let mut result = *a;
+    for i in 0..5 {
+        result[i] += b[i];
     }
-    result
+    fe_carry(result)
 }
 
 /// Subtract two field elements
 fn fe_sub(a: &Fe, b: &Fe) -> Fe {
-    let mut result = [0u32; 10];
-    for i in 0..10 {
-        result[i] = a[i].wrapping_sub(b[i]);
+    // Add 2p first, so no limb goes below zero
+    const TWO_P: Fe = [0xFFFFFFFFFFFDA, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE];
+    let mut result = [0u64; 5];
+    for i in 0..5 {
+        result[i] = a[i] + TWO_P[i] - b[i];
     }
-    result
+    fe_carry(result)
 }
 
 /// Multiply two field elements
 fn fe_mul(a: &Fe, b: &Fe) -> Fe {
-    let mut t = [0u64; 19];
-    
-    // Schoolbook multiplication
-    for i in 0..10 {
-        for j in 0..10 {
-            t[i + j] += (a[i] as u64) * (b[j] as u64);
-        }
-    }
-    
-    // Reduce
-    let mut result = [0u32; 10];
-    let mut carry = 0u64;
-    
-    for i in 0..10 {
+    let a = a.map(|limb| limb as u128);
+    let b = b.map(|limb| limb as u128);
+    // Limbs past the fifth come back around times 19, as 2^255 = 19
+    let b19 = b.map(|limb| limb * 19);
+    let t = [
+        a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+        a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+        a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+        a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+        a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+    ];
+    let mut result = [0u64; 5];
+    let mut carry = 0u128;
+    for i in 0..5 {
         let sum = t[i] + carry;
-        result[i] = (sum & 0x1ffffff) as u32;
-        carry = sum >> 25;
-    }
-    
-    // Handle overflow with multiplication by 19
-    carry *= 19;
-    for i in 0..10 {
-        let sum = (result[i] as u64) + carry;
-        result[i] = (sum & 0x1ffffff) as u32;
-        carry = sum >> 25;
+        result[i] = (sum as u64) & MASK;
+        carry = sum >> 51;
     }
-    
-    result
+    result[0] += (carry * 19) as u64;
+    fe_carry(result)
+}
+
+/// Multiply a field element by a small constant
+fn fe_mul_small(a: &Fe, n: u64) -> Fe {
+    fe_mul(a, &[n, 0, 0, 0, 0])
 }
 
 /// Square a field element
@@ -112,147 +102,65 @@ fn fe_sq(a: &Fe) -> Fe {
     fe_mul(a, a)
 }
 
-/// Compute a^n
-fn fe_pow(a: &Fe, n: &[u8]) -> Fe {
-    let mut result = [0u32; 10];
-    result[0] = 1; // 1
-    let mut base = *a;
-    
-    for byte in n.iter().rev() {
-        for i in 0..8 {
-            result = fe_sq(&result);
-            if (byte >> (7 - i)) & 1 == 1 {
-                result = fe_mul(&result, &base);
-            }
-        }
-    }
-    
-    result
-}
-
-/// Compute multiplicative inverse
+/// Compute multiplicative inverse, a^(p-2); the exponent is public, so
+/// the time this takes says nothing of `a`
 fn fe_inv(a: &Fe) -> Fe {
-    // a^(p-2) = a^(2^255 - 21)
-    let mut t0 = fe_sq(a);
-    let mut t1 = fe_sq(&t0);
-    t1 = fe_sq(&t1);
-    t1 = fe_mul(a, &t1);
-    t0 = fe_mul(&t0, &t1);
-    
-    t0 = fe_sq(&t0);
-    t0 = fe_mul(&t1, &t0);
-    
-    // Continue with exponentiation
-    let mut result = fe_sq(&t0);
-    for _ in 0..4 {
-        result = fe_sq(&result);
-    }
-    result = fe_mul(&t0, &result);
-    
-    t0 = fe_sq(&result);
-    for _ in 0..9 {
-        t0 = fe_sq(&t0);
-    }
-    t0 = fe_mul(&result, &t0);
-    
-    result = fe_sq(&t0);
-    for _ in 0..19 {
-        result = fe_sq(&result);
-    }
-    result = fe_mul(&t0, &result);
-    
-    for _ in 0..50 {
+    let mut result = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
         result = fe_sq(&result);
+        if (P_MINUS_2[bit / 8] >> (bit % 8)) & 1 == 1 {
+            result = fe_mul(&result, a);
+        }
     }
-    result = fe_mul(&result, &t0);
-    
-    t0 = fe_sq(&result);
-    for _ in 0..99 {
-        t0 = fe_sq(&t0);
-    }
-    t0 = fe_mul(&result, &t0);
-    
-    for _ in 0..100 {
-        t0 = fe_sq(&t0);
-    }
-    t0 = fe_mul(&t0, &result);
-    
-    t0 = fe_sq(&t0);
-    for _ in 0..49 {
-        t0 = fe_sq(&t0);
-    }
-    fe_mul(&t0, a)
+    result
 }
 
-/// Convert bytes to field element
+/// Convert bytes to field element, ignoring the top bit as RFC 7748 asks
 fn fe_from_bytes(s: &[u8; 32]) -> Fe {
-    let mut result = [0u32; 10];
-    
-    result[0] = u32::from_le_bytes([s[0], s[1], s[2], 0]) & 0x1ffffff;
-    result[1] = u32::from_le_bytes([s[2], s[3], s[4], 0]) >> 5 & 0x1ffffff;
-    result[2] = u32::from_le_bytes([s[5], s[6], s[7], 0]) & 0x1ffffff;
-    result[3] = u32::from_le_bytes([s[7], s[8], s[9], 0]) >> 5 & 0x1ffffff;
-    result[4] = u32::from_le_bytes([s[10], s[11], s[12], 0]) & 0x1ffffff;
-    result[5] = u32::from_le_bytes([s[12], s[13], s[14], 0]) >> 5 & 0x1ffffff;
-    result[6] = u32::from_le_bytes([s[15], s[16], s[17], 0]) & 0x1ffffff;
-    result[7] = u32::from_le_bytes([s[17], s[18], s[19], 0]) >> 5 & 0x1ffffff;
-    result[8] = u32::from_le_bytes([s[20], s[21], s[22], 0]) & 0x1ffffff;
-    result[9] = u32::from_le_bytes([s[23], s[24], s[25], 0]) >> 5 & 0x1ffffff;
-    
-    result
+    let load = |at: usize| {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&s[at..at + 8]);
+        u64::from_le_bytes(word)
+    };
+    [
+        load(0) & MASK,
+        (load(6) >> 3) & MASK,
+        (load(12) >> 6) & MASK,
+        (load(19) >> 1) & MASK,
+        (load(24) >> 12) & MASK,
+    ]
 }
 
-/// Convert field element to bytes
+/// Convert field element to bytes, fully reduced
 fn fe_to_bytes(a: &Fe) -> [u8; 32] {
+    let mut t = fe_carry(fe_carry(*a));
+    // t is below 2^255 + a little; take p away if it is at least p
+    let mut q = (t[0] + 19) >> 51;
+    for limb in &t[1..] {
+        q = (limb + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK;
+    }
+    t[4] &= MASK;
+
+    let words = [t[0] | t[1] << 51, t[1] >> 13 | t[2] << 38, t[2] >> 26 | t[3] << 25, t[3] >> 39 | t[4] << 12];
     let mut result = [0u8; 32];
-    let mut t = *a;
-    fe_reduce(&mut t);
-    
-    result[0] = (t[0] & 0xff) as u8;
-    result[1] = ((t[0] >> 8) & 0xff) as u8;
-    result[2] = ((t[0] >> 16) | ((t[1] & 0x1f) << 5)) as u8;
-    result[3] = ((t[1] >> 5) & 0xff) as u8;
-    result[4] = ((t[1] >> 13) & 0xff) as u8;
-    result[5] = ((t[1] >> 21) | ((t[2] & 0x03) << 7)) as u8;
-    result[6] = ((t[2] >> 2) & 0xff) as u8;
-    result[7] = ((t[2] >> 10) & 0xff) as u8;
-    result[8] = ((t[2] >> 18) | ((t[3] & 0x7f) << 3)) as u8;
-    result[9] = ((t[3] >> 7) & 0xff) as u8;
-    result[10] = ((t[3] >> 15) & 0xff) as u8;
-    result[11] = ((t[3] >> 23) | ((t[4] & 0x0f) << 4)) as u8;
-    result[12] = ((t[4] >> 4) & 0xff) as u8;
-    result[13] = ((t[4] >> 12) & 0xff) as u8;
-    result[14] = ((t[4] >> 20) | ((t[5] & 0x01) << 6)) as u8;
-    result[15] = ((t[5] >> 1) & 0xff) as u8;
-    result[16] = ((t[5] >> 9) & 0xff) as u8;
-    result[17] = ((t[5] >> 17) | ((t[6] & 0x3f) << 2)) as u8;
-    result[18] = ((t[6] >> 6) & 0xff) as u8;
-    result[19] = ((t[6] >> 14) & 0xff) as u8;
-    result[20] = ((t[6] >> 22) | ((t[7] & 0x7f) << 1)) as u8;
-    result[21] = ((t[7] >> 7) & 0xff) as u8;
-    result[22] = ((t[7] >> 15) & 0xff) as u8;
-    result[23] = ((t[7] >> 23) | ((t[8] & 0x0f) << 5)) as u8;
-    result[24] = ((t[8] >> 4) & 0xff) as u8;
-    result[25] = ((t[8] >> 12) & 0xff) as u8;
-    result[26] = ((t[8] >> 20) | ((t[9] & 0x03) << 7)) as u8;
-    result[27] = ((t[9] >> 2) & 0xff) as u8;
-    result[28] = ((t[9] >> 10) & 0xff) as u8;
-    result[29] = ((t[9] >> 18) & 0xff) as u8;
-    result[30] = 0;
-    result[31] = 0;
-    
+    for (chunk, word) in result.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
     result
 }
 
 /// Montgomery ladder for X25519
 fn x25519_ladder(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
-    let mut x1 = fe_from_bytes(point);
-    let mut x2 = [0u32; 10];
-    x2[0] = 1; // 1
-    let mut z2 = [0u32; 10];
+    let x1 = fe_from_bytes(point);
+    let mut x2: Fe = [1, 0, 0, 0, 0];
+    let mut z2: Fe = [0; 5];
     let mut x3 = x1;
-    let mut z3 = [0u32; 10];
-    z3[0] = 1; // 1
+    let mut z3: Fe = [1, 0, 0, 0, 0];
     
     let mut swap = 0u8;
     
@@ -261,13 +169,11 @@ fn x25519_ladder(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
         swap ^= bit;
         
         // Conditional swap
-        if swap == 1 {
-            core::mem::swap(&mut x2, &mut x3);
-            core::mem::swap(&mut z2, &mut z3);
-        }
+        ct::conditional_swap_u64(swap, &mut x2, &mut x3);
+        ct::conditional_swap_u64(swap, &mut z2, &mut z3);
         swap = bit;
         
-        // Montgomery ladder step
+        // Montgomery ladder step, as RFC 7748 section 5 gives it
         let a = fe_add(&x2, &z2);
         let aa = fe_sq(&a);
         let b = fe_sub(&x2, &z2);
@@ -278,29 +184,18 @@ fn x25519_ladder(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
         let da = fe_mul(&d, &a);
         let cb = fe_mul(&c, &b);
         
-        x3 = fe_add(&da, &cb);
-        x3 = fe_sq(&x3);
-        z3 = fe_sub(&da, &cb);
-        z3 = fe_sq(&z3);
-        z3 = fe_mul(&z3, &x1);
-        
+        x3 = fe_sq(&fe_add(&da, &cb));
+        z3 = fe_mul(&x1, &fe_sq(&fe_sub(&da, &cb)));
         x2 = fe_mul(&aa, &bb);
-        let e_121665 = fe_mul(&e, &D);
-        let aa_plus_e_121665 = fe_add(&aa, &e_121665);
-        z2 = fe_mul(&e, &aa_plus_e_121665);
+        z2 = fe_mul(&e, &fe_add(&aa, &fe_mul_small(&e, A24)));
     }
     
     // Conditional swap
-    if swap == 1 {
-        core::mem::swap(&mut x2, &mut x3);
-        core::mem::swap(&mut z2, &mut z3);
-    }
+    ct::conditional_swap_u64(swap, &mut x2, &mut x3);
+    ct::conditional_swap_u64(swap, &mut z2, &mut z3);
     
     // Recover x
-    let z2_inv = fe_inv(&z2);
-    let x = fe_mul(&x2, &z2_inv);
-    
-    fe_to_bytes(&x)
+    fe_to_bytes(&fe_mul(&x2, &fe_inv(&z2)))
 }
 
 /// Clamp a private key (as per RFC 7748)
@@ -321,10 +216,7 @@ pub fn generate_keypair() -> (PrivateKey, PublicKey) {
     let mut private_key = [0u8; 32];
     
     // Generate random private key
-    // In a real implementation, use a CSPRNG
-    for (i, byte) in private_key.iter_mut().enumerate() {
-        *byte = (i * 7 + 13) as u8;
-    }
+    crate::crypto::rng::fill_bytes(&mut private_key);
     
     let public_key = public_key_from_private(&mut private_key);
     (private_key, public_key)
@@ -339,5 +231,42 @@ pub fn shared_secret(private_key: &PrivateKey, public_key: &PublicKey) -> Shared
 
 /// Initialize X25519 module
 pub fn init() {
-    crate::println!("[x25519] X25519 initialized");
+    crate::info!("x25519", "X25519 initialized");
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    /// RFC 7748, section 6.1
+    #[kernel_test]
+    fn rfc7748_key_agreement() -> Result<(), String> {
+        let mut alice = [
+            0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2, 0x66, 0x45,
+            0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9, 0x2c, 0x2a,
+        ];
+        let mut bob = [
+            0x5d, 0xab, 0x08, 0x7e, 0x62, 0x4a, 0x8a, 0x4b, 0x79, 0xe1, 0x7f, 0x8b, 0x83, 0x80, 0x0e, 0xe6,
+            0x6f, 0x3b, 0xb1, 0x29, 0x26, 0x18, 0xb6, 0xfd, 0x1c, 0x2f, 0x8b, 0x27, 0xff, 0x88, 0xe0, 0xeb,
+        ];
+        let alice_public = public_key_from_private(&mut alice);
+        let bob_public = public_key_from_private(&mut bob);
+        check_eq!(alice_public, [
+            0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e, 0xf7, 0x5a,
+            0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e, 0xaa, 0x9b, 0x4e, 0x6a,
+        ]);
+        check_eq!(bob_public, [
+            0xde, 0x9e, 0xdb, 0x7d, 0x7b, 0x7d, 0xc1, 0xb4, 0xd3, 0x5b, 0x61, 0xc2, 0xec, 0xe4, 0x35, 0x37,
+            0x3f, 0x83, 0x43, 0xc8, 0x5b, 0x78, 0x67, 0x4d, 0xad, 0xfc, 0x7e, 0x14, 0x6f, 0x88, 0x2b, 0x4f,
+        ]);
+        let shared = [
+            0x4a, 0x5d, 0x9d, 0x5b, 0xa4, 0xce, 0x2d, 0xe1, 0x72, 0x8e, 0x3b, 0xf4, 0x80, 0x35, 0x0f, 0x25,
+            0xe0, 0x7e, 0x21, 0xc9, 0x47, 0xd1, 0x9e, 0x33, 0x76, 0xf0, 0x9b, 0x3c, 0x1e, 0x16, 0x17, 0x42,
+        ];
+        check_eq!(shared_secret(&alice, &bob_public), shared);
+        check_eq!(shared_secret(&bob, &alice_public), shared);
+        Ok(())
+    }
 }
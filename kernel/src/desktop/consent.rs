@@ -0,0 +1,134 @@
+//! Permission prompt
+//!
+//! The first time an installed app uses a capability its manifest
+//! declares, the desktop asks the user whether to allow it. The prompt is
+//! modal like the file chooser and is centred on the primary display.
+//! Allow carries out the request that needed the capability; Deny, Esc
+//! or N refuses it. The answer is kept, so the app is not asked again.
+//! Enter does nothing, so a key meant for the app cannot allow it.
+
+use alloc::format;
+use alloc::string::String;
+
+use super::ipc::Request;
+use super::paint::{self, draw_text, Theme};
+use super::permissions::Capability;
+use super::WindowId;
+use crate::drivers::vesa::colors;
+use crate::graphics::compositor::{Compositor, Rect};
+use crate::graphics::font;
+use crate::graphics::raster;
+
+const PROMPT_WIDTH: u32 = 400;
+const PROMPT_HEIGHT: u32 = 160;
+const PADDING: i32 = 12;
+const BUTTON_WIDTH: u32 = 80;
+const BUTTON_HEIGHT: u32 = 28;
+
+/// An open permission prompt
+#[derive(Debug, Clone)]
+pub struct ConsentPrompt {
+    /// Window whose app asked and gets the reply
+    pub owner: WindowId,
+    /// Name of the app, which the answer is kept under
+    pub app: String,
+    title: String,
+    pub capability: Capability,
+    /// What to carry out if the user allows it
+    pub request: Request,
+    /// Area the prompt is centred in
+    area: Rect,
+}
+
+/// Where the prompt's parts are on screen
+struct Layout {
+    frame: Rect,
+    deny: Rect,
+    allow: Rect,
+}
+
+impl ConsentPrompt {
+    pub fn new(owner: WindowId, app: &str, title: &str, capability: Capability, request: Request, area: Rect) -> Self {
+        Self {
+            owner,
+            app: String::from(app),
+            title: String::from(title),
+            capability,
+            request,
+            area,
+        }
+    }
+
+    /// Area covered on screen, shadow included
+    pub fn extent(&self) -> Rect {
+        paint::window_extent(self.layout().frame)
+    }
+
+    fn layout(&self) -> Layout {
+        let w = PROMPT_WIDTH.min(self.area.w);
+        let h = PROMPT_HEIGHT.min(self.area.h);
+        let frame = Rect::new(
+            self.area.x + (self.area.w as i32 - w as i32) / 2,
+            self.area.y + (self.area.h as i32 - h as i32) / 2,
+            w,
+            h,
+        );
+        let button_y = frame.bottom() - PADDING - BUTTON_HEIGHT as i32;
+        let allow = Rect::new(frame.right() - PADDING - BUTTON_WIDTH as i32, button_y, BUTTON_WIDTH, BUTTON_HEIGHT);
+        let deny = Rect::new(allow.x - 8 - BUTTON_WIDTH as i32, button_y, BUTTON_WIDTH, BUTTON_HEIGHT);
+        Layout { frame, deny, allow }
+    }
+
+    /// Key pressed while the prompt is up; the user's answer once given
+    pub fn handle_key(&self, keycode: u16, ch: char) -> Option<bool> {
+        match (keycode, ch.to_ascii_lowercase()) {
+            (0x01, _) | (_, 'n') => Some(false),
+            (_, 'y') => Some(true),
+            _ => None,
+        }
+    }
+
+    /// Left button pressed; the user's answer if a button was clicked
+    pub fn pointer_press(&self, x: i32, y: i32) -> Option<bool> {
+        let layout = self.layout();
+        if layout.allow.contains(x, y) {
+            Some(true)
+        } else if layout.deny.contains(x, y) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Draw the prompt
+pub fn paint(c: &mut Compositor, prompt: &ConsentPrompt, theme: &Theme) {
+    let l = prompt.layout();
+    let r = l.frame;
+    let (cell_w, cell_h) = font::cell_size();
+    let radius = paint::CORNER_RADIUS;
+
+    raster::fill_rounded_rect(c, r.x + paint::SHADOW_OFFSET, r.y + paint::SHADOW_OFFSET, r.w, r.h, radius, paint::SHADOW);
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, radius, theme.title_active);
+    let bar_h = paint::TITLE_BAR_HEIGHT.min(r.h);
+    raster::fill_rounded_rect(c, r.x, r.y + bar_h as i32, r.w, r.h - bar_h, radius, theme.window_body);
+    c.fill_rect(r.x, r.y + bar_h as i32, r.w, (r.h - bar_h).min(radius), theme.window_body);
+    draw_text(c, "Permission", r.x + radius as i32, r.y + (bar_h as i32 - cell_h as i32) / 2, r.w, colors::WHITE);
+
+    let text_w = r.w.saturating_sub(2 * PADDING as u32);
+    let text_y = r.y + bar_h as i32 + PADDING;
+    let question = format!("Allow {} to {}?", prompt.title, prompt.capability.describe());
+    draw_text(c, &question, r.x + PADDING, text_y, text_w, theme.content_text);
+    let note = "You will not be asked again.";
+    draw_text(c, note, r.x + PADDING, text_y + cell_h as i32 + 8, text_w, theme.content_text);
+
+    let label_y = l.allow.y + (BUTTON_HEIGHT as i32 - cell_h as i32) / 2;
+    for (b, label, fill, text) in [
+        (l.deny, "Deny", theme.field, theme.content_text),
+        (l.allow, "Allow", theme.title_active, colors::WHITE),
+    ] {
+        raster::fill_rounded_rect(c, b.x, b.y, b.w, b.h, 6, fill);
+        let label_x = b.x + (b.w as i32 - (label.len() as u32 * cell_w) as i32) / 2;
+        draw_text(c, label, label_x, label_y, b.w, text);
+    }
+}
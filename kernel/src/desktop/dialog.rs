@@ -0,0 +1,406 @@
+//! File chooser dialog
+//!
+//! Apps ask for a file with `dialog_open` and `dialog_save`. The desktop
+//! shows one chooser at a time, centred on the primary display, listing a
+//! VFS directory with folders first and files narrowed to the requested
+//! extensions. It is modal: while it is up it takes all keyboard and mouse
+//! input. When the user picks a file the dialog reads or writes it and the
+//! result goes back to the window that asked as an IPC reply.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::ipc::Response;
+use super::paint::{self, draw_text, Theme};
+use super::WindowId;
+use crate::drivers::vesa::colors;
+use crate::fs::{self, FileType};
+use crate::graphics::compositor::{Compositor, Rect};
+use crate::graphics::font;
+use crate::graphics::raster;
+
+const DIALOG_WIDTH: u32 = 480;
+const DIALOG_HEIGHT: u32 = 360;
+const PADDING: i32 = 12;
+const BUTTON_WIDTH: u32 = 80;
+const BUTTON_HEIGHT: u32 = 28;
+const FOOTER_HEIGHT: u32 = BUTTON_HEIGHT + 2 * PADDING as u32;
+
+const FOLDER_TEXT: u32 = colors::rgb(0x1E, 0x4E, 0x8C);
+const ERROR_TEXT: u32 = colors::rgb(0xC0, 0x30, 0x30);
+const FIELD_BORDER: u32 = colors::rgb(0xA0, 0xA4, 0xAC);
+
+/// What the dialog is choosing a file for
+#[derive(Debug, Clone)]
+pub enum DialogMode {
+    Open,
+    /// Save `content` under the chosen name
+    Save { content: String },
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+}
+
+/// How handling an input left the dialog
+pub enum Outcome {
+    /// Still open; `changed` says whether it needs repainting
+    Open { changed: bool },
+    /// Closed, with the reply for the window that asked
+    Closed(Response),
+}
+
+/// Where the dialog's parts are on screen
+struct Layout {
+    frame: Rect,
+    path_y: i32,
+    list: Rect,
+    row_h: u32,
+    rows: usize,
+    name_field: Option<Rect>,
+    cancel: Rect,
+    confirm: Rect,
+}
+
+/// An open file chooser
+#[derive(Debug, Clone)]
+pub struct FileDialog {
+    /// Window that asked and gets the reply
+    pub owner: WindowId,
+    mode: DialogMode,
+    /// Lower-case extensions to list, like ".txt"; empty lists every file
+    filter: Vec<String>,
+    /// Area the dialog is centred in
+    area: Rect,
+    dir: String,
+    entries: Vec<Entry>,
+    selected: usize,
+    scroll: usize,
+    /// File name typed in save mode
+    name: String,
+    error: Option<String>,
+}
+
+impl FileDialog {
+    /// Open a chooser in `start_dir`, or the root if that cannot be listed
+    ///
+    /// `filter` is a comma separated list of extensions.
+    pub fn new(owner: WindowId, mode: DialogMode, filter: &str, start_dir: &str, area: Rect) -> Self {
+        let filter = filter.split(',')
+            .map(|f| f.trim().to_ascii_lowercase())
+            .filter(|f| !f.is_empty())
+            .map(|f| if f.starts_with('.') { f } else { format!(".{}", f) })
+            .collect();
+        let mut dialog = Self {
+            owner,
+            mode,
+            filter,
+            area,
+            dir: String::new(),
+            entries: Vec::new(),
+            selected: 0,
+            scroll: 0,
+            name: String::new(),
+            error: None,
+        };
+        if !dialog.load(start_dir) {
+            dialog.load("/");
+        }
+        dialog
+    }
+
+    fn is_save(&self) -> bool {
+        matches!(self.mode, DialogMode::Save { .. })
+    }
+
+    /// Area covered on screen, shadow included
+    pub fn extent(&self) -> Rect {
+        paint::window_extent(self.layout().frame)
+    }
+
+    fn layout(&self) -> Layout {
+        let (_, cell_h) = font::cell_size();
+        let w = DIALOG_WIDTH.min(self.area.w);
+        let h = DIALOG_HEIGHT.min(self.area.h);
+        let frame = Rect::new(
+            self.area.x + (self.area.w as i32 - w as i32) / 2,
+            self.area.y + (self.area.h as i32 - h as i32) / 2,
+            w,
+            h,
+        );
+        let path_y = frame.y + paint::TITLE_BAR_HEIGHT as i32 + PADDING;
+        let list_y = path_y + cell_h as i32 + 8;
+        let footer_y = frame.bottom() - FOOTER_HEIGHT as i32;
+        let list = Rect::new(
+            frame.x + PADDING,
+            list_y,
+            frame.w.saturating_sub(2 * PADDING as u32),
+            (footer_y - list_y).max(0) as u32,
+        );
+        let row_h = cell_h + 8;
+        let button_y = footer_y + PADDING;
+        let confirm = Rect::new(frame.right() - PADDING - BUTTON_WIDTH as i32, button_y, BUTTON_WIDTH, BUTTON_HEIGHT);
+        let cancel = Rect::new(confirm.x - 8 - BUTTON_WIDTH as i32, button_y, BUTTON_WIDTH, BUTTON_HEIGHT);
+        let name_field = if self.is_save() {
+            let w = (cancel.x - 8 - (frame.x + PADDING)).max(0) as u32;
+            Some(Rect::new(frame.x + PADDING, button_y, w, BUTTON_HEIGHT))
+        } else {
+            None
+        };
+        Layout {
+            frame,
+            path_y,
+            list,
+            row_h,
+            rows: (list.h / row_h).max(1) as usize,
+            name_field,
+            cancel,
+            confirm,
+        }
+    }
+
+    /// List `dir`; returns false and keeps the current listing if it
+    /// cannot be read
+    fn load(&mut self, dir: &str) -> bool {
+        let listing = match fs::read_dir(dir) {
+            Ok(listing) => listing,
+            Err(e) => {
+                self.error = Some(format!("{}: {:?}", dir, e));
+                return false;
+            }
+        };
+        let mut entries: Vec<Entry> = listing.into_iter()
+            .map(|e| Entry { is_dir: e.metadata.file_type == FileType::Directory, name: e.name })
+            .filter(|e| e.is_dir || self.matches_filter(&e.name))
+            .collect();
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        if dir != "/" {
+            entries.insert(0, Entry { name: String::from(".."), is_dir: true });
+        }
+        self.dir = String::from(dir);
+        self.entries = entries;
+        self.selected = 0;
+        self.scroll = 0;
+        self.error = None;
+        true
+    }
+
+    fn matches_filter(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.filter.is_empty() || self.filter.iter().any(|ext| name.ends_with(ext.as_str()))
+    }
+
+    fn path_of(&self, name: &str) -> String {
+        if self.dir == "/" {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", self.dir.trim_end_matches('/'), name)
+        }
+    }
+
+    fn parent_dir(&self) -> String {
+        match self.dir.trim_end_matches('/').rsplit_once('/') {
+            Some(("", _)) | None => String::from("/"),
+            Some((parent, _)) => String::from(parent),
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index.min(self.entries.len().saturating_sub(1));
+        let rows = self.layout().rows;
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+        if self.is_save() {
+            if let Some(entry) = self.entries.get(self.selected).filter(|e| !e.is_dir) {
+                self.name = entry.name.clone();
+            }
+        }
+    }
+
+    /// Enter the selected folder, or choose the selected file
+    fn activate(&mut self) -> Outcome {
+        let entry = match self.entries.get(self.selected) {
+            Some(entry) => entry.clone(),
+            None => return Outcome::Open { changed: false },
+        };
+        if entry.is_dir {
+            let dir = if entry.name == ".." { self.parent_dir() } else { self.path_of(&entry.name) };
+            self.load(&dir);
+            return Outcome::Open { changed: true };
+        }
+        match self.mode {
+            DialogMode::Open => self.open(&entry.name),
+            DialogMode::Save { .. } => {
+                self.name = entry.name;
+                self.save()
+            }
+        }
+    }
+
+    fn open(&mut self, name: &str) -> Outcome {
+        let path = self.path_of(name);
+        match fs::read_file(&path) {
+            Ok(data) => Outcome::Closed(Response::FileOpened {
+                name: String::from(name),
+                content: String::from_utf8_lossy(&data).into_owned(),
+                path,
+            }),
+            Err(e) => {
+                self.error = Some(format!("{}: {:?}", name, e));
+                Outcome::Open { changed: true }
+            }
+        }
+    }
+
+    /// Write the content under the typed name, adding the extension when
+    /// the filter names exactly one and the name has none
+    fn save(&mut self) -> Outcome {
+        let mut name = String::from(self.name.trim());
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            self.error = Some(String::from("Enter a file name"));
+            return Outcome::Open { changed: true };
+        }
+        if let [ext] = self.filter.as_slice() {
+            if !name.contains('.') {
+                name.push_str(ext);
+            }
+        }
+        let path = self.path_of(&name);
+        let result = match &self.mode {
+            DialogMode::Save { content } => fs::write_file(&path, content.as_bytes()),
+            DialogMode::Open => return Outcome::Open { changed: false },
+        };
+        match result {
+            Ok(()) => Outcome::Closed(Response::FileSaved { path, name }),
+            Err(e) => {
+                self.error = Some(format!("{}: {:?}", name, e));
+                Outcome::Open { changed: true }
+            }
+        }
+    }
+
+    fn confirm(&mut self) -> Outcome {
+        if self.is_save() && !self.name.trim().is_empty() {
+            self.save()
+        } else {
+            self.activate()
+        }
+    }
+
+    /// Key pressed while the dialog is up
+    ///
+    /// Arrows move the selection, Enter opens a folder or chooses a file
+    /// (in save mode it saves once a name is typed), Esc cancels, and in
+    /// save mode typing edits the name.
+    pub fn handle_key(&mut self, keycode: u16, ch: char) -> Outcome {
+        match keycode {
+            0x01 => return Outcome::Closed(Response::DialogCancelled),
+            0x48 => self.select(self.selected.saturating_sub(1)),
+            0x50 => self.select(self.selected + 1),
+            0x49 => self.select(self.selected.saturating_sub(self.layout().rows)),
+            0x51 => self.select(self.selected + self.layout().rows),
+            0x1C => return self.confirm(),
+            0x0E if self.is_save() => {
+                self.name.pop();
+            }
+            _ if self.is_save() && !ch.is_control() && ch != '/' => {
+                self.name.push(ch);
+            }
+            _ => return Outcome::Open { changed: false },
+        }
+        Outcome::Open { changed: true }
+    }
+
+    /// Left button pressed; clicks outside the dialog are ignored
+    ///
+    /// Clicking an entry selects it; clicking the selected entry again
+    /// opens it.
+    pub fn pointer_press(&mut self, x: i32, y: i32) -> Outcome {
+        let layout = self.layout();
+        if layout.cancel.contains(x, y) {
+            return Outcome::Closed(Response::DialogCancelled);
+        }
+        if layout.confirm.contains(x, y) {
+            return self.confirm();
+        }
+        if layout.list.contains(x, y) {
+            let row = ((y - layout.list.y) as u32 / layout.row_h) as usize + self.scroll;
+            if row < self.entries.len() {
+                if row == self.selected {
+                    return self.activate();
+                }
+                self.select(row);
+                return Outcome::Open { changed: true };
+            }
+        }
+        Outcome::Open { changed: false }
+    }
+}
+
+/// Draw the dialog
+pub fn paint(c: &mut Compositor, dialog: &FileDialog, theme: &Theme) {
+    let l = dialog.layout();
+    let r = l.frame;
+    let (cell_w, cell_h) = font::cell_size();
+    let radius = paint::CORNER_RADIUS;
+
+    raster::fill_rounded_rect(c, r.x + paint::SHADOW_OFFSET, r.y + paint::SHADOW_OFFSET, r.w, r.h, radius, paint::SHADOW);
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, radius, theme.title_active);
+    let bar_h = paint::TITLE_BAR_HEIGHT.min(r.h);
+    raster::fill_rounded_rect(c, r.x, r.y + bar_h as i32, r.w, r.h - bar_h, radius, theme.window_body);
+    c.fill_rect(r.x, r.y + bar_h as i32, r.w, (r.h - bar_h).min(radius), theme.window_body);
+    let title = if dialog.is_save() { "Save File" } else { "Open File" };
+    draw_text(c, title, r.x + radius as i32, r.y + (bar_h as i32 - cell_h as i32) / 2, r.w, colors::WHITE);
+
+    let text_w = r.w.saturating_sub(2 * PADDING as u32);
+    match &dialog.error {
+        Some(error) => draw_text(c, error, r.x + PADDING, l.path_y, text_w, ERROR_TEXT),
+        None => draw_text(c, &dialog.dir, r.x + PADDING, l.path_y, text_w, theme.content_text),
+    }
+
+    c.fill_rect(l.list.x, l.list.y, l.list.w, l.list.h, theme.field);
+    let visible = dialog.entries.iter().enumerate().skip(dialog.scroll).take(l.rows);
+    for (row, (i, entry)) in visible.enumerate() {
+        let y = l.list.y + (row as u32 * l.row_h) as i32;
+        if i == dialog.selected {
+            c.fill_rect(l.list.x, y, l.list.w, l.row_h, theme.selection);
+        }
+        let (label, color) = if entry.is_dir {
+            (format!("{}/", entry.name), FOLDER_TEXT)
+        } else {
+            (entry.name.clone(), theme.content_text)
+        };
+        let text_y = y + (l.row_h as i32 - cell_h as i32) / 2;
+        draw_text(c, &label, l.list.x + 8, text_y, l.list.w.saturating_sub(16), color);
+    }
+    if dialog.entries.is_empty() {
+        draw_text(c, "No matching files", l.list.x + 8, l.list.y + 4, l.list.w, FIELD_BORDER);
+    }
+
+    let text_y = l.confirm.y + (BUTTON_HEIGHT as i32 - cell_h as i32) / 2;
+    if let Some(field) = l.name_field {
+        c.fill_rect(field.x, field.y, field.w, field.h, FIELD_BORDER);
+        c.fill_rect(field.x + 1, field.y + 1, field.w.saturating_sub(2), field.h.saturating_sub(2), theme.field);
+        // Show the end of a name too long for the field, then the caret
+        let fits = (field.w.saturating_sub(16) / cell_w).saturating_sub(1) as usize;
+        let skip = dialog.name.chars().count().saturating_sub(fits);
+        let shown: String = dialog.name.chars().skip(skip).chain(core::iter::once('_')).collect();
+        draw_text(c, &shown, field.x + 8, text_y, field.w.saturating_sub(16), theme.content_text);
+    }
+
+    let confirm = if dialog.is_save() { "Save" } else { "Open" };
+    for (b, label, fill, text) in [
+        (l.cancel, "Cancel", theme.field, theme.content_text),
+        (l.confirm, confirm, theme.title_active, colors::WHITE),
+    ] {
+        raster::fill_rounded_rect(c, b.x, b.y, b.w, b.h, 6, fill);
+        let label_x = b.x + (b.w as i32 - (label.len() as u32 * cell_w) as i32) / 2;
+        draw_text(c, label, label_x, text_y, b.w, text);
+    }
+}
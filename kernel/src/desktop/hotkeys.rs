@@ -0,0 +1,241 @@
+//! Global keyboard shortcuts
+//!
+//! The desktop looks up every key press here before anything else sees
+//! it. Bindings come from `/etc/hotkeys.conf`, one `action = chord` per
+//! line, e.g. `snap_left = Super+Left`; `#` starts a comment. Actions the
+//! file does not mention keep their default chords.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+use crate::drivers::input::{MOD_ALT, MOD_CTRL, MOD_SHIFT, MOD_SUPER};
+use crate::fs::{self, FsResult};
+use crate::println;
+use crate::{info, warn};
+
+/// Where the bindings are kept
+pub const CONFIG_PATH: &str = "/etc/hotkeys.conf";
+
+/// Modifiers a chord can name; lock keys are ignored when matching
+const CHORD_MODIFIERS: u8 = MOD_SHIFT | MOD_CTRL | MOD_ALT | MOD_SUPER;
+
+/// Something a shortcut does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Open the window switcher, or move to the next window in it
+    SwitchNext,
+    SwitchPrevious,
+    /// Fill the left half of the display
+    SnapLeft,
+    SnapRight,
+    Maximize,
+    /// Undo a maximize or snap, or minimize a window that has neither
+    Restore,
+    /// Minimize every window, or bring them back
+    ShowDesktop,
+    /// Lock the session
+    Lock,
+}
+
+impl Action {
+    pub const ALL: [Action; 8] = [
+        Action::SwitchNext,
+        Action::SwitchPrevious,
+        Action::SnapLeft,
+        Action::SnapRight,
+        Action::Maximize,
+        Action::Restore,
+        Action::ShowDesktop,
+        Action::Lock,
+    ];
+
+    /// Name used in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::SwitchNext => "switch_next",
+            Action::SwitchPrevious => "switch_previous",
+            Action::SnapLeft => "snap_left",
+            Action::SnapRight => "snap_right",
+            Action::Maximize => "maximize",
+            Action::Restore => "restore",
+            Action::ShowDesktop => "show_desktop",
+            Action::Lock => "lock",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.name() == name)
+    }
+
+    fn default_chord(self) -> Chord {
+        let (modifiers, keycode) = match self {
+            Action::SwitchNext => (MOD_ALT, 0x0F),
+            Action::SwitchPrevious => (MOD_ALT | MOD_SHIFT, 0x0F),
+            Action::SnapLeft => (MOD_SUPER, 0x4B),
+            Action::SnapRight => (MOD_SUPER, 0x4D),
+            Action::Maximize => (MOD_SUPER, 0x48),
+            Action::Restore => (MOD_SUPER, 0x50),
+            Action::ShowDesktop => (MOD_SUPER, 0x20),
+            Action::Lock => (MOD_SUPER, 0x26),
+        };
+        Chord { modifiers, keycode }
+    }
+}
+
+/// Key names and their scancodes
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("Esc", 0x01), ("Backspace", 0x0E), ("Tab", 0x0F), ("Enter", 0x1C), ("Space", 0x39),
+    ("F1", 0x3B), ("F2", 0x3C), ("F3", 0x3D), ("F4", 0x3E), ("F5", 0x3F), ("F6", 0x40),
+    ("F7", 0x41), ("F8", 0x42), ("F9", 0x43), ("F10", 0x44), ("F11", 0x57), ("F12", 0x58),
+    ("Home", 0x47), ("Up", 0x48), ("PageUp", 0x49), ("Left", 0x4B), ("Right", 0x4D),
+    ("End", 0x4F), ("Down", 0x50), ("PageDown", 0x51), ("Insert", 0x52), ("Delete", 0x53),
+];
+
+/// Letters in scancode order, by keyboard row
+const LETTER_ROWS: [(&str, u16); 3] = [("QWERTYUIOP", 0x10), ("ASDFGHJKL", 0x1E), ("ZXCVBNM", 0x2C)];
+
+/// A key with the modifiers held down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chord {
+    pub modifiers: u8,
+    pub keycode: u16,
+}
+
+impl Chord {
+    /// Parse a chord like `Alt+Shift+Tab`; names are case-insensitive
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut modifiers = 0;
+        let mut keycode = None;
+        for part in text.split('+').map(str::trim) {
+            if keycode.is_some() {
+                // The key must come last
+                return None;
+            }
+            match part.to_ascii_lowercase().as_str() {
+                "shift" => modifiers |= MOD_SHIFT,
+                "ctrl" | "control" => modifiers |= MOD_CTRL,
+                "alt" => modifiers |= MOD_ALT,
+                "super" | "win" | "meta" => modifiers |= MOD_SUPER,
+                _ => keycode = Some(key_code(part)?),
+            }
+        }
+        Some(Chord { modifiers, keycode: keycode? })
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (bit, name) in [(MOD_CTRL, "Ctrl"), (MOD_ALT, "Alt"), (MOD_SHIFT, "Shift"), (MOD_SUPER, "Super")] {
+            if self.modifiers & bit != 0 {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match key_name(self.keycode) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{:#04x}", self.keycode),
+        }
+    }
+}
+
+fn key_code(name: &str) -> Option<u16> {
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        return Some(code);
+    }
+    let ch = match name.as_bytes() {
+        [ch] => ch.to_ascii_uppercase(),
+        _ => return None,
+    };
+    match ch {
+        b'1'..=b'9' => Some((ch - b'1') as u16 + 0x02),
+        b'0' => Some(0x0B),
+        _ => LETTER_ROWS.iter().find_map(|(row, first)| {
+            row.bytes().position(|c| c == ch).map(|i| first + i as u16)
+        }),
+    }
+}
+
+fn key_name(keycode: u16) -> Option<String> {
+    if let Some(&(name, _)) = KEY_NAMES.iter().find(|(_, code)| *code == keycode) {
+        return Some(String::from(name));
+    }
+    match keycode {
+        0x02..=0x0A => Some(format!("{}", keycode - 0x01)),
+        0x0B => Some(String::from("0")),
+        _ => LETTER_ROWS.iter().find_map(|(row, first)| {
+            let i = keycode.checked_sub(*first)? as usize;
+            row.get(i..i + 1).map(String::from)
+        }),
+    }
+}
+
+static BINDINGS: Mutex<Vec<(Action, Chord)>> = Mutex::new(Vec::new());
+
+fn defaults() -> Vec<(Action, Chord)> {
+    Action::ALL.into_iter().map(|a| (a, a.default_chord())).collect()
+}
+
+/// Load the bindings from the config file, falling back to the defaults
+/// for actions it does not bind or when there is no file
+pub fn load() {
+    let mut bindings = defaults();
+    match fs::read_file(CONFIG_PATH) {
+        Ok(data) => {
+            let text = String::from_utf8_lossy(&data);
+            for (n, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let parsed = line.split_once('=').and_then(|(action, chord)| {
+                    Some((Action::from_name(action.trim())?, Chord::parse(chord)?))
+                });
+                match parsed {
+                    Some((action, chord)) => set(&mut bindings, action, chord),
+                    None => warn!("hotkeys", "{}:{}: ignoring '{}'", CONFIG_PATH, n + 1, line),
+                }
+            }
+            info!("hotkeys", "Loaded {}", CONFIG_PATH);
+        }
+        Err(_) => info!("hotkeys", "No {}, using default bindings", CONFIG_PATH),
+    }
+    *BINDINGS.lock() = bindings;
+}
+
+fn set(bindings: &mut [(Action, Chord)], action: Action, chord: Chord) {
+    if let Some(binding) = bindings.iter_mut().find(|(a, _)| *a == action) {
+        binding.1 = chord;
+    }
+}
+
+/// Action bound to a key pressed with `modifiers`
+pub fn lookup(keycode: u16, modifiers: u8) -> Option<Action> {
+    let pressed = Chord { modifiers: modifiers & CHORD_MODIFIERS, keycode };
+    BINDINGS.lock().iter().find(|(_, chord)| *chord == pressed).map(|&(action, _)| action)
+}
+
+/// Bind `action` to `chord` and save the bindings to the config file
+///
+/// The new binding is in effect even if saving fails.
+pub fn bind(action: Action, chord: Chord) -> FsResult<()> {
+    let text = {
+        let mut bindings = BINDINGS.lock();
+        set(&mut bindings, action, chord);
+        let mut text = String::from("# action = chord\n");
+        for (action, chord) in bindings.iter() {
+            text.push_str(&format!("{} = {}\n", action.name(), chord));
+        }
+        text
+    };
+    fs::write_file(CONFIG_PATH, text.as_bytes())
+}
+
+/// Print the bindings
+pub fn print_info() {
+    println!("Keyboard shortcuts:");
+    for (action, chord) in BINDINGS.lock().iter() {
+        println!("  {:<16} {}", action.name(), chord);
+    }
+}
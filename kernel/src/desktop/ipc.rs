@@ -0,0 +1,1317 @@
+//! Desktop message router
+//!
+//! Bundled apps talk to the kernel with `window.parent.postMessage({ type,
+//! ... })`. Each message arrives here as JSON along with the ID of the
+//! window that sent it, is parsed into a `Request`, and is dispatched to
+//! the desktop, fs, process, users, net, tls or display subsystem. Replies are
+//! `Response`s queued for the sending window, which collects them with
+//! `take_responses` and receives them as its `message` events.
+//!
+//! Admin requests from a session without admin rights are answered with
+//! `elevation_required`. The app may then ask for an administrator's name
+//! and password and post the request again inside an `elevate` message,
+//! `{ type: 'elevate', username, password, request }`.
+//!
+//! Installed apps must also hold the capability a request needs, as
+//! described in `permissions`: `fs_write` needs the path, and
+//! `browser_navigate` and `kill_process` need `network` and `processes`.
+//! A request the app may not make is answered with `permission_denied`.
+//! The first use of a declared capability shows a prompt instead, and the
+//! request is carried out once the user allows it.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::dialog::DialogMode;
+use super::packages;
+use super::permissions::{self, Access, Capability};
+use super::paint;
+use super::terminal;
+use super::WindowId;
+use crate::config::{self, ConfigError};
+use crate::drivers::input::{self, Layout};
+use crate::drivers::pty::WinSize;
+use crate::drivers::rtc;
+use crate::drivers::vesa;
+use crate::fs::{self, FileType};
+use crate::process::{self, ProcessState, PROCESSES};
+use crate::tls::certs;
+use crate::tls::keylog::hex;
+use crate::users;
+use webbos_shared::types::Pid;
+
+/// Replies kept per window before the oldest are dropped
+const MAX_PENDING: usize = 64;
+
+/// Messages kept waiting for `deliver` before the oldest are dropped
+const MAX_INBOX: usize = 256;
+
+/// A message posted by an app
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// `code` is the one-time or recovery code of a user with two-factor
+    /// login
+    Login { username: String, password: String, code: Option<String> },
+    Logout,
+    Launch { app: String },
+    WindowTitle { title: String },
+    Notify { title: String, body: String, icon: char, timeout: u32 },
+    FsList { path: String },
+    FsWrite { path: String, content: String },
+    DialogOpen { filter: String },
+    DialogSave { content: String, filter: String },
+    SaveImage { data: String },
+    GetSystemStats,
+    KillProcess { pid: u64 },
+    ListUsers,
+    AddUser { username: String, password: String, is_admin: bool },
+    ToggleUser { id: u32, active: bool },
+    /// `home` is `keep`, `remove` or `archive`; it is kept if not given
+    DeleteUser { id: u32, home: users::HomeAction },
+    UnlockUser { id: u32 },
+    /// Start setting up two-factor login; `algorithm` is `sha1` unless
+    /// given
+    BeginTotp { id: u32, algorithm: users::totp::Algorithm },
+    /// Finish setting it up with a code from the new secret
+    ConfirmTotp { id: u32, code: String },
+    DisableTotp { id: u32 },
+    /// The Terminal app is up; starts its shell
+    TerminalReady { cols: u16, rows: u16 },
+    /// Keys typed in the Terminal app; Enter is `\r`
+    TerminalInput { data: String },
+    TerminalResize { cols: u16, rows: u16 },
+    BrowserNavigate { url: String },
+    GetDisplayModes { bpp: u8 },
+    SetDisplayMode { width: u32, height: u32, bpp: u8 },
+    GetSettings,
+    SetSetting { key: String, value: String },
+    ListPackages,
+    /// Install a `.wapp` package from a path or URL
+    InstallPackage { source: String },
+    UninstallPackage { name: String },
+    ListCertificates,
+    /// Trust the certificates in PEM text
+    AddCertificate { pem: String },
+    /// `id` is a trust anchor's, as listed
+    RemoveCertificate { id: String },
+    /// `certificate` is PEM text or a SHA-256 fingerprint in hex
+    PinCertificate { host: String, certificate: String },
+    UnpinCertificate { host: String },
+    /// Carry out `request` with the rights of administrator `username`
+    Elevate { username: String, password: String, request: Box<Request> },
+}
+
+/// A directory entry in an `fs_list_response`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub name: String,
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// A row of the task manager's process table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: u64,
+    pub name: String,
+    pub status: &'static str,
+    pub cpu: u32,
+    /// Kilobytes
+    pub memory: u64,
+}
+
+/// A row of the user manager's table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub id: u32,
+    pub username: String,
+    pub is_admin: bool,
+    pub is_active: bool,
+    /// Empty if they never have
+    pub last_login: String,
+    /// Seconds the account stays locked; 0 if it is not
+    pub locked_for: u64,
+    /// When the latest wrong passwords were given, oldest first
+    pub failures: Vec<String>,
+    pub two_factor: bool,
+}
+
+/// A row of the Settings app's list of installed packages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub name: String,
+    pub title: String,
+    pub icon: char,
+    pub description: String,
+}
+
+/// A row of the Settings app's list of trust anchors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    pub id: String,
+    pub subject: String,
+    pub issuer: String,
+    /// YYYY-MM-DD
+    pub expires: String,
+}
+
+/// A reply sent back to the window that posted the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    FsList { path: String, files: Vec<FileInfo> },
+    FileOpened { path: String, name: String, content: String },
+    FileSaved { path: String, name: String },
+    /// The file dialog was closed without choosing a file
+    DialogCancelled,
+    SystemStats {
+        cpu: u32,
+        /// Megabytes of kernel heap in use
+        memory: u64,
+        processes: Vec<ProcessInfo>,
+    },
+    UsersList { users: Vec<UserInfo> },
+    /// A secret to set up an authenticator app with, as base32 text and
+    /// as an `otpauth://` URL
+    TotpSecret { id: u32, secret: String, url: String },
+    /// Two-factor login is on; the codes are shown once only
+    RecoveryCodes { id: u32, codes: Vec<String> },
+    /// The password was right, and a one-time code is needed as well
+    CodeRequired,
+    /// Shell output, with ANSI escape sequences left in
+    TerminalOutput { text: String },
+    /// The terminal's shell exited
+    TerminalExited,
+    BrowserContent { url: String, html: String },
+    DisplayModes { modes: Vec<(u32, u32)>, width: u32, height: u32 },
+    DisplayModeResult { ok: bool, error: String },
+    /// Every setting's value, and the values some of them can take, comma
+    /// separated
+    Settings { values: Vec<(String, String)>, choices: Vec<(String, String)> },
+    /// `ok` is true once the new value is in effect; `error` may still
+    /// say it could not be saved
+    SettingResult { key: String, ok: bool, error: String },
+    Packages { packages: Vec<PackageInfo> },
+    /// Outcome of an install or uninstall; `name` is the app's
+    PackageResult { name: String, ok: bool, error: String },
+    /// Trust anchors, and pinned hosts with their fingerprints in hex
+    Certificates { anchors: Vec<CertificateInfo>, pins: Vec<(String, String)> },
+    /// Outcome of a change to the store; `name` is the certificate's
+    /// subject or the pinned host
+    CertificateResult { name: String, ok: bool, error: String },
+    /// The app may not make the request; `capability` is what it needs
+    PermissionDenied { request: String, capability: String },
+    /// An admin request was refused, but may be posted again in an
+    /// `elevate` message; `request` is its type
+    ElevationRequired { request: String, message: String },
+    /// A request that could not be carried out; `request` is its type
+    Error { request: String, message: String },
+}
+
+/// Why a message could not be turned into a `Request`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpcError {
+    /// Not a JSON object
+    Malformed,
+    /// A field is missing or has the wrong type
+    MissingField(&'static str),
+    UnknownType(String),
+}
+
+impl Request {
+    /// Parse a posted message
+    pub fn parse(message: &str) -> Result<Self, IpcError> {
+        match json::parse(message) {
+            Some(json::Value::Object(fields)) => Self::from_message(Message(fields)),
+            _ => Err(IpcError::Malformed),
+        }
+    }
+
+    fn from_message(msg: Message) -> Result<Self, IpcError> {
+        let kind = msg.str("type")?;
+        Ok(match kind.as_str() {
+            "login" => Self::Login {
+                username: msg.str("username")?,
+                password: msg.str("password")?,
+                code: msg.str("code").ok().filter(|c| !c.is_empty()),
+            },
+            "logout" => Self::Logout,
+            "launch" => Self::Launch { app: msg.str("app")? },
+            "window_title" => Self::WindowTitle { title: msg.str("title")? },
+            "notify" => Self::Notify {
+                title: msg.str("title")?,
+                body: msg.str("body").unwrap_or_default(),
+                icon: msg.str("icon").ok().and_then(|s| s.chars().next()).unwrap_or('i'),
+                timeout: msg.int("timeout").unwrap_or(5).max(0) as u32,
+            },
+            "fs_list" => Self::FsList { path: msg.str("path")? },
+            "fs_write" => Self::FsWrite { path: msg.str("path")?, content: msg.str("content")? },
+            "dialog_open" => Self::DialogOpen { filter: msg.str("filter").unwrap_or_default() },
+            "dialog_save" => Self::DialogSave {
+                content: msg.str("content")?,
+                filter: msg.str("filter").unwrap_or_default(),
+            },
+            "save_image" => Self::SaveImage { data: msg.str("data")? },
+            "get_system_stats" => Self::GetSystemStats,
+            "kill_process" => Self::KillProcess { pid: msg.int("pid")? as u64 },
+            "list_users" => Self::ListUsers,
+            "add_user" => Self::AddUser {
+                username: msg.str("username")?,
+                password: msg.str("password")?,
+                is_admin: msg.bool("is_admin").unwrap_or(false),
+            },
+            "toggle_user" => Self::ToggleUser { id: msg.int("id")? as u32, active: msg.bool("active")? },
+            "delete_user" => Self::DeleteUser {
+                id: msg.int("id")? as u32,
+                home: match msg.str("home").as_deref() {
+                    Ok("remove") => users::HomeAction::Remove,
+                    Ok("archive") => users::HomeAction::Archive,
+                    _ => users::HomeAction::Keep,
+                },
+            },
+            "unlock_user" => Self::UnlockUser { id: msg.int("id")? as u32 },
+            "begin_totp" => Self::BeginTotp {
+                id: msg.int("id")? as u32,
+                algorithm: msg.str("algorithm").ok()
+                    .and_then(|a| users::totp::Algorithm::parse(&a))
+                    .unwrap_or(users::totp::Algorithm::Sha1),
+            },
+            "confirm_totp" => Self::ConfirmTotp { id: msg.int("id")? as u32, code: msg.str("code")? },
+            "disable_totp" => Self::DisableTotp { id: msg.int("id")? as u32 },
+            "terminal_ready" => Self::TerminalReady {
+                cols: msg.int("cols").unwrap_or(80).clamp(1, u16::MAX as i64) as u16,
+                rows: msg.int("rows").unwrap_or(24).clamp(1, u16::MAX as i64) as u16,
+            },
+            "terminal_input" => Self::TerminalInput { data: msg.str("data")? },
+            "terminal_resize" => Self::TerminalResize {
+                cols: msg.int("cols")?.clamp(1, u16::MAX as i64) as u16,
+                rows: msg.int("rows")?.clamp(1, u16::MAX as i64) as u16,
+            },
+            "browser_navigate" => Self::BrowserNavigate { url: msg.str("url")? },
+            "get_display_modes" => Self::GetDisplayModes { bpp: msg.int("bpp").unwrap_or(32) as u8 },
+            "set_display_mode" => Self::SetDisplayMode {
+                width: msg.int("width")? as u32,
+                height: msg.int("height")? as u32,
+                bpp: msg.int("bpp").unwrap_or(32) as u8,
+            },
+            "get_settings" => Self::GetSettings,
+            "set_setting" => Self::SetSetting {
+                key: msg.str("key")?,
+                value: msg.str("value")?,
+            },
+            "list_packages" => Self::ListPackages,
+            "install_package" => Self::InstallPackage { source: msg.str("source")? },
+            "uninstall_package" => Self::UninstallPackage { name: msg.str("name")? },
+            "list_certificates" => Self::ListCertificates,
+            "add_certificate" => Self::AddCertificate { pem: msg.str("pem")? },
+            "remove_certificate" => Self::RemoveCertificate { id: msg.str("id")? },
+            "pin_certificate" => Self::PinCertificate {
+                host: msg.str("host")?,
+                certificate: msg.str("certificate")?,
+            },
+            "unpin_certificate" => Self::UnpinCertificate { host: msg.str("host")? },
+            "elevate" => Self::Elevate {
+                username: msg.str("username")?,
+                password: msg.str("password")?,
+                request: match msg.get("request") {
+                    Some(json::Value::Object(fields)) => Box::new(Self::from_message(Message(fields.clone()))?),
+                    _ => return Err(IpcError::MissingField("request")),
+                },
+            },
+            _ => return Err(IpcError::UnknownType(kind)),
+        })
+    }
+
+    /// The message's `type`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Login { .. } => "login",
+            Self::Logout => "logout",
+            Self::Launch { .. } => "launch",
+            Self::WindowTitle { .. } => "window_title",
+            Self::Notify { .. } => "notify",
+            Self::FsList { .. } => "fs_list",
+            Self::FsWrite { .. } => "fs_write",
+            Self::DialogOpen { .. } => "dialog_open",
+            Self::DialogSave { .. } => "dialog_save",
+            Self::SaveImage { .. } => "save_image",
+            Self::GetSystemStats => "get_system_stats",
+            Self::KillProcess { .. } => "kill_process",
+            Self::ListUsers => "list_users",
+            Self::AddUser { .. } => "add_user",
+            Self::ToggleUser { .. } => "toggle_user",
+            Self::DeleteUser { .. } => "delete_user",
+            Self::UnlockUser { .. } => "unlock_user",
+            Self::BeginTotp { .. } => "begin_totp",
+            Self::ConfirmTotp { .. } => "confirm_totp",
+            Self::DisableTotp { .. } => "disable_totp",
+            Self::TerminalReady { .. } => "terminal_ready",
+            Self::TerminalInput { .. } => "terminal_input",
+            Self::TerminalResize { .. } => "terminal_resize",
+            Self::BrowserNavigate { .. } => "browser_navigate",
+            Self::GetDisplayModes { .. } => "get_display_modes",
+            Self::SetDisplayMode { .. } => "set_display_mode",
+            Self::GetSettings => "get_settings",
+            Self::SetSetting { .. } => "set_setting",
+            Self::ListPackages => "list_packages",
+            Self::InstallPackage { .. } => "install_package",
+            Self::UninstallPackage { .. } => "uninstall_package",
+            Self::ListCertificates => "list_certificates",
+            Self::AddCertificate { .. } => "add_certificate",
+            Self::RemoveCertificate { .. } => "remove_certificate",
+            Self::PinCertificate { .. } => "pin_certificate",
+            Self::UnpinCertificate { .. } => "unpin_certificate",
+            Self::Elevate { .. } => "elevate",
+        }
+    }
+}
+
+impl Response {
+    /// The message's `type`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::FsList { .. } => "fs_list_response",
+            Self::FileOpened { .. } => "file_opened",
+            Self::FileSaved { .. } => "file_saved",
+            Self::DialogCancelled => "dialog_cancelled",
+            Self::SystemStats { .. } => "system_stats",
+            Self::UsersList { .. } => "users_list",
+            Self::TotpSecret { .. } => "totp_secret",
+            Self::RecoveryCodes { .. } => "recovery_codes",
+            Self::CodeRequired => "code_required",
+            Self::TerminalOutput { .. } => "terminal_output",
+            Self::TerminalExited => "terminal_exited",
+            Self::BrowserContent { .. } => "browser_content",
+            Self::DisplayModes { .. } => "display_modes",
+            Self::DisplayModeResult { .. } => "display_mode_result",
+            Self::Settings { .. } => "settings",
+            Self::SettingResult { .. } => "setting_result",
+            Self::Packages { .. } => "packages",
+            Self::PackageResult { .. } => "package_result",
+            Self::Certificates { .. } => "certificates",
+            Self::CertificateResult { .. } => "certificate_result",
+            Self::PermissionDenied { .. } => "permission_denied",
+            Self::ElevationRequired { .. } => "elevation_required",
+            Self::Error { .. } => "error",
+        }
+    }
+
+    /// Encode as the JSON object the app receives as `event.data`
+    pub fn to_json(&self) -> String {
+        let mut out = json::Object::new(self.kind());
+        match self {
+            Self::FsList { path, files } => {
+                out.str("path", path);
+                out.array("files", files, |o, f| {
+                    o.str("name", &f.name);
+                    o.str("path", &f.path);
+                    o.bool("is_dir", f.is_dir);
+                    o.int("size", f.size as i64);
+                });
+            }
+            Self::FileOpened { path, name, content } => {
+                out.str("path", path);
+                out.str("name", name);
+                out.str("content", content);
+            }
+            Self::FileSaved { path, name } => {
+                out.str("path", path);
+                out.str("name", name);
+            }
+            Self::DialogCancelled => {}
+            Self::SystemStats { cpu, memory, processes } => {
+                out.int("cpu", *cpu as i64);
+                out.int("memory", *memory as i64);
+                out.array("processes", processes, |o, p| {
+                    o.int("pid", p.pid as i64);
+                    o.str("name", &p.name);
+                    o.str("status", p.status);
+                    o.int("cpu", p.cpu as i64);
+                    o.int("memory", p.memory as i64);
+                });
+            }
+            Self::UsersList { users } => {
+                out.array("users", users, |o, u| {
+                    o.int("id", u.id as i64);
+                    o.str("username", &u.username);
+                    o.bool("is_admin", u.is_admin);
+                    o.bool("is_active", u.is_active);
+                    o.str("last_login", &u.last_login);
+                    o.int("locked_for", u.locked_for as i64);
+                    o.str("failures", &u.failures.join(", "));
+                    o.bool("two_factor", u.two_factor);
+                });
+            }
+            Self::TotpSecret { id, secret, url } => {
+                out.int("id", *id as i64);
+                out.str("secret", secret);
+                out.str("url", url);
+            }
+            Self::RecoveryCodes { id, codes } => {
+                out.int("id", *id as i64);
+                out.str("codes", &codes.join(" "));
+            }
+            Self::CodeRequired => {}
+            Self::TerminalOutput { text } => out.str("text", text),
+            Self::TerminalExited => {}
+            Self::BrowserContent { url, html } => {
+                out.str("url", url);
+                out.str("html", html);
+            }
+            Self::DisplayModes { modes, width, height } => {
+                out.array("modes", modes, |o, &(w, h)| {
+                    o.int("width", w as i64);
+                    o.int("height", h as i64);
+                });
+                out.int("width", *width as i64);
+                out.int("height", *height as i64);
+            }
+            Self::DisplayModeResult { ok, error } => {
+                out.bool("ok", *ok);
+                out.str("error", error);
+            }
+            Self::Settings { values, choices } => {
+                out.array("values", values, |o, (key, value)| {
+                    o.str("key", key);
+                    o.str("value", value);
+                });
+                out.array("choices", choices, |o, (key, values)| {
+                    o.str("key", key);
+                    o.str("values", values);
+                });
+            }
+            Self::SettingResult { key, ok, error } => {
+                out.str("key", key);
+                out.bool("ok", *ok);
+                out.str("error", error);
+            }
+            Self::Packages { packages } => {
+                out.array("packages", packages, |o, p| {
+                    o.str("name", &p.name);
+                    o.str("title", &p.title);
+                    o.str("icon", &p.icon.to_string());
+                    o.str("description", &p.description);
+                });
+            }
+            Self::PackageResult { name, ok, error } | Self::CertificateResult { name, ok, error } => {
+                out.str("name", name);
+                out.bool("ok", *ok);
+                out.str("error", error);
+            }
+            Self::Certificates { anchors, pins } => {
+                out.array("anchors", anchors, |o, c| {
+                    o.str("id", &c.id);
+                    o.str("subject", &c.subject);
+                    o.str("issuer", &c.issuer);
+                    o.str("expires", &c.expires);
+                });
+                out.array("pins", pins, |o, (host, fingerprint)| {
+                    o.str("host", host);
+                    o.str("fingerprint", fingerprint);
+                });
+            }
+            Self::PermissionDenied { request, capability } => {
+                out.str("request", request);
+                out.str("capability", capability);
+            }
+            Self::ElevationRequired { request, message } | Self::Error { request, message } => {
+                out.str("request", request);
+                out.str("message", message);
+            }
+        }
+        out.finish()
+    }
+}
+
+/// Replies waiting to be collected, per window
+static PENDING: Mutex<BTreeMap<WindowId, VecDeque<Response>>> = Mutex::new(BTreeMap::new());
+
+/// Messages posted while the desktop was busy, oldest first
+static INBOX: Mutex<VecDeque<(WindowId, String)>> = Mutex::new(VecDeque::new());
+
+/// Requests the user allowed from the permission prompt, oldest first
+static ALLOWED: Mutex<VecDeque<(WindowId, Request)>> = Mutex::new(VecDeque::new());
+
+/// Handle a message posted by `window`
+///
+/// Replies are queued for the window. A message that does not parse is
+/// answered with an error reply.
+pub fn post(window: WindowId, message: &str) {
+    let responses = match Request::parse(message) {
+        Ok(request) => dispatch(window, request),
+        Err(e) => alloc::vec![Response::Error {
+            request: String::new(),
+            message: format!("{:?}", e),
+        }],
+    };
+    responses.into_iter().for_each(|response| send(window, response));
+}
+
+/// Hold a message posted by `window` until `deliver` handles it
+///
+/// Apps post while the desktop is ticking them, when a request cannot
+/// be carried out yet.
+pub fn queue(window: WindowId, message: String) {
+    let mut inbox = INBOX.lock();
+    if inbox.len() >= MAX_INBOX {
+        inbox.pop_front();
+    }
+    inbox.push_back((window, message));
+}
+
+/// Carry out `request` for `window` when `deliver` next runs, once the
+/// user has allowed it
+pub fn retry(window: WindowId, request: Request) {
+    ALLOWED.lock().push_back((window, request));
+}
+
+/// Handle the messages `queue` is holding and the requests `retry` is
+pub fn deliver() {
+    loop {
+        let next = ALLOWED.lock().pop_front();
+        match next {
+            Some((window, request)) => dispatch(window, request).into_iter().for_each(|r| send(window, r)),
+            None => break,
+        }
+    }
+    loop {
+        // Handling a message may queue more
+        let next = INBOX.lock().pop_front();
+        match next {
+            Some((window, message)) => post(window, &message),
+            None => break,
+        }
+    }
+}
+
+/// Queue a reply for `window` outside of a request, e.g. once a dialog
+/// it opened closes
+pub fn send(window: WindowId, response: Response) {
+    let mut pending = PENDING.lock();
+    let queue = pending.entry(window).or_default();
+    if queue.len() >= MAX_PENDING {
+        queue.pop_front();
+    }
+    queue.push_back(response);
+}
+
+/// Replies queued for `window`, oldest first
+pub fn take_responses(window: WindowId) -> Vec<Response> {
+    PENDING.lock().remove(&window).map(Vec::from).unwrap_or_default()
+}
+
+/// Drop the replies of a window that closed, and hang up its terminal
+pub fn forget(window: WindowId) {
+    PENDING.lock().remove(&window);
+    terminal::close(window);
+}
+
+/// Carry out a request for `window` and return its replies
+pub fn dispatch(window: WindowId, request: Request) -> Vec<Response> {
+    let kind = request.kind();
+    let fail = |message: String| alloc::vec![Response::Error { request: kind.to_string(), message }];
+    let needs_admin = |message: &str| alloc::vec![Response::ElevationRequired {
+        request: kind.to_string(),
+        message: String::from(message),
+    }];
+
+    if let Some(needed) = needed_capability(&request) {
+        if let Some((app, declared)) = super::window_capabilities(window) {
+            match permissions::check(&app, &declared, &needed) {
+                Access::Allowed => {}
+                Access::Denied => return alloc::vec![Response::PermissionDenied {
+                    request: kind.to_string(),
+                    capability: needed.to_string(),
+                }],
+                Access::Ask => {
+                    let asked = permissions::declared_for(&declared, &needed)
+                        .map_or(false, |capability| super::ask_consent(window, capability, request));
+                    if !asked {
+                        return fail(String::from("Another prompt is open; try again"));
+                    }
+                    return Vec::new();
+                }
+            }
+        }
+    }
+
+    match request {
+        Request::Login { username, password, code } => match super::login(&username, &password, code.as_deref()) {
+            Ok(()) => Vec::new(),
+            Err(users::UserError::CodeRequired) => alloc::vec![Response::CodeRequired],
+            Err(e) => fail(e.to_string()),
+        },
+        Request::Logout => {
+            super::logout();
+            Vec::new()
+        }
+        Request::Launch { app } => match super::launch_app(&app, super::window_pid(window)) {
+            Some(_) => Vec::new(),
+            None => fail(format!("No application '{}'", app)),
+        },
+        Request::WindowTitle { title } => {
+            super::set_window_title(window, &title);
+            Vec::new()
+        }
+        Request::Notify { title, body, icon, timeout } => {
+            super::notifications::notify(&title, &body, icon, timeout);
+            Vec::new()
+        }
+        Request::FsList { path } => match fs::read_dir(&path) {
+            Ok(entries) => {
+                let base = path.trim_end_matches('/');
+                let files = entries.into_iter().map(|e| FileInfo {
+                    path: format!("{}/{}", base, e.name),
+                    is_dir: e.metadata.file_type == FileType::Directory,
+                    size: e.metadata.size,
+                    name: e.name,
+                }).collect();
+                alloc::vec![Response::FsList { path, files }]
+            }
+            Err(e) => fail(format!("{}: {:?}", path, e)),
+        },
+        Request::FsWrite { path, content } => match fs::write_file(&path, content.as_bytes()) {
+            Ok(()) => Vec::new(),
+            Err(e) => fail(format!("{}: {:?}", path, e)),
+        },
+        Request::DialogOpen { filter } => {
+            if !super::open_file_dialog(window, DialogMode::Open, &filter) {
+                return fail(String::from("A file dialog is already open"));
+            }
+            Vec::new()
+        }
+        Request::DialogSave { content, filter } => {
+            if !super::open_file_dialog(window, DialogMode::Save { content }, &filter) {
+                return fail(String::from("A file dialog is already open"));
+            }
+            Vec::new()
+        }
+        Request::SaveImage { .. } => fail(String::from("Saving images is not supported")),
+        Request::GetSystemStats => alloc::vec![system_stats()],
+        Request::KillProcess { pid } => match process::kill(Pid::new(pid)) {
+            Ok(()) => alloc::vec![system_stats()],
+            Err(e) => fail(format!("Process {}: {:?}", pid, e)),
+        },
+        Request::ListUsers => alloc::vec![users_list()],
+        Request::AddUser { username, password, is_admin } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can manage users");
+            }
+            match users::create_user(&username, &password, is_admin) {
+                Ok(_) => alloc::vec![users_list()],
+                Err(e) => fail(format!("{:?}", e)),
+            }
+        }
+        Request::ToggleUser { id, active } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can manage users");
+            }
+            match users::set_user_active(id, active) {
+                Ok(()) => alloc::vec![users_list()],
+                Err(e) => fail(format!("{:?}", e)),
+            }
+        }
+        Request::DeleteUser { id, home } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can manage users");
+            }
+            match users::delete_user(id, home) {
+                Ok(()) => alloc::vec![users_list()],
+                Err(e) => fail(e.to_string()),
+            }
+        }
+        Request::UnlockUser { id } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can manage users");
+            }
+            match users::unlock_user(id) {
+                Ok(()) => alloc::vec![users_list()],
+                Err(e) => fail(format!("{:?}", e)),
+            }
+        }
+        Request::BeginTotp { id, algorithm } => {
+            if !is_self_or_admin(id) {
+                return needs_admin("Only administrators can set up two-factor login for others");
+            }
+            match users::begin_totp(id, algorithm) {
+                Ok((secret, url)) => alloc::vec![Response::TotpSecret { id, secret, url }],
+                Err(e) => fail(e.to_string()),
+            }
+        }
+        Request::ConfirmTotp { id, code } => {
+            if !is_self_or_admin(id) {
+                return needs_admin("Only administrators can set up two-factor login for others");
+            }
+            match users::confirm_totp(id, &code) {
+                Ok(codes) => alloc::vec![Response::RecoveryCodes { id, codes }, users_list()],
+                Err(e) => fail(e.to_string()),
+            }
+        }
+        Request::DisableTotp { id } => {
+            if !is_self_or_admin(id) {
+                return needs_admin("Only administrators can turn off two-factor login for others");
+            }
+            match users::disable_totp(id) {
+                Ok(()) => alloc::vec![users_list()],
+                Err(e) => fail(e.to_string()),
+            }
+        }
+        Request::TerminalReady { cols, rows } => {
+            terminal::open(window, WinSize { cols, rows });
+            Vec::new()
+        }
+        Request::TerminalInput { data } => {
+            if !terminal::input(window, &data) {
+                return fail(String::from("No shell; send terminal_ready first"));
+            }
+            Vec::new()
+        }
+        Request::TerminalResize { cols, rows } => {
+            if !terminal::resize(window, WinSize { cols, rows }) {
+                return fail(String::from("No shell; send terminal_ready first"));
+            }
+            Vec::new()
+        }
+        Request::BrowserNavigate { url } => match fetch_page(&url) {
+            Ok(html) => alloc::vec![Response::BrowserContent { url, html }],
+            Err(message) => fail(format!("{}: {}", url, message)),
+        },
+        Request::GetDisplayModes { bpp } => {
+            let (width, height) = vesa::info().map_or((0, 0), |i| (i.width, i.height));
+            alloc::vec![Response::DisplayModes { modes: vesa::available_modes(bpp), width, height }]
+        }
+        Request::SetDisplayMode { width, height, bpp } => {
+            // Through the config store, so the mode is used again next boot
+            let (ok, error) = set_setting("display.mode", &format!("{}x{}x{}", width, height, bpp));
+            alloc::vec![Response::DisplayModeResult { ok, error }]
+        }
+        Request::GetSettings => alloc::vec![settings()],
+        Request::SetSetting { key, value } => {
+            if ["network.", "nat.", "auth.", "tls."].iter().any(|prefix| key.starts_with(prefix)) {
+                if !is_admin_session() {
+                    return needs_admin("Only administrators can change network, login and TLS settings");
+                }
+                users::audit::record(users::audit::Kind::Settings, &format!("set {} to {}", key, value));
+            }
+            let (ok, error) = set_setting(&key, &value);
+            alloc::vec![Response::SettingResult { key, ok, error }]
+        }
+        Request::ListPackages => alloc::vec![packages_list()],
+        Request::InstallPackage { source } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can install apps");
+            }
+            let result = match packages::install(&source) {
+                Ok(name) => Response::PackageResult { name, ok: true, error: String::new() },
+                Err(e) => Response::PackageResult { name: source, ok: false, error: e.to_string() },
+            };
+            alloc::vec![result, packages_list()]
+        }
+        Request::UninstallPackage { name } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can uninstall apps");
+            }
+            let error = packages::uninstall(&name).err().map(|e| e.to_string());
+            alloc::vec![
+                Response::PackageResult { name, ok: error.is_none(), error: error.unwrap_or_default() },
+                packages_list(),
+            ]
+        }
+        Request::ListCertificates => alloc::vec![certificates_list()],
+        Request::AddCertificate { pem } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can change trusted certificates");
+            }
+            let result = match certs::add(&pem) {
+                Ok(added) => Response::CertificateResult {
+                    name: added.iter().map(|c| c.subject.as_str()).collect::<Vec<_>>().join(", "),
+                    ok: true,
+                    error: String::new(),
+                },
+                Err(e) => Response::CertificateResult { name: String::new(), ok: false, error: e.to_string() },
+            };
+            alloc::vec![result, certificates_list()]
+        }
+        Request::RemoveCertificate { id } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can change trusted certificates");
+            }
+            let result = match certs::remove(&id) {
+                Ok(c) => Response::CertificateResult { name: c.subject, ok: true, error: String::new() },
+                Err(e) => Response::CertificateResult { name: id, ok: false, error: e.to_string() },
+            };
+            alloc::vec![result, certificates_list()]
+        }
+        Request::PinCertificate { host, certificate } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can change trusted certificates");
+            }
+            let error = certs::pin(&host, &certificate).err().map(|e| e.to_string());
+            alloc::vec![
+                Response::CertificateResult { name: host, ok: error.is_none(), error: error.unwrap_or_default() },
+                certificates_list(),
+            ]
+        }
+        Request::UnpinCertificate { host } => {
+            if !is_admin_session() {
+                return needs_admin("Only administrators can change trusted certificates");
+            }
+            let error = certs::unpin(&host).err().map(|e| e.to_string());
+            alloc::vec![
+                Response::CertificateResult { name: host, ok: error.is_none(), error: error.unwrap_or_default() },
+                certificates_list(),
+            ]
+        }
+        Request::Elevate { username, password, request } => {
+            let action = request.kind();
+            match users::elevate(&username, &password, action, || dispatch(window, *request)) {
+                Ok(responses) => responses,
+                Err(_) => alloc::vec![Response::ElevationRequired {
+                    request: action.to_string(),
+                    message: String::from("Wrong administrator name or password"),
+                }],
+            }
+        }
+    }
+}
+
+/// Capability an installed app needs to make `request`, if any
+fn needed_capability(request: &Request) -> Option<Capability> {
+    match request {
+        Request::FsWrite { path, .. } => Some(permissions::fs_write(path)),
+        Request::BrowserNavigate { .. } => Some(Capability::Network),
+        Request::KillProcess { .. } => Some(Capability::Processes),
+        _ => None,
+    }
+}
+
+fn packages_list() -> Response {
+    Response::Packages {
+        packages: packages::installed().into_iter().map(|m| PackageInfo {
+            name: m.name,
+            title: m.title,
+            icon: m.icon,
+            description: m.description,
+        }).collect(),
+    }
+}
+
+fn certificates_list() -> Response {
+    Response::Certificates {
+        anchors: certs::list().into_iter().map(|c| CertificateInfo {
+            id: c.id(),
+            subject: c.subject,
+            issuer: c.issuer,
+            expires: c.not_after,
+        }).collect(),
+        pins: certs::pins().into_iter().map(|(host, fingerprint)| (host, hex(&fingerprint))).collect(),
+    }
+}
+
+fn is_admin_session() -> bool {
+    users::is_privileged()
+}
+
+/// Whether the session may change account settings of user `id`: it is
+/// theirs, or may carry out admin actions
+fn is_self_or_admin(id: u32) -> bool {
+    users::current_user().map_or(false, |u| u.id == id) || is_admin_session()
+}
+
+/// Change a setting for an app
+///
+/// Returns whether the value is in effect and any error to show.
+fn set_setting(key: &str, value: &str) -> (bool, String) {
+    // Users each have their own layout
+    if key == "keyboard.layout" && users::current_user().is_some() {
+        return match super::set_keyboard_layout(value) {
+            Ok(()) => (true, String::new()),
+            Err(e) => (input::layout().name() == value, e),
+        };
+    }
+    match config::set(key, value) {
+        Ok(()) => (true, String::new()),
+        Err(e @ ConfigError::NotSaved(_)) => (true, e.to_string()),
+        Err(e) => (false, e.to_string()),
+    }
+}
+
+fn settings() -> Response {
+    let wallpapers: Vec<&str> = paint::WALLPAPERS.iter().map(|(name, _)| *name).collect();
+    let themes: Vec<&str> = paint::THEMES.iter().map(|t| t.name).collect();
+    let layouts: Vec<&str> = Layout::ALL.iter().map(|l| l.name()).collect();
+    Response::Settings {
+        values: config::entries().into_iter()
+            .map(|(key, value)| match key {
+                "keyboard.layout" => (String::from(key), String::from(input::layout().name())),
+                _ => (String::from(key), value),
+            })
+            .collect(),
+        choices: alloc::vec![
+            (String::from("desktop.wallpaper"), wallpapers.join(",")),
+            (String::from("desktop.theme"), themes.join(",")),
+            (String::from("keyboard.layout"), layouts.join(",")),
+            (String::from("clock.timezone"), rtc::timezones().join(",")),
+            (String::from("network.mode"), String::from("dhcp,static")),
+            (String::from("network.dns_mode"), String::from("udp,tls,https")),
+            (String::from("network.dns_fallback"), String::from("on,off")),
+            (String::from("watchdog.action"), String::from("log,backtrace,reboot")),
+            (String::from("sound.notifications"), String::from("on,off")),
+        ],
+    }
+}
+
+fn system_stats() -> Response {
+    let cpu = process::cpu_usage();
+    let processes = PROCESSES.lock().iter().map(|(&pid, p)| ProcessInfo {
+        pid,
+        name: p.name().to_string(),
+        status: match p.state {
+            ProcessState::Running => "Running",
+            ProcessState::Ready => "Ready",
+            ProcessState::Blocked => "Blocked",
+            ProcessState::Zombie => "Zombie",
+            ProcessState::Creating => "Starting",
+        },
+        cpu: cpu.get(&pid).copied().unwrap_or(0),
+        memory: crate::mm::resident(Pid::new(pid)) / 1024,
+    }).collect();
+    // Whatever the idle process did not get, something else used
+    let idle = cpu.get(&0).copied().unwrap_or(100);
+    Response::SystemStats {
+        cpu: 100 - idle,
+        memory: crate::mm::allocator::used_heap() / (1024 * 1024),
+        processes,
+    }
+}
+
+fn users_list() -> Response {
+    Response::UsersList {
+        users: users::list_users().into_iter().map(|u| UserInfo {
+            id: u.id,
+            is_admin: u.is_admin,
+            is_active: u.is_active,
+            last_login: u.last_login.map(|t| t.to_string()).unwrap_or_default(),
+            locked_for: u.lockout_remaining().unwrap_or(0),
+            failures: u.failures.iter().map(|t| t.to_string()).collect(),
+            two_factor: u.totp.is_some(),
+            username: u.username,
+        }).collect(),
+    }
+}
+
+/// Page source for the browser app: http(s) through the network stack,
+/// anything else from the filesystem
+fn fetch_page(url: &str) -> Result<String, String> {
+    let body = if url.starts_with("http://") || url.starts_with("https://") {
+        let response = crate::net::http::get(url).map_err(|e| format!("{:?}", e))?;
+        if response.status >= 400 {
+            return Err(format!("HTTP {} {}", response.status, response.status_text));
+        }
+        response.body
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        fs::read_file(path).map_err(|e| format!("{:?}", e))?
+    };
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Accessors over a parsed message object
+struct Message(Vec<(String, json::Value)>);
+
+impl Message {
+    fn get(&self, key: &str) -> Option<&json::Value> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn str(&self, key: &'static str) -> Result<String, IpcError> {
+        match self.get(key) {
+            Some(json::Value::String(s)) => Ok(s.clone()),
+            _ => Err(IpcError::MissingField(key)),
+        }
+    }
+
+    fn int(&self, key: &'static str) -> Result<i64, IpcError> {
+        match self.get(key) {
+            Some(json::Value::Number(n)) => Ok(*n),
+            _ => Err(IpcError::MissingField(key)),
+        }
+    }
+
+    fn bool(&self, key: &'static str) -> Result<bool, IpcError> {
+        match self.get(key) {
+            Some(json::Value::Bool(b)) => Ok(*b),
+            _ => Err(IpcError::MissingField(key)),
+        }
+    }
+}
+
+/// Just enough JSON for the message protocol
+///
+/// Numbers are integers; a fraction or exponent is rejected, as no message
+/// carries one.
+mod json {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use core::fmt::Write;
+
+    /// Nesting deeper than this is rejected rather than recursed into
+    const MAX_DEPTH: usize = 32;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Value {
+        Null,
+        Bool(bool),
+        Number(i64),
+        String(String),
+        Array(Vec<Value>),
+        Object(Vec<(String, Value)>),
+    }
+
+    /// Parse a complete JSON document
+    pub fn parse(text: &str) -> Option<Value> {
+        let mut p = Parser { s: text.as_bytes(), pos: 0 };
+        let value = p.value(0)?;
+        p.skip_ws();
+        if p.pos == p.s.len() { Some(value) } else { None }
+    }
+
+    struct Parser<'a> {
+        s: &'a [u8],
+        pos: usize,
+    }
+
+    impl Parser<'_> {
+        fn skip_ws(&mut self) {
+            while matches!(self.s.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+                self.pos += 1;
+            }
+        }
+
+        fn eat(&mut self, byte: u8) -> bool {
+            self.skip_ws();
+            if self.s.get(self.pos) == Some(&byte) {
+                self.pos += 1;
+                true
+            } else {
+                false
+            }
+        }
+
+        fn literal(&mut self, word: &str, value: Value) -> Option<Value> {
+            if self.s[self.pos..].starts_with(word.as_bytes()) {
+                self.pos += word.len();
+                Some(value)
+            } else {
+                None
+            }
+        }
+
+        fn value(&mut self, depth: usize) -> Option<Value> {
+            if depth > MAX_DEPTH {
+                return None;
+            }
+            self.skip_ws();
+            match *self.s.get(self.pos)? {
+                b'{' => {
+                    self.pos += 1;
+                    let mut fields = Vec::new();
+                    if self.eat(b'}') {
+                        return Some(Value::Object(fields));
+                    }
+                    loop {
+                        self.skip_ws();
+                        let key = self.string()?;
+                        if !self.eat(b':') {
+                            return None;
+                        }
+                        fields.push((key, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            return Some(Value::Object(fields));
+                        }
+                        if !self.eat(b',') {
+                            return None;
+                        }
+                    }
+                }
+                b'[' => {
+                    self.pos += 1;
+                    let mut items = Vec::new();
+                    if self.eat(b']') {
+                        return Some(Value::Array(items));
+                    }
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            return Some(Value::Array(items));
+                        }
+                        if !self.eat(b',') {
+                            return None;
+                        }
+                    }
+                }
+                b'"' => self.string().map(Value::String),
+                b't' => self.literal("true", Value::Bool(true)),
+                b'f' => self.literal("false", Value::Bool(false)),
+                b'n' => self.literal("null", Value::Null),
+                b'-' | b'0'..=b'9' => self.number(),
+                _ => None,
+            }
+        }
+
+        fn number(&mut self) -> Option<Value> {
+            let start = self.pos;
+            if self.s[self.pos] == b'-' {
+                self.pos += 1;
+            }
+            while matches!(self.s.get(self.pos), Some(b'0'..=b'9')) {
+                self.pos += 1;
+            }
+            if matches!(self.s.get(self.pos), Some(b'.' | b'e' | b'E')) {
+                return None;
+            }
+            core::str::from_utf8(&self.s[start..self.pos]).ok()?.parse().ok().map(Value::Number)
+        }
+
+        fn string(&mut self) -> Option<String> {
+            if self.s.get(self.pos) != Some(&b'"') {
+                return None;
+            }
+            self.pos += 1;
+            let mut out = Vec::new();
+            loop {
+                let byte = *self.s.get(self.pos)?;
+                self.pos += 1;
+                match byte {
+                    b'"' => return String::from_utf8(out).ok(),
+                    b'\\' => {
+                        let escaped = *self.s.get(self.pos)?;
+                        self.pos += 1;
+                        let ch = match escaped {
+                            b'"' => '"',
+                            b'\\' => '\\',
+                            b'/' => '/',
+                            b'b' => '\u{8}',
+                            b'f' => '\u{c}',
+                            b'n' => '\n',
+                            b'r' => '\r',
+                            b't' => '\t',
+                            b'u' => self.unicode_escape()?,
+                            _ => return None,
+                        };
+                        let mut buf = [0; 4];
+                        out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                    }
+                    _ => out.push(byte),
+                }
+            }
+        }
+
+        /// The code point after `\u`, combining a surrogate pair
+        fn unicode_escape(&mut self) -> Option<char> {
+            let high = self.hex4()?;
+            if !(0xD800..0xDC00).contains(&high) {
+                return char::from_u32(high);
+            }
+            if !self.s[self.pos..].starts_with(b"\\u") {
+                return None;
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return None;
+            }
+            char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+        }
+
+        fn hex4(&mut self) -> Option<u32> {
+            let digits = self.s.get(self.pos..self.pos + 4)?;
+            if !digits.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            self.pos += 4;
+            u32::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()
+        }
+    }
+
+    /// Writes one JSON object, fields in the order they are added
+    pub struct Object {
+        out: String,
+    }
+
+    impl Object {
+        /// Start an object whose first field is `"type": kind`
+        pub fn new(kind: &str) -> Self {
+            let mut out = String::from("{");
+            write_str(&mut out, "type");
+            out.push(':');
+            write_str(&mut out, kind);
+            Self { out }
+        }
+
+        fn key(&mut self, key: &str) {
+            if self.out.len() > 1 {
+                self.out.push(',');
+            }
+            write_str(&mut self.out, key);
+            self.out.push(':');
+        }
+
+        pub fn str(&mut self, key: &str, value: &str) {
+            self.key(key);
+            write_str(&mut self.out, value);
+        }
+
+        pub fn int(&mut self, key: &str, value: i64) {
+            self.key(key);
+            let _ = write!(self.out, "{}", value);
+        }
+
+        pub fn bool(&mut self, key: &str, value: bool) {
+            self.key(key);
+            self.out.push_str(if value { "true" } else { "false" });
+        }
+
+        /// An array of objects, each filled in by `f`
+        pub fn array<T>(&mut self, key: &str, items: &[T], mut f: impl FnMut(&mut Object, &T)) {
+            self.key(key);
+            self.out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let mut object = Object { out: String::from("{") };
+                f(&mut object, item);
+                self.out.push_str(&object.finish());
+            }
+            self.out.push(']');
+        }
+
+        pub fn finish(mut self) -> String {
+            self.out.push('}');
+            self.out
+        }
+    }
+
+    fn write_str(out: &mut String, s: &str) {
+        out.push('"');
+        for ch in s.chars() {
+            match ch {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => {
+                    let _ = write!(out, "\\u{:04x}", c as u32);
+                }
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
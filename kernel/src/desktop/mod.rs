@@ -2,16 +2,44 @@
 //!
 //! HTML-based desktop with window manager, taskbar, and applications.
 
-use alloc::string::String;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::format;
 use alloc::collections::BTreeMap;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
+use crate::browser;
+use crate::syscall::filter::SyscallFilter;
+use crate::config;
+use crate::fs;
+use crate::graphics::compositor::Rect;
+use crate::graphics::cursor::CursorShape;
+use crate::drivers::input::{self, EventType, InputEvent, Layout, MouseButton, MOD_ALT, MOD_CTRL, MOD_SUPER};
 use crate::println;
+use crate::process;
 use crate::users::{self, User};
+use webbos_shared::types::Pid;
+use consent::ConsentPrompt;
+use dialog::{DialogMode, FileDialog, Outcome};
+use permissions::Capability;
+use vesa_login::LockScreen;
+use widgets::{NativeApp, NativeConstructor, WidgetKind};
+use hotkeys::Action;
+use crate::{debug, info, warn};
 
+pub mod consent;
+pub mod dialog;
+pub mod hotkeys;
+pub mod ipc;
+pub mod notifications;
+pub mod packages;
+pub mod paint;
+pub mod permissions;
+pub mod terminal;
+pub mod widgets;
 pub mod vesa_login;
 
 /// Window ID
@@ -29,6 +57,76 @@ pub enum WindowState {
     Focused,
 }
 
+/// Dirty rectangles kept before the whole desktop is invalidated instead
+const MAX_DIRTY_RECTS: usize = 32;
+
+/// The RTC counts seconds of the day
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Pixels of a window that must stay on screen when it is moved
+const WINDOW_MIN_VISIBLE: i32 = 40;
+
+/// Width of the frame inside a window's edge that resizes it when dragged
+const RESIZE_BORDER: i32 = 6;
+
+/// Rows a window's content scrolls by for each notch of the wheel
+const WHEEL_SCROLL: i32 = 48;
+
+/// Rows a window's content scrolls by for an arrow key
+const KEY_SCROLL: i32 = 24;
+
+/// Most bookmarks the start menu lists, the newest
+const MENU_BOOKMARKS: usize = 8;
+
+/// Smallest size a window can be resized to
+const WINDOW_MIN_WIDTH: u32 = 160;
+const WINDOW_MIN_HEIGHT: u32 = 100;
+
+/// Window edges, combined in `WindowPart::Border`
+pub const EDGE_LEFT: u8 = 0x01;
+pub const EDGE_RIGHT: u8 = 0x02;
+pub const EDGE_TOP: u8 = 0x04;
+pub const EDGE_BOTTOM: u8 = 0x08;
+
+/// Part of a window under the pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowPart {
+    TitleBar,
+    CloseButton,
+    MaximizeButton,
+    MinimizeButton,
+    Content,
+    /// Resize frame; the value holds the `EDGE_*` bits being grabbed
+    Border(u8),
+}
+
+/// What a start menu entry does
+#[derive(Debug, Clone)]
+enum MenuAction {
+    Launch(String),
+    /// Open a bookmarked page in the browser
+    OpenUrl(String),
+    Logout,
+}
+
+/// What a held mouse button is doing
+#[derive(Debug, Clone, Copy)]
+enum PointerGrab {
+    /// Dragging a window by its title bar; the pointer stays at
+    /// (`dx`, `dy`) from the window's origin
+    Move { window: WindowId, dx: i32, dy: i32 },
+    /// Dragging the given edges from where the drag started
+    Resize { window: WindowId, edges: u8, start_x: i32, start_y: i32, start: Rect },
+    /// Pressed a title bar button; it fires if released over the same one
+    Button { window: WindowId, part: WindowPart },
+    /// Dragging the thumb of the window's scrollbar, or of a scrollbar
+    /// widget of its app; the pointer stays `dy` below the thumb's top
+    Scroll { window: WindowId, widget: Option<u32>, dy: i32 },
+    /// Pressed a widget of the window's app, which follows the pointer
+    /// until the button is released, wherever it goes
+    Widget { window: WindowId, widget: u32 },
+}
+
 /// Window structure
 #[derive(Debug, Clone)]
 pub struct Window {
@@ -43,6 +141,23 @@ pub struct Window {
     pub z_index: u32,
     pub content: String, // HTML content
     pub icon: char, // Unicode icon
+    pub restore: Option<Rect>, // Geometry to return to when un-maximized
+    pub pid: Option<Pid>, // Process the app runs as
+    pub scroll_y: u32, // Content row at the top of the body, if it overflows
+}
+
+impl Window {
+    /// Area covered, in virtual desktop coordinates
+    pub fn rect(&self) -> Rect {
+        Rect::new(self.x, self.y, self.width, self.height)
+    }
+
+    fn set_rect(&mut self, r: Rect) {
+        self.x = r.x;
+        self.y = r.y;
+        self.width = r.w;
+        self.height = r.h;
+    }
 }
 
 /// Application structure
@@ -57,6 +172,8 @@ pub struct Application {
     pub css_styles: String,
     pub js_scripts: String,
     pub singleton: bool, // Only one instance allowed
+    pub native: Option<NativeConstructor>, // Drawn with widgets instead of its HTML
+    pub capabilities: Option<Vec<Capability>>, // What an installed app declared; None for built-in apps
 }
 
 /// Desktop item (icon on desktop)
@@ -71,6 +188,11 @@ pub struct DesktopItem {
     pub path: String,
 }
 
+/// UTC time as seconds since midnight
+fn seconds_of_day() -> u32 {
+    crate::drivers::rtc::now().seconds_of_day()
+}
+
 /// Desktop manager
 pub struct DesktopManager {
     windows: BTreeMap<WindowId, Window>,
@@ -80,13 +202,32 @@ pub struct DesktopManager {
     next_app_id: AppId,
     next_item_id: u32,
     active_window: Option<WindowId>,
-    wallpaper: String,
+    wallpaper: &'static str, // Name of one of paint::WALLPAPERS
+    theme: &'static str, // Name of one of paint::THEMES
     current_user: Option<User>,
     show_login: bool,
     show_desktop: bool,
     screen_width: u32,
     screen_height: u32,
+    displays: Vec<Rect>, // Virtual desktop area of each display
     taskbar_height: u32,
+    dirty: Vec<Rect>, // Regions changed since the last redraw
+    grab: Option<PointerGrab>, // Drag in progress with the left button
+    start_menu: bool,
+    notification_panel: bool,
+    dialog: Option<FileDialog>, // File chooser an app opened; modal
+    consent: Option<ConsentPrompt>, // Permission prompt for an installed app; modal
+    switcher: Option<(Vec<WindowId>, usize)>, // Alt+Tab list and the window picked
+    super_alone: bool, // Super is down and no shortcut used it yet
+    desktop_shown: Vec<WindowId>, // Windows Show Desktop minimized, bottom first
+    menu_selected: usize, // Start menu entry chosen with the arrow keys
+    clock: String, // Time shown on the taskbar, HH:MM
+    battery: Option<paint::BatteryChrome>, // Charge shown on the taskbar
+    lock: Option<LockScreen>, // Password prompt while the session is locked
+    input_seen: bool, // Input arrived since the last tick
+    last_input: u32, // RTC second of the day input last arrived
+    lock_timeout: u32, // Idle minutes before the session locks; 0 never
+    native: BTreeMap<WindowId, Box<dyn NativeApp>>, // Widget apps by window
 }
 
 impl DesktopManager {
@@ -100,13 +241,32 @@ impl DesktopManager {
             next_app_id: 1,
             next_item_id: 1,
             active_window: None,
-            wallpaper: String::from("/system/wallpapers/default.jpg"),
+            wallpaper: "default",
+            theme: "light",
             current_user: None,
             show_login: true,
             show_desktop: false,
             screen_width: 1024,
             screen_height: 768,
+            displays: vec![Rect::new(0, 0, 1024, 768)],
             taskbar_height: 40,
+            dirty: Vec::new(),
+            grab: None,
+            start_menu: false,
+            notification_panel: false,
+            dialog: None,
+            consent: None,
+            switcher: None,
+            super_alone: false,
+            desktop_shown: Vec::new(),
+            menu_selected: 0,
+            clock: String::new(),
+            battery: None,
+            lock: None,
+            input_seen: false,
+            last_input: seconds_of_day(),
+            lock_timeout: 5,
+            native: BTreeMap::new(),
         };
         
         // Register built-in applications
@@ -131,6 +291,8 @@ impl DesktopManager {
             css_styles: get_filemanager_css(),
             js_scripts: get_filemanager_js(),
             singleton: false,
+            native: None,
+            capabilities: None,
         });
         
         // Notepad
@@ -144,6 +306,8 @@ impl DesktopManager {
             css_styles: get_notepad_css(),
             js_scripts: get_notepad_js(),
             singleton: false,
+            native: None,
+            capabilities: None,
         });
         
         // Paint
@@ -157,6 +321,8 @@ impl DesktopManager {
             css_styles: get_paint_css(),
             js_scripts: get_paint_js(),
             singleton: false,
+            native: None,
+            capabilities: None,
         });
         
         // Task Manager
@@ -170,6 +336,8 @@ impl DesktopManager {
             css_styles: get_taskmanager_css(),
             js_scripts: get_taskmanager_js(),
             singleton: true,
+            native: None,
+            capabilities: None,
         });
         
         // User Manager
@@ -183,6 +351,8 @@ impl DesktopManager {
             css_styles: get_usermanager_css(),
             js_scripts: get_usermanager_js(),
             singleton: true,
+            native: None,
+            capabilities: None,
         });
         
         // Terminal
@@ -196,6 +366,8 @@ impl DesktopManager {
             css_styles: get_terminal_css(),
             js_scripts: get_terminal_js(),
             singleton: false,
+            native: None,
+            capabilities: None,
         });
         
         // Web Browser
@@ -205,20 +377,77 @@ impl DesktopManager {
             title: String::from("WebbBrowser"),
             icon: '🌐',
             description: String::from("Browse the web"),
-            html_content: get_browser_html(),
-            css_styles: get_browser_css(),
-            js_scripts: get_browser_js(),
+            html_content: String::new(),
+            css_styles: String::new(),
+            js_scripts: String::new(),
             singleton: false,
+            native: Some(widgets::browser::new),
+            capabilities: None,
         });
+        
+        // Settings
+        self.register_app(Application {
+            id: 0,
+            name: String::from("settings"),
+            title: String::from("Settings"),
+            icon: '⚙',
+            description: String::from("Display and system settings"),
+            html_content: get_settings_html(),
+            css_styles: get_settings_css(),
+            js_scripts: get_settings_js(),
+            singleton: true,
+            native: None,
+            capabilities: None,
+        });
+
+        // Native apps, drawn with widgets
+        for (name, title, icon, description, native) in [
+            ("calculator", "Calculator", '🧮', "Four-function calculator", widgets::calculator::new as NativeConstructor),
+            ("clock", "Clock", '🕒', "Current time and date", widgets::clock::new),
+            ("sysmonitor", "System Monitor", '📈', "Live memory graph", widgets::monitor::new),
+        ] {
+            self.register_app(Application {
+                id: 0,
+                name: String::from(name),
+                title: String::from(title),
+                icon,
+                description: String::from(description),
+                html_content: String::new(),
+                css_styles: String::new(),
+                js_scripts: String::new(),
+                singleton: false,
+                native: Some(native),
+                capabilities: None,
+            });
+        }
     }
     
-    /// Register an application
-    fn register_app(&mut self, mut app: Application) {
+    /// Register an application; it is listed in the start menu from then on
+    pub fn register_app(&mut self, mut app: Application) -> AppId {
+        // The menu changes size
+        self.close_start_menu();
         let id = self.next_app_id;
         self.next_app_id += 1;
         app.id = id;
-        println!("[desktop] Registered app: {} ({})", app.name, app.title);
+        debug!("desktop", "Registered app: {} ({})", app.name, app.title);
         self.applications.insert(id, app);
+        id
+    }
+
+    /// Remove the application called `name`, closing its windows
+    pub fn unregister_app(&mut self, name: &str) -> bool {
+        let id = match self.applications.iter().find(|(_, a)| a.name == name) {
+            Some((&id, _)) => id,
+            None => return false,
+        };
+        let windows: Vec<WindowId> = self.windows.values().filter(|w| w.app_id == id).map(|w| w.id).collect();
+        for window in windows {
+            self.close_window(window);
+        }
+        self.close_start_menu();
+        self.applications.remove(&id);
+        info!("desktop", "Unregistered app: {}", name);
+        true
     }
     
     /// Create default desktop items
@@ -257,8 +486,11 @@ impl DesktopManager {
         self.next_item_id += 1;
     }
     
-    /// Launch an application
-    pub fn launch_app(&mut self, app_id: AppId) -> Option<WindowId> {
+    /// Launch an application, as a child of `parent` if given
+    ///
+    /// The app's process gets a copy of its parent's environment, or else
+    /// starts in the logged-in user's home directory.
+    pub fn launch_app(&mut self, app_id: AppId, parent: Option<Pid>) -> Option<WindowId> {
         // Check if singleton app already running
         if let Some(app) = self.applications.get(&app_id) {
             if app.singleton {
@@ -273,89 +505,1401 @@ impl DesktopManager {
             let window_id = self.next_window_id;
             self.next_window_id += 1;
             
-            // Calculate window position (cascade)
+            // Cascade on the display the pointer is over
+            let (px, py) = input::mouse_position();
+            let screen = crate::graphics::display::display_at(px, py).map_or(self.displays[0], |d| d.rect);
             let offset = (self.windows.len() as i32 * 30) % 200;
-            let x = 100 + offset;
-            let y = 50 + offset;
+            let x = screen.x + 100 + offset;
+            let y = screen.y + 50 + offset;
             
+            // Each window's app runs as a process of its own, so the task
+            // manager can show and end it. What shows web content gets
+            // only the syscalls a renderer needs.
+            let filter = (app.name == "browser" || !app.html_content.is_empty()).then(SyscallFilter::renderer);
+            let environment = match parent {
+                Some(parent) => process::environment(parent).unwrap_or_default(),
+                None => self.current_user.as_ref().map_or_else(process::Environment::new, |u| {
+                    process::Environment::login(&u.username, &u.home_directory)
+                }),
+            };
+            let pid = process::exec(&app.name, parent, environment, filter).ok();
+            // HTML apps run in the browser engine, drawn as a page
+            let native = match app.native {
+                Some(new) => Some(new()),
+                None if !app.html_content.is_empty() => Some(widgets::webapp::new(window_id, app)),
+                None => None,
+            };
+            if let Some(pid) = pid {
+                let held = native.as_ref().map_or(0, |n| core::mem::size_of_val(&**n));
+                crate::mm::charge(pid, (app.html_content.len() + held) as u64);
+            }
+            let (width, height) = native.as_ref().map_or((800, 600), |n| {
+                let (w, h) = n.size();
+                (w, h + paint::TITLE_BAR_HEIGHT)
+            });
             let window = Window {
                 id: window_id,
                 app_id,
                 title: app.title.clone(),
                 x,
                 y,
-                width: 800,
-                height: 600,
+                width,
+                height,
                 state: WindowState::Focused,
                 z_index: self.windows.len() as u32 + 1,
                 content: app.html_content.clone(),
                 icon: app.icon,
+                restore: None,
+                pid,
+                scroll_y: 0,
             };
             
-            println!("[desktop] Launched {} (window {})", app.name, window_id);
+            info!("desktop", "Launched {} (window {})", app.name, window_id);
+            self.invalidate_window(self.active_window);
             self.windows.insert(window_id, window);
+            if let Some(native) = native {
+                self.native.insert(window_id, native);
+            }
             self.active_window = Some(window_id);
+            self.invalidate_window(Some(window_id));
+            self.invalidate(self.taskbar_rect());
             
             Some(window_id)
         } else {
             None
         }
     }
-    
-    /// Launch app by name
-    pub fn launch_app_by_name(&mut self, name: &str) -> Option<WindowId> {
-        if let Some((id, _)) = self.applications.iter().find(|(_, a)| a.name == name) {
-            self.launch_app(*id)
-        } else {
-            None
+    
+    /// Open `url` in the topmost Browser window, or a new one if there is
+    /// none, and return the window's title
+    pub fn open_url(&mut self, url: &str) -> Result<String, String> {
+        let browser = self.applications.values().find(|a| a.name == "browser").map(|a| a.id);
+        let existing = self.windows.values()
+            .filter(|w| Some(w.app_id) == browser)
+            .max_by_key(|w| w.z_index)
+            .map(|w| w.id);
+        let id = match existing {
+            Some(id) => id,
+            None => self.launch_app_by_name("browser", None).ok_or_else(|| String::from("No browser app"))?,
+        };
+        let result = match self.native.get_mut(&id) {
+            Some(app) => app.open(url),
+            None => Err(String::from("The browser app has no window")),
+        };
+        self.native_changed(id);
+        self.focus_window(id);
+        result.map(|()| self.windows[&id].title.clone())
+    }
+
+    /// Launch app by name
+    pub fn launch_app_by_name(&mut self, name: &str, parent: Option<Pid>) -> Option<WindowId> {
+        if let Some((id, _)) = self.applications.iter().find(|(_, a)| a.name == name) {
+            self.launch_app(*id, parent)
+        } else {
+            None
+        }
+    }
+    
+    /// Close window
+    pub fn close_window(&mut self, window_id: WindowId) -> bool {
+        self.invalidate_window(Some(window_id));
+        if let Some(window) = self.windows.remove(&window_id) {
+            ipc::forget(window_id);
+            if let Some(pid) = window.pid {
+                let _ = process::terminate(pid);
+            }
+            self.native.remove(&window_id);
+            if self.dialog.as_ref().map(|d| d.owner) == Some(window_id) {
+                self.invalidate_dialog();
+                self.dialog = None;
+            }
+            if self.consent.as_ref().map(|p| p.owner) == Some(window_id) {
+                self.invalidate_consent();
+                self.consent = None;
+            }
+            self.close_switcher(false);
+            if self.active_window == Some(window_id) {
+                // Focus next window
+                self.active_window = self.windows.keys().last().copied();
+                self.invalidate_window(self.active_window);
+            }
+            self.invalidate(self.taskbar_rect());
+            debug!("desktop", "Closed window {}", window_id);
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Focus window
+    pub fn focus_window(&mut self, window_id: WindowId) {
+        if self.windows.contains_key(&window_id) {
+            let new_z = self.get_max_z_index() + 1;
+            if let Some(window) = self.windows.get_mut(&window_id) {
+                if window.state != WindowState::Maximized {
+                    window.state = WindowState::Focused;
+                }
+                window.z_index = new_z;
+            }
+            self.invalidate_window(self.active_window);
+            self.active_window = Some(window_id);
+            self.invalidate_window(Some(window_id));
+            self.invalidate(self.taskbar_rect());
+        }
+    }
+    
+    /// Get max z-index
+    fn get_max_z_index(&self) -> u32 {
+        self.windows.values().map(|w| w.z_index).max().unwrap_or(0)
+    }
+    
+    /// Minimize window
+    ///
+    /// If it was active, focus passes to the topmost visible window.
+    pub fn minimize_window(&mut self, window_id: WindowId) {
+        self.invalidate_window(Some(window_id));
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            window.state = WindowState::Minimized;
+        }
+        if self.active_window == Some(window_id) {
+            let next = self.windows.values()
+                .filter(|w| w.state != WindowState::Minimized)
+                .max_by_key(|w| w.z_index)
+                .map(|w| w.id);
+            self.active_window = None;
+            if let Some(id) = next {
+                self.focus_window(id);
+            }
+            self.invalidate(self.taskbar_rect());
+        }
+    }
+    
+    /// Maximize/restore window
+    ///
+    /// A maximized window fills the display it is mostly on, less the
+    /// taskbar on the primary display.
+    pub fn maximize_window(&mut self, window_id: WindowId) {
+        let area = match self.windows.get(&window_id) {
+            Some(window) => self.work_area(self.display_of(window)),
+            None => return,
+        };
+        self.invalidate_window(Some(window_id));
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            if window.state == WindowState::Maximized {
+                window.state = WindowState::Normal;
+                if let Some(r) = window.restore.take() {
+                    window.set_rect(r);
+                }
+            } else {
+                window.state = WindowState::Maximized;
+                let r = window.rect();
+                window.restore.get_or_insert(r);
+                window.set_rect(area);
+            }
+        }
+        self.invalidate_window(Some(window_id));
+    }
+
+    /// Fit a window to the left or right half of its display's work area
+    ///
+    /// Restoring it, or dragging it away, brings back its earlier size.
+    pub fn snap_window(&mut self, window_id: WindowId, left: bool) {
+        let area = match self.windows.get(&window_id) {
+            Some(window) => self.work_area(self.display_of(window)),
+            None => return,
+        };
+        let half = area.w / 2;
+        let rect = if left {
+            Rect::new(area.x, area.y, half, area.h)
+        } else {
+            Rect::new(area.x + half as i32, area.y, area.w - half, area.h)
+        };
+        self.invalidate_window(Some(window_id));
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            if window.state == WindowState::Maximized {
+                window.state = WindowState::Normal;
+            }
+            let r = window.rect();
+            window.restore.get_or_insert(r);
+            window.set_rect(rect);
+        }
+        self.invalidate_window(Some(window_id));
+    }
+
+    /// Undo a maximize or snap; a window with neither is minimized
+    pub fn restore_window(&mut self, window_id: WindowId) {
+        let (maximized, snapped) = match self.windows.get(&window_id) {
+            Some(w) => (w.state == WindowState::Maximized, w.restore.is_some()),
+            None => return,
+        };
+        if maximized {
+            self.maximize_window(window_id);
+        } else if snapped {
+            self.invalidate_window(Some(window_id));
+            if let Some(window) = self.windows.get_mut(&window_id) {
+                if let Some(r) = window.restore.take() {
+                    window.set_rect(r);
+                }
+            }
+            self.invalidate_window(Some(window_id));
+        } else {
+            self.minimize_window(window_id);
+        }
+    }
+
+    /// Minimize every window; if none is showing, bring back the ones
+    /// this minimized last time
+    pub fn toggle_show_desktop(&mut self) {
+        let mut visible: Vec<&Window> = self.windows.values()
+            .filter(|w| w.state != WindowState::Minimized)
+            .collect();
+        if visible.is_empty() {
+            // Bottom first, so the previously active window ends up on top
+            for id in core::mem::take(&mut self.desktop_shown) {
+                if self.windows.get(&id).map_or(false, |w| w.state == WindowState::Minimized) {
+                    self.focus_window(id);
+                }
+            }
+            return;
+        }
+        visible.sort_by_key(|w| w.z_index);
+        let ids: Vec<WindowId> = visible.into_iter().map(|w| w.id).collect();
+        for &id in &ids {
+            self.minimize_window(id);
+        }
+        self.desktop_shown = ids;
+    }
+
+    /// Move a window's top-left corner to (x, y) in virtual desktop
+    /// coordinates, which may put it on another display
+    ///
+    /// The title bar is kept on screen. Moving a maximized or snapped
+    /// window restores its size first.
+    pub fn move_window(&mut self, window_id: WindowId, x: i32, y: i32) -> bool {
+        let (bounds_w, bounds_h) = (self.screen_width as i32, self.screen_height as i32);
+        let before = match self.windows.get(&window_id) {
+            Some(window) => self.display_of(window),
+            None => return false,
+        };
+        self.invalidate_window(Some(window_id));
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            if window.state == WindowState::Maximized {
+                window.state = WindowState::Normal;
+            }
+            if let Some(r) = window.restore.take() {
+                window.width = r.w;
+                window.height = r.h;
+            }
+            window.x = x.max(WINDOW_MIN_VISIBLE - window.width as i32).min(bounds_w - WINDOW_MIN_VISIBLE);
+            window.y = y.min(bounds_h - WINDOW_MIN_VISIBLE).max(0);
+        }
+        self.invalidate_window(Some(window_id));
+        let after = self.window_display(window_id).unwrap_or(before);
+        if after != before {
+            debug!("desktop", "Window {} moved to display {}", window_id, after);
+        }
+        true
+    }
+
+    /// Change a window's title, as shown in its title bar and taskbar button
+    pub fn set_window_title(&mut self, window_id: WindowId, title: &str) {
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            window.title = String::from(title);
+        }
+        self.invalidate_window(Some(window_id));
+        self.invalidate(self.taskbar_rect());
+    }
+
+    /// Give a window a new position and size
+    ///
+    /// The size is held between the minimum window size and the work area
+    /// of the window's display; the position is left as given.
+    pub fn resize_window(&mut self, window_id: WindowId, rect: Rect) -> bool {
+        let (max_w, max_h) = match self.windows.get(&window_id) {
+            Some(window) => {
+                let area = self.work_area(self.display_of(window));
+                (area.w.max(WINDOW_MIN_WIDTH), area.h.max(WINDOW_MIN_HEIGHT))
+            }
+            None => return false,
+        };
+        self.invalidate_window(Some(window_id));
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            if window.state == WindowState::Maximized {
+                window.state = WindowState::Normal;
+            }
+            window.restore = None;
+            window.set_rect(Rect::new(
+                rect.x,
+                rect.y,
+                rect.w.max(WINDOW_MIN_WIDTH).min(max_w),
+                rect.h.max(WINDOW_MIN_HEIGHT).min(max_h),
+            ));
+        }
+        self.invalidate_window(Some(window_id));
+        true
+    }
+
+    /// Area of a display windows may fill: all of it, less the taskbar on
+    /// the primary
+    fn work_area(&self, display: usize) -> Rect {
+        let mut area = self.displays[display];
+        if display == 0 {
+            area.h = area.h.saturating_sub(self.taskbar_height);
+        }
+        area
+    }
+
+    /// Display a window is mostly on
+    pub fn window_display(&self, window_id: WindowId) -> Option<usize> {
+        self.windows.get(&window_id).map(|w| self.display_of(w))
+    }
+
+    fn display_of(&self, window: &Window) -> usize {
+        crate::graphics::display::display_for(&window.rect())
+            .map_or(0, |d| d.id.min(self.displays.len() - 1))
+    }
+
+    /// Take the display layout from the graphics subsystem
+    ///
+    /// Windows left on a display that went away are pulled back on screen.
+    fn sync_displays(&mut self) {
+        let displays = crate::graphics::display::list();
+        if displays.is_empty() {
+            return;
+        }
+        self.displays = displays.iter().map(|d| d.rect).collect();
+        let bounds = crate::graphics::display::virtual_bounds();
+        self.screen_width = bounds.w;
+        self.screen_height = bounds.h;
+
+        let ids: Vec<WindowId> = self.windows.keys().copied().collect();
+        for id in ids {
+            let (x, y) = (self.windows[&id].x, self.windows[&id].y);
+            self.move_window(id, x, y);
+        }
+        self.invalidate_all();
+    }
+
+    /// Record a changed region for the next `redraw`
+    fn invalidate(&mut self, rect: Rect) {
+        if self.dirty.len() >= MAX_DIRTY_RECTS {
+            return self.invalidate_all();
+        }
+        self.dirty.push(rect);
+    }
+
+    /// Record that the area under a window (and its shadow) changed
+    fn invalidate_window(&mut self, window_id: Option<WindowId>) {
+        let rect = match window_id.and_then(|id| self.windows.get(&id)) {
+            Some(window) => paint::window_extent(window.rect()),
+            None => return,
+        };
+        self.invalidate(rect);
+    }
+
+    fn invalidate_dialog(&mut self) {
+        if let Some(rect) = self.dialog.as_ref().map(|d| d.extent()) {
+            self.invalidate(rect);
+        }
+    }
+
+    fn invalidate_consent(&mut self) {
+        if let Some(rect) = self.consent.as_ref().map(|p| p.extent()) {
+            self.invalidate(rect);
+        }
+    }
+
+    /// Whether a file chooser or permission prompt is taking all input
+    fn modal(&self) -> bool {
+        self.dialog.is_some() || self.consent.is_some()
+    }
+
+    fn invalidate_all(&mut self) {
+        self.dirty.clear();
+        self.dirty.push(Rect::new(0, 0, self.screen_width, self.screen_height));
+    }
+
+    fn taskbar_rect(&self) -> Rect {
+        paint::taskbar_rect(self.displays[0], self.taskbar_height)
+    }
+
+    /// Start menu entries: every application, the newest bookmarks, then
+    /// logging out
+    fn menu_entries(&self) -> Vec<(String, MenuAction)> {
+        let mut entries: Vec<(String, MenuAction)> = self.applications.values()
+            .map(|app| (app.title.clone(), MenuAction::Launch(app.name.clone())))
+            .collect();
+        let bookmarks = browser::bookmarks::list();
+        let newest = bookmarks.len().saturating_sub(MENU_BOOKMARKS);
+        entries.extend(bookmarks.into_iter().skip(newest).map(|b| {
+            let label = if b.title.is_empty() { &b.url } else { &b.title };
+            (format!("* {}", label), MenuAction::OpenUrl(b.url))
+        }));
+        entries.push((String::from("Log out"), MenuAction::Logout));
+        entries
+    }
+
+    fn start_menu_rect(&self) -> Rect {
+        paint::start_menu_rect(self.taskbar_rect(), self.menu_entries().len())
+    }
+
+    /// Open or close the start menu
+    pub fn toggle_start_menu(&mut self) {
+        self.close_notification_panel();
+        self.start_menu = !self.start_menu;
+        self.menu_selected = 0;
+        self.invalidate(self.start_menu_rect());
+        self.invalidate(paint::start_button_rect(self.taskbar_rect()));
+    }
+
+    fn close_start_menu(&mut self) {
+        if self.start_menu {
+            self.toggle_start_menu();
+        }
+    }
+
+    /// Open or close the notification panel; opening it marks every
+    /// notification as read
+    pub fn toggle_notification_panel(&mut self) {
+        self.close_start_menu();
+        self.notification_panel = !self.notification_panel;
+        if self.notification_panel {
+            notifications::mark_all_read();
+        }
+        self.invalidate(paint::notification_panel_rect(self.taskbar_rect()));
+    }
+
+    fn close_notification_panel(&mut self) {
+        if self.notification_panel {
+            self.toggle_notification_panel();
+        }
+    }
+
+    /// Redraw everything that shows notifications
+    fn invalidate_notifications(&mut self) {
+        let bar = self.taskbar_rect();
+        self.invalidate(paint::toasts_extent(self.displays[0], notifications::MAX_TOASTS));
+        self.invalidate(paint::notification_button_rect(bar));
+        if self.notification_panel {
+            self.invalidate(paint::notification_panel_rect(bar));
+        }
+    }
+
+    /// Highlight the start menu entry `delta` places from the current one
+    fn select_menu_entry(&mut self, delta: i32) {
+        let count = self.menu_entries().len() as i32;
+        self.menu_selected = (self.menu_selected as i32 + delta).rem_euclid(count) as usize;
+        self.invalidate(self.start_menu_rect());
+    }
+
+    /// Close the start menu and run entry `index`
+    fn activate_menu_entry(&mut self, index: usize) {
+        let action = self.menu_entries().into_iter().nth(index).map(|(_, action)| action);
+        self.close_start_menu();
+        match action {
+            Some(MenuAction::Launch(name)) => {
+                self.launch_app_by_name(&name, None);
+            }
+            Some(MenuAction::OpenUrl(url)) => {
+                if let Err(e) = self.open_url(&url) {
+                    warn!("desktop", "Cannot open {}: {}", url, e);
+                }
+            }
+            Some(MenuAction::Logout) => self.logout(),
+            None => {}
+        }
+    }
+
+    /// Window IDs in taskbar order, the order they were opened
+    fn task_windows(&self) -> Vec<WindowId> {
+        self.windows.keys().copied().collect()
+    }
+
+    /// Taskbar button pressed: the active window is minimized, any other is
+    /// restored and focused
+    fn activate_task(&mut self, window_id: WindowId) {
+        let minimized = self.windows.get(&window_id).map(|w| w.state == WindowState::Minimized);
+        match minimized {
+            Some(false) if self.active_window == Some(window_id) => self.minimize_window(window_id),
+            Some(_) => self.focus_window(window_id),
+            None => {}
+        }
+    }
+
+    /// Press on the taskbar: the start button, the notification button or
+    /// a window's button
+    fn taskbar_press(&mut self, x: i32, y: i32) {
+        let bar = self.taskbar_rect();
+        if paint::start_button_rect(bar).contains(x, y) {
+            self.toggle_start_menu();
+            return;
+        }
+        if paint::notification_button_rect(bar).contains(x, y) {
+            self.toggle_notification_panel();
+            return;
+        }
+        self.close_start_menu();
+        self.close_notification_panel();
+        let hit = self.task_windows().into_iter().enumerate()
+            .find(|&(i, _)| paint::task_rect(bar, i).map_or(false, |r| r.contains(x, y)));
+        if let Some((_, id)) = hit {
+            self.activate_task(id);
+        }
+    }
+
+    /// Switch to the wallpaper called `name`; false if there is none
+    pub fn set_wallpaper(&mut self, name: &str) -> bool {
+        match paint::WALLPAPERS.iter().find(|(n, _)| *n == name) {
+            Some((name, _)) => {
+                self.wallpaper = name;
+                self.invalidate_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Switch to the theme called `name`; false if there is none
+    pub fn set_theme(&mut self, name: &str) -> bool {
+        match paint::theme(name) {
+            Some(theme) => {
+                self.theme = theme.name;
+                self.invalidate_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Show a file chooser for `owner`, centred on the primary display
+    ///
+    /// It starts in the user's home directory. Returns false if a dialog
+    /// is already open; only one can be up at a time.
+    pub fn open_file_dialog(&mut self, owner: WindowId, mode: DialogMode, filter: &str) -> bool {
+        if self.modal() || !self.windows.contains_key(&owner) {
+            return false;
+        }
+        self.close_start_menu();
+        self.close_notification_panel();
+        self.grab = None;
+        self.focus_window(owner);
+        let home = self.current_user.as_ref().map_or("/", |u| u.home_directory.as_str());
+        self.dialog = Some(FileDialog::new(owner, mode, filter, home, self.work_area(0)));
+        self.invalidate_dialog();
+        true
+    }
+
+    /// Apply what handling an input did to the dialog
+    ///
+    /// When it closes, its reply goes to the window that opened it.
+    fn dialog_outcome(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Open { changed } => {
+                if changed {
+                    self.invalidate_dialog();
+                }
+            }
+            Outcome::Closed(response) => {
+                self.invalidate_dialog();
+                if let Some(dialog) = self.dialog.take() {
+                    ipc::send(dialog.owner, response);
+                }
+            }
+        }
+    }
+
+    /// Ask the user whether the app in `owner` may use `capability`,
+    /// carrying out `request` if they allow it
+    ///
+    /// Returns false if a prompt or file chooser is already open.
+    pub fn ask_consent(&mut self, owner: WindowId, capability: Capability, request: ipc::Request) -> bool {
+        if self.modal() {
+            return false;
+        }
+        let app = match self.windows.get(&owner).and_then(|w| self.applications.get(&w.app_id)) {
+            Some(app) => app,
+            None => return false,
+        };
+        let prompt = ConsentPrompt::new(owner, &app.name, &app.title, capability, request, self.work_area(0));
+        self.close_start_menu();
+        self.close_notification_panel();
+        self.grab = None;
+        self.focus_window(owner);
+        self.consent = Some(prompt);
+        self.invalidate_consent();
+        true
+    }
+
+    /// Keep the user's answer to the permission prompt and close it
+    ///
+    /// An allowed request is carried out once the desktop is next ticked;
+    /// a refused one is answered with `permission_denied`.
+    fn consent_answered(&mut self, allowed: bool) {
+        self.invalidate_consent();
+        let prompt = match self.consent.take() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        permissions::record(&prompt.app, &prompt.capability, allowed);
+        if allowed {
+            ipc::retry(prompt.owner, prompt.request);
+        } else {
+            ipc::send(prompt.owner, ipc::Response::PermissionDenied {
+                request: prompt.request.kind().to_string(),
+                capability: prompt.capability.to_string(),
+            });
+        }
+    }
+
+    /// Windows in switcher order: showing windows from the top of the
+    /// stack down, then minimized ones
+    fn switcher_windows(&self) -> Vec<WindowId> {
+        let mut windows: Vec<&Window> = self.windows.values().collect();
+        windows.sort_by_key(|w| (w.state == WindowState::Minimized, core::cmp::Reverse(w.z_index)));
+        windows.into_iter().map(|w| w.id).collect()
+    }
+
+    fn invalidate_switcher(&mut self) {
+        if let Some(n) = self.switcher.as_ref().map(|(ids, _)| ids.len()) {
+            self.invalidate(paint::switcher_rect(self.displays[0], n));
+        }
+    }
+
+    /// Open the window switcher on the window after the active one, or
+    /// move its selection forwards or backwards
+    fn step_switcher(&mut self, forward: bool) {
+        self.invalidate_switcher();
+        let (windows, selected) = match self.switcher.take() {
+            Some((windows, selected)) => {
+                let n = windows.len();
+                let next = if forward { (selected + 1) % n } else { (selected + n - 1) % n };
+                (windows, next)
+            }
+            None => {
+                let windows = self.switcher_windows();
+                let n = windows.len();
+                if n == 0 {
+                    return;
+                }
+                self.close_start_menu();
+                self.close_notification_panel();
+                (windows, if forward { 1 % n } else { n - 1 })
+            }
+        };
+        self.switcher = Some((windows, selected));
+        self.invalidate_switcher();
+    }
+
+    /// Close the window switcher, focusing the window picked if `commit`
+    fn close_switcher(&mut self, commit: bool) {
+        self.invalidate_switcher();
+        if let Some((windows, selected)) = self.switcher.take() {
+            if commit {
+                self.focus_window(windows[selected]);
+            }
+        }
+    }
+
+    /// Carry out a keyboard shortcut
+    fn run_hotkey(&mut self, action: Action) {
+        let active = self.active_window
+            .filter(|id| self.windows.get(id).map_or(false, |w| w.state != WindowState::Minimized));
+        match (action, active) {
+            (Action::SwitchNext, _) => self.step_switcher(true),
+            (Action::SwitchPrevious, _) => self.step_switcher(false),
+            (Action::ShowDesktop, _) => self.toggle_show_desktop(),
+            (Action::SnapLeft, Some(id)) => self.snap_window(id, true),
+            (Action::SnapRight, Some(id)) => self.snap_window(id, false),
+            (Action::Maximize, Some(id)) => {
+                if self.windows[&id].state != WindowState::Maximized {
+                    self.maximize_window(id);
+                }
+            }
+            (Action::Restore, Some(id)) => self.restore_window(id),
+            (Action::Lock, _) => self.lock(),
+            (_, None) => {}
+        }
+    }
+
+    /// Key pressed or released on the desktop; returns true if it was used
+    ///
+    /// The lock screen takes every key while the session is locked, and an
+    /// open file dialog every key while it is up. Otherwise keyboard shortcuts
+    /// come first; Alt+Tab's switcher stays up until Alt is released and
+    /// Esc closes it without switching. Super on its own opens and closes
+    /// the start menu when released. While the menu is open the arrow keys
+    /// pick an entry, Enter runs it and Esc closes the menu; Esc also
+    /// closes the notification panel.
+    pub fn handle_key(&mut self, event: &InputEvent) -> bool {
+        let keycode = event.keycode;
+        self.input_seen = true;
+        if let Some(lock) = self.lock.as_mut() {
+            if event.event_type == EventType::KeyPress {
+                if lock.handle_key(keycode, event.ch) {
+                    self.unlock();
+                } else {
+                    self.invalidate_lock();
+                }
+            }
+            return true;
+        }
+        if let Some(prompt) = self.consent.as_ref() {
+            if event.event_type == EventType::KeyPress {
+                if let Some(allowed) = prompt.handle_key(keycode, event.ch) {
+                    self.consent_answered(allowed);
+                }
+            }
+            return true;
+        }
+        if let Some(dialog) = self.dialog.as_mut() {
+            if event.event_type == EventType::KeyPress {
+                let outcome = dialog.handle_key(keycode, event.ch);
+                self.dialog_outcome(outcome);
+            }
+            return true;
+        }
+        if event.event_type == EventType::KeyRelease {
+            match keycode {
+                // Left and right Super (E0 5B / E0 5C)
+                0x5B | 0x5C if core::mem::take(&mut self.super_alone) => self.toggle_start_menu(),
+                0x38 if self.switcher.is_some() => self.close_switcher(true),
+                _ => return false,
+            }
+            return true;
+        }
+
+        let is_super = keycode == 0x5B || keycode == 0x5C;
+        if !is_super && event.modifiers & MOD_SUPER != 0 {
+            self.super_alone = false;
+        }
+        if let Some(action) = hotkeys::lookup(keycode, event.modifiers) {
+            self.run_hotkey(action);
+            return true;
+        }
+        match keycode {
+            _ if is_super => self.super_alone = true,
+            0x01 if self.switcher.is_some() => self.close_switcher(false),
+            0x48 if self.start_menu => self.select_menu_entry(-1),
+            0x50 if self.start_menu => self.select_menu_entry(1),
+            0x1C if self.start_menu => self.activate_menu_entry(self.menu_selected),
+            0x01 if self.start_menu => self.close_start_menu(),
+            0x01 if self.notification_panel => self.close_notification_panel(),
+            _ => {
+                // The focused widget app, if any, has the rest
+                let id = match self.active_window {
+                    Some(id) if self.windows[&id].state != WindowState::Minimized => id,
+                    _ => return false,
+                };
+                let shortcut = event.modifiers & (MOD_CTRL | MOD_ALT) != 0;
+                let used = self.native.get_mut(&id).map_or(false, |app| {
+                    (shortcut && app.shortcut(keycode, event.modifiers)) || app.key(keycode, event.ch)
+                });
+                if !used {
+                    // Keys the app has no use for scroll its content
+                    return self.scroll_key(id, keycode);
+                }
+                self.native_changed(id);
+            }
+        }
+        true
+    }
+
+    /// Bring the taskbar clock up to date with the RTC and the battery
+    /// indicator with the power status, pick up notification changes and
+    /// lock the session once it has been idle for the lock timeout
+    pub fn tick(&mut self) {
+        if notifications::update() && self.lock.is_none() {
+            self.invalidate_notifications();
+        }
+        browser::downloads::poll();
+        let changed: Vec<WindowId> = self.native.iter_mut().filter_map(|(&id, app)| app.tick().then_some(id)).collect();
+        for id in changed {
+            self.native_changed(id);
+        }
+        let now = crate::drivers::rtc::local();
+        let clock = format!("{:02}:{:02}", now.hour, now.minute);
+        if clock != self.clock {
+            self.clock = clock;
+            if self.lock.is_some() {
+                self.invalidate_lock();
+            } else {
+                self.invalidate(paint::clock_rect(self.taskbar_rect()));
+            }
+        }
+        let battery = crate::power::battery().map(|(percent, charging)| paint::BatteryChrome { percent, charging });
+        if battery != self.battery {
+            self.battery = battery;
+            if self.lock.is_none() {
+                self.invalidate(paint::battery_rect(self.taskbar_rect()));
+            }
+        }
+
+        let seconds = seconds_of_day();
+        if core::mem::take(&mut self.input_seen) {
+            self.last_input = seconds;
+        } else if self.lock_timeout > 0 {
+            // The RTC wraps at midnight
+            let idle = (seconds + SECONDS_PER_DAY - self.last_input) % SECONDS_PER_DAY;
+            if idle >= self.lock_timeout * 60 {
+                self.lock();
+            }
+        }
+    }
+
+    /// Lock the session: hide the windows and ask for the user's password
+    ///
+    /// Applications keep running; only input is held back until the
+    /// session is unlocked.
+    pub fn lock(&mut self) {
+        if self.lock.is_some() || !self.show_desktop {
+            return;
+        }
+        let username = match &self.current_user {
+            Some(user) => user.username.clone(),
+            None => return,
+        };
+        self.grab = None;
+        self.close_start_menu();
+        self.close_notification_panel();
+        self.close_switcher(false);
+        self.super_alone = false;
+        self.lock = Some(LockScreen::new(&username));
+        self.invalidate_all();
+        info!("desktop", "Session locked");
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+        self.last_input = seconds_of_day();
+        self.invalidate_all();
+    }
+
+    /// Whether the lock screen is up
+    pub fn locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    /// Idle minutes before the session locks itself; 0 turns it off
+    pub fn set_lock_timeout(&mut self, minutes: u32) {
+        self.lock_timeout = minutes;
+        self.last_input = seconds_of_day();
+    }
+
+    fn invalidate_lock(&mut self) {
+        if let Some(&primary) = self.displays.first() {
+            self.invalidate(vesa_login::lock_panel_rect(primary));
+        }
+    }
+
+    /// Snapshot of what the painter draws
+    fn scene(&self) -> paint::Scene {
+        let mut windows: Vec<&Window> = self.windows.values().collect();
+        windows.sort_by_key(|w| w.z_index);
+        paint::Scene {
+            displays: self.displays.clone(),
+            icons: self.desktop_items.iter().map(|item| paint::IconChrome {
+                name: item.name.clone(),
+                x: item.x,
+                y: item.y,
+            }).collect(),
+            windows: windows.into_iter().map(|w| {
+                let body = paint::body_rect(w.rect());
+                let content = paint::html_text(&w.content);
+                let widgets = self.native.get(&w.id).map(|app| app.widgets(body.w, body.h));
+                let height = paint::content_height(&content, widgets.as_deref());
+                paint::WindowChrome {
+                    title: w.title.clone(),
+                    rect: w.rect(),
+                    focused: self.active_window == Some(w.id),
+                    minimized: w.state == WindowState::Minimized,
+                    maximized: w.state == WindowState::Maximized,
+                    content,
+                    widgets,
+                    scroll_y: w.scroll_y.min(height.saturating_sub(body.h)),
+                }
+            }).collect(),
+            tasks: self.windows.values().map(|w| paint::TaskChrome {
+                title: w.title.clone(),
+                active: self.active_window == Some(w.id),
+                minimized: w.state == WindowState::Minimized,
+            }).collect(),
+            switcher: self.switcher.as_ref().map(|(ids, selected)| paint::MenuChrome {
+                entries: ids.iter().map(|id| self.windows[id].title.clone()).collect(),
+                selected: *selected,
+            }),
+            start_menu: if self.start_menu {
+                Some(paint::MenuChrome {
+                    entries: self.menu_entries().into_iter().map(|(label, _)| label).collect(),
+                    selected: self.menu_selected,
+                })
+            } else {
+                None
+            },
+            toasts: notifications::toasts().iter().map(notice_chrome).collect(),
+            notification_panel: if self.notification_panel {
+                Some(notifications::history().iter().take(paint::PANEL_ENTRIES).map(notice_chrome).collect())
+            } else {
+                None
+            },
+            unread: notifications::unread(),
+            battery: self.battery,
+            dialog: self.dialog.clone(),
+            consent: self.consent.clone(),
+            lock: self.lock.as_ref().map(LockScreen::chrome),
+            clock: self.clock.clone(),
+            taskbar_height: self.taskbar_height,
+            wallpaper: paint::wallpaper(self.wallpaper).unwrap_or(&paint::WALLPAPERS[0].1),
+            theme: paint::theme(self.theme).unwrap_or(&paint::THEMES[0]),
+        }
+    }
+
+    /// Topmost visible window under (x, y) and the part of it hit
+    pub fn hit_test(&self, x: i32, y: i32) -> Option<(WindowId, WindowPart)> {
+        let window = self.windows.values()
+            .filter(|w| w.state != WindowState::Minimized && w.rect().contains(x, y))
+            .max_by_key(|w| w.z_index)?;
+        let r = window.rect();
+
+        if window.state != WindowState::Maximized {
+            let mut edges = 0;
+            if x < r.x + RESIZE_BORDER { edges |= EDGE_LEFT; }
+            if x >= r.right() - RESIZE_BORDER { edges |= EDGE_RIGHT; }
+            if y < r.y + RESIZE_BORDER { edges |= EDGE_TOP; }
+            if y >= r.bottom() - RESIZE_BORDER { edges |= EDGE_BOTTOM; }
+            if edges != 0 {
+                return Some((window.id, WindowPart::Border(edges)));
+            }
+        }
+
+        if y >= r.y + paint::TITLE_BAR_HEIGHT as i32 {
+            return Some((window.id, WindowPart::Content));
+        }
+        let by = r.y + (paint::TITLE_BAR_HEIGHT as i32 - paint::BUTTON_SIZE as i32) / 2;
+        let buttons = [WindowPart::CloseButton, WindowPart::MaximizeButton, WindowPart::MinimizeButton];
+        for (i, part) in buttons.into_iter().enumerate() {
+            let button = Rect::new(paint::button_x(r, i), by, paint::BUTTON_SIZE, paint::BUTTON_SIZE);
+            if button.contains(x, y) {
+                return Some((window.id, part));
+            }
+        }
+        Some((window.id, WindowPart::TitleBar))
+    }
+
+    /// Left button pressed at (x, y)
+    ///
+    /// An open permission prompt or file dialog takes every click. The start menu,
+    /// notification panel, taskbar and toasts sit above the windows.
+    /// Otherwise the window under the pointer is focused and raised.
+    /// Pressing its title bar starts a move and pressing its frame starts
+    /// a resize.
+    pub fn pointer_press(&mut self, x: i32, y: i32) {
+        if let Some(prompt) = self.consent.as_ref() {
+            if let Some(allowed) = prompt.pointer_press(x, y) {
+                self.consent_answered(allowed);
+            }
+            return;
+        }
+        if let Some(dialog) = self.dialog.as_mut() {
+            let outcome = dialog.pointer_press(x, y);
+            return self.dialog_outcome(outcome);
+        }
+        if self.start_menu {
+            let menu = self.start_menu_rect();
+            if menu.contains(x, y) {
+                let count = self.menu_entries().len();
+                if let Some(i) = (0..count).find(|&i| paint::menu_entry_rect(menu, i).contains(x, y)) {
+                    self.activate_menu_entry(i);
+                }
+                return;
+            }
+        }
+        if self.notification_panel {
+            let bar = self.taskbar_rect();
+            let panel = paint::notification_panel_rect(bar);
+            if panel.contains(x, y) {
+                if paint::panel_clear_rect(panel).contains(x, y) {
+                    notifications::clear();
+                }
+                return;
+            }
+        }
+        if self.taskbar_rect().contains(x, y) {
+            return self.taskbar_press(x, y);
+        }
+        self.close_start_menu();
+        self.close_notification_panel();
+
+        // Clicking a toast dismisses it
+        let primary = self.displays[0];
+        let toast = notifications::toasts().into_iter().enumerate()
+            .find(|(i, _)| paint::toast_rect(primary, *i).contains(x, y));
+        if let Some((_, n)) = toast {
+            notifications::dismiss(n.id);
+            return;
+        }
+
+        let (id, part) = match self.hit_test(x, y) {
+            Some(hit) => hit,
+            None => return,
+        };
+        if self.active_window != Some(id) || !self.is_topmost(id) {
+            self.focus_window(id);
+        }
+        let r = self.windows[&id].rect();
+        self.grab = match part {
+            WindowPart::TitleBar => Some(PointerGrab::Move { window: id, dx: x - r.x, dy: y - r.y }),
+            WindowPart::Border(edges) => Some(PointerGrab::Resize {
+                window: id, edges, start_x: x, start_y: y, start: r,
+            }),
+            WindowPart::CloseButton | WindowPart::MaximizeButton | WindowPart::MinimizeButton => {
+                Some(PointerGrab::Button { window: id, part })
+            }
+            WindowPart::Content => self.content_press(id, x, y),
+        };
+    }
+
+    /// Body of a window and the height of its content, which may be more
+    /// than the body shows
+    fn window_content(&self, id: WindowId) -> Option<(Rect, u32)> {
+        let window = self.windows.get(&id)?;
+        let body = paint::body_rect(window.rect());
+        let height = match self.native.get(&id) {
+            Some(app) => paint::content_height(&[], Some(&app.widgets(body.w, body.h))),
+            None => paint::content_height(&paint::html_text(&window.content), None),
+        };
+        Some((body, height))
+    }
+
+    /// Content row at the top of a window's body, kept within its content
+    fn window_scroll(&self, id: WindowId) -> u32 {
+        match self.window_content(id) {
+            Some((body, height)) => self.windows[&id].scroll_y.min(height.saturating_sub(body.h)),
+            None => 0,
+        }
+    }
+
+    /// Scroll a window's content to row `y`, or as near as it goes;
+    /// returns whether it moved
+    fn scroll_window(&mut self, id: WindowId, y: u32) -> bool {
+        let (body, height) = match self.window_content(id) {
+            Some(content) => content,
+            None => return false,
+        };
+        let y = y.min(height.saturating_sub(body.h));
+        let window = self.windows.get_mut(&id).unwrap();
+        if window.scroll_y == y {
+            return false;
+        }
+        window.scroll_y = y;
+        self.invalidate_window(Some(id));
+        true
+    }
+
+    /// Left button pressed at (x, y) in a window's content
+    ///
+    /// Pressing a scrollbar's thumb starts dragging it, and pressing its
+    /// track scrolls a page towards the pointer. Anything else goes to the
+    /// window's widget app, if it has one.
+    fn content_press(&mut self, id: WindowId, x: i32, y: i32) -> Option<PointerGrab> {
+        let (body, height) = self.window_content(id)?;
+        let scroll_y = self.window_scroll(id);
+        if height > body.h {
+            let track = paint::window_scrollbar_rect(body);
+            if track.contains(x, y) {
+                let thumb = widgets::scrollbar_thumb(track, scroll_y, height, body.h);
+                if thumb.contains(x, y) {
+                    return Some(PointerGrab::Scroll { window: id, widget: None, dy: y - thumb.y });
+                }
+                let page = if y < thumb.y { scroll_y.saturating_sub(body.h) } else { scroll_y + body.h };
+                self.scroll_window(id, page);
+                return None;
+            }
+        }
+        self.native_click(id, x - body.x, y - body.y + scroll_y as i32)
+    }
+
+    /// Pass a click at (x, y) in a window's scrolled content to its widget
+    /// app, if it has one
+    fn native_click(&mut self, id: WindowId, x: i32, y: i32) -> Option<PointerGrab> {
+        let body = paint::body_rect(self.windows[&id].rect());
+        let app = self.native.get_mut(&id)?;
+        let widgets = app.widgets(body.w, body.h);
+        let widget = widgets::widget_at(&widgets, x, y)?;
+        let changed = match widget.kind {
+            WidgetKind::Scrollbar { offset, content, view } => {
+                let thumb = widgets::scrollbar_thumb(widget.rect, offset, content, view);
+                if thumb.contains(x, y) {
+                    return Some(PointerGrab::Scroll { window: id, widget: Some(widget.id), dy: y - thumb.y });
+                }
+                let page = if y < thumb.y { offset.saturating_sub(view) } else { offset + view };
+                app.scroll_to(widget.id, page)
+            }
+            _ => {
+                let changed = app.click_at(widget.id, x - widget.rect.x, y - widget.rect.y);
+                if changed {
+                    self.native_changed(id);
+                }
+                return Some(PointerGrab::Widget { window: id, widget: widget.id });
+            }
+        };
+        if changed {
+            self.native_changed(id);
+        }
+        None
+    }
+
+    /// Whether screen point (x, y) is over a text input of a window's app
+    fn over_text(&self, window: WindowId, x: i32, y: i32) -> bool {
+        let body = paint::body_rect(self.windows[&window].rect());
+        let widgets = match self.native.get(&window) {
+            Some(app) => app.widgets(body.w, body.h),
+            None => return false,
+        };
+        let y = y - body.y + self.window_scroll(window) as i32;
+        widgets::widget_at(&widgets, x - body.x, y)
+            .map_or(false, |w| matches!(w.kind, WidgetKind::TextInput { .. }))
+    }
+
+    /// Screen point (x, y) relative to widget `widget` of a window's app
+    fn widget_point(&self, window: WindowId, widget: u32, x: i32, y: i32) -> Option<(i32, i32)> {
+        let body = paint::body_rect(self.windows.get(&window)?.rect());
+        let widgets = self.native.get(&window)?.widgets(body.w, body.h);
+        let rect = widgets.iter().find(|w| w.id == widget)?.rect;
+        let y = y - body.y + self.window_scroll(window) as i32;
+        Some((x - body.x - rect.x, y - rect.y))
+    }
+
+    /// Mouse wheel turned `notches` with the pointer at (x, y), positive
+    /// downwards
+    ///
+    /// The widget under the pointer gets the first go; if it has nothing
+    /// to scroll, the window's content scrolls.
+    pub fn pointer_scroll(&mut self, x: i32, y: i32, notches: i32) {
+        if self.modal() || self.grab.is_some() || self.taskbar_rect().contains(x, y) {
+            return;
+        }
+        let id = match self.hit_test(x, y) {
+            Some((id, WindowPart::Content)) => id,
+            _ => return,
+        };
+        let body = paint::body_rect(self.windows[&id].rect());
+        let scroll_y = self.window_scroll(id);
+        if let Some(app) = self.native.get_mut(&id) {
+            let widgets = app.widgets(body.w, body.h);
+            let used = widgets::widget_at(&widgets, x - body.x, y - body.y + scroll_y as i32)
+                .map_or(false, |w| app.scroll(w.id, notches));
+            if used {
+                return self.native_changed(id);
+            }
         }
+        self.scroll_window(id, (scroll_y as i32 + notches * WHEEL_SCROLL).max(0) as u32);
     }
-    
-    /// Close window
-    pub fn close_window(&mut self, window_id: WindowId) -> bool {
-        if self.windows.remove(&window_id).is_some() {
-            if self.active_window == Some(window_id) {
-                // Focus next window
-                self.active_window = self.windows.keys().last().copied();
+
+    /// The mouse's back or forward button was pressed at (x, y); the app
+    /// in the window under it goes through its history
+    pub fn pointer_history(&mut self, x: i32, y: i32, forward: bool) {
+        if self.modal() || self.grab.is_some() || self.taskbar_rect().contains(x, y) {
+            return;
+        }
+        let id = match self.hit_test(x, y) {
+            Some((id, WindowPart::Content)) => id,
+            _ => return,
+        };
+        if self.native.get_mut(&id).map_or(false, |app| app.history(forward)) {
+            self.native_changed(id);
+        }
+    }
+
+    /// Scroll a window's content with Up, Down, Page Up, Page Down, Home
+    /// or End; false for other keys, or if the content fits
+    fn scroll_key(&mut self, id: WindowId, keycode: u16) -> bool {
+        let (body, height) = match self.window_content(id) {
+            Some(content) => content,
+            None => return false,
+        };
+        if height <= body.h {
+            return false;
+        }
+        let y = self.window_scroll(id) as i32;
+        // Page Up and Down keep a line of the old view in sight
+        let page = (body.h as i32 - KEY_SCROLL).max(KEY_SCROLL);
+        let target = match keycode {
+            0x48 => y - KEY_SCROLL,
+            0x50 => y + KEY_SCROLL,
+            0x49 => y - page,
+            0x51 => y + page,
+            0x47 => 0,
+            0x4F => i32::MAX,
+            _ => return false,
+        };
+        self.scroll_window(id, target.max(0) as u32);
+        true
+    }
+
+    /// Repaint a widget app's window after it changed, taking up the title
+    /// it wants, if any
+    fn native_changed(&mut self, id: WindowId) {
+        let title = self.native.get(&id).and_then(|app| app.title());
+        match title {
+            Some(title) if self.windows.get(&id).map_or(false, |w| w.title != title) => {
+                self.set_window_title(id, &title);
             }
-            println!("[desktop] Closed window {}", window_id);
-            true
-        } else {
-            false
+            _ => self.invalidate_window(Some(id)),
         }
     }
-    
-    /// Focus window
-    pub fn focus_window(&mut self, window_id: WindowId) {
-        if self.windows.contains_key(&window_id) {
-            let new_z = self.get_max_z_index() + 1;
-            if let Some(window) = self.windows.get_mut(&window_id) {
-                window.state = WindowState::Focused;
-                window.z_index = new_z;
+
+    /// Pointer moved to (x, y), with or without a button held
+    pub fn pointer_move(&mut self, x: i32, y: i32) {
+        match self.grab {
+            Some(PointerGrab::Move { window, dx, dy }) => {
+                self.move_window(window, x - dx, y - dy);
+                // Dragging a maximized or snapped window off restores its size, which
+                // may leave the grab point past its right edge
+                if let Some(w) = self.windows.get(&window) {
+                    if dx >= w.width as i32 {
+                        let dx = w.width as i32 / 2;
+                        self.grab = Some(PointerGrab::Move { window, dx, dy });
+                        self.move_window(window, x - dx, y - dy);
+                    }
+                }
             }
-            self.active_window = Some(window_id);
+            Some(PointerGrab::Resize { window, edges, start_x, start_y, start }) => {
+                let rect = self.resized(window, edges, x - start_x, y - start_y, start);
+                if self.windows.get(&window).map(|w| w.rect()) != Some(rect) {
+                    self.resize_window(window, rect);
+                }
+            }
+            Some(PointerGrab::Scroll { window, widget: None, dy }) => {
+                if let Some((body, height)) = self.window_content(window) {
+                    let track = paint::window_scrollbar_rect(body);
+                    self.scroll_window(window, widgets::scrollbar_offset(track, height, body.h, y - dy));
+                }
+            }
+            Some(PointerGrab::Scroll { window, widget: Some(widget), dy }) => {
+                let body = match self.windows.get(&window) {
+                    Some(w) => paint::body_rect(w.rect()),
+                    None => return,
+                };
+                let y = y - body.y + self.window_scroll(window) as i32;
+                let app = match self.native.get_mut(&window) {
+                    Some(app) => app,
+                    None => return,
+                };
+                let bar = app.widgets(body.w, body.h).into_iter().find(|w| w.id == widget);
+                let changed = match bar {
+                    Some(widgets::Widget { rect, kind: WidgetKind::Scrollbar { content, view, .. }, .. }) => {
+                        app.scroll_to(widget, widgets::scrollbar_offset(rect, content, view, y - dy))
+                    }
+                    _ => false,
+                };
+                if changed {
+                    self.native_changed(window);
+                }
+            }
+            Some(PointerGrab::Widget { window, widget }) => {
+                let (x, y) = match self.widget_point(window, widget, x, y) {
+                    Some(point) => point,
+                    None => return,
+                };
+                if self.native.get_mut(&window).map_or(false, |app| app.drag(widget, x, y)) {
+                    self.native_changed(window);
+                }
+            }
+            _ => {}
         }
     }
-    
-    /// Get max z-index
-    fn get_max_z_index(&self) -> u32 {
-        self.windows.values().map(|w| w.z_index).max().unwrap_or(0)
+
+    /// Left button released at (x, y)
+    ///
+    /// A title bar button fires only if the pointer is still over it; a
+    /// pressed widget hears of the release wherever it is.
+    pub fn pointer_release(&mut self, x: i32, y: i32) {
+        match self.grab.take() {
+            Some(PointerGrab::Button { window, part }) if self.hit_test(x, y) == Some((window, part)) => {
+                match part {
+                    WindowPart::CloseButton => { self.close_window(window); }
+                    WindowPart::MaximizeButton => self.maximize_window(window),
+                    WindowPart::MinimizeButton => self.minimize_window(window),
+                    _ => {}
+                }
+            }
+            Some(PointerGrab::Widget { window, widget }) => {
+                let (x, y) = match self.widget_point(window, widget, x, y) {
+                    Some(point) => point,
+                    None => return,
+                };
+                if self.native.get_mut(&window).map_or(false, |app| app.release(widget, x, y)) {
+                    self.native_changed(window);
+                }
+            }
+            _ => {}
+        }
     }
-    
-    /// Minimize window
-    pub fn minimize_window(&mut self, window_id: WindowId) {
-        if let Some(window) = self.windows.get_mut(&window_id) {
-            window.state = WindowState::Minimized;
+
+    /// Window rectangle after dragging `edges` of `start` by (dx, dy)
+    ///
+    /// The dragged edges stop where the window would get smaller than the
+    /// minimum or larger than its display's work area; the opposite edges
+    /// stay put. The top edge does not go above the virtual desktop.
+    fn resized(&self, window_id: WindowId, edges: u8, dx: i32, dy: i32, start: Rect) -> Rect {
+        let area = self.windows.get(&window_id)
+            .map_or(self.displays[0], |w| self.work_area(self.display_of(w)));
+        let (min_w, min_h) = (WINDOW_MIN_WIDTH as i32, WINDOW_MIN_HEIGHT as i32);
+        let max_w = (area.w as i32).max(min_w);
+        let max_h = (area.h as i32).max(min_h);
+        let mut r = start;
+
+        if edges & EDGE_LEFT != 0 {
+            let w = (start.w as i32 - dx).max(min_w).min(max_w);
+            r.x = start.right() - w;
+            r.w = w as u32;
+        } else if edges & EDGE_RIGHT != 0 {
+            r.w = (start.w as i32 + dx).max(min_w).min(max_w) as u32;
         }
+        if edges & EDGE_TOP != 0 {
+            let h = (start.h as i32 - dy).max(min_h).min(max_h).min(start.bottom().max(min_h));
+            r.y = start.bottom() - h;
+            r.h = h as u32;
+        } else if edges & EDGE_BOTTOM != 0 {
+            r.h = (start.h as i32 + dy).max(min_h).min(max_h) as u32;
+        }
+        r
     }
-    
-    /// Maximize/restore window
-    pub fn maximize_window(&mut self, window_id: WindowId) {
-        if let Some(window) = self.windows.get_mut(&window_id) {
-            window.state = match window.state {
-                WindowState::Maximized => WindowState::Normal,
-                _ => WindowState::Maximized,
-            };
+
+    fn is_topmost(&self, window_id: WindowId) -> bool {
+        self.windows.get(&window_id).map(|w| w.z_index) == Some(self.get_max_z_index())
+    }
+
+    /// Cursor shape for the pointer at (x, y): a resize arrow over a
+    /// window frame or while resizing, the text beam over a text input,
+    /// the arrow otherwise
+    pub fn pointer_shape(&self, x: i32, y: i32) -> CursorShape {
+        let edges = match self.grab {
+            Some(PointerGrab::Resize { edges, .. }) => edges,
+            Some(_) => 0,
+            None if self.modal() || self.taskbar_rect().contains(x, y) => 0,
+            None => match self.hit_test(x, y) {
+                Some((_, WindowPart::Border(edges))) => edges,
+                Some((id, WindowPart::Content)) if self.over_text(id, x, y) => return CursorShape::TextBeam,
+                _ => 0,
+            },
+        };
+        match edges {
+            0 => CursorShape::Arrow,
+            e if e == EDGE_LEFT | EDGE_TOP || e == EDGE_RIGHT | EDGE_BOTTOM => CursorShape::ResizeNwse,
+            e if e == EDGE_RIGHT | EDGE_TOP || e == EDGE_LEFT | EDGE_BOTTOM => CursorShape::ResizeNesw,
+            e if e & (EDGE_LEFT | EDGE_RIGHT) != 0 => CursorShape::ResizeHorizontal,
+            _ => CursorShape::ResizeVertical,
+        }
+    }
+
+    /// Focus the window above the active one in stacking order, wrapping
+    pub fn focus_next(&mut self) {
+        let mut ids: Vec<(u32, WindowId)> = self.windows.values().map(|w| (w.z_index, w.id)).collect();
+        ids.sort();
+        // The active window is on top, so the next is the bottom one
+        if let Some(&(_, id)) = ids.first() {
+            self.focus_window(id);
         }
     }
     
@@ -380,26 +1924,75 @@ impl DesktopManager {
     }
     
     /// Login
-    pub fn login(&mut self, username: &str, password: &str) -> bool {
-        if let Some(session_id) = users::login(username, password) {
-            self.current_user = users::current_user();
-            self.show_login = false;
-            self.show_desktop = true;
-            println!("[desktop] Logged in as {}", username);
-            true
-        } else {
-            false
+    pub fn login(&mut self, username: &str, password: &str, code: Option<&str>) -> Result<(), users::UserError> {
+        users::login(username, password, code)?;
+        self.current_user = users::current_user();
+        self.show_login = false;
+        self.show_desktop = true;
+        self.apply_user_settings();
+        info!("desktop", "Logged in as {}", username);
+        Ok(())
+    }
+
+    /// Use the wallpaper, theme and keyboard layout set in the `.desktop`
+    /// file in the user's home directory, `key = value` lines; what it
+    /// does not set, or nobody logged in, follows the system settings
+    fn apply_user_settings(&mut self) {
+        let mut wallpaper = config::get("desktop.wallpaper").unwrap_or_default();
+        let mut theme = config::get("desktop.theme").unwrap_or_default();
+        let mut keyboard = config::get("keyboard.layout").unwrap_or_default();
+        let path = self.current_user.as_ref()
+            .map(|u| format!("{}/.desktop", u.home_directory.trim_end_matches('/')));
+        if let Some(data) = path.and_then(|path| fs::read_file(&path).ok()) {
+            for line in String::from_utf8_lossy(&data).lines() {
+                let line = line.split('#').next().unwrap_or("");
+                match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                    Some(("wallpaper", value)) => wallpaper = String::from(value),
+                    Some(("theme", value)) => theme = String::from(value),
+                    Some(("keyboard", value)) => keyboard = String::from(value),
+                    _ => {}
+                }
+            }
         }
+        self.set_wallpaper(&wallpaper);
+        self.set_theme(&theme);
+        input::set_layout(Layout::from_name(&keyboard).unwrap_or(Layout::Us));
     }
     
     /// Logout
     pub fn logout(&mut self) {
+        self.invalidate_all();
+        self.grab = None;
+        self.start_menu = false;
+        self.notification_panel = false;
+        self.dialog = None;
+        self.consent = None;
+        self.switcher = None;
+        self.lock = None;
+        self.desktop_shown.clear();
+        for (&id, window) in self.windows.iter() {
+            ipc::forget(id);
+            if let Some(pid) = window.pid {
+                let _ = process::terminate(pid);
+            }
+        }
         self.windows.clear();
+        self.native.clear();
         self.active_window = None;
         self.current_user = None;
         self.show_login = true;
         self.show_desktop = false;
-        println!("[desktop] Logged out");
+        self.apply_user_settings();
+        info!("desktop", "Logged out");
+    }
+    
+    /// Show the desktop for whoever is logged in through the user subsystem
+    pub fn show(&mut self) {
+        self.current_user = users::current_user();
+        self.show_login = false;
+        self.show_desktop = true;
+        self.apply_user_settings();
+        self.invalidate_all();
     }
     
     /// Check if showing login
@@ -427,6 +2020,15 @@ impl DesktopManager {
     }
 }
 
+fn notice_chrome(n: &notifications::Notification) -> paint::NoticeChrome {
+    paint::NoticeChrome {
+        title: n.title.clone(),
+        body: n.body.clone(),
+        icon: n.icon,
+        unread: !n.read,
+    }
+}
+
 /// Global desktop manager
 lazy_static! {
     static ref DESKTOP_MANAGER: Mutex<DesktopManager> = Mutex::new(DesktopManager::new());
@@ -434,19 +2036,42 @@ lazy_static! {
 
 /// Initialize desktop environment
 pub fn init() {
-    println!("[desktop] Initializing desktop environment...");
-    
-    let manager = DESKTOP_MANAGER.lock();
-    println!("[desktop] {} applications registered", manager.applications.len());
-    println!("[desktop] {} desktop items", manager.desktop_items.len());
+    info!("desktop", "Initializing desktop environment...");
     
+    let mut manager = DESKTOP_MANAGER.lock();
+    manager.sync_displays();
+    info!("desktop", "{} applications registered", manager.applications.len());
+    info!("desktop", "{} desktop items", manager.desktop_items.len());
+    drop(manager);
+    hotkeys::load();
+    packages::load();
+
     // Show login screen
-    println!("[desktop] Showing login screen");
+    info!("desktop", "Showing login screen");
+}
+
+/// Launch application by name, as a child of `parent` if given
+pub fn launch_app(name: &str, parent: Option<Pid>) -> Option<WindowId> {
+    DESKTOP_MANAGER.lock().launch_app_by_name(name, parent)
 }
 
-/// Launch application by name
-pub fn launch_app(name: &str) -> Option<WindowId> {
-    DESKTOP_MANAGER.lock().launch_app_by_name(name)
+/// Process a window's app runs as
+pub fn window_pid(window_id: WindowId) -> Option<Pid> {
+    DESKTOP_MANAGER.lock().windows.get(&window_id).and_then(|w| w.pid)
+}
+
+/// What the app in a window declared it may do; None for built-in apps
+/// and windows that are gone
+pub fn window_capabilities(window_id: WindowId) -> Option<(String, Vec<Capability>)> {
+    let desktop = DESKTOP_MANAGER.lock();
+    let app = desktop.windows.get(&window_id).and_then(|w| desktop.applications.get(&w.app_id))?;
+    app.capabilities.clone().map(|c| (app.name.clone(), c))
+}
+
+/// Ask the user whether `owner`'s app may use `capability`; false if a
+/// prompt or file chooser is already open
+pub fn ask_consent(owner: WindowId, capability: Capability, request: ipc::Request) -> bool {
+    DESKTOP_MANAGER.lock().ask_consent(owner, capability, request)
 }
 
 /// Close window
@@ -454,9 +2079,21 @@ pub fn close_window(window_id: WindowId) -> bool {
     DESKTOP_MANAGER.lock().close_window(window_id)
 }
 
+/// Close the windows of a process that was killed
+pub fn close_process_windows(pid: Pid) {
+    let mut manager = DESKTOP_MANAGER.lock();
+    let windows: Vec<WindowId> = manager.windows.values()
+        .filter(|w| w.pid == Some(pid))
+        .map(|w| w.id)
+        .collect();
+    for id in windows {
+        manager.close_window(id);
+    }
+}
+
 /// Login
-pub fn login(username: &str, password: &str) -> bool {
-    DESKTOP_MANAGER.lock().login(username, password)
+pub fn login(username: &str, password: &str, code: Option<&str>) -> Result<(), users::UserError> {
+    DESKTOP_MANAGER.lock().login(username, password, code)
 }
 
 /// Logout
@@ -479,14 +2116,258 @@ pub fn list_apps() -> Vec<Application> {
     DESKTOP_MANAGER.lock().list_apps().into_iter().cloned().collect()
 }
 
+/// Add an application to the desktop, e.g. one installed from a package
+pub fn register_app(app: Application) -> AppId {
+    DESKTOP_MANAGER.lock().register_app(app)
+}
+
+/// Remove an application and close its windows; false if there is none
+pub fn unregister_app(name: &str) -> bool {
+    DESKTOP_MANAGER.lock().unregister_app(name)
+}
+
+/// Change the display mode and resize the desktop to match
+pub fn set_display_mode(width: u32, height: u32, bpp: u8) -> crate::drivers::DriverResult<()> {
+    let result = crate::graphics::set_mode(width, height, bpp);
+    DESKTOP_MANAGER.lock().sync_displays();
+    result
+}
+
+/// Change the desktop background; false if there is no such wallpaper
+pub fn set_wallpaper(name: &str) -> bool {
+    DESKTOP_MANAGER.lock().set_wallpaper(name)
+}
+
+/// Change the window colors; false if there is no such theme
+pub fn set_theme(name: &str) -> bool {
+    DESKTOP_MANAGER.lock().set_theme(name)
+}
+
+/// Switch the keyboard layout, and keep it in the `.desktop` file of the
+/// user logged in, or as the system setting if nobody is
+pub fn set_keyboard_layout(name: &str) -> Result<(), String> {
+    let layout = Layout::from_name(name).ok_or_else(|| format!("no keyboard layout '{}'", name))?;
+    let user = match users::current_user() {
+        Some(user) => user,
+        None => return config::set("keyboard.layout", name).map_err(|e| format!("{}", e)),
+    };
+    input::set_layout(layout);
+    save_user_setting(&user, "keyboard", name)
+        .map_err(|e| format!("in effect, but not saved to {}/.desktop: {:?}", user.home_directory.trim_end_matches('/'), e))
+}
+
+/// Set `key` to `value` in `user`'s `.desktop` file, keeping the rest
+fn save_user_setting(user: &User, key: &str, value: &str) -> fs::FsResult<()> {
+    let path = format!("{}/.desktop", user.home_directory.trim_end_matches('/'));
+    let old = fs::read_file(&path).unwrap_or_default();
+    let mut text = String::new();
+    let mut found = false;
+    for line in String::from_utf8_lossy(&old).lines() {
+        let setting = line.split('#').next().unwrap_or("").split_once('=').map(|(k, _)| k.trim());
+        if setting == Some(key) {
+            if !found {
+                text.push_str(&format!("{} = {}\n", key, value));
+            }
+            found = true;
+        } else {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    if !found {
+        text.push_str(&format!("{} = {}\n", key, value));
+    }
+    fs::write_file(&path, text.as_bytes())
+}
+
+/// Change a window's title
+pub fn set_window_title(window_id: WindowId, title: &str) {
+    DESKTOP_MANAGER.lock().set_window_title(window_id, title);
+}
+
+/// Open a URL in a Browser window, returning the window's title
+pub fn open_url(url: &str) -> Result<String, String> {
+    DESKTOP_MANAGER.lock().open_url(url)
+}
+
+/// Move a window, possibly onto another display
+pub fn move_window(window_id: WindowId, x: i32, y: i32) -> bool {
+    DESKTOP_MANAGER.lock().move_window(window_id, x, y)
+}
+
+/// Move the active window by (dx, dy)
+pub fn nudge_active_window(dx: i32, dy: i32) {
+    let mut manager = DESKTOP_MANAGER.lock();
+    if let Some((id, x, y)) = manager.active_window().map(|w| (w.id, w.x, w.y)) {
+        manager.move_window(id, x + dx, y + dy);
+    }
+}
+
+/// Focus the next window in stacking order
+pub fn focus_next() {
+    DESKTOP_MANAGER.lock().focus_next();
+}
+
+/// Maximize or restore the active window
+pub fn maximize_active_window() {
+    let mut manager = DESKTOP_MANAGER.lock();
+    if let Some(id) = manager.active_window {
+        manager.maximize_window(id);
+    }
+}
+
+/// Minimize the active window
+pub fn minimize_active_window() {
+    let mut manager = DESKTOP_MANAGER.lock();
+    if let Some(id) = manager.active_window {
+        manager.minimize_window(id);
+    }
+}
+
+/// Close the active window
+pub fn close_active_window() {
+    let mut manager = DESKTOP_MANAGER.lock();
+    if let Some(id) = manager.active_window {
+        manager.close_window(id);
+    }
+}
+
+/// Feed a mouse event to the desktop
+///
+/// The left button focuses, moves and resizes windows, works the title
+/// bar buttons and drags scrollbars, the wheel scrolls what is under the
+/// pointer, and the back and forward buttons go through its history; the
+/// cursor shape follows what is under it.
+pub fn handle_mouse(event: &InputEvent) {
+    let shape = {
+        let mut manager = DESKTOP_MANAGER.lock();
+        manager.input_seen = true;
+        if manager.locked() {
+            // The lock screen is worked from the keyboard only
+            CursorShape::Arrow
+        } else {
+            match event.event_type {
+                EventType::MouseMove => manager.pointer_move(event.x, event.y),
+                EventType::MouseScroll => manager.pointer_scroll(event.x, event.y, event.scroll as i32),
+                EventType::MouseButtonPress if event.button == MouseButton::Left as u8 => {
+                    manager.pointer_press(event.x, event.y);
+                }
+                EventType::MouseButtonRelease if event.button == MouseButton::Left as u8 => {
+                    // The move in the release packet comes first
+                    manager.pointer_move(event.x, event.y);
+                    manager.pointer_release(event.x, event.y);
+                }
+                EventType::MouseButtonPress if event.button == MouseButton::Back as u8 => {
+                    manager.pointer_history(event.x, event.y, false);
+                }
+                EventType::MouseButtonPress if event.button == MouseButton::Forward as u8 => {
+                    manager.pointer_history(event.x, event.y, true);
+                }
+                _ => return,
+            }
+            manager.pointer_shape(event.x, event.y)
+        }
+    };
+    crate::graphics::cursor::set_shape(shape);
+}
+
+/// Feed a key press or release to the desktop; returns true if it was
+/// used
+pub fn handle_key(event: &InputEvent) -> bool {
+    DESKTOP_MANAGER.lock().handle_key(event)
+}
+
+/// Input handler of a desktop session: keys go to `handle_key` and the
+/// pointer to `handle_mouse`; false for keys nothing used
+pub fn handle_event(event: &InputEvent) -> bool {
+    match event.event_type {
+        EventType::KeyPress | EventType::KeyRelease => handle_key(event),
+        _ => {
+            handle_mouse(event);
+            true
+        }
+    }
+}
+
+/// Show a file chooser whose result goes to `owner`; false if one is
+/// already open
+pub fn open_file_dialog(owner: WindowId, mode: DialogMode, filter: &str) -> bool {
+    DESKTOP_MANAGER.lock().open_file_dialog(owner, mode, filter)
+}
+
+/// Update time-driven parts of the desktop, such as the taskbar clock,
+/// pass shell output to Terminal windows and handle the messages apps
+/// posted
+pub fn tick() {
+    terminal::pump();
+    DESKTOP_MANAGER.lock().tick();
+    ipc::deliver();
+}
+
+/// Lock the session until the user's password is entered
+pub fn lock() {
+    DESKTOP_MANAGER.lock().lock();
+}
+
+/// Whether the session is locked
+pub fn locked() -> bool {
+    DESKTOP_MANAGER.lock().locked()
+}
+
+/// Lock the session after `minutes` without input; 0 never does
+pub fn set_lock_timeout(minutes: u32) {
+    DESKTOP_MANAGER.lock().set_lock_timeout(minutes);
+}
+
+/// Switch from the login screen to the desktop of the logged-in user
+pub fn show() {
+    DESKTOP_MANAGER.lock().show();
+}
+
+/// Whether the desktop is showing, rather than the login screen
+pub fn showing_desktop() -> bool {
+    DESKTOP_MANAGER.lock().showing_desktop()
+}
+
+/// Mark the whole desktop for repainting, e.g. after something else drew
+/// over the screen
+pub fn invalidate_all() {
+    DESKTOP_MANAGER.lock().invalidate_all();
+}
+
+/// Repaint the regions of the desktop that changed and present them
+///
+/// Returns the number of pixels repainted.
+pub fn redraw() -> u64 {
+    crate::watchdog::pet(crate::watchdog::Subsystem::Compositor);
+    let (scene, dirty) = {
+        let mut manager = DESKTOP_MANAGER.lock();
+        if manager.dirty.is_empty() {
+            return 0;
+        }
+        (manager.scene(), core::mem::take(&mut manager.dirty))
+    };
+    crate::graphics::compositor::with(|c| {
+        for rect in dirty {
+            c.invalidate(rect);
+        }
+    });
+    crate::graphics::compositor::compose(|c, area| paint::paint(c, area, &scene))
+}
+
 /// Print desktop info
 pub fn print_info() {
     let manager = DESKTOP_MANAGER.lock();
     
     println!("\nDesktop Environment:");
-    println!("  Resolution: {}x{}", manager.screen_width, manager.screen_height);
+    println!("  Resolution: {}x{} ({} display{})", manager.screen_width, manager.screen_height,
+        manager.displays.len(), if manager.displays.len() == 1 { "" } else { "s" });
     println!("  Applications: {}", manager.applications.len());
     println!("  Windows open: {}", manager.windows.len());
+    for window in manager.windows.values() {
+        println!("    {} {} at ({}, {}) {}x{}, display {}", window.id, window.title,
+            window.x, window.y, window.width, window.height, manager.display_of(window));
+    }
     println!("  Desktop items: {}", manager.desktop_items.len());
     
     if let Some(user) = &manager.current_user {
@@ -590,6 +2471,12 @@ fn generate_login_page() -> String {
             border-radius: 3px;
             font-family: monospace;
         }
+        .error {
+            margin-top: 12px;
+            color: #e53e3e;
+            font-size: 13px;
+            text-align: center;
+        }
     </style>
 </head>
 <body>
@@ -606,8 +2493,13 @@ fn generate_login_page() -> String {
                 <label for="password">Password</label>
                 <input type="password" id="password" name="password" placeholder="Enter password" required>
             </div>
+            <div class="input-group" id="code-group" style="display:none;">
+                <label for="code">Authentication code</label>
+                <input type="text" id="code" name="code" placeholder="Code from your app, or a recovery code" autocomplete="one-time-code">
+            </div>
             <button type="submit">Sign In</button>
         </form>
+        <div class="error" id="error"></div>
         <div class="hint">
             Default accounts:<br>
             Admin: <code>admin</code> / <code>admin</code><br>
@@ -619,8 +2511,20 @@ fn generate_login_page() -> String {
             e.preventDefault();
             const username = document.getElementById('username').value;
             const password = document.getElementById('password').value;
+            const code = document.getElementById('code').value;
             // Send login request to kernel
-            window.parent.postMessage({ type: 'login', username, password }, '*');
+            window.parent.postMessage({ type: 'login', username, password, code }, '*');
+        });
+        // Wrong password, or the account is waiting or locked
+        window.addEventListener('message', function(e) {
+            if (e.data.type === 'error' && e.data.request === 'login') {
+                document.getElementById('error').textContent = e.data.message;
+            } else if (e.data.type === 'code_required') {
+                // Two-factor login: the password was right
+                document.getElementById('code-group').style.display = 'block';
+                document.getElementById('error').textContent = 'Enter the code from your authenticator app';
+                document.getElementById('code').focus();
+            }
         });
     </script>
 </body>
@@ -988,6 +2892,8 @@ fn generate_desktop_page(manager: &DesktopManager) -> String {
                 const action = item.dataset.action;
                 if (action === 'logout') {{
                     window.parent.postMessage({{ type: 'logout' }}, '*');
+                }} else if (action === 'settings') {{
+                    window.parent.postMessage({{ type: 'launch', app: 'settings' }}, '*');
                 }}
                 startMenu.classList.remove('show');
             }});
@@ -1101,7 +3007,7 @@ function saveFile() {
     if (currentFile) {
         window.parent.postMessage({ type: 'fs_write', path: currentFile, content }, '*');
     } else {
-        window.parent.postMessage({ type: 'dialog_save', content }, '*');
+        window.parent.postMessage({ type: 'dialog_save', content, filter: '.txt' }, '*');
     }
 }
 window.addEventListener('message', (e) => {
@@ -1109,6 +3015,9 @@ window.addEventListener('message', (e) => {
         document.getElementById('editor').value = e.data.content;
         currentFile = e.data.path;
         window.parent.postMessage({ type: 'window_title', title: 'Notepad - ' + e.data.name }, '*');
+    } else if (e.data.type === 'file_saved') {
+        currentFile = e.data.path;
+        window.parent.postMessage({ type: 'window_title', title: 'Notepad - ' + e.data.name }, '*');
     }
 });
 "#)
@@ -1283,6 +3192,9 @@ fn get_usermanager_html() -> String {
                 <th>Username</th>
                 <th>Type</th>
                 <th>Status</th>
+                <th>Last Login</th>
+                <th>Failed Logins</th>
+                <th>Two-Factor</th>
                 <th>Actions</th>
             </tr>
         </thead>
@@ -1299,6 +3211,37 @@ fn get_usermanager_html() -> String {
             <button onclick="hideAddUser()">Cancel</button>
         </div>
     </div>
+    <div id="totp-dialog" class="dialog" style="display:none;">
+        <h3>Set Up Two-Factor Login</h3>
+        <div id="totp-setup">
+            <p>Add this account to your authenticator app with the link or the key, then enter the code it shows.</p>
+            <p class="secret"><a id="totp-url"></a></p>
+            <p class="secret" id="totp-secret"></p>
+            <input type="text" id="totp-code" placeholder="6-digit code" autocomplete="off">
+            <p class="dialog-error" id="totp-error"></p>
+            <div class="dialog-buttons">
+                <button onclick="confirmTotp()">Turn On</button>
+                <button onclick="hideTotp()">Cancel</button>
+            </div>
+        </div>
+        <div id="totp-done" style="display:none;">
+            <p>Two-factor login is on. Keep these recovery codes somewhere safe; each logs in once without the app, and they will not be shown again.</p>
+            <pre id="recovery-codes"></pre>
+            <div class="dialog-buttons">
+                <button onclick="hideTotp()">Done</button>
+            </div>
+        </div>
+    </div>
+    <div id="elevate-dialog" class="dialog" style="display:none;">
+        <h3>Administrator Rights Needed</h3>
+        <p id="elevate-reason"></p>
+        <input type="text" id="elevate-username" placeholder="Administrator" value="admin">
+        <input type="password" id="elevate-password" placeholder="Password">
+        <div class="dialog-buttons">
+            <button onclick="elevate()">Continue</button>
+            <button onclick="hideElevate()">Cancel</button>
+        </div>
+    </div>
 </div>"#)
 }
 
@@ -1321,11 +3264,23 @@ fn get_usermanager_css() -> String {
 .dialog-buttons button { flex: 1; padding: 10px; border: none; border-radius: 6px; cursor: pointer; }
 .dialog-buttons button:first-child { background: #667eea; color: white; }
 .dialog-buttons button:last-child { background: #f0f0f0; }
+.dialog-buttons button:only-child { background: #667eea; color: white; }
+.dialog p { margin-bottom: 12px; max-width: 420px; }
+.dialog .secret { font-family: monospace; word-break: break-all; }
+.dialog-error { color: #e53e3e; }
+#recovery-codes { background: #f5f5f5; padding: 12px; border-radius: 6px; margin-bottom: 12px; }
 "#)
 }
 
 fn get_usermanager_js() -> String {
     String::from(r#"
+// Admin requests last sent, by type, to send again with elevation
+const sent = {};
+let elevating = null;
+function send(msg) {
+    sent[msg.type] = msg;
+    window.parent.postMessage(msg, '*');
+}
 function loadUsers() {
     window.parent.postMessage({ type: 'list_users' }, '*');
 }
@@ -1336,8 +3291,12 @@ function renderUsers(users) {
             <td>${u.id}</td>
             <td>${u.username}</td>
             <td>${u.is_admin ? 'Administrator' : 'User'}</td>
-            <td>${u.is_active ? 'Active' : 'Inactive'}</td>
+            <td>${!u.is_active ? 'Inactive' : u.locked_for ? `Locked (${Math.ceil(u.locked_for / 60)} min)` : 'Active'}</td>
+            <td>${u.last_login || 'Never'}</td>
+            <td title="${u.failures}">${u.failures ? u.failures.split(', ').length + ', last ' + u.failures.split(', ').pop() : 'None'}</td>
+            <td>${u.two_factor ? `On <button onclick="disableTotp(${u.id})">Turn Off</button>` : `Off <button onclick="beginTotp(${u.id})">Set Up</button>`}</td>
             <td>
+                ${u.locked_for ? `<button onclick="unlockUser(${u.id})">Unlock</button>` : ''}
                 <button onclick="toggleUser(${u.id}, ${!u.is_active})">${u.is_active ? 'Deactivate' : 'Activate'}</button>
                 <button class="delete" onclick="deleteUser(${u.id})">Delete</button>
             </td>
@@ -1355,21 +3314,84 @@ function addUser() {
     const password = document.getElementById('new-password').value;
     const isAdmin = document.getElementById('new-is-admin').checked;
     if (username && password) {
-        window.parent.postMessage({ type: 'add_user', username, password, is_admin: isAdmin }, '*');
+        send({ type: 'add_user', username, password, is_admin: isAdmin });
         hideAddUser();
     }
 }
+function unlockUser(id) {
+    send({ type: 'unlock_user', id });
+}
+let totpUser = null;
+function beginTotp(id) {
+    send({ type: 'begin_totp', id, algorithm: 'sha1' });
+}
+function showTotp(id, secret, url) {
+    totpUser = id;
+    document.getElementById('totp-url').textContent = url;
+    document.getElementById('totp-url').href = url;
+    document.getElementById('totp-secret').textContent = secret.match(/.{1,4}/g).join(' ');
+    document.getElementById('totp-code').value = '';
+    document.getElementById('totp-error').textContent = '';
+    document.getElementById('totp-setup').style.display = 'block';
+    document.getElementById('totp-done').style.display = 'none';
+    document.getElementById('totp-dialog').style.display = 'block';
+}
+function confirmTotp() {
+    const code = document.getElementById('totp-code').value.trim();
+    if (code) send({ type: 'confirm_totp', id: totpUser, code });
+}
+function showRecoveryCodes(codes) {
+    document.getElementById('recovery-codes').textContent = codes.split(' ').join('\n');
+    document.getElementById('totp-setup').style.display = 'none';
+    document.getElementById('totp-done').style.display = 'block';
+}
+function hideTotp() {
+    totpUser = null;
+    document.getElementById('totp-dialog').style.display = 'none';
+}
+function disableTotp(id) {
+    if (confirm('Turn off two-factor login for this user?')) {
+        send({ type: 'disable_totp', id });
+    }
+}
 function toggleUser(id, active) {
-    window.parent.postMessage({ type: 'toggle_user', id, active }, '*');
+    send({ type: 'toggle_user', id, active });
 }
 function deleteUser(id) {
     if (confirm('Are you sure you want to delete this user?')) {
-        window.parent.postMessage({ type: 'delete_user', id }, '*');
+        const home = confirm('Keep an archive of their home directory in /var/archive? Cancel removes it without one.')
+            ? 'archive' : 'remove';
+        send({ type: 'delete_user', id, home });
     }
 }
+function showElevate(request, message) {
+    elevating = sent[request];
+    if (!elevating) return;
+    document.getElementById('elevate-reason').textContent = message;
+    document.getElementById('elevate-password').value = '';
+    document.getElementById('elevate-dialog').style.display = 'block';
+}
+function hideElevate() {
+    elevating = null;
+    document.getElementById('elevate-dialog').style.display = 'none';
+}
+function elevate() {
+    const username = document.getElementById('elevate-username').value;
+    const password = document.getElementById('elevate-password').value;
+    window.parent.postMessage({ type: 'elevate', username, password, request: elevating }, '*');
+    hideElevate();
+}
 window.addEventListener('message', (e) => {
     if (e.data.type === 'users_list') {
         renderUsers(e.data.users);
+    } else if (e.data.type === 'totp_secret') {
+        showTotp(e.data.id, e.data.secret, e.data.url);
+    } else if (e.data.type === 'recovery_codes') {
+        showRecoveryCodes(e.data.codes);
+    } else if (e.data.type === 'error' && e.data.request === 'confirm_totp') {
+        document.getElementById('totp-error').textContent = e.data.message;
+    } else if (e.data.type === 'elevation_required') {
+        showElevate(e.data.request, e.data.message);
     }
 });
 loadUsers();
@@ -1379,43 +3401,103 @@ loadUsers();
 fn get_terminal_html() -> String {
     String::from(r#"<div class="terminal">
     <div id="output"></div>
-    <div class="input-line">
-        <span class="prompt">$</span>
-        <input type="text" id="input" autofocus autocomplete="off">
-    </div>
+    <input type="text" id="input" autofocus autocomplete="off">
+    <span id="measure">M</span>
 </div>"#)
 }
 
 fn get_terminal_css() -> String {
     String::from(r#"
-.terminal { height: 100%; background: #1e1e1e; color: #d4d4d4; font-family: 'Consolas', monospace; font-size: 14px; padding: 12px; overflow-y: auto; display: flex; flex-direction: column; }
-#output { flex: 1; white-space: pre-wrap; }
-.input-line { display: flex; align-items: center; }
-.prompt { color: #667eea; margin-right: 8px; }
-#input { flex: 1; background: transparent; border: none; color: #d4d4d4; font-family: inherit; font-size: inherit; outline: none; }
+.terminal { height: 100%; background: #1e1e1e; color: #d4d4d4; font-family: 'Consolas', monospace; font-size: 14px; padding: 12px; overflow-y: auto; box-sizing: border-box; }
+#output { white-space: pre-wrap; display: inline; }
+#input { background: transparent; border: none; color: #d4d4d4; font-family: inherit; font-size: inherit; outline: none; width: 50%; padding: 0; }
+#measure { position: absolute; visibility: hidden; }
+.bold { font-weight: bold; }
 "#)
 }
 
 fn get_terminal_js() -> String {
     String::from(r#"
+const terminal = document.querySelector('.terminal');
 const output = document.getElementById('output');
 const input = document.getElementById('input');
+const post = (msg) => window.parent.postMessage(msg, '*');
+const palette = ['#1e1e1e', '#f14c4c', '#23d18b', '#f5f543', '#3b8eea', '#d670d6', '#29b8db', '#e5e5e5'];
+const bright = ['#666666', '#f14c4c', '#23d18b', '#f5f543', '#3b8eea', '#d670d6', '#29b8db', '#ffffff'];
 const history = [];
 let historyIndex = -1;
-function println(text) {
-    output.textContent += text + '\n';
-    output.parentElement.scrollTop = output.parentElement.scrollHeight;
+let style = { fg: null, bg: null, bold: false };
+let exited = false;
+
+function size() {
+    const cell = document.getElementById('measure').getBoundingClientRect();
+    return {
+        cols: Math.max(1, Math.floor((terminal.clientWidth - 24) / cell.width)),
+        rows: Math.max(1, Math.floor((terminal.clientHeight - 24) / cell.height)),
+    };
+}
+function append(text) {
+    if (!text) return;
+    const span = document.createElement('span');
+    if (style.fg) span.style.color = style.fg;
+    if (style.bg) span.style.background = style.bg;
+    if (style.bold) span.className = 'bold';
+    span.textContent = text;
+    output.appendChild(span);
+}
+function erase() {
+    const last = output.lastChild;
+    if (last) {
+        last.textContent = last.textContent.slice(0, -1);
+        if (!last.textContent) output.removeChild(last);
+    }
+}
+// Select Graphic Rendition: colors and bold
+function sgr(params) {
+    const codes = params ? params.split(';').map(Number) : [0];
+    for (const code of codes) {
+        if (code === 0) style = { fg: null, bg: null, bold: false };
+        else if (code === 1) style.bold = true;
+        else if (code === 22) style.bold = false;
+        else if (code >= 30 && code <= 37) style.fg = palette[code - 30];
+        else if (code === 39) style.fg = null;
+        else if (code >= 40 && code <= 47) style.bg = palette[code - 40];
+        else if (code === 49) style.bg = null;
+        else if (code >= 90 && code <= 97) style.fg = bright[code - 90];
+    }
+}
+function write(data) {
+    // Text and CSI sequences alternate: text, params, final byte, text, ...
+    const parts = data.split(/\x1b\[([0-9;]*)([A-Za-z])/);
+    for (let i = 0; i < parts.length; i += 3) {
+        let text = '';
+        for (const ch of parts[i].replace(/\r\n/g, '\n')) {
+            if (ch === '\b') { append(text); text = ''; erase(); }
+            else if (ch !== '\r') text += ch;
+        }
+        append(text);
+        if (i + 2 < parts.length) {
+            const [params, command] = [parts[i + 1], parts[i + 2]];
+            if (command === 'm') sgr(params);
+            else if (command === 'J' && params === '2') output.textContent = '';
+        }
+    }
+    terminal.scrollTop = terminal.scrollHeight;
 }
 input.addEventListener('keydown', (e) => {
+    if (exited) return;
     if (e.key === 'Enter') {
-        const cmd = input.value.trim();
-        if (cmd) {
+        const cmd = input.value;
+        if (cmd.trim()) {
             history.push(cmd);
             historyIndex = history.length;
-            println('$ ' + cmd);
-            window.parent.postMessage({ type: 'terminal_command', command: cmd }, '*');
         }
+        post({ type: 'terminal_input', data: cmd + '\r' });
+        input.value = '';
+    } else if (e.ctrlKey && e.key === 'c') {
+        e.preventDefault();
         input.value = '';
+        post({ type: 'terminal_input', data: '\x03' });
     } else if (e.key === 'ArrowUp') {
         e.preventDefault();
         if (historyIndex > 0) {
@@ -1433,72 +3515,323 @@ input.addEventListener('keydown', (e) => {
         }
     }
 });
+terminal.addEventListener('click', () => input.focus());
+window.addEventListener('resize', () => post({ type: 'terminal_resize', ...size() }));
 window.addEventListener('message', (e) => {
     if (e.data.type === 'terminal_output') {
-        println(e.data.text);
+        write(e.data.text);
+    } else if (e.data.type === 'terminal_exited') {
+        exited = true;
+        input.disabled = true;
+        write('\n[shell exited]\n');
     }
 });
-window.parent.postMessage({ type: 'terminal_ready' }, '*');
+post({ type: 'terminal_ready', ...size() });
 "#)
 }
 
-fn get_browser_html() -> String {
-    String::from(r#"<div class="browser">
-    <div class="toolbar">
-        <button onclick="goBack()">◀</button>
-        <button onclick="goForward()">▶</button>
-        <button onclick="reload()">↻</button>
-        <input type="text" id="url-bar" placeholder="Enter URL...">
-        <button onclick="navigate()">Go</button>
+fn get_settings_html() -> String {
+    String::from(r#"<div class="settings">
+    <div class="sidebar">
+        <div class="page active" data-page="display">🖥 Display</div>
+        <div class="page" data-page="personalization">🎨 Personalization</div>
+        <div class="page" data-page="keyboard">⌨ Keyboard</div>
+        <div class="page" data-page="network">🌐 Network</div>
+        <div class="page" data-page="apps">📦 Apps</div>
+        <div class="page" data-page="certificates">🔒 Certificates</div>
+    </div>
+    <div class="content" id="page-display">
+        <h2>Display</h2>
+        <div class="row">
+            <label for="resolution">Resolution</label>
+            <select id="resolution"></select>
+        </div>
+        <div class="row">
+            <label for="depth">Color depth</label>
+            <select id="depth">
+                <option value="32">32-bit</option>
+                <option value="24">24-bit</option>
+                <option value="16">16-bit</option>
+            </select>
+        </div>
+        <div class="row">
+            <button onclick="applyMode()">Apply</button>
+        </div>
+    </div>
+    <div class="content hidden" id="page-personalization">
+        <h2>Personalization</h2>
+        <div class="row">
+            <label for="wallpaper">Wallpaper</label>
+            <select id="wallpaper" data-key="desktop.wallpaper"></select>
+        </div>
+        <div class="row">
+            <label for="theme">Theme</label>
+            <select id="theme" data-key="desktop.theme"></select>
+        </div>
+        <div class="row">
+            <label for="lock-timeout">Lock after</label>
+            <select id="lock-timeout" data-key="desktop.lock_timeout">
+                <option value="1">1 minute</option>
+                <option value="5">5 minutes</option>
+                <option value="15">15 minutes</option>
+                <option value="30">30 minutes</option>
+                <option value="0">Never</option>
+            </select>
+        </div>
+    </div>
+    <div class="content hidden" id="page-keyboard">
+        <h2>Keyboard</h2>
+        <div class="row">
+            <label for="layout">Layout</label>
+            <select id="layout" data-key="keyboard.layout"></select>
+        </div>
+    </div>
+    <div class="content hidden" id="page-network">
+        <h2>Network</h2>
+        <div class="row">
+            <label for="net-mode">Mode</label>
+            <select id="net-mode" data-key="network.mode"></select>
+        </div>
+        <div class="row"><label for="net-address">Address</label><input id="net-address" data-field="network.address"></div>
+        <div class="row"><label for="net-netmask">Netmask</label><input id="net-netmask" data-field="network.netmask"></div>
+        <div class="row"><label for="net-gateway">Gateway</label><input id="net-gateway" data-field="network.gateway"></div>
+        <div class="row"><label for="net-dns">DNS server</label><input id="net-dns" data-field="network.dns"></div>
+        <div class="row">
+            <label for="dns-mode">Encrypted DNS</label>
+            <select id="dns-mode" data-key="network.dns_mode"></select>
+        </div>
+        <div class="row"><label for="dns-upstream">Resolver</label><input id="dns-upstream" data-field="network.dns_upstream" placeholder="1.1.1.1"></div>
+        <div class="row">
+            <label for="dns-fallback">Plain DNS if it fails</label>
+            <select id="dns-fallback" data-key="network.dns_fallback"></select>
+        </div>
+        <div class="row"><label for="net-proxy">SOCKS proxy</label><input id="net-proxy" data-field="network.proxy" placeholder="socks5://host:1080 (optional)"></div>
+        <div class="row"><label for="auth-server">Login server</label><input id="auth-server" data-field="auth.server" placeholder="https://... (optional)"></div>
+        <div class="row">
+            <button onclick="applyNetwork()">Apply</button>
+        </div>
+    </div>
+    <div class="content hidden" id="page-apps">
+        <h2>Apps</h2>
+        <div class="row">
+            <label for="package-source">Install</label>
+            <input id="package-source" placeholder="/home/user/clock.wapp or URL">
+            <button onclick="installPackage()">Install</button>
+        </div>
+        <table class="packages">
+            <tbody id="package-list"></tbody>
+        </table>
+    </div>
+    <div class="content hidden" id="page-certificates">
+        <h2>Certificates</h2>
+        <div class="row">
+            <textarea id="cert-pem" placeholder="-----BEGIN CERTIFICATE-----"></textarea>
+        </div>
+        <div class="row">
+            <button onclick="addCertificate()">Trust</button>
+            <input id="pin-host" placeholder="lab.example (to pin)">
+            <button onclick="pinCertificate()">Pin to host</button>
+        </div>
+        <h3>Trusted</h3>
+        <table class="packages">
+            <tbody id="certificate-list"></tbody>
+        </table>
+        <h3>Pinned</h3>
+        <table class="packages">
+            <tbody id="pin-list"></tbody>
+        </table>
+    </div>
+    <div id="status"></div>
+    <div id="elevate-dialog" class="dialog" style="display:none;">
+        <h3>Administrator Rights Needed</h3>
+        <p id="elevate-reason"></p>
+        <input type="text" id="elevate-username" placeholder="Administrator" value="admin">
+        <input type="password" id="elevate-password" placeholder="Password">
+        <div class="dialog-buttons">
+            <button onclick="elevate()">Continue</button>
+            <button onclick="hideElevate()">Cancel</button>
+        </div>
     </div>
-    <iframe id="webview" sandbox="allow-scripts allow-same-origin"></iframe>
 </div>"#)
 }
 
-fn get_browser_css() -> String {
+fn get_settings_css() -> String {
     String::from(r#"
-.browser { height: 100%; display: flex; flex-direction: column; }
-.toolbar { padding: 8px; background: #f0f0f0; border-bottom: 1px solid #ddd; display: flex; gap: 8px; }
-.toolbar button { padding: 6px 12px; background: white; border: 1px solid #ccc; border-radius: 4px; cursor: pointer; }
-#url-bar { flex: 1; padding: 6px 12px; border: 1px solid #ccc; border-radius: 4px; }
-#webview { flex: 1; border: none; }
+.settings { display: flex; height: 100%; position: relative; }
+.sidebar { width: 160px; background: #f5f5f5; border-right: 1px solid #ddd; padding: 8px 0; }
+.page { padding: 10px 16px; cursor: pointer; }
+.page.active { background: #667eea; color: white; }
+.content { flex: 1; padding: 20px; }
+.content.hidden { display: none; }
+.content h2 { margin: 0 0 16px; font-size: 18px; }
+.row { display: flex; align-items: center; gap: 12px; margin-bottom: 12px; }
+.row label { width: 100px; }
+.row select, .row input { padding: 6px; min-width: 160px; }
+.row button { padding: 6px 20px; background: #667eea; color: white; border: none; border-radius: 4px; cursor: pointer; }
+.packages { width: 100%; border-collapse: collapse; }
+.packages td { padding: 6px 4px; border-bottom: 1px solid #eee; }
+.content h3 { margin: 16px 0 8px; font-size: 14px; }
+#cert-pem { width: 100%; height: 90px; font-family: monospace; font-size: 11px; }
+.packages .fingerprint { font-family: monospace; font-size: 11px; color: #666; }
+.packages button { padding: 4px 12px; border: 1px solid #ccc; border-radius: 4px; background: white; cursor: pointer; }
+#status { position: absolute; left: 176px; bottom: 12px; font-size: 12px; color: #666; }
+.dialog { position: absolute; top: 50%; left: 50%; transform: translate(-50%, -50%); width: 280px; background: white; padding: 20px; border-radius: 10px; box-shadow: 0 20px 60px rgba(0,0,0,0.3); }
+.dialog h3 { margin: 0 0 8px; }
+.dialog p { font-size: 13px; color: #666; }
+.dialog input { display: block; width: 100%; box-sizing: border-box; padding: 8px; margin-bottom: 10px; border: 1px solid #ddd; border-radius: 6px; }
+.dialog-buttons { display: flex; gap: 10px; }
+.dialog-buttons button { flex: 1; padding: 8px; border: none; border-radius: 6px; cursor: pointer; }
+.dialog-buttons button:first-child { background: #667eea; color: white; }
+.dialog-buttons button:last-child { background: #f0f0f0; }
 "#)
 }
 
-fn get_browser_js() -> String {
+fn get_settings_js() -> String {
     String::from(r#"
-const urlBar = document.getElementById('url-bar');
-const webview = document.getElementById('webview');
-let history = [];
-let historyPos = -1;
-function navigate() {
-    let url = urlBar.value;
-    if (!url.match(/^https?:\/\//)) url = 'http://' + url;
-    window.parent.postMessage({ type: 'browser_navigate', url }, '*');
-}
-function goBack() {
-    if (historyPos > 0) {
-        historyPos--;
-        webview.src = history[historyPos];
-    }
-}
-function goForward() {
-    if (historyPos < history.length - 1) {
-        historyPos++;
-        webview.src = history[historyPos];
-    }
-}
-function reload() {
-    webview.contentWindow.location.reload();
-}
-urlBar.addEventListener('keypress', (e) => {
-    if (e.key === 'Enter') navigate();
+const resolution = document.getElementById('resolution');
+const depth = document.getElementById('depth');
+const status = document.getElementById('status');
+const post = (msg) => window.parent.postMessage(msg, '*');
+// What the last admin action posted, to post again with elevation
+let adminRequests = [];
+let elevating = false;
+function postAdmin(msgs) {
+    adminRequests = msgs;
+    msgs.forEach(post);
+}
+document.querySelectorAll('.page').forEach(page => page.addEventListener('click', () => {
+    document.querySelectorAll('.page').forEach(p => p.classList.toggle('active', p === page));
+    document.querySelectorAll('.content').forEach(c =>
+        c.classList.toggle('hidden', c.id !== 'page-' + page.dataset.page));
+}));
+function loadModes() {
+    post({ type: 'get_display_modes', bpp: parseInt(depth.value) });
+}
+function applyMode() {
+    const [width, height] = resolution.value.split('x').map(Number);
+    status.textContent = 'Switching...';
+    post({ type: 'set_display_mode', width, height, bpp: parseInt(depth.value) });
+}
+function applyNetwork() {
+    // Addresses first, so switching to static mode has them
+    const msgs = [];
+    document.querySelectorAll('[data-field]').forEach(input =>
+        msgs.push({ type: 'set_setting', key: input.dataset.field, value: input.value }));
+    document.querySelectorAll('#page-network select[data-key]').forEach(select => {
+        if (select.dataset.key !== 'network.mode')
+            msgs.push({ type: 'set_setting', key: select.dataset.key, value: select.value });
+    });
+    msgs.push({ type: 'set_setting', key: 'network.mode', value: document.getElementById('net-mode').value });
+    postAdmin(msgs);
+}
+function installPackage() {
+    const source = document.getElementById('package-source').value.trim();
+    if (!source) return;
+    status.textContent = 'Installing...';
+    postAdmin([{ type: 'install_package', source }]);
+}
+function uninstallPackage(name) {
+    if (confirm('Uninstall ' + name + '?')) postAdmin([{ type: 'uninstall_package', name }]);
+}
+function addCertificate() {
+    const pem = document.getElementById('cert-pem').value.trim();
+    if (!pem) return;
+    postAdmin([{ type: 'add_certificate', pem }]);
+}
+function removeCertificate(id) {
+    if (confirm('Stop trusting certificate ' + id + '?')) postAdmin([{ type: 'remove_certificate', id }]);
+}
+// The pasted certificate, or a SHA-256 fingerprint typed in its place
+function pinCertificate() {
+    const host = document.getElementById('pin-host').value.trim();
+    const certificate = document.getElementById('cert-pem').value.trim();
+    if (!host || !certificate) return;
+    postAdmin([{ type: 'pin_certificate', host, certificate }]);
+}
+function unpinCertificate(host) {
+    postAdmin([{ type: 'unpin_certificate', host }]);
+}
+// Each refused request of an action asks; one dialog covers them all
+function showElevate(message) {
+    if (elevating || !adminRequests.length) return;
+    elevating = true;
+    document.getElementById('elevate-reason').textContent = message;
+    document.getElementById('elevate-password').value = '';
+    document.getElementById('elevate-dialog').style.display = 'block';
+}
+function hideElevate() {
+    elevating = false;
+    document.getElementById('elevate-dialog').style.display = 'none';
+}
+function elevate() {
+    const username = document.getElementById('elevate-username').value;
+    const password = document.getElementById('elevate-password').value;
+    adminRequests.forEach(request => post({ type: 'elevate', username, password, request }));
+    hideElevate();
+}
+// Choices other than the network's apply as soon as they change
+document.querySelectorAll('select[data-key]').forEach(select => {
+    if (select.dataset.key.startsWith('network.')) return;
+    select.addEventListener('change', () => post({ type: 'set_setting', key: select.dataset.key, value: select.value }));
 });
+depth.addEventListener('change', loadModes);
 window.addEventListener('message', (e) => {
-    if (e.data.type === 'browser_content') {
-        webview.srcdoc = e.data.html;
-        urlBar.value = e.data.url;
+    if (e.data.type === 'display_modes') {
+        resolution.innerHTML = e.data.modes.map(m =>
+            `<option value="${m.width}x${m.height}">${m.width} × ${m.height}</option>`
+        ).join('');
+        resolution.value = e.data.width + 'x' + e.data.height;
+        if (!e.data.modes.length) status.textContent = 'Mode setting not supported on this adapter';
+    } else if (e.data.type === 'display_mode_result') {
+        status.textContent = e.data.ok ? 'Display mode changed' : 'Failed: ' + e.data.error;
+    } else if (e.data.type === 'settings') {
+        e.data.choices.forEach(c => {
+            const select = document.querySelector(`select[data-key="${c.key}"]`);
+            if (select) select.innerHTML = c.values.split(',').map(v => `<option value="${v}">${v}</option>`).join('');
+        });
+        e.data.values.forEach(v => {
+            const el = document.querySelector(`[data-key="${v.key}"], [data-field="${v.key}"]`);
+            if (el) el.value = v.value;
+        });
+    } else if (e.data.type === 'setting_result') {
+        status.textContent = e.data.error ? e.data.key + ': ' + e.data.error : 'Saved ' + e.data.key;
+    } else if (e.data.type === 'packages') {
+        document.getElementById('package-list').innerHTML = e.data.packages.length
+            ? e.data.packages.map(p => `<tr>
+                <td>${p.icon} ${p.title}</td>
+                <td>${p.description}</td>
+                <td><button onclick="uninstallPackage('${p.name}')">Uninstall</button></td>
+            </tr>`).join('')
+            : '<tr><td>No apps installed</td></tr>';
+    } else if (e.data.type === 'package_result') {
+        status.textContent = e.data.ok ? 'Done: ' + e.data.name : 'Failed: ' + e.data.error;
+    } else if (e.data.type === 'certificates') {
+        document.getElementById('certificate-list').innerHTML = e.data.anchors.length
+            ? e.data.anchors.map(c => `<tr>
+                <td>${c.subject}</td>
+                <td>until ${c.expires}</td>
+                <td class="fingerprint">${c.id}</td>
+                <td><button onclick="removeCertificate('${c.id}')">Remove</button></td>
+            </tr>`).join('')
+            : '<tr><td>No certificates added</td></tr>';
+        document.getElementById('pin-list').innerHTML = e.data.pins.length
+            ? e.data.pins.map(p => `<tr>
+                <td>${p.host}</td>
+                <td class="fingerprint">${p.fingerprint.slice(0, 32)}…</td>
+                <td><button onclick="unpinCertificate('${p.host}')">Unpin</button></td>
+            </tr>`).join('')
+            : '<tr><td>No hosts pinned</td></tr>';
+    } else if (e.data.type === 'certificate_result') {
+        status.textContent = e.data.ok ? 'Done: ' + e.data.name : 'Failed: ' + e.data.error;
+        if (e.data.ok) document.getElementById('cert-pem').value = '';
+    } else if (e.data.type === 'elevation_required') {
+        status.textContent = '';
+        showElevate(e.data.message);
     }
 });
+loadModes();
+post({ type: 'get_settings' });
+post({ type: 'list_packages' });
+post({ type: 'list_certificates' });
 "#)
 }
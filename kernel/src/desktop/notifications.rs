@@ -0,0 +1,170 @@
+//! Notification center
+//!
+//! Kernel subsystems and apps post notifications with `notify`. Each one
+//! shows as a toast in the top right corner of the primary display until
+//! its timeout runs out or it is clicked, and stays in the history the
+//! taskbar's notification panel lists.
+//!
+//! `notify` only takes this module's lock, so it is safe to call from any
+//! subsystem, even one the desktop calls into. The desktop picks changes up
+//! on its next `tick`.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::println;
+use crate::info;
+
+/// Notifications kept in the history
+const MAX_HISTORY: usize = 50;
+
+/// Toasts on screen at once; later ones wait for a slot to free up
+pub const MAX_TOASTS: usize = 4;
+
+/// A posted notification
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub id: u32,
+    pub title: String,
+    pub body: String,
+    pub icon: char,
+    /// RTC time of day it was posted, in seconds
+    pub posted: u32,
+    /// Seconds the toast stays up; `None` keeps it until dismissed
+    pub timeout: Option<u32>,
+    /// RTC time of day the toast got a slot on screen
+    pub shown: Option<u32>,
+    /// Still showing as a toast
+    pub toast: bool,
+    /// Seen in the notification panel
+    pub read: bool,
+}
+
+struct NotificationCenter {
+    history: VecDeque<Notification>,
+    next_id: u32,
+}
+
+static CENTER: Mutex<NotificationCenter> = Mutex::new(NotificationCenter {
+    history: VecDeque::new(),
+    next_id: 1,
+});
+
+/// Set whenever what the desktop shows of notifications changes
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+fn now() -> u32 {
+    crate::drivers::rtc::now().seconds_of_day()
+}
+
+/// Seconds from `start` to `end`, both RTC times of day
+fn elapsed(start: u32, end: u32) -> u32 {
+    (end + 86400 - start) % 86400
+}
+
+/// Post a notification and return its ID
+///
+/// `timeout` is in seconds; 0 keeps the toast up until it is clicked.
+pub fn notify(title: &str, body: &str, icon: char, timeout: u32) -> u32 {
+    let mut center = CENTER.lock();
+    let id = center.next_id;
+    center.next_id += 1;
+    if center.history.len() >= MAX_HISTORY {
+        center.history.pop_front();
+    }
+    center.history.push_back(Notification {
+        id,
+        title: String::from(title),
+        body: String::from(body),
+        icon,
+        posted: now(),
+        timeout: if timeout == 0 { None } else { Some(timeout) },
+        shown: None,
+        toast: true,
+        read: false,
+    });
+    drop(center);
+    CHANGED.store(true, Ordering::Release);
+    crate::sound::chime();
+    info!("notify", "{}: {}", title, body);
+    id
+}
+
+/// Take a notification's toast off the screen; it stays in the history
+pub fn dismiss(id: u32) {
+    let mut center = CENTER.lock();
+    if let Some(n) = center.history.iter_mut().find(|n| n.id == id && n.toast) {
+        n.toast = false;
+        CHANGED.store(true, Ordering::Release);
+    }
+}
+
+/// Toasts to show, oldest first
+pub fn toasts() -> Vec<Notification> {
+    CENTER.lock().history.iter().filter(|n| n.toast).take(MAX_TOASTS).cloned().collect()
+}
+
+/// All notifications, newest first
+pub fn history() -> Vec<Notification> {
+    CENTER.lock().history.iter().rev().cloned().collect()
+}
+
+/// Notifications not yet seen in the panel
+pub fn unread() -> usize {
+    CENTER.lock().history.iter().filter(|n| !n.read).count()
+}
+
+/// Mark everything as seen, e.g. when the panel opens
+pub fn mark_all_read() {
+    let mut center = CENTER.lock();
+    if center.history.iter().any(|n| !n.read) {
+        center.history.iter_mut().for_each(|n| n.read = true);
+        CHANGED.store(true, Ordering::Release);
+    }
+}
+
+/// Empty the history, toasts included
+pub fn clear() {
+    let mut center = CENTER.lock();
+    if !center.history.is_empty() {
+        center.history.clear();
+        CHANGED.store(true, Ordering::Release);
+    }
+}
+
+/// Retire toasts whose timeout ran out and report whether anything the
+/// desktop shows changed since the last call
+///
+/// A toast's timeout counts from when it got a slot on screen.
+pub fn update() -> bool {
+    let t = now();
+    {
+        let mut center = CENTER.lock();
+        for n in center.history.iter_mut().filter(|n| n.toast).take(MAX_TOASTS) {
+            let shown = *n.shown.get_or_insert(t);
+            if n.timeout.map_or(false, |timeout| elapsed(shown, t) >= timeout) {
+                n.toast = false;
+                CHANGED.store(true, Ordering::Release);
+            }
+        }
+    }
+    CHANGED.swap(false, Ordering::AcqRel)
+}
+
+/// Print the notification history
+pub fn print_info() {
+    let center = CENTER.lock();
+    if center.history.is_empty() {
+        println!("No notifications");
+        return;
+    }
+    println!("Notifications (newest first):");
+    for n in center.history.iter().rev() {
+        let t = n.posted;
+        println!("  {:>3} {:02}:{:02}:{:02} {}{} - {}: {}", n.id, t / 3600, t / 60 % 60, t % 60,
+            if n.toast { "*" } else { " " }, if n.read { " " } else { "!" }, n.title, n.body);
+    }
+}
@@ -0,0 +1,304 @@
+//! Installable app packages
+//!
+//! A `.wapp` package is a tar archive with a `manifest` at its top level
+//! and the app's HTML, CSS, JS and any images beside it. The manifest
+//! uses `key = value` lines; `#` starts a comment:
+//!
+//! ```text
+//! name = clock
+//! title = Clock
+//! icon = ⏰
+//! description = A world clock
+//! singleton = true
+//! html = index.html
+//! css = style.css
+//! js = app.js
+//! capabilities = fs:/home/clock, network
+//! ```
+//!
+//! `name` is required and may only use lowercase letters, digits, `-` and
+//! `_`; `html` is required, `css` and `js` are optional, and `icon` is the
+//! character shown in menus. `capabilities` lists what the app may do,
+//! comma separated, as described in `permissions`; an app that declares
+//! none may only use requests that need no capability. Installing unpacks the archive into
+//! `/var/apps/<name>/` and registers the app with the desktop; packages
+//! found there are registered again at boot.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::permissions::{self, Capability};
+use super::Application;
+use crate::fs::{self, tar, FsError, FsResult};
+use crate::println;
+use crate::users::audit;
+use crate::info;
+
+/// Where installed packages are unpacked, one directory each
+pub const INSTALL_DIR: &str = "/var/apps";
+
+const MANIFEST: &str = "manifest";
+
+/// What a package's manifest says about its app
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub name: String,
+    pub title: String,
+    pub icon: char,
+    pub description: String,
+    pub singleton: bool,
+    pub html: String,
+    pub css: Option<String>,
+    pub js: Option<String>,
+    pub capabilities: Vec<Capability>,
+}
+
+/// Why a package could not be installed or removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageError {
+    /// The archive or its manifest is malformed
+    Invalid(String),
+    /// An app with the package's name is already on the desktop
+    Exists(String),
+    NotInstalled(String),
+    Fs(FsError),
+    /// The package could not be downloaded
+    Download(String),
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackageError::Invalid(message) => write!(f, "invalid package: {}", message),
+            PackageError::Exists(name) => write!(f, "an app called '{}' is already installed", name),
+            PackageError::NotInstalled(name) => write!(f, "no installed package '{}'", name),
+            PackageError::Fs(e) => write!(f, "{:?}", e),
+            PackageError::Download(message) => write!(f, "download failed: {}", message),
+        }
+    }
+}
+
+impl From<FsError> for PackageError {
+    fn from(e: FsError) -> Self {
+        PackageError::Fs(e)
+    }
+}
+
+impl Manifest {
+    /// Parse a manifest file
+    pub fn parse(text: &str) -> Result<Self, PackageError> {
+        let invalid = |message: String| PackageError::Invalid(message);
+        let mut manifest = Manifest {
+            name: String::new(),
+            title: String::new(),
+            icon: '📦',
+            description: String::new(),
+            singleton: false,
+            html: String::new(),
+            css: None,
+            js: None,
+            capabilities: Vec::new(),
+        };
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| invalid(format!("manifest line {}: expected key = value", n + 1)))?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "name" => manifest.name = value,
+                "title" => manifest.title = value,
+                "icon" => manifest.icon = value.chars().next().unwrap_or(manifest.icon),
+                "description" => manifest.description = value,
+                "singleton" => manifest.singleton = value == "true",
+                "html" => manifest.html = value,
+                "css" => manifest.css = Some(value),
+                "js" => manifest.js = Some(value),
+                "capabilities" => {
+                    for entry in value.split(',').filter(|e| !e.trim().is_empty()) {
+                        let capability = Capability::parse(entry)
+                            .ok_or_else(|| invalid(format!("manifest line {}: unknown capability '{}'", n + 1, entry.trim())))?;
+                        manifest.capabilities.push(capability);
+                    }
+                }
+                key => return Err(invalid(format!("manifest line {}: unknown key '{}'", n + 1, key))),
+            }
+        }
+
+        let valid_name = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+        if manifest.name.is_empty() || !manifest.name.chars().all(valid_name) {
+            return Err(invalid(format!("bad app name '{}'", manifest.name)));
+        }
+        if manifest.html.is_empty() {
+            return Err(invalid(String::from("manifest names no html file")));
+        }
+        if manifest.title.is_empty() {
+            manifest.title = manifest.name.clone();
+        }
+        Ok(manifest)
+    }
+
+    /// Files the manifest refers to
+    fn files(&self) -> impl Iterator<Item = &str> {
+        core::iter::once(self.html.as_str()).chain(self.css.as_deref()).chain(self.js.as_deref())
+    }
+}
+
+/// Directory an installed package lives in
+fn app_dir(name: &str) -> String {
+    format!("{}/{}", INSTALL_DIR, name)
+}
+
+/// Build the desktop's application from an installed package
+fn application(manifest: &Manifest) -> Result<Application, PackageError> {
+    let dir = app_dir(&manifest.name);
+    let read = |file: &str| -> Result<String, PackageError> {
+        let data = fs::read_file(&format!("{}/{}", dir, file))?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    };
+    Ok(Application {
+        id: 0,
+        name: manifest.name.clone(),
+        title: manifest.title.clone(),
+        icon: manifest.icon,
+        description: manifest.description.clone(),
+        html_content: read(&manifest.html)?,
+        css_styles: manifest.css.as_deref().map(read).transpose()?.unwrap_or_default(),
+        js_scripts: manifest.js.as_deref().map(read).transpose()?.unwrap_or_default(),
+        singleton: manifest.singleton,
+        native: None,
+        capabilities: Some(manifest.capabilities.clone()),
+    })
+}
+
+/// Install a package from a file path or an `http://` or `https://` URL
+///
+/// Returns the installed app's name.
+pub fn install(source: &str) -> Result<String, PackageError> {
+    let data = if source.starts_with("http://") || source.starts_with("https://") {
+        let response = crate::net::http::get(source).map_err(|e| PackageError::Download(format!("{:?}", e)))?;
+        if response.status >= 400 {
+            return Err(PackageError::Download(format!("HTTP {} {}", response.status, response.status_text)));
+        }
+        response.body
+    } else {
+        fs::read_file(source)?
+    };
+    install_archive(&data)
+}
+
+/// Install a package held in memory
+pub fn install_archive(archive: &[u8]) -> Result<String, PackageError> {
+    let entries = tar::entries(archive).map_err(|_| PackageError::Invalid(String::from("not a tar archive")))?;
+    let manifest = entries.iter()
+        .find(|e| e.path == MANIFEST && !e.is_dir)
+        .ok_or_else(|| PackageError::Invalid(String::from("no manifest")))?;
+    let manifest = Manifest::parse(&String::from_utf8_lossy(manifest.data))?;
+    for file in manifest.files() {
+        if !entries.iter().any(|e| e.path == file && !e.is_dir) {
+            return Err(PackageError::Invalid(format!("missing {}", file)));
+        }
+    }
+    if entries.iter().any(|e| e.path.split('/').any(|part| part == "..")) {
+        return Err(PackageError::Invalid(String::from("paths may not leave the package")));
+    }
+    if super::list_apps().iter().any(|a| a.name == manifest.name) {
+        return Err(PackageError::Exists(manifest.name));
+    }
+
+    let dir = app_dir(&manifest.name);
+    ensure_dir(INSTALL_DIR)?;
+    fs::create_dir(&dir)?;
+    let unpacked = entries.iter().try_for_each(|entry| unpack(&dir, entry));
+    let app = unpacked.map_err(PackageError::from).and_then(|()| application(&manifest));
+    match app {
+        Ok(app) => {
+            super::register_app(app);
+            info!("packages", "Installed {} into {}", manifest.name, dir);
+            audit::record(audit::Kind::Apps, &format!("installed app {}", manifest.name));
+            Ok(manifest.name)
+        }
+        Err(e) => {
+            let _ = fs::remove(&dir);
+            Err(e)
+        }
+    }
+}
+
+fn ensure_dir(path: &str) -> FsResult<()> {
+    match fs::create_dir(path) {
+        Ok(()) | Err(FsError::AlreadyExists) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write an archive member under `dir`, creating the directories on its
+/// path; archives need not list them before their files
+fn unpack(dir: &str, entry: &tar::Entry) -> FsResult<()> {
+    let mut path = String::from(dir);
+    let mut parts = entry.path.split('/').filter(|p| !p.is_empty()).peekable();
+    while let Some(part) = parts.next() {
+        path.push('/');
+        path.push_str(part);
+        if parts.peek().is_some() || entry.is_dir {
+            ensure_dir(&path)?;
+        } else {
+            fs::write_file(&path, entry.data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove an installed package and its app
+pub fn uninstall(name: &str) -> Result<(), PackageError> {
+    if manifest(name).is_none() {
+        return Err(PackageError::NotInstalled(String::from(name)));
+    }
+    super::unregister_app(name);
+    fs::remove(&app_dir(name))?;
+    permissions::forget(name);
+    info!("packages", "Uninstalled {}", name);
+    audit::record(audit::Kind::Apps, &format!("uninstalled app {}", name));
+    Ok(())
+}
+
+/// Manifest of the installed package called `name`
+fn manifest(name: &str) -> Option<Manifest> {
+    let data = fs::read_file(&format!("{}/{}", app_dir(name), MANIFEST)).ok()?;
+    Manifest::parse(&String::from_utf8_lossy(&data)).ok()
+}
+
+/// Manifests of every installed package
+pub fn installed() -> Vec<Manifest> {
+    fs::read_dir(INSTALL_DIR)
+        .map(|entries| entries.iter().filter_map(|e| manifest(&e.name)).collect())
+        .unwrap_or_default()
+}
+
+/// Register the apps of packages installed earlier
+pub fn load() {
+    for manifest in installed() {
+        match application(&manifest) {
+            Ok(app) => {
+                super::register_app(app);
+            }
+            Err(e) => info!("packages", "{}: {}", manifest.name, e),
+        }
+    }
+}
+
+/// Print the installed packages
+pub fn print_info() {
+    let packages = installed();
+    println!("Installed packages ({}):", INSTALL_DIR);
+    if packages.is_empty() {
+        println!("  (none)");
+    }
+    for p in packages {
+        println!("  {} {:<16} {}", p.icon, p.name, p.description);
+    }
+}
@@ -0,0 +1,735 @@
+//! Desktop painter
+//!
+//! Draws the desktop into the compositor: a gradient background on every
+//! display, desktop icons, windows in stacking order, and on the primary
+//! display notification toasts, the taskbar with its start button, window
+//! buttons, battery, notification button and clock, the start menu, notification
+//! panel and window switcher, and an open file dialog or permission
+//! prompt on top. While the session is locked only the background and
+//! the lock screen are drawn.
+//! A window's content area shows the visible text of its HTML, one line
+//! per block element, or the widgets of a native app, clipped to the
+//! area and with a scrollbar when they do not fit in it. The desktop
+//! reports what changed through `invalidate`; the compositor calls
+//! `paint` for each invalid region with drawing clipped to it, so only
+//! changed areas are redrawn.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::consent::ConsentPrompt;
+use super::dialog::FileDialog;
+use super::vesa_login::{self, LockChrome};
+use super::widgets::{self, Widget};
+use crate::drivers::vesa::colors;
+use crate::graphics::compositor::{Compositor, Rect};
+use crate::graphics::font;
+use crate::graphics::raster::{self, ColorStop};
+
+/// Height of a window's title bar
+pub const TITLE_BAR_HEIGHT: u32 = 28;
+
+/// Diameter of the title bar buttons
+pub const BUTTON_SIZE: u32 = 12;
+
+pub(super) const CORNER_RADIUS: u32 = 8;
+pub(super) const SHADOW_OFFSET: i32 = 4;
+const BUTTON_GAP: u32 = 8;
+const CONTENT_PADDING: i32 = 12;
+/// Space between lines of a window's text
+const LINE_GAP: u32 = 4;
+const ICON_SIZE: u32 = 48;
+const START_BUTTON_WIDTH: u32 = 96;
+const TASK_WIDTH: u32 = 160;
+const TASK_GAP: i32 = 4;
+/// Battery glyph, nub included
+const BATTERY_GLYPH_WIDTH: u32 = 24;
+const MENU_WIDTH: u32 = 240;
+const MENU_PADDING: i32 = 8;
+const MENU_ENTRY_HEIGHT: u32 = 28;
+const SWITCHER_WIDTH: u32 = 360;
+const TOAST_WIDTH: u32 = 300;
+const TOAST_HEIGHT: u32 = 64;
+const TOAST_GAP: i32 = 8;
+const TOAST_MARGIN: i32 = 12;
+const PANEL_WIDTH: u32 = 320;
+/// Notifications listed in the panel; older ones are only in `notify` output
+pub const PANEL_ENTRIES: usize = 6;
+
+/// Desktop backgrounds by name, each a top to bottom gradient
+pub const WALLPAPERS: [(&str, [ColorStop; 2]); 5] = [
+    ("default", [
+        ColorStop::new(0, colors::rgb(0x1E, 0x3C, 0x72)),
+        ColorStop::new(255, colors::rgb(0x2A, 0x52, 0x98)),
+    ]),
+    ("ocean", [
+        ColorStop::new(0, colors::rgb(0x0F, 0x4C, 0x5C)),
+        ColorStop::new(255, colors::rgb(0x3C, 0x9D, 0x9B)),
+    ]),
+    ("sunset", [
+        ColorStop::new(0, colors::rgb(0x6A, 0x2C, 0x70)),
+        ColorStop::new(255, colors::rgb(0xF0, 0x8A, 0x5D)),
+    ]),
+    ("forest", [
+        ColorStop::new(0, colors::rgb(0x1B, 0x3B, 0x2F)),
+        ColorStop::new(255, colors::rgb(0x4E, 0x7D, 0x4B)),
+    ]),
+    ("graphite", [
+        ColorStop::new(0, colors::rgb(0x1C, 0x1E, 0x22)),
+        ColorStop::new(255, colors::rgb(0x4A, 0x4E, 0x56)),
+    ]),
+];
+
+/// Colors of window bodies and the dialogs drawn like them
+#[derive(Debug)]
+pub struct Theme {
+    pub name: &'static str,
+    pub window_body: u32,
+    pub title_active: u32,
+    pub title_inactive: u32,
+    pub content_text: u32,
+    /// Background of lists, fields and plain buttons inside a window
+    pub field: u32,
+    /// Highlight behind the selected list entry
+    pub selection: u32,
+}
+
+pub const THEMES: [Theme; 2] = [
+    Theme {
+        name: "light",
+        window_body: colors::WHITE,
+        title_active: colors::rgb(0x3A, 0x6E, 0xA5),
+        title_inactive: colors::rgb(0x8A, 0x8F, 0x98),
+        content_text: colors::rgb(0x30, 0x30, 0x30),
+        field: colors::rgb(0xF4, 0xF5, 0xF7),
+        selection: colors::rgb(0xC8, 0xDA, 0xF0),
+    },
+    Theme {
+        name: "dark",
+        window_body: colors::rgb(0x26, 0x28, 0x2E),
+        title_active: colors::rgb(0x4C, 0x5E, 0xC8),
+        title_inactive: colors::rgb(0x44, 0x47, 0x50),
+        content_text: colors::rgb(0xE0, 0xE2, 0xE6),
+        field: colors::rgb(0x32, 0x35, 0x3C),
+        selection: colors::rgb(0x3A, 0x4C, 0x7A),
+    },
+];
+
+/// Wallpaper gradient called `name`
+pub fn wallpaper(name: &str) -> Option<&'static [ColorStop; 2]> {
+    WALLPAPERS.iter().find(|(n, _)| *n == name).map(|(_, stops)| stops)
+}
+
+/// Theme called `name`
+pub fn theme(name: &str) -> Option<&'static Theme> {
+    THEMES.iter().find(|t| t.name == name)
+}
+
+pub(super) const SHADOW: u32 = colors::argb(0x50, 0, 0, 0);
+const MINIMIZE_BUTTON: u32 = colors::rgb(0xFF, 0xBD, 0x2E);
+const MAXIMIZE_BUTTON: u32 = colors::rgb(0x28, 0xC8, 0x40);
+const CLOSE_BUTTON: u32 = colors::rgb(0xFF, 0x5F, 0x57);
+const ICON_TILE: u32 = colors::argb(0x50, 0xFF, 0xFF, 0xFF);
+const TASKBAR: u32 = colors::argb(0xE0, 0x20, 0x20, 0x28);
+const TASKBAR_ITEM: u32 = colors::argb(0x40, 0xFF, 0xFF, 0xFF);
+const TASKBAR_ITEM_ACTIVE: u32 = colors::argb(0x90, 0xFF, 0xFF, 0xFF);
+const TASK_TEXT_MINIMIZED: u32 = colors::rgb(0xB0, 0xB0, 0xB8);
+const START_BUTTON: u32 = colors::rgb(0x6E, 0x64, 0xC8);
+const MENU: u32 = colors::argb(0xE6, 0x10, 0x10, 0x18);
+const MENU_SELECTED: u32 = colors::argb(0x50, 0xFF, 0xFF, 0xFF);
+const TOAST: u32 = colors::argb(0xE6, 0x24, 0x26, 0x30);
+const TOAST_ICON: u32 = colors::rgb(0x3A, 0x6E, 0xA5);
+const TOAST_BODY: u32 = colors::rgb(0xC8, 0xC8, 0xD0);
+const UNREAD: u32 = colors::rgb(0xE0, 0x50, 0x40);
+const BATTERY_CHARGING: u32 = colors::rgb(0x28, 0xC8, 0x40);
+
+/// What the painter needs of a window, copied out of the desktop manager
+#[derive(Debug, Clone)]
+pub struct WindowChrome {
+    pub title: String,
+    pub rect: Rect,
+    pub focused: bool,
+    pub minimized: bool,
+    pub maximized: bool,
+    /// Lines of text for the content area
+    pub content: Vec<String>,
+    /// Widgets drawn in place of the text, for native apps
+    pub widgets: Option<Vec<Widget>>,
+    /// Content row at the top of the body, when the content is taller
+    /// than the body
+    pub scroll_y: u32,
+}
+
+/// A desktop icon
+#[derive(Debug, Clone)]
+pub struct IconChrome {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// A window's taskbar button
+#[derive(Debug, Clone)]
+pub struct TaskChrome {
+    pub title: String,
+    pub active: bool,
+    pub minimized: bool,
+}
+
+/// A notification, as a toast or a panel entry
+#[derive(Debug, Clone)]
+pub struct NoticeChrome {
+    pub title: String,
+    pub body: String,
+    pub icon: char,
+    pub unread: bool,
+}
+
+/// Battery charge shown on the taskbar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryChrome {
+    pub percent: u32,
+    pub charging: bool,
+}
+
+/// The open start menu
+#[derive(Debug, Clone)]
+pub struct MenuChrome {
+    pub entries: Vec<String>,
+    /// Entry highlighted for keyboard selection
+    pub selected: usize,
+}
+
+/// Everything visible on the desktop
+#[derive(Debug, Clone)]
+pub struct Scene {
+    /// Display areas in virtual desktop coordinates, primary first
+    pub displays: Vec<Rect>,
+    pub icons: Vec<IconChrome>,
+    /// Windows from bottom to top
+    pub windows: Vec<WindowChrome>,
+    /// Taskbar buttons in the order windows were opened
+    pub tasks: Vec<TaskChrome>,
+    pub start_menu: Option<MenuChrome>,
+    /// Alt+Tab window switcher, listing window titles
+    pub switcher: Option<MenuChrome>,
+    /// Toasts from the top of the stack down
+    pub toasts: Vec<NoticeChrome>,
+    /// Notification panel entries, newest first, when the panel is open
+    pub notification_panel: Option<Vec<NoticeChrome>>,
+    pub unread: usize,
+    /// None on machines without a battery
+    pub battery: Option<BatteryChrome>,
+    /// File chooser drawn above everything else
+    pub dialog: Option<FileDialog>,
+    /// Permission prompt drawn above everything else
+    pub consent: Option<ConsentPrompt>,
+    /// Lock screen; when set nothing of the session is drawn
+    pub lock: Option<LockChrome>,
+    pub clock: String,
+    pub taskbar_height: u32,
+    pub wallpaper: &'static [ColorStop; 2],
+    pub theme: &'static Theme,
+}
+
+/// Taskbar area, along the bottom of the primary display
+pub fn taskbar_rect(primary: Rect, height: u32) -> Rect {
+    let h = height.min(primary.h);
+    Rect::new(primary.x, primary.bottom() - h as i32, primary.w, h)
+}
+
+/// Start button, at the left end of the taskbar
+pub fn start_button_rect(bar: Rect) -> Rect {
+    Rect::new(bar.x + 8, bar.y + 4, START_BUTTON_WIDTH, bar.h.saturating_sub(8))
+}
+
+/// Clock, at the right end of the taskbar
+pub fn clock_rect(bar: Rect) -> Rect {
+    let (cell_w, _) = font::cell_size();
+    let w = 5 * cell_w + 24;
+    Rect::new(bar.right() - w as i32, bar.y, w, bar.h)
+}
+
+/// Notification button, left of the clock
+pub fn notification_button_rect(bar: Rect) -> Rect {
+    let (cell_w, _) = font::cell_size();
+    let w = 3 * cell_w + 16;
+    Rect::new(clock_rect(bar).x - w as i32, bar.y + 4, w, bar.h.saturating_sub(8))
+}
+
+/// Battery indicator, left of the notification button: a battery glyph
+/// and the charge in percent
+pub fn battery_rect(bar: Rect) -> Rect {
+    let (cell_w, _) = font::cell_size();
+    let w = BATTERY_GLYPH_WIDTH + 4 * cell_w + 12;
+    Rect::new(notification_button_rect(bar).x - 8 - w as i32, bar.y + 4, w, bar.h.saturating_sub(8))
+}
+
+/// Notification panel, opening upwards from the right end of the taskbar
+pub fn notification_panel_rect(bar: Rect) -> Rect {
+    let (_, cell_h) = font::cell_size();
+    let h = 2 * MENU_PADDING as u32 + (cell_h + 16) + PANEL_ENTRIES as u32 * (2 * cell_h + 16);
+    Rect::new(bar.right() - 8 - PANEL_WIDTH as i32, bar.y - 4 - h as i32, PANEL_WIDTH, h)
+}
+
+/// "Clear" in the notification panel's header
+pub fn panel_clear_rect(panel: Rect) -> Rect {
+    let (cell_w, cell_h) = font::cell_size();
+    let w = 5 * cell_w + 16;
+    Rect::new(panel.right() - MENU_PADDING - w as i32, panel.y + MENU_PADDING, w, cell_h + 16)
+}
+
+/// Toast `index` from the top, in the top right corner of the primary
+pub fn toast_rect(primary: Rect, index: usize) -> Rect {
+    Rect::new(
+        primary.right() - TOAST_MARGIN - TOAST_WIDTH as i32,
+        primary.y + TOAST_MARGIN + index as i32 * (TOAST_HEIGHT as i32 + TOAST_GAP),
+        TOAST_WIDTH,
+        TOAST_HEIGHT,
+    )
+}
+
+/// Area the toast stack can cover, shadows included
+pub fn toasts_extent(primary: Rect, max_toasts: usize) -> Rect {
+    let first = toast_rect(primary, 0);
+    let last = toast_rect(primary, max_toasts.saturating_sub(1));
+    window_extent(first.union(&last))
+}
+
+/// Taskbar button `index`, or None if it does not fit
+pub fn task_rect(bar: Rect, index: usize) -> Option<Rect> {
+    let x = start_button_rect(bar).right() + 12 + index as i32 * (TASK_WIDTH as i32 + TASK_GAP);
+    if x + TASK_WIDTH as i32 > battery_rect(bar).x - 8 {
+        return None;
+    }
+    Some(Rect::new(x, bar.y + 4, TASK_WIDTH, bar.h.saturating_sub(8)))
+}
+
+/// Start menu with `entries` entries, opening upwards from the start button
+pub fn start_menu_rect(bar: Rect, entries: usize) -> Rect {
+    let h = 2 * MENU_PADDING as u32 + entries as u32 * MENU_ENTRY_HEIGHT;
+    Rect::new(bar.x + 8, bar.y - 4 - h as i32, MENU_WIDTH, h)
+}
+
+/// Window switcher with `entries` entries, centred on the primary display
+pub fn switcher_rect(primary: Rect, entries: usize) -> Rect {
+    let h = 2 * MENU_PADDING as u32 + entries as u32 * MENU_ENTRY_HEIGHT;
+    Rect::new(
+        primary.x + (primary.w as i32 - SWITCHER_WIDTH as i32) / 2,
+        primary.y + (primary.h as i32 - h as i32) / 2,
+        SWITCHER_WIDTH,
+        h,
+    )
+}
+
+/// Start menu or window switcher entry `index`
+pub fn menu_entry_rect(menu: Rect, index: usize) -> Rect {
+    Rect::new(
+        menu.x + MENU_PADDING,
+        menu.y + MENU_PADDING + index as i32 * MENU_ENTRY_HEIGHT as i32,
+        menu.w - 2 * MENU_PADDING as u32,
+        MENU_ENTRY_HEIGHT,
+    )
+}
+
+/// Area a window covers on screen, including its shadow
+pub fn window_extent(rect: Rect) -> Rect {
+    Rect::new(rect.x, rect.y, rect.w + SHADOW_OFFSET as u32, rect.h + SHADOW_OFFSET as u32)
+}
+
+/// Repaint everything visible in `area`
+pub fn paint(c: &mut Compositor, area: Rect, scene: &Scene) {
+    for d in &scene.displays {
+        if let Some(r) = area.intersect(d) {
+            raster::fill_linear_gradient(c, r.x, r.y, r.w, r.h, (d.x, d.y), (d.x, d.bottom()), scene.wallpaper);
+        }
+    }
+
+    if let Some(lock) = &scene.lock {
+        if let Some(&primary) = scene.displays.first() {
+            if vesa_login::lock_panel_rect(primary).intersect(&area).is_some() {
+                vesa_login::paint_lock_screen(c, primary, lock, &scene.clock, scene.theme);
+            }
+        }
+        return;
+    }
+
+    for icon in &scene.icons {
+        if icon_extent(icon).intersect(&area).is_some() {
+            paint_icon(c, icon);
+        }
+    }
+
+    for w in scene.windows.iter().filter(|w| !w.minimized) {
+        if window_extent(w.rect).intersect(&area).is_some() {
+            paint_window(c, w, scene.theme);
+        }
+    }
+
+    if let Some(&primary) = scene.displays.first() {
+        for (i, toast) in scene.toasts.iter().enumerate() {
+            let r = toast_rect(primary, i);
+            if window_extent(r).intersect(&area).is_some() {
+                paint_toast(c, r, toast);
+            }
+        }
+
+        let bar = taskbar_rect(primary, scene.taskbar_height);
+        if bar.intersect(&area).is_some() {
+            paint_taskbar(c, bar, scene);
+        }
+        if let Some(menu) = &scene.start_menu {
+            let r = start_menu_rect(bar, menu.entries.len());
+            if r.intersect(&area).is_some() {
+                paint_start_menu(c, r, menu);
+            }
+        }
+        if let Some(entries) = &scene.notification_panel {
+            let r = notification_panel_rect(bar);
+            if r.intersect(&area).is_some() {
+                paint_notification_panel(c, r, entries);
+            }
+        }
+        if let Some(switcher) = &scene.switcher {
+            let r = switcher_rect(primary, switcher.entries.len());
+            if r.intersect(&area).is_some() {
+                paint_start_menu(c, r, switcher);
+            }
+        }
+    }
+
+    if let Some(dialog) = &scene.dialog {
+        if dialog.extent().intersect(&area).is_some() {
+            super::dialog::paint(c, dialog, scene.theme);
+        }
+    }
+    if let Some(prompt) = &scene.consent {
+        if prompt.extent().intersect(&area).is_some() {
+            super::consent::paint(c, prompt, scene.theme);
+        }
+    }
+}
+
+fn paint_window(c: &mut Compositor, w: &WindowChrome, theme: &Theme) {
+    let r = w.rect;
+    // Maximized windows fill their display edge to edge
+    let radius = if w.maximized { 0 } else { CORNER_RADIUS };
+    if !w.maximized {
+        raster::fill_rounded_rect(c, r.x + SHADOW_OFFSET, r.y + SHADOW_OFFSET, r.w, r.h, radius, SHADOW);
+    }
+
+    let title = if w.focused { theme.title_active } else { theme.title_inactive };
+    let bar_h = TITLE_BAR_HEIGHT.min(r.h);
+    // The title color fills the whole frame; the body then covers it below
+    // the title bar, squared off where the two meet
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, radius, title);
+    if r.h > bar_h {
+        raster::fill_rounded_rect(c, r.x, r.y + bar_h as i32, r.w, r.h - bar_h, radius, theme.window_body);
+        c.fill_rect(r.x, r.y + bar_h as i32, r.w, (r.h - bar_h).min(radius), theme.window_body);
+    }
+
+    let (_, cell_h) = font::cell_size();
+    let buttons_w = 3 * BUTTON_SIZE + 2 * BUTTON_GAP;
+    let text_y = r.y + (bar_h as i32 - cell_h as i32) / 2;
+    let max_w = r.w.saturating_sub(buttons_w + 3 * CORNER_RADIUS);
+    draw_text(c, &w.title, r.x + CORNER_RADIUS as i32, text_y, max_w, colors::WHITE);
+
+    let by = r.y + (bar_h as i32 - BUTTON_SIZE as i32) / 2;
+    for (i, color) in [CLOSE_BUTTON, MAXIMIZE_BUTTON, MINIMIZE_BUTTON].into_iter().enumerate() {
+        let bx = button_x(r, i);
+        raster::fill_rounded_rect(c, bx, by, BUTTON_SIZE, BUTTON_SIZE, BUTTON_SIZE / 2, color);
+    }
+
+    // Content, clipped to the body and scrolled
+    let body = body_rect(r);
+    let origin = Rect::new(body.x, body.y - w.scroll_y as i32, body.w, body.h);
+    c.clipped(body, |c| match &w.widgets {
+        Some(widgets) => widgets::paint(c, origin, widgets, theme),
+        None => {
+            let line_h = (cell_h + LINE_GAP) as i32;
+            let max_w = body.w.saturating_sub(2 * CONTENT_PADDING as u32);
+            let mut y = origin.y + CONTENT_PADDING;
+            for line in &w.content {
+                if y + line_h > body.y && y < body.bottom() {
+                    draw_text(c, line, body.x + CONTENT_PADDING, y, max_w, theme.content_text);
+                }
+                y += line_h;
+            }
+        }
+    });
+    let height = content_height(&w.content, w.widgets.as_deref());
+    if height > body.h {
+        widgets::paint_scrollbar(c, window_scrollbar_rect(body), w.scroll_y, height, body.h, theme);
+    }
+}
+
+/// Height of what a window's body holds, which may be more than shows
+pub fn content_height(content: &[String], widgets: Option<&[Widget]>) -> u32 {
+    match widgets {
+        Some(widgets) => widgets.iter().map(|w| w.rect.bottom().max(0) as u32).max().unwrap_or(0),
+        None => {
+            let (_, cell_h) = font::cell_size();
+            2 * CONTENT_PADDING as u32 + content.len() as u32 * (cell_h + LINE_GAP)
+        }
+    }
+}
+
+/// Scrollbar along the right of a window's body, shown when the content
+/// is taller than the body
+pub fn window_scrollbar_rect(body: Rect) -> Rect {
+    Rect::new(body.right() - widgets::SCROLLBAR_WIDTH as i32, body.y, widgets::SCROLLBAR_WIDTH, body.h)
+}
+
+/// Content area of a window, below its title bar
+pub fn body_rect(window: Rect) -> Rect {
+    let bar_h = TITLE_BAR_HEIGHT.min(window.h);
+    Rect::new(window.x, window.y + bar_h as i32, window.w, window.h - bar_h)
+}
+
+/// Left edge of title bar button `index`: 0 close, 1 maximize, 2 minimize
+pub fn button_x(window: Rect, index: usize) -> i32 {
+    window.right() - CORNER_RADIUS as i32 - ((index as u32 + 1) * BUTTON_SIZE + index as u32 * BUTTON_GAP) as i32
+}
+
+/// Area an icon covers, tile and label
+fn icon_extent(icon: &IconChrome) -> Rect {
+    let (cell_w, cell_h) = font::cell_size();
+    let label_w = icon.name.chars().count() as u32 * cell_w;
+    let w = ICON_SIZE.max(label_w);
+    let x = icon.x + ICON_SIZE as i32 / 2 - w as i32 / 2;
+    Rect::new(x, icon.y, w, ICON_SIZE + 4 + cell_h)
+}
+
+fn paint_icon(c: &mut Compositor, icon: &IconChrome) {
+    let (cell_w, _) = font::cell_size();
+    raster::fill_rounded_rect(c, icon.x, icon.y, ICON_SIZE, ICON_SIZE, 10, ICON_TILE);
+    // The tile shows the initial, as the font has no emoji
+    if let Some(initial) = icon.name.chars().next() {
+        let (_, cell_h) = font::cell_size();
+        let s = format!("{}", initial);
+        let cx = icon.x + (ICON_SIZE as i32 - cell_w as i32) / 2;
+        let cy = icon.y + (ICON_SIZE as i32 - cell_h as i32) / 2;
+        draw_text(c, &s, cx, cy, cell_w, colors::WHITE);
+    }
+    let extent = icon_extent(icon);
+    draw_text(c, &icon.name, extent.x, icon.y + ICON_SIZE as i32 + 4, extent.w, colors::WHITE);
+}
+
+/// Visible text of an HTML fragment, one line per block element
+///
+/// Script and style contents are dropped, buttons are shown in brackets
+/// and table cells are separated by spaces.
+pub fn html_text(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut rest = html;
+
+    while let Some(lt) = rest.find('<') {
+        push_text(&mut line, &rest[..lt]);
+        let after = &rest[lt + 1..];
+        let gt = match after.find('>') {
+            Some(gt) => gt,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let tag = &after[..gt];
+        rest = &after[gt + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag.trim_start_matches('/')
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "script" | "style" if !closing => {
+                let end = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&end) {
+                    Some(i) => &rest[i..],
+                    None => "",
+                };
+            }
+            "button" if closing => line.push(']'),
+            "button" => {
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push('[');
+            }
+            "td" | "th" if !closing && !line.is_empty() => line.push_str("  "),
+            "br" | "div" | "p" | "li" | "tr" | "table" | "ul" | "ol" | "form"
+            | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "textarea" | "select" => {
+                flush_line(&mut lines, &mut line);
+            }
+            _ => {}
+        }
+    }
+    push_text(&mut line, rest);
+    flush_line(&mut lines, &mut line);
+    lines
+}
+
+/// Append text with whitespace collapsed and common entities decoded
+fn push_text(line: &mut String, text: &str) {
+    let text = text.replace("&nbsp;", " ").replace("&lt;", "<").replace("&gt;", ">")
+        .replace("&quot;", "\"").replace("&amp;", "&");
+    for word in text.split_ascii_whitespace() {
+        if !line.is_empty() && !line.ends_with(['[', ' ']) {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+}
+
+fn flush_line(lines: &mut Vec<String>, line: &mut String) {
+    let trimmed = line.trim();
+    if !trimmed.is_empty() && trimmed != "[]" {
+        lines.push(String::from(trimmed));
+    }
+    line.clear();
+}
+
+fn paint_taskbar(c: &mut Compositor, bar: Rect, scene: &Scene) {
+    c.fill_rect_alpha(bar.x, bar.y, bar.w, bar.h, TASKBAR);
+
+    let (cell_w, cell_h) = font::cell_size();
+    let text_y = bar.y + (bar.h as i32 - cell_h as i32) / 2;
+
+    let start = start_button_rect(bar);
+    raster::fill_rounded_rect(c, start.x, start.y, start.w, start.h, 4, START_BUTTON);
+    draw_text(c, "WebbOS", start.x + cell_w as i32, text_y, start.w - 2 * cell_w, colors::WHITE);
+
+    for (i, task) in scene.tasks.iter().enumerate() {
+        let r = match task_rect(bar, i) {
+            Some(r) => r,
+            None => break,
+        };
+        let fill = if task.active { TASKBAR_ITEM_ACTIVE } else { TASKBAR_ITEM };
+        let text = if task.minimized { TASK_TEXT_MINIMIZED } else { colors::WHITE };
+        raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, 4, fill);
+        draw_text(c, &task.title, r.x + cell_w as i32, text_y, r.w - 2 * cell_w, text);
+    }
+
+    if let Some(battery) = scene.battery {
+        paint_battery(c, battery_rect(bar), battery, text_y);
+    }
+
+    let bell = notification_button_rect(bar);
+    let fill = if scene.unread > 0 { UNREAD } else { TASKBAR_ITEM };
+    raster::fill_rounded_rect(c, bell.x, bell.y, bell.w, bell.h, bell.h / 2, fill);
+    let count = if scene.unread > 99 { String::from("99+") } else { format!("{}", scene.unread) };
+    let count_x = bell.x + (bell.w as i32 - (count.len() as u32 * cell_w) as i32) / 2;
+    draw_text(c, &count, count_x, text_y, bell.w, colors::WHITE);
+
+    let clock = clock_rect(bar);
+    draw_text(c, &scene.clock, clock.x + 12, text_y, clock.w, colors::WHITE);
+}
+
+/// Battery glyph filled to the charge, green while charging and red when
+/// low, then the percentage
+fn paint_battery(c: &mut Compositor, r: Rect, battery: BatteryChrome, text_y: i32) {
+    let (_, cell_h) = font::cell_size();
+    let h = (cell_h * 3 / 4).min(r.h) as i32;
+    let x = r.x + 4;
+    let y = r.y + (r.h as i32 - h) / 2;
+    let body_w = BATTERY_GLYPH_WIDTH as i32 - 3;
+    c.fill_rect(x, y, body_w as u32, h as u32, colors::WHITE);
+    c.fill_rect(x + body_w, y + h / 4, 3, (h / 2) as u32, colors::WHITE);
+    c.fill_rect(x + 1, y + 1, (body_w - 2) as u32, (h - 2) as u32, TASKBAR);
+    let fill = if battery.charging {
+        BATTERY_CHARGING
+    } else if battery.percent <= crate::power::LOW_PERCENT {
+        UNREAD
+    } else {
+        colors::WHITE
+    };
+    let level = (body_w - 4) * battery.percent.min(100) as i32 / 100;
+    c.fill_rect(x + 2, y + 2, level.max(1) as u32, (h - 4) as u32, fill);
+    let text = format!("{}%", battery.percent.min(100));
+    draw_text(c, &text, x + BATTERY_GLYPH_WIDTH as i32 + 6, text_y, r.w - BATTERY_GLYPH_WIDTH - 10, colors::WHITE);
+}
+
+fn paint_toast(c: &mut Compositor, r: Rect, toast: &NoticeChrome) {
+    raster::fill_rounded_rect(c, r.x + SHADOW_OFFSET, r.y + SHADOW_OFFSET, r.w, r.h, CORNER_RADIUS, SHADOW);
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, CORNER_RADIUS, TOAST);
+    paint_notice(c, Rect::new(r.x + 12, r.y + 12, r.w - 24, r.h - 24), toast);
+}
+
+fn paint_notification_panel(c: &mut Compositor, r: Rect, entries: &[NoticeChrome]) {
+    let (_, cell_h) = font::cell_size();
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, 12, MENU);
+
+    let header_y = r.y + MENU_PADDING + 8;
+    draw_text(c, "Notifications", r.x + MENU_PADDING + 8, header_y, r.w / 2, colors::WHITE);
+    let clear = panel_clear_rect(r);
+    raster::fill_rounded_rect(c, clear.x, clear.y, clear.w, clear.h, 6, TASKBAR_ITEM);
+    draw_text(c, "Clear", clear.x + 8, header_y, clear.w, colors::WHITE);
+
+    let entry_h = 2 * cell_h as i32 + 16;
+    let mut y = clear.bottom();
+    if entries.is_empty() {
+        draw_text(c, "Nothing new", r.x + MENU_PADDING + 8, y + 8, r.w, TOAST_BODY);
+    }
+    for entry in entries.iter().take(PANEL_ENTRIES) {
+        let e = Rect::new(r.x + MENU_PADDING, y, r.w - 2 * MENU_PADDING as u32, entry_h as u32);
+        if entry.unread {
+            raster::fill_rounded_rect(c, e.x, e.y + 2, e.w, e.h - 4, 6, MENU_SELECTED);
+        }
+        paint_notice(c, Rect::new(e.x + 8, e.y + 8, e.w - 16, e.h as u32 - 16), entry);
+        y += entry_h;
+    }
+}
+
+/// Icon, title and first line of the body, laid out in `r`
+fn paint_notice(c: &mut Compositor, r: Rect, notice: &NoticeChrome) {
+    let (cell_w, cell_h) = font::cell_size();
+    let icon_d = (2 * cell_h).min(r.h);
+    raster::fill_rounded_rect(c, r.x, r.y, icon_d, icon_d, icon_d / 2, TOAST_ICON);
+    // Only ASCII icons are drawn; the font has no emoji, so others show the
+    // title's initial
+    let icon = if notice.icon.is_ascii_graphic() {
+        notice.icon
+    } else {
+        notice.title.chars().next().unwrap_or('!')
+    };
+    let s = format!("{}", icon);
+    let icon_x = r.x + (icon_d as i32 - cell_w as i32) / 2;
+    draw_text(c, &s, icon_x, r.y + (icon_d as i32 - cell_h as i32) / 2, cell_w, colors::WHITE);
+
+    let text_x = r.x + icon_d as i32 + 10;
+    let text_w = r.w.saturating_sub(icon_d + 10);
+    draw_text(c, &notice.title, text_x, r.y, text_w, colors::WHITE);
+    let body = notice.body.lines().next().unwrap_or("");
+    draw_text(c, body, text_x, r.y + cell_h as i32 + 2, text_w, TOAST_BODY);
+}
+
+fn paint_start_menu(c: &mut Compositor, r: Rect, menu: &MenuChrome) {
+    let (cell_w, cell_h) = font::cell_size();
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, 12, MENU);
+    for (i, entry) in menu.entries.iter().enumerate() {
+        let e = menu_entry_rect(r, i);
+        if i == menu.selected {
+            raster::fill_rounded_rect(c, e.x, e.y, e.w, e.h, 6, MENU_SELECTED);
+        }
+        let text_y = e.y + (e.h as i32 - cell_h as i32) / 2;
+        draw_text(c, entry, e.x + cell_w as i32, text_y, e.w - 2 * cell_w, colors::WHITE);
+    }
+}
+
+/// Draw a line of text in the active font, cut off at `max_w` pixels
+pub(super) fn draw_text(c: &mut Compositor, text: &str, x: i32, y: i32, max_w: u32, color: u32) {
+    let (cell_w, _) = font::cell_size();
+    let mut cx = x;
+    for ch in text.chars() {
+        if cx + cell_w as i32 > x + max_w as i32 {
+            break;
+        }
+        font::glyph(ch).for_each_run(|gx, gy, len| {
+            c.fill_rect(cx + gx as i32, y + gy as i32, len, 1, color);
+        });
+        cx += cell_w as i32;
+    }
+}
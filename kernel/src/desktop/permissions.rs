@@ -0,0 +1,215 @@
+//! What installed apps may do
+//!
+//! A package's manifest lists the capabilities its app needs, such as
+//! `capabilities = fs:/home/notes, network`. The message router refuses
+//! requests needing a capability the app did not declare. The first time
+//! the app uses one it did declare, the desktop asks the user whether to
+//! allow it, and the answer is kept in `/etc/permissions/<app>` so the
+//! question is asked once. Built-in apps are trusted and are not checked.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::packages::INSTALL_DIR;
+use crate::fs::{self, FsError};
+use crate::info;
+use crate::users::audit;
+
+/// Where the user's answers are kept, a file per app
+pub const PERMISSIONS_DIR: &str = "/etc/permissions";
+
+/// Something an app may be allowed to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capability {
+    /// Write files at or under a path; `fs:<path>`
+    FsWrite(String),
+    /// Fetch pages and reach other hosts; `network`
+    Network,
+    /// End other processes; `processes`
+    Processes,
+    /// Use a device such as a camera or microphone; `device:<name>`
+    Device(String),
+}
+
+/// Whether an app may go ahead with a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Allowed,
+    /// The app did not declare the capability, or the user refused it
+    Denied,
+    /// The app declared the capability but the user has not answered yet
+    Ask,
+}
+
+impl Capability {
+    /// Parse a manifest entry such as `fs:/home` or `network`
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        match text.split_once(':') {
+            Some(("fs", path)) if path.starts_with('/') => Some(Capability::FsWrite(fs::absolute("/", path))),
+            Some(("device", name)) if !name.trim().is_empty() => Some(Capability::Device(name.trim().to_string())),
+            Some(_) => None,
+            None => match text {
+                "network" => Some(Capability::Network),
+                "processes" => Some(Capability::Processes),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether holding this capability covers `needed`
+    pub fn covers(&self, needed: &Capability) -> bool {
+        match (self, needed) {
+            (Capability::FsWrite(dir), Capability::FsWrite(path)) => within(path, dir),
+            _ => self == needed,
+        }
+    }
+
+    /// What it lets an app do, for the consent prompt
+    pub fn describe(&self) -> String {
+        match self {
+            Capability::FsWrite(path) => format!("write files in {}", path),
+            Capability::Network => String::from("use the network"),
+            Capability::Processes => String::from("end running programs"),
+            Capability::Device(name) => format!("use the {}", name),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Capability::FsWrite(path) => write!(f, "fs:{}", path),
+            Capability::Network => write!(f, "network"),
+            Capability::Processes => write!(f, "processes"),
+            Capability::Device(name) => write!(f, "device:{}", name),
+        }
+    }
+}
+
+/// Whether absolute path `path` is `dir` or inside it
+fn within(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || path.strip_prefix(dir).map_or(false, |rest| rest.starts_with('/'))
+}
+
+/// Capability needed to write `path`, with `.` and `..` resolved
+pub fn fs_write(path: &str) -> Capability {
+    Capability::FsWrite(fs::absolute("/", path))
+}
+
+/// Whether app `name`, which declared `declared`, may use `needed`
+///
+/// Packaged apps may never write to where apps are installed or where
+/// their permissions are kept, whatever they declared.
+pub fn check(name: &str, declared: &[Capability], needed: &Capability) -> Access {
+    if let Capability::FsWrite(path) = needed {
+        if within(path, INSTALL_DIR) || within(path, PERMISSIONS_DIR) {
+            return Access::Denied;
+        }
+    }
+    let capability = match declared.iter().find(|c| c.covers(needed)) {
+        Some(capability) => capability,
+        None => return Access::Denied,
+    };
+    let answer = answers(name).into_iter().find(|(c, _)| c == capability).map(|(_, allowed)| allowed);
+    match answer {
+        Some(true) => Access::Allowed,
+        Some(false) => Access::Denied,
+        None => Access::Ask,
+    }
+}
+
+/// The declared capability that covers `needed`, which is what the user
+/// is asked about and what their answer is kept for
+pub fn declared_for(declared: &[Capability], needed: &Capability) -> Option<Capability> {
+    declared.iter().find(|c| c.covers(needed)).cloned()
+}
+
+fn answers_path(name: &str) -> String {
+    format!("{}/{}", PERMISSIONS_DIR, name)
+}
+
+/// The user's answers for app `name`, kept as `capability = allow` or
+/// `deny` lines
+pub fn answers(name: &str) -> Vec<(Capability, bool)> {
+    let data = match fs::read_file(&answers_path(name)) {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+    String::from_utf8_lossy(&data).lines()
+        .filter_map(|line| {
+            let (capability, answer) = line.split_once('=')?;
+            Some((Capability::parse(capability)?, answer.trim() == "allow"))
+        })
+        .collect()
+}
+
+/// Keep the user's answer about `capability` for app `name`
+pub fn record(name: &str, capability: &Capability, allowed: bool) {
+    let mut answers = answers(name);
+    answers.retain(|(c, _)| c != capability);
+    answers.push((capability.clone(), allowed));
+    let text: String = answers.iter()
+        .map(|(c, allowed)| format!("{} = {}\n", c, if *allowed { "allow" } else { "deny" }))
+        .collect();
+    let saved = match fs::create_dir(PERMISSIONS_DIR) {
+        Ok(()) | Err(FsError::AlreadyExists) => fs::write_file(&answers_path(name), text.as_bytes()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = saved {
+        info!("permissions", "Could not save answers for {}: {:?}", name, e);
+    }
+    audit::record(audit::Kind::Apps, &format!(
+        "{} app {} to {}",
+        if allowed { "allowed" } else { "refused" },
+        name,
+        capability.describe(),
+    ));
+}
+
+/// Forget the answers for app `name`, once it is uninstalled
+pub fn forget(name: &str) {
+    let _ = fs::remove(&answers_path(name));
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn capabilities_parse_and_print() -> Result<(), String> {
+        for text in ["fs:/home/notes", "network", "processes", "device:camera"] {
+            let capability = Capability::parse(text).ok_or_else(|| format!("{} did not parse", text))?;
+            check_eq!(capability.to_string(), String::from(text));
+        }
+        check_eq!(Capability::parse("fs:/home/../etc"), Some(Capability::FsWrite(String::from("/etc"))));
+        check!(Capability::parse("fs:home").is_none());
+        check!(Capability::parse("camera").is_none());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn fs_capability_covers_paths_below_it() -> Result<(), String> {
+        let home = Capability::FsWrite(String::from("/home/notes"));
+        check!(home.covers(&fs_write("/home/notes")));
+        check!(home.covers(&fs_write("/home/notes/todo.txt")));
+        check!(!home.covers(&fs_write("/home/notes2/todo.txt")));
+        check!(!home.covers(&fs_write("/home/notes/../secret")));
+        check!(!home.covers(&Capability::Network));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn undeclared_and_protected_capabilities_are_denied() -> Result<(), String> {
+        let declared = [Capability::FsWrite(String::from("/")), Capability::Network];
+        check_eq!(check("permissions-test", &declared, &Capability::Processes), Access::Denied);
+        check_eq!(check("permissions-test", &declared, &fs_write("/var/apps/x/index.html")), Access::Denied);
+        check_eq!(check("permissions-test", &declared, &fs_write("/etc/permissions/x")), Access::Denied);
+        check_eq!(check("permissions-test", &declared, &Capability::Network), Access::Ask);
+        Ok(())
+    }
+}
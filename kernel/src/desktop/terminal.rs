@@ -0,0 +1,72 @@
+//! Terminal app sessions
+//!
+//! Each Terminal window is the master end of a PTY with a shell on the
+//! slave end. What the window types goes to the PTY through `input`; what
+//! the shell writes comes back to the window as `terminal_output`
+//! messages, collected by `pump`, escape sequences and all.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::ipc::{self, Response};
+use super::WindowId;
+use crate::drivers::pty::{self, Master, WinSize};
+
+static TERMINALS: Mutex<BTreeMap<WindowId, Master>> = Mutex::new(BTreeMap::new());
+
+/// Start a shell for `window` on a new PTY, replacing any it had; the
+/// shell's session is a child of the window's process
+pub fn open(window: WindowId, size: WinSize) {
+    let (master, slave) = pty::open(size);
+    crate::shell::spawn(slave, super::window_pid(window));
+    TERMINALS.lock().insert(window, master);
+}
+
+/// Pass what was typed in `window` to its PTY; false if it has none
+pub fn input(window: WindowId, data: &str) -> bool {
+    match TERMINALS.lock().get(&window) {
+        Some(master) => {
+            master.write(data.as_bytes());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Tell the shell in `window` the terminal's new size
+pub fn resize(window: WindowId, size: WinSize) -> bool {
+    match TERMINALS.lock().get(&window) {
+        Some(master) => {
+            master.resize(size);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Hang up the PTY of a window that closed
+pub fn close(window: WindowId) {
+    TERMINALS.lock().remove(&window);
+}
+
+/// Send shell output to the windows, and tell those whose shell exited
+pub fn pump() {
+    let mut replies: Vec<(WindowId, Response)> = Vec::new();
+    TERMINALS.lock().retain(|&window, master| {
+        let output = master.read();
+        if !output.is_empty() {
+            let text = String::from_utf8_lossy(&output).into_owned();
+            replies.push((window, Response::TerminalOutput { text }));
+        }
+        if master.slave_closed() {
+            replies.push((window, Response::TerminalExited));
+            return false;
+        }
+        true
+    });
+    for (window, response) in replies {
+        ipc::send(window, response);
+    }
+}
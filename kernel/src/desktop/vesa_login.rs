@@ -1,11 +1,18 @@
 //! VESA Login Screen
 //!
-//! A graphical login screen using the VESA framebuffer
+//! A graphical login screen using the VESA framebuffer, and the lock
+//! screen the desktop draws through the compositor while a session is
+//! locked.
 
 use crate::drivers::vesa::{self, colors};
 use alloc::string::String;
 use crate::drivers::input;
-use crate::println;
+use crate::graphics::compositor::{Compositor, Rect};
+use crate::graphics::font;
+use crate::graphics::raster;
+use crate::info;
+use crate::users;
+use super::paint::{self, draw_text, Theme};
 
 const KEY_ENTER: u16 = 0x1C; // Enter key scancode
 
@@ -37,6 +44,7 @@ pub fn show_login_screen() -> Option<(u64, String)> {
     
     // Wait for keypress
     loop {
+        crate::drivers::virtio_gpu::present();
         if let Some(key) = input::get_key() {
             if key.keycode == KEY_ENTER {
                 return Some((1, String::from("admin")));
@@ -64,21 +72,143 @@ pub fn show_welcome_message() {
     vesa::draw_text("Login successful", cx - 120, cy + 20, colors::GREEN, 2);
 }
 
-/// Draw a circle (another shape after login)
-pub fn draw_post_login_shape() {
-    let info = vesa::info().unwrap();
-    let cx = (info.width / 2) as i32;
-    let cy = (info.height / 2) as i32 + 100;
-    
-    // Draw a filled circle below the welcome message
-    vesa::fill_circle(cx, cy, 60, colors::MAGENTA);
-    vesa::draw_circle(cx, cy, 60, colors::WHITE);
-    
-    println!("[vesa] Post-login circle drawn at ({}, {})", cx, cy);
-}
-
 /// Simple text input for VESA (basic version)
 pub fn read_line_vesa(_prompt: &str, _x: i32, _y: i32) -> String {
     // For now, just return admin - full text input would need more work
     String::from("admin")
 }
+
+const LOCK_PANEL_WIDTH: u32 = 360;
+const LOCK_PANEL_HEIGHT: u32 = 200;
+const LOCK_ERROR: u32 = colors::rgb(0xC0, 0x30, 0x30);
+
+/// Password prompt of a locked session
+///
+/// Only the current user's password unlocks it, followed by a one-time
+/// code if they have two-factor login.
+#[derive(Debug, Clone)]
+pub struct LockScreen {
+    pub username: String,
+    /// Password, or the code once the password has been accepted
+    password: String,
+    /// Why the last password was refused
+    error: Option<String>,
+    /// User whose password was right and who still has to give a code
+    code_for: Option<users::UserId>,
+}
+
+/// What the painter needs of the lock screen; the password is only
+/// shown as its length
+#[derive(Debug, Clone)]
+pub struct LockChrome {
+    pub username: String,
+    pub typed: usize,
+    /// The code typed so far, once a code is asked for
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+impl LockScreen {
+    pub fn new(username: &str) -> Self {
+        Self { username: String::from(username), password: String::new(), error: None, code_for: None }
+    }
+
+    /// Key pressed while locked; returns true once the right password is
+    /// entered
+    ///
+    /// Enter checks the password or code, Backspace deletes a character
+    /// and Esc clears what was typed.
+    pub fn handle_key(&mut self, keycode: u16, ch: char) -> bool {
+        match keycode {
+            KEY_ENTER => {
+                let result = match self.code_for {
+                    Some(user_id) => users::verify_code(user_id, &self.password).map(|()| user_id),
+                    None => users::authenticate(&self.username, &self.password),
+                };
+                self.password.clear();
+                if let Ok(user_id) = result {
+                    if self.code_for.is_none() && users::needs_code(user_id) {
+                        self.code_for = Some(user_id);
+                        self.error = None;
+                        return false;
+                    }
+                }
+                let unlocked = result.is_ok();
+                self.error = result.err().map(|e| match e {
+                    users::UserError::WrongPassword => String::from("Incorrect password"),
+                    e => alloc::format!("{}", e),
+                });
+                if unlocked {
+                    self.code_for = None;
+                    info!("desktop", "Session unlocked");
+                }
+                return unlocked;
+            }
+            0x01 => self.password.clear(),
+            0x0E => {
+                self.password.pop();
+            }
+            _ if !ch.is_control() => {
+                self.password.push(ch);
+                self.error = None;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    pub fn chrome(&self) -> LockChrome {
+        LockChrome {
+            username: self.username.clone(),
+            typed: self.password.chars().count(),
+            code: self.code_for.map(|_| self.password.clone()),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// Where the password prompt sits, centred on the primary display
+pub fn lock_panel_rect(primary: Rect) -> Rect {
+    Rect::new(
+        primary.x + (primary.w as i32 - LOCK_PANEL_WIDTH as i32) / 2,
+        primary.y + (primary.h as i32 - LOCK_PANEL_HEIGHT as i32) / 2,
+        LOCK_PANEL_WIDTH,
+        LOCK_PANEL_HEIGHT,
+    )
+}
+
+/// Draw the password prompt; the wallpaper behind it is already painted
+pub fn paint_lock_screen(c: &mut Compositor, primary: Rect, lock: &LockChrome, clock: &str, theme: &Theme) {
+    let r = lock_panel_rect(primary);
+    let (cell_w, cell_h) = font::cell_size();
+    let line = cell_h as i32 + 12;
+    let centred = |text: &str| r.x + (r.w as i32 - (text.chars().count() as u32 * cell_w) as i32) / 2;
+
+    raster::fill_rounded_rect(c, r.x + paint::SHADOW_OFFSET, r.y + paint::SHADOW_OFFSET, r.w, r.h, 12, paint::SHADOW);
+    raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, 12, theme.window_body);
+
+    let mut y = r.y + 20;
+    draw_text(c, clock, centred(clock), y, r.w, theme.content_text);
+    y += line;
+    let title = alloc::format!("Locked by {}", lock.username);
+    draw_text(c, &title, centred(&title), y, r.w, theme.content_text);
+    y += line + 8;
+
+    let field = Rect::new(r.x + 40, y, r.w - 80, cell_h + 12);
+    c.fill_rect(field.x, field.y, field.w, field.h, theme.title_inactive);
+    c.fill_rect(field.x + 1, field.y + 1, field.w - 2, field.h - 2, theme.field);
+    let fits = (field.w / cell_w).saturating_sub(2) as usize;
+    let typed: String = match &lock.code {
+        Some(code) => code.chars().take(fits).chain(core::iter::once('_')).collect(),
+        None => core::iter::repeat('*').take(lock.typed.min(fits)).chain(core::iter::once('_')).collect(),
+    };
+    draw_text(c, &typed, field.x + cell_w as i32, field.y + 6, field.w - cell_w, theme.content_text);
+    y += field.h as i32 + 16;
+
+    let (hint, color) = match (&lock.error, &lock.code) {
+        (Some(error), _) => (error.as_str(), LOCK_ERROR),
+        (None, Some(_)) => ("Enter the code from your authenticator app", theme.title_inactive),
+        (None, None) => ("Enter your password to unlock", theme.title_inactive),
+    };
+    draw_text(c, hint, centred(hint), y, r.w, color);
+}
@@ -0,0 +1,541 @@
+//! WebbBrowser
+//!
+//! A strip of tabs, then a toolbar with back, forward and reload buttons
+//! and an address bar, above the page the browser engine renders for the
+//! tab shown. Each tab has a page, history and load of its own; Ctrl+T
+//! opens one, Ctrl+W closes the one shown, Ctrl+Tab and Ctrl+Shift+Tab
+//! move between them, and clicking a tab shows it. Closing the last tab
+//! leaves an empty one in its place. The star beside the address bar
+//! bookmarks the page, or takes its bookmark away. Downloads show in a
+//! bar along the bottom, the newest with its progress, until cleared.
+//! Clicking a link
+//! follows it, and clicking a form control gives it the keyboard; Tab
+//! moves between controls and Escape leaves them. Keys the focused control
+//! has no use for go to the page: it scrolls with Up, Down, Page Up, Page
+//! Down, Space, Home and End, Backspace goes back and F5 reloads. The
+//! page also scrolls with the mouse wheel and the scrollbar beside it, and
+//! the mouse's back and forward buttons go through the history. A page
+//! is shown as it arrives, taking in what has come each tick. Images
+//! load one per tick after the page is in, each appearing as it arrives,
+//! as do the requests the page's scripts make, and the page's timers run
+//! each tick, in every tab whether shown or not.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use super::{Align, NativeApp, Widget, WidgetKind, SCROLLBAR_WIDTH};
+use crate::browser::downloads::{self, Download, State};
+use crate::browser::forms::Key;
+use crate::browser::bookmarks;
+use crate::browser::{self, Browser, BrowserError, TabId};
+use crate::drivers::input::{MOD_CTRL, MOD_SHIFT};
+use crate::graphics::compositor::Rect;
+use crate::graphics::font;
+
+const TAB_STRIP_HEIGHT: u32 = 30;
+const TAB_WIDTH: u32 = 180;
+const CLOSE_WIDTH: u32 = 20;
+const TOOLBAR_HEIGHT: u32 = 36;
+const PADDING: u32 = 6;
+const BUTTON_WIDTH: u32 = 32;
+const RELOAD_WIDTH: u32 = 64;
+const GO_WIDTH: u32 = 40;
+const DOWNLOAD_BAR_HEIGHT: u32 = 30;
+const CLEAR_WIDTH: u32 = 56;
+
+/// Rows an arrow key scrolls by
+pub(super) const LINE_SCROLL: i32 = 40;
+
+const BACK_ID: u32 = 0;
+const FORWARD_ID: u32 = 1;
+const RELOAD_ID: u32 = 2;
+const ADDRESS_ID: u32 = 3;
+const GO_ID: u32 = 4;
+const PAGE_ID: u32 = 5;
+const SCROLLBAR_ID: u32 = 6;
+const NEW_TAB_ID: u32 = 7;
+const BOOKMARK_ID: u32 = 8;
+const DOWNLOADS_ID: u32 = 9;
+const CLEAR_DOWNLOADS_ID: u32 = 10;
+/// Tab `i` is widget `TAB_ID + 2 * i`, and its close button the one after
+const TAB_ID: u32 = 100;
+
+/// A tab's page, with the address bar as it stands for it
+struct Tab {
+    id: TabId,
+    /// Text of the address bar
+    address: String,
+    /// The address bar has the keyboard
+    editing: bool,
+    /// Why the last navigation failed, shown in place of the page
+    error: Option<String>,
+}
+
+pub struct WebBrowser {
+    /// Never empty
+    tabs: Vec<Tab>,
+    /// Index of the tab shown
+    current: usize,
+    /// Size of the page area when the window was last painted, which the
+    /// tabs' viewports follow
+    view: Cell<(u32, u32)>,
+    /// `downloads::generation` when the downloads bar was last painted
+    downloads_shown: Cell<u32>,
+}
+
+pub fn new() -> Box<dyn NativeApp> {
+    Box::new(WebBrowser {
+        tabs: alloc::vec![Tab::open()],
+        current: 0,
+        view: Cell::new((0, 0)),
+        downloads_shown: Cell::new(0),
+    })
+}
+
+impl Drop for Tab {
+    fn drop(&mut self) {
+        browser::close_tab(self.id);
+    }
+}
+
+/// What was typed in the address bar as a URL: with a scheme, or an
+/// about: page, as it is, a path as a file, and anything else as a web
+/// address
+fn address_url(text: &str) -> String {
+    let text = text.trim();
+    if text.contains("://") || text.starts_with("about:") {
+        String::from(text)
+    } else if text.starts_with('/') {
+        format!("file://{}", text)
+    } else {
+        format!("http://{}", text)
+    }
+}
+
+/// Scroll `b`'s page for a key, with a viewport `page_h` rows high;
+/// `None` if the key does not scroll
+pub(super) fn scroll_key(b: &mut Browser, keycode: u16, page_h: i32) -> Option<bool> {
+    // Page Up and Down keep a line of the old view in sight
+    let page = (page_h - LINE_SCROLL).max(LINE_SCROLL);
+    Some(match keycode {
+        0x48 => b.scroll_by(-LINE_SCROLL),
+        0x50 => b.scroll_by(LINE_SCROLL),
+        0x49 => b.scroll_by(-page),
+        0x51 | 0x39 => b.scroll_by(page),
+        0x47 => b.scroll_to(0),
+        0x4F => b.scroll_to(u32::MAX),
+        _ => return None,
+    })
+}
+
+/// A key as the page's form controls take it
+pub(super) fn page_key(keycode: u16, ch: char) -> Option<Key> {
+    match (keycode, ch) {
+        (0x1C, _) => Some(Key::Enter),
+        (0x0E, _) => Some(Key::Backspace),
+        (0x48, _) => Some(Key::Up),
+        (0x50, _) => Some(Key::Down),
+        (0x0F, _) => Some(Key::Tab),
+        (0x01, _) => Some(Key::Escape),
+        (_, c) if !c.is_control() => Some(Key::Char(c)),
+        _ => None,
+    }
+}
+
+/// `text` cut to `width` pixels, ending in "..." if it had to be cut
+fn fit_text(text: &str, width: u32) -> String {
+    let fits = (width / font::cell_size().0) as usize;
+    if text.chars().count() <= fits {
+        return String::from(text);
+    }
+    let mut cut: String = text.chars().take(fits.saturating_sub(3)).collect();
+    cut.push_str("...");
+    cut
+}
+
+/// `bytes` in KB or MB
+fn size_text(bytes: usize) -> String {
+    if bytes < 1024 * 1024 {
+        format!("{} KB", bytes.div_ceil(1024))
+    } else {
+        format!("{}.{} MB", bytes / (1024 * 1024), bytes % (1024 * 1024) * 10 / (1024 * 1024))
+    }
+}
+
+/// What the downloads bar says: how the newest download is going, and
+/// how many others there are
+fn downloads_text(list: &[Download]) -> String {
+    let newest = match list.last() {
+        Some(newest) => newest,
+        None => return String::new(),
+    };
+    let mut text = match (newest.state, newest.total) {
+        (State::Active, Some(total)) if total > 0 => format!(
+            "Downloading {}: {}% of {}",
+            newest.name(),
+            newest.received.min(total) * 100 / total,
+            size_text(total)
+        ),
+        (State::Active, _) => format!("Downloading {}: {}", newest.name(), size_text(newest.received)),
+        (State::Done, _) => format!("Saved {} ({})", newest.path, size_text(newest.received)),
+        (State::Failed, _) => format!("Could not download {}", newest.name()),
+    };
+    if list.len() > 1 {
+        text.push_str(&format!(", and {} more", list.len() - 1));
+    }
+    text
+}
+
+impl Tab {
+    /// A new tab with nothing in it yet, waiting for an address
+    fn open() -> Self {
+        Tab { id: browser::open_tab(), address: String::new(), editing: true, error: None }
+    }
+
+    /// What the tab strip calls the tab
+    fn label(&self) -> String {
+        browser::with_tab(self.id, |b| match (&b.document, b.is_loading()) {
+            (Some(_), _) if !b.title.is_empty() => b.title.clone(),
+            (None, true) => String::from("Loading..."),
+            _ if !b.current_url.is_empty() => b.current_url.clone(),
+            _ => String::from("New tab"),
+        })
+        .unwrap_or_default()
+    }
+
+    /// Run `action` on the tab, then show where it ended up or why it failed
+    fn go(&mut self, action: impl FnOnce(&mut Browser) -> Result<(), BrowserError>) -> Result<(), String> {
+        let (result, url) = browser::with_tab(self.id, |b| (action(b), b.current_url.clone()))
+            .unwrap_or((Err(BrowserError::Unknown), String::new()));
+        self.editing = false;
+        match result {
+            Ok(()) => {
+                self.error = None;
+                self.address = url;
+                Ok(())
+            }
+            Err(e) => {
+                let message = format!("Cannot open {}: {:?}", self.address, e);
+                self.error = Some(message.clone());
+                Err(message)
+            }
+        }
+    }
+
+    fn navigate(&mut self, url: &str) -> Result<(), String> {
+        self.address = String::from(url);
+        self.go(|b| b.navigate(url))
+    }
+
+    fn with_page(&mut self, action: impl FnOnce(&mut Browser) -> bool) -> bool {
+        browser::with_tab(self.id, action).unwrap_or(false)
+    }
+
+    /// Take in what has arrived for the tab's page, laid out `w` by `h`;
+    /// returns true if the tab needs repainting
+    fn tick(&mut self, w: u32, h: u32) -> bool {
+        let ticked = browser::with_tab(self.id, |b| {
+            let resized = w > 0 && h > 0 && b.set_viewport(w, h);
+            let loaded = b.load_next_chunk();
+            let changed = resized | b.load_next_image() | b.send_next_request() | b.run_timers();
+            (loaded, changed, b.current_url.clone())
+        });
+        let (loaded, changed, url) = match ticked {
+            Some(ticked) => ticked,
+            None => return false,
+        };
+        match loaded {
+            // Redirects may have taken the page elsewhere
+            Ok(true) if !self.editing => self.address = url,
+            Ok(_) => return changed,
+            Err(e) => self.error = Some(format!("Cannot open {}: {:?}", self.address, e)),
+        }
+        true
+    }
+}
+
+impl WebBrowser {
+    fn tab(&mut self) -> &mut Tab {
+        &mut self.tabs[self.current]
+    }
+
+    fn open_tab(&mut self) {
+        self.tabs.push(Tab::open());
+        self.current = self.tabs.len() - 1;
+    }
+
+    fn close_tab(&mut self, index: usize) {
+        if index >= self.tabs.len() {
+            return;
+        }
+        self.tabs.remove(index);
+        if self.tabs.is_empty() {
+            self.tabs.push(Tab::open());
+        }
+        if self.current > index || self.current == self.tabs.len() {
+            self.current -= 1;
+        }
+    }
+
+    /// Show the tab `step` places along from the one shown, wrapping
+    /// around at either end
+    fn cycle_tab(&mut self, step: isize) {
+        let n = self.tabs.len() as isize;
+        self.current = (self.current as isize + step).rem_euclid(n) as usize;
+    }
+
+    /// The tab strip: a button for each tab, with one to close it, and one
+    /// to open a new tab, for a window `w` wide
+    fn tab_strip(&self, w: u32) -> Vec<Widget> {
+        let n = self.tabs.len() as u32;
+        let room = w.saturating_sub(PADDING + BUTTON_WIDTH + 2 * PADDING);
+        let tab_w = (room / n).saturating_sub(PADDING).min(TAB_WIDTH);
+        let (top, h) = (PADDING as i32, TAB_STRIP_HEIGHT - PADDING);
+        let mut widgets = Vec::new();
+        let mut x = PADDING as i32;
+        for (i, tab) in self.tabs.iter().enumerate() {
+            let id = TAB_ID + 2 * i as u32;
+            let label = fit_text(&tab.label(), tab_w.saturating_sub(CLOSE_WIDTH + 2 * PADDING));
+            widgets.push(Widget::button(id, Rect::new(x, top, tab_w, h), &label, i == self.current));
+            let close_x = x + tab_w as i32 - CLOSE_WIDTH as i32 - 2;
+            widgets.push(Widget::button(id + 1, Rect::new(close_x, top + 2, CLOSE_WIDTH, h - 4), "x", false));
+            x += (tab_w + PADDING) as i32;
+        }
+        widgets.push(Widget::button(NEW_TAB_ID, Rect::new(x, top, BUTTON_WIDTH, h), "+", false));
+        widgets
+    }
+}
+
+impl NativeApp for WebBrowser {
+    fn size(&self) -> (u32, u32) {
+        (800, 600)
+    }
+
+    fn widgets(&self, w: u32, h: u32) -> Vec<Widget> {
+        let tab = &self.tabs[self.current];
+        self.downloads_shown.set(downloads::generation());
+        let downloads = downloads::list();
+        let bar_h = if downloads.is_empty() { 0 } else { DOWNLOAD_BAR_HEIGHT };
+        let page_top = TAB_STRIP_HEIGHT + TOOLBAR_HEIGHT;
+        let page_h = h.saturating_sub(page_top + bar_h);
+        let url = browser::with_tab(tab.id, |b| b.current_url.clone()).unwrap_or_default();
+        let starred = !url.is_empty() && bookmarks::contains(&url);
+
+        let button_h = TOOLBAR_HEIGHT - 2 * PADDING;
+        let top = (TAB_STRIP_HEIGHT + PADDING) as i32;
+        let mut x = PADDING as i32;
+        let mut place = |width: u32| {
+            let rect = Rect::new(x, top, width, button_h);
+            x += (width + PADDING) as i32;
+            rect
+        };
+        let mut widgets = self.tab_strip(w);
+        widgets.extend([
+            Widget::arrow(BACK_ID, place(BUTTON_WIDTH), false),
+            Widget::arrow(FORWARD_ID, place(BUTTON_WIDTH), true),
+            Widget::button(RELOAD_ID, place(RELOAD_WIDTH), "Reload", false),
+            // Lit up when the page is bookmarked
+            Widget::button(BOOKMARK_ID, place(BUTTON_WIDTH), "*", starred),
+        ]);
+        let address_w = (w as i32 - x - (GO_WIDTH + 2 * PADDING) as i32).max(0) as u32;
+        widgets.push(Widget {
+            id: ADDRESS_ID,
+            rect: Rect::new(x, top, address_w, button_h),
+            kind: WidgetKind::TextInput { text: tab.address.clone(), focused: tab.editing },
+        });
+        let go_x = x + (address_w + PADDING) as i32;
+        widgets.push(Widget::button(GO_ID, Rect::new(go_x, top, GO_WIDTH, button_h), "Go", true));
+
+        // The scrollbar always has its place, so the page does not reflow
+        // when it gets long enough to scroll
+        let page_w = w.saturating_sub(SCROLLBAR_WIDTH);
+        self.view.set((page_w, page_h));
+        let area = Rect::new(0, page_top as i32, w, page_h);
+        let (page, loading, offset, content) = browser::with_tab(tab.id, |b| {
+            (b.render_context.framebuffer.clone(), b.is_loading(), b.scroll_y, b.page_height())
+        })
+        .unwrap_or((None, false, 0, 0));
+        match (&tab.error, page) {
+            (None, Some(page)) => {
+                let page_area = Rect::new(0, area.y, page_w, page_h);
+                widgets.push(Widget { id: PAGE_ID, rect: page_area, kind: WidgetKind::Page { page } });
+                widgets.push(Widget {
+                    id: SCROLLBAR_ID,
+                    rect: Rect::new(page_w as i32, area.y, SCROLLBAR_WIDTH, page_h),
+                    kind: WidgetKind::Scrollbar { offset, content, view: page_h },
+                });
+            }
+            (Some(error), _) => widgets.push(Widget::label(PAGE_ID, area, error, Align::Center)),
+            (None, None) if loading => widgets.push(Widget::label(PAGE_ID, area, "Loading...", Align::Center)),
+            (None, None) => widgets.push(Widget::label(PAGE_ID, area, "Type an address and press Enter", Align::Center)),
+        }
+
+        if bar_h > 0 {
+            let y = (page_top + page_h) as i32;
+            let clear_x = w as i32 - (CLEAR_WIDTH + PADDING) as i32;
+            let text_w = (clear_x - 2 * PADDING as i32).max(0) as u32;
+            let text = fit_text(&downloads_text(&downloads), text_w);
+            widgets.push(Widget::label(DOWNLOADS_ID, Rect::new(PADDING as i32, y, text_w, bar_h), &text, Align::Left));
+            let button = Rect::new(clear_x, y + 3, CLEAR_WIDTH, bar_h - 6);
+            widgets.push(Widget::button(CLEAR_DOWNLOADS_ID, button, "Clear", false));
+        }
+        widgets
+    }
+
+    fn click(&mut self, id: u32) -> bool {
+        let tab = self.tab();
+        let _ = match id {
+            BACK_ID => tab.go(|b| b.go_back().map(|_| ())),
+            FORWARD_ID => tab.go(|b| b.go_forward().map(|_| ())),
+            RELOAD_ID => tab.go(|b| b.reload()),
+            GO_ID => {
+                let url = address_url(&tab.address);
+                tab.navigate(&url)
+            }
+            ADDRESS_ID => {
+                tab.editing = true;
+                Ok(())
+            }
+            NEW_TAB_ID => {
+                self.open_tab();
+                Ok(())
+            }
+            BOOKMARK_ID => {
+                let page = browser::with_tab(tab.id, |b| (b.current_url.clone(), b.title.clone(), b.document.is_some()));
+                if let Some((url, title, true)) = page {
+                    let _ = bookmarks::toggle(&url, &title);
+                }
+                Ok(())
+            }
+            CLEAR_DOWNLOADS_ID => {
+                downloads::clear();
+                Ok(())
+            }
+            TAB_ID.. => {
+                let index = ((id - TAB_ID) / 2) as usize;
+                if (id - TAB_ID) % 2 == 1 {
+                    self.close_tab(index);
+                } else if index < self.tabs.len() {
+                    self.current = index;
+                }
+                Ok(())
+            }
+            _ => {
+                tab.editing = false;
+                Ok(())
+            }
+        };
+        true
+    }
+
+    fn click_at(&mut self, id: u32, x: i32, y: i32) -> bool {
+        if id != PAGE_ID {
+            return self.click(id);
+        }
+        let _ = self.tab().go(|b| b.click(x, y).map(|_| ()));
+        true
+    }
+
+    fn key(&mut self, keycode: u16, ch: char) -> bool {
+        let page_h = self.view.get().1 as i32;
+        let tab = self.tab();
+        if tab.editing {
+            match (keycode, ch) {
+                (0x1C, _) => {
+                    let url = address_url(&tab.address);
+                    let _ = tab.navigate(&url);
+                }
+                (0x01, _) => {
+                    tab.editing = false;
+                    tab.address = browser::with_tab(tab.id, |b| b.current_url.clone()).unwrap_or_default();
+                }
+                (0x0E, _) => {
+                    tab.address.pop();
+                }
+                (_, c) if !c.is_control() => tab.address.push(c),
+                _ => return false,
+            }
+            return true;
+        }
+
+        if let Some(key) = page_key(keycode, ch) {
+            let result = browser::with_tab(tab.id, |b| b.key(key)).unwrap_or(Ok(false));
+            if result != Ok(false) {
+                let _ = tab.go(|_| result.map(|_| ()));
+                return true;
+            }
+        }
+
+        if let Some(scrolled) = browser::with_tab(tab.id, |b| scroll_key(b, keycode, page_h)).flatten() {
+            return scrolled;
+        }
+        match keycode {
+            0x0E => {
+                let _ = tab.go(|b| b.go_back().map(|_| ()));
+                true
+            }
+            0x3F => {
+                let _ = tab.go(|b| b.reload());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn shortcut(&mut self, keycode: u16, modifiers: u8) -> bool {
+        if modifiers & MOD_CTRL == 0 {
+            return false;
+        }
+        match keycode {
+            0x14 => self.open_tab(),
+            0x11 => self.close_tab(self.current),
+            0x0F if modifiers & MOD_SHIFT != 0 => self.cycle_tab(-1),
+            0x0F => self.cycle_tab(1),
+            _ => return false,
+        }
+        true
+    }
+
+    fn scroll(&mut self, id: u32, notches: i32) -> bool {
+        matches!(id, PAGE_ID | SCROLLBAR_ID) && self.tab().with_page(|b| b.scroll_by(notches * LINE_SCROLL))
+    }
+
+    fn history(&mut self, forward: bool) -> bool {
+        let tab = self.tab();
+        let _ = match forward {
+            true => tab.go(|b| b.go_forward().map(|_| ())),
+            false => tab.go(|b| b.go_back().map(|_| ())),
+        };
+        true
+    }
+
+    fn scroll_to(&mut self, id: u32, offset: u32) -> bool {
+        id == SCROLLBAR_ID && self.tab().with_page(|b| b.scroll_to(offset))
+    }
+
+    fn tick(&mut self) -> bool {
+        let (w, h) = self.view.get();
+        let current = self.current;
+        // Every tab keeps loading, but only the one shown is painted; the
+        // others only show in the tab strip, by their titles
+        let mut changed = false;
+        for (i, tab) in self.tabs.iter_mut().enumerate() {
+            let title = (i != current).then(|| tab.label());
+            let ticked = tab.tick(w, h);
+            changed |= if i == current { ticked } else { ticked && title != Some(tab.label()) };
+        }
+        changed || downloads::generation() != self.downloads_shown.get()
+    }
+
+    fn title(&self) -> Option<String> {
+        let tab = &self.tabs[self.current];
+        browser::with_tab(tab.id, |b| b.document.is_some().then(|| format!("{} - WebbBrowser", b.title))).flatten()
+    }
+
+    fn open(&mut self, target: &str) -> Result<(), String> {
+        self.tab().navigate(&address_url(target))
+    }
+}
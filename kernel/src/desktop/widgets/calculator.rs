@@ -0,0 +1,194 @@
+//! Calculator
+//!
+//! A four-function calculator that works left to right, like a pocket
+//! calculator: `2 + 3 * 4 =` gives 20. The keyboard works too: digits,
+//! `.`, `+ - * / %`, Enter or `=`, Backspace, and C to clear.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{grid_cell, NativeApp, Widget, WidgetKind};
+use crate::graphics::compositor::Rect;
+
+const PADDING: u32 = 12;
+const GAP: u32 = 8;
+const DISPLAY_HEIGHT: u32 = 40;
+const MAX_DIGITS: usize = 16;
+
+/// Button labels, four to a row; a button's widget ID is its index
+const BUTTONS: [&str; 20] = [
+    "C", "+/-", "%", "/",
+    "7", "8", "9", "*",
+    "4", "5", "6", "-",
+    "1", "2", "3", "+",
+    "0", ".", "<-", "=",
+];
+
+const DISPLAY_ID: u32 = 100;
+
+pub struct Calculator {
+    /// Number being typed, or the last result
+    entry: String,
+    /// Left operand and the operator waiting for the right one
+    pending: Option<(f64, char)>,
+    /// The next digit starts a new entry
+    fresh: bool,
+}
+
+pub fn new() -> Box<dyn NativeApp> {
+    Box::new(Calculator { entry: String::from("0"), pending: None, fresh: true })
+}
+
+impl Calculator {
+    fn value(&self) -> f64 {
+        self.entry.parse().unwrap_or(0.0)
+    }
+
+    fn digit(&mut self, d: char) {
+        if self.fresh || self.entry == "0" || self.entry == "Error" {
+            self.entry.clear();
+            self.fresh = false;
+        }
+        if d == '.' {
+            if self.entry.contains('.') {
+                return;
+            }
+            if self.entry.is_empty() {
+                self.entry.push('0');
+            }
+        }
+        if self.entry.len() < MAX_DIGITS {
+            self.entry.push(d);
+        }
+    }
+
+    /// Finish the pending operation, if any, and show its result
+    fn evaluate(&mut self) {
+        if let Some((left, op)) = self.pending.take() {
+            let right = self.value();
+            let result = match op {
+                '+' => Some(left + right),
+                '-' => Some(left - right),
+                '*' => Some(left * right),
+                '/' if right != 0.0 => Some(left / right),
+                _ => None,
+            };
+            self.entry = result.map_or(String::from("Error"), format_number);
+        }
+        self.fresh = true;
+    }
+
+    fn operator(&mut self, op: char) {
+        if !self.fresh || self.pending.is_none() {
+            self.evaluate();
+        }
+        self.pending = (self.entry != "Error").then(|| (self.value(), op));
+        self.fresh = true;
+    }
+
+    fn press(&mut self, label: &str) {
+        match label {
+            "C" => {
+                self.entry = String::from("0");
+                self.pending = None;
+                self.fresh = true;
+            }
+            "+/-" => {
+                self.entry = match self.entry.strip_prefix('-') {
+                    Some(positive) => String::from(positive),
+                    None if self.entry != "0" && self.entry != "Error" => format!("-{}", self.entry),
+                    None => return,
+                };
+            }
+            "%" => {
+                self.entry = format_number(self.value() / 100.0);
+                self.fresh = true;
+            }
+            "<-" => {
+                if !self.fresh {
+                    self.entry.pop();
+                    if self.entry.is_empty() || self.entry == "-" {
+                        self.entry = String::from("0");
+                    }
+                }
+            }
+            "=" => self.evaluate(),
+            "+" | "-" | "*" | "/" => self.operator(label.chars().next().unwrap_or('+')),
+            _ => {
+                if let Some(d) = label.chars().next() {
+                    self.digit(d);
+                }
+            }
+        }
+    }
+}
+
+/// Show whole numbers without a decimal point, and keep others short
+fn format_number(n: f64) -> String {
+    if n.is_nan() || n.is_infinite() {
+        return String::from("Error");
+    }
+    if n == (n as i64) as f64 && n.abs() < 1e15 {
+        return format!("{}", n as i64);
+    }
+    let mut text = format!("{:.10}", n);
+    while text.ends_with('0') {
+        text.pop();
+    }
+    text.truncate(MAX_DIGITS + 2);
+    text
+}
+
+impl NativeApp for Calculator {
+    fn size(&self) -> (u32, u32) {
+        (300, 400)
+    }
+
+    fn widgets(&self, w: u32, h: u32) -> Vec<Widget> {
+        let inner_w = w.saturating_sub(2 * PADDING);
+        let mut widgets = alloc::vec![Widget {
+            id: DISPLAY_ID,
+            rect: Rect::new(PADDING as i32, PADDING as i32, inner_w, DISPLAY_HEIGHT),
+            kind: WidgetKind::TextInput { text: self.entry.clone(), focused: false },
+        }];
+        let top = PADDING + DISPLAY_HEIGHT + GAP;
+        let grid = Rect::new(PADDING as i32, top as i32, inner_w, h.saturating_sub(top + PADDING));
+        for (i, label) in BUTTONS.iter().enumerate() {
+            // The operator waiting for its right operand stays lit
+            let waiting = self.fresh && self.pending.map_or(false, |(_, op)| label.len() == 1 && label.starts_with(op));
+            let accent = *label == "=" || waiting;
+            widgets.push(Widget::button(i as u32, grid_cell(grid, 4, 5, GAP, i as u32), label, accent));
+        }
+        widgets
+    }
+
+    fn click(&mut self, id: u32) -> bool {
+        match BUTTONS.get(id as usize) {
+            Some(label) => {
+                self.press(label);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn key(&mut self, keycode: u16, ch: char) -> bool {
+        let label = match (keycode, ch) {
+            (0x1C, _) | (_, '=') => "=",
+            (0x0E, _) => "<-",
+            (_, 'c') | (_, 'C') => "C",
+            (_, '+') => "+",
+            (_, '-') => "-",
+            (_, '*') => "*",
+            (_, '/') => "/",
+            (_, '%') => "%",
+            (_, '.') | (_, ',') => ".",
+            (_, '0'..='9') => BUTTONS.iter().find(|l| l.chars().eq([ch])).copied().unwrap_or("0"),
+            _ => return false,
+        };
+        self.press(label);
+        true
+    }
+}
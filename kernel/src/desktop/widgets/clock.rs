@@ -0,0 +1,54 @@
+//! Clock
+//!
+//! The local time and date, updated every second.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::{Align, NativeApp, Widget};
+use crate::drivers::rtc::{self, DateTime};
+use crate::graphics::compositor::Rect;
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+pub struct Clock {
+    now: DateTime,
+}
+
+pub fn new() -> Box<dyn NativeApp> {
+    Box::new(Clock { now: rtc::local() })
+}
+
+impl NativeApp for Clock {
+    fn size(&self) -> (u32, u32) {
+        (260, 140)
+    }
+
+    fn widgets(&self, w: u32, h: u32) -> Vec<Widget> {
+        let t = &self.now;
+        let time = format!("{:02}:{:02}:{:02}", t.hour, t.minute, t.second);
+        let month = MONTHS.get((t.month as usize).wrapping_sub(1)).copied().unwrap_or("?");
+        let date = format!("{} {} {}", t.day, month, t.year);
+        let half = h / 2;
+        alloc::vec![
+            Widget::label(0, Rect::new(0, 0, w, half), &time, Align::Center),
+            Widget::label(1, Rect::new(0, half as i32, w, half / 2), &date, Align::Center),
+        ]
+    }
+
+    fn click(&mut self, _id: u32) -> bool {
+        false
+    }
+
+    fn key(&mut self, _keycode: u16, _ch: char) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> bool {
+        let now = rtc::local();
+        let changed = now.second != self.now.second || now.minute != self.now.minute;
+        self.now = now;
+        changed
+    }
+}
@@ -0,0 +1,335 @@
+//! Native widgets
+//!
+//! Some apps are built from widgets the desktop draws itself rather than
+//! from HTML: labels, buttons, text inputs, graphs and pages rendered by
+//! the browser engine. A `NativeApp` lays
+//! its widgets out for the size of its window's content area whenever the
+//! desktop paints, and is told about clicks on them, keys typed while its
+//! window has focus and the passing of time. Widget rectangles are
+//! relative to the top left of the content area.
+//!
+//! An app that scrolls something itself, such as a page, shows a
+//! `Scrollbar` beside it and is told when the wheel turns over its widgets
+//! and when the scrollbar is dragged. Widgets that reach below the content
+//! area are scrolled by the desktop instead.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::paint::{draw_text, Theme};
+use crate::browser::render::Framebuffer;
+use crate::drivers::vesa::colors;
+use crate::graphics::compositor::{Compositor, Rect};
+use crate::graphics::font;
+use crate::graphics::raster;
+
+pub mod browser;
+pub mod calculator;
+pub mod clock;
+pub mod monitor;
+pub mod webapp;
+
+/// Where a label's text sits within its rectangle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+pub enum WidgetKind {
+    Label { text: String, align: Align },
+    /// `accent` buttons are drawn in the theme's highlight color
+    Button { label: String, accent: bool },
+    TextInput { text: String, focused: bool },
+    /// A button showing an arrow rather than a label, pointing right if
+    /// `forward` and left otherwise
+    Arrow { forward: bool },
+    /// Samples from 0 to 100, oldest first, drawn right-aligned so the
+    /// newest is at the right edge
+    Graph { samples: Vec<u8> },
+    /// A rendered web page, shown from its top left corner
+    Page { page: Arc<Framebuffer> },
+    /// A vertical scrollbar for `view` rows shown of `content`, starting
+    /// at row `offset`
+    Scrollbar { offset: u32, content: u32, view: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Widget {
+    /// Identifies the widget to the app in `click`
+    pub id: u32,
+    pub rect: Rect,
+    pub kind: WidgetKind,
+}
+
+impl Widget {
+    pub fn label(id: u32, rect: Rect, text: &str, align: Align) -> Self {
+        Widget { id, rect, kind: WidgetKind::Label { text: String::from(text), align } }
+    }
+
+    pub fn button(id: u32, rect: Rect, label: &str, accent: bool) -> Self {
+        Widget { id, rect, kind: WidgetKind::Button { label: String::from(label), accent } }
+    }
+
+    pub fn arrow(id: u32, rect: Rect, forward: bool) -> Self {
+        Widget { id, rect, kind: WidgetKind::Arrow { forward } }
+    }
+}
+
+/// An app drawn with widgets
+pub trait NativeApp: Send {
+    /// Window size to open with
+    fn size(&self) -> (u32, u32);
+
+    /// Widgets for a content area `w` by `h` pixels
+    fn widgets(&self, w: u32, h: u32) -> Vec<Widget>;
+
+    /// Widget `id` was clicked; returns true if anything changed
+    fn click(&mut self, id: u32) -> bool;
+
+    /// Widget `id` was clicked at (x, y) within it; apps that only need to
+    /// know which widget was clicked leave this to `click`
+    fn click_at(&mut self, id: u32, _x: i32, _y: i32) -> bool {
+        self.click(id)
+    }
+
+    /// The pointer moved to (x, y), relative to widget `id`, while the
+    /// button pressed on it is held; it may be outside the widget or the
+    /// window. Returns true if anything changed
+    fn drag(&mut self, _id: u32, _x: i32, _y: i32) -> bool {
+        false
+    }
+
+    /// The button pressed on widget `id` was let go at (x, y) relative to
+    /// it; returns true if anything changed
+    fn release(&mut self, _id: u32, _x: i32, _y: i32) -> bool {
+        false
+    }
+
+    /// Key pressed while the window has focus, with the character it
+    /// types (`'\0'` if none); returns true if it was used
+    fn key(&mut self, keycode: u16, ch: char) -> bool;
+
+    /// Key pressed with Ctrl or Alt held while the window has focus, before
+    /// `key` sees it; returns true if it was used
+    fn shortcut(&mut self, _keycode: u16, _modifiers: u8) -> bool {
+        false
+    }
+
+    /// The mouse wheel turned `notches` over widget `id`, positive
+    /// downwards; returns true if anything scrolled
+    fn scroll(&mut self, _id: u32, _notches: i32) -> bool {
+        false
+    }
+
+    /// The mouse's back button, or forward if `forward`, was pressed over
+    /// the window; returns true if it was used
+    fn history(&mut self, _forward: bool) -> bool {
+        false
+    }
+
+    /// Scrollbar `id` was dragged, or its track clicked, to show from row
+    /// `offset`; returns true if anything scrolled
+    fn scroll_to(&mut self, _id: u32, _offset: u32) -> bool {
+        false
+    }
+
+    /// Called every desktop tick; returns true if the app needs repainting
+    fn tick(&mut self) -> bool {
+        false
+    }
+
+    /// Window title the app wants in place of its name, if any
+    fn title(&self) -> Option<String> {
+        None
+    }
+
+    /// Show `target`, a path or URL, as when the app is asked to open a
+    /// document; the error says why it could not
+    fn open(&mut self, _target: &str) -> Result<(), String> {
+        Err(String::from("This app does not open documents"))
+    }
+}
+
+/// Creates a fresh instance of a native app for a new window
+pub type NativeConstructor = fn() -> Box<dyn NativeApp>;
+
+/// Topmost widget under (x, y), relative to the content area
+pub fn widget_at(widgets: &[Widget], x: i32, y: i32) -> Option<&Widget> {
+    widgets.iter().rev().find(|w| w.rect.contains(x, y))
+}
+
+/// Width of a scrollbar
+pub const SCROLLBAR_WIDTH: u32 = 12;
+
+/// Shortest a scrollbar's thumb gets
+const MIN_THUMB: u32 = 20;
+
+/// The thumb of a scrollbar along `track`, for `view` rows shown of
+/// `content` from row `offset`; the whole track if everything is shown
+pub fn scrollbar_thumb(track: Rect, offset: u32, content: u32, view: u32) -> Rect {
+    if content <= view || track.h == 0 {
+        return track;
+    }
+    let h = ((track.h as u64 * view as u64 / content as u64) as u32).max(MIN_THUMB).min(track.h);
+    let max = content - view;
+    let y = (track.h - h) as u64 * offset.min(max) as u64 / max as u64;
+    Rect::new(track.x, track.y + y as i32, track.w, h)
+}
+
+/// The row to show from with the top of a scrollbar's thumb at `thumb_y`
+pub fn scrollbar_offset(track: Rect, content: u32, view: u32, thumb_y: i32) -> u32 {
+    let travel = track.h - scrollbar_thumb(track, 0, content, view).h;
+    if travel == 0 {
+        return 0;
+    }
+    let y = (thumb_y - track.y).max(0).min(travel as i32) as u64;
+    (y * (content - view) as u64 / travel as u64) as u32
+}
+
+/// Draw a scrollbar along `track`; the thumb shows only when there is
+/// something to scroll
+pub fn paint_scrollbar(c: &mut Compositor, track: Rect, offset: u32, content: u32, view: u32, theme: &Theme) {
+    c.fill_rect(track.x, track.y, track.w, track.h, theme.field);
+    if content > view {
+        let thumb = scrollbar_thumb(track, offset, content, view);
+        let w = thumb.w.saturating_sub(4);
+        raster::fill_rounded_rect(c, thumb.x + 2, thumb.y + 2, w, thumb.h.saturating_sub(4), w / 2, theme.title_inactive);
+    }
+}
+
+/// Corners of the arrow drawn on an `Arrow` button filling `r`
+fn arrow_points(r: Rect, forward: bool) -> [(i32, i32); 3] {
+    let size = (r.w.min(r.h) / 3) as i32;
+    let (cx, cy) = (r.x + r.w as i32 / 2, r.y + r.h as i32 / 2);
+    let (tip, back) = if forward { (cx + size / 2, cx - size / 2) } else { (cx - size / 2, cx + size / 2) };
+    [(tip, cy), (back, cy - size / 2), (back, cy + size / 2)]
+}
+
+/// Points of a graph of `samples` in `r`, one every `step` pixels with the
+/// newest at the right edge; the oldest that do not fit are left out
+fn graph_points(r: Rect, samples: &[u8], step: u32) -> Vec<(i32, i32)> {
+    let shown = (r.w / step) as usize;
+    let start = samples.len().saturating_sub(shown);
+    samples[start..].iter().enumerate().map(|(i, &sample)| {
+        let x = r.right() - 1 - ((samples.len() - start - 1 - i) as u32 * step) as i32;
+        let y = r.bottom() - 1 - ((r.h - 1) * sample.min(100) as u32 / 100) as i32;
+        (x, y)
+    }).collect()
+}
+
+/// Grid cell `index` of `columns` by `rows` cells filling `area`, with
+/// `gap` pixels between cells
+pub fn grid_cell(area: Rect, columns: u32, rows: u32, gap: u32, index: u32) -> Rect {
+    let w = area.w.saturating_sub(gap * (columns - 1)) / columns;
+    let h = area.h.saturating_sub(gap * (rows - 1)) / rows;
+    let (col, row) = (index % columns, index / columns);
+    Rect::new(area.x + (col * (w + gap)) as i32, area.y + (row * (h + gap)) as i32, w, h)
+}
+
+/// Draw `widgets` into the content area `content`
+pub fn paint(c: &mut Compositor, content: Rect, widgets: &[Widget], theme: &Theme) {
+    let (cell_w, cell_h) = font::cell_size();
+    for widget in widgets {
+        let r = Rect::new(content.x + widget.rect.x, content.y + widget.rect.y, widget.rect.w, widget.rect.h);
+        let text_y = r.y + (r.h as i32 - cell_h as i32) / 2;
+        let centred = |text: &str| r.x + (r.w as i32 - (text.chars().count() as u32 * cell_w) as i32) / 2;
+        match &widget.kind {
+            WidgetKind::Label { text, align } => {
+                let width = (text.chars().count() as u32 * cell_w) as i32;
+                let x = match align {
+                    Align::Left => r.x,
+                    Align::Center => centred(text),
+                    Align::Right => (r.right() - width).max(r.x),
+                };
+                draw_text(c, text, x, text_y, r.w, theme.content_text);
+            }
+            WidgetKind::Button { label, accent } => {
+                let (fill, text) = if *accent { (theme.title_active, colors::WHITE) } else { (theme.field, theme.content_text) };
+                raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, 6, theme.title_inactive);
+                raster::fill_rounded_rect(c, r.x + 1, r.y + 1, r.w.saturating_sub(2), r.h.saturating_sub(2), 5, fill);
+                draw_text(c, label, centred(label), text_y, r.w, text);
+            }
+            WidgetKind::Arrow { forward } => {
+                raster::fill_rounded_rect(c, r.x, r.y, r.w, r.h, 6, theme.title_inactive);
+                raster::fill_rounded_rect(c, r.x + 1, r.y + 1, r.w.saturating_sub(2), r.h.saturating_sub(2), 5, theme.field);
+                let [tip, top, bottom] = arrow_points(r, *forward);
+                raster::fill_triangle(c, tip, top, bottom, theme.content_text);
+            }
+            WidgetKind::TextInput { text, focused } => {
+                let border = if *focused { theme.title_active } else { theme.title_inactive };
+                c.fill_rect(r.x, r.y, r.w, r.h, border);
+                c.fill_rect(r.x + 1, r.y + 1, r.w.saturating_sub(2), r.h.saturating_sub(2), theme.field);
+                let mut shown = text.clone();
+                if *focused {
+                    shown.push('_');
+                }
+                // Keep the end of long text in view
+                let fits = (r.w.saturating_sub(2 * cell_w) / cell_w) as usize;
+                let skip = shown.chars().count().saturating_sub(fits);
+                let shown: String = shown.chars().skip(skip).collect();
+                draw_text(c, &shown, r.x + cell_w as i32, text_y, r.w.saturating_sub(cell_w), theme.content_text);
+            }
+            WidgetKind::Graph { samples } => {
+                c.fill_rect(r.x, r.y, r.w, r.h, theme.field);
+                for level in [25, 50, 75] {
+                    c.fill_rect(r.x, r.bottom() - (r.h * level / 100) as i32, r.w, 1, theme.selection);
+                }
+                let mut points = graph_points(r, samples, 4);
+                let line = points.len();
+                // The area under the line is shaded, closed off along the bottom
+                if let (Some(&(first, _)), Some(&(last, _))) = (points.first(), points.last()) {
+                    points.extend([(last + 1, r.bottom()), (first, r.bottom())]);
+                    raster::fill_polygon(c, &points, theme.title_active & 0x00FF_FFFF | 0x4000_0000);
+                }
+                for pair in points[..line].windows(2) {
+                    raster::draw_line_aa(c, pair[0].0, pair[0].1, pair[1].0, pair[1].1, theme.title_active);
+                }
+            }
+            WidgetKind::Page { page } => {
+                let w = page.width.min(r.w);
+                let h = page.height.min(r.h);
+                for row in 0..h {
+                    let start = (row * page.width) as usize;
+                    c.blit(&page.data[start..start + w as usize], w, r.x, r.y + row as i32);
+                }
+                // An area larger than the page shows blank beyond it
+                c.fill_rect(r.x + w as i32, r.y, r.w - w, r.h, colors::WHITE);
+                c.fill_rect(r.x, r.y + h as i32, w, r.h - h, colors::WHITE);
+            }
+            WidgetKind::Scrollbar { offset, content, view } => {
+                paint_scrollbar(c, r, *offset, *content, *view, theme);
+            }
+        }
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn arrows_point_their_way() -> Result<(), String> {
+        let r = Rect::new(10, 10, 30, 24);
+        let [tip, top, bottom] = arrow_points(r, true);
+        check!(tip.0 > top.0 && top.0 == bottom.0 && top.1 < tip.1 && tip.1 < bottom.1);
+        let [tip, top, _] = arrow_points(r, false);
+        check!(tip.0 < top.0);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn graph_keeps_the_newest_samples_at_the_right_edge() -> Result<(), String> {
+        let r = Rect::new(0, 0, 8, 11);
+        let points = graph_points(r, &[50, 0, 100, 50], 4);
+        // Two fit; the newest is in the last column, 100 at the top
+        check_eq!(points, alloc::vec![(3, 0), (7, 5)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! System monitor
+//!
+//! Kernel heap use plotted over the last few minutes, one sample a
+//! second, with the current figures and process count above the graph.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::{Align, NativeApp, Widget, WidgetKind};
+use crate::drivers::rtc;
+use crate::graphics::compositor::Rect;
+use crate::mm::allocator;
+use crate::process::PROCESSES;
+
+const PADDING: u32 = 12;
+const LINE_HEIGHT: u32 = 24;
+/// Samples kept; the graph shows as many as fit
+const HISTORY: usize = 300;
+
+pub struct Monitor {
+    /// Heap use in percent, oldest first
+    samples: Vec<u8>,
+    used: u64,
+    total: u64,
+    processes: usize,
+    /// RTC second the last sample was taken in
+    second: u8,
+}
+
+pub fn new() -> Box<dyn NativeApp> {
+    let mut monitor = Monitor { samples: Vec::new(), used: 0, total: 0, processes: 0, second: 0xFF };
+    monitor.tick();
+    Box::new(monitor)
+}
+
+impl NativeApp for Monitor {
+    fn size(&self) -> (u32, u32) {
+        (480, 320)
+    }
+
+    fn widgets(&self, w: u32, h: u32) -> Vec<Widget> {
+        let inner_w = w.saturating_sub(2 * PADDING);
+        let mb = |bytes: u64| bytes / (1024 * 1024);
+        let percent = self.samples.last().copied().unwrap_or(0);
+        let memory = format!("Heap: {} MB of {} MB ({}%)", mb(self.used), mb(self.total), percent);
+        let processes = format!("Processes: {}", self.processes);
+        let top = PADDING + LINE_HEIGHT + PADDING / 2;
+        alloc::vec![
+            Widget::label(0, Rect::new(PADDING as i32, PADDING as i32, inner_w, LINE_HEIGHT), &memory, Align::Left),
+            Widget::label(1, Rect::new(PADDING as i32, PADDING as i32, inner_w, LINE_HEIGHT), &processes, Align::Right),
+            Widget {
+                id: 2,
+                rect: Rect::new(PADDING as i32, top as i32, inner_w, h.saturating_sub(top + PADDING)),
+                kind: WidgetKind::Graph { samples: self.samples.clone() },
+            },
+        ]
+    }
+
+    fn click(&mut self, _id: u32) -> bool {
+        false
+    }
+
+    fn key(&mut self, _keycode: u16, _ch: char) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> bool {
+        let second = rtc::now().second;
+        if second == self.second {
+            return false;
+        }
+        self.second = second;
+        self.used = allocator::used_heap();
+        self.total = self.used + allocator::free_heap();
+        self.processes = PROCESSES.lock().len();
+        let percent = if self.total == 0 { 0 } else { self.used * 100 / self.total };
+        if self.samples.len() == HISTORY {
+            self.samples.remove(0);
+        }
+        self.samples.push(percent as u8);
+        true
+    }
+}
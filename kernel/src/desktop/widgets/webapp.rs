@@ -0,0 +1,110 @@
+//! HTML apps
+//!
+//! The desktop's bundled HTML apps, shown as a page in a browser tab of
+//! their own with their scripts running. Messages a page posts to
+//! `window.parent` go to the desktop's message router, and the replies
+//! come back to the page as `message` events, a tick later. Pages longer
+//! than the window scroll with the wheel and with the keys the page has
+//! no use for.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use super::browser::{page_key, scroll_key, LINE_SCROLL};
+use super::{Align, NativeApp, Widget, WidgetKind};
+use crate::browser::{self, TabId};
+use crate::desktop::{ipc, Application, WindowId};
+use crate::graphics::compositor::Rect;
+use crate::warn;
+
+const PAGE_ID: u32 = 0;
+
+pub struct WebApp {
+    tab: TabId,
+    window: WindowId,
+    /// Size of the window's content area when it was last painted, which
+    /// the tab's viewport follows
+    view: Cell<(u32, u32)>,
+}
+
+/// Open `app` in window `window`, as a page made of its markup, styles
+/// and script
+pub fn new(window: WindowId, app: &Application) -> Box<dyn NativeApp> {
+    let page = format!(
+        "<!DOCTYPE html><html><head><title>{}</title><style>{}</style></head><body>{}<script>{}</script></body></html>",
+        app.title, app.css_styles, app.html_content, app.js_scripts,
+    );
+    let tab = browser::open_tab();
+    let url = format!("webbos://apps/{}.html", app.name);
+    let shown = browser::with_tab(tab, |b| b.show_html(&url, page.as_bytes()));
+    if let Some(Err(e)) = shown {
+        warn!("desktop", "Cannot show {}: {:?}", url, e);
+    }
+    Box::new(WebApp { tab, window, view: Cell::new((0, 0)) })
+}
+
+impl Drop for WebApp {
+    fn drop(&mut self) {
+        browser::close_tab(self.tab);
+    }
+}
+
+impl NativeApp for WebApp {
+    fn size(&self) -> (u32, u32) {
+        let config = browser::BrowserConfig::default();
+        (config.viewport_width, config.viewport_height)
+    }
+
+    fn widgets(&self, w: u32, h: u32) -> Vec<Widget> {
+        self.view.set((w, h));
+        let area = Rect::new(0, 0, w, h);
+        let page = browser::with_tab(self.tab, |b| b.render_context.framebuffer.clone()).flatten();
+        match page {
+            Some(page) => alloc::vec![Widget { id: PAGE_ID, rect: area, kind: WidgetKind::Page { page } }],
+            None => alloc::vec![Widget::label(PAGE_ID, area, "This app could not be shown", Align::Center)],
+        }
+    }
+
+    fn click(&mut self, _id: u32) -> bool {
+        false
+    }
+
+    fn click_at(&mut self, _id: u32, x: i32, y: i32) -> bool {
+        browser::with_tab(self.tab, |b| b.click(x, y).unwrap_or(true)).unwrap_or(false)
+    }
+
+    fn key(&mut self, keycode: u16, ch: char) -> bool {
+        let page_h = self.view.get().1 as i32;
+        browser::with_tab(self.tab, |b| {
+            let used = page_key(keycode, ch).map_or(false, |key| b.key(key).unwrap_or(true));
+            used || scroll_key(b, keycode, page_h).unwrap_or(false)
+        })
+        .unwrap_or(false)
+    }
+
+    fn scroll(&mut self, _id: u32, notches: i32) -> bool {
+        browser::with_tab(self.tab, |b| b.scroll_by(notches * LINE_SCROLL)).unwrap_or(false)
+    }
+
+    fn tick(&mut self) -> bool {
+        let (w, h) = self.view.get();
+        let window = self.window;
+        browser::with_tab(self.tab, |b| {
+            let mut changed = w > 0 && h > 0 && b.set_viewport(w, h);
+            changed |= b.load_next_image();
+            changed |= b.send_next_request();
+            changed |= b.run_timers();
+            for message in b.take_messages() {
+                ipc::queue(window, message);
+            }
+            let responses = ipc::take_responses(window);
+            for response in &responses {
+                b.deliver_message(&response.to_json());
+            }
+            changed || !responses.is_empty()
+        })
+        .unwrap_or(false)
+    }
+}
@@ -0,0 +1,78 @@
+//! High Precision Event Timer
+//!
+//! The HPET's main counter runs at a fixed rate, given in femtoseconds
+//! per count, whatever the processor does. The timer uses it as the
+//! reference to calibrate the TSC against, and to tell the time with if
+//! the TSC is not invariant.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use webbos_shared::types::PhysAddr;
+
+use crate::{info, warn};
+
+// Registers
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xF0;
+
+/// Configuration: the main counter runs
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// The HPET's period can be no more than 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Virtual address of the registers; 0 until `init` succeeds
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Femtoseconds per count of the main counter
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: u64) -> u64 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile((base + reg) as *const u64) }
+}
+
+fn write(reg: u64, value: u64) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u64, value) }
+}
+
+/// Start the main counter of the HPET the ACPI tables list, if any
+pub fn init() -> bool {
+    let phys = match crate::acpi::hpet() {
+        Some(phys) => phys,
+        None => return false,
+    };
+    let base = match crate::mm::map_mmio(PhysAddr::new(phys), 0x400) {
+        Some(virt) => virt.as_u64(),
+        None => {
+            warn!("hpet", "Could not map the HPET at {:#x}", phys);
+            return false;
+        }
+    };
+    BASE.store(base, Ordering::Relaxed);
+    let period = read(CAPABILITIES) >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        warn!("hpet", "HPET at {:#x} has a bad period of {}fs", phys, period);
+        BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    write(CONFIGURATION, read(CONFIGURATION) | ENABLE_CNF);
+    info!("hpet", "HPET at {:#x}, {}kHz", phys, 1_000_000_000_000 / period);
+    true
+}
+
+/// The main counter
+pub fn counter() -> u64 {
+    read(MAIN_COUNTER)
+}
+
+/// Femtoseconds per count of the main counter
+pub fn period_fs() -> u64 {
+    PERIOD_FS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds `counts` of the main counter take
+pub fn counts_to_ns(counts: u64) -> u64 {
+    (counts as u128 * period_fs() as u128 / 1_000_000) as u64
+}
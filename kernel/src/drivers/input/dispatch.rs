@@ -0,0 +1,122 @@
+//! Input dispatch
+//!
+//! Decides who gets queued input events. A desktop session, while one is
+//! active, takes every event through the handler it began with: keys go
+//! to the focused window and pointer events to the window under the
+//! pointer, or to the one holding the pointer during a drag. Only when no
+//! session is active does the console see key presses, through
+//! `get_key`.
+//!
+//! Events queued with `inject_event` take the same route as those from
+//! the keyboard and mouse.
+
+use spin::Mutex;
+
+use super::{EventType, InputEvent};
+
+/// Takes an event; returns true if it was used
+pub type Handler = fn(&InputEvent) -> bool;
+
+/// Handler of the active session, if any
+static SESSION: Mutex<Option<Handler>> = Mutex::new(None);
+
+/// Send input to `handler` until `end_session`
+pub fn begin_session(handler: Handler) {
+    *SESSION.lock() = Some(handler);
+}
+
+/// Give input back to the console
+pub fn end_session() {
+    *SESSION.lock() = None;
+}
+
+/// Whether a session has the input
+pub fn session_active() -> bool {
+    SESSION.lock().is_some()
+}
+
+/// Read the devices and pass queued events to the session's handler,
+/// stopping at the first key press it does not use, which is returned
+///
+/// Without a session the events stay queued for the console.
+pub fn dispatch() -> Option<InputEvent> {
+    let handler = match *SESSION.lock() {
+        Some(handler) => handler,
+        None => return None,
+    };
+    super::poll();
+    while let Some(event) = super::poll_event() {
+        if !handler(&event) && event.event_type == EventType::KeyPress {
+            return Some(event);
+        }
+    }
+    None
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+    use super::super::{get_key, inject_event, mouse_position, poll_event};
+
+    /// Events the test handler was given
+    static SEEN: Mutex<Vec<InputEvent>> = Mutex::new(Vec::new());
+
+    /// Takes everything but the `q` key
+    fn record(event: &InputEvent) -> bool {
+        SEEN.lock().push(*event);
+        event.ch != 'q'
+    }
+
+    fn drain() {
+        while poll_event().is_some() {}
+    }
+
+    #[kernel_test]
+    fn injected_events_reach_the_session() -> Result<(), String> {
+        check!(!session_active());
+        drain();
+        SEEN.lock().clear();
+        begin_session(record);
+        inject_event(InputEvent::key(EventType::KeyPress, 0x1E, 'a', 0));
+        inject_event(InputEvent::pointer(EventType::MouseMove, 40, 30, 0));
+        inject_event(InputEvent::pointer(EventType::MouseButtonPress, 40, 30, 0));
+        inject_event(InputEvent::key(EventType::KeyPress, 0x10, 'q', 0));
+        inject_event(InputEvent::key(EventType::KeyPress, 0x30, 'b', 0));
+        // The console gets nothing while the session has the input
+        check!(get_key().is_none());
+        // Dispatch stops at the key the session did not use
+        let unused = dispatch();
+        let seen: Vec<(EventType, char, i32)> = SEEN.lock().iter().map(|e| (e.event_type, e.ch, e.x)).collect();
+        check_eq!(unused.map(|e| e.ch), Some('q'));
+        check_eq!(seen, alloc::vec![
+            (EventType::KeyPress, 'a', 0), (EventType::MouseMove, '\0', 40),
+            (EventType::MouseButtonPress, '\0', 40), (EventType::KeyPress, 'q', 0),
+        ]);
+        check_eq!(mouse_position(), (40, 30));
+        check!(dispatch().is_none());
+        check_eq!(SEEN.lock().last().map(|e| e.ch), Some('b'));
+        end_session();
+        check!(dispatch().is_none());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn without_a_session_keys_go_to_the_console() -> Result<(), String> {
+        check!(!session_active());
+        drain();
+        inject_event(InputEvent::pointer(EventType::MouseMove, 5, 5, 0));
+        inject_event(InputEvent::key(EventType::KeyRelease, 0x1E, 'a', 0));
+        inject_event(InputEvent::key(EventType::KeyPress, 0x1E, 'x', 0));
+        // Pointer events and releases are skipped on the way to a press
+        let mut key = None;
+        for _ in 0..3 {
+            key = key.or(get_key());
+        }
+        check_eq!(key.map(|e| e.ch), Some('x'));
+        check!(dispatch().is_none());
+        Ok(())
+    }
+}
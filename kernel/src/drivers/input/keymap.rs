@@ -0,0 +1,267 @@
+//! Keyboard layouts
+//!
+//! A layout gives the character of each key that types one: on its own,
+//! with Shift, and with AltGr (right Alt). Caps Lock acts as Shift on
+//! letters only. Keys such as Enter and Backspace are the same on every
+//! layout.
+//!
+//! Dead keys, the accents on German and French keyboards, type nothing
+//! themselves but put their accent on the next letter: ´ then e gives é.
+//! A space or another dead key after one gives the accent on its own; any
+//! other key gives its own character and the accent is dropped.
+
+/// Keyboard layout used to turn scancodes into characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    Fr,
+    Dvorak,
+}
+
+/// Characters of a row of keys, from scancode `first` on; a space is a
+/// key that types nothing, and combining accents are dead keys
+struct Row {
+    first: u8,
+    normal: &'static str,
+    shift: &'static str,
+    altgr: &'static str,
+}
+
+const fn row(first: u8, normal: &'static str, shift: &'static str, altgr: &'static str) -> Row {
+    Row { first, normal, shift, altgr }
+}
+
+const US: &[Row] = &[
+    row(0x02, "1234567890-=", "!@#$%^&*()_+", ""),
+    row(0x10, "qwertyuiop[]", "QWERTYUIOP{}", ""),
+    row(0x1E, "asdfghjkl;'`", "ASDFGHJKL:\"~", ""),
+    row(0x2B, "\\", "|", ""),
+    row(0x2C, "zxcvbnm,./", "ZXCVBNM<>?", ""),
+    row(0x56, "\\", "|", ""),
+];
+
+const UK: &[Row] = &[
+    row(0x02, "1234567890-=", "!\"£$%^&*()_+", "   €"),
+    row(0x10, "qwertyuiop[]", "QWERTYUIOP{}", "  é   úíó"),
+    row(0x1E, "asdfghjkl;'`", "ASDFGHJKL:@¬", "á          ¦"),
+    row(0x2B, "#", "~", ""),
+    row(0x2C, "zxcvbnm,./", "ZXCVBNM<>?", ""),
+    row(0x56, "\\", "|", ""),
+];
+
+const DE: &[Row] = &[
+    row(0x02, "1234567890ß\u{301}", "!\"§$%&/()=?\u{300}", " ²³   {[]}\\"),
+    row(0x10, "qwertzuiopü+", "QWERTZUIOPÜ*", "@ €        ~"),
+    row(0x1E, "asdfghjklöä\u{302}", "ASDFGHJKLÖÄ°", ""),
+    row(0x2B, "#", "'", ""),
+    row(0x2C, "yxcvbnm,.-", "YXCVBNM;:_", "      µ"),
+    row(0x56, "<", ">", "|"),
+];
+
+const FR: &[Row] = &[
+    row(0x02, "&é\"'(-è_çà)=", "1234567890°+", " ~#{[|`\\^@]}"),
+    row(0x10, "azertyuiop\u{302}$", "AZERTYUIOP\u{308}£", "  €        ¤"),
+    row(0x1E, "qsdfghjklmù²", "QSDFGHJKLM%", ""),
+    row(0x2B, "*", "µ", ""),
+    row(0x2C, "wxcvbn,;:!", "WXCVBN?./§", ""),
+    row(0x56, "<", ">", ""),
+];
+
+const DVORAK: &[Row] = &[
+    row(0x02, "1234567890[]", "!@#$%^&*(){}", ""),
+    row(0x10, "',.pyfgcrl/=", "\"<>PYFGCRL?+", ""),
+    row(0x1E, "aoeuidhtns-`", "AOEUIDHTNS_~", ""),
+    row(0x2B, "\\", "|", ""),
+    row(0x2C, ";qjkxbmwvz", ":QJKXBMWVZ", ""),
+    row(0x56, "\\", "|", ""),
+];
+
+/// Letters each dead key accents, and what they become
+const COMPOSE: &[(char, &str, &str)] = &[
+    ('\u{300}', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('\u{301}', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('\u{302}', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('\u{303}', "anoANO", "ãñõÃÑÕ"),
+    ('\u{308}', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::De, Layout::Fr, Layout::Dvorak];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Fr => "fr",
+            Layout::Dvorak => "dvorak",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Layout::Us => "English (US)",
+            Layout::Uk => "English (UK)",
+            Layout::De => "German",
+            Layout::Fr => "French (AZERTY)",
+            Layout::Dvorak => "English (Dvorak)",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.name() == name)
+    }
+
+    fn rows(self) -> &'static [Row] {
+        match self {
+            Layout::Us => US,
+            Layout::Uk => UK,
+            Layout::De => DE,
+            Layout::Fr => FR,
+            Layout::Dvorak => DVORAK,
+        }
+    }
+
+    /// Whether AltGr types anything; on layouts where it does not, right
+    /// Alt is just Alt
+    pub fn has_altgr(self) -> bool {
+        self.rows().iter().any(|r| !r.altgr.trim().is_empty())
+    }
+
+    /// Character key `scancode` types, or a combining accent for a dead
+    /// key; `None` for keys that type nothing
+    pub fn lookup(self, scancode: u8, shift: bool, caps: bool, altgr: bool) -> Option<char> {
+        match scancode {
+            0x01 => return Some('\x1b'),
+            0x0E => return Some('\x08'),
+            0x0F => return Some('\t'),
+            0x1C => return Some('\n'),
+            0x37 => return Some('*'),
+            0x39 => return Some(' '),
+            _ => {}
+        }
+        let row = self.rows().iter().find(|r| (r.first..r.first + r.normal.chars().count() as u8).contains(&scancode))?;
+        let index = (scancode - row.first) as usize;
+        let pick = |chars: &str| chars.chars().nth(index).filter(|&c| c != ' ');
+        if altgr {
+            return pick(row.altgr);
+        }
+        let normal = pick(row.normal)?;
+        let upper = pick(row.shift);
+        // Caps Lock shifts letters only, and Shift undoes it
+        let letter = normal.is_alphabetic() && upper.map_or(false, char::is_alphabetic);
+        if shift ^ (caps && letter) { upper } else { Some(normal) }
+    }
+}
+
+/// Whether `c` is a dead key's accent
+pub fn is_dead(c: char) -> bool {
+    COMPOSE.iter().any(|&(accent, _, _)| accent == c)
+}
+
+/// What dead key `accent` followed by `c` types
+pub fn compose(accent: char, c: char) -> char {
+    if c == ' ' || is_dead(c) {
+        return match accent {
+            '\u{300}' => '`',
+            '\u{301}' => '´',
+            '\u{302}' => '^',
+            '\u{303}' => '~',
+            _ => '¨',
+        };
+    }
+    COMPOSE.iter()
+        .find(|&&(a, _, _)| a == accent)
+        .and_then(|&(_, plain, accented)| plain.chars().position(|p| p == c).and_then(|i| accented.chars().nth(i)))
+        .unwrap_or(c)
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn plain(layout: Layout, scancode: u8) -> Option<char> {
+        layout.lookup(scancode, false, false, false)
+    }
+
+    #[kernel_test]
+    fn each_layout_has_its_own_letters() -> Result<(), String> {
+        // The keys US calls q, y, a, z and m
+        let keys = [0x10, 0x15, 0x1E, 0x2C, 0x32];
+        let typed = |layout| keys.iter().map(|&k| plain(layout, k).unwrap_or('?')).collect::<String>();
+        check_eq!(typed(Layout::Us), "qyazm");
+        check_eq!(typed(Layout::Uk), "qyazm");
+        check_eq!(typed(Layout::De), "qzaym");
+        check_eq!(typed(Layout::Fr), "ayqw,");
+        check_eq!(typed(Layout::Dvorak), "'fa;m");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn shift_and_altgr_pick_other_characters() -> Result<(), String> {
+        // Shift+2 and Shift+3
+        check_eq!(Layout::Us.lookup(0x03, true, false, false), Some('@'));
+        check_eq!(Layout::Uk.lookup(0x03, true, false, false), Some('"'));
+        check_eq!(Layout::Uk.lookup(0x04, true, false, false), Some('£'));
+        check_eq!(plain(Layout::Fr, 0x03), Some('é'));
+        check_eq!(Layout::Fr.lookup(0x03, true, false, false), Some('2'));
+        // AltGr
+        check_eq!(Layout::De.lookup(0x10, false, false, true), Some('@'));
+        check_eq!(Layout::Uk.lookup(0x05, false, false, true), Some('€'));
+        check_eq!(Layout::Fr.lookup(0x09, false, false, true), Some('\\'));
+        check_eq!(Layout::De.lookup(0x56, false, false, true), Some('|'));
+        // A key with nothing on AltGr types nothing
+        check_eq!(Layout::De.lookup(0x11, false, false, true), None);
+        check!(!Layout::Us.has_altgr() && !Layout::Dvorak.has_altgr());
+        check!(Layout::Uk.has_altgr() && Layout::De.has_altgr() && Layout::Fr.has_altgr());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn caps_lock_shifts_letters_only() -> Result<(), String> {
+        check_eq!(Layout::Us.lookup(0x1E, false, true, false), Some('A'));
+        check_eq!(Layout::Us.lookup(0x1E, true, true, false), Some('a'));
+        check_eq!(Layout::Us.lookup(0x02, false, true, false), Some('1'));
+        check_eq!(Layout::De.lookup(0x1A, false, true, false), Some('Ü'));
+        // é shifts to 2, which is not a letter
+        check_eq!(Layout::Fr.lookup(0x03, false, true, false), Some('é'));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn shared_keys_and_unknown_ones() -> Result<(), String> {
+        for layout in Layout::ALL {
+            check_eq!(plain(layout, 0x1C), Some('\n'));
+            check_eq!(plain(layout, 0x0E), Some('\x08'));
+            check_eq!(plain(layout, 0x39), Some(' '));
+            // F1 and the key release range
+            check_eq!(plain(layout, 0x3B), None);
+            check_eq!(plain(layout, 0x9E), None);
+            check_eq!(Layout::from_name(layout.name()), Some(layout));
+        }
+        check_eq!(Layout::from_name("xx"), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn dead_keys_accent_the_next_letter() -> Result<(), String> {
+        let acute = plain(Layout::De, 0x0D).unwrap_or(' ');
+        let circumflex = plain(Layout::Fr, 0x1A).unwrap_or(' ');
+        check!(is_dead(acute) && is_dead(circumflex));
+        check!(!is_dead('e'));
+        check_eq!(compose(acute, 'e'), 'é');
+        check_eq!(compose(acute, 'E'), 'É');
+        check_eq!(compose(circumflex, 'o'), 'ô');
+        check_eq!(compose('\u{308}', 'u'), 'ü');
+        // A space or another dead key gives the accent itself; a letter it
+        // does not go on comes through plain
+        check_eq!(compose(circumflex, ' '), '^');
+        check_eq!(compose(acute, circumflex), '´');
+        check_eq!(compose(acute, 'x'), 'x');
+        Ok(())
+    }
+}
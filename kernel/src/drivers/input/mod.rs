@@ -1,12 +1,22 @@
 //! Input Subsystem
 //!
 //! Handles keyboard and mouse input for WebbOS.
+//!
+//! Key events carry the scancode and the character it types in the
+//! current `keymap` layout, if any. `dispatch` decides who gets them.
 
 use spin::Mutex;
 use lazy_static::lazy_static;
 use alloc::collections::VecDeque;
 
 use crate::println;
+
+pub mod dispatch;
+pub mod keymap;
+
+pub use keymap::Layout;
+use crate::info;
+
 // Port I/O functions
 #[inline]
 pub unsafe fn inb(port: u16) -> u8 {
@@ -52,6 +62,28 @@ pub unsafe fn outw(port: u16, value: u16) {
     );
 }
 
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+    let result: u32;
+    core::arch::asm!(
+        "in eax, dx",
+        in("dx") port,
+        out("eax") result,
+        options(nomem, nostack)
+    );
+    result
+}
+
+#[inline]
+pub unsafe fn outl(port: u16, value: u32) {
+    core::arch::asm!(
+        "out dx, eax",
+        in("dx") port,
+        in("eax") value,
+        options(nomem, nostack)
+    );
+}
+
 /// Maximum event queue size
 const MAX_EVENTS: usize = 256;
 
@@ -72,6 +104,9 @@ pub enum MouseButton {
     Left = 0,
     Right = 1,
     Middle = 2,
+    /// Side buttons of five-button mice
+    Back = 3,
+    Forward = 4,
 }
 
 /// Input event
@@ -79,28 +114,59 @@ pub enum MouseButton {
 pub struct InputEvent {
     pub event_type: EventType,
     pub keycode: u16,
+    /// Character typed; `'\0'` if none
+    pub ch: char,
+    /// `ch` if it is ASCII, else 0
     pub ascii: u8,
     pub x: i32,
     pub y: i32,
     pub button: u8,
+    /// Notches the wheel turned, positive towards the user (scrolling
+    /// down)
     pub scroll: i8,
     pub modifiers: u8,
 }
 
+impl InputEvent {
+    /// Key press or release of `keycode` typing `ch`
+    pub fn key(event_type: EventType, keycode: u16, ch: char, modifiers: u8) -> Self {
+        Self {
+            event_type, keycode, ch,
+            ascii: if ch.is_ascii() { ch as u8 } else { 0 },
+            x: 0, y: 0, button: 0, scroll: 0, modifiers,
+        }
+    }
+
+    /// Pointer event at (x, y); `button` for presses and releases
+    pub fn pointer(event_type: EventType, x: i32, y: i32, button: u8) -> Self {
+        Self { event_type, keycode: 0, ch: '\0', ascii: 0, x, y, button, scroll: 0, modifiers: 0 }
+    }
+}
+
 /// Key modifiers
 pub const MOD_SHIFT: u8 = 0x01;
 pub const MOD_CTRL: u8 = 0x02;
 pub const MOD_ALT: u8 = 0x04;
 pub const MOD_CAPS: u8 = 0x08;
 pub const MOD_NUM: u8 = 0x10;
+pub const MOD_SUPER: u8 = 0x20;
+/// Right Alt on layouts that use it to type more characters
+pub const MOD_ALTGR: u8 = 0x40;
 
 /// Keyboard driver
 pub struct KeyboardDriver {
     shift_pressed: bool,
     ctrl_pressed: bool,
     alt_pressed: bool,
+    altgr_pressed: bool,
+    super_pressed: bool,
     caps_lock: bool,
     num_lock: bool,
+    layout: Layout,
+    /// The last scancode was the E0 prefix of an extended key
+    extended: bool,
+    /// Accent of a dead key waiting for the next character
+    dead: Option<char>,
 }
 
 impl KeyboardDriver {
@@ -109,13 +175,18 @@ impl KeyboardDriver {
             shift_pressed: false,
             ctrl_pressed: false,
             alt_pressed: false,
+            altgr_pressed: false,
+            super_pressed: false,
             caps_lock: false,
             num_lock: true,
+            layout: Layout::Us,
+            extended: false,
+            dead: None,
         }
     }
     
     pub fn init(&mut self) {
-        println!("[input] Initializing keyboard...");
+        info!("input", "Initializing keyboard...");
         
         unsafe {
             let ctrl = inb(0x61);
@@ -127,15 +198,17 @@ impl KeyboardDriver {
             }
         }
         
-        println!("[input] Keyboard initialized");
+        info!("input", "Keyboard initialized");
     }
     
     pub fn handle_interrupt(&mut self) -> Option<InputEvent> {
         let scancode = unsafe { inb(0x60) };
         
         if scancode == 0xE0 {
+            self.extended = true;
             return None;
         }
+        let extended = core::mem::take(&mut self.extended);
         
         let is_release = scancode & 0x80 != 0;
         let keycode = scancode & 0x7F;
@@ -143,7 +216,11 @@ impl KeyboardDriver {
         match keycode {
             0x2A | 0x36 => self.shift_pressed = !is_release,
             0x1D => self.ctrl_pressed = !is_release,
+            // Right Alt is AltGr where the layout types anything with it
+            0x38 if extended && self.layout.has_altgr() => self.altgr_pressed = !is_release,
             0x38 => self.alt_pressed = !is_release,
+            // Left and right Super, sent after an E0 prefix
+            0x5B | 0x5C => self.super_pressed = !is_release,
             0x3A => if is_release { self.caps_lock = !self.caps_lock; }
             0x45 => if is_release { self.num_lock = !self.num_lock; }
             _ => {}
@@ -153,90 +230,64 @@ impl KeyboardDriver {
         if self.shift_pressed { modifiers |= MOD_SHIFT; }
         if self.ctrl_pressed { modifiers |= MOD_CTRL; }
         if self.alt_pressed { modifiers |= MOD_ALT; }
+        if self.super_pressed { modifiers |= MOD_SUPER; }
+        if self.altgr_pressed { modifiers |= MOD_ALTGR; }
         if self.caps_lock { modifiers |= MOD_CAPS; }
         if self.num_lock { modifiers |= MOD_NUM; }
         
-        let ascii = if is_release {
-            0
-        } else {
-            scancode_to_ascii(keycode, self.shift_pressed, self.caps_lock)
+        // Extended keys (arrows, keypad Enter and /, and so on) type
+        // nothing through the layout
+        let typed = match (is_release, extended) {
+            (false, false) => self.layout.lookup(keycode, self.shift_pressed, self.caps_lock, self.altgr_pressed),
+            _ => None,
+        };
+        let ch = match (typed, self.dead) {
+            (Some(c), _) if keymap::is_dead(c) && self.dead.is_none() => {
+                self.dead = Some(c);
+                '\0'
+            }
+            (Some(c), Some(accent)) => {
+                self.dead = None;
+                keymap::compose(accent, c)
+            }
+            (Some(c), None) => c,
+            (None, _) => '\0',
         };
         
         Some(InputEvent {
             event_type: if is_release { EventType::KeyRelease } else { EventType::KeyPress },
             keycode: keycode as u16,
-            ascii,
+            ch,
+            ascii: if ch.is_ascii() { ch as u8 } else { 0 },
             x: 0, y: 0, button: 0, scroll: 0, modifiers,
         })
     }
 }
 
-fn scancode_to_ascii(scancode: u8, shift: bool, caps: bool) -> u8 {
-    let base_table: [u8; 128] = [
-        0, 27, 49, 50, 51, 52, 53, 54,
-        55, 56, 57, 48, 45, 61, 8, 9,
-        113, 119, 101, 114, 116, 121, 117, 105,
-        111, 112, 91, 93, 10, 0, 97, 115,
-        100, 102, 103, 104, 106, 107, 108, 59,
-        39, 96, 0, 92, 122, 120, 99, 118,
-        98, 110, 109, 44, 46, 47, 0, 42,
-        0, 32, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-    ];
-    
-    let shift_table: [u8; 128] = [
-        0, 27, 33, 64, 35, 36, 37, 94,
-        38, 42, 40, 41, 95, 43, 8, 9,
-        81, 87, 69, 82, 84, 89, 85, 73,
-        79, 80, 123, 125, 10, 0, 65, 83,
-        68, 70, 71, 72, 74, 75, 76, 58,
-        34, 126, 0, 124, 90, 88, 67, 86,
-        66, 78, 77, 60, 62, 63, 0, 42,
-        0, 32, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0,
-    ];
-    
-    if scancode >= 128 {
-        return 0;
-    }
-    
-    let use_shift = shift ^ caps;
-    if use_shift {
-        shift_table[scancode as usize]
-    } else {
-        base_table[scancode as usize]
-    }
-}
-
 /// Mouse driver
 pub struct MouseDriver {
     x: i32, y: i32,
+    max_x: i32, max_y: i32,
     buttons: u8,
     cycle: u8,
     packet: [u8; 4],
+    /// The mouse has a wheel and sends 4-byte packets
+    wheel: bool,
+    /// The mouse also has buttons 4 and 5, reported in the fourth byte
+    extra_buttons: bool,
 }
 
 impl MouseDriver {
     const fn new() -> Self {
-        Self { x: 400, y: 300, buttons: 0, cycle: 0, packet: [0; 4] }
+        Self {
+            x: 400, y: 300, max_x: 1023, max_y: 767,
+            buttons: 0, cycle: 0, packet: [0; 4],
+            wheel: false, extra_buttons: false,
+        }
     }
     
     pub fn init(&mut self) {
-        println!("[input] Initializing mouse...");
+        info!("input", "Initializing mouse...");
         
         unsafe {
             self.wait_write();
@@ -255,11 +306,38 @@ impl MouseDriver {
             self.write(0xF6);
             self.read();
             
+            // Setting the sample rate to 200, 100 and then 80 turns on
+            // the wheel of an IntelliMouse, which then reports ID 3; then
+            // 200, 200 and 80 turn on buttons 4 and 5 of an IntelliMouse
+            // Explorer, which reports ID 4
+            self.wheel = self.knock([200, 100, 80]) == 3;
+            if self.wheel {
+                self.extra_buttons = self.knock([200, 200, 80]) == 4;
+            }
+            
             self.write(0xF4);
             self.read();
         }
         
-        println!("[input] Mouse initialized");
+        info!("input", "Mouse initialized{}", match (self.wheel, self.extra_buttons) {
+            (true, true) => " with wheel and 5 buttons",
+            (true, false) => " with wheel",
+            _ => "",
+        });
+    }
+
+    /// Set the sample rate to each of `rates` in turn, then return the
+    /// device ID the mouse reports
+    fn knock(&self, rates: [u8; 3]) -> u8 {
+        for rate in rates {
+            self.write(0xF3);
+            self.read();
+            self.write(rate);
+            self.read();
+        }
+        self.write(0xF2);
+        self.read();
+        self.read()
     }
     
     pub fn handle_interrupt(&mut self) -> Option<InputEvent> {
@@ -278,11 +356,21 @@ impl MouseDriver {
                 self.cycle = 2;
                 None
             }
+            2 if self.wheel => {
+                self.packet[2] = data;
+                self.cycle = 3;
+                None
+            }
             2 => {
                 self.packet[2] = data;
                 self.cycle = 0;
                 self.process_packet()
             }
+            3 => {
+                self.packet[3] = data;
+                self.cycle = 0;
+                self.process_packet()
+            }
             _ => {
                 self.cycle = 0;
                 None
@@ -301,28 +389,42 @@ impl MouseDriver {
         self.x += x_delta;
         self.y -= y_delta;
         
-        self.x = self.x.max(0).min(1023);
-        self.y = self.y.max(0).min(767);
+        self.x = self.x.max(0).min(self.max_x);
+        self.y = self.y.max(0).min(self.max_y);
         
-        let new_buttons = flags & 0x07;
+        // Buttons 4 and 5 are bits 4 and 5 of the fourth byte
+        let extra = if self.extra_buttons { (self.packet[3] >> 1) & 0x18 } else { 0 };
+        let new_buttons = (flags & 0x07) | extra;
         let button_change = self.buttons ^ new_buttons;
         self.buttons = new_buttons;
         
-        if x_delta != 0 || y_delta != 0 {
-            Some(InputEvent {
-                event_type: EventType::MouseMove,
-                keycode: 0, ascii: 0, x: self.x, y: self.y,
-                button: new_buttons, scroll: 0, modifiers: 0,
-            })
-        } else if button_change != 0 {
+        // The wheel moves in the low four bits of the fourth byte, signed
+        let scroll = if self.wheel { ((self.packet[3] << 4) as i8) >> 4 } else { 0 };
+        
+        // A button change wins over the wheel and movement in the same
+        // packet; the event carries the new position, so the move is not
+        // lost
+        if button_change != 0 {
             let button = button_change.trailing_zeros() as u8;
             let pressed = new_buttons & button_change != 0;
             
             Some(InputEvent {
                 event_type: if pressed { EventType::MouseButtonPress } else { EventType::MouseButtonRelease },
-                keycode: 0, ascii: 0, x: self.x, y: self.y,
+                keycode: 0, ch: '\0', ascii: 0, x: self.x, y: self.y,
                 button, scroll: 0, modifiers: 0,
             })
+        } else if scroll != 0 {
+            Some(InputEvent {
+                event_type: EventType::MouseScroll,
+                keycode: 0, ch: '\0', ascii: 0, x: self.x, y: self.y,
+                button: new_buttons, scroll, modifiers: 0,
+            })
+        } else if x_delta != 0 || y_delta != 0 {
+            Some(InputEvent {
+                event_type: EventType::MouseMove,
+                keycode: 0, ch: '\0', ascii: 0, x: self.x, y: self.y,
+                button: new_buttons, scroll: 0, modifiers: 0,
+            })
         } else {
             None
         }
@@ -330,6 +432,15 @@ impl MouseDriver {
     
     pub fn position(&self) -> (i32, i32) { (self.x, self.y) }
     pub fn set_position(&mut self, x: i32, y: i32) { self.x = x; self.y = y; }
+    
+    /// Confine the pointer to a `width` x `height` screen
+    pub fn set_bounds(&mut self, width: u32, height: u32) {
+        self.max_x = width.max(1) as i32 - 1;
+        self.max_y = height.max(1) as i32 - 1;
+        self.x = self.x.min(self.max_x);
+        self.y = self.y.min(self.max_y);
+    }
+    
     pub fn buttons(&self) -> u8 { self.buttons }
     
     fn wait_write(&self) { unsafe { while inb(0x64) & 0x02 != 0 {} } }
@@ -379,16 +490,47 @@ impl InputManager {
     
     pub fn handle_mouse(&mut self) {
         if let Some(event) = self.mouse.handle_interrupt() {
+            crate::graphics::cursor::move_to(event.x, event.y);
             if self.events.len() < MAX_EVENTS {
                 self.events.push_back(event);
             }
         }
     }
     
+    /// Read whatever the PS/2 controller has buffered, routing mouse bytes
+    /// to the mouse driver and the rest to the keyboard
+    pub fn poll(&mut self) {
+        // Bound the loop in case the controller keeps reporting data
+        for _ in 0..64 {
+            let status = unsafe { inb(0x64) };
+            if status & 0x01 == 0 {
+                break;
+            }
+            if status & 0x20 != 0 {
+                self.handle_mouse();
+            } else {
+                self.handle_keyboard();
+            }
+        }
+    }
+    
+    /// Queue an event as if a device had sent it; pointer events also
+    /// move the pointer, so later mouse packets carry on from there
+    pub fn inject(&mut self, event: InputEvent) {
+        if matches!(event.event_type, EventType::MouseMove | EventType::MouseButtonPress | EventType::MouseButtonRelease) {
+            self.mouse.set_position(event.x, event.y);
+            crate::graphics::cursor::move_to(event.x, event.y);
+        }
+        if self.events.len() < MAX_EVENTS {
+            self.events.push_back(event);
+        }
+    }
+
     pub fn poll_event(&mut self) -> Option<InputEvent> { self.events.pop_front() }
     pub fn has_events(&self) -> bool { !self.events.is_empty() }
     pub fn mouse_position(&self) -> (i32, i32) { self.mouse.position() }
     pub fn set_mouse_position(&mut self, x: i32, y: i32) { self.mouse.set_position(x, y); }
+    pub fn set_mouse_bounds(&mut self, width: u32, height: u32) { self.mouse.set_bounds(width, height); }
     pub fn mouse_buttons(&self) -> u8 { self.mouse.buttons() }
 }
 
@@ -397,20 +539,40 @@ lazy_static! {
 }
 
 pub fn init() {
-    println!("[input] Initializing input subsystem...");
+    info!("input", "Initializing input subsystem...");
     INPUT_MANAGER.lock().init();
-    println!("[input] Input subsystem ready");
+    info!("input", "Input subsystem ready");
 }
 
 pub fn handle_keyboard_interrupt() { INPUT_MANAGER.lock().handle_keyboard(); }
 pub fn handle_mouse_interrupt() { INPUT_MANAGER.lock().handle_mouse(); }
+/// Drain the PS/2 controller into the event queue
+///
+/// Keyboard and mouse interrupts are not routed yet, so loops that want
+/// input call this before `poll_event`.
+pub fn poll() {
+    crate::watchdog::pet(crate::watchdog::Subsystem::Input);
+    INPUT_MANAGER.lock().poll();
+}
 pub fn poll_event() -> Option<InputEvent> { INPUT_MANAGER.lock().poll_event() }
+/// Queue a synthetic event for whoever takes input next, for driving
+/// the UI from tests
+pub fn inject_event(event: InputEvent) { INPUT_MANAGER.lock().inject(event); }
 pub fn has_events() -> bool { INPUT_MANAGER.lock().has_events() }
 pub fn mouse_position() -> (i32, i32) { INPUT_MANAGER.lock().mouse_position() }
+pub fn set_mouse_bounds(width: u32, height: u32) { INPUT_MANAGER.lock().set_mouse_bounds(width, height); }
+pub fn layout() -> Layout { INPUT_MANAGER.lock().keyboard.layout }
+pub fn set_layout(layout: Layout) {
+    let mut manager = INPUT_MANAGER.lock();
+    manager.keyboard.layout = layout;
+    manager.keyboard.dead = None;
+    manager.keyboard.altgr_pressed = false;
+}
 
+/// Wait for a key press for the console
 pub fn wait_key() -> InputEvent {
     loop {
-        if let Some(event) = poll_event() {
+        if let Some(event) = get_key() {
             if event.event_type == EventType::KeyPress {
                 return event;
             }
@@ -419,7 +581,15 @@ pub fn wait_key() -> InputEvent {
     }
 }
 
+/// Next key press for the console, if any
+///
+/// While a desktop session is active its events stay queued for it and
+/// the console gets nothing.
 pub fn get_key() -> Option<InputEvent> {
+    if dispatch::session_active() {
+        return None;
+    }
+    poll();
     if let Some(event) = poll_event() {
         if event.event_type == EventType::KeyPress {
             return Some(event);
@@ -433,6 +603,6 @@ pub fn print_info() {
     let (x, y) = manager.mouse_position();
     println!("Input Status:");
     println!("  Mouse position: ({}, {})", x, y);
-    println!("  Mouse buttons: {:03b}", manager.mouse_buttons());
+    println!("  Mouse buttons: {:05b}", manager.mouse_buttons());
     println!("  Events in queue: {}", manager.events.len());
 }
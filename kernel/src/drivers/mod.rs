@@ -3,22 +3,27 @@
 //! Hardware-specific drivers for various devices.
 
 pub mod timer;
+pub mod hpet;
+pub mod rtc;
 pub mod pci;
 pub mod storage;
 pub mod vesa;
+pub mod virtio_gpu;
 pub mod input;
+pub mod pty;
 
-use crate::println;
+use crate::info;
 
 /// Initialize all drivers
 pub fn init() {
-    println!("[drivers] Initializing device drivers...");
+    info!("drivers", "Initializing device drivers...");
     
     timer::init();
+    rtc::init();
     pci::init();
     // Storage drivers initialized separately after PCI enumeration
     
-    println!("[drivers] Device drivers initialized");
+    info!("drivers", "Device drivers initialized");
 }
 
 /// Driver error
@@ -1,11 +1,35 @@
 //! PCI/PCIe bus driver
 //!
-//! Enumerates PCI devices and provides access to configuration space.
+//! Enumerates PCI devices and provides access to configuration space,
+//! memory-mapped (ECAM) where the ACPI MCFG table says where, and through
+//! the legacy 0xCF8/0xCFC ports otherwise. ECAM also reaches the extended
+//! capabilities past the first 256 bytes.
+//!
+//! Each BAR is sized when the bus is scanned. Drivers do not scan for
+//! themselves: they `register_driver` with the classes or IDs they handle
+//! and are handed each matching function no other driver has bound,
+//! powered up and with decoding and bus mastering on.
 
+use alloc::collections::BTreeMap;
+use alloc::vec;
 use alloc::vec::Vec;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use webbos_shared::types::PhysAddr;
+use crate::arch::apic;
+use crate::arch::interrupts::InterruptStackFrame;
 use crate::println;
+use crate::{debug, info};
+
+/// Capability IDs
+const CAP_POWER: u8 = 0x01;
+const CAP_MSI: u8 = 0x05;
+const CAP_PCIE: u8 = 0x10;
+const CAP_MSIX: u8 = 0x11;
+
+/// Extended configuration space starts here, and only ECAM reaches it
+const EXTENDED_START: u16 = 0x100;
+const CONFIG_SPACE_SIZE: u16 = 0x1000;
 
 /// PCI Configuration Space ports
 const CONFIG_ADDRESS: u16 = 0xCF8;
@@ -34,54 +58,243 @@ pub struct PciDevice {
     pub header_type: u8,
     /// Base address registers
     pub bars: [u32; 6],
+    /// What each BAR decodes, as sized at the scan; the upper half of a
+    /// 64-bit BAR is None
+    pub regions: [Option<Bar>; 6],
+}
+
+/// What a base address register decodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { base: u64, size: u64, prefetchable: bool, wide: bool },
+    Io { port: u16, size: u32 },
+}
+
+impl Bar {
+    /// Map a memory BAR uncached; None for ports
+    pub fn map(&self) -> Option<u64> {
+        match *self {
+            Bar::Memory { base, size, .. } => {
+                crate::mm::map_mmio(PhysAddr::new(base), size as usize).map(|v| v.as_u64())
+            }
+            Bar::Io { .. } => None,
+        }
+    }
+}
+
+/// An entry in a function's capability list
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u16,
+    pub offset: u16,
+    /// From the extended list past the first 256 bytes
+    pub extended: bool,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match (self.extended, self.id) {
+            (false, 0x01) => "Power Management",
+            (false, 0x05) => "MSI",
+            (false, 0x09) => "Vendor Specific",
+            (false, 0x0D) => "Bridge Subsystem",
+            (false, 0x10) => "PCI Express",
+            (false, 0x11) => "MSI-X",
+            (false, 0x12) => "SATA",
+            (true, 0x0001) => "Advanced Error Reporting",
+            (true, 0x0002) | (true, 0x0009) => "Virtual Channel",
+            (true, 0x0003) => "Device Serial Number",
+            (true, 0x000B) => "Vendor Specific",
+            (true, 0x000D) => "Access Control Services",
+            (true, 0x000E) => "Alternative Routing-ID",
+            (true, 0x0010) => "SR-IOV",
+            (true, 0x0015) => "Resizable BAR",
+            (true, 0x0018) => "Latency Tolerance Reporting",
+            (true, 0x001E) => "L1 PM Substates",
+            _ => "Unknown",
+        }
+    }
 }
 
 impl PciDevice {
     /// Read configuration space
     pub fn read_config(&self, offset: u8) -> u32 {
-        let address = pci_address(self.bus, self.device, self.function, offset);
-        unsafe {
-            // Write address
-            core::arch::asm!(
-                "out dx, eax",
-                in("dx") CONFIG_ADDRESS,
-                in("eax") address,
-                options(nomem, nostack)
-            );
-            
-            // Read data
-            let val: u32;
-            core::arch::asm!(
-                "in eax, dx",
-                in("dx") CONFIG_DATA,
-                out("eax") val,
-                options(nomem, nostack)
-            );
-            
-            val
-        }
+        read_config32(self.bus, self.device, self.function, offset)
     }
 
     /// Write configuration space
     pub fn write_config(&self, offset: u8, value: u32) {
-        let address = pci_address(self.bus, self.device, self.function, offset);
+        write_config32(self.bus, self.device, self.function, offset, value)
+    }
+
+    /// Read the dword at `offset` in extended configuration space; None
+    /// without ECAM
+    pub fn read_config_extended(&self, offset: u16) -> Option<u32> {
+        if offset >= CONFIG_SPACE_SIZE {
+            return None;
+        }
+        let addr = ecam_address(self.bus, self.device, self.function)?;
+        Some(unsafe { core::ptr::read_volatile((addr + (offset & !3) as u64) as *const u32) })
+    }
+
+    /// Bus, device and function
+    pub fn address(&self) -> (u8, u8, u8) {
+        (self.bus, self.device, self.function)
+    }
+
+    /// BAR `index`, sized
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.regions.get(index).copied().flatten()
+    }
+
+    /// The capability list, then the extended one where ECAM reaches it
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut caps = Vec::new();
+        // Status bit 4: there is a capability list
+        if (self.read_config(0x04) >> 16) & 0x10 != 0 {
+            let mut offset = (self.read_config(0x34) & 0xFC) as u8;
+            // The list lives in the 192 bytes past the header
+            for _ in 0..48 {
+                if offset == 0 {
+                    break;
+                }
+                let header = self.read_config(offset);
+                caps.push(Capability { id: header as u8 as u16, offset: offset as u16, extended: false });
+                offset = ((header >> 8) & 0xFC) as u8;
+            }
+        }
+        // Conventional PCI has no extended space; reads of it come back 0
+        // or all ones
+        let mut offset = EXTENDED_START;
+        for _ in 0..(CONFIG_SPACE_SIZE - EXTENDED_START) / 4 {
+            let header = match self.read_config_extended(offset) {
+                Some(0) | Some(0xFFFF_FFFF) | None => break,
+                Some(header) => header,
+            };
+            caps.push(Capability { id: header as u16, offset, extended: true });
+            offset = ((header >> 20) & 0xFFC) as u16;
+            if offset < EXTENDED_START {
+                break;
+            }
+        }
+        caps
+    }
+
+    /// Offset of capability `id` in configuration space
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities().iter()
+            .find(|c| !c.extended && c.id == id as u16)
+            .map(|c| c.offset as u8)
+    }
+
+    /// Link speed (as the PCIe generation) and width of a PCIe function
+    pub fn pcie_link(&self) -> Option<(u8, u8)> {
+        let cap = self.find_capability(CAP_PCIE)?;
+        // Link status is the upper half of the dword at 0x10
+        let status = self.read_config(cap + 0x10) >> 16;
+        Some(((status & 0xF) as u8, ((status >> 4) & 0x3F) as u8))
+    }
+
+    /// Power state, D0 to D3, of a function with power management
+    pub fn power_state(&self) -> Option<u8> {
+        let cap = self.find_capability(CAP_POWER)?;
+        Some((self.read_config(cap + 4) & 0b11) as u8)
+    }
+
+    /// Bring a function in a low-power state back to D0
+    pub fn wake(&self) {
+        let cap = match self.find_capability(CAP_POWER) {
+            Some(cap) => cap,
+            None => return,
+        };
+        let control = self.read_config(cap + 4);
+        if control & 0b11 != 0 {
+            // Bit 15, PME status, clears when written with 1; leave it be
+            self.write_config(cap + 4, control & !0b11 & !(1 << 15));
+            // Coming out of D3hot takes up to 10ms
+            super::timer::sleep_ms(10);
+        }
+    }
+
+    /// Turn on decoding of the function's BARs and bus mastering
+    pub fn enable(&self) {
+        let io = self.regions.iter().any(|r| matches!(r, Some(Bar::Io { .. })));
+        // Memory space (bit 1) and bus master (bit 2), I/O space (bit 0)
+        // if it has ports; writing 0 to the status half leaves it alone
+        let command = (self.read_config(0x04) & 0xFFFF) | 0x06 | io as u32;
+        self.write_config(0x04, command);
+    }
+
+    /// Have the device raise `handler` by message-signalled interrupt, with
+    /// MSI-X where it has it and MSI otherwise, in place of its INTx line
+    ///
+    /// One vector is used; with MSI-X it is table entry 0's and the other
+    /// entries stay masked. Returns the vector, or None if the device can
+    /// do neither or the APICs are not in use.
+    pub fn enable_msi(&self, handler: extern "x86-interrupt" fn(InterruptStackFrame)) -> Option<u8> {
+        if !apic::is_enabled() {
+            return None;
+        }
+        let vector = match (self.find_capability(CAP_MSIX), self.find_capability(CAP_MSI)) {
+            (Some(cap), _) => self.enable_msix(cap, handler)?,
+            (None, Some(cap)) => {
+                let vector = apic::allocate_vector(handler)?;
+                let (address, data) = apic::msi_message(vector);
+                let control = self.read_config(cap) >> 16;
+                self.write_config(cap + 4, address as u32);
+                // 64-bit capable functions have the data a dword further on
+                let data_offset = if control & 0x80 != 0 {
+                    self.write_config(cap + 8, (address >> 32) as u32);
+                    cap + 12
+                } else {
+                    cap + 8
+                };
+                self.write_config(data_offset, data);
+                // Enabled, with a single message
+                let control = (control | 0x01) & !0x70;
+                self.write_config(cap, (self.read_config(cap) & 0xFFFF) | (control << 16));
+                vector
+            }
+            (None, None) => return None,
+        };
+        // Command bit 10: INTx off
+        self.write_config(0x04, (self.read_config(0x04) & 0xFFFF) | (1 << 10));
+        debug!("pci", "{:02X}:{:02X}.{} interrupts on vector {:#x}", self.bus, self.device, self.function, vector);
+        Some(vector)
+    }
+
+    /// Point MSI-X table entry 0 at a new vector for `handler` and turn
+    /// MSI-X on
+    fn enable_msix(&self, cap: u8, handler: extern "x86-interrupt" fn(InterruptStackFrame)) -> Option<u8> {
+        let control = self.read_config(cap) >> 16;
+        let entries = (control & 0x7FF) as usize + 1;
+        // The table is in a BAR's memory, at an offset
+        let table = self.read_config(cap + 4);
+        let base = match self.bar((table & 0x07) as usize)? {
+            Bar::Memory { base, .. } => base,
+            Bar::Io { .. } => return None,
+        };
+        let table = crate::mm::map_mmio(PhysAddr::new(base + (table & !0x07) as u64), entries * 16)?.as_u64();
+
+        let vector = apic::allocate_vector(handler)?;
+        let (address, data) = apic::msi_message(vector);
         unsafe {
-            // Write address
-            core::arch::asm!(
-                "out dx, eax",
-                in("dx") CONFIG_ADDRESS,
-                in("eax") address,
-                options(nomem, nostack)
-            );
-            
-            // Write data
-            core::arch::asm!(
-                "out dx, eax",
-                in("dx") CONFIG_DATA,
-                in("eax") value,
-                options(nomem, nostack)
-            );
+            for i in 0..entries as u64 {
+                let entry = (table + i * 16) as *mut u32;
+                if i == 0 {
+                    core::ptr::write_volatile(entry, address as u32);
+                    core::ptr::write_volatile(entry.add(1), (address >> 32) as u32);
+                    core::ptr::write_volatile(entry.add(2), data);
+                    core::ptr::write_volatile(entry.add(3), 0);
+                } else {
+                    core::ptr::write_volatile(entry.add(3), 1);
+                }
+            }
         }
+        // Enabled (bit 15), function mask (bit 14) off
+        let control = (control | 0x8000) & !0x4000;
+        self.write_config(cap, (self.read_config(cap) & 0xFFFF) | (control << 16));
+        Some(vector)
     }
 
     /// Get device description
@@ -119,73 +332,174 @@ fn pci_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
 }
 
 /// Initialize PCI and enumerate devices
+///
+/// Scanning starts at bus 0 and follows bridges to the buses behind them,
+/// so only buses that exist are visited (and, with ECAM, mapped).
 pub fn init() {
-    println!("[pci] Enumerating PCI bus...");
+    info!("pci", "Enumerating PCI bus...");
 
     let mut devices = PCI_DEVICES.lock();
     devices.clear();
 
-    // Scan all buses (0-255), devices (0-31), functions (0-7)
-    for bus in 0..=255u8 {
-        for device in 0..32u8 {
-            for function in 0..8u8 {
-                // Skip functions > 0 if not a multifunction device
-                if function > 0 {
-                    let header = read_config8(bus, device, 0, 0x0E);
-                    if header & 0x80 == 0 {
-                        continue;
-                    }
-                }
+    let mut scanned = [false; 256];
+    let mut buses = vec![0u8];
+    while let Some(bus) = buses.pop() {
+        if core::mem::replace(&mut scanned[bus as usize], true) {
+            continue;
+        }
+        scan_bus(bus, &mut devices, &mut buses);
+    }
+
+    info!("pci", "Found {} PCI devices", devices.len());
+}
 
-                let vendor_id = read_config16(bus, device, function, 0x00);
-                
-                if vendor_id == 0xFFFF {
-                    continue; // No device
+/// Add the devices on `bus`, and the buses behind its bridges to `buses`
+fn scan_bus(bus: u8, devices: &mut Vec<PciDevice>, buses: &mut Vec<u8>) {
+    for device in 0..32u8 {
+        for function in 0..8u8 {
+            // Skip functions > 0 if not a multifunction device
+            if function > 0 {
+                let header = read_config8(bus, device, 0, 0x0E);
+                if header & 0x80 == 0 {
+                    continue;
                 }
+            }
 
-                let device_id = read_config16(bus, device, function, 0x02);
-                let class = read_config8(bus, device, function, 0x0B);
-                let subclass = read_config8(bus, device, function, 0x0A);
-                let prog_if = read_config8(bus, device, function, 0x09);
-                let header_type = read_config8(bus, device, function, 0x0E);
+            let vendor_id = read_config16(bus, device, function, 0x00);
+            
+            if vendor_id == 0xFFFF {
+                continue; // No device
+            }
 
-                let mut bars = [0u32; 6];
-                for i in 0..6 {
-                    bars[i] = read_config32(bus, device, function, 0x10 + (i as u8 * 4));
-                }
+            let device_id = read_config16(bus, device, function, 0x02);
+            let class = read_config8(bus, device, function, 0x0B);
+            let subclass = read_config8(bus, device, function, 0x0A);
+            let prog_if = read_config8(bus, device, function, 0x09);
+            let header_type = read_config8(bus, device, function, 0x0E);
 
-                let pci_dev = PciDevice {
-                    bus,
-                    device,
-                    function,
-                    vendor_id,
-                    device_id,
-                    class,
-                    subclass,
-                    prog_if,
-                    header_type,
-                    bars,
-                };
+            let mut bars = [0u32; 6];
+            for i in 0..6 {
+                bars[i] = read_config32(bus, device, function, 0x10 + (i as u8 * 4));
+            }
+            let regions = size_bars(bus, device, function, header_type);
 
-                println!("[pci] Found {:04X}:{:04X} at {:02X}:{:02X}.{} - {}",
-                    vendor_id, device_id, bus, device, function,
-                    pci_dev.description());
+            let pci_dev = PciDevice {
+                bus,
+                device,
+                function,
+                vendor_id,
+                device_id,
+                class,
+                subclass,
+                prog_if,
+                header_type,
+                bars,
+                regions,
+            };
 
-                devices.push(pci_dev);
+            debug!("pci", "Found {:04X}:{:04X} at {:02X}:{:02X}.{} - {}",
+                vendor_id, device_id, bus, device, function,
+                pci_dev.description());
 
-                // Only scan function 0 if not multifunction
-                if function == 0 && header_type & 0x80 == 0 {
-                    break;
-                }
+            match (class, subclass) {
+                // A PCI-to-PCI bridge's secondary bus
+                (class::BRIDGE, 0x04) => buses.push(read_config8(bus, device, function, 0x19)),
+                // Further host bridges on bus 0 each lead to the bus of their function number
+                (class::BRIDGE, 0x00) if bus == 0 && device == 0 && function > 0 => buses.push(function),
+                _ => {}
+            }
+
+            devices.push(pci_dev);
+
+            // Only scan function 0 if not multifunction
+            if function == 0 && header_type & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Size the BARs of a function by writing all ones to each and reading
+/// back which bits stick, with decoding off meanwhile
+fn size_bars(bus: u8, device: u8, function: u8, header_type: u8) -> [Option<Bar>; 6] {
+    let mut regions = [None; 6];
+    // Bridges have two BARs, CardBus bridges none we use
+    let count = match header_type & 0x7F {
+        0 => 6,
+        1 => 2,
+        _ => return regions,
+    };
+    let command = read_config16(bus, device, function, 0x04);
+    write_config32(bus, device, function, 0x04, (command & !0x03) as u32);
+
+    let probe = |offset: u8| {
+        let raw = read_config32(bus, device, function, offset);
+        write_config32(bus, device, function, offset, 0xFFFF_FFFF);
+        let mask = read_config32(bus, device, function, offset);
+        write_config32(bus, device, function, offset, raw);
+        (raw, mask)
+    };
+    let mut i = 0;
+    while i < count {
+        let offset = 0x10 + i as u8 * 4;
+        let (raw, mask) = probe(offset);
+        if raw & 1 == 1 {
+            // Ports decode at most 16 bits
+            let mask = mask & 0xFFFC;
+            if mask != 0 {
+                regions[i] = Some(Bar::Io { port: (raw & 0xFFFC) as u16, size: (!mask & 0xFFFF) + 1 });
+            }
+        } else {
+            // A 64-bit BAR takes the next one for its upper half
+            let wide = (raw >> 1) & 0b11 == 0b10 && i + 1 < count;
+            let (mut base, mut mask) = ((raw & !0xF) as u64, (mask & !0xF) as u64);
+            if wide {
+                let (high, high_mask) = probe(offset + 4);
+                base |= (high as u64) << 32;
+                mask |= (high_mask as u64) << 32;
+            } else if mask != 0 {
+                mask |= 0xFFFF_FFFF_0000_0000;
+            }
+            // A BAR that is not there keeps no bits
+            if mask != 0 {
+                regions[i] = Some(Bar::Memory {
+                    base,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: raw & 0x08 != 0,
+                    wide,
+                });
+            }
+            if wide {
+                i += 1;
             }
         }
+        i += 1;
     }
 
-    println!("[pci] Found {} PCI devices", devices.len());
+    write_config32(bus, device, function, 0x04, command as u32);
+    regions
 }
 
-/// Read 8-bit value from PCI config space
-pub fn read_config8(bus: u8, device: u8, function: u8, offset: u8) -> u8 {
+/// Virtual address of each bus's ECAM window, mapped the first time the
+/// bus is used; None where there is no ECAM for it
+static ECAM_BUSES: Mutex<BTreeMap<u8, Option<u64>>> = Mutex::new(BTreeMap::new());
+
+/// Where function `function` of `device` on `bus` has its configuration
+/// space in ECAM, if the MCFG table covers the bus
+fn ecam_address(bus: u8, device: u8, function: u8) -> Option<u64> {
+    let base = *ECAM_BUSES.lock().entry(bus).or_insert_with(|| {
+        let phys = crate::acpi::ecam_bus(bus)?;
+        crate::mm::map_mmio(PhysAddr::new(phys), 1 << 20).map(|v| v.as_u64())
+    });
+    Some(base? + ((device as u64) << 15) + ((function as u64) << 12))
+}
+
+/// Read the dword at `offset`, through ECAM where there is one and else
+/// through the legacy ports
+fn config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    if let Some(addr) = ecam_address(bus, device, function) {
+        return unsafe { core::ptr::read_volatile((addr + (offset & 0xFC) as u64) as *const u32) };
+    }
     let address = pci_address(bus, device, function, offset);
     unsafe {
         core::arch::asm!(
@@ -203,12 +517,16 @@ pub fn read_config8(bus: u8, device: u8, function: u8, offset: u8) -> u8 {
             options(nomem, nostack)
         );
         
-        (val >> ((offset & 3) * 8)) as u8
+        val
     }
 }
 
-/// Read 16-bit value from PCI config space
-pub fn read_config16(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
+/// Write the dword at `offset`, like `config_read`
+fn config_write(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    if let Some(addr) = ecam_address(bus, device, function) {
+        unsafe { core::ptr::write_volatile((addr + (offset & 0xFC) as u64) as *mut u32, value) };
+        return;
+    }
     let address = pci_address(bus, device, function, offset);
     unsafe {
         core::arch::asm!(
@@ -218,39 +536,33 @@ pub fn read_config16(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
             options(nomem, nostack)
         );
         
-        let val: u16;
         core::arch::asm!(
-            "in eax, dx",
+            "out dx, eax",
             in("dx") CONFIG_DATA,
-            out("eax") val,
+            in("eax") value,
             options(nomem, nostack)
         );
-        
-        (val as u32 >> ((offset & 2) as u32 * 8)) as u16
     }
 }
 
+/// Read 8-bit value from PCI config space
+pub fn read_config8(bus: u8, device: u8, function: u8, offset: u8) -> u8 {
+    (config_read(bus, device, function, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Read 16-bit value from PCI config space
+pub fn read_config16(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
+    (config_read(bus, device, function, offset) >> ((offset & 2) * 8)) as u16
+}
+
 /// Read 32-bit value from PCI config space
 pub fn read_config32(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
-    let address = pci_address(bus, device, function, offset);
-    unsafe {
-        core::arch::asm!(
-            "out dx, eax",
-            in("dx") CONFIG_ADDRESS,
-            in("eax") address,
-            options(nomem, nostack)
-        );
-        
-        let val: u32;
-        core::arch::asm!(
-            "in eax, dx",
-            in("dx") CONFIG_DATA,
-            out("eax") val,
-            options(nomem, nostack)
-        );
-        
-        val
-    }
+    config_read(bus, device, function, offset)
+}
+
+/// Write 32-bit value to PCI config space
+pub fn write_config32(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    config_write(bus, device, function, offset, value)
 }
 
 /// Find device by class/subclass
@@ -284,19 +596,122 @@ pub fn get_devices() -> Vec<PciDevice> {
     PCI_DEVICES.lock().clone()
 }
 
-/// Print PCI device list
-pub fn print_devices() {
-    let devices = PCI_DEVICES.lock();
+/// Functions a driver handles; every field that is set has to match
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl DeviceMatch {
+    /// Functions of a class and subclass
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self { vendor_id: None, device_id: None, class: Some(class), subclass: Some(subclass), prog_if: None }
+    }
+
+    /// Functions with a vendor and device ID
+    pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+        Self { vendor_id: Some(vendor_id), device_id: Some(device_id), class: None, subclass: None, prog_if: None }
+    }
+
+    /// Only those with this programming interface
+    pub const fn prog_if(self, prog_if: u8) -> Self {
+        Self { prog_if: Some(prog_if), ..self }
+    }
+
+    fn matches(&self, dev: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |v| v == dev.vendor_id)
+            && self.device_id.map_or(true, |d| d == dev.device_id)
+            && self.class.map_or(true, |c| c == dev.class)
+            && self.subclass.map_or(true, |s| s == dev.subclass)
+            && self.prog_if.map_or(true, |p| p == dev.prog_if)
+    }
+}
+
+/// A driver for PCI functions
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    /// Set up a function that matches; false if it cannot, which leaves
+    /// the function to other drivers
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+/// Name of the driver bound to each function, by bus, device and function
+static BINDINGS: Mutex<BTreeMap<(u8, u8, u8), &'static str>> = Mutex::new(BTreeMap::new());
+
+/// Offer every unbound function that matches to `driver`; returns how many
+/// it took
+pub fn register_driver(driver: &'static PciDriver) -> usize {
+    let devices = get_devices();
+    let mut bound = 0;
+    for dev in devices.iter().filter(|dev| driver.matches.iter().any(|m| m.matches(dev))) {
+        if driver_of(dev).is_some() {
+            continue;
+        }
+        dev.wake();
+        dev.enable();
+        if (driver.probe)(dev) {
+            claim(dev, driver.name);
+            bound += 1;
+        }
+    }
+    bound
+}
+
+/// Bind a function to a driver that found it some other way; false if
+/// another driver has it
+pub fn claim(dev: &PciDevice, name: &'static str) -> bool {
+    let mut bindings = BINDINGS.lock();
+    if bindings.contains_key(&dev.address()) {
+        return false;
+    }
+    bindings.insert(dev.address(), name);
+    debug!("pci", "{:02X}:{:02X}.{} bound to {}", dev.bus, dev.device, dev.function, name);
+    true
+}
+
+/// The driver bound to a function
+pub fn driver_of(dev: &PciDevice) -> Option<&'static str> {
+    BINDINGS.lock().get(&dev.address()).copied()
+}
+
+/// Print PCI device list; `verbose` adds each one's BARs and capabilities
+pub fn print_devices(verbose: bool) {
+    let devices = get_devices();
     
     println!("PCI Devices:");
-    println!("Bus  Dev Fn   Vendor Device Description");
-    println!("------------------------------------------------");
+    println!("Bus  Dev Fn   Vendor Device Description          Driver");
+    println!("------------------------------------------------------------");
     
     for dev in devices.iter() {
-        println!("{:02X}:{:02X} {:02X}   {:04X}   {:04X}   {}",
+        println!("{:02X}:{:02X} {:02X}   {:04X}   {:04X}   {:<20} {}",
             dev.bus, dev.device, dev.function,
             dev.vendor_id, dev.device_id,
-            dev.description());
+            dev.description(), driver_of(dev).unwrap_or("-"));
+        if !verbose {
+            continue;
+        }
+        for (i, bar) in dev.regions.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory { base, size, prefetchable, wide }) => println!("    BAR{}: memory at {:#x}, {} KB{}{}",
+                    i, base, size / 1024, if *wide { ", 64-bit" } else { "" }, if *prefetchable { ", prefetchable" } else { "" }),
+                Some(Bar::Io { port, size }) => println!("    BAR{}: ports {:#x}-{:#x}", i, port, *port as u32 + size - 1),
+                None => {}
+            }
+        }
+        for cap in dev.capabilities() {
+            println!("    {:#05x}: {}{}", cap.offset, cap.name(), if cap.extended { " (extended)" } else { "" });
+        }
+        if let Some((generation, width)) = dev.pcie_link() {
+            println!("    Link: Gen{} x{}", generation, width);
+        }
+        if let Some(state) = dev.power_state() {
+            println!("    Power: D{}", state);
+        }
     }
 }
 
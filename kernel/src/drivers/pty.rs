@@ -0,0 +1,262 @@
+//! Pseudo-terminals
+//!
+//! A PTY is a pair of character devices joined back to back. The master
+//! end belongs to whatever draws the terminal, such as the Terminal app;
+//! it writes what the user types and reads what should be shown. The
+//! slave end, `/dev/pts/N`, is what a shell reads its input from and
+//! writes its output to.
+//!
+//! Between the two sits a small line discipline, as on a real terminal:
+//! typed characters are echoed back to the master, Backspace and Ctrl+U
+//! edit the line, and the slave only sees input a line at a time, when
+//! Enter is pressed. Ctrl+C throws the line away and raises an interrupt
+//! the slave can check for. Output newlines become CR LF on the way to
+//! the master. Resizing the master records the new size and tells the
+//! slave once.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::println;
+
+/// PTY number, N in `/dev/pts/N`
+pub type PtyId = u32;
+
+/// Most bytes buffered in either direction; more are dropped
+const BUFFER_LIMIT: usize = 64 * 1024;
+
+/// Longest input line the line discipline keeps
+const LINE_LIMIT: usize = 1024;
+
+/// Terminal size in character cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WinSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl WinSize {
+    pub const DEFAULT: WinSize = WinSize { cols: 80, rows: 24 };
+}
+
+struct Pty {
+    /// Line being typed, not yet passed to the slave
+    line: Vec<u8>,
+    /// Finished lines waiting for the slave
+    input: VecDeque<u8>,
+    /// Slave output waiting for the master
+    output: VecDeque<u8>,
+    size: WinSize,
+    resized: bool,
+    interrupted: bool,
+    /// The master was closed; the slave reads end of file
+    hung_up: bool,
+    slave_open: bool,
+}
+
+impl Pty {
+    fn emit(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if self.output.len() >= BUFFER_LIMIT {
+                break;
+            }
+            if b == b'\n' {
+                self.output.push_back(b'\r');
+            }
+            self.output.push_back(b);
+        }
+    }
+
+    /// Run typed bytes through the line discipline
+    fn type_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            match b {
+                b'\r' | b'\n' => {
+                    self.emit(b"\n");
+                    if self.input.len() + self.line.len() < BUFFER_LIMIT {
+                        self.input.extend(self.line.drain(..));
+                        self.input.push_back(b'\n');
+                    }
+                    self.line.clear();
+                }
+                // Backspace and Delete
+                0x08 | 0x7F => {
+                    if self.line.pop().is_some() {
+                        self.emit(b"\x08 \x08");
+                    }
+                }
+                // Ctrl+U
+                0x15 => {
+                    for _ in 0..self.line.len() {
+                        self.emit(b"\x08 \x08");
+                    }
+                    self.line.clear();
+                }
+                // Ctrl+C
+                0x03 => {
+                    self.emit(b"^C\n");
+                    self.line.clear();
+                    self.interrupted = true;
+                }
+                0x20..=0x7E if self.line.len() < LINE_LIMIT => {
+                    self.line.push(b);
+                    self.emit(&[b]);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+static PTYS: Mutex<BTreeMap<PtyId, Pty>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: Mutex<PtyId> = Mutex::new(0);
+
+/// Terminal end of a PTY; closing it hangs up the slave
+#[derive(Debug)]
+pub struct Master {
+    id: PtyId,
+}
+
+/// Shell end of a PTY, `/dev/pts/N`
+#[derive(Debug)]
+pub struct Slave {
+    id: PtyId,
+}
+
+/// Open a new PTY of the given size
+pub fn open(size: WinSize) -> (Master, Slave) {
+    let id = {
+        let mut next = NEXT_ID.lock();
+        let id = *next;
+        *next += 1;
+        id
+    };
+    PTYS.lock().insert(id, Pty {
+        line: Vec::new(),
+        input: VecDeque::new(),
+        output: VecDeque::new(),
+        size,
+        resized: false,
+        interrupted: false,
+        hung_up: false,
+        slave_open: true,
+    });
+    (Master { id }, Slave { id })
+}
+
+impl Master {
+    /// Pass typed bytes to the line discipline
+    pub fn write(&self, bytes: &[u8]) {
+        if let Some(pty) = PTYS.lock().get_mut(&self.id) {
+            pty.type_bytes(bytes);
+        }
+    }
+
+    /// Take everything waiting to be shown
+    pub fn read(&self) -> Vec<u8> {
+        PTYS.lock().get_mut(&self.id).map(|pty| pty.output.drain(..).collect()).unwrap_or_default()
+    }
+
+    /// Change the terminal size; the slave sees it with `take_resize`
+    pub fn resize(&self, size: WinSize) {
+        if let Some(pty) = PTYS.lock().get_mut(&self.id) {
+            if pty.size != size {
+                pty.size = size;
+                pty.resized = true;
+            }
+        }
+    }
+
+    /// Whether the slave end has been closed
+    pub fn slave_closed(&self) -> bool {
+        PTYS.lock().get(&self.id).map_or(true, |pty| !pty.slave_open)
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        let mut ptys = PTYS.lock();
+        let release = match ptys.get_mut(&self.id) {
+            Some(pty) => {
+                pty.hung_up = true;
+                !pty.slave_open
+            }
+            None => false,
+        };
+        if release {
+            ptys.remove(&self.id);
+        }
+    }
+}
+
+impl Slave {
+    pub fn id(&self) -> PtyId {
+        self.id
+    }
+
+    /// Take the next complete input line, without its newline
+    ///
+    /// `None` means no line is ready yet.
+    pub fn read_line(&self) -> Option<Vec<u8>> {
+        let mut ptys = PTYS.lock();
+        let pty = ptys.get_mut(&self.id)?;
+        let end = pty.input.iter().position(|&b| b == b'\n')?;
+        let line: Vec<u8> = pty.input.drain(..=end).take(end).collect();
+        Some(line)
+    }
+
+    /// Write output for the terminal
+    pub fn write(&self, bytes: &[u8]) {
+        if let Some(pty) = PTYS.lock().get_mut(&self.id) {
+            pty.emit(bytes);
+        }
+    }
+
+    pub fn size(&self) -> WinSize {
+        PTYS.lock().get(&self.id).map_or(WinSize::DEFAULT, |pty| pty.size)
+    }
+
+    /// The new size, if the terminal was resized since the last call
+    pub fn take_resize(&self) -> Option<WinSize> {
+        let mut ptys = PTYS.lock();
+        let pty = ptys.get_mut(&self.id)?;
+        core::mem::take(&mut pty.resized).then_some(pty.size)
+    }
+
+    /// Whether Ctrl+C was pressed since the last call
+    pub fn take_interrupt(&self) -> bool {
+        PTYS.lock().get_mut(&self.id).map_or(false, |pty| core::mem::take(&mut pty.interrupted))
+    }
+
+    /// Whether the terminal has gone away
+    pub fn hung_up(&self) -> bool {
+        PTYS.lock().get(&self.id).map_or(true, |pty| pty.hung_up)
+    }
+}
+
+impl Drop for Slave {
+    fn drop(&mut self) {
+        let mut ptys = PTYS.lock();
+        let release = match ptys.get_mut(&self.id) {
+            Some(pty) => {
+                pty.slave_open = false;
+                pty.hung_up
+            }
+            None => false,
+        };
+        if release {
+            ptys.remove(&self.id);
+        }
+    }
+}
+
+/// Print the open PTYs
+pub fn print_info() {
+    let ptys = PTYS.lock();
+    println!("Pseudo-terminals: {}", ptys.len());
+    for (id, pty) in ptys.iter() {
+        let state = if pty.hung_up { "hung up" } else if pty.slave_open { "open" } else { "slave closed" };
+        println!("  /dev/pts/{:<3} {}x{}  {}", id, pty.size.cols, pty.size.rows, state);
+    }
+}
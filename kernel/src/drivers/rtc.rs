@@ -0,0 +1,294 @@
+//! CMOS real-time clock
+//!
+//! The RTC is read once at boot: after any update in progress, until two
+//! reads agree, in BCD or binary and 12 or 24 hours as status register B
+//! says, with the century from the register the FADT names. From then on
+//! the time is that plus the timer's `now_ns`, so telling it costs no port
+//! I/O and it moves on smoothly between seconds.
+//!
+//! The RTC is taken to keep UTC. Local time is UTC moved by the offset of
+//! the `clock.timezone` setting.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use super::timer;
+use crate::drivers::input::{inb, outb};
+use crate::info;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+/// Status A: the RTC is updating its registers
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: 24-hour hours, binary rather than BCD
+const HOURS_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+/// In 12-hour mode, the hours register's PM bit
+const PM: u8 = 0x80;
+
+/// Unix time in nanoseconds at `BOOT_NS`; 0 until `init`
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
+/// The timer's `now_ns` when the RTC was read
+static BOOT_NS: AtomicU64 = AtomicU64::new(0);
+/// Seconds local time is ahead of UTC
+static OFFSET: AtomicI32 = AtomicI32::new(0);
+
+/// A calendar date and time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date and time `time` seconds after 1970-01-01 00:00:00
+    pub fn from_unix(time: u64) -> Self {
+        // The inverse of `unix_time`'s day count
+        let days = (time / 86_400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let (year, month) = match month {
+            10 | 11 => (era * 400 + year_of_era + 1, month - 9),
+            m => (era * 400 + year_of_era, m + 3),
+        };
+        let seconds = time % 86_400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn unix_time(&self) -> u64 {
+        // Days from the civil calendar, counting years from March so the
+        // leap day comes last
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            m => (self.year as i64, m as i64 - 3),
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds.max(0) as u64
+    }
+
+    /// Seconds since midnight
+    pub fn seconds_of_day(&self) -> u32 {
+        self.hour as u32 * 3600 + self.minute as u32 * 60 + self.second as u32
+    }
+
+    /// Three-letter day of the week
+    pub fn weekday(&self) -> &'static str {
+        const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        DAYS[(self.unix_time() / 86_400 % 7) as usize]
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Read the RTC and keep the time from here on
+pub fn init() {
+    let time = read();
+    BOOT_NS.store(timer::now_ns(), Ordering::Relaxed);
+    BOOT_TIME_NS.store(time.unix_time() * 1_000_000_000, Ordering::Relaxed);
+    info!("rtc", "RTC reads {} UTC", time);
+}
+
+/// Unix time in nanoseconds
+pub fn unix_ns() -> u64 {
+    let boot_time = BOOT_TIME_NS.load(Ordering::Relaxed);
+    if boot_time == 0 {
+        return read().unix_time() * 1_000_000_000;
+    }
+    boot_time + timer::now_ns().saturating_sub(BOOT_NS.load(Ordering::Relaxed))
+}
+
+/// Set the clock to Unix time `ns`, as from a time server; the RTC itself
+/// is left as it is
+pub fn set_unix_ns(ns: u64) {
+    BOOT_NS.store(timer::now_ns(), Ordering::Relaxed);
+    BOOT_TIME_NS.store(ns, Ordering::Relaxed);
+}
+
+/// Unix time in seconds
+pub fn unix_time() -> u64 {
+    unix_ns() / 1_000_000_000
+}
+
+/// The date and time in UTC
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
+
+/// The date and time in the configured timezone
+pub fn local() -> DateTime {
+    let offset = OFFSET.load(Ordering::Relaxed) as i64;
+    DateTime::from_unix((unix_time() as i64 + offset).max(0) as u64)
+}
+
+/// Seconds local time is ahead of UTC
+pub fn offset() -> i32 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+pub fn set_offset(seconds: i32) {
+    OFFSET.store(seconds, Ordering::Relaxed);
+}
+
+/// Seconds ahead of UTC of a timezone written `UTC`, `UTC+2`, `UTC-5:30`
+/// or just `+02:00`; offsets run from -12 to +14 hours
+pub fn parse_timezone(name: &str) -> Option<i32> {
+    let name = name.trim();
+    let offset = name.strip_prefix("UTC").unwrap_or(name);
+    if offset.is_empty() {
+        return (name == "UTC").then_some(0);
+    }
+    let (sign, offset) = match offset.as_bytes()[0] {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+    let seconds = sign * (hours * 3600 + minutes * 60);
+    (-12 * 3600..=14 * 3600).contains(&seconds).then_some(seconds)
+}
+
+/// A timezone as `parse_timezone` takes it: `UTC`, `UTC+2`, `UTC-5:30`
+pub fn timezone_name(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.unsigned_abs() / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, 0) => String::from("UTC"),
+        (hours, 0) => format!("UTC{}{}", sign, hours),
+        (hours, minutes) => format!("UTC{}{}:{:02}", sign, hours, minutes),
+    }
+}
+
+/// The timezones to choose from: every whole hour, and the half and
+/// quarter hours in use
+pub fn timezones() -> Vec<String> {
+    const OTHERS: [i32; 10] = [-570, -210, 210, 270, 330, 345, 390, 570, 630, 765];
+    let mut minutes: Vec<i32> = (-12..=14).map(|hours| hours * 60).chain(OTHERS).collect();
+    minutes.sort_unstable();
+    minutes.into_iter().map(|m| timezone_name(m * 60)).collect()
+}
+
+/// Read the date and time from the CMOS
+fn read() -> DateTime {
+    let century_register = crate::acpi::century_register();
+    let registers = || {
+        // The registers are not to be trusted while they are updated
+        let mut spins = 0;
+        while read_cmos(STATUS_A) & UPDATE_IN_PROGRESS != 0 && spins < 1_000_000 {
+            spins += 1;
+            core::hint::spin_loop();
+        }
+        let century = century_register.map(read_cmos).unwrap_or(0);
+        let [second, minute, hour, day, month, year] = [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_cmos);
+        [second, minute, hour, day, month, year, century]
+    };
+    // An update may still have come between the check and the reads
+    let mut values = registers();
+    for _ in 0..4 {
+        let again = registers();
+        if again == values {
+            break;
+        }
+        values = again;
+    }
+
+    let status = read_cmos(STATUS_B);
+    let decode = |value: u8| if status & BINARY != 0 { value } else { (value >> 4) * 10 + (value & 0x0F) };
+    let [second, minute, hour, day, month, year, century] = values;
+    let mut hour_24 = decode(hour & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 AM is 0, 12 PM is 12
+        hour_24 %= 12;
+        if hour & PM != 0 {
+            hour_24 += 12;
+        }
+    }
+    let century = match century_register {
+        Some(_) => decode(century) as u16,
+        None => 20,
+    };
+    DateTime {
+        year: century * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour: hour_24,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+fn read_cmos(reg: u8) -> u8 {
+    unsafe {
+        outb(0x70, reg);
+        inb(0x71)
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn converts_dates() -> Result<(), String> {
+        let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 34, second: 56 };
+        check_eq!(leap_day.unix_time(), 1_709_210_096);
+        check_eq!(DateTime::from_unix(1_709_210_096), leap_day);
+        check_eq!(leap_day.weekday(), "Thu");
+        check_eq!(format!("{}", DateTime::from_unix(978_307_199)), "2000-12-31 23:59:59");
+        check!(DateTime::from_unix(0).unix_time() == 0);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn parses_timezones() -> Result<(), String> {
+        check_eq!(parse_timezone("UTC"), Some(0));
+        check_eq!(parse_timezone("UTC+2"), Some(7200));
+        check_eq!(parse_timezone("UTC-5:30"), Some(-19_800));
+        check_eq!(parse_timezone("+02:00"), Some(7200));
+        check_eq!(parse_timezone("UTC+15"), None);
+        check_eq!(parse_timezone("UTC+1:60"), None);
+        check!(timezones().iter().all(|name| parse_timezone(name).map(timezone_name).as_ref() == Some(name)));
+        Ok(())
+    }
+}
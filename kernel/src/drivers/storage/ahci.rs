@@ -1,6 +1,6 @@
 //! AHCI (Advanced Host Controller Interface) driver
 
-use crate::println;
+use crate::{debug, info, warn};
 //
 // Driver for SATA controllers in AHCI mode.
 
@@ -100,14 +100,14 @@ struct AhciController {
 
 /// Initialize AHCI controllers
 pub fn init() {
-    println!("[ahci] Looking for AHCI controllers...");
+    info!("ahci", "Looking for AHCI controllers...");
 
     // Find SATA controllers in AHCI mode
     let devices = pci::get_devices();
     
     for dev in devices.iter() {
         if dev.class == class::MASS_STORAGE && dev.subclass == 0x06 {
-            println!("[ahci] Found AHCI controller at {:02X}:{:02X}.{}: {:04X}:{:04X}",
+            info!("ahci", "Found AHCI controller at {:02X}:{:02X}.{}: {:04X}:{:04X}",
                 dev.bus, dev.device, dev.function,
                 dev.vendor_id, dev.device_id);
             
@@ -115,7 +115,7 @@ pub fn init() {
             // For now just print that we found it
             
             if let Err(e) = init_controller(dev) {
-                println!("[ahci] Failed to initialize controller: {:?}", e);
+                warn!("ahci", "Failed to initialize controller: {:?}", e);
             }
         }
     }
@@ -133,7 +133,7 @@ fn init_controller(dev: &PciDevice) -> Result<(), AhciError> {
     // 32-bit BAR (bars[6] doesn't exist in our structure)
     let hba_base = (bar5 & 0xFFFFFFF0) as u64;
 
-    println!("[ahci] HBA base address: {:016X}", hba_base);
+    debug!("ahci", "HBA base address: {:016X}", hba_base);
 
     // TODO: Map HBA memory and initialize ports
     // This requires proper MMIO support
@@ -9,6 +9,7 @@ use alloc::boxed::Box;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::println;
+use crate::info;
 
 /// Storage device trait
 pub trait StorageDevice: Send + Sync {
@@ -56,18 +57,18 @@ lazy_static! {
 
 /// Initialize storage drivers
 pub fn init() {
-    println!("[storage] Initializing storage drivers...");
+    info!("storage", "Initializing storage drivers...");
 
     // Initialize AHCI
     ahci::init();
 
-    println!("[storage] Storage drivers initialized");
+    info!("storage", "Storage drivers initialized");
 }
 
 /// Register a storage device
 pub fn register_device(device: Box<dyn StorageDevice>) {
     let mut devices = STORAGE_DEVICES.lock();
-    println!("[storage] Registered device: {} ({} bytes)", 
+    info!("storage", "Registered device: {} ({} bytes)", 
         device.name(), device.size());
     devices.push(device);
 }
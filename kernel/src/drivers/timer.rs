@@ -1,8 +1,29 @@
 //! Programmable Interval Timer (PIT) and APIC Timer
 //!
 //! Provides timing services and preemptive scheduling.
+//!
+//! The kernel tick comes from the APIC timer, or the PIT without one. For
+//! finer time the TSC is calibrated at boot against the HPET, or the PIT's
+//! channel 2 without one, and `now_ns` reads it; if the TSC is not
+//! invariant the HPET counter is read instead, and failing both, ticks.
+//!
+//! `after` runs a callback once a delay has passed. Timers sit in a wheel
+//! of `WHEEL_SLOTS` one-tick slots, by deadline. Callbacks take locks such
+//! as the network's, so they are not run by the interrupt but by `poll`,
+//! which the kernel's main loops call.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
 
+use super::hpet;
+use crate::arch::cpu::rdtsc;
+use crate::drivers::input::{inb, outb};
 use crate::println;
+use crate::trace;
+use crate::info;
 
 /// PIT frequency (Hz)
 const PIT_FREQUENCY: u32 = 1193182;
@@ -12,9 +33,52 @@ const TIMER_FREQUENCY: u32 = 1000;
 /// Number of ticks since boot
 static mut TICKS: u64 = 0;
 
+/// What `now_ns` reads
+const CLOCK_TICKS: u8 = 0;
+const CLOCK_TSC: u8 = 1;
+const CLOCK_HPET: u8 = 2;
+
+static CLOCK: AtomicU8 = AtomicU8::new(CLOCK_TICKS);
+/// TSC ticks per millisecond; 0 until calibrated
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// The clock's reading at boot
+static CLOCK_START: AtomicU64 = AtomicU64::new(0);
+
+/// Slots in the timer wheel, one per tick
+const WHEEL_SLOTS: usize = 256;
+
+/// A timer set with `after`, to cancel it by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: u64,
+    /// Tick at which it is due
+    deadline: u64,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+/// Timers in slots by deadline; one due in more than `WHEEL_SLOTS` ticks
+/// waits in its slot for the wheel to come round again
+struct Wheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+    /// Last tick `poll` has run the timers of
+    current: u64,
+    next_id: u64,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+impl Wheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Timer> = Vec::new();
+        Self { slots: [EMPTY; WHEEL_SLOTS], current: 0, next_id: 1 }
+    }
+}
+
 /// Initialize the timer
 pub fn init() {
-    println!("[timer] Initializing PIT timer at {}Hz...", TIMER_FREQUENCY);
+    info!("timer", "Initializing PIT timer at {}Hz...", TIMER_FREQUENCY);
 
     unsafe {
         // Calculate PIT divisor
@@ -45,7 +109,98 @@ pub fn init() {
         );
     }
 
-    println!("[timer] PIT timer initialized");
+    info!("timer", "PIT timer initialized");
+
+    let hpet = hpet::init();
+    let tsc_per_ms = if hpet { calibrate_tsc_hpet() } else { calibrate_tsc_pit() };
+    TSC_PER_MS.store(tsc_per_ms, Ordering::Relaxed);
+    if tsc_per_ms > 0 && tsc_invariant() {
+        CLOCK_START.store(rdtsc(), Ordering::Relaxed);
+        CLOCK.store(CLOCK_TSC, Ordering::Relaxed);
+    } else if hpet {
+        CLOCK_START.store(hpet::counter(), Ordering::Relaxed);
+        CLOCK.store(CLOCK_HPET, Ordering::Relaxed);
+    }
+    info!("timer", "TSC at {}kHz against the {}; clock: {}", tsc_per_ms,
+        if hpet { "HPET" } else { "PIT" }, clock_name());
+
+    // The APIC timer ticks where there is one; the PIT's IRQ 0 otherwise
+    if !crate::arch::apic::start_timer(TIMER_FREQUENCY) {
+        crate::arch::interrupts::set_irq_handler(0, pit_interrupt);
+    }
+}
+
+extern "x86-interrupt" fn pit_interrupt(_stack_frame: crate::arch::interrupts::InterruptStackFrame) {
+    crate::arch::interrupts::end_of_interrupt(0);
+    unsafe { timer_interrupt() };
+}
+
+/// Whether the TSC runs at a constant rate through power states
+fn tsc_invariant() -> bool {
+    let max = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    max >= 0x8000_0007 && unsafe { core::arch::x86_64::__cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// TSC ticks per millisecond, counted over 10ms of the HPET
+fn calibrate_tsc_hpet() -> u64 {
+    const MS: u64 = 10;
+    let counts = MS * 1_000_000_000_000 / hpet::period_fs();
+    let start = hpet::counter();
+    let tsc = rdtsc();
+    while hpet::counter().wrapping_sub(start) < counts {
+        core::hint::spin_loop();
+    }
+    let tsc_ticks = rdtsc() - tsc;
+    let elapsed = hpet::counts_to_ns(hpet::counter().wrapping_sub(start));
+    tsc_ticks * 1_000_000 / elapsed.max(1)
+}
+
+/// TSC ticks per millisecond, counted while the PIT's channel 2 counts
+/// down 10ms
+fn calibrate_tsc_pit() -> u64 {
+    const MS: u32 = 10;
+    unsafe {
+        // Gate channel 2 on, the speaker off
+        outb(0x61, (inb(0x61) & !0x02) | 0x01);
+        // Channel 2, lobyte/hibyte, mode 0: the output goes high at zero
+        outb(0x43, 0xB0);
+        let count = PIT_FREQUENCY * MS / 1000;
+        outb(0x42, count as u8);
+        outb(0x42, (count >> 8) as u8);
+        // Restart the count
+        let gate = inb(0x61) & !0x01;
+        outb(0x61, gate);
+        outb(0x61, gate | 0x01);
+
+        let tsc = rdtsc();
+        while inb(0x61) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        (rdtsc() - tsc) / MS as u64
+    }
+}
+
+/// TSC ticks per millisecond, as calibrated at boot
+pub fn tsc_per_ms() -> u64 {
+    TSC_PER_MS.load(Ordering::Relaxed)
+}
+
+fn clock_name() -> &'static str {
+    match CLOCK.load(Ordering::Relaxed) {
+        CLOCK_TSC => "TSC",
+        CLOCK_HPET => "HPET",
+        _ => "ticks",
+    }
+}
+
+/// Nanoseconds since the timer started
+pub fn now_ns() -> u64 {
+    let start = CLOCK_START.load(Ordering::Relaxed);
+    match CLOCK.load(Ordering::Relaxed) {
+        CLOCK_TSC => ((rdtsc() - start) as u128 * 1_000_000 / tsc_per_ms() as u128) as u64,
+        CLOCK_HPET => hpet::counts_to_ns(hpet::counter().wrapping_sub(start)),
+        _ => elapsed_ms() * 1_000_000,
+    }
 }
 
 /// Get current tick count
@@ -63,130 +218,98 @@ pub fn elapsed_sec() -> u64 {
     unsafe { TICKS / TIMER_FREQUENCY as u64 }
 }
 
-/// Sleep for a number of milliseconds (busy wait)
-pub fn sleep_ms(ms: u64) {
-    let target = elapsed_ms() + ms;
-    while elapsed_ms() < target {
+/// Sleep for a number of nanoseconds (busy wait)
+pub fn sleep_ns(ns: u64) {
+    let target = now_ns() + ns;
+    while now_ns() < target {
         core::hint::spin_loop();
     }
 }
 
+/// Sleep for a number of milliseconds (busy wait)
+pub fn sleep_ms(ms: u64) {
+    sleep_ns(ms * 1_000_000);
+}
+
 /// Sleep for a number of seconds (busy wait)
 pub fn sleep_sec(sec: u64) {
     sleep_ms(sec * 1000);
 }
 
-/// Timer interrupt handler
-///
-/// # Safety
-/// This is called from interrupt context.
-pub unsafe fn timer_interrupt() {
-    TICKS += 1;
-    
-    // Call scheduler tick
-    crate::process::scheduler::timer_tick();
+/// Run `callback` from `poll` once `delay` has passed
+pub fn after<F: FnOnce() + Send + 'static>(delay: Duration, callback: F) -> TimerId {
+    let per_tick = 1_000_000_000 / TIMER_FREQUENCY as u128;
+    // Round up, so it never fires early
+    let delay_ticks = ((delay.as_nanos() + per_tick - 1) / per_tick).min(u64::MAX as u128 / 2) as u64;
+    let mut wheel = WHEEL.lock();
+    let id = wheel.next_id;
+    wheel.next_id += 1;
+    // Due no earlier than the next tick `poll` looks at
+    let deadline = (ticks() + delay_ticks).max(wheel.current + 1);
+    wheel.slots[deadline as usize % WHEEL_SLOTS].push(Timer { id, deadline, callback: Box::new(callback) });
+    TimerId(id)
 }
 
-/// Read current time from CMOS RTC
-pub fn read_rtc() -> RtcTime {
-    unsafe {
-        // Read CMOS registers
-        let second = read_cmos(0x00);
-        let minute = read_cmos(0x02);
-        let hour = read_cmos(0x04);
-        let day = read_cmos(0x07);
-        let month = read_cmos(0x08);
-        let year = read_cmos(0x09);
-
-        RtcTime {
-            second: bcd_to_binary(second),
-            minute: bcd_to_binary(minute),
-            hour: bcd_to_binary(hour),
-            day: bcd_to_binary(day),
-            month: bcd_to_binary(month),
-            year: 2000 + bcd_to_binary(year) as u16,
+/// Stop a timer from firing; false if it already has, or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    let mut wheel = WHEEL.lock();
+    for slot in wheel.slots.iter_mut() {
+        if let Some(i) = slot.iter().position(|t| t.id == id.0) {
+            slot.swap_remove(i);
+            return true;
         }
     }
+    false
 }
 
-/// Read CMOS register
-unsafe fn read_cmos(reg: u8) -> u8 {
-    // Select register
-    core::arch::asm!(
-        "out 0x70, al",
-        in("al") reg,
-        options(nomem, nostack)
-    );
-    
-    // Small delay
-    for _ in 0..100 {
-        core::arch::asm!("nop", options(nomem, nostack));
+/// Run the callbacks of timers that are due
+pub fn poll() {
+    let now = ticks();
+    let mut due = Vec::new();
+    {
+        let mut wheel = WHEEL.lock();
+        if wheel.current >= now {
+            return;
+        }
+        // Past a full turn every slot has been looked at
+        let last = now.min(wheel.current + WHEEL_SLOTS as u64);
+        for tick in wheel.current + 1..=last {
+            let slot = &mut wheel.slots[tick as usize % WHEEL_SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].deadline <= now {
+                    due.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        wheel.current = now;
+    }
+    // Callbacks may set timers of their own
+    due.sort_by_key(|t| t.deadline);
+    for timer in due {
+        (timer.callback)();
     }
-    
-    // Read value
-    let val: u8;
-    core::arch::asm!(
-        "in al, 0x71",
-        out("al") val,
-        options(nomem, nostack)
-    );
-    
-    val
-}
-
-/// Convert BCD to binary
-fn bcd_to_binary(bcd: u8) -> u8 {
-    ((bcd >> 4) * 10) + (bcd & 0x0F)
 }
 
-/// RTC time structure
-#[derive(Debug, Clone, Copy)]
-pub struct RtcTime {
-    pub second: u8,
-    pub minute: u8,
-    pub hour: u8,
-    pub day: u8,
-    pub month: u8,
-    pub year: u16,
+/// Timers waiting to fire
+pub fn pending() -> usize {
+    WHEEL.lock().slots.iter().map(Vec::len).sum()
 }
 
-impl RtcTime {
-    /// Format as string
-    pub fn format(&self) -> [u8; 20] {
-        let mut buf = [0u8; 20];
-        
-        fn write_num(buf: &mut [u8], pos: usize, num: u16, width: usize) {
-            let s = format_num(num, width);
-            buf[pos..pos+width].copy_from_slice(&s[..width]);
-        }
-        
-        write_num(&mut buf, 0, self.year as u16, 4);
-        buf[4] = b'-';
-        write_num(&mut buf, 5, self.month as u16, 2);
-        buf[7] = b'-';
-        write_num(&mut buf, 8, self.day as u16, 2);
-        buf[10] = b' ';
-        write_num(&mut buf, 11, self.hour as u16, 2);
-        buf[13] = b':';
-        write_num(&mut buf, 14, self.minute as u16, 2);
-        buf[16] = b':';
-        write_num(&mut buf, 17, self.second as u16, 2);
-        
-        buf
-    }
-}
-
-/// Format number as fixed-width decimal
-fn format_num(num: u16, width: usize) -> [u8; 4] {
-    let mut buf = [b'0'; 4];
-    let mut n = num;
-    
-    for i in (0..width).rev() {
-        buf[i] = b'0' + (n % 10) as u8;
-        n /= 10;
-    }
+/// Timer interrupt handler
+///
+/// # Safety
+/// This is called from interrupt context.
+pub unsafe fn timer_interrupt() {
+    let begin = trace::begin();
+    TICKS += 1;
     
-    buf
+    // Call scheduler tick
+    crate::process::scheduler::timer_tick();
+    crate::watchdog::tick(TICKS);
+    trace::end(trace::Kind::Irq, begin, 0, 0);
 }
 
 /// Print timer statistics
@@ -195,10 +318,8 @@ pub fn print_stats() {
     println!("  Ticks: {}", ticks());
     println!("  Elapsed: {}s", elapsed_sec());
     println!("  Frequency: {}Hz", TIMER_FREQUENCY);
-    
-    let rtc = read_rtc();
-    let formatted = rtc.format();
-    if let Ok(time_str) = core::str::from_utf8(&formatted) {
-        println!("  RTC: {}", time_str);
-    }
+    println!("  Clock: {} ({}ns since boot), TSC at {}kHz", clock_name(), now_ns(), tsc_per_ms());
+    println!("  Pending timers: {}", pending());
+    println!("  Time: {} UTC, {} {}", super::rtc::now(), super::rtc::local(),
+        super::rtc::timezone_name(super::rtc::offset()));
 }
@@ -0,0 +1,116 @@
+//! Bochs VBE display interface ("dispi")
+//!
+//! The QEMU standard VGA, Bochs and VirtualBox adapters expose their mode
+//! registers through an index/data port pair. Writing the resolution and
+//! depth there reprograms the scanout of the same linear framebuffer that
+//! GOP handed us at boot.
+
+use crate::drivers::{DriverError, DriverResult};
+use crate::drivers::input::{inw, outw};
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+// Register indices
+const REG_ID: u16 = 0x0;
+const REG_XRES: u16 = 0x1;
+const REG_YRES: u16 = 0x2;
+const REG_BPP: u16 = 0x3;
+const REG_ENABLE: u16 = 0x4;
+const REG_VIRT_WIDTH: u16 = 0x6;
+const REG_X_OFFSET: u16 = 0x8;
+const REG_Y_OFFSET: u16 = 0x9;
+const REG_VIDEO_MEMORY_64K: u16 = 0xA;
+
+// Interface versions
+const ID_MIN: u16 = 0xB0C0;
+const ID_MAX: u16 = 0xB0C5;
+/// First version with the GETCAPS flag and the video memory register
+const ID_CAPS: u16 = 0xB0C2;
+
+// ENABLE register flags
+const ENABLED: u16 = 0x01;
+const GETCAPS: u16 = 0x02;
+const LFB_ENABLED: u16 = 0x40;
+
+/// Largest mode when the adapter cannot report its limits
+const FALLBACK_MAX: (u32, u32) = (1600, 1200);
+
+fn read(index: u16) -> u16 {
+    unsafe {
+        outw(INDEX_PORT, index);
+        inw(DATA_PORT)
+    }
+}
+
+fn write(index: u16, value: u16) {
+    unsafe {
+        outw(INDEX_PORT, index);
+        outw(DATA_PORT, value);
+    }
+}
+
+/// Interface version, if a dispi adapter answers
+fn version() -> Option<u16> {
+    let id = read(REG_ID);
+    (ID_MIN..=ID_MAX).contains(&id).then_some(id)
+}
+
+/// Check for a dispi adapter
+pub fn is_present() -> bool {
+    version().is_some()
+}
+
+/// Video memory in bytes
+pub fn vram_size() -> usize {
+    match version() {
+        Some(id) if id >= ID_CAPS => read(REG_VIDEO_MEMORY_64K) as usize * 64 * 1024,
+        // Older interfaces always have 4MB
+        Some(_) => 4 * 1024 * 1024,
+        None => 0,
+    }
+}
+
+/// Largest supported resolution
+pub fn max_resolution() -> (u32, u32) {
+    match version() {
+        Some(id) if id >= ID_CAPS => {
+            let enable = read(REG_ENABLE);
+            write(REG_ENABLE, enable | GETCAPS);
+            let max = (read(REG_XRES) as u32, read(REG_YRES) as u32);
+            write(REG_ENABLE, enable);
+            max
+        }
+        _ => FALLBACK_MAX,
+    }
+}
+
+/// Program a mode, returning its pitch in bytes
+pub fn set_mode(width: u32, height: u32, bpp: u8) -> DriverResult<u32> {
+    if version().is_none() {
+        return Err(DriverError::NotFound);
+    }
+    let (max_w, max_h) = max_resolution();
+    if width > max_w || height > max_h {
+        return Err(DriverError::Unsupported);
+    }
+    let pitch = width * ((bpp as u32 + 7) / 8);
+    if (pitch * height) as usize > vram_size() {
+        return Err(DriverError::Unsupported);
+    }
+
+    // Registers only take effect while the display is disabled
+    write(REG_ENABLE, 0);
+    write(REG_XRES, width as u16);
+    write(REG_YRES, height as u16);
+    write(REG_BPP, bpp as u16);
+    write(REG_VIRT_WIDTH, width as u16);
+    write(REG_X_OFFSET, 0);
+    write(REG_Y_OFFSET, 0);
+    write(REG_ENABLE, ENABLED | LFB_ENABLED);
+
+    if read(REG_XRES) as u32 != width || read(REG_YRES) as u32 != height || read(REG_BPP) != bpp as u16 {
+        return Err(DriverError::InitFailed);
+    }
+    Ok(pitch)
+}
@@ -0,0 +1,110 @@
+//! EDID: what the display says about itself
+//!
+//! The bootloader passes on the EDID GOP read from the display. Only the
+//! 128-byte base block is read here: who made the display, its name, its
+//! size and its preferred mode, the first detailed timing.
+
+use alloc::string::String;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+const BLOCK_SIZE: usize = 128;
+/// Where the four 18-byte descriptors start
+const DESCRIPTORS: usize = 54;
+/// Descriptor tag for the display's name
+const TAG_NAME: u8 = 0xFC;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edid {
+    /// Three-letter PNP ID of the manufacturer
+    pub manufacturer: String,
+    pub product: u16,
+    pub name: Option<String>,
+    /// Visible area in centimetres, if the display says
+    pub size_cm: Option<(u32, u32)>,
+    /// The mode the display looks best in
+    pub preferred: Option<(u32, u32)>,
+}
+
+/// Read the base block; None unless it has the header and checksum
+pub fn parse(data: &[u8]) -> Option<Edid> {
+    let block = data.get(..BLOCK_SIZE)?;
+    if block[..8] != HEADER || block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+        return None;
+    }
+
+    let id = u16::from_be_bytes([block[8], block[9]]);
+    let manufacturer = [10, 5, 0].iter().map(|shift| (b'A' - 1 + ((id >> shift) & 0x1F) as u8) as char).collect();
+    let product = u16::from_le_bytes([block[10], block[11]]);
+    let size_cm = match (block[21], block[22]) {
+        (0, _) | (_, 0) => None,
+        (w, h) => Some((w as u32, h as u32)),
+    };
+
+    let mut name = None;
+    let mut preferred = None;
+    for descriptor in block[DESCRIPTORS..DESCRIPTORS + 4 * 18].chunks_exact(18) {
+        if descriptor[0] != 0 || descriptor[1] != 0 {
+            // A detailed timing; the first is the preferred one
+            if preferred.is_none() {
+                let width = descriptor[2] as u32 | ((descriptor[4] as u32 & 0xF0) << 4);
+                let height = descriptor[5] as u32 | ((descriptor[7] as u32 & 0xF0) << 4);
+                preferred = Some((width, height));
+            }
+        } else if descriptor[3] == TAG_NAME {
+            let text = &descriptor[5..];
+            let end = text.iter().position(|&byte| byte == b'\n').unwrap_or(text.len());
+            name = Some(String::from_utf8_lossy(&text[..end]).trim().into());
+        }
+    }
+
+    Some(Edid { manufacturer, product, name, size_cm, preferred })
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// QEMU's EDID for a 1280x800 display
+    fn sample() -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(&HEADER);
+        block[8..10].copy_from_slice(&[0x49, 0x14]); // "RHT"
+        block[10..12].copy_from_slice(&1234u16.to_le_bytes());
+        block[21] = 34;
+        block[22] = 21;
+        // 1280x800: 0x500 and 0x320
+        let timing = &mut block[54..72];
+        timing[..2].copy_from_slice(&[0x58, 0x1B]);
+        timing[2] = 0x00;
+        timing[4] = 0x50;
+        timing[5] = 0x20;
+        timing[7] = 0x30;
+        let name = &mut block[72..90];
+        name[3] = TAG_NAME;
+        name[5..15].copy_from_slice(b"QEMU Mon\n ");
+        let sum = block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        block[127] = 0u8.wrapping_sub(sum);
+        block
+    }
+
+    #[kernel_test]
+    fn reads_the_base_block() -> Result<(), String> {
+        let edid = parse(&sample()).ok_or("did not parse")?;
+        check_eq!(edid.manufacturer.as_str(), "RHT");
+        check_eq!(edid.product, 1234);
+        check_eq!(edid.name.as_deref(), Some("QEMU Mon"));
+        check_eq!(edid.size_cm, Some((34, 21)));
+        check_eq!(edid.preferred, Some((1280, 800)));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn rejects_a_bad_checksum() -> Result<(), String> {
+        let mut block = sample();
+        block[20] ^= 1;
+        check!(parse(&block).is_none());
+        check!(parse(&block[..64]).is_none());
+        Ok(())
+    }
+}
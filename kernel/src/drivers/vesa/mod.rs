@@ -2,14 +2,49 @@
 //!
 //! Graphics driver for VESA BIOS Extensions (VBE) providing
 //! high-resolution framebuffer access for WebbOS desktop.
+//!
+//! GOP fixes the mode at boot. On adapters with a mode-setting register
+//! interface (`dispi` for Bochs/QEMU, `svga` for VMware) `set_mode` can
+//! change resolution and depth at runtime, within the framebuffer window
+//! the bootloader maps. With a virtio-gpu device the "framebuffer" is guest
+//! memory that `drivers::virtio_gpu` uploads to the host.
+//!
+//! The bootloader passes on every mode GOP offered and the display's
+//! EDID, kept here for mode setting to choose from.
 
+pub mod dispi;
+pub mod edid;
+pub mod svga;
+
+use alloc::vec::Vec;
 use core::ptr::{read_volatile, write_volatile};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
 use crate::println;
+use crate::arch::simd;
+use crate::drivers::{DriverError, DriverResult};
+use crate::drivers::virtio_gpu;
+use crate::graphics::font;
 use crate::mm::phys_to_virt;
+use webbos_shared::bootinfo::VideoMode;
 use webbos_shared::types::PhysAddr;
+use crate::{debug, info};
+
+/// Bytes of framebuffer modes may use, QEMU's default VRAM
+pub const FB_WINDOW_SIZE: usize = 16 * 1024 * 1024;
+
+/// Resolutions offered by `available_modes`
+pub const STANDARD_MODES: [(u32, u32); 8] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 1024),
+    (1440, 900),
+    (1600, 900),
+    (1920, 1080),
+];
 
 /// VBE 2.0+ Information Block
 #[repr(C, packed)]
@@ -111,6 +146,8 @@ pub struct VesaDriver {
     pub initialized: bool,
     pub info: FramebufferInfo,
     pub fb_virt_addr: *mut u8,
+    /// Bounding box (x0, y0, x1, y1) drawn since the last `take_damage`
+    damage: Option<(u32, u32, u32, u32)>,
 }
 
 unsafe impl Send for VesaDriver {}
@@ -134,6 +171,7 @@ impl VesaDriver {
                 size: 0,
             },
             fb_virt_addr: core::ptr::null_mut(),
+            damage: None,
         }
     }
     
@@ -144,12 +182,34 @@ impl VesaDriver {
     
     /// Initialize with pre-mapped virtual address
     pub fn init_with_virt_addr(&mut self, width: u32, height: u32, bpp: u8, phys_addr: u64, virt_addr: u64) {
-        println!("[vesa] Initializing VESA framebuffer...");
-        println!("[vesa] Resolution: {}x{} @ {}bpp", width, height, bpp);
-        println!("[vesa] Physical address: 0x{:016x}", phys_addr);
+        info!("vesa", "Initializing VESA framebuffer...");
+        info!("vesa", "Resolution: {}x{} @ {}bpp", width, height, bpp);
+        debug!("vesa", "Physical address: 0x{:016x}", phys_addr);
+        
+        let pitch = width * ((bpp as u32 + 7) / 8);
+        self.set_geometry(width, height, bpp, pitch, phys_addr);
+        let size = self.info.size;
+        
+        // Use pre-mapped virtual address if provided, otherwise calculate
+        if virt_addr != 0 {
+            self.fb_virt_addr = virt_addr as *mut u8;
+        } else {
+            self.fb_virt_addr = phys_to_virt(PhysAddr::new(phys_addr)).as_u64() as *mut u8;
+        }
         
+        debug!("vesa", "Virtual address: {:p}", self.fb_virt_addr);
+        info!("vesa", "Framebuffer size: {} KB", size / 1024);
+        
+        // Clear framebuffer to black
+        self.clear(0);
+        
+        self.initialized = true;
+        info!("vesa", "Initialization complete");
+    }
+    
+    /// Record a new mode without touching the mapping
+    fn set_geometry(&mut self, width: u32, height: u32, bpp: u8, pitch: u32, phys_addr: u64) {
         let bytes_per_pixel = (bpp + 7) / 8;
-        let pitch = width * bytes_per_pixel as u32;
         let size = (pitch * height) as usize;
         
         // Calculate color masks based on bpp
@@ -173,22 +233,6 @@ impl VesaDriver {
             phys_addr,
             size,
         };
-        
-        // Use pre-mapped virtual address if provided, otherwise calculate
-        if virt_addr != 0 {
-            self.fb_virt_addr = virt_addr as *mut u8;
-        } else {
-            self.fb_virt_addr = phys_to_virt(PhysAddr::new(phys_addr)).as_u64() as *mut u8;
-        }
-        
-        println!("[vesa] Virtual address: {:p}", self.fb_virt_addr);
-        println!("[vesa] Framebuffer size: {} KB", size / 1024);
-        
-        // Clear framebuffer to black
-        self.clear(0);
-        
-        self.initialized = true;
-        println!("[vesa] Initialization complete");
     }
     
     /// Check if initialized
@@ -203,21 +247,65 @@ impl VesaDriver {
     
     /// Clear framebuffer with color
     pub fn clear(&mut self, color: u32) {
-        if !self.initialized {
+        if self.fb_virt_addr.is_null() {
             return;
         }
         
         let pixel = self.color_to_pixel(color);
-        let count = (self.info.pitch * self.info.height) as usize / self.info.bytes_per_pixel as usize;
+        let bpp = self.info.bytes_per_pixel as u32;
+        self.add_damage(0, 0, self.info.width, self.info.height);
         
-        unsafe {
-            let fb = self.fb_virt_addr as *mut u32;
-            for i in 0..count {
-                write_volatile(fb.add(i), pixel);
+        if self.info.pitch == self.info.width * bpp {
+            // No padding between scanlines: the whole buffer is one span
+            let count = (self.info.width * self.info.height) as usize;
+            unsafe {
+                fill_pixels(self.fb_virt_addr, count, pixel, self.info.bytes_per_pixel);
+            }
+        } else {
+            for y in 0..self.info.height {
+                unsafe {
+                    fill_pixels(self.row_ptr(y), self.info.width as usize, pixel, self.info.bytes_per_pixel);
+                }
             }
         }
     }
     
+    /// Grow the damaged area to include `[x0, x1) x [y0, y1)`
+    #[inline]
+    fn add_damage(&mut self, x0: u32, y0: u32, x1: u32, y1: u32) {
+        self.damage = Some(match self.damage {
+            Some((a0, b0, a1, b1)) => (a0.min(x0), b0.min(y0), a1.max(x1), b1.max(y1)),
+            None => (x0, y0, x1, y1),
+        });
+    }
+    
+    /// Take the bounding box of everything drawn since the last call, as
+    /// (x, y, width, height)
+    ///
+    /// Displays that scan out a copy of the framebuffer (virtio-gpu) use
+    /// this to upload only what changed.
+    pub fn take_damage(&mut self) -> Option<(u32, u32, u32, u32)> {
+        self.damage.take().map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0, y1 - y0))
+    }
+    
+    /// Pointer to the first byte of scanline `y`
+    #[inline]
+    fn row_ptr(&self, y: u32) -> *mut u8 {
+        unsafe { self.fb_virt_addr.add((y * self.info.pitch) as usize) }
+    }
+    
+    /// Clip a rectangle to the screen, returning (x0, y0, x1, y1) exclusive
+    fn clip(&self, x: i32, y: i32, w: u32, h: u32) -> Option<(u32, u32, u32, u32)> {
+        let x0 = x.max(0) as i64;
+        let y0 = y.max(0) as i64;
+        let x1 = (x as i64 + w as i64).min(self.info.width as i64);
+        let y1 = (y as i64 + h as i64).min(self.info.height as i64);
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+        Some((x0 as u32, y0 as u32, x1 as u32, y1 as u32))
+    }
+    
     /// Set pixel at (x, y) with color
     pub fn set_pixel(&mut self, x: u32, y: u32, color: u32) {
         if !self.initialized || x >= self.info.width || y >= self.info.height {
@@ -226,6 +314,7 @@ impl VesaDriver {
         
         let offset = (y * self.info.pitch + x * self.info.bytes_per_pixel as u32) as usize;
         let pixel = self.color_to_pixel(color);
+        self.add_damage(x, y, x + 1, y + 1);
         
         unsafe {
             match self.info.bytes_per_pixel {
@@ -280,42 +369,35 @@ impl VesaDriver {
     
     /// Draw filled rectangle
     pub fn fill_rect(&mut self, x: i32, y: i32, w: u32, h: u32, color: u32) {
-        let x0 = x.max(0) as u32;
-        let y0 = y.max(0) as u32;
-        let x1 = ((x as u32) + w).min(self.info.width);
-        let y1 = ((y as u32) + h).min(self.info.height);
+        if !self.initialized {
+            return;
+        }
+        let (x0, y0, x1, y1) = match self.clip(x, y, w, h) {
+            Some(r) => r,
+            None => return,
+        };
+        
+        let pixel = self.color_to_pixel(color);
+        let bpp = self.info.bytes_per_pixel;
+        let count = (x1 - x0) as usize;
+        self.add_damage(x0, y0, x1, y1);
         
         for py in y0..y1 {
-            for px in x0..x1 {
-                self.set_pixel(px, py, color);
+            unsafe {
+                let row = self.row_ptr(py).add((x0 * bpp as u32) as usize);
+                fill_pixels(row, count, pixel, bpp);
             }
         }
     }
     
     /// Draw horizontal line
     pub fn hline(&mut self, x: i32, y: i32, w: u32, color: u32) {
-        if y < 0 || y >= self.info.height as i32 {
-            return;
-        }
-        let x0 = x.max(0) as u32;
-        let x1 = ((x as u32) + w).min(self.info.width);
-        
-        for px in x0..x1 {
-            self.set_pixel(px, y as u32, color);
-        }
+        self.fill_rect(x, y, w, 1, color);
     }
     
     /// Draw vertical line
     pub fn vline(&mut self, x: i32, y: i32, h: u32, color: u32) {
-        if x < 0 || x >= self.info.width as i32 {
-            return;
-        }
-        let y0 = y.max(0) as u32;
-        let y1 = ((y as u32) + h).min(self.info.height);
-        
-        for py in y0..y1 {
-            self.set_pixel(x as u32, py, color);
-        }
+        self.fill_rect(x, y, 1, h, color);
     }
     
     /// Draw rectangle outline
@@ -384,7 +466,7 @@ impl VesaDriver {
     pub fn fill_circle(&mut self, cx: i32, cy: i32, r: i32, color: u32) {
         for dy in -r..=r {
             let dx = integer_sqrt(r * r - dy * dy);
-            self.draw_line(cx - dx, cy + dy, cx + dx, cy + dy, color);
+            self.hline(cx - dx, cy + dy, (2 * dx + 1) as u32, color);
         }
     }
     
@@ -413,7 +495,7 @@ impl VesaDriver {
                 let x_b = interpolate(y, v1.1, v3.1, v1.0, v3.0);
                 (x_a.min(x_b), x_a.max(x_b))
             };
-            self.draw_line(x_start, y, x_end, y, color);
+            self.hline(x_start, y, (x_end - x_start + 1) as u32, color);
         }
     }
     
@@ -424,52 +506,199 @@ impl VesaDriver {
         self.draw_line(x3, y3, x1, y1, color);
     }
     
-    /// Draw character using 8x8 font
+    /// Draw character using the active font
     pub fn draw_char(&mut self, ch: char, x: i32, y: i32, color: u32, scale: u32) {
-        let bitmap = get_char_bitmap(ch);
-        for row in 0..8usize {
-            for col in 0..8usize {
-                if bitmap[row] & (1 << (7 - col)) != 0 {
-                    for sy in 0..scale {
-                        for sx in 0..scale {
-                            self.set_pixel(
-                                (x as u32) + (col as u32) * scale + sx,
-                                (y as u32) + (row as u32) * scale + sy,
-                                color
-                            );
-                        }
-                    }
-                }
-            }
-        }
+        let glyph = font::glyph(ch);
+        let s = scale as i32;
+        glyph.for_each_run(|gx, gy, len| {
+            self.fill_rect(x + gx as i32 * s, y + gy as i32 * s, len * scale, scale, color);
+        });
     }
     
     /// Draw text string
     pub fn draw_text(&mut self, text: &str, x: i32, y: i32, color: u32, scale: u32) {
-        let mut cx = x;
-        for ch in text.chars() {
-            self.draw_char(ch, cx, y, color, scale);
-            cx += (8 * scale) as i32;
+        self.draw_chars(text.chars(), x, y, color, scale);
+    }
+    
+    /// Draw a sequence of characters, starting a new line at each '\n'
+    pub fn draw_chars(&mut self, chars: impl Iterator<Item = char>, x: i32, y: i32, color: u32, scale: u32) {
+        let (cell_w, cell_h) = font::cell_size();
+        let (mut cx, mut cy) = (x, y);
+        for ch in chars {
+            if ch == '\n' {
+                cx = x;
+                cy += (cell_h * scale) as i32;
+                continue;
+            }
+            self.draw_char(ch, cx, cy, color, scale);
+            cx += (cell_w * scale) as i32;
+        }
+    }
+    
+    /// Scroll the band of scanlines `[y, y + h)` up by `dy` pixels,
+    /// filling the exposed rows at the bottom with `color`
+    pub fn scroll_up(&mut self, y: u32, h: u32, dy: u32, color: u32) {
+        if !self.initialized {
+            return;
         }
+        let y1 = (y + h).min(self.info.height);
+        if y >= y1 {
+            return;
+        }
+        let dy = dy.min(y1 - y);
+        let keep = y1 - y - dy;
+        
+        if keep > 0 {
+            self.add_damage(0, y, self.info.width, y + keep);
+            // Scanlines are contiguous, so the whole band moves in one copy
+            unsafe {
+                core::ptr::copy(
+                    self.row_ptr(y + dy),
+                    self.row_ptr(y),
+                    (keep * self.info.pitch) as usize,
+                );
+            }
+        }
+        self.fill_rect(0, (y + keep) as i32, self.info.width, dy, color);
     }
     
     /// Blit buffer to screen (for double buffering)
     pub fn blit(&mut self, buffer: &[u32], x: u32, y: u32, w: u32, h: u32) {
-        if !self.initialized {
+        self.blit_fast(buffer, w, x as i32, y as i32, w, h);
+    }
+    
+    /// Copy a `w`x`h` rectangle from `src` (row stride `src_stride` pixels)
+    /// to the screen at (x, y), clipped to the framebuffer
+    ///
+    /// `src` may start partway into a larger image, so its last row can
+    /// be short of the stride. In 32bpp modes each scanline is a single
+    /// bulk copy.
+    pub fn blit_fast(&mut self, src: &[u32], src_stride: u32, x: i32, y: i32, w: u32, h: u32) {
+        if !self.initialized || src_stride == 0 {
             return;
         }
+        let w = w.min(src_stride);
+        let rows = match src.len().checked_sub(w as usize) {
+            Some(rest) => (rest / src_stride as usize + 1) as u32,
+            None => return,
+        };
+        let (x0, y0, x1, y1) = match self.clip(x, y, w, h.min(rows)) {
+            Some(r) => r,
+            None => return,
+        };
+        let sx = (x0 as i64 - x as i64) as usize;
+        let count = (x1 - x0) as usize;
+        self.add_damage(x0, y0, x1, y1);
         
-        for row in 0..h {
-            for col in 0..w {
-                let src_idx = (row * w + col) as usize;
-                if src_idx < buffer.len() {
-                    self.set_pixel(x + col, y + row, buffer[src_idx]);
+        for py in y0..y1 {
+            let sy = (py as i64 - y as i64) as usize;
+            let src_row = &src[sy * src_stride as usize + sx..][..count];
+            
+            if self.info.bpp == 32 {
+                unsafe {
+                    let dst = self.row_ptr(py).add(x0 as usize * 4);
+                    simd::memcpy(dst, src_row.as_ptr() as *const u8, count * 4);
+                }
+            } else {
+                for (i, &c) in src_row.iter().enumerate() {
+                    self.set_pixel(x0 + i as u32, py, c);
                 }
             }
         }
     }
     
     /// Convert RGB color to pixel value
+    /// Get pixel at (x, y) as an opaque ARGB color
+    pub fn get_color(&self, x: u32, y: u32) -> u32 {
+        self.pixel_to_color(self.get_pixel(x, y))
+    }
+    
+    /// Blend an ARGB color over the pixel at (x, y)
+    pub fn blend_pixel(&mut self, x: u32, y: u32, color: u32) {
+        match color >> 24 {
+            0 => {}
+            0xFF => self.set_pixel(x, y, color),
+            _ => {
+                let dst = self.get_color(x, y);
+                self.set_pixel(x, y, colors::blend(dst, color));
+            }
+        }
+    }
+    
+    /// Fill a rectangle with a translucent ARGB color
+    pub fn fill_rect_alpha(&mut self, x: i32, y: i32, w: u32, h: u32, color: u32) {
+        match color >> 24 {
+            0 => return,
+            0xFF => return self.fill_rect(x, y, w, h, color),
+            _ => {}
+        }
+        if !self.initialized {
+            return;
+        }
+        let (x0, y0, x1, y1) = match self.clip(x, y, w, h) {
+            Some(r) => r,
+            None => return,
+        };
+        self.add_damage(x0, y0, x1, y1);
+        
+        for py in y0..y1 {
+            if self.info.bpp == 32 {
+                unsafe {
+                    let row = self.row_ptr(py) as *mut u32;
+                    for px in x0..x1 {
+                        let ptr = row.add(px as usize);
+                        write_volatile(ptr, colors::blend(read_volatile(ptr), color));
+                    }
+                }
+            } else {
+                for px in x0..x1 {
+                    self.blend_pixel(px, py, color);
+                }
+            }
+        }
+    }
+    
+    /// Like `blit_fast`, but blends each source pixel by its alpha channel
+    pub fn blit_with_alpha(&mut self, src: &[u32], src_stride: u32, x: i32, y: i32, w: u32, h: u32) {
+        if !self.initialized || src_stride == 0 {
+            return;
+        }
+        let w = w.min(src_stride);
+        let rows = match src.len().checked_sub(w as usize) {
+            Some(rest) => (rest / src_stride as usize + 1) as u32,
+            None => return,
+        };
+        let (x0, y0, x1, y1) = match self.clip(x, y, w, h.min(rows)) {
+            Some(r) => r,
+            None => return,
+        };
+        let sx = (x0 as i64 - x as i64) as usize;
+        let count = (x1 - x0) as usize;
+        self.add_damage(x0, y0, x1, y1);
+        
+        for py in y0..y1 {
+            let sy = (py as i64 - y as i64) as usize;
+            let src_row = &src[sy * src_stride as usize + sx..][..count];
+            
+            if self.info.bpp == 32 {
+                unsafe {
+                    let dst = (self.row_ptr(py) as *mut u32).add(x0 as usize);
+                    for (i, &c) in src_row.iter().enumerate() {
+                        match c >> 24 {
+                            0 => {}
+                            0xFF => write_volatile(dst.add(i), c),
+                            _ => write_volatile(dst.add(i), colors::blend(read_volatile(dst.add(i)), c)),
+                        }
+                    }
+                }
+            } else {
+                for (i, &c) in src_row.iter().enumerate() {
+                    self.blend_pixel(x0 + i as u32, py, c);
+                }
+            }
+        }
+    }
+    
     fn color_to_pixel(&self, color: u32) -> u32 {
         match self.info.bpp {
             32 => color,
@@ -483,6 +712,100 @@ impl VesaDriver {
             _ => color,
         }
     }
+    
+    /// Convert a raw framebuffer pixel back to an opaque ARGB color
+    fn pixel_to_color(&self, pixel: u32) -> u32 {
+        match self.info.bpp {
+            16 => {
+                let r = (pixel >> 11) & 0x1F;
+                let g = (pixel >> 5) & 0x3F;
+                let b = pixel & 0x1F;
+                0xFF000000 | ((r << 3 | r >> 2) << 16) | ((g << 2 | g >> 4) << 8) | (b << 3 | b >> 2)
+            }
+            _ => 0xFF000000 | pixel,
+        }
+    }
+}
+
+/// Fill `count` pixels starting at `dst` with an already-converted pixel value
+///
+/// 32bpp and 16bpp spans are written as aligned 64-bit stores, unrolled
+/// four at a time; a byte-uniform pattern (black, white, grays) uses
+/// `write_bytes` directly.
+unsafe fn fill_pixels(dst: *mut u8, count: usize, pixel: u32, bytes_per_pixel: u8) {
+    match bytes_per_pixel {
+        4 => {
+            let bytes = pixel.to_le_bytes();
+            if bytes.iter().all(|&b| b == bytes[0]) {
+                core::ptr::write_bytes(dst, bytes[0], count * 4);
+                return;
+            }
+            
+            let mut p = dst as *mut u32;
+            let mut n = count;
+            
+            // Align to 8 bytes
+            if (p as usize) & 7 != 0 && n > 0 {
+                write_volatile(p, pixel);
+                p = p.add(1);
+                n -= 1;
+            }
+            
+            let wide = (pixel as u64) | ((pixel as u64) << 32);
+            let mut q = p as *mut u64;
+            let mut pairs = n / 2;
+            while pairs >= 4 {
+                write_volatile(q, wide);
+                write_volatile(q.add(1), wide);
+                write_volatile(q.add(2), wide);
+                write_volatile(q.add(3), wide);
+                q = q.add(4);
+                pairs -= 4;
+            }
+            while pairs > 0 {
+                write_volatile(q, wide);
+                q = q.add(1);
+                pairs -= 1;
+            }
+            if n % 2 == 1 {
+                write_volatile(q as *mut u32, pixel);
+            }
+        }
+        2 => {
+            let half = pixel as u16;
+            let mut p = dst as *mut u16;
+            let mut n = count;
+            while (p as usize) & 7 != 0 && n > 0 {
+                write_volatile(p, half);
+                p = p.add(1);
+                n -= 1;
+            }
+            let h = half as u64;
+            let wide = h | (h << 16) | (h << 32) | (h << 48);
+            let mut q = p as *mut u64;
+            while n >= 4 {
+                write_volatile(q, wide);
+                q = q.add(1);
+                n -= 4;
+            }
+            let mut p = q as *mut u16;
+            while n > 0 {
+                write_volatile(p, half);
+                p = p.add(1);
+                n -= 1;
+            }
+        }
+        3 => {
+            let b = pixel.to_le_bytes();
+            for i in 0..count {
+                let px = dst.add(i * 3);
+                write_volatile(px, b[0]);
+                write_volatile(px.add(1), b[1]);
+                write_volatile(px.add(2), b[2]);
+            }
+        }
+        _ => {}
+    }
 }
 
 /// Integer square root
@@ -500,51 +823,6 @@ fn integer_sqrt(n: i32) -> i32 {
     x as i32
 }
 
-/// Get 8x8 bitmap for character
-fn get_char_bitmap(ch: char) -> [u8; 8] {
-    match ch {
-        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-        '!' => [0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x18, 0x00],
-        '0' => [0x3c, 0x66, 0x6e, 0x76, 0x66, 0x66, 0x3c, 0x00],
-        '1' => [0x18, 0x18, 0x38, 0x18, 0x18, 0x18, 0x7e, 0x00],
-        '2' => [0x3c, 0x66, 0x06, 0x0c, 0x30, 0x60, 0x7e, 0x00],
-        '3' => [0x3c, 0x66, 0x06, 0x1c, 0x06, 0x66, 0x3c, 0x00],
-        '4' => [0x06, 0x0e, 0x1e, 0x66, 0x7f, 0x06, 0x06, 0x00],
-        '5' => [0x7e, 0x60, 0x7c, 0x06, 0x06, 0x66, 0x3c, 0x00],
-        '6' => [0x3c, 0x66, 0x60, 0x7c, 0x66, 0x66, 0x3c, 0x00],
-        '7' => [0x7e, 0x66, 0x0c, 0x18, 0x18, 0x18, 0x18, 0x00],
-        '8' => [0x3c, 0x66, 0x66, 0x3c, 0x66, 0x66, 0x3c, 0x00],
-        '9' => [0x3c, 0x66, 0x66, 0x3e, 0x06, 0x66, 0x3c, 0x00],
-        'A' => [0x18, 0x3c, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x00],
-        'B' => [0x7c, 0x66, 0x66, 0x7c, 0x66, 0x66, 0x7c, 0x00],
-        'C' => [0x3c, 0x66, 0x60, 0x60, 0x60, 0x66, 0x3c, 0x00],
-        'D' => [0x78, 0x6c, 0x66, 0x66, 0x66, 0x6c, 0x78, 0x00],
-        'E' => [0x7e, 0x60, 0x60, 0x78, 0x60, 0x60, 0x7e, 0x00],
-        'F' => [0x7e, 0x60, 0x60, 0x78, 0x60, 0x60, 0x60, 0x00],
-        'G' => [0x3c, 0x66, 0x60, 0x6e, 0x66, 0x66, 0x3c, 0x00],
-        'H' => [0x66, 0x66, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x00],
-        'I' => [0x3c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x00],
-        'J' => [0x1e, 0x0c, 0x0c, 0x0c, 0x0c, 0x6c, 0x38, 0x00],
-        'K' => [0x66, 0x6c, 0x78, 0x70, 0x78, 0x6c, 0x66, 0x00],
-        'L' => [0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x7e, 0x00],
-        'M' => [0x63, 0x77, 0x7f, 0x6b, 0x63, 0x63, 0x63, 0x00],
-        'N' => [0x66, 0x76, 0x7e, 0x7e, 0x6e, 0x66, 0x66, 0x00],
-        'O' => [0x3c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x00],
-        'P' => [0x7c, 0x66, 0x66, 0x7c, 0x60, 0x60, 0x60, 0x00],
-        'Q' => [0x3c, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x0e, 0x00],
-        'R' => [0x7c, 0x66, 0x66, 0x7c, 0x78, 0x6c, 0x66, 0x00],
-        'S' => [0x3c, 0x66, 0x60, 0x3c, 0x06, 0x66, 0x3c, 0x00],
-        'T' => [0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00],
-        'U' => [0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x00],
-        'V' => [0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x18, 0x00],
-        'W' => [0x63, 0x63, 0x63, 0x6b, 0x7f, 0x77, 0x63, 0x00],
-        'X' => [0x66, 0x66, 0x3c, 0x18, 0x3c, 0x66, 0x66, 0x00],
-        'Y' => [0x66, 0x66, 0x66, 0x3c, 0x18, 0x18, 0x18, 0x00],
-        'Z' => [0x7e, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x7e, 0x00],
-        _ => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-    }
-}
-
 /// Global VESA driver
 lazy_static! {
     static ref VESA_DRIVER: Mutex<VesaDriver> = Mutex::new(VesaDriver::new());
@@ -560,6 +838,104 @@ pub fn init_with_virt_addr(width: u32, height: u32, bpp: u8, phys_addr: u64, vir
     VESA_DRIVER.lock().init_with_virt_addr(width, height, bpp, phys_addr, virt_addr);
 }
 
+/// The modes GOP offered at boot
+static BOOT_MODES: Mutex<Vec<VideoMode>> = Mutex::new(Vec::new());
+/// The display's EDID, as the bootloader found it
+static EDID: Mutex<Option<edid::Edid>> = Mutex::new(None);
+
+/// Keep what the bootloader found out about the display; needs the heap
+pub fn keep_boot_modes(modes: &[VideoMode], edid_data: Option<&[u8]>) {
+    *BOOT_MODES.lock() = modes.to_vec();
+    let parsed = edid_data.and_then(edid::parse);
+    if edid_data.is_some() && parsed.is_none() {
+        debug!("vesa", "The EDID from the firmware does not parse");
+    }
+    *EDID.lock() = parsed;
+    info!("vesa", "GOP offered {} modes", modes.len());
+}
+
+/// The modes GOP offered at boot
+pub fn boot_modes() -> Vec<VideoMode> {
+    BOOT_MODES.lock().clone()
+}
+
+/// What the display said about itself, if the firmware passed it on
+pub fn edid() -> Option<edid::Edid> {
+    EDID.lock().clone()
+}
+
+/// Name of the adapter interface that can change modes, if any
+pub fn mode_setter() -> Option<&'static str> {
+    if virtio_gpu::is_active() {
+        Some("virtio-gpu")
+    } else if dispi::is_present() {
+        Some("Bochs VBE (dispi)")
+    } else if svga::is_present() {
+        Some("VMware SVGA II")
+    } else {
+        None
+    }
+}
+
+/// Standard resolutions the adapter and framebuffer window can hold at `bpp`
+pub fn available_modes(bpp: u8) -> Vec<(u32, u32)> {
+    let (max_w, max_h) = if virtio_gpu::is_active() {
+        if bpp != 32 {
+            return Vec::new();
+        }
+        virtio_gpu::MAX_RESOLUTION
+    } else if dispi::is_present() {
+        dispi::max_resolution()
+    } else if svga::is_present() {
+        svga::max_resolution()
+    } else {
+        return Vec::new();
+    };
+    let bytes = ((bpp as u32 + 7) / 8) as usize;
+    STANDARD_MODES.iter()
+        .copied()
+        .filter(|&(w, h)| w <= max_w && h <= max_h)
+        .filter(|&(w, h)| w as usize * h as usize * bytes <= FB_WINDOW_SIZE)
+        .collect()
+}
+
+/// Change resolution and depth at runtime
+///
+/// The screen is cleared to black. Anything sized to the old mode (the
+/// compositor back buffer, the framebuffer console) must be recreated by
+/// the caller; `graphics::set_mode` does that.
+pub fn set_mode(width: u32, height: u32, bpp: u8) -> DriverResult<()> {
+    if width == 0 || height == 0 || !matches!(bpp, 16 | 24 | 32) {
+        return Err(DriverError::Unsupported);
+    }
+    let mut driver = VESA_DRIVER.lock();
+    if !driver.is_initialized() {
+        return Err(DriverError::NotFound);
+    }
+    let needed = width as usize * height as usize * ((bpp as usize + 7) / 8);
+    if needed > FB_WINDOW_SIZE {
+        return Err(DriverError::Unsupported);
+    }
+
+    let phys_addr = driver.info.phys_addr;
+    let pitch = if virtio_gpu::is_active() {
+        if bpp != 32 {
+            return Err(DriverError::Unsupported);
+        }
+        virtio_gpu::set_mode(width, height)?
+    } else if dispi::is_present() {
+        dispi::set_mode(width, height, bpp)?
+    } else if svga::is_present() {
+        svga::set_mode(width, height, bpp, phys_addr)?
+    } else {
+        return Err(DriverError::NotFound);
+    };
+
+    driver.set_geometry(width, height, bpp, pitch, phys_addr);
+    driver.clear(0);
+    Ok(())
+}
+
 /// Get driver instance
 pub fn driver() -> &'static Mutex<VesaDriver> {
     &VESA_DRIVER
@@ -637,9 +1013,14 @@ pub fn print_info() {
         println!("  Pitch: {} bytes", info.pitch);
         println!("  Physical address: 0x{:016x}", info.phys_addr);
         println!("  Size: {} KB", info.size / 1024);
+        println!("  Mode setting: {}", mode_setter().unwrap_or("unavailable (GOP mode only)"));
     } else {
         println!("VESA driver not initialized");
     }
+    drop(driver);
+    if virtio_gpu::is_active() {
+        virtio_gpu::print_info();
+    }
 }
 
 /// Color utilities
@@ -648,6 +1029,39 @@ pub mod colors {
         0xFF000000 | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
     }
     
+    pub const fn argb(a: u8, r: u8, g: u8, b: u8) -> u32 {
+        ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+    }
+    
+    /// Replace the alpha channel of `color`
+    pub const fn with_alpha(color: u32, a: u8) -> u32 {
+        (color & 0x00FFFFFF) | ((a as u32) << 24)
+    }
+    
+    /// Composite `src` over `dst` ("source over"), both 0xAARRGGBB
+    ///
+    /// Red/blue and alpha/green are processed as pairs of 16-bit lanes,
+    /// dividing by 255 to within one step of exact rounding.
+    #[inline]
+    pub fn blend(dst: u32, src: u32) -> u32 {
+        let a = src >> 24;
+        if a == 0xFF {
+            return src;
+        }
+        if a == 0 {
+            return dst;
+        }
+        let inv = 255 - a;
+        
+        let div255 = |x: u32| ((x + 0x0080_0080 + ((x >> 8) & 0x00FF_00FF)) >> 8) & 0x00FF_00FF;
+        
+        let rb = div255((src & 0x00FF_00FF) * a + (dst & 0x00FF_00FF) * inv);
+        // Source alpha contributes 255 * a to the alpha lane
+        let ag = div255((((src >> 8) & 0x0000_00FF) | 0x00FF_0000) * a + ((dst >> 8) & 0x00FF_00FF) * inv);
+        
+        rb | (ag << 8)
+    }
+    
     pub const BLACK: u32 = 0xFF000000;
     pub const WHITE: u32 = 0xFFFFFFFF;
     pub const RED: u32 = 0xFFFF0000;
@@ -660,3 +1074,55 @@ pub mod colors {
     pub const DARK_GRAY: u32 = 0xFF404040;
     pub const LIGHT_GRAY: u32 = 0xFFC0C0C0;
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    /// A driver drawing into `memory`, a `w`x`h` 32bpp screen
+    fn offscreen(memory: &mut [u32], w: u32, h: u32) -> VesaDriver {
+        let mut driver = VesaDriver::new();
+        driver.init_with_virt_addr(w, h, 32, 0, memory.as_mut_ptr() as u64);
+        driver
+    }
+
+    #[kernel_test]
+    fn blit_takes_a_rectangle_out_of_a_larger_image() -> Result<(), String> {
+        let mut memory = vec![0u32; 8 * 4];
+        let mut driver = offscreen(&mut memory, 8, 4);
+        let image: Vec<u32> = (0..8 * 4).collect();
+        // The bottom-right 3x2 of the image, to the same place on screen
+        let start = 2 * 8 + 5;
+        driver.blit_fast(&image[start..], 8, 5, 2, 3, 2);
+        check_eq!(driver.get_pixel(5, 2), 21);
+        check_eq!(driver.get_pixel(7, 3), 31);
+        check_eq!(driver.get_pixel(4, 3), 0);
+        check_eq!(driver.take_damage(), Some((0, 0, 8, 4)));
+        // Clipped at the screen's edges
+        driver.blit_fast(&image, 8, -6, 3, 8, 4);
+        check_eq!(driver.get_pixel(0, 3), 6);
+        check_eq!(driver.get_pixel(1, 3), 7);
+        check_eq!(driver.get_pixel(2, 3), 0);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn translucent_drawing_blends_with_the_screen() -> Result<(), String> {
+        let mut memory = vec![0u32; 4 * 2];
+        let mut driver = offscreen(&mut memory, 4, 2);
+        driver.fill_rect(0, 0, 4, 2, colors::WHITE);
+        driver.fill_rect_alpha(0, 0, 2, 2, colors::argb(0x80, 0, 0, 0));
+        check_eq!(driver.get_color(0, 0), 0xFF7F7F7F);
+        check_eq!(driver.get_color(2, 0), colors::WHITE);
+        // Clear pixels leave the screen alone, opaque ones replace it
+        driver.blit_with_alpha(&[0, colors::RED, colors::argb(0x80, 0, 0, 0xFF), 0], 4, 0, 1, 4, 1);
+        check_eq!(driver.get_color(0, 1), 0xFF7F7F7F);
+        check_eq!(driver.get_color(1, 1), colors::RED);
+        check_eq!(driver.get_color(2, 1), 0xFF7F7FFF);
+        check_eq!(driver.get_color(3, 1), colors::WHITE);
+        Ok(())
+    }
+}
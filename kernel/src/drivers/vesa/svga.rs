@@ -0,0 +1,123 @@
+//! VMware SVGA II mode setting
+//!
+//! Only the register interface is used: the mode is programmed through
+//! the index/value port pair in BAR0 and the adapter keeps scanning out
+//! the framebuffer in BAR1. The command FIFO is left unconfigured, so the
+//! device treats the whole framebuffer as dirty and refreshes it itself.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use crate::drivers::{DriverError, DriverResult};
+use crate::drivers::input::{inl, outl};
+use crate::drivers::pci;
+
+const PCI_VENDOR_VMWARE: u16 = 0x15AD;
+const PCI_DEVICE_SVGA2: u16 = 0x0405;
+
+// Port offsets from the BAR0 I/O base
+const INDEX_PORT: u16 = 0;
+const VALUE_PORT: u16 = 1;
+
+// Registers
+const REG_ID: u32 = 0;
+const REG_ENABLE: u32 = 1;
+const REG_WIDTH: u32 = 2;
+const REG_HEIGHT: u32 = 3;
+const REG_MAX_WIDTH: u32 = 4;
+const REG_MAX_HEIGHT: u32 = 5;
+const REG_BITS_PER_PIXEL: u32 = 7;
+const REG_BYTES_PER_LINE: u32 = 12;
+const REG_FB_START: u32 = 13;
+const REG_FB_OFFSET: u32 = 14;
+const REG_VRAM_SIZE: u32 = 15;
+
+const SVGA_ID_2: u32 = 0x9000_0002;
+
+static PROBED: AtomicBool = AtomicBool::new(false);
+/// I/O base of the negotiated adapter, 0 if there is none
+static IO_BASE: AtomicU16 = AtomicU16::new(0);
+
+fn read(base: u16, index: u32) -> u32 {
+    unsafe {
+        outl(base + INDEX_PORT, index);
+        inl(base + VALUE_PORT)
+    }
+}
+
+fn write(base: u16, index: u32, value: u32) {
+    unsafe {
+        outl(base + INDEX_PORT, index);
+        outl(base + VALUE_PORT, value);
+    }
+}
+
+/// Find the adapter and negotiate the SVGA II interface, once
+fn io_base() -> Option<u16> {
+    if !PROBED.swap(true, Ordering::AcqRel) {
+        if let Some(dev) = pci::find_device_by_id(PCI_VENDOR_VMWARE, PCI_DEVICE_SVGA2) {
+            let bar0 = dev.bars[0];
+            if bar0 & 1 != 0 {
+                let base = (bar0 & !0x3) as u16;
+                write(base, REG_ID, SVGA_ID_2);
+                if read(base, REG_ID) == SVGA_ID_2 {
+                    IO_BASE.store(base, Ordering::Release);
+                }
+            }
+        }
+    }
+    match IO_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(base),
+    }
+}
+
+/// Check for an SVGA II adapter
+pub fn is_present() -> bool {
+    io_base().is_some()
+}
+
+/// Largest supported resolution
+pub fn max_resolution() -> (u32, u32) {
+    match io_base() {
+        Some(base) => (read(base, REG_MAX_WIDTH), read(base, REG_MAX_HEIGHT)),
+        None => (0, 0),
+    }
+}
+
+/// Program a mode, returning its pitch in bytes
+///
+/// `fb_phys` is where the current framebuffer is mapped; the mode is
+/// refused if the adapter would scan out from anywhere else.
+pub fn set_mode(width: u32, height: u32, bpp: u8, fb_phys: u64) -> DriverResult<u32> {
+    let base = io_base().ok_or(DriverError::NotFound)?;
+    let (max_w, max_h) = max_resolution();
+    if width > max_w || height > max_h {
+        return Err(DriverError::Unsupported);
+    }
+    if (width * height * ((bpp as u32 + 7) / 8)) as usize > read(base, REG_VRAM_SIZE) as usize {
+        return Err(DriverError::Unsupported);
+    }
+
+    let previous = (read(base, REG_WIDTH), read(base, REG_HEIGHT), read(base, REG_BITS_PER_PIXEL));
+    program(base, width, height, bpp as u32);
+
+    // The device ignores depths it cannot scan out and may move the
+    // framebuffer; put the old mode back rather than lose the screen
+    let scanout = read(base, REG_FB_START) as u64 + read(base, REG_FB_OFFSET) as u64;
+    if read(base, REG_WIDTH) != width
+        || read(base, REG_HEIGHT) != height
+        || read(base, REG_BITS_PER_PIXEL) != bpp as u32
+        || scanout != fb_phys
+    {
+        program(base, previous.0, previous.1, previous.2);
+        return Err(DriverError::Unsupported);
+    }
+    Ok(read(base, REG_BYTES_PER_LINE))
+}
+
+fn program(base: u16, width: u32, height: u32, bpp: u32) {
+    write(base, REG_WIDTH, width);
+    write(base, REG_HEIGHT, height);
+    write(base, REG_BITS_PER_PIXEL, bpp);
+    write(base, REG_ENABLE, 1);
+}
@@ -0,0 +1,863 @@
+//! VirtIO GPU driver (2D)
+//!
+//! Drives a virtio-gpu-pci device through the VirtIO 1.0 PCI transport.
+//! One host resource covers the display and is backed by a block of guest
+//! memory. The VESA driver draws into that memory as if it were a linear
+//! framebuffer; `present` uploads the damaged area with TRANSFER_TO_HOST_2D
+//! and shows it with RESOURCE_FLUSH, so the host only copies what changed.
+//! It is called after compositor flips and console output, and from the
+//! idle loops so nothing drawn stays unpresented.
+//!
+//! Host window resizes raise a display event in the device config space.
+//! `present` notices it and `poll_display_change` reports the new size.
+//!
+//! Further enabled scanouts (QEMU `max_outputs`) each get their own
+//! resource and backing, exposed through `extra_scanouts` for the
+//! compositor to draw into and `present_scanout` to upload.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::drivers::{DriverError, DriverResult};
+use crate::drivers::pci::{self, read_config8, read_config32, PciDevice};
+use crate::drivers::vesa;
+use crate::mm::{self, phys_to_virt};
+use crate::println;
+use webbos_shared::types::PhysAddr;
+use crate::{info, warn};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+const VIRTIO_GPU_DEVICE_ID: u16 = 0x1050;
+
+/// PCI capability describing a VirtIO structure
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+const PCI_STATUS_CAP_LIST: u32 = 1 << 20;
+
+// VirtIO structure types
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Common configuration offsets
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+// Device configuration offsets
+const GPU_EVENTS_READ: u64 = 0x00;
+const GPU_EVENTS_CLEAR: u64 = 0x04;
+const GPU_EVENT_DISPLAY: u32 = 1 << 0;
+
+// Device status flags
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// VIRTIO_F_VERSION_1, bit 0 of the second feature word
+const FEATURE_VERSION_1: u32 = 1 << 0;
+
+// Control commands
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+// Responses
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Matches the VESA driver's 0xAARRGGBB pixels in memory (alpha ignored)
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const MAX_SCANOUTS: usize = 16;
+
+/// Control queue length; only six descriptors are ever in use
+const QUEUE_SIZE: u16 = 16;
+
+// Queue page layout
+const AVAIL_OFFSET: u64 = 256;
+const USED_OFFSET: u64 = 512;
+
+// Descriptor chains: each is a request followed by its response
+const CHAIN_SYNC: u16 = 0;
+const CHAIN_TRANSFER: u16 = 2;
+const CHAIN_FLUSH: u16 = 4;
+
+/// Command page layout: (request offset, response offset, response size)
+const BUFFERS: [(u64, u64, u32); 3] = [
+    (0, 512, 1024),
+    (1536, 1600, 64),
+    (1664, 1728, 64),
+];
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Spins before a synchronous command is abandoned
+const COMMAND_TIMEOUT: u32 = 10_000_000;
+
+/// Mode used when the host reports no enabled scanout
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+/// Largest mode offered by `vesa::available_modes`
+pub const MAX_RESOLUTION: (u32, u32) = (3840, 2160);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CtrlHdr {
+    cmd_type: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+impl CtrlHdr {
+    fn new(cmd_type: u32) -> Self {
+        Self { cmd_type, ..Default::default() }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct DisplayOne {
+    r: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RespDisplayInfo {
+    hdr: CtrlHdr,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceCreate2d {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceUnref {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+/// Attach with a single entry; the backing is physically contiguous
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceAttachBacking {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    nr_entries: u32,
+    entry: MemEntry,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SetScanout {
+    hdr: CtrlHdr,
+    r: GpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceFlush {
+    hdr: CtrlHdr,
+    r: GpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct TransferToHost2d {
+    hdr: CtrlHdr,
+    r: GpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// Where the display's pixels live, for handing to the VESA driver
+#[derive(Debug, Clone, Copy)]
+pub struct Scanout {
+    pub width: u32,
+    pub height: u32,
+    pub phys_addr: u64,
+    pub virt_addr: u64,
+}
+
+/// A scanout other than the primary, with its own resource
+struct ExtraScanout {
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    backing_phys: u64,
+}
+
+/// Location of a VirtIO structure: (BAR, offset, length)
+type CapRegion = (u8, u32, u32);
+
+struct GpuDevice {
+    common: u64,
+    device_cfg: Option<u64>,
+    /// Notify register for the control queue
+    notify: u64,
+    queue_size: u16,
+    queue_virt: u64,
+    cmd_phys: u64,
+    cmd_virt: u64,
+    /// Next free slot in the available ring
+    avail_idx: u16,
+    /// Used ring entries consumed so far
+    used_idx: u16,
+    /// Chains submitted but not yet returned
+    in_flight: u16,
+    resource_id: u32,
+    next_resource_id: u32,
+    width: u32,
+    height: u32,
+    backing_phys: u64,
+    backing_size: usize,
+    extra: Vec<ExtraScanout>,
+    flushes: u64,
+    pixels_uploaded: u64,
+}
+
+static GPU: Mutex<Option<GpuDevice>> = Mutex::new(None);
+
+/// Set once a device is driving the display
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Set by the tick when the host reports a display change
+static DISPLAY_CHANGED: AtomicBool = AtomicBool::new(false);
+
+unsafe fn mmio_read<T: Copy>(addr: u64) -> T {
+    read_volatile(addr as *const T)
+}
+
+unsafe fn mmio_write<T>(addr: u64, value: T) {
+    write_volatile(addr as *mut T, value);
+}
+
+/// Physical address of a memory BAR, following 64-bit BARs into the next slot
+fn bar_address(dev: &PciDevice, bar: u8) -> Option<u64> {
+    let bar = bar as usize;
+    let low = *dev.bars.get(bar)?;
+    if low & 1 != 0 {
+        return None;
+    }
+    let mut addr = (low & 0xFFFF_FFF0) as u64;
+    if (low >> 1) & 0x3 == 0x2 {
+        addr |= (*dev.bars.get(bar + 1)? as u64) << 32;
+    }
+    Some(addr)
+}
+
+/// Map one VirtIO structure described by a capability
+fn map_region(dev: &PciDevice, (bar, offset, length): CapRegion) -> Option<u64> {
+    let base = bar_address(dev, bar)?;
+    mm::map_mmio(PhysAddr::new(base + offset as u64), length as usize).map(|v| v.as_u64())
+}
+
+impl GpuDevice {
+    /// Bring up the device and its control queue
+    fn new(dev: &PciDevice) -> DriverResult<Self> {
+        // Walk the capability list for the VirtIO structures
+        if dev.read_config(0x04) & PCI_STATUS_CAP_LIST == 0 {
+            return Err(DriverError::Unsupported);
+        }
+        let (mut common, mut notify, mut device_cfg) = (None, None, None);
+        let mut notify_multiplier = 0;
+        let mut ptr = read_config8(dev.bus, dev.device, dev.function, 0x34) & 0xFC;
+        while ptr != 0 {
+            let id = read_config8(dev.bus, dev.device, dev.function, ptr);
+            let next = read_config8(dev.bus, dev.device, dev.function, ptr + 1);
+            if id == PCI_CAP_ID_VENDOR {
+                let cfg_type = read_config8(dev.bus, dev.device, dev.function, ptr + 3);
+                let region = (
+                    read_config8(dev.bus, dev.device, dev.function, ptr + 4),
+                    read_config32(dev.bus, dev.device, dev.function, ptr + 8),
+                    read_config32(dev.bus, dev.device, dev.function, ptr + 12),
+                );
+                match cfg_type {
+                    CAP_COMMON_CFG if common.is_none() => common = Some(region),
+                    CAP_NOTIFY_CFG if notify.is_none() => {
+                        notify = Some(region);
+                        notify_multiplier = read_config32(dev.bus, dev.device, dev.function, ptr + 16);
+                    }
+                    CAP_DEVICE_CFG if device_cfg.is_none() => device_cfg = Some(region),
+                    _ => {}
+                }
+            }
+            ptr = next & 0xFC;
+        }
+        let common = common.ok_or(DriverError::Unsupported)?;
+        let notify = notify.ok_or(DriverError::Unsupported)?;
+
+        // Memory decoding and bus mastering
+        let command = dev.read_config(0x04) & 0xFFFF;
+        dev.write_config(0x04, command | 0x6);
+
+        let common = map_region(dev, common).ok_or(DriverError::InitFailed)?;
+        let notify_base = map_region(dev, notify).ok_or(DriverError::InitFailed)?;
+        let device_cfg = device_cfg.and_then(|r| map_region(dev, r));
+
+        // Queue rings in one page, command buffers in the next
+        let dma = mm::alloc_dma_frames(2).ok_or(DriverError::InitFailed)?.as_u64();
+        let dma_virt = phys_to_virt(PhysAddr::new(dma)).as_u64();
+
+        let mut gpu = Self {
+            common,
+            device_cfg,
+            notify: 0,
+            queue_size: 0,
+            queue_virt: dma_virt,
+            cmd_phys: dma + 0x1000,
+            cmd_virt: dma_virt + 0x1000,
+            avail_idx: 0,
+            used_idx: 0,
+            in_flight: 0,
+            resource_id: 0,
+            next_resource_id: 1,
+            width: 0,
+            height: 0,
+            backing_phys: 0,
+            backing_size: 0,
+            extra: Vec::new(),
+            flushes: 0,
+            pixels_uploaded: 0,
+        };
+
+        unsafe {
+            // Reset, then announce ourselves
+            mmio_write::<u8>(common + COMMON_DEVICE_STATUS, 0);
+            while mmio_read::<u8>(common + COMMON_DEVICE_STATUS) != 0 {}
+            mmio_write::<u8>(common + COMMON_DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+            mmio_write::<u8>(common + COMMON_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+            // Only VERSION_1 is needed; no 3D, EDID or other extras
+            mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+            if mmio_read::<u32>(common + COMMON_DEVICE_FEATURE) & FEATURE_VERSION_1 == 0 {
+                gpu.fail();
+                return Err(DriverError::Unsupported);
+            }
+            mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+            mmio_write::<u32>(common + COMMON_DRIVER_FEATURE, 0);
+            mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+            mmio_write::<u32>(common + COMMON_DRIVER_FEATURE, FEATURE_VERSION_1);
+
+            let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+            mmio_write::<u8>(common + COMMON_DEVICE_STATUS, status);
+            if mmio_read::<u8>(common + COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+                gpu.fail();
+                return Err(DriverError::Unsupported);
+            }
+
+            // Control queue (queue 0)
+            mmio_write::<u16>(common + COMMON_QUEUE_SELECT, 0);
+            let max = mmio_read::<u16>(common + COMMON_QUEUE_SIZE);
+            if max == 0 {
+                gpu.fail();
+                return Err(DriverError::InitFailed);
+            }
+            gpu.queue_size = max.min(QUEUE_SIZE);
+            mmio_write::<u16>(common + COMMON_QUEUE_SIZE, gpu.queue_size);
+            write_u64(common + COMMON_QUEUE_DESC, dma);
+            write_u64(common + COMMON_QUEUE_DRIVER, dma + AVAIL_OFFSET);
+            write_u64(common + COMMON_QUEUE_DEVICE, dma + USED_OFFSET);
+            let notify_off = mmio_read::<u16>(common + COMMON_QUEUE_NOTIFY_OFF) as u64;
+            gpu.notify = notify_base + notify_off * notify_multiplier as u64;
+            mmio_write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+
+            mmio_write::<u8>(common + COMMON_DEVICE_STATUS, status | STATUS_DRIVER_OK);
+        }
+
+        gpu.init_descriptors();
+        Ok(gpu)
+    }
+
+    fn fail(&self) {
+        unsafe {
+            let status = mmio_read::<u8>(self.common + COMMON_DEVICE_STATUS);
+            mmio_write::<u8>(self.common + COMMON_DEVICE_STATUS, status | STATUS_FAILED);
+        }
+    }
+
+    /// Link the fixed request/response descriptor pairs
+    fn init_descriptors(&mut self) {
+        for (i, &(req, resp, resp_len)) in BUFFERS.iter().enumerate() {
+            let head = (i * 2) as u16;
+            self.write_desc(head, self.cmd_phys + req, 0, DESC_F_NEXT, head + 1);
+            self.write_desc(head + 1, self.cmd_phys + resp, resp_len, DESC_F_WRITE, 0);
+        }
+    }
+
+    fn write_desc(&mut self, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = self.queue_virt + index as u64 * 16;
+        unsafe {
+            mmio_write::<u64>(desc, addr);
+            mmio_write::<u32>(desc + 8, len);
+            mmio_write::<u16>(desc + 12, flags);
+            mmio_write::<u16>(desc + 14, next);
+        }
+    }
+
+    /// Copy a request into the buffer of chain `head`
+    fn write_request<T: Copy>(&mut self, head: u16, req: &T) {
+        let (req_off, resp_off, _) = BUFFERS[(head / 2) as usize];
+        unsafe {
+            mmio_write::<T>(self.cmd_virt + req_off, *req);
+            mmio_write::<u32>(self.cmd_virt + resp_off, 0);
+            mmio_write::<u32>(self.queue_virt + head as u64 * 16 + 8, size_of::<T>() as u32);
+        }
+    }
+
+    /// Put chain `head` on the available ring (without notifying)
+    fn push(&mut self, head: u16) {
+        let ring = self.queue_virt + AVAIL_OFFSET + 4;
+        unsafe {
+            mmio_write::<u16>(ring + (self.avail_idx % self.queue_size) as u64 * 2, head);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            mmio_write::<u16>(self.queue_virt + AVAIL_OFFSET + 2, self.avail_idx);
+        }
+        self.in_flight += 1;
+    }
+
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe {
+            mmio_write::<u16>(self.notify, 0);
+        }
+    }
+
+    /// Consume returned chains
+    fn reap(&mut self) {
+        let device_idx = unsafe { mmio_read::<u16>(self.queue_virt + USED_OFFSET + 2) };
+        while self.used_idx != device_idx && self.in_flight > 0 {
+            self.used_idx = self.used_idx.wrapping_add(1);
+            self.in_flight -= 1;
+        }
+    }
+
+    /// Wait for every submitted chain to come back
+    fn wait_idle(&mut self) -> DriverResult<()> {
+        let mut spins = 0;
+        loop {
+            self.reap();
+            if self.in_flight == 0 {
+                return Ok(());
+            }
+            spins += 1;
+            if spins >= COMMAND_TIMEOUT {
+                return Err(DriverError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Run one command to completion, returning the response type
+    fn command<T: Copy>(&mut self, req: &T) -> DriverResult<u32> {
+        self.wait_idle()?;
+        self.write_request(CHAIN_SYNC, req);
+        self.push(CHAIN_SYNC);
+        self.notify();
+        self.wait_idle()?;
+        Ok(unsafe { mmio_read::<u32>(self.cmd_virt + BUFFERS[0].1) })
+    }
+
+    /// Run a command that answers with RESP_OK_NODATA
+    fn command_ok<T: Copy>(&mut self, req: &T) -> DriverResult<()> {
+        match self.command(req)? {
+            RESP_OK_NODATA => Ok(()),
+            _ => Err(DriverError::IoError),
+        }
+    }
+
+    /// Size of every scanout the host has enabled
+    fn display_info(&mut self) -> DriverResult<[Option<(u32, u32)>; MAX_SCANOUTS]> {
+        if self.command(&CtrlHdr::new(CMD_GET_DISPLAY_INFO))? != RESP_OK_DISPLAY_INFO {
+            return Err(DriverError::IoError);
+        }
+        let info = unsafe { mmio_read::<RespDisplayInfo>(self.cmd_virt + BUFFERS[0].1) };
+        let mut modes = [None; MAX_SCANOUTS];
+        for (mode, m) in modes.iter_mut().zip(info.pmodes.iter()) {
+            if m.enabled != 0 && m.r.width != 0 && m.r.height != 0 {
+                *mode = Some((m.r.width, m.r.height));
+            }
+        }
+        Ok(modes)
+    }
+
+    /// Size of the first enabled scanout
+    fn preferred_mode(&mut self) -> DriverResult<Option<(u32, u32)>> {
+        Ok(self.display_info()?.iter().flatten().next().copied())
+    }
+
+    /// Give scanout `scanout_id` a resource of its own and scan it out
+    fn add_scanout(&mut self, scanout_id: u32, width: u32, height: u32) -> DriverResult<ExtraScanout> {
+        let size = width as usize * height as usize * 4;
+        let backing_phys = mm::alloc_dma_frames((size + 0xFFF) / 0x1000)
+            .ok_or(DriverError::InitFailed)?
+            .as_u64();
+        let id = self.next_resource_id;
+        self.next_resource_id += 1;
+
+        self.command_ok(&ResourceCreate2d {
+            hdr: CtrlHdr::new(CMD_RESOURCE_CREATE_2D),
+            resource_id: id,
+            format: FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        })?;
+        self.command_ok(&ResourceAttachBacking {
+            hdr: CtrlHdr::new(CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: id,
+            nr_entries: 1,
+            entry: MemEntry {
+                addr: backing_phys,
+                length: size as u32,
+                padding: 0,
+            },
+        })?;
+        self.command_ok(&SetScanout {
+            hdr: CtrlHdr::new(CMD_SET_SCANOUT),
+            r: GpuRect { x: 0, y: 0, width, height },
+            scanout_id,
+            resource_id: id,
+        })?;
+        Ok(ExtraScanout { scanout_id, resource_id: id, width, height, backing_phys })
+    }
+
+    /// Create a resource for a `width` x `height` display, back it with the
+    /// framebuffer memory and scan it out, replacing the current one
+    fn set_mode(&mut self, width: u32, height: u32) -> DriverResult<()> {
+        if width as usize * height as usize * 4 > self.backing_size {
+            return Err(DriverError::Unsupported);
+        }
+        let id = self.next_resource_id;
+        self.next_resource_id += 1;
+
+        self.command_ok(&ResourceCreate2d {
+            hdr: CtrlHdr::new(CMD_RESOURCE_CREATE_2D),
+            resource_id: id,
+            format: FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        })?;
+        self.command_ok(&ResourceAttachBacking {
+            hdr: CtrlHdr::new(CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: id,
+            nr_entries: 1,
+            entry: MemEntry {
+                addr: self.backing_phys,
+                length: self.backing_size as u32,
+                padding: 0,
+            },
+        })?;
+        self.command_ok(&SetScanout {
+            hdr: CtrlHdr::new(CMD_SET_SCANOUT),
+            r: GpuRect { x: 0, y: 0, width, height },
+            scanout_id: 0,
+            resource_id: id,
+        })?;
+
+        // The old resource is no longer scanned out; dropping it also
+        // detaches its (shared) backing
+        if self.resource_id != 0 {
+            let old = self.resource_id;
+            self.command_ok(&ResourceUnref {
+                hdr: CtrlHdr::new(CMD_RESOURCE_UNREF),
+                resource_id: old,
+                padding: 0,
+            })?;
+        }
+        self.resource_id = id;
+        self.width = width;
+        self.height = height;
+        Ok(())
+    }
+
+    /// Queue an upload and flush of a damaged rectangle without waiting
+    ///
+    /// `stride` is the resource width in pixels.
+    fn present(&mut self, resource_id: u32, stride: u32, x: u32, y: u32, width: u32, height: u32) {
+        let r = GpuRect { x, y, width, height };
+        self.write_request(CHAIN_TRANSFER, &TransferToHost2d {
+            hdr: CtrlHdr::new(CMD_TRANSFER_TO_HOST_2D),
+            r,
+            offset: (y as u64 * stride as u64 + x as u64) * 4,
+            resource_id,
+            padding: 0,
+        });
+        self.write_request(CHAIN_FLUSH, &ResourceFlush {
+            hdr: CtrlHdr::new(CMD_RESOURCE_FLUSH),
+            r,
+            resource_id,
+            padding: 0,
+        });
+        self.push(CHAIN_TRANSFER);
+        self.push(CHAIN_FLUSH);
+        self.notify();
+        self.flushes += 1;
+        self.pixels_uploaded += width as u64 * height as u64;
+    }
+
+    /// Check and acknowledge a host display change event
+    fn take_display_event(&mut self) -> bool {
+        let cfg = match self.device_cfg {
+            Some(cfg) => cfg,
+            None => return false,
+        };
+        unsafe {
+            if mmio_read::<u32>(cfg + GPU_EVENTS_READ) & GPU_EVENT_DISPLAY == 0 {
+                return false;
+            }
+            mmio_write::<u32>(cfg + GPU_EVENTS_CLEAR, GPU_EVENT_DISPLAY);
+        }
+        true
+    }
+}
+
+unsafe fn write_u64(addr: u64, value: u64) {
+    mmio_write::<u32>(addr, value as u32);
+    mmio_write::<u32>(addr + 4, (value >> 32) as u32);
+}
+
+/// Find and start a virtio-gpu device
+///
+/// On success the display is scanning out guest memory sized for
+/// `vesa::FB_WINDOW_SIZE`; pass the returned scanout to the VESA driver.
+pub fn init() -> Option<Scanout> {
+    let dev = pci::find_device_by_id(VIRTIO_VENDOR_ID, VIRTIO_GPU_DEVICE_ID)?;
+    // Its scanout goes back to `main` rather than through a registered
+    // driver, so it claims the device itself
+    if !pci::claim(&dev, "virtio-gpu") {
+        return None;
+    }
+    info!("virtio-gpu", "Found device at {:02x}:{:02x}.{}", dev.bus, dev.device, dev.function);
+
+    let mut gpu = match GpuDevice::new(&dev) {
+        Ok(gpu) => gpu,
+        Err(e) => {
+            warn!("virtio-gpu", "Device setup failed: {:?}", e);
+            return None;
+        }
+    };
+
+    let pages = vesa::FB_WINDOW_SIZE / 0x1000;
+    gpu.backing_phys = match mm::alloc_dma_frames(pages) {
+        Some(phys) => phys.as_u64(),
+        None => {
+            info!("virtio-gpu", "No contiguous memory for a {} KB framebuffer", vesa::FB_WINDOW_SIZE / 1024);
+            return None;
+        }
+    };
+    gpu.backing_size = vesa::FB_WINDOW_SIZE;
+
+    let fits = |&(w, h): &(u32, u32)| w as usize * h as usize * 4 <= vesa::FB_WINDOW_SIZE;
+    let (width, height) = match gpu.preferred_mode() {
+        Ok(mode) => mode.filter(fits).unwrap_or(DEFAULT_MODE),
+        Err(e) => {
+            warn!("virtio-gpu", "GET_DISPLAY_INFO failed: {:?}", e);
+            return None;
+        }
+    };
+    if let Err(e) = gpu.set_mode(width, height) {
+        warn!("virtio-gpu", "Failed to set up scanout {}x{}: {:?}", width, height, e);
+        return None;
+    }
+    info!("virtio-gpu", "Scanout 0: {}x{}, resource {}", width, height, gpu.resource_id);
+
+    if let Ok(modes) = gpu.display_info() {
+        for (id, mode) in modes.iter().enumerate().skip(1) {
+            let (w, h) = match *mode {
+                Some(mode) => mode,
+                None => continue,
+            };
+            match gpu.add_scanout(id as u32, w, h) {
+                Ok(extra) => {
+                    info!("virtio-gpu", "Scanout {}: {}x{}, resource {}", id, w, h, extra.resource_id);
+                    gpu.extra.push(extra);
+                }
+                Err(e) => warn!("virtio-gpu", "Scanout {} ({}x{}) not set up: {:?}", id, w, h, e),
+            }
+        }
+    }
+
+    let scanout = Scanout {
+        width,
+        height,
+        phys_addr: gpu.backing_phys,
+        virt_addr: phys_to_virt(PhysAddr::new(gpu.backing_phys)).as_u64(),
+    };
+    *GPU.lock() = Some(gpu);
+    ACTIVE.store(true, Ordering::Release);
+    Some(scanout)
+}
+
+/// Whether a virtio-gpu device is driving the display
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Switch the scanout to a new size (32bpp only), returning the pitch
+///
+/// The framebuffer memory is reused, so the VESA mapping stays valid.
+pub fn set_mode(width: u32, height: u32) -> DriverResult<u32> {
+    let mut guard = GPU.lock();
+    let gpu = guard.as_mut().ok_or(DriverError::NotFound)?;
+    gpu.set_mode(width, height)?;
+    Ok(width * 4)
+}
+
+/// Upload the damaged part of the framebuffer to the host display
+///
+/// Never blocks: if the device or framebuffer is busy, or the previous
+/// upload is still in flight, the damage waits for the next call.
+pub fn present() {
+    if !is_active() {
+        return;
+    }
+    let mut guard = match GPU.try_lock() {
+        Some(g) => g,
+        None => return,
+    };
+    let gpu = match guard.as_mut() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+    gpu.reap();
+    if gpu.in_flight > 0 {
+        return;
+    }
+    if gpu.take_display_event() {
+        DISPLAY_CHANGED.store(true, Ordering::Release);
+    }
+
+    let damage = match vesa::driver().try_lock() {
+        Some(mut driver) => driver.take_damage(),
+        None => return,
+    };
+    if let Some((x, y, w, h)) = damage {
+        // Damage from before a mode switch may exceed the new resource
+        let w = w.min(gpu.width.saturating_sub(x));
+        let h = h.min(gpu.height.saturating_sub(y));
+        if w > 0 && h > 0 {
+            let (id, stride) = (gpu.resource_id, gpu.width);
+            gpu.present(id, stride, x, y, w, h);
+        }
+    }
+}
+
+/// Scanouts beyond the primary, as (scanout id, memory to draw into)
+///
+/// Their pixels are 0xAARRGGBB with a pitch of `width * 4`.
+pub fn extra_scanouts() -> Vec<(u32, Scanout)> {
+    match GPU.lock().as_ref() {
+        Some(gpu) => gpu.extra.iter().map(|s| (s.scanout_id, Scanout {
+            width: s.width,
+            height: s.height,
+            phys_addr: s.backing_phys,
+            virt_addr: phys_to_virt(PhysAddr::new(s.backing_phys)).as_u64(),
+        })).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Upload a rectangle of an extra scanout to the host display
+///
+/// Waits for any earlier upload to finish, since all uploads share the
+/// same descriptor chains.
+pub fn present_scanout(scanout_id: u32, x: u32, y: u32, width: u32, height: u32) {
+    let mut guard = GPU.lock();
+    let gpu = match guard.as_mut() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+    let (resource_id, max_w, max_h) = match gpu.extra.iter().find(|s| s.scanout_id == scanout_id) {
+        Some(s) => (s.resource_id, s.width, s.height),
+        None => return,
+    };
+    let w = width.min(max_w.saturating_sub(x));
+    let h = height.min(max_h.saturating_sub(y));
+    if w == 0 || h == 0 || gpu.wait_idle().is_err() {
+        return;
+    }
+    gpu.present(resource_id, max_w, x, y, w, h);
+}
+
+/// New display size requested by the host, if it changed since last asked
+pub fn poll_display_change() -> Option<(u32, u32)> {
+    if !DISPLAY_CHANGED.swap(false, Ordering::AcqRel) {
+        return None;
+    }
+    let mut guard = GPU.lock();
+    let gpu = guard.as_mut()?;
+    let mode = gpu.preferred_mode().ok()??;
+    (mode != (gpu.width, gpu.height)).then_some(mode)
+}
+
+/// Print virtio-gpu state
+pub fn print_info() {
+    match GPU.lock().as_ref() {
+        Some(gpu) => {
+            println!("VirtIO GPU:");
+            println!("  Scanout: {}x{} (resource {})", gpu.width, gpu.height, gpu.resource_id);
+            for s in &gpu.extra {
+                println!("  Scanout {}: {}x{} (resource {})", s.scanout_id, s.width, s.height, s.resource_id);
+            }
+            println!("  Backing: {} KB at 0x{:x}", gpu.backing_size / 1024, gpu.backing_phys);
+            println!("  Flushes: {}, pixels uploaded: {}", gpu.flushes, gpu.pixels_uploaded);
+        }
+        None => println!("VirtIO GPU: not present"),
+    }
+}
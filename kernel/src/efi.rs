@@ -0,0 +1,240 @@
+//! EFI runtime services
+//!
+//! The firmware keeps a few services after boot, most usefully its
+//! variables: the boot order, whether Secure Boot is on, and whatever
+//! else the platform stores there. The bootloader passes the runtime
+//! services table. Nothing calls SetVirtualAddressMap, so the services
+//! run in physical mode: `init` maps the regions the memory map marks
+//! `EfiRuntime` at their own addresses first.
+//!
+//! The firmware is not reentrant, so calls go one at a time with
+//! interrupts off.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use webbos_shared::types::{MemoryRegion, MemoryRegionType, PhysAddr};
+
+use crate::arch::interrupts;
+use crate::mm;
+use crate::{info, print, println, warn};
+
+/// "RUNTSERV"
+const SIGNATURE: u64 = 0x5652_4553_544E_5552;
+
+const STATUS_ERROR: usize = 1 << 63;
+const BUFFER_TOO_SMALL: usize = STATUS_ERROR | 5;
+const NOT_FOUND: usize = STATUS_ERROR | 14;
+
+/// An EFI GUID, as the firmware lays it out
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl Guid {
+    /// The vendor of the variables the UEFI specification defines
+    pub const GLOBAL_VARIABLE: Guid = Guid {
+        data1: 0x8BE4_DF61,
+        data2: 0x93CA,
+        data3: 0x11D2,
+        data4: [0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C],
+    };
+
+    const ZERO: Guid = Guid { data1: 0, data2: 0, data3: 0, data4: [0; 8] };
+
+    /// `8be4df61-93ca-11d2-aa0d-00e098032b8c`
+    pub fn parse(text: &str) -> Option<Guid> {
+        let groups: Vec<&str> = text.split('-').collect();
+        if groups.len() != 5 || groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12]) {
+            return None;
+        }
+        let rest = u64::from_str_radix(&alloc::format!("{}{}", groups[3], groups[4]), 16).ok()?;
+        Some(Guid {
+            data1: u32::from_str_radix(groups[0], 16).ok()?,
+            data2: u16::from_str_radix(groups[1], 16).ok()?,
+            data3: u16::from_str_radix(groups[2], 16).ok()?,
+            data4: rest.to_be_bytes(),
+        })
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.data4;
+        write!(f, "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7])
+    }
+}
+
+/// EFI_RUNTIME_SERVICES, as far as it is used
+#[repr(C)]
+struct RuntimeServices {
+    signature: u64,
+    _header: [u8; 16],
+    _time: [usize; 4],
+    _virtual_memory: [usize; 2],
+    get_variable: unsafe extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> usize,
+    get_next_variable_name: unsafe extern "efiapi" fn(*mut usize, *mut u16, *mut Guid) -> usize,
+}
+
+static RUNTIME: Mutex<Option<&'static RuntimeServices>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfiError {
+    /// No runtime services: not booted by UEFI, or they could not be mapped
+    Unavailable,
+    NotFound,
+    /// Any other EFI status
+    Status(usize),
+}
+
+/// Map the runtime services and check their table; needs the heap
+pub fn init(runtime_services: Option<PhysAddr>, memory_map: &[MemoryRegion]) {
+    let addr = match runtime_services {
+        Some(addr) => addr,
+        None => {
+            warn!("efi", "No runtime services from the bootloader");
+            return;
+        }
+    };
+    for region in memory_map.iter().filter(|r| matches!(r.region_type, MemoryRegionType::EfiRuntime)) {
+        if !mm::map_identity(region.base, region.size.as_u64()) {
+            warn!("efi", "Cannot map the runtime region at {:?}; not using runtime services", region.base);
+            return;
+        }
+    }
+    if !mm::map_identity(addr, core::mem::size_of::<RuntimeServices>() as u64) {
+        warn!("efi", "Cannot map the runtime services table at {:?}", addr);
+        return;
+    }
+    let services = unsafe { &*(addr.as_u64() as *const RuntimeServices) };
+    if services.signature != SIGNATURE {
+        warn!("efi", "No runtime services table at {:?}", addr);
+        return;
+    }
+    *RUNTIME.lock() = Some(services);
+    info!("efi", "Runtime services at {:?}", addr);
+}
+
+/// Call into the firmware, alone and with interrupts off
+fn call(f: impl FnOnce(&RuntimeServices) -> usize) -> Result<(), EfiError> {
+    let runtime = RUNTIME.lock();
+    let services = runtime.ok_or(EfiError::Unavailable)?;
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let status = f(services);
+    if enabled {
+        interrupts::enable();
+    }
+    match status {
+        0 => Ok(()),
+        NOT_FOUND => Err(EfiError::NotFound),
+        status => Err(EfiError::Status(status)),
+    }
+}
+
+/// A variable's attributes and contents
+pub fn variable(name: &str, vendor: &Guid) -> Result<(u32, Vec<u8>), EfiError> {
+    let name: Vec<u16> = name.encode_utf16().chain(core::iter::once(0)).collect();
+    let mut attributes = 0;
+    let mut data = Vec::new();
+    loop {
+        let mut size = data.len();
+        let result = call(|services| unsafe {
+            (services.get_variable)(name.as_ptr(), vendor, &mut attributes, &mut size, data.as_mut_ptr())
+        });
+        match result {
+            Ok(()) => {
+                data.truncate(size);
+                return Ok((attributes, data));
+            }
+            Err(EfiError::Status(BUFFER_TOO_SMALL)) if size > data.len() => data.resize(size, 0),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The name and vendor of every variable the firmware has
+pub fn variables() -> Result<Vec<(String, Guid)>, EfiError> {
+    let mut found = Vec::new();
+    // Starts empty; each call replaces it with the next name
+    let mut name = alloc::vec![0u16; 128];
+    let mut vendor = Guid::ZERO;
+    loop {
+        let mut size = name.len() * 2;
+        let result = call(|services| unsafe {
+            (services.get_next_variable_name)(&mut size, name.as_mut_ptr(), &mut vendor)
+        });
+        match result {
+            Ok(()) => {
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                found.push((String::from_utf16_lossy(&name[..len]), vendor));
+            }
+            Err(EfiError::Status(BUFFER_TOO_SMALL)) => name.resize(size.div_ceil(2), 0),
+            Err(EfiError::NotFound) => return Ok(found),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// `efivar`: list the variables, or show one given as `Name` (a global
+/// variable) or `Name-GUID`
+pub fn print_variable(spec: Option<&str>) {
+    let spec = match spec {
+        Some(spec) => spec,
+        None => {
+            match variables() {
+                Ok(list) => {
+                    for (name, vendor) in &list {
+                        println!("{}-{}", name, vendor);
+                    }
+                    println!("{} variables", list.len());
+                }
+                Err(e) => println!("efivar: {:?}", e),
+            }
+            return;
+        }
+    };
+
+    // A GUID is 36 characters with the four dashes
+    let split = spec.len().checked_sub(37)
+        .filter(|&at| spec.as_bytes()[at] == b'-')
+        .and_then(|at| Some((&spec[..at], Guid::parse(spec.get(at + 1..)?)?)));
+    let (name, vendor) = split.unwrap_or((spec, Guid::GLOBAL_VARIABLE));
+    match variable(name, &vendor) {
+        Ok((attributes, data)) => {
+            println!("{}-{}: {} bytes, attributes {:#x}", name, vendor, data.len(), attributes);
+            for (i, row) in data.chunks(16).enumerate() {
+                print!("{:08x} ", i * 16);
+                for byte in row {
+                    print!(" {:02x}", byte);
+                }
+                println!();
+            }
+        }
+        Err(e) => println!("efivar: {}-{}: {:?}", name, vendor, e),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn guids_round_trip() -> Result<(), String> {
+        let text = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+        let guid = Guid::parse(text).ok_or("did not parse")?;
+        check!(guid == Guid::GLOBAL_VARIABLE);
+        check_eq!(alloc::format!("{}", guid), text);
+        check!(Guid::parse("8be4df61-93ca-11d2-aa0d").is_none());
+        check!(Guid::parse("8be4df6x-93ca-11d2-aa0d-00e098032b8c").is_none());
+        Ok(())
+    }
+}
@@ -11,6 +11,7 @@ use spin::Mutex;
 use crate::fs::{FileSystem, FileType, Metadata, Permissions, INode, FsResult, FsError};
 use crate::storage::{BlockDevice, StorageError};
 use crate::println;
+use crate::info;
 
 /// EXT2 superblock (located at offset 1024)
 #[repr(C)]
@@ -57,6 +58,47 @@ pub struct Superblock {
 /// EXT2 magic number
 const EXT2_MAGIC: u16 = 0xEF53;
 
+/// Largest block size, as `log_block_size`: 64KB
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+impl Superblock {
+    /// Parse and check a superblock
+    pub fn parse(data: &[u8; 1024]) -> FsResult<Self> {
+        // Every field is an integer, so any bytes make a superblock
+        let superblock = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const Superblock) };
+
+        // Verify magic number
+        if superblock.magic != EXT2_MAGIC {
+            return Err(FsError::InvalidFilesystem);
+        }
+        // Geometry the rest of the driver divides by and shifts by
+        if superblock.log_block_size > MAX_LOG_BLOCK_SIZE
+            || superblock.blocks_count == 0
+            || superblock.inodes_count == 0
+            || superblock.blocks_per_group == 0
+            || superblock.inodes_per_group == 0
+        {
+            return Err(FsError::InvalidFilesystem);
+        }
+        if superblock.rev_level >= 1 {
+            let inode_size = superblock.inode_size as u32;
+            if !inode_size.is_power_of_two() || inode_size < 128 || inode_size > superblock.block_size() {
+                return Err(FsError::InvalidFilesystem);
+            }
+        }
+        Ok(superblock)
+    }
+
+    pub fn block_size(&self) -> u32 {
+        1024 << self.log_block_size
+    }
+
+    /// Block groups the blocks are split into
+    pub fn groups_count(&self) -> u32 {
+        self.blocks_count.div_ceil(self.blocks_per_group)
+    }
+}
+
 /// Block group descriptor
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -134,6 +176,7 @@ const S_IXGRP: u16 = 0x0008;  // Group execute
 const S_IROTH: u16 = 0x0004;  // Other read
 const S_IWOTH: u16 = 0x0002;  // Other write
 const S_IXOTH: u16 = 0x0001;  // Other execute
+const S_ISVTX: u16 = 0x0200;  // Sticky
 
 /// EXT2 filesystem instance
 pub struct Ext2Fs {
@@ -152,20 +195,17 @@ impl Ext2Fs {
         device.read_blocks(2, 2, &mut superblock_data)
             .map_err(|_| FsError::IoError)?;
 
-        let superblock = unsafe {
-            core::ptr::read(superblock_data.as_ptr() as *const Superblock)
-        };
-
-        // Verify magic number
-        if superblock.magic != EXT2_MAGIC {
+        let superblock = Superblock::parse(&superblock_data)?;
+        let block_size = superblock.block_size();
+        let groups_count = superblock.groups_count();
+        let device_bytes = device.block_count() * device.block_size() as u64;
+        if (block_size as usize) < device.block_size()
+            || superblock.blocks_count as u64 * block_size as u64 > device_bytes
+        {
             return Err(FsError::InvalidFilesystem);
         }
 
-        let block_size = 1024 << superblock.log_block_size;
-        let blocks_per_group = superblock.blocks_per_group;
-        let groups_count = (superblock.blocks_count + blocks_per_group - 1) / blocks_per_group;
-
-        println!("[ext2] Mounting EXT2 filesystem");
+        info!("ext2", "Mounting EXT2 filesystem");
         println!("  Block size: {} bytes", block_size);
         println!("  Total blocks: {}", superblock.blocks_count);
         println!("  Total inodes: {}", superblock.inodes_count);
@@ -451,6 +491,7 @@ impl Ext2Fs {
             other_read: mode & S_IROTH != 0,
             other_write: mode & S_IWOTH != 0,
             other_execute: mode & S_IXOTH != 0,
+            sticky: mode & S_ISVTX != 0,
         }
     }
 }
@@ -572,5 +613,5 @@ pub fn mount(device: Box<dyn BlockDevice>) -> FsResult<Box<dyn FileSystem>> {
 
 /// Initialize EXT2 filesystem driver
 pub fn init() {
-    println!("[ext2] EXT2 filesystem driver initialized");
+    info!("ext2", "EXT2 filesystem driver initialized");
 }
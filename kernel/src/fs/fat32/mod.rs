@@ -1,6 +1,10 @@
 //! FAT32 Filesystem
 //!
 //! Implementation of the FAT32 filesystem.
+//!
+//! Mounted, it is read-only. `Fat32Fs::overwrite` can write over a file
+//! in place, which is what updates need of the ESP: nothing is allocated
+//! and no size changes, so the FAT and directories stay as they are.
 
 use alloc::string::String;
 use alloc::vec;
@@ -10,6 +14,7 @@ use alloc::boxed::Box;
 use crate::fs::{FileSystem, FileType, Metadata, Permissions, INode, FsResult, FsError};
 use crate::storage::BlockDevice;
 use crate::println;
+use crate::info;
 
 /// FAT32 boot sector
 #[repr(C)]
@@ -45,6 +50,82 @@ pub struct BootSector {
     pub fs_type: [u8; 8],
 }
 
+impl BootSector {
+    /// Parse and check a boot sector; its fields are unaligned on disk,
+    /// so they are read one by one
+    pub fn parse(data: &[u8; 512]) -> FsResult<Self> {
+        let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let bytes = |at: usize, out: &mut [u8]| out.copy_from_slice(&data[at..at + out.len()]);
+
+        let mut boot_sector = Self {
+            jmp: [0; 3],
+            oem: [0; 8],
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: data[13],
+            reserved_sectors: u16_at(14),
+            fat_count: data[16],
+            root_entries: u16_at(17),
+            total_sectors_16: u16_at(19),
+            media_type: data[21],
+            sectors_per_fat_16: u16_at(22),
+            sectors_per_track: u16_at(24),
+            head_count: u16_at(26),
+            hidden_sectors: u32_at(28),
+            total_sectors_32: u32_at(32),
+            sectors_per_fat_32: u32_at(36),
+            ext_flags: u16_at(40),
+            fs_version: u16_at(42),
+            root_cluster: u32_at(44),
+            fs_info_sector: u16_at(48),
+            backup_boot_sector: u16_at(50),
+            reserved: [0; 12],
+            drive_num: data[64],
+            reserved1: data[65],
+            boot_sig: data[66],
+            volume_id: u32_at(67),
+            volume_label: [0; 11],
+            fs_type: [0; 8],
+        };
+        bytes(0, &mut boot_sector.jmp);
+        bytes(3, &mut boot_sector.oem);
+        bytes(52, &mut boot_sector.reserved);
+        bytes(71, &mut boot_sector.volume_label);
+        bytes(82, &mut boot_sector.fs_type);
+
+        // Verify FAT32 signature
+        if boot_sector.boot_sig != 0x29 {
+            return Err(FsError::InvalidFilesystem);
+        }
+        // Geometry the rest of the driver divides by and allocates from
+        let bytes_per_sector = boot_sector.bytes_per_sector;
+        let sectors_per_cluster = boot_sector.sectors_per_cluster;
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || boot_sector.reserved_sectors == 0
+            || boot_sector.fat_count == 0
+            || boot_sector.sectors_per_fat_32 == 0
+            || boot_sector.root_cluster < 2
+        {
+            return Err(FsError::InvalidFilesystem);
+        }
+        let metadata_sectors = boot_sector.reserved_sectors as u64
+            + boot_sector.fat_count as u64 * boot_sector.sectors_per_fat_32 as u64;
+        if metadata_sectors >= boot_sector.total_sectors() as u64 {
+            return Err(FsError::InvalidFilesystem);
+        }
+        Ok(boot_sector)
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        if self.total_sectors_32 != 0 {
+            self.total_sectors_32
+        } else {
+            self.total_sectors_16 as u32
+        }
+    }
+}
+
 /// Directory entry (32 bytes)
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -117,12 +198,11 @@ impl Fat32Fs {
         device.read_blocks(0, 1, &mut boot_data)
             .map_err(|_| FsError::IoError)?;
 
-        let boot_sector = unsafe {
-            core::ptr::read(boot_data.as_ptr() as *const BootSector)
-        };
-
-        // Verify FAT32 signature
-        if boot_sector.boot_sig != 0x29 {
+        let boot_sector = BootSector::parse(&boot_data)?;
+        // Sectors are read as the device's blocks
+        if boot_sector.bytes_per_sector as usize != device.block_size()
+            || boot_sector.total_sectors() as u64 > device.block_count()
+        {
             return Err(FsError::InvalidFilesystem);
         }
 
@@ -137,17 +217,12 @@ impl Fat32Fs {
         let data_start_sector = boot_sector.reserved_sectors as u32 + 
                                (boot_sector.fat_count as u32 * sectors_per_fat);
 
-        println!("[fat32] Mounting FAT32 filesystem");
+        info!("fat32", "Mounting FAT32 filesystem");
         println!("  Volume: {}", 
             core::str::from_utf8(&boot_sector.volume_label).unwrap_or("Unknown").trim());
         println!("  Bytes per sector: {}", bytes_per_sector);
         println!("  Sectors per cluster: {}", sectors_per_cluster);
-        println!("  Total sectors: {}", 
-            if boot_sector.total_sectors_32 != 0 { 
-                boot_sector.total_sectors_32 
-            } else { 
-                boot_sector.total_sectors_16 as u32 
-            });
+        println!("  Total sectors: {}", boot_sector.total_sectors());
         println!("  Root cluster: {}", boot_sector.root_cluster);
 
         // Read FAT into memory
@@ -454,6 +529,59 @@ impl Fat32Fs {
         ((entry.cluster_high as u32) << 16) | (entry.cluster_low as u32)
     }
 
+    /// A whole file, by its path from the root
+    pub fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
+        let entry = self.lookup(path)?;
+        if entry.attrs & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        let mut data = vec![0u8; entry.size as usize];
+        if !data.is_empty() {
+            let read = self.read_clusters(Self::entry_to_cluster(&entry), 0, &mut data)?;
+            data.truncate(read);
+        }
+        Ok(data)
+    }
+
+    /// Size of the file at `path`
+    pub fn file_size(&self, path: &str) -> FsResult<u64> {
+        let entry = self.lookup(path)?;
+        if entry.attrs & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        Ok(entry.size as u64)
+    }
+
+    /// Write `data` over the start of the file at `path`, which must be at
+    /// least as long; the rest of it stays as it was
+    pub fn overwrite(&self, path: &str, data: &[u8]) -> FsResult<()> {
+        let entry = self.lookup(path)?;
+        if entry.attrs & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        if data.len() as u64 > entry.size as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let cluster_size = self.bytes_per_cluster as usize;
+        let mut cluster = Self::entry_to_cluster(&entry);
+        let mut chunks = data.chunks(cluster_size).peekable();
+        let mut buffer = vec![0u8; cluster_size];
+        while let Some(chunk) = chunks.next() {
+            // A last, partial cluster keeps what follows in it
+            if chunk.len() < cluster_size {
+                self.read_cluster(cluster, &mut buffer)?;
+            }
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            self.device.write_blocks(self.cluster_to_sector(cluster), self.sectors_per_cluster as usize, &buffer)
+                .map_err(|_| FsError::IoError)?;
+            if chunks.peek().is_some() {
+                cluster = self.next_cluster(cluster).ok_or(FsError::InvalidFilesystem)?;
+            }
+        }
+        self.device.flush().map_err(|_| FsError::IoError)
+    }
+
     /// Convert FAT attributes to FileType
     fn attrs_to_file_type(attrs: u8) -> FileType {
         if attrs & ATTR_DIRECTORY != 0 {
@@ -493,6 +621,7 @@ impl FileSystem for Fat32Fs {
                 other_read: true,
                 other_write: true,
                 other_execute: true,
+                sticky: false,
             },
             created: 0,
             modified: 0,
@@ -555,5 +684,5 @@ pub fn mount(device: Box<dyn BlockDevice>) -> FsResult<Box<dyn FileSystem>> {
 
 /// Initialize FAT32 filesystem driver
 pub fn init() {
-    println!("[fat32] FAT32 filesystem driver initialized");
+    info!("fat32", "FAT32 filesystem driver initialized");
 }
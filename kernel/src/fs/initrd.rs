@@ -2,13 +2,15 @@
 //!
 //! Simple RAM-based filesystem for early boot.
 
+use alloc::format;
 use alloc::vec::Vec;
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use spin::Mutex;
 
 use super::{FileSystem, INode, Metadata, FileType, Permissions, FsResult, FsError};
+use crate::println;
 
 /// Inode data
 struct InodeData {
@@ -302,6 +304,137 @@ impl FileSystem for InitRamFs {
     }
 }
 
+/// Sample page exercising block and inline layout
+/// What new users' home directories start with; see `users::make_home`
+const SKELETON: &[(&str, &str)] = &[
+    ("/etc/skel/.bookmarks", "about:\tabout: pages\nfile:///test.html\tTest page\n"),
+    (
+        "/etc/skel/.desktop",
+        "# Your desktop, key = value. What is not set here follows the\n\
+         # system settings.\n\
+         # wallpaper = default\n\
+         # theme = light\n\
+         # keyboard = us\n",
+    ),
+];
+
+/// The services started at boot; see `service`
+const SERVICES: &[(&str, &str)] = &[
+    (
+        "/etc/services.d/network.service",
+        "description = Network interfaces and the protocol stack
+",
+    ),
+    (
+        "/etc/services.d/desktop.service",
+        "description = Desktop environment
+",
+    ),
+    (
+        "/etc/services.d/httpd.service",
+        "description = Web server for the files in root
+         requires = network
+         port = 80
+         root = /var/www
+",
+    ),
+    (
+        "/etc/services.d/ntp.service",
+        "description = Sets the clock from a time server
+         requires = network
+         server = pool.ntp.org
+         # Seconds between requests
+         interval = 3600
+",
+    ),
+];
+
+/// What the web server serves until something else is put there
+const WWW_INDEX: &str = "<!DOCTYPE html>
+<html>
+<head><title>WebbOS</title></head>
+<body><h1>WebbOS</h1><p>This page is served from /var/www.</p></body>
+</html>
+";
+
+/// Commands run at the end of boot
+const RC_LOCAL: &str = "\
+# Shell script run at the end of boot; see 'help' for the commands.
+# For example, a static address and a clock on the desktop:
+#
+# ADDRESS=10.0.2.15
+# if [ -n \"$ADDRESS\" ]; then
+#     config network.mode static
+#     config network.address $ADDRESS
+#     config network.gateway 10.0.2.2
+#     config network.dns 10.0.2.3
+# fi
+# for app in clock; do launch $app; done
+";
+
+const TEST_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<title>WebbOS Test Page</title>
+<style>
+.note { background-color: #fff3c4; border: 1px solid #d9b84a; padding: 8px; }
+</style>
+</head>
+<body>
+<h1>WebbOS Browser</h1>
+<p>This page is laid out by the kernel's own browser engine. Long paragraphs
+wrap at the edge of the window, and <b>bold</b>, <i>italic</i>,
+<a href="file:///test.html">linked</a> and <code style="background: #eee">highlighted</code>
+text flow together on the same lines.</p>
+<h2 id="lists">Lists</h2>
+<ul>
+<li>Block and inline formatting</li>
+<li>Line breaking at spaces</li>
+<li>Backgrounds and borders</li>
+</ul>
+<ol>
+<li>Parse</li>
+<li>Style</li>
+<li>Lay out and paint</li>
+</ol>
+<div class="note">Boxes take their padding, borders and backgrounds from CSS.</div>
+<p style="text-align: center">Centered text<br>on two lines</p>
+<pre>
+Preformatted    text keeps
+    its spacing.
+</pre>
+<p>Images are decoded from their files, at their own size
+<img src="gradient.ppm" alt="A gradient"> or scaled
+<img src="./gradient.ppm" alt="A gradient" width="48">,
+and an image without its file shows its alt text:
+<img src="logo.png" alt="WebbOS logo" width="120" height="40"></p>
+<h2>Forms</h2>
+<form action="test.html">
+<p>Search <input name="q" placeholder="Type here"> in
+<select name="in"><option>Pages</option><option>Images</option></select>
+<input type="checkbox" name="exact" value="yes"> exact
+<button>Search</button> <input type="reset"></p>
+</form>
+<hr>
+<p style="color: gray">End of page. <a href="#lists">Back to the lists</a></p>
+</body>
+</html>
+"##;
+
+/// A 96x32 color gradient for the test page, as a binary PPM
+fn test_image() -> Vec<u8> {
+    let (width, height) = (96u32, 32u32);
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for y in 0..height {
+        for x in 0..width {
+            data.push((x * 255 / (width - 1)) as u8);
+            data.push((y * 255 / (height - 1)) as u8);
+            data.push(0xC0);
+        }
+    }
+    data
+}
+
 /// Create a basic initrd with essential directories
 pub fn create_basic_initrd() -> Arc<InitRamFs> {
     let initrd = Arc::new(InitRamFs::new("initrd"));
@@ -315,13 +448,64 @@ pub fn create_basic_initrd() -> Arc<InitRamFs> {
     let _ = initrd.create_dir("/var");
     let _ = initrd.create_dir("/home");
 
+    // Anyone may keep files in /tmp, and remove only their own
+    if let Ok(tmp) = initrd.lookup_path("/tmp") {
+        if let Ok(mut metadata) = initrd.read_metadata(tmp) {
+            metadata.permissions = Permissions::from_mode(0o1777);
+            let _ = initrd.write_metadata(tmp, &metadata);
+        }
+    }
+
+    let _ = initrd.create_dir("/etc/skel");
+    let _ = initrd.create_dir("/etc/skel/Documents");
+    for (path, text) in SKELETON {
+        let _ = initrd.create_file(path, text.as_bytes().to_vec());
+    }
+
+    let _ = initrd.create_file("/etc/rc.local", RC_LOCAL.as_bytes().to_vec());
+
+    let _ = initrd.create_dir("/etc/services.d");
+    for (path, text) in SERVICES {
+        let _ = initrd.create_file(path, text.as_bytes().to_vec());
+    }
+    let _ = initrd.create_dir("/var/www");
+    let _ = initrd.create_file("/var/www/index.html", WWW_INDEX.as_bytes().to_vec());
+
     // Create a welcome file
     let welcome = b"Welcome to WebbOS v0.1.0\n";
     let _ = initrd.create_file("/etc/welcome", welcome.to_vec());
 
+    // A page for the browser engine: `navigate file:///test.html`
+    let _ = initrd.create_file("/test.html", TEST_PAGE.as_bytes().to_vec());
+    let _ = initrd.create_file("/gradient.ppm", test_image());
+
     initrd
 }
 
+/// Unpack the tar archive the boot entry brought over the root, creating
+/// the directories on each member's path; returns how many members there
+/// were
+pub fn unpack(archive: &[u8]) -> FsResult<usize> {
+    let entries = super::tar::entries(archive)?;
+    for entry in &entries {
+        let mut path = String::new();
+        let mut parts = entry.path.split('/').filter(|p| !p.is_empty()).peekable();
+        while let Some(part) = parts.next() {
+            path.push('/');
+            path.push_str(part);
+            if parts.peek().is_some() || entry.is_dir {
+                match super::create_dir(&path) {
+                    Ok(()) | Err(FsError::AlreadyExists) => {}
+                    Err(e) => return Err(e),
+                }
+            } else {
+                super::write_file(&path, entry.data)?;
+            }
+        }
+    }
+    Ok(entries.len())
+}
+
 /// Print initrd contents
 pub fn print_initrd(initrd: &InitRamFs) {
     fn print_dir(initrd: &InitRamFs, inode: INode, prefix: &str) {
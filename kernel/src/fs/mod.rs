@@ -1,13 +1,25 @@
 //! Virtual File System (VFS)
 //!
 //! Provides a unified interface for different filesystem implementations.
-
+//!
+//! Every access goes through the permission check against the calling
+//! session's credentials (`users::credentials`): reading a file or listing
+//! a directory needs read permission on it, and writing one needs write
+//! permission on it, or on its directory if it is new. Creating and
+//! removing entries needs write permission on the directory, and reaching
+//! any file needs search (execute) permission on each directory above it.
+//! New files and directories belong to whoever makes them. Append-only files, such
+//! as the audit log, may only be added to, even by the superuser.
+
+use alloc::format;
 use alloc::sync::Arc;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::println;
+use crate::users;
+use crate::{debug, info};
 
 /// File permissions
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,6 +33,8 @@ pub struct Permissions {
     pub other_read: bool,
     pub other_write: bool,
     pub other_execute: bool,
+    /// In a directory, only an entry's owner or the directory's may remove it
+    pub sticky: bool,
 }
 
 impl Permissions {
@@ -36,6 +50,23 @@ impl Permissions {
             other_read: true,
             other_write: false,
             other_execute: false,
+            sticky: false,
+        }
+    }
+
+    /// Permissions from mode bits, such as `0o644`
+    pub const fn from_mode(mode: u16) -> Self {
+        Self {
+            owner_read: mode & 0o400 != 0,
+            owner_write: mode & 0o200 != 0,
+            owner_execute: mode & 0o100 != 0,
+            group_read: mode & 0o040 != 0,
+            group_write: mode & 0o020 != 0,
+            group_execute: mode & 0o010 != 0,
+            other_read: mode & 0o004 != 0,
+            other_write: mode & 0o002 != 0,
+            other_execute: mode & 0o001 != 0,
+            sticky: mode & 0o1000 != 0,
         }
     }
 
@@ -51,6 +82,7 @@ impl Permissions {
         if self.other_read { mode |= 0o004; }
         if self.other_write { mode |= 0o002; }
         if self.other_execute { mode |= 0o001; }
+        if self.sticky { mode |= 0o1000; }
         mode
     }
 }
@@ -109,7 +141,7 @@ impl Metadata {
         Self {
             file_type: FileType::Directory,
             size: 0,
-            permissions: Permissions::default(),
+            permissions: Permissions::from_mode(0o755),
             created: 0,
             modified: 0,
             accessed: 0,
@@ -139,6 +171,38 @@ impl Metadata {
     }
 }
 
+/// What a caller wants to do with a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Whether the calling session may have `access` to a file with `metadata`
+///
+/// Its owner gets the owner's bits, members of its group the group's, and
+/// everyone else the others'; the superuser may do anything.
+pub fn permitted(metadata: &Metadata, access: Access) -> bool {
+    let credentials = users::credentials();
+    if credentials.superuser {
+        return true;
+    }
+    let p = &metadata.permissions;
+    let (read, write, execute) = if metadata.uid == credentials.uid {
+        (p.owner_read, p.owner_write, p.owner_execute)
+    } else if credentials.in_group(metadata.gid) {
+        (p.group_read, p.group_write, p.group_execute)
+    } else {
+        (p.other_read, p.other_write, p.other_execute)
+    };
+    match access {
+        Access::Read => read,
+        Access::Write => write,
+        Access::Execute => execute,
+    }
+}
+
 /// Directory entry
 #[derive(Debug, Clone)]
 pub struct DirEntry {
@@ -293,19 +357,39 @@ lazy_static! {
     static ref NEXT_FD: Mutex<u32> = Mutex::new(3); // Start after stdin/stdout/stderr
 }
 
+/// Files that may only be added to
+static APPEND_ONLY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Let the file at `path` only be added to from now on: `append_file`
+/// still works, but nobody may rewrite or remove it
+pub fn set_append_only(path: &str) {
+    let mut paths = APPEND_ONLY.lock();
+    if !paths.iter().any(|p| p == path) {
+        paths.push(path.to_string());
+    }
+}
+
+fn is_append_only(path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    APPEND_ONLY.lock().iter().any(|p| p == path)
+}
+
 /// File type
 pub mod ext2;
 pub mod fat32;
+pub mod initrd;
+pub mod procfs;
+pub mod tar;
 
 /// Initialize VFS
 pub fn init() {
-    println!("[vfs] Initializing virtual file system...");
+    info!("vfs", "Initializing virtual file system...");
 
     // Initialize filesystem drivers
     ext2::init();
     fat32::init();
 
-    println!("[vfs] VFS initialized");
+    info!("vfs", "VFS initialized");
 }
 
 /// Mount a filesystem
@@ -325,7 +409,10 @@ pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> FsResult<()> {
         fs,
     });
 
-    println!("[vfs] Mounted {} at {}", fs_name, path);
+    drop(mounts);
+
+    info!("vfs", "Mounted {} at {}", fs_name, path);
+    users::audit::record(users::audit::Kind::Mount, &format!("mounted {} on {}", fs_name, path));
     Ok(())
 }
 
@@ -338,31 +425,285 @@ pub fn unmount(path: &str) -> FsResult<()> {
         .ok_or(FsError::NotFound)?;
 
     mounts.remove(pos);
-    println!("[vfs] Unmounted {}", path);
+    drop(mounts);
+    info!("vfs", "Unmounted {}", path);
+    users::audit::record(users::audit::Kind::Mount, &format!("unmounted {}", path));
     Ok(())
 }
 
 /// Open a file
-pub fn open(path: &str, _flags: OpenFlags) -> FsResult<FileHandle> {
-    let mounts = MOUNTS.lock();
-    
-    // Find the filesystem that owns this path
-    for mount in mounts.iter() {
-        if path.starts_with(&mount.path) {
-            let rel_path = &path[mount.path.len()..];
-            // TODO: Resolve path and open file
-            println!("[vfs] Opening {} on {}", rel_path, mount.fs.name());
-            
-            // Allocate file descriptor
-            let mut next_fd = NEXT_FD.lock();
-            let fd = *next_fd;
-            *next_fd += 1;
-            
-            return Ok(FileHandle { fd });
+pub fn open(path: &str, flags: OpenFlags) -> FsResult<FileHandle> {
+    if flags.write && !flags.append && is_append_only(path) {
+        return Err(FsError::PermissionDenied);
+    }
+    let (fs, inode) = if flags.create {
+        writable(path)?
+    } else {
+        let (fs, inode) = resolve(path)?;
+        if flags.write {
+            check(&fs, inode, Access::Write)?;
+        }
+        (fs, inode)
+    };
+    if flags.read {
+        check(&fs, inode, Access::Read)?;
+    }
+    debug!("vfs", "Opening {} on {}", path, fs.name());
+
+    // Allocate file descriptor
+    let mut next_fd = NEXT_FD.lock();
+    let fd = *next_fd;
+    *next_fd += 1;
+
+    Ok(FileHandle { fd })
+}
+
+/// Find the filesystem and inode for a path
+///
+/// Resolves `path` against the mount with the longest matching prefix and
+/// walks it one component at a time from that filesystem's root. The
+/// caller needs search (execute) permission on every directory it passes.
+fn resolve(path: &str) -> FsResult<(Arc<dyn FileSystem>, INode)> {
+    let (fs, rel_path) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|m| path.starts_with(m.path.as_str()))
+            .max_by_key(|m| m.path.len())
+            .ok_or(FsError::NotFound)?;
+        (mount.fs.clone(), path[mount.path.len()..].to_string())
+    };
+
+    let mut inode = fs.root();
+    for name in rel_path.split('/').filter(|c| !c.is_empty()) {
+        check(&fs, inode, Access::Execute)?;
+        inode = fs.lookup(inode, name)?;
+    }
+    Ok((fs, inode))
+}
+
+/// Read an inode's metadata, failing unless the caller has `access` to it
+fn check(fs: &Arc<dyn FileSystem>, inode: INode, access: Access) -> FsResult<Metadata> {
+    let metadata = fs.read_metadata(inode)?;
+    if permitted(&metadata, access) {
+        Ok(metadata)
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+/// Create an entry in `parent`, owned by the caller
+fn create_owned(fs: &Arc<dyn FileSystem>, parent: INode, name: &str, file_type: FileType) -> FsResult<INode> {
+    check(fs, parent, Access::Write)?;
+    let inode = fs.create(parent, name, file_type)?;
+    let credentials = users::credentials();
+    let mut metadata = fs.read_metadata(inode)?;
+    metadata.uid = credentials.uid;
+    metadata.gid = credentials.gid;
+    fs.write_metadata(inode, &metadata)?;
+    Ok(inode)
+}
+
+/// Find a file to write to, creating it if it does not exist
+fn writable(path: &str) -> FsResult<(Arc<dyn FileSystem>, INode)> {
+    let (dir, name) = split_path(path)?;
+    let (fs, parent) = resolve(dir)?;
+    let inode = match fs.lookup(parent, name) {
+        Ok(inode) => {
+            check(&fs, inode, Access::Write)?;
+            inode
+        }
+        Err(FsError::NotFound) => create_owned(&fs, parent, name, FileType::Regular)?,
+        Err(e) => return Err(e),
+    };
+    Ok((fs, inode))
+}
+
+/// A file's metadata; needs no permission on the file itself
+pub fn metadata(path: &str) -> FsResult<Metadata> {
+    let (fs, inode) = resolve(path)?;
+    fs.read_metadata(inode)
+}
+
+/// Give a file to another user and group; only the superuser may
+pub fn chown(path: &str, uid: u32, gid: u32) -> FsResult<()> {
+    if !users::credentials().superuser {
+        return Err(FsError::PermissionDenied);
+    }
+    let (fs, inode) = resolve(path)?;
+    let mut metadata = fs.read_metadata(inode)?;
+    metadata.uid = uid;
+    metadata.gid = gid;
+    fs.write_metadata(inode, &metadata)
+}
+
+/// Change a file's permissions; only its owner and the superuser may
+pub fn chmod(path: &str, permissions: Permissions) -> FsResult<()> {
+    let (fs, inode) = resolve(path)?;
+    let mut metadata = fs.read_metadata(inode)?;
+    let credentials = users::credentials();
+    if !credentials.superuser && credentials.uid != metadata.uid {
+        return Err(FsError::PermissionDenied);
+    }
+    metadata.permissions = permissions;
+    fs.write_metadata(inode, &metadata)
+}
+
+/// List a directory
+pub fn read_dir(path: &str) -> FsResult<Vec<DirEntry>> {
+    let (fs, inode) = resolve(path)?;
+    if check(&fs, inode, Access::Read)?.file_type != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    let mut entries = Vec::new();
+    for (name, child) in fs.read_dir(inode)? {
+        if name == "." || name == ".." {
+            continue;
         }
+        let metadata = fs.read_metadata(child)?;
+        entries.push(DirEntry { name, metadata, inode: child.as_u64() });
     }
+    Ok(entries)
+}
 
-    Err(FsError::NotFound)
+/// Replace a file's contents, creating it if it does not exist
+pub fn write_file(path: &str, data: &[u8]) -> FsResult<()> {
+    if is_append_only(path) {
+        return Err(FsError::PermissionDenied);
+    }
+    let (fs, inode) = writable(path)?;
+
+    let mut metadata = fs.read_metadata(inode)?;
+    if metadata.file_type == FileType::Directory {
+        return Err(FsError::IsDirectory);
+    }
+    let mut offset = 0;
+    while offset < data.len() {
+        let n = fs.write(inode, offset as u64, &data[offset..])?;
+        if n == 0 {
+            return Err(FsError::IoError);
+        }
+        offset += n;
+    }
+    if metadata.size != data.len() as u64 {
+        metadata.size = data.len() as u64;
+        fs.write_metadata(inode, &metadata)?;
+    }
+    Ok(())
+}
+
+/// Add `data` to the end of a file, creating it if it does not exist
+pub fn append_file(path: &str, data: &[u8]) -> FsResult<()> {
+    let (fs, inode) = writable(path)?;
+
+    let mut metadata = fs.read_metadata(inode)?;
+    if metadata.file_type == FileType::Directory {
+        return Err(FsError::IsDirectory);
+    }
+    let start = metadata.size;
+    let mut offset = 0;
+    while offset < data.len() {
+        let n = fs.write(inode, start + offset as u64, &data[offset..])?;
+        if n == 0 {
+            return Err(FsError::IoError);
+        }
+        offset += n;
+    }
+    let size = start + data.len() as u64;
+    if metadata.size != size {
+        metadata.size = size;
+        fs.write_metadata(inode, &metadata)?;
+    }
+    Ok(())
+}
+
+/// `path` as an absolute path with no `.` or `..` in it, taking a
+/// relative one from `cwd`
+pub fn absolute(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    let start = if path.starts_with('/') { "" } else { cwd };
+    for part in start.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Split a path into its parent directory and final component
+fn split_path(path: &str) -> FsResult<(&str, &str)> {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((dir, name)) if !name.is_empty() => Ok((if dir.is_empty() { "/" } else { dir }, name)),
+        _ => Err(FsError::InvalidArgument),
+    }
+}
+
+/// Create a directory; its parent must exist
+pub fn create_dir(path: &str) -> FsResult<()> {
+    let (dir, name) = split_path(path)?;
+    let (fs, parent) = resolve(dir)?;
+    match fs.lookup(parent, name) {
+        Ok(_) => Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => create_owned(&fs, parent, name, FileType::Directory).map(|_| ()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove a file, or a directory with everything in it
+///
+/// From a sticky directory, such as /tmp, only the entry's owner, the
+/// directory's owner or the superuser may remove it.
+pub fn remove(path: &str) -> FsResult<()> {
+    if is_append_only(path) {
+        return Err(FsError::PermissionDenied);
+    }
+    let (dir, name) = split_path(path)?;
+    let (fs, parent) = resolve(dir)?;
+    let directory = check(&fs, parent, Access::Write)?;
+    let inode = fs.lookup(parent, name)?;
+    let metadata = fs.read_metadata(inode)?;
+    if directory.permissions.sticky {
+        let credentials = users::credentials();
+        if !credentials.superuser && credentials.uid != directory.uid && credentials.uid != metadata.uid {
+            return Err(FsError::PermissionDenied);
+        }
+    }
+    if metadata.file_type == FileType::Directory {
+        for entry in read_dir(path)? {
+            remove(&format!("{}/{}", path.trim_end_matches('/'), entry.name))?;
+        }
+    }
+    fs.remove(parent, name)
+}
+
+/// Read a whole file
+pub fn read_file(path: &str) -> FsResult<Vec<u8>> {
+    let (fs, inode) = resolve(path)?;
+
+    let metadata = check(&fs, inode, Access::Read)?;
+    if metadata.file_type == FileType::Directory {
+        return Err(FsError::IsDirectory);
+    }
+
+    let mut data = Vec::new();
+    data.try_reserve_exact(metadata.size as usize)
+        .map_err(|_| FsError::OutOfMemory)?;
+    data.resize(metadata.size as usize, 0);
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let n = fs.read(inode, offset as u64, &mut data[offset..])?;
+        if n == 0 {
+            break;
+        }
+        offset += n;
+    }
+    data.truncate(offset);
+    Ok(data)
 }
 
 /// File handle
@@ -434,3 +775,77 @@ pub fn print_stats() {
         println!("    {} -> {} ({})", mount.path, mount.fs.name(), mount.fs.root().as_u64());
     }
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// A user with no other session, logged in for the test
+    fn log_in(name: &str) -> Result<(users::UserId, u64), String> {
+        let id = users::create_user(name, "hunter22", false).map_err(|e| format!("{:?}", e))?;
+        let session = users::login(name, "hunter22", None).map_err(|e| format!("{:?}", e))?;
+        Ok((id, session))
+    }
+
+    fn log_out(id: users::UserId, session: u64) -> Result<(), String> {
+        users::logout(session);
+        users::delete_user(id, users::HomeAction::Remove).map_err(|e| format!("{:?}", e))
+    }
+
+    #[kernel_test]
+    fn closed_directories_hide_their_files() -> Result<(), String> {
+        let dir = "/tmp/fs-search-test";
+        create_dir(dir).map_err(|e| format!("{:?}", e))?;
+        write_file("/tmp/fs-search-test/readable", b"open to all").map_err(|e| format!("{:?}", e))?;
+        chmod(dir, Permissions::from_mode(0o700)).map_err(|e| format!("{:?}", e))?;
+
+        let (id, session) = log_in("fs-search-test")?;
+        let denied = read_file("/tmp/fs-search-test/readable");
+        log_out(id, session)?;
+        check_eq!(denied, Err(FsError::PermissionDenied));
+
+        // Search alone is enough to reach a file by name
+        chmod(dir, Permissions::from_mode(0o711)).map_err(|e| format!("{:?}", e))?;
+        let (id, session) = log_in("fs-search-test")?;
+        let read = read_file("/tmp/fs-search-test/readable");
+        let listed = read_dir(dir).map(|_| ());
+        log_out(id, session)?;
+        check_eq!(read.as_deref(), Ok(&b"open to all"[..]));
+        check_eq!(listed, Err(FsError::PermissionDenied));
+
+        remove(dir).map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn sticky_directories_keep_others_files() -> Result<(), String> {
+        check!(metadata("/tmp").map_err(|e| format!("{:?}", e))?.permissions.sticky);
+        let dir = "/tmp/fs-sticky-test";
+        create_dir(dir).map_err(|e| format!("{:?}", e))?;
+        chmod(dir, Permissions::from_mode(0o1777)).map_err(|e| format!("{:?}", e))?;
+
+        let (owner, session) = log_in("fs-sticky-owner")?;
+        let written = write_file("/tmp/fs-sticky-test/mine", b"keep out");
+        log_out(owner, session)?;
+        check_eq!(written, Ok(()));
+
+        let (other, session) = log_in("fs-sticky-other")?;
+        let theirs = remove("/tmp/fs-sticky-test/mine");
+        let own = write_file("/tmp/fs-sticky-test/yours", b"").and_then(|()| remove("/tmp/fs-sticky-test/yours"));
+        log_out(other, session)?;
+        check_eq!(theirs, Err(FsError::PermissionDenied));
+        check_eq!(own, Ok(()));
+
+        // Without the bit, write permission on the directory is enough
+        chmod(dir, Permissions::from_mode(0o777)).map_err(|e| format!("{:?}", e))?;
+        let (other, session) = log_in("fs-sticky-other")?;
+        let theirs = remove("/tmp/fs-sticky-test/mine");
+        log_out(other, session)?;
+        check_eq!(theirs, Ok(()));
+
+        remove(dir).map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+}
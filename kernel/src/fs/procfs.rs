@@ -0,0 +1,110 @@
+//! Process filesystem
+//!
+//! Mounted on `/proc`, it holds read-only files whose contents the
+//! kernel makes up each time they are read, such as `/proc/kmsg` for the
+//! kernel log, `/proc/cmdline` for the kernel command line,
+//! `/proc/trace.json` for the trace events, `/proc/power` for the
+//! batteries and the AC adapter, `/proc/shm` for shared memory objects
+//! and `/proc/audit` for the latest audit log entries.
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{FileSystem, FsError, FsResult, INode, Metadata, Permissions};
+
+/// A file: its name, its mode and what makes its contents
+struct ProcFile {
+    name: &'static str,
+    mode: u16,
+    contents: fn() -> Vec<u8>,
+}
+
+const FILES: [ProcFile; 6] = [
+    // Only root may read the kernel log
+    ProcFile { name: "kmsg", mode: 0o400, contents: crate::log::contents },
+    ProcFile { name: "cmdline", mode: 0o444, contents: crate::cmdline::contents },
+    ProcFile { name: "trace.json", mode: 0o444, contents: crate::trace::chrome_json },
+    ProcFile { name: "power", mode: 0o444, contents: crate::power::contents },
+    ProcFile { name: "shm", mode: 0o444, contents: crate::mm::shm::contents },
+    // Like the log file, only root may read it
+    ProcFile { name: "audit", mode: 0o400, contents: crate::users::audit::contents },
+];
+
+const ROOT: u64 = 0;
+
+pub struct ProcFs;
+
+/// The file behind an inode; inode `n + 1` is `FILES[n]`
+fn file(inode: INode) -> FsResult<&'static ProcFile> {
+    let index = inode.as_u64().checked_sub(1).ok_or(FsError::IsDirectory)?;
+    FILES.get(index as usize).ok_or(FsError::NotFound)
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &str {
+        "proc"
+    }
+
+    fn root(&self) -> INode {
+        INode::new(ROOT)
+    }
+
+    fn read_metadata(&self, inode: INode) -> FsResult<Metadata> {
+        if inode.as_u64() == ROOT {
+            let mut metadata = Metadata::directory();
+            metadata.permissions = Permissions::from_mode(0o555);
+            return Ok(metadata);
+        }
+        let file = file(inode)?;
+        let mut metadata = Metadata::file((file.contents)().len() as u64);
+        metadata.permissions = Permissions::from_mode(file.mode);
+        Ok(metadata)
+    }
+
+    fn write_metadata(&self, _inode: INode, _metadata: &Metadata) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn read(&self, inode: INode, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let contents = (file(inode)?.contents)();
+        let start = (offset as usize).min(contents.len());
+        let n = buf.len().min(contents.len() - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&self, _inode: INode, _offset: u64, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn lookup(&self, parent: INode, name: &str) -> FsResult<INode> {
+        if parent.as_u64() != ROOT {
+            return Err(FsError::NotDirectory);
+        }
+        FILES.iter()
+            .position(|f| f.name == name)
+            .map(|i| INode::new(i as u64 + 1))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, _parent: INode, _name: &str, _file_type: super::FileType) -> FsResult<INode> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn remove(&self, _parent: INode, _name: &str) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn read_dir(&self, inode: INode) -> FsResult<Vec<(String, INode)>> {
+        if inode.as_u64() != ROOT {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(FILES.iter().enumerate().map(|(i, f)| (f.name.to_string(), INode::new(i as u64 + 1))).collect())
+    }
+}
+
+/// A process filesystem to mount on `/proc`
+pub fn create() -> Arc<ProcFs> {
+    Arc::new(ProcFs)
+}
@@ -0,0 +1,135 @@
+//! Tar archive reader and writer
+//!
+//! Reads POSIX ustar archives held in memory: a 512-byte header per
+//! member followed by its data, padded to a whole block, and two zero
+//! blocks at the end. Only regular files and directories are returned;
+//! links and special files are skipped. `build` writes the same format.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{FsError, FsResult};
+
+const BLOCK_SIZE: usize = 512;
+
+/// A member of an archive
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    /// Path inside the archive, without a leading `./` or trailing `/`
+    pub path: String,
+    pub is_dir: bool,
+    pub data: &'a [u8],
+}
+
+/// Text field of a header, up to its first NUL
+fn field(header: &[u8], offset: usize, len: usize) -> &[u8] {
+    let raw = &header[offset..offset + len];
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(len);
+    &raw[..end]
+}
+
+/// Octal number field of a header
+fn octal(header: &[u8], offset: usize, len: usize) -> FsResult<usize> {
+    let text = core::str::from_utf8(field(header, offset, len)).map_err(|_| FsError::InvalidFilesystem)?;
+    let text = text.trim_matches(|c| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(text, 8).map_err(|_| FsError::InvalidFilesystem)
+}
+
+/// Every file and directory in `archive`, in archive order
+pub fn entries(archive: &[u8]) -> FsResult<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let stored: usize = header[..148].iter().chain(header[156..].iter()).map(|&b| b as usize).sum();
+        if octal(header, 148, 8)? != stored + 8 * b' ' as usize {
+            return Err(FsError::InvalidFilesystem);
+        }
+
+        let size = octal(header, 124, 12)?;
+        let start = offset + BLOCK_SIZE;
+        let data = archive.get(start..start + size).ok_or(FsError::InvalidFilesystem)?;
+        offset = start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        let mut path = String::from_utf8_lossy(field(header, 0, 100)).into_owned();
+        if &header[257..262] == b"ustar" {
+            let prefix = field(header, 345, 155);
+            if !prefix.is_empty() {
+                path = alloc::format!("{}/{}", String::from_utf8_lossy(prefix), path);
+            }
+        }
+        let path = path.trim_start_matches("./").trim_end_matches('/');
+        if path.is_empty() {
+            continue;
+        }
+        let is_dir = match header[156] {
+            b'0' | 0 => false,
+            b'5' => true,
+            _ => continue,
+        };
+        entries.push(Entry { path: String::from(path), is_dir, data });
+    }
+    Ok(entries)
+}
+
+/// Put `text` in a header field, cut to fit
+fn put(header: &mut [u8], offset: usize, len: usize, text: &[u8]) {
+    let n = text.len().min(len);
+    header[offset..offset + n].copy_from_slice(&text[..n]);
+}
+
+/// Put a number in an octal header field, zero padded and NUL terminated
+fn put_octal(header: &mut [u8], offset: usize, len: usize, value: usize) {
+    let text = alloc::format!("{:0width$o}", value, width = len - 1);
+    put(header, offset, len - 1, text.as_bytes());
+}
+
+/// Split a path between the prefix and name fields of a header, at a
+/// `/` if it is too long for the name alone
+fn split_path(path: &str) -> (&str, &str) {
+    if path.len() <= 100 {
+        return ("", path);
+    }
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+        .unwrap_or(("", path))
+}
+
+/// Write `entries` as a ustar archive
+pub fn build(entries: &[Entry]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for entry in entries {
+        let mut header = [0u8; BLOCK_SIZE];
+        let (prefix, name) = split_path(&entry.path);
+        let size = if entry.is_dir { 0 } else { entry.data.len() };
+        put(&mut header, 0, 100, name.as_bytes());
+        put_octal(&mut header, 100, 8, if entry.is_dir { 0o755 } else { 0o644 });
+        put_octal(&mut header, 108, 8, 0);
+        put_octal(&mut header, 116, 8, 0);
+        put_octal(&mut header, 124, 12, size);
+        put_octal(&mut header, 136, 12, 0);
+        header[156] = if entry.is_dir { b'5' } else { b'0' };
+        put(&mut header, 257, 8, b"ustar\000");
+        put(&mut header, 345, 155, prefix.as_bytes());
+        // The checksum is taken with its own field as spaces
+        header[148..156].fill(b' ');
+        let sum: usize = header.iter().map(|&b| b as usize).sum();
+        put_octal(&mut header, 148, 7, sum);
+        header[154] = 0;
+
+        archive.extend_from_slice(&header);
+        if size > 0 {
+            archive.extend_from_slice(entry.data);
+            archive.resize(archive.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        }
+    }
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    archive
+}
@@ -0,0 +1,676 @@
+//! Double-buffered compositor
+//!
+//! All drawing lands in an off-screen back buffer in RAM. Each draw call
+//! records the rectangle it touched; `flip` waits for vertical retrace and
+//! copies only those damaged rectangles to the VESA framebuffer using wide
+//! stores, so the screen never shows a half-drawn frame and untouched
+//! regions cost nothing.
+//!
+//! With several displays each has its own back buffer. Drawing uses
+//! virtual desktop coordinates (see `graphics::display`) and is split
+//! across every display the shape overlaps, so a window straddling two
+//! monitors is drawn on both.
+//!
+//! Colors are 0xAARRGGBB, matching `drivers::vesa::colors`.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use crate::arch::simd;
+use crate::drivers::vesa::{self, colors};
+use crate::drivers::virtio_gpu;
+use crate::graphics::cursor;
+use crate::graphics::display::{self, Display, Output};
+use crate::mm::shm;
+use crate::println;
+use crate::{info, warn};
+
+/// Above this many damage rectangles they are merged into their bounding box
+const MAX_DAMAGE_RECTS: usize = 32;
+
+/// Spin iterations before giving up on vertical retrace
+const VRETRACE_TIMEOUT: u32 = 100_000;
+
+/// VGA input status register 1 (bit 3 = vertical retrace)
+const VGA_INPUT_STATUS_1: u16 = 0x3DA;
+
+/// Axis-aligned rectangle in screen coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, w: u32, h: u32) -> Self {
+        Self { x, y, w, h }
+    }
+
+    /// Right edge (exclusive)
+    pub fn right(&self) -> i32 {
+        self.x + self.w as i32
+    }
+
+    /// Bottom edge (exclusive)
+    pub fn bottom(&self) -> i32 {
+        self.y + self.h as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.w == 0 || self.h == 0
+    }
+
+    /// Overlapping area, if any
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = self.right().min(other.right());
+        let y1 = self.bottom().min(other.bottom());
+        if x1 > x0 && y1 > y0 {
+            Some(Rect::new(x0, y0, (x1 - x0) as u32, (y1 - y0) as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Smallest rectangle containing both
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x0 = self.x.min(other.x);
+        let y0 = self.y.min(other.y);
+        let x1 = self.right().max(other.right());
+        let y1 = self.bottom().max(other.bottom());
+        Rect::new(x0, y0, (x1 - x0) as u32, (y1 - y0) as u32)
+    }
+
+    /// True if the rectangles overlap or share an edge
+    pub fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.right() && other.x <= self.right()
+            && self.y <= other.bottom() && other.y <= self.bottom()
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+}
+
+/// One display's back buffer plus damage list
+pub struct OutputBuffer {
+    display: Display,
+    back: Vec<u32>,
+    damage: Vec<Rect>,
+}
+
+impl OutputBuffer {
+    /// Allocate a back buffer, or `None` if the heap cannot hold it
+    fn new(display: Display) -> Option<Self> {
+        let len = (display.rect.w as usize) * (display.rect.h as usize);
+        let mut back = Vec::new();
+        back.try_reserve_exact(len).ok()?;
+        back.resize(len, 0);
+        Some(Self { display, back, damage: Vec::new() })
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    /// Back buffer contents (row-major, display width pixels per row)
+    pub fn pixels(&self) -> &[u32] {
+        &self.back
+    }
+
+    /// Pending damage, in display-local coordinates
+    pub fn damaged(&self) -> &[Rect] {
+        &self.damage
+    }
+
+    fn local_bounds(&self) -> Rect {
+        Rect::new(0, 0, self.display.rect.w, self.display.rect.h)
+    }
+
+    /// `len` pixels of row `y` starting at column `x`, in local coordinates
+    fn span_mut(&mut self, x: i32, y: i32, len: u32) -> &mut [u32] {
+        let start = (y as u32 * self.display.rect.w + x as u32) as usize;
+        &mut self.back[start..start + len as usize]
+    }
+
+    /// Record a local region that must be copied on the next flip
+    fn damage(&mut self, rect: Rect) {
+        let bounds = self.local_bounds();
+        add_rect(&mut self.damage, rect, bounds);
+    }
+
+    fn damage_all(&mut self) {
+        self.damage.clear();
+        self.damage.push(self.local_bounds());
+    }
+
+    /// Copy damaged regions to the display, returning the pixels copied
+    fn flip(&mut self) -> u64 {
+        match self.display.output {
+            Output::Primary => self.flip_primary(),
+            Output::VirtioScanout(id) => self.flip_scanout(id),
+        }
+    }
+
+    fn flip_primary(&mut self) -> u64 {
+        let mut driver = vesa::driver().lock();
+        if !driver.is_initialized() {
+            self.damage.clear();
+            return 0;
+        }
+        let info = *driver.info();
+        let screen = Rect::new(0, 0, info.width, info.height);
+        let width = self.display.rect.w;
+
+        let mut copied = 0u64;
+        for rect in self.damage.drain(..) {
+            let r = match rect.intersect(&screen) {
+                Some(r) => r,
+                None => continue,
+            };
+            // The back buffer from the rectangle's first pixel, rows `width` apart
+            let start = (r.y as u32 * width + r.x as u32) as usize;
+            driver.blit_fast(&self.back[start..], width, r.x, r.y, r.w, r.h);
+            copied += r.w as u64 * r.h as u64;
+        }
+        copied
+    }
+
+    /// Copy into the scanout's memory, then upload the bounding box once
+    fn flip_scanout(&mut self, scanout_id: u32) -> u64 {
+        let width = self.display.rect.w;
+        let fb = self.display.fb_virt as *mut u8;
+        let pitch = self.display.pitch as usize;
+
+        let mut copied = 0u64;
+        let mut uploaded: Option<Rect> = None;
+        for r in self.damage.drain(..) {
+            for row in r.y..r.bottom() {
+                let src_start = (row as u32 * width + r.x as u32) as usize;
+                let src = &self.back[src_start..src_start + r.w as usize];
+                unsafe {
+                    copy_row(fb.add(row as usize * pitch + r.x as usize * 4) as *mut u32, src);
+                }
+            }
+            copied += r.w as u64 * r.h as u64;
+            uploaded = Some(uploaded.map_or(r, |u| u.union(&r)));
+        }
+        if let Some(u) = uploaded {
+            virtio_gpu::present_scanout(scanout_id, u.x as u32, u.y as u32, u.w, u.h);
+        }
+        copied
+    }
+}
+
+/// Add `rect` (clipped to `bounds`) to a rectangle list, merging it into
+/// a rectangle it touches and collapsing the list when it grows too long
+fn add_rect(list: &mut Vec<Rect>, rect: Rect, bounds: Rect) {
+    let rect = match rect.intersect(&bounds) {
+        Some(r) => r,
+        None => return,
+    };
+
+    // Merge with an existing rectangle it touches
+    for existing in list.iter_mut() {
+        if existing.touches(&rect) {
+            *existing = existing.union(&rect);
+            return;
+        }
+    }
+
+    list.push(rect);
+    if list.len() > MAX_DAMAGE_RECTS {
+        let bbox = list.iter().skip(1).fold(list[0], |acc, r| acc.union(r));
+        list.clear();
+        list.push(bbox);
+    }
+}
+
+/// Repaint counters, with per-second rates over whole RTC seconds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RedrawStats {
+    /// `compose` calls that repainted something
+    pub composes: u64,
+    /// Pixels repainted in total
+    pub area: u64,
+    /// Frames and pixels repainted per second, as of the last second
+    pub frames_per_sec: u64,
+    pub area_per_sec: u64,
+    window_start: u32,
+    window_frames: u64,
+    window_area: u64,
+}
+
+impl RedrawStats {
+    fn record(&mut self, area: u64) {
+        let now = rtc_seconds();
+        if now != self.window_start {
+            // Spread the last window over the seconds it covered, so an
+            // idle gap reads as a low rate rather than a stale one
+            let elapsed = now.wrapping_sub(self.window_start).clamp(1, 86_400) as u64;
+            self.frames_per_sec = self.window_frames / elapsed;
+            self.area_per_sec = self.window_area / elapsed;
+            self.window_start = now;
+            self.window_frames = 0;
+            self.window_area = 0;
+        }
+        self.composes += 1;
+        self.area += area;
+        self.window_frames += 1;
+        self.window_area += area;
+    }
+}
+
+/// Seconds since midnight from the RTC (the PIT tick count does not advance)
+fn rtc_seconds() -> u32 {
+    crate::drivers::rtc::now().seconds_of_day()
+}
+
+/// Back buffers for every display, addressed in virtual desktop coordinates
+///
+/// Besides damage (what must be copied to the screen) the compositor keeps
+/// a list of invalid regions (what must be repainted). Whoever owns the
+/// scene reports changes with `invalidate`; `compose` then asks it to
+/// repaint only those regions, with drawing clipped to each in turn.
+pub struct Compositor {
+    outputs: Vec<OutputBuffer>,
+    bounds: Rect,
+    invalid: Vec<Rect>,
+    clip: Option<Rect>,
+    frames: u64,
+    pixels_copied: u64,
+    redraw: RedrawStats,
+}
+
+impl Compositor {
+    /// Allocate a back buffer per display
+    ///
+    /// A display whose buffer does not fit in the heap is left out (and
+    /// stays blank); `None` if not even the first one fits.
+    pub fn new(displays: &[Display]) -> Option<Self> {
+        let mut outputs: Vec<OutputBuffer> = Vec::new();
+        for d in displays {
+            match OutputBuffer::new(*d) {
+                Some(o) => outputs.push(o),
+                None if outputs.is_empty() => return None,
+                None => warn!("compositor", "Not enough memory for display {}, left blank", d.id),
+            }
+        }
+        let first = outputs.first()?.display.rect;
+        let bounds = outputs.iter().fold(first, |acc, o| acc.union(&o.display.rect));
+
+        Some(Self {
+            outputs,
+            bounds,
+            invalid: Vec::new(),
+            clip: None,
+            frames: 0,
+            pixels_copied: 0,
+            redraw: RedrawStats::default(),
+        })
+    }
+
+    /// Virtual desktop width
+    pub fn width(&self) -> u32 {
+        self.bounds.w
+    }
+
+    /// Virtual desktop height
+    pub fn height(&self) -> u32 {
+        self.bounds.h
+    }
+
+    /// Area covered by all displays
+    pub fn bounds(&self) -> Rect {
+        self.bounds
+    }
+
+    /// Per-display back buffers
+    pub fn outputs(&self) -> &[OutputBuffer] {
+        &self.outputs
+    }
+
+    /// Run `f` on every display overlapping `rect` with the overlap in that
+    /// display's local coordinates, then damage it
+    ///
+    /// Drawing outside the current clip rectangle is dropped.
+    fn each_output(&mut self, rect: Rect, mut f: impl FnMut(&mut OutputBuffer, Rect)) {
+        let rect = match self.clip {
+            Some(clip) => match rect.intersect(&clip) {
+                Some(r) => r,
+                None => return,
+            },
+            None => rect,
+        };
+        for out in &mut self.outputs {
+            let d = out.display.rect;
+            if let Some(r) = rect.intersect(&d) {
+                let local = Rect::new(r.x - d.x, r.y - d.y, r.w, r.h);
+                f(out, local);
+                out.damage(local);
+            }
+        }
+    }
+
+    /// Record a region that must be copied on the next flip
+    pub fn damage(&mut self, rect: Rect) {
+        self.each_output(rect, |_, _| {});
+    }
+
+    /// Mark every display damaged
+    pub fn damage_all(&mut self) {
+        for out in &mut self.outputs {
+            out.damage_all();
+        }
+    }
+
+    /// Pending damage on the primary display
+    ///
+    /// The primary sits at the origin, so these are also virtual desktop
+    /// coordinates.
+    pub fn damaged(&self) -> &[Rect] {
+        self.outputs.iter()
+            .find(|o| o.display.is_primary())
+            .map_or(&[][..], |o| &o.damage[..])
+    }
+
+    /// Clear every back buffer
+    pub fn clear(&mut self, color: u32) {
+        if self.clip.is_some() {
+            return self.fill_rect(self.bounds.x, self.bounds.y, self.bounds.w, self.bounds.h, color);
+        }
+        for out in &mut self.outputs {
+            out.back.fill(color);
+            out.damage_all();
+        }
+    }
+
+    /// Limit drawing to `clip`, or lift the limit with `None`
+    pub fn set_clip(&mut self, clip: Option<Rect>) {
+        self.clip = clip;
+    }
+
+    /// Run `f` with drawing further limited to `rect`, for content that
+    /// must not spill out of its area
+    pub fn clipped(&mut self, rect: Rect, f: impl FnOnce(&mut Self)) {
+        let saved = self.clip;
+        let clip = match saved {
+            Some(clip) => clip.intersect(&rect),
+            None => Some(rect),
+        };
+        if let Some(clip) = clip {
+            self.clip = Some(clip);
+            f(self);
+            self.clip = saved;
+        }
+    }
+
+    /// Report a region whose contents changed and must be repainted
+    pub fn invalidate(&mut self, rect: Rect) {
+        add_rect(&mut self.invalid, rect, self.bounds);
+    }
+
+    /// Report that everything must be repainted
+    pub fn invalidate_all(&mut self) {
+        self.invalid.clear();
+        self.invalid.push(self.bounds);
+    }
+
+    /// Regions waiting to be repainted
+    pub fn invalid(&self) -> &[Rect] {
+        &self.invalid
+    }
+
+    /// Repaint the invalid regions
+    ///
+    /// `paint` is called once per region and should draw everything that
+    /// is visible there; drawing is clipped to the region. Returns the
+    /// number of pixels repainted.
+    pub fn compose(&mut self, mut paint: impl FnMut(&mut Compositor, Rect)) -> u64 {
+        if self.invalid.is_empty() {
+            return 0;
+        }
+        let regions = core::mem::take(&mut self.invalid);
+        let mut area = 0;
+        for &r in &regions {
+            self.clip = Some(r);
+            paint(self, r);
+            area += r.w as u64 * r.h as u64;
+        }
+        self.clip = None;
+        self.redraw.record(area);
+        area
+    }
+
+    /// Repaint counters
+    pub fn redraw_stats(&self) -> RedrawStats {
+        self.redraw
+    }
+
+    /// Set a single pixel
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: u32) {
+        self.fill_rect(x, y, 1, 1, color);
+    }
+
+    /// Read a pixel from the back buffers
+    pub fn get_pixel(&self, x: i32, y: i32) -> u32 {
+        match self.outputs.iter().find(|o| o.display.rect.contains(x, y)) {
+            Some(o) => {
+                let d = o.display.rect;
+                o.back[((y - d.y) as u32 * d.w + (x - d.x) as u32) as usize]
+            }
+            None => 0,
+        }
+    }
+
+    /// Fill a rectangle
+    pub fn fill_rect(&mut self, x: i32, y: i32, w: u32, h: u32, color: u32) {
+        self.each_output(Rect::new(x, y, w, h), |o, r| {
+            for row in r.y..r.bottom() {
+                o.span_mut(r.x, row, r.w).fill(color);
+            }
+        });
+    }
+
+    /// Copy a `w`-pixel-wide source image into the back buffers at (x, y)
+    pub fn blit(&mut self, src: &[u32], src_w: u32, x: i32, y: i32) {
+        if src_w == 0 {
+            return;
+        }
+        let src_h = (src.len() / src_w as usize) as u32;
+        self.each_output(Rect::new(x, y, src_w, src_h), |o, r| {
+            let d = o.display.rect;
+            let sx = (r.x + d.x - x) as usize;
+            for row in r.y..r.bottom() {
+                let src_start = (row + d.y - y) as usize * src_w as usize + sx;
+                o.span_mut(r.x, row, r.w)
+                    .copy_from_slice(&src[src_start..src_start + r.w as usize]);
+            }
+        });
+    }
+
+    /// Copy a `src_w` by `src_h` image from a shared memory object into the
+    /// back buffers at (x, y), straight from the object's pages
+    pub fn blit_shared(&mut self, src: &shm::Object, src_w: u32, src_h: u32, x: i32, y: i32) {
+        if src_w as u64 * src_h as u64 * 4 > src.size() {
+            return;
+        }
+        self.each_output(Rect::new(x, y, src_w, src_h), |o, r| {
+            let d = o.display.rect;
+            let sx = (r.x + d.x - x) as u64;
+            for row in r.y..r.bottom() {
+                let offset = ((row + d.y - y) as u64 * src_w as u64 + sx) * 4;
+                let span = o.span_mut(r.x, row, r.w);
+                let mut at = 0;
+                // Pieces end at page boundaries, so they hold whole pixels
+                src.with_bytes(offset, r.w as usize * 4, |piece| {
+                    let (_, pixels, _) = unsafe { piece.align_to::<u32>() };
+                    span[at..at + pixels.len()].copy_from_slice(pixels);
+                    at += pixels.len();
+                });
+            }
+        });
+    }
+
+    /// Blend an ARGB color over a single pixel
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: u32) {
+        if color >> 24 == 0 {
+            return;
+        }
+        self.each_output(Rect::new(x, y, 1, 1), |o, r| {
+            let px = &mut o.span_mut(r.x, r.y, 1)[0];
+            *px = colors::blend(*px, color);
+        });
+    }
+
+    /// Fill a rectangle with a translucent ARGB color
+    pub fn fill_rect_alpha(&mut self, x: i32, y: i32, w: u32, h: u32, color: u32) {
+        if color >> 24 == 0 {
+            return;
+        }
+        self.each_output(Rect::new(x, y, w, h), |o, r| {
+            for row in r.y..r.bottom() {
+                for px in o.span_mut(r.x, row, r.w) {
+                    *px = colors::blend(*px, color);
+                }
+            }
+        });
+    }
+
+    /// Present damaged regions on every display
+    ///
+    /// Returns the number of pixels copied.
+    pub fn flip(&mut self) -> u64 {
+        if self.outputs.iter().all(|o| o.damage.is_empty()) {
+            return 0;
+        }
+
+        wait_vretrace();
+
+        let copied = self.outputs.iter_mut().map(|o| o.flip()).sum();
+        self.frames += 1;
+        self.pixels_copied += copied;
+        copied
+    }
+}
+
+/// Copy one scanline to video memory, with the widest stores the CPU has
+unsafe fn copy_row(dst: *mut u32, src: &[u32]) {
+    simd::memcpy(dst as *mut u8, src.as_ptr() as *const u8, src.len() * 4);
+}
+
+/// Wait for the start of the next vertical retrace (bounded)
+///
+/// Works on VGA-compatible adapters including QEMU's std VGA and Bochs
+/// VBE; elsewhere the status bit never toggles and the timeout applies.
+fn wait_vretrace() {
+    let in_retrace = || unsafe { crate::drivers::input::inb(VGA_INPUT_STATUS_1) } & 0x08 != 0;
+
+    // If we are mid-retrace, wait for it to end so we catch a full interval
+    let mut spins = 0;
+    while in_retrace() && spins < VRETRACE_TIMEOUT {
+        spins += 1;
+    }
+    spins = 0;
+    while !in_retrace() && spins < VRETRACE_TIMEOUT {
+        spins += 1;
+    }
+}
+
+lazy_static! {
+    static ref COMPOSITOR: Mutex<Option<Compositor>> = Mutex::new(None);
+}
+
+/// Create the compositor with a back buffer per display
+///
+/// Returns false if there is no framebuffer or the back buffer does not fit
+/// in the heap; drawing then falls back to direct framebuffer writes.
+pub fn init() -> bool {
+    let displays = display::list();
+    if displays.is_empty() {
+        warn!("compositor", "No framebuffer, compositor disabled");
+        return false;
+    }
+
+    match Compositor::new(&displays) {
+        Some(c) => {
+            for o in c.outputs() {
+                let r = o.display().rect;
+                info!("compositor", "Back buffer {}x{} for display {} ({} KB)",
+                    r.w, r.h, o.display().id, (r.w * r.h * 4) / 1024);
+            }
+            *COMPOSITOR.lock() = Some(c);
+            true
+        }
+        None => {
+            warn!("compositor", "Not enough memory for back buffer, compositor disabled");
+            false
+        }
+    }
+}
+
+/// Release the back buffers, e.g. before a mode switch
+///
+/// Returns true if the compositor was active.
+pub fn shutdown() -> bool {
+    COMPOSITOR.lock().take().is_some()
+}
+
+/// Whether the compositor is active
+pub fn is_active() -> bool {
+    COMPOSITOR.lock().is_some()
+}
+
+/// Run `f` with the compositor, if active
+pub fn with<R>(f: impl FnOnce(&mut Compositor) -> R) -> Option<R> {
+    COMPOSITOR.lock().as_mut().map(f)
+}
+
+/// Repaint invalid regions with `paint`, then present
+///
+/// Returns the number of pixels repainted.
+pub fn compose(paint: impl FnMut(&mut Compositor, Rect)) -> u64 {
+    let area = with(|c| c.compose(paint)).unwrap_or(0);
+    if area > 0 {
+        flip();
+    }
+    area
+}
+
+/// Present pending damage
+///
+/// The cursor is lifted first if the damage overlaps it.
+pub fn flip() -> u64 {
+    with(|c| {
+        let lifted = cursor::begin_flip(c.damaged());
+        let copied = c.flip();
+        cursor::end_flip(lifted);
+        crate::drivers::virtio_gpu::present();
+        copied
+    }).unwrap_or(0)
+}
+
+/// Print compositor statistics
+pub fn print_stats() {
+    match COMPOSITOR.lock().as_ref() {
+        Some(c) => {
+            println!("Compositor:");
+            println!("  Virtual desktop: {}x{}", c.bounds.w, c.bounds.h);
+            for o in &c.outputs {
+                let r = o.display.rect;
+                println!("  Display {}: {}x{} at ({}, {}), {} pending damage rects",
+                    o.display.id, r.w, r.h, r.x, r.y, o.damage.len());
+            }
+            println!("  Frames presented: {}", c.frames);
+            println!("  Pixels copied: {}", c.pixels_copied);
+            println!("  Repaints: {} ({} pixels)", c.redraw.composes, c.redraw.area);
+            println!("  Repaint rate: {} frames/s, {} pixels/s", c.redraw.frames_per_sec, c.redraw.area_per_sec);
+            println!("  Pending invalid rects: {}", c.invalid.len());
+        }
+        None => println!("Compositor not active"),
+    }
+}
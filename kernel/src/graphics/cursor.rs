@@ -0,0 +1,390 @@
+//! Mouse cursor layer
+//!
+//! The cursor is drawn straight onto the framebuffer above everything the
+//! compositor presents. Before drawing, the pixels underneath are saved;
+//! moving the cursor restores them and draws at the new position, so the
+//! compositor never has to redraw anything for a cursor move. When a flip
+//! touches the cursor area the cursor is lifted first and redrawn after.
+//!
+//! Mouse moves arrive in interrupt context, where the framebuffer may
+//! already be locked. In that case the new position is parked and applied
+//! on the next move or flip.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use crate::drivers::vesa::{self, colors, VesaDriver};
+use crate::graphics::compositor::Rect;
+use crate::println;
+use crate::info;
+
+/// Cursor images are `CURSOR_SIZE` x `CURSOR_SIZE` pixels
+pub const CURSOR_SIZE: usize = 16;
+
+const CURSOR_PIXELS: usize = CURSOR_SIZE * CURSOR_SIZE;
+
+/// ARGB cursor image with its hotspot
+#[derive(Clone, Copy)]
+pub struct CursorImage {
+    pub pixels: [u32; CURSOR_PIXELS],
+    pub hot_x: i32,
+    pub hot_y: i32,
+}
+
+impl CursorImage {
+    /// Build an image from ASCII art: 'X' is black, '.' is white, anything
+    /// else is transparent
+    pub fn from_art(art: &[&str; CURSOR_SIZE], hot_x: i32, hot_y: i32) -> Self {
+        let mut pixels = [0u32; CURSOR_PIXELS];
+        for (y, row) in art.iter().enumerate() {
+            for (x, ch) in row.bytes().take(CURSOR_SIZE).enumerate() {
+                pixels[y * CURSOR_SIZE + x] = match ch {
+                    b'X' => colors::BLACK,
+                    b'.' => colors::WHITE,
+                    _ => 0,
+                };
+            }
+        }
+        Self { pixels, hot_x, hot_y }
+    }
+}
+
+/// Built-in cursor shapes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    Arrow,
+    TextBeam,
+    ResizeHorizontal,
+    ResizeVertical,
+    /// Diagonal resize, top-left to bottom-right
+    ResizeNwse,
+    /// Diagonal resize, top-right to bottom-left
+    ResizeNesw,
+}
+
+impl CursorShape {
+    pub fn image(self) -> CursorImage {
+        match self {
+            Self::Arrow => CursorImage::from_art(&ARROW, 0, 0),
+            Self::TextBeam => CursorImage::from_art(&TEXT_BEAM, 6, 8),
+            Self::ResizeHorizontal => CursorImage::from_art(&RESIZE_H, 7, 7),
+            Self::ResizeVertical => CursorImage::from_art(&RESIZE_V, 7, 7),
+            Self::ResizeNwse => CursorImage::from_art(&RESIZE_NWSE, 7, 7),
+            Self::ResizeNesw => CursorImage::from_art(&RESIZE_NESW, 8, 7),
+        }
+    }
+}
+
+struct CursorLayer {
+    image: CursorImage,
+    shape: CursorShape,
+    x: i32,
+    y: i32,
+    visible: bool,
+    /// Top-left corner of the cursor currently on screen
+    drawn_at: Option<(i32, i32)>,
+    /// Screen contents under the drawn cursor
+    saved: [u32; CURSOR_PIXELS],
+    moves: u64,
+    deferred: u64,
+}
+
+impl CursorLayer {
+    fn new() -> Self {
+        Self {
+            image: CursorShape::Arrow.image(),
+            shape: CursorShape::Arrow,
+            x: 0,
+            y: 0,
+            visible: false,
+            drawn_at: None,
+            saved: [0; CURSOR_PIXELS],
+            moves: 0,
+            deferred: 0,
+        }
+    }
+
+    /// Screen area covered by the cursor at its current position
+    fn rect(&self) -> Rect {
+        Rect::new(
+            self.x - self.image.hot_x,
+            self.y - self.image.hot_y,
+            CURSOR_SIZE as u32,
+            CURSOR_SIZE as u32,
+        )
+    }
+
+    /// Put back the pixels saved under the cursor
+    fn restore(&mut self, driver: &mut VesaDriver) {
+        let (ox, oy) = match self.drawn_at.take() {
+            Some(p) => p,
+            None => return,
+        };
+        let size = CURSOR_SIZE as u32;
+        driver.blit_fast(&self.saved, size, ox, oy, size, size);
+    }
+
+    /// Save the pixels under the cursor, then draw it
+    fn draw(&mut self, driver: &mut VesaDriver) {
+        if !self.visible || !driver.is_initialized() {
+            return;
+        }
+        let r = self.rect();
+        // The whole square is saved, so it goes back in one copy
+        for (i, saved) in self.saved.iter_mut().enumerate() {
+            let (px, py) = (r.x + (i % CURSOR_SIZE) as i32, r.y + (i / CURSOR_SIZE) as i32);
+            if px >= 0 && py >= 0 {
+                *saved = driver.get_color(px as u32, py as u32);
+            }
+        }
+        let size = CURSOR_SIZE as u32;
+        driver.blit_with_alpha(&self.image.pixels, size, r.x, r.y, size, size);
+        self.drawn_at = Some((r.x, r.y));
+    }
+
+    fn redraw(&mut self, driver: &mut VesaDriver) {
+        self.restore(driver);
+        self.draw(driver);
+    }
+}
+
+lazy_static! {
+    static ref CURSOR: Mutex<CursorLayer> = Mutex::new(CursorLayer::new());
+}
+
+/// Position parked by `move_to` when the locks were busy, packed as x:y
+static PENDING_POS: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+fn pack(x: i32, y: i32) -> u64 {
+    ((x as u32 as u64) << 32) | y as u32 as u64
+}
+
+fn unpack(v: u64) -> (i32, i32) {
+    ((v >> 32) as u32 as i32, v as u32 as i32)
+}
+
+/// Show the cursor at the current mouse position
+pub fn init() {
+    if vesa::info().is_none() {
+        return;
+    }
+    let (x, y) = crate::drivers::input::mouse_position();
+    let mut cursor = CURSOR.lock();
+    cursor.x = x;
+    cursor.y = y;
+    cursor.visible = true;
+    cursor.draw(&mut vesa::driver().lock());
+    info!("cursor", "Cursor layer enabled at ({}, {})", x, y);
+}
+
+/// Drop the saved background without drawing it back
+///
+/// Used when the screen is about to be repainted from scratch.
+pub fn forget() {
+    CURSOR.lock().drawn_at = None;
+}
+
+/// Redraw at the mouse position after the screen was cleared or resized
+pub fn reset() {
+    let (x, y) = crate::drivers::input::mouse_position();
+    let mut cursor = CURSOR.lock();
+    cursor.drawn_at = None;
+    cursor.x = x;
+    cursor.y = y;
+    cursor.draw(&mut vesa::driver().lock());
+}
+
+/// Move the cursor; safe to call from the mouse interrupt handler
+pub fn move_to(x: i32, y: i32) {
+    PENDING_POS.store(pack(x, y), Ordering::Release);
+    PENDING.store(true, Ordering::Release);
+    apply_pending();
+}
+
+/// Apply a parked position if the cursor and framebuffer are free
+fn apply_pending() {
+    if !PENDING.load(Ordering::Acquire) {
+        return;
+    }
+    let mut cursor = match CURSOR.try_lock() {
+        Some(c) => c,
+        None => return,
+    };
+    let mut driver = match vesa::driver().try_lock() {
+        Some(d) => d,
+        None => {
+            cursor.deferred += 1;
+            return;
+        }
+    };
+    PENDING.store(false, Ordering::Release);
+    let (x, y) = unpack(PENDING_POS.load(Ordering::Acquire));
+    if (x, y) != (cursor.x, cursor.y) {
+        cursor.x = x;
+        cursor.y = y;
+        cursor.moves += 1;
+        cursor.redraw(&mut driver);
+    }
+}
+
+/// Switch to a built-in cursor shape
+pub fn set_shape(shape: CursorShape) {
+    let mut cursor = CURSOR.lock();
+    if cursor.shape == shape {
+        return;
+    }
+    let mut driver = vesa::driver().lock();
+    cursor.restore(&mut driver);
+    cursor.image = shape.image();
+    cursor.shape = shape;
+    cursor.draw(&mut driver);
+}
+
+/// Lift the cursor off the screen if any of `damage` overlaps it
+///
+/// Returns true if the cursor was lifted; pass that to `end_flip`.
+pub(crate) fn begin_flip(damage: &[Rect]) -> bool {
+    let mut cursor = CURSOR.lock();
+    let drawn = match cursor.drawn_at {
+        Some((x, y)) => Rect::new(x, y, CURSOR_SIZE as u32, CURSOR_SIZE as u32),
+        None => return false,
+    };
+    if !damage.iter().any(|d| d.intersect(&drawn).is_some()) {
+        return false;
+    }
+    cursor.restore(&mut vesa::driver().lock());
+    true
+}
+
+/// Redraw the cursor after a flip and apply any parked move
+pub(crate) fn end_flip(lifted: bool) {
+    if lifted {
+        let mut cursor = CURSOR.lock();
+        cursor.draw(&mut vesa::driver().lock());
+    }
+    apply_pending();
+}
+
+/// Print cursor state
+pub fn print_info() {
+    let cursor = CURSOR.lock();
+    println!("Cursor: {:?} at ({}, {})", cursor.shape, cursor.x, cursor.y);
+    println!("  Visible: {}", cursor.visible);
+    println!("  Moves drawn: {}, deferred from IRQ: {}", cursor.moves, cursor.deferred);
+}
+
+const ARROW: [&str; CURSOR_SIZE] = [
+    "X               ",
+    "XX              ",
+    "X.X             ",
+    "X..X            ",
+    "X...X           ",
+    "X....X          ",
+    "X.....X         ",
+    "X......X        ",
+    "X.......X       ",
+    "X........X      ",
+    "X.....XXXXX     ",
+    "X..X..X         ",
+    "X.X X..X        ",
+    "XX  X..X        ",
+    "X    X..X       ",
+    "      XX        ",
+];
+
+const TEXT_BEAM: [&str; CURSOR_SIZE] = [
+    "   XXX XXX      ",
+    "   X..X..X      ",
+    "   XXX.XXX      ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "     X.X        ",
+    "   XXX.XXX      ",
+    "   X..X..X      ",
+    "   XXX XXX      ",
+];
+
+const RESIZE_H: [&str; CURSOR_SIZE] = [
+    "                ",
+    "                ",
+    "                ",
+    "                ",
+    "    X      X    ",
+    "   XX      XX   ",
+    "  X.XXXXXXXX.X  ",
+    " X............X ",
+    "  X.XXXXXXXX.X  ",
+    "   XX      XX   ",
+    "    X      X    ",
+    "                ",
+    "                ",
+    "                ",
+    "                ",
+    "                ",
+];
+
+const RESIZE_V: [&str; CURSOR_SIZE] = [
+    "                ",
+    "       X        ",
+    "      X.X       ",
+    "     X...X      ",
+    "    XXX.XXX     ",
+    "      X.X       ",
+    "      X.X       ",
+    "      X.X       ",
+    "      X.X       ",
+    "      X.X       ",
+    "      X.X       ",
+    "    XXX.XXX     ",
+    "     X...X      ",
+    "      X.X       ",
+    "       X        ",
+    "                ",
+];
+
+const RESIZE_NWSE: [&str; CURSOR_SIZE] = [
+    "XXXXXX          ",
+    "X....X          ",
+    "X...X           ",
+    "X....X          ",
+    "X.XX..X         ",
+    "XX  X..X        ",
+    "     X..X       ",
+    "      X..X      ",
+    "       X..X     ",
+    "        X..X  XX",
+    "         X..XX.X",
+    "          X....X",
+    "           X...X",
+    "          X....X",
+    "          XXXXXX",
+    "                ",
+];
+
+const RESIZE_NESW: [&str; CURSOR_SIZE] = [
+    "          XXXXXX",
+    "          X....X",
+    "           X...X",
+    "          X....X",
+    "         X..XX.X",
+    "        X..X  XX",
+    "       X..X     ",
+    "      X..X      ",
+    "     X..X       ",
+    "XX  X..X        ",
+    "X.XX..X         ",
+    "X....X          ",
+    "X...X           ",
+    "X....X          ",
+    "XXXXXX          ",
+    "                ",
+];
@@ -0,0 +1,171 @@
+//! Display list
+//!
+//! Every connected output gets a place in one virtual desktop. The VESA
+//! framebuffer (GOP or the primary virtio-gpu scanout) is the primary
+//! display at the origin; extra virtio-gpu scanouts are laid out to its
+//! right in scanout order. Desktop and compositor coordinates are virtual
+//! desktop coordinates. The cursor layer draws through the VESA driver, so
+//! the pointer is only visible while it is over the primary display.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::drivers::{vesa, virtio_gpu};
+use crate::graphics::compositor::Rect;
+use crate::println;
+use crate::info;
+
+/// Where a display's pixels go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// The VESA framebuffer, drawn through the VESA driver
+    Primary,
+    /// An extra virtio-gpu scanout (32bpp, uploaded per damage rectangle)
+    VirtioScanout(u32),
+}
+
+/// A display and its position in the virtual desktop
+#[derive(Debug, Clone, Copy)]
+pub struct Display {
+    pub id: usize,
+    pub output: Output,
+    /// Area covered, in virtual desktop coordinates
+    pub rect: Rect,
+    /// Bytes per scanline (extra scanouts only; the primary uses VESA's)
+    pub pitch: u32,
+    /// Mapped framebuffer (extra scanouts only)
+    pub fb_virt: u64,
+}
+
+impl Display {
+    pub fn is_primary(&self) -> bool {
+        self.output == Output::Primary
+    }
+}
+
+static DISPLAYS: Mutex<Vec<Display>> = Mutex::new(Vec::new());
+
+/// Rebuild the display list from the active outputs
+///
+/// Called at boot and after a mode switch, since the primary's width
+/// moves every display to its right.
+pub fn init() {
+    let mut displays = Vec::new();
+    let mut next_x = 0i32;
+
+    if let Some(info) = vesa::info() {
+        displays.push(Display {
+            id: 0,
+            output: Output::Primary,
+            rect: Rect::new(0, 0, info.width, info.height),
+            pitch: info.pitch,
+            fb_virt: 0,
+        });
+        next_x = info.width as i32;
+
+        for (scanout_id, scanout) in virtio_gpu::extra_scanouts() {
+            displays.push(Display {
+                id: displays.len(),
+                output: Output::VirtioScanout(scanout_id),
+                rect: Rect::new(next_x, 0, scanout.width, scanout.height),
+                pitch: scanout.width * 4,
+                fb_virt: scanout.virt_addr,
+            });
+            next_x += scanout.width as i32;
+        }
+    }
+
+    if displays.len() > 1 {
+        info!("display", "{} displays, virtual desktop {}x{}", displays.len(), next_x,
+            displays.iter().map(|d| d.rect.h).max().unwrap_or(0));
+    }
+    *DISPLAYS.lock() = displays;
+}
+
+/// All displays, primary first
+pub fn list() -> Vec<Display> {
+    DISPLAYS.lock().clone()
+}
+
+/// Number of displays
+pub fn count() -> usize {
+    DISPLAYS.lock().len()
+}
+
+/// Bounding box of all displays
+pub fn virtual_bounds() -> Rect {
+    let displays = DISPLAYS.lock();
+    match displays.split_first() {
+        Some((first, rest)) => rest.iter().fold(first.rect, |acc, d| acc.union(&d.rect)),
+        None => Rect::new(0, 0, 0, 0),
+    }
+}
+
+/// Display containing a virtual desktop point
+pub fn display_at(x: i32, y: i32) -> Option<Display> {
+    find_at(&DISPLAYS.lock(), x, y)
+}
+
+/// Display sharing the most area with `rect`, or the primary if none does
+pub fn display_for(rect: &Rect) -> Option<Display> {
+    find_for(&DISPLAYS.lock(), rect)
+}
+
+fn find_at(displays: &[Display], x: i32, y: i32) -> Option<Display> {
+    displays.iter().find(|d| d.rect.contains(x, y)).copied()
+}
+
+fn find_for(displays: &[Display], rect: &Rect) -> Option<Display> {
+    let area = |d: &Display| d.rect.intersect(rect).map_or(0, |r| r.w as u64 * r.h as u64);
+    displays.iter()
+        .max_by_key(|d| (area(d), d.is_primary()))
+        .copied()
+}
+
+/// Print the display list
+pub fn print_info() {
+    let displays = DISPLAYS.lock();
+    if displays.is_empty() {
+        println!("No displays");
+        return;
+    }
+    println!("Displays:");
+    for d in displays.iter() {
+        let output = match d.output {
+            Output::Primary => alloc::string::String::from("primary (VESA)"),
+            Output::VirtioScanout(id) => alloc::format!("virtio-gpu scanout {}", id),
+        };
+        println!("  {}: {}x{} at ({}, {}) - {}", d.id, d.rect.w, d.rect.h, d.rect.x, d.rect.y, output);
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    fn side_by_side() -> [Display; 2] {
+        let display = |id, output, x| Display { id, output, rect: Rect::new(x, 0, 800, 600), pitch: 3200, fb_virt: 0 };
+        [display(0, Output::Primary, 0), display(1, Output::VirtioScanout(1), 800)]
+    }
+
+    #[kernel_test]
+    fn points_find_the_display_under_them() -> Result<(), String> {
+        let displays = side_by_side();
+        check_eq!(find_at(&displays, 799, 10).map(|d| d.id), Some(0));
+        check_eq!(find_at(&displays, 800, 10).map(|d| d.id), Some(1));
+        check_eq!(find_at(&displays, 100, 600).map(|d| d.id), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn windows_belong_where_most_of_them_is() -> Result<(), String> {
+        let displays = side_by_side();
+        check_eq!(find_for(&displays, &Rect::new(700, 100, 300, 200)).map(|d| d.id), Some(1));
+        check_eq!(find_for(&displays, &Rect::new(600, 100, 300, 200)).map(|d| d.id), Some(0));
+        // Off every display, a window goes to the primary
+        check_eq!(find_for(&displays, &Rect::new(5000, 5000, 10, 10)).map(|d| d.id), Some(0));
+        Ok(())
+    }
+}
@@ -0,0 +1,658 @@
+//! Framebuffer console
+//!
+//! A text terminal drawn on the VESA framebuffer with the active font.
+//! Once enabled it replaces the VGA text path for `print!`/`println!`.
+//! It keeps a scrollback buffer and understands the common ANSI escape
+//! sequences: cursor movement and positioning, erase in line/display,
+//! save/restore cursor, and SGR colors (16, 256 and 24-bit).
+//!
+//! Output can arrive while another path holds the framebuffer lock (the
+//! VESA driver prints during its own init, for example). Text always lands
+//! in the cell grid; rows that could not be drawn stay dirty and are
+//! painted on the next write that gets the lock.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::drivers::vesa::{self, VesaDriver};
+use crate::graphics::font;
+use crate::{info, warn};
+
+/// Lines kept above the visible screen
+const SCROLLBACK_LINES: usize = 200;
+
+const TAB_WIDTH: usize = 8;
+
+/// Maximum parameters in one CSI sequence
+const MAX_PARAMS: usize = 8;
+
+/// Height of the underline cursor in pixels
+const CURSOR_HEIGHT: u32 = 2;
+
+/// Standard 16-color terminal palette (0xAARRGGBB)
+const PALETTE: [u32; 16] = [
+    0xFF000000, 0xFFAA0000, 0xFF00AA00, 0xFFAA5500,
+    0xFF0000AA, 0xFFAA00AA, 0xFF00AAAA, 0xFFAAAAAA,
+    0xFF555555, 0xFFFF5555, 0xFF55FF55, 0xFFFFFF55,
+    0xFF5555FF, 0xFFFF55FF, 0xFF55FFFF, 0xFFFFFFFF,
+];
+
+const DEFAULT_FG: usize = 7;
+const DEFAULT_BG: usize = 0;
+
+/// Foreground or background color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    /// Palette entry; 0-7 are brightened by bold
+    Indexed(u8),
+    /// Direct 0xAARRGGBB value
+    Rgb(u32),
+}
+
+impl Color {
+    fn resolve(self, bold: bool) -> u32 {
+        match self {
+            Self::Indexed(i) if bold && i < 8 => PALETTE[i as usize + 8],
+            Self::Indexed(i) => xterm_color(i),
+            Self::Rgb(c) => c,
+        }
+    }
+}
+
+/// xterm 256-color palette entry
+fn xterm_color(i: u8) -> u32 {
+    match i {
+        0..=15 => PALETTE[i as usize],
+        16..=231 => {
+            const LEVELS: [u32; 6] = [0, 95, 135, 175, 215, 255];
+            let n = (i - 16) as usize;
+            0xFF000000 | (LEVELS[n / 36] << 16) | (LEVELS[(n / 6) % 6] << 8) | LEVELS[n % 6]
+        }
+        _ => {
+            let v = 8 + 10 * (i - 232) as u32;
+            0xFF000000 | (v << 16) | (v << 8) | v
+        }
+    }
+}
+
+/// One character cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    fg: u32,
+    bg: u32,
+}
+
+/// Escape sequence parser state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    Normal,
+    /// Saw ESC
+    Escape,
+    /// Inside `ESC [`
+    Csi,
+}
+
+pub struct FbCon {
+    cols: usize,
+    rows: usize,
+    cell_w: u32,
+    cell_h: u32,
+    cells: Vec<Cell>,
+    dirty: Vec<bool>,
+    scrollback: VecDeque<Vec<Cell>>,
+    /// Lines scrolled back from the live screen (0 = live)
+    view: usize,
+    col: usize,
+    row: usize,
+    /// Set after writing the last column; the next character wraps first
+    wrap_pending: bool,
+    saved_cursor: (usize, usize),
+    cursor_visible: bool,
+    /// Cell where the cursor is currently drawn
+    cursor_drawn: Option<(usize, usize)>,
+    fg: Color,
+    bg: Color,
+    bold: bool,
+    reverse: bool,
+    state: ParseState,
+    params: [u32; MAX_PARAMS],
+    param_count: usize,
+    private: bool,
+    /// Drawing allowed; false while a graphical app owns the screen
+    active: bool,
+}
+
+impl FbCon {
+    /// Create a console filling a `width` x `height` pixel screen
+    fn new(width: u32, height: u32) -> Option<Self> {
+        let (cell_w, cell_h) = font::cell_size();
+        let cols = (width / cell_w) as usize;
+        let rows = (height / cell_h) as usize;
+        if cols == 0 || rows == 0 {
+            return None;
+        }
+
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(cols * rows).ok()?;
+
+        let mut con = Self {
+            cols,
+            rows,
+            cell_w,
+            cell_h,
+            cells,
+            dirty: alloc::vec![true; rows],
+            scrollback: VecDeque::new(),
+            view: 0,
+            col: 0,
+            row: 0,
+            wrap_pending: false,
+            saved_cursor: (0, 0),
+            cursor_visible: true,
+            cursor_drawn: None,
+            fg: Color::Indexed(DEFAULT_FG as u8),
+            bg: Color::Indexed(DEFAULT_BG as u8),
+            bold: false,
+            reverse: false,
+            state: ParseState::Normal,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            private: false,
+            active: true,
+        };
+        let blank = con.blank();
+        con.cells.resize(cols * rows, blank);
+        Some(con)
+    }
+
+    /// Empty cell in the current background color
+    fn blank(&self) -> Cell {
+        let (_, bg) = self.colors();
+        Cell { ch: ' ', fg: bg, bg }
+    }
+
+    /// Effective (foreground, background) after bold and reverse video
+    fn colors(&self) -> (u32, u32) {
+        let fg = self.fg.resolve(self.bold);
+        let bg = self.bg.resolve(false);
+        if self.reverse { (bg, fg) } else { (fg, bg) }
+    }
+
+    /// Feed one character through the escape parser
+    fn put_char(&mut self, ch: char, driver: &mut Option<&mut VesaDriver>) {
+        match self.state {
+            ParseState::Normal => self.put_normal(ch, driver),
+            ParseState::Escape => {
+                self.state = ParseState::Normal;
+                match ch {
+                    '[' => {
+                        self.state = ParseState::Csi;
+                        self.params = [0; MAX_PARAMS];
+                        self.param_count = 0;
+                        self.private = false;
+                    }
+                    '7' => self.saved_cursor = (self.col, self.row),
+                    '8' => self.restore_cursor(),
+                    'c' => self.reset(),
+                    _ => {}
+                }
+            }
+            ParseState::Csi => match ch {
+                '0'..='9' => {
+                    if self.param_count == 0 {
+                        self.param_count = 1;
+                    }
+                    let p = &mut self.params[self.param_count - 1];
+                    *p = p.saturating_mul(10).saturating_add(ch as u32 - '0' as u32);
+                }
+                ';' => {
+                    if self.param_count == 0 {
+                        self.param_count = 1;
+                    }
+                    if self.param_count < MAX_PARAMS {
+                        self.param_count += 1;
+                    }
+                }
+                '?' => self.private = true,
+                '\x40'..='\x7e' => {
+                    self.state = ParseState::Normal;
+                    self.csi_dispatch(ch);
+                }
+                _ => self.state = ParseState::Normal,
+            },
+        }
+    }
+
+    fn put_normal(&mut self, ch: char, driver: &mut Option<&mut VesaDriver>) {
+        match ch {
+            '\x1b' => self.state = ParseState::Escape,
+            '\n' => {
+                self.col = 0;
+                self.line_feed(driver);
+            }
+            '\r' => {
+                self.col = 0;
+                self.wrap_pending = false;
+            }
+            '\t' => {
+                self.col = ((self.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols - 1);
+                self.wrap_pending = false;
+            }
+            '\x08' => {
+                self.col = self.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            '\x07' => {}
+            c if (c as u32) < 0x20 => {}
+            c => {
+                if self.wrap_pending {
+                    self.col = 0;
+                    self.line_feed(driver);
+                }
+                let (fg, bg) = self.colors();
+                self.cells[self.row * self.cols + self.col] = Cell { ch: c, fg, bg };
+                self.dirty[self.row] = true;
+                if self.col + 1 < self.cols {
+                    self.col += 1;
+                } else {
+                    self.wrap_pending = true;
+                }
+            }
+        }
+    }
+
+    /// Move down a line, scrolling at the bottom
+    fn line_feed(&mut self, driver: &mut Option<&mut VesaDriver>) {
+        self.wrap_pending = false;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        // Lift the cursor while the grid still matches the screen
+        let can_scroll = self.active && self.view == 0;
+        if let (Some(d), true) = (driver.as_deref_mut(), can_scroll) {
+            self.erase_cursor(d);
+        }
+
+        // Retire the top line into scrollback, reusing the oldest line's buffer
+        let mut line = if self.scrollback.len() >= SCROLLBACK_LINES {
+            self.scrollback.pop_front().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        line.clear();
+        line.extend_from_slice(&self.cells[..self.cols]);
+        self.scrollback.push_back(line);
+
+        self.cells.copy_within(self.cols.., 0);
+        let blank = self.blank();
+        let last = (self.rows - 1) * self.cols;
+        self.cells[last..].fill(blank);
+
+        // Move the pixels too when we can draw right now; dirty flags
+        // travel with their rows
+        match driver.as_deref_mut() {
+            Some(d) if can_scroll => {
+                d.scroll_up(0, self.rows as u32 * self.cell_h, self.cell_h, blank.bg);
+                self.dirty.remove(0);
+                self.dirty.push(false);
+            }
+            _ => {
+                self.dirty.fill(true);
+                self.cursor_drawn = None;
+            }
+        }
+    }
+
+    fn csi_dispatch(&mut self, cmd: char) {
+        let n = self.params[0].max(1) as usize;
+        let (p0, p1) = (self.params[0] as usize, self.params[1] as usize);
+
+        if self.private {
+            if self.params[0] == 25 {
+                match cmd {
+                    'h' => self.cursor_visible = true,
+                    'l' => self.cursor_visible = false,
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        self.wrap_pending = false;
+        match cmd {
+            'A' => self.row = self.row.saturating_sub(n),
+            'B' => self.row = (self.row + n).min(self.rows - 1),
+            'C' => self.col = (self.col + n).min(self.cols - 1),
+            'D' => self.col = self.col.saturating_sub(n),
+            'E' => {
+                self.row = (self.row + n).min(self.rows - 1);
+                self.col = 0;
+            }
+            'F' => {
+                self.row = self.row.saturating_sub(n);
+                self.col = 0;
+            }
+            'G' => self.col = (n - 1).min(self.cols - 1),
+            'd' => self.row = (n - 1).min(self.rows - 1),
+            'H' | 'f' => {
+                self.row = (p0.max(1) - 1).min(self.rows - 1);
+                self.col = (p1.max(1) - 1).min(self.cols - 1);
+            }
+            'J' => {
+                let cursor = self.row * self.cols + self.col;
+                match p0 {
+                    0 => self.erase(cursor, self.cells.len()),
+                    1 => self.erase(0, cursor + 1),
+                    2 => self.erase(0, self.cells.len()),
+                    3 => {
+                        self.erase(0, self.cells.len());
+                        self.scrollback.clear();
+                        self.view = 0;
+                    }
+                    _ => {}
+                }
+            }
+            'K' => {
+                let start = self.row * self.cols;
+                let cursor = start + self.col;
+                match p0 {
+                    0 => self.erase(cursor, start + self.cols),
+                    1 => self.erase(start, cursor + 1),
+                    2 => self.erase(start, start + self.cols),
+                    _ => {}
+                }
+            }
+            'm' => self.sgr(),
+            's' => self.saved_cursor = (self.col, self.row),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition
+    fn sgr(&mut self) {
+        let count = self.param_count.max(1);
+        let mut i = 0;
+        while i < count {
+            match self.params[i] {
+                0 => {
+                    self.fg = Color::Indexed(DEFAULT_FG as u8);
+                    self.bg = Color::Indexed(DEFAULT_BG as u8);
+                    self.bold = false;
+                    self.reverse = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                p @ 30..=37 => self.fg = Color::Indexed((p - 30) as u8),
+                39 => self.fg = Color::Indexed(DEFAULT_FG as u8),
+                p @ 40..=47 => self.bg = Color::Indexed((p - 40) as u8),
+                49 => self.bg = Color::Indexed(DEFAULT_BG as u8),
+                p @ 90..=97 => self.fg = Color::Indexed((p - 90 + 8) as u8),
+                p @ 100..=107 => self.bg = Color::Indexed((p - 100 + 8) as u8),
+                p @ (38 | 48) => {
+                    let color = match self.params.get(i + 1) {
+                        Some(5) if i + 2 < count => {
+                            let c = Color::Indexed(self.params[i + 2].min(255) as u8);
+                            i += 2;
+                            Some(c)
+                        }
+                        Some(2) if i + 4 < count => {
+                            let r = self.params[i + 2].min(255);
+                            let g = self.params[i + 3].min(255);
+                            let b = self.params[i + 4].min(255);
+                            i += 4;
+                            Some(Color::Rgb(0xFF000000 | (r << 16) | (g << 8) | b))
+                        }
+                        _ => None,
+                    };
+                    if let Some(c) = color {
+                        if p == 38 { self.fg = c } else { self.bg = c }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    fn erase(&mut self, start: usize, end: usize) {
+        let end = end.min(self.cells.len());
+        if start >= end {
+            return;
+        }
+        let blank = self.blank();
+        self.cells[start..end].fill(blank);
+        for row in start / self.cols..=(end - 1) / self.cols {
+            self.dirty[row] = true;
+        }
+    }
+
+    fn restore_cursor(&mut self) {
+        let (col, row) = self.saved_cursor;
+        self.col = col.min(self.cols - 1);
+        self.row = row.min(self.rows - 1);
+        self.wrap_pending = false;
+    }
+
+    /// Back to power-on state
+    fn reset(&mut self) {
+        self.fg = Color::Indexed(DEFAULT_FG as u8);
+        self.bg = Color::Indexed(DEFAULT_BG as u8);
+        self.bold = false;
+        self.reverse = false;
+        self.cursor_visible = true;
+        self.erase(0, self.cells.len());
+        self.col = 0;
+        self.row = 0;
+    }
+
+    /// Cell shown at screen row `row`, accounting for scrollback view
+    fn visible_cell(&self, row: usize, col: usize) -> Cell {
+        let top = self.scrollback.len() - self.view;
+        let line = top + row;
+        if line < self.scrollback.len() {
+            self.scrollback[line].get(col).copied().unwrap_or(Cell { ch: ' ', fg: PALETTE[0], bg: PALETTE[0] })
+        } else {
+            self.cells[(line - self.scrollback.len()) * self.cols + col]
+        }
+    }
+
+    fn draw_cell(&self, driver: &mut VesaDriver, row: usize, col: usize) {
+        let cell = self.visible_cell(row, col);
+        let x = (col as u32 * self.cell_w) as i32;
+        let y = (row as u32 * self.cell_h) as i32;
+        driver.fill_rect(x, y, self.cell_w, self.cell_h, cell.bg);
+        if cell.ch != ' ' {
+            font::glyph(cell.ch).for_each_run(|gx, gy, len| {
+                driver.fill_rect(x + gx as i32, y + gy as i32, len, 1, cell.fg);
+            });
+        }
+    }
+
+    fn erase_cursor(&mut self, driver: &mut VesaDriver) {
+        if let Some((col, row)) = self.cursor_drawn.take() {
+            self.draw_cell(driver, row, col);
+        }
+    }
+
+    /// Paint dirty rows and the cursor
+    fn render(&mut self, driver: &mut VesaDriver) {
+        if !self.active || !driver.is_initialized() {
+            return;
+        }
+        self.erase_cursor(driver);
+
+        for row in 0..self.rows {
+            if !self.dirty[row] {
+                continue;
+            }
+            for col in 0..self.cols {
+                self.draw_cell(driver, row, col);
+            }
+            self.dirty[row] = false;
+        }
+
+        if self.cursor_visible && self.view == 0 {
+            let (fg, _) = self.colors();
+            let x = (self.col as u32 * self.cell_w) as i32;
+            let y = ((self.row + 1) as u32 * self.cell_h - CURSOR_HEIGHT) as i32;
+            driver.fill_rect(x, y, self.cell_w, CURSOR_HEIGHT, fg);
+            self.cursor_drawn = Some((self.col, self.row));
+        }
+    }
+
+    fn write_str(&mut self, s: &str) {
+        // Never block: the caller may be printing while holding the driver
+        let mut guard = vesa::driver().try_lock();
+        let mut driver = guard.as_deref_mut();
+
+        if self.view != 0 {
+            self.view = 0;
+            self.dirty.fill(true);
+        }
+        for ch in s.chars() {
+            self.put_char(ch, &mut driver);
+        }
+        if let Some(d) = driver {
+            self.render(d);
+        }
+    }
+
+    /// Scroll the view by `delta` lines (positive = back into history)
+    fn scroll_view(&mut self, delta: isize) {
+        let view = (self.view as isize + delta).clamp(0, self.scrollback.len() as isize) as usize;
+        if view != self.view {
+            self.view = view;
+            self.dirty.fill(true);
+            self.render(&mut vesa::driver().lock());
+        }
+    }
+}
+
+static FBCON: Mutex<Option<FbCon>> = Mutex::new(None);
+
+/// Fast check for the console writer, avoiding the lock when disabled
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Start the framebuffer console and route console output to it
+pub fn init() -> bool {
+    let info = match vesa::info() {
+        Some(info) => info,
+        None => return false,
+    };
+    let con = match FbCon::new(info.width, info.height) {
+        Some(con) => con,
+        None => {
+            warn!("fbcon", "Not enough memory for console, staying on VGA text");
+            return false;
+        }
+    };
+    let (cols, rows) = (con.cols, con.rows);
+    vesa::driver().lock().clear(PALETTE[DEFAULT_BG]);
+    *FBCON.lock() = Some(con);
+    ENABLED.store(true, Ordering::Release);
+
+    info!("fbcon", "Framebuffer console {}x{} ({} lines of scrollback)", cols, rows, SCROLLBACK_LINES);
+    true
+}
+
+/// Rebuild the console for the current screen size
+///
+/// History carries over: the old scrollback and the lines on screen up to
+/// the cursor become the new console's scrollback.
+pub fn resize() {
+    let info = match vesa::info() {
+        Some(info) => info,
+        None => return,
+    };
+    let mut guard = FBCON.lock();
+    let old = match guard.take() {
+        Some(old) => old,
+        None => return,
+    };
+    let mut con = match FbCon::new(info.width, info.height) {
+        Some(con) => con,
+        None => {
+            drop(guard);
+            ENABLED.store(false, Ordering::Release);
+            warn!("fbcon", "Not enough memory for console, staying on VGA text");
+            return;
+        }
+    };
+
+    let FbCon { mut scrollback, cells, cols, row, active, .. } = old;
+    for line in cells.chunks(cols).take(row) {
+        if scrollback.len() >= SCROLLBACK_LINES {
+            scrollback.pop_front();
+        }
+        scrollback.push_back(line.to_vec());
+    }
+    con.scrollback = scrollback;
+    con.active = active;
+
+    if active {
+        let mut driver = vesa::driver().lock();
+        driver.clear(PALETTE[DEFAULT_BG]);
+        con.render(&mut driver);
+    }
+    *guard = Some(con);
+}
+
+/// Whether console output goes to the framebuffer
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Write text, interpreting control characters and ANSI escapes
+pub fn write_str(s: &str) {
+    if let Some(con) = FBCON.lock().as_mut() {
+        con.write_str(s);
+    }
+    crate::drivers::virtio_gpu::present();
+}
+
+/// Stop drawing while a graphical program owns the screen
+///
+/// Output is still recorded and appears on `resume`.
+pub fn suspend() {
+    if let Some(con) = FBCON.lock().as_mut() {
+        con.active = false;
+        con.cursor_drawn = None;
+    }
+}
+
+/// Take the screen back and repaint everything
+pub fn resume() {
+    if let Some(con) = FBCON.lock().as_mut() {
+        con.active = true;
+        con.dirty.fill(true);
+        let mut driver = vesa::driver().lock();
+        let bg = PALETTE[DEFAULT_BG];
+        let (w, h) = (driver.info().width, driver.info().height);
+        driver.fill_rect(0, 0, w, h, bg);
+        con.render(&mut driver);
+    }
+}
+
+/// Scroll back into history by half a screen
+pub fn scroll_back() {
+    if let Some(con) = FBCON.lock().as_mut() {
+        let page = (con.rows / 2) as isize;
+        con.scroll_view(page);
+    }
+}
+
+/// Scroll toward the live screen by half a screen
+pub fn scroll_forward() {
+    if let Some(con) = FBCON.lock().as_mut() {
+        let page = (con.rows / 2) as isize;
+        con.scroll_view(-page);
+    }
+}
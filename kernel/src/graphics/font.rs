@@ -0,0 +1,660 @@
+//! Bitmap fonts
+//!
+//! The built-in 8x16 font covers printable ASCII, box drawing (U+2500 to
+//! U+257F) and block elements (U+2580 to U+259F). A PC Screen Font (PSF1 or
+//! PSF2) loaded from the VFS replaces it as the active font. Characters the
+//! active font lacks fall back to the built-in font, then to an ASCII
+//! look-alike, and finally to a hollow replacement box.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::{self, FsError};
+use crate::println;
+use crate::{info, warn};
+
+/// Width of the built-in font
+pub const BUILTIN_WIDTH: u32 = 8;
+
+/// Height of the built-in font
+pub const BUILTIN_HEIGHT: u32 = 16;
+
+/// Largest glyph dimension a loaded font may use
+pub const MAX_GLYPH_SIZE: u32 = 32;
+
+/// Font loaded at boot if present
+const DEFAULT_FONT_PATH: &str = "/etc/font.psf";
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE512: u8 = 0x01;
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_STARTSEQ: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_STARTSEQ: u8 = 0xFE;
+
+/// Font loading error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// Not a PSF1 or PSF2 file
+    BadMagic,
+    /// File ends before the header says it should
+    Truncated,
+    /// Glyphs wider or taller than `MAX_GLYPH_SIZE`, or zero-sized
+    BadDimensions,
+    /// Could not read the file
+    Io(FsError),
+}
+
+/// A single rendered glyph, one bitmask per row (MSB-first within `width` bits)
+#[derive(Debug, Clone, Copy)]
+pub struct Glyph {
+    pub width: u32,
+    pub height: u32,
+    rows: [u32; MAX_GLYPH_SIZE as usize],
+}
+
+impl Glyph {
+    const fn empty(width: u32, height: u32) -> Self {
+        Self { width, height, rows: [0; MAX_GLYPH_SIZE as usize] }
+    }
+
+    /// Decode a PSF-style bitmap: rows padded to whole bytes, MSB is leftmost
+    fn from_bytes(width: u32, height: u32, bytes: &[u8]) -> Self {
+        let mut glyph = Self::empty(width, height);
+        let stride = ((width + 7) / 8) as usize;
+        for (y, row) in bytes.chunks(stride).take(height as usize).enumerate() {
+            let bits = row.iter().fold(0u32, |acc, &b| (acc << 8) | b as u32);
+            glyph.rows[y] = bits >> (stride as u32 * 8 - width);
+        }
+        glyph
+    }
+
+    /// Build a glyph by evaluating `f(x, y)` for every pixel
+    fn from_fn(width: u32, height: u32, f: impl Fn(u32, u32) -> bool) -> Self {
+        let mut glyph = Self::empty(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                if f(x, y) {
+                    glyph.rows[y as usize] |= 1 << (width - 1 - x);
+                }
+            }
+        }
+        glyph
+    }
+
+    /// Whether pixel (x, y) is set
+    pub fn is_set(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.rows[y as usize] & (1 << (self.width - 1 - x)) != 0
+    }
+
+    /// Call `f(x, y, len)` for every horizontal run of set pixels
+    ///
+    /// Lets renderers fill whole spans instead of testing each pixel.
+    pub fn for_each_run(&self, mut f: impl FnMut(u32, u32, u32)) {
+        for y in 0..self.height {
+            let mut x = 0;
+            while x < self.width {
+                if !self.is_set(x, y) {
+                    x += 1;
+                    continue;
+                }
+                let start = x;
+                while x < self.width && self.is_set(x, y) {
+                    x += 1;
+                }
+                f(start, y, x - start);
+            }
+        }
+    }
+}
+
+/// A font loaded from a PSF file
+pub struct Font {
+    name: String,
+    width: u32,
+    height: u32,
+    glyph_size: usize,
+    glyph_count: usize,
+    data: Vec<u8>,
+    unicode: BTreeMap<char, u32>,
+}
+
+impl Font {
+    /// Parse a PSF1 or PSF2 font
+    pub fn from_psf(name: &str, data: &[u8]) -> Result<Self, FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::from_psf2(name, data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::from_psf1(name, data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn from_psf1(name: &str, data: &[u8]) -> Result<Self, FontError> {
+        if data.len() < 4 {
+            return Err(FontError::Truncated);
+        }
+        let mode = data[2];
+        let height = data[3] as u32;
+        let glyph_count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+        let glyph_size = height as usize;
+        let mut font = Self::new(name, data, 4, BUILTIN_WIDTH, height, glyph_size, glyph_count)?;
+
+        if mode & PSF1_MODEHASTAB != 0 {
+            let table = &data[4 + glyph_size * glyph_count..];
+            let mut index = 0u32;
+            let mut in_sequence = false;
+            for entry in table.chunks_exact(2) {
+                match u16::from_le_bytes([entry[0], entry[1]]) {
+                    PSF1_SEPARATOR => {
+                        index += 1;
+                        in_sequence = false;
+                    }
+                    PSF1_STARTSEQ => in_sequence = true,
+                    cp if !in_sequence => {
+                        if let Some(ch) = char::from_u32(cp as u32) {
+                            font.unicode.entry(ch).or_insert(index);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(font)
+    }
+
+    fn from_psf2(name: &str, data: &[u8]) -> Result<Self, FontError> {
+        if data.len() < 32 {
+            return Err(FontError::Truncated);
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let header_size = field(8) as usize;
+        let flags = field(12);
+        let glyph_count = field(16) as usize;
+        let glyph_size = field(20) as usize;
+        let height = field(24);
+        let width = field(28);
+
+        if glyph_size < ((width as usize + 7) / 8) * height as usize {
+            return Err(FontError::Truncated);
+        }
+        let mut font = Self::new(name, data, header_size, width, height, glyph_size, glyph_count)?;
+
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let table = &data[header_size + glyph_size * glyph_count..];
+            for (index, entry) in table.split(|&b| b == PSF2_SEPARATOR).enumerate() {
+                // Only single code points; combining sequences follow STARTSEQ
+                let singles = entry.split(|&b| b == PSF2_STARTSEQ).next().unwrap_or(&[]);
+                if let Ok(s) = core::str::from_utf8(singles) {
+                    for ch in s.chars() {
+                        font.unicode.entry(ch).or_insert(index as u32);
+                    }
+                }
+            }
+        }
+        Ok(font)
+    }
+
+    fn new(
+        name: &str,
+        data: &[u8],
+        offset: usize,
+        width: u32,
+        height: u32,
+        glyph_size: usize,
+        glyph_count: usize,
+    ) -> Result<Self, FontError> {
+        if width == 0 || height == 0 || width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE {
+            return Err(FontError::BadDimensions);
+        }
+        let end = glyph_size
+            .checked_mul(glyph_count)
+            .and_then(|len| len.checked_add(offset))
+            .ok_or(FontError::Truncated)?;
+        if end > data.len() {
+            return Err(FontError::Truncated);
+        }
+
+        Ok(Self {
+            name: String::from(name),
+            width,
+            height,
+            glyph_size,
+            glyph_count,
+            data: data[offset..end].to_vec(),
+            unicode: BTreeMap::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    /// Glyph for `ch`, if the font has one
+    ///
+    /// Fonts without a Unicode table are assumed to be indexed by code point,
+    /// which is only trusted for ASCII.
+    pub fn glyph(&self, ch: char) -> Option<Glyph> {
+        let index = if self.unicode.is_empty() {
+            if !ch.is_ascii() {
+                return None;
+            }
+            ch as usize
+        } else {
+            *self.unicode.get(&ch)? as usize
+        };
+        if index >= self.glyph_count {
+            return None;
+        }
+        let start = index * self.glyph_size;
+        Some(Glyph::from_bytes(self.width, self.height, &self.data[start..start + self.glyph_size]))
+    }
+}
+
+/// Active loaded font; `None` means the built-in font
+static FONT: Mutex<Option<Font>> = Mutex::new(None);
+
+/// Load the default font if one is installed
+pub fn init() {
+    match load_psf(DEFAULT_FONT_PATH) {
+        Ok(()) => {}
+        Err(FontError::Io(_)) => {
+            info!("font", "Using built-in {}x{} font", BUILTIN_WIDTH, BUILTIN_HEIGHT);
+        }
+        Err(e) => warn!("font", "Failed to load {}: {:?}", DEFAULT_FONT_PATH, e),
+    }
+}
+
+/// Load a PSF font from the VFS and make it the active font
+pub fn load_psf(path: &str) -> Result<(), FontError> {
+    let data = fs::read_file(path).map_err(FontError::Io)?;
+    let font = Font::from_psf(path, &data)?;
+    info!("font", "Loaded {} ({}x{}, {} glyphs, {} mapped)",
+        path, font.width, font.height, font.glyph_count, font.unicode.len());
+    *FONT.lock() = Some(font);
+    Ok(())
+}
+
+/// Return to the built-in font
+pub fn reset() {
+    *FONT.lock() = None;
+}
+
+/// Character cell size of the active font
+pub fn cell_size() -> (u32, u32) {
+    match FONT.lock().as_ref() {
+        Some(font) => (font.width, font.height),
+        None => (BUILTIN_WIDTH, BUILTIN_HEIGHT),
+    }
+}
+
+/// Glyph to draw for `ch`, always succeeds
+pub fn glyph(ch: char) -> Glyph {
+    let font = FONT.lock();
+    let lookup = |c: char| font.as_ref().and_then(|f| f.glyph(c)).or_else(|| builtin_glyph(c));
+
+    if let Some(g) = lookup(ch) {
+        return g;
+    }
+    if let Some(g) = fallback_char(ch).and_then(lookup) {
+        return g;
+    }
+    let (w, h) = match font.as_ref() {
+        Some(f) => (f.width, f.height),
+        None => (BUILTIN_WIDTH, BUILTIN_HEIGHT),
+    };
+    replacement_glyph(w, h)
+}
+
+/// Decode UTF-8, substituting U+FFFD for each invalid sequence
+pub fn decode_utf8(bytes: &[u8]) -> impl Iterator<Item = char> + '_ {
+    bytes.utf8_chunks().flat_map(|chunk| {
+        let bad = if chunk.invalid().is_empty() { None } else { Some(char::REPLACEMENT_CHARACTER) };
+        chunk.valid().chars().chain(bad)
+    })
+}
+
+/// Print active font information
+pub fn print_info() {
+    match FONT.lock().as_ref() {
+        Some(font) => {
+            println!("Font: {} ({}x{}, {} glyphs, {} Unicode mappings)",
+                font.name, font.width, font.height, font.glyph_count, font.unicode.len());
+        }
+        None => println!("Font: built-in {}x{}", BUILTIN_WIDTH, BUILTIN_HEIGHT),
+    }
+}
+
+/// Glyph from the built-in font
+pub fn builtin_glyph(ch: char) -> Option<Glyph> {
+    let cp = ch as u32;
+    match cp {
+        0x20..=0x7E => Some(Glyph::from_bytes(
+            BUILTIN_WIDTH,
+            BUILTIN_HEIGHT,
+            &ASCII_8X16[(cp - 0x20) as usize],
+        )),
+        0x2571..=0x2573 => Some(diagonal_glyph(cp)),
+        0x2500..=0x257F => Some(box_glyph(BOX_ARMS[(cp - 0x2500) as usize])),
+        0x2580..=0x259F => Some(Glyph::from_fn(BUILTIN_WIDTH, BUILTIN_HEIGHT, |x, y| block_pixel(cp, x, y))),
+        _ => None,
+    }
+}
+
+/// ASCII look-alike for common typographic and Latin-1 characters
+fn fallback_char(ch: char) -> Option<char> {
+    Some(match ch {
+        '\u{00A0}' | '\u{2000}'..='\u{200A}' => ' ',
+        '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{2032}' => '\'',
+        '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{2033}' => '"',
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => '-',
+        '\u{2022}' | '\u{00B7}' | '\u{2219}' => '*',
+        '\u{2026}' => '.',
+        '\u{00AB}' | '\u{2039}' => '<',
+        '\u{00BB}' | '\u{203A}' => '>',
+        '\u{00D7}' => 'x',
+        '\u{00F7}' => '/',
+        'À'..='Å' => 'A',
+        'Ç' => 'C',
+        'È'..='Ë' => 'E',
+        'Ì'..='Ï' => 'I',
+        'Ñ' => 'N',
+        'Ò'..='Ö' | 'Ø' => 'O',
+        'Ù'..='Ü' => 'U',
+        'Ý' => 'Y',
+        'ß' => 's',
+        'à'..='å' => 'a',
+        'ç' => 'c',
+        'è'..='ë' => 'e',
+        'ì'..='ï' => 'i',
+        'ñ' => 'n',
+        'ò'..='ö' | 'ø' => 'o',
+        'ù'..='ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        '\u{FFFD}' => '?',
+        _ => return None,
+    })
+}
+
+/// Hollow box drawn for characters no font can show
+fn replacement_glyph(width: u32, height: u32) -> Glyph {
+    let (x0, x1) = (1, width.saturating_sub(2));
+    let (y0, y1) = (2, height.saturating_sub(3));
+    Glyph::from_fn(width, height, |x, y| {
+        (x >= x0 && x <= x1 && y >= y0 && y <= y1) && (x == x0 || x == x1 || y == y0 || y == y1)
+    })
+}
+
+// Box drawing line weights
+const N: u8 = 0;
+const L: u8 = 1;
+const H: u8 = 2;
+const D: u8 = 3;
+
+/// Pack the up/right/down/left line weights of a box drawing character
+const fn arms(up: u8, right: u8, down: u8, left: u8) -> u8 {
+    (up << 6) | (right << 4) | (down << 2) | left
+}
+
+/// Column or row offsets covered by a line of the given weight
+///
+/// Offsets are relative to the cell center (column 3, row 7).
+fn stroke(weight: u8) -> &'static [i32] {
+    match weight {
+        L => &[0],
+        H => &[0, 1],
+        D => &[-1, 2],
+        _ => &[],
+    }
+}
+
+/// Render a box drawing character from its four arm weights
+fn box_glyph(packed: u8) -> Glyph {
+    let (cx, cy) = (3i32, 7i32);
+    let up = packed >> 6;
+    let right = (packed >> 4) & 3;
+    let down = (packed >> 2) & 3;
+    let left = packed & 3;
+
+    // Extent of the perpendicular strokes, so arms meet without gaps
+    let span = |a: u8, b: u8| {
+        let offsets = stroke(a).iter().chain(stroke(b));
+        let lo = offsets.clone().min().copied().unwrap_or(0);
+        let hi = offsets.max().copied().unwrap_or(0);
+        (lo, hi)
+    };
+    let (vx_lo, vx_hi) = span(up, down);
+    let (hy_lo, hy_hi) = span(left, right);
+
+    Glyph::from_fn(BUILTIN_WIDTH, BUILTIN_HEIGHT, |x, y| {
+        let (x, y) = (x as i32, y as i32);
+        let on_v = |w: u8| stroke(w).iter().any(|&o| x == cx + o);
+        let on_h = |w: u8| stroke(w).iter().any(|&o| y == cy + o);
+        (on_v(up) && y <= cy + hy_hi)
+            || (on_v(down) && y >= cy + hy_lo)
+            || (on_h(left) && x <= cx + vx_hi)
+            || (on_h(right) && x >= cx + vx_lo)
+    })
+}
+
+/// Diagonal box drawing characters U+2571 to U+2573
+fn diagonal_glyph(cp: u32) -> Glyph {
+    Glyph::from_fn(BUILTIN_WIDTH, BUILTIN_HEIGHT, |x, y| {
+        let col = y * BUILTIN_WIDTH / BUILTIN_HEIGHT;
+        let rising = x == BUILTIN_WIDTH - 1 - col;
+        let falling = x == col;
+        match cp {
+            0x2571 => rising,
+            0x2572 => falling,
+            _ => rising || falling,
+        }
+    })
+}
+
+/// Block elements U+2580 to U+259F
+fn block_pixel(cp: u32, x: u32, y: u32) -> bool {
+    let (w, h) = (BUILTIN_WIDTH, BUILTIN_HEIGHT);
+    let (left, top) = (x < w / 2, y < h / 2);
+    let quadrants = |ul: bool, ur: bool, ll: bool, lr: bool| match (top, left) {
+        (true, true) => ul,
+        (true, false) => ur,
+        (false, true) => ll,
+        (false, false) => lr,
+    };
+    match cp {
+        0x2580 => top,
+        0x2581..=0x2588 => y >= h - (cp - 0x2580) * h / 8,
+        0x2589..=0x258F => x < (0x2590 - cp) * w / 8,
+        0x2590 => !left,
+        0x2591 => x % 4 == 0 && y % 2 == 0 || x % 4 == 2 && y % 2 == 1,
+        0x2592 => (x + y) % 2 == 0,
+        0x2593 => !(x % 4 == 0 && y % 2 == 0 || x % 4 == 2 && y % 2 == 1),
+        0x2594 => y < h / 8,
+        0x2595 => x >= w - w / 8,
+        0x2596 => quadrants(false, false, true, false),
+        0x2597 => quadrants(false, false, false, true),
+        0x2598 => quadrants(true, false, false, false),
+        0x2599 => quadrants(true, false, true, true),
+        0x259A => quadrants(true, false, false, true),
+        0x259B => quadrants(true, true, true, false),
+        0x259C => quadrants(true, true, false, true),
+        0x259D => quadrants(false, true, false, false),
+        0x259E => quadrants(false, true, true, false),
+        0x259F => quadrants(false, true, true, true),
+        _ => false,
+    }
+}
+
+/// Line weights for U+2500 to U+257F (diagonals U+2571-2573 drawn separately)
+static BOX_ARMS: [u8; 128] = [
+    arms(N, L, N, L), arms(N, H, N, H), arms(L, N, L, N), arms(H, N, H, N), // U+2500
+    arms(N, L, N, L), arms(N, H, N, H), arms(L, N, L, N), arms(H, N, H, N), // U+2504
+    arms(N, L, N, L), arms(N, H, N, H), arms(L, N, L, N), arms(H, N, H, N), // U+2508
+    arms(N, L, L, N), arms(N, H, L, N), arms(N, L, H, N), arms(N, H, H, N), // U+250C
+    arms(N, N, L, L), arms(N, N, L, H), arms(N, N, H, L), arms(N, N, H, H), // U+2510
+    arms(L, L, N, N), arms(L, H, N, N), arms(H, L, N, N), arms(H, H, N, N), // U+2514
+    arms(L, N, N, L), arms(L, N, N, H), arms(H, N, N, L), arms(H, N, N, H), // U+2518
+    arms(L, L, L, N), arms(L, H, L, N), arms(H, L, L, N), arms(L, L, H, N), // U+251C
+    arms(H, L, H, N), arms(H, H, L, N), arms(L, H, H, N), arms(H, H, H, N), // U+2520
+    arms(L, N, L, L), arms(L, N, L, H), arms(H, N, L, L), arms(L, N, H, L), // U+2524
+    arms(H, N, H, L), arms(H, N, L, H), arms(L, N, H, H), arms(H, N, H, H), // U+2528
+    arms(N, L, L, L), arms(N, L, L, H), arms(N, H, L, L), arms(N, H, L, H), // U+252C
+    arms(N, L, H, L), arms(N, L, H, H), arms(N, H, H, L), arms(N, H, H, H), // U+2530
+    arms(L, L, N, L), arms(L, L, N, H), arms(L, H, N, L), arms(L, H, N, H), // U+2534
+    arms(H, L, N, L), arms(H, L, N, H), arms(H, H, N, L), arms(H, H, N, H), // U+2538
+    arms(L, L, L, L), arms(L, L, L, H), arms(L, H, L, L), arms(L, H, L, H), // U+253C
+    arms(H, L, L, L), arms(L, L, H, L), arms(H, L, H, L), arms(H, L, L, H), // U+2540
+    arms(H, H, L, L), arms(L, L, H, H), arms(L, H, H, L), arms(H, H, L, H), // U+2544
+    arms(L, H, H, H), arms(H, L, H, H), arms(H, H, H, L), arms(H, H, H, H), // U+2548
+    arms(N, L, N, L), arms(N, H, N, H), arms(L, N, L, N), arms(H, N, H, N), // U+254C
+    arms(N, D, N, D), arms(D, N, D, N), arms(N, D, L, N), arms(N, L, D, N), // U+2550
+    arms(N, D, D, N), arms(N, N, L, D), arms(N, N, D, L), arms(N, N, D, D), // U+2554
+    arms(L, D, N, N), arms(D, L, N, N), arms(D, D, N, N), arms(L, N, N, D), // U+2558
+    arms(D, N, N, L), arms(D, N, N, D), arms(L, D, L, N), arms(D, L, D, N), // U+255C
+    arms(D, D, D, N), arms(L, N, L, D), arms(D, N, D, L), arms(D, N, D, D), // U+2560
+    arms(N, D, L, D), arms(N, L, D, L), arms(N, D, D, D), arms(L, D, N, D), // U+2564
+    arms(D, L, N, L), arms(D, D, N, D), arms(L, D, L, D), arms(D, L, D, L), // U+2568
+    arms(D, D, D, D), arms(N, L, L, N), arms(N, N, L, L), arms(L, N, N, L), // U+256C
+    arms(L, L, N, N), arms(N, N, N, N), arms(N, N, N, N), arms(N, N, N, N), // U+2570
+    arms(N, N, N, L), arms(L, N, N, N), arms(N, L, N, N), arms(N, N, L, N), // U+2574
+    arms(N, N, N, H), arms(H, N, N, N), arms(N, H, N, N), arms(N, N, H, N), // U+2578
+    arms(N, H, N, L), arms(L, N, H, N), arms(N, L, N, H), arms(H, N, L, N), // U+257C
+];
+
+/// Built-in 8x16 glyphs for U+0020 to U+007E
+static ASCII_8X16: [[u8; 16]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x18, 0x18, 0x00], // '!'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0xff, 0xff, 0x66, 0x66, 0xff, 0xff, 0x66, 0x66, 0x66, 0x66, 0x00], // '#'
+    [0x00, 0x18, 0x18, 0x3e, 0x3e, 0x60, 0x60, 0x3c, 0x3c, 0x06, 0x06, 0x7c, 0x7c, 0x18, 0x18, 0x00], // '$'
+    [0x00, 0x62, 0x62, 0x66, 0x66, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x66, 0x66, 0x46, 0x46, 0x00], // '%'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x3c, 0x3c, 0x38, 0x38, 0x67, 0x67, 0x66, 0x66, 0x3f, 0x3f, 0x00], // '&'
+    [0x00, 0x06, 0x06, 0x0c, 0x0c, 0x18, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // "'"
+    [0x00, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x18, 0x18, 0x0c, 0x0c, 0x00], // '('
+    [0x00, 0x30, 0x30, 0x18, 0x18, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x00], // ')'
+    [0x00, 0x00, 0x00, 0x66, 0x66, 0x3c, 0x3c, 0xff, 0xff, 0x3c, 0x3c, 0x66, 0x66, 0x00, 0x00, 0x00], // '*'
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x30], // ','
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7e, 0x7e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x00], // '.'
+    [0x00, 0x00, 0x00, 0x03, 0x03, 0x06, 0x06, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x60, 0x60, 0x00], // '/'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x6e, 0x6e, 0x76, 0x76, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // '0'
+    [0x00, 0x18, 0x18, 0x18, 0x18, 0x38, 0x38, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x7e, 0x00], // '1'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x06, 0x06, 0x0c, 0x0c, 0x30, 0x30, 0x60, 0x60, 0x7e, 0x7e, 0x00], // '2'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x06, 0x06, 0x1c, 0x1c, 0x06, 0x06, 0x66, 0x66, 0x3c, 0x3c, 0x00], // '3'
+    [0x00, 0x06, 0x06, 0x0e, 0x0e, 0x1e, 0x1e, 0x66, 0x66, 0x7f, 0x7f, 0x06, 0x06, 0x06, 0x06, 0x00], // '4'
+    [0x00, 0x7e, 0x7e, 0x60, 0x60, 0x7c, 0x7c, 0x06, 0x06, 0x06, 0x06, 0x66, 0x66, 0x3c, 0x3c, 0x00], // '5'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x60, 0x60, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // '6'
+    [0x00, 0x7e, 0x7e, 0x66, 0x66, 0x0c, 0x0c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00], // '7'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // '8'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x3e, 0x06, 0x06, 0x66, 0x66, 0x3c, 0x3c, 0x00], // '9'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x00], // ':'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x30], // ';'
+    [0x00, 0x0e, 0x0e, 0x18, 0x18, 0x30, 0x30, 0x60, 0x60, 0x30, 0x30, 0x18, 0x18, 0x0e, 0x0e, 0x00], // '<'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x7e, 0x7e, 0x00, 0x00, 0x7e, 0x7e, 0x00, 0x00, 0x00, 0x00, 0x00], // '='
+    [0x00, 0x70, 0x70, 0x18, 0x18, 0x0c, 0x0c, 0x06, 0x06, 0x0c, 0x0c, 0x18, 0x18, 0x70, 0x70, 0x00], // '>'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x06, 0x06, 0x0c, 0x0c, 0x18, 0x18, 0x00, 0x00, 0x18, 0x18, 0x00], // '?'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x6e, 0x6e, 0x6e, 0x6e, 0x60, 0x60, 0x62, 0x62, 0x3c, 0x3c, 0x00], // '@'
+    [0x00, 0x18, 0x18, 0x3c, 0x3c, 0x66, 0x66, 0x7e, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00], // 'A'
+    [0x00, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x7c, 0x7c, 0x00], // 'B'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x66, 0x66, 0x3c, 0x3c, 0x00], // 'C'
+    [0x00, 0x78, 0x78, 0x6c, 0x6c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x6c, 0x6c, 0x78, 0x78, 0x00], // 'D'
+    [0x00, 0x7e, 0x7e, 0x60, 0x60, 0x60, 0x60, 0x78, 0x78, 0x60, 0x60, 0x60, 0x60, 0x7e, 0x7e, 0x00], // 'E'
+    [0x00, 0x7e, 0x7e, 0x60, 0x60, 0x60, 0x60, 0x78, 0x78, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x00], // 'F'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x60, 0x60, 0x6e, 0x6e, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // 'G'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00], // 'H'
+    [0x00, 0x3c, 0x3c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x3c, 0x00], // 'I'
+    [0x00, 0x1e, 0x1e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x6c, 0x6c, 0x38, 0x38, 0x00], // 'J'
+    [0x00, 0x66, 0x66, 0x6c, 0x6c, 0x78, 0x78, 0x70, 0x70, 0x78, 0x78, 0x6c, 0x6c, 0x66, 0x66, 0x00], // 'K'
+    [0x00, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x7e, 0x7e, 0x00], // 'L'
+    [0x00, 0x63, 0x63, 0x77, 0x77, 0x7f, 0x7f, 0x6b, 0x6b, 0x63, 0x63, 0x63, 0x63, 0x63, 0x63, 0x00], // 'M'
+    [0x00, 0x66, 0x66, 0x76, 0x76, 0x7e, 0x7e, 0x7e, 0x7e, 0x6e, 0x6e, 0x66, 0x66, 0x66, 0x66, 0x00], // 'N'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // 'O'
+    [0x00, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x7c, 0x7c, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x00], // 'P'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x0e, 0x0e, 0x00], // 'Q'
+    [0x00, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x7c, 0x7c, 0x78, 0x78, 0x6c, 0x6c, 0x66, 0x66, 0x00], // 'R'
+    [0x00, 0x3c, 0x3c, 0x66, 0x66, 0x60, 0x60, 0x3c, 0x3c, 0x06, 0x06, 0x66, 0x66, 0x3c, 0x3c, 0x00], // 'S'
+    [0x00, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00], // 'T'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // 'U'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x00], // 'V'
+    [0x00, 0x63, 0x63, 0x63, 0x63, 0x63, 0x63, 0x6b, 0x6b, 0x7f, 0x7f, 0x77, 0x77, 0x63, 0x63, 0x00], // 'W'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x00], // 'X'
+    [0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00], // 'Y'
+    [0x00, 0x7e, 0x7e, 0x06, 0x06, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x60, 0x60, 0x7e, 0x7e, 0x00], // 'Z'
+    [0x00, 0x3c, 0x3c, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x3c, 0x3c, 0x00], // '['
+    [0x00, 0x00, 0x00, 0x60, 0x60, 0x30, 0x30, 0x18, 0x18, 0x0c, 0x0c, 0x06, 0x06, 0x03, 0x03, 0x00], // '\\'
+    [0x00, 0x3c, 0x3c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x3c, 0x3c, 0x00], // ']'
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x3c, 0x3c, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff], // '_'
+    [0x00, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x3c, 0x06, 0x06, 0x3e, 0x3e, 0x66, 0x66, 0x3e, 0x3e, 0x00], // 'a'
+    [0x00, 0x00, 0x00, 0x60, 0x60, 0x60, 0x60, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x7c, 0x7c, 0x00], // 'b'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x3c, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x3c, 0x3c, 0x00], // 'c'
+    [0x00, 0x00, 0x00, 0x06, 0x06, 0x06, 0x06, 0x3e, 0x3e, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x3e, 0x00], // 'd'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x3c, 0x66, 0x66, 0x7e, 0x7e, 0x60, 0x60, 0x3c, 0x3c, 0x00], // 'e'
+    [0x00, 0x00, 0x00, 0x0e, 0x0e, 0x18, 0x18, 0x3e, 0x3e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00], // 'f'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3e, 0x3e, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x3e, 0x06, 0x06, 0x7c], // 'g'
+    [0x00, 0x00, 0x00, 0x60, 0x60, 0x60, 0x60, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00], // 'h'
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x38, 0x38, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x3c, 0x00], // 'i'
+    [0x00, 0x00, 0x00, 0x06, 0x06, 0x00, 0x00, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x3c], // 'j'
+    [0x00, 0x00, 0x00, 0x60, 0x60, 0x60, 0x60, 0x6c, 0x6c, 0x78, 0x78, 0x6c, 0x6c, 0x66, 0x66, 0x00], // 'k'
+    [0x00, 0x00, 0x00, 0x38, 0x38, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x3c, 0x00], // 'l'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x7f, 0x7f, 0x7f, 0x7f, 0x6b, 0x6b, 0x63, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00], // 'n'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x3c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x00], // 'o'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x7c, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x7c, 0x7c, 0x60, 0x60, 0x60], // 'p'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3e, 0x3e, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x3e, 0x06, 0x06, 0x06], // 'q'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x7c, 0x7c, 0x66, 0x66, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x00], // 'r'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3e, 0x3e, 0x60, 0x60, 0x3c, 0x3c, 0x06, 0x06, 0x7c, 0x7c, 0x00], // 's'
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x0e, 0x0e, 0x00], // 't'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x3e, 0x00], // 'u'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x00], // 'v'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x63, 0x63, 0x6b, 0x6b, 0x7f, 0x7f, 0x3e, 0x3e, 0x36, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x3c, 0x3c, 0x66, 0x66, 0x00], // 'x'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x3e, 0x0c, 0x0c, 0x78], // 'y'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x7e, 0x7e, 0x0c, 0x0c, 0x18, 0x18, 0x30, 0x30, 0x7e, 0x7e, 0x00], // 'z'
+    [0x00, 0x0c, 0x0c, 0x18, 0x18, 0x18, 0x18, 0x30, 0x30, 0x18, 0x18, 0x18, 0x18, 0x0c, 0x0c, 0x00], // '{'
+    [0x00, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x00, 0x30, 0x30, 0x18, 0x18, 0x18, 0x18, 0x0c, 0x0c, 0x18, 0x18, 0x18, 0x18, 0x30, 0x30, 0x00], // '}'
+    [0x00, 0x31, 0x31, 0x6b, 0x6b, 0x46, 0x46, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    fn decoded(bytes: &[u8]) -> String {
+        decode_utf8(bytes).collect()
+    }
+
+    #[kernel_test]
+    fn utf8_decodes_with_replacement_for_bad_sequences() -> Result<(), String> {
+        check_eq!(decoded("box \u{2500}\u{2588} é".as_bytes()), "box \u{2500}\u{2588} é");
+        // A stray continuation byte, a lead byte cut short, an overlong '/'
+        check_eq!(decoded(b"a\x80b"), "a\u{FFFD}b");
+        check_eq!(decoded(b"ab\xE2\x94"), "ab\u{FFFD}");
+        check_eq!(decoded(b"\xC0\xAFz"), "\u{FFFD}\u{FFFD}z");
+        Ok(())
+    }
+}
@@ -0,0 +1,705 @@
+//! Image decoding
+//!
+//! Decodes PNG, GIF, BMP and binary PNM (PGM/PPM) files into 32-bit ARGB
+//! bitmaps, picking the format from the file's signature. PNG supports
+//! every color type and bit depth, palettes with transparency and Adam7
+//! interlacing; GIF decodes the first frame. Images larger than
+//! `MAX_PIXELS` are refused before anything is allocated for them.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::inflate::{self, InflateError};
+
+/// Largest image, in pixels, that will be decoded
+///
+/// Its bitmap takes 1 MiB, and a PNG as much again for its decompressed
+/// rows, out of the kernel's 8 MiB heap.
+pub const MAX_PIXELS: u32 = 512 * 512;
+
+/// A decoded image
+#[derive(Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// ARGB pixels row by row, alpha not premultiplied
+    pub pixels: Vec<u32>,
+}
+
+impl core::fmt::Debug for Image {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Image({}x{})", self.width, self.height)
+    }
+}
+
+impl Image {
+    fn new(width: u32, height: u32) -> Result<Self, ImageError> {
+        if width == 0 || height == 0 {
+            return Err(ImageError::Corrupt);
+        }
+        if width as u64 * height as u64 > MAX_PIXELS as u64 {
+            return Err(ImageError::TooLarge);
+        }
+        Ok(Self { width, height, pixels: vec![0; (width * height) as usize] })
+    }
+
+    /// Pixel at (x, y); both must be in range
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Bytes the pixels take
+    pub fn size_in_bytes(&self) -> usize {
+        self.pixels.len() * 4
+    }
+
+    fn set(&mut self, x: u32, y: u32, color: u32) {
+        let width = self.width;
+        self.pixels[(y * width + x) as usize] = color;
+    }
+}
+
+/// Image file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Gif,
+    Bmp,
+    Pnm,
+}
+
+/// Why an image could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// Not a format this module decodes
+    Unsupported,
+    /// The file ends early
+    Truncated,
+    /// The file is malformed
+    Corrupt,
+    /// The image exceeds `MAX_PIXELS`
+    TooLarge,
+}
+
+impl From<InflateError> for ImageError {
+    fn from(e: InflateError) -> Self {
+        match e {
+            InflateError::Truncated => ImageError::Truncated,
+            InflateError::TooLarge => ImageError::TooLarge,
+            InflateError::Corrupt | InflateError::Checksum => ImageError::Corrupt,
+        }
+    }
+}
+
+/// Recognise an image format by its signature
+pub fn format_of(data: &[u8]) -> Option<Format> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(Format::Png)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(Format::Gif)
+    } else if data.starts_with(b"BM") {
+        Some(Format::Bmp)
+    } else if data.starts_with(b"P5") || data.starts_with(b"P6") {
+        Some(Format::Pnm)
+    } else {
+        None
+    }
+}
+
+/// Decode an image file of any supported format
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    match format_of(data) {
+        Some(Format::Png) => decode_png(data),
+        Some(Format::Gif) => decode_gif(data),
+        Some(Format::Bmp) => decode_bmp(data),
+        Some(Format::Pnm) => decode_pnm(data),
+        None => Err(ImageError::Unsupported),
+    }
+}
+
+#[inline]
+fn argb(a: u8, r: u8, g: u8, b: u8) -> u32 {
+    (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+fn be32(data: &[u8], at: usize) -> Result<u32, ImageError> {
+    let b = data.get(at..at + 4).ok_or(ImageError::Truncated)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn le16(data: &[u8], at: usize) -> Result<u16, ImageError> {
+    let b = data.get(at..at + 2).ok_or(ImageError::Truncated)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn le32(data: &[u8], at: usize) -> Result<u32, ImageError> {
+    let b = data.get(at..at + 4).ok_or(ImageError::Truncated)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// ---------------------------------------------------------------------------
+// PNG
+// ---------------------------------------------------------------------------
+
+/// Adam7 passes: starting column and row, then column and row step
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+struct PngHeader {
+    width: u32,
+    height: u32,
+    depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl PngHeader {
+    fn channels(&self) -> u32 {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    /// Bytes in one filtered row of `width` pixels, not counting the filter byte
+    fn row_bytes(&self, width: u32) -> usize {
+        ((width as usize * self.channels() as usize * self.depth as usize) + 7) / 8
+    }
+
+    /// Bytes per complete pixel, at least one, as the filters see them
+    fn filter_step(&self) -> usize {
+        ((self.channels() as usize * self.depth as usize) / 8).max(1)
+    }
+}
+
+fn decode_png(data: &[u8]) -> Result<Image, ImageError> {
+    let mut pos = 8;
+    let mut header = None;
+    let mut palette: Vec<u32> = Vec::new();
+    let mut transparent: Option<(u16, u16, u16)> = None;
+    let mut compressed = Vec::new();
+
+    while pos + 8 <= data.len() {
+        let len = be32(data, pos)? as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let body = data.get(pos + 8..pos + 8 + len).ok_or(ImageError::Truncated)?;
+        // Skip the CRC; a damaged chunk shows up as bad image data instead
+        pos += 12 + len;
+        match kind {
+            b"IHDR" => {
+                if body.len() < 13 {
+                    return Err(ImageError::Corrupt);
+                }
+                let h = PngHeader {
+                    width: be32(body, 0)?,
+                    height: be32(body, 4)?,
+                    depth: body[8],
+                    color_type: body[9],
+                    interlaced: body[12] == 1,
+                };
+                let valid = match h.color_type {
+                    0 => matches!(h.depth, 1 | 2 | 4 | 8 | 16),
+                    3 => matches!(h.depth, 1 | 2 | 4 | 8),
+                    2 | 4 | 6 => matches!(h.depth, 8 | 16),
+                    _ => false,
+                };
+                if !valid || body[10] != 0 || body[11] != 0 {
+                    return Err(ImageError::Unsupported);
+                }
+                header = Some(h);
+            }
+            b"PLTE" => {
+                palette = body.chunks_exact(3).map(|c| argb(0xFF, c[0], c[1], c[2])).collect();
+            }
+            b"tRNS" => match header.as_ref().map(|h| h.color_type) {
+                Some(3) => {
+                    for (entry, &alpha) in palette.iter_mut().zip(body) {
+                        *entry = (*entry & 0x00FF_FFFF) | (alpha as u32) << 24;
+                    }
+                }
+                Some(0) if body.len() >= 2 => {
+                    let v = u16::from_be_bytes([body[0], body[1]]);
+                    transparent = Some((v, v, v));
+                }
+                Some(2) if body.len() >= 6 => {
+                    let c = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+                    transparent = Some((c(0), c(2), c(4)));
+                }
+                _ => {}
+            },
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or(ImageError::Corrupt)?;
+    let mut image = Image::new(header.width, header.height)?;
+    if header.color_type == 3 && palette.is_empty() {
+        return Err(ImageError::Corrupt);
+    }
+
+    // Every pass's rows, each with its filter byte
+    let passes: Vec<(u32, u32, u32, u32)> = if header.interlaced { ADAM7.to_vec() } else { vec![(0, 0, 1, 1)] };
+    let mut expected = 0usize;
+    for &(x0, y0, dx, dy) in &passes {
+        let (w, h) = pass_size(header.width, header.height, x0, y0, dx, dy);
+        if w > 0 && h > 0 {
+            expected += h as usize * (header.row_bytes(w) + 1);
+        }
+    }
+    let raw = inflate::zlib_decompress(&compressed, expected)?;
+    if raw.len() < expected {
+        return Err(ImageError::Truncated);
+    }
+
+    let mut offset = 0;
+    for &(x0, y0, dx, dy) in &passes {
+        let (w, h) = pass_size(header.width, header.height, x0, y0, dx, dy);
+        if w == 0 || h == 0 {
+            continue;
+        }
+        let stride = header.row_bytes(w);
+        let step = header.filter_step();
+        let mut previous = vec![0u8; stride];
+        let mut row = vec![0u8; stride];
+        for py in 0..h {
+            let filter = raw[offset];
+            row.copy_from_slice(&raw[offset + 1..offset + 1 + stride]);
+            offset += stride + 1;
+            unfilter(filter, &mut row, &previous, step)?;
+            for px in 0..w {
+                let color = png_pixel(&header, &row, px, &palette, transparent);
+                image.set(x0 + px * dx, y0 + py * dy, color);
+            }
+            core::mem::swap(&mut row, &mut previous);
+        }
+    }
+    Ok(image)
+}
+
+/// Width and height of one interlace pass
+fn pass_size(width: u32, height: u32, x0: u32, y0: u32, dx: u32, dy: u32) -> (u32, u32) {
+    let w = if width > x0 { (width - x0 + dx - 1) / dx } else { 0 };
+    let h = if height > y0 { (height - y0 + dy - 1) / dy } else { 0 };
+    (w, h)
+}
+
+/// Undo a row's PNG filter in place
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], step: usize) -> Result<(), ImageError> {
+    match filter {
+        0 => {}
+        1 => {
+            for i in step..row.len() {
+                row[i] = row[i].wrapping_add(row[i - step]);
+            }
+        }
+        2 => {
+            for i in 0..row.len() {
+                row[i] = row[i].wrapping_add(previous[i]);
+            }
+        }
+        3 => {
+            for i in 0..row.len() {
+                let left = if i >= step { row[i - step] as u16 } else { 0 };
+                row[i] = row[i].wrapping_add(((left + previous[i] as u16) / 2) as u8);
+            }
+        }
+        4 => {
+            for i in 0..row.len() {
+                let a = if i >= step { row[i - step] as i16 } else { 0 };
+                let b = previous[i] as i16;
+                let c = if i >= step { previous[i - step] as i16 } else { 0 };
+                let p = a + b - c;
+                let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+                let predictor = if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c };
+                row[i] = row[i].wrapping_add(predictor as u8);
+            }
+        }
+        _ => return Err(ImageError::Corrupt),
+    }
+    Ok(())
+}
+
+/// Color of pixel `x` of an unfiltered PNG row
+fn png_pixel(h: &PngHeader, row: &[u8], x: u32, palette: &[u32], transparent: Option<(u16, u16, u16)>) -> u32 {
+    // Sample `i` of the pixel, at the image's bit depth
+    let sample = |i: u32| -> u16 {
+        let index = x * h.channels() + i;
+        match h.depth {
+            16 => u16::from_be_bytes([row[index as usize * 2], row[index as usize * 2 + 1]]),
+            8 => row[index as usize] as u16,
+            depth => {
+                let bit = index * depth as u32;
+                let byte = row[(bit / 8) as usize];
+                let shift = 8 - depth as u32 - bit % 8;
+                ((byte >> shift) & ((1 << depth) - 1)) as u16
+            }
+        }
+    };
+    // Scale a sample to 8 bits
+    let to8 = |v: u16| -> u8 {
+        match h.depth {
+            16 => (v >> 8) as u8,
+            8 => v as u8,
+            depth => (v as u32 * 255 / ((1 << depth) - 1)) as u8,
+        }
+    };
+    match h.color_type {
+        0 => {
+            let v = sample(0);
+            let g = to8(v);
+            let alpha = if transparent.map_or(false, |t| t.0 == v) { 0 } else { 0xFF };
+            argb(alpha, g, g, g)
+        }
+        2 => {
+            let (r, g, b) = (sample(0), sample(1), sample(2));
+            let alpha = if transparent == Some((r, g, b)) { 0 } else { 0xFF };
+            argb(alpha, to8(r), to8(g), to8(b))
+        }
+        3 => palette.get(sample(0) as usize).copied().unwrap_or(0),
+        4 => {
+            let g = to8(sample(0));
+            argb(to8(sample(1)), g, g, g)
+        }
+        _ => argb(to8(sample(3)), to8(sample(0)), to8(sample(1)), to8(sample(2))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GIF
+// ---------------------------------------------------------------------------
+
+fn decode_gif(data: &[u8]) -> Result<Image, ImageError> {
+    let width = le16(data, 6)? as u32;
+    let height = le16(data, 8)? as u32;
+    let flags = *data.get(10).ok_or(ImageError::Truncated)?;
+    let mut pos = 13;
+    let mut global = Vec::new();
+    if flags & 0x80 != 0 {
+        let size = 3 << ((flags & 7) + 1);
+        global = read_palette(data, pos, size)?;
+        pos += size;
+    }
+
+    let mut image = Image::new(width, height)?;
+    let mut transparent_index = None;
+    loop {
+        match *data.get(pos).ok_or(ImageError::Truncated)? {
+            // Extension: only the graphic control block matters
+            0x21 => {
+                let label = *data.get(pos + 1).ok_or(ImageError::Truncated)?;
+                pos += 2;
+                if label == 0xF9 {
+                    let block = data.get(pos..pos + 6).ok_or(ImageError::Truncated)?;
+                    if block[1] & 1 != 0 {
+                        transparent_index = Some(block[4]);
+                    }
+                }
+                pos = skip_sub_blocks(data, pos)?;
+            }
+            // Image descriptor: decode the first frame and stop
+            0x2C => {
+                let left = le16(data, pos + 1)? as u32;
+                let top = le16(data, pos + 3)? as u32;
+                let w = le16(data, pos + 5)? as u32;
+                let h = le16(data, pos + 7)? as u32;
+                let frame_flags = *data.get(pos + 9).ok_or(ImageError::Truncated)?;
+                pos += 10;
+                let mut palette = &global;
+                let local;
+                if frame_flags & 0x80 != 0 {
+                    let size = 3 << ((frame_flags & 7) + 1);
+                    local = read_palette(data, pos, size)?;
+                    palette = &local;
+                    pos += size;
+                }
+                if palette.is_empty() {
+                    return Err(ImageError::Corrupt);
+                }
+                let min_code_size = *data.get(pos).ok_or(ImageError::Truncated)?;
+                let mut lzw_data = Vec::new();
+                let mut p = pos + 1;
+                loop {
+                    let len = *data.get(p).ok_or(ImageError::Truncated)? as usize;
+                    p += 1;
+                    if len == 0 {
+                        break;
+                    }
+                    lzw_data.extend_from_slice(data.get(p..p + len).ok_or(ImageError::Truncated)?);
+                    p += len;
+                }
+                if w == 0 || h == 0 {
+                    return Ok(image);
+                }
+                let indices = lzw_decode(&lzw_data, min_code_size, (w * h) as usize)?;
+
+                let interlaced = frame_flags & 0x40 != 0;
+                let rows = gif_row_order(h, interlaced);
+                for (i, &index) in indices.iter().enumerate() {
+                    let (fx, fy) = (i as u32 % w, rows[i / w as usize]);
+                    let (x, y) = (left + fx, top + fy);
+                    if x >= width || y >= height || Some(index) == transparent_index {
+                        continue;
+                    }
+                    if let Some(&color) = palette.get(index as usize) {
+                        image.set(x, y, color);
+                    }
+                }
+                return Ok(image);
+            }
+            0x3B => return Ok(image),
+            _ => return Err(ImageError::Corrupt),
+        }
+    }
+}
+
+fn read_palette(data: &[u8], pos: usize, size: usize) -> Result<Vec<u32>, ImageError> {
+    let bytes = data.get(pos..pos + size).ok_or(ImageError::Truncated)?;
+    Ok(bytes.chunks_exact(3).map(|c| argb(0xFF, c[0], c[1], c[2])).collect())
+}
+
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Result<usize, ImageError> {
+    loop {
+        let len = *data.get(pos).ok_or(ImageError::Truncated)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// The image row each stored row of a frame belongs to
+fn gif_row_order(height: u32, interlaced: bool) -> Vec<u32> {
+    if !interlaced {
+        return (0..height).collect();
+    }
+    let mut rows = Vec::with_capacity(height as usize);
+    for (start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)] {
+        rows.extend((start..height).step_by(step));
+    }
+    rows
+}
+
+/// Decode GIF's variable-width LZW into at most `limit` color indices
+fn lzw_decode(data: &[u8], min_code_size: u8, limit: usize) -> Result<Vec<u8>, ImageError> {
+    if !(2..=8).contains(&min_code_size) {
+        return Err(ImageError::Corrupt);
+    }
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    // Each code is a previous code plus one trailing index
+    let mut prefix = [0u16; 4096];
+    let mut suffix = [0u8; 4096];
+    let mut first = [0u8; 4096];
+    for i in 0..clear {
+        suffix[i as usize] = i as u8;
+        first[i as usize] = i as u8;
+    }
+
+    let mut out = Vec::with_capacity(limit);
+    let mut stack = Vec::with_capacity(4096);
+    let mut code_size = min_code_size as u32 + 1;
+    let mut next = end + 1;
+    let mut previous: Option<u16> = None;
+    let (mut bit_buf, mut bit_count, mut pos) = (0u32, 0u32, 0usize);
+
+    while out.len() < limit {
+        while bit_count < code_size {
+            match data.get(pos) {
+                Some(&byte) => {
+                    bit_buf |= (byte as u32) << bit_count;
+                    bit_count += 8;
+                    pos += 1;
+                }
+                // Streams often end without an end code
+                None => return Ok(out),
+            }
+        }
+        let code = (bit_buf & ((1 << code_size) - 1)) as u16;
+        bit_buf >>= code_size;
+        bit_count -= code_size;
+
+        if code == clear {
+            code_size = min_code_size as u32 + 1;
+            next = end + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+        let prev = match previous {
+            None => {
+                if code >= clear {
+                    return Err(ImageError::Corrupt);
+                }
+                out.push(code as u8);
+                previous = Some(code);
+                continue;
+            }
+            Some(prev) => prev,
+        };
+
+        // A code not yet in the table is the previous string plus its own
+        // first index
+        let known = code < next;
+        if !known && code != next {
+            return Err(ImageError::Corrupt);
+        }
+        let lead = if known { first[code as usize] } else { first[prev as usize] };
+        if next < 4096 {
+            prefix[next as usize] = prev;
+            suffix[next as usize] = lead;
+            first[next as usize] = first[prev as usize];
+            next += 1;
+            if next == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+        }
+
+        let mut c = code;
+        stack.clear();
+        while c >= clear {
+            stack.push(suffix[c as usize]);
+            c = prefix[c as usize];
+        }
+        stack.push(c as u8);
+        out.extend(stack.iter().rev().take(limit - out.len()));
+        previous = Some(code);
+    }
+    Ok(out)
+}
+
+// ---------------------------------------------------------------------------
+// BMP
+// ---------------------------------------------------------------------------
+
+fn decode_bmp(data: &[u8]) -> Result<Image, ImageError> {
+    let pixel_offset = le32(data, 10)? as usize;
+    let header_size = le32(data, 14)? as usize;
+    let width = le32(data, 18)? as i32;
+    let height = le32(data, 22)? as i32;
+    let bpp = le16(data, 28)?;
+    let compression = le32(data, 30)?;
+    if header_size < 40 || width <= 0 || height == 0 {
+        return Err(ImageError::Unsupported);
+    }
+    // 0 is plain RGB; 3 is bit fields, accepted for the usual 32-bit BGRA layout
+    if compression != 0 && !(compression == 3 && bpp == 32) {
+        return Err(ImageError::Unsupported);
+    }
+    let top_down = height < 0;
+    let (w, h) = (width as u32, height.unsigned_abs());
+    let mut image = Image::new(w, h)?;
+
+    let mut palette = Vec::new();
+    if bpp <= 8 {
+        let used = le32(data, 46)? as usize;
+        let count = if used == 0 { 1 << bpp } else { used.min(256) };
+        let start = 14 + header_size;
+        let bytes = data.get(start..start + count * 4).ok_or(ImageError::Truncated)?;
+        palette = bytes.chunks_exact(4).map(|c| argb(0xFF, c[2], c[1], c[0])).collect();
+    }
+
+    let stride = ((w as usize * bpp as usize + 31) / 32) * 4;
+    for row in 0..h {
+        let y = if top_down { row } else { h - 1 - row };
+        let start = pixel_offset + row as usize * stride;
+        let line = data.get(start..start + stride).ok_or(ImageError::Truncated)?;
+        for x in 0..w {
+            let i = x as usize;
+            let color = match bpp {
+                32 => {
+                    let c = &line[i * 4..i * 4 + 4];
+                    // Plain 32-bit bitmaps often leave alpha zero; treat them as opaque
+                    let alpha = if compression == 3 { c[3] } else { 0xFF };
+                    argb(alpha, c[2], c[1], c[0])
+                }
+                24 => {
+                    let c = &line[i * 3..i * 3 + 3];
+                    argb(0xFF, c[2], c[1], c[0])
+                }
+                8 | 4 | 1 => {
+                    let bit = i * bpp as usize;
+                    let shift = 8 - bpp as usize - bit % 8;
+                    let index = (line[bit / 8] >> shift) & ((1u16 << bpp) - 1) as u8;
+                    palette.get(index as usize).copied().unwrap_or(0xFF00_0000)
+                }
+                _ => return Err(ImageError::Unsupported),
+            };
+            image.set(x, y, color);
+        }
+    }
+    Ok(image)
+}
+
+// ---------------------------------------------------------------------------
+// PNM
+// ---------------------------------------------------------------------------
+
+fn decode_pnm(data: &[u8]) -> Result<Image, ImageError> {
+    let color = data[1] == b'6';
+    let mut pos = 2;
+    // Width, height and maximum value, separated by white space and comments
+    let mut fields = [0u32; 3];
+    for field in &mut fields {
+        loop {
+            match data.get(pos) {
+                Some(b'#') => {
+                    while data.get(pos).map_or(false, |&c| c != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => pos += 1,
+                Some(_) => break,
+                None => return Err(ImageError::Truncated),
+            }
+        }
+        let start = pos;
+        while data.get(pos).map_or(false, u8::is_ascii_digit) {
+            pos += 1;
+        }
+        *field = core::str::from_utf8(&data[start..pos]).ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(ImageError::Corrupt)?;
+    }
+    // Exactly one white space character precedes the samples
+    pos += 1;
+    let [width, height, max] = fields;
+    if max == 0 || max > 0xFFFF {
+        return Err(ImageError::Corrupt);
+    }
+    let mut image = Image::new(width, height)?;
+    let channels = if color { 3 } else { 1 };
+    let sample_bytes = if max > 255 { 2 } else { 1 };
+    let needed = (width * height) as usize * channels * sample_bytes;
+    let samples = data.get(pos..pos + needed).ok_or(ImageError::Truncated)?;
+    let sample = |i: usize| -> u8 {
+        let v = if sample_bytes == 2 {
+            u16::from_be_bytes([samples[i * 2], samples[i * 2 + 1]]) as u32
+        } else {
+            samples[i] as u32
+        };
+        (v * 255 / max) as u8
+    };
+    for i in 0..(width * height) as usize {
+        let color = if color {
+            argb(0xFF, sample(i * 3), sample(i * 3 + 1), sample(i * 3 + 2))
+        } else {
+            let g = sample(i);
+            argb(0xFF, g, g, g)
+        };
+        image.pixels[i] = color;
+    }
+    Ok(image)
+}
@@ -0,0 +1,374 @@
+//! DEFLATE decompression
+//!
+//! Decodes zlib streams (RFC 1950) and the DEFLATE data inside them
+//! (RFC 1951), as PNG image data uses. Huffman codes are decoded from
+//! their canonical form one bit at a time: a code of each length is
+//! compared against the range of codes of that length, so no lookup
+//! tables need building beyond symbol counts and the sorted symbols.
+
+use alloc::vec::Vec;
+
+/// Why a stream could not be decompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    /// The input ended in the middle of the stream
+    Truncated,
+    /// The input is not valid DEFLATE or zlib data
+    Corrupt,
+    /// The output would exceed the caller's limit
+    TooLarge,
+    /// The zlib checksum does not match the output
+    Checksum,
+}
+
+const MAX_BITS: usize = 15;
+
+/// Base lengths for length codes 257..285, and their extra bits
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances for distance codes 0..29, and their extra bits
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order the code length code lengths are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Reads bits least significant first, as DEFLATE packs them
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, bit_buf: 0, bit_count: 0 }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, InflateError> {
+        while self.bit_count < n {
+            let byte = *self.data.get(self.pos).ok_or(InflateError::Truncated)?;
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u64 << n) - 1) as u32;
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(value)
+    }
+
+    /// Drop the bits left in the current byte
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+
+    fn byte(&mut self) -> Result<u8, InflateError> {
+        let byte = *self.data.get(self.pos).ok_or(InflateError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+}
+
+/// A canonical Huffman code: how many codes have each length, and the
+/// symbols ordered by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; 288],
+}
+
+impl Huffman {
+    /// Build a code from each symbol's code length (0 for unused symbols)
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut h = Huffman { counts: [0; MAX_BITS + 1], symbols: [0; 288] };
+        for &len in lengths {
+            h.counts[len as usize] += 1;
+        }
+        // Reject codes with more codes of some length than fit
+        let mut left = 1i32;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - h.counts[len] as i32;
+            if left < 0 {
+                return Err(InflateError::Corrupt);
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + h.counts[len];
+        }
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                h.symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(h)
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, InflateError> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_BITS {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::Corrupt)
+    }
+}
+
+/// Decompress a zlib stream, checking its Adler-32 checksum
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    if data.len() < 6 {
+        return Err(InflateError::Truncated);
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    // Method 8 (deflate), a header checksum, and no preset dictionary
+    if cmf & 0x0F != 8 || ((cmf as u16) << 8 | flg as u16) % 31 != 0 || flg & 0x20 != 0 {
+        return Err(InflateError::Corrupt);
+    }
+    let mut r = BitReader::new(&data[2..]);
+    let mut out = Vec::new();
+    inflate_into(&mut r, &mut out, limit)?;
+
+    r.align();
+    let mut expected = 0u32;
+    for _ in 0..4 {
+        expected = (expected << 8) | r.byte()? as u32;
+    }
+    if adler32(&out) != expected {
+        return Err(InflateError::Checksum);
+    }
+    Ok(out)
+}
+
+/// Adler-32 checksum
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before `b` could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+fn inflate_into(r: &mut BitReader, out: &mut Vec<u8>, limit: usize) -> Result<(), InflateError> {
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => stored(r, out, limit)?,
+            1 => {
+                let (lit, dist) = fixed_codes()?;
+                codes(r, out, limit, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(r)?;
+                codes(r, out, limit, &lit, &dist)?;
+            }
+            _ => return Err(InflateError::Corrupt),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Copy a stored (uncompressed) block
+fn stored(r: &mut BitReader, out: &mut Vec<u8>, limit: usize) -> Result<(), InflateError> {
+    r.align();
+    let len = r.byte()? as u16 | (r.byte()? as u16) << 8;
+    let nlen = r.byte()? as u16 | (r.byte()? as u16) << 8;
+    if len != !nlen {
+        return Err(InflateError::Corrupt);
+    }
+    if out.len() + len as usize > limit {
+        return Err(InflateError::TooLarge);
+    }
+    let end = r.pos + len as usize;
+    let bytes = r.data.get(r.pos..end).ok_or(InflateError::Truncated)?;
+    out.extend_from_slice(bytes);
+    r.pos = end;
+    Ok(())
+}
+
+/// The codes of a fixed Huffman block
+fn fixed_codes() -> Result<(Huffman, Huffman), InflateError> {
+    let mut lengths = [0u8; 288];
+    for (symbol, len) in lengths.iter_mut().enumerate() {
+        *len = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+/// Read the codes of a dynamic Huffman block from its header
+fn dynamic_codes(r: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let nlen = r.bits(5)? as usize + 257;
+    let ndist = r.bits(5)? as usize + 1;
+    let ncode = r.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(InflateError::Corrupt);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[index] = r.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    // Literal/length and distance code lengths, run-length coded together
+    let mut lengths = [0u8; 286 + 30];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = code_length_code.decode(r)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err(InflateError::Corrupt);
+                }
+                (lengths[i - 1], 3 + r.bits(2)? as usize)
+            }
+            17 => (0, 3 + r.bits(3)? as usize),
+            _ => (0, 11 + r.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(InflateError::Corrupt);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    // Without an end-of-block code the block could never finish
+    if lengths[256] == 0 {
+        return Err(InflateError::Corrupt);
+    }
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..nlen + ndist])?))
+}
+
+/// Decode the symbols of a compressed block until its end code
+fn codes(r: &mut BitReader, out: &mut Vec<u8>, limit: usize, lit: &Huffman, dist: &Huffman) -> Result<(), InflateError> {
+    loop {
+        let symbol = lit.decode(r)? as usize;
+        if symbol < 256 {
+            if out.len() >= limit {
+                return Err(InflateError::TooLarge);
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(InflateError::Corrupt);
+        }
+        let len = LENGTH_BASE[index] as usize + r.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let dist_index = dist.decode(r)? as usize;
+        if dist_index >= DIST_BASE.len() {
+            return Err(InflateError::Corrupt);
+        }
+        let distance = DIST_BASE[dist_index] as usize + r.bits(DIST_EXTRA[dist_index] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError::Corrupt);
+        }
+        if out.len() + len > limit {
+            return Err(InflateError::TooLarge);
+        }
+        // The copy may overlap what it produces, so go byte by byte
+        let start = out.len() - distance;
+        for k in 0..len {
+            let byte = out[start + k];
+            out.push(byte);
+        }
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// "hello hello hello hello" as zlib level 0 and level 9 give it
+    const STORED: [u8; 34] = [
+        0x78, 0x01, 0x01, 0x17, 0x00, 0xe8, 0xff, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x68, 0x65, 0x6c,
+        0x6c, 0x6f, 0x20, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x68, 0x03,
+        0x08, 0xb1,
+    ];
+    const FIXED: [u8; 16] = [
+        0x78, 0xda, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01, 0x68, 0x03, 0x08, 0xb1,
+    ];
+    /// "0 squared is 0; 1 squared is 1; ..." up to 9, which zlib codes
+    /// with a dynamic Huffman table
+    const DYNAMIC: [u8; 76] = [
+        0x78, 0xda, 0x55, 0x8e, 0xbb, 0x0d, 0xc0, 0x20, 0x10, 0x43, 0x57, 0xf1, 0x08, 0x7c, 0x0f, 0x90,
+        0xa7, 0x89, 0x94, 0x14, 0x94, 0x09, 0x62, 0xff, 0x5c, 0x69, 0xca, 0xf7, 0x64, 0xd9, 0x0e, 0x58,
+        0xef, 0xbe, 0xbe, 0xe7, 0xc6, 0x5c, 0x08, 0x44, 0x54, 0x8e, 0x44, 0x52, 0x2e, 0x44, 0x56, 0x1e,
+        0x44, 0x39, 0xf2, 0x46, 0x54, 0x15, 0xa9, 0x12, 0xa6, 0x22, 0x7b, 0xa2, 0x1d, 0x95, 0xde, 0xd1,
+        0x55, 0x98, 0x8f, 0x0c, 0x15, 0xdd, 0x5f, 0xfc, 0xc3, 0x28, 0x32, 0xcb,
+    ];
+
+    #[kernel_test]
+    fn each_block_type_decodes() -> Result<(), String> {
+        let hello = b"hello hello hello hello".to_vec();
+        check_eq!(zlib_decompress(&STORED, 100), Ok(hello.clone()));
+        check_eq!(zlib_decompress(&FIXED, 100), Ok(hello));
+        let mut squares = String::new();
+        for i in 0..10 {
+            squares.push_str(&alloc::format!("{} squared is {}; ", i, i * i));
+        }
+        check_eq!(zlib_decompress(&DYNAMIC, 1000), Ok(squares.into_bytes()));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn bad_streams_are_refused() -> Result<(), String> {
+        check_eq!(zlib_decompress(&FIXED, 10), Err(InflateError::TooLarge));
+        check_eq!(zlib_decompress(&FIXED[..9], 100), Err(InflateError::Truncated));
+        let mut wrong_sum = FIXED;
+        wrong_sum[15] ^= 1;
+        check_eq!(zlib_decompress(&wrong_sum, 100), Err(InflateError::Checksum));
+        // A header whose check bits do not add up
+        let mut header = FIXED;
+        header[1] = 0xdb;
+        check_eq!(zlib_decompress(&header, 100), Err(InflateError::Corrupt));
+        // Block type 3 does not exist
+        let mut reserved = STORED;
+        reserved[2] = 0x07;
+        check!(zlib_decompress(&reserved, 100).is_err());
+        check_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        Ok(())
+    }
+}
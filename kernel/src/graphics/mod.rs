@@ -9,6 +9,16 @@ use spin::Mutex;
 use lazy_static::lazy_static;
 
 use crate::println;
+use crate::info;
+
+pub mod compositor;
+pub mod cursor;
+pub mod display;
+pub mod fbcon;
+pub mod font;
+pub mod image;
+pub mod inflate;
+pub mod raster;
 
 /// Framebuffer info
 #[derive(Debug, Clone, Copy)]
@@ -170,6 +180,26 @@ impl GraphicsContext {
         }
     }
     
+    /// Draw an anti-aliased line
+    pub fn draw_line_aa(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+        raster::draw_line_aa(self, x0, y0, x1, y1, color);
+    }
+    
+    /// Fill a polygon given its vertices in order
+    pub fn fill_polygon(&mut self, points: &[(i32, i32)], color: u32) {
+        raster::fill_polygon(self, points, color);
+    }
+    
+    /// Fill a rectangle with rounded corners
+    pub fn fill_rounded_rect(&mut self, x: i32, y: i32, w: u32, h: u32, radius: u32, color: u32) {
+        raster::fill_rounded_rect(self, x, y, w, h, radius, color);
+    }
+    
+    /// Fill a rectangle with a gradient running from `from` to `to`
+    pub fn fill_linear_gradient(&mut self, x: i32, y: i32, w: u32, h: u32, from: (i32, i32), to: (i32, i32), stops: &[raster::ColorStop]) {
+        raster::fill_linear_gradient(self, x, y, w, h, from, to, stops);
+    }
+    
     /// Blit another graphics context onto this one
     pub fn blit(&mut self, src: &GraphicsContext, x: i32, y: i32) {
         for sy in 0..src.height {
@@ -182,31 +212,26 @@ impl GraphicsContext {
     
     /// Draw text (using bitmap font)
     pub fn draw_text(&mut self, text: &str, x: i32, y: i32, color: u32, scale: u32) {
-        let mut cx = x;
+        let (cell_w, cell_h) = font::cell_size();
+        let (mut cx, mut cy) = (x, y);
         for ch in text.chars() {
-            self.draw_char(ch, cx, y, color, scale);
-            cx += (8 * scale) as i32;
+            if ch == '\n' {
+                cx = x;
+                cy += (cell_h * scale) as i32;
+                continue;
+            }
+            self.draw_char(ch, cx, cy, color, scale);
+            cx += (cell_w * scale) as i32;
         }
     }
     
     /// Draw single character
     fn draw_char(&mut self, ch: char, x: i32, y: i32, color: u32, scale: u32) {
-        let bitmap = get_char_bitmap(ch);
-        for row in 0..8usize {
-            for col in 0..8usize {
-                if bitmap[row] & (1 << (7 - col)) != 0 {
-                    for sy in 0..scale {
-                        for sx in 0..scale {
-                            self.set_pixel(
-                                (x + (col * scale as usize + sx as usize) as i32) as u32,
-                                (y + (row * scale as usize + sy as usize) as i32) as u32,
-                                color
-                            );
-                        }
-                    }
-                }
-            }
-        }
+        let glyph = font::glyph(ch);
+        let s = scale as i32;
+        glyph.for_each_run(|gx, gy, len| {
+            self.fill_rect(x + gx as i32 * s, y + gy as i32 * s, len * scale, scale, color);
+        });
     }
     
     /// Get raw pixel buffer
@@ -220,109 +245,6 @@ impl GraphicsContext {
     }
 }
 
-/// Get 8x8 bitmap for character
-fn get_char_bitmap(ch: char) -> [u8; 8] {
-    // Simple 8x8 font bitmaps for common characters
-    match ch {
-        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-        '!' => [0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x18, 0x00],
-        '"' => [0x66, 0x66, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00],
-        '#' => [0x66, 0x66, 0xff, 0x66, 0xff, 0x66, 0x66, 0x00],
-        '$' => [0x18, 0x3e, 0x60, 0x3c, 0x06, 0x7c, 0x18, 0x00],
-        '%' => [0x62, 0x66, 0x0c, 0x18, 0x30, 0x66, 0x46, 0x00],
-        '&' => [0x3c, 0x66, 0x3c, 0x38, 0x67, 0x66, 0x3f, 0x00],
-        '\'' => [0x06, 0x0c, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00],
-        '(' => [0x0c, 0x18, 0x30, 0x30, 0x30, 0x18, 0x0c, 0x00],
-        ')' => [0x30, 0x18, 0x0c, 0x0c, 0x0c, 0x18, 0x30, 0x00],
-        '*' => [0x00, 0x66, 0x3c, 0xff, 0x3c, 0x66, 0x00, 0x00],
-        '+' => [0x00, 0x18, 0x18, 0x7e, 0x18, 0x18, 0x00, 0x00],
-        ',' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x30],
-        '-' => [0x00, 0x00, 0x00, 0x7e, 0x00, 0x00, 0x00, 0x00],
-        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x00],
-        '/' => [0x00, 0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x00],
-        '0' => [0x3c, 0x66, 0x6e, 0x76, 0x66, 0x66, 0x3c, 0x00],
-        '1' => [0x18, 0x18, 0x38, 0x18, 0x18, 0x18, 0x7e, 0x00],
-        '2' => [0x3c, 0x66, 0x06, 0x0c, 0x30, 0x60, 0x7e, 0x00],
-        '3' => [0x3c, 0x66, 0x06, 0x1c, 0x06, 0x66, 0x3c, 0x00],
-        '4' => [0x06, 0x0e, 0x1e, 0x66, 0x7f, 0x06, 0x06, 0x00],
-        '5' => [0x7e, 0x60, 0x7c, 0x06, 0x06, 0x66, 0x3c, 0x00],
-        '6' => [0x3c, 0x66, 0x60, 0x7c, 0x66, 0x66, 0x3c, 0x00],
-        '7' => [0x7e, 0x66, 0x0c, 0x18, 0x18, 0x18, 0x18, 0x00],
-        '8' => [0x3c, 0x66, 0x66, 0x3c, 0x66, 0x66, 0x3c, 0x00],
-        '9' => [0x3c, 0x66, 0x66, 0x3e, 0x06, 0x66, 0x3c, 0x00],
-        ':' => [0x00, 0x00, 0x18, 0x00, 0x00, 0x18, 0x00, 0x00],
-        ';' => [0x00, 0x00, 0x18, 0x00, 0x00, 0x18, 0x18, 0x30],
-        '<' => [0x0e, 0x18, 0x30, 0x60, 0x30, 0x18, 0x0e, 0x00],
-        '=' => [0x00, 0x00, 0x7e, 0x00, 0x7e, 0x00, 0x00, 0x00],
-        '>' => [0x70, 0x18, 0x0c, 0x06, 0x0c, 0x18, 0x70, 0x00],
-        '?' => [0x3c, 0x66, 0x06, 0x0c, 0x18, 0x00, 0x18, 0x00],
-        '@' => [0x3c, 0x66, 0x6e, 0x6e, 0x60, 0x62, 0x3c, 0x00],
-        'A' => [0x18, 0x3c, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x00],
-        'B' => [0x7c, 0x66, 0x66, 0x7c, 0x66, 0x66, 0x7c, 0x00],
-        'C' => [0x3c, 0x66, 0x60, 0x60, 0x60, 0x66, 0x3c, 0x00],
-        'D' => [0x78, 0x6c, 0x66, 0x66, 0x66, 0x6c, 0x78, 0x00],
-        'E' => [0x7e, 0x60, 0x60, 0x78, 0x60, 0x60, 0x7e, 0x00],
-        'F' => [0x7e, 0x60, 0x60, 0x78, 0x60, 0x60, 0x60, 0x00],
-        'G' => [0x3c, 0x66, 0x60, 0x6e, 0x66, 0x66, 0x3c, 0x00],
-        'H' => [0x66, 0x66, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x00],
-        'I' => [0x3c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x00],
-        'J' => [0x1e, 0x0c, 0x0c, 0x0c, 0x0c, 0x6c, 0x38, 0x00],
-        'K' => [0x66, 0x6c, 0x78, 0x70, 0x78, 0x6c, 0x66, 0x00],
-        'L' => [0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x7e, 0x00],
-        'M' => [0x63, 0x77, 0x7f, 0x6b, 0x63, 0x63, 0x63, 0x00],
-        'N' => [0x66, 0x76, 0x7e, 0x7e, 0x6e, 0x66, 0x66, 0x00],
-        'O' => [0x3c, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x00],
-        'P' => [0x7c, 0x66, 0x66, 0x7c, 0x60, 0x60, 0x60, 0x00],
-        'Q' => [0x3c, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x0e, 0x00],
-        'R' => [0x7c, 0x66, 0x66, 0x7c, 0x78, 0x6c, 0x66, 0x00],
-        'S' => [0x3c, 0x66, 0x60, 0x3c, 0x06, 0x66, 0x3c, 0x00],
-        'T' => [0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00],
-        'U' => [0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x00],
-        'V' => [0x66, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x18, 0x00],
-        'W' => [0x63, 0x63, 0x63, 0x6b, 0x7f, 0x77, 0x63, 0x00],
-        'X' => [0x66, 0x66, 0x3c, 0x18, 0x3c, 0x66, 0x66, 0x00],
-        'Y' => [0x66, 0x66, 0x66, 0x3c, 0x18, 0x18, 0x18, 0x00],
-        'Z' => [0x7e, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x7e, 0x00],
-        '[' => [0x3c, 0x30, 0x30, 0x30, 0x30, 0x30, 0x3c, 0x00],
-        '\\' => [0x00, 0x60, 0x30, 0x18, 0x0c, 0x06, 0x03, 0x00],
-        ']' => [0x3c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x3c, 0x00],
-        '^' => [0x00, 0x18, 0x3c, 0x7e, 0x18, 0x18, 0x18, 0x18],
-        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff],
-        '`' => [0x0c, 0x18, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00],
-        'a' => [0x00, 0x00, 0x3c, 0x06, 0x3e, 0x66, 0x3e, 0x00],
-        'b' => [0x00, 0x60, 0x60, 0x7c, 0x66, 0x66, 0x7c, 0x00],
-        'c' => [0x00, 0x00, 0x3c, 0x60, 0x60, 0x60, 0x3c, 0x00],
-        'd' => [0x00, 0x06, 0x06, 0x3e, 0x66, 0x66, 0x3e, 0x00],
-        'e' => [0x00, 0x00, 0x3c, 0x66, 0x7e, 0x60, 0x3c, 0x00],
-        'f' => [0x00, 0x0e, 0x18, 0x3e, 0x18, 0x18, 0x18, 0x00],
-        'g' => [0x00, 0x00, 0x3e, 0x66, 0x66, 0x3e, 0x06, 0x7c],
-        'h' => [0x00, 0x60, 0x60, 0x7c, 0x66, 0x66, 0x66, 0x00],
-        'i' => [0x00, 0x18, 0x00, 0x38, 0x18, 0x18, 0x3c, 0x00],
-        'j' => [0x00, 0x06, 0x00, 0x06, 0x06, 0x06, 0x06, 0x3c],
-        'k' => [0x00, 0x60, 0x60, 0x6c, 0x78, 0x6c, 0x66, 0x00],
-        'l' => [0x00, 0x38, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x00],
-        'm' => [0x00, 0x00, 0x66, 0x7f, 0x7f, 0x6b, 0x63, 0x00],
-        'n' => [0x00, 0x00, 0x7c, 0x66, 0x66, 0x66, 0x66, 0x00],
-        'o' => [0x00, 0x00, 0x3c, 0x66, 0x66, 0x66, 0x3c, 0x00],
-        'p' => [0x00, 0x00, 0x7c, 0x66, 0x66, 0x7c, 0x60, 0x60],
-        'q' => [0x00, 0x00, 0x3e, 0x66, 0x66, 0x3e, 0x06, 0x06],
-        'r' => [0x00, 0x00, 0x7c, 0x66, 0x60, 0x60, 0x60, 0x00],
-        's' => [0x00, 0x00, 0x3e, 0x60, 0x3c, 0x06, 0x7c, 0x00],
-        't' => [0x00, 0x18, 0x7e, 0x18, 0x18, 0x18, 0x0e, 0x00],
-        'u' => [0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x3e, 0x00],
-        'v' => [0x00, 0x00, 0x66, 0x66, 0x66, 0x3c, 0x18, 0x00],
-        'w' => [0x00, 0x00, 0x63, 0x6b, 0x7f, 0x3e, 0x36, 0x00],
-        'x' => [0x00, 0x00, 0x66, 0x3c, 0x18, 0x3c, 0x66, 0x00],
-        'y' => [0x00, 0x00, 0x66, 0x66, 0x66, 0x3e, 0x0c, 0x78],
-        'z' => [0x00, 0x00, 0x7e, 0x0c, 0x18, 0x30, 0x7e, 0x00],
-        '{' => [0x0c, 0x18, 0x18, 0x30, 0x18, 0x18, 0x0c, 0x00],
-        '|' => [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00],
-        '}' => [0x30, 0x18, 0x18, 0x0c, 0x18, 0x18, 0x30, 0x00],
-        '~' => [0x31, 0x6b, 0x46, 0x00, 0x00, 0x00, 0x00, 0x00],
-        _ => [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], // Block for unknown chars
-    }
-}
-
 /// Global graphics context
 lazy_static! {
     static ref GRAPHICS_CONTEXT: Mutex<Option<GraphicsContext>> = Mutex::new(None);
@@ -330,14 +252,16 @@ lazy_static! {
 
 /// Initialize graphics subsystem
 pub fn init() {
-    println!("[graphics] Initializing graphics subsystem...");
+    info!("graphics", "Initializing graphics subsystem...");
     
     // Create default 1024x768 graphics context
     let ctx = GraphicsContext::new(1024, 768);
     *GRAPHICS_CONTEXT.lock() = Some(ctx);
     
-    println!("[graphics] Graphics context created: 1024x768");
-    println!("[graphics] Ready for rendering");
+    info!("graphics", "Graphics context created: 1024x768");
+    
+    font::init();
+    info!("graphics", "Ready for rendering");
 }
 
 /// Get graphics context
@@ -350,6 +274,31 @@ pub fn set_context(ctx: GraphicsContext) {
     *GRAPHICS_CONTEXT.lock() = Some(ctx);
 }
 
+/// Switch display mode and rebuild everything sized to the screen
+///
+/// The compositor back buffer is released before the switch so the old and
+/// new buffers never need to fit in the heap together.
+pub fn set_mode(width: u32, height: u32, bpp: u8) -> crate::drivers::DriverResult<()> {
+    cursor::forget();
+    let had_compositor = compositor::shutdown();
+    let result = crate::drivers::vesa::set_mode(width, height, bpp);
+
+    // Rebuild for whichever mode is now active, even if the switch failed
+    display::init();
+    if had_compositor {
+        compositor::init();
+    }
+    fbcon::resize();
+    let bounds = display::virtual_bounds();
+    crate::drivers::input::set_mouse_bounds(bounds.w, bounds.h);
+    cursor::reset();
+
+    if result.is_ok() {
+        info!("graphics", "Display mode {}x{} @ {}bpp", width, height, bpp);
+    }
+    result
+}
+
 /// Print graphics info
 pub fn print_info() {
     if let Some(ref ctx) = *GRAPHICS_CONTEXT.lock() {
@@ -359,6 +308,10 @@ pub fn print_info() {
     } else {
         println!("Graphics context not initialized");
     }
+    font::print_info();
+    display::print_info();
+    cursor::print_info();
+    compositor::print_stats();
 }
 
 /// Integer square root (no_std compatible)
@@ -0,0 +1,424 @@
+//! Shape rasterizer
+//!
+//! Anti-aliased lines, filled polygons and triangles, rounded rectangles
+//! and linear gradients, drawn onto anything implementing `Surface`.
+//!
+//! Colors are 32-bit with alpha in the top byte. The channel order of the
+//! other three bytes does not matter (the VESA driver and browser use
+//! ARGB, the graphics context ABGR) as long as one surface sticks to one
+//! order. Coverage is folded into the alpha channel and blended with
+//! `vesa::colors::blend`. All arithmetic is fixed point.
+
+use alloc::vec::Vec;
+
+use crate::drivers::vesa::colors::blend;
+use crate::drivers::vesa::VesaDriver;
+
+/// A pixel target for the rasterizer
+///
+/// Coordinates passed to the methods are already clipped to `size()`.
+pub trait Surface {
+    /// Width and height in pixels
+    fn size(&self) -> (u32, u32);
+
+    /// Overwrite `len` pixels of row `y` starting at `x`
+    fn fill_span(&mut self, x: u32, y: u32, len: u32, color: u32);
+
+    /// Blend `color` by its alpha over the pixel at (x, y)
+    fn blend_pixel(&mut self, x: u32, y: u32, color: u32);
+
+    /// Blend `color` over `len` pixels of row `y` starting at `x`
+    fn blend_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        for i in 0..len {
+            self.blend_pixel(x + i, y, color);
+        }
+    }
+}
+
+/// A color at a position along a gradient, `offset` from 0 (start) to 255 (end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorStop {
+    pub offset: u8,
+    pub color: u32,
+}
+
+impl ColorStop {
+    pub const fn new(offset: u8, color: u32) -> Self {
+        Self { offset, color }
+    }
+}
+
+/// Scale a color's alpha by `coverage` (0-255)
+#[inline]
+fn with_coverage(color: u32, coverage: u32) -> u32 {
+    let a = (color >> 24) * coverage / 255;
+    (color & 0x00FF_FFFF) | (a << 24)
+}
+
+/// Fill a clipped span, blending only when the color is translucent
+fn span<S: Surface + ?Sized>(s: &mut S, x0: i32, x1: i32, y: i32, color: u32) {
+    let (w, h) = s.size();
+    if y < 0 || y >= h as i32 {
+        return;
+    }
+    let x0 = x0.max(0);
+    let x1 = x1.min(w as i32);
+    if x1 <= x0 {
+        return;
+    }
+    match color >> 24 {
+        0 => {}
+        0xFF => s.fill_span(x0 as u32, y as u32, (x1 - x0) as u32, color),
+        _ => s.blend_span(x0 as u32, y as u32, (x1 - x0) as u32, color),
+    }
+}
+
+/// Blend one pixel at partial coverage, clipped
+fn plot<S: Surface + ?Sized>(s: &mut S, x: i32, y: i32, color: u32, coverage: u32) {
+    let (w, h) = s.size();
+    if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 || coverage == 0 {
+        return;
+    }
+    s.blend_pixel(x as u32, y as u32, with_coverage(color, coverage));
+}
+
+/// Anti-aliased line (Xiaolin Wu)
+///
+/// Each step along the major axis splits the color between the two pixels
+/// straddling the ideal line in proportion to their distance from it.
+pub fn draw_line_aa<S: Surface + ?Sized>(s: &mut S, x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+    let steep = (y1 - y0).abs() > (x1 - x0).abs();
+    let (mut x0, mut y0, mut x1, mut y1) = if steep { (y0, x0, y1, x1) } else { (x0, y0, x1, y1) };
+    if x0 > x1 {
+        core::mem::swap(&mut x0, &mut x1);
+        core::mem::swap(&mut y0, &mut y1);
+    }
+    let dx = (x1 - x0) as i64;
+    let dy = (y1 - y0) as i64;
+    // Minor-axis position in 16.16 fixed point
+    let gradient = if dx == 0 { 0 } else { (dy << 16) / dx };
+    let mut inter = (y0 as i64) << 16;
+
+    for x in x0..=x1 {
+        let y = (inter >> 16) as i32;
+        let frac = ((inter & 0xFFFF) >> 8) as u32;
+        if steep {
+            plot(s, y, x, color, 255 - frac);
+            plot(s, y + 1, x, color, frac);
+        } else {
+            plot(s, x, y, color, 255 - frac);
+            plot(s, x, y + 1, color, frac);
+        }
+        inter += gradient;
+    }
+}
+
+/// Fill a polygon (even-odd rule)
+///
+/// Pixels whose centers fall inside are filled, so polygons sharing an
+/// edge neither overlap nor leave gaps. Convex and concave outlines both
+/// work; edges are not anti-aliased.
+pub fn fill_polygon<S: Surface + ?Sized>(s: &mut S, points: &[(i32, i32)], color: u32) {
+    if points.len() < 3 {
+        return;
+    }
+    let (_, h) = s.size();
+    let top = points.iter().map(|p| p.1).min().unwrap_or(0).max(0);
+    let bottom = points.iter().map(|p| p.1).max().unwrap_or(0).min(h as i32);
+
+    let mut crossings: Vec<i64> = Vec::with_capacity(points.len());
+    for y in top..bottom {
+        // Sample at the pixel center, in doubled coordinates
+        let cy2 = 2 * y as i64 + 1;
+        crossings.clear();
+        for (i, &(ax, ay)) in points.iter().enumerate() {
+            let (bx, by) = points[(i + 1) % points.len()];
+            let (ay2, by2) = (2 * ay as i64, 2 * by as i64);
+            // Half-open so a vertex shared by two edges counts once
+            if (ay2 <= cy2) == (by2 <= cy2) {
+                continue;
+            }
+            // Crossing x in 16.16 fixed point
+            let x = ((ax as i64) << 16) + (((cy2 - ay2) * (bx - ax) as i64) << 16) / (by2 - ay2);
+            crossings.push(x);
+        }
+        crossings.sort_unstable();
+        for pair in crossings.chunks_exact(2) {
+            // First and one-past-last pixel whose center is inside
+            let x0 = ((pair[0] - 0x8000 + 0xFFFF) >> 16) as i32;
+            let x1 = ((pair[1] - 0x8000 + 0xFFFF) >> 16) as i32;
+            span(s, x0, x1, y, color);
+        }
+    }
+}
+
+/// Fill a triangle
+pub fn fill_triangle<S: Surface + ?Sized>(s: &mut S, p0: (i32, i32), p1: (i32, i32), p2: (i32, i32), color: u32) {
+    fill_polygon(s, &[p0, p1, p2], color);
+}
+
+/// Fill a rectangle with corners rounded to `radius`, anti-aliased
+pub fn fill_rounded_rect<S: Surface + ?Sized>(s: &mut S, x: i32, y: i32, w: u32, h: u32, radius: u32, color: u32) {
+    let r = radius.min(w / 2).min(h / 2) as i64;
+    let (w, h) = (w as i64, h as i64);
+    let r256 = r * 256;
+
+    for row in 0..h {
+        // Distance from the corner circles' center line to this row's
+        // center, in 1/256 pixel
+        let center = row * 256 + 128;
+        let dy = if row < r {
+            r256 - center
+        } else if row >= h - r {
+            center - (h - r) * 256
+        } else {
+            0
+        };
+        // How far the edge is pulled in on this row, in 1/256 pixel
+        let inset = if dy > 0 { r256 - isqrt((r256 * r256 - dy * dy).max(0) as u64) as i64 } else { 0 };
+        let whole = (inset >> 8) as i32;
+        let frac = (inset & 0xFF) as u32;
+
+        let py = y + row as i32;
+        let left = x + whole;
+        let right = x + w as i32 - whole;
+        if frac == 0 {
+            span(s, left, right, py, color);
+        } else {
+            // Edge pixels are partly covered
+            let coverage = 255 - frac;
+            plot(s, left, py, color, coverage);
+            plot(s, right - 1, py, color, coverage);
+            span(s, left + 1, right - 1, py, color);
+        }
+    }
+}
+
+/// Fill a rectangle with a linear gradient
+///
+/// The gradient runs from `from` (offset 0) to `to` (offset 255), both in
+/// surface coordinates; pixels beyond either end take the end color, as in
+/// CSS `linear-gradient`. `stops` must be sorted by offset.
+pub fn fill_linear_gradient<S: Surface + ?Sized>(
+    s: &mut S,
+    x: i32,
+    y: i32,
+    w: u32,
+    h: u32,
+    from: (i32, i32),
+    to: (i32, i32),
+    stops: &[ColorStop],
+) {
+    if stops.is_empty() {
+        return;
+    }
+    let ramp = build_ramp(stops);
+    let (dx, dy) = ((to.0 - from.0) as i64, (to.1 - from.1) as i64);
+    let len2 = dx * dx + dy * dy;
+    if len2 == 0 {
+        return fill_rect(s, x, y, w, h, ramp[255]);
+    }
+
+    for row in 0..h as i32 {
+        let py = y + row;
+        // Position along the gradient in 16.16, where 1.0 is the end
+        let py_rel = (py - from.1) as i64;
+        let mut t = (((x - from.0) as i64 * dx + py_rel * dy) << 16) / len2;
+        let step = (dx << 16) / len2;
+
+        if dx == 0 {
+            // Vertical gradient: one color per row
+            span(s, x, x + w as i32, py, ramp[ramp_index(t)]);
+            continue;
+        }
+        let mut run_start = x;
+        let mut run_color = ramp[ramp_index(t)];
+        for px in x..x + w as i32 {
+            let c = ramp[ramp_index(t)];
+            if c != run_color {
+                span(s, run_start, px, py, run_color);
+                run_start = px;
+                run_color = c;
+            }
+            t += step;
+        }
+        span(s, run_start, x + w as i32, py, run_color);
+    }
+}
+
+/// Fill a plain rectangle
+pub fn fill_rect<S: Surface + ?Sized>(s: &mut S, x: i32, y: i32, w: u32, h: u32, color: u32) {
+    for row in 0..h as i32 {
+        span(s, x, x + w as i32, y + row, color);
+    }
+}
+
+/// Map a 16.16 gradient position to a ramp entry
+#[inline]
+fn ramp_index(t: i64) -> usize {
+    (t.clamp(0, 0xFFFF) >> 8) as usize
+}
+
+/// Expand color stops into 256 interpolated colors
+fn build_ramp(stops: &[ColorStop]) -> [u32; 256] {
+    let mut ramp = [0u32; 256];
+    for (i, slot) in ramp.iter_mut().enumerate() {
+        let i = i as u32;
+        let next = stops.iter().position(|st| st.offset as u32 >= i);
+        *slot = match next {
+            None => stops[stops.len() - 1].color,
+            Some(0) => stops[0].color,
+            Some(n) => {
+                let (a, b) = (stops[n - 1], stops[n]);
+                let span = (b.offset - a.offset) as u32;
+                lerp(a.color, b.color, (i - a.offset as u32) * 255 / span.max(1))
+            }
+        };
+    }
+    ramp
+}
+
+/// Interpolate each channel of two colors, `t` from 0 (a) to 255 (b)
+fn lerp(a: u32, b: u32, t: u32) -> u32 {
+    let mut out = 0;
+    for shift in [0, 8, 16, 24] {
+        let ca = (a >> shift) & 0xFF;
+        let cb = (b >> shift) & 0xFF;
+        let c = (ca * (255 - t) + cb * t + 127) / 255;
+        out |= c << shift;
+    }
+    out
+}
+
+/// Integer square root
+fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+impl Surface for super::GraphicsContext {
+    fn size(&self) -> (u32, u32) {
+        (self.width(), self.height())
+    }
+
+    fn fill_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        self.init_buffer();
+        let start = (y * self.width() + x) as usize;
+        self.pixels_mut()[start..start + len as usize].fill(color);
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, color: u32) {
+        let dst = self.get_pixel(x, y);
+        self.set_pixel(x, y, blend(dst, color));
+    }
+}
+
+impl Surface for super::compositor::Compositor {
+    fn size(&self) -> (u32, u32) {
+        (self.width(), self.height())
+    }
+
+    fn fill_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        self.fill_rect(x as i32, y as i32, len, 1, color);
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, color: u32) {
+        super::compositor::Compositor::blend_pixel(self, x as i32, y as i32, color);
+    }
+
+    fn blend_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        self.fill_rect_alpha(x as i32, y as i32, len, 1, color);
+    }
+}
+
+impl Surface for VesaDriver {
+    fn size(&self) -> (u32, u32) {
+        (self.info.width, self.info.height)
+    }
+
+    fn fill_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        self.fill_rect(x as i32, y as i32, len, 1, color);
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, color: u32) {
+        VesaDriver::blend_pixel(self, x, y, color);
+    }
+
+    fn blend_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        self.fill_rect_alpha(x as i32, y as i32, len, 1, color);
+    }
+}
+
+impl Surface for crate::browser::render::Framebuffer {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn fill_span(&mut self, x: u32, y: u32, len: u32, color: u32) {
+        let start = (y * self.width + x) as usize;
+        self.data[start..start + len as usize].fill(color);
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, color: u32) {
+        let idx = (y * self.width + x) as usize;
+        self.data[idx] = blend(self.data[idx], color);
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::browser::render::Framebuffer;
+    use crate::drivers::vesa::colors;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    const INK: u32 = 0xFF00_0000;
+
+    fn filled_in_row(fb: &Framebuffer, y: u32) -> usize {
+        let row = (y * fb.width) as usize;
+        fb.data[row..row + fb.width as usize].iter().filter(|&&p| p != colors::WHITE).count()
+    }
+
+    #[kernel_test]
+    fn triangle_fills_pixels_whose_centers_are_inside() -> Result<(), String> {
+        let mut fb = Framebuffer::new(6, 6);
+        fill_triangle(&mut fb, (0, 0), (4, 0), (0, 4), INK);
+        let rows: Vec<usize> = (0..6).map(|y| filled_in_row(&fb, y)).collect();
+        check_eq!(rows, alloc::vec![3, 2, 1, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn triangles_sharing_an_edge_neither_overlap_nor_leave_gaps() -> Result<(), String> {
+        // Translucent, so a pixel filled twice comes out darker
+        let mut fb = Framebuffer::new(8, 8);
+        let half = 0x8000_0000;
+        fill_triangle(&mut fb, (0, 0), (8, 0), (8, 8), half);
+        fill_triangle(&mut fb, (0, 0), (8, 8), (0, 8), half);
+        let once = blend(colors::WHITE, half);
+        check!(fb.data.iter().all(|&p| p == once));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn concave_polygon_leaves_its_notch_empty() -> Result<(), String> {
+        let mut fb = Framebuffer::new(6, 6);
+        let c = [(0, 0), (6, 0), (6, 2), (2, 2), (2, 4), (6, 4), (6, 6), (0, 6)];
+        fill_polygon(&mut fb, &c, INK);
+        let at = |x: u32, y: u32| fb.data[(y * 6 + x) as usize];
+        check_eq!(at(4, 1), INK);
+        check_eq!(at(1, 3), INK);
+        check_eq!(at(4, 3), colors::WHITE);
+        check_eq!(at(4, 5), INK);
+        Ok(())
+    }
+}
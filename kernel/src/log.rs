@@ -0,0 +1,259 @@
+//! Kernel log
+//!
+//! Subsystems report what they do with `error!`, `warn!`, `info!`,
+//! `debug!` and `trace!`, naming themselves first:
+//! `info!("vfs", "Mounted {} at {}", name, path)`. Records at or above
+//! the kept level, or the level set for their module, go into a ring
+//! buffer of `LOG_SIZE` bytes, where the oldest make way for new ones;
+//! `dmesg` and `/proc/kmsg` read it. Records are also mirrored to the
+//! screen and the serial port down to levels of their own.
+//!
+//! The buffer is a static array, so logging works before the heap does.
+//! Each record in it is one line, `<N>[seconds] module: text`, where `N`
+//! is the level from 1 for errors to 5 for tracing.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+/// Bytes of records kept
+pub const LOG_SIZE: usize = 64 * 1024;
+
+/// How much a record matters, most severe first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&l| l as u8 == value)
+    }
+}
+
+/// Bytes in a circle; full, it drops whole lines from the front
+struct Ring {
+    buf: [u8; LOG_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self { buf: [0; LOG_SIZE], start: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == LOG_SIZE {
+            // Drop the oldest line
+            while self.len > 0 {
+                let dropped = self.buf[self.start];
+                self.start = (self.start + 1) % LOG_SIZE;
+                self.len -= 1;
+                if dropped == b'\n' {
+                    break;
+                }
+            }
+        }
+        self.buf[(self.start + self.len) % LOG_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(move |i| self.buf[(self.start + i) % LOG_SIZE])
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Least severe level kept; 0 keeps nothing
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Least severe level shown on screen; 0 shows nothing
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Least severe level sent to the serial port; 0 sends nothing
+static SERIAL_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Levels for single modules, in place of `LEVEL`
+static FILTERS: Mutex<Vec<(String, Level)>> = Mutex::new(Vec::new());
+
+fn to_u8(level: Option<Level>) -> u8 {
+    level.map_or(0, |l| l as u8)
+}
+
+/// Keep records down to `level`, or none
+pub fn set_level(level: Option<Level>) {
+    LEVEL.store(to_u8(level), Ordering::Relaxed);
+}
+
+/// Show records on screen down to `level`, or none
+pub fn set_console_level(level: Option<Level>) {
+    CONSOLE_LEVEL.store(to_u8(level), Ordering::Relaxed);
+}
+
+/// Send records to the serial port down to `level`, or none
+pub fn set_serial_level(level: Option<Level>) {
+    SERIAL_LEVEL.store(to_u8(level), Ordering::Relaxed);
+}
+
+/// Keep records of some modules down to levels of their own; a module
+/// also covers those under it, so `net` covers `net/drivers`
+pub fn set_filters(filters: Vec<(String, Level)>) {
+    *FILTERS.lock() = filters;
+}
+
+/// Parse `module=level,...` for `set_filters`
+pub fn parse_filters(text: &str) -> Option<Vec<(String, Level)>> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (module, level) = part.split_once('=')?;
+            Some((String::from(module.trim()), Level::from_name(level.trim())?))
+        })
+        .collect()
+}
+
+/// Whether records of `module` at `level` are kept
+pub fn enabled(module: &str, level: Level) -> bool {
+    let filters = FILTERS.lock();
+    let filter = filters.iter().find(|(name, _)| {
+        module == name || module.strip_prefix(name.as_str()).map_or(false, |rest| rest.starts_with('/'))
+    });
+    let kept = filter.map_or(LEVEL.load(Ordering::Relaxed), |&(_, l)| l as u8);
+    level as u8 <= kept
+}
+
+/// Record a message; called by the level macros
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(module, level) {
+        return;
+    }
+    let ms = crate::drivers::timer::elapsed_ms();
+    {
+        let mut ring = RING.lock();
+        let _ = writeln!(ring, "<{}>[{:5}.{:03}] {}: {}", level as u8, ms / 1000, ms % 1000, module, args);
+    }
+    let screen = level as u8 <= CONSOLE_LEVEL.load(Ordering::Relaxed);
+    let serial = level as u8 <= SERIAL_LEVEL.load(Ordering::Relaxed);
+    if screen || serial {
+        crate::console::write_log(format_args!("[{}] {}\n", module, args), screen, serial);
+    }
+}
+
+/// A record read back from the buffer
+pub struct Record<'a> {
+    pub level: Level,
+    /// `[seconds] module: text`
+    pub text: &'a str,
+}
+
+/// Everything in the buffer, oldest first, as `/proc/kmsg` shows it
+pub fn contents() -> Vec<u8> {
+    RING.lock().bytes().collect()
+}
+
+/// The last `bytes` of the buffer, a byte at a time, for the panic
+/// handler; false, without waiting, if the buffer is locked
+pub fn tail(bytes: usize, mut f: impl FnMut(u8)) -> bool {
+    let ring = match RING.try_lock() {
+        Some(ring) => ring,
+        None => return false,
+    };
+    ring.bytes().skip(ring.len.saturating_sub(bytes)).for_each(&mut f);
+    true
+}
+
+/// Split `contents` into records
+pub fn records(contents: &str) -> impl Iterator<Item = Record<'_>> {
+    contents.lines().filter_map(|line| {
+        let rest = line.strip_prefix('<')?;
+        let (level, text) = rest.split_once('>')?;
+        Some(Record { level: Level::from_u8(level.parse().ok()?)?, text })
+    })
+}
+
+/// Empty the buffer
+pub fn clear() {
+    let mut ring = RING.lock();
+    ring.start = 0;
+    ring.len = 0;
+}
+
+/// Log an error
+#[macro_export]
+macro_rules! error {
+    ($module:expr, $($arg:tt)*) => ($crate::log::_log($crate::log::Level::Error, $module, format_args!($($arg)*)));
+}
+
+/// Log a warning
+#[macro_export]
+macro_rules! warn {
+    ($module:expr, $($arg:tt)*) => ($crate::log::_log($crate::log::Level::Warn, $module, format_args!($($arg)*)));
+}
+
+/// Log what happened
+#[macro_export]
+macro_rules! info {
+    ($module:expr, $($arg:tt)*) => ($crate::log::_log($crate::log::Level::Info, $module, format_args!($($arg)*)));
+}
+
+/// Log detail useful when looking into a problem
+#[macro_export]
+macro_rules! debug {
+    ($module:expr, $($arg:tt)*) => ($crate::log::_log($crate::log::Level::Debug, $module, format_args!($($arg)*)));
+}
+
+/// Log step-by-step detail
+#[macro_export]
+macro_rules! trace {
+    ($module:expr, $($arg:tt)*) => ($crate::log::_log($crate::log::Level::Trace, $module, format_args!($($arg)*)));
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn parses_filters() -> Result<(), String> {
+        let filters = parse_filters("net=debug, vfs = trace,").ok_or("no filters")?;
+        check_eq!(filters.len(), 2);
+        check_eq!(filters[0], (String::from("net"), Level::Debug));
+        check_eq!(filters[1], (String::from("vfs"), Level::Trace));
+        check!(parse_filters("net=loud").is_none());
+        check!(parse_filters("net").is_none());
+        Ok(())
+    }
+}
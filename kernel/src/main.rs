@@ -13,12 +13,16 @@
 extern crate alloc;
 
 use core::arch::naked_asm;
-use webbos_shared::bootinfo::BootInfo;
+use webbos_shared::bootinfo::{self, BootInfo, ElfNote, FEATURE_PHYSMAP};
 
 mod arch;
 mod mm;
+mod acpi;
 mod console;
+mod log;
+mod cmdline;
 mod panic;
+mod symbols;
 mod process;
 mod syscall;
 mod fs;
@@ -26,15 +30,34 @@ mod drivers;
 mod net;
 mod browser;
 mod storage;
+mod sound;
 mod crypto;
 mod tls;
 mod graphics;
 mod testing;
 mod users;
 mod desktop;
+mod config;
+mod shell;
+mod watchdog;
+mod trace;
+mod crashdump;
+mod efi;
+mod smbios;
+mod update;
+mod module;
+mod power;
+mod service;
 
 use arch::cpu;
 use arch::interrupts;
+use shell::Command;
+
+/// Tells the bootloader what this kernel needs from the boot info: it is
+/// linked to run in the physmap
+#[used]
+#[link_section = ".note.webbos"]
+static BOOT_NOTE: ElfNote = ElfNote::new(FEATURE_PHYSMAP);
 
 /// Kernel entry point
 /// 
@@ -44,8 +67,8 @@ use arch::interrupts;
 #[no_mangle]
 pub extern "C" fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     // Validate boot info
-    if !boot_info.verify() {
-        panic!("Invalid boot info magic number!");
+    if let Err(e) = unsafe { boot_info.check() } {
+        panic!("Invalid boot info: {:?}", e);
     }
 
     // Initialize console for early output
@@ -76,6 +99,14 @@ pub extern "C" fn kernel_entry(boot_info: &'static BootInfo) -> ! {
         boot_info.stack_size / 1024
     );
     println!("  Memory map: {} entries", boot_info.memory_map_count);
+    print!("  Features:");
+    for name in bootinfo::feature_names(boot_info.features) {
+        print!(" {}", name);
+    }
+    println!();
+    if let Some(initrd) = unsafe { boot_info.initrd() } {
+        println!("  Initrd: {} bytes", initrd.len());
+    }
 
     unsafe {
         if let Some(name) = boot_info.bootloader_name().split('.').next() {
@@ -84,110 +115,173 @@ pub extern "C" fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     }
 
     // Initialize architecture-specific features
-    println!("\n[cpu] Initializing...");
+    info!("cpu", "Initializing...");
     cpu::init();
-    println!("[cpu] CPU features detected");
+    info!("cpu", "CPU features detected");
 
     // Initialize memory management
-    println!("\n[mm] Initializing memory management...");
+    info!("mm", "Initializing memory management...");
     unsafe {
         mm::init(boot_info);
     }
-    println!("[mm] Memory management initialized");
+    info!("mm", "Memory management initialized");
+
+    // Options from the bootloader, now that there is a heap to keep them
+    cmdline::init(unsafe { boot_info.cmdline() }.unwrap_or(""));
+
+    // What the previous boot left if it crashed, before this one can
+    crashdump::init(unsafe { boot_info.memory_map() });
+
+    // What the display can do, for mode setting later
+    drivers::vesa::keep_boot_modes(unsafe { boot_info.video_modes() }, unsafe { boot_info.edid() });
+
+    // Firmware tables, before the drivers that look things up in them
+    acpi::init(unsafe { boot_info.rsdp_addr() });
+    smbios::init(unsafe { boot_info.smbios_addr() });
+    efi::init(unsafe { boot_info.efi_runtime_services() }, unsafe { boot_info.memory_map() });
 
     // Initialize interrupt handling
-    println!("\n[interrupts] Initializing IDT...");
+    info!("interrupts", "Initializing IDT...");
     interrupts::init();
-    println!("[interrupts] IDT initialized");
+    arch::apic::init();
+    console::listen_serial();
+    info!("interrupts", "IDT initialized");
 
     // Print memory statistics
     mm::print_stats();
 
     // Initialize VFS
-    println!("\n[fs] Initializing VFS...");
+    info!("fs", "Initializing VFS...");
     fs::init();
     
-    // Create and mount initrd (temporarily disabled)
-    // let initrd = fs::initrd::create_basic_initrd();
-    // fs::initrd::print_initrd(&initrd);
-    // let _ = fs::mount("/initrd", initrd);
-    // println!("[fs] Initrd mounted at /initrd");
+    // The root is a RAM filesystem: the disk filesystems are read-only, and
+    // /etc has to be writable for the configuration files
+    let _ = fs::mount("/", fs::initrd::create_basic_initrd());
+    // What the boot entry brought, over the built-in files
+    if let Some(archive) = unsafe { boot_info.initrd() } {
+        match fs::initrd::unpack(archive) {
+            Ok(count) => info!("fs", "Unpacked {} files from the initrd", count),
+            Err(e) => warn!("fs", "Cannot unpack the initrd: {:?}", e),
+        }
+    }
+    let _ = fs::mount("/proc", fs::procfs::create());
 
     // Initialize process management
-    println!("\n[process] Initializing...");
+    info!("process", "Initializing...");
     process::init();
 
     // Initialize system calls
-    println!("\n[syscall] Initializing...");
+    info!("syscall", "Initializing...");
     syscall::init();
 
     // Initialize device drivers
-    println!("\n[drivers] Initializing...");
+    info!("drivers", "Initializing...");
     drivers::init();
+    watchdog::init();
+    // The firmware's AML, now that the methods in it can sleep
+    acpi::load_namespace();
+    power::init();
 
     // Initialize storage subsystem
-    println!("\n[storage] Initializing...");
+    info!("storage", "Initializing...");
     storage::init();
+    cmdline::mount_root();
+    crashdump::save_previous();
+    update::init();
 
-    // Initialize network stack
-    println!("\n[net] Initializing network stack...");
-    net::init();
+    // Initialize sound
+    sound::init();
 
     // Initialize browser engine
-    println!("\n[browser] Initializing browser engine...");
+    info!("browser", "Initializing browser engine...");
     browser::init();
-    println!("[browser] Browser engine initialized");
+    info!("browser", "Browser engine initialized");
 
     // Initialize cryptographic subsystem
-    println!("\n[crypto] Initializing cryptographic subsystem...");
+    info!("crypto", "Initializing cryptographic subsystem...");
     crypto::init();
-    println!("[crypto] Cryptographic subsystem initialized");
+    info!("crypto", "Cryptographic subsystem initialized");
 
     // Initialize TLS 1.3
-    println!("\n[tls] Initializing TLS 1.3...");
+    info!("tls", "Initializing TLS 1.3...");
     tls::init();
-    println!("[tls] TLS 1.3 initialized");
+    info!("tls", "TLS 1.3 initialized");
 
     // Initialize HTTP client
-    println!("\n[http] Initializing HTTP client...");
+    info!("http", "Initializing HTTP client...");
     net::http::init();
-    println!("[http] HTTP client initialized");
+    info!("http", "HTTP client initialized");
 
     // Initialize graphics subsystem
-    println!("\n[graphics] Initializing graphics subsystem...");
+    info!("graphics", "Initializing graphics subsystem...");
     graphics::init();
-    println!("[graphics] Graphics subsystem initialized");
+    info!("graphics", "Graphics subsystem initialized");
 
     // Initialize VESA framebuffer using boot info
-    println!("\n[vesa] Initializing VESA framebuffer...");
-    let fb_info = &boot_info.framebuffer;
-    if fb_info.is_valid() {
-        // Use the pre-mapped virtual address for the framebuffer
-        // Bootloader mapped 0x80000000 -> 0xFFFF800080000000
-        let fb_virt_addr = 0xFFFF_8000_8000_0000u64;
+    info!("vesa", "Initializing VESA framebuffer...");
+    let fb_info = unsafe { boot_info.framebuffer() };
+    if let Some(scanout) = drivers::virtio_gpu::init() {
+        // virtio-gpu replaces the GOP framebuffer with uploaded guest memory
+        drivers::vesa::init_with_virt_addr(scanout.width, scanout.height, 32, scanout.phys_addr, scanout.virt_addr);
+        info!("vesa", "virtio-gpu: {}x{} (virt: {:016X})", scanout.width, scanout.height, scanout.virt_addr);
+    } else if let Some(fb_info) = fb_info {
+        // The framebuffer is in the physmap like everything else
+        let fb_virt_addr = mm::phys_to_virt(fb_info.addr).as_u64();
         drivers::vesa::init_with_virt_addr(fb_info.width, fb_info.height, fb_info.bpp as u8, fb_info.addr.as_u64(), fb_virt_addr);
-        println!("[vesa] VESA: {}x{} @ {:?} (virt: {:016X})", fb_info.width, fb_info.height, fb_info.addr, fb_virt_addr);
+        info!("vesa", "VESA: {}x{} @ {:?} (virt: {:016X})", fb_info.width, fb_info.height, fb_info.addr, fb_virt_addr);
+    }
+    if drivers::vesa::info().is_some() {
+        graphics::display::init();
+        graphics::compositor::init();
+        graphics::fbcon::init();
         
         // Boot triangle skipped - will draw shapes after login instead
     } else {
-        println!("[vesa] No valid framebuffer");
+        warn!("vesa", "No valid framebuffer");
     }
 
+    // Everything the bootloader passed has been read by now
+    mm::reclaim_boot_memory(boot_info);
+
     // Initialize user management
-    println!("\n[users] Initializing user management...");
+    info!("users", "Initializing user management...");
     users::init();
-    println!("[users] User management initialized");
+    info!("users", "User management initialized");
 
     // Initialize input subsystem
-    println!("\n[input] Initializing input subsystem...");
+    info!("input", "Initializing input subsystem...");
     drivers::input::init();
-    println!("[input] Input subsystem initialized");
+    let bounds = graphics::display::virtual_bounds();
+    if !bounds.is_empty() {
+        drivers::input::set_mouse_bounds(bounds.w, bounds.h);
+    }
+    graphics::cursor::init();
+    info!("input", "Input subsystem initialized");
+
+    // Apply saved settings now that the subsystems they change are up
+    info!("config", "Loading settings...");
+    config::init();
+
+    // The network, the desktop and the daemons, as /etc/services.d says; one
+    // that fails is restarted later rather than stopping the boot
+    service::init();
+
+    // Console commands, for the console and terminal shells
+    for command in COMMANDS {
+        shell::command::register(command);
+    }
+    shell::init();
+
+    // Booted to run the tests: run them and leave QEMU
+    if cmdline::flag("test") {
+        testing::run_and_exit();
+    }
 
-    // Initialize desktop environment
-    println!("\n[desktop] Initializing desktop environment...");
-    desktop::init();
-    println!("[desktop] Desktop environment initialized");
+    // Whatever the administrator wants done at boot
+    shell::script::run_startup();
 
+    crashdump::print_previous();
+    update::confirm();
     println!("\n✓ WebbOS kernel initialized successfully!");
     println!("\nSystem is ready. Type 'help' for available commands.");
 
@@ -214,7 +308,7 @@ fn draw_vesa_triangle() {
     drivers::vesa::fill_rect(cx - size, cy - size, 200, 200, colors::GREEN);
     drivers::vesa::draw_rect(cx - size, cy - size, 200, 200, colors::WHITE);
     
-    println!("[vesa] Triangle (rectangle) drawn at ({}, {})", cx, cy);
+    info!("vesa", "Triangle (rectangle) drawn at ({}, {})", cx, cy);
 }
 
 /// Draw a simple triangle using VGA text buffer with colored blocks (fallback)
@@ -273,7 +367,7 @@ fn draw_boot_triangle() {
         core::ptr::write_volatile(vga_buffer.add(row * 80 + right_col), white_block);
     }
     
-    println!("[boot] Triangle drawn to VGA buffer");
+    info!("boot", "Triangle drawn to VGA buffer");
 }
 
 /// Main kernel loop
@@ -282,65 +376,61 @@ fn kernel_main() -> ! {
     let vesa_available = drivers::vesa::info().is_some();
     
     if vesa_available {
-        println!("[main] Showing VESA login screen...");
+        info!("main", "Showing VESA login screen...");
+        graphics::fbcon::suspend();
         
         // Show login screen on VESA
         if let Some((session_id, username)) = desktop::vesa_login::show_login_screen() {
-            println!("[main] User '{}' logged in with session {}", username, session_id);
-            
-            // Clear screen and show welcome
-            desktop::vesa_login::show_welcome_message();
-            
-            // Draw post-login shape (circle)
-            desktop::vesa_login::draw_post_login_shape();
+            info!("main", "User '{}' logged in with session {}", username, session_id);
             
-            // Wait a bit so user can see the result
-            println!("[main] Login complete - press any key to continue to console");
-            
-            // Wait for any key
-            loop {
-                if drivers::input::get_key().is_some() {
-                    break;
+            if graphics::compositor::is_active() {
+                desktop_session();
+            } else {
+                desktop::vesa_login::show_welcome_message();
+                info!("main", "Login complete - press any key to continue to console");
+                loop {
+                    if drivers::input::get_key().is_some() {
+                        break;
+                    }
+                    cpu::halt();
                 }
-                cpu::halt();
             }
-            
-            // Clear to black for console
-            drivers::vesa::clear(drivers::vesa::colors::BLACK);
-            drivers::vesa::draw_text("WebbOS Console", 10, 10, drivers::vesa::colors::WHITE, 2);
         }
+        
+        // Give the screen back to the console
+        graphics::fbcon::resume();
     }
     
     // Fall back to serial console
-    let mut buffer = [0u8; 256];
-    let mut pos = 0;
+    let names = shell::command::all().iter().map(|c| c.name).collect();
+    let mut editor = console::LineEditor::new("$ ", names);
 
     loop {
-        print!("$ ");
+        editor.start();
         
         // Simple command loop
         loop {
-            if let Some(c) = console::getchar() {
-                match c {
-                    b'\n' | b'\r' => {
-                        println!();
-                        buffer[pos] = 0;
-                        process_command(&buffer[..pos]);
-                        pos = 0;
-                        break;
-                    }
-                    8 | 127 => { // Backspace
-                        if pos > 0 {
-                            pos -= 1;
-                            print!("\x08 \x08");
-                        }
-                    }
-                    c if pos < buffer.len() - 1 => {
-                        buffer[pos] = c;
-                        pos += 1;
-                        print!("{}", c as char);
-                    }
-                    _ => {}
+            if let Some(line) = editor.poll() {
+                shell::execute(&line);
+                break;
+            }
+            
+            // Terminal app shells keep running behind the console
+            shell::poll();
+            net::poll();
+            drivers::timer::poll();
+            sound::poll();
+            watchdog::poll();
+            power::poll();
+            service::poll();
+            desktop::terminal::pump();
+
+            // Push anything drawn since the last present and follow host
+            // window resizes on virtio-gpu
+            drivers::virtio_gpu::present();
+            if let Some((w, h)) = drivers::virtio_gpu::poll_display_change() {
+                if let Err(e) = desktop::set_display_mode(w, h, 32) {
+                    warn!("virtio-gpu", "Resize to {}x{} failed: {:?}", w, h, e);
                 }
             }
             
@@ -350,174 +440,659 @@ fn kernel_main() -> ! {
     }
 }
 
-/// Process a user command
-fn process_command(cmd: &[u8]) {
-    let cmd_str = core::str::from_utf8(cmd).unwrap_or("").trim();
-    
-    match cmd_str {
-        "" => {}
-        "help" => {
-            println!("Available commands:");
-            println!("  help       - Show this help message");
-            println!("  info       - Show system information");
-            println!("  memory     - Show memory statistics");
-            println!("  processes  - Show process list");
-            println!("  scheduler  - Show scheduler statistics");
-            println!("  vfs        - Show VFS statistics");
-            println!("  pci        - Show PCI devices");
-            println!("  time       - Show time/timers");
-            println!("  network    - Show network status");
-            println!("  dhcp       - Start DHCP discovery");
-            println!("  ping       - Ping a host");
-            println!("  netstat    - Show network connections");
-            println!("  storage    - Show storage devices");
-            println!("  tls        - Test TLS connection");
-            println!("  http       - HTTP client usage");
-            println!("  fetch      - Fetch a URL (e.g., fetch http://example.com)");
-            println!("  graphics   - Show graphics info");
-            println!("  vesa       - Show VESA framebuffer info");
-            println!("  input      - Show input status");
-            println!("  test       - Run test suite");
-            println!("  users      - List user accounts");
-            println!("  sessions   - List active sessions");
-            println!("  login      - Login to desktop");
-            println!("  desktop    - Show desktop info");
-            println!("  launch     - Launch application (e.g., launch notepad)");
-            println!("  browser    - Show browser engine status");
-            println!("  navigate   - Navigate to URL (e.g., navigate file:///test.html)");
-            println!("  reboot     - Reboot the system");
-            println!("  shutdown   - Shutdown the system");
-        }
-        "info" => {
-            println!("System Information:");
-            println!("  OS: WebbOS v0.1.0");
-            println!("  Architecture: x86_64");
-            cpu::print_info();
-        }
-        "memory" => {
-            mm::print_stats();
-        }
-        "processes" | "ps" => {
-            process::print_process_list();
-        }
-        "scheduler" => {
-            process::scheduler::print_stats();
-        }
-        "vfs" => {
-            fs::print_stats();
-        }
-        "pci" => {
-            drivers::pci::print_devices();
-        }
-        "time" => {
-            drivers::timer::print_stats();
-        }
-        "network" | "net" => {
+/// Console commands, registered with the shell at boot; those without a
+/// description are aliases `help` leaves out
+const COMMANDS: [Command; 70] = [
+    Command { name: "help", description: "Show this help message", run: help_command },
+    Command { name: "info", description: "Show system information", run: |_, _| {
+        println!("System Information:");
+        println!("  OS: WebbOS v0.1.0");
+        println!("  Architecture: x86_64");
+        cpu::print_info();
+    } },
+    Command { name: "memory", description: "Show memory statistics", run: |_, _| mm::print_stats() },
+    Command { name: "processes", description: "Show process list", run: |_, _| process::print_process_list() },
+    Command { name: "ps", description: "", run: |_, _| process::print_process_list() },
+    Command { name: "scheduler", description: "Show scheduler statistics", run: |_, _| process::scheduler::print_stats() },
+    Command { name: "vfs", description: "Show VFS statistics", run: |_, _| fs::print_stats() },
+    Command { name: "pci", description: "Show PCI devices (-v: BARs and capabilities)", run: |args, _| drivers::pci::print_devices(args.contains(&"-v")) },
+    Command { name: "acpi", description: "Show ACPI tables, processors and interrupt controllers", run: |_, _| acpi::print_info() },
+    Command { name: "power", description: "Show the batteries and the AC adapter", run: |_, _| power::print_status() },
+    Command { name: "hwinfo", description: "Show the firmware, system, processor sockets and memory modules", run: |_, _| smbios::print_info() },
+    Command { name: "efivar", description: "List EFI variables, or show one (efivar [Name[-GUID]])", run: |args, _| efi::print_variable(args.first().copied()) },
+    Command { name: "update", description: "Show the update slots, install an update or roll one back (update [status | install URL | rollback])", run: |args, _| update::command(args) },
+    Command { name: "insmod", description: "Load a kernel module (insmod PATH)", run: |args, _| module::insmod_command(args) },
+    Command { name: "rmmod", description: "Unload a kernel module (rmmod NAME)", run: |args, _| module::rmmod_command(args) },
+    Command { name: "lsmod", description: "List the loaded kernel modules", run: |_, _| module::print_modules() },
+    Command { name: "time", description: "Show time/timers", run: |_, _| drivers::timer::print_stats() },
+    Command { name: "service", description: "Show, start or stop services (service [status [NAME] | start|stop|restart NAME])", run: |args, _| service::command(args) },
+    Command { name: "watchdog", description: "Show what the watchdog watches and when each was last seen", run: |_, _| watchdog::print_info() },
+    Command { name: "date", description: "Show the local date and time", run: |_, _| {
+        let now = drivers::rtc::local();
+        println!("{} {} {}", now.weekday(), now, drivers::rtc::timezone_name(drivers::rtc::offset()));
+    } },
+    Command { name: "network", description: "Show network status", run: network_command },
+    Command { name: "net", description: "", run: network_command },
+    Command { name: "dhcp", description: "Start DHCP discovery", run: |_, _| net::dhcp::start_dhcp() },
+    Command { name: "ping", description: "Ping a host (e.g., ping 8.8.8.8)", run: ping_command },
+    Command { name: "netstat", description: "Show network connections", run: |_, _| net::socket::print_sockets() },
+    Command { name: "storage", description: "Show storage devices", run: |_, _| storage::print_devices() },
+    Command { name: "sound", description: "Show sound card, set volume or stop playing (e.g., sound 60)", run: sound_command },
+    Command { name: "beep", description: "Beep (e.g., beep 440 200 for 440Hz, 200ms)", run: beep_command },
+    Command { name: "play", description: "Play a WAV file (e.g., play /home/chime.wav)", run: play_command },
+    Command { name: "tls", description: "Test TLS connection (e.g., tls example.com)", run: tls_command },
+    Command { name: "certs", description: "List, add or remove trust anchors and pins (e.g., certs pin lab.local /home/lab.pem)", run: |args, _| tls::certs::command(args) },
+    Command { name: "crypto", description: "Benchmark crypto primitives (e.g., crypto bench)", run: |_, _| crypto::accel::bench() },
+    Command { name: "http", description: "Fetch a URL and show the response (e.g., http http://example.com)", run: http_command },
+    Command { name: "tcpdump", description: "Capture packets (tcpdump start <iface|any> <file>|live [iface]|stop)", run: tcpdump_command },
+    Command { name: "nat", description: "Route between two interfaces and forward ports (nat [on INSIDE OUTSIDE | off | forward ...])", run: |args, _| net::nat::command(args) },
+    Command { name: "proxy", description: "Show or set the SOCKS5 proxy (proxy [socks5://host:port | off])", run: |args, _| net::socks::command(args) },
+    Command { name: "fetch", description: "Fetch a URL (e.g., fetch http://example.com)", run: fetch_command },
+    Command { name: "graphics", description: "Show graphics info", run: |_, _| graphics::print_info() },
+    Command { name: "font", description: "Show active font", run: |_, _| graphics::font::print_info() },
+    Command { name: "vesa", description: "Show VESA framebuffer info", run: |_, _| drivers::vesa::print_info() },
+    Command { name: "mode", description: "Show or set display mode (e.g., mode 1920x1080)", run: mode_command },
+    Command { name: "loadkeys", description: "Show or set keyboard layout (e.g., loadkeys de)", run: loadkeys_command },
+    Command { name: "displays", description: "List displays and their layout", run: |_, _| graphics::display::print_info() },
+    Command { name: "input", description: "Show input status", run: |_, _| drivers::input::print_info() },
+    Command { name: "pty", description: "List pseudo-terminals", run: |_, _| drivers::pty::print_info() },
+    Command { name: "dmesg", description: "Show the kernel log (e.g., dmesg -l warn); -c clears it", run: dmesg_command },
+    Command { name: "cmdline", description: "Show the kernel command line", run: |_, _| cmdline::print_info() },
+    Command { name: "test", description: "Run the kernel tests, or those matching a name (e.g., test rtc)", run: |args, _| {
+        testing::run_tests(args.first().copied());
+    } },
+    Command { name: "fuzz", description: "Fuzz the parsers (e.g., fuzz dns 10000; fuzz all 1000 0x5eed)", run: fuzz_command },
+    Command { name: "crash", description: "Show the crash dump the previous boot left", run: |_, _| crashdump::print_dump() },
+    Command { name: "trace", description: "Trace events (trace start|stop|dump [n]|export <file>)", run: trace_command },
+    Command { name: "users", description: "List user accounts", run: |_, _| users::print_users() },
+    Command { name: "groups", description: "List or manage groups (e.g., groups join user wheel)", run: groups_command },
+    Command { name: "sessions", description: "List active sessions", run: |_, _| users::print_sessions() },
+    Command { name: "audit", description: "Show or check the audit log (audit [show [KIND] [COUNT] | verify])", run: |args, _| users::audit::command(args) },
+    Command { name: "login", description: "Log in to the desktop (e.g., login admin admin)", run: login_command },
+    Command { name: "desktop", description: "Show desktop info", run: |_, _| desktop::print_info() },
+    Command { name: "gui", description: "Show the desktop on screen (Esc returns)", run: |_, _| desktop_session() },
+    Command { name: "launch", description: "Launch application (e.g., launch notepad)", run: launch_command },
+    Command { name: "apps", description: "List, install or remove app packages (e.g., apps install /home/clock.wapp)", run: apps_command },
+    Command { name: "movewin", description: "Move a window (e.g., movewin 1 1200 100)", run: movewin_command },
+    Command { name: "notify", description: "Post a notification (e.g., notify Backup: finished)", run: notify_command },
+    Command { name: "notifications", description: "List notifications", run: |_, _| desktop::notifications::print_info() },
+    Command { name: "config", description: "List or change settings (e.g., config desktop.theme dark)", run: config_command },
+    Command { name: "hotkeys", description: "List or change shortcuts (e.g., hotkeys snap_left Ctrl+Alt+Left)", run: hotkeys_command },
+    Command { name: "ipc", description: "Post an app message as a window (e.g., ipc 1 '{\"type\":\"list_users\"}')", run: ipc_command },
+    Command { name: "browser", description: "Show browser engine status", run: |_, _| browser::print_stats() },
+    Command { name: "navigate", description: "Navigate to URL (e.g., navigate file:///test.html)", run: navigate_command },
+    Command { name: "reboot", description: "Reboot the system", run: |_, _| {
+        println!("Rebooting...");
+        cpu::reboot();
+    } },
+    Command { name: "shutdown", description: "Shutdown the system", run: |_, _| {
+        println!("Shutting down...");
+        power::shutdown();
+    } },
+];
+
+fn help_command(_args: &[&str], _input: &str) {
+    println!("Available commands:");
+    for command in shell::command::all().iter().filter(|c| !c.description.is_empty()) {
+        println!("  {:<10} - {}", command.name, command.description);
+    }
+}
+
+fn network_command(args: &[&str], _input: &str) {
+    match args {
+        [] => {
             net::print_interfaces();
             println!();
             net::print_stats();
         }
-        "dhcp" => {
-            net::dhcp::start_dhcp();
-        }
-        "ping" => {
+        ["promisc", name, setting @ ("on" | "off")] => match net::interface_index(name) {
+            Some(iface) => match net::set_promiscuous(iface, *setting == "on") {
+                Ok(()) => println!("{}: promiscuous mode {}", name, setting),
+                Err(e) => println!("network: {}: {:?}", name, e),
+            },
+            None => println!("network: no interface '{}'", name),
+        },
+        _ => println!("Usage: network [promisc <iface> on|off]"),
+    }
+}
+
+fn ping_command(args: &[&str], _input: &str) {
+    let target = match args {
+        [target] => target,
+        _ => {
             println!("Usage: ping <ip_address>");
-            println!("Example: ping 8.8.8.8");
-        }
-        "netstat" => {
-            net::socket::print_sockets();
-        }
-        "storage" => {
-            storage::print_devices();
-        }
-        "tls" => {
-            let _ = tls::connect("example.com");
-        }
-        "http" => {
-            println!("Usage: http <url>");
-            println!("Example: http http://example.com");
-        }
-        "fetch" => {
-            if net::dns::resolve("example.com").is_none() {
-                println!("Configuring network with static IP...");
-                let config = net::NetworkConfig {
-                    ip: net::Ipv4Address::from_octets(10, 0, 2, 15),
-                    netmask: net::Ipv4Address::from_octets(255, 255, 255, 0),
-                    gateway: net::Ipv4Address::from_octets(10, 0, 2, 2),
-                    dns: net::Ipv4Address::from_octets(8, 8, 8, 8),
-                };
-                net::set_config(config);
+            return;
+        }
+    };
+    let address = match net::Ipv4Address::parse(target).or_else(|| net::dns::resolve(target)) {
+        Some(address) => address,
+        None => {
+            println!("ping: cannot resolve {}", target);
+            return;
+        }
+    };
+    match net::ip::ping(address) {
+        Ok(()) => println!("Sent echo request to {}", address),
+        Err(()) => println!("ping: could not send to {}", address),
+    }
+}
+
+fn sound_command(args: &[&str], _input: &str) {
+    match args {
+        [] => sound::print_info(),
+        ["stop"] => sound::stop(),
+        [volume] => match volume.parse::<u32>() {
+            Ok(level) if level <= 100 => match config::set("sound.volume", volume) {
+                Ok(()) => println!("Volume: {}%", volume),
+                Err(e) => println!("sound: {}", e),
+            },
+            _ => println!("Usage: sound [0-100 | stop]"),
+        },
+        _ => println!("Usage: sound [0-100 | stop]"),
+    }
+}
+
+fn fuzz_command(args: &[&str], _input: &str) {
+    let targets: alloc::vec::Vec<&testing::fuzz::Target> = match args.first().copied() {
+        None | Some("all") => testing::fuzz::TARGETS.iter().collect(),
+        Some(name) => match testing::fuzz::target(name) {
+            Some(target) => alloc::vec![target],
+            None => {
+                let names: alloc::vec::Vec<&str> = testing::fuzz::TARGETS.iter().map(|t| t.name).collect();
+                println!("fuzz: no target '{}'; there are {}", name, names.join(", "));
+                return;
             }
-            match net::http::get("http://example.com") {
-                Ok(response) => net::http::print_response(&response),
-                Err(e) => println!("HTTP request failed: {:?}", e),
+        },
+    };
+    let rounds = args.get(1).and_then(|r| r.parse().ok()).unwrap_or(1000);
+    let seed = match args.get(2) {
+        Some(seed) => match u64::from_str_radix(seed.trim_start_matches("0x"), 16) {
+            Ok(seed) => seed,
+            Err(_) => {
+                println!("fuzz: the seed is a hex number, not '{}'", seed);
+                return;
             }
+        },
+        None => cpu::rdtsc(),
+    };
+    println!("Fuzzing from seed {:#x}", seed);
+    for target in targets {
+        let outcome = testing::fuzz::run(target, rounds, seed);
+        println!("  {:6} {} rounds, {} inputs accepted", target.name, outcome.rounds, outcome.accepted);
+    }
+}
+
+fn trace_command(args: &[&str], _input: &str) {
+    match args {
+        [] => trace::print_info(),
+        ["start"] => {
+            trace::start();
+            println!("Tracing");
         }
-        "graphics" => {
-            graphics::print_info();
+        ["stop"] => {
+            trace::stop();
+            println!("Tracing stopped");
         }
-        "vesa" => {
-            drivers::vesa::print_info();
+        ["dump"] => trace::dump(40),
+        ["dump", count] => match count.parse() {
+            Ok(count) => trace::dump(count),
+            Err(_) => println!("trace: '{}' is not a count", count),
+        },
+        ["export", path] => {
+            let path = shell::env::path(path);
+            match fs::write_file(&path, &trace::chrome_json()) {
+                Ok(()) => println!("Wrote {}; open it in chrome://tracing or Perfetto", path),
+                Err(e) => println!("trace: {}: {:?}", path, e),
+            }
         }
-        "input" => {
-            drivers::input::print_info();
+        _ => println!("Usage: trace [start|stop|dump [n]|export <file>]"),
+    }
+}
+
+fn tcpdump_command(args: &[&str], _input: &str) {
+    // None for no such interface, Some(None) for all of them
+    let iface = |name: &str| match name {
+        "any" => Some(None),
+        name => net::interface_index(name).map(Some),
+    };
+    match args {
+        [] => net::capture::print_info(),
+        ["start", name, path] => match iface(name) {
+            Some(iface) => {
+                let path = shell::env::path(path);
+                println!("Capturing on {}; tcpdump stop writes {}", name, path);
+                net::capture::start(iface, Some(path));
+            }
+            None => println!("tcpdump: no interface '{}'", name),
+        },
+        ["live"] | ["live", _] => {
+            let name = args.get(1).copied().unwrap_or("any");
+            match iface(name) {
+                Some(iface) => net::capture::start(iface, None),
+                None => println!("tcpdump: no interface '{}'", name),
+            }
         }
-        "test" => {
-            testing::run_tests();
+        ["stop"] => match net::capture::stop() {
+            Ok(Some((path, count))) => println!("Wrote {} frames to {}; open it in Wireshark", count, path),
+            Ok(None) => println!("Capture stopped"),
+            Err(e) => println!("tcpdump: {}", e),
+        },
+        _ => println!("Usage: tcpdump [start <iface|any> <file> | live [iface] | stop]"),
+    }
+}
+
+fn beep_command(args: &[&str], _input: &str) {
+    let frequency = args.first().and_then(|f| f.parse().ok()).unwrap_or(880);
+    let ms = args.get(1).and_then(|ms| ms.parse().ok()).unwrap_or(150);
+    if !sound::beep(frequency, ms) {
+        println!("beep: no sound card");
+    }
+}
+
+fn play_command(args: &[&str], _input: &str) {
+    let path = match args {
+        [path] => shell::env::path(path),
+        _ => {
+            println!("Usage: play <file.wav>");
+            return;
         }
-        "users" => {
-            users::print_users();
+    };
+    let data = match fs::read_file(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("play: {}: {:?}", path, e);
+            return;
         }
-        "sessions" => {
-            users::print_sessions();
+    };
+    match sound::play_wav(&data) {
+        Ok(_) => println!("Playing {}", path),
+        Err(e) => println!("play: {}: {:?}", path, e),
+    }
+}
+
+fn tls_command(args: &[&str], _input: &str) {
+    let _ = tls::connect(args.first().copied().unwrap_or("example.com"));
+}
+
+fn http_command(args: &[&str], _input: &str) {
+    match args {
+        [url] => match net::http::get(url) {
+            Ok(response) => net::http::print_response(&response),
+            Err(e) => println!("http: {:?}", e),
+        },
+        _ => println!("Usage: http <url>"),
+    }
+}
+
+/// Fetch a page, setting up QEMU's user networking first if DNS does not
+/// work yet
+fn fetch_command(args: &[&str], _input: &str) {
+    if net::dns::resolve("example.com").is_none() {
+        println!("Configuring network with static IP...");
+        let config = net::NetworkConfig {
+            ip: net::Ipv4Address::from_octets(10, 0, 2, 15),
+            netmask: net::Ipv4Address::from_octets(255, 255, 255, 0),
+            gateway: net::Ipv4Address::from_octets(10, 0, 2, 2),
+            dns: net::Ipv4Address::from_octets(8, 8, 8, 8),
+        };
+        net::set_config(config);
+    }
+    match net::http::get(args.first().copied().unwrap_or("http://example.com")) {
+        Ok(response) => net::http::print_response(&response),
+        Err(e) => println!("HTTP request failed: {:?}", e),
+    }
+}
+
+fn login_command(args: &[&str], _input: &str) {
+    let (username, password, code) = match args {
+        [username, password] => (username, password, None),
+        [username, password, code] => (username, password, Some(*code)),
+        _ => {
+            println!("Usage: login <username> <password> [code]");
+            return;
         }
-        "login" => {
-            println!("Usage: login <username> <password>");
-            println!("Example: login admin admin");
+    };
+    match desktop::login(username, password, code) {
+        Ok(()) => println!("Logged in as {}", username),
+        Err(e) => println!("login: {:?}", e),
+    }
+}
+
+fn launch_command(args: &[&str], _input: &str) {
+    let app_name = match args {
+        [app_name] => app_name,
+        _ => {
+            println!("Usage: launch <app_name>");
+            println!("Available apps:");
+            for app in desktop::list_apps() {
+                println!("  {} - {} {}", app.name, app.icon, app.title);
+            }
+            return;
         }
-        "desktop" => {
-            desktop::print_info();
+    };
+    if let Some(window_id) = desktop::launch_app(app_name, shell::env::session()) {
+        println!("Launched {} (window {})", app_name, window_id);
+    } else {
+        println!("Failed to launch {}", app_name);
+        println!("Available apps: filemanager, notepad, paint, taskmanager, usermanager, terminal, browser, settings,");
+        println!("  calculator, clock, sysmonitor");
+    }
+}
+
+fn apps_command(args: &[&str], _input: &str) {
+    match args {
+        [] => desktop::packages::print_info(),
+        // URLs are left alone; relative paths are from the working directory
+        ["install", source] if source.contains("://") => install_package(source),
+        ["install", source] => install_package(&shell::env::path(source)),
+        ["remove", name] => match desktop::packages::uninstall(name) {
+            Ok(()) => println!("Removed {}", name),
+            Err(e) => println!("apps: {}", e),
+        },
+        _ => println!("Usage: apps [install <path or url> | remove <name>]"),
+    }
+}
+
+fn dmesg_command(args: &[&str], _input: &str) {
+    let (level, clear) = match args {
+        [] => (log::Level::Trace, false),
+        ["-c"] => (log::Level::Trace, true),
+        ["-l", level] => match log::Level::from_name(level) {
+            Some(level) => (level, false),
+            None => {
+                println!("dmesg: levels are error, warn, info, debug and trace");
+                return;
+            }
+        },
+        _ => {
+            println!("Usage: dmesg [-c | -l <level>]");
+            return;
         }
-        "launch" => {
-            // Parse command to get app name
-            let args = &cmd_str[cmd_str.len().min(6)..];
-            let app_name = args.trim();
-            if !app_name.is_empty() {
-                if let Some(window_id) = desktop::launch_app(app_name) {
-                    println!("Launched {} (window {})", app_name, window_id);
-                } else {
-                    println!("Failed to launch {}", app_name);
-                    println!("Available apps: filemanager, notepad, paint, taskmanager, usermanager, terminal, browser");
-                }
+    };
+    let contents = log::contents();
+    for record in log::records(&alloc::string::String::from_utf8_lossy(&contents)) {
+        if record.level <= level {
+            println!("{}", record.text);
+        }
+    }
+    if clear {
+        log::clear();
+    }
+}
+
+fn install_package(source: &str) {
+    match desktop::packages::install(source) {
+        Ok(name) => println!("Installed {}", name),
+        Err(e) => println!("apps: {}", e),
+    }
+}
+
+fn movewin_command(args: &[&str], _input: &str) {
+    let numbers: Option<alloc::vec::Vec<i32>> = args.iter().map(|a| a.parse().ok()).collect();
+    match numbers.as_deref() {
+        Some(&[id, x, y]) if id >= 0 => {
+            if desktop::move_window(id as u32, x, y) {
+                println!("Moved window {}", id);
             } else {
-                println!("Usage: launch <app_name>");
-                println!("Available apps:");
-                for app in desktop::list_apps() {
-                    println!("  {} - {} {}", app.name, app.icon, app.title);
+                println!("No window {}", id);
+            }
+        }
+        _ => println!("Usage: movewin <window_id> <x> <y>"),
+    }
+}
+
+fn notify_command(args: &[&str], _input: &str) {
+    let text = args.join(" ");
+    if text.is_empty() {
+        println!("Usage: notify <title>[: <body>]");
+    } else {
+        let (title, body) = text.split_once(':').unwrap_or((&text, ""));
+        desktop::notifications::notify(title.trim(), body.trim(), 'i', 5);
+    }
+}
+
+fn config_command(args: &[&str], _input: &str) {
+    match args {
+        [] => config::print_info(),
+        [key, value @ ..] => {
+            if let Err(e) = config::set(key, &value.join(" ")) {
+                println!("config: {}", e);
+            }
+        }
+    }
+}
+
+fn hotkeys_command(args: &[&str], _input: &str) {
+    match args {
+        [] => desktop::hotkeys::print_info(),
+        ["reload"] => desktop::hotkeys::load(),
+        [action, chord] => {
+            match (desktop::hotkeys::Action::from_name(action), desktop::hotkeys::Chord::parse(chord)) {
+                (Some(action), Some(chord)) => {
+                    if let Err(e) = desktop::hotkeys::bind(action, chord) {
+                        println!("Bound for this session; could not save {}: {:?}",
+                            desktop::hotkeys::CONFIG_PATH, e);
+                    }
                 }
+                _ => println!("Usage: hotkeys [reload | <action> <chord>]"),
             }
         }
-        "browser" => {
-            browser::print_stats();
+        _ => println!("Usage: hotkeys [reload | <action> <chord>]"),
+    }
+}
+
+fn ipc_command(args: &[&str], _input: &str) {
+    let id = args.first().and_then(|id| id.parse::<u32>().ok());
+    let message = args.get(1..).map(|rest| rest.join(" ")).unwrap_or_default();
+    match id {
+        Some(id) if !message.trim().is_empty() => {
+            desktop::ipc::post(id, message.trim());
+            for response in desktop::ipc::take_responses(id) {
+                println!("{}", response.to_json());
+            }
         }
-        "navigate" => {
+        _ => println!("Usage: ipc <window_id> <json message>"),
+    }
+}
+
+fn navigate_command(args: &[&str], _input: &str) {
+    let url = match args {
+        [url] => url,
+        _ => {
             println!("Usage: navigate <url>");
             println!("Examples:");
             println!("  navigate file:///test.html");
             println!("  navigate http://example.com");
+            return;
         }
-        "reboot" => {
-            println!("Rebooting...");
-            cpu::reboot();
+    };
+    match desktop::open_url(url) {
+        Ok(title) => {
+            println!("Loaded {} ({})", url, title);
+            println!("Shown in a Browser window; type 'desktop' to view it");
         }
-        "shutdown" => {
-            println!("Shutting down...");
-            cpu::shutdown();
+        Err(e) => println!("navigate: {}", e),
+    }
+}
+
+/// Show the display mode, or switch to `WIDTHxHEIGHT[xBPP]`
+/// Show the desktop on the framebuffer until Esc is pressed
+///
+/// Keys 1-9 launch applications, Tab cycles window focus, M/N/Delete
+/// maximize, minimize and close the focused window, and the arrow keys
+/// move it, so it can be taken across to another display.
+fn desktop_session() {
+    if !graphics::compositor::is_active() {
+        println!("The desktop needs the compositor, which is not active");
+        return;
+    }
+    println!("Showing desktop: 1-9 launch apps, Super opens the start menu, Tab or Alt+Tab switches windows,");
+    println!("Super+arrows snap, Super+D shows the desktop, Super+L locks (see 'hotkeys'),");
+    println!("M/N/Del maximize/minimize/close, arrows or the mouse move the focused window, Esc returns");
+    let apps = desktop::list_apps();
+    graphics::fbcon::suspend();
+    desktop::show();
+    drivers::input::dispatch::begin_session(desktop::handle_event);
+
+    'session: while desktop::showing_desktop() {
+        shell::poll();
+        net::poll();
+        drivers::timer::poll();
+        sound::poll();
+        watchdog::poll();
+        power::poll();
+        service::poll();
+        desktop::tick();
+        desktop::redraw();
+        drivers::virtio_gpu::present();
+        // Keys the desktop has no use for are the session's own
+        while let Some(event) = drivers::input::dispatch::dispatch() {
+            match event.keycode {
+                0x01 => break 'session,
+                // Scancodes 0x02-0x0A are the digits 1-9
+                code @ 0x02..=0x0A => {
+                    if let Some(app) = apps.get((code - 0x02) as usize) {
+                        desktop::launch_app(&app.name, None);
+                    }
+                }
+                0x0F => desktop::focus_next(),
+                0x32 => desktop::maximize_active_window(),
+                0x31 => desktop::minimize_active_window(),
+                0x53 => desktop::close_active_window(),
+                0x48 => desktop::nudge_active_window(0, -16),
+                0x50 => desktop::nudge_active_window(0, 16),
+                0x4B => desktop::nudge_active_window(-16, 0),
+                0x4D => desktop::nudge_active_window(16, 0),
+                _ => {}
+            }
+        }
+        cpu::halt();
+    }
+
+    drivers::input::dispatch::end_session();
+    watchdog::rest(watchdog::Subsystem::Compositor);
+    graphics::cursor::set_shape(graphics::cursor::CursorShape::Arrow);
+    graphics::fbcon::resume();
+}
+
+fn groups_command(args: &[&str], _input: &str) {
+    let result = match args {
+        [] => {
+            users::print_groups();
+            return;
+        }
+        ["add", name] => users::create_group(name).map(|_| ()),
+        ["del", name] => users::find_group(name)
+            .ok_or(users::UserError::GroupNotFound)
+            .and_then(|group| users::delete_group(group.id)),
+        [action @ ("join" | "leave" | "primary"), user, group] => {
+            match (users::find_user(user), users::find_group(group)) {
+                (None, _) => Err(users::UserError::UserNotFound),
+                (_, None) => Err(users::UserError::GroupNotFound),
+                (Some(user), Some(group)) => match *action {
+                    "join" => users::add_to_group(user.id, group.id),
+                    "leave" => users::remove_from_group(user.id, group.id),
+                    _ => users::set_primary_group(user.id, group.id),
+                },
+            }
+        }
+        _ => {
+            println!("Usage: groups [add <group> | del <group> | join|leave|primary <user> <group>]");
+            return;
+        }
+    };
+    match result {
+        Ok(()) => println!("Done"),
+        Err(e) => println!("groups: {:?}", e),
+    }
+}
+
+fn loadkeys_command(args: &[&str], _input: &str) {
+    use drivers::input::{self, Layout};
+
+    let name = match args {
+        [name] => name,
+        _ => {
+            let current = input::layout();
+            println!("Keyboard layouts:");
+            for layout in Layout::ALL {
+                let marker = if layout == current { "*" } else { " " };
+                println!(" {} {:<8} {}", marker, layout.name(), layout.description());
+            }
+            println!("Usage: loadkeys <layout>");
+            return;
+        }
+    };
+
+    match desktop::set_keyboard_layout(name) {
+        Ok(()) => println!("Keyboard layout set to {}", name),
+        Err(e) => println!("loadkeys: {}", e),
+    }
+}
+
+fn mode_command(args: &[&str], _input: &str) {
+    let info = match drivers::vesa::info() {
+        Some(info) => info,
+        None => {
+            println!("No framebuffer");
+            return;
         }
+    };
+
+    let mode = match args {
+        [mode] => mode,
         _ => {
-            println!("Unknown command: {}", cmd_str);
-            println!("Type 'help' for available commands.");
+            println!("Current mode: {}x{} @ {}bpp", info.width, info.height, info.bpp);
+            match drivers::vesa::mode_setter() {
+                Some(name) => {
+                    println!("Mode setting via {}", name);
+                    print!("Available:");
+                    for (w, h) in drivers::vesa::available_modes(info.bpp) {
+                        print!(" {}x{}", w, h);
+                    }
+                    println!();
+                }
+                None => println!("Mode setting unavailable: no Bochs VBE or VMware SVGA adapter"),
+            }
+            let boot_modes = drivers::vesa::boot_modes();
+            if !boot_modes.is_empty() {
+                print!("GOP offered:");
+                for mode in boot_modes {
+                    print!(" {}x{}", mode.width, mode.height);
+                }
+                println!();
+            }
+            if let Some(edid) = drivers::vesa::edid() {
+                print!("Display: {} {:04x}", edid.manufacturer, edid.product);
+                if let Some(name) = &edid.name {
+                    print!(" \"{}\"", name);
+                }
+                if let Some((w, h)) = edid.size_cm {
+                    print!(", {}x{} cm", w, h);
+                }
+                if let Some((w, h)) = edid.preferred {
+                    print!(", prefers {}x{}", w, h);
+                }
+                println!();
+            }
+            println!("Usage: mode <width>x<height>[x<bpp>]");
+            return;
         }
+    };
+
+    let mut parts = mode.split('x').map(|p| p.trim().parse::<u32>());
+    let (width, height, bpp) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(w)), Some(Ok(h)), None, None) => (w, h, info.bpp),
+        (Some(Ok(w)), Some(Ok(h)), Some(Ok(b)), None) if b <= 32 => (w, h, b as u8),
+        _ => {
+            println!("Usage: mode <width>x<height>[x<bpp>]");
+            return;
+        }
+    };
+
+    match desktop::set_display_mode(width, height, bpp) {
+        Ok(()) => println!("Display mode set to {}x{} @ {}bpp", width, height, bpp),
+        Err(e) => println!("Failed to set {}x{} @ {}bpp: {:?}", width, height, bpp, e),
     }
 }
 
@@ -0,0 +1,456 @@
+//! Per-process address spaces
+//!
+//! A process that maps memory of its own gets its own PML4. Its upper
+//! half, the kernel's, and its first entry, the identity map of low
+//! memory, point at the kernel's tables, so kernel mappings are the same
+//! in every address space and switching leaves the kernel where it was.
+//! The process's pages go between `USER_START` and `USER_END`, in tables
+//! it owns, user-accessible and no-execute unless they hold code; they
+//! and their tables are freed with it. Processes that map nothing run on
+//! the kernel's own tables.
+//!
+//! What is mapped is also kept as a sorted list of areas (VMAs), runs of
+//! pages with the same access, which syscalls check user pointers against.
+//!
+//! Shared memory objects are mapped from `MMAP_BASE` up unless the process
+//! picks the address. Their frames are marked `SHARED` in the tables and
+//! stay with the object when the mapping or the address space goes.
+
+use alloc::vec::Vec;
+use webbos_shared::types::PhysAddr;
+
+use super::shm::Shm;
+use super::{alloc_frame, free_frame, kernel_root, phys_to_virt};
+use crate::arch::paging::{MapToError, PageTable, PageTableEntry, PageTableFlags};
+
+/// Lowest user address: the second PML4 entry, past the shared identity
+/// map in the first
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+/// End of the lower half
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// Where shared mappings go when no address is asked for
+pub const MMAP_BASE: u64 = 0x0000_4000_0000_0000;
+
+/// PML4 entries the process owns
+const USER_ENTRIES: core::ops::Range<usize> = 1..256;
+
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The PML4 CR3 points at
+pub fn active_root() -> PhysAddr {
+    let cr3: u64;
+    unsafe { core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack)) };
+    PhysAddr::new(cr3 & ADDRESS_MASK)
+}
+
+/// Run on the tables at `root`, unless they are already the ones in use
+pub fn activate(root: PhysAddr) {
+    if active_root() != root {
+        unsafe { core::arch::asm!("mov cr3, {}", in(reg) root.as_u64(), options(nostack)) };
+    }
+}
+
+/// Drop the TLB entry for the page at `virt`
+pub fn flush(virt: u64) {
+    unsafe { core::arch::asm!("invlpg [{}]", in(reg) virt, options(nostack)) };
+}
+
+/// The table an entry points to
+///
+/// # Safety
+/// The entry must point to a page table.
+unsafe fn table(entry_addr: PhysAddr) -> &'static mut PageTable {
+    &mut *(phys_to_virt(entry_addr).as_u64() as *mut PageTable)
+}
+
+fn indexes(virt: u64) -> [usize; 4] {
+    [(virt >> 39) as usize & 0x1FF, (virt >> 30) as usize & 0x1FF, (virt >> 21) as usize & 0x1FF, (virt >> 12) as usize & 0x1FF]
+}
+
+/// The 4KB page table entry for `virt` under `root`, or None if a level is
+/// missing or maps a larger page
+///
+/// # Safety
+/// `root` must be a PML4, and the caller the only one changing it.
+pub unsafe fn leaf(root: PhysAddr, virt: u64) -> Option<&'static mut PageTableEntry> {
+    let [p4, p3, p2, p1] = indexes(virt);
+    let mut current = table(root);
+    for index in [p4, p3, p2] {
+        let entry = current.get_entry(index);
+        if !entry.is_present() || entry.is_huge_page() {
+            return None;
+        }
+        current = table(entry.addr());
+    }
+    Some(current.get_entry_mut(p1))
+}
+
+/// A run of user pages mapped with the same access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub writable: bool,
+    pub executable: bool,
+    /// Part of a shared memory mapping
+    pub shared: bool,
+}
+
+/// A shared memory object mapped in, holding a reference to it
+#[derive(Debug)]
+struct SharedMapping {
+    start: u64,
+    pages: usize,
+    object: Shm,
+}
+
+/// A process's page tables
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: PhysAddr,
+    /// False for the kernel's tables, which are not freed
+    owned: bool,
+    /// Mapped areas, sorted and not overlapping
+    vmas: Vec<Vma>,
+    shared: Vec<SharedMapping>,
+}
+
+impl AddressSpace {
+    /// The kernel's tables, for processes with no user pages
+    pub fn kernel() -> Self {
+        AddressSpace { root: kernel_root(), owned: false, vmas: Vec::new(), shared: Vec::new() }
+    }
+
+    /// Tables of its own, sharing the kernel's mappings
+    pub fn new() -> Option<Self> {
+        let root = alloc_frame()?;
+        let (kernel, own) = unsafe { (table(kernel_root()), table(root)) };
+        for index in (0..512).filter(|index| !USER_ENTRIES.contains(index)) {
+            *own.get_entry_mut(index) = *kernel.get_entry(index);
+        }
+        Some(AddressSpace { root, owned: true, vmas: Vec::new(), shared: Vec::new() })
+    }
+
+    /// What CR3 is loaded with to run in it
+    pub fn root(&self) -> PhysAddr {
+        self.root
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        !self.owned
+    }
+
+    /// Map a new zeroed page at `virt`, user-accessible; it is no-execute
+    /// unless `executable`
+    pub fn map(&mut self, virt: u64, writable: bool, executable: bool) -> Result<PhysAddr, MapToError> {
+        let entry = self.empty_entry(virt)?;
+        let frame = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER;
+        if writable {
+            flags = flags | PageTableFlags::WRITABLE;
+        }
+        if !executable {
+            flags = flags | PageTableFlags::NO_EXECUTE;
+        }
+        entry.set_addr(frame, flags);
+        if active_root() == self.root {
+            flush(virt);
+        }
+        self.add_vma(Vma { start: virt & !0xFFF, end: (virt & !0xFFF) + 0x1000, writable, executable, shared: false });
+        Ok(frame)
+    }
+
+    /// The unused leaf entry for user address `virt`, making the tables
+    /// down to it
+    fn empty_entry(&mut self, virt: u64) -> Result<&'static mut PageTableEntry, MapToError> {
+        if !self.owned || !(USER_START..USER_END).contains(&virt) {
+            return Err(MapToError::OutOfRange);
+        }
+        let [p4, p3, p2, p1] = indexes(virt);
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER;
+        let mut current = unsafe { table(self.root) };
+        for index in [p4, p3, p2] {
+            let entry = current.get_entry_mut(index);
+            if !entry.is_present() {
+                let frame = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                entry.set_addr(frame, table_flags);
+            }
+            current = unsafe { table(entry.addr()) };
+        }
+        let entry = current.get_entry_mut(p1);
+        if entry.is_present() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        Ok(entry)
+    }
+
+    /// Map the first `len` bytes of a shared memory object, no-execute, at
+    /// `virt` or, if None, the first gap from `MMAP_BASE`; returns where
+    pub fn map_shared(&mut self, virt: Option<u64>, object: &Shm, len: u64, writable: bool) -> Result<u64, MapToError> {
+        let pages = len.div_ceil(0x1000) as usize;
+        let size = pages as u64 * 0x1000;
+        let start = match virt {
+            Some(virt) if virt & 0xFFF != 0 => return Err(MapToError::OutOfRange),
+            Some(virt) if !self.is_free(virt, size) => return Err(MapToError::PageAlreadyMapped),
+            Some(virt) => virt,
+            None => self.find_free(size).ok_or(MapToError::OutOfRange)?,
+        };
+        if pages == 0 || start.checked_add(size).map_or(true, |end| end > USER_END) {
+            return Err(MapToError::OutOfRange);
+        }
+        let frames = object.map_frames(pages).ok_or(MapToError::OutOfRange)?;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER | PageTableFlags::NO_EXECUTE | PageTableFlags::SHARED;
+        if writable {
+            flags = flags | PageTableFlags::WRITABLE;
+        }
+        for (page, &frame) in frames.iter().enumerate() {
+            match self.empty_entry(start + page as u64 * 0x1000) {
+                Ok(entry) => entry.set_addr(frame, flags),
+                Err(e) => {
+                    self.clear_shared(start, page);
+                    object.unmapped();
+                    return Err(e);
+                }
+            }
+        }
+        self.add_vma(Vma { start, end: start + size, writable, executable: false, shared: true });
+        self.shared.push(SharedMapping { start, pages, object: object.clone() });
+        Ok(start)
+    }
+
+    /// Undo the shared mapping made at `start`; false if there is none
+    pub fn unmap_shared(&mut self, start: u64) -> bool {
+        let index = match self.shared.iter().position(|m| m.start == start) {
+            Some(index) => index,
+            None => return false,
+        };
+        let mapping = self.shared.remove(index);
+        self.clear_shared(start, mapping.pages);
+        self.remove_vmas(start, start + mapping.pages as u64 * 0x1000);
+        mapping.object.unmapped();
+        true
+    }
+
+    /// Clear the entries of `pages` shared pages from `start`
+    fn clear_shared(&mut self, start: u64, pages: usize) {
+        let flush_each = active_root() == self.root;
+        for page in 0..pages {
+            let virt = start + page as u64 * 0x1000;
+            if let Some(entry) = unsafe { leaf(self.root, virt) } {
+                entry.clear();
+                if flush_each {
+                    flush(virt);
+                }
+            }
+        }
+    }
+
+    /// Whether nothing is mapped in the `len` bytes at `start`
+    fn is_free(&self, start: u64, len: u64) -> bool {
+        let end = start.saturating_add(len);
+        self.vmas.iter().all(|v| v.end <= start || v.start >= end)
+    }
+
+    /// The first gap of `len` bytes from `MMAP_BASE`
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let mut at = MMAP_BASE;
+        for vma in self.vmas.iter().filter(|v| v.end > MMAP_BASE) {
+            if vma.start >= at + len {
+                break;
+            }
+            at = at.max(vma.end);
+        }
+        (at + len <= USER_END).then_some(at)
+    }
+
+    /// Drop the areas between `start` and `end`, splitting any that cross
+    /// either
+    fn remove_vmas(&mut self, start: u64, end: u64) {
+        let mut kept = Vec::with_capacity(self.vmas.len() + 1);
+        for vma in self.vmas.drain(..) {
+            if vma.start < start {
+                kept.push(Vma { end: vma.end.min(start), ..vma });
+            }
+            if vma.end > end {
+                kept.push(Vma { start: vma.start.max(end), ..vma });
+            }
+        }
+        self.vmas = kept;
+    }
+
+    /// Record a mapped page, merging it with the areas either side when
+    /// their access is the same
+    fn add_vma(&mut self, vma: Vma) {
+        let at = self.vmas.partition_point(|v| v.start < vma.start);
+        self.vmas.insert(at, vma);
+        let same = |a: &Vma, b: &Vma| {
+            a.end == b.start && a.writable == b.writable && a.executable == b.executable && !a.shared && !b.shared
+        };
+        if at + 1 < self.vmas.len() && same(&self.vmas[at], &self.vmas[at + 1]) {
+            self.vmas[at].end = self.vmas.remove(at + 1).end;
+        }
+        if at > 0 && same(&self.vmas[at - 1], &self.vmas[at]) {
+            self.vmas[at - 1].end = self.vmas.remove(at).end;
+        }
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    /// Whether the `len` bytes at `start` are all mapped, and writable if
+    /// `write`
+    pub fn covers(&self, start: u64, len: usize, write: bool) -> bool {
+        let end = match start.checked_add(len as u64) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut at = start;
+        for vma in self.vmas.iter().skip_while(|v| v.end <= start) {
+            if at >= end {
+                break;
+            }
+            if vma.start > at || (write && !vma.writable) {
+                return false;
+            }
+            at = vma.end;
+        }
+        at >= end
+    }
+
+    /// The frame mapped at user address `virt`
+    pub fn translate(&self, virt: u64) -> Option<PhysAddr> {
+        if !(USER_START..USER_END).contains(&virt) {
+            return None;
+        }
+        let entry = unsafe { leaf(self.root, virt)? };
+        entry.is_present().then(|| PhysAddr::new(entry.addr().as_u64() + (virt & 0xFFF)))
+    }
+}
+
+/// Free a user table at `level`, 3 for a PDPT down to 1 for a page table,
+/// with what it maps
+unsafe fn free_table(addr: PhysAddr, level: usize) {
+    let t = table(addr);
+    for index in 0..512 {
+        let entry = t.get_entry_mut(index);
+        if !entry.is_present() {
+            continue;
+        }
+        if level > 1 {
+            free_table(entry.addr(), level - 1);
+        } else if !entry.flags().contains(PageTableFlags::SHARED) {
+            free_frame(entry.addr());
+        }
+        entry.clear();
+    }
+    free_frame(addr);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        // Off these tables before they go
+        if active_root() == self.root {
+            activate(kernel_root());
+        }
+        unsafe {
+            let root = table(self.root);
+            for index in USER_ENTRIES {
+                let entry = root.get_entry(index);
+                if entry.is_present() {
+                    free_table(entry.addr(), 3);
+                }
+            }
+            free_frame(self.root);
+        }
+        for mapping in &self.shared {
+            mapping.object.unmapped();
+        }
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn shares_the_kernel_half_and_keeps_user_pages_apart() -> Result<(), String> {
+        let mut space = match AddressSpace::new() {
+            Some(space) => space,
+            None => return Err(String::from("no frames")),
+        };
+        let kernel = unsafe { table(kernel_root()) };
+        let own = unsafe { table(space.root()) };
+        check_eq!(own.get_entry(511).addr(), kernel.get_entry(511).addr());
+        check!(!own.get_entry(1).is_present());
+
+        let frame = space.map(USER_START + 0x5000, true, false).map_err(|e| alloc::format!("{:?}", e))?;
+        check_eq!(space.translate(USER_START + 0x5123), Some(PhysAddr::new(frame.as_u64() + 0x123)));
+        let entry = unsafe { leaf(space.root(), USER_START + 0x5000) }.ok_or("no entry")?;
+        check!(entry.flags().contains(PageTableFlags::USER | PageTableFlags::NO_EXECUTE));
+        check!(matches!(space.map(USER_START + 0x5000, true, false), Err(MapToError::PageAlreadyMapped)));
+        check!(matches!(space.map(0xFFFF_8000_0000_0000, true, false), Err(MapToError::OutOfRange)));
+        check_eq!(space.vmas().len(), 1);
+        // The kernel's tables never see the user page
+        check!(unsafe { leaf(kernel_root(), USER_START + 0x5000) }.is_none());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn merges_areas_and_checks_ranges_against_them() -> Result<(), String> {
+        let mut space = match AddressSpace::new() {
+            Some(space) => space,
+            None => return Err(String::from("no frames")),
+        };
+        for page in [2, 0, 1] {
+            space.map(USER_START + page * 0x1000, true, false).map_err(|e| alloc::format!("{:?}", e))?;
+        }
+        space.map(USER_START + 0x3000, false, false).map_err(|e| alloc::format!("{:?}", e))?;
+        check_eq!(space.vmas().len(), 2);
+        check_eq!((space.vmas()[0].start, space.vmas()[0].end), (USER_START, USER_START + 0x3000));
+        check!(space.covers(USER_START + 0x10, 0x3800, false));
+        check!(!space.covers(USER_START + 0x10, 0x3800, true));
+        check!(space.covers(USER_START + 0x2800, 0x400, true));
+        check!(!space.covers(USER_START + 0x3800, 0x1000, false));
+        check!(!space.covers(USER_START - 0x10, 0x20, false));
+        check!(space.covers(USER_START, 0, true));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn shared_mappings_see_the_same_frames() -> Result<(), String> {
+        let object = crate::mm::shm::open("test-address-space", true, true).map_err(|e| alloc::format!("{:?}", e))?;
+        let _ = crate::mm::shm::unlink("test-address-space");
+        object.resize(0x3000).map_err(|e| alloc::format!("{:?}", e))?;
+        let (mut a, mut b) = match (AddressSpace::new(), AddressSpace::new()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(String::from("no frames")),
+        };
+        a.map(MMAP_BASE, true, false).map_err(|e| alloc::format!("{:?}", e))?;
+        let at = a.map_shared(None, &object, 0x2001, true).map_err(|e| alloc::format!("{:?}", e))?;
+        check_eq!(at, MMAP_BASE + 0x1000);
+        let at_b = b.map_shared(Some(USER_START), &object, 0x1000, false).map_err(|e| alloc::format!("{:?}", e))?;
+        check_eq!(a.translate(at), b.translate(at_b));
+        check_eq!(a.translate(at + 0x2000), object.frame(2));
+        check!(!b.covers(at_b, 8, true));
+        check_eq!(a.vmas().len(), 2);
+        check!(matches!(a.map_shared(Some(at), &object, 0x1000, true), Err(MapToError::PageAlreadyMapped)));
+        check!(matches!(a.map_shared(None, &object, 0x4000, true), Err(MapToError::OutOfRange)));
+
+        check!(a.unmap_shared(at));
+        check!(!a.unmap_shared(at));
+        check_eq!(a.translate(at), None);
+        check_eq!(a.vmas().len(), 1);
+        drop(a);
+        drop(b);
+        // The frames went with neither address space
+        check_eq!(alloc::sync::Arc::strong_count(&object), 1);
+        check_eq!(object.resize(0), Ok(()));
+        Ok(())
+    }
+}
@@ -33,7 +33,8 @@ pub fn init_heap(
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
         let flags = PageTableFlags::PRESENT
-            .union(PageTableFlags::WRITABLE);
+            .union(PageTableFlags::WRITABLE)
+            .union(PageTableFlags::NO_EXECUTE);
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?;
         }
@@ -51,6 +52,12 @@ pub fn used_heap() -> u64 {
     ALLOCATOR.lock().used() as u64
 }
 
+/// Used heap bytes, or `None` while the heap is locked; for the panic
+/// handler, which cannot wait
+pub fn try_used_heap() -> Option<u64> {
+    ALLOCATOR.try_lock().map(|heap| heap.used() as u64)
+}
+
 /// Get free heap bytes
 pub fn free_heap() -> u64 {
     ALLOCATOR.lock().free() as u64
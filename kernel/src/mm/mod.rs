@@ -2,25 +2,76 @@
 //!
 //! Handles physical memory allocation, virtual memory mapping,
 //! and the kernel heap allocator.
+//!
+//! Everything the kernel maps for data, the heap and device registers, is
+//! no-execute; only the kernel image, reached through the physmap, and
+//! module code, marked with `set_executable`, run.
 
-use webbos_shared::bootinfo::BootInfo;
-use webbos_shared::types::{MemoryRegionType, PhysAddr, VirtAddr, KERNEL_BASE};
-use crate::arch::paging::BootInfoFrameAllocator;
-use crate::println;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use webbos_shared::bootinfo::{BootInfo, CRASH_REGION_ADDR, CRASH_REGION_SIZE};
+use webbos_shared::types::{MemoryRegion, MemoryRegionType, Pid, PhysAddr, VirtAddr, KERNEL_BASE};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use crate::arch::simd;
+use crate::arch::paging::{BootInfoFrameAllocator, OffsetPageTable, Page, PageTableFlags, PhysFrame};
+use crate::{info, println};
 
+pub mod address_space;
 pub mod allocator;
 pub mod bump;
+pub mod shm;
+pub mod user;
 
 /// Physical memory offset for kernel
 /// 
 /// The kernel is mapped at this virtual offset from physical addresses
 pub const PHYSICAL_MEMORY_OFFSET: u64 = KERNEL_BASE;
 
+/// Where the kernel's own mappings go, clear of the physmap
+const KERNEL_MAPPINGS: u64 = 0xFFFF_E000_0000_0000;
+
 /// Kernel heap start address
-pub const HEAP_START: u64 = KERNEL_BASE + 0x40000000; // 1GB after kernel base
+pub const HEAP_START: u64 = KERNEL_MAPPINGS;
 /// Initial kernel heap size
 pub const HEAP_SIZE: u64 = 8 * 1024 * 1024; // 8MB heap for browser and apps
 
+/// Physical memory the bootloader also maps at its own address
+const IDENTITY_MAPPED_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Virtual window for device register mappings
+pub const MMIO_START: u64 = KERNEL_MAPPINGS + 0x1000_0000;
+/// Size of the MMIO window
+pub const MMIO_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Next free address in the MMIO window
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Where the bootloader mapped all of physical memory, and how much of it
+/// (the physmap); until `init` reads the boot info, the first 512MB at
+/// `PHYSICAL_MEMORY_OFFSET`
+static PHYSMAP_BASE: AtomicU64 = AtomicU64::new(PHYSICAL_MEMORY_OFFSET);
+static PHYSMAP_SIZE: AtomicU64 = AtomicU64::new(512 * 1024 * 1024);
+
+/// The kernel's PML4, which processes without an address space of their
+/// own run on
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+/// Page tables and frame allocator, kept after boot for MMIO and DMA
+static MAPPER: Mutex<Option<(OffsetPageTable, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+/// Heap bytes held on behalf of each process, by PID
+///
+/// Everything lives in the one kernel heap, so there are no per-process
+/// page counts to read; instead whatever allocates for a process charges
+/// it here and the charge goes when the memory does.
+static RESIDENT: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+
+/// Bytes given back by `reclaim_boot_memory`, from the bootloader and
+/// from ACPI's reclaimable tables
+static RECLAIMED_BOOTLOADER: AtomicU64 = AtomicU64::new(0);
+static RECLAIMED_ACPI: AtomicU64 = AtomicU64::new(0);
+
 /// Global bump allocator for early boot
 static mut BUMP_ALLOCATOR: Option<bump::BumpAllocator> = None;
 
@@ -42,6 +93,7 @@ pub unsafe fn init(boot_info: &'static BootInfo) {
             MemoryRegionType::AcpiReclaimable => "ACPI Reclaimable",
             MemoryRegionType::AcpiNvs => "ACPI NVS",
             MemoryRegionType::Bad => "Bad",
+            MemoryRegionType::EfiRuntime => "EFI Runtime",
             MemoryRegionType::Kernel => "Kernel",
             MemoryRegionType::Bootloader => "Bootloader",
             MemoryRegionType::PageTables => "Page Tables",
@@ -66,9 +118,16 @@ pub unsafe fn init(boot_info: &'static BootInfo) {
         .sum();
     
     println!("  Total available memory: {} MB", total_memory / (1024 * 1024));
+
+    if let Some(physmap) = boot_info.physmap() {
+        PHYSMAP_BASE.store(physmap.base.as_u64(), Ordering::Relaxed);
+        PHYSMAP_SIZE.store(physmap.size, Ordering::Relaxed);
+    }
+    println!("  Physical memory mapped: {} MB at {:016X}", physmap_size() / (1024 * 1024), physmap_base());
     
     // Initialize paging
-    let mut mapper = crate::arch::paging::init(PHYSICAL_MEMORY_OFFSET);
+    KERNEL_ROOT.store(address_space::active_root().as_u64(), Ordering::Relaxed);
+    let mut mapper = crate::arch::paging::init(physmap_base());
     
     // Initialize frame allocator
     let mut frame_allocator = BootInfoFrameAllocator::init(memory_map);
@@ -81,6 +140,207 @@ pub unsafe fn init(boot_info: &'static BootInfo) {
         HEAP_SIZE / 1024, 
         HEAP_START
     );
+    
+    *MAPPER.lock() = Some((mapper, frame_allocator));
+}
+
+/// Map `size` bytes of device registers at `phys` into the MMIO window
+///
+/// The mapping is uncached and lives for the rest of the kernel's life.
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Option<VirtAddr> {
+    let offset = phys.as_u64() & 0xFFF;
+    let pages = (offset + size as u64 + 0xFFF) / 0x1000;
+    let virt = MMIO_NEXT.fetch_add(pages * 0x1000, Ordering::Relaxed);
+    if virt + pages * 0x1000 > MMIO_START + MMIO_SIZE {
+        return None;
+    }
+
+    let mut guard = MAPPER.lock();
+    let (mapper, frames) = guard.as_mut()?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+    for i in 0..pages {
+        let page = Page::containing_address(virt + i * 0x1000);
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys.as_u64() - offset + i * 0x1000));
+        unsafe {
+            mapper.map_to(page, frame, flags, frames).ok()?;
+        }
+    }
+    Some(VirtAddr::new(virt + offset))
+}
+
+/// Copy `len` bytes of physical memory; firmware tables may lie beyond the
+/// memory the bootloader mapped
+pub fn read_physical(addr: u64, len: usize) -> Option<alloc::vec::Vec<u8>> {
+    if addr == 0 {
+        return None;
+    }
+    let virt = if addr + len as u64 <= physmap_size() {
+        phys_to_virt(PhysAddr::new(addr))
+    } else {
+        map_mmio(PhysAddr::new(addr), len)?
+    };
+    let bytes = unsafe { core::slice::from_raw_parts(virt.as_u64() as *const u8, len) };
+    Some(bytes.to_vec())
+}
+
+/// Map `size` bytes at `phys` to the same virtual address, for firmware
+/// that must run where it was loaded
+///
+/// The first `IDENTITY_MAPPED_SIZE` bytes already are; pages mapped
+/// before are left as they are. The mapping lives for the rest of the
+/// kernel's life.
+pub fn map_identity(phys: PhysAddr, size: u64) -> bool {
+    use crate::arch::paging::MapToError;
+
+    let start = phys.as_u64().max(IDENTITY_MAPPED_SIZE) & !0xFFF;
+    let end = phys.as_u64() + size;
+    let mut guard = MAPPER.lock();
+    let (mapper, frames) = match guard.as_mut() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut addr = start;
+    while addr < end {
+        let page = Page::containing_address(addr);
+        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+        match unsafe { mapper.map_to(page, frame, flags, frames) } {
+            Ok(()) | Err(MapToError::PageAlreadyMapped) => {}
+            Err(_) => return false,
+        }
+        addr += 0x1000;
+    }
+    true
+}
+
+/// Allocate `pages` physically contiguous, zeroed frames for device DMA
+///
+/// The frames come from the boot frame allocator and are never freed.
+/// Returns the physical address; the memory is reachable via `phys_to_virt`.
+pub fn alloc_dma_frames(pages: usize) -> Option<PhysAddr> {
+    if pages == 0 {
+        return None;
+    }
+    let mut guard = MAPPER.lock();
+    let (_, frames) = guard.as_mut()?;
+
+    // Frames come out in ascending order, so runs are usually contiguous;
+    // restart the run at any gap
+    let mut start = frames.allocate_frame()?.start_address().as_u64();
+    let mut run = 1;
+    while run < pages {
+        let next = frames.allocate_frame()?.start_address().as_u64();
+        if next == start + run as u64 * 0x1000 {
+            run += 1;
+        } else {
+            start = next;
+            run = 1;
+        }
+    }
+    if start + pages as u64 * 0x1000 > physmap_size() {
+        return None;
+    }
+
+    let addr = PhysAddr::new(start);
+    unsafe {
+        simd::memset(phys_to_virt(addr).as_u64() as *mut u8, 0, pages * 0x1000);
+    }
+    Some(addr)
+}
+
+/// Give the frame allocator the memory the bootloader passed things in,
+/// and ACPI's reclaimable tables, once the kernel has read them
+///
+/// What is still read stays: the boot info and its memory map, which the
+/// frame allocator walks, and the crash dump region.
+pub fn reclaim_boot_memory(boot_info: &BootInfo) {
+    let info = virt_to_phys_u64(boot_info as *const BootInfo as u64);
+    let map = boot_info.memory_map_addr.as_u64();
+    let keep = [
+        (info, info + boot_info.total_size as u64),
+        (map, map + (boot_info.memory_map_count * core::mem::size_of::<MemoryRegion>()) as u64),
+        (CRASH_REGION_ADDR, CRASH_REGION_ADDR + CRASH_REGION_SIZE),
+    ];
+
+    let mut guard = MAPPER.lock();
+    let (_, frames) = match guard.as_mut() {
+        Some(mapper) => mapper,
+        None => return,
+    };
+    for region in unsafe { boot_info.memory_map() } {
+        let reclaimed = match region.region_type {
+            MemoryRegionType::Bootloader => &RECLAIMED_BOOTLOADER,
+            MemoryRegionType::AcpiReclaimable => &RECLAIMED_ACPI,
+            _ => continue,
+        };
+        for (start, end) in free_ranges(region.base.as_u64(), region.end().as_u64(), &keep) {
+            unsafe { frames.add_free_range(start, end) };
+            reclaimed.fetch_add(end - start, Ordering::Relaxed);
+        }
+    }
+    info!("mm", "Reclaimed {} KB from the bootloader and {} KB of ACPI tables",
+        RECLAIMED_BOOTLOADER.load(Ordering::Relaxed) / 1024, RECLAIMED_ACPI.load(Ordering::Relaxed) / 1024);
+}
+
+/// The whole pages of `start..end` outside every `keep` range
+fn free_ranges(start: u64, end: u64, keep: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut ranges = alloc::vec![((start + 0xFFF) & !0xFFF, end & !0xFFF)];
+    for &(keep_start, keep_end) in keep {
+        let (keep_start, keep_end) = (keep_start & !0xFFF, (keep_end + 0xFFF) & !0xFFF);
+        ranges = ranges.into_iter()
+            .flat_map(|(start, end)| [(start, end.min(keep_start)), (start.max(keep_end), end)])
+            .filter(|(start, end)| start < end)
+            .collect();
+    }
+    ranges.retain(|(start, end)| start < end);
+    ranges
+}
+
+/// The kernel's PML4
+pub fn kernel_root() -> PhysAddr {
+    PhysAddr::new(KERNEL_ROOT.load(Ordering::Relaxed))
+}
+
+/// A zeroed frame, for page tables and user pages
+pub fn alloc_frame() -> Option<PhysAddr> {
+    let frame = MAPPER.lock().as_mut()?.1.allocate_frame()?.start_address();
+    unsafe { simd::memset(phys_to_virt(frame).as_u64() as *mut u8, 0, 0x1000) };
+    Some(frame)
+}
+
+/// Give back a frame from `alloc_frame`
+///
+/// # Safety
+/// Nothing may map or use the frame any more.
+pub unsafe fn free_frame(frame: PhysAddr) {
+    if let Some((_, frames)) = MAPPER.lock().as_mut() {
+        frames.add_free_range(frame.as_u64(), frame.as_u64() + 0x1000);
+    }
+}
+
+/// Let the kernel run the `len` bytes of its own mappings at `virt`, or
+/// stop it; they must be mapped in 4KB pages, as the heap is, and the
+/// pages are all of them changed. False if one is not.
+pub fn set_executable(virt: u64, len: usize, executable: bool) -> bool {
+    let start = virt & !0xFFF;
+    let end = virt + len as u64;
+    let _guard = MAPPER.lock();
+    let mut page = start;
+    while page < end {
+        let entry = match unsafe { address_space::leaf(kernel_root(), page) } {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let flags = entry.flags();
+        entry.set_flags(if executable {
+            flags.difference(PageTableFlags::NO_EXECUTE)
+        } else {
+            flags | PageTableFlags::NO_EXECUTE
+        });
+        address_space::flush(page);
+        page += 0x1000;
+    }
+    true
 }
 
 /// Print memory statistics
@@ -96,27 +356,83 @@ pub fn print_stats() {
         total / 1024,
         free / 1024
     );
+    println!("  Reclaimed after boot: {} KB from the bootloader, {} KB of ACPI tables",
+        RECLAIMED_BOOTLOADER.load(Ordering::Relaxed) / 1024,
+        RECLAIMED_ACPI.load(Ordering::Relaxed) / 1024
+    );
+}
+
+/// Charge `bytes` held for a process to it
+pub fn charge(pid: Pid, bytes: u64) {
+    *RESIDENT.lock().entry(pid.as_u64()).or_insert(0) += bytes;
+}
+
+/// Take back a charge made with `charge`
+pub fn uncharge(pid: Pid, bytes: u64) {
+    if let Some(resident) = RESIDENT.lock().get_mut(&pid.as_u64()) {
+        *resident = resident.saturating_sub(bytes);
+    }
+}
+
+/// Bytes currently charged to a process
+pub fn resident(pid: Pid) -> u64 {
+    RESIDENT.lock().get(&pid.as_u64()).copied().unwrap_or(0)
+}
+
+/// Drop every charge of a process that has gone
+pub fn release(pid: Pid) {
+    RESIDENT.lock().remove(&pid.as_u64());
+}
+
+/// Start of the physmap
+pub fn physmap_base() -> u64 {
+    PHYSMAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Physical memory reachable through `phys_to_virt`
+pub fn physmap_size() -> u64 {
+    PHYSMAP_SIZE.load(Ordering::Relaxed)
 }
 
 /// Convert physical address to virtual address
 pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
-    VirtAddr::new(addr.as_u64() + PHYSICAL_MEMORY_OFFSET)
+    VirtAddr::new(addr.as_u64() + physmap_base())
 }
 
 /// Convert virtual address to physical address (if mapped)
 pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
-    crate::arch::paging::translate_addr(addr.as_u64(), PHYSICAL_MEMORY_OFFSET)
+    crate::arch::paging::translate_addr(addr.as_u64(), physmap_base())
         .map(|p| PhysAddr::new(p.as_u64()))
 }
 
 /// Convert virtual address (u64) to physical address (u64) for DMA
-/// 
-/// # Safety
-/// This assumes the address is identity mapped with PHYSICAL_MEMORY_OFFSET
+///
+/// Physmap addresses convert directly; other kernel addresses, such as
+/// the heap, go through the page tables. Lower-half addresses are taken
+/// to be physical already.
 pub fn virt_to_phys_u64(addr: u64) -> u64 {
-    if addr >= PHYSICAL_MEMORY_OFFSET {
-        addr - PHYSICAL_MEMORY_OFFSET
-    } else {
+    let base = physmap_base();
+    if addr < base {
         addr // Already physical
+    } else if addr - base < physmap_size() {
+        addr - base
+    } else {
+        virt_to_phys(VirtAddr::new(addr)).map_or(addr - base, |p| p.as_u64())
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+    use alloc::string::String;
+
+    #[kernel_test]
+    fn reclaims_around_what_is_kept() -> Result<(), String> {
+        let keep = [(0x2100, 0x2200), (0x5000, 0x7000)];
+        check_eq!(free_ranges(0x1000, 0x9000, &keep), alloc::vec![(0x1000, 0x2000), (0x3000, 0x5000), (0x7000, 0x9000)]);
+        check_eq!(free_ranges(0x5800, 0x6800, &keep), alloc::vec![]);
+        check_eq!(free_ranges(0x800, 0x1800, &[]), alloc::vec![]);
+        Ok(())
     }
 }
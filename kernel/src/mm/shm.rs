@@ -0,0 +1,285 @@
+//! Shared memory objects
+//!
+//! An object is a run of pages, not necessarily contiguous in physical
+//! memory, under a name in a flat namespace. Processes open it by name,
+//! size it, and map it into their address spaces, where every mapping
+//! sees the same frames; the kernel reads it through the physmap, so a
+//! frame a renderer draws into reaches the compositor without a copy.
+//!
+//! An object is an `Arc`: the name holds a reference until unlinked, and
+//! each open handle and each mapping holds one. Its frames are freed when
+//! the last goes.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use webbos_shared::types::{Pid, PhysAddr};
+
+use super::{alloc_frame, free_frame, phys_to_virt};
+
+/// Longest object name
+pub const NAME_MAX: usize = 255;
+
+/// Largest object, 64MB
+pub const SIZE_MAX: u64 = 64 * 1024 * 1024;
+
+/// First handle number; lower ones are the console's
+const FIRST_HANDLE: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// No object by that name
+    NotFound,
+    /// Asked to create one that exists
+    Exists,
+    /// Empty name, too long, or containing `/` past the first byte
+    BadName,
+    /// Larger than `SIZE_MAX`
+    TooLarge,
+    /// Shrinking an object that is mapped
+    Busy,
+    OutOfMemory,
+    /// No such handle in the process
+    BadHandle,
+}
+
+/// A shared memory object
+#[derive(Debug)]
+pub struct Object {
+    frames: Mutex<Vec<PhysAddr>>,
+    /// Mappings of it; it cannot shrink while there are any
+    mapped: Mutex<usize>,
+}
+
+pub type Shm = Arc<Object>;
+
+impl Object {
+    fn new() -> Self {
+        Object { frames: Mutex::new(Vec::new()), mapped: Mutex::new(0) }
+    }
+
+    /// Size in bytes, always whole pages
+    pub fn size(&self) -> u64 {
+        self.frames.lock().len() as u64 * 0x1000
+    }
+
+    /// Grow with zeroed pages or shrink to `bytes`, rounded up to a page
+    pub fn resize(&self, bytes: u64) -> Result<(), ShmError> {
+        if bytes > SIZE_MAX {
+            return Err(ShmError::TooLarge);
+        }
+        let pages = bytes.div_ceil(0x1000) as usize;
+        let mapped = self.mapped.lock();
+        let mut frames = self.frames.lock();
+        if pages < frames.len() {
+            if *mapped > 0 {
+                return Err(ShmError::Busy);
+            }
+            for frame in frames.drain(pages..) {
+                unsafe { free_frame(frame) };
+            }
+        }
+        while frames.len() < pages {
+            frames.push(alloc_frame().ok_or(ShmError::OutOfMemory)?);
+        }
+        Ok(())
+    }
+
+    /// The frame backing page `index`
+    pub fn frame(&self, index: usize) -> Option<PhysAddr> {
+        self.frames.lock().get(index).copied()
+    }
+
+    /// The frames of the first `pages` pages, counted as a mapping until
+    /// `unmapped`; None if it is smaller
+    pub fn map_frames(&self, pages: usize) -> Option<Vec<PhysAddr>> {
+        let mut mapped = self.mapped.lock();
+        let frames = self.frames.lock();
+        let frames = frames.get(..pages)?.to_vec();
+        *mapped += 1;
+        Some(frames)
+    }
+
+    /// A mapping from `map_frames` went away
+    pub fn unmapped(&self) {
+        let mut mapped = self.mapped.lock();
+        *mapped = mapped.saturating_sub(1);
+    }
+
+    /// Hand `f` the `len` bytes at `offset` in page-sized pieces, as the
+    /// kernel sees them; false if they run past the end
+    pub fn with_bytes(&self, offset: u64, len: usize, mut f: impl FnMut(&[u8])) -> bool {
+        let frames = self.frames.lock();
+        let end = match offset.checked_add(len as u64) {
+            Some(end) if end <= frames.len() as u64 * 0x1000 => end,
+            _ => return false,
+        };
+        let mut at = offset;
+        while at < end {
+            let in_page = (at & 0xFFF) as usize;
+            let take = (0x1000 - in_page).min((end - at) as usize);
+            let page = phys_to_virt(frames[(at >> 12) as usize]).as_u64() as *const u8;
+            f(unsafe { core::slice::from_raw_parts(page.add(in_page), take) });
+            at += take as u64;
+        }
+        true
+    }
+
+    /// Copy `bytes` in at `offset`; false if they run past the end
+    pub fn write(&self, offset: u64, bytes: &[u8]) -> bool {
+        let frames = self.frames.lock();
+        if offset.checked_add(bytes.len() as u64).map_or(true, |end| end > frames.len() as u64 * 0x1000) {
+            return false;
+        }
+        let (mut at, mut rest) = (offset, bytes);
+        while !rest.is_empty() {
+            let in_page = (at & 0xFFF) as usize;
+            let take = (0x1000 - in_page).min(rest.len());
+            let page = phys_to_virt(frames[(at >> 12) as usize]).as_u64() as *mut u8;
+            unsafe { core::ptr::copy_nonoverlapping(rest.as_ptr(), page.add(in_page), take) };
+            rest = &rest[take..];
+            at += take as u64;
+        }
+        true
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        for &frame in self.frames.get_mut().iter() {
+            unsafe { free_frame(frame) };
+        }
+    }
+}
+
+/// Objects by name
+static NAMES: Mutex<BTreeMap<String, Shm>> = Mutex::new(BTreeMap::new());
+
+/// Open handles, by PID and handle number
+static HANDLES: Mutex<BTreeMap<(u64, i32), Shm>> = Mutex::new(BTreeMap::new());
+
+/// Names are one path component, with or without a leading `/`
+fn check_name(name: &str) -> Result<&str, ShmError> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    if name.is_empty() || name.len() > NAME_MAX || name.contains('/') {
+        return Err(ShmError::BadName);
+    }
+    Ok(name)
+}
+
+/// The object called `name`, created empty if `create` and it does not
+/// exist; with `exclusive` as well, it must not
+pub fn open(name: &str, create: bool, exclusive: bool) -> Result<Shm, ShmError> {
+    let name = check_name(name)?;
+    let mut names = NAMES.lock();
+    match names.get(name) {
+        Some(_) if create && exclusive => Err(ShmError::Exists),
+        Some(object) => Ok(object.clone()),
+        None if create => {
+            let object = Arc::new(Object::new());
+            names.insert(String::from(name), object.clone());
+            Ok(object)
+        }
+        None => Err(ShmError::NotFound),
+    }
+}
+
+/// Remove a name; the object lives on while opened or mapped
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+    let name = check_name(name)?;
+    NAMES.lock().remove(name).map(|_| ()).ok_or(ShmError::NotFound)
+}
+
+/// Give a process a handle on an object
+pub fn add_handle(pid: Pid, object: Shm) -> i32 {
+    let mut handles = HANDLES.lock();
+    let mut handle = FIRST_HANDLE;
+    while handles.contains_key(&(pid.as_u64(), handle)) {
+        handle += 1;
+    }
+    handles.insert((pid.as_u64(), handle), object);
+    handle
+}
+
+/// The object behind a process's handle
+pub fn handle(pid: Pid, handle: i32) -> Result<Shm, ShmError> {
+    HANDLES.lock().get(&(pid.as_u64(), handle)).cloned().ok_or(ShmError::BadHandle)
+}
+
+pub fn close(pid: Pid, handle: i32) -> Result<(), ShmError> {
+    HANDLES.lock().remove(&(pid.as_u64(), handle)).map(|_| ()).ok_or(ShmError::BadHandle)
+}
+
+/// Close everything a process had open, when it ends
+pub fn release(pid: Pid) {
+    HANDLES.lock().retain(|&(owner, _), _| owner != pid.as_u64());
+}
+
+/// `/proc/shm`: each named object, its size and how many mappings it has
+pub fn contents() -> Vec<u8> {
+    let mut out = String::new();
+    for (name, object) in NAMES.lock().iter() {
+        out.push_str(&alloc::format!("/{} {} {}\n", name, object.size(), *object.mapped.lock()));
+    }
+    out.into_bytes()
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn names_open_the_same_object() -> Result<(), String> {
+        let a = open("/test-shm-names", true, true).map_err(|e| alloc::format!("{:?}", e))?;
+        check_eq!(open("test-shm-names", true, true).err(), Some(ShmError::Exists));
+        let b = open("test-shm-names", false, false).map_err(|e| alloc::format!("{:?}", e))?;
+        check!(Arc::ptr_eq(&a, &b));
+        check_eq!(open("a/b", true, false).err(), Some(ShmError::BadName));
+        check_eq!(unlink("test-shm-names"), Ok(()));
+        check_eq!(open("test-shm-names", false, false).err(), Some(ShmError::NotFound));
+        // Unlinked, it is still there for those holding it
+        check_eq!(Arc::strong_count(&a), 2);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn bytes_cross_pages_and_mappings_pin_the_size() -> Result<(), String> {
+        let object = Arc::new(Object::new());
+        object.resize(5000).map_err(|e| alloc::format!("{:?}", e))?;
+        check_eq!(object.size(), 0x2000);
+        check!(object.write(4090, b"across a page"));
+        check!(!object.write(0x1FFF, b"past"));
+        let mut read = Vec::new();
+        check!(object.with_bytes(4090, 13, |piece| read.extend_from_slice(piece)));
+        check_eq!(&read[..], b"across a page");
+
+        let frames = object.map_frames(2).ok_or("too small")?;
+        check_eq!(frames[1], object.frame(1).ok_or("no frame")?);
+        check!(object.map_frames(3).is_none());
+        check_eq!(object.resize(0x1000), Err(ShmError::Busy));
+        check_eq!(object.resize(0x3000), Ok(()));
+        object.unmapped();
+        check_eq!(object.resize(0), Ok(()));
+        check_eq!(object.size(), 0);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn handles_are_per_process() -> Result<(), String> {
+        let object = Arc::new(Object::new());
+        let (one, two) = (Pid::new(9001), Pid::new(9002));
+        let first = add_handle(one, object.clone());
+        check_eq!(first, FIRST_HANDLE);
+        check_eq!(add_handle(one, object.clone()), FIRST_HANDLE + 1);
+        check!(handle(two, first).is_err());
+        check_eq!(close(one, first), Ok(()));
+        check_eq!(add_handle(one, object.clone()), first);
+        release(one);
+        check_eq!(Arc::strong_count(&object), 1);
+        Ok(())
+    }
+}
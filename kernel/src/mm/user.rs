@@ -0,0 +1,125 @@
+//! Reaching user memory from syscalls
+//!
+//! A pointer from user space is checked to lie in the user half, then
+//! the copy is one `rep movsb` with SMAP lifted for it. The page fault
+//! handler knows that instruction: a fault there, on a page the process
+//! never mapped, resumes at the fixup, and the copy fails instead of the
+//! kernel.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::address_space::{USER_END, USER_START};
+use crate::arch::cpu;
+
+/// A user pointer that is outside the user half or not mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress;
+
+// Copy rdx bytes from rsi to rdi; returns how many were left when a
+// fault stopped it
+core::arch::global_asm!(
+    ".global webbos_copy_user_bytes",
+    ".global webbos_user_copy",
+    ".global webbos_user_copy_fixup",
+    "webbos_copy_user_bytes:",
+    "mov rcx, rdx",
+    "webbos_user_copy:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    "webbos_user_copy_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    fn webbos_copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    /// The `rep movsb` that may fault, and where to go when it does
+    fn webbos_user_copy();
+    fn webbos_user_copy_fixup();
+}
+
+/// Whether `len` bytes at `addr` are all user addresses
+fn in_user_half(addr: u64, len: usize) -> bool {
+    match addr.checked_add(len as u64) {
+        Some(end) => len == 0 || (addr >= USER_START && end <= USER_END),
+        None => false,
+    }
+}
+
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), BadAddress> {
+    if len == 0 {
+        return Ok(());
+    }
+    let smap = cpu::smap_enabled();
+    let left = unsafe {
+        if smap {
+            core::arch::asm!("stac", options(nomem, nostack));
+        }
+        let left = webbos_copy_user_bytes(dst, src, len);
+        if smap {
+            core::arch::asm!("clac", options(nomem, nostack));
+        }
+        left
+    };
+    if left == 0 { Ok(()) } else { Err(BadAddress) }
+}
+
+/// Fill `dst` from user address `src`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), BadAddress> {
+    if !in_user_half(src, dst.len()) {
+        return Err(BadAddress);
+    }
+    copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Copy `src` to user address `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), BadAddress> {
+    if !in_user_half(dst, src.len()) {
+        return Err(BadAddress);
+    }
+    copy(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// The `len` bytes at user address `src`
+pub fn read_user(src: u64, len: usize) -> Result<Vec<u8>, BadAddress> {
+    let mut bytes = vec![0u8; len];
+    copy_from_user(&mut bytes, src)?;
+    Ok(bytes)
+}
+
+/// Where a page fault at `rip` on `address` resumes, if it is a user
+/// copy's
+pub fn fixup(rip: u64, address: u64) -> Option<u64> {
+    (rip == webbos_user_copy as u64 && address < USER_END).then(|| webbos_user_copy_fixup as u64)
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn refuses_kernel_and_wrapping_ranges() -> Result<(), String> {
+        let mut buf = [0u8; 16];
+        let kernel = buf.as_ptr() as u64;
+        check_eq!(copy_from_user(&mut buf, kernel), Err(BadAddress));
+        check_eq!(copy_to_user(kernel, &[1, 2, 3]), Err(BadAddress));
+        check_eq!(copy_from_user(&mut buf, USER_END - 8), Err(BadAddress));
+        check_eq!(copy_from_user(&mut buf, u64::MAX - 4), Err(BadAddress));
+        check!(copy_from_user(&mut [], kernel).is_ok());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn an_unmapped_user_page_fails_the_copy() -> Result<(), String> {
+        // Nothing is mapped at the bottom of the user half on the kernel's
+        // tables; the fault is fixed up rather than fatal
+        let mut buf = [0u8; 8];
+        check_eq!(copy_from_user(&mut buf, USER_START), Err(BadAddress));
+        check_eq!(copy_to_user(USER_START + 0x1000, &buf), Err(BadAddress));
+        Ok(())
+    }
+}
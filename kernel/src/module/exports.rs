@@ -0,0 +1,121 @@
+//! What modules may call
+//!
+//! Each function here is `extern "C"` and marked `#[kernel_export]`, which
+//! puts its name in the table modules are linked against. Pointers and
+//! lengths cross as they are; a module that passes bad ones has only
+//! itself to blame, as it runs in the kernel anyway.
+
+use core::alloc::Layout;
+
+use webbos_macros::kernel_export;
+use webbos_shared::types::PhysAddr;
+
+use crate::drivers::{input, pci, timer};
+use crate::log::{self, Level};
+use crate::mm;
+
+/// Log `len` bytes of UTF-8 at `message`; `level` is 1 for errors to 5
+/// for tracing, as `log::Level`
+#[kernel_export]
+pub extern "C" fn kernel_log(level: u32, message: *const u8, len: usize) {
+    let level = match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    };
+    let bytes = unsafe { core::slice::from_raw_parts(message, len) };
+    let text = core::str::from_utf8(bytes).unwrap_or("(not UTF-8)");
+    log::_log(level, "module", format_args!("{}", text));
+}
+
+/// Heap memory; null if there is none
+#[kernel_export]
+pub extern "C" fn kernel_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) if size > 0 => unsafe { alloc::alloc::alloc_zeroed(layout) },
+        _ => core::ptr::null_mut(),
+    }
+}
+
+/// Free what `kernel_alloc` gave, with the same size and alignment
+#[kernel_export]
+pub extern "C" fn kernel_free(ptr: *mut u8, size: usize, align: usize) {
+    if let Ok(layout) = Layout::from_size_align(size, align) {
+        if !ptr.is_null() && size > 0 {
+            unsafe { alloc::alloc::dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// Where the kernel reaches physical address `phys`
+#[kernel_export]
+pub extern "C" fn kernel_phys_to_virt(phys: u64) -> u64 {
+    mm::phys_to_virt(PhysAddr::new(phys)).as_u64()
+}
+
+/// Map device registers; 0 if the MMIO window is full
+#[kernel_export]
+pub extern "C" fn kernel_map_mmio(phys: u64, size: usize) -> u64 {
+    mm::map_mmio(PhysAddr::new(phys), size).map_or(0, |virt| virt.as_u64())
+}
+
+/// Contiguous zeroed pages for DMA, never freed; their physical address,
+/// or 0
+#[kernel_export]
+pub extern "C" fn kernel_alloc_dma(pages: usize) -> u64 {
+    mm::alloc_dma_frames(pages).map_or(0, |phys| phys.as_u64())
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_inb(port: u16) -> u8 {
+    unsafe { input::inb(port) }
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_outb(port: u16, value: u8) {
+    unsafe { input::outb(port, value) }
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_inw(port: u16) -> u16 {
+    unsafe { input::inw(port) }
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_outw(port: u16, value: u16) {
+    unsafe { input::outw(port, value) }
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_inl(port: u16) -> u32 {
+    unsafe { input::inl(port) }
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_outl(port: u16, value: u32) {
+    unsafe { input::outl(port, value) }
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_pci_read32(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    pci::read_config32(bus, device, function, offset)
+}
+
+#[kernel_export]
+pub extern "C" fn kernel_pci_write32(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    pci::write_config32(bus, device, function, offset, value)
+}
+
+/// Milliseconds since boot
+#[kernel_export]
+pub extern "C" fn kernel_uptime_ms() -> u64 {
+    timer::elapsed_ms()
+}
+
+/// Busy-wait
+#[kernel_export]
+pub extern "C" fn kernel_sleep_ms(ms: u64) {
+    timer::sleep_ms(ms)
+}
@@ -0,0 +1,399 @@
+//! Loading ELF relocatable objects
+//!
+//! The object's allocated sections go into one block of heap, each at its
+//! alignment; its symbols are resolved against that block, or by name for
+//! the undefined ones; then its `RELA` relocations are applied. Only the
+//! relocations a `code-model=large` object has, and the 32-bit ones when
+//! they happen to reach, are handled. The heap is no-execute, so the
+//! block, whole pages of its own, is made executable once relocated and
+//! no-execute again when it goes.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::ModuleError;
+
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHF_ALLOC: u64 = 2;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const SHN_COMMON: u16 = 0xFFF2;
+const STB_WEAK: u8 = 2;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+/// A module's memory; freed when dropped
+pub struct Image {
+    base: *mut u8,
+    layout: Layout,
+}
+
+// The module's code and data, owned by whoever holds the image
+unsafe impl Send for Image {}
+
+impl Image {
+    fn new(size: usize, align: usize) -> Result<Self, ModuleError> {
+        let size = (size.max(1) + 0xFFF) & !0xFFF;
+        let layout = Layout::from_size_align(size, align.max(0x1000)).map_err(|_| ModuleError::Malformed)?;
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return Err(ModuleError::OutOfMemory);
+        }
+        Ok(Self { base, layout })
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.base, self.layout.size()) }
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        crate::mm::set_executable(self.base as u64, self.layout.size(), false);
+        unsafe { dealloc(self.base, self.layout) };
+    }
+}
+
+/// A module loaded and relocated, not yet run
+pub struct Loaded {
+    pub image: Image,
+    /// Address of `module_init`
+    pub init: u64,
+    /// Address of `module_exit`, if it has one
+    pub exit: Option<u64>,
+}
+
+/// A section header, the fields used
+#[derive(Clone, Copy)]
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+/// A symbol, the fields used
+struct Symbol {
+    name: u32,
+    bind: u8,
+    section: u16,
+    value: u64,
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, ModuleError> {
+    Ok(u16::from_le_bytes(data.get(at..at + 2).ok_or(ModuleError::Malformed)?.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, ModuleError> {
+    Ok(u32::from_le_bytes(data.get(at..at + 4).ok_or(ModuleError::Malformed)?.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Result<u64, ModuleError> {
+    Ok(u64::from_le_bytes(data.get(at..at + 8).ok_or(ModuleError::Malformed)?.try_into().unwrap()))
+}
+
+/// The bytes of `section` in the file
+fn contents<'a>(data: &'a [u8], section: &Section) -> Result<&'a [u8], ModuleError> {
+    let end = section.offset.checked_add(section.size).ok_or(ModuleError::Malformed)?;
+    data.get(section.offset as usize..end as usize).ok_or(ModuleError::Malformed)
+}
+
+/// The NUL-terminated name at `offset` in a string table
+fn name_at(strings: &[u8], offset: u32) -> Result<&str, ModuleError> {
+    let rest = strings.get(offset as usize..).ok_or(ModuleError::Malformed)?;
+    let len = rest.iter().position(|&b| b == 0).ok_or(ModuleError::Malformed)?;
+    core::str::from_utf8(&rest[..len]).map_err(|_| ModuleError::Malformed)
+}
+
+/// Load the object in `data`, resolving what it does not define with
+/// `resolve`
+pub fn load(data: &[u8], resolve: impl Fn(&str) -> Option<u64>) -> Result<Loaded, ModuleError> {
+    if data.get(..4) != Some(b"\x7fELF".as_slice()) || data.get(4) != Some(&2) || data.get(5) != Some(&1) {
+        return Err(ModuleError::NotObject);
+    }
+    if u16_at(data, 16)? != ET_REL || u16_at(data, 18)? != EM_X86_64 {
+        return Err(ModuleError::NotObject);
+    }
+    let section_offset = u64_at(data, 40)? as usize;
+    let section_size = u16_at(data, 58)? as usize;
+    let section_count = u16_at(data, 60)? as usize;
+    if section_size != 64 {
+        return Err(ModuleError::Malformed);
+    }
+    let sections = (0..section_count)
+        .map(|index| {
+            let at = section_offset.checked_add(index * 64).ok_or(ModuleError::Malformed)?;
+            Ok(Section {
+                kind: u32_at(data, at + 4)?,
+                flags: u64_at(data, at + 8)?,
+                offset: u64_at(data, at + 24)?,
+                size: u64_at(data, at + 32)?,
+                link: u32_at(data, at + 40)?,
+                info: u32_at(data, at + 44)?,
+                align: u64_at(data, at + 48)?,
+                entry_size: u64_at(data, at + 56)?,
+            })
+        })
+        .collect::<Result<Vec<Section>, ModuleError>>()?;
+
+    // Where each allocated section goes in the image
+    let mut placed: Vec<Option<usize>> = Vec::with_capacity(sections.len());
+    let mut size = 0usize;
+    let mut align = 1usize;
+    for section in &sections {
+        if section.flags & SHF_ALLOC == 0 || section.size == 0 {
+            placed.push(None);
+            continue;
+        }
+        let section_align = (section.align as usize).max(1);
+        if !section_align.is_power_of_two() {
+            return Err(ModuleError::Malformed);
+        }
+        size = size.next_multiple_of(section_align);
+        placed.push(Some(size));
+        size = size.checked_add(section.size as usize).ok_or(ModuleError::Malformed)?;
+        align = align.max(section_align);
+    }
+    let mut image = Image::new(size, align)?;
+    let base = image.base();
+    for (section, offset) in sections.iter().zip(&placed) {
+        if let (Some(offset), true) = (offset, section.kind != SHT_NOBITS) {
+            image.bytes()[*offset..*offset + section.size as usize].copy_from_slice(contents(data, section)?);
+        }
+    }
+
+    // Every symbol's address
+    let symtab = sections.iter().find(|section| section.kind == SHT_SYMTAB).ok_or(ModuleError::NotObject)?;
+    let strings = contents(data, sections.get(symtab.link as usize).ok_or(ModuleError::Malformed)?)?;
+    let symbol_data = contents(data, symtab)?;
+    if symtab.entry_size != 24 {
+        return Err(ModuleError::Malformed);
+    }
+    let symbols = symbol_data
+        .chunks_exact(24)
+        .map(|entry| Symbol {
+            name: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+            bind: entry[4] >> 4,
+            section: u16::from_le_bytes(entry[6..8].try_into().unwrap()),
+            value: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+        })
+        .collect::<Vec<Symbol>>();
+    let mut addresses = Vec::with_capacity(symbols.len());
+    for (index, symbol) in symbols.iter().enumerate() {
+        let address = match symbol.section {
+            // The null symbol
+            SHN_UNDEF if index == 0 => 0,
+            SHN_UNDEF => {
+                let name = name_at(strings, symbol.name)?;
+                match resolve(name) {
+                    Some(address) => address,
+                    None if symbol.bind == STB_WEAK => 0,
+                    None => return Err(ModuleError::UndefinedSymbol(String::from(name))),
+                }
+            }
+            SHN_ABS => symbol.value,
+            SHN_COMMON => return Err(ModuleError::Unsupported("common symbols; build with -fno-common")),
+            section => match placed.get(section as usize) {
+                Some(Some(offset)) => base + *offset as u64 + symbol.value,
+                // In a section that is not loaded, like debug info
+                _ => 0,
+            },
+        };
+        addresses.push(address);
+    }
+
+    // Relocations, of the loaded sections
+    for section in &sections {
+        if section.kind == SHT_REL {
+            return Err(ModuleError::Unsupported("REL relocations"));
+        }
+        if section.kind != SHT_RELA {
+            continue;
+        }
+        let target = match placed.get(section.info as usize) {
+            Some(Some(offset)) => *offset,
+            _ => continue,
+        };
+        let target_size = sections[section.info as usize].size as usize;
+        for entry in contents(data, section)?.chunks_exact(24) {
+            let offset = u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize;
+            let info = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let addend = i64::from_le_bytes(entry[16..24].try_into().unwrap());
+            let symbol = (info >> 32) as usize;
+            let kind = info as u32;
+            let s = *addresses.get(symbol).ok_or(ModuleError::Malformed)?;
+            let value = s.wrapping_add(addend as u64);
+            let place = base + (target + offset) as u64;
+            let overflow = || ModuleError::Overflow(
+                name_at(strings, symbols[symbol].name).map(String::from).unwrap_or_default());
+            let bytes: ([u8; 8], usize) = match kind {
+                R_X86_64_NONE => continue,
+                R_X86_64_64 => (value.to_le_bytes(), 8),
+                R_X86_64_PC64 => (value.wrapping_sub(place).to_le_bytes(), 8),
+                R_X86_64_PC32 | R_X86_64_PLT32 => {
+                    let relative = value.wrapping_sub(place) as i64;
+                    let relative = i32::try_from(relative).map_err(|_| overflow())?;
+                    ((relative as i64).to_le_bytes(), 4)
+                }
+                R_X86_64_32 => ((u32::try_from(value).map_err(|_| overflow())? as u64).to_le_bytes(), 4),
+                R_X86_64_32S => ((i32::try_from(value as i64).map_err(|_| overflow())? as i64).to_le_bytes(), 4),
+                _ => return Err(ModuleError::Unsupported("a relocation type; build with -C code-model=large")),
+            };
+            let (bytes, width) = bytes;
+            if offset + width > target_size {
+                return Err(ModuleError::Malformed);
+            }
+            image.bytes()[target + offset..target + offset + width].copy_from_slice(&bytes[..width]);
+        }
+    }
+
+    // The entry points, among the symbols the module defines
+    let defined = |wanted: &str| {
+        symbols.iter().zip(&addresses).find_map(|(symbol, &address)| {
+            let defined = symbol.section != SHN_UNDEF && address != 0;
+            (defined && name_at(strings, symbol.name) == Ok(wanted)).then_some(address)
+        })
+    };
+    let init = defined("module_init").ok_or(ModuleError::NoInit)?;
+    let exit = defined("module_exit");
+    if !crate::mm::set_executable(image.base(), image.size(), true) {
+        return Err(ModuleError::Unsupported("code outside 4KB heap pages"));
+    }
+    Ok(Loaded { image, init, exit })
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn push_u16(out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u64(out: &mut Vec<u8>, value: u64) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// An object with `module_init` in `.text` and a `.data` of two
+    /// pointers: to `kernel_uptime_ms` plus 4, and to `module_init`
+    fn object() -> Vec<u8> {
+        let text = [0x31, 0xC0, 0xC3, 0x90, 0x90, 0x90, 0x90, 0x90];
+        let strings = b"\0module_init\0kernel_uptime_ms\0";
+        let symbols: [(u32, u8, u16); 3] = [(0, 0, 0), (1, 0x12, 1), (13, 0x10, 0)];
+        let relocations: [(u64, u64, i64); 2] = [(0, 2 << 32 | 1, 4), (8, 1 << 32 | 1, 0)];
+
+        let mut out = alloc::vec![0u8; 64];
+        let text_at = out.len();
+        out.extend_from_slice(&text);
+        let data_at = out.len();
+        out.extend_from_slice(&[0; 16]);
+        let symtab_at = out.len();
+        for (name, info, section) in symbols {
+            push_u32(&mut out, name);
+            out.push(info);
+            out.push(0);
+            push_u16(&mut out, section);
+            push_u64(&mut out, 0);
+            push_u64(&mut out, 0);
+        }
+        let strtab_at = out.len();
+        out.extend_from_slice(strings);
+        out.resize(out.len().next_multiple_of(8), 0);
+        let rela_at = out.len();
+        for (offset, info, addend) in relocations {
+            push_u64(&mut out, offset);
+            push_u64(&mut out, info);
+            out.extend_from_slice(&addend.to_le_bytes());
+        }
+        let sections_at = out.len();
+        // kind, flags, offset, size, link, info, align, entry size
+        let sections: [(u32, u64, usize, usize, u32, u32, u64, u64); 6] = [
+            (0, 0, 0, 0, 0, 0, 0, 0),
+            (1, 6, text_at, text.len(), 0, 0, 16, 0),
+            (1, 3, data_at, 16, 0, 0, 8, 0),
+            (SHT_SYMTAB, 0, symtab_at, symbols.len() * 24, 4, 1, 8, 24),
+            (3, 0, strtab_at, strings.len(), 0, 0, 1, 0),
+            (SHT_RELA, 0, rela_at, relocations.len() * 24, 3, 2, 8, 24),
+        ];
+        for (kind, flags, offset, size, link, info, align, entry_size) in sections {
+            push_u32(&mut out, 0);
+            push_u32(&mut out, kind);
+            push_u64(&mut out, flags);
+            push_u64(&mut out, 0);
+            push_u64(&mut out, offset as u64);
+            push_u64(&mut out, size as u64);
+            push_u32(&mut out, link);
+            push_u32(&mut out, info);
+            push_u64(&mut out, align);
+            push_u64(&mut out, entry_size);
+        }
+
+        out[..4].copy_from_slice(b"\x7fELF");
+        out[4] = 2;
+        out[5] = 1;
+        out[6] = 1;
+        out[16..18].copy_from_slice(&ET_REL.to_le_bytes());
+        out[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        out[40..48].copy_from_slice(&(sections_at as u64).to_le_bytes());
+        out[52..54].copy_from_slice(&64u16.to_le_bytes());
+        out[58..60].copy_from_slice(&64u16.to_le_bytes());
+        out[60..62].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        out
+    }
+
+    #[kernel_test]
+    fn relocates_against_exports() -> Result<(), String> {
+        let resolve = |name: &str| (name == "kernel_uptime_ms").then_some(0x1000);
+        let mut loaded = load(&object(), resolve).map_err(|e| alloc::format!("{:?}", e))?;
+        let base = loaded.image.base();
+        check_eq!(loaded.init, base);
+        check_eq!(loaded.exit, None);
+        let bytes = loaded.image.bytes();
+        check_eq!(&bytes[..3], &[0x31, 0xC0, 0xC3]);
+        check_eq!(u64::from_le_bytes(bytes[8..16].try_into().unwrap()), 0x1004);
+        check_eq!(u64::from_le_bytes(bytes[16..24].try_into().unwrap()), base);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn refuses_what_it_cannot_link() -> Result<(), String> {
+        let result = load(&object(), |_| None);
+        check!(matches!(result, Err(ModuleError::UndefinedSymbol(ref name)) if name == "kernel_uptime_ms"));
+        let mut executable = object();
+        executable[16] = 2;
+        check!(matches!(load(&executable, |_| Some(0)), Err(ModuleError::NotObject)));
+        check!(matches!(load(&object()[..100], |_| Some(0)), Err(ModuleError::Malformed)));
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! Loadable kernel modules
+//!
+//! A module is an x86_64 ELF relocatable object on the VFS, such as
+//! `/lib/modules/hda.ko`, built for `x86_64-unknown-none` with
+//! `-C code-model=large`: it is loaded into the heap, far from the kernel,
+//! where 32-bit relocations do not reach. It calls the kernel only through
+//! the functions marked `#[kernel_export]`, all `extern "C"`, in `exports`,
+//! and has these of its own:
+//!
+//! - `module_init`, `extern "C" fn() -> i32`, run when it is loaded; any
+//!   result but 0 is a failure, and the module is dropped
+//! - `module_exit`, `extern "C" fn()`, if it has one, run before it is
+//!   unloaded
+//!
+//! `insmod`, `rmmod` and `lsmod` load, unload and list them; `/etc/rc.local`
+//! can load what a machine needs at boot.
+
+pub mod exports;
+mod loader;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::{self, FsError};
+use crate::{info, println};
+
+/// A function modules may call, put in `.kernel_exports` by
+/// `#[kernel_export]`
+pub struct Export {
+    pub name: &'static str,
+    pub address: *const (),
+}
+
+// Only ever read, and the addresses are of functions
+unsafe impl Sync for Export {}
+
+extern "C" {
+    static __kernel_exports_start: u8;
+    static __kernel_exports_end: u8;
+}
+
+/// Every function modules may call
+pub fn exports() -> &'static [Export] {
+    let start = core::ptr::addr_of!(__kernel_exports_start) as *const Export;
+    let end = core::ptr::addr_of!(__kernel_exports_end) as usize;
+    let count = (end - start as usize) / core::mem::size_of::<Export>();
+    unsafe { core::slice::from_raw_parts(start, count) }
+}
+
+/// Address of the exported function `name`
+pub fn find_export(name: &str) -> Option<u64> {
+    exports().iter().find(|export| export.name == name).map(|export| export.address as u64)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    Fs(FsError),
+    /// Not an x86_64 ELF relocatable object
+    NotObject,
+    /// Something in the object this loader does not handle
+    Unsupported(&'static str),
+    /// The object refers to something the kernel does not export
+    UndefinedSymbol(String),
+    /// A relocation's result does not fit its field
+    Overflow(String),
+    /// The object is cut short or points outside itself
+    Malformed,
+    NoInit,
+    AlreadyLoaded,
+    NotLoaded,
+    /// `module_init` returned this
+    InitFailed(i32),
+    OutOfMemory,
+}
+
+/// A module that is in
+struct Module {
+    name: String,
+    path: String,
+    image: loader::Image,
+    exit: Option<u64>,
+}
+
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+/// The module name for `path`: the file name without its extension
+fn module_name(path: &str) -> &str {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    match file.rfind('.') {
+        Some(dot) if dot > 0 => &file[..dot],
+        _ => file,
+    }
+}
+
+/// Load the module at `path` and run its `module_init`; returns its name
+pub fn load(path: &str) -> Result<String, ModuleError> {
+    let name = String::from(module_name(path));
+    if MODULES.lock().iter().any(|module| module.name == name) {
+        return Err(ModuleError::AlreadyLoaded);
+    }
+    let data = fs::read_file(path).map_err(ModuleError::Fs)?;
+    let loaded = loader::load(&data, find_export)?;
+
+    // Not under the lock: init may take its time, and the lock is not
+    // for it to wait on
+    let init: extern "C" fn() -> i32 = unsafe { core::mem::transmute(loaded.init as usize) };
+    let result = init();
+    if result != 0 {
+        return Err(ModuleError::InitFailed(result));
+    }
+
+    info!("module", "Loaded {} from {}: {} bytes at {:#x}", name, path, loaded.image.size(), loaded.image.base());
+    MODULES.lock().push(Module { name: name.clone(), path: String::from(path), image: loaded.image, exit: loaded.exit });
+    Ok(name)
+}
+
+/// Run `name`'s `module_exit` and free it
+pub fn unload(name: &str) -> Result<(), ModuleError> {
+    let module = {
+        let mut modules = MODULES.lock();
+        let index = modules.iter().position(|module| module.name == name).ok_or(ModuleError::NotLoaded)?;
+        modules.remove(index)
+    };
+    if let Some(exit) = module.exit {
+        let exit: extern "C" fn() = unsafe { core::mem::transmute(exit as usize) };
+        exit();
+    }
+    info!("module", "Unloaded {}", name);
+    // Dropping the image frees the module's memory
+    drop(module);
+    Ok(())
+}
+
+/// `lsmod`: the modules that are in
+pub fn print_modules() {
+    let modules = MODULES.lock();
+    if modules.is_empty() {
+        println!("No modules loaded ({} kernel exports)", exports().len());
+        return;
+    }
+    println!("{:<16} {:>8}  {:<18}  {}", "Module", "Size", "Address", "From");
+    for module in modules.iter() {
+        println!("{:<16} {:>8}  {:#018x}  {}", module.name, module.image.size(), module.image.base(), module.path);
+    }
+}
+
+/// `insmod PATH`
+pub fn insmod_command(args: &[&str]) {
+    match args {
+        [path] => match load(path) {
+            Ok(name) => println!("Loaded {}", name),
+            Err(e) => println!("insmod: {}: {:?}", path, e),
+        },
+        _ => println!("Usage: insmod PATH"),
+    }
+}
+
+/// `rmmod NAME`
+pub fn rmmod_command(args: &[&str]) {
+    match args {
+        [name] => {
+            if let Err(e) = unload(name) {
+                println!("rmmod: {}: {:?}", name, e);
+            }
+        }
+        _ => println!("Usage: rmmod NAME"),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn names_modules_by_file() -> Result<(), String> {
+        check_eq!(module_name("/lib/modules/hda.ko"), "hda");
+        check_eq!(module_name("usb.o"), "usb");
+        check_eq!(module_name("/lib/modules/.hidden"), ".hidden");
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn exports_are_found() -> Result<(), String> {
+        check!(!exports().is_empty());
+        check_eq!(find_export("kernel_log"), Some(exports::kernel_log as usize as u64));
+        check_eq!(find_export("no_such_function"), None);
+        Ok(())
+    }
+}
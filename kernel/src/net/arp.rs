@@ -1,14 +1,22 @@
 //! ARP (Address Resolution Protocol)
 //!
 //! Maps IP addresses to MAC addresses.
+//!
+//! Once the gateway's MAC address is known, ARP naming another one for it
+//! is ignored unless `network.arp_gateway_updates` is on: unasked-for
+//! replies are how traffic leaving the network gets diverted through a
+//! spoofing host.
 
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-use crate::net::{Ipv4Address, MacAddress, EtherType};
+use crate::config;
+use crate::net::{Ipv4Address, MacAddress, EtherType, DROPS};
 use crate::println;
+use crate::warn;
 
 /// ARP hardware types
 const ARP_HW_ETHERNET: u16 = 1;
@@ -106,10 +114,16 @@ pub fn process_arp_packet(src_mac: MacAddress, data: &[u8]) {
 
     let sender_ip = Ipv4Address::new(packet.sender_ip);
     let target_ip = Ipv4Address::new(packet.target_ip);
+    let config = super::get_config();
 
     // Update cache with sender's info
     {
         let mut cache = ARP_CACHE.lock();
+        if config.is_configured() && sender_ip == config.gateway && moves_gateway(&cache, sender_ip, src_mac) {
+            DROPS.gateway_arp.fetch_add(1, Ordering::Relaxed);
+            warn!("arp", "Ignoring ARP from {:?} claiming gateway {}", src_mac, sender_ip);
+            return;
+        }
         cache.insert(sender_ip, ArpEntry {
             mac: src_mac,
             timestamp: crate::drivers::timer::elapsed_ms(),
@@ -120,7 +134,6 @@ pub fn process_arp_packet(src_mac: MacAddress, data: &[u8]) {
     match packet.op {
         ARP_OP_REQUEST => {
             // Check if it's asking for our IP
-            let config = super::get_config();
             if config.is_configured() && target_ip == config.ip {
                 // Send ARP reply
                 send_arp_reply(src_mac, sender_ip);
@@ -133,6 +146,17 @@ pub fn process_arp_packet(src_mac: MacAddress, data: &[u8]) {
     }
 }
 
+/// Whether taking `mac` for the gateway at `gateway` would replace the
+/// one already known, and that is not allowed
+fn moves_gateway(cache: &BTreeMap<Ipv4Address, ArpEntry>, gateway: Ipv4Address, mac: MacAddress) -> bool {
+    match cache.get(&gateway) {
+        Some(entry) if !entry.pending && entry.mac != mac => {
+            config::get("network.arp_gateway_updates").as_deref() != Some("on")
+        }
+        _ => false,
+    }
+}
+
 /// Send ARP request
 pub fn send_arp_request(target_ip: Ipv4Address) {
     let config = super::get_config();
@@ -0,0 +1,301 @@
+//! Packet capture
+//!
+//! While a capture runs, every frame received or sent on its interface,
+//! or on any with `any`, is copied into a ring as the stack sees it, up to
+//! `SNAPLEN` bytes each; the oldest make way once the ring holds
+//! `RING_BYTES`. Stopping it writes the ring to a pcap file, for Wireshark
+//! or tcpdump elsewhere to read. A live capture prints a line per frame
+//! instead. With nothing captured, the taps in `process_packet` and
+//! `send_packet` cost one atomic load.
+//!
+//! A capture puts its interfaces in promiscuous mode, so that it sees
+//! frames for other hosts too, and takes them out again when it stops.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::drivers::rtc;
+use crate::fs;
+use crate::net::{self, EtherType, IpProtocol, Ipv4Address, MacAddress};
+use crate::println;
+
+/// Bytes kept of each frame
+pub const SNAPLEN: usize = 2048;
+/// Bytes of frames kept before the oldest are dropped
+const RING_BYTES: usize = 4 * 1024 * 1024;
+
+/// pcap link type for Ethernet
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Which way a frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// A captured frame
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Unix time it was seen, in nanoseconds
+    pub ns: u64,
+    /// Its length on the wire, which `data` may fall short of
+    pub length: usize,
+    pub data: Vec<u8>,
+}
+
+struct Session {
+    /// Interface captured, or None for all of them
+    iface: Option<usize>,
+    /// Interfaces the capture made promiscuous
+    promiscuous: Vec<usize>,
+    /// File the frames go to when the capture stops; None when live
+    path: Option<String>,
+    frames: VecDeque<Frame>,
+    bytes: usize,
+    /// Frames seen, kept or not
+    seen: u64,
+    dropped: u64,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// Start capturing on interface `iface`, or on all of them, into the ring
+/// for `path`, or live to the console if no path is given
+pub fn start(iface: Option<usize>, path: Option<String>) {
+    // A capture started over another replaces it
+    let old = SESSION.lock().take();
+    if let Some(old) = old {
+        restore(&old);
+    }
+    let ifaces: Vec<usize> = match iface {
+        Some(iface) => vec![iface],
+        None => (0..net::interface_count()).collect(),
+    };
+    let promiscuous = ifaces.into_iter()
+        .filter(|i| !net::is_promiscuous(*i) && net::set_promiscuous(*i, true).is_ok())
+        .collect();
+    *SESSION.lock() = Some(Session {
+        iface, promiscuous, path, frames: VecDeque::new(), bytes: 0, seen: 0, dropped: 0,
+    });
+    ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Take the interfaces a capture made promiscuous out of it
+fn restore(session: &Session) {
+    for iface in &session.promiscuous {
+        let _ = net::set_promiscuous(*iface, false);
+    }
+}
+
+/// Stop capturing; writes the file if the capture had one
+///
+/// Returns the file and how many frames went into it.
+pub fn stop() -> Result<Option<(String, usize)>, String> {
+    ACTIVE.store(false, Ordering::SeqCst);
+    let session = match SESSION.lock().take() {
+        Some(session) => session,
+        None => return Err(String::from("no capture running")),
+    };
+    restore(&session);
+    let path = match session.path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let frames: Vec<Frame> = session.frames.into_iter().collect();
+    fs::write_file(&path, &pcap(&frames)).map_err(|e| format!("{}: {:?}", path, e))?;
+    Ok(Some((path, frames.len())))
+}
+
+/// Copy a frame seen on interface `iface` if a capture wants it
+pub fn tap(iface: usize, direction: Direction, data: &[u8]) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let frame = Frame { ns: rtc::unix_ns(), length: data.len(), data: data[..data.len().min(SNAPLEN)].to_vec() };
+    let line = {
+        let mut session = SESSION.lock();
+        let session = match session.as_mut() {
+            Some(session) if session.iface.map_or(true, |i| i == iface) => session,
+            _ => return,
+        };
+        session.seen += 1;
+        if session.path.is_none() {
+            format!("{} {} {} {}", clock(frame.ns), iface, if direction == Direction::In { "<" } else { ">" }, summarize(data))
+        } else {
+            session.bytes += frame.data.len();
+            session.frames.push_back(frame);
+            while session.bytes > RING_BYTES {
+                match session.frames.pop_front() {
+                    Some(old) => {
+                        session.bytes -= old.data.len();
+                        session.dropped += 1;
+                    }
+                    None => break,
+                }
+            }
+            return;
+        }
+    };
+    println!("{}", line);
+}
+
+/// Time of day of Unix time `ns`, to the microsecond
+fn clock(ns: u64) -> String {
+    let seconds = ns / 1_000_000_000;
+    format!("{:02}:{:02}:{:02}.{:06}", seconds / 3600 % 24, seconds / 60 % 60, seconds % 60, ns / 1000 % 1_000_000)
+}
+
+/// `frames` as a pcap file
+pub fn pcap(frames: &[Frame]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(24 + frames.iter().map(|f| 16 + f.data.len()).sum::<usize>());
+    out.extend_from_slice(&0xA1B2_C3D4u32.to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&0i32.to_le_bytes()); // Times are UTC
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(SNAPLEN as u32).to_le_bytes());
+    out.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    for frame in frames {
+        out.extend_from_slice(&((frame.ns / 1_000_000_000) as u32).to_le_bytes());
+        out.extend_from_slice(&((frame.ns / 1000 % 1_000_000) as u32).to_le_bytes());
+        out.extend_from_slice(&(frame.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(frame.length as u32).to_le_bytes());
+        out.extend_from_slice(&frame.data);
+    }
+    out
+}
+
+fn mac(bytes: &[u8]) -> String {
+    let mac = MacAddress::new([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]]);
+    String::from(core::str::from_utf8(&mac.format()).unwrap_or("?"))
+}
+
+fn ipv4(bytes: &[u8]) -> Ipv4Address {
+    Ipv4Address::new([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// A line saying what an Ethernet frame holds
+pub fn summarize(frame: &[u8]) -> String {
+    if frame.len() < 14 {
+        return format!("runt frame, {} bytes", frame.len());
+    }
+    let ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+    let payload = &frame[14..];
+    match EtherType::from_u16(ether_type) {
+        Some(EtherType::Arp) if payload.len() >= 28 => {
+            let (sender, target) = (ipv4(&payload[14..18]), ipv4(&payload[24..28]));
+            match u16::from_be_bytes([payload[6], payload[7]]) {
+                1 => format!("ARP who-has {} tell {}", target, sender),
+                2 => format!("ARP reply {} is-at {}", sender, mac(&payload[8..14])),
+                op => format!("ARP op {}", op),
+            }
+        }
+        Some(EtherType::Ipv4) if payload.len() >= 20 => summarize_ipv4(payload),
+        Some(EtherType::Ipv6) => format!("IPv6, {} bytes", frame.len()),
+        _ => format!("{} > {} ethertype 0x{:04x}, {} bytes", mac(&frame[6..12]), mac(&frame[0..6]), ether_type, frame.len()),
+    }
+}
+
+fn summarize_ipv4(packet: &[u8]) -> String {
+    let ihl = ((packet[0] & 0x0F) as usize) * 4;
+    let (src, dst) = (ipv4(&packet[12..16]), ipv4(&packet[16..20]));
+    let total = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+    let body = if ihl >= 20 && ihl <= total { &packet[ihl..total] } else { &[][..] };
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match IpProtocol::from_u8(packet[9]) {
+        Some(IpProtocol::Tcp) if body.len() >= 20 => {
+            let flags = body[13];
+            let names: String = [(0x02, 'S'), (0x01, 'F'), (0x04, 'R'), (0x08, 'P'), (0x10, '.')]
+                .iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            let data = body.len().saturating_sub(((body[12] >> 4) as usize) * 4);
+            let seq = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+            format!("{}:{} > {}:{} TCP [{}] seq {} length {}", src, port(0), dst, port(2), names, seq, data)
+        }
+        Some(IpProtocol::Udp) if body.len() >= 8 => {
+            format!("{}:{} > {}:{} UDP length {}", src, port(0), dst, port(2), body.len() - 8)
+        }
+        Some(IpProtocol::Icmp) if body.len() >= 4 => {
+            let kind = match body[0] {
+                0 => String::from("echo reply"),
+                8 => String::from("echo request"),
+                3 => String::from("destination unreachable"),
+                11 => String::from("time exceeded"),
+                other => format!("type {}", other),
+            };
+            format!("{} > {} ICMP {}", src, dst, kind)
+        }
+        _ => format!("{} > {} protocol {}, {} bytes", src, dst, packet[9], packet.len()),
+    }
+}
+
+/// Print what is being captured
+pub fn print_info() {
+    let session = SESSION.lock();
+    let session = match session.as_ref() {
+        Some(session) => session,
+        None => {
+            println!("No capture running");
+            return;
+        }
+    };
+    let iface = match session.iface {
+        Some(iface) => format!("interface {}", iface),
+        None => String::from("all interfaces"),
+    };
+    match &session.path {
+        Some(path) => println!(
+            "Capturing {} for {}: {} frames seen, {} kept ({} bytes), {} dropped",
+            iface, path, session.seen, session.frames.len(), session.bytes, session.dropped,
+        ),
+        None => println!("Printing frames on {}: {} seen", iface, session.seen),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn udp_frame() -> Vec<u8> {
+        let mut frame = vec![0xFF; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 2, 15, 1, 1, 1, 1]);
+        frame.extend_from_slice(&[0xC0, 0x00, 0, 53, 0, 13, 0, 0]);
+        frame.extend_from_slice(b"hello");
+        frame
+    }
+
+    #[kernel_test]
+    fn frames_are_summarized() -> Result<(), String> {
+        check_eq!(summarize(&udp_frame()), String::from("10.0.2.15:49152 > 1.1.1.1:53 UDP length 5"));
+        check!(summarize(&[0; 6]).starts_with("runt"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn pcap_files_have_a_header_and_records() -> Result<(), String> {
+        let data = udp_frame();
+        let frame = Frame { ns: 1_700_000_000_123_456_000, length: data.len() + 10, data: data.clone() };
+        let file = pcap(&[frame]);
+        check_eq!(file[..4], [0xD4, 0xC3, 0xB2, 0xA1]);
+        check_eq!(u32::from_le_bytes([file[20], file[21], file[22], file[23]]), LINKTYPE_ETHERNET);
+        check_eq!(u32::from_le_bytes([file[24], file[25], file[26], file[27]]), 1_700_000_000);
+        check_eq!(u32::from_le_bytes([file[28], file[29], file[30], file[31]]), 123_456);
+        check_eq!(u32::from_le_bytes([file[32], file[33], file[34], file[35]]) as usize, data.len());
+        check_eq!(u32::from_le_bytes([file[36], file[37], file[38], file[39]]) as usize, data.len() + 10);
+        check_eq!(file.len(), 24 + 16 + data.len());
+        Ok(())
+    }
+}
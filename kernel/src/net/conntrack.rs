@@ -0,0 +1,171 @@
+//! Connection tracking
+//!
+//! One entry per TCP or UDP conversation passing through this machine
+//! when it routes between interfaces: the inside host's address and port,
+//! the port it appears on from outside, and the remote end. NAT fills the
+//! table and maps replies back with it. Entries lapse once a conversation
+//! has been idle too long.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::net::{IpProtocol, Ipv4Address};
+
+/// Idle time before a TCP entry lapses
+const TCP_TIMEOUT_MS: u64 = 300_000;
+/// Idle time before a UDP entry lapses
+const UDP_TIMEOUT_MS: u64 = 60_000;
+
+/// Entries kept before new conversations are refused
+const MAX_FLOWS: usize = 1024;
+
+/// Ports handed out to inside hosts, below the stack's own ephemeral ports
+const FIRST_PORT: u16 = 20000;
+const LAST_PORT: u16 = 29999;
+
+/// An address and port
+pub type Endpoint = (Ipv4Address, u16);
+
+/// A tracked conversation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flow {
+    pub protocol: IpProtocol,
+    /// The inside host's end
+    pub inside: Endpoint,
+    /// Port the inside host appears on from outside
+    pub outside_port: u16,
+    pub remote: Endpoint,
+    /// Started from outside by a port forward rather than from inside
+    pub forwarded: bool,
+    /// When a packet of it last passed, in milliseconds since boot
+    pub last_seen: u64,
+}
+
+impl Flow {
+    fn expired(&self, now: u64) -> bool {
+        let timeout = match self.protocol {
+            IpProtocol::Tcp => TCP_TIMEOUT_MS,
+            _ => UDP_TIMEOUT_MS,
+        };
+        now.saturating_sub(self.last_seen) > timeout
+    }
+}
+
+/// The connection tracking table
+#[derive(Debug)]
+pub struct Table {
+    flows: Vec<Flow>,
+    next_port: u16,
+}
+
+impl Table {
+    pub const fn new() -> Self {
+        Self { flows: Vec::new(), next_port: FIRST_PORT }
+    }
+
+    /// Drop entries idle too long
+    pub fn expire(&mut self, now: u64) {
+        self.flows.retain(|f| !f.expired(now));
+    }
+
+    /// Outside port for a packet from `inside` to `remote`, tracking a new
+    /// conversation if needed; None when the table or the ports are full
+    pub fn outbound(&mut self, protocol: IpProtocol, inside: Endpoint, remote: Endpoint, now: u64) -> Option<u16> {
+        if let Some(flow) = self.flows.iter_mut().find(|f| f.protocol == protocol && f.inside == inside && f.remote == remote) {
+            flow.last_seen = now;
+            return Some(flow.outside_port);
+        }
+        self.expire(now);
+        if self.flows.len() >= MAX_FLOWS {
+            return None;
+        }
+        let outside_port = self.free_port(protocol)?;
+        self.flows.push(Flow { protocol, inside, outside_port, remote, forwarded: false, last_seen: now });
+        Some(outside_port)
+    }
+
+    /// Inside end for a packet from `remote` to outside port `port`, if
+    /// it belongs to a tracked conversation
+    pub fn inbound(&mut self, protocol: IpProtocol, port: u16, remote: Endpoint, now: u64) -> Option<Endpoint> {
+        let flow = self.flows.iter_mut()
+            .find(|f| f.protocol == protocol && f.outside_port == port && f.remote == remote && !f.expired(now))?;
+        flow.last_seen = now;
+        Some(flow.inside)
+    }
+
+    /// Track a conversation `remote` starts with forwarded port `port`,
+    /// which leads to `inside`
+    pub fn forwarded(&mut self, protocol: IpProtocol, port: u16, inside: Endpoint, remote: Endpoint, now: u64) -> bool {
+        self.expire(now);
+        if self.flows.len() >= MAX_FLOWS {
+            return false;
+        }
+        self.flows.push(Flow { protocol, inside, outside_port: port, remote, forwarded: true, last_seen: now });
+        true
+    }
+
+    pub fn flows(&self) -> &[Flow] {
+        &self.flows
+    }
+
+    pub fn clear(&mut self) {
+        self.flows.clear();
+    }
+
+    fn free_port(&mut self, protocol: IpProtocol) -> Option<u16> {
+        for _ in FIRST_PORT..=LAST_PORT {
+            let port = self.next_port;
+            self.next_port = if port >= LAST_PORT { FIRST_PORT } else { port + 1 };
+            if !self.flows.iter().any(|f| f.protocol == protocol && f.outside_port == port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+/// The table NAT keeps
+pub static TABLE: Mutex<Table> = Mutex::new(Table::new());
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    const HOST: Endpoint = (Ipv4Address::from_octets(192, 168, 7, 2), 5000);
+    const SERVER: Endpoint = (Ipv4Address::from_octets(93, 184, 216, 34), 80);
+
+    #[kernel_test]
+    fn replies_map_back_to_the_inside_host() -> Result<(), String> {
+        let mut table = Table::new();
+        let port = table.outbound(IpProtocol::Tcp, HOST, SERVER, 0).ok_or("no port")?;
+        check_eq!(table.outbound(IpProtocol::Tcp, HOST, SERVER, 10), Some(port));
+        check_eq!(table.inbound(IpProtocol::Tcp, port, SERVER, 20), Some(HOST));
+        // Another remote, or the other protocol, is not let in
+        check_eq!(table.inbound(IpProtocol::Tcp, port, (SERVER.0, 81), 20), None);
+        check_eq!(table.inbound(IpProtocol::Udp, port, SERVER, 20), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn idle_flows_lapse() -> Result<(), String> {
+        let mut table = Table::new();
+        let port = table.outbound(IpProtocol::Udp, HOST, SERVER, 0).ok_or("no port")?;
+        // A reply keeps the flow alive
+        check_eq!(table.inbound(IpProtocol::Udp, port, SERVER, UDP_TIMEOUT_MS), Some(HOST));
+        check_eq!(table.inbound(IpProtocol::Udp, port, SERVER, 2 * UDP_TIMEOUT_MS + 1), None);
+        table.expire(2 * UDP_TIMEOUT_MS + 1);
+        check!(table.flows().is_empty());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn forwarded_flows_answer_from_the_forwarded_port() -> Result<(), String> {
+        let mut table = Table::new();
+        let inside = (HOST.0, 80);
+        check!(table.forwarded(IpProtocol::Tcp, 8080, inside, SERVER, 0));
+        check_eq!(table.outbound(IpProtocol::Tcp, inside, SERVER, 5), Some(8080));
+        Ok(())
+    }
+}
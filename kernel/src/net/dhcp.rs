@@ -1,12 +1,19 @@
 //! DHCP (Dynamic Host Configuration Protocol)
 //!
 //! Client for automatic IP configuration.
+//!
+//! Once bound, the lease is renewed with the server that gave it at T1,
+//! half the lease unless the server says otherwise. If the lease runs out
+//! before the server answers, discovery starts over.
 
 use alloc::vec;
 use alloc::vec::Vec;
+use core::time::Duration;
 
-use crate::net::{Ipv4Address, Port, IpProtocol, udp, NetworkConfig};
-use crate::println;
+use crate::drivers::timer::{self, TimerId};
+use crate::net::{Ipv4Address, Port, IpProtocol, NetworkConfig};
+use crate::net::socket::{self, SocketDomain, SocketProtocol, SocketType};
+use crate::{debug, info, warn};
 
 /// DHCP ports
 const DHCP_CLIENT_PORT: Port = Port::new(68);
@@ -48,7 +55,9 @@ const OPT_SUBNET_MASK: u8 = 1;
 const OPT_ROUTER: u8 = 3;
 const OPT_DNS: u8 = 6;
 const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
 const OPT_SERVER_ID: u8 = 54;
+const OPT_RENEWAL_TIME: u8 = 58;
 const OPT_END: u8 = 255;
 
 /// Current DHCP state
@@ -58,27 +67,63 @@ enum DhcpState {
     Selecting,
     Requesting,
     Bound,
+    Renewing,
 }
 
+/// Lease time meaning the address is ours for good
+const INFINITE_LEASE: u32 = 0xFFFF_FFFF;
+
 static mut DHCP_STATE: DhcpState = DhcpState::Idle;
 static mut DHCP_XID: u32 = 0x12345678;
+/// Address and server of the lease requested or held
+static mut DHCP_LEASE: Option<(Ipv4Address, Ipv4Address)> = None;
+/// Renewal or expiry, whichever is next
+static mut DHCP_TIMER: Option<TimerId> = None;
+/// The client's socket, bound to port 68
+static mut DHCP_SOCKET: Option<usize> = None;
 
 /// Start DHCP discovery
 pub fn start_dhcp() {
-    println!("[dhcp] Starting DHCP discovery...");
+    info!("dhcp", "Starting DHCP discovery...");
 
     unsafe {
         DHCP_STATE = DhcpState::Selecting;
         DHCP_XID = 0x12345678;
     }
+    set_timer(None);
 
-    // Bind DHCP client port
-    let _ = udp::bind(DHCP_CLIENT_PORT);
+    if client_socket().is_none() {
+        warn!("dhcp", "Port {} is in use", DHCP_CLIENT_PORT.as_u16());
+        return;
+    }
 
     // Send DHCP discover
     send_discover();
 }
 
+/// The socket on the client port, opened the first time it is needed
+fn client_socket() -> Option<usize> {
+    if let Some(fd) = unsafe { DHCP_SOCKET } {
+        return Some(fd);
+    }
+    let fd = socket::socket(SocketDomain::Inet, SocketType::Dgram, SocketProtocol::Udp).ok()?;
+    if socket::bind(fd, Ipv4Address::unspecified(), DHCP_CLIENT_PORT).is_err() {
+        let _ = socket::close(fd);
+        return None;
+    }
+    unsafe {
+        DHCP_SOCKET = Some(fd);
+    }
+    Some(fd)
+}
+
+/// Send a message to the server port of `to`
+fn send(to: Ipv4Address, packet: &[u8]) {
+    if let Some(fd) = unsafe { DHCP_SOCKET } {
+        let _ = socket::sendto(fd, packet, 0, to, DHCP_SERVER_PORT);
+    }
+}
+
 /// Send DHCP discover
 fn send_discover() {
     let mut packet = vec![0u8; 300];
@@ -152,18 +197,14 @@ fn send_discover() {
     packet[opt_pos] = OPT_END;
 
     // Send broadcast
-    let _ = udp::send_to(
-        DHCP_CLIENT_PORT,
-        Ipv4Address::broadcast(),
-        DHCP_SERVER_PORT,
-        &packet[..opt_pos + 1]
-    );
-
-    println!("[dhcp] Sent DISCOVER");
+    send(Ipv4Address::broadcast(), &packet[..opt_pos + 1]);
+
+    debug!("dhcp", "Sent DISCOVER");
 }
 
-/// Send DHCP request
-fn send_request(offer: &DhcpOffer) {
+/// Send DHCP request; renewing, it comes from the address it renews and
+/// goes to the server alone
+fn send_request(ip: Ipv4Address, server: Ipv4Address, renewing: bool) {
     let mut packet = vec![0u8; 300];
 
     // Fill DHCP header
@@ -176,8 +217,14 @@ fn send_request(offer: &DhcpOffer) {
         packet[4..8].copy_from_slice(&DHCP_XID.to_be_bytes());
     }
     
-    packet[8..12].copy_from_slice(&[0, 0, 0x80, 0x00]); // Broadcast
-    packet[12..28].fill(0); // IPs
+    if renewing {
+        packet[8..12].fill(0);
+        packet[12..16].copy_from_slice(ip.as_bytes()); // Client IP
+    } else {
+        packet[8..12].copy_from_slice(&[0, 0, 0x80, 0x00]); // Broadcast
+        packet[12..16].fill(0);
+    }
+    packet[16..28].fill(0); // IPs
     
     // Client MAC
     packet[28..34].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
@@ -196,32 +243,70 @@ fn send_request(offer: &DhcpOffer) {
     packet[opt_pos + 2] = DHCP_REQUEST;
     opt_pos += 3;
 
-    // Requested IP
-    packet[opt_pos] = OPT_REQUESTED_IP;
-    packet[opt_pos + 1] = 4;
-    packet[opt_pos + 2..opt_pos + 6].copy_from_slice(offer.ip.as_bytes());
-    opt_pos += 6;
-
-    // Server ID
-    packet[opt_pos] = OPT_SERVER_ID;
-    packet[opt_pos + 1] = 4;
-    packet[opt_pos + 2..opt_pos + 6].copy_from_slice(offer.server.as_bytes());
-    opt_pos += 6;
+    if !renewing {
+        // Requested IP
+        packet[opt_pos] = OPT_REQUESTED_IP;
+        packet[opt_pos + 1] = 4;
+        packet[opt_pos + 2..opt_pos + 6].copy_from_slice(ip.as_bytes());
+        opt_pos += 6;
+
+        // Server ID
+        packet[opt_pos] = OPT_SERVER_ID;
+        packet[opt_pos + 1] = 4;
+        packet[opt_pos + 2..opt_pos + 6].copy_from_slice(server.as_bytes());
+        opt_pos += 6;
+    }
 
     // End
     packet[opt_pos] = OPT_END;
 
-    let _ = udp::send_to(
-        DHCP_CLIENT_PORT,
-        Ipv4Address::broadcast(),
-        DHCP_SERVER_PORT,
-        &packet[..opt_pos + 1]
-    );
+    send(if renewing { server } else { Ipv4Address::broadcast() }, &packet[..opt_pos + 1]);
 
-    println!("[dhcp] Sent REQUEST for {:?}", offer.ip);
+    debug!("dhcp", "Sent REQUEST for {:?}", ip);
 
     unsafe {
-        DHCP_STATE = DhcpState::Requesting;
+        DHCP_LEASE = Some((ip, server));
+    }
+}
+
+/// Replace the pending renewal or expiry timer
+fn set_timer(timer: Option<TimerId>) {
+    let old = unsafe { core::mem::replace(&mut *core::ptr::addr_of_mut!(DHCP_TIMER), timer) };
+    if let Some(id) = old {
+        timer::cancel(id);
+    }
+}
+
+/// Renew at T1, with the rest of the lease to get an answer in
+fn schedule_renewal(lease: u32, renewal: Option<u32>) {
+    if lease == INFINITE_LEASE {
+        set_timer(None);
+        return;
+    }
+    let t1 = renewal.filter(|&t1| t1 < lease).unwrap_or(lease / 2);
+    let remaining = lease - t1;
+    set_timer(Some(timer::after(Duration::from_secs(t1 as u64), move || renew(remaining))));
+}
+
+/// Ask the server for the lease again
+fn renew(remaining: u32) {
+    let (ip, server) = match unsafe { DHCP_LEASE } {
+        Some(lease) if is_bound() => lease,
+        _ => return,
+    };
+    info!("dhcp", "Renewing the lease on {:?} with {:?}", ip, server);
+    unsafe {
+        DHCP_STATE = DhcpState::Renewing;
+    }
+    send_request(ip, server, true);
+    set_timer(Some(timer::after(Duration::from_secs(remaining as u64), expire)));
+}
+
+/// The lease is up without the server renewing it
+fn expire() {
+    if unsafe { matches!(DHCP_STATE, DhcpState::Renewing) } {
+        warn!("dhcp", "Lease ran out without being renewed");
+        start_dhcp();
     }
 }
 
@@ -234,9 +319,25 @@ struct DhcpOffer {
     dns: Ipv4Address,
 }
 
+/// Hand the replies waiting on the client socket, and who sent each, to
+/// `process_dhcp_packet`
+pub fn poll() {
+    let fd = match unsafe { DHCP_SOCKET } {
+        Some(fd) => fd,
+        None => return,
+    };
+    let mut buf = [0u8; 1500];
+    while let Ok((len, addr, port)) = socket::recvfrom(fd, &mut buf, 0) {
+        process_dhcp_packet((addr, port), &buf[..len]);
+    }
+}
+
 /// Process DHCP packet
-pub fn process_dhcp_packet(data: &[u8]) {
-    if data.len() < 240 {
+///
+/// `from` is who sent it: a server port for any message, and for an ACK
+/// the server the request went to.
+pub fn process_dhcp_packet(from: (Ipv4Address, Port), data: &[u8]) {
+    if data.len() < 240 || from.1 != DHCP_SERVER_PORT {
         return;
     }
 
@@ -246,17 +347,32 @@ pub fn process_dhcp_packet(data: &[u8]) {
         DhcpState::Selecting => {
             // Looking for DHCPOFFER
             if let Some(offer) = parse_offer(data) {
-                println!("[dhcp] Received OFFER from {:?}", offer.server);
-                send_request(&offer);
+                debug!("dhcp", "Received OFFER from {:?}", offer.server);
+                send_request(offer.ip, offer.server, false);
+                unsafe {
+                    DHCP_STATE = DhcpState::Requesting;
+                }
             }
         }
-        DhcpState::Requesting => {
+        DhcpState::Requesting | DhcpState::Renewing => {
             // Looking for DHCPACK
-            if parse_ack(data) {
-                println!("[dhcp] Received ACK - configuration complete");
+            if unsafe { DHCP_LEASE }.map_or(false, |(_, server)| server != from.0) {
+                debug!("dhcp", "Ignoring a reply from {}, not the server asked", from.0);
+                return;
+            }
+            if let Some((lease, renewal)) = parse_ack(data) {
                 unsafe {
                     DHCP_STATE = DhcpState::Bound;
                 }
+                schedule_renewal(lease, renewal);
+                if matches!(state, DhcpState::Renewing) {
+                    info!("dhcp", "Lease renewed for {}s", lease);
+                    return;
+                }
+                info!("dhcp", "Received ACK - configuration complete");
+                let ip = crate::net::get_config().ip;
+                crate::desktop::notifications::notify(
+                    "Network connected", &alloc::format!("DHCP lease acquired: {:?}", ip), '*', 5);
             }
         }
         _ => {}
@@ -351,13 +467,14 @@ fn parse_offer(data: &[u8]) -> Option<DhcpOffer> {
     })
 }
 
-/// Parse DHCP ACK
-fn parse_ack(data: &[u8]) -> bool {
+/// Parse DHCP ACK and apply it; gives the lease time and T1, if the
+/// server set one
+fn parse_ack(data: &[u8]) -> Option<(u32, Option<u32>)> {
     // Check XID
     let xid = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
     unsafe {
         if xid != DHCP_XID {
-            return false;
+            return None;
         }
     }
 
@@ -370,6 +487,9 @@ fn parse_ack(data: &[u8]) -> bool {
     let mut subnet_mask = Ipv4Address::from_octets(255, 255, 255, 0);
     let mut gateway = Ipv4Address::unspecified();
     let mut dns = Ipv4Address::unspecified();
+    // Without one the address is ours for good
+    let mut lease = INFINITE_LEASE;
+    let mut renewal = None;
 
     while pos < data.len() && data[pos] != OPT_END {
         let opt = data[pos];
@@ -383,6 +503,10 @@ fn parse_ack(data: &[u8]) -> bool {
         }
         let len = data[pos + 1] as usize;
 
+        if pos + 2 + len > data.len() {
+            break;
+        }
+
         match opt {
             OPT_MESSAGE_TYPE => {
                 message_type = data[pos + 2];
@@ -408,6 +532,12 @@ fn parse_ack(data: &[u8]) -> bool {
                     ]);
                 }
             }
+            OPT_LEASE_TIME if len == 4 => {
+                lease = u32::from_be_bytes([data[pos + 2], data[pos + 3], data[pos + 4], data[pos + 5]]);
+            }
+            OPT_RENEWAL_TIME if len == 4 => {
+                renewal = Some(u32::from_be_bytes([data[pos + 2], data[pos + 3], data[pos + 4], data[pos + 5]]));
+            }
             _ => {}
         }
 
@@ -415,7 +545,7 @@ fn parse_ack(data: &[u8]) -> bool {
     }
 
     if message_type != DHCP_ACK {
-        return false;
+        return None;
     }
 
     // Apply configuration
@@ -427,7 +557,19 @@ fn parse_ack(data: &[u8]) -> bool {
     };
     super::set_config(config);
 
-    true
+    Some((lease, renewal))
+}
+
+/// Stop DHCP, e.g. when the address is set by hand, and close the client
+/// port
+pub fn stop() {
+    unsafe {
+        DHCP_STATE = DhcpState::Idle;
+    }
+    set_timer(None);
+    if let Some(fd) = unsafe { core::mem::take(&mut *core::ptr::addr_of_mut!(DHCP_SOCKET)) } {
+        let _ = socket::close(fd);
+    }
 }
 
 /// Check if DHCP is bound
@@ -436,3 +578,86 @@ pub fn is_bound() -> bool {
         matches!(DHCP_STATE, DhcpState::Bound)
     }
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::net::udp;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// A reply of `message_type` from `server` giving `ip` for `lease` seconds
+    fn reply(message_type: u8, ip: Ipv4Address, server: Ipv4Address, lease: u32) -> Vec<u8> {
+        let mut packet = vec![0u8; 240];
+        packet[0] = 2; // BOOTREPLY
+        packet[4..8].copy_from_slice(&unsafe { DHCP_XID }.to_be_bytes());
+        packet[16..20].copy_from_slice(ip.as_bytes());
+        packet[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+        packet.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type, OPT_SERVER_ID, 4]);
+        packet.extend_from_slice(server.as_bytes());
+        packet.extend_from_slice(&[OPT_LEASE_TIME, 4]);
+        packet.extend_from_slice(&lease.to_be_bytes());
+        packet.push(OPT_END);
+        packet
+    }
+
+    /// `packet` arriving from port 67 of `from`
+    fn arrive(from: Ipv4Address, packet: &[u8]) {
+        let mut datagram = vec![0, 67, 0, 68];
+        datagram.extend_from_slice(&(8 + packet.len() as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(packet);
+        udp::process_udp_packet(from, Ipv4Address::broadcast(), &datagram);
+    }
+
+    #[kernel_test]
+    fn acks_schedule_a_renewal() -> Result<(), String> {
+        let config = crate::net::get_config();
+        let ip = Ipv4Address::from_octets(10, 0, 2, 15);
+        let server = Ipv4Address::from_octets(10, 0, 2, 2);
+        client_socket().ok_or("port 68 is in use")?;
+        unsafe {
+            DHCP_STATE = DhcpState::Renewing;
+            DHCP_LEASE = Some((ip, server));
+        }
+        let timers = timer::pending();
+
+        arrive(server, &reply(DHCP_ACK, ip, server, 3600));
+        poll();
+        let renewed = is_bound();
+        let scheduled = timer::pending();
+
+        stop();
+        unsafe { DHCP_LEASE = None; }
+        crate::net::set_config(config);
+        check!(renewed);
+        check_eq!(scheduled, timers + 1);
+        check_eq!(timer::pending(), timers);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn only_the_server_asked_may_answer() -> Result<(), String> {
+        let config = crate::net::get_config();
+        let ip = Ipv4Address::from_octets(10, 0, 2, 15);
+        let server = Ipv4Address::from_octets(10, 0, 2, 2);
+        let rogue = Ipv4Address::from_octets(10, 0, 2, 66);
+        client_socket().ok_or("port 68 is in use")?;
+        unsafe {
+            DHCP_STATE = DhcpState::Requesting;
+            DHCP_LEASE = Some((ip, server));
+        }
+
+        arrive(rogue, &reply(DHCP_ACK, Ipv4Address::from_octets(10, 0, 2, 99), rogue, 60));
+        poll();
+        let taken = is_bound();
+        let address = crate::net::get_config().ip;
+
+        stop();
+        unsafe { DHCP_LEASE = None; }
+        crate::net::set_config(config.clone());
+        check!(!taken);
+        check_eq!(address, config.ip);
+        Ok(())
+    }
+}
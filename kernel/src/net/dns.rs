@@ -1,19 +1,37 @@
 //! DNS (Domain Name System)
 //!
-//! Simple DNS client for hostname resolution.
-
+//! Simple DNS client for hostname resolution. Queries go over plain UDP
+//! to the network's DNS server, or encrypted over TLS or HTTPS to the
+//! resolver `network.dns_upstream` names when `network.dns_mode` asks
+//! for it. `network.dns_fallback` says whether a query that cannot be
+//! sent encrypted may go over UDP instead, and a caller can pass its own
+//! `Policy` to `lookup_with`.
+
+use alloc::format;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-use crate::net::{Ipv4Address, Port, udp};
-use crate::println;
+use crate::config;
+use crate::net::{http, Ipv4Address, Port, udp};
+use crate::tls::{self, TlsState};
+use crate::{debug, warn};
 
 /// DNS port
 const DNS_PORT: Port = Port::new(53);
 
+/// DNS-over-TLS port
+const DOT_PORT: Port = Port::new(853);
+
+/// Port DNS-over-HTTPS resolvers take queries on, and where
+const HTTPS_PORT: Port = Port::new(443);
+const DOH_PATH: &str = "/dns-query";
+
+/// Content type of DNS messages over HTTPS
+const DOH_MEDIA_TYPE: &str = "application/dns-message";
+
 /// DNS opcodes
 const DNS_OPCODE_QUERY: u16 = 0;
 
@@ -26,6 +44,9 @@ const DNS_TYPE_A: u16 = 1;
 /// DNS classes
 const DNS_CLASS_IN: u16 = 1;
 
+/// Compression pointers followed in one name
+const MAX_POINTERS: usize = 16;
+
 /// DNS header
 #[repr(C)]
 struct DnsHeader {
@@ -75,7 +96,8 @@ struct DnsQuery {
 
 lazy_static! {
     static ref DNS_QUERIES: Mutex<Vec<DnsQuery>> = Mutex::new(Vec::new());
-    static ref DNS_CACHE: Mutex<Vec<(String, Ipv4Address, u64)>> = Mutex::new(Vec::new());
+    /// Name, address, when it was looked up and whether it came encrypted
+    static ref DNS_CACHE: Mutex<Vec<(String, Ipv4Address, u64, bool)>> = Mutex::new(Vec::new());
     static ref NEXT_QUERY_ID: Mutex<u16> = Mutex::new(1);
 }
 
@@ -98,6 +120,8 @@ fn decode_name(data: &[u8], offset: usize) -> (String, usize) {
     let mut pos = offset;
     let mut jumped = false;
     let mut jump_offset = 0;
+    // Pointers may point back at themselves; a real name needs few
+    let mut jumps = 0;
 
     loop {
         if pos >= data.len() {
@@ -113,6 +137,11 @@ fn decode_name(data: &[u8], offset: usize) -> (String, usize) {
 
         if len & 0xC0 == 0xC0 {
             // Compression pointer
+            if pos + 1 >= data.len() || jumps == MAX_POINTERS {
+                pos = data.len();
+                break;
+            }
+            jumps += 1;
             if !jumped {
                 jump_offset = pos + 2;
             }
@@ -135,33 +164,109 @@ fn decode_name(data: &[u8], offset: usize) -> (String, usize) {
     (result, if jumped { jump_offset } else { pos })
 }
 
-/// Lookup hostname
-pub fn lookup(hostname: &str) -> Option<Ipv4Address> {
-    let config = super::get_config();
-    if !config.is_configured() || config.dns.as_u32() == 0 {
-        println!("[dns] No DNS server configured");
-        return None;
+/// How queries reach a resolver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Plain UDP to the network's DNS server
+    Udp,
+    /// DNS-over-TLS (RFC 7858) to the upstream resolver
+    Tls,
+    /// DNS-over-HTTPS (RFC 8484) to the upstream resolver
+    Https,
+}
+
+impl Transport {
+    pub const ALL: [Transport; 3] = [Transport::Udp, Transport::Tls, Transport::Https];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+        }
     }
 
-    // Check cache
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+}
+
+/// How a single query may be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Plain,
+    /// Encrypted, or over plain UDP if that fails
+    PreferEncrypted(Transport),
+    /// Encrypted or not at all
+    RequireEncrypted(Transport),
+}
+
+impl Policy {
+    /// The policy `network.dns_mode` and `network.dns_fallback` ask for
+    pub fn configured() -> Self {
+        let transport = config::get("network.dns_mode")
+            .and_then(|mode| Transport::from_name(&mode))
+            .unwrap_or(Transport::Udp);
+        let fallback = config::get("network.dns_fallback").as_deref() != Some("off");
+        match (transport, fallback) {
+            (Transport::Udp, _) => Policy::Plain,
+            (transport, true) => Policy::PreferEncrypted(transport),
+            (transport, false) => Policy::RequireEncrypted(transport),
+        }
+    }
+
+    fn encrypted(self) -> bool {
+        self != Policy::Plain
+    }
+}
+
+/// Lookup hostname as the settings ask
+pub fn lookup(hostname: &str) -> Option<Ipv4Address> {
+    lookup_with(hostname, Policy::configured())
+}
+
+/// Lookup hostname, sending the query as `policy` allows
+///
+/// Answers are cached; one that came over plain UDP does not satisfy a
+/// query that must be encrypted.
+pub fn lookup_with(hostname: &str, policy: Policy) -> Option<Ipv4Address> {
     {
         let cache = DNS_CACHE.lock();
-        for (name, ip, _) in cache.iter() {
-            if name.eq_ignore_ascii_case(hostname) {
-                return Some(*ip);
-            }
+        let cached = cache.iter()
+            .find(|(name, _, _, encrypted)| name.eq_ignore_ascii_case(hostname) && (*encrypted || !policy.encrypted()));
+        if let Some((_, ip, _, _)) = cached {
+            return Some(*ip);
         }
     }
 
-    // Bind DNS client port
-    let _ = udp::bind(Port::new(12345));
-
-    // Build query
     let mut query_id = NEXT_QUERY_ID.lock();
     let id = *query_id;
     *query_id = id.wrapping_add(1);
     drop(query_id);
+    let query = build_query(id, hostname);
+
+    let (ip, encrypted) = match policy {
+        Policy::Plain => (exchange_udp(&query, id, hostname)?, false),
+        Policy::PreferEncrypted(transport) | Policy::RequireEncrypted(transport) => {
+            match exchange_encrypted(transport, &query).and_then(|reply| parse_response(&reply, id).ok_or("no address in reply")) {
+                Ok(ip) => (ip, true),
+                Err(reason) if matches!(policy, Policy::PreferEncrypted(_)) => {
+                    warn!("dns", "{} over {} failed ({}), asking over UDP", hostname, transport.name(), reason);
+                    (exchange_udp(&query, id, hostname)?, false)
+                }
+                Err(reason) => {
+                    warn!("dns", "{} over {} failed ({}); plain DNS is not allowed", hostname, transport.name(), reason);
+                    return None;
+                }
+            }
+        }
+    };
+    DNS_CACHE.lock().push((String::from(hostname), ip, crate::drivers::timer::elapsed_ms(), encrypted));
+    Some(ip)
+}
 
+/// A query for the A record of `hostname`
+fn build_query(id: u16, hostname: &str) -> Vec<u8> {
     let header = DnsHeader {
         id,
         flags: 0x0100, // Standard query, recursion desired
@@ -181,14 +286,26 @@ pub fn lookup(hostname: &str) -> Option<Ipv4Address> {
     query[12 + name.len()..12 + name.len() + 2].copy_from_slice(&DNS_TYPE_A.to_be_bytes());
     // QCLASS: IN
     query[12 + name.len() + 2..12 + name.len() + 4].copy_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    query
+}
+
+/// Send a query to the network's DNS server over UDP and wait for the
+/// answer
+fn exchange_udp(query: &[u8], id: u16, hostname: &str) -> Option<Ipv4Address> {
+    let config = super::get_config();
+    if !config.is_configured() || config.dns.as_u32() == 0 {
+        warn!("dns", "No DNS server configured");
+        return None;
+    }
+
+    // Bind DNS client port
+    let _ = udp::bind(Port::new(12345));
 
     // Send query
-    if udp::send_to(Port::new(12345), config.dns, DNS_PORT, &query).is_err() {
+    if udp::send_to(Port::new(12345), config.dns, DNS_PORT, query).is_err() {
         return None;
     }
 
-    // Wait for response (simplified - should poll)
-    // For now, register query and return None
     DNS_QUERIES.lock().push(DnsQuery {
         id,
         name: String::from(hostname),
@@ -201,14 +318,13 @@ pub fn lookup(hostname: &str) -> Option<Ipv4Address> {
     let start = crate::drivers::timer::elapsed_ms();
     
     while crate::drivers::timer::elapsed_ms() - start < 5000 {
-        if let Some((_, _, len)) = udp::receive_from(Port::new(12345), &mut buf) {
+        if let Some((from, from_port, len)) = udp::receive_from(Port::new(12345), &mut buf) {
+            // Only the server asked can answer
+            if from != config.dns || from_port != DNS_PORT {
+                debug!("dns", "Ignoring a reply from {}:{}", from, from_port.as_u16());
+                continue;
+            }
             if let Some(ip) = parse_response(&buf[..len], id) {
-                // Cache result
-                DNS_CACHE.lock().push((
-                    String::from(hostname),
-                    ip,
-                    crate::drivers::timer::elapsed_ms()
-                ));
                 return Some(ip);
             }
         }
@@ -217,8 +333,52 @@ pub fn lookup(hostname: &str) -> Option<Ipv4Address> {
     None
 }
 
+/// The resolver `network.dns_upstream` names for encrypted queries
+fn upstream() -> Option<Ipv4Address> {
+    config::get("network.dns_upstream").and_then(|value| Ipv4Address::parse(&value))
+}
+
+/// A query as sent over a TLS stream: its length, then the query
+fn frame_tcp(query: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(2 + query.len());
+    framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    framed.extend_from_slice(query);
+    framed
+}
+
+/// A query as a DNS-over-HTTPS POST to `upstream`
+fn doh_request(upstream: Ipv4Address, query: &[u8]) -> Option<Vec<u8>> {
+    let url = format!("https://{}{}", upstream, DOH_PATH);
+    let mut request = http::Request::post(&url, query.to_vec()).ok()?;
+    request.header("Content-Type", DOH_MEDIA_TYPE).header("Accept", DOH_MEDIA_TYPE);
+    Some(request.to_bytes())
+}
+
+/// Send a query to the upstream resolver over TLS or HTTPS and return
+/// the reply
+///
+/// The TLS client does not complete a handshake yet, so this fails
+/// rather than let the query go out in the clear; the policy decides
+/// whether plain UDP is asked instead.
+fn exchange_encrypted(transport: Transport, query: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let upstream = upstream().ok_or("network.dns_upstream is not an address")?;
+    let message = match transport {
+        Transport::Tls => frame_tcp(query),
+        Transport::Https => doh_request(upstream, query).ok_or("cannot build the request")?,
+        Transport::Udp => return Err("not an encrypted transport"),
+    };
+    let port = if transport == Transport::Tls { DOT_PORT } else { HTTPS_PORT };
+    let conn = tls::connect(&format!("{}:{}", upstream, port.as_u16())).map_err(|_| "TLS connection failed")?;
+    if conn.state() != TlsState::Connected {
+        return Err("TLS handshake not supported");
+    }
+    // No record layer to carry `message` over yet
+    let _ = message;
+    Err("TLS records not supported")
+}
+
 /// Parse DNS response
-fn parse_response(data: &[u8], expected_id: u16) -> Option<Ipv4Address> {
+pub fn parse_response(data: &[u8], expected_id: u16) -> Option<Ipv4Address> {
     let header = DnsHeader::from_bytes(data)?;
 
     if header.id != expected_id {
@@ -305,3 +465,36 @@ fn parse_ipv4(s: &str) -> Option<Ipv4Address> {
 
     Some(Ipv4Address::new(bytes))
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn tls_queries_carry_their_length() -> Result<(), String> {
+        let query = build_query(7, "example.com");
+        let framed = frame_tcp(&query);
+        check_eq!(u16::from_be_bytes([framed[0], framed[1]]) as usize, query.len());
+        check_eq!(&framed[2..], &query[..]);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn required_encryption_never_falls_back() -> Result<(), String> {
+        let policy = Policy::RequireEncrypted(Transport::Tls);
+        check!(lookup_with("dns-test.invalid", policy).is_none());
+        check!(DNS_CACHE.lock().iter().all(|(name, ..)| name != "dns-test.invalid"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn transports_round_trip_their_names() -> Result<(), String> {
+        for transport in Transport::ALL {
+            check_eq!(Transport::from_name(transport.name()), Some(transport));
+        }
+        check_eq!(Transport::from_name("quic"), None);
+        Ok(())
+    }
+}
@@ -2,16 +2,16 @@
 //!
 //! VirtIO network device driver implementation.
 
-use crate::println;
+use crate::info;
 
 pub mod virtio_net;
 
 /// Initialize network drivers
 pub fn init() {
-    println!("[net/drivers] Initializing network drivers...");
+    info!("net/drivers", "Initializing network drivers...");
 
     // Try to initialize VirtIO net
     virtio_net::init();
 
-    println!("[net/drivers] Network drivers initialized");
+    info!("net/drivers", "Network drivers initialized");
 }
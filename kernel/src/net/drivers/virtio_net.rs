@@ -11,9 +11,10 @@ use spin::Mutex;
 
 use crate::net::{MacAddress, NetworkInterface, NetError};
 use crate::net;
-use crate::drivers::pci::{read_config8, read_config16, read_config32};
+use crate::arch::simd;
+use crate::drivers::pci;
 use crate::mm::{phys_to_virt, virt_to_phys_u64};
-use crate::println;
+use crate::{info, warn};
 
 /// VirtIO PCI device IDs
 const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
@@ -411,11 +412,7 @@ impl NetworkInterface for VirtioNetDevice {
         unsafe {
             // Copy data to transmit buffer (after virtio_net_hdr)
             const HDR_SIZE: usize = 12; // sizeof(struct virtio_net_hdr)
-            core::ptr::copy_nonoverlapping(
-                data.as_ptr(),
-                (tx_buf.1).add(HDR_SIZE),
-                data.len()
-            );
+            simd::memcpy((tx_buf.1).add(HDR_SIZE), data.as_ptr(), data.len());
 
             // Clear virtio header
             core::ptr::write_bytes(tx_buf.1, 0, HDR_SIZE);
@@ -451,11 +448,7 @@ impl NetworkInterface for VirtioNetDevice {
                 let copy_len = data_len.min(buf.len());
 
                 unsafe {
-                    core::ptr::copy_nonoverlapping(
-                        virt.add(hdr_size),
-                        buf.as_mut_ptr(),
-                        copy_len
-                    );
+                    simd::memcpy(buf.as_mut_ptr(), virt.add(hdr_size), copy_len);
                 }
 
                 // Re-add buffer to queue
@@ -478,72 +471,45 @@ impl NetworkInterface for VirtioNetDevice {
     }
 }
 
+static DRIVER: pci::PciDriver = pci::PciDriver {
+    name: "virtio-net",
+    matches: &[pci::DeviceMatch::id(VIRTIO_VENDOR_ID, VIRTIO_NET_DEVICE_ID)],
+    probe,
+};
+
 /// Initialize VirtIO network driver
 pub fn init() {
-    // Scan PCI for VirtIO network device
-    if let Some(device) = find_virtio_net_device() {
-        println!("[virtio-net] Found device at {:08X}", device.base_addr);
-        
-        if let Some(net_dev) = VirtioNetDevice::new(device.base_addr) {
+    pci::register_driver(&DRIVER);
+}
+
+/// Set up a device and register it with the network stack
+fn probe(device: &pci::PciDevice) -> bool {
+    // Read BAR0 for base address
+    let bar0 = device.bars[0];
+    let base_addr = if bar0 & 1 == 0 {
+        // Memory mapped
+        bar0 & 0xFFFFFFF0
+    } else {
+        // I/O mapped
+        (bar0 & 0xFFFFFFFC) | 0x80000000 // Mark as I/O
+    };
+    info!("virtio-net", "Found device at {:08X}", base_addr);
+
+    match VirtioNetDevice::new(base_addr) {
+        Some(net_dev) => {
             let mac = net_dev.mac_address();
             let mac_str = mac.format();
             let mac_str = core::str::from_utf8(&mac_str).unwrap_or("?");
             
-            println!("[virtio-net] MAC: {}", mac_str);
+            info!("virtio-net", "MAC: {}", mac_str);
             
             // Register with network stack
             net::register_interface(Box::new(net_dev));
-        } else {
-            println!("[virtio-net] Failed to initialize device");
+            true
         }
-    }
-}
-
-/// PCI device info
-struct PciDevice {
-    bus: u8,
-    slot: u8,
-    func: u8,
-    vendor: u16,
-    device: u16,
-    base_addr: u32,
-}
-
-/// Find VirtIO network device on PCI bus
-fn find_virtio_net_device() -> Option<PciDevice> {
-    // Scan all PCI buses (simplified)
-    for bus in 0..256u16 {
-        for slot in 0..32u8 {
-            let vendor = read_config32(bus as u8, slot, 0, 0) as u16;
-            
-            if vendor == 0xFFFF || vendor != VIRTIO_VENDOR_ID {
-                continue;
-            }
-
-            let device = (read_config32(bus as u8, slot, 0, 0) >> 16) as u16;
-            
-            if device == VIRTIO_NET_DEVICE_ID {
-                // Read BAR0 for base address
-                let bar0 = read_config32(bus as u8, slot, 0, 0x10);
-                let base_addr = if bar0 & 1 == 0 {
-                    // Memory mapped
-                    bar0 & 0xFFFFFFF0
-                } else {
-                    // I/O mapped
-                    (bar0 & 0xFFFFFFFC) | 0x80000000 // Mark as I/O
-                };
-
-                return Some(PciDevice {
-                    bus: bus as u8,
-                    slot,
-                    func: 0,
-                    vendor,
-                    device,
-                    base_addr,
-                });
-            }
+        None => {
+            warn!("virtio-net", "Failed to initialize device");
+            false
         }
     }
-
-    None
 }
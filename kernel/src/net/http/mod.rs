@@ -9,10 +9,11 @@ use alloc::collections::BTreeMap;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-use crate::net::{Ipv4Address, Port, tcp, socket};
+use crate::net::{Ipv4Address, Port, tcp, socket, socks};
 use crate::net::socket::{Socket, SocketDomain, SocketType, SocketProtocol};
 use crate::tls::{TlsConnection, TlsError};
 use crate::println;
+use crate::{info, warn};
 
 /// HTTP methods
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -173,10 +174,42 @@ pub struct Response {
 impl Response {
     /// Parse response from bytes
     pub fn parse(data: &[u8]) -> Result<(Self, usize), HttpError> {
+        let (mut response, body_start) = Self::parse_head(data)?.ok_or(HttpError::InvalidResponse)?;
+        let headers = &response.headers;
+        
+        // Check for Content-Length
+        let body = if let Some(len_str) = headers.get("content-length") {
+            let content_len: usize = len_str.parse().map_err(|_| HttpError::InvalidResponse)?;
+            if data.len() - body_start >= content_len {
+                data[body_start..body_start + content_len].to_vec()
+            } else {
+                // Incomplete body
+                Vec::new()
+            }
+        } else if headers.get("transfer-encoding").map(|v| v == "chunked").unwrap_or(false) {
+            // Handle chunked encoding (simplified)
+            Self::decode_chunked(&data[body_start..])?
+        } else {
+            // Read rest of data
+            data[body_start..].to_vec()
+        };
+        
+        let body_len = body.len();
+        response.body = body;
+        
+        Ok((response, body_start + body_len))
+    }
+    
+    /// Parse the status line and headers, leaving the body empty
+    ///
+    /// Returns the response and where its body starts, or `None` if the
+    /// headers have not all arrived yet.
+    pub fn parse_head(data: &[u8]) -> Result<Option<(Self, usize)>, HttpError> {
         // Find end of headers
-        let header_end = data.windows(4)
-            .position(|w| w == b"\r\n\r\n")
-            .ok_or(HttpError::InvalidResponse)?;
+        let header_end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None => return Ok(None),
+        };
         
         let header_data = &data[..header_end];
         let body_start = header_end + 4;
@@ -204,7 +237,7 @@ impl Response {
         let status_text = parts[2..].join(" ");
         
         // Parse headers
-        let mut headers = BTreeMap::new();
+        let mut headers: BTreeMap<String, String> = BTreeMap::new();
         let header_lines = core::str::from_utf8(&header_data[status_line_end + 1..])
             .map_err(|_| HttpError::InvalidResponse)?;
         
@@ -212,36 +245,27 @@ impl Response {
             if let Some(pos) = line.find(':') {
                 let name = line[..pos].trim().to_lowercase();
                 let value = line[pos + 1..].trim().to_string();
-                headers.insert(name, value);
+                // Cookies cannot be joined with commas, which dates in
+                // them contain, so each `Set-Cookie` gets a line of its own
+                match headers.get_mut(&name) {
+                    Some(existing) if name == "set-cookie" => {
+                        existing.push('\n');
+                        existing.push_str(&value);
+                    }
+                    _ => {
+                        headers.insert(name, value);
+                    }
+                }
             }
         }
         
-        // Check for Content-Length
-        let body = if let Some(len_str) = headers.get("content-length") {
-            let content_len: usize = len_str.parse().map_err(|_| HttpError::InvalidResponse)?;
-            if data.len() >= body_start + content_len {
-                data[body_start..body_start + content_len].to_vec()
-            } else {
-                // Incomplete body
-                Vec::new()
-            }
-        } else if headers.get("transfer-encoding").map(|v| v == "chunked").unwrap_or(false) {
-            // Handle chunked encoding (simplified)
-            Self::decode_chunked(&data[body_start..])?
-        } else {
-            // Read rest of data
-            data[body_start..].to_vec()
-        };
-        
-        let body_len = body.len();
-        
-        Ok((Self {
+        Ok(Some((Self {
             version,
             status,
             status_text,
             headers,
-            body,
-        }, body_start + body_len))
+            body: Vec::new(),
+        }, body_start)))
     }
     
     /// Decode chunked transfer encoding
@@ -269,11 +293,11 @@ impl Response {
             pos += line_end + 1;
             
             // Copy chunk data
-            if pos + chunk_size > data.len() {
+            if chunk_size > data.len() - pos {
                 return Err(HttpError::InvalidResponse);
             }
             result.extend_from_slice(&data[pos..pos + chunk_size]);
-            pos += chunk_size + 2; // Skip CRLF
+            pos = (pos + chunk_size + 2).min(data.len()); // Skip CRLF
         }
         
         Ok(result)
@@ -373,17 +397,15 @@ impl Client {
     
     /// Send HTTP request (plaintext)
     fn request_http(&self, req: &Request) -> Result<Response, HttpError> {
-        // Resolve host
-        let ip = resolve_host(&req.url.host)?;
-        
-        // Create socket
-        let fd = socket::socket(SocketDomain::Inet, SocketType::Stream, SocketProtocol::Tcp)
-            .map_err(|_| HttpError::ConnectionFailed)?;
-        
-        // Connect
-        let addr = crate::net::SocketAddr::new_v4(ip, Port::new(req.url.port));
-        socket::connect(fd, ip, Port::new(req.url.port))
-            .map_err(|_| HttpError::ConnectionFailed)?;
+        // Connect, through the proxy if there is one
+        let (fd, handshake) = dial(&req.url.host, req.url.port)?;
+        if let Some(mut handshake) = handshake {
+            if let Err(e) = socks::tunnel(fd, &mut handshake, PROXY_TIMEOUT_MS) {
+                warn!("http", "Proxy: {}", e);
+                let _ = socket::close(fd);
+                return Err(if e == socks::SocksError::Timeout { HttpError::Timeout } else { HttpError::ConnectionFailed });
+            }
+        }
         
         // Send request
         let request_data = req.to_bytes();
@@ -426,7 +448,7 @@ impl Client {
         let ip = resolve_host(&req.url.host)?;
         
         // Create TLS connection
-        let mut tls = TlsConnection::new();
+        let mut tls = TlsConnection::for_host(&req.url.host);
         
         // Create socket
         let fd = socket::socket(SocketDomain::Inet, SocketType::Stream, SocketProtocol::Tcp)
@@ -437,29 +459,39 @@ impl Client {
             .map_err(|_| HttpError::ConnectionFailed)?;
         
         // Send Client Hello
-        let client_hello = tls.generate_client_hello();
+        let client_hello = crate::tls::record(crate::tls::ContentType::Handshake, &tls.generate_client_hello());
         socket::send(fd, &client_hello, 0)
             .map_err(|_| HttpError::ConnectionFailed)?;
         
-        // Receive Server Hello (simplified - would need proper TLS handshake)
+        // Take the server's flight up to its Finished (simplified - the
+        // client's Finished and application keys are still to come)
         let mut buffer = [0u8; 4096];
         let mut handshake_data = Vec::new();
         
-        for _ in 0..10 {
+        'handshake: for _ in 0..10 {
             match socket::recv(fd, &mut buffer, 0) {
-                Ok(n) if n > 0 => {
-                    handshake_data.extend_from_slice(&buffer[..n]);
-                    if tls.state() == crate::tls::TlsState::Connected {
-                        break;
+                Ok(n) if n > 0 => handshake_data.extend_from_slice(&buffer[..n]),
+                _ => break,
+            }
+            while let Some(len) = crate::tls::record_len(&handshake_data) {
+                let record: Vec<u8> = handshake_data.drain(..len).collect();
+                match tls.process_record(&record) {
+                    // An untrusted or wrongly pinned server, or one that
+                    // cannot prove it holds its certificate's key, gets nothing
+                    Err(crate::tls::TlsError::CertificateError | crate::tls::TlsError::DecryptError) => {
+                        let _ = socket::close(fd);
+                        return Err(HttpError::TlsError);
                     }
+                    Err(_) => break 'handshake,
+                    Ok(()) if tls.state() == crate::tls::TlsState::FinishedReceived => break 'handshake,
+                    Ok(()) => {}
                 }
-                _ => break,
             }
         }
         
         // For now, fall back to HTTP (TLS not fully implemented)
         // In production, this would complete the TLS handshake
-        println!("[http] HTTPS connection not yet fully implemented, falling back to HTTP");
+        warn!("http", "HTTPS connection not yet fully implemented, falling back to HTTP");
         let _ = socket::close(fd);
         
         // Try HTTP instead
@@ -488,6 +520,230 @@ impl Default for Client {
     }
 }
 
+/// How long a `Stream` waits for the server to say anything
+const STREAM_TIMEOUT_MS: u64 = 30000;
+
+/// How the end of a response body is found
+#[derive(Debug, Clone, Copy)]
+enum Framing {
+    /// This many bytes are still to come
+    Length(usize),
+    /// Chunked; bytes left of the current chunk and the line break after
+    /// it, or 0 when a chunk size line comes next
+    Chunked(usize),
+    /// The body runs until the server closes the connection
+    Close,
+}
+
+/// A response read as it arrives, without waiting for all of it
+///
+/// Opening a stream only starts the connection. Each `poll` sends the
+/// request once the connection is up and takes in whatever has arrived
+/// since, so a caller can show a large response piece by piece, polling
+/// between other work. Redirects are not followed.
+pub struct Stream {
+    fd: usize,
+    /// Opening the tunnel through the proxy, until it is open
+    tunnel: Option<socks::Handshake>,
+    /// The request, until it has been sent
+    request: Vec<u8>,
+    /// The response has no body whatever its headers say
+    head_only: bool,
+    /// Received bytes not decoded yet
+    input: Vec<u8>,
+    head: Option<Response>,
+    framing: Framing,
+    /// Body bytes decoded and not yet taken
+    body: Vec<u8>,
+    done: bool,
+    /// When to give up if nothing more arrives, in milliseconds since boot
+    deadline: u64,
+}
+
+impl Stream {
+    /// Connect to the server and get ready to send `req`
+    pub fn open(req: &Request) -> Result<Self, HttpError> {
+        let mut req = req.clone();
+        if req.url.is_https() {
+            warn!("http", "HTTPS connection not yet fully implemented, falling back to HTTP");
+            req.url.scheme = "http".to_string();
+            req.url.port = 80;
+        }
+        let (fd, tunnel) = dial(&req.url.host, req.url.port)?;
+        Ok(Self {
+            fd,
+            tunnel,
+            request: req.to_bytes(),
+            head_only: req.method == Method::Head,
+            input: Vec::new(),
+            head: None,
+            framing: Framing::Close,
+            body: Vec::new(),
+            done: false,
+            deadline: crate::drivers::timer::elapsed_ms() + STREAM_TIMEOUT_MS,
+        })
+    }
+
+    /// Status and headers, once they have arrived; the body is left empty
+    pub fn head(&self) -> Option<&Response> {
+        self.head.as_ref()
+    }
+
+    /// The body that has arrived since the last call
+    pub fn take_body(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.body)
+    }
+
+    /// Whether the whole response has arrived
+    pub fn finished(&self) -> bool {
+        self.done
+    }
+
+    /// Send the request if it can go now and take in what has arrived;
+    /// never waits
+    pub fn poll(&mut self) -> Result<(), HttpError> {
+        if self.done {
+            return Ok(());
+        }
+        let now = crate::drivers::timer::elapsed_ms();
+        let id = socket::get_socket(self.fd).and_then(|s| s.tcp_id).ok_or(HttpError::ConnectionFailed)?;
+        // Read before receiving: data that came with the server closing
+        // the connection is then in the buffer
+        let state = tcp::state(id);
+        if !self.request.is_empty() {
+            match state {
+                Some(tcp::TcpState::SynSent) => {
+                    return if now > self.deadline { Err(HttpError::Timeout) } else { Ok(()) };
+                }
+                Some(tcp::TcpState::Established) => {
+                    if let Some(handshake) = self.tunnel.as_mut() {
+                        match socks::step(self.fd, handshake) {
+                            Ok(true) => self.tunnel = None,
+                            Ok(false) => {
+                                return if now > self.deadline { Err(HttpError::Timeout) } else { Ok(()) };
+                            }
+                            Err(e) => {
+                                warn!("http", "Proxy: {}", e);
+                                return Err(HttpError::ConnectionFailed);
+                            }
+                        }
+                    }
+                    // What the send buffer has no room for goes on a later poll
+                    let sent = match socket::send(self.fd, &self.request, 0) {
+                        Ok(sent) => sent,
+                        Err(socket::NetError::WouldBlock) => 0,
+                        Err(_) => return Err(HttpError::ConnectionFailed),
+                    };
+                    self.request.drain(..sent);
+                }
+                _ => return Err(HttpError::ConnectionFailed),
+            }
+        }
+
+        let mut buffer = [0u8; 4096];
+        while let Ok(n) = socket::recv(self.fd, &mut buffer, 0) {
+            if n == 0 {
+                break;
+            }
+            self.input.extend_from_slice(&buffer[..n]);
+            self.deadline = now + STREAM_TIMEOUT_MS;
+        }
+        while self.head.is_none() {
+            let (head, body_start) = match Response::parse_head(&self.input)? {
+                Some(parsed) => parsed,
+                None => break,
+            };
+            self.input.drain(..body_start);
+            // An interim response such as `100 Continue` comes before the
+            // real one
+            if (100..200).contains(&head.status) {
+                continue;
+            }
+            self.framing = if self.head_only || matches!(head.status, 204 | 304) {
+                Framing::Length(0)
+            } else if head.headers.get("transfer-encoding").map_or(false, |v| v.eq_ignore_ascii_case("chunked")) {
+                Framing::Chunked(0)
+            } else if let Some(length) = head.headers.get("content-length") {
+                Framing::Length(length.trim().parse().map_err(|_| HttpError::InvalidResponse)?)
+            } else {
+                Framing::Close
+            };
+            self.head = Some(head);
+        }
+        if self.head.is_some() {
+            self.decode()?;
+        }
+
+        let open = matches!(state, Some(tcp::TcpState::Established | tcp::TcpState::FinWait1 | tcp::TcpState::FinWait2));
+        if !self.done && !open {
+            if self.head.is_some() && matches!(self.framing, Framing::Close) {
+                self.done = true;
+            } else {
+                // The server hung up part way through
+                return Err(HttpError::InvalidResponse);
+            }
+        }
+        if !self.done && now > self.deadline {
+            return Err(HttpError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Move what can be decoded of the input into the body
+    fn decode(&mut self) -> Result<(), HttpError> {
+        loop {
+            match self.framing {
+                Framing::Length(left) => {
+                    let n = left.min(self.input.len());
+                    self.body.extend(self.input.drain(..n));
+                    self.framing = Framing::Length(left - n);
+                    self.done = left == n;
+                    return Ok(());
+                }
+                Framing::Close => {
+                    self.body.append(&mut self.input);
+                    return Ok(());
+                }
+                Framing::Chunked(0) => {
+                    let line_end = match self.input.iter().position(|&b| b == b'\n') {
+                        Some(end) => end,
+                        None => return Ok(()),
+                    };
+                    let line = core::str::from_utf8(&self.input[..line_end]).map_err(|_| HttpError::InvalidResponse)?;
+                    // Chunk extensions after `;` are ignored
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = usize::from_str_radix(size, 16).map_err(|_| HttpError::InvalidResponse)?;
+                    self.input.drain(..=line_end);
+                    if size == 0 {
+                        // Trailers after the last chunk are ignored
+                        self.done = true;
+                        return Ok(());
+                    }
+                    self.framing = Framing::Chunked(size + 2);
+                }
+                Framing::Chunked(left) => {
+                    if self.input.is_empty() {
+                        return Ok(());
+                    }
+                    // The last two bytes are the line break after the data
+                    let data = (left - 2.min(left)).min(self.input.len());
+                    self.body.extend(self.input.drain(..data));
+                    let rest = left - data;
+                    let newline = if rest <= 2 { rest.min(self.input.len()) } else { 0 };
+                    self.input.drain(..newline);
+                    self.framing = Framing::Chunked(rest - newline);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = socket::close(self.fd);
+    }
+}
+
 /// HTTP error types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpError {
@@ -501,6 +757,40 @@ pub enum HttpError {
     Unknown = 255,
 }
 
+/// How long a blocking request waits for the proxy to open a tunnel
+const PROXY_TIMEOUT_MS: u64 = 10_000;
+
+/// Start connecting to `host`:`port`, or to the proxy in `network.proxy`
+/// if one is set
+///
+/// With a proxy, the handshake that opens the tunnel is returned too and
+/// must be carried out before the request is sent.
+fn dial(host: &str, port: u16) -> Result<(usize, Option<socks::Handshake>), HttpError> {
+    let (ip, port, handshake) = match socks::Proxy::configured() {
+        Some(proxy) => {
+            let opened = proxy.address().and_then(|ip| {
+                let handshake = socks::Handshake::new(&proxy, &proxy.destination(host)?, port)?;
+                Ok((ip, handshake))
+            });
+            match opened {
+                Ok((ip, handshake)) => (ip, proxy.port, Some(handshake)),
+                Err(e) => {
+                    warn!("http", "Proxy {}: {}", proxy, e);
+                    return Err(HttpError::ConnectionFailed);
+                }
+            }
+        }
+        None => (resolve_host(host)?, port, None),
+    };
+    let fd = socket::socket(SocketDomain::Inet, SocketType::Stream, SocketProtocol::Tcp)
+        .map_err(|_| HttpError::ConnectionFailed)?;
+    if socket::connect(fd, ip, Port::new(port)).is_err() {
+        let _ = socket::close(fd);
+        return Err(HttpError::ConnectionFailed);
+    }
+    Ok((fd, handshake))
+}
+
 /// Resolve hostname to IP
 fn resolve_host(host: &str) -> Result<Ipv4Address, HttpError> {
     // Check if it's already an IP address
@@ -552,10 +842,16 @@ pub fn post(url: &str, body: Vec<u8>) -> Result<Response, HttpError> {
     HTTP_CLIENT.post(url, body)
 }
 
+/// Send a request without following redirects, for callers that check
+/// where each one leads
+pub fn request_once(req: &Request) -> Result<Response, HttpError> {
+    Client { follow_redirects: false, ..Client::new() }.request(req)
+}
+
 /// Initialize HTTP client
 pub fn init() {
-    println!("[http] HTTP/HTTPS client initialized");
-    println!("[http] Supported: HTTP/1.1, HTTPS (TLS 1.3 partial)");
+    info!("http", "HTTP/HTTPS client initialized");
+    info!("http", "Supported: HTTP/1.1, HTTPS (TLS 1.3 partial)");
 }
 
 /// Print HTTP response
@@ -0,0 +1,169 @@
+//! Static web server
+//!
+//! Run as the `httpd` service: it listens on the definition's `port`, 80
+//! unless set, and answers `GET` and `HEAD` with files under its `root`,
+//! `/var/www` unless set, `index.html` standing for a directory. Each
+//! connection gets one response and is closed.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::{self, FileType};
+use crate::net::tcp::{self, ConnectionId};
+use crate::net::Port;
+use crate::service::Definition;
+use crate::{debug, info};
+
+/// Largest request header read before giving up on a client
+const MAX_REQUEST: usize = 8192;
+
+struct Server {
+    port: Port,
+    root: String,
+    /// Connections still sending their request, and what came so far
+    clients: Vec<(ConnectionId, Vec<u8>)>,
+}
+
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+pub fn start(definition: &Definition) -> Result<(), String> {
+    let port = definition.option("port").unwrap_or("80");
+    let port = port.parse().map(Port::new).map_err(|_| format!("'{}' is not a port", port))?;
+    let root = String::from(definition.option("root").unwrap_or("/var/www").trim_end_matches('/'));
+    match fs::metadata(&root) {
+        Ok(metadata) if metadata.file_type == FileType::Directory => {}
+        _ => return Err(format!("{} is not a directory", root)),
+    }
+    tcp::listen(port).map_err(|_| String::from("cannot listen"))?;
+    info!("httpd", "Serving {} on port {}", root, port.as_u16());
+    *SERVER.lock() = Some(Server { port, root, clients: Vec::new() });
+    Ok(())
+}
+
+/// Take in requests and answer those that are complete
+pub fn poll() -> Result<(), String> {
+    let mut guard = SERVER.lock();
+    let server = match guard.as_mut() {
+        Some(server) => server,
+        None => return Err(String::from("not running")),
+    };
+    for id in tcp::established(server.port) {
+        if !server.clients.iter().any(|(client, _)| *client == id) {
+            server.clients.push((id, Vec::new()));
+        }
+    }
+    let root = server.root.clone();
+    server.clients.retain_mut(|(id, request)| {
+        let mut buf = [0u8; 1024];
+        while let Ok(n @ 1..) = tcp::receive(*id, &mut buf) {
+            request.extend_from_slice(&buf[..n]);
+        }
+        let complete = request.windows(4).any(|w| w == b"\r\n\r\n");
+        if !complete && request.len() < MAX_REQUEST {
+            // Keep waiting while the connection is up
+            return tcp::state(*id).is_some();
+        }
+        let response = respond(request, &root);
+        let _ = tcp::send(*id, &response);
+        let _ = tcp::close(*id);
+        false
+    });
+    Ok(())
+}
+
+pub fn stop() {
+    if let Some(server) = SERVER.lock().take() {
+        tcp::unlisten(server.port);
+        for (id, _) in server.clients {
+            let _ = tcp::close(id);
+        }
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("png") => "image/png",
+        Some("ppm") => "image/x-portable-pixmap",
+        _ => "application/octet-stream",
+    }
+}
+
+fn response(status: &str, content_type: &str, body: &[u8], head: bool) -> Vec<u8> {
+    let mut out = format!(
+        "HTTP/1.1 {}\r\nServer: WebbOS\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len()
+    ).into_bytes();
+    if !head {
+        out.extend_from_slice(body);
+    }
+    out
+}
+
+/// The response to `request`, from the files under `root`
+pub fn respond(request: &[u8], root: &str) -> Vec<u8> {
+    let text = String::from_utf8_lossy(request);
+    let mut parts = text.lines().next().unwrap_or("").split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => (method, target),
+        _ => return response("400 Bad Request", "text/plain", b"Bad request\n", false),
+    };
+    let head = method == "HEAD";
+    if method != "GET" && !head {
+        return response("405 Method Not Allowed", "text/plain", b"Only GET and HEAD\n", false);
+    }
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+    if !path.starts_with('/') || path.split('/').any(|part| part == "..") {
+        return response("400 Bad Request", "text/plain", b"Bad path\n", head);
+    }
+    let mut file = format!("{}{}", root, path);
+    if fs::metadata(&file).map(|m| m.file_type == FileType::Directory).unwrap_or(false) {
+        file = format!("{}/index.html", file.trim_end_matches('/'));
+    }
+    debug!("httpd", "{} {}", method, path);
+    match fs::read_file(&file) {
+        Ok(body) => response("200 OK", content_type(&file), &body, head),
+        Err(_) => response("404 Not Found", "text/plain", format!("{} not found\n", path).as_bytes(), head),
+    }
+}
+
+/// What `service status` shows for it
+pub fn describe() -> Option<String> {
+    SERVER.lock().as_ref().map(|s| format!("{} on port {}, {} clients", s.root, s.port.as_u16(), s.clients.len()))
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::ToString;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn status(response: &[u8]) -> String {
+        String::from_utf8_lossy(response).lines().next().unwrap_or("").to_string()
+    }
+
+    #[kernel_test]
+    fn serves_files_and_refuses_the_rest() -> Result<(), String> {
+        let _ = fs::create_dir("/tmp/httpd-test");
+        fs::write_file("/tmp/httpd-test/index.html", b"<p>hi</p>").map_err(|e| format!("{:?}", e))?;
+        let ok = respond(b"GET /?x=1 HTTP/1.1\r\nHost: a\r\n\r\n", "/tmp/httpd-test");
+        check_eq!(status(&ok), "HTTP/1.1 200 OK");
+        check!(ok.ends_with(b"\r\n\r\n<p>hi</p>"));
+        check!(String::from_utf8_lossy(&ok).contains("Content-Type: text/html"));
+        let head = respond(b"HEAD /index.html HTTP/1.0\r\n\r\n", "/tmp/httpd-test");
+        check!(head.ends_with(b"\r\n\r\n"));
+        check_eq!(status(&respond(b"GET /missing HTTP/1.1\r\n\r\n", "/tmp/httpd-test")), "HTTP/1.1 404 Not Found");
+        check_eq!(status(&respond(b"GET /../etc/passwd HTTP/1.1\r\n\r\n", "/tmp/httpd-test")), "HTTP/1.1 400 Bad Request");
+        check_eq!(status(&respond(b"POST / HTTP/1.1\r\n\r\n", "/tmp/httpd-test")), "HTTP/1.1 405 Method Not Allowed");
+        check_eq!(status(&respond(b"nonsense\r\n\r\n", "/tmp/httpd-test")), "HTTP/1.1 400 Bad Request");
+        let _ = fs::remove("/tmp/httpd-test/index.html");
+        let _ = fs::remove("/tmp/httpd-test");
+        Ok(())
+    }
+}
@@ -1,10 +1,15 @@
 //! IP (Internet Protocol) layer
 //!
 //! Handles IPv4 packet processing and routing.
+//!
+//! Packets from the wire whose source is their destination, or is this
+//! host (LAND attacks), are dropped, and so are martians: addresses no
+//! packet arriving here could honestly carry (RFC 1812 5.3.7).
 
 use alloc::vec;
 use alloc::vec::Vec;
-use crate::net::{Ipv4Address, IpProtocol, arp};
+use core::sync::atomic::Ordering;
+use crate::net::{Ipv4Address, IpProtocol, arp, DROPS};
 use crate::println;
 
 /// IPv4 header
@@ -157,6 +162,16 @@ pub fn process_ipv4_packet(data: &[u8]) {
 
     let payload = &data[header_len..total_len];
 
+    let (src, dst) = (header.src_ip(), header.dst_ip());
+    if src == dst || src == super::get_config().ip {
+        DROPS.land.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if martian(src, dst) {
+        DROPS.martian.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
     // Dispatch based on protocol
     match IpProtocol::from_u8(header.protocol) {
         Some(IpProtocol::Tcp) => {
@@ -174,6 +189,15 @@ pub fn process_ipv4_packet(data: &[u8]) {
     }
 }
 
+/// Whether a packet from `src` to `dst` has an address that cannot be
+/// real: a source that is "this network", loopback, multicast, reserved
+/// or broadcast, or a destination that is "this network" or loopback
+pub fn martian(src: Ipv4Address, dst: Ipv4Address) -> bool {
+    let [s, ..] = src.0;
+    let [d, ..] = dst.0;
+    s == 0 || s == 127 || s >= 224 || d == 0 || d == 127
+}
+
 /// Send IPv4 packet
 pub fn send_ipv4_packet(
     protocol: IpProtocol,
@@ -355,3 +379,23 @@ pub fn next_packet_id() -> u16 {
         PACKET_ID
     }
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::check;
+
+    #[kernel_test]
+    fn martians_are_told_apart() -> Result<(), String> {
+        let host = Ipv4Address::from_octets(10, 0, 2, 15);
+        check!(!martian(Ipv4Address::from_octets(93, 184, 216, 34), host));
+        check!(!martian(Ipv4Address::from_octets(10, 0, 2, 2), Ipv4Address::broadcast()));
+        check!(martian(Ipv4Address::loopback(), host));
+        check!(martian(Ipv4Address::unspecified(), host));
+        check!(martian(Ipv4Address::from_octets(224, 0, 0, 1), host));
+        check!(martian(Ipv4Address::broadcast(), host));
+        check!(martian(Ipv4Address::from_octets(10, 0, 2, 2), Ipv4Address::loopback()));
+        Ok(())
+    }
+}
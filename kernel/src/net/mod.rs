@@ -1,9 +1,19 @@
 //! Network stack
 //!
 //! TCP/IP network implementation for WebbOS.
+//!
+//! Frames are taken in by `poll`, which the kernel's main loops call, and
+//! so do sockets while they wait, along with the timers TCP acknowledges
+//! and retransmits from.
+//!
+//! Frames addressed to another host are dropped on arrival unless their
+//! interface is promiscuous, as it is while a capture runs on it; even
+//! then only the capture sees them. ARP and IP turn away some spoofed
+//! traffic too, and `DROPS` counts all of it for `network`.
 
 use alloc::vec::Vec;
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
@@ -16,8 +26,16 @@ pub mod dhcp;
 pub mod dns;
 pub mod socket;
 pub mod http;
+pub mod httpd;
+pub mod ntp;
+pub mod socks;
+pub mod conntrack;
+pub mod nat;
+pub mod capture;
 
 use crate::println;
+use crate::trace;
+use crate::info;
 
 /// MAC address (48-bit)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -137,6 +155,26 @@ impl Ipv4Address {
         let b = other.as_u32() & netmask.as_u32();
         a == b
     }
+
+    /// Parse dotted-quad notation, e.g. "10.0.2.15"
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut bytes = [0u8; 4];
+        let mut parts = s.trim().split('.');
+        for byte in bytes.iter_mut() {
+            *byte = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self(bytes))
+    }
+}
+
+impl core::fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
 }
 
 /// IPv6 address
@@ -257,8 +295,42 @@ pub trait NetworkInterface: Send + Sync {
     fn receive(&self, buf: &mut [u8]) -> Result<usize, NetError>;
     /// Check if link is up
     fn is_link_up(&self) -> bool;
+    /// Pass up frames for any address, or only for this interface's own,
+    /// broadcast and multicast ones
+    ///
+    /// Devices that filter by address override this; the stack filters
+    /// again in any case.
+    fn set_promiscuous(&self, _on: bool) -> Result<(), NetError> {
+        Ok(())
+    }
 }
 
+/// Options kept for each interface
+#[derive(Debug, Clone, Copy)]
+struct InterfaceState {
+    mac: MacAddress,
+    promiscuous: bool,
+}
+
+/// Traffic the stack turned away, by reason
+pub struct Drops {
+    /// Frames for another host on an interface that is not promiscuous
+    pub foreign: AtomicU64,
+    /// ARP that would have moved the gateway to another MAC address
+    pub gateway_arp: AtomicU64,
+    /// Packets whose source is their destination (LAND attacks)
+    pub land: AtomicU64,
+    /// Packets with addresses no host could use (martians)
+    pub martian: AtomicU64,
+}
+
+pub static DROPS: Drops = Drops {
+    foreign: AtomicU64::new(0),
+    gateway_arp: AtomicU64::new(0),
+    land: AtomicU64::new(0),
+    martian: AtomicU64::new(0),
+};
+
 /// Network error
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NetError {
@@ -289,17 +361,18 @@ pub enum NetError {
 /// Global network interfaces
 lazy_static! {
     static ref INTERFACES: Mutex<Vec<Box<dyn NetworkInterface>>> = Mutex::new(Vec::new());
+    static ref INTERFACE_STATE: Mutex<Vec<InterfaceState>> = Mutex::new(Vec::new());
     static ref DEFAULT_INTERFACE: Mutex<Option<usize>> = Mutex::new(None);
 }
 
 /// Initialize network stack
 pub fn init() {
-    println!("[net] Initializing network stack...");
+    info!("net", "Initializing network stack...");
 
     // Initialize drivers
     drivers::init();
 
-    println!("[net] Network stack initialized");
+    info!("net", "Network stack initialized");
 }
 
 /// Register network interface
@@ -307,9 +380,10 @@ pub fn register_interface(iface: Box<dyn NetworkInterface>) {
     let mut interfaces = INTERFACES.lock();
     let idx = interfaces.len();
     
-    println!("[net] Registered interface {}: {} (MAC: {:?})",
+    info!("net", "Registered interface {}: {} (MAC: {:?})",
         idx, iface.name(), iface.mac_address());
     
+    INTERFACE_STATE.lock().push(InterfaceState { mac: iface.mac_address(), promiscuous: false });
     interfaces.push(iface);
 
     // Set as default if first interface
@@ -329,6 +403,41 @@ pub fn default_interface() -> Option<usize> {
     *DEFAULT_INTERFACE.lock()
 }
 
+/// Index of the interface called `name`, or numbered by it
+pub fn interface_index(name: &str) -> Option<usize> {
+    let interfaces = INTERFACES.lock();
+    interfaces.iter().position(|iface| iface.name() == name)
+        .or_else(|| name.parse().ok().filter(|idx| *idx < interfaces.len()))
+}
+
+/// Hardware address of an interface
+pub fn interface_mac(iface_idx: usize) -> Option<MacAddress> {
+    INTERFACES.lock().get(iface_idx).map(|iface| iface.mac_address())
+}
+
+/// Set whether an interface takes in frames addressed to other hosts
+pub fn set_promiscuous(iface_idx: usize, on: bool) -> Result<(), NetError> {
+    let interfaces = INTERFACES.lock();
+    let iface = interfaces.get(iface_idx).ok_or(NetError::NoDevice)?;
+    iface.set_promiscuous(on)?;
+    if let Some(state) = INTERFACE_STATE.lock().get_mut(iface_idx) {
+        state.promiscuous = on;
+    }
+    info!("net", "Interface {} {} promiscuous mode", iface_idx, if on { "entered" } else { "left" });
+    Ok(())
+}
+
+/// Whether an interface takes in frames addressed to other hosts
+pub fn is_promiscuous(iface_idx: usize) -> bool {
+    INTERFACE_STATE.lock().get(iface_idx).map_or(false, |state| state.promiscuous)
+}
+
+/// Whether a frame is addressed to the interface with `state`: to its MAC
+/// address, to broadcast, or to a multicast group
+fn addressed_here(state: &InterfaceState, frame: &[u8]) -> bool {
+    frame[0] & 1 != 0 || frame[..6] == state.mac.as_bytes()[..]
+}
+
 /// Print network interface list
 pub fn print_interfaces() {
     let interfaces = INTERFACES.lock();
@@ -345,10 +454,11 @@ pub fn print_interfaces() {
         let mac_str = mac.format();
         let mac_str = core::str::from_utf8(&mac_str).unwrap_or("?");
         
-        println!("{}{:<3} {:<10} {:<20} {:<8} {}",
+        println!("{}{:<3} {:<10} {:<20} {:<8} {}{}",
             default_mark, i, iface.name(), mac_str,
             iface.mtu(),
-            if iface.is_link_up() { "UP" } else { "DOWN" });
+            if iface.is_link_up() { "UP" } else { "DOWN" },
+            if is_promiscuous(i) { " PROMISC" } else { "" });
     }
 }
 
@@ -356,6 +466,8 @@ pub fn print_interfaces() {
 pub fn send_packet(iface_idx: usize, data: &[u8]) -> Result<usize, NetError> {
     let interfaces = INTERFACES.lock();
     if let Some(iface) = interfaces.get(iface_idx) {
+        trace::instant(trace::Kind::PacketOut, iface_idx as u64, data.len() as u64);
+        capture::tap(iface_idx, capture::Direction::Out, data);
         iface.send(data)
     } else {
         Err(NetError::NoDevice)
@@ -372,12 +484,31 @@ pub fn receive_packet(iface_idx: usize, buf: &mut [u8]) -> Result<usize, NetErro
     }
 }
 
-/// Process received packet
-pub fn process_packet(data: &[u8]) {
+/// Process packet received on interface `iface_idx`
+pub fn process_packet(iface_idx: usize, data: &[u8]) {
+    trace::instant(trace::Kind::PacketIn, iface_idx as u64, data.len() as u64);
     if data.len() < 14 {
+        capture::tap(iface_idx, capture::Direction::In, data);
         return; // Too short for Ethernet header
     }
 
+    // Another host's frame is only for a capture to see
+    let state = INTERFACE_STATE.lock().get(iface_idx).copied();
+    let here = state.map_or(true, |state| addressed_here(&state, data));
+    if !here && !state.map_or(false, |state| state.promiscuous) {
+        DROPS.foreign.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    capture::tap(iface_idx, capture::Direction::In, data);
+    if !here {
+        return;
+    }
+
+    // Passing through between the NAT interfaces
+    if nat::route(iface_idx, data) {
+        return;
+    }
+
     // Parse Ethernet header
     let dst_mac = MacAddress::new([data[0], data[1], data[2], data[3], data[4], data[5]]);
     let src_mac = MacAddress::new([data[6], data[7], data[8], data[9], data[10], data[11]]);
@@ -401,6 +532,25 @@ pub fn process_packet(data: &[u8]) {
     }
 }
 
+/// Frames `poll` takes from each interface at a time, so that a flood
+/// cannot hold up the loop calling it
+const POLL_BUDGET: usize = 64;
+
+/// Take in the frames waiting on every interface, and give the DHCP client
+/// its replies
+pub fn poll() {
+    let mut frame = [0u8; 2048];
+    for iface_idx in 0..interface_count() {
+        for _ in 0..POLL_BUDGET {
+            match receive_packet(iface_idx, &mut frame) {
+                Ok(len) if len > 0 => process_packet(iface_idx, &frame[..len]),
+                _ => break,
+            }
+        }
+    }
+    dhcp::poll();
+}
+
 /// Network configuration
 #[derive(Debug, Clone)]
 pub struct NetworkConfig {
@@ -450,7 +600,7 @@ pub fn set_config(config: NetworkConfig) {
     let gw_str = config.gateway.format();
     let gw_str = core::str::from_utf8(&gw_str).unwrap_or("?");
     
-    println!("[net] Configured: IP={}/{} GW={}", ip_str, nm_str, gw_str);
+    info!("net", "Configured: IP={}/{} GW={}", ip_str, nm_str, gw_str);
     *NET_CONFIG.lock() = config;
 }
 
@@ -469,6 +619,10 @@ pub fn print_stats() {
         println!("  IP: Not configured");
     }
 
+    println!("  Dropped: {} for other hosts, {} gateway ARP changes, {} LAND, {} martian",
+        DROPS.foreign.load(Ordering::Relaxed), DROPS.gateway_arp.load(Ordering::Relaxed),
+        DROPS.land.load(Ordering::Relaxed), DROPS.martian.load(Ordering::Relaxed));
+
     tcp::print_stats();
     udp::print_stats();
 }
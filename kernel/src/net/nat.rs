@@ -0,0 +1,412 @@
+//! NAT and port forwarding
+//!
+//! When `nat.inside` and `nat.outside` name two interfaces, such as
+//! virtio-net and a USB tether, WebbOS routes between them. TCP and UDP
+//! from hosts on the inside leave through the outside interface from this
+//! machine's address, on a port connection tracking hands out, and
+//! replies are mapped back to the host that sent them. `nat.forwards`
+//! lists ports on the outside address that lead to an inside host, such
+//! as `tcp:8080=192.168.7.2:80`. Anything else, including all traffic
+//! addressed to WebbOS itself, is left to the local stack.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+use crate::config;
+use crate::drivers::timer;
+use crate::net::conntrack::{self, Endpoint};
+use crate::net::ip::Ipv4Header;
+use crate::net::{arp, EtherType, IpProtocol, Ipv4Address, MacAddress};
+use crate::{debug, info, println};
+
+/// An outside port that leads to an inside host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub protocol: IpProtocol,
+    pub port: u16,
+    pub to: Endpoint,
+}
+
+fn protocol_name(protocol: IpProtocol) -> &'static str {
+    match protocol {
+        IpProtocol::Tcp => "tcp",
+        IpProtocol::Udp => "udp",
+        IpProtocol::Icmp => "icmp",
+    }
+}
+
+fn protocol_from_name(name: &str) -> Option<IpProtocol> {
+    match name {
+        "tcp" => Some(IpProtocol::Tcp),
+        "udp" => Some(IpProtocol::Udp),
+        _ => None,
+    }
+}
+
+impl Forward {
+    /// Parse `tcp:8080=192.168.7.2:80`; the inside port defaults to the
+    /// outside one
+    pub fn parse(text: &str) -> Option<Self> {
+        let (outside, inside) = text.trim().split_once('=')?;
+        let (protocol, port) = outside.split_once(':')?;
+        let port = port.parse().ok().filter(|p| *p != 0)?;
+        let (host, to_port) = match inside.split_once(':') {
+            Some((host, to_port)) => (host, to_port.parse().ok().filter(|p| *p != 0)?),
+            None => (inside, port),
+        };
+        Some(Forward { protocol: protocol_from_name(protocol)?, port, to: (Ipv4Address::parse(host)?, to_port) })
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}={}:{}", protocol_name(self.protocol), self.port, self.to.0, self.to.1)
+    }
+}
+
+/// Parse a comma separated list of forwards
+pub fn parse_forwards(text: &str) -> Result<Vec<Forward>, &'static str> {
+    let mut forwards: Vec<Forward> = Vec::new();
+    for item in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let forward = Forward::parse(item).ok_or("forwards look like tcp:8080=192.168.7.2:80")?;
+        if forwards.iter().any(|f| f.protocol == forward.protocol && f.port == forward.port) {
+            return Err("a port is forwarded twice");
+        }
+        forwards.push(forward);
+    }
+    Ok(forwards)
+}
+
+/// Routing between the two interfaces, while it is on
+struct Router {
+    inside: String,
+    outside: String,
+    forwards: Vec<Forward>,
+    /// Hardware addresses of inside hosts, learned from what they send
+    neighbors: BTreeMap<Ipv4Address, MacAddress>,
+}
+
+static ROUTER: Mutex<Option<Router>> = Mutex::new(None);
+
+/// Start, change or stop routing to match the `nat.*` settings
+pub fn configure() -> Result<(), &'static str> {
+    let inside = config::get("nat.inside").unwrap_or_default();
+    let outside = config::get("nat.outside").unwrap_or_default();
+    let forwards = parse_forwards(&config::get("nat.forwards").unwrap_or_default())?;
+    let mut router = ROUTER.lock();
+    if inside.is_empty() || outside.is_empty() {
+        if router.take().is_some() {
+            conntrack::TABLE.lock().clear();
+            info!("nat", "Routing stopped");
+        }
+        return Ok(());
+    }
+    if inside == outside {
+        return Err("the inside and outside interfaces must differ");
+    }
+    let neighbors = router.take().map(|r| r.neighbors).unwrap_or_default();
+    info!("nat", "Routing {} to {}, {} ports forwarded", inside, outside, forwards.len());
+    *router = Some(Router { inside, outside, forwards, neighbors });
+    Ok(())
+}
+
+/// Which address and port of a packet to rewrite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum End {
+    Source,
+    Destination,
+}
+
+/// Update ones' complement checksum `checksum` for `old` bytes becoming
+/// `new` ones (RFC 1624)
+fn adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = (!checksum) as u32;
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        sum += (!u16::from_be_bytes([old[0], old[1]])) as u32;
+        sum += u16::from_be_bytes([new[0], new[1]]) as u32;
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Offset of the transport checksum in a packet with an `ihl`-byte header
+fn checksum_offset(protocol: IpProtocol, ihl: usize) -> usize {
+    match protocol {
+        IpProtocol::Udp => ihl + 6,
+        _ => ihl + 16,
+    }
+}
+
+/// Rewrite one end of TCP or UDP `packet` to `to`, keeping both checksums
+/// right
+fn rewrite(packet: &mut [u8], protocol: IpProtocol, end: End, to: Endpoint) {
+    let ihl = ((packet[0] & 0x0F) as usize) * 4;
+    let (address_at, port_at) = match end {
+        End::Source => (12, ihl),
+        End::Destination => (16, ihl + 2),
+    };
+    let old_address: [u8; 4] = packet[address_at..address_at + 4].try_into().unwrap_or([0; 4]);
+    let old_port = [packet[port_at], packet[port_at + 1]];
+    let new_address = *to.0.as_bytes();
+    let new_port = to.1.to_be_bytes();
+
+    let header = u16::from_be_bytes([packet[10], packet[11]]);
+    packet[10..12].copy_from_slice(&adjust(header, &old_address, &new_address).to_be_bytes());
+
+    let at = checksum_offset(protocol, ihl);
+    let checksum = u16::from_be_bytes([packet[at], packet[at + 1]]);
+    // A UDP checksum of zero means the sender did not compute one
+    if !(protocol == IpProtocol::Udp && checksum == 0) {
+        let mut checksum = adjust(adjust(checksum, &old_address, &new_address), &old_port, &new_port);
+        if protocol == IpProtocol::Udp && checksum == 0 {
+            checksum = 0xFFFF;
+        }
+        packet[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet[address_at..address_at + 4].copy_from_slice(&new_address);
+    packet[port_at..port_at + 2].copy_from_slice(&new_port);
+}
+
+/// Count the hop against the packet's TTL; false if it has run out
+fn hop(packet: &mut [u8]) -> bool {
+    if packet[8] <= 1 {
+        return false;
+    }
+    let old = [packet[8], packet[9]];
+    packet[8] -= 1;
+    let new = [packet[8], packet[9]];
+    let header = u16::from_be_bytes([packet[10], packet[11]]);
+    packet[10..12].copy_from_slice(&adjust(header, &old, &new).to_be_bytes());
+    true
+}
+
+fn send(iface: usize, dst_mac: MacAddress, packet: &[u8]) {
+    let src_mac = super::interface_mac(iface).unwrap_or(MacAddress::new([0; 6]));
+    let mut frame = vec![0u8; 14 + packet.len()];
+    frame[0..6].copy_from_slice(dst_mac.as_bytes());
+    frame[6..12].copy_from_slice(src_mac.as_bytes());
+    frame[12..14].copy_from_slice(&(EtherType::Ipv4 as u16).to_be_bytes());
+    frame[14..].copy_from_slice(packet);
+    let _ = super::send_packet(iface, &frame);
+}
+
+/// Route Ethernet `frame`, received on interface `iface`, to the other
+/// side if it is passing through; false if it is for the local stack
+pub fn route(iface: usize, frame: &[u8]) -> bool {
+    if frame.len() < 14 + 20 || u16::from_be_bytes([frame[12], frame[13]]) != EtherType::Ipv4 as u16 {
+        return false;
+    }
+    let mut router = ROUTER.lock();
+    let router = match router.as_mut() {
+        Some(router) => router,
+        None => return false,
+    };
+    let (inside, outside) = match (super::interface_index(&router.inside), super::interface_index(&router.outside)) {
+        (Some(inside), Some(outside)) => (inside, outside),
+        _ => return false,
+    };
+    if iface != inside && iface != outside {
+        return false;
+    }
+
+    let mut packet = frame[14..].to_vec();
+    let header = match Ipv4Header::from_bytes(&packet) {
+        Some(header) => header,
+        None => return false,
+    };
+    let protocol = match IpProtocol::from_u8(header.protocol) {
+        Some(protocol @ (IpProtocol::Tcp | IpProtocol::Udp)) => protocol,
+        _ => return false,
+    };
+    let ihl = header.header_len();
+    let total = header.total_len as usize;
+    // Later fragments carry no ports to translate
+    if ihl < 20 || total < ihl + 8 || total > packet.len() || header.flags_frag & 0x1FFF != 0 {
+        return false;
+    }
+    packet.truncate(total);
+    let port = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+    let src = (header.src_ip(), port(ihl));
+    let dst = (header.dst_ip(), port(ihl + 2));
+    let config = super::get_config();
+    let public = config.ip;
+    let now = timer::elapsed_ms();
+
+    if iface == inside {
+        let mac = MacAddress::new(frame[6..12].try_into().unwrap_or([0; 6]));
+        router.neighbors.insert(src.0, mac);
+        if !config.is_configured() || dst.0 == public || dst.0 == Ipv4Address::broadcast() {
+            return false;
+        }
+        let outside_port = match conntrack::TABLE.lock().outbound(protocol, src, dst, now) {
+            Some(port) => port,
+            None => {
+                debug!("nat", "Connection table full, dropping packet from {}", src.0);
+                return true;
+            }
+        };
+        if !hop(&mut packet) {
+            return true;
+        }
+        rewrite(&mut packet, protocol, End::Source, (public, outside_port));
+        // Until the next hop answers ARP, the sender's retry gets through
+        if let Some(mac) = arp::resolve(dst.0) {
+            send(outside, mac, &packet);
+        }
+        return true;
+    }
+
+    if !config.is_configured() || dst.0 != public {
+        return false;
+    }
+    let tracked = conntrack::TABLE.lock().inbound(protocol, dst.1, src, now);
+    let to = match tracked {
+        Some(to) => to,
+        None => match router.forwards.iter().find(|f| f.protocol == protocol && f.port == dst.1) {
+            Some(forward) => {
+                if !conntrack::TABLE.lock().forwarded(protocol, dst.1, forward.to, src, now) {
+                    return true;
+                }
+                forward.to
+            }
+            None => return false,
+        },
+    };
+    if !hop(&mut packet) {
+        return true;
+    }
+    rewrite(&mut packet, protocol, End::Destination, to);
+    match router.neighbors.get(&to.0).copied().or_else(|| arp::lookup(to.0)) {
+        Some(mac) => send(inside, mac, &packet),
+        None => debug!("nat", "No hardware address for {} yet, dropping packet", to.0),
+    }
+    true
+}
+
+/// The `nat` shell command
+pub fn command(args: &[&str]) {
+    let result = match args {
+        [] | ["status"] => {
+            print_status();
+            return;
+        }
+        ["on", inside, outside] => config::set("nat.inside", inside).and_then(|_| config::set("nat.outside", outside)),
+        ["off"] => config::set("nat.inside", ""),
+        ["forward", protocol, port, to] => {
+            let mut forwards = config::get("nat.forwards").unwrap_or_default();
+            if !forwards.is_empty() {
+                forwards.push(',');
+            }
+            forwards.push_str(&alloc::format!("{}:{}={}", protocol, port, to));
+            config::set("nat.forwards", &forwards)
+        }
+        ["unforward", protocol, port] => {
+            let current = parse_forwards(&config::get("nat.forwards").unwrap_or_default()).unwrap_or_default();
+            let kept: Vec<String> = current.iter()
+                .filter(|f| !(protocol_name(f.protocol) == *protocol && f.port.to_string() == *port))
+                .map(|f| f.to_string())
+                .collect();
+            if kept.len() == current.len() {
+                println!("nat: {} port {} is not forwarded", protocol, port);
+                return;
+            }
+            config::set("nat.forwards", &kept.join(","))
+        }
+        _ => {
+            println!("Usage: nat [status | on INSIDE OUTSIDE | off | forward tcp|udp PORT HOST[:PORT] | unforward tcp|udp PORT]");
+            return;
+        }
+    };
+    match result {
+        Ok(()) => println!("Done"),
+        Err(e) => println!("nat: {}", e),
+    }
+}
+
+fn print_status() {
+    {
+        let router = ROUTER.lock();
+        let router = match router.as_ref() {
+            Some(router) => router,
+            None => {
+                println!("NAT is off");
+                return;
+            }
+        };
+        println!("Routing {} (inside) to {} (outside)", router.inside, router.outside);
+        for forward in &router.forwards {
+            println!("  forward {}", forward);
+        }
+    }
+    let table = conntrack::TABLE.lock();
+    println!("{} connections tracked:", table.flows().len());
+    for flow in table.flows() {
+        println!("  {:<4} {}:{} as :{} <-> {}:{}{}",
+            protocol_name(flow.protocol), flow.inside.0, flow.inside.1, flow.outside_port,
+            flow.remote.0, flow.remote.1, if flow.forwarded { " (forwarded)" } else { "" });
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::net::udp::UdpHeader;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// A UDP packet with correct checksums
+    fn udp_packet(src: Endpoint, dst: Endpoint, data: &[u8]) -> Vec<u8> {
+        let mut udp = UdpHeader { src_port: src.1, dst_port: dst.1, length: 8 + data.len() as u16, checksum: 0 };
+        udp.checksum = udp.calculate_checksum(src.0, dst.0, data);
+        let mut ip = Ipv4Header::new(IpProtocol::Udp, src.0, dst.0, 8 + data.len() as u16);
+        ip.checksum = ip.calculate_checksum();
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&ip.to_bytes());
+        packet.extend_from_slice(&udp.to_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[kernel_test]
+    fn rewriting_keeps_checksums_right() -> Result<(), String> {
+        let host = (Ipv4Address::from_octets(192, 168, 7, 2), 5353);
+        let server = (Ipv4Address::from_octets(1, 1, 1, 1), 53);
+        let public = (Ipv4Address::from_octets(10, 0, 2, 15), 20000);
+        let mut packet = udp_packet(host, server, b"query");
+        check!(hop(&mut packet));
+        rewrite(&mut packet, IpProtocol::Udp, End::Source, public);
+
+        let mut expected = udp_packet(public, server, b"query");
+        expected[8] -= 1;
+        let mut ip = Ipv4Header::from_bytes(&expected).ok_or("bad header")?;
+        ip.checksum = ip.calculate_checksum();
+        expected[..20].copy_from_slice(&ip.to_bytes());
+        check_eq!(packet, expected);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn forwards_parse() -> Result<(), String> {
+        let forward = Forward::parse("tcp:8080=192.168.7.2:80").ok_or("did not parse")?;
+        check_eq!(forward.to_string(), String::from("tcp:8080=192.168.7.2:80"));
+        check_eq!(Forward::parse("udp:53=192.168.7.2").map(|f| f.to.1), Some(53));
+        check!(Forward::parse("icmp:1=192.168.7.2").is_none());
+        check!(parse_forwards("tcp:80=10.0.0.2, tcp:80=10.0.0.3").is_err());
+        check_eq!(parse_forwards("").map(|f| f.len()), Ok(0));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn expired_ttl_is_not_forwarded() -> Result<(), String> {
+        let mut packet = udp_packet((Ipv4Address::from_octets(192, 168, 7, 2), 1), (Ipv4Address::from_octets(1, 1, 1, 1), 2), b"");
+        packet[8] = 1;
+        check!(!hop(&mut packet));
+        Ok(())
+    }
+}
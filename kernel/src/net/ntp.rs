@@ -0,0 +1,162 @@
+//! Time from a network time server
+//!
+//! Run as the `ntp` service: an SNTP client (RFC 4330) that asks the
+//! definition's `server`, pool.ntp.org unless set, for the time once an
+//! `interval`, 3600 seconds unless set, and sets the clock from the
+//! reply. A server that leaves three requests in a row unanswered fails
+//! the service, and the service manager starts it again later.
+
+use alloc::format;
+use alloc::string::String;
+use spin::Mutex;
+
+use crate::drivers::{rtc, timer};
+use crate::net::{self, dns, udp, Ipv4Address, Port};
+use crate::service::Definition;
+use crate::{debug, info};
+
+const NTP_PORT: Port = Port::new(123);
+const LOCAL_PORT: Port = Port::new(50123);
+
+/// Seconds from 1900, where NTP time starts, to 1970
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+/// How long a reply may take, and how many requests go unanswered before
+/// the service fails
+const TIMEOUT_MS: u64 = 5000;
+const TRIES: u32 = 3;
+
+struct Client {
+    server: Ipv4Address,
+    interval_ms: u64,
+    /// When the next request goes
+    next_ms: u64,
+    /// When the request waiting for a reply went
+    sent_ms: Option<u64>,
+    /// Requests in a row without a reply
+    tries: u32,
+    /// Unix time of the last reply, and how far the clock was moved
+    last: Option<(u64, i64)>,
+}
+
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+pub fn start(definition: &Definition) -> Result<(), String> {
+    let interval = definition.option("interval").unwrap_or("3600");
+    let interval: u64 = interval.parse().map_err(|_| format!("'{}' is not a number of seconds", interval))?;
+    if !net::get_config().is_configured() {
+        return Err(String::from("no network address yet"));
+    }
+    let name = definition.option("server").unwrap_or("pool.ntp.org");
+    let server = dns::resolve(name).ok_or_else(|| format!("cannot resolve {}", name))?;
+    udp::bind(LOCAL_PORT).map_err(|_| format!("port {} is in use", LOCAL_PORT.as_u16()))?;
+    info!("ntp", "Synchronizing with {} ({:?}) every {}s", name, server, interval);
+    *CLIENT.lock() = Some(Client {
+        server,
+        interval_ms: interval.max(16) * 1000,
+        next_ms: timer::elapsed_ms(),
+        sent_ms: None,
+        tries: 0,
+        last: None,
+    });
+    Ok(())
+}
+
+/// The time in a server's reply, in Unix nanoseconds
+pub fn parse_reply(data: &[u8]) -> Option<u64> {
+    // Mode 4 is a server's answer; stratum 0 is a refusal
+    if data.len() < 48 || data[0] & 7 != 4 || !(1..16).contains(&data[1]) {
+        return None;
+    }
+    let seconds = u32::from_be_bytes([data[40], data[41], data[42], data[43]]) as u64;
+    let fraction = u32::from_be_bytes([data[44], data[45], data[46], data[47]]) as u64;
+    let seconds = seconds.checked_sub(UNIX_EPOCH)?;
+    Some(seconds * 1_000_000_000 + ((fraction * 1_000_000_000) >> 32))
+}
+
+/// Send requests when they are due and take in replies
+pub fn poll() -> Result<(), String> {
+    let mut guard = CLIENT.lock();
+    let client = match guard.as_mut() {
+        Some(client) => client,
+        None => return Err(String::from("not running")),
+    };
+    let now = timer::elapsed_ms();
+    let mut buf = [0u8; 68];
+    while let Some((from, _, len)) = udp::receive_from(LOCAL_PORT, &mut buf) {
+        let sent = match client.sent_ms {
+            Some(sent) if from == client.server => sent,
+            _ => continue,
+        };
+        if let Some(time) = parse_reply(&buf[..len]) {
+            // The server read its clock about halfway through the round trip
+            let time = time + now.saturating_sub(sent) * 1_000_000 / 2;
+            let moved = (time as i64 - rtc::unix_ns() as i64) / 1_000_000;
+            rtc::set_unix_ns(time);
+            debug!("ntp", "Clock moved by {}ms", moved);
+            client.last = Some((time / 1_000_000_000, moved));
+            client.sent_ms = None;
+            client.tries = 0;
+            client.next_ms = now + client.interval_ms;
+        }
+    }
+    if let Some(sent) = client.sent_ms {
+        if now.saturating_sub(sent) < TIMEOUT_MS {
+            return Ok(());
+        }
+        client.sent_ms = None;
+        if client.tries >= TRIES {
+            return Err(format!("no reply from {:?}", client.server));
+        }
+        client.next_ms = now;
+    }
+    if now >= client.next_ms {
+        let mut request = [0u8; 48];
+        // Leap indicator 0, version 4, mode 3: a client
+        request[0] = 0x23;
+        let _ = udp::send_to(LOCAL_PORT, client.server, NTP_PORT, &request);
+        client.sent_ms = Some(now);
+        client.tries += 1;
+    }
+    Ok(())
+}
+
+pub fn stop() {
+    if CLIENT.lock().take().is_some() {
+        udp::close(LOCAL_PORT);
+    }
+}
+
+/// What `service status` shows for it
+pub fn describe() -> Option<String> {
+    let guard = CLIENT.lock();
+    let client = guard.as_ref()?;
+    Some(match client.last {
+        Some((time, moved)) => format!("{:?}, last reply {} UTC, moved {}ms", client.server, rtc::DateTime::from_unix(time), moved),
+        None => format!("{:?}, no reply yet", client.server),
+    })
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    #[kernel_test]
+    fn reads_the_transmit_time() -> Result<(), String> {
+        let mut reply = [0u8; 48];
+        reply[0] = 0x24;
+        reply[1] = 2;
+        // 2024-01-01 00:00:00 UTC and a half
+        reply[40..44].copy_from_slice(&((1_704_067_200 + UNIX_EPOCH) as u32).to_be_bytes());
+        reply[44..48].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        check_eq!(parse_reply(&reply), Some(1_704_067_200_500_000_000));
+        reply[1] = 0;
+        check_eq!(parse_reply(&reply), None);
+        reply[1] = 2;
+        reply[0] = 0x23;
+        check_eq!(parse_reply(&reply), None);
+        check_eq!(parse_reply(&reply[..40]), None);
+        Ok(())
+    }
+}
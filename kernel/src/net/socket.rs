@@ -1,13 +1,20 @@
 //! Socket API
 //!
 //! BSD-style socket interface for network programming.
+//!
+//! `setsockopt` takes options a socket keeps for its life. Those for TCP
+//! go to the connection when it is made, or at once if it already is, and
+//! an accepted socket starts with the listening socket's options.
 
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 use alloc::string::String;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use webbos_shared::types::Pid;
 
+use crate::drivers::timer;
 use crate::net::{Ipv4Address, Port, tcp, udp};
 use crate::net;
 use crate::println;
@@ -45,6 +52,78 @@ pub enum SocketState {
     Closed,
 }
 
+/// An option `setsockopt` sets; times are in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketOption {
+    /// TCP_NODELAY: send small writes at once rather than holding them
+    /// while earlier data is unacknowledged
+    NoDelay(bool),
+    /// TCP_QUICKACK: acknowledge every segment at once rather than
+    /// waiting for data to ride on
+    QuickAck(bool),
+    /// SO_RCVBUF: bytes received and not yet read that may be held
+    RecvBuffer(usize),
+    /// SO_SNDBUF: bytes written and not yet acknowledged that may be held
+    SendBuffer(usize),
+    /// SO_REUSEADDR: bind a port that connections still linger on
+    ReuseAddr(bool),
+    /// SO_LINGER: how long `close` waits for unsent data to be
+    /// acknowledged; `Some(0)` resets the connection instead, `None`
+    /// returns at once and lets the data go in the background
+    Linger(Option<u64>),
+    /// SO_RCVTIMEO: how long `recv` waits for data; `None` returns at once
+    RecvTimeout(Option<u64>),
+    /// SO_SNDTIMEO: how long `send` waits for room in the send buffer;
+    /// `None` returns at once
+    SendTimeout(Option<u64>),
+}
+
+/// The options a socket has, as `getsockopt` reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub no_delay: bool,
+    pub quick_ack: bool,
+    pub recv_buffer: usize,
+    pub send_buffer: usize,
+    pub reuse_addr: bool,
+    pub linger: Option<u64>,
+    pub recv_timeout: Option<u64>,
+    pub send_timeout: Option<u64>,
+}
+
+impl SocketOptions {
+    pub const fn new() -> Self {
+        Self {
+            no_delay: false,
+            quick_ack: false,
+            recv_buffer: tcp::RECV_BUFFER,
+            send_buffer: tcp::SEND_BUFFER,
+            reuse_addr: false,
+            linger: None,
+            recv_timeout: None,
+            send_timeout: None,
+        }
+    }
+
+    fn set(&mut self, option: SocketOption) {
+        match option {
+            SocketOption::NoDelay(on) => self.no_delay = on,
+            SocketOption::QuickAck(on) => self.quick_ack = on,
+            SocketOption::RecvBuffer(bytes) => self.recv_buffer = bytes,
+            SocketOption::SendBuffer(bytes) => self.send_buffer = bytes,
+            SocketOption::ReuseAddr(on) => self.reuse_addr = on,
+            SocketOption::Linger(ms) => self.linger = ms,
+            SocketOption::RecvTimeout(ms) => self.recv_timeout = ms,
+            SocketOption::SendTimeout(ms) => self.send_timeout = ms,
+        }
+    }
+
+    /// Hand the TCP options to connection `id`
+    fn apply(&self, id: tcp::ConnectionId) {
+        let _ = tcp::tune(id, self.no_delay, self.quick_ack, self.recv_buffer, self.send_buffer);
+    }
+}
+
 /// Socket structure
 pub struct Socket {
     /// Socket file descriptor
@@ -71,6 +150,9 @@ pub struct Socket {
     pub rx_buffer: Vec<u8>,
     /// Non-blocking mode
     pub non_blocking: bool,
+    /// Process that opened the socket; its sockets close when it dies
+    pub owner: Option<Pid>,
+    pub options: SocketOptions,
 }
 
 impl Socket {
@@ -86,12 +168,31 @@ impl Socket {
             remote_addr: None,
             remote_port: None,
             tcp_id: None,
-            rx_buffer: Vec::with_capacity(65536),
+            rx_buffer: Vec::with_capacity(RX_BUFFER_SIZE),
             non_blocking: false,
+            owner: None,
+            options: SocketOptions::new(),
         }
     }
 }
 
+/// Receive buffer reserved for each socket
+const RX_BUFFER_SIZE: usize = 65536;
+
+/// Put a new socket in the table, owned by the running process if any
+fn insert(mut socket: Socket) {
+    socket.owner = crate::process::current_pid();
+    if let Some(pid) = socket.owner {
+        crate::mm::charge(pid, RX_BUFFER_SIZE as u64);
+    }
+    let fd = socket.fd;
+    let mut sockets = SOCKETS.lock();
+    if fd >= sockets.len() {
+        sockets.resize_with(fd + 1, || None);
+    }
+    sockets[fd] = Some(Box::new(socket));
+}
+
 /// Socket table
 lazy_static! {
     static ref SOCKETS: Mutex<Vec<Option<Box<Socket>>>> = Mutex::new(Vec::new());
@@ -107,16 +208,7 @@ pub fn socket(domain: SocketDomain, type_: SocketType, protocol: SocketProtocol)
         fd
     };
 
-    let socket = Socket::new(fd, domain, type_, protocol);
-
-    let mut sockets = SOCKETS.lock();
-    
-    // Extend vector if needed
-    if fd >= sockets.len() {
-        sockets.resize_with(fd + 1, || None);
-    }
-    
-    sockets[fd] = Some(Box::new(socket));
+    insert(Socket::new(fd, domain, type_, protocol));
 
     Ok(fd)
 }
@@ -132,6 +224,22 @@ pub fn bind(fd: usize, addr: Ipv4Address, port: Port) -> Result<(), NetError> {
         return Err(NetError::InvalidState);
     }
 
+    // For TCP sockets: a port another socket has bound is taken, and so is
+    // one old connections still use unless SO_REUSEADDR is set
+    if socket.type_ == SocketType::Stream {
+        let reuse = socket.options.reuse_addr;
+        let bound = sockets.iter().flatten().any(|s| {
+            s.fd != fd && s.type_ == SocketType::Stream && s.local_port == Some(port)
+                && matches!(s.state, SocketState::Bound | SocketState::Listening)
+        });
+        if bound || (!reuse && tcp::port_in_use(port)) {
+            return Err(NetError::AddressInUse);
+        }
+    }
+    let socket = sockets.get_mut(fd)
+        .and_then(|s| s.as_mut())
+        .ok_or(NetError::InvalidSocket)?;
+
     // For UDP sockets
     if socket.type_ == SocketType::Dgram {
         udp::bind(port).map_err(|_| NetError::AddressInUse)?;
@@ -169,7 +277,7 @@ pub fn listen(fd: usize, _backlog: usize) -> Result<(), NetError> {
 
 /// Accept connection
 pub fn accept(fd: usize) -> Result<usize, NetError> {
-    let local_port = {
+    let (local_port, options) = {
         let mut sockets = SOCKETS.lock();
         let socket = sockets.get_mut(fd)
             .and_then(|s| s.as_mut())
@@ -179,7 +287,7 @@ pub fn accept(fd: usize) -> Result<usize, NetError> {
             return Err(NetError::InvalidState);
         }
 
-        socket.local_port.unwrap()
+        (socket.local_port.unwrap(), socket.options)
     };
 
     // Try to accept
@@ -200,12 +308,9 @@ pub fn accept(fd: usize) -> Result<usize, NetError> {
     new_socket.remote_addr = Some(conn_id.remote_addr);
     new_socket.remote_port = Some(conn_id.remote_port);
     new_socket.tcp_id = Some(conn_id);
-
-    let mut sockets = SOCKETS.lock();
-    if new_fd >= sockets.len() {
-        sockets.resize_with(new_fd + 1, || None);
-    }
-    sockets[new_fd] = Some(Box::new(new_socket));
+    new_socket.options = options;
+    options.apply(conn_id);
+    insert(new_socket);
 
     Ok(new_fd)
 }
@@ -221,6 +326,7 @@ pub fn connect(fd: usize, addr: Ipv4Address, port: Port) -> Result<(), NetError>
         SocketType::Stream => {
             // TCP connect
             let conn_id = tcp::connect(addr, port).map_err(|_| NetError::ConnectionRefused)?;
+            socket.options.apply(conn_id);
             socket.tcp_id = Some(conn_id);
             socket.state = SocketState::Connecting;
             socket.remote_addr = Some(addr);
@@ -240,8 +346,39 @@ pub fn connect(fd: usize, addr: Ipv4Address, port: Port) -> Result<(), NetError>
     Ok(())
 }
 
+/// `recv` flags, as on Linux
+/// Look at what has arrived, leaving it to be received again
+pub const MSG_PEEK: i32 = 0x02;
+/// Wait for the buffers to fill rather than for any data at all
+pub const MSG_WAITALL: i32 = 0x100;
+
 /// Send data
-pub fn send(fd: usize, data: &[u8], _flags: i32) -> Result<usize, NetError> {
+pub fn send(fd: usize, data: &[u8], flags: i32) -> Result<usize, NetError> {
+    sendmsg(fd, &[data], flags, None)
+}
+
+/// Receive data
+pub fn recv(fd: usize, buf: &mut [u8], flags: i32) -> Result<usize, NetError> {
+    recvmsg(fd, &mut [buf], flags).map(|(len, _)| len)
+}
+
+/// Send `bufs` one after another, as a single datagram for UDP, to `to`
+/// or else to the address connected to
+///
+/// No flags apply to sending yet.
+pub fn sendmsg(fd: usize, bufs: &[&[u8]], flags: i32, to: Option<(Ipv4Address, Port)>) -> Result<usize, NetError> {
+    if flags != 0 {
+        return Err(NetError::InvalidArgument);
+    }
+    let gathered;
+    let data = match bufs {
+        [one] => *one,
+        _ => {
+            gathered = bufs.concat();
+            &gathered[..]
+        }
+    };
+
     let mut sockets = SOCKETS.lock();
     let socket = sockets.get_mut(fd)
         .and_then(|s| s.as_mut())
@@ -249,13 +386,23 @@ pub fn send(fd: usize, data: &[u8], _flags: i32) -> Result<usize, NetError> {
 
     match socket.type_ {
         SocketType::Stream => {
+            if to.is_some() {
+                return Err(NetError::NotSupported);
+            }
             let conn_id = socket.tcp_id.ok_or(NetError::NotConnected)?;
-            tcp::send(conn_id, data).map_err(|_| NetError::ConnectionReset)
+            let timeout = if socket.non_blocking { None } else { socket.options.send_timeout };
+            drop(sockets);
+            send_stream(conn_id, data, timeout)
         }
         SocketType::Dgram => {
             let local_port = socket.local_port.ok_or(NetError::NotBound)?;
-            let remote_addr = socket.remote_addr.ok_or(NetError::NotConnected)?;
-            let remote_port = socket.remote_port.ok_or(NetError::NotConnected)?;
+            let (remote_addr, remote_port) = match to {
+                Some(to) => to,
+                None => (
+                    socket.remote_addr.ok_or(NetError::NotConnected)?,
+                    socket.remote_port.ok_or(NetError::NotConnected)?,
+                ),
+            };
             
             udp::send_to(local_port, remote_addr, remote_port, data)
                 .map_err(|_| NetError::NetworkError)
@@ -263,73 +410,203 @@ pub fn send(fd: usize, data: &[u8], _flags: i32) -> Result<usize, NetError> {
     }
 }
 
-/// Receive data
-pub fn recv(fd: usize, buf: &mut [u8], _flags: i32) -> Result<usize, NetError> {
+/// Receive into `bufs`, filling each before the next; for UDP, also says
+/// who sent the datagram
+///
+/// `MSG_PEEK` leaves what is read to be received again. `MSG_WAITALL`
+/// waits for the buffers to fill until the connection ends or the receive
+/// timeout passes; a datagram is never split between receives, so UDP
+/// ignores it.
+pub fn recvmsg(fd: usize, bufs: &mut [&mut [u8]], flags: i32) -> Result<(usize, Option<(Ipv4Address, Port)>), NetError> {
+    if flags & !(MSG_PEEK | MSG_WAITALL) != 0 {
+        return Err(NetError::InvalidArgument);
+    }
+    if let [buf] = bufs {
+        return receive_into(fd, buf, flags);
+    }
+    let mut data = vec![0u8; bufs.iter().map(|buf| buf.len()).sum()];
+    let (len, from) = receive_into(fd, &mut data, flags)?;
+    scatter(&data[..len], bufs);
+    Ok((len, from))
+}
+
+fn receive_into(fd: usize, buf: &mut [u8], flags: i32) -> Result<(usize, Option<(Ipv4Address, Port)>), NetError> {
+    let peek = flags & MSG_PEEK != 0;
     let mut sockets = SOCKETS.lock();
     let socket = sockets.get_mut(fd)
         .and_then(|s| s.as_mut())
         .ok_or(NetError::InvalidSocket)?;
+    let timeout = if socket.non_blocking { None } else { socket.options.recv_timeout };
 
     match socket.type_ {
         SocketType::Stream => {
             let conn_id = socket.tcp_id.ok_or(NetError::NotConnected)?;
-            tcp::receive(conn_id, buf).map_err(|_| NetError::ConnectionReset)
+            drop(sockets);
+            let len = recv_stream(conn_id, buf, timeout, peek, flags & MSG_WAITALL != 0)?;
+            Ok((len, None))
         }
         SocketType::Dgram => {
             let local_port = socket.local_port.ok_or(NetError::NotBound)?;
-            
-            match udp::receive_from(local_port, buf) {
-                Some((_, _, len)) => Ok(len),
-                None => Err(NetError::WouldBlock),
+            drop(sockets);
+            let (addr, port, len) = recv_datagram(local_port, buf, timeout, peek)?;
+            Ok((len, Some((addr, port))))
+        }
+    }
+}
+
+/// Spread `data` over `bufs`, filling each before the next
+fn scatter(mut data: &[u8], bufs: &mut [&mut [u8]]) {
+    for buf in bufs.iter_mut() {
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+/// Take in frames and run the timers, delayed acknowledgements and
+/// retransmissions among them, while a call waits
+fn idle() {
+    net::poll();
+    timer::poll();
+}
+
+/// Write to a TCP connection, waiting up to `timeout` ms for room in its
+/// send buffer when none is left
+fn send_stream(id: tcp::ConnectionId, data: &[u8], timeout: Option<u64>) -> Result<usize, NetError> {
+    let deadline = timeout.map(|ms| timer::elapsed_ms() + ms);
+    loop {
+        let sent = tcp::send(id, data).map_err(|_| NetError::ConnectionReset)?;
+        if sent > 0 || data.is_empty() {
+            return Ok(sent);
+        }
+        match deadline {
+            Some(deadline) if timer::elapsed_ms() < deadline => idle(),
+            _ => return Err(NetError::WouldBlock),
+        }
+    }
+}
+
+/// Read from a TCP connection, waiting up to `timeout` ms for data while
+/// the peer may still send some; with no timeout, returns 0 at once when
+/// nothing has arrived
+///
+/// With `wait_all`, waits for `buf` to fill instead, for as long as it
+/// takes if there is no timeout.
+fn recv_stream(id: tcp::ConnectionId, buf: &mut [u8], timeout: Option<u64>, peek: bool, wait_all: bool) -> Result<usize, NetError> {
+    let deadline = timeout.map(|ms| timer::elapsed_ms() + ms);
+    loop {
+        let len = tcp::peek(id, buf).map_err(|_| NetError::ConnectionReset)?;
+        let open = matches!(tcp::state(id), Some(
+            tcp::TcpState::SynSent | tcp::TcpState::SynReceived | tcp::TcpState::Established
+                | tcp::TcpState::FinWait1 | tcp::TcpState::FinWait2
+        ));
+        let enough = if wait_all { len == buf.len() } else { len > 0 || buf.is_empty() };
+        if enough || !open {
+            return consume(id, buf, len, peek);
+        }
+        match deadline {
+            None if !wait_all => return Ok(0),
+            Some(deadline) if timer::elapsed_ms() >= deadline => {
+                return if len > 0 { consume(id, buf, len, peek) } else { Err(NetError::WouldBlock) };
             }
+            _ => idle(),
         }
     }
 }
 
-/// Send to specific address (UDP)
-pub fn sendto(fd: usize, data: &[u8], _flags: i32, addr: Ipv4Address, port: Port) -> Result<usize, NetError> {
+/// Take the `len` bytes just peeked at off the connection, unless only
+/// peeking
+fn consume(id: tcp::ConnectionId, buf: &mut [u8], len: usize, peek: bool) -> Result<usize, NetError> {
+    if peek {
+        return Ok(len);
+    }
+    tcp::receive(id, &mut buf[..len]).map_err(|_| NetError::ConnectionReset)
+}
+
+/// Take the oldest datagram waiting on `local_port`, waiting up to
+/// `timeout` ms for one to arrive
+fn recv_datagram(local_port: Port, buf: &mut [u8], timeout: Option<u64>, peek: bool) -> Result<(Ipv4Address, Port, usize), NetError> {
+    let deadline = timeout.map(|ms| timer::elapsed_ms() + ms);
+    loop {
+        let received = if peek { udp::peek_from(local_port, buf) } else { udp::receive_from(local_port, buf) };
+        if let Some(received) = received {
+            return Ok(received);
+        }
+        match deadline {
+            Some(deadline) if timer::elapsed_ms() < deadline => idle(),
+            _ => return Err(NetError::WouldBlock),
+        }
+    }
+}
+
+/// Set an option on a socket
+pub fn setsockopt(fd: usize, option: SocketOption) -> Result<(), NetError> {
     let mut sockets = SOCKETS.lock();
     let socket = sockets.get_mut(fd)
         .and_then(|s| s.as_mut())
         .ok_or(NetError::InvalidSocket)?;
 
-    if socket.type_ != SocketType::Dgram {
-        return Err(NetError::NotSupported);
+    match option {
+        SocketOption::NoDelay(_) | SocketOption::QuickAck(_) | SocketOption::Linger(_)
+            if socket.type_ != SocketType::Stream => return Err(NetError::NotSupported),
+        SocketOption::RecvBuffer(0) | SocketOption::SendBuffer(0) => return Err(NetError::InvalidArgument),
+        _ => {}
+    }
+    socket.options.set(option);
+    if let Some(conn_id) = socket.tcp_id {
+        socket.options.apply(conn_id);
     }
 
-    let local_port = socket.local_port.ok_or(NetError::NotBound)?;
+    Ok(())
+}
+
+/// The options a socket has
+pub fn getsockopt(fd: usize) -> Result<SocketOptions, NetError> {
+    SOCKETS.lock().get(fd)
+        .and_then(|s| s.as_ref())
+        .map(|s| s.options)
+        .ok_or(NetError::InvalidSocket)
+}
 
-    udp::send_to(local_port, addr, port, data)
-        .map_err(|_| NetError::NetworkError)
+/// Send to specific address (UDP)
+pub fn sendto(fd: usize, data: &[u8], flags: i32, addr: Ipv4Address, port: Port) -> Result<usize, NetError> {
+    sendmsg(fd, &[data], flags, Some((addr, port)))
 }
 
 /// Receive from address (UDP)
-pub fn recvfrom(fd: usize, buf: &mut [u8], _flags: i32) -> Result<(usize, Ipv4Address, Port), NetError> {
-    let mut sockets = SOCKETS.lock();
-    let socket = sockets.get_mut(fd)
-        .and_then(|s| s.as_mut())
+pub fn recvfrom(fd: usize, buf: &mut [u8], flags: i32) -> Result<(usize, Ipv4Address, Port), NetError> {
+    let type_ = SOCKETS.lock().get(fd)
+        .and_then(|s| s.as_ref())
+        .map(|s| s.type_)
         .ok_or(NetError::InvalidSocket)?;
-
-    if socket.type_ != SocketType::Dgram {
+    if type_ != SocketType::Dgram {
         return Err(NetError::NotSupported);
     }
 
-    let local_port = socket.local_port.ok_or(NetError::NotBound)?;
-
-    match udp::receive_from(local_port, buf) {
-        Some((addr, port, len)) => Ok((len, addr, port)),
-        None => Err(NetError::WouldBlock),
+    match recvmsg(fd, &mut [buf], flags)? {
+        (len, Some((addr, port))) => Ok((len, addr, port)),
+        (_, None) => Err(NetError::NotSupported),
     }
 }
 
 /// Close socket
+///
+/// With SO_LINGER set, waits for what the connection has not sent to be
+/// acknowledged, or resets it for a linger of 0.
 pub fn close(fd: usize) -> Result<(), NetError> {
     let mut sockets = SOCKETS.lock();
+    let mut lingering = None;
     
     if let Some(Some(socket)) = sockets.get_mut(fd) {
         if socket.type_ == SocketType::Stream {
             if let Some(conn_id) = socket.tcp_id {
-                let _ = tcp::close(conn_id);
+                match socket.options.linger {
+                    Some(0) => tcp::abort(conn_id),
+                    linger => {
+                        let _ = tcp::close(conn_id);
+                        lingering = linger.map(|ms| (conn_id, timer::elapsed_ms() + ms));
+                    }
+                }
             }
         } else if socket.type_ == SocketType::Dgram {
             if let Some(port) = socket.local_port {
@@ -338,15 +615,36 @@ pub fn close(fd: usize) -> Result<(), NetError> {
         }
         
         socket.state = SocketState::Closed;
+        if let Some(pid) = socket.owner {
+            crate::mm::uncharge(pid, RX_BUFFER_SIZE as u64);
+        }
     }
 
     if fd < sockets.len() {
         sockets[fd] = None;
     }
+    drop(sockets);
+
+    if let Some((conn_id, deadline)) = lingering {
+        while tcp::unacknowledged(conn_id) > 0 && timer::elapsed_ms() < deadline {
+            idle();
+        }
+    }
 
     Ok(())
 }
 
+/// Close every socket a process opened
+pub fn close_owned(pid: Pid) {
+    let fds: Vec<usize> = SOCKETS.lock().iter().flatten()
+        .filter(|s| s.owner == Some(pid))
+        .map(|s| s.fd)
+        .collect();
+    for fd in fds {
+        let _ = close(fd);
+    }
+}
+
 /// Get socket by fd
 pub fn get_socket(fd: usize) -> Option<Box<Socket>> {
     SOCKETS.lock().get(fd).and_then(|opt| opt.as_ref().map(|s| alloc::boxed::Box::new(Socket {
@@ -362,6 +660,8 @@ pub fn get_socket(fd: usize) -> Option<Box<Socket>> {
         tcp_id: s.tcp_id,
         rx_buffer: alloc::vec::Vec::new(),
         non_blocking: s.non_blocking,
+        owner: s.owner,
+        options: s.options,
     })))
 }
 
@@ -491,4 +791,152 @@ pub enum NetError {
     WouldBlock = 8,
     NotSupported = 9,
     NetworkError = 10,
+    InvalidArgument = 11,
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn options_are_kept_and_checked() -> Result<(), String> {
+        let tcp = socket(SocketDomain::Inet, SocketType::Stream, SocketProtocol::Tcp).map_err(|_| "no socket")?;
+        let udp = socket(SocketDomain::Inet, SocketType::Dgram, SocketProtocol::Udp).map_err(|_| "no socket")?;
+        check_eq!(getsockopt(tcp).map(|o| o.no_delay), Ok(false));
+        check!(setsockopt(tcp, SocketOption::NoDelay(true)).is_ok());
+        check!(setsockopt(tcp, SocketOption::RecvTimeout(Some(250))).is_ok());
+        let options = getsockopt(tcp).map_err(|_| "no options")?;
+        check!(options.no_delay);
+        check_eq!(options.recv_timeout, Some(250));
+        check_eq!(setsockopt(udp, SocketOption::NoDelay(true)), Err(NetError::NotSupported));
+        check_eq!(setsockopt(tcp, SocketOption::SendBuffer(0)), Err(NetError::InvalidArgument));
+        let _ = close(tcp);
+        let _ = close(udp);
+        check_eq!(getsockopt(tcp), Err(NetError::InvalidSocket));
+        Ok(())
+    }
+
+    const HERE: Ipv4Address = Ipv4Address::new([192, 0, 2, 1]);
+    const PEER: Ipv4Address = Ipv4Address::new([192, 0, 2, 2]);
+    const PEER_PORT: u16 = 50123;
+
+    /// A segment from the peer to `port`
+    fn arrive(port: Port, seq: u32, flags: u8, payload: &[u8]) {
+        let header = tcp::TcpHeader {
+            src_port: PEER_PORT,
+            dst_port: port.as_u16(),
+            seq,
+            ack: 0,
+            data_offset: 5 << 4,
+            flags,
+            window: 65535,
+            checksum: 0,
+            urgent: 0,
+        };
+        let mut segment = header.to_bytes().to_vec();
+        segment.extend_from_slice(payload);
+        tcp::process_tcp_packet(PEER, HERE, &segment);
+    }
+
+    /// A connection the peer made to `port`, as accepted; its first data
+    /// byte is sequence number 1001
+    fn accepted(port: Port) -> Result<(usize, tcp::ConnectionId), String> {
+        let listener = socket(SocketDomain::Inet, SocketType::Stream, SocketProtocol::Tcp).map_err(|_| "no socket")?;
+        bind(listener, Ipv4Address::unspecified(), port).map_err(|e| alloc::format!("{:?}", e))?;
+        listen(listener, 1).map_err(|e| alloc::format!("{:?}", e))?;
+        arrive(port, 1000, tcp::TCP_FLAG_SYN, &[]);
+        arrive(port, 1001, tcp::TCP_FLAG_ACK, &[]);
+        let fd = accept(listener).map_err(|e| alloc::format!("{:?}", e))?;
+        let _ = close(listener);
+        tcp::unlisten(port);
+        let id = SOCKETS.lock().get(fd).and_then(|s| s.as_ref()).and_then(|s| s.tcp_id).ok_or("no connection")?;
+        Ok((fd, id))
+    }
+
+    #[kernel_test]
+    fn each_option_changes_the_connection() -> Result<(), String> {
+        let port = Port::new(40505);
+        let (fd, id) = accepted(port)?;
+        let mut buf = [0u8; 8192];
+
+        // SO_RCVBUF: a segment bigger than the buffer is refused
+        check!(setsockopt(fd, SocketOption::RecvBuffer(4096)).is_ok());
+        arrive(port, 1001, tcp::TCP_FLAG_ACK | tcp::TCP_FLAG_PSH, &[1; 5000]);
+        check_eq!(recv(fd, &mut buf, 0), Ok(0));
+        check!(setsockopt(fd, SocketOption::RecvBuffer(8192)).is_ok());
+        arrive(port, 1001, tcp::TCP_FLAG_ACK | tcp::TCP_FLAG_PSH, &[1; 5000]);
+        check_eq!(recv(fd, &mut buf, 0), Ok(5000));
+
+        // TCP_QUICKACK: a small segment is acknowledged at once rather
+        // than on a timer
+        let timers = timer::pending();
+        arrive(port, 6001, tcp::TCP_FLAG_ACK | tcp::TCP_FLAG_PSH, &[2; 10]);
+        check_eq!(timer::pending(), timers + 1);
+        check!(setsockopt(fd, SocketOption::QuickAck(true)).is_ok());
+        check_eq!(timer::pending(), timers);
+        arrive(port, 6011, tcp::TCP_FLAG_ACK | tcp::TCP_FLAG_PSH, &[2; 10]);
+        check_eq!(timer::pending(), timers);
+        check_eq!(recv(fd, &mut buf, 0), Ok(20));
+
+        // SO_SNDTIMEO: with the send buffer full, send waits that long
+        // for room
+        check!(setsockopt(fd, SocketOption::SendBuffer(4096)).is_ok());
+        check_eq!(send(fd, &[3; 4096], 0), Ok(4096));
+        let start = timer::elapsed_ms();
+        check_eq!(send(fd, &[3], 0), Err(NetError::WouldBlock));
+        check!(timer::elapsed_ms() - start < 30);
+        check!(setsockopt(fd, SocketOption::SendTimeout(Some(30))).is_ok());
+        let start = timer::elapsed_ms();
+        check_eq!(send(fd, &[3], 0), Err(NetError::WouldBlock));
+        check!(timer::elapsed_ms() - start >= 30);
+
+        // SO_REUSEADDR: the port the connection still uses may be bound
+        let again = socket(SocketDomain::Inet, SocketType::Stream, SocketProtocol::Tcp).map_err(|_| "no socket")?;
+        check_eq!(bind(again, Ipv4Address::unspecified(), port), Err(NetError::AddressInUse));
+        check!(setsockopt(again, SocketOption::ReuseAddr(true)).is_ok());
+        check_eq!(bind(again, Ipv4Address::unspecified(), port), Ok(()));
+        let _ = close(again);
+
+        // SO_LINGER: close waits for the unacknowledged data, up to the
+        // time given
+        check!(setsockopt(fd, SocketOption::Linger(Some(30))).is_ok());
+        let start = timer::elapsed_ms();
+        check_eq!(close(fd), Ok(()));
+        check!(timer::elapsed_ms() - start >= 30);
+        check_eq!(tcp::state(id), Some(tcp::TcpState::FinWait1));
+        tcp::abort(id);
+
+        // and a linger of 0 resets the connection instead
+        let (fd, id) = accepted(port)?;
+        check!(setsockopt(fd, SocketOption::Linger(Some(0))).is_ok());
+        check_eq!(close(fd), Ok(()));
+        check_eq!(tcp::state(id), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn datagrams_are_peeked_scattered_and_sourced() -> Result<(), String> {
+        let fd = socket(SocketDomain::Inet, SocketType::Dgram, SocketProtocol::Udp).map_err(|_| "no socket")?;
+        let port = Port::new(40404);
+        bind(fd, Ipv4Address::unspecified(), port).map_err(|_| "cannot bind")?;
+        let server = Ipv4Address::from_octets(10, 0, 2, 3);
+        for payload in [&b"first"[..], &b"second"[..]] {
+            let mut datagram = alloc::vec![0, 53, 0x9D, 0xD4, 0, 8 + payload.len() as u8, 0, 0];
+            datagram.extend_from_slice(payload);
+            udp::process_udp_packet(server, Ipv4Address::unspecified(), &datagram);
+        }
+
+        let mut buf = [0u8; 16];
+        check_eq!(recvfrom(fd, &mut buf, MSG_PEEK), Ok((5, server, Port::new(53))));
+        let (mut head, mut tail) = ([0u8; 3], [0u8; 8]);
+        let (len, from) = recvmsg(fd, &mut [&mut head[..], &mut tail[..]], 0).map_err(|_| "no datagram")?;
+        check_eq!((len, from), (5, Some((server, Port::new(53)))));
+        check_eq!((&head[..], &tail[..2]), (&b"fir"[..], &b"st"[..]));
+        check_eq!(recv(fd, &mut buf, 0), Ok(6));
+        check_eq!(recv(fd, &mut buf, 0), Err(NetError::WouldBlock));
+        check_eq!(recv(fd, &mut buf, 0x4000), Err(NetError::InvalidArgument));
+        let _ = close(fd);
+        Ok(())
+    }
 }
@@ -0,0 +1,308 @@
+//! TCP congestion control
+//!
+//! How much a connection may have in flight. It starts at ten segments
+//! (RFC 6928) and doubles each round trip in slow start. Past the slow
+//! start threshold, Reno (RFC 5681) adds a segment per round trip, and
+//! CUBIC (RFC 8312) grows along a cubic curve centred on the window at
+//! the last loss, so it gets back to speed quickly on long fat paths.
+//! Three duplicate acknowledgements start fast retransmit and recovery,
+//! which lasts until everything sent before the loss is acknowledged
+//! (NewReno, RFC 6582). A retransmission timeout goes back to one
+//! segment.
+//!
+//! The kernel has no floating point to spare, so CUBIC works in bytes and
+//! milliseconds with its constants as fractions.
+
+use crate::config;
+
+/// Segments in flight to begin with
+const INITIAL_SEGMENTS: usize = 10;
+
+/// How the window grows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Reno,
+    Cubic,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reno" => Some(Algorithm::Reno),
+            "cubic" => Some(Algorithm::Cubic),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Reno => "reno",
+            Algorithm::Cubic => "cubic",
+        }
+    }
+
+    /// The algorithm `network.tcp_congestion` names
+    pub fn configured() -> Self {
+        config::get("network.tcp_congestion").and_then(|name| Self::from_name(&name)).unwrap_or(Algorithm::Cubic)
+    }
+}
+
+/// Whether sequence number `a` comes before `b`
+pub fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Integer cube root
+fn icbrt(n: u128) -> u64 {
+    let (mut low, mut high) = (0u64, 1u64 << 42);
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if (mid as u128).pow(3) <= n {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+/// A connection's congestion window and what governs it
+#[derive(Debug, Clone)]
+pub struct Congestion {
+    algorithm: Algorithm,
+    mss: usize,
+    /// Bytes that may be in flight
+    pub cwnd: usize,
+    /// Window past which slow start ends
+    pub ssthresh: usize,
+    /// Last sequence number sent when loss was found; recovery lasts
+    /// until it is acknowledged
+    recover: Option<u32>,
+    /// Bytes acknowledged toward the next segment of linear growth
+    acked: usize,
+    /// CUBIC: the window when loss was last found
+    w_max: usize,
+    /// CUBIC: when growth since the last loss began, in milliseconds
+    epoch: Option<u64>,
+    /// CUBIC: milliseconds from the epoch until the curve is back at `w_max`
+    k_ms: u64,
+    /// CUBIC: the window Reno would have, which CUBIC never falls below
+    w_est: usize,
+}
+
+impl Congestion {
+    pub fn new(algorithm: Algorithm, mss: usize) -> Self {
+        Self {
+            algorithm,
+            mss,
+            cwnd: INITIAL_SEGMENTS * mss,
+            ssthresh: usize::MAX,
+            recover: None,
+            acked: 0,
+            w_max: 0,
+            epoch: None,
+            k_ms: 0,
+            w_est: 0,
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn in_recovery(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    /// Segment size changed by the peer's SYN
+    pub fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        self.cwnd = INITIAL_SEGMENTS * mss;
+    }
+
+    /// `acked` new bytes acknowledged up to `ack`, at `now` ms with a
+    /// smoothed round trip of `rtt_ms`
+    ///
+    /// Returns true for a partial acknowledgement during recovery, when
+    /// the next unacknowledged segment is to be sent again at once.
+    pub fn on_ack(&mut self, ack: u32, acked: usize, now: u64, rtt_ms: u64) -> bool {
+        if let Some(recover) = self.recover {
+            if seq_before(ack, recover) {
+                // Deflate by what left the network, keep one segment's room
+                self.cwnd = self.cwnd.saturating_sub(acked) + self.mss;
+                return true;
+            }
+            self.recover = None;
+            self.cwnd = self.ssthresh;
+            return false;
+        }
+        if self.cwnd < self.ssthresh {
+            // Counting at most two segments per ACK (RFC 3465)
+            self.cwnd += acked.min(2 * self.mss);
+            return false;
+        }
+        match self.algorithm {
+            Algorithm::Reno => {
+                self.acked += acked;
+                if self.acked >= self.cwnd {
+                    self.acked -= self.cwnd;
+                    self.cwnd += self.mss;
+                }
+            }
+            Algorithm::Cubic => self.cubic_grow(acked, now, rtt_ms),
+        }
+        false
+    }
+
+    fn cubic_grow(&mut self, acked: usize, now: u64, rtt_ms: u64) {
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                if self.cwnd < self.w_max {
+                    // K = cbrt((W_max - cwnd) / C), C = 0.4 segments/s³
+                    let deficit = (self.w_max - self.cwnd) as u128;
+                    self.k_ms = icbrt(deficit * 2_500_000_000 / self.mss as u128);
+                } else {
+                    self.k_ms = 0;
+                    self.w_max = self.cwnd;
+                }
+                self.w_est = self.cwnd;
+                self.acked = 0;
+                self.epoch = Some(now);
+                now
+            }
+        };
+        // Where the curve is a round trip from now
+        let t = (now.saturating_sub(epoch) + rtt_ms) as i128;
+        let d = t - self.k_ms as i128;
+        let target = self.w_max as i128 + 4 * d * d * d * self.mss as i128 / 10_000_000_000;
+        let target = target.clamp(self.mss as i128, (self.cwnd + self.cwnd / 2) as i128) as usize;
+
+        // Reno's window under CUBIC's decrease: 3(1 - β)/(1 + β) = 9/17
+        // segments per round trip
+        self.acked += acked;
+        let per_segment = self.cwnd * 17 / 9;
+        while self.acked >= per_segment {
+            self.acked -= per_segment;
+            self.w_est += self.mss;
+        }
+
+        if self.w_est > self.cwnd.max(target) {
+            self.cwnd = self.w_est;
+        } else if target > self.cwnd {
+            self.cwnd += ((target - self.cwnd) * acked / self.cwnd).max(1);
+        }
+    }
+
+    /// New threshold on loss, with `in_flight` bytes out
+    fn reduce(&mut self, in_flight: usize) {
+        self.ssthresh = match self.algorithm {
+            Algorithm::Reno => in_flight / 2,
+            Algorithm::Cubic => {
+                // Fast convergence: a window shrinking since the last loss
+                // gives way to newer flows
+                self.w_max = if self.cwnd < self.w_max { self.cwnd * 17 / 20 } else { self.cwnd };
+                self.epoch = None;
+                self.cwnd * 7 / 10
+            }
+        }.max(2 * self.mss);
+        self.acked = 0;
+    }
+
+    /// Three duplicate acknowledgements: the segment at the front was
+    /// lost. `high` is the next sequence number to be sent.
+    pub fn on_fast_retransmit(&mut self, in_flight: usize, high: u32) {
+        self.reduce(in_flight);
+        self.cwnd = self.ssthresh + 3 * self.mss;
+        self.recover = Some(high);
+    }
+
+    /// A further duplicate acknowledgement during recovery: another
+    /// segment has left the network
+    pub fn on_duplicate(&mut self) {
+        if self.recover.is_some() {
+            self.cwnd += self.mss;
+        }
+    }
+
+    /// The retransmission timer went off; `first` unless it already had
+    /// for the same data
+    pub fn on_timeout(&mut self, in_flight: usize, high: u32, first: bool) {
+        if first {
+            self.reduce(in_flight);
+        }
+        self.cwnd = self.mss;
+        self.recover = Some(high);
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    const MSS: usize = 1000;
+
+    #[kernel_test]
+    fn slow_start_doubles_then_reno_adds_a_segment() -> Result<(), String> {
+        let mut cc = Congestion::new(Algorithm::Reno, MSS);
+        check_eq!(cc.cwnd, 10 * MSS);
+        for _ in 0..10 {
+            cc.on_ack(0, MSS, 0, 100);
+        }
+        check_eq!(cc.cwnd, 20 * MSS);
+
+        cc.ssthresh = cc.cwnd;
+        for _ in 0..20 {
+            cc.on_ack(0, MSS, 0, 100);
+        }
+        check_eq!(cc.cwnd, 21 * MSS);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn fast_recovery_halves_and_ends_on_a_full_ack() -> Result<(), String> {
+        let mut cc = Congestion::new(Algorithm::Reno, MSS);
+        cc.on_fast_retransmit(20 * MSS, 5000);
+        check_eq!(cc.ssthresh, 10 * MSS);
+        check_eq!(cc.cwnd, 13 * MSS);
+        cc.on_duplicate();
+        check_eq!(cc.cwnd, 14 * MSS);
+        check!(cc.on_ack(3000, MSS, 0, 100));
+        check!(cc.in_recovery());
+        check!(!cc.on_ack(5000, MSS, 0, 100));
+        check!(!cc.in_recovery());
+        check_eq!(cc.cwnd, 10 * MSS);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn cubic_backs_off_less_and_regrows_toward_the_old_window() -> Result<(), String> {
+        let mut cc = Congestion::new(Algorithm::Cubic, MSS);
+        cc.cwnd = 100 * MSS;
+        cc.on_fast_retransmit(100 * MSS, 1);
+        check_eq!(cc.ssthresh, 70 * MSS);
+        cc.on_ack(1, MSS, 0, 50);
+        check_eq!(cc.cwnd, 70 * MSS);
+
+        // Acks for a few seconds bring it back near where it was lost
+        let mut now = 0;
+        while now < 5000 {
+            cc.on_ack(1, MSS, now, 50);
+            now += 5;
+        }
+        check!(cc.cwnd > 90 * MSS);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn timeouts_go_back_to_one_segment() -> Result<(), String> {
+        let mut cc = Congestion::new(Algorithm::Cubic, MSS);
+        cc.on_timeout(10 * MSS, 1, true);
+        check_eq!(cc.cwnd, MSS);
+        check_eq!(cc.ssthresh, 7 * MSS);
+        check_eq!(icbrt(27_000_000), 300);
+        Ok(())
+    }
+}
@@ -0,0 +1,1113 @@
+//! TCP (Transmission Control Protocol)
+//!
+//! Full TCP implementation with connection state management.
+//!
+//! Whatever takes sequence space — a SYN, data, a FIN — stays outstanding
+//! until it is acknowledged. A timer then sends the oldest of it again,
+//! doubling the timeout each time, and the connection is dropped after
+//! `MAX_RETRIES` tries.
+//!
+//! Data written goes into a send buffer and out in segments as the peer's
+//! window and the congestion window (`congestion`) allow. Window scaling
+//! lets either side offer more than 64KB, and timestamps time the round
+//! trip, from which the retransmission timeout is worked out (RFC 6298).
+//!
+//! A small write waits while earlier data is unacknowledged, so that it
+//! goes out with what follows (Nagle, RFC 896) unless the connection is
+//! set `nodelay`. Data received is acknowledged after every second full
+//! segment, or once `network.tcp_delayed_ack` milliseconds have passed
+//! without anything else carrying the acknowledgement.
+
+mod congestion;
+mod options;
+
+pub use congestion::Algorithm;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::arch::simd;
+use crate::config;
+use crate::drivers::timer::{self, TimerId};
+use crate::net::{Ipv4Address, Port, IpProtocol, ip};
+use crate::println;
+use crate::warn;
+use congestion::Congestion;
+use options::Options;
+
+/// Retransmission timeout to start with, its floor and its ceiling
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 60_000;
+/// Retransmissions before giving up on the connection
+const MAX_RETRIES: u32 = 8;
+/// Largest segment we take, and send
+const MSS: usize = 1460;
+/// Segment size to assume when the peer gives none
+const DEFAULT_MSS: usize = 536;
+/// Bytes a connection holds that have arrived and not been read
+pub const RECV_BUFFER: usize = 1 << 20;
+/// Bytes a connection holds that have been written and not acknowledged
+pub const SEND_BUFFER: usize = 1 << 20;
+/// Least either buffer can be set to
+const MIN_BUFFER: usize = 4096;
+/// Shift of our window field, enough to offer all of `RECV_BUFFER`
+const RECV_SCALE: u8 = 5;
+/// Room the timestamps option takes in each segment
+const TIMESTAMPS_LEN: usize = 12;
+/// Duplicate acknowledgements that mean a segment was lost
+const DUP_ACK_THRESHOLD: u32 = 3;
+/// Longest an acknowledgement may be held back (RFC 1122)
+pub const MAX_ACK_DELAY_MS: u64 = 500;
+
+/// TCP header
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub data_offset: u8,
+    pub flags: u8,
+    pub window: u16,
+    pub checksum: u16,
+    pub urgent: u16,
+}
+
+/// TCP flags
+pub const TCP_FLAG_FIN: u8 = 0x01;
+pub const TCP_FLAG_SYN: u8 = 0x02;
+pub const TCP_FLAG_RST: u8 = 0x04;
+pub const TCP_FLAG_PSH: u8 = 0x08;
+pub const TCP_FLAG_ACK: u8 = 0x10;
+pub const TCP_FLAG_URG: u8 = 0x20;
+
+impl TcpHeader {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < 20 {
+            return None;
+        }
+
+        Some(Self {
+            src_port: u16::from_be_bytes([data[0], data[1]]),
+            dst_port: u16::from_be_bytes([data[2], data[3]]),
+            seq: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ack: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            data_offset: data[12],
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            checksum: u16::from_be_bytes([data[16], data[17]]),
+            urgent: u16::from_be_bytes([data[18], data[19]]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 20] {
+        let mut buf = [0u8; 20];
+        buf[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        buf[12] = self.data_offset;
+        buf[13] = self.flags;
+        buf[14..16].copy_from_slice(&self.window.to_be_bytes());
+        buf[16..18].copy_from_slice(&self.checksum.to_be_bytes());
+        buf[18..20].copy_from_slice(&self.urgent.to_be_bytes());
+        buf
+    }
+
+    pub fn header_len(&self) -> usize {
+        (((self.data_offset >> 4) & 0x0F) as usize) * 4
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        (self.flags & flag) != 0
+    }
+
+    /// Calculate TCP checksum (pseudo-header + header + data)
+    pub fn calculate_checksum(&self, src: Ipv4Address, dst: Ipv4Address, data: &[u8]) -> u16 {
+        let header_bytes = self.to_bytes();
+        let mut sum: u32 = 0;
+
+        // Pseudo-header
+        for chunk in src.as_bytes().chunks(2) {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        for chunk in dst.as_bytes().chunks(2) {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        sum += IpProtocol::Tcp as u32;
+        sum += (20 + data.len()) as u32;
+
+        // TCP header
+        for i in (0..20).step_by(2) {
+            sum += u16::from_be_bytes([header_bytes[i], header_bytes[i + 1]]) as u32;
+        }
+
+        // TCP data
+        for i in (0..data.len()).step_by(2) {
+            if i + 1 < data.len() {
+                sum += u16::from_be_bytes([data[i], data[i + 1]]) as u32;
+            } else {
+                sum += (data[i] as u32) << 8;
+            }
+        }
+
+        while (sum >> 16) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
+}
+
+/// TCP connection state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// TCP connection identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId {
+    pub local_addr: Ipv4Address,
+    pub local_port: Port,
+    pub remote_addr: Ipv4Address,
+    pub remote_port: Port,
+}
+
+/// TCP connection
+pub struct TcpConnection {
+    pub id: ConnectionId,
+    pub state: TcpState,
+    pub seq_num: u32,
+    pub ack_num: u32,
+    /// Oldest sequence number not yet acknowledged
+    pub snd_una: u32,
+    /// Bytes the peer will take past `snd_una`
+    pub send_window: usize,
+    /// Receive buffer
+    pub rx_buffer: Vec<u8>,
+    /// Data written and waiting for room in the windows
+    pub tx_buffer: Vec<u8>,
+    /// Data sent and not yet acknowledged, from `snd_una`
+    pub unacked: Vec<u8>,
+    /// User waiting on this connection
+    pub waiting: bool,
+    /// Most that `rx_buffer`, and `tx_buffer` with `unacked`, may hold
+    recv_buffer: usize,
+    send_buffer: usize,
+    /// Send small writes at once rather than holding them for Nagle
+    nodelay: bool,
+    /// How long an acknowledgement may wait for data to ride on; 0 to
+    /// acknowledge every segment at once
+    ack_delay_ms: u64,
+    /// Bytes taken in and not yet acknowledged
+    ack_pending: usize,
+    ack_timer: Option<TimerId>,
+    /// Largest segment the peer takes, options included
+    mss: usize,
+    /// Shifts of the peer's window field and of ours; 0 unless both
+    /// sides offered scaling
+    snd_scale: u8,
+    rcv_scale: u8,
+    /// Whether segments carry timestamps
+    timestamps: bool,
+    /// The peer's latest timestamp, echoed back to it
+    ts_recent: u32,
+    congestion: Congestion,
+    /// The same acknowledgement received again, in a row
+    dup_acks: u32,
+    /// Our FIN is to follow what is left in `tx_buffer`
+    fin_pending: bool,
+    /// Smoothed round trip and its variation, once one has been timed
+    srtt_ms: Option<u64>,
+    rttvar_ms: u64,
+    /// Current retransmission timeout
+    rto_ms: u64,
+    /// Retransmissions since the last acknowledgement
+    retries: u32,
+    retransmit_timer: Option<TimerId>,
+}
+
+impl TcpConnection {
+    pub fn new(id: ConnectionId) -> Self {
+        static NEXT_SEQ: AtomicU32 = AtomicU32::new(1000);
+
+        let seq = NEXT_SEQ.fetch_add(1, Ordering::SeqCst);
+        Self {
+            id,
+            state: TcpState::Closed,
+            seq_num: seq,
+            ack_num: 0,
+            snd_una: seq,
+            send_window: 65535,
+            rx_buffer: Vec::new(),
+            tx_buffer: Vec::new(),
+            unacked: Vec::new(),
+            waiting: false,
+            recv_buffer: RECV_BUFFER,
+            send_buffer: SEND_BUFFER,
+            nodelay: false,
+            ack_delay_ms: ack_delay(),
+            ack_pending: 0,
+            ack_timer: None,
+            mss: DEFAULT_MSS,
+            // Offered in our SYN, and kept if the peer's offers them too
+            snd_scale: 0,
+            rcv_scale: RECV_SCALE,
+            timestamps: true,
+            ts_recent: 0,
+            congestion: Congestion::new(Algorithm::configured(), DEFAULT_MSS),
+            dup_acks: 0,
+            fin_pending: false,
+            srtt_ms: None,
+            rttvar_ms: 0,
+            rto_ms: INITIAL_RTO_MS,
+            retries: 0,
+            retransmit_timer: None,
+        }
+    }
+
+    /// Whether anything sent is still waiting for its acknowledgement
+    fn outstanding(&self) -> bool {
+        self.snd_una != self.seq_num
+    }
+
+    /// Take in what the peer's SYN offered
+    fn negotiate(&mut self, options: &Options) {
+        self.mss = options.mss.map_or(DEFAULT_MSS, |mss| mss as usize).clamp(64, MSS);
+        match options.window_scale {
+            Some(shift) => self.snd_scale = shift,
+            None => {
+                self.snd_scale = 0;
+                self.rcv_scale = 0;
+            }
+        }
+        self.timestamps = options.timestamps.is_some();
+        if let Some((value, _)) = options.timestamps {
+            self.ts_recent = value;
+        }
+        self.congestion.set_mss(self.mss);
+    }
+
+    /// Most data that fits in one segment
+    fn segment_size(&self) -> usize {
+        if self.timestamps { self.mss - TIMESTAMPS_LEN } else { self.mss }
+    }
+
+    /// Window to offer: the room left in the receive buffer
+    fn advertised_window(&self, syn: bool) -> u16 {
+        let free = self.recv_buffer.saturating_sub(self.rx_buffer.len());
+        // The window in a SYN is never scaled
+        let shift = if syn { 0 } else { self.rcv_scale };
+        (free >> shift).min(u16::MAX as usize) as u16
+    }
+
+    /// Options for a segment with `flags`
+    fn options(&self, flags: u8) -> Options {
+        let now = timer::elapsed_ms() as u32;
+        let timestamps = if self.timestamps { Some((now, self.ts_recent)) } else { None };
+        if flags & TCP_FLAG_SYN != 0 {
+            let window_scale = if self.rcv_scale > 0 { Some(self.rcv_scale) } else { None };
+            Options { mss: Some(MSS as u16), window_scale, timestamps }
+        } else {
+            Options { timestamps, ..Options::default() }
+        }
+    }
+
+    /// Take a round trip time into the smoothed estimate
+    fn measure(&mut self, rtt: u64) {
+        match self.srtt_ms {
+            None => {
+                self.srtt_ms = Some(rtt);
+                self.rttvar_ms = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar_ms = (3 * self.rttvar_ms + srtt.abs_diff(rtt)) / 4;
+                self.srtt_ms = Some((7 * srtt + rtt) / 8);
+            }
+        }
+    }
+
+    /// Retransmission timeout from the round trips timed so far
+    fn rto(&self) -> u64 {
+        match self.srtt_ms {
+            Some(srtt) => (srtt + (4 * self.rttvar_ms).max(10)).clamp(MIN_RTO_MS, MAX_RTO_MS),
+            None => INITIAL_RTO_MS,
+        }
+    }
+
+    /// Take an acknowledgement of everything before `ack`, which came
+    /// with the peer's `window` and the timestamp `echo` of ours
+    ///
+    /// `pure` if the segment carried nothing else, so that the same
+    /// acknowledgement again means a segment after a lost one arrived.
+    fn acknowledge(&mut self, ack: u32, window: usize, echo: Option<u32>, pure: bool) {
+        let acked = ack.wrapping_sub(self.snd_una);
+        let in_flight = self.seq_num.wrapping_sub(self.snd_una);
+        if acked > in_flight {
+            return;
+        }
+        if acked == 0 {
+            if pure && self.outstanding() && window == self.send_window {
+                self.dup_acks += 1;
+                if self.dup_acks == DUP_ACK_THRESHOLD && !self.congestion.in_recovery() {
+                    self.congestion.on_fast_retransmit(in_flight as usize, self.seq_num);
+                    resend(self);
+                } else if self.dup_acks > DUP_ACK_THRESHOLD {
+                    self.congestion.on_duplicate();
+                }
+            }
+            self.send_window = window;
+            return;
+        }
+        self.send_window = window;
+        self.dup_acks = 0;
+        let data = (acked as usize).min(self.unacked.len());
+        self.unacked.drain(..data);
+        self.snd_una = ack;
+
+        let now = timer::elapsed_ms();
+        if let Some(echo) = echo.filter(|echo| *echo != 0) {
+            let rtt = (now as u32).wrapping_sub(echo) as u64;
+            if rtt < MAX_RTO_MS {
+                self.measure(rtt);
+            }
+        }
+        self.rto_ms = self.rto();
+        self.retries = 0;
+        if let Some(id) = self.retransmit_timer.take() {
+            timer::cancel(id);
+        }
+        if self.congestion.on_ack(ack, acked as usize, now, self.srtt_ms.unwrap_or(100)) {
+            resend(self);
+        }
+        arm_retransmit(self);
+    }
+}
+
+/// Acknowledgement delay `network.tcp_delayed_ack` sets
+fn ack_delay() -> u64 {
+    config::get("network.tcp_delayed_ack")
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(40)
+        .min(MAX_ACK_DELAY_MS)
+}
+
+/// Acknowledge `len` bytes just taken in: at once for every second full
+/// segment, otherwise when the delay runs out unless data going the other
+/// way carries the acknowledgement first
+fn delay_ack(conn: &mut TcpConnection, len: usize) {
+    conn.ack_pending += len;
+    if conn.ack_delay_ms == 0 || conn.ack_pending >= 2 * conn.segment_size() {
+        send_ack(conn);
+        return;
+    }
+    if conn.ack_timer.is_none() {
+        let id = conn.id;
+        conn.ack_timer = Some(timer::after(Duration::from_millis(conn.ack_delay_ms), move || delayed_ack(id)));
+    }
+}
+
+fn delayed_ack(id: ConnectionId) {
+    let mut connections = CONNECTIONS.lock();
+    if let Some(conn) = connections.get_mut(&id) {
+        conn.ack_timer = None;
+        if conn.ack_pending > 0 && conn.state != TcpState::Closed {
+            send_ack(conn);
+        }
+    }
+}
+
+/// A segment carrying our acknowledgement has gone; nothing is owed
+fn ack_sent(conn: &mut TcpConnection) {
+    conn.ack_pending = 0;
+    if let Some(id) = conn.ack_timer.take() {
+        timer::cancel(id);
+    }
+}
+
+/// Start the retransmission timer if something is outstanding and it is
+/// not already running
+fn arm_retransmit(conn: &mut TcpConnection) {
+    if conn.retransmit_timer.is_some() || !conn.outstanding() {
+        return;
+    }
+    let id = conn.id;
+    conn.retransmit_timer = Some(timer::after(Duration::from_millis(conn.rto_ms), move || retransmit(id)));
+}
+
+/// Send the oldest unacknowledged segment of a connection again
+fn retransmit(id: ConnectionId) {
+    let mut connections = CONNECTIONS.lock();
+    let conn = match connections.get_mut(&id) {
+        Some(conn) => conn,
+        None => return,
+    };
+    conn.retransmit_timer = None;
+    if !conn.outstanding() || conn.state == TcpState::Closed {
+        return;
+    }
+    if conn.retries >= MAX_RETRIES {
+        warn!("tcp", "No acknowledgement from {:?}:{} after {} tries, dropping the connection",
+            id.remote_addr, id.remote_port.as_u16(), conn.retries + 1);
+        conn.state = TcpState::Closed;
+        conn.unacked.clear();
+        return;
+    }
+    let in_flight = conn.seq_num.wrapping_sub(conn.snd_una) as usize;
+    conn.congestion.on_timeout(in_flight, conn.seq_num, conn.retries == 0);
+    conn.dup_acks = 0;
+    conn.retries += 1;
+    conn.rto_ms = (conn.rto_ms * 2).min(MAX_RTO_MS);
+    resend(conn);
+    arm_retransmit(conn);
+}
+
+/// Send the oldest unacknowledged segment again
+fn resend(conn: &TcpConnection) {
+    let seq = conn.snd_una;
+    let _ = match conn.state {
+        TcpState::SynSent => send_segment(conn, seq, TCP_FLAG_SYN, &[]),
+        TcpState::SynReceived => send_segment(conn, seq, TCP_FLAG_SYN | TCP_FLAG_ACK, &[]),
+        _ if !conn.unacked.is_empty() => {
+            let len = conn.unacked.len().min(conn.segment_size());
+            let data = conn.unacked[..len].to_vec();
+            send_segment(conn, seq, TCP_FLAG_ACK | TCP_FLAG_PSH, &data)
+        }
+        // All that is left is our FIN
+        _ => send_segment(conn, seq, TCP_FLAG_FIN | TCP_FLAG_ACK, &[]),
+    };
+}
+
+/// Send what the windows have room for from the send buffer, then our
+/// FIN if it is due
+fn transmit(conn: &mut TcpConnection) {
+    if !matches!(conn.state, TcpState::Established | TcpState::CloseWait | TcpState::FinWait1 | TcpState::LastAck) {
+        return;
+    }
+    while !conn.tx_buffer.is_empty() {
+        let in_flight = conn.seq_num.wrapping_sub(conn.snd_una) as usize;
+        let mut window = conn.congestion.cwnd.min(conn.send_window);
+        // A byte probes a closed window; the retransmission timer repeats it
+        if window == 0 && in_flight == 0 {
+            window = 1;
+        }
+        if in_flight >= window {
+            break;
+        }
+        let full = conn.tx_buffer.len().min(conn.segment_size());
+        let len = full.min(window - in_flight);
+        // Rather than a runt, wait for acknowledgements to make room; and
+        // hold a small write back while data is in flight, unless told not
+        // to or closing
+        let nagle = !conn.nodelay && !conn.fin_pending && len < conn.segment_size();
+        if in_flight > 0 && (len < full || nagle) {
+            break;
+        }
+        let data: Vec<u8> = conn.tx_buffer.drain(..len).collect();
+        // A segment the link would not take counts as lost, and goes again
+        let _ = send_segment(conn, conn.seq_num, TCP_FLAG_ACK | TCP_FLAG_PSH, &data);
+        ack_sent(conn);
+        conn.seq_num = conn.seq_num.wrapping_add(len as u32);
+        conn.unacked.extend_from_slice(&data);
+    }
+    if conn.fin_pending && conn.tx_buffer.is_empty() {
+        send_fin_ack(conn);
+        conn.seq_num = conn.seq_num.wrapping_add(1);
+        conn.fin_pending = false;
+    }
+    arm_retransmit(conn);
+}
+
+/// Move to `state` and send our FIN after whatever is still buffered
+fn finish(conn: &mut TcpConnection, state: TcpState) {
+    conn.state = state;
+    conn.fin_pending = true;
+    transmit(conn);
+}
+
+/// Send one segment of a connection, starting at `seq`
+fn send_segment(conn: &TcpConnection, seq: u32, flags: u8, data: &[u8]) -> Result<usize, ()> {
+    let id = conn.id;
+    let options = conn.options(flags).to_bytes();
+    let mut header = TcpHeader {
+        src_port: id.local_port.as_u16(),
+        dst_port: id.remote_port.as_u16(),
+        seq,
+        ack: if flags & TCP_FLAG_ACK != 0 { conn.ack_num } else { 0 },
+        data_offset: (((20 + options.len()) / 4) as u8) << 4,
+        flags,
+        window: conn.advertised_window(flags & TCP_FLAG_SYN != 0),
+        checksum: 0,
+        urgent: 0,
+    };
+
+    // Options sit between the header and the data, so they are summed as
+    // the start of the data
+    let mut body = options;
+    body.extend_from_slice(data);
+    header.checksum = header.calculate_checksum(id.local_addr, id.remote_addr, &body);
+
+    let mut packet = vec![0u8; 20 + body.len()];
+    packet[0..20].copy_from_slice(&header.to_bytes());
+    simd::copy(&mut packet[20..], &body);
+
+    ip::send_ipv4_packet(IpProtocol::Tcp, id.remote_addr, &packet)
+}
+
+/// TCP socket table
+lazy_static! {
+    static ref CONNECTIONS: Mutex<BTreeMap<ConnectionId, TcpConnection>> = Mutex::new(BTreeMap::new());
+    static ref LISTENING_SOCKETS: Mutex<BTreeMap<Port, ConnectionId>> = Mutex::new(BTreeMap::new());
+    static ref NEXT_EPHEMERAL_PORT: Mutex<u16> = Mutex::new(49152);
+}
+
+/// Get ephemeral port
+fn get_ephemeral_port() -> Port {
+    let mut port = NEXT_EPHEMERAL_PORT.lock();
+    let p = *port;
+    *port = if *port >= 65535 { 49152 } else { *port + 1 };
+    Port::new(p)
+}
+
+/// Process incoming TCP packet
+pub fn process_tcp_packet(src: Ipv4Address, dst: Ipv4Address, data: &[u8]) {
+    let header = match TcpHeader::from_bytes(data) {
+        Some(h) => h,
+        None => return,
+    };
+
+    let header_len = header.header_len();
+    if header_len < 20 || header_len > data.len() {
+        return;
+    }
+
+    let options = Options::parse(&data[20..header_len]);
+    let payload = &data[header_len..];
+
+    // Build connection ID
+    let id = ConnectionId {
+        local_addr: dst,
+        local_port: Port::new(header.dst_port),
+        remote_addr: src,
+        remote_port: Port::new(header.src_port),
+    };
+
+    // Check for existing connection
+    let mut connections = CONNECTIONS.lock();
+
+    if let Some(conn) = connections.get_mut(&id) {
+        // Handle based on state
+        handle_packet(conn, &header, &options, payload);
+    } else {
+        // Check for listening socket
+        let listening = LISTENING_SOCKETS.lock();
+        
+        if let Some(_) = listening.get(&Port::new(header.dst_port)) {
+            // New connection attempt
+            if header.has_flag(TCP_FLAG_SYN) {
+                drop(listening);
+                drop(connections);
+                handle_syn(dst, src, header, &options);
+            }
+        } else {
+            // No such connection - send RST
+            send_rst(dst, src, header.dst_port, header.src_port, header.ack);
+        }
+    }
+}
+
+/// Handle packet for established connection
+fn handle_packet(conn: &mut TcpConnection, header: &TcpHeader, options: &Options, payload: &[u8]) {
+    let syn = header.has_flag(TCP_FLAG_SYN);
+    if syn && conn.state == TcpState::SynSent {
+        conn.negotiate(options);
+    }
+
+    // Take in the segment if it is the next one and there is room for it;
+    // otherwise the peer sends it again
+    let accepted = header.seq == conn.ack_num && conn.rx_buffer.len() + payload.len() <= conn.recv_buffer;
+    if accepted {
+        if let Some((value, _)) = options.timestamps {
+            conn.ts_recent = value;
+        }
+        conn.ack_num = header.seq.wrapping_add(payload.len() as u32);
+        
+        if syn {
+            conn.ack_num = conn.ack_num.wrapping_add(1);
+        }
+        if header.has_flag(TCP_FLAG_FIN) {
+            conn.ack_num = conn.ack_num.wrapping_add(1);
+        }
+
+        conn.rx_buffer.extend_from_slice(payload);
+    }
+
+    if header.has_flag(TCP_FLAG_ACK) {
+        // The window in a SYN is never scaled
+        let window = (header.window as usize) << if syn { 0 } else { conn.snd_scale };
+        let pure = payload.is_empty() && !syn && !header.has_flag(TCP_FLAG_FIN);
+        conn.acknowledge(header.ack, window, options.timestamps.map(|(_, echo)| echo), pure);
+    }
+
+    // Our FIN has been acknowledged
+    let fin_acked = !conn.fin_pending && !conn.outstanding();
+    match conn.state {
+        TcpState::SynSent => {
+            if syn && header.has_flag(TCP_FLAG_ACK) {
+                conn.state = TcpState::Established;
+                conn.ack_num = header.seq.wrapping_add(1);
+                
+                // Send ACK
+                send_ack(conn);
+            }
+        }
+        TcpState::SynReceived => {
+            if header.has_flag(TCP_FLAG_ACK) {
+                conn.state = TcpState::Established;
+            }
+        }
+        TcpState::Established => {
+            if header.has_flag(TCP_FLAG_FIN) {
+                // Close our side too, once what is buffered has gone
+                send_ack(conn);
+                finish(conn, TcpState::LastAck);
+            } else if accepted && !payload.is_empty() {
+                delay_ack(conn, payload.len());
+            } else if !payload.is_empty() {
+                // The same point again: a segment before this one is
+                // missing, and the duplicate tells the peer at once
+                send_ack(conn);
+            }
+        }
+        TcpState::FinWait1 => {
+            let fin = header.has_flag(TCP_FLAG_FIN);
+            if fin {
+                send_ack(conn);
+            }
+            conn.state = match (fin_acked, fin) {
+                (true, true) => TcpState::TimeWait,
+                (true, false) => TcpState::FinWait2,
+                (false, true) => TcpState::Closing,
+                (false, false) => TcpState::FinWait1,
+            };
+        }
+        TcpState::FinWait2 => {
+            if header.has_flag(TCP_FLAG_FIN) {
+                send_ack(conn);
+                conn.state = TcpState::TimeWait;
+            }
+        }
+        TcpState::Closing => {
+            if fin_acked {
+                conn.state = TcpState::TimeWait;
+            }
+        }
+        TcpState::LastAck => {
+            if fin_acked {
+                conn.state = TcpState::Closed;
+            }
+        }
+        _ => {}
+    }
+
+    // Acknowledgements may have made room
+    transmit(conn);
+}
+
+/// Handle incoming SYN (new connection)
+fn handle_syn(dst: Ipv4Address, src: Ipv4Address, header: TcpHeader, options: &Options) {
+    let local_port = Port::new(header.dst_port);
+    let remote_port = Port::new(header.src_port);
+
+    let id = ConnectionId {
+        local_addr: dst,
+        local_port,
+        remote_addr: src,
+        remote_port,
+    };
+
+    let mut conn = TcpConnection::new(id);
+    conn.state = TcpState::SynReceived;
+    conn.ack_num = header.seq.wrapping_add(1);
+    conn.negotiate(options);
+    conn.send_window = header.window as usize;
+
+    // Send SYN-ACK
+    let _ = send_segment(&conn, conn.seq_num, TCP_FLAG_SYN | TCP_FLAG_ACK, &[]);
+
+    conn.seq_num = conn.seq_num.wrapping_add(1);
+    arm_retransmit(&mut conn);
+
+    // Store connection
+    CONNECTIONS.lock().insert(id, conn);
+}
+
+/// Send ACK
+fn send_ack(conn: &mut TcpConnection) {
+    let _ = send_segment(conn, conn.seq_num, TCP_FLAG_ACK, &[]);
+    ack_sent(conn);
+}
+
+/// Send FIN-ACK
+fn send_fin_ack(conn: &mut TcpConnection) {
+    let _ = send_segment(conn, conn.seq_num, TCP_FLAG_FIN | TCP_FLAG_ACK, &[]);
+    ack_sent(conn);
+}
+
+/// Send RST
+fn send_rst(src: Ipv4Address, dst: Ipv4Address, src_port: u16, dst_port: u16, ack: u32) {
+    let mut header = TcpHeader {
+        src_port,
+        dst_port,
+        seq: 0,
+        ack: ack.wrapping_add(1),
+        data_offset: 0x50,
+        flags: TCP_FLAG_RST | TCP_FLAG_ACK,
+        window: 0,
+        checksum: 0,
+        urgent: 0,
+    };
+
+    header.checksum = header.calculate_checksum(src, dst, &[]);
+
+    let mut packet = vec![0u8; 20];
+    packet.copy_from_slice(&header.to_bytes());
+
+    let _ = ip::send_ipv4_packet(IpProtocol::Tcp, dst, &packet);
+}
+
+/// Connect to remote host
+pub fn connect(remote_addr: Ipv4Address, remote_port: Port) -> Result<ConnectionId, ()> {
+    let config = super::get_config();
+    if !config.is_configured() {
+        return Err(());
+    }
+
+    let local_port = get_ephemeral_port();
+
+    let id = ConnectionId {
+        local_addr: config.ip,
+        local_port,
+        remote_addr,
+        remote_port,
+    };
+
+    let mut conn = TcpConnection::new(id);
+    conn.state = TcpState::SynSent;
+
+    // Send SYN
+    send_segment(&conn, conn.seq_num, TCP_FLAG_SYN, &[])?;
+
+    conn.seq_num = conn.seq_num.wrapping_add(1);
+    arm_retransmit(&mut conn);
+
+    CONNECTIONS.lock().insert(id, conn);
+
+    Ok(id)
+}
+
+/// Listen on port
+pub fn listen(port: Port) -> Result<(), ()> {
+    LISTENING_SOCKETS.lock().insert(port, ConnectionId {
+        local_addr: Ipv4Address::unspecified(),
+        local_port: port,
+        remote_addr: Ipv4Address::unspecified(),
+        remote_port: Port::new(0),
+    });
+    Ok(())
+}
+
+/// Stop listening on port; connections already made carry on
+pub fn unlisten(port: Port) {
+    LISTENING_SOCKETS.lock().remove(&port);
+}
+
+/// Every established connection to local `port`
+pub fn established(port: Port) -> Vec<ConnectionId> {
+    CONNECTIONS.lock().iter()
+        .filter(|(id, conn)| id.local_port == port && conn.state == TcpState::Established)
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+/// Accept connection
+pub fn accept(port: Port) -> Option<ConnectionId> {
+    let connections = CONNECTIONS.lock();
+    
+    for (id, conn) in connections.iter() {
+        if id.local_port == port && conn.state == TcpState::Established {
+            return Some(*id);
+        }
+    }
+    
+    None
+}
+
+/// Send data on connection
+///
+/// Takes as much as the send buffer has room for, which may be none.
+pub fn send(id: ConnectionId, data: &[u8]) -> Result<usize, ()> {
+    let mut connections = CONNECTIONS.lock();
+    let conn = connections.get_mut(&id).ok_or(())?;
+
+    if conn.state != TcpState::Established {
+        return Err(());
+    }
+
+    let room = conn.send_buffer.saturating_sub(conn.tx_buffer.len() + conn.unacked.len());
+    let len = data.len().min(room);
+    conn.tx_buffer.extend_from_slice(&data[..len]);
+    transmit(conn);
+
+    Ok(len)
+}
+
+/// Bytes written on a connection that the peer has not acknowledged
+pub fn unacknowledged(id: ConnectionId) -> usize {
+    CONNECTIONS.lock().get(&id).map_or(0, |conn| conn.tx_buffer.len() + conn.unacked.len())
+}
+
+/// Set how a connection sends and acknowledges: `nodelay` turns Nagle
+/// off, `quick_ack` acknowledges every segment at once, and the buffers
+/// bound what is held each way
+pub fn tune(id: ConnectionId, nodelay: bool, quick_ack: bool, recv_buffer: usize, send_buffer: usize) -> Result<(), ()> {
+    let mut connections = CONNECTIONS.lock();
+    let conn = connections.get_mut(&id).ok_or(())?;
+    conn.nodelay = nodelay;
+    conn.ack_delay_ms = if quick_ack { 0 } else { ack_delay() };
+    // The window field, as scaled, cannot offer more
+    conn.recv_buffer = recv_buffer.clamp(MIN_BUFFER, (u16::MAX as usize) << RECV_SCALE);
+    conn.send_buffer = send_buffer.max(MIN_BUFFER);
+    if quick_ack && conn.ack_pending > 0 {
+        send_ack(conn);
+    }
+    // Whatever Nagle held back may go now
+    transmit(conn);
+    Ok(())
+}
+
+/// Whether any connection other than a closed one uses local `port`
+pub fn port_in_use(port: Port) -> bool {
+    CONNECTIONS.lock().iter().any(|(id, conn)| id.local_port == port && conn.state != TcpState::Closed)
+}
+
+/// Drop a connection at once, resetting it rather than closing it, and
+/// throw away whatever it had not sent
+pub fn abort(id: ConnectionId) {
+    let mut conn = match CONNECTIONS.lock().remove(&id) {
+        Some(conn) => conn,
+        None => return,
+    };
+    for timer_id in [conn.retransmit_timer.take(), conn.ack_timer.take()].into_iter().flatten() {
+        timer::cancel(timer_id);
+    }
+    if conn.state != TcpState::Closed {
+        let _ = send_segment(&conn, conn.seq_num, TCP_FLAG_RST | TCP_FLAG_ACK, &[]);
+    }
+}
+
+/// Receive data from connection
+pub fn receive(id: ConnectionId, buf: &mut [u8]) -> Result<usize, ()> {
+    let mut connections = CONNECTIONS.lock();
+    let conn = connections.get_mut(&id).ok_or(())?;
+
+    let len = buf.len().min(conn.rx_buffer.len());
+    if len == 0 {
+        return Ok(0);
+    }
+
+    simd::copy(&mut buf[..len], &conn.rx_buffer[..len]);
+    conn.rx_buffer.drain(..len);
+
+    Ok(len)
+}
+
+/// Copy what has arrived on a connection without taking it from the
+/// buffer
+pub fn peek(id: ConnectionId, buf: &mut [u8]) -> Result<usize, ()> {
+    let connections = CONNECTIONS.lock();
+    let conn = connections.get(&id).ok_or(())?;
+
+    let len = buf.len().min(conn.rx_buffer.len());
+    simd::copy(&mut buf[..len], &conn.rx_buffer[..len]);
+    Ok(len)
+}
+
+/// State of a connection, or `None` once it is gone
+pub fn state(id: ConnectionId) -> Option<TcpState> {
+    CONNECTIONS.lock().get(&id).map(|conn| conn.state)
+}
+
+/// Close connection
+pub fn close(id: ConnectionId) -> Result<(), ()> {
+    let mut connections = CONNECTIONS.lock();
+    let conn = connections.get_mut(&id).ok_or(())?;
+
+    match conn.state {
+        TcpState::Established => {
+            finish(conn, TcpState::FinWait1);
+            Ok(())
+        }
+        TcpState::CloseWait => {
+            finish(conn, TcpState::LastAck);
+            Ok(())
+        }
+        _ => Err(()),
+    }
+}
+
+/// Print TCP statistics
+pub fn print_stats() {
+    let connections = CONNECTIONS.lock();
+    let listening = LISTENING_SOCKETS.lock();
+
+    println!("TCP Connections: {}", connections.len());
+    println!("Listening Ports: {}", listening.len());
+    for (id, conn) in connections.iter() {
+        let ssthresh = match conn.congestion.ssthresh {
+            usize::MAX => String::from("-"),
+            ssthresh => format!("{}", ssthresh),
+        };
+        let srtt = match conn.srtt_ms {
+            Some(srtt) => format!("{}ms", srtt),
+            None => String::from("-"),
+        };
+        println!("  :{} -> {}:{} {:?} {} cwnd {} ssthresh {} window {} rtt {} rto {}ms",
+            id.local_port.as_u16(), id.remote_addr, id.remote_port.as_u16(), conn.state,
+            conn.congestion.algorithm().name(), conn.congestion.cwnd, ssthresh, conn.send_window, srtt, conn.rto_ms);
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// A connection to a documentation address, nothing that answers
+    fn connection(state: TcpState) -> TcpConnection {
+        let mut conn = TcpConnection::new(ConnectionId {
+            local_addr: Ipv4Address::new([192, 0, 2, 1]),
+            local_port: Port::new(49000),
+            remote_addr: Ipv4Address::new([192, 0, 2, 2]),
+            remote_port: Port::new(80),
+        });
+        conn.state = state;
+        conn
+    }
+
+    fn segment(conn: &TcpConnection, seq: u32, flags: u8) -> TcpHeader {
+        TcpHeader {
+            src_port: conn.id.remote_port.as_u16(),
+            dst_port: conn.id.local_port.as_u16(),
+            seq,
+            ack: conn.seq_num,
+            data_offset: 5 << 4,
+            flags,
+            window: 65535,
+            checksum: 0,
+            urgent: 0,
+        }
+    }
+
+    #[kernel_test]
+    fn syn_ack_negotiates_and_times_the_round_trip() -> Result<(), String> {
+        let mut conn = connection(TcpState::SynSent);
+        // Our SYN is outstanding
+        conn.seq_num = conn.seq_num.wrapping_add(1);
+        let echo = (timer::elapsed_ms() as u32).wrapping_sub(30) | 1;
+        let options = Options { mss: Some(1000), window_scale: Some(7), timestamps: Some((5000, echo)) };
+        let header = segment(&conn, 7000, TCP_FLAG_SYN | TCP_FLAG_ACK);
+        handle_packet(&mut conn, &header, &options, &[]);
+
+        check_eq!(conn.state, TcpState::Established);
+        check_eq!(conn.ack_num, 7001);
+        check_eq!(conn.mss, 1000);
+        check_eq!(conn.snd_scale, 7);
+        check_eq!(conn.ts_recent, 5000);
+        check!(!conn.outstanding());
+        check!(matches!(conn.srtt_ms, Some(rtt) if (29..100).contains(&rtt)));
+        // A short round trip gives the floor
+        check_eq!(conn.rto_ms, MIN_RTO_MS);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn syn_without_options_falls_back_to_defaults() -> Result<(), String> {
+        let mut conn = connection(TcpState::SynSent);
+        conn.seq_num = conn.seq_num.wrapping_add(1);
+        let header = segment(&conn, 100, TCP_FLAG_SYN | TCP_FLAG_ACK);
+        handle_packet(&mut conn, &header, &Options::default(), &[]);
+        check_eq!(conn.mss, DEFAULT_MSS);
+        check_eq!((conn.snd_scale, conn.rcv_scale), (0, 0));
+        check!(!conn.timestamps);
+        // Nothing timed, so the first timeout stands
+        check_eq!(conn.srtt_ms, None);
+        check_eq!(conn.rto_ms, INITIAL_RTO_MS);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn rto_follows_the_smoothed_round_trip() -> Result<(), String> {
+        let mut conn = connection(TcpState::Established);
+        conn.measure(400);
+        check_eq!((conn.srtt_ms, conn.rttvar_ms), (Some(400), 200));
+        check_eq!(conn.rto(), 1200);
+        conn.measure(800);
+        check_eq!((conn.srtt_ms, conn.rttvar_ms), (Some(450), 250));
+        check_eq!(conn.rto(), 1450);
+        conn.measure(100_000);
+        check_eq!(conn.rto(), MAX_RTO_MS);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn data_is_acknowledged_late_or_every_second_segment() -> Result<(), String> {
+        let mut conn = connection(TcpState::Established);
+        conn.ack_delay_ms = 40;
+        conn.ack_num = 500;
+        let header = segment(&conn, 500, TCP_FLAG_ACK | TCP_FLAG_PSH);
+        handle_packet(&mut conn, &header, &Options::default(), &[7; 100]);
+        check_eq!(conn.rx_buffer.len(), 100);
+        check_eq!(conn.ack_pending, 100);
+        check!(conn.ack_timer.is_some());
+
+        // A second full segment's worth goes out at once
+        let full = vec![7; 2 * conn.segment_size()];
+        let header = segment(&conn, conn.ack_num, TCP_FLAG_ACK | TCP_FLAG_PSH);
+        handle_packet(&mut conn, &header, &Options::default(), &full);
+        check_eq!(conn.ack_pending, 0);
+        check!(conn.ack_timer.is_none());
+
+        // Otherwise the timer sends it
+        let id = conn.id;
+        let header = segment(&conn, conn.ack_num, TCP_FLAG_ACK | TCP_FLAG_PSH);
+        handle_packet(&mut conn, &header, &Options::default(), &[7; 10]);
+        check!(conn.ack_timer.is_some());
+        CONNECTIONS.lock().insert(id, conn);
+        delayed_ack(id);
+        let conn = CONNECTIONS.lock().remove(&id);
+        check!(matches!(conn, Some(conn) if conn.ack_pending == 0 && conn.ack_timer.is_none()));
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! TCP options
+//!
+//! Each side says in its SYN the largest segment it takes and, by RFC
+//! 7323, how far its window field is to be shifted and whether it stamps
+//! segments. Scaling and timestamps are used only when both sides offer
+//! them; timestamps then go on every segment, and their echoes time the
+//! round trip.
+
+use alloc::vec::Vec;
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_TIMESTAMPS: u8 = 8;
+
+/// Largest shift RFC 7323 allows
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Options carried by a segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    /// The sender's clock, and the latest value of ours it has seen
+    pub timestamps: Option<(u32, u32)>,
+}
+
+impl Options {
+    /// Read the options of a segment, skipping ones not known
+    pub fn parse(mut data: &[u8]) -> Self {
+        let mut options = Options::default();
+        while let Some(&kind) = data.first() {
+            match kind {
+                KIND_END => break,
+                KIND_NOP => {
+                    data = &data[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            let len = match data.get(1) {
+                Some(&len) if len >= 2 && len as usize <= data.len() => len as usize,
+                _ => break,
+            };
+            let body = &data[2..len];
+            match (kind, body.len()) {
+                (KIND_MSS, 2) => options.mss = Some(u16::from_be_bytes([body[0], body[1]])),
+                (KIND_WINDOW_SCALE, 1) => options.window_scale = Some(body[0].min(MAX_WINDOW_SCALE)),
+                (KIND_TIMESTAMPS, 8) => {
+                    let value = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                    let echo = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                    options.timestamps = Some((value, echo));
+                }
+                _ => {}
+            }
+            data = &data[len..];
+        }
+        options
+    }
+
+    /// The options as they go after the header, a multiple of four bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(mss) = self.mss {
+            out.extend_from_slice(&[KIND_MSS, 4]);
+            out.extend_from_slice(&mss.to_be_bytes());
+        }
+        if let Some(shift) = self.window_scale {
+            out.extend_from_slice(&[KIND_NOP, KIND_WINDOW_SCALE, 3, shift]);
+        }
+        if let Some((value, echo)) = self.timestamps {
+            out.extend_from_slice(&[KIND_NOP, KIND_NOP, KIND_TIMESTAMPS, 10]);
+            out.extend_from_slice(&value.to_be_bytes());
+            out.extend_from_slice(&echo.to_be_bytes());
+        }
+        out
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn options_round_trip() -> Result<(), String> {
+        let options = Options { mss: Some(1460), window_scale: Some(7), timestamps: Some((12345, 678)) };
+        let bytes = options.to_bytes();
+        check_eq!(bytes.len() % 4, 0);
+        check_eq!(Options::parse(&bytes), options);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn unknown_and_malformed_options_are_skipped() -> Result<(), String> {
+        // SACK permitted, then a window scale past the limit
+        let bytes = [4, 2, 1, 3, 3, 20, 0, 0];
+        check_eq!(Options::parse(&bytes).window_scale, Some(MAX_WINDOW_SCALE));
+        // A length running past the end stops parsing
+        let bytes = [2, 4, 5, 180, 8, 10, 0, 0];
+        let options = Options::parse(&bytes);
+        check_eq!(options.mss, Some(1460));
+        check!(options.timestamps.is_none());
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! Simple connectionless transport protocol.
 
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec;
 use alloc::vec::Vec;
 use spin::Mutex;
@@ -88,7 +88,8 @@ impl UdpHeader {
 /// UDP socket
 pub struct UdpSocket {
     pub local_port: Port,
-    pub receive_queue: Vec<(Ipv4Address, Port, Vec<u8>)>,
+    /// Datagrams waiting to be read, oldest first, with who sent them
+    pub receive_queue: VecDeque<(Ipv4Address, Port, Vec<u8>)>,
 }
 
 /// UDP socket table
@@ -120,7 +121,7 @@ pub fn process_udp_packet(src: Ipv4Address, dst: Ipv4Address, data: &[u8]) {
     if let Some(socket) = sockets.get_mut(&dst_port) {
         // Store in receive queue
         if socket.receive_queue.len() < 64 {
-            socket.receive_queue.push((
+            socket.receive_queue.push_back((
                 src,
                 Port::new(header.src_port),
                 payload.to_vec()
@@ -139,7 +140,7 @@ pub fn bind(port: Port) -> Result<(), ()> {
 
     sockets.insert(port, UdpSocket {
         local_port: port,
-        receive_queue: Vec::new(),
+        receive_queue: VecDeque::new(),
     });
 
     Ok(())
@@ -174,20 +175,32 @@ pub fn send_to(
 }
 
 /// Receive UDP packet
+///
+/// Takes the oldest datagram waiting; what does not fit in `buf` is lost.
 pub fn receive_from(
     local_port: Port,
     buf: &mut [u8]
 ) -> Option<(Ipv4Address, Port, usize)> {
+    take(local_port, buf, false)
+}
+
+/// Copy the oldest datagram waiting, leaving it to be received
+pub fn peek_from(local_port: Port, buf: &mut [u8]) -> Option<(Ipv4Address, Port, usize)> {
+    take(local_port, buf, true)
+}
+
+fn take(local_port: Port, buf: &mut [u8], peek: bool) -> Option<(Ipv4Address, Port, usize)> {
     let mut sockets = SOCKETS.lock();
     let socket = sockets.get_mut(&local_port)?;
 
-    if let Some((src_addr, src_port, data)) = socket.receive_queue.pop() {
-        let len = buf.len().min(data.len());
-        buf[..len].copy_from_slice(&data[..len]);
-        Some((src_addr, src_port, len))
-    } else {
-        None
+    let (src_addr, src_port, data) = socket.receive_queue.front()?;
+    let len = buf.len().min(data.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    let received = (*src_addr, *src_port, len);
+    if !peek {
+        socket.receive_queue.pop_front();
     }
+    Some(received)
 }
 
 /// Close UDP socket
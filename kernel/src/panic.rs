@@ -1,30 +1,303 @@
 //! Panic handler for kernel
+//!
+//! A panic stops the machine and says why: the message, the registers and
+//! a backtrace from the frame pointer chain, named from the kernel's
+//! symbol table. It goes to the serial port and, white on blue, over the
+//! whole framebuffer. Whatever panicked may hold any lock or have broken
+//! the heap, so nothing here allocates or waits on a lock; the
+//! framebuffer's is forced. The report, the end of the log and the
+//! counters also go to the crash dump region, for the next boot to show.
+//! Then `r` reboots and `d` dumps the stack and the end of the log, from
+//! the keyboard or the serial port.
 
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
-use crate::println;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::MutexGuard;
+
+use crate::arch::cpu;
+use crate::console::{emergency_read, emergency_write};
+use crate::crashdump::{self, Dump};
+use crate::drivers::input::inb;
+use crate::drivers::vesa::{self, VesaDriver};
+use crate::graphics::font::{self, BUILTIN_HEIGHT, BUILTIN_WIDTH};
+use crate::symbols;
+
+const BACKGROUND: u32 = 0x000000AA;
+const FOREGROUND: u32 = 0x00FFFFFF;
+/// Pixels left around the text
+const MARGIN: u32 = 16;
+/// Frames followed before giving up
+const MAX_FRAMES: usize = 32;
+/// How far above the stack pointer frames may be
+const STACK_SPAN: u64 = 2 * 1024 * 1024;
+/// Stack bytes `d` dumps at most
+const DUMP_STACK: u64 = 2048;
+/// Log bytes `d` dumps
+const DUMP_LOG: usize = 2048;
+
+// Set 1 scancodes of the keys
+const SCANCODE_R: u8 = 0x13;
+const SCANCODE_D: u8 = 0x20;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The registers as the panic handler found them
+struct Registers {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    fn capture() -> Self {
+        let (rsp, rbp, rflags, cr0, cr2, cr3, cr4): (u64, u64, u64, u64, u64, u64, u64);
+        unsafe {
+            core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+            core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+            core::arch::asm!("pushfq", "pop {}", out(reg) rflags, options(nomem));
+            core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+            core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack));
+            core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+            core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+        }
+        Self { rsp, rbp, rflags, cr0, cr2, cr3, cr4 }
+    }
+}
+
+/// Text to the serial port, the blue screen and the crash dump
+struct Report {
+    driver: Option<MutexGuard<'static, VesaDriver>>,
+    dump: Option<Dump>,
+    column: u32,
+    row: u32,
+    columns: u32,
+    rows: u32,
+}
+
+impl Report {
+    fn open() -> Self {
+        let vesa = vesa::driver();
+        // Whatever panicked may have been drawing
+        if vesa.is_locked() {
+            unsafe { vesa.force_unlock() };
+        }
+        let driver = vesa.lock();
+        let mut report = Self { driver: None, dump: crashdump::begin(), column: 0, row: 0, columns: 0, rows: 0 };
+        if driver.is_initialized() {
+            let info = driver.info();
+            report.columns = info.width.saturating_sub(2 * MARGIN) / BUILTIN_WIDTH;
+            report.rows = info.height.saturating_sub(2 * MARGIN) / BUILTIN_HEIGHT;
+            report.driver = Some(driver);
+        }
+        report.clear();
+        report
+    }
+
+    fn clear(&mut self) {
+        self.column = 0;
+        self.row = 0;
+        if let Some(driver) = self.driver.as_mut() {
+            let (width, height) = (driver.info().width, driver.info().height);
+            driver.fill_rect(0, 0, width, height, BACKGROUND);
+        }
+    }
+
+    fn put(&mut self, ch: char) {
+        if ch == '\n' {
+            self.column = 0;
+            self.row += 1;
+            return;
+        }
+        if self.column >= self.columns {
+            self.column = 0;
+            self.row += 1;
+        }
+        // Past the bottom, only the serial port gets the rest
+        if self.row >= self.rows {
+            return;
+        }
+        let glyph = match font::builtin_glyph(ch).or_else(|| font::builtin_glyph('?')) {
+            Some(glyph) => glyph,
+            None => return,
+        };
+        let x = (MARGIN + self.column * BUILTIN_WIDTH) as i32;
+        let y = (MARGIN + self.row * BUILTIN_HEIGHT) as i32;
+        if let Some(driver) = self.driver.as_mut() {
+            glyph.for_each_run(|gx, gy, len| driver.fill_rect(x + gx as i32, y + gy as i32, len, 1, FOREGROUND));
+        }
+        self.column += 1;
+    }
+}
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        emergency_write(format_args!("{}", s));
+        if let Some(dump) = self.dump.as_mut() {
+            let _ = dump.write_str(s);
+        }
+        s.chars().for_each(|ch| self.put(ch));
+        Ok(())
+    }
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    // Disable interrupts
-    unsafe { core::arch::asm!("cli") };
-    
-    println!("\n╔══════════════════════════════════════════════════╗");
-    println!("║              KERNEL PANIC                        ║");
-    println!("╚══════════════════════════════════════════════════╝");
-    
+    let registers = Registers::capture();
+    cpu::disable_interrupts();
+
+    // A panic while reporting one: say so plainly and stop
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        emergency_write(format_args!("\nKERNEL PANIC while panicking: {}\n", info.message()));
+        halt();
+    }
+
+    let mut report = Report::open();
+    print_panic(&mut report, info, &registers);
+    if let Some(dump) = report.dump.take() {
+        dump.end_summary();
+        dump.finish();
+    }
+    crate::testing::panicked();
+    let _ = write!(report, "\nPress r to reboot or d to dump the stack and log\n");
+
+    loop {
+        match read_key() {
+            Some(b'r') => cpu::reboot(),
+            Some(b'd') => {
+                report.clear();
+                dump(&mut report, &registers);
+                let _ = write!(report, "\nPress r to reboot or d to dump again\n");
+            }
+            _ => core::hint::spin_loop(),
+        }
+    }
+}
+
+fn print_panic(report: &mut Report, info: &PanicInfo, registers: &Registers) {
+    let _ = writeln!(report, "\n*** KERNEL PANIC ***\n");
+    let _ = writeln!(report, "{}", info.message());
     if let Some(location) = info.location() {
-        println!("Location: {}:{}:{}", 
-            location.file(), 
-            location.line(), 
-            location.column()
-        );
-    }
-    
-    println!("Message: {:?}", info.message());
-    
-    println!("\nSystem halted.");
-    
-    // Halt forever
+        let _ = writeln!(report, "at {}:{}:{}", location.file(), location.line(), location.column());
+    }
+
+    if let Some((target, seed)) = crate::testing::fuzz::current() {
+        let _ = writeln!(report, "while fuzzing {}; `fuzz {} 1 {:#x}` repeats the input", target, target, seed);
+    }
+
+    let _ = writeln!(report, "\nRSP {:016x}  RBP {:016x}  RFLAGS {:016x}",
+        registers.rsp, registers.rbp, registers.rflags);
+    let _ = writeln!(report, "CR0 {:016x}  CR2 {:016x}  CR3 {:016x}  CR4 {:016x}",
+        registers.cr0, registers.cr2, registers.cr3, registers.cr4);
+
+    let _ = writeln!(report, "\nBacktrace:");
+    let mut frames = 0;
+    walk(registers, |return_address| {
+        let _ = write!(report, "  {:2} {:#018x}", frames, return_address);
+        match symbols::lookup(return_address) {
+            Some((name, offset)) => { let _ = writeln!(report, "  {}+{:#x}", name, offset); }
+            None => { let _ = writeln!(report); }
+        }
+        frames += 1;
+    });
+    if frames == 0 {
+        let _ = writeln!(report, "  (no frames)");
+    } else if symbols::count() == 0 {
+        let _ = writeln!(report, "  (no symbols; run tools/embed-symbols.py on the kernel)");
+    }
+}
+
+/// Follow the frame pointer chain from `registers`, calling `f` with each
+/// return address; returns the highest frame reached
+fn walk(registers: &Registers, mut f: impl FnMut(u64)) -> u64 {
+    let mut rbp = registers.rbp;
+    let mut highest = registers.rsp;
+    for _ in 0..MAX_FRAMES {
+        // A frame must be aligned, on this stack and older than the last
+        if rbp & 7 != 0 || rbp < highest || rbp - registers.rsp > STACK_SPAN {
+            break;
+        }
+        let (next, return_address) = unsafe {
+            (core::ptr::read_volatile(rbp as *const u64), core::ptr::read_volatile((rbp + 8) as *const u64))
+        };
+        if !symbols::is_text(return_address) {
+            break;
+        }
+        f(return_address);
+        highest = rbp + 16;
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    highest
+}
+
+/// The stack from the stack pointer, and the end of the log
+fn dump(report: &mut Report, registers: &Registers) {
+    // Up to the end of the stack pointer's page or the last frame, which
+    // are both mapped
+    let start = registers.rsp & !7;
+    let end = walk(registers, |_| {}).max((start | 0xFFF) + 1).min(start + DUMP_STACK);
+    let _ = writeln!(report, "Stack from {:#x}:", start);
+    for line in (start..end).step_by(32) {
+        let _ = write!(report, "{:016x}:", line);
+        for address in (line..(line + 32).min(end)).step_by(8) {
+            let word = unsafe { core::ptr::read_volatile(address as *const u64) };
+            let _ = write!(report, " {:016x}", word);
+        }
+        let _ = writeln!(report);
+    }
+
+    let _ = writeln!(report, "\nLog:");
+    let mut line = [0u8; 160];
+    let mut len = 0;
+    let read = crate::log::tail(DUMP_LOG, |byte| {
+        if byte == b'\n' || len == line.len() {
+            let _ = writeln!(report, "{}", core::str::from_utf8(&line[..len]).unwrap_or("?"));
+            len = 0;
+        }
+        if byte != b'\n' {
+            line[len] = byte;
+            len += 1;
+        }
+    });
+    if !read {
+        let _ = writeln!(report, "  (the log is locked)");
+    } else if len > 0 {
+        let _ = writeln!(report, "{}", core::str::from_utf8(&line[..len]).unwrap_or("?"));
+    }
+}
+
+/// `r` or `d` from the keyboard or COM1, without waiting
+fn read_key() -> Option<u8> {
+    unsafe {
+        let status = inb(0x64);
+        // Output full, and from the keyboard rather than the mouse
+        if status & 0x01 != 0 {
+            let scancode = inb(0x60);
+            if status & 0x20 == 0 {
+                match scancode {
+                    SCANCODE_R => return Some(b'r'),
+                    SCANCODE_D => return Some(b'd'),
+                    _ => {}
+                }
+            }
+        }
+    }
+    match emergency_read() {
+        Some(b'r') | Some(b'R') => Some(b'r'),
+        Some(b'd') | Some(b'D') => Some(b'd'),
+        _ => None,
+    }
+}
+
+fn halt() -> ! {
     loop {
         unsafe { core::arch::asm!("hlt") };
     }
@@ -0,0 +1,506 @@
+//! Batteries and the AC adapter
+//!
+//! ACPI describes them as devices: batteries (`PNP0C0A`), whose `_BIX`,
+//! or `_BIF` on older firmware, says what they are and whose `_BST` says
+//! how they are doing, and AC adapters (`ACPI0003`), whose `_PSR` says
+//! whether they are plugged in. `poll`, from the main loops, asks every
+//! few seconds. On batteries, a notification says when they run low;
+//! once they are critical the system shuts down cleanly, after a grace
+//! period for the charger to be plugged in. `/proc/power`, the `power`
+//! command and the taskbar show the state.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::acpi::{self, aml::{self, Value}};
+use crate::arch::cpu;
+use crate::desktop::notifications;
+use crate::drivers::timer;
+use crate::{info, print, println, storage, warn};
+
+const BATTERY_HID: &str = "PNP0C0A";
+const AC_ADAPTER_HID: &str = "ACPI0003";
+
+/// Milliseconds between looks at the batteries
+const POLL_INTERVAL: u64 = 10_000;
+
+/// Milliseconds from a critical battery to the shutdown
+const CRITICAL_GRACE: u64 = 30_000;
+
+/// Charge in percent at which batteries are low, and critical
+pub const LOW_PERCENT: u32 = 10;
+const CRITICAL_PERCENT: u32 = 3;
+
+/// What `_BIF` and `_BST` say for a value they do not know
+const UNKNOWN: u64 = 0xFFFF_FFFF;
+
+/// `_BST` state bits
+const DISCHARGING: u64 = 1 << 0;
+const CHARGING: u64 = 1 << 1;
+const CRITICAL: u64 = 1 << 2;
+
+/// `_STA` bit: a battery is in the slot
+const STA_BATTERY: u64 = 1 << 4;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Battery {
+    /// The device's path, such as `\_SB_.BAT0`
+    pub path: String,
+    pub present: bool,
+    /// Capacities and rates in mAh and mA rather than mWh and mW
+    pub amps: bool,
+    pub design: Option<u32>,
+    /// Capacity at the last full charge
+    pub full: Option<u32>,
+    /// Capacities the firmware calls low, and critical
+    pub warning: Option<u32>,
+    pub low: Option<u32>,
+    pub remaining: Option<u32>,
+    pub rate: Option<u32>,
+    /// mV
+    pub voltage: Option<u32>,
+    /// `_BST` state bits
+    pub state: u64,
+    pub cycles: Option<u32>,
+    pub model: String,
+    /// Chemistry, such as `LION`
+    pub kind: String,
+}
+
+impl Battery {
+    fn new(path: &str) -> Self {
+        Battery { path: String::from(path), ..Battery::default() }
+    }
+
+    /// The last part of the path, such as `BAT0`
+    pub fn name(&self) -> &str {
+        self.path.rsplit(['.', '\\']).next().unwrap_or(&self.path)
+    }
+
+    pub fn charging(&self) -> bool {
+        self.state & CHARGING != 0
+    }
+
+    pub fn discharging(&self) -> bool {
+        self.state & DISCHARGING != 0
+    }
+
+    /// Charge in percent of the last full charge
+    pub fn percent(&self) -> Option<u32> {
+        let full = self.full.or(self.design).filter(|&full| full > 0)?;
+        Some((self.remaining? as u64 * 100 / full as u64).min(100) as u32)
+    }
+
+    /// Minutes until empty, or until full when charging, at the present
+    /// rate
+    pub fn minutes_left(&self) -> Option<u32> {
+        let rate = self.rate.filter(|&rate| rate > 0)? as u64;
+        let remaining = self.remaining? as u64;
+        if self.charging() {
+            Some((self.full?.saturating_sub(remaining as u32) as u64 * 60 / rate) as u32)
+        } else if self.discharging() {
+            Some((remaining * 60 / rate) as u32)
+        } else {
+            None
+        }
+    }
+
+    /// At or under the firmware's critical capacity, or said to be critical
+    fn critical(&self) -> bool {
+        self.state & CRITICAL != 0 || matches!((self.remaining, self.low), (Some(r), Some(low)) if r <= low)
+    }
+
+    fn state_name(&self) -> &'static str {
+        if self.state & CRITICAL != 0 {
+            "critical"
+        } else if self.charging() {
+            "charging"
+        } else if self.discharging() {
+            "discharging"
+        } else if self.percent() == Some(100) {
+            "charged"
+        } else {
+            "idle"
+        }
+    }
+}
+
+/// A number from a `_BIF`, `_BIX` or `_BST` package, unless unknown
+fn known(values: &[Value], index: usize) -> Option<u32> {
+    let n = values.get(index)?.as_integer()?;
+    (n != UNKNOWN && n <= u32::MAX as u64).then_some(n as u32)
+}
+
+/// What `_BIF` says, or `_BIX`, which adds a revision first and the
+/// cycle count and measurement details after the low capacity
+fn parse_info(battery: &mut Battery, info: &[Value], extended: bool) {
+    let at = extended as usize;
+    battery.amps = known(info, at) == Some(1);
+    battery.design = known(info, at + 1);
+    battery.full = known(info, at + 2);
+    battery.warning = known(info, at + 5);
+    battery.low = known(info, at + 6);
+    let strings = if extended {
+        battery.cycles = known(info, 8);
+        16
+    } else {
+        9
+    };
+    let text = |index: usize| info.get(index).and_then(Value::as_string).map(|s| String::from(s.trim())).unwrap_or_default();
+    battery.model = text(strings);
+    battery.kind = text(strings + 2);
+}
+
+/// What `_BST` says: state, rate, remaining capacity and voltage
+fn parse_status(battery: &mut Battery, status: &[Value]) {
+    battery.state = status.first().and_then(Value::as_integer).unwrap_or(0);
+    battery.rate = known(status, 1);
+    battery.remaining = known(status, 2);
+    battery.voltage = known(status, 3);
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PowerStatus {
+    /// Whether an AC adapter is plugged in; None if there is none
+    pub ac_online: Option<bool>,
+    pub batteries: Vec<Battery>,
+}
+
+impl PowerStatus {
+    fn present(&self) -> impl Iterator<Item = &Battery> {
+        self.batteries.iter().filter(|battery| battery.present)
+    }
+
+    /// Charge of the batteries together, in percent
+    pub fn percent(&self) -> Option<u32> {
+        let (mut remaining, mut full) = (0u64, 0u64);
+        for battery in self.present() {
+            remaining += battery.remaining? as u64;
+            full += battery.full.or(battery.design)? as u64;
+        }
+        (full > 0).then(|| (remaining * 100 / full).min(100) as u32)
+    }
+
+    pub fn charging(&self) -> bool {
+        self.present().any(Battery::charging)
+    }
+
+    /// Running on batteries: unplugged or, without an adapter to ask,
+    /// discharging
+    pub fn on_battery(&self) -> bool {
+        let discharging = self.present().any(Battery::discharging);
+        match self.ac_online {
+            Some(online) => !online && self.present().next().is_some(),
+            None => discharging && !self.charging(),
+        }
+    }
+}
+
+/// How urgently the batteries need a charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Normal,
+    Low,
+    Critical,
+}
+
+fn level(status: &PowerStatus) -> Level {
+    if !status.on_battery() {
+        return Level::Normal;
+    }
+    let percent = status.percent();
+    let all = |test: fn(&Battery) -> bool| status.present().next().is_some() && status.present().all(test);
+    if percent.map_or(false, |p| p <= CRITICAL_PERCENT) || all(Battery::critical) {
+        Level::Critical
+    } else if percent.map_or(false, |p| p <= LOW_PERCENT)
+        || all(|b| matches!((b.remaining, b.warning), (Some(r), Some(warning)) if r <= warning))
+    {
+        Level::Low
+    } else {
+        Level::Normal
+    }
+}
+
+struct Power {
+    batteries: Vec<String>,
+    adapters: Vec<String>,
+    status: PowerStatus,
+    last_poll: u64,
+    /// The level the user was last told about
+    told: Level,
+    /// When a critical battery shuts the system down
+    shutdown_at: Option<u64>,
+}
+
+static POWER: Mutex<Option<Power>> = Mutex::new(None);
+
+fn integer(path: &str, method: &str) -> Option<u64> {
+    acpi::evaluate(&aml::join(path, method), Vec::new()).and_then(|value| value.as_integer())
+}
+
+/// Ask the firmware how the adapters and batteries are; what batteries
+/// are stays as it was while they stay in
+fn read_status(batteries: &[String], adapters: &[String], previous: &PowerStatus) -> PowerStatus {
+    let mut ac_online = None;
+    for adapter in adapters {
+        if let Some(online) = integer(adapter, "_PSR") {
+            ac_online = Some(ac_online.unwrap_or(false) || online != 0);
+        }
+    }
+
+    let mut status = PowerStatus { ac_online, batteries: Vec::new() };
+    for path in batteries {
+        // Without `_STA` the battery is always there
+        let present = integer(path, "_STA").map_or(true, |sta| sta & STA_BATTERY != 0);
+        let mut battery = match previous.batteries.iter().find(|b| &b.path == path && b.present) {
+            Some(battery) if present => battery.clone(),
+            _ => Battery::new(path),
+        };
+        if present && !battery.present {
+            let bix = acpi::exists(&aml::join(path, "_BIX"));
+            let info = acpi::evaluate(&aml::join(path, if bix { "_BIX" } else { "_BIF" }), Vec::new());
+            if let Some(info) = info.as_ref().and_then(Value::as_package) {
+                parse_info(&mut battery, info, bix);
+            }
+        }
+        if present {
+            if let Some(bst) = acpi::evaluate(&aml::join(path, "_BST"), Vec::new()) {
+                parse_status(&mut battery, bst.as_package().unwrap_or(&[]));
+            }
+        }
+        battery.present = present;
+        status.batteries.push(battery);
+    }
+    status
+}
+
+/// Find the batteries and AC adapters; needs the AML namespace
+pub fn init() {
+    let batteries = acpi::find_devices(BATTERY_HID);
+    let adapters = acpi::find_devices(AC_ADAPTER_HID);
+    if batteries.is_empty() && adapters.is_empty() {
+        return;
+    }
+    let status = read_status(&batteries, &adapters, &PowerStatus::default());
+    info!("power", "{} batteries, {} AC adapters: {}", batteries.len(), adapters.len(), summary(&status));
+    *POWER.lock() = Some(Power {
+        batteries,
+        adapters,
+        status,
+        last_poll: timer::elapsed_ms(),
+        told: Level::Normal,
+        shutdown_at: None,
+    });
+}
+
+/// One line on how things stand
+fn summary(status: &PowerStatus) -> String {
+    let source = match status.ac_online {
+        Some(true) => "on AC power",
+        Some(false) => "on batteries",
+        None if status.on_battery() => "on batteries",
+        None => "power source unknown",
+    };
+    match status.percent() {
+        Some(percent) => format!("{}, {}%{}", source, percent, if status.charging() { " and charging" } else { "" }),
+        None if status.present().next().is_none() && !status.batteries.is_empty() => format!("{}, no battery in", source),
+        None => String::from(source),
+    }
+}
+
+/// Look at the batteries if it is time to, and warn or shut down if they
+/// are running out
+pub fn poll() {
+    let now = timer::elapsed_ms();
+    let (status, level, told, shutdown_at) = {
+        let mut power = POWER.lock();
+        let power = match power.as_mut() {
+            Some(power) => power,
+            None => return,
+        };
+        if now.saturating_sub(power.last_poll) < POLL_INTERVAL {
+            return;
+        }
+        power.last_poll = now;
+        power.status = read_status(&power.batteries, &power.adapters, &power.status);
+        let level = level(&power.status);
+        let told = core::mem::replace(&mut power.told, level);
+        power.shutdown_at = match (level, power.shutdown_at) {
+            (Level::Critical, None) => Some(now + CRITICAL_GRACE),
+            (Level::Critical, at) => at,
+            _ => None,
+        };
+        (power.status.clone(), level, told, power.shutdown_at)
+    };
+
+    let percent = status.percent().map_or(String::new(), |p| format!("{}% left. ", p));
+    match level {
+        Level::Critical if told < Level::Critical => {
+            warn!("power", "Batteries critical: {}; shutting down in {} seconds", summary(&status), CRITICAL_GRACE / 1000);
+            notifications::notify("Battery critical",
+                &format!("{}Shutting down in {} seconds unless the charger is plugged in.", percent, CRITICAL_GRACE / 1000),
+                '!', 0);
+        }
+        Level::Low if told < Level::Low => {
+            let minutes = status.present().find_map(Battery::minutes_left);
+            let left = minutes.map_or(String::new(), |m| format!("About {}:{:02} to go. ", m / 60, m % 60));
+            info!("power", "Batteries low: {}", summary(&status));
+            notifications::notify("Battery low", &format!("{}{}Plug in the charger soon.", percent, left), '!', 10);
+        }
+        _ => {}
+    }
+    if told == Level::Critical && level < Level::Critical {
+        info!("power", "Charging again; not shutting down");
+    }
+    if shutdown_at.map_or(false, |at| now >= at) {
+        warn!("power", "Batteries still critical; shutting down");
+        shutdown();
+    }
+}
+
+/// Flush the disks' write caches and power off
+pub fn shutdown() -> ! {
+    storage::flush_all();
+    cpu::shutdown()
+}
+
+/// The charge of the batteries in percent, and whether they are
+/// charging, for the taskbar; None without batteries
+pub fn battery() -> Option<(u32, bool)> {
+    let power = POWER.lock();
+    let status = &power.as_ref()?.status;
+    Some((status.percent()?, status.charging()))
+}
+
+/// `/proc/power`
+pub fn contents() -> Vec<u8> {
+    let power = POWER.lock();
+    let status = match power.as_ref() {
+        Some(power) => &power.status,
+        None => return Vec::from(&b"No batteries or AC adapters\n"[..]),
+    };
+    let mut out = String::new();
+    let ac = match status.ac_online {
+        Some(true) => "online",
+        Some(false) => "offline",
+        None => "unknown",
+    };
+    out.push_str(&format!("ac_adapter: {}\n", ac));
+    for battery in &status.batteries {
+        out.push_str(&format!("battery {}:\n", battery.name()));
+        if !battery.present {
+            out.push_str("  present: no\n");
+            continue;
+        }
+        let (energy, power) = if battery.amps { ("mAh", "mA") } else { ("mWh", "mW") };
+        out.push_str(&format!("  state: {}\n", battery.state_name()));
+        if let Some(percent) = battery.percent() {
+            out.push_str(&format!("  charge: {}%\n", percent));
+        }
+        for (name, value, unit) in [
+            ("remaining", battery.remaining, energy),
+            ("full", battery.full, energy),
+            ("design", battery.design, energy),
+            ("rate", battery.rate, power),
+            ("voltage", battery.voltage, "mV"),
+        ] {
+            if let Some(value) = value {
+                out.push_str(&format!("  {}: {} {}\n", name, value, unit));
+            }
+        }
+        if let Some(minutes) = battery.minutes_left() {
+            let to = if battery.charging() { "full" } else { "empty" };
+            out.push_str(&format!("  time_to_{}: {}:{:02}\n", to, minutes / 60, minutes % 60));
+        }
+        if let Some(cycles) = battery.cycles {
+            out.push_str(&format!("  cycles: {}\n", cycles));
+        }
+        if !battery.model.is_empty() {
+            out.push_str(&format!("  model: {}\n", battery.model));
+        }
+        if !battery.kind.is_empty() {
+            out.push_str(&format!("  type: {}\n", battery.kind));
+        }
+    }
+    out.into_bytes()
+}
+
+/// `power`: the batteries and the AC adapter
+pub fn print_status() {
+    if let Some(power) = POWER.lock().as_ref() {
+        println!("{}", summary(&power.status));
+    }
+    print!("{}", String::from_utf8_lossy(&contents()));
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn integers(values: &[u64]) -> Vec<Value> {
+        values.iter().map(|&n| Value::Integer(n)).collect()
+    }
+
+    fn battery(remaining: u32, full: u32, state: u64) -> Battery {
+        Battery { present: true, remaining: Some(remaining), full: Some(full), low: Some(full / 50), state, ..Battery::new("\\_SB_.BAT0") }
+    }
+
+    #[kernel_test]
+    fn parses_battery_packages() -> Result<(), String> {
+        let mut bat = Battery::new("\\_SB_.PCI0.LPCB.BAT0");
+        check_eq!(bat.name(), "BAT0");
+        let mut bif = integers(&[0, 50000, 48000, 1, 11100, 2400, 960, 1, 1]);
+        bif.extend([Value::String(String::from("DELL 1234")), Value::String(String::from("42")),
+            Value::String(String::from("LION")), Value::String(String::from("SMP"))]);
+        parse_info(&mut bat, &bif, false);
+        check!(!bat.amps);
+        check_eq!((bat.design, bat.full, bat.warning, bat.low), (Some(50000), Some(48000), Some(2400), Some(960)));
+        check_eq!((bat.model.as_str(), bat.kind.as_str()), ("DELL 1234", "LION"));
+
+        let mut bix = integers(&[1, 1, 5200, 5000, 1, 11100, 500, 200, 321, 0, 0, 0, 0, 0, 1, 1]);
+        bix.extend([Value::Buffer(b"X1 Carbon\0".to_vec()), Value::String(String::new()), Value::String(String::from("LiP"))]);
+        parse_info(&mut bat, &bix, true);
+        check!(bat.amps);
+        check_eq!((bat.full, bat.cycles, bat.model.as_str(), bat.kind.as_str()), (Some(5000), Some(321), "X1 Carbon", "LiP"));
+
+        parse_status(&mut bat, &integers(&[1, 1000, 2500, UNKNOWN]));
+        check!(bat.discharging() && !bat.charging());
+        check_eq!((bat.percent(), bat.voltage), (Some(50), None));
+        check_eq!(bat.minutes_left(), Some(150));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn levels_follow_the_charge() -> Result<(), String> {
+        let mut status = PowerStatus { ac_online: Some(false), batteries: alloc::vec![battery(400, 1000, DISCHARGING)] };
+        check_eq!(level(&status), Level::Normal);
+        status.batteries[0].remaining = Some(80);
+        check_eq!(level(&status), Level::Low);
+        status.batteries[0].remaining = Some(20);
+        check_eq!(level(&status), Level::Critical);
+        status.ac_online = Some(true);
+        check_eq!(level(&status), Level::Normal);
+
+        // Two batteries count together, and an absent one not at all
+        status.ac_online = Some(false);
+        status.batteries.push(battery(900, 1000, DISCHARGING));
+        status.batteries.push(Battery::new("\\_SB_.BAT2"));
+        check_eq!(status.percent(), Some(46));
+        check_eq!(level(&status), Level::Normal);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn without_an_adapter_discharging_is_on_battery() -> Result<(), String> {
+        let mut status = PowerStatus { ac_online: None, batteries: alloc::vec![battery(10, 1000, CRITICAL | DISCHARGING)] };
+        check!(status.on_battery());
+        check_eq!(level(&status), Level::Critical);
+        status.batteries[0].state = CHARGING;
+        check!(!status.on_battery());
+        check!(summary(&status).contains("charging"));
+        Ok(())
+    }
+}
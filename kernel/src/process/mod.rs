@@ -4,6 +4,7 @@
 
 use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
+use alloc::string::String;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
@@ -11,8 +12,13 @@ pub mod context;
 pub mod scheduler;
 
 use context::Context;
+use crate::arch::paging::MapToError;
+use crate::mm::address_space::AddressSpace;
+use crate::mm::shm::{self, Shm};
+use crate::syscall::filter::SyscallFilter;
 use webbos_shared::types::{Pid, Tid};
 use crate::println;
+use crate::{debug, info};
 
 /// Maximum number of processes
 pub const MAX_PROCESSES: usize = 1024;
@@ -132,8 +138,104 @@ pub struct Process {
     pub name: [u8; 256],
     /// Exit code (if zombie)
     pub exit_code: i32,
-    /// Working directory
-    pub cwd: [u8; 256],
+    /// Working directory and environment variables
+    pub environment: Environment,
+    /// Rate limit for the GetRandom syscall
+    pub random_budget: RandomBudget,
+    /// Page tables: the kernel's until the process maps pages of its own
+    pub address_space: AddressSpace,
+    /// The syscalls it may make, if not all
+    pub syscall_filter: Option<SyscallFilter>,
+}
+
+/// Where a process works and the variables it sees, passed on to the
+/// processes it starts
+#[derive(Debug, Clone)]
+pub struct Environment {
+    /// Absolute path of the working directory
+    pub cwd: String,
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Environment {
+    /// `/` with `PATH=/bin`
+    pub fn new() -> Self {
+        let mut vars = BTreeMap::new();
+        vars.insert(String::from("PATH"), String::from("/bin"));
+        Self { cwd: String::from("/"), vars }
+    }
+
+    /// A user's session: `HOME` and `USER` set, working in the home
+    /// directory if there is one
+    pub fn login(username: &str, home: &str) -> Self {
+        let mut environment = Self::new();
+        environment.vars.insert(String::from("HOME"), String::from(home));
+        environment.vars.insert(String::from("USER"), String::from(username));
+        let _ = environment.chdir(home);
+        environment
+    }
+
+    /// `path` as an absolute path, taken from the working directory if it
+    /// is relative
+    pub fn resolve(&self, path: &str) -> String {
+        crate::fs::absolute(&self.cwd, path)
+    }
+
+    /// Make `path` the working directory if it is a directory
+    pub fn chdir(&mut self, path: &str) -> Result<(), crate::fs::FsError> {
+        let path = self.resolve(path);
+        if crate::fs::metadata(&path)?.file_type != crate::fs::FileType::Directory {
+            return Err(crate::fs::FsError::NotDirectory);
+        }
+        self.cwd = path;
+        Ok(())
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Token bucket limiting how fast a process may draw random bytes
+#[derive(Debug, Clone, Copy)]
+pub struct RandomBudget {
+    tokens: u64,
+    last_refill_ms: u64,
+}
+
+impl RandomBudget {
+    /// Bucket capacity in bytes
+    pub const CAPACITY: u64 = 64 * 1024;
+    /// Refill rate in bytes per second
+    pub const RATE: u64 = 64 * 1024;
+
+    pub fn new() -> Self {
+        Self {
+            tokens: Self::CAPACITY,
+            last_refill_ms: crate::drivers::timer::elapsed_ms(),
+        }
+    }
+
+    /// Take up to `want` bytes from the bucket, returning how many were granted
+    pub fn take(&mut self, want: u64) -> u64 {
+        let now = crate::drivers::timer::elapsed_ms();
+        let elapsed = now.saturating_sub(self.last_refill_ms);
+        if elapsed > 0 {
+            self.tokens = (self.tokens + elapsed * Self::RATE / 1000).min(Self::CAPACITY);
+            self.last_refill_ms = now;
+        }
+        let granted = want.min(self.tokens);
+        self.tokens -= granted;
+        granted
+    }
+}
+
+impl Default for RandomBudget {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Process {
@@ -153,7 +255,10 @@ impl Process {
             main_thread: Tid::new(0),
             name: name_buf,
             exit_code: 0,
-            cwd: [0u8; 256],
+            environment: Environment::new(),
+            random_budget: RandomBudget::new(),
+            address_space: AddressSpace::kernel(),
+            syscall_filter: None,
         }
     }
 
@@ -175,7 +280,7 @@ lazy_static! {
 
 /// Initialize process management
 pub fn init() {
-    println!("[process] Initializing process management...");
+    info!("process", "Initializing process management...");
 
     // Create idle process (PID 0)
     let idle_process = Process::new(Pid::new(0), None, "idle");
@@ -187,13 +292,17 @@ pub fn init() {
         processes.insert(0, idle_process);
         threads.insert(0, idle_thread);
     }
+    crate::mm::charge(Pid::new(0), CONTROL_BLOCKS_SIZE);
 
     // Initialize scheduler
     scheduler::init();
 
-    println!("[process] Process management initialized");
+    info!("process", "Process management initialized");
 }
 
+/// Heap taken by a process and its main thread's control blocks
+const CONTROL_BLOCKS_SIZE: u64 = (core::mem::size_of::<Process>() + core::mem::size_of::<Thread>()) as u64;
+
 /// Allocate a new process ID
 fn alloc_pid() -> Pid {
     let mut next = NEXT_PID.lock();
@@ -210,12 +319,25 @@ fn alloc_tid() -> Tid {
     Tid::new(tid)
 }
 
-/// Create a new process
+/// Create a new process, with a copy of its parent's environment
 pub fn create_process(name: &str, parent: Option<Pid>) -> Result<Pid, ProcessError> {
+    let environment = parent.and_then(environment).unwrap_or_default();
+    exec(name, parent, environment, None)
+}
+
+/// Create a new process working in `environment`, limited to the syscalls
+/// `filter` and its parent's filter both allow
+pub fn exec(name: &str, parent: Option<Pid>, environment: Environment, filter: Option<SyscallFilter>) -> Result<Pid, ProcessError> {
     let pid = alloc_pid();
     let tid = alloc_tid();
 
     let mut process = Process::new(pid, parent, name);
+    process.environment = environment;
+    let inherited = parent.and_then(|p| PROCESSES.lock().get(&p.as_u64()).and_then(|p| p.syscall_filter));
+    process.syscall_filter = match (inherited, filter) {
+        (Some(inherited), Some(filter)) => Some(inherited.and(filter)),
+        (inherited, filter) => inherited.or(filter),
+    };
     process.main_thread = tid;
     process.threads.push(tid);
     process.state = ProcessState::Ready;
@@ -236,11 +358,17 @@ pub fn create_process(name: &str, parent: Option<Pid>) -> Result<Pid, ProcessErr
         processes.insert(pid.as_u64(), process);
         threads.insert(tid.as_u64(), thread);
     }
+    crate::mm::charge(pid, CONTROL_BLOCKS_SIZE);
+
+    // A forked child must not share generator state with its parent
+    if parent.is_some() {
+        crate::crypto::rng::reseed();
+    }
 
     // Add to scheduler
     scheduler::add_thread(tid);
 
-    println!("[process] Created process {}:{} ({})", pid.as_u64(), tid.as_u64(), name);
+    debug!("process", "Created process {}:{} ({})", pid.as_u64(), tid.as_u64(), name);
     Ok(pid)
 }
 
@@ -264,9 +392,70 @@ pub fn get_thread(tid: Tid) -> Option<spin::MutexGuard<'static, BTreeMap<u64, Th
     }
 }
 
+/// A copy of a process's working directory and variables
+pub fn environment(pid: Pid) -> Option<Environment> {
+    PROCESSES.lock().get(&pid.as_u64()).map(|p| p.environment.clone())
+}
+
+/// Change a process's environment
+pub fn update_environment<R>(pid: Pid, f: impl FnOnce(&mut Environment) -> R) -> Result<R, ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid.as_u64()).ok_or(ProcessError::ProcessNotFound)?;
+    Ok(f(&mut process.environment))
+}
+
+/// Map the first `len` bytes of a shared memory object into a process, at
+/// `virt` or wherever there is room; returns the address
+pub fn map_shared(pid: Pid, virt: Option<u64>, object: &Shm, len: u64, writable: bool) -> Result<u64, ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid.as_u64()).ok_or(ProcessError::ProcessNotFound)?;
+    own_address_space(process)?;
+    process.address_space.map_shared(virt, object, len, writable).map_err(map_error)
+}
+
+/// Undo a process's shared mapping at `start`
+pub fn unmap_shared(pid: Pid, start: u64) -> Result<(), ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid.as_u64()).ok_or(ProcessError::ProcessNotFound)?;
+    if process.address_space.unmap_shared(start) {
+        Ok(())
+    } else {
+        Err(ProcessError::InvalidOperation)
+    }
+}
+
+/// Give a process an address space of its own if it is on the kernel's
+fn own_address_space(process: &mut Process) -> Result<(), ProcessError> {
+    if process.address_space.is_kernel() {
+        process.address_space = AddressSpace::new().ok_or(ProcessError::OutOfMemory)?;
+    }
+    Ok(())
+}
+
+fn map_error(e: MapToError) -> ProcessError {
+    match e {
+        MapToError::FrameAllocationFailed => ProcessError::OutOfMemory,
+        _ => ProcessError::InvalidOperation,
+    }
+}
+
+/// Whether a process's filter, if it has one, lets it make syscall `num`
+pub fn syscall_allowed(pid: Pid, num: u64) -> bool {
+    match PROCESSES.lock().get(&pid.as_u64()).and_then(|p| p.syscall_filter) {
+        Some(filter) => filter.allows(num),
+        None => true,
+    }
+}
+
+/// Whether a process has the `len` bytes at user address `addr` mapped,
+/// and writable if `write`
+pub fn user_range_mapped(pid: Pid, addr: u64, len: usize, write: bool) -> bool {
+    PROCESSES.lock().get(&pid.as_u64()).map_or(false, |p| p.address_space.covers(addr, len, write))
+}
+
 /// Exit current process
 pub fn exit_process(pid: Pid, exit_code: i32) {
-    println!("[process] Process {} exiting with code {}", pid.as_u64(), exit_code);
+    info!("process", "Process {} exiting with code {}", pid.as_u64(), exit_code);
 
     let mut processes = PROCESSES.lock();
     
@@ -289,6 +478,96 @@ pub fn exit_process(pid: Pid, exit_code: i32) {
     }
 }
 
+/// Process the running thread belongs to
+pub fn current_pid() -> Option<Pid> {
+    let tid = scheduler::current_thread()?;
+    THREADS.lock().get(&tid.as_u64()).map(|t| t.pid)
+}
+
+/// Kill another process and free what it holds
+///
+/// Besides `terminate`, this closes the process's windows. The idle
+/// process and the caller's own process cannot be killed this way; the
+/// latter exits with `exit_process`.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    if pid.as_u64() == 0 || current_pid() == Some(pid) {
+        return Err(ProcessError::InvalidOperation);
+    }
+    terminate(pid)?;
+    crate::desktop::close_process_windows(pid);
+    info!("process", "Killed process {}", pid.as_u64());
+    Ok(())
+}
+
+/// Remove a process and everything it holds except its windows
+///
+/// Its threads leave the run queues and the thread table, its sockets are
+/// closed, its memory charge is dropped and its children are orphaned.
+/// Nothing waits on processes yet, so it is reaped at once rather than
+/// left as a zombie. The desktop calls this when an app's window closes.
+pub fn terminate(pid: Pid) -> Result<(), ProcessError> {
+    if pid.as_u64() == 0 {
+        return Err(ProcessError::InvalidOperation);
+    }
+    let tids = {
+        let mut processes = PROCESSES.lock();
+        let mut threads = THREADS.lock();
+        let process = processes.remove(&pid.as_u64()).ok_or(ProcessError::ProcessNotFound)?;
+        for tid in &process.threads {
+            threads.remove(&tid.as_u64());
+        }
+        if let Some(parent) = process.parent.and_then(|p| processes.get_mut(&p.as_u64())) {
+            parent.children.retain(|&c| c != pid);
+        }
+        for child in &process.children {
+            if let Some(child) = processes.get_mut(&child.as_u64()) {
+                child.parent = None;
+            }
+        }
+        process.threads
+    };
+    // The scheduler locks THREADS after itself, so it is entered only once
+    // the tables are released
+    for tid in tids {
+        scheduler::remove_thread(tid);
+    }
+    crate::net::socket::close_owned(pid);
+    shm::release(pid);
+    crate::mm::release(pid);
+    Ok(())
+}
+
+/// Share of the CPU each process had since the previous call, by PID
+///
+/// Percentages come from the ticks the scheduler charged to each thread.
+/// The idle process's share is time nothing else wanted the CPU. The map
+/// is empty if no tick has passed since the last call.
+pub fn cpu_usage() -> BTreeMap<u64, u32> {
+    static LAST: Mutex<(u64, BTreeMap<u64, u64>)> = Mutex::new((0, BTreeMap::new()));
+
+    let now = scheduler::ticks();
+    let run_ticks = scheduler::run_ticks();
+    let mut last = LAST.lock();
+    let elapsed = now.saturating_sub(last.0);
+    if elapsed == 0 {
+        return BTreeMap::new();
+    }
+    let mut usage: BTreeMap<u64, u64> = BTreeMap::new();
+    {
+        let threads = THREADS.lock();
+        for (tid, &ticks) in &run_ticks {
+            if let Some(thread) = threads.get(tid) {
+                let ran = ticks.saturating_sub(last.1.get(tid).copied().unwrap_or(0));
+                *usage.entry(thread.pid.as_u64()).or_insert(0) += ran;
+            }
+        }
+    }
+    *last = (now, run_ticks);
+    usage.into_iter()
+        .map(|(pid, ran)| (pid, (ran * 100 / elapsed).min(100) as u32))
+        .collect()
+}
+
 /// Get current process info
 pub fn print_process_list() {
     let processes = PROCESSES.lock();
@@ -305,8 +584,9 @@ pub fn print_process_list() {
             ProcessState::Zombie => "ZMB",
             ProcessState::Creating => "NEW",
         };
-        println!("{:>3}  {:<8} {:<12} {}", 
-            pid, state_str, process.name(), process.threads.len());
+        let filtered = if process.syscall_filter.is_some() { "  (syscalls filtered)" } else { "" };
+        println!("{:>3}  {:<8} {:<12} {}{}", 
+            pid, state_str, process.name(), process.threads.len(), filtered);
     }
 
     println!("\nTID  PID  State    Priority");
@@ -338,4 +618,6 @@ pub enum ProcessError {
     ThreadNotFound,
     /// Invalid operation
     InvalidOperation,
+    /// No frames left for page tables or pages
+    OutOfMemory,
 }
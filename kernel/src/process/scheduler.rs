@@ -2,15 +2,18 @@
 //!
 //! Implements a simple preemptive round-robin scheduler.
 
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, VecDeque};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
 use super::{Priority, Tid};
+use crate::drivers::timer;
+use crate::trace;
 use crate::println;
+use crate::info;
 
-/// Time slice in timer ticks (10ms per tick, so 100ms default)
-pub const DEFAULT_TIME_SLICE: u64 = 10;
+/// How long a thread runs before the next gets a turn
+pub const TIME_SLICE_NS: u64 = 10_000_000;
 
 /// Current running thread on each CPU
 static mut CURRENT_THREADS: [Option<Tid>; 8] = [None; 8]; // Support up to 8 CPUs
@@ -19,12 +22,14 @@ static mut CURRENT_THREADS: [Option<Tid>; 8] = [None; 8]; // Support up to 8 CPU
 struct Scheduler {
     /// Ready queue for each priority level
     ready_queues: [VecDeque<Tid>; 32],
-    /// Current time slice remaining
-    time_slice: u64,
+    /// When the running thread's slice is up, in `timer::now_ns` time
+    slice_end: u64,
     /// Whether scheduling is enabled
     enabled: bool,
     /// Total ticks elapsed
     ticks: u64,
+    /// Ticks each thread has been charged with, by TID
+    run_ticks: BTreeMap<u64, u64>,
 }
 
 impl Scheduler {
@@ -32,9 +37,10 @@ impl Scheduler {
         const EMPTY_QUEUE: VecDeque<Tid> = VecDeque::new();
         Self {
             ready_queues: [EMPTY_QUEUE; 32],
-            time_slice: DEFAULT_TIME_SLICE,
+            slice_end: 0,
             enabled: false,
             ticks: 0,
+            run_ticks: BTreeMap::new(),
         }
     }
 
@@ -72,12 +78,14 @@ lazy_static! {
 
 /// Initialize the scheduler
 pub fn init() {
-    println!("[scheduler] Initializing round-robin scheduler...");
+    info!("scheduler", "Initializing round-robin scheduler...");
 
     let mut scheduler = SCHEDULER.lock();
     scheduler.enabled = true;
+    // The idle thread is charged whenever nothing else is running
+    scheduler.run_ticks.insert(0, 0);
 
-    println!("[scheduler] Scheduler initialized");
+    info!("scheduler", "Scheduler initialized");
 }
 
 /// Add a thread to the scheduler
@@ -91,6 +99,7 @@ pub fn add_thread(tid: Tid) {
     if let Some(thread) = threads.get(&tid.as_u64()) {
         let priority = thread.priority;
         scheduler.enqueue(tid, priority);
+        scheduler.run_ticks.entry(tid.as_u64()).or_insert(0);
     }
 }
 
@@ -102,6 +111,7 @@ pub fn remove_thread(tid: Tid) {
     for queue in &mut scheduler.ready_queues {
         queue.retain(|&t| t.as_u64() != tid.as_u64());
     }
+    scheduler.run_ticks.remove(&tid.as_u64());
 }
 
 /// Schedule next thread to run
@@ -119,6 +129,16 @@ pub unsafe fn schedule_next() {
     let cpu_id = 0; // TODO: Get actual CPU ID
     let current_tid = CURRENT_THREADS[cpu_id];
 
+    // From the timer interrupt, the thread table may be held by the
+    // code it interrupted; switch at a later tick instead
+    let threads = match super::THREADS.try_lock() {
+        Some(threads) => threads,
+        None => {
+            scheduler.slice_end = timer::now_ns() + TIME_SLICE_NS;
+            return;
+        }
+    };
+
     // Get next thread from ready queue
     let next_tid = scheduler.dequeue()
         .or(current_tid)
@@ -126,30 +146,25 @@ pub unsafe fn schedule_next() {
 
     // If same thread, just reset time slice and return
     if Some(next_tid) == current_tid {
-        scheduler.time_slice = DEFAULT_TIME_SLICE;
+        scheduler.slice_end = timer::now_ns() + TIME_SLICE_NS;
         return;
     }
 
     // Put current thread back in queue if it's still runnable
     if let Some(tid) = current_tid {
-        use super::THREADS;
-        let threads = THREADS.lock();
         if let Some(thread) = threads.get(&tid.as_u64()) {
             if thread.is_runnable() {
-                let priority = thread.priority;
-                // Need to reacquire scheduler lock
-                drop(scheduler);
-                SCHEDULER.lock().enqueue(tid, priority);
-                
-                // Reacquire for the rest of the function
-                scheduler = SCHEDULER.lock();
+                scheduler.enqueue(tid, thread.priority);
             }
         }
     }
 
+    drop(threads);
+
     // Update current thread
     CURRENT_THREADS[cpu_id] = Some(next_tid);
-    scheduler.time_slice = DEFAULT_TIME_SLICE;
+    trace::instant(trace::Kind::Switch, current_tid.map_or(0, |tid| tid.as_u64()), next_tid.as_u64());
+    scheduler.slice_end = timer::now_ns() + TIME_SLICE_NS;
 
     // Perform context switch
     // Note: This is a simplified version - real implementation needs more care
@@ -164,21 +179,30 @@ pub unsafe fn schedule_next() {
 /// # Safety
 /// This function is unsafe because it may trigger a context switch.
 pub unsafe fn timer_tick() {
-    let mut scheduler = SCHEDULER.lock();
+    crate::watchdog::pet(crate::watchdog::Subsystem::Scheduler);
+
+    // The tick may land while the scheduler is being changed; it can
+    // skip one rather than wait on a lock its own CPU holds
+    let mut scheduler = match SCHEDULER.try_lock() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
 
     scheduler.ticks += 1;
 
-    if !scheduler.enabled {
-        return;
+    // Charge the tick to whoever it interrupted. Entries are made when a
+    // thread is added, so nothing is allocated here.
+    let running = current_thread().map_or(0, |tid| tid.as_u64());
+    if let Some(ticks) = scheduler.run_ticks.get_mut(&running) {
+        *ticks += 1;
     }
 
-    // Decrement time slice
-    if scheduler.time_slice > 0 {
-        scheduler.time_slice -= 1;
+    if !scheduler.enabled {
+        return;
     }
 
     // If time slice expired, schedule next thread
-    if scheduler.time_slice == 0 && scheduler.has_runnable() {
+    if timer::now_ns() >= scheduler.slice_end && scheduler.has_runnable() {
         drop(scheduler);
         schedule_next();
     }
@@ -192,6 +216,16 @@ pub unsafe fn yield_current() {
     schedule_next();
 }
 
+/// Timer ticks since boot
+pub fn ticks() -> u64 {
+    SCHEDULER.lock().ticks
+}
+
+/// Timer ticks each thread has spent running, by TID
+pub fn run_ticks() -> BTreeMap<u64, u64> {
+    SCHEDULER.lock().run_ticks.clone()
+}
+
 /// Get current thread ID
 pub fn current_thread() -> Option<Tid> {
     let cpu_id = 0; // TODO: Get actual CPU ID
@@ -205,7 +239,7 @@ pub fn print_stats() {
     println!("Scheduler Statistics:");
     println!("  Ticks: {}", scheduler.ticks);
     println!("  Enabled: {}", scheduler.enabled);
-    println!("  Time slice remaining: {}", scheduler.time_slice);
+    println!("  Time slice remaining: {}us", scheduler.slice_end.saturating_sub(timer::now_ns()) / 1000);
 
     // Count threads in each priority queue
     for (i, queue) in scheduler.ready_queues.iter().enumerate() {
@@ -0,0 +1,626 @@
+//! Service manager
+//!
+//! The long-running parts of the system are services, each defined by a
+//! file `/etc/services.d/NAME.service` of `key = value` lines, `#`
+//! starting a comment:
+//!
+//! - `description`: one line for `service status`
+//! - `requires`: services, separated by spaces or commas, that must be
+//!   running before it starts
+//! - `enabled`: `yes` to start it at boot, the default, or `no`
+//! - `restart`: `on-failure`, the default, or `no`
+//!
+//! Any other key is an option for the service itself. The kernel has the
+//! code for each service, a `Unit` by the same name; a definition without
+//! one is ignored.
+//!
+//! `init` starts the enabled services, each after those it requires. One
+//! that fails is logged and the rest carry on without it. `poll` watches
+//! the running ones: one that fails has what requires it stopped, and is
+//! started again after a second, then twice as long after each failure up
+//! to a minute, until it has stayed up a minute. A service waiting for
+//! one it requires starts once that is running.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::drivers::timer;
+use crate::{desktop, fs, net, println};
+use crate::{info, warn};
+
+/// Where the definitions are
+pub const SERVICES_DIR: &str = "/etc/services.d";
+
+/// Wait before the first restart, and the longest wait
+const FIRST_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF_MS: u64 = 60_000;
+/// How long a service stays up before its failures are forgotten
+const STABLE_MS: u64 = 60_000;
+
+/// A service's definition file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub name: String,
+    pub description: String,
+    pub requires: Vec<String>,
+    pub enabled: bool,
+    pub restart: bool,
+    options: BTreeMap<String, String>,
+}
+
+impl Definition {
+    /// Read the definition of service `name` from its file's `text`
+    pub fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let mut definition = Definition {
+            name: String::from(name),
+            description: String::new(),
+            requires: Vec::new(),
+            enabled: true,
+            restart: true,
+            options: BTreeMap::new(),
+        };
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(format!("line {}: '{}' is not key = value", n + 1, line)),
+            };
+            match key {
+                "description" => definition.description = String::from(value),
+                "requires" => {
+                    definition.requires = value.split([' ', ',']).filter(|s| !s.is_empty()).map(String::from).collect();
+                }
+                "enabled" => definition.enabled = match value {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(format!("line {}: enabled is yes or no", n + 1)),
+                },
+                "restart" => definition.restart = match value {
+                    "on-failure" => true,
+                    "no" => false,
+                    _ => return Err(format!("line {}: restart is on-failure or no", n + 1)),
+                },
+                _ => {
+                    definition.options.insert(String::from(key), String::from(value));
+                }
+            }
+        }
+        Ok(definition)
+    }
+
+    /// A setting for the service itself
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(String::as_str)
+    }
+}
+
+/// The code behind a service
+///
+/// `start` may be called again after the service failed or was stopped.
+/// `poll` is called often while it runs, and fails the service by
+/// returning an error. Services that cannot be stopped have no `stop`.
+pub struct Unit {
+    pub name: &'static str,
+    pub start: fn(&Definition) -> Result<(), String>,
+    pub poll: fn() -> Result<(), String>,
+    pub stop: Option<fn()>,
+    /// A line on how it is doing, for `service status`
+    pub describe: fn() -> Option<String>,
+}
+
+const UNITS: [Unit; 4] = [
+    Unit { name: "network", start: start_network, poll: poll_network, stop: None, describe: describe_network },
+    Unit { name: "httpd", start: net::httpd::start, poll: net::httpd::poll, stop: Some(net::httpd::stop), describe: net::httpd::describe },
+    Unit { name: "ntp", start: net::ntp::start, poll: net::ntp::poll, stop: Some(net::ntp::stop), describe: net::ntp::describe },
+    Unit { name: "desktop", start: start_desktop, poll: || Ok(()), stop: None, describe: || None },
+];
+
+/// The drivers are registered once; later starts only look for an
+/// interface again
+static NETWORK_PROBED: Mutex<bool> = Mutex::new(false);
+
+fn start_network(_: &Definition) -> Result<(), String> {
+    let mut probed = NETWORK_PROBED.lock();
+    if !*probed {
+        net::init();
+        *probed = true;
+    }
+    poll_network()
+}
+
+fn poll_network() -> Result<(), String> {
+    if net::interface_count() == 0 {
+        return Err(String::from("no network interfaces"));
+    }
+    Ok(())
+}
+
+fn describe_network() -> Option<String> {
+    let config = net::get_config();
+    Some(if config.is_configured() { format!("{:?}", config.ip) } else { String::from("no address yet") })
+}
+
+fn start_desktop(_: &Definition) -> Result<(), String> {
+    desktop::init();
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    /// Stopped, or not enabled
+    Inactive,
+    /// To start once what it requires is running
+    Waiting,
+    Running { since_ms: u64 },
+    /// Started again at `retry_ms`, if it restarts
+    Failed { reason: String, retry_ms: Option<u64> },
+}
+
+struct Service {
+    definition: Definition,
+    unit: &'static Unit,
+    state: State,
+    /// Failures since it last stayed up
+    failures: u32,
+    /// Its requirements cannot be met, so it never starts
+    broken: bool,
+}
+
+/// Services in the order they start: each after those it requires
+pub struct Manager {
+    services: Vec<Service>,
+}
+
+/// How long to wait before starting a service again after its `failures`th
+/// failure in a row
+pub fn backoff_ms(failures: u32) -> u64 {
+    FIRST_BACKOFF_MS.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_BACKOFF_MS)
+}
+
+impl Manager {
+    /// Services for the definitions that have a unit; those that require
+    /// one that does not exist, or that require each other, fail for good
+    pub fn new(definitions: Vec<Definition>, units: &'static [Unit]) -> Self {
+        let mut pending: Vec<Service> = Vec::new();
+        for definition in definitions {
+            match units.iter().find(|u| u.name == definition.name) {
+                Some(unit) => {
+                    let state = if definition.enabled { State::Waiting } else { State::Inactive };
+                    pending.push(Service { definition, unit, state, failures: 0, broken: false });
+                }
+                None => warn!("service", "No service called '{}'; ignoring its definition", definition.name),
+            }
+        }
+        pending.sort_by(|a, b| a.definition.name.cmp(&b.definition.name));
+
+        let mut services: Vec<Service> = Vec::new();
+        // Kahn's algorithm: take whatever has all it requires placed
+        loop {
+            let placed: Vec<String> = services.iter().map(|s| s.definition.name.clone()).collect();
+            let ready = pending.iter().position(|s| s.definition.requires.iter().all(|r| placed.contains(r)));
+            match ready {
+                Some(index) => services.push(pending.remove(index)),
+                None => break,
+            }
+        }
+        let names: Vec<String> = services.iter().chain(pending.iter()).map(|s| s.definition.name.clone()).collect();
+        for mut service in pending {
+            let reason = match service.definition.requires.iter().find(|r| !names.contains(r)) {
+                Some(missing) => format!("requires '{}', which is not defined", missing),
+                None => String::from("its requirements depend on it"),
+            };
+            service.state = State::Failed { reason, retry_ms: None };
+            service.broken = true;
+            services.push(service);
+        }
+        Manager { services }
+    }
+
+    fn index(&self, name: &str) -> Result<usize, String> {
+        self.services.iter().position(|s| s.definition.name == name).ok_or_else(|| format!("no service '{}'", name))
+    }
+
+    fn is_running(&self, name: &str) -> bool {
+        self.services.iter().any(|s| s.definition.name == name && matches!(s.state, State::Running { .. }))
+    }
+
+    /// Start the waiting services whose requirements are running
+    fn settle(&mut self, now: u64) {
+        // Requirements come first, so one pass starts whole chains
+        for index in 0..self.services.len() {
+            let ready = self.services[index].state == State::Waiting
+                && self.services[index].definition.requires.iter().all(|r| self.is_running(r));
+            if ready {
+                self.launch(index, now);
+            }
+        }
+    }
+
+    fn launch(&mut self, index: usize, now: u64) {
+        let service = &mut self.services[index];
+        match (service.unit.start)(&service.definition) {
+            Ok(()) => {
+                info!("service", "Started {}", service.definition.name);
+                service.state = State::Running { since_ms: now };
+            }
+            Err(reason) => self.fail(index, reason, now),
+        }
+    }
+
+    /// Mark a service failed, and send what requires it back to waiting
+    fn fail(&mut self, index: usize, reason: String, now: u64) {
+        let service = &mut self.services[index];
+        service.failures += 1;
+        let retry_ms = service.definition.restart.then(|| now + backoff_ms(service.failures));
+        match retry_ms {
+            Some(at) => warn!("service", "{} failed: {}; restarting in {}s", service.definition.name, reason, (at - now) / 1000),
+            None => warn!("service", "{} failed: {}", service.definition.name, reason),
+        }
+        service.state = State::Failed { reason, retry_ms };
+        let name = service.definition.name.clone();
+        for dependent in self.dependents(&name).into_iter().rev() {
+            let service = &mut self.services[dependent];
+            if let State::Running { .. } = service.state {
+                if let Some(stop) = service.unit.stop {
+                    stop();
+                }
+                service.state = State::Waiting;
+            }
+        }
+    }
+
+    /// Services that require `name`, directly or not, in starting order
+    fn dependents(&self, name: &str) -> Vec<usize> {
+        let mut names = alloc::vec![String::from(name)];
+        let mut found = Vec::new();
+        for (index, service) in self.services.iter().enumerate() {
+            if service.definition.requires.iter().any(|r| names.contains(r)) {
+                names.push(service.definition.name.clone());
+                found.push(index);
+            }
+        }
+        found
+    }
+
+    /// Check the running services, restart failed ones that are due and
+    /// start those that can
+    pub fn poll(&mut self, now: u64) {
+        for index in 0..self.services.len() {
+            match self.services[index].state.clone() {
+                State::Running { since_ms } => {
+                    if now.saturating_sub(since_ms) >= STABLE_MS {
+                        self.services[index].failures = 0;
+                    }
+                    if let Err(reason) = (self.services[index].unit.poll)() {
+                        self.fail(index, reason, now);
+                    }
+                }
+                State::Failed { retry_ms: Some(at), .. } if now >= at => self.services[index].state = State::Waiting,
+                _ => {}
+            }
+        }
+        self.settle(now);
+    }
+
+    /// Start a service, and what it requires
+    pub fn start(&mut self, name: &str, now: u64) -> Result<(), String> {
+        let index = self.index(name)?;
+        let mut wanted = alloc::vec![index];
+        while let Some(index) = wanted.pop() {
+            let service = &mut self.services[index];
+            if let (true, State::Failed { reason, .. }) = (service.broken, &service.state) {
+                return Err(format!("{}: {}", service.definition.name, reason));
+            }
+            if !matches!(service.state, State::Running { .. }) {
+                service.state = State::Waiting;
+            }
+            for requirement in service.definition.requires.clone() {
+                wanted.push(self.index(&requirement)?);
+            }
+        }
+        self.settle(now);
+        match &self.services[index].state {
+            State::Running { .. } => Ok(()),
+            State::Failed { reason, .. } => Err(reason.clone()),
+            _ => Err(String::from("what it requires is not running")),
+        }
+    }
+
+    /// Stop a service and what requires it
+    pub fn stop(&mut self, name: &str) -> Result<(), String> {
+        let index = self.index(name)?;
+        let mut stopping = self.dependents(name);
+        stopping.insert(0, index);
+        for &index in &stopping {
+            let service = &self.services[index];
+            if service.unit.stop.is_none() && matches!(service.state, State::Running { .. }) {
+                return Err(format!("{} cannot be stopped", service.definition.name));
+            }
+        }
+        for &index in stopping.iter().rev() {
+            let service = &mut self.services[index];
+            if service.broken {
+                continue;
+            }
+            if let (State::Running { .. }, Some(stop)) = (&service.state, service.unit.stop) {
+                stop();
+                info!("service", "Stopped {}", service.definition.name);
+            }
+            service.state = State::Inactive;
+            service.failures = 0;
+        }
+        Ok(())
+    }
+
+    pub fn state(&self, name: &str) -> Option<&State> {
+        self.services.iter().find(|s| s.definition.name == name).map(|s| &s.state)
+    }
+
+    /// Names in starting order
+    pub fn names(&self) -> Vec<&str> {
+        self.services.iter().map(|s| s.definition.name.as_str()).collect()
+    }
+}
+
+static MANAGER: Mutex<Option<Manager>> = Mutex::new(None);
+
+/// The definitions in `SERVICES_DIR`
+fn load() -> Vec<Definition> {
+    let entries = match fs::read_dir(SERVICES_DIR) {
+        Ok(entries) => entries,
+        Err(_) => {
+            warn!("service", "No {}; no services", SERVICES_DIR);
+            return Vec::new();
+        }
+    };
+    let mut definitions = Vec::new();
+    for entry in entries {
+        let name = match entry.name.strip_suffix(".service") {
+            Some(name) => name,
+            None => continue,
+        };
+        let path = format!("{}/{}", SERVICES_DIR, entry.name);
+        let text = match fs::read_file(&path) {
+            Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+            Err(e) => {
+                warn!("service", "Cannot read {}: {:?}", path, e);
+                continue;
+            }
+        };
+        match Definition::parse(name, &text) {
+            Ok(definition) => definitions.push(definition),
+            Err(e) => warn!("service", "{}: {}", path, e),
+        }
+    }
+    definitions
+}
+
+/// Read the definitions and start the enabled services
+pub fn init() {
+    let mut manager = Manager::new(load(), &UNITS);
+    info!("service", "Starting {}", manager.names().join(", "));
+    manager.settle(timer::elapsed_ms());
+    *MANAGER.lock() = Some(manager);
+}
+
+/// Watch the services; called from the main loops
+pub fn poll() {
+    // Not while a command is changing them
+    if let Some(mut guard) = MANAGER.try_lock() {
+        if let Some(manager) = guard.as_mut() {
+            manager.poll(timer::elapsed_ms());
+        }
+    }
+}
+
+fn duration(ms: u64) -> String {
+    let seconds = ms / 1000;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+fn print_service(manager: &Manager, service: &Service, now: u64) {
+    let (state, detail) = match &service.state {
+        State::Inactive => ("inactive", String::new()),
+        State::Waiting => {
+            let missing: Vec<&str> = service.definition.requires.iter()
+                .filter(|r| !manager.is_running(r))
+                .map(String::as_str)
+                .collect();
+            ("waiting", format!("for {}", missing.join(", ")))
+        }
+        State::Running { since_ms } => {
+            let up = format!("up {}", duration(now.saturating_sub(*since_ms)));
+            match (service.unit.describe)() {
+                Some(line) => ("running", format!("{}; {}", up, line)),
+                None => ("running", up),
+            }
+        }
+        State::Failed { reason, retry_ms: Some(at) } => ("failed", format!("{}; retry in {}", reason, duration(at.saturating_sub(now)))),
+        State::Failed { reason, retry_ms: None } => ("failed", reason.clone()),
+    };
+    println!("{:<10} {:<9} {}", service.definition.name, state, detail);
+}
+
+/// `service [status [NAME] | start NAME | stop NAME | restart NAME]`
+pub fn command(args: &[&str]) {
+    let mut guard = MANAGER.lock();
+    let manager = match guard.as_mut() {
+        Some(manager) => manager,
+        None => {
+            println!("service: the service manager is not running");
+            return;
+        }
+    };
+    let now = timer::elapsed_ms();
+    let result = match args {
+        [] | ["status"] => {
+            println!("{:<10} {:<9} DETAIL", "SERVICE", "STATE");
+            for service in &manager.services {
+                print_service(manager, service, now);
+            }
+            return;
+        }
+        ["status", name] => match manager.index(name) {
+            Ok(index) => {
+                let service = &manager.services[index];
+                if !service.definition.description.is_empty() {
+                    println!("{}: {}", service.definition.name, service.definition.description);
+                }
+                if !service.definition.requires.is_empty() {
+                    println!("Requires: {}", service.definition.requires.join(", "));
+                }
+                print_service(manager, service, now);
+                return;
+            }
+            Err(e) => Err(e),
+        },
+        ["start", name] => manager.start(name, now),
+        ["stop", name] => manager.stop(name),
+        ["restart", name] => manager.stop(name).and_then(|()| manager.start(name, now)),
+        _ => {
+            println!("Usage: service [status [NAME] | start NAME | stop NAME | restart NAME]");
+            return;
+        }
+    };
+    match result {
+        Ok(()) => println!("{}: {}", args[1], if args[0] == "stop" { "stopped" } else { "running" }),
+        Err(e) => println!("service: {}", e),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    static BASE_UP: AtomicBool = AtomicBool::new(true);
+    static BASE_STARTS: AtomicU32 = AtomicU32::new(0);
+    static TOP_STOPS: AtomicU32 = AtomicU32::new(0);
+
+    static TEST_UNITS: [Unit; 3] = [
+        Unit {
+            name: "base",
+            start: |_| {
+                BASE_STARTS.fetch_add(1, Ordering::Relaxed);
+                if BASE_UP.load(Ordering::Relaxed) { Ok(()) } else { Err(String::from("down")) }
+            },
+            poll: || if BASE_UP.load(Ordering::Relaxed) { Ok(()) } else { Err(String::from("down")) },
+            stop: Some(|| {}),
+            describe: || None,
+        },
+        Unit { name: "middle", start: |_| Ok(()), poll: || Ok(()), stop: Some(|| {}), describe: || None },
+        Unit {
+            name: "top",
+            start: |_| Ok(()),
+            poll: || Ok(()),
+            stop: Some(|| {
+                TOP_STOPS.fetch_add(1, Ordering::Relaxed);
+            }),
+            describe: || None,
+        },
+    ];
+
+    fn definition(name: &str, text: &str) -> Definition {
+        Definition::parse(name, text).expect("valid definition")
+    }
+
+    fn running(manager: &Manager, name: &str) -> bool {
+        matches!(manager.state(name), Some(State::Running { .. }))
+    }
+
+    #[kernel_test]
+    fn parses_definitions() -> Result<(), String> {
+        let d = Definition::parse("httpd", "# web\ndescription = Web server\nrequires = network, ntp\nport = 8080\nenabled = no\n")?;
+        check_eq!(d.description, "Web server");
+        check_eq!(d.requires, alloc::vec![String::from("network"), String::from("ntp")]);
+        check!(!d.enabled);
+        check!(d.restart);
+        check_eq!(d.option("port"), Some("8080"));
+        check!(Definition::parse("x", "restart = sometimes").is_err());
+        check!(Definition::parse("x", "no equals sign").is_err());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn backs_off_up_to_a_minute() -> Result<(), String> {
+        check_eq!(backoff_ms(1), 1000);
+        check_eq!(backoff_ms(2), 2000);
+        check_eq!(backoff_ms(4), 8000);
+        check_eq!(backoff_ms(7), 60_000);
+        check_eq!(backoff_ms(u32::MAX), 60_000);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn orders_by_requirement_and_rejects_cycles() -> Result<(), String> {
+        let manager = Manager::new(alloc::vec![
+            definition("top", "requires = middle"),
+            definition("middle", "requires = base"),
+            definition("base", ""),
+            definition("unknown", ""),
+        ], &TEST_UNITS);
+        check_eq!(manager.names(), alloc::vec!["base", "middle", "top"]);
+
+        let mut manager = Manager::new(alloc::vec![
+            definition("base", "requires = top"),
+            definition("middle", "requires = nothing"),
+            definition("top", "requires = base"),
+        ], &TEST_UNITS);
+        check!(matches!(manager.state("base"), Some(State::Failed { retry_ms: None, .. })));
+        check!(manager.start("middle", 0).is_err());
+        manager.settle(0);
+        check!(!running(&manager, "top"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn restarts_with_backoff_and_stops_dependents() -> Result<(), String> {
+        BASE_UP.store(true, Ordering::Relaxed);
+        BASE_STARTS.store(0, Ordering::Relaxed);
+        TOP_STOPS.store(0, Ordering::Relaxed);
+        let mut manager = Manager::new(alloc::vec![
+            definition("top", "requires = base"),
+            definition("base", ""),
+            definition("middle", "enabled = no"),
+        ], &TEST_UNITS);
+        manager.settle(0);
+        check!(running(&manager, "base") && running(&manager, "top"));
+        check_eq!(manager.state("middle"), Some(&State::Inactive));
+
+        // base goes down: top is stopped and waits for it
+        BASE_UP.store(false, Ordering::Relaxed);
+        manager.poll(10);
+        check!(matches!(manager.state("base"), Some(State::Failed { retry_ms: Some(1010), .. })));
+        check_eq!(manager.state("top"), Some(&State::Waiting));
+        check_eq!(TOP_STOPS.load(Ordering::Relaxed), 1);
+        // Not before the backoff, then a second failure waits twice as long
+        manager.poll(500);
+        check_eq!(BASE_STARTS.load(Ordering::Relaxed), 1);
+        manager.poll(1010);
+        check_eq!(BASE_STARTS.load(Ordering::Relaxed), 2);
+        check!(matches!(manager.state("base"), Some(State::Failed { retry_ms: Some(3010), .. })));
+
+        BASE_UP.store(true, Ordering::Relaxed);
+        manager.poll(3010);
+        check!(running(&manager, "base") && running(&manager, "top"));
+
+        // Stopping base takes top with it; starting top brings base back
+        check_eq!(manager.stop("base"), Ok(()));
+        check_eq!(manager.state("top"), Some(&State::Inactive));
+        check_eq!(manager.start("top", 4000), Ok(()));
+        check!(running(&manager, "base"));
+        check!(manager.start("missing", 4000).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! Text commands
+//!
+//! Small commands for putting text into pipelines and files and picking
+//! through it, and `sh` for scripts, registered by `shell::init`.
+
+use super::command::Command;
+use crate::println;
+
+pub const BUILTINS: [Command; 5] = [
+    Command { name: "echo", description: "Print its arguments (e.g., echo hello > /tmp/note)", run: echo },
+    Command { name: "grep", description: "Print input lines containing a text (e.g., ps | grep init)", run: grep },
+    Command { name: "head", description: "Print the first lines of its input (e.g., help | head 5)", run: head },
+    Command { name: "wc", description: "Count lines, words and bytes of its input", run: wc },
+    Command { name: "sh", description: "Run a shell script (e.g., sh /etc/rc.local)", run: super::script::sh_command },
+];
+
+fn echo(args: &[&str], _input: &str) {
+    println!("{}", args.join(" "));
+}
+
+fn grep(args: &[&str], input: &str) {
+    let pattern = match args {
+        [pattern] => pattern,
+        _ => {
+            println!("Usage: grep <text>");
+            return;
+        }
+    };
+    for line in input.lines().filter(|line| line.contains(pattern)) {
+        println!("{}", line);
+    }
+}
+
+fn head(args: &[&str], input: &str) {
+    let count = match args {
+        [] => Some(10),
+        [n] => n.parse::<usize>().ok(),
+        _ => None,
+    };
+    match count {
+        Some(count) => input.lines().take(count).for_each(|line| println!("{}", line)),
+        None => println!("Usage: head [lines]"),
+    }
+}
+
+fn wc(_args: &[&str], input: &str) {
+    println!("{} {} {}", input.lines().count(), input.split_whitespace().count(), input.len());
+}
@@ -0,0 +1,41 @@
+//! Command registry
+//!
+//! The commands the console and terminal shells run. Each takes its
+//! arguments, without its own name, and the text piped into it, empty if
+//! nothing was; what it prints is its output.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Runs a command with its arguments and input
+pub type Run = fn(args: &[&str], input: &str);
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// What `help` says about it; commands without one, such as
+    /// short aliases, are left out of `help`
+    pub description: &'static str,
+    pub run: Run,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Add a command, replacing any of the same name
+pub fn register(command: Command) {
+    let mut commands = COMMANDS.lock();
+    match commands.iter_mut().find(|c| c.name == command.name) {
+        Some(existing) => *existing = command,
+        None => commands.push(command),
+    }
+}
+
+/// The command called `name`
+pub fn find(name: &str) -> Option<Command> {
+    COMMANDS.lock().iter().find(|c| c.name == name).copied()
+}
+
+/// Every command, in the order they were registered
+pub fn all() -> Vec<Command> {
+    COMMANDS.lock().clone()
+}
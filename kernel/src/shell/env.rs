@@ -0,0 +1,123 @@
+//! Session environments
+//!
+//! The console and each terminal shell are a session with a process of
+//! their own, and the process's environment is the session's: `cd`
+//! changes its working directory, `export` its variables, and apps
+//! launched from it start with a copy. Commands take relative paths from
+//! the working directory of the session running them.
+
+use alloc::string::String;
+use spin::Mutex;
+use webbos_shared::types::Pid;
+
+use super::command::Command;
+use crate::process::{self, Environment};
+use crate::{println, users};
+
+pub const ENV_COMMANDS: [Command; 5] = [
+    Command { name: "cd", description: "Change directory (e.g., cd /etc); home if none is given", run: cd },
+    Command { name: "pwd", description: "Print the working directory", run: pwd },
+    Command { name: "export", description: "Set environment variables (e.g., export PATH=/bin)", run: export },
+    Command { name: "unset", description: "Remove environment variables", run: unset },
+    Command { name: "env", description: "List environment variables", run: env },
+];
+
+/// Process of the session whose commands are running
+static SESSION: Mutex<Option<Pid>> = Mutex::new(None);
+
+/// Start a session's process, with `parent`'s environment if it has one
+/// and else that of whoever is logged in
+pub fn start(name: &str, parent: Option<Pid>) -> Option<Pid> {
+    let result = match parent {
+        Some(_) => process::create_process(name, parent),
+        None => process::exec(name, None, users::current_user().map_or_else(Environment::new, |u| {
+            Environment::login(&u.username, &u.home_directory)
+        }), None),
+    };
+    result.ok()
+}
+
+/// Make `pid` the session commands run in, returning the one before
+pub fn enter(pid: Option<Pid>) -> Option<Pid> {
+    core::mem::replace(&mut *SESSION.lock(), pid)
+}
+
+/// Process of the session commands are running in
+pub fn session() -> Option<Pid> {
+    *SESSION.lock()
+}
+
+/// A copy of the running session's environment
+pub fn environment() -> Environment {
+    session().and_then(process::environment).unwrap_or_default()
+}
+
+/// `path` as an absolute path, taken from the session's working directory
+/// if it is relative
+pub fn path(path: &str) -> String {
+    environment().resolve(path)
+}
+
+/// An environment variable of the running session
+pub fn var(name: &str) -> Option<String> {
+    environment().vars.get(name).cloned()
+}
+
+fn update(f: impl FnOnce(&mut Environment)) {
+    match session() {
+        Some(pid) => {
+            if process::update_environment(pid, f).is_err() {
+                println!("shell: the session's process has ended");
+            }
+        }
+        None => println!("shell: no session to change"),
+    }
+}
+
+fn cd(args: &[&str], _input: &str) {
+    let mut environment = environment();
+    let dir = match args {
+        [] => environment.vars.get("HOME").cloned().unwrap_or_else(|| String::from("/")),
+        [dir] => String::from(*dir),
+        _ => return println!("Usage: cd [directory]"),
+    };
+    match environment.chdir(&dir) {
+        Ok(()) => update(|e| e.cwd = environment.cwd),
+        Err(e) => println!("cd: {}: {:?}", dir, e),
+    }
+}
+
+fn pwd(_args: &[&str], _input: &str) {
+    println!("{}", environment().cwd);
+}
+
+fn export(args: &[&str], input: &str) {
+    if args.is_empty() {
+        return env(args, input);
+    }
+    for arg in args {
+        match super::script::assignment(arg) {
+            Some((name, value)) => update(|e| {
+                e.vars.insert(String::from(name), String::from(value));
+            }),
+            None => println!("export: {}: not NAME=value", arg),
+        }
+    }
+}
+
+fn unset(args: &[&str], _input: &str) {
+    if args.is_empty() {
+        return println!("Usage: unset <name...>");
+    }
+    update(|e| {
+        for name in args {
+            e.vars.remove(*name);
+        }
+    });
+}
+
+fn env(_args: &[&str], _input: &str) {
+    for (name, value) in &environment().vars {
+        println!("{}={}", name, value);
+    }
+}
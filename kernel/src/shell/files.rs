@@ -0,0 +1,320 @@
+//! File commands
+//!
+//! `ls`, `cat`, `cp`, `mv`, `rm`, `mkdir` and `hexdump`, working through
+//! the VFS with the permissions of whoever is logged in. Relative paths
+//! are from the session's working directory. Options come before the
+//! paths, singly or together (`-rf`), and `--` ends them.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::command::Command;
+use super::env::path as absolute;
+use crate::fs::{self, FileType, FsError, FsResult, Metadata};
+use crate::{print, println};
+
+pub const FILE_COMMANDS: [Command; 7] = [
+    Command { name: "ls", description: "List directories (e.g., ls -l /etc); -a shows dot files, -R subdirectories", run: ls },
+    Command { name: "cat", description: "Print files, or its input (e.g., cat /etc/welcome)", run: cat },
+    Command { name: "cp", description: "Copy files (e.g., cp /etc/welcome /tmp); -r copies directories", run: cp },
+    Command { name: "mv", description: "Move or rename files (e.g., mv /tmp/a /tmp/b)", run: mv },
+    Command { name: "rm", description: "Remove files (e.g., rm /tmp/*.txt); -r removes directories, -f ignores missing", run: rm },
+    Command { name: "mkdir", description: "Make directories; -p makes parents too", run: mkdir },
+    Command { name: "hexdump", description: "Show a file, or its input, in hex", run: hexdump },
+];
+
+/// Split `args` into the options out of `allowed` and the rest; the
+/// error is an option not allowed
+fn options<'a>(args: &[&'a str], allowed: &str) -> Result<(String, Vec<&'a str>), char> {
+    let mut flags = String::new();
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    for &arg in args.by_ref() {
+        if arg == "--" {
+            break;
+        }
+        match arg.strip_prefix('-') {
+            Some(letters) if !letters.is_empty() && rest.is_empty() => {
+                for c in letters.chars() {
+                    if !allowed.contains(c) {
+                        return Err(c);
+                    }
+                    flags.push(c);
+                }
+            }
+            _ => rest.push(arg),
+        }
+    }
+    rest.extend(args);
+    Ok((flags, rest))
+}
+
+/// `options`, printing the usage for a wrong option
+fn parse_args<'a>(name: &str, args: &[&'a str], allowed: &str, usage: &str) -> Option<(String, Vec<&'a str>)> {
+    match options(args, allowed) {
+        Ok(parsed) => Some(parsed),
+        Err(c) => {
+            println!("{}: unknown option -{}", name, c);
+            println!("Usage: {}", usage);
+            None
+        }
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+fn file_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+fn is_dir(path: &str) -> bool {
+    fs::metadata(&absolute(path)).map_or(false, |m| m.file_type == FileType::Directory)
+}
+
+/// `drwxr-xr-x` and the like
+fn mode_string(metadata: &Metadata) -> String {
+    let kind = match metadata.file_type {
+        FileType::Directory => 'd',
+        FileType::CharDevice => 'c',
+        _ => '-',
+    };
+    let mode = metadata.permissions.to_mode();
+    let mut s = String::from(kind);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 7;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    if metadata.permissions.sticky {
+        s.pop();
+        s.push(if mode & 1 != 0 { 't' } else { 'T' });
+    }
+    s
+}
+
+fn print_entry(name: &str, metadata: &Metadata, long: bool) {
+    let suffix = if metadata.file_type == FileType::Directory { "/" } else { "" };
+    if long {
+        println!("{} {:>5} {:>5} {:>9} {}{}", mode_string(metadata), metadata.uid, metadata.gid, metadata.size, name, suffix);
+    } else {
+        println!("{}{}", name, suffix);
+    }
+}
+
+fn ls(args: &[&str], _input: &str) {
+    let (flags, mut paths) = match parse_args("ls", args, "laR", "ls [-laR] [path...]") {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if paths.is_empty() {
+        paths.push(".");
+    }
+    let (long, all, recursive) = (flags.contains('l'), flags.contains('a'), flags.contains('R'));
+    let headers = paths.len() > 1 || recursive;
+    for path in paths {
+        match fs::metadata(&absolute(path)) {
+            Ok(metadata) if metadata.file_type != FileType::Directory => print_entry(path, &metadata, long),
+            Ok(_) => list_dir(path, long, all, recursive, headers),
+            Err(e) => println!("ls: {}: {:?}", path, e),
+        }
+    }
+}
+
+fn list_dir(path: &str, long: bool, all: bool, recursive: bool, header: bool) {
+    let mut entries = match fs::read_dir(&absolute(path)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("ls: {}: {:?}", path, e);
+            return;
+        }
+    };
+    entries.retain(|e| all || !e.name.starts_with('.'));
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    if header {
+        println!("{}:", path);
+    }
+    for entry in &entries {
+        print_entry(&entry.name, &entry.metadata, long);
+    }
+    if recursive {
+        for entry in entries.iter().filter(|e| e.metadata.file_type == FileType::Directory) {
+            println!();
+            list_dir(&join(path, &entry.name), long, all, true, true);
+        }
+    }
+}
+
+fn cat(args: &[&str], input: &str) {
+    if args.is_empty() {
+        print!("{}", input);
+        return;
+    }
+    for path in args {
+        match fs::read_file(&absolute(path)) {
+            Ok(data) => print!("{}", String::from_utf8_lossy(&data)),
+            Err(e) => println!("cat: {}: {:?}", path, e),
+        }
+    }
+}
+
+/// Copy `from` to `to`, which must not exist yet if `from` is a
+/// directory; directories go only if `recursive`
+fn copy(from: &str, to: &str, recursive: bool) -> FsResult<()> {
+    if !is_dir(from) {
+        return fs::write_file(&absolute(to), &fs::read_file(&absolute(from))?);
+    }
+    if !recursive {
+        return Err(FsError::IsDirectory);
+    }
+    fs::create_dir(&absolute(to))?;
+    for entry in fs::read_dir(&absolute(from))? {
+        copy(&join(from, &entry.name), &join(to, &entry.name), true)?;
+    }
+    Ok(())
+}
+
+/// Where each source goes: into `target` if it is a directory, else to
+/// `target` itself, which then takes one source only
+fn destinations<'a>(name: &str, sources: &[&'a str], target: &str) -> Option<Vec<(&'a str, String)>> {
+    if is_dir(target) {
+        return Some(sources.iter().map(|&s| (s, join(target, file_name(s)))).collect());
+    }
+    match sources {
+        [source] => Some(alloc::vec![(*source, String::from(target))]),
+        _ => {
+            println!("{}: {}: not a directory", name, target);
+            None
+        }
+    }
+}
+
+/// Whether `to` is `from` or inside it
+fn inside(from: &str, to: &str) -> bool {
+    let (from, to) = (absolute(from), absolute(to));
+    from == "/" || to == from || to.starts_with(&format!("{}/", from))
+}
+
+fn cp(args: &[&str], _input: &str) {
+    let usage = "cp [-r] <source...> <target>";
+    let (flags, paths) = match parse_args("cp", args, "r", usage) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let (sources, target) = match paths.split_last() {
+        Some((target, sources)) if !sources.is_empty() => (sources, *target),
+        _ => return println!("Usage: {}", usage),
+    };
+    for (from, to) in destinations("cp", sources, target).unwrap_or_default() {
+        if inside(from, &to) {
+            println!("cp: cannot copy {} into itself", from);
+            continue;
+        }
+        if let Err(e) = copy(from, &to, flags.contains('r')) {
+            println!("cp: {}: {:?}", from, e);
+        }
+    }
+}
+
+fn mv(args: &[&str], _input: &str) {
+    let usage = "mv <source...> <target>";
+    let (sources, target) = match args.split_last() {
+        Some((target, sources)) if !sources.is_empty() => (sources, *target),
+        _ => return println!("Usage: {}", usage),
+    };
+    for (from, to) in destinations("mv", sources, target).unwrap_or_default() {
+        if inside(from, &to) {
+            println!("mv: cannot move {} into itself", from);
+            continue;
+        }
+        // The VFS has no rename, so the file is copied and the original
+        // removed; a directory there already is replaced only if a file
+        // is moved onto it
+        if let Err(e) = copy(from, &to, true).and_then(|_| fs::remove(&absolute(from))) {
+            println!("mv: {}: {:?}", from, e);
+        }
+    }
+}
+
+fn rm(args: &[&str], _input: &str) {
+    let usage = "rm [-rf] <path...>";
+    let (flags, paths) = match parse_args("rm", args, "rf", usage) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if paths.is_empty() {
+        return println!("Usage: {}", usage);
+    }
+    let (recursive, force) = (flags.contains('r'), flags.contains('f'));
+    for path in paths {
+        let full = absolute(path);
+        let result = match fs::metadata(&full) {
+            Ok(m) if m.file_type == FileType::Directory && !recursive => Err(FsError::IsDirectory),
+            Ok(_) => fs::remove(&full),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {}
+            Err(FsError::NotFound) if force => {}
+            Err(e) => println!("rm: {}: {:?}", path, e),
+        }
+    }
+}
+
+fn mkdir(args: &[&str], _input: &str) {
+    let usage = "mkdir [-p] <path...>";
+    let (flags, paths) = match parse_args("mkdir", args, "p", usage) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if paths.is_empty() {
+        return println!("Usage: {}", usage);
+    }
+    for path in paths {
+        let result = if flags.contains('p') {
+            // Each directory on the way, leaving those already there
+            let mut at = String::new();
+            absolute(path).split('/').filter(|p| !p.is_empty()).try_for_each(|part| {
+                at = join(&at, part);
+                match fs::create_dir(&at) {
+                    Err(FsError::AlreadyExists) if is_dir(&at) => Ok(()),
+                    result => result,
+                }
+            })
+        } else {
+            fs::create_dir(&absolute(path))
+        };
+        if let Err(e) = result {
+            println!("mkdir: {}: {:?}", path, e);
+        }
+    }
+}
+
+fn hexdump(args: &[&str], input: &str) {
+    let data = match args {
+        [] => input.as_bytes().to_vec(),
+        [path] => match fs::read_file(&absolute(path)) {
+            Ok(data) => data,
+            Err(e) => return println!("hexdump: {}: {:?}", path, e),
+        },
+        _ => return println!("Usage: hexdump [path]"),
+    };
+    for (i, row) in data.chunks(16).enumerate() {
+        print!("{:08x} ", i * 16);
+        for j in 0..16 {
+            if j == 8 {
+                print!(" ");
+            }
+            match row.get(j) {
+                Some(byte) => print!(" {:02x}", byte),
+                None => print!("   "),
+            }
+        }
+        let text: String = row.iter().map(|&b| if b == b' ' || b.is_ascii_graphic() { b as char } else { '.' }).collect();
+        println!("  |{}|", text);
+    }
+    println!("{:08x}", data.len());
+}
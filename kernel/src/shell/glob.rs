@@ -0,0 +1,78 @@
+//! Wildcards
+//!
+//! A word with an unquoted `*` or `?` is a pattern for paths: `*` stands
+//! for any run of characters within a name and `?` for any one. Names
+//! starting with `.` only match a pattern starting with `.` too. Relative
+//! patterns match relative paths, from the session's working directory.
+//! A pattern that matches nothing is left as it is.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use crate::fs;
+
+/// Whether `name` fits `pattern`
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it has taken
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Paths `pattern` matches, sorted, or the pattern itself if none do
+pub fn expand(pattern: &str) -> Vec<String> {
+    let environment = super::env::environment();
+    // Relative paths build up from nothing, read from the working directory
+    let mut paths = vec![String::from(if pattern.starts_with('/') { "/" } else { "" })];
+    for part in pattern.split('/').filter(|p| !p.is_empty()) {
+        let wild = part.contains(|c| c == '*' || c == '?');
+        let mut next = Vec::new();
+        for dir in &paths {
+            let join = |name: &str| match dir.as_str() {
+                "" => String::from(name),
+                "/" => format!("/{}", name),
+                dir => format!("{}/{}", dir, name),
+            };
+            if !wild {
+                next.push(join(part));
+                continue;
+            }
+            let mut names: Vec<String> = fs::read_dir(&environment.resolve(dir)).map(|entries| {
+                entries.into_iter().map(|e| e.name).filter(|name| matches(part, name)).collect()
+            }).unwrap_or_default();
+            names.sort();
+            next.extend(names.iter().map(|name| join(name)));
+        }
+        paths = next;
+    }
+    paths.retain(|path| fs::metadata(&environment.resolve(path)).is_ok());
+    if paths.is_empty() {
+        return vec![String::from(pattern)];
+    }
+    paths
+}
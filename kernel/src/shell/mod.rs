@@ -0,0 +1,212 @@
+//! Shell sessions
+//!
+//! A shell runs console commands on the slave end of a PTY: it reads a
+//! line at a time, runs the line as a console command and writes what the
+//! command printed back to the terminal, followed by a colored prompt.
+//! Shells have no thread of their own; `poll` gives each a turn and is
+//! called from the console and desktop loops, so shells keep running
+//! whichever of the two is on screen.
+//!
+//! `execute` runs a command line for the console, shells and `script`s
+//! alike: its variables are expanded from the session's `env`, it is
+//! parsed by `parse`, its wildcards expanded by `glob` and its commands
+//! looked up in the `command` registry, or else run as scripts found in
+//! the directories of `$PATH`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use webbos_shared::types::Pid;
+
+use crate::drivers::pty::{Slave, WinSize};
+use crate::{console, fs, println, process};
+
+mod builtins;
+pub mod command;
+pub mod env;
+mod files;
+mod glob;
+pub mod parse;
+pub mod script;
+
+pub use command::Command;
+use crate::info;
+
+/// Commands that take over the screen or the machine
+const CONSOLE_ONLY: [&str; 3] = ["gui", "shutdown", "reboot"];
+
+struct Shell {
+    slave: Slave,
+    size: WinSize,
+    /// The session's process, holding its environment
+    pid: Option<Pid>,
+}
+
+static SHELLS: Mutex<Vec<Shell>> = Mutex::new(Vec::new());
+
+impl Shell {
+    fn prompt(&self) {
+        let environment = self.pid.and_then(process::environment).unwrap_or_default();
+        let user = environment.vars.get("USER").map_or("root", String::as_str);
+        // The home directory shows as `~`
+        let mut dir = environment.cwd.as_str();
+        let mut home = "";
+        if let Some(rest) = environment.vars.get("HOME").and_then(|h| dir.strip_prefix(h.as_str())) {
+            if rest.is_empty() || rest.starts_with('/') {
+                (home, dir) = ("~", rest);
+            }
+        }
+        self.slave.write(format!("\x1b[1;32m{}@webbos\x1b[0m:\x1b[1;34m{}{}\x1b[0m$ ", user, home, dir).as_bytes());
+    }
+
+    /// Handle waiting input; false once the shell has exited
+    fn poll(&mut self) -> bool {
+        // Ending the session's process, e.g. from the task manager, ends
+        // the shell too
+        if self.slave.hung_up() || self.pid.map_or(false, |pid| process::environment(pid).is_none()) {
+            return false;
+        }
+        if let Some(size) = self.slave.take_resize() {
+            self.size = size;
+        }
+        if self.slave.take_interrupt() {
+            self.prompt();
+        }
+        while let Some(line) = self.slave.read_line() {
+            let line = String::from_utf8_lossy(&line);
+            if !self.run(line.trim()) {
+                return false;
+            }
+            self.prompt();
+        }
+        true
+    }
+
+    /// Run a command line; false if it was `exit`
+    fn run(&mut self, line: &str) -> bool {
+        let name = line.split_whitespace().next().unwrap_or("");
+        match line {
+            "exit" => return false,
+            "clear" => self.slave.write(b"\x1b[2J\x1b[H"),
+            "stty size" => self.slave.write(format!("{} {}\n", self.size.rows, self.size.cols).as_bytes()),
+            _ if CONSOLE_ONLY.contains(&name) => {
+                self.slave.write(format!("\x1b[31m'{}' can only be run from the console\x1b[0m\n", name).as_bytes());
+            }
+            _ => {
+                let console = env::enter(self.pid);
+                let output = console::capture(|| execute(line));
+                env::enter(console);
+                self.slave.write(output.as_bytes());
+            }
+        }
+        true
+    }
+}
+
+/// Register the text commands and start the console's session
+pub fn init() {
+    let commands = builtins::BUILTINS.into_iter().chain(files::FILE_COMMANDS).chain(env::ENV_COMMANDS);
+    for command in commands {
+        command::register(command);
+    }
+    env::enter(env::start("console", None));
+}
+
+/// Run a command line, printing its output or writing it to the file it
+/// is redirected to
+pub fn execute(line: &str) {
+    run_expanded(&script::expand(line, &Default::default()));
+}
+
+/// `execute` a line whose variables are already expanded
+fn run_expanded(line: &str) {
+    let pipeline = match parse::parse(line) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            println!("shell: {}", e);
+            return;
+        }
+    };
+    let last = match pipeline.stages.len() {
+        0 => return,
+        n => n - 1,
+    };
+    let mut input = String::new();
+    for (i, argv) in pipeline.stages.iter().enumerate() {
+        let args: Vec<&str> = argv[1..].iter().map(String::as_str).collect();
+        let run = || match command::find(&argv[0]) {
+            Some(command) => (command.run)(&args, &input),
+            None => match find_script(&argv[0]) {
+                Some(path) => {
+                    if let Err(e) = script::run_file(&path, &args) {
+                        script::report(&path, &e);
+                    }
+                }
+                None => {
+                    println!("Unknown command: {}", argv[0]);
+                    println!("Type 'help' for available commands.");
+                }
+            },
+        };
+        if i == last && pipeline.redirect.is_none() {
+            run();
+        } else {
+            input = console::capture(run);
+        }
+    }
+    if let Some(redirect) = pipeline.redirect {
+        let path = env::path(&redirect.path);
+        let result = if redirect.append {
+            fs::append_file(&path, input.as_bytes())
+        } else {
+            fs::write_file(&path, input.as_bytes())
+        };
+        if let Err(e) = result {
+            println!("shell: {}: {:?}", redirect.path, e);
+        }
+    }
+}
+
+/// A script to run for `name`: the file itself if it is a path, else the
+/// first one of that name in the directories of `$PATH`
+fn find_script(name: &str) -> Option<String> {
+    let is_file = |path: &str| fs::metadata(path).map_or(false, |m| m.file_type == fs::FileType::Regular);
+    if name.contains('/') {
+        return Some(String::from(name)).filter(|_| is_file(&env::path(name)));
+    }
+    let dirs = env::var("PATH").unwrap_or_default();
+    dirs.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| format!("{}/{}", env::path(dir).trim_end_matches('/'), name))
+        .find(|path| is_file(path))
+}
+
+/// Start a shell on `slave`, in a session whose process is a child of
+/// `parent`
+pub fn spawn(slave: Slave, parent: Option<Pid>) {
+    let shell = Shell { size: slave.size(), slave, pid: env::start("sh", parent) };
+    shell.slave.write(b"WebbOS kernel shell. Type 'help' for commands.\n");
+    shell.prompt();
+    info!("shell", "Started on /dev/pts/{}", shell.slave.id());
+    SHELLS.lock().push(shell);
+}
+
+/// Give every shell a turn, dropping those that exited or whose terminal
+/// went away
+pub fn poll() {
+    // Commands run without the lock held
+    let mut shells = core::mem::take(&mut *SHELLS.lock());
+    shells.retain_mut(|shell| {
+        let running = shell.poll();
+        if !running {
+            info!("shell", "Exited on /dev/pts/{}", shell.slave.id());
+            if let Some(pid) = shell.pid {
+                let _ = process::terminate(pid);
+            }
+        }
+        running
+    });
+    SHELLS.lock().extend(shells);
+}
@@ -0,0 +1,221 @@
+//! Command line parsing
+//!
+//! Splits a line into words and the `|`, `>` and `>>` operators. Single
+//! quotes keep everything up to the closing quote as it is; double quotes
+//! do the same but let a backslash escape `"` and `\`; anywhere else a
+//! backslash makes the next character an ordinary one. Quoted text joins
+//! the word it touches, and `''` is an empty word. Words with an unquoted
+//! `*` or `?` are patterns, which `glob` turns into the paths they match.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    /// A word with wildcards
+    Pattern(String),
+    /// `|`
+    Pipe,
+    /// `>`
+    Write,
+    /// `>>`
+    Append,
+}
+
+/// Where the output of a pipeline goes instead of the console
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub path: String,
+    /// `>>` adds to the file; `>` replaces it
+    pub append: bool,
+}
+
+/// Commands joined by `|`, each a name and its arguments; the output of
+/// one is the input of the next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub stages: Vec<Vec<String>>,
+    pub redirect: Option<Redirect>,
+}
+
+/// Split `line` into tokens
+pub fn tokenize(line: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    // A quote can make an empty word, so whether there is one is kept
+    // apart from `word`
+    let mut in_word = false;
+    let mut pattern = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"' | '\\')) => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some(c) => word.push(c),
+                    None => return Err("nothing to escape at end of line"),
+                }
+            }
+            '|' | '>' | ' ' | '\t' => {
+                if core::mem::take(&mut in_word) {
+                    tokens.push(word_token(core::mem::take(&mut word), core::mem::take(&mut pattern)));
+                }
+                match c {
+                    '|' => tokens.push(Token::Pipe),
+                    '>' if chars.peek() == Some(&'>') => {
+                        chars.next();
+                        tokens.push(Token::Append);
+                    }
+                    '>' => tokens.push(Token::Write),
+                    _ => {}
+                }
+            }
+            c => {
+                in_word = true;
+                pattern |= c == '*' || c == '?';
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        tokens.push(word_token(word, pattern));
+    }
+    Ok(tokens)
+}
+
+fn word_token(word: String, pattern: bool) -> Token {
+    if pattern { Token::Pattern(word) } else { Token::Word(word) }
+}
+
+/// Parse `line` into a pipeline, expanding patterns; one with no stages
+/// if the line is blank
+///
+/// A redirection goes at the end and takes the output of the whole
+/// pipeline.
+pub fn parse(line: &str) -> Result<Pipeline, &'static str> {
+    let mut tokens = tokenize(line)?.into_iter();
+    let mut pipeline = Pipeline { stages: Vec::new(), redirect: None };
+    let mut stage = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => stage.push(word),
+            Token::Pattern(pattern) => stage.extend(super::glob::expand(&pattern)),
+            Token::Pipe => {
+                if stage.is_empty() {
+                    return Err("missing command around '|'");
+                }
+                pipeline.stages.push(core::mem::take(&mut stage));
+            }
+            Token::Write | Token::Append => {
+                let path = match tokens.next() {
+                    Some(Token::Word(path) | Token::Pattern(path)) => path,
+                    _ => return Err("missing file name after '>'"),
+                };
+                if tokens.next().is_some() {
+                    return Err("a redirection must come last");
+                }
+                pipeline.redirect = Some(Redirect { path, append: token == Token::Append });
+            }
+        }
+    }
+    if stage.is_empty() {
+        if !pipeline.stages.is_empty() || pipeline.redirect.is_some() {
+            return Err("missing command");
+        }
+    } else {
+        pipeline.stages.push(stage);
+    }
+    Ok(pipeline)
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn word(s: &str) -> Token {
+        Token::Word(String::from(s))
+    }
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| String::from(*s)).collect()
+    }
+
+    #[kernel_test]
+    fn quotes_keep_text_together() -> Result<(), String> {
+        check_eq!(tokenize("echo  'a  b'\t\"c d\"")?, vec![word("echo"), word("a  b"), word("c d")]);
+        // Quoted text joins the word it touches
+        check_eq!(tokenize("pre'fix'\"ed\" x''y")?, vec![word("prefixed"), word("xy")]);
+        check_eq!(tokenize("'' \"\"")?, vec![word(""), word("")]);
+        // Operators and wildcards inside quotes are ordinary characters
+        check_eq!(tokenize("'a|b>c' \"*.txt\"")?, vec![word("a|b>c"), word("*.txt")]);
+        // A backslash does not escape a single quote, so the last one opens
+        check_eq!(tokenize("'it\\''"), Err("unterminated quote"));
+        check_eq!(tokenize("\"open"), Err("unterminated quote"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn backslashes_escape() -> Result<(), String> {
+        check_eq!(tokenize("a\\ b \\| \\>")?, vec![word("a b"), word("|"), word(">")]);
+        check_eq!(tokenize("\\*")?, vec![word("*")]);
+        // In double quotes only `"` and `\` are escaped; in single quotes nothing is
+        check_eq!(tokenize("\"\\\" \\\\ \\n\" '\\n'")?, vec![word("\" \\ \\n"), word("\\n")]);
+        check_eq!(tokenize("trailing\\"), Err("nothing to escape at end of line"));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn operators_split_words() -> Result<(), String> {
+        check_eq!(tokenize("a|b>c>>d")?, vec![
+            word("a"), Token::Pipe, word("b"), Token::Write, word("c"), Token::Append, word("d"),
+        ]);
+        check_eq!(tokenize("ls *.rs a?c")?, vec![
+            word("ls"), Token::Pattern(String::from("*.rs")), Token::Pattern(String::from("a?c")),
+        ]);
+        check!(tokenize("   ")?.is_empty());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn pipelines_and_redirects() -> Result<(), String> {
+        let pipeline = parse("cat notes | grep 'to do' | wc > count")?;
+        check_eq!(pipeline.stages, vec![words(&["cat", "notes"]), words(&["grep", "to do"]), words(&["wc"])]);
+        check_eq!(pipeline.redirect, Some(Redirect { path: String::from("count"), append: false }));
+        check_eq!(parse("echo hi >> log")?.redirect, Some(Redirect { path: String::from("log"), append: true }));
+        check!(parse("")?.stages.is_empty());
+        check_eq!(parse("| wc"), Err("missing command around '|'"));
+        check_eq!(parse("a || b"), Err("missing command around '|'"));
+        check_eq!(parse("ls |"), Err("missing command"));
+        check_eq!(parse("> out"), Err("missing command"));
+        check_eq!(parse("ls >"), Err("missing file name after '>'"));
+        check_eq!(parse("ls > a b"), Err("a redirection must come last"));
+        Ok(())
+    }
+}
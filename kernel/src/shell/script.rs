@@ -0,0 +1,366 @@
+//! Shell scripts
+//!
+//! A script is console command lines run one after another, with a little
+//! more around them:
+//!
+//! - `#` starts a comment, and `;` separates commands on one line
+//! - `NAME=value` sets a variable, which `$NAME` or `${NAME}` stands for
+//!   later, except in single quotes; `$0` is the script and `$1` on its
+//!   arguments, and names the script has not set are environment
+//!   variables
+//! - `if TEST; then ... [else ...] fi` runs commands on a condition
+//! - `for NAME in WORDS; do ... done` runs commands once per word
+//!
+//! Commands do not report success or failure, so conditions are tests in
+//! brackets: `[ a = b ]`, `[ a != b ]`, `[ -n text ]`, `[ -z text ]`, and
+//! `[ -e path ]`, `[ -f path ]` or `[ -d path ]` for files; `!` in front
+//! turns a test around.
+//!
+//! `/etc/rc.local` runs at the end of boot.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::parse::{self, Token};
+use crate::fs::{self, FileType};
+use crate::println;
+use crate::info;
+
+/// Script run at the end of boot
+pub const STARTUP: &str = "/etc/rc.local";
+
+/// Most scripts running inside one another, so one that runs itself
+/// stops
+const MAX_DEPTH: usize = 8;
+
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+enum Statement {
+    /// A command line, with variables still to expand
+    Run(String),
+    Assign(String, String),
+    If { test: String, then: Block, otherwise: Block },
+    For { name: String, words: String, body: Block },
+}
+
+/// Statements with the lines they start on
+type Block = Vec<(usize, Statement)>;
+
+/// Why a script could not run, and the line it went wrong on
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+fn error(line: usize, message: &str) -> ScriptError {
+    ScriptError { line, message: String::from(message) }
+}
+
+/// Turns the script's pieces into statements
+struct Parser {
+    /// Commands and keywords with the lines they are on
+    pieces: Vec<(usize, String)>,
+    next: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Self {
+        let mut pieces = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            for piece in split_line(line) {
+                // `then`, `do` and `else` may have a command after them
+                let keyword = piece.split_whitespace().next().unwrap_or("");
+                if matches!(keyword, "then" | "do" | "else") && piece.trim() != keyword {
+                    let rest = piece.trim()[keyword.len()..].trim();
+                    pieces.push((i + 1, String::from(keyword)));
+                    pieces.push((i + 1, String::from(rest)));
+                } else {
+                    pieces.push((i + 1, piece));
+                }
+            }
+        }
+        Self { pieces, next: 0 }
+    }
+
+    /// Statements up to one of `ends`, which is returned, or to the end
+    /// of the script if `ends` is empty
+    fn block(&mut self, ends: &[&str]) -> Result<(Block, String), ScriptError> {
+        let mut statements = Vec::new();
+        while let Some((line, piece)) = self.pieces.get(self.next).cloned() {
+            self.next += 1;
+            let keyword = piece.split_whitespace().next().unwrap_or("");
+            let rest = piece[keyword.len()..].trim();
+            if ends.contains(&keyword) && rest.is_empty() {
+                return Ok((statements, String::from(keyword)));
+            }
+            let statement = match keyword {
+                "if" => {
+                    self.expect("then", line)?;
+                    let (then, end) = self.block(&["else", "fi"])?;
+                    let otherwise = if end == "else" { self.block(&["fi"])?.0 } else { Vec::new() };
+                    Statement::If { test: String::from(rest), then, otherwise }
+                }
+                "for" => {
+                    let mut parts = rest.splitn(3, char::is_whitespace);
+                    let (name, words) = match (parts.next(), parts.next(), parts.next()) {
+                        (Some(name), Some("in"), words) if is_name(name) => (name, words.unwrap_or("").trim()),
+                        _ => return Err(error(line, "expected 'for NAME in WORDS'")),
+                    };
+                    self.expect("do", line)?;
+                    let body = self.block(&["done"])?.0;
+                    Statement::For { name: String::from(name), words: String::from(words), body }
+                }
+                "then" | "do" | "else" | "fi" | "done" => {
+                    return Err(ScriptError { line, message: format!("unexpected '{}'", keyword) });
+                }
+                _ => match assignment(&piece) {
+                    Some((name, value)) => Statement::Assign(String::from(name), String::from(value)),
+                    None => Statement::Run(piece),
+                },
+            };
+            statements.push((line, statement));
+        }
+        match ends.first() {
+            Some(end) => Err(ScriptError { line: self.last_line(), message: format!("missing '{}'", end) }),
+            None => Ok((statements, String::new())),
+        }
+    }
+
+    fn expect(&mut self, keyword: &str, line: usize) -> Result<(), ScriptError> {
+        match self.pieces.get(self.next) {
+            Some((_, piece)) if piece == keyword => {
+                self.next += 1;
+                Ok(())
+            }
+            _ => Err(ScriptError { line, message: format!("expected '{}'", keyword) }),
+        }
+    }
+
+    fn last_line(&self) -> usize {
+        self.pieces.last().map_or(0, |(line, _)| *line)
+    }
+}
+
+/// Split a line at `;`, dropping any comment and blank pieces; quotes and
+/// backslashes keep both as they are
+fn split_line(line: &str) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                piece.push(c);
+                piece.extend(chars.next());
+                continue;
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') if piece.is_empty() || piece.ends_with(char::is_whitespace) => break,
+            (None, ';') => {
+                pieces.push(core::mem::take(&mut piece));
+                continue;
+            }
+            _ => {}
+        }
+        piece.push(c);
+    }
+    pieces.push(piece);
+    pieces.into_iter().map(|p| String::from(p.trim())).filter(|p| !p.is_empty()).collect()
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `NAME=value`, split in two
+pub(super) fn assignment(piece: &str) -> Option<(&str, &str)> {
+    let (name, value) = piece.split_once('=')?;
+    is_name(name).then_some((name, value))
+}
+
+/// Replace `$NAME`, `${NAME}` and `$1` with the values of the variables,
+/// or of the environment's, or nothing for those not set; single quotes
+/// and a backslash keep a `$`
+pub(super) fn expand(text: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    let (mut single, mut double) = (false, false);
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            '\\' if !single => {
+                out.push(c);
+                out.extend(chars.next());
+                continue;
+            }
+            '$' if !single => {
+                let mut name = String::new();
+                if chars.peek() == Some(&'{') {
+                    chars.next();
+                    while let Some(c) = chars.next() {
+                        if c == '}' {
+                            break;
+                        }
+                        name.push(c);
+                    }
+                } else if chars.peek().map_or(false, char::is_ascii_digit) {
+                    name.extend(chars.next());
+                } else {
+                    while let Some(&c) = chars.peek() {
+                        if !(c.is_ascii_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                }
+                if name.is_empty() {
+                    out.push('$');
+                } else if let Some(value) = vars.get(&name).cloned().or_else(|| super::env::var(&name)) {
+                    out.push_str(&value);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Words of `text` after expanding variables and wildcards and taking
+/// off quotes
+fn words(text: &str, vars: &BTreeMap<String, String>) -> Result<Vec<String>, &'static str> {
+    parse::tokenize(&expand(text, vars))?
+        .into_iter()
+        .map(|token| match token {
+            Token::Word(word) => Ok(vec![word]),
+            Token::Pattern(pattern) => Ok(super::glob::expand(&pattern)),
+            _ => Err("'|' and '>' only go in commands"),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|words| words.concat())
+}
+
+/// Evaluate a bracketed test
+fn test(text: &str, vars: &BTreeMap<String, String>) -> Result<bool, &'static str> {
+    let words = words(text, vars)?;
+    let mut words: Vec<&str> = words.iter().map(String::as_str).collect();
+    if words.first() != Some(&"[") || words.last() != Some(&"]") {
+        return Err("a condition goes in '[ ]'");
+    }
+    words.remove(0);
+    words.pop();
+    let negate = words.first() == Some(&"!");
+    if negate {
+        words.remove(0);
+    }
+    let file_type = |path: &str| fs::metadata(&super::env::path(path)).ok().map(|m| m.file_type);
+    let result = match words.as_slice() {
+        [a, "=", b] => a == b,
+        [a, "!=", b] => a != b,
+        ["-n", text] => !text.is_empty(),
+        ["-z", text] => text.is_empty(),
+        ["-e", path] => file_type(path).is_some(),
+        ["-f", path] => file_type(path) == Some(FileType::Regular),
+        ["-d", path] => file_type(path) == Some(FileType::Directory),
+        [text] => !text.is_empty(),
+        [] => false,
+        _ => return Err("unknown test"),
+    };
+    Ok(result != negate)
+}
+
+fn run_block(statements: &Block, vars: &mut BTreeMap<String, String>) -> Result<(), ScriptError> {
+    for (line, statement) in statements {
+        let at = |message| error(*line, message);
+        match statement {
+            Statement::Run(command) => super::run_expanded(&expand(command, vars)),
+            Statement::Assign(name, value) => {
+                let value = words(value, vars).map_err(at)?.join(" ");
+                vars.insert(name.clone(), value);
+            }
+            Statement::If { test: condition, then, otherwise } => {
+                if test(condition, vars).map_err(at)? {
+                    run_block(then, vars)?;
+                } else {
+                    run_block(otherwise, vars)?;
+                }
+            }
+            Statement::For { name, words: list, body } => {
+                for word in words(list, vars).map_err(at)? {
+                    vars.insert(name.clone(), word);
+                    run_block(body, vars)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run a script's text; `args` are `$0` on
+pub fn run(source: &str, args: &[&str]) -> Result<(), ScriptError> {
+    let statements = Parser::new(source).block(&[])?.0;
+    if DEPTH.fetch_add(1, Ordering::SeqCst) >= MAX_DEPTH {
+        DEPTH.fetch_sub(1, Ordering::SeqCst);
+        return Err(error(0, "scripts nested too deeply"));
+    }
+    let mut vars = BTreeMap::new();
+    for (i, arg) in args.iter().enumerate() {
+        vars.insert(format!("{}", i), String::from(*arg));
+    }
+    let result = run_block(&statements, &mut vars);
+    DEPTH.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+/// Run the script at `path` with `args` as `$1` on
+pub fn run_file(path: &str, args: &[&str]) -> Result<(), ScriptError> {
+    let source = fs::read_file(&super::env::path(path)).map_err(|e| ScriptError { line: 0, message: format!("{:?}", e) })?;
+    let mut all = Vec::with_capacity(args.len() + 1);
+    all.push(path);
+    all.extend_from_slice(args);
+    run(&String::from_utf8_lossy(&source), &all)
+}
+
+/// `sh <path> [args]`
+pub fn sh_command(args: &[&str], _input: &str) {
+    match args {
+        [path, rest @ ..] => {
+            if let Err(e) = run_file(path, rest) {
+                report(path, &e);
+            }
+        }
+        [] => println!("Usage: sh <script> [arguments]"),
+    }
+}
+
+pub(super) fn report(path: &str, e: &ScriptError) {
+    if e.line == 0 {
+        println!("sh: {}: {}", path, e.message);
+    } else {
+        println!("sh: {}:{}: {}", path, e.line, e.message);
+    }
+}
+
+/// Run `/etc/rc.local`, if there is one
+pub fn run_startup() {
+    if fs::metadata(STARTUP).is_err() {
+        return;
+    }
+    info!("rc", "Running {}", STARTUP);
+    if let Err(e) = run_file(STARTUP, &[]) {
+        report(STARTUP, &e);
+    }
+}
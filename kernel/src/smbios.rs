@@ -0,0 +1,387 @@
+//! SMBIOS: what the firmware says the machine is
+//!
+//! The bootloader passes the SMBIOS entry point from the UEFI
+//! configuration table, the 3.0 one where there is one. `init` copies the
+//! structure table and keeps what `hwinfo` and `about:system` show: the
+//! firmware, the system and its board, each processor socket and each
+//! populated memory device, which is what a hardware-specific bug report
+//! needs to say.
+//!
+//! Each structure is a header (type, length, handle), `length` bytes of
+//! fields, then its strings, each ending in a NUL and the set in another.
+//! Fields name strings by number, from 1; 0 is none.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use webbos_shared::types::PhysAddr;
+
+use crate::mm::read_physical;
+use crate::{info, println, warn};
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BOARD: u8 = 2;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+/// Largest structure table read
+const MAX_TABLE: usize = 64 * 1024;
+
+/// Processor status bit: the socket has a processor in it
+const SOCKET_POPULATED: u8 = 1 << 6;
+
+/// A processor socket
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Processor {
+    /// What the board calls the socket, such as "CPU 0"
+    pub socket: Option<String>,
+    pub manufacturer: Option<String>,
+    /// The model name
+    pub version: Option<String>,
+    pub populated: bool,
+    /// 0 where unknown
+    pub cores: u16,
+    pub threads: u16,
+    pub max_mhz: u16,
+    pub current_mhz: u16,
+}
+
+/// A populated memory device, usually a module in a slot
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryDevice {
+    /// What the board calls the slot, such as "DIMM 0"
+    pub slot: Option<String>,
+    pub bank: Option<String>,
+    pub size_mb: u64,
+    /// The memory type, such as "DDR4"
+    pub kind: &'static str,
+    /// In MT/s, 0 where unknown; the configured speed where there is one
+    pub speed: u16,
+    pub manufacturer: Option<String>,
+    pub part_number: Option<String>,
+    pub serial: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Smbios {
+    pub version: (u8, u8),
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_date: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub uuid: Option<[u8; 16]>,
+    pub board: Option<String>,
+    pub processors: Vec<Processor>,
+    pub memory: Vec<MemoryDevice>,
+}
+
+impl Smbios {
+    /// The memory devices' sizes added up
+    pub fn memory_mb(&self) -> u64 {
+        self.memory.iter().map(|device| device.size_mb).sum()
+    }
+}
+
+static SMBIOS: Mutex<Option<Smbios>> = Mutex::new(None);
+
+/// Read the tables the entry point at `entry` leads to
+pub fn init(entry: Option<PhysAddr>) {
+    let entry = match entry {
+        Some(entry) => entry.as_u64(),
+        None => {
+            warn!("smbios", "No SMBIOS entry point from the bootloader");
+            return;
+        }
+    };
+    match read(entry) {
+        Some(smbios) => {
+            info!("smbios", "SMBIOS {}.{}: {} {}", smbios.version.0, smbios.version.1,
+                smbios.manufacturer.as_deref().unwrap_or("?"), smbios.product.as_deref().unwrap_or("?"));
+            *SMBIOS.lock() = Some(smbios);
+        }
+        None => warn!("smbios", "No valid SMBIOS entry point at {:#x}", entry),
+    }
+}
+
+/// The entry point, then the table it points to
+fn read(entry: u64) -> Option<Smbios> {
+    let anchor = read_physical(entry, 5)?;
+    let (version, table_addr, table_len) = if &anchor[..] == b"_SM3_" {
+        let header = read_physical(entry, 24)?;
+        let len = header[6] as usize;
+        if len < 24 || !checksum_ok(&read_physical(entry, len)?) {
+            return None;
+        }
+        let max = u32::from_le_bytes(header[12..16].try_into().ok()?) as usize;
+        let addr = u64::from_le_bytes(header[16..24].try_into().ok()?);
+        ((header[7], header[8]), addr, max)
+    } else if &anchor[..4] == b"_SM_" {
+        let header = read_physical(entry, 31)?;
+        let len = header[5] as usize;
+        if len < 31 || !checksum_ok(&read_physical(entry, len)?) {
+            return None;
+        }
+        let table_len = u16::from_le_bytes([header[22], header[23]]) as usize;
+        let addr = u32::from_le_bytes(header[24..28].try_into().ok()?) as u64;
+        ((header[6], header[7]), addr, table_len)
+    } else {
+        return None;
+    };
+    let table = read_physical(table_addr, table_len.min(MAX_TABLE))?;
+    Some(parse(&table, version))
+}
+
+/// Memory device type names, by the type field
+fn memory_kind(kind: u8) -> &'static str {
+    match kind {
+        0x03 => "DRAM",
+        0x07 => "RAM",
+        0x0F => "SDRAM",
+        0x12 => "DDR",
+        0x13 => "DDR2",
+        0x18 => "DDR3",
+        0x1A => "DDR4",
+        0x1B => "LPDDR",
+        0x1C => "LPDDR2",
+        0x1D => "LPDDR3",
+        0x1E => "LPDDR4",
+        0x22 => "DDR5",
+        0x23 => "LPDDR5",
+        _ => "Unknown",
+    }
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Walk the structure table
+fn parse(table: &[u8], version: (u8, u8)) -> Smbios {
+    let mut smbios = Smbios { version, ..Smbios::default() };
+    let mut at = 0;
+    while at + 4 <= table.len() {
+        let kind = table[at];
+        let len = table[at + 1] as usize;
+        if len < 4 || at + len > table.len() {
+            break;
+        }
+        let fields = &table[at..at + len];
+        // The strings run to a double NUL
+        let strings_start = at + len;
+        let mut end = strings_start;
+        while end + 1 < table.len() && (table[end] != 0 || table[end + 1] != 0) {
+            end += 1;
+        }
+        let strings: Vec<&[u8]> = table[strings_start..end].split(|&b| b == 0).collect();
+        let word = |offset: usize| fields.get(offset..offset + 2).map_or(0, |w| u16::from_le_bytes([w[0], w[1]]));
+        let string = |offset: usize| {
+            let index = *fields.get(offset)? as usize;
+            let text = strings.get(index.checked_sub(1)?)?;
+            let text = String::from_utf8_lossy(text).trim().into();
+            Some(text).filter(|text: &String| !text.is_empty())
+        };
+
+        match kind {
+            TYPE_BIOS => {
+                smbios.bios_vendor = string(4);
+                smbios.bios_version = string(5);
+                smbios.bios_date = string(8);
+            }
+            TYPE_SYSTEM => {
+                smbios.manufacturer = string(4);
+                smbios.product = string(5);
+                smbios.serial = string(7);
+                smbios.uuid = fields.get(8..24).and_then(|uuid| uuid.try_into().ok());
+            }
+            TYPE_BOARD => smbios.board = string(5),
+            TYPE_PROCESSOR => {
+                // Counts of 0xFF mean the 16-bit fields of SMBIOS 3.0 have
+                // them
+                let count = |short: usize, long: usize| match fields.get(short) {
+                    Some(0xFF) => word(long),
+                    Some(&count) => count as u16,
+                    None => 0,
+                };
+                smbios.processors.push(Processor {
+                    socket: string(0x04),
+                    manufacturer: string(0x07),
+                    version: string(0x10),
+                    populated: fields.get(0x18).map_or(false, |status| status & SOCKET_POPULATED != 0),
+                    cores: count(0x23, 0x2A),
+                    threads: count(0x25, 0x2E),
+                    max_mhz: word(0x14),
+                    current_mhz: word(0x16),
+                });
+            }
+            TYPE_MEMORY_DEVICE => {
+                let size = word(0x0C);
+                let mb = match size {
+                    0 | 0xFFFF => 0,
+                    // Larger sizes are in the extended field
+                    0x7FFF => fields.get(0x1C..0x20).map_or(0, |s| u32::from_le_bytes([s[0], s[1], s[2], s[3]]) as u64),
+                    // Bit 15 set means KB
+                    size if size & 0x8000 != 0 => (size & 0x7FFF) as u64 / 1024,
+                    size => size as u64,
+                };
+                if mb > 0 {
+                    let speed = match word(0x20) {
+                        0 => word(0x15),
+                        configured => configured,
+                    };
+                    smbios.memory.push(MemoryDevice {
+                        slot: string(0x10),
+                        bank: string(0x11),
+                        size_mb: mb,
+                        kind: fields.get(0x12).map_or("Unknown", |&kind| memory_kind(kind)),
+                        speed,
+                        manufacturer: string(0x17),
+                        part_number: string(0x1A),
+                        serial: string(0x18),
+                    });
+                }
+            }
+            TYPE_END => break,
+            _ => {}
+        }
+        at = end + 2;
+    }
+    smbios
+}
+
+/// "Manufacturer Model" of the system, for summaries
+pub fn machine() -> Option<String> {
+    let smbios = SMBIOS.lock();
+    let smbios = smbios.as_ref()?;
+    match (&smbios.manufacturer, &smbios.product) {
+        (Some(vendor), Some(product)) => Some(format!("{} {}", vendor, product)),
+        (vendor, product) => vendor.clone().or_else(|| product.clone()),
+    }
+}
+
+/// The hardware inventory `hwinfo` prints
+pub fn print_info() {
+    let smbios = SMBIOS.lock();
+    let smbios = match smbios.as_ref() {
+        Some(smbios) => smbios,
+        None => {
+            println!("No SMBIOS tables");
+            return;
+        }
+    };
+    let show = |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("-"));
+    println!("SMBIOS {}.{}", smbios.version.0, smbios.version.1);
+    println!("  Firmware: {} {} ({})", show(&smbios.bios_vendor), show(&smbios.bios_version), show(&smbios.bios_date));
+    println!("  System:   {} {}", show(&smbios.manufacturer), show(&smbios.product));
+    println!("  Board:    {}", show(&smbios.board));
+    println!("  Serial:   {}", show(&smbios.serial));
+    if let Some(u) = smbios.uuid {
+        println!("  UUID:     {:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            u[3], u[2], u[1], u[0], u[5], u[4], u[7], u[6], u[8], u[9], u[10], u[11], u[12], u[13], u[14], u[15]);
+    }
+    println!("Processors:");
+    for cpu in &smbios.processors {
+        let socket = show(&cpu.socket);
+        if !cpu.populated {
+            println!("  {}: empty", socket);
+            continue;
+        }
+        let mut details = String::new();
+        if cpu.cores > 0 {
+            details.push_str(&format!(", {} cores", cpu.cores));
+        }
+        if cpu.threads > 0 {
+            details.push_str(&format!(", {} threads", cpu.threads));
+        }
+        if cpu.current_mhz > 0 {
+            details.push_str(&format!(", {} MHz", cpu.current_mhz));
+        }
+        if cpu.max_mhz > 0 {
+            details.push_str(&format!(" (max {} MHz)", cpu.max_mhz));
+        }
+        println!("  {}: {} {}{}", socket, show(&cpu.manufacturer), show(&cpu.version), details);
+    }
+    println!("Memory: {} MB in {} devices", smbios.memory_mb(), smbios.memory.len());
+    for device in &smbios.memory {
+        let speed = if device.speed > 0 { format!(" {} MT/s", device.speed) } else { String::new() };
+        println!("  {}: {} MB {}{}, {} {}", show(&device.slot), device.size_mb, device.kind, speed,
+            show(&device.manufacturer), show(&device.part_number));
+        if device.bank.is_some() || device.serial.is_some() {
+            println!("      bank {}, serial {}", show(&device.bank), show(&device.serial));
+        }
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn reads_the_structures() -> Result<(), String> {
+        let mut table = Vec::new();
+        // BIOS: vendor 1, version 2, date 3
+        table.extend_from_slice(&[TYPE_BIOS, 18, 0, 0, 1, 2, 0, 0, 3]);
+        table.resize(18, 0);
+        table.extend_from_slice(b"SeaBIOS\0 1.16 \0 04/01/2014\0\0");
+        // System: manufacturer 1, product 2, no serial
+        let system = table.len();
+        table.extend_from_slice(&[TYPE_SYSTEM, 27, 1, 0, 1, 2, 0, 0]);
+        table.extend((0..16).map(|i| i as u8));
+        table.resize(system + 27, 0);
+        table.extend_from_slice(b"QEMU\0Standard PC\0\0");
+        // A memory device of 512MB in slot 1
+        let memory = table.len();
+        table.extend_from_slice(&[TYPE_MEMORY_DEVICE, 0x28, 2, 0]);
+        table.resize(memory + 0x28, 0);
+        table[memory + 0x0C..memory + 0x0E].copy_from_slice(&512u16.to_le_bytes());
+        table[memory + 0x10] = 1;
+        table[memory + 0x12] = 0x1A;
+        table[memory + 0x15..memory + 0x17].copy_from_slice(&2666u16.to_le_bytes());
+        table[memory + 0x1A] = 2;
+        table[memory + 0x20..memory + 0x22].copy_from_slice(&2400u16.to_le_bytes());
+        table.extend_from_slice(b"DIMM 0\0M378A1K43CB2\0\0");
+        // A populated socket with 4 cores and 8 threads, and an empty one
+        for (populated, socket) in [(true, &b"CPU 0\0Xeon\0\0"[..]), (false, &b"CPU 1\0\0"[..])] {
+            let processor = table.len();
+            table.extend_from_slice(&[TYPE_PROCESSOR, 0x30, 4, 0, 1]);
+            table.resize(processor + 0x30, 0);
+            if populated {
+                table[processor + 0x10] = 2;
+                table[processor + 0x14..processor + 0x16].copy_from_slice(&3000u16.to_le_bytes());
+                table[processor + 0x18] = SOCKET_POPULATED | 1;
+                table[processor + 0x23] = 4;
+                table[processor + 0x25] = 8;
+            }
+            table.extend_from_slice(socket);
+        }
+        table.extend_from_slice(&[TYPE_END, 4, 3, 0, 0, 0]);
+
+        let smbios = parse(&table, (3, 0));
+        check_eq!(smbios.bios_vendor.as_deref(), Some("SeaBIOS"));
+        check_eq!(smbios.bios_version.as_deref(), Some("1.16"));
+        check_eq!(smbios.bios_date.as_deref(), Some("04/01/2014"));
+        check_eq!(smbios.manufacturer.as_deref(), Some("QEMU"));
+        check_eq!(smbios.product.as_deref(), Some("Standard PC"));
+        check!(smbios.serial.is_none());
+        check_eq!(smbios.uuid.map(|u| u[15]), Some(15));
+        check_eq!(smbios.memory.len(), 1);
+        check_eq!(smbios.memory[0].size_mb, 512);
+        check_eq!(smbios.memory[0].slot.as_deref(), Some("DIMM 0"));
+        check_eq!(smbios.memory[0].kind, "DDR4");
+        check_eq!(smbios.memory[0].speed, 2400);
+        check_eq!(smbios.memory[0].part_number.as_deref(), Some("M378A1K43CB2"));
+        check_eq!(smbios.processors.len(), 2);
+        check_eq!(smbios.processors[0].socket.as_deref(), Some("CPU 0"));
+        check_eq!(smbios.processors[0].version.as_deref(), Some("Xeon"));
+        check!(smbios.processors[0].populated);
+        check_eq!((smbios.processors[0].cores, smbios.processors[0].threads), (4, 8));
+        check_eq!(smbios.processors[0].max_mhz, 3000);
+        check!(!smbios.processors[1].populated);
+        Ok(())
+    }
+}
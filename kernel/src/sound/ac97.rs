@@ -0,0 +1,154 @@
+//! AC'97 audio
+//!
+//! The mixer registers are ports in BAR0 and the bus master in BAR1. The
+//! PCM out channel plays the sound ring as `BDL_ENTRIES` buffers; each
+//! time the position is read the last valid index is moved on to just
+//! behind the current one, so the channel never reaches the end and stops.
+
+use alloc::boxed::Box;
+use webbos_shared::types::PhysAddr;
+
+use super::{AudioDevice, CHANNELS, SAMPLE_RATE};
+use crate::drivers::input::{inb, inw, outb, outl, outw};
+use crate::drivers::pci::{self, Bar, PciDevice};
+use crate::drivers::timer;
+use crate::mm;
+use crate::{info, warn};
+
+// Mixer registers
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_VOLUME: u16 = 0x18;
+const NAM_EXTENDED_ID: u16 = 0x28;
+const NAM_EXTENDED_CONTROL: u16 = 0x2A;
+const NAM_FRONT_RATE: u16 = 0x2C;
+
+// Bus master registers
+const NABM_PCM_OUT: u16 = 0x10;
+const NABM_GLOBAL_CONTROL: u16 = 0x2C;
+
+// Channel registers, from the channel's base
+const BDBAR: u16 = 0x00;
+const CIV: u16 = 0x04;
+const LVI: u16 = 0x05;
+const SR: u16 = 0x06;
+const PICB: u16 = 0x08;
+const CR: u16 = 0x0B;
+
+/// Channel control: run, reset
+const CR_RUN: u8 = 0x01;
+const CR_RESET: u8 = 0x02;
+/// Channel status: halted
+const SR_HALTED: u16 = 0x01;
+
+/// The ring: 32 buffers of 1KB, about 170ms
+const BDL_ENTRIES: usize = 32;
+const BUFFER_SAMPLES: usize = 512;
+const RING_SAMPLES: usize = BDL_ENTRIES * BUFFER_SAMPLES;
+
+pub struct Ac97 {
+    /// Base port of the PCM out channel
+    channel: u16,
+    ring: *mut i16,
+}
+
+// The device is only reached through the mixer's lock
+unsafe impl Send for Ac97 {}
+
+static DRIVER: pci::PciDriver = pci::PciDriver {
+    name: "ac97",
+    matches: &[pci::DeviceMatch::class(pci::class::MULTIMEDIA, 0x01)],
+    probe,
+};
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+fn probe(device: &PciDevice) -> bool {
+    let (nam, nabm) = match (device.bar(0), device.bar(1)) {
+        (Some(Bar::Io { port: nam, .. }), Some(Bar::Io { port: nabm, .. })) => (nam, nabm),
+        _ => {
+            warn!("ac97", "Mixer and bus master are not both in port space");
+            return false;
+        }
+    };
+    match Ac97::new(nam, nabm) {
+        Some(ac97) => super::register_device(Box::new(ac97)),
+        None => false,
+    }
+}
+
+impl Ac97 {
+    fn new(nam: u16, nabm: u16) -> Option<Self> {
+        let bdl = mm::alloc_dma_frames(1)?.as_u64();
+        let ring = mm::alloc_dma_frames(RING_SAMPLES * 2 / 0x1000)?.as_u64();
+        let channel = nabm + NABM_PCM_OUT;
+        unsafe {
+            // Out of cold reset, and the codec's registers to defaults
+            outl(nabm + NABM_GLOBAL_CONTROL, 0x02);
+            timer::sleep_ms(20);
+            outw(nam + NAM_RESET, 0);
+
+            // Master at full, PCM at 0dB, neither muted
+            outw(nam + NAM_MASTER_VOLUME, 0x0000);
+            outw(nam + NAM_PCM_VOLUME, 0x0808);
+            // Codecs with variable rate audio may not start at 48kHz
+            if inw(nam + NAM_EXTENDED_ID) & 0x01 != 0 {
+                outw(nam + NAM_EXTENDED_CONTROL, inw(nam + NAM_EXTENDED_CONTROL) | 0x01);
+                outw(nam + NAM_FRONT_RATE, SAMPLE_RATE as u16);
+            }
+
+            outb(channel + CR, CR_RESET);
+            let deadline = timer::now_ns() + 10_000_000;
+            while inb(channel + CR) & CR_RESET != 0 {
+                if timer::now_ns() > deadline {
+                    return None;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        let entries = mm::phys_to_virt(PhysAddr::new(bdl)).as_u64() as *mut u32;
+        for i in 0..BDL_ENTRIES {
+            let address = ring + (i * BUFFER_SAMPLES * 2) as u64;
+            unsafe {
+                core::ptr::write_volatile(entries.add(i * 2), address as u32);
+                // Samples in the buffer, no flags
+                core::ptr::write_volatile(entries.add(i * 2 + 1), BUFFER_SAMPLES as u32);
+            }
+        }
+        unsafe {
+            outl(channel + BDBAR, bdl as u32);
+            outb(channel + LVI, (BDL_ENTRIES - 1) as u8);
+            outb(channel + CR, CR_RUN);
+        }
+        info!("ac97", "Mixer at {:#x}, bus master at {:#x}", nam, nabm);
+        Some(Self { channel, ring: mm::phys_to_virt(PhysAddr::new(ring)).as_u64() as *mut i16 })
+    }
+}
+
+impl AudioDevice for Ac97 {
+    fn name(&self) -> &'static str {
+        "AC'97"
+    }
+
+    fn ring(&mut self) -> &mut [i16] {
+        unsafe { core::slice::from_raw_parts_mut(self.ring, RING_SAMPLES) }
+    }
+
+    fn position(&mut self) -> usize {
+        unsafe {
+            let current = inb(self.channel + CIV) as usize % BDL_ENTRIES;
+            let left = (inw(self.channel + PICB) as usize).min(BUFFER_SAMPLES);
+            outb(self.channel + LVI, ((current + BDL_ENTRIES - 1) % BDL_ENTRIES) as u8);
+            // Should it have stopped anyway, clear the status and go again
+            if inw(self.channel + SR) & SR_HALTED != 0 {
+                outw(self.channel + SR, 0x1C);
+                outb(self.channel + CR, CR_RUN);
+            }
+            let sample = current * BUFFER_SAMPLES + (BUFFER_SAMPLES - left);
+            sample % RING_SAMPLES / CHANNELS * CHANNELS
+        }
+    }
+}
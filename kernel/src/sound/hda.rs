@@ -0,0 +1,477 @@
+//! Intel High Definition Audio
+//!
+//! Commands go to the codecs through the CORB ring and their responses
+//! come back in the RIRB. The first codec with an audio function group
+//! is searched for a path from a DAC to an output pin, through mixers or
+//! selectors if need be, and every widget on it is powered up and
+//! unmuted. The first output stream then plays the sound ring, split into
+//! `BDL_ENTRIES` buffers.
+//!
+//! The stream interrupts as it finishes each buffer, by MSI where the
+//! controller has it and on its legacy line otherwise, so a halted kernel
+//! wakes up to refill the ring.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use webbos_shared::types::PhysAddr;
+
+use super::{AudioDevice, CHANNELS};
+use crate::arch::interrupts::{self, InterruptStackFrame};
+use crate::drivers::pci::{self, PciDevice};
+use crate::drivers::timer;
+use crate::mm;
+use crate::{info, warn};
+
+// Controller registers
+const GCAP: u64 = 0x00;
+const GCTL: u64 = 0x08;
+const STATESTS: u64 = 0x0E;
+const INTCTL: u64 = 0x20;
+const CORBLBASE: u64 = 0x40;
+const CORBUBASE: u64 = 0x44;
+const CORBWP: u64 = 0x48;
+const CORBRP: u64 = 0x4A;
+const CORBCTL: u64 = 0x4C;
+const CORBSIZE: u64 = 0x4E;
+const RIRBLBASE: u64 = 0x50;
+const RIRBUBASE: u64 = 0x54;
+const RIRBWP: u64 = 0x58;
+const RINTCNT: u64 = 0x5A;
+const RIRBCTL: u64 = 0x5C;
+const RIRBSIZE: u64 = 0x5E;
+
+// Stream descriptor registers, from the descriptor's base
+const SD_CTL: u64 = 0x00;
+const SD_STS: u64 = 0x03;
+const SD_LPIB: u64 = 0x04;
+const SD_CBL: u64 = 0x08;
+const SD_LVI: u64 = 0x0C;
+const SD_FMT: u64 = 0x12;
+const SD_BDPL: u64 = 0x18;
+const SD_BDPU: u64 = 0x1C;
+
+/// SD_CTL: run, and interrupt on buffer completion
+const SD_RUN: u32 = 0x02;
+const SD_IOCE: u32 = 0x04;
+/// SD_STS: buffer completed, FIFO error, descriptor error; written back
+/// to clear
+const SD_STATUS_BITS: u8 = 0x1C;
+/// INTCTL: global interrupt enable
+const INTCTL_GIE: u32 = 1 << 31;
+
+// Verbs
+const GET_PARAMETER: u32 = 0xF00;
+const GET_CONNECTION_LIST: u32 = 0xF02;
+const SET_CONNECTION_SELECT: u32 = 0x701;
+const SET_POWER_STATE: u32 = 0x705;
+const SET_STREAM: u32 = 0x706;
+const SET_PIN_CONTROL: u32 = 0x707;
+const SET_EAPD: u32 = 0x70C;
+const GET_CONFIG_DEFAULT: u32 = 0xF1C;
+/// Verbs with a 16-bit payload, shifted down
+const SET_FORMAT: u32 = 0x2;
+const SET_AMP_GAIN: u32 = 0x3;
+
+// Parameters
+const NODE_COUNT: u32 = 0x04;
+const FUNCTION_GROUP_TYPE: u32 = 0x05;
+const WIDGET_CAPABILITIES: u32 = 0x09;
+const PIN_CAPABILITIES: u32 = 0x0C;
+const CONNECTION_LIST_LENGTH: u32 = 0x0E;
+const OUTPUT_AMP_CAPABILITIES: u32 = 0x12;
+
+// Widget types, from bits 20-23 of the capabilities
+const WIDGET_OUTPUT: u32 = 0x0;
+const WIDGET_MIXER: u32 = 0x2;
+const WIDGET_SELECTOR: u32 = 0x3;
+const WIDGET_PIN: u32 = 0x4;
+
+/// 48kHz, 16 bits, two channels
+const STREAM_FORMAT: u16 = 0x0011;
+/// Tag the DAC and the stream descriptor agree on
+const STREAM_TAG: u32 = 1;
+
+/// The ring: 4 buffers of 8KB, about 170ms
+const BDL_ENTRIES: usize = 4;
+const BUFFER_BYTES: usize = 8192;
+const RING_BYTES: usize = BDL_ENTRIES * BUFFER_BYTES;
+
+/// Entries in each of the CORB and RIRB
+const RING_ENTRIES: usize = 256;
+
+/// Widgets a path from a pin to a DAC may go through
+const MAX_PATH: usize = 4;
+
+pub struct Hda {
+    regs: u64,
+    /// Base of the output stream descriptor
+    stream: u64,
+    corb: *mut u32,
+    rirb: *const u64,
+    corb_wp: usize,
+    rirb_rp: usize,
+    ring: *mut i16,
+}
+
+// The controller is only reached through the mixer's lock
+unsafe impl Send for Hda {}
+
+/// Address of the playing stream's status register, for the interrupt
+/// handler; zero until a stream runs
+static STREAM_STATUS: AtomicU64 = AtomicU64::new(0);
+/// Legacy line the controller interrupts on, for the end of interrupt
+static IRQ: AtomicU8 = AtomicU8::new(0);
+
+static DRIVER: pci::PciDriver = pci::PciDriver {
+    name: "hda",
+    matches: &[pci::DeviceMatch::class(pci::class::MULTIMEDIA, 0x03)],
+    probe,
+};
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+fn probe(device: &PciDevice) -> bool {
+    let regs = match device.bar(0).and_then(|bar| bar.map()) {
+        Some(regs) => regs,
+        None => return false,
+    };
+    match Hda::new(regs) {
+        Some(hda) => {
+            hda.listen(device);
+            super::register_device(Box::new(hda))
+        }
+        None => {
+            warn!("hda", "Controller at {:02X}:{:02X}.{} did not come up", device.bus, device.device, device.function);
+            false
+        }
+    }
+}
+
+/// A buffer has been played: acknowledge it. Waking the kernel is all it
+/// takes; the mixer refills the ring from `sound::poll`.
+extern "x86-interrupt" fn stream_interrupt(_stack_frame: InterruptStackFrame) {
+    let status = STREAM_STATUS.load(Ordering::Relaxed);
+    if status != 0 {
+        unsafe { core::ptr::write_volatile(status as *mut u8, SD_STATUS_BITS) };
+    }
+    interrupts::end_of_interrupt(IRQ.load(Ordering::Relaxed));
+}
+
+/// Wait up to `ms` for `done`
+fn wait(ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = timer::now_ns() + ms * 1_000_000;
+    while !done() {
+        if timer::now_ns() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+impl Hda {
+    fn read8(&self, reg: u64) -> u8 {
+        unsafe { core::ptr::read_volatile((self.regs + reg) as *const u8) }
+    }
+
+    fn read16(&self, reg: u64) -> u16 {
+        unsafe { core::ptr::read_volatile((self.regs + reg) as *const u16) }
+    }
+
+    fn read32(&self, reg: u64) -> u32 {
+        unsafe { core::ptr::read_volatile((self.regs + reg) as *const u32) }
+    }
+
+    fn write8(&self, reg: u64, value: u8) {
+        unsafe { core::ptr::write_volatile((self.regs + reg) as *mut u8, value) }
+    }
+
+    fn write16(&self, reg: u64, value: u16) {
+        unsafe { core::ptr::write_volatile((self.regs + reg) as *mut u16, value) }
+    }
+
+    fn write32(&self, reg: u64, value: u32) {
+        unsafe { core::ptr::write_volatile((self.regs + reg) as *mut u32, value) }
+    }
+
+    fn new(regs: u64) -> Option<Self> {
+        // CORB, RIRB and the buffer descriptor list share a page
+        let control = mm::alloc_dma_frames(1)?.as_u64();
+        let ring = mm::alloc_dma_frames(RING_BYTES / 0x1000)?.as_u64();
+        let control_virt = mm::phys_to_virt(PhysAddr::new(control)).as_u64();
+        let mut hda = Self {
+            regs,
+            stream: 0,
+            corb: control_virt as *mut u32,
+            rirb: (control_virt + 0x400) as *const u64,
+            corb_wp: 0,
+            rirb_rp: 0,
+            ring: mm::phys_to_virt(PhysAddr::new(ring)).as_u64() as *mut i16,
+        };
+
+        // Out of reset, then give the codecs time to say they are there
+        hda.write32(GCTL, 0);
+        if !wait(100, || hda.read32(GCTL) & 1 == 0) {
+            return None;
+        }
+        hda.write32(GCTL, 1);
+        if !wait(100, || hda.read32(GCTL) & 1 == 1) {
+            return None;
+        }
+        timer::sleep_ms(1);
+        let codecs = hda.read16(STATESTS);
+
+        hda.start_rings(control);
+        let (codec, dac) = (0..15).filter(|c| codecs & (1 << c) != 0)
+            .find_map(|codec| hda.configure_codec(codec).map(|dac| (codec, dac)))?;
+        hda.command(codec, dac, SET_STREAM, STREAM_TAG << 4);
+        hda.command16(codec, dac, SET_FORMAT, STREAM_FORMAT);
+
+        // Input streams come first, then output streams
+        let gcap = hda.read16(GCAP);
+        let inputs = ((gcap >> 8) & 0xF) as u64;
+        if (gcap >> 12) & 0xF == 0 {
+            return None;
+        }
+        hda.stream = 0x80 + inputs * 0x20;
+        hda.start_stream(control + 0xC00, ring);
+        info!("hda", "Codec {} playing through DAC node {}", codec, dac);
+        Some(hda)
+    }
+
+    /// Take the stream's interrupts by MSI, or on the legacy line the
+    /// firmware assigned if the controller cannot; without either the
+    /// mixer is only fed when something else wakes the kernel
+    fn listen(&self, device: &PciDevice) {
+        STREAM_STATUS.store(self.regs + self.stream + SD_STS, Ordering::Relaxed);
+        if device.enable_msi(stream_interrupt).is_some() {
+            info!("hda", "Interrupts by MSI");
+        } else {
+            let line = (device.read_config(0x3C) & 0xFF) as u8;
+            // 0xFF is unconnected; above 15 is no ISA line either
+            if line >= 16 {
+                warn!("hda", "No interrupt line, playing by polling");
+                return;
+            }
+            IRQ.store(line, Ordering::Relaxed);
+            interrupts::set_irq_handler(line, stream_interrupt);
+            info!("hda", "Interrupts on IRQ {}", line);
+        }
+        // Output streams are numbered on from the input streams
+        let index = (self.stream - 0x80) / 0x20;
+        self.write32(INTCTL, INTCTL_GIE | 1 << index);
+    }
+
+    /// Set up the CORB and RIRB with 256 entries each and start them
+    fn start_rings(&mut self, control: u64) {
+        self.write8(CORBCTL, 0);
+        self.write8(RIRBCTL, 0);
+        wait(10, || self.read8(CORBCTL) & 0x02 == 0 && self.read8(RIRBCTL) & 0x02 == 0);
+
+        self.write32(CORBLBASE, control as u32);
+        self.write32(CORBUBASE, (control >> 32) as u32);
+        self.write8(CORBSIZE, 0x02);
+        self.write16(CORBWP, 0);
+        // Reset the read pointer: set bit 15, see it set, clear it
+        self.write16(CORBRP, 0x8000);
+        wait(10, || self.read16(CORBRP) & 0x8000 != 0);
+        self.write16(CORBRP, 0);
+        wait(10, || self.read16(CORBRP) & 0x8000 == 0);
+
+        let rirb = control + 0x400;
+        self.write32(RIRBLBASE, rirb as u32);
+        self.write32(RIRBUBASE, (rirb >> 32) as u32);
+        self.write8(RIRBSIZE, 0x02);
+        self.write16(RIRBWP, 0x8000);
+        self.write16(RINTCNT, 1);
+
+        self.write8(CORBCTL, 0x02);
+        self.write8(RIRBCTL, 0x02);
+        self.corb_wp = 0;
+        self.rirb_rp = 0;
+    }
+
+    /// Send a verb to a node and wait for the response
+    fn send(&mut self, verb: u32) -> Option<u32> {
+        self.corb_wp = (self.corb_wp + 1) % RING_ENTRIES;
+        unsafe { core::ptr::write_volatile(self.corb.add(self.corb_wp), verb) };
+        self.write16(CORBWP, self.corb_wp as u16);
+        let expected = (self.rirb_rp + 1) % RING_ENTRIES;
+        if !wait(10, || self.read16(RIRBWP) as usize & 0xFF == expected) {
+            return None;
+        }
+        self.rirb_rp = expected;
+        // The low dword of each entry is the response
+        Some(unsafe { core::ptr::read_volatile(self.rirb.add(expected)) } as u32)
+    }
+
+    /// A verb with an 8-bit payload
+    fn command(&mut self, codec: u32, node: u32, verb: u32, payload: u32) -> u32 {
+        self.send(codec << 28 | node << 20 | verb << 8 | (payload & 0xFF)).unwrap_or(0)
+    }
+
+    /// A verb with a 16-bit payload
+    fn command16(&mut self, codec: u32, node: u32, verb: u32, payload: u16) -> u32 {
+        self.send(codec << 28 | node << 20 | verb << 16 | payload as u32).unwrap_or(0)
+    }
+
+    fn parameter(&mut self, codec: u32, node: u32, parameter: u32) -> u32 {
+        self.command(codec, node, GET_PARAMETER, parameter)
+    }
+
+    /// The first node and how many there are under `node`
+    fn children(&mut self, codec: u32, node: u32) -> core::ops::Range<u32> {
+        let count = self.parameter(codec, node, NODE_COUNT);
+        let first = (count >> 16) & 0xFF;
+        first..first + (count & 0xFF)
+    }
+
+    /// The nodes `node` takes its input from
+    fn connections(&mut self, codec: u32, node: u32) -> Vec<u32> {
+        let length = self.parameter(codec, node, CONNECTION_LIST_LENGTH);
+        // Long-form lists have 16-bit entries, two to a response
+        let (per_response, bits) = if length & 0x80 != 0 { (2, 16) } else { (4, 8) };
+        let count = length & 0x7F;
+        let mut list = Vec::new();
+        for i in (0..count).step_by(per_response) {
+            let response = self.command(codec, node, GET_CONNECTION_LIST, i);
+            for j in 0..per_response.min((count - i) as usize) {
+                list.push((response >> (j as u32 * bits)) & ((1 << bits) - 1));
+            }
+        }
+        list
+    }
+
+    fn widget_type(&mut self, codec: u32, node: u32) -> u32 {
+        (self.parameter(codec, node, WIDGET_CAPABILITIES) >> 20) & 0xF
+    }
+
+    /// Nodes from `node` down to a DAC, each with the connection it takes
+    fn path_to_dac(&mut self, codec: u32, node: u32, depth: usize) -> Option<Vec<(u32, usize)>> {
+        if depth > MAX_PATH {
+            return None;
+        }
+        for (index, input) in self.connections(codec, node).into_iter().enumerate() {
+            match self.widget_type(codec, input) {
+                WIDGET_OUTPUT => return Some(alloc::vec![(node, index), (input, 0)]),
+                WIDGET_MIXER | WIDGET_SELECTOR => {
+                    if let Some(mut rest) = self.path_to_dac(codec, input, depth + 1) {
+                        rest.insert(0, (node, index));
+                        return Some(rest);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Find an output pin with a path to a DAC and turn it all on; returns
+    /// the DAC
+    fn configure_codec(&mut self, codec: u32) -> Option<u32> {
+        let group = self.children(codec, 0)
+            .find(|&fg| self.parameter(codec, fg, FUNCTION_GROUP_TYPE) & 0xFF == 1)?;
+        self.command(codec, group, SET_POWER_STATE, 0);
+
+        let mut pins = Vec::new();
+        for node in self.children(codec, group) {
+            if self.widget_type(codec, node) != WIDGET_PIN {
+                continue;
+            }
+            let caps = self.parameter(codec, node, PIN_CAPABILITIES);
+            let config = self.command(codec, node, GET_CONFIG_DEFAULT, 0);
+            // Output capable, and wired to something
+            if caps & (1 << 4) == 0 || config >> 30 == 1 {
+                continue;
+            }
+            // Speakers first, then line out, then headphones
+            let rank = match (config >> 20) & 0xF {
+                1 => 0,
+                0 => 1,
+                2 => 2,
+                _ => 3,
+            };
+            pins.push((rank, node, caps));
+        }
+        pins.sort();
+
+        for (_, pin, caps) in pins {
+            let path = match self.path_to_dac(codec, pin, 0) {
+                Some(path) => path,
+                None => continue,
+            };
+            for &(node, index) in &path {
+                self.command(codec, node, SET_POWER_STATE, 0);
+                if self.connections(codec, node).len() > 1 {
+                    self.command(codec, node, SET_CONNECTION_SELECT, index as u32);
+                }
+                // Output amp unmuted on both sides at 0dB, and the input
+                // amp of the connection taken
+                let gain = (self.parameter(codec, node, OUTPUT_AMP_CAPABILITIES) & 0x7F) as u16;
+                self.command16(codec, node, SET_AMP_GAIN, 0xB000 | gain);
+                self.command16(codec, node, SET_AMP_GAIN, 0x7000 | (index as u16) << 8 | gain);
+            }
+            // Output on, headphone drive where the pin can, and the
+            // external amplifier where the pin has one
+            let headphone = if caps & (1 << 3) != 0 { 0x80 } else { 0 };
+            self.command(codec, pin, SET_PIN_CONTROL, 0x40 | headphone);
+            if caps & (1 << 16) != 0 {
+                self.command(codec, pin, SET_EAPD, 0x02);
+            }
+            return path.last().map(|&(dac, _)| dac);
+        }
+        None
+    }
+
+    /// Point the output stream at the ring, through the buffer descriptor
+    /// list at `bdl`, and run it
+    fn start_stream(&mut self, bdl: u64, ring: u64) {
+        let sd = self.stream;
+        // Reset the stream: set SRST, see it set, clear it
+        self.write8(sd + SD_CTL, 0x01);
+        wait(10, || self.read8(sd + SD_CTL) & 0x01 != 0);
+        self.write8(sd + SD_CTL, 0x00);
+        wait(10, || self.read8(sd + SD_CTL) & 0x01 == 0);
+
+        let entries = mm::phys_to_virt(PhysAddr::new(bdl)).as_u64() as *mut u32;
+        for i in 0..BDL_ENTRIES {
+            let address = ring + (i * BUFFER_BYTES) as u64;
+            unsafe {
+                let entry = entries.add(i * 4);
+                core::ptr::write_volatile(entry, address as u32);
+                core::ptr::write_volatile(entry.add(1), (address >> 32) as u32);
+                core::ptr::write_volatile(entry.add(2), BUFFER_BYTES as u32);
+                // Interrupt on completion
+                core::ptr::write_volatile(entry.add(3), 1);
+            }
+        }
+        self.write32(sd + SD_BDPL, bdl as u32);
+        self.write32(sd + SD_BDPU, (bdl >> 32) as u32);
+        self.write32(sd + SD_CBL, RING_BYTES as u32);
+        self.write16(sd + SD_LVI, (BDL_ENTRIES - 1) as u16);
+        self.write16(sd + SD_FMT, STREAM_FORMAT);
+        // Stream tag in bits 20-23, then run; the completion interrupt
+        // only reaches the CPU once `listen` enables the stream's in INTCTL
+        self.write32(sd + SD_CTL, STREAM_TAG << 20 | SD_IOCE);
+        self.write32(sd + SD_CTL, STREAM_TAG << 20 | SD_IOCE | SD_RUN);
+    }
+}
+
+impl AudioDevice for Hda {
+    fn name(&self) -> &'static str {
+        "Intel HDA"
+    }
+
+    fn ring(&mut self) -> &mut [i16] {
+        unsafe { core::slice::from_raw_parts_mut(self.ring, RING_BYTES / 2) }
+    }
+
+    fn position(&mut self) -> usize {
+        // Bytes into the ring, to the frame
+        let bytes = self.read32(self.stream + SD_LPIB) as usize % RING_BYTES;
+        bytes / 2 / CHANNELS * CHANNELS
+    }
+}
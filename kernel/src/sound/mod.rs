@@ -0,0 +1,221 @@
+//! Sound subsystem
+//!
+//! One output device plays 16-bit stereo at `SAMPLE_RATE` from a ring of
+//! DMA memory it goes round and round. The mixer sums the voices that are
+//! playing into the ring a little ahead of the device, from `poll`, which
+//! the kernel's main loops call, and silences what the device has played,
+//! so it falls quiet rather than repeating itself if `poll` stops coming.
+//!
+//! Intel HDA controllers are tried first, then AC'97, which is what QEMU
+//! gives with `-device AC97`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub mod ac97;
+pub mod hda;
+pub mod wav;
+
+use crate::println;
+use crate::info;
+
+/// Frames a second every device plays at
+pub const SAMPLE_RATE: u32 = 48_000;
+/// Samples per frame: left, then right
+pub const CHANNELS: usize = 2;
+
+/// How far ahead of the device the mixer writes, in samples: 50ms
+const LEAD: usize = SAMPLE_RATE as usize / 20 * CHANNELS;
+
+/// A sound card, playing from its ring
+pub trait AudioDevice: Send {
+    fn name(&self) -> &'static str;
+    /// The ring of interleaved stereo samples the device plays
+    fn ring(&mut self) -> &mut [i16];
+    /// Index in the ring of the sample being played; also keeps the
+    /// device going round
+    fn position(&mut self) -> usize;
+}
+
+/// A sound being played
+struct Voice {
+    samples: Vec<i16>,
+    next: usize,
+}
+
+struct Mixer {
+    device: Option<Box<dyn AudioDevice>>,
+    voices: Vec<Voice>,
+    /// Ring index the next mixed sample goes to
+    write: usize,
+    /// Where the device was at the last `poll`
+    played: usize,
+    /// Master volume, 0 to 100
+    volume: u32,
+}
+
+static MIXER: Mutex<Mixer> = Mutex::new(Mixer {
+    device: None,
+    voices: Vec::new(),
+    write: 0,
+    played: 0,
+    volume: 80,
+});
+
+/// Whether notifications chime
+static CHIME: AtomicBool = AtomicBool::new(true);
+
+/// Find a sound card
+pub fn init() {
+    info!("sound", "Probing for sound cards...");
+    hda::init();
+    if !is_present() {
+        ac97::init();
+    }
+    if !is_present() {
+        info!("sound", "No sound card");
+    }
+}
+
+/// Play through `device` from now on; the first one registered wins
+pub fn register_device(mut device: Box<dyn AudioDevice>) -> bool {
+    let mut mixer = MIXER.lock();
+    if mixer.device.is_some() {
+        return false;
+    }
+    info!("sound", "Output on {}", device.name());
+    let position = device.position();
+    mixer.write = position;
+    mixer.played = position;
+    mixer.device = Some(device);
+    true
+}
+
+/// Whether there is a sound card to play on
+pub fn is_present() -> bool {
+    MIXER.lock().device.is_some()
+}
+
+/// Play interleaved stereo samples at `SAMPLE_RATE`, mixed with whatever
+/// else is playing; false without a sound card
+pub fn play(samples: Vec<i16>) -> bool {
+    let mut mixer = MIXER.lock();
+    if mixer.device.is_none() {
+        return false;
+    }
+    mixer.voices.push(Voice { samples, next: 0 });
+    true
+}
+
+/// Stop everything that is playing
+pub fn stop() {
+    MIXER.lock().voices.clear();
+}
+
+/// Decode a WAV file and play it
+pub fn play_wav(data: &[u8]) -> Result<(), wav::WavError> {
+    let samples = wav::decode(data)?;
+    play(samples).then_some(()).ok_or(wav::WavError::NoDevice)
+}
+
+/// A square wave of `frequency` Hz for `ms` milliseconds, at a quarter of
+/// full scale
+pub fn tone(frequency: u32, ms: u32) -> Vec<i16> {
+    const AMPLITUDE: i16 = i16::MAX / 4;
+    let frames = (SAMPLE_RATE as u64 * ms as u64 / 1000) as usize;
+    let half_period = (SAMPLE_RATE / frequency.clamp(20, 20_000) / 2).max(1) as usize;
+    // A few milliseconds of fade at each end keep it from clicking
+    let fade = (SAMPLE_RATE as usize / 200).min(frames / 2).max(1);
+    let mut samples = Vec::with_capacity(frames * CHANNELS);
+    for i in 0..frames {
+        let level = if (i / half_period) % 2 == 0 { AMPLITUDE } else { -AMPLITUDE };
+        let ramp = i.min(frames - 1 - i).min(fade) as i32;
+        let sample = (level as i32 * ramp / fade as i32) as i16;
+        samples.push(sample);
+        samples.push(sample);
+    }
+    samples
+}
+
+/// Beep; false without a sound card
+pub fn beep(frequency: u32, ms: u32) -> bool {
+    play(tone(frequency, ms))
+}
+
+/// The two rising notes played when a notification comes in, unless
+/// turned off
+pub fn chime() {
+    if !CHIME.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut samples = tone(880, 80);
+    samples.extend(tone(1320, 120));
+    play(samples);
+}
+
+pub fn set_chime_enabled(enabled: bool) {
+    CHIME.store(enabled, Ordering::Relaxed);
+}
+
+/// Master volume, 0 to 100
+pub fn set_volume(volume: u32) {
+    MIXER.lock().volume = volume.min(100);
+}
+
+/// Mix what is playing into the ring up to `LEAD` ahead of the device
+pub fn poll() {
+    let mut guard = MIXER.lock();
+    let mixer = &mut *guard;
+    let device = match mixer.device.as_mut() {
+        Some(device) => device,
+        None => return,
+    };
+    let position = device.position();
+    let ring = device.ring();
+    let len = ring.len();
+
+    // Silence what has been played since last time
+    let mut i = mixer.played;
+    while i != position {
+        ring[i] = 0;
+        i = (i + 1) % len;
+    }
+    mixer.played = position;
+
+    // If the device has caught up with the mixer, start again from it
+    let ahead = (mixer.write + len - position) % len;
+    if ahead > LEAD {
+        mixer.write = position;
+    }
+    let wanted = LEAD.saturating_sub((mixer.write + len - position) % len);
+    for _ in 0..wanted {
+        let mut sum = 0i32;
+        for voice in mixer.voices.iter_mut() {
+            if let Some(&sample) = voice.samples.get(voice.next) {
+                sum += sample as i32;
+                voice.next += 1;
+            }
+        }
+        ring[mixer.write] = (sum * mixer.volume as i32 / 100).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        mixer.write = (mixer.write + 1) % len;
+    }
+    mixer.voices.retain(|v| v.next < v.samples.len());
+}
+
+/// Print the sound card and what is playing
+pub fn print_info() {
+    let mut mixer = MIXER.lock();
+    let volume = mixer.volume;
+    let voices = mixer.voices.len();
+    match mixer.device.as_mut() {
+        Some(device) => {
+            let ring = device.ring().len();
+            println!("Sound: {}, {}Hz 16-bit stereo, {}ms ring", device.name(), SAMPLE_RATE,
+                ring as u64 * 1000 / (SAMPLE_RATE as u64 * CHANNELS as u64));
+            println!("  Volume: {}%, {} sounds playing", volume, voices);
+        }
+        None => println!("No sound card"),
+    }
+}
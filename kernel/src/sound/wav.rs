@@ -0,0 +1,113 @@
+//! WAV files
+//!
+//! Uncompressed PCM only, 8 or 16 bits, mono or stereo, at any rate;
+//! `decode` turns it into what the mixer plays.
+
+use alloc::vec::Vec;
+
+use super::{CHANNELS, SAMPLE_RATE};
+
+/// Why a WAV file cannot be played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavError {
+    /// Not a RIFF WAVE file
+    NotWav,
+    /// No `fmt ` or `data` chunk, or one cut short
+    Truncated,
+    /// Compressed, or more than two channels, or neither 8 nor 16 bits
+    Unsupported,
+    /// No sound card to play it on
+    NoDevice,
+}
+
+/// What the `fmt ` chunk says
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits: u16,
+}
+
+const FORMAT_PCM: u16 = 1;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// The format and the sample data of a WAV file
+pub fn parse(data: &[u8]) -> Result<(Format, &[u8]), WavError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(WavError::NotWav);
+    }
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let size = u32_at(data, offset + 4) as usize;
+        let body = offset + 8;
+        // A data chunk cut short still plays as far as it goes
+        let end = body.saturating_add(size).min(data.len());
+        match id {
+            b"fmt " => {
+                if end - body < 16 {
+                    return Err(WavError::Truncated);
+                }
+                if u16_at(data, body) != FORMAT_PCM {
+                    return Err(WavError::Unsupported);
+                }
+                format = Some(Format {
+                    channels: u16_at(data, body + 2),
+                    sample_rate: u32_at(data, body + 4),
+                    bits: u16_at(data, body + 14),
+                });
+            }
+            b"data" => {
+                let format = format.ok_or(WavError::Truncated)?;
+                if !matches!(format.channels, 1 | 2) || !matches!(format.bits, 8 | 16) || format.sample_rate == 0 {
+                    return Err(WavError::Unsupported);
+                }
+                return Ok((format, &data[body..end]));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        offset = body.saturating_add(size + (size & 1));
+    }
+    Err(WavError::Truncated)
+}
+
+/// Interleaved stereo samples at the mixer's rate
+pub fn decode(data: &[u8]) -> Result<Vec<i16>, WavError> {
+    let (format, pcm) = parse(data)?;
+    let bytes = (format.bits / 8) as usize;
+    let frame_size = bytes * format.channels as usize;
+    let frames = pcm.len() / frame_size;
+    let sample = |frame: usize, channel: usize| -> i32 {
+        let at = frame * frame_size + channel.min(format.channels as usize - 1) * bytes;
+        match bytes {
+            // 8-bit samples are unsigned
+            1 => ((pcm[at] as i32) - 128) << 8,
+            _ => i16::from_le_bytes([pcm[at], pcm[at + 1]]) as i32,
+        }
+    };
+
+    // Resample by straight lines between neighbouring frames
+    let out_frames = (frames as u64 * SAMPLE_RATE as u64 / format.sample_rate as u64) as usize;
+    let mut samples = Vec::with_capacity(out_frames * CHANNELS);
+    for i in 0..out_frames {
+        let position = i as u64 * format.sample_rate as u64;
+        let frame = (position / SAMPLE_RATE as u64) as usize;
+        let fraction = (position % SAMPLE_RATE as u64) as i64;
+        let next = (frame + 1).min(frames - 1);
+        for channel in 0..CHANNELS {
+            let (a, b) = (sample(frame, channel) as i64, sample(next, channel) as i64);
+            let value = a + (b - a) * fraction / SAMPLE_RATE as i64;
+            samples.push(value as i16);
+        }
+    }
+    Ok(samples)
+}
@@ -9,7 +9,7 @@ use core::mem::size_of;
 use crate::storage::{BlockDevice, StorageError};
 use crate::drivers::pci::{self, PciDevice};
 use crate::mm::virt_to_phys_u64;
-use crate::println;
+use crate::{debug, info, warn};
 
 /// AHCI PCI class/subclass
 const SATA_CLASS: u8 = 0x01;
@@ -540,72 +540,71 @@ impl BlockDevice for AhciPort {
     }
 }
 
+static DRIVER: pci::PciDriver = pci::PciDriver {
+    name: "ahci",
+    matches: &[pci::DeviceMatch::class(SATA_CLASS, SATA_SUBCLASS).prog_if(AHCI_PROGIF)],
+    probe,
+};
+
 /// Initialize AHCI controller
 pub fn init() {
-    println!("[ahci] Probing for AHCI controllers...");
-
-    // Find AHCI controller on PCI
-    if let Some(device) = pci::find_device(SATA_CLASS, SATA_SUBCLASS) {
-        // Check programming interface for AHCI mode
-        if device.prog_if != AHCI_PROGIF {
-            println!("[ahci] Controller not in AHCI mode");
-            return;
-        }
-
-        println!("[ahci] Found AHCI controller at {:02X}:{:02X}.{}",
-            device.bus, device.device, device.function);
+    info!("ahci", "Probing for AHCI controllers...");
+    pci::register_driver(&DRIVER);
+}
 
-        // Read BAR5 (AHCI base address)
-        let bar5 = device.read_config(0x24);
-        let base_addr = if bar5 & 1 == 0 {
-            (bar5 & 0xFFFFFFF0) as u64
-        } else {
-            println!("[ahci] Unexpected I/O BAR");
-            return;
-        };
+/// Set up a controller and the drives on its ports
+fn probe(device: &PciDevice) -> bool {
+    info!("ahci", "Found AHCI controller at {:02X}:{:02X}.{}",
+        device.bus, device.device, device.function);
+
+    // The HBA registers are in BAR5
+    let ahci_base = match device.bar(5).and_then(|bar| bar.map()) {
+        Some(base) => base as *mut u8,
+        None => {
+            warn!("ahci", "BAR5 is not memory, or could not be mapped");
+            return false;
+        }
+    };
 
-        // Map the memory region (for now assume it's identity mapped)
-        let ahci_base = (base_addr + crate::mm::PHYSICAL_MEMORY_OFFSET) as *mut u8;
+    // Read capabilities
+    let cap = unsafe { read_reg(ahci_base, REG_CAP) };
+    let port_count = ((cap >> 0) & 0x1F) + 1; // Number of ports
+    let cmd_slots = ((cap >> 8) & 0x1F) + 1;  // Number of command slots
 
-        // Read capabilities
-        let cap = unsafe { read_reg(ahci_base, REG_CAP) };
-        let port_count = ((cap >> 0) & 0x1F) + 1; // Number of ports
-        let cmd_slots = ((cap >> 8) & 0x1F) + 1;  // Number of command slots
+    debug!("ahci", "Ports: {}, Command slots: {}", port_count, cmd_slots);
 
-        println!("[ahci] Ports: {}, Command slots: {}", port_count, cmd_slots);
+    // Read ports implemented bitmap
+    let pi = unsafe { read_reg(ahci_base, REG_PI) };
 
-        // Read ports implemented bitmap
-        let pi = unsafe { read_reg(ahci_base, REG_PI) };
+    // Enable AHCI mode
+    let ghc = unsafe { read_reg(ahci_base, REG_GHC) };
+    unsafe {
+        write_reg(ahci_base, REG_GHC, ghc | 0x80000000); // AHCI Enable
+    }
 
-        // Enable AHCI mode
-        let ghc = unsafe { read_reg(ahci_base, REG_GHC) };
-        unsafe {
-            write_reg(ahci_base, REG_GHC, ghc | 0x80000000); // AHCI Enable
+    // Probe each implemented port
+    for port in 0..32 {
+        if pi & (1 << port) == 0 {
+            continue;
         }
 
-        // Probe each implemented port
-        for port in 0..32 {
-            if pi & (1 << port) == 0 {
-                continue;
-            }
-
-            let port_base = unsafe { ahci_base.add(0x100 + port * 0x80) };
-
-            if let Some(mut ahci_port) = AhciPort::new(port as u32, port_base) {
-                if ahci_port.init().is_ok() {
-                    let model = core::str::from_utf8(&ahci_port.model)
-                        .unwrap_or("Unknown")
-                        .trim();
-                    println!("[ahci] Port {}: {} ({} sectors)",
-                        port, model, ahci_port.sector_count);
-                    
-                    crate::storage::register_device(Box::new(ahci_port));
-                } else {
-                    println!("[ahci] Port {}: No device or initialization failed", port);
-                }
+        let port_base = unsafe { ahci_base.add(0x100 + port * 0x80) };
+
+        if let Some(mut ahci_port) = AhciPort::new(port as u32, port_base) {
+            if ahci_port.init().is_ok() {
+                let model = core::str::from_utf8(&ahci_port.model)
+                    .unwrap_or("Unknown")
+                    .trim();
+                info!("ahci", "Port {}: {} ({} sectors)",
+                    port, model, ahci_port.sector_count);
+                
+                crate::storage::register_device(Box::new(ahci_port));
+            } else {
+                warn!("ahci", "Port {}: No device or initialization failed", port);
             }
         }
     }
+    true
 }
 
 /// Read AHCI register
@@ -9,7 +9,7 @@ use alloc::string::String;
 use spin::Mutex;
 
 use crate::storage::{BlockDevice, StorageError};
-use crate::println;
+use crate::info;
 
 /// ATA I/O ports (primary channel)
 const PRIMARY_DATA: u16 = 0x1F0;
@@ -331,14 +331,14 @@ impl BlockDevice for AtaDrive {
 
 /// Initialize ATA drives
 pub fn init() {
-    println!("[ata] Probing for ATA drives...");
+    info!("ata", "Probing for ATA drives...");
 
     // Try primary master
     let mut drive0 = AtaDrive::new(PRIMARY_DATA, PRIMARY_CONTROL, true);
     if drive0.init().is_ok() {
         let model = core::str::from_utf8(&drive0.model).unwrap_or("Unknown").trim();
         let serial = core::str::from_utf8(&drive0.serial).unwrap_or("Unknown").trim();
-        println!("[ata] Found drive: {} ({})", model, serial);
+        info!("ata", "Found drive: {} ({})", model, serial);
         
         crate::storage::register_device(Box::new(drive0));
     }
@@ -348,7 +348,7 @@ pub fn init() {
     if drive1.init().is_ok() {
         let model = core::str::from_utf8(&drive1.model).unwrap_or("Unknown").trim();
         let serial = core::str::from_utf8(&drive1.serial).unwrap_or("Unknown").trim();
-        println!("[ata] Found drive: {} ({})", model, serial);
+        info!("ata", "Found drive: {} ({})", model, serial);
         
         crate::storage::register_device(Box::new(drive1));
     }
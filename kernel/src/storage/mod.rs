@@ -12,9 +12,12 @@ use lazy_static::lazy_static;
 pub mod ata;
 pub mod ahci;
 pub mod nvme;
+pub mod partition;
 
 use crate::drivers::pci::PciDevice;
 use crate::println;
+use crate::trace;
+use crate::{info, warn};
 
 /// Block device trait
 pub trait BlockDevice: Send + Sync {
@@ -79,7 +82,7 @@ lazy_static! {
 
 /// Initialize storage subsystem
 pub fn init() {
-    println!("[storage] Initializing storage subsystem...");
+    info!("storage", "Initializing storage subsystem...");
 
     // Try to initialize NVMe first (modern)
     nvme::init();
@@ -90,7 +93,7 @@ pub fn init() {
     // Fall back to ATA/IDE
     ata::init();
 
-    println!("[storage] Storage subsystem initialized");
+    info!("storage", "Storage subsystem initialized");
 }
 
 /// Register block device
@@ -98,7 +101,7 @@ pub fn register_device(device: Box<dyn BlockDevice>) {
     let mut devices = BLOCK_DEVICES.lock();
     let idx = devices.len();
     
-    println!("[storage] Registered block device {}: {} ({} blocks, {} MB)",
+    info!("storage", "Registered block device {}: {} ({} blocks, {} MB)",
         idx,
         device.name(),
         device.block_count(),
@@ -113,21 +116,67 @@ pub fn device_count() -> usize {
     BLOCK_DEVICES.lock().len()
 }
 
+/// A registered device, reached through the global list
+struct DeviceRef {
+    idx: usize,
+    name: String,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl BlockDevice for DeviceRef {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, start: u64, count: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        read(self.idx, start, count, buf)
+    }
+
+    fn write_blocks(&self, start: u64, count: usize, buf: &[u8]) -> Result<(), StorageError> {
+        write(self.idx, start, count, buf)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        BLOCK_DEVICES.lock().get(self.idx).ok_or(StorageError::NotFound)?.flush()
+    }
+}
+
 /// Get block device by index
 pub fn get_device(idx: usize) -> Option<Box<dyn BlockDevice>> {
-    BLOCK_DEVICES.lock().get(idx).map(|d| {
-        // Create a simple wrapper - in reality we'd use Arc or similar
-        // For now, just return None since we can't easily clone Box<dyn BlockDevice>
-        // The actual usage would be through the global list
-        None
-    }).flatten()
+    BLOCK_DEVICES.lock().get(idx).map(|d| Box::new(DeviceRef {
+        idx,
+        name: String::from(d.name()),
+        block_size: d.block_size(),
+        block_count: d.block_count(),
+    }) as Box<dyn BlockDevice>)
+}
+
+/// Get block device by index or name
+pub fn find_device(name: &str) -> Option<Box<dyn BlockDevice>> {
+    let idx = match name.parse() {
+        Ok(idx) => idx,
+        Err(_) => BLOCK_DEVICES.lock().iter().position(|d| d.name() == name)?,
+    };
+    get_device(idx)
 }
 
 /// Read from block device
 pub fn read(idx: usize, start: u64, count: usize, buf: &mut [u8]) -> Result<(), StorageError> {
     let devices = BLOCK_DEVICES.lock();
     if let Some(device) = devices.get(idx) {
-        device.read_blocks(start, count, buf)
+        let begin = trace::begin();
+        let result = device.read_blocks(start, count, buf);
+        trace::end(trace::Kind::BlockRead, begin, trace::block_detail(idx, start), count as u64);
+        result
     } else {
         Err(StorageError::NotFound)
     }
@@ -137,12 +186,26 @@ pub fn read(idx: usize, start: u64, count: usize, buf: &mut [u8]) -> Result<(),
 pub fn write(idx: usize, start: u64, count: usize, buf: &[u8]) -> Result<(), StorageError> {
     let devices = BLOCK_DEVICES.lock();
     if let Some(device) = devices.get(idx) {
-        device.write_blocks(start, count, buf)
+        let begin = trace::begin();
+        let result = device.write_blocks(start, count, buf);
+        trace::end(trace::Kind::BlockWrite, begin, trace::block_detail(idx, start), count as u64);
+        result
     } else {
         Err(StorageError::NotFound)
     }
 }
 
+/// Flush every device's write cache, before the power goes
+pub fn flush_all() {
+    for idx in 0..device_count() {
+        if let Some(device) = get_device(idx) {
+            if let Err(e) = device.flush() {
+                warn!("storage", "Cannot flush {}: {:?}", device.name(), e);
+            }
+        }
+    }
+}
+
 /// Print storage device list
 pub fn print_devices() {
     let devices = BLOCK_DEVICES.lock();
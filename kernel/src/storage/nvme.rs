@@ -10,7 +10,7 @@ use alloc::string::String;
 use crate::storage::{BlockDevice, StorageError};
 use crate::drivers::pci::{self, PciDevice};
 use crate::mm::virt_to_phys_u64;
-use crate::println;
+use crate::{debug, info, warn};
 
 /// NVMe PCI class/subclass
 const NVME_CLASS: u8 = 0x01;
@@ -164,7 +164,7 @@ impl NvmeController {
         let cap = self.read_cap();
         let doorbell_stride = 4 << ((cap >> 32) & 0xF); // DSTRD field
 
-        println!("[nvme] CAP: {:016X}", cap);
+        debug!("nvme", "CAP: {:016X}", cap);
 
         // Disable controller
         self.write_reg(REG_CC, 0);
@@ -592,48 +592,56 @@ impl BlockDevice for NvmeNamespace {
     }
 }
 
+static DRIVER: pci::PciDriver = pci::PciDriver {
+    name: "nvme",
+    matches: &[pci::DeviceMatch::class(NVME_CLASS, NVME_SUBCLASS)],
+    probe,
+};
+
 /// Initialize NVMe controller
 pub fn init() {
-    println!("[nvme] Probing for NVMe controllers...");
-
-    if let Some(device) = pci::find_device(NVME_CLASS, NVME_SUBCLASS) {
-        println!("[nvme] Found NVMe controller at {:02X}:{:02X}.{}",
-            device.bus, device.device, device.function);
-
-        // Read BAR0
-        let bar0 = device.read_config(0x10);
-        let base_addr = if bar0 & 1 == 0 {
-            (bar0 & 0xFFFFFFF0) as u64
-        } else {
-            println!("[nvme] Unexpected I/O BAR");
-            return;
-        };
-
-        // Map memory
-        let nvme_base = (base_addr + crate::mm::PHYSICAL_MEMORY_OFFSET) as *mut u8;
-
-        if let Some(mut controller) = NvmeController::new(nvme_base) {
-            if controller.init().is_ok() {
-                let model = core::str::from_utf8(&controller.model)
-                    .unwrap_or("Unknown")
-                    .trim();
-                let serial = core::str::from_utf8(&controller.serial)
-                    .unwrap_or("Unknown")
-                    .trim();
-                
-                println!("[nvme] {} ({})", model, serial);
-                println!("[nvme] Namespace 1: {} sectors ({} MB)",
-                    controller.sector_count,
-                    (controller.sector_count * controller.sector_size) / (1024 * 1024));
-
-                // Create namespace device
-                let ns = NvmeNamespace::from_controller(&mut controller, 1);
-                crate::storage::register_device(Box::new(ns));
-            } else {
-                println!("[nvme] Failed to initialize controller");
-            }
+    info!("nvme", "Probing for NVMe controllers...");
+    pci::register_driver(&DRIVER);
+}
+
+/// Set up a controller and its first namespace
+fn probe(device: &PciDevice) -> bool {
+    info!("nvme", "Found NVMe controller at {:02X}:{:02X}.{}",
+        device.bus, device.device, device.function);
+
+    // Registers are in BAR0
+    let nvme_base = match device.bar(0).and_then(|bar| bar.map()) {
+        Some(base) => base as *mut u8,
+        None => {
+            warn!("nvme", "BAR0 is not memory, or could not be mapped");
+            return false;
         }
+    };
+
+    let mut controller = match NvmeController::new(nvme_base) {
+        Some(controller) => controller,
+        None => return false,
+    };
+    if controller.init().is_err() {
+        warn!("nvme", "Failed to initialize controller");
+        return false;
     }
+    let model = core::str::from_utf8(&controller.model)
+        .unwrap_or("Unknown")
+        .trim();
+    let serial = core::str::from_utf8(&controller.serial)
+        .unwrap_or("Unknown")
+        .trim();
+
+    info!("nvme", "{} ({})", model, serial);
+    info!("nvme", "Namespace 1: {} sectors ({} MB)",
+        controller.sector_count,
+        (controller.sector_count * controller.sector_size) / (1024 * 1024));
+
+    // Create namespace device
+    let ns = NvmeNamespace::from_controller(&mut controller, 1);
+    crate::storage::register_device(Box::new(ns));
+    true
 }
 
 /// Allocate DMA memory
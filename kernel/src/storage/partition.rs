@@ -0,0 +1,124 @@
+//! Partitions
+//!
+//! Enough of MBR and GPT partition tables to find a partition by type,
+//! such as the EFI System Partition, and read and write it as a device of
+//! its own.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+
+use crate::storage::{BlockDevice, StorageError};
+
+/// MBR partition type of the ESP
+const MBR_ESP: u8 = 0xEF;
+/// MBR partition types of FAT32, which small ESPs are often made as
+const MBR_FAT32: [u8; 2] = [0x0B, 0x0C];
+/// MBR partition type of a protective MBR: the disk has a GPT
+const MBR_GPT: u8 = 0xEE;
+
+/// GPT partition type of the ESP, C12A7328-F81F-11D2-BA4B-00A0C93EC93B, as
+/// it is on disk
+const GPT_ESP: [u8; 16] = [
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+];
+
+/// Part of a device, from `start` for `count` blocks
+pub struct Partition {
+    device: Box<dyn BlockDevice>,
+    name: String,
+    start: u64,
+    count: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, start: u64, count: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        if start + count as u64 > self.count {
+            return Err(StorageError::InvalidArgument);
+        }
+        self.device.read_blocks(self.start + start, count, buf)
+    }
+
+    fn write_blocks(&self, start: u64, count: usize, buf: &[u8]) -> Result<(), StorageError> {
+        if start + count as u64 > self.count {
+            return Err(StorageError::InvalidArgument);
+        }
+        self.device.write_blocks(self.start + start, count, buf)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.device.flush()
+    }
+}
+
+/// The ESP on `device`: a GPT partition of the ESP type, or an MBR one of
+/// the ESP or FAT32 type
+pub fn find_esp(device: Box<dyn BlockDevice>) -> Option<Partition> {
+    let block_size = device.block_size();
+    let mut mbr = vec![0u8; block_size];
+    device.read_blocks(0, 1, &mut mbr).ok()?;
+    if block_size < 512 || mbr[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    let entries: alloc::vec::Vec<(u8, u64, u64)> = (0..4)
+        .map(|i| {
+            let entry = &mbr[446 + i * 16..462 + i * 16];
+            let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64;
+            let count = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as u64;
+            (entry[4], start, count)
+        })
+        .filter(|&(_, _, count)| count > 0)
+        .collect();
+
+    let (start, count) = if entries.iter().any(|&(kind, _, _)| kind == MBR_GPT) {
+        find_gpt_esp(device.as_ref())?
+    } else {
+        entries.iter()
+            .find(|&&(kind, _, _)| kind == MBR_ESP)
+            .or_else(|| entries.iter().find(|&&(kind, _, _)| MBR_FAT32.contains(&kind)))
+            .map(|&(_, start, count)| (start, count))?
+    };
+    if start == 0 || start + count > device.block_count() {
+        return None;
+    }
+    let name = format!("{}p", device.name());
+    Some(Partition { device, name, start, count })
+}
+
+/// Start and length of the ESP in the GPT
+fn find_gpt_esp(device: &dyn BlockDevice) -> Option<(u64, u64)> {
+    let block_size = device.block_size();
+    let mut header = vec![0u8; block_size];
+    device.read_blocks(1, 1, &mut header).ok()?;
+    if &header[..8] != b"EFI PART" {
+        return None;
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().ok()?);
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().ok()?) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().ok()?) as usize;
+    if entry_size < 128 || entry_count == 0 {
+        return None;
+    }
+    // 128 entries is as many as disks are made with
+    let len = entry_count.min(128) * entry_size;
+    let mut entries = vec![0u8; len.div_ceil(block_size) * block_size];
+    device.read_blocks(entries_lba, entries.len() / block_size, &mut entries).ok()?;
+    entries[..len].chunks_exact(entry_size).find(|entry| entry[..16] == GPT_ESP).and_then(|entry| {
+        let first = u64::from_le_bytes(entry[32..40].try_into().ok()?);
+        let last = u64::from_le_bytes(entry[40..48].try_into().ok()?);
+        Some((first, last.checked_sub(first)? + 1))
+    })
+}
@@ -0,0 +1,93 @@
+//! Kernel symbol table
+//!
+//! The kernel carries its own function names for backtraces, in the
+//! `.ksymtab` section. The build leaves the section as `TABLE` below, an
+//! empty table, and `tools/embed-symbols.py` then writes the names in from
+//! the ELF symbol table: after the magic and a count, an entry per symbol
+//! by address, `{ address: u64, size: u32, name: u32 }`, and the names,
+//! each ending in a NUL, which `name` is the offset of.
+
+/// Bytes kept for the table
+const TABLE_SIZE: usize = 512 * 1024;
+const ENTRY_SIZE: usize = 16;
+
+#[repr(C)]
+struct Table {
+    magic: [u8; 4],
+    count: u32,
+    data: [u8; TABLE_SIZE - 8],
+}
+
+#[used]
+#[link_section = ".ksymtab"]
+static TABLE: Table = Table { magic: *b"KSYM", count: 0, data: [0; TABLE_SIZE - 8] };
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// The table as the build left it; read through `black_box` so the empty
+/// one in the source is not what the compiler reads
+fn table() -> &'static Table {
+    core::hint::black_box(&TABLE)
+}
+
+/// Number of symbols; 0 if the kernel was not run through the tool
+pub fn count() -> usize {
+    let table = table();
+    let count = table.count as usize;
+    if &table.magic != b"KSYM" || count * ENTRY_SIZE > table.data.len() {
+        return 0;
+    }
+    count
+}
+
+/// Whether `address` is in the kernel's code
+pub fn is_text(address: u64) -> bool {
+    let (start, end) = (core::ptr::addr_of!(__text_start) as u64, core::ptr::addr_of!(__text_end) as u64);
+    (start..end).contains(&address)
+}
+
+/// Address, size and name offset of entry `index`
+fn entry(index: usize) -> (u64, u32, u32) {
+    let bytes = &table().data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+    let address = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+    let size = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    let name = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    (address, size, name)
+}
+
+fn name(count: usize, offset: u32) -> &'static str {
+    let names = &table().data[count * ENTRY_SIZE..];
+    let start = (offset as usize).min(names.len());
+    let names = &names[start..];
+    let end = names.iter().position(|&b| b == 0).unwrap_or(names.len());
+    core::str::from_utf8(&names[..end]).unwrap_or("?")
+}
+
+/// The function `address` is in, and how far into it
+pub fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    let count = count();
+    if count == 0 || !is_text(address) {
+        return None;
+    }
+    // The last symbol at or before the address
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle).0 <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+    let (start, size, offset) = entry(low - 1);
+    if size != 0 && address >= start + size as u64 {
+        return None;
+    }
+    Some((name(count, offset), address - start))
+}
@@ -0,0 +1,100 @@
+//! Syscall errors
+//!
+//! Every syscall fails with one of these, returned to user space as the
+//! negated number, as Linux does; the numbers are Linux's too.
+
+use crate::fs::FsError;
+use crate::mm::shm::ShmError;
+use crate::mm::user::BadAddress;
+
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// EPERM
+    NotPermitted = 1,
+    /// ENOENT
+    NoEntry = 2,
+    /// ESRCH
+    NoProcess = 3,
+    /// EINTR
+    Interrupted = 4,
+    /// EIO
+    Io = 5,
+    /// EBADF
+    BadFd = 9,
+    /// EAGAIN
+    Again = 11,
+    /// ENOMEM
+    NoMemory = 12,
+    /// EACCES
+    Access = 13,
+    /// EFAULT: a pointer the caller has not mapped
+    Fault = 14,
+    /// EBUSY
+    Busy = 16,
+    /// EEXIST
+    Exists = 17,
+    /// ENOTDIR
+    NotDirectory = 20,
+    /// EISDIR
+    IsDirectory = 21,
+    /// EINVAL
+    Invalid = 22,
+    /// EMFILE
+    TooManyFiles = 24,
+    /// EFBIG
+    TooBig = 27,
+    /// EROFS
+    ReadOnly = 30,
+    /// ERANGE
+    Range = 34,
+    /// ENAMETOOLONG
+    NameTooLong = 36,
+    /// ENOSYS
+    NoSys = 38,
+}
+
+impl Errno {
+    /// What the syscall returns in rax
+    pub fn as_return(self) -> i64 {
+        -(self as i64)
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::PermissionDenied => Errno::Access,
+            FsError::NotFound => Errno::NoEntry,
+            FsError::AlreadyExists => Errno::Exists,
+            FsError::NotDirectory => Errno::NotDirectory,
+            FsError::IsDirectory => Errno::IsDirectory,
+            FsError::InvalidArgument => Errno::Invalid,
+            FsError::TooManyOpenFiles => Errno::TooManyFiles,
+            FsError::OutOfMemory => Errno::NoMemory,
+            FsError::NotImplemented => Errno::NoSys,
+            FsError::ReadOnly => Errno::ReadOnly,
+            FsError::IoError | FsError::InvalidFilesystem | FsError::Unknown | FsError::Success => Errno::Io,
+        }
+    }
+}
+
+impl From<ShmError> for Errno {
+    fn from(e: ShmError) -> Self {
+        match e {
+            ShmError::NotFound => Errno::NoEntry,
+            ShmError::Exists => Errno::Exists,
+            ShmError::BadName => Errno::Invalid,
+            ShmError::TooLarge => Errno::TooBig,
+            ShmError::Busy => Errno::Busy,
+            ShmError::OutOfMemory => Errno::NoMemory,
+            ShmError::BadHandle => Errno::BadFd,
+        }
+    }
+}
+
+impl From<BadAddress> for Errno {
+    fn from(_: BadAddress) -> Self {
+        Errno::Fault
+    }
+}
@@ -0,0 +1,84 @@
+//! Per-process syscall filters
+//!
+//! A process may be given an allowlist of syscalls when it is started.
+//! `syscall_handler` checks it before dispatching: a call outside it is
+//! logged and the process is ended. A child gets its parent's filter, and
+//! what it is started with can only narrow that, never widen it. `Exit`
+//! and `ExitThread` are always allowed, so a filtered process can end
+//! itself.
+
+use alloc::vec::Vec;
+
+use super::Syscall;
+
+/// The syscalls a process may make
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallFilter {
+    /// Bit n allows syscall number n
+    allowed: u64,
+}
+
+impl SyscallFilter {
+    /// Only `syscalls`, and exiting
+    pub fn only(syscalls: &[Syscall]) -> Self {
+        let allowed = syscalls.iter()
+            .filter(|&&s| (s as u64) < 64)
+            .fold(0, |mask, &s| mask | 1 << s as u64);
+        SyscallFilter { allowed }
+    }
+
+    /// For web-facing code, such as the browser's renderers: writing
+    /// output, reading input, shared memory for frames and receiving from
+    /// sockets
+    pub fn renderer() -> Self {
+        Self::only(&[Syscall::Read, Syscall::Write, Syscall::Mmap, Syscall::Recv])
+    }
+
+    pub fn allows(&self, num: u64) -> bool {
+        let syscall = Syscall::from_number(num);
+        if matches!(syscall, Syscall::Exit | Syscall::ExitThread) {
+            return true;
+        }
+        syscall != Syscall::Unknown && self.allowed & 1 << num != 0
+    }
+
+    /// What both allow
+    pub fn and(self, other: Self) -> Self {
+        SyscallFilter { allowed: self.allowed & other.allowed }
+    }
+
+    /// Numbers of the syscalls allowed, exiting aside
+    pub fn numbers(&self) -> Vec<u64> {
+        (0..64).filter(|n| self.allowed & 1 << n != 0).collect()
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn allows_only_the_list_and_exiting() -> Result<(), String> {
+        let filter = SyscallFilter::renderer();
+        check!(filter.allows(Syscall::Write as u64));
+        check!(filter.allows(Syscall::Mmap as u64));
+        check!(!filter.allows(Syscall::Open as u64));
+        check!(!filter.allows(Syscall::Chdir as u64));
+        check!(filter.allows(Syscall::Exit as u64));
+        check!(!filter.allows(200));
+        check_eq!(filter.numbers(), alloc::vec![1, 2, 5, 20]);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn combining_only_narrows() -> Result<(), String> {
+        let wide = SyscallFilter::only(&[Syscall::Write, Syscall::Open]);
+        let narrowed = wide.and(SyscallFilter::renderer());
+        check!(narrowed.allows(Syscall::Write as u64));
+        check!(!narrowed.allows(Syscall::Open as u64));
+        check!(!narrowed.allows(Syscall::Read as u64));
+        Ok(())
+    }
+}
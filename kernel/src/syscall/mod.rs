@@ -1,9 +1,23 @@
 //! System call interface
 //!
-//! Implements system calls for user space programs.
+//! Implements system calls for user space programs. Pointers they are
+//! given are user addresses, taken as a `UserSlice` or `UserPtr` and
+//! checked against the caller's mapped areas before they are used.
+//! Syscalls return a value, or an `Errno` negated. A process with a
+//! `SyscallFilter` is ended when it makes a call the filter leaves out.
 
+pub mod errno;
+pub mod filter;
+pub mod user;
+
+use alloc::vec;
+
+pub use errno::Errno;
+use user::UserSlice;
+use crate::mm::shm;
 use crate::println;
 use crate::print;
+use crate::{debug, info, warn};
 
 /// System call numbers
 #[repr(u64)]
@@ -77,6 +91,14 @@ pub enum Syscall {
     CreateThread = 32,
     /// Exit thread
     ExitThread = 33,
+    /// Fill buffer with random bytes
+    GetRandom = 34,
+    /// Open or create a shared memory object
+    ShmOpen = 35,
+    /// Set a shared memory object's size
+    Ftruncate = 36,
+    /// Remove a shared memory object's name
+    ShmUnlink = 37,
     /// Unknown syscall
     Unknown = 0xFF,
 }
@@ -119,6 +141,10 @@ impl Syscall {
             31 => Self::GetTid,
             32 => Self::CreateThread,
             33 => Self::ExitThread,
+            34 => Self::GetRandom,
+            35 => Self::ShmOpen,
+            36 => Self::Ftruncate,
+            37 => Self::ShmUnlink,
             _ => Self::Unknown,
         }
     }
@@ -142,14 +168,14 @@ pub type SyscallResult = i64;
 
 /// Initialize system call interface
 pub fn init() {
-    println!("[syscall] Initializing system call interface...");
+    info!("syscall", "Initializing system call interface...");
 
     // Setup syscall MSRs (IA32_STAR, IA32_LSTAR, IA32_FMASK)
     unsafe {
         setup_syscall_msrs();
     }
 
-    println!("[syscall] System call interface initialized");
+    info!("syscall", "System call interface initialized");
 }
 
 /// Setup syscall MSRs
@@ -168,8 +194,9 @@ unsafe fn setup_syscall_msrs() {
     // IA32_LSTAR - syscall entry point
     let lstar = syscall_entry as u64;
 
-    // IA32_FMASK - RFLAGS mask (clear interrupt flag)
-    let fmask = 0x200; // Clear IF
+    // IA32_FMASK - RFLAGS mask: clear IF, and AC so user space cannot
+    // turn SMAP off for the kernel
+    let fmask = 0x200 | 0x40000;
 
     // Write MSRs
     core::arch::asm!(
@@ -216,7 +243,11 @@ unsafe fn setup_syscall_msrs() {
 
 /// System call entry point
 ///
-/// This is called by the SYSCALL instruction.
+/// This is called by the SYSCALL instruction, with the number in rax and
+/// the arguments in rdi, rsi, rdx, r10, r8 and r9, as on Linux. They are
+/// pushed so that they form a `SyscallArgs` on the kernel stack, which
+/// the handler is given a pointer to; all but rax, rcx and r11 reach user
+/// space again unchanged.
 #[naked]
 unsafe extern "C" fn syscall_entry() {
     core::arch::naked_asm!(
@@ -230,33 +261,30 @@ unsafe extern "C" fn syscall_entry() {
         // Push user state
         "push r11",             // Save RFLAGS
         "push rcx",             // Save RIP (return address)
+        "sub rsp, 8",           // Keep the stack 16-byte aligned at the call
         
-        // Save remaining registers
-        "push rax",
-        "push rdx",
-        "push rsi",
-        "push rdi",
-        "push r8",
-        "push r9",
-        "push r10",
+        // Build SyscallArgs, last field first
+        "push r9",              // arg6
+        "push r8",              // arg5
+        "push r10",             // arg4
+        "push rdx",             // arg3
+        "push rsi",             // arg2
+        "push rdi",             // arg1
+        "push rax",             // num
         
         // Call handler
-        "mov rdi, rax",         // Syscall number
-        "mov rsi, rdi",         // Arg1
-        "mov rdx, rsi",         // Arg2
-        "mov rcx, rdx",         // Arg3
-        "mov r8, r10",          // Arg4
-        "mov r9, r8",           // Arg5
+        "mov rdi, rsp",
         "call {handler}",
         
         // Restore registers
-        "pop r10",
-        "pop r9",
-        "pop r8",
+        "add rsp, 8",           // Skip num (return value is in rax)
         "pop rdi",
         "pop rsi",
         "pop rdx",
-        "add rsp, 8",           // Skip rax (return value is in rax)
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "add rsp, 8",
         
         // Restore user state
         "pop rcx",              // Restore RIP
@@ -272,34 +300,58 @@ unsafe extern "C" fn syscall_entry() {
     );
 }
 
+/// Exit code of a process its syscall filter ended, as a shell reports
+/// one killed by SIGSYS
+pub const FILTERED_EXIT_CODE: i32 = 128 + 31;
+
 /// System call handler
-extern "C" fn syscall_handler(
-    num: u64,
-    arg1: u64,
-    arg2: u64,
-    arg3: u64,
-    arg4: u64,
-    arg5: u64,
-) -> i64 {
-    let syscall = Syscall::from_number(num);
+///
+/// Returns the syscall's result, or its error negated.
+extern "C" fn syscall_handler(args: &SyscallArgs) -> i64 {
+    if let Some(pid) = crate::process::current_pid() {
+        if !crate::process::syscall_allowed(pid, args.num) {
+            warn!("syscall", "Process {} made {:?}({}), which its filter does not allow; ending it",
+                pid.as_u64(), Syscall::from_number(args.num), args.num);
+            crate::process::exit_process(pid, FILTERED_EXIT_CODE);
+            return Errno::NoSys.as_return();
+        }
+    }
+    match dispatch(args) {
+        Ok(value) => value,
+        Err(errno) => errno.as_return(),
+    }
+}
+
+fn dispatch(args: &SyscallArgs) -> Result<i64, Errno> {
+    let syscall = Syscall::from_number(args.num);
 
     match syscall {
-        Syscall::Exit => sys_exit(arg1 as i32),
-        Syscall::Write => sys_write(arg1 as i32, arg2 as *const u8, arg3 as usize),
-        Syscall::Read => sys_read(arg1 as i32, arg2 as *mut u8, arg3 as usize),
+        Syscall::Exit => sys_exit(args.arg1 as i32),
+        Syscall::Write => sys_write(args.arg1 as i32, UserSlice::new(args.arg2, args.arg3 as usize)),
+        Syscall::Read => sys_read(args.arg1 as i32, UserSlice::new(args.arg2, args.arg3 as usize)),
         Syscall::GetPid => sys_getpid(),
         Syscall::GetTid => sys_gettid(),
+        Syscall::GetTime => sys_gettime(args.arg1),
         Syscall::Yield => sys_yield(),
-        Syscall::Sleep => sys_sleep(arg1),
+        Syscall::Sleep => sys_sleep(args.arg1),
+        Syscall::GetRandom => sys_getrandom(UserSlice::new(args.arg1, args.arg2 as usize), args.arg3 as u32),
+        Syscall::GetCwd => sys_getcwd(UserSlice::new(args.arg1, args.arg2 as usize)),
+        Syscall::Chdir => sys_chdir(UserSlice::new(args.arg1, args.arg2 as usize)),
+        Syscall::ShmOpen => sys_shm_open(UserSlice::new(args.arg1, args.arg2 as usize), args.arg3 as u32),
+        Syscall::Ftruncate => sys_ftruncate(args.arg1 as i32, args.arg2),
+        Syscall::ShmUnlink => sys_shm_unlink(UserSlice::new(args.arg1, args.arg2 as usize)),
+        Syscall::Mmap => sys_mmap(args.arg1, args.arg2, args.arg3 as u32, args.arg4 as u32, args.arg5 as i32, args.arg6),
+        Syscall::Munmap => sys_munmap(args.arg1),
+        Syscall::Close => sys_close(args.arg1 as i32),
         _ => {
-            println!("[syscall] Unimplemented syscall: {:?}({})", syscall, num);
-            -1
+            warn!("syscall", "Unimplemented syscall: {:?}({})", syscall, args.num);
+            Err(Errno::NoSys)
         }
     }
 }
 
 /// Exit system call
-fn sys_exit(code: i32) -> i64 {
+fn sys_exit(code: i32) -> Result<i64, Errno> {
     use crate::process;
     use crate::process::scheduler;
 
@@ -311,77 +363,335 @@ fn sys_exit(code: i32) -> i64 {
 
     if let Some(_pid) = pid {
         // Process exit - just print for now
-        println!("[syscall] Process exit with code {}", code);
+        debug!("syscall", "Process exit with code {}", code);
     }
 
-    0
+    Ok(0)
 }
 
+/// Largest number of bytes a single `Write` takes
+pub const WRITE_MAX: usize = 64 * 1024;
+
 /// Write system call
-fn sys_write(fd: i32, buf: *const u8, count: usize) -> i64 {
+fn sys_write(fd: i32, buf: UserSlice) -> Result<i64, Errno> {
     // For now, just write to console
-    if fd == 1 || fd == 2 { // stdout or stderr
-        unsafe {
-            let slice = core::slice::from_raw_parts(buf, count);
-            if let Ok(s) = core::str::from_utf8(slice) {
-                print!("{}", s);
-            }
-        }
-        count as i64
-    } else {
-        -1
+    if fd != 1 && fd != 2 { // stdout or stderr
+        return Err(Errno::BadFd);
     }
+    let buf = buf.truncate(WRITE_MAX);
+    let bytes = buf.read()?;
+    // Malformed UTF-8 shows as U+FFFD rather than losing the whole write
+    let text: alloc::string::String = crate::graphics::font::decode_utf8(&bytes).collect();
+    print!("{}", text);
+    Ok(buf.len() as i64)
 }
 
 /// Read system call
-fn sys_read(_fd: i32, _buf: *mut u8, _count: usize) -> i64 {
+fn sys_read(_fd: i32, _buf: UserSlice) -> Result<i64, Errno> {
     // TODO: Implement proper file reading
-    -1
+    Err(Errno::NoSys)
 }
 
 /// Get process ID
-fn sys_getpid() -> i64 {
-    use crate::process::scheduler;
-    
-    scheduler::current_thread()
-        .map(|tid| {
-            let threads = crate::process::THREADS.lock();
-            threads.get(&tid.as_u64()).map(|t| t.pid.as_u64() as i64)
-                .unwrap_or(-1)
-        })
-        .unwrap_or(-1)
+fn sys_getpid() -> Result<i64, Errno> {
+    crate::process::current_pid()
+        .map(|pid| pid.as_u64() as i64)
+        .ok_or(Errno::NoProcess)
 }
 
 /// Get thread ID
-fn sys_gettid() -> i64 {
+fn sys_gettid() -> Result<i64, Errno> {
     use crate::process::scheduler;
     
     scheduler::current_thread()
         .map(|tid| tid.as_u64() as i64)
-        .unwrap_or(-1)
+        .ok_or(Errno::NoProcess)
+}
+
+/// `GetTime` clocks: Unix time, and time since boot
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// Nanoseconds by `clock`
+fn sys_gettime(clock: u64) -> Result<i64, Errno> {
+    match clock {
+        CLOCK_REALTIME => Ok(crate::drivers::rtc::unix_ns() as i64),
+        CLOCK_MONOTONIC => Ok(crate::drivers::timer::now_ns() as i64),
+        _ => Err(Errno::Invalid),
+    }
 }
 
 /// Yield system call
-fn sys_yield() -> i64 {
+fn sys_yield() -> Result<i64, Errno> {
     unsafe {
         crate::process::scheduler::yield_current();
     }
-    0
+    Ok(0)
 }
 
 /// Sleep system call
-fn sys_sleep(ticks: u64) -> i64 {
+fn sys_sleep(ticks: u64) -> Result<i64, Errno> {
     unsafe {
         crate::process::scheduler::sleep_current(ticks);
     }
-    0
+    Ok(0)
+}
+
+/// `GetRandom` flag: fail instead of waiting when the budget is exhausted
+pub const GRND_NONBLOCK: u32 = 1;
+
+/// Largest number of bytes returned by a single `GetRandom` call
+pub const GETRANDOM_MAX: usize = 4096;
+
+/// Get random bytes
+///
+/// Returns the number of bytes written, which may be less than `count`
+/// when the request exceeds `GETRANDOM_MAX` or the caller's rate budget.
+/// With `GRND_NONBLOCK` and no budget left it fails with `Again`.
+fn sys_getrandom(buf: UserSlice, flags: u32) -> Result<i64, Errno> {
+    use crate::process;
+
+    let buf = buf.truncate(GETRANDOM_MAX);
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let count = buf.len();
+
+    let pid = process::current_pid();
+
+    let granted = loop {
+        let granted = match pid {
+            Some(pid) => {
+                let mut processes = process::PROCESSES.lock();
+                match processes.get_mut(&pid.as_u64()) {
+                    Some(p) => p.random_budget.take(count as u64) as usize,
+                    None => count,
+                }
+            }
+            None => count,
+        };
+        if granted > 0 || flags & GRND_NONBLOCK != 0 {
+            break granted;
+        }
+        // Budget exhausted: wait for it to refill
+        unsafe {
+            crate::process::scheduler::sleep_current(10);
+        }
+    };
+
+    if granted == 0 {
+        return Err(Errno::Again);
+    }
+
+    let mut bytes = vec![0u8; granted];
+    crate::crypto::rng::fill_bytes(&mut bytes);
+    buf.write(&bytes)?;
+    Ok(granted as i64)
+}
+
+/// Get the working directory
+///
+/// Copies the path, ending in a NUL, into `buf` and returns its length
+/// without the NUL; fails with `Range` if `buf` is too small.
+fn sys_getcwd(buf: UserSlice) -> Result<i64, Errno> {
+    let cwd = match crate::process::current_pid().and_then(crate::process::environment) {
+        Some(environment) => environment.cwd,
+        None => return Err(Errno::NoProcess),
+    };
+    let mut bytes = cwd.clone().into_bytes();
+    bytes.push(0);
+    buf.write(&bytes)?;
+    Ok(cwd.len() as i64)
+}
+
+/// Longest path `Chdir` takes
+const PATH_MAX: usize = 4096;
+
+/// Change the working directory to the bytes in `path`
+fn sys_chdir(path: UserSlice) -> Result<i64, Errno> {
+    use crate::process;
+
+    if path.len() > PATH_MAX {
+        return Err(Errno::NameTooLong);
+    }
+    let bytes = path.read()?;
+    let path = core::str::from_utf8(&bytes).map_err(|_| Errno::Invalid)?;
+    let pid = process::current_pid().ok_or(Errno::NoProcess)?;
+    // The directory is looked up before the process table is locked
+    let mut environment = process::environment(pid).ok_or(Errno::NoProcess)?;
+    environment.chdir(path)?;
+    process::update_environment(pid, |e| e.cwd = environment.cwd).map_err(|_| Errno::NoProcess)?;
+    Ok(0)
+}
+
+/// `ShmOpen` flags
+pub const O_CREAT: u32 = 0x40;
+pub const O_EXCL: u32 = 0x80;
+
+/// The name in `name`, for the shm calls
+fn shm_name(name: UserSlice) -> Result<alloc::string::String, Errno> {
+    if name.len() > shm::NAME_MAX + 1 {
+        return Err(Errno::NameTooLong);
+    }
+    let bytes = name.read()?;
+    alloc::string::String::from_utf8(bytes).map_err(|_| Errno::Invalid)
+}
+
+/// Open the shared memory object called `name`, creating it empty with
+/// `O_CREAT`; returns a handle for `Ftruncate`, `Mmap` and `Close`
+fn sys_shm_open(name: UserSlice, flags: u32) -> Result<i64, Errno> {
+    let name = shm_name(name)?;
+    let pid = crate::process::current_pid().ok_or(Errno::NoProcess)?;
+    let object = shm::open(&name, flags & O_CREAT != 0, flags & O_EXCL != 0)?;
+    Ok(shm::add_handle(pid, object) as i64)
+}
+
+/// Size a shared memory object; it cannot shrink while mapped
+fn sys_ftruncate(handle: i32, size: u64) -> Result<i64, Errno> {
+    let pid = crate::process::current_pid().ok_or(Errno::NoProcess)?;
+    shm::handle(pid, handle)?.resize(size)?;
+    Ok(0)
+}
+
+fn sys_shm_unlink(name: UserSlice) -> Result<i64, Errno> {
+    shm::unlink(&shm_name(name)?)?;
+    Ok(0)
+}
+
+/// `Mmap` protection and flags
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_FIXED: u32 = 0x10;
+
+/// Map `len` bytes of the shared memory object behind `handle`, from its
+/// start, at `addr` with `MAP_FIXED` or wherever there is room; returns
+/// the address
+fn sys_mmap(addr: u64, len: u64, prot: u32, flags: u32, handle: i32, offset: u64) -> Result<i64, Errno> {
+    if flags & MAP_SHARED == 0 || offset != 0 || len == 0 {
+        return Err(Errno::Invalid);
+    }
+    if prot & PROT_EXEC != 0 {
+        return Err(Errno::NotPermitted);
+    }
+    let pid = crate::process::current_pid().ok_or(Errno::NoProcess)?;
+    let object = shm::handle(pid, handle)?;
+    if len > object.size() {
+        return Err(Errno::Invalid);
+    }
+    let virt = if flags & MAP_FIXED != 0 { Some(addr) } else { None };
+    match crate::process::map_shared(pid, virt, &object, len, prot & PROT_WRITE != 0) {
+        Ok(start) => Ok(start as i64),
+        Err(crate::process::ProcessError::OutOfMemory) => Err(Errno::NoMemory),
+        Err(_) => Err(Errno::Invalid),
+    }
+}
+
+/// Undo the mapping `Mmap` returned `addr` for
+fn sys_munmap(addr: u64) -> Result<i64, Errno> {
+    let pid = crate::process::current_pid().ok_or(Errno::NoProcess)?;
+    crate::process::unmap_shared(pid, addr).map_err(|_| Errno::Invalid)?;
+    Ok(0)
+}
+
+fn sys_close(handle: i32) -> Result<i64, Errno> {
+    let pid = crate::process::current_pid().ok_or(Errno::NoProcess)?;
+    shm::close(pid, handle)?;
+    Ok(0)
 }
 
 /// Print syscall statistics
 pub fn print_stats() {
     println!("System Call Statistics:");
-    println!("  Implemented: 7/34");
+    println!("  Implemented: 17/38");
     println!("  - exit, write, read");
-    println!("  - getpid, gettid");
+    println!("  - getpid, gettid, gettime");
     println!("  - yield, sleep");
+    println!("  - getrandom");
+    println!("  - getcwd, chdir");
+    println!("  - shm_open, ftruncate, shm_unlink, mmap, munmap, close");
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn call(num: Syscall, arg1: u64, arg2: u64, arg3: u64) -> i64 {
+        syscall_handler(&SyscallArgs { num: num as u64, arg1, arg2, arg3, arg4: 0, arg5: 0, arg6: 0 })
+    }
+
+    #[kernel_test]
+    fn args_are_laid_out_in_push_order() -> Result<(), String> {
+        // syscall_entry pushes r9, r8, r10, rdx, rsi, rdi, rax
+        check_eq!(core::mem::offset_of!(SyscallArgs, num), 0);
+        check_eq!(core::mem::offset_of!(SyscallArgs, arg1), 8);
+        check_eq!(core::mem::offset_of!(SyscallArgs, arg3), 24);
+        check_eq!(core::mem::offset_of!(SyscallArgs, arg4), 32);
+        check_eq!(core::mem::offset_of!(SyscallArgs, arg6), 48);
+        check_eq!(core::mem::size_of::<SyscallArgs>(), 56);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn errors_come_back_negated() -> Result<(), String> {
+        check_eq!(call(Syscall::Fork, 0, 0, 0), -38);
+        check_eq!(call(Syscall::GetTime, 7, 0, 0), Errno::Invalid.as_return());
+        check!(call(Syscall::GetTime, CLOCK_MONOTONIC, 0, 0) >= 0);
+        check_eq!(call(Syscall::Write, 9, 0, 0), Errno::BadFd.as_return());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn pointers_outside_the_callers_areas_fault() -> Result<(), String> {
+        let text = b"kernel memory";
+        check_eq!(call(Syscall::Write, 1, text.as_ptr() as u64, text.len() as u64), -14);
+        check_eq!(call(Syscall::Write, 1, crate::mm::address_space::USER_START, 8), -14);
+        check_eq!(call(Syscall::GetRandom, u64::MAX - 2, 16, GRND_NONBLOCK as u64), -14);
+        check_eq!(user::UserPtr::<u64>::new(text.as_ptr() as u64).read(), Err(Errno::Fault));
+        // Nothing is copied for an empty write, so any pointer will do
+        check_eq!(call(Syscall::Write, 1, 0, 0), 0);
+        check_eq!(UserSlice::new(0, 4).write(b"too long"), Err(Errno::Range));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn filters_are_inherited_and_checked() -> Result<(), String> {
+        use crate::process;
+        let renderer = process::exec("filter-test", None, process::Environment::new(), Some(filter::SyscallFilter::renderer()))
+            .map_err(|e| alloc::format!("{:?}", e))?;
+        let child = process::create_process("filter-child", Some(renderer)).map_err(|e| alloc::format!("{:?}", e))?;
+        let narrower = filter::SyscallFilter::only(&[Syscall::Write, Syscall::Chdir]);
+        let grandchild = process::exec("filter-narrow", Some(child), process::Environment::new(), Some(narrower))
+            .map_err(|e| alloc::format!("{:?}", e))?;
+        check!(process::syscall_allowed(renderer, Syscall::Recv as u64));
+        check!(!process::syscall_allowed(renderer, Syscall::GetCwd as u64));
+        check!(!process::syscall_allowed(child, Syscall::GetCwd as u64));
+        check!(process::syscall_allowed(grandchild, Syscall::Write as u64));
+        check!(!process::syscall_allowed(grandchild, Syscall::Chdir as u64));
+        check!(!process::syscall_allowed(grandchild, Syscall::Recv as u64));
+        check!(process::syscall_allowed(grandchild, Syscall::Exit as u64));
+        for pid in [grandchild, child, renderer] {
+            let _ = process::terminate(pid);
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn shm_calls_check_their_arguments() -> Result<(), String> {
+        let mmap = |prot: u32, flags: u32, handle: i32| {
+            let args = SyscallArgs { num: Syscall::Mmap as u64, arg1: 0, arg2: 0x1000, arg3: prot as u64, arg4: flags as u64, arg5: handle as u64, arg6: 0 };
+            syscall_handler(&args)
+        };
+        check_eq!(mmap(PROT_READ, 0, 3), Errno::Invalid.as_return());
+        check_eq!(mmap(PROT_READ | PROT_EXEC, MAP_SHARED, 3), Errno::NotPermitted.as_return());
+        check_eq!(call(Syscall::ShmOpen, 0, shm::NAME_MAX as u64 + 2, O_CREAT as u64), Errno::NameTooLong.as_return());
+        if crate::process::current_pid().is_some() {
+            check_eq!(call(Syscall::Close, 999, 0, 0), Errno::BadFd.as_return());
+            check_eq!(mmap(PROT_READ, MAP_SHARED, 999), Errno::BadFd.as_return());
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,111 @@
+//! User pointers handed to syscalls
+//!
+//! A `UserSlice` or `UserPtr` is only an address until it is used; each
+//! read or write first checks the range against the calling process's
+//! mapped areas, so a pointer into the kernel, into a hole, or at a
+//! read-only page fails with `Fault` before anything is copied. The copy
+//! itself still goes through `mm::user`, which catches the page being
+//! unmapped in between.
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use super::errno::Errno;
+use crate::mm::user::{copy_to_user, read_user};
+use crate::process;
+
+/// Types any bytes are a valid value of, so they can be read from user
+/// memory
+///
+/// # Safety
+/// Every bit pattern of the type's size must be a valid value.
+pub unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u16 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl Plain for i32 {}
+unsafe impl Plain for i64 {}
+
+/// Check that the caller has the range mapped, writable if `write`
+fn check(addr: u64, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    match process::current_pid() {
+        Some(pid) if process::user_range_mapped(pid, addr, len, write) => Ok(()),
+        _ => Err(Errno::Fault),
+    }
+}
+
+/// `len` bytes at a user address
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: u64,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: u64, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The first `len` bytes, or all of them if fewer
+    pub fn truncate(self, len: usize) -> Self {
+        UserSlice { addr: self.addr, len: self.len.min(len) }
+    }
+
+    /// Copy the bytes in
+    pub fn read(&self) -> Result<Vec<u8>, Errno> {
+        check(self.addr, self.len, false)?;
+        Ok(read_user(self.addr, self.len)?)
+    }
+
+    /// Copy `bytes` out to the start of the slice; `Range` if they do not
+    /// fit
+    pub fn write(&self, bytes: &[u8]) -> Result<(), Errno> {
+        if bytes.len() > self.len {
+            return Err(Errno::Range);
+        }
+        check(self.addr, bytes.len(), true)?;
+        Ok(copy_to_user(self.addr, bytes)?)
+    }
+}
+
+/// One `T` at a user address
+#[derive(Debug, Clone, Copy)]
+pub struct UserPtr<T: Plain> {
+    addr: u64,
+    _type: PhantomData<T>,
+}
+
+impl<T: Plain> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        UserPtr { addr, _type: PhantomData }
+    }
+
+    fn slice(&self) -> UserSlice {
+        UserSlice::new(self.addr, core::mem::size_of::<T>())
+    }
+
+    pub fn read(&self) -> Result<T, Errno> {
+        let bytes = self.slice().read()?;
+        Ok(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), Errno> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&value as *const T as *const u8, core::mem::size_of::<T>())
+        };
+        self.slice().write(bytes)
+    }
+}
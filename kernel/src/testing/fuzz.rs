@@ -0,0 +1,302 @@
+//! Fuzzing of the kernel's parsers
+//!
+//! Each target is a parser of untrusted bytes, with a small corpus of
+//! well-formed inputs. A round mutates one of them, flipping bits, planting
+//! boundary values, cutting, growing and splicing, or makes bytes up
+//! outright, and hands the result to the parser, which has to turn it down
+//! rather than panic, read out of bounds or hang.
+//!
+//! Rounds are repeatable: each draws from a generator seeded by its own
+//! seed. If one panics, the panic handler names the target and that seed,
+//! and `fuzz TARGET 1 SEED` feeds the same input again.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::fs::{ext2, fat32};
+use crate::net::{dns, http};
+use crate::tls::TlsConnection;
+
+/// Longest input made
+const MAX_INPUT: usize = 4096;
+/// Values that tend to sit on a boundary
+const INTERESTING: [u32; 9] = [0, 1, 2, 0x7F, 0x80, 0xFF, 0xFFFF, 0x7FFF_FFFF, 0xFFFF_FFFF];
+
+/// A parser to fuzz
+pub struct Target {
+    pub name: &'static str,
+    /// Well-formed inputs to start from
+    corpus: fn() -> Vec<Vec<u8>>,
+    /// Parse the input; true if it was accepted
+    parse: fn(&[u8]) -> bool,
+}
+
+pub static TARGETS: [Target; 5] = [
+    Target { name: "fat32", corpus: fat32_corpus, parse: |data| fat32::BootSector::parse(&sized(data)).is_ok() },
+    Target { name: "ext2", corpus: ext2_corpus, parse: |data| ext2::Superblock::parse(&sized(data)).is_ok() },
+    Target { name: "http", corpus: http_corpus, parse: |data| http::Response::parse(data).is_ok() },
+    Target { name: "dns", corpus: dns_corpus, parse: |data| dns::parse_response(data, DNS_ID).is_some() },
+    Target { name: "tls", corpus: tls_corpus, parse: |data| TlsConnection::new().process_server_hello(data).is_ok() },
+];
+
+/// Index in `TARGETS` of the target being fuzzed, or `usize::MAX`
+static CURRENT_TARGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Seed of the round being run
+static CURRENT_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// How a target stood up to a run
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Outcome {
+    pub rounds: u64,
+    pub accepted: u64,
+}
+
+/// xorshift64*: small, quick and the same everywhere, which is all a
+/// fuzzer needs
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero would stay zero
+        Self(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number below `n`, which is not 0
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+pub fn target(name: &str) -> Option<&'static Target> {
+    TARGETS.iter().find(|target| target.name == name)
+}
+
+/// The seed of round `round` of a run from `seed`
+pub fn round_seed(seed: u64, round: u64) -> u64 {
+    Rng::new(seed ^ round.wrapping_mul(0xD6E8_FEB8_6659_FD93)).next()
+}
+
+/// Run `rounds` rounds of `target` from `seed`
+pub fn run(target: &Target, rounds: u64, seed: u64) -> Outcome {
+    let index = TARGETS.iter().position(|t| core::ptr::eq(t, target)).unwrap_or(usize::MAX);
+    let corpus = (target.corpus)();
+    let mut outcome = Outcome::default();
+    CURRENT_TARGET.store(index, Ordering::Relaxed);
+    for round in 0..rounds {
+        // One round on its own repeats with a seed of that round's seed
+        let round_seed = if rounds == 1 { seed } else { round_seed(seed, round) };
+        CURRENT_SEED.store(round_seed, Ordering::Relaxed);
+        let input = make_input(&corpus, &mut Rng::new(round_seed));
+        if (target.parse)(&input) {
+            outcome.accepted += 1;
+        }
+        outcome.rounds += 1;
+    }
+    CURRENT_TARGET.store(usize::MAX, Ordering::Relaxed);
+    outcome
+}
+
+/// The target being fuzzed and the seed of the round, for the panic
+/// handler
+pub fn current() -> Option<(&'static str, u64)> {
+    let target = TARGETS.get(CURRENT_TARGET.load(Ordering::Relaxed))?;
+    Some((target.name, CURRENT_SEED.load(Ordering::Relaxed)))
+}
+
+/// One input: mostly a mutated corpus entry, sometimes random bytes
+fn make_input(corpus: &[Vec<u8>], rng: &mut Rng) -> Vec<u8> {
+    if corpus.is_empty() || rng.below(8) == 0 {
+        let mut input = vec![0u8; rng.below(MAX_INPUT + 1)];
+        input.iter_mut().for_each(|byte| *byte = rng.next() as u8);
+        return input;
+    }
+    let mut input = corpus[rng.below(corpus.len())].clone();
+    for _ in 0..1 + rng.below(8) {
+        mutate(&mut input, corpus, rng);
+    }
+    input.truncate(MAX_INPUT);
+    input
+}
+
+fn mutate(input: &mut Vec<u8>, corpus: &[Vec<u8>], rng: &mut Rng) {
+    if input.is_empty() {
+        input.push(rng.next() as u8);
+        return;
+    }
+    let at = rng.below(input.len());
+    match rng.below(8) {
+        // Flip a bit
+        0 => input[at] ^= 1 << rng.below(8),
+        // A random byte
+        1 => input[at] = rng.next() as u8,
+        // A boundary value, in 1, 2 or 4 bytes either way round
+        2 => {
+            let value = INTERESTING[rng.below(INTERESTING.len())];
+            let width = [1, 2, 4][rng.below(3)];
+            let bytes = if rng.below(2) == 0 { value.to_le_bytes() } else { value.to_be_bytes() };
+            let skip = if rng.below(2) == 0 { 0 } else { 4 - width };
+            for (i, &byte) in bytes[skip..skip + width].iter().enumerate() {
+                if let Some(slot) = input.get_mut(at + i) {
+                    *slot = byte;
+                }
+            }
+        }
+        // Cut the end off
+        3 => input.truncate(at),
+        // Take some out
+        4 => {
+            let len = rng.below(input.len() - at) + 1;
+            input.drain(at..at + len);
+        }
+        // Put random bytes in
+        5 => {
+            let len = rng.below(64) + 1;
+            let bytes: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            input.splice(at..at, bytes);
+        }
+        // Repeat a piece
+        6 => {
+            let len = rng.below(input.len() - at) + 1;
+            let piece = input[at..at + len].to_vec();
+            input.splice(at..at, piece);
+        }
+        // Splice in the end of another corpus entry
+        _ => {
+            let other = &corpus[rng.below(corpus.len())];
+            if !other.is_empty() {
+                let from = rng.below(other.len());
+                input.truncate(at);
+                input.extend_from_slice(&other[from..]);
+            }
+        }
+    }
+}
+
+/// The input as the fixed-size sector a superblock parser takes
+fn sized<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut sector = [0u8; N];
+    let len = data.len().min(N);
+    sector[..len].copy_from_slice(&data[..len]);
+    sector
+}
+
+fn put_u16(data: &mut [u8], at: usize, value: u16) {
+    data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], at: usize, value: u32) {
+    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn fat32_corpus() -> Vec<Vec<u8>> {
+    let mut sector = vec![0u8; 512];
+    sector[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"WEBBOS  ");
+    put_u16(&mut sector, 11, 512);
+    sector[13] = 8;
+    put_u16(&mut sector, 14, 32);
+    sector[16] = 2;
+    sector[21] = 0xF8;
+    put_u32(&mut sector, 32, 131_072);
+    put_u32(&mut sector, 36, 128);
+    put_u32(&mut sector, 44, 2);
+    put_u16(&mut sector, 48, 1);
+    put_u16(&mut sector, 50, 6);
+    sector[66] = 0x29;
+    sector[71..82].copy_from_slice(b"WEBBOS     ");
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[510..].copy_from_slice(&[0x55, 0xAA]);
+    vec![sector]
+}
+
+fn ext2_corpus() -> Vec<Vec<u8>> {
+    let mut superblock = vec![0u8; 1024];
+    put_u32(&mut superblock, 0, 256);
+    put_u32(&mut superblock, 4, 1024);
+    put_u32(&mut superblock, 20, 1);
+    put_u32(&mut superblock, 32, 8192);
+    put_u32(&mut superblock, 36, 8192);
+    put_u32(&mut superblock, 40, 256);
+    put_u16(&mut superblock, 56, 0xEF53);
+    put_u16(&mut superblock, 58, 1);
+    put_u32(&mut superblock, 76, 1);
+    put_u32(&mut superblock, 84, 11);
+    put_u16(&mut superblock, 88, 128);
+    let mut large_blocks = superblock.clone();
+    put_u32(&mut large_blocks, 24, 2);
+    put_u16(&mut large_blocks, 88, 256);
+    vec![superblock, large_blocks]
+}
+
+fn http_corpus() -> Vec<Vec<u8>> {
+    vec![
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\nhello".to_vec(),
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n".to_vec(),
+        b"HTTP/1.0 302 Found\r\nLocation: /\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\n".to_vec(),
+    ]
+}
+
+/// The query ID the DNS inputs answer
+const DNS_ID: u16 = 0x1234;
+
+fn dns_corpus() -> Vec<Vec<u8>> {
+    let mut reply = Vec::new();
+    // Header: a response with one question and one answer
+    reply.extend_from_slice(&DNS_ID.to_be_bytes());
+    reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+    // example.com, A, IN
+    reply.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+    // The same name by pointer, A, IN, TTL, and the address
+    reply.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4, 93, 184, 216, 34]);
+    vec![reply]
+}
+
+fn tls_corpus() -> Vec<Vec<u8>> {
+    let mut body = Vec::new();
+    // Legacy version, random and a 32-byte session ID
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&[0x5A; 32]);
+    body.push(32);
+    body.extend_from_slice(&[0xA5; 32]);
+    // ChaCha20-Poly1305, no compression, supported_versions: TLS 1.3
+    body.extend_from_slice(&[0x13, 0x03, 0x00]);
+    body.extend_from_slice(&[0x00, 0x06, 0x00, 0x2B, 0x00, 0x02, 0x03, 0x04]);
+    let mut hello = vec![0x02, 0, (body.len() >> 8) as u8, body.len() as u8];
+    hello.extend_from_slice(&body);
+    vec![hello]
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use alloc::string::String;
+
+    /// A short run of every target; a parser that fails it panics
+    #[kernel_test]
+    fn parsers_survive_fuzzing() -> Result<(), String> {
+        for target in TARGETS.iter() {
+            run(target, 256, 0x5EED);
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn corpus_is_accepted() -> Result<(), String> {
+        for target in TARGETS.iter() {
+            for input in (target.corpus)() {
+                if !(target.parse)(&input) {
+                    return Err(alloc::format!("{} turns down its own corpus", target.name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
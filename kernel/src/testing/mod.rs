@@ -1,276 +1,159 @@
-//! Testing Framework
+//! Kernel tests
 //!
-//! Kernel testing framework for unit and integration tests.
+//! Tests live next to the code they cover, as functions marked
+//! `#[kernel_test]` that return `Ok(())` or why they failed; `check!` and
+//! `check_eq!` give the reason with the place. The attribute puts each
+//! test in the `.kernel_tests` linker section, so `tests` has them all.
+//!
+//! `run_tests` prints the results to the console and writes them to the
+//! serial port as TAP, for a machine to read:
+//!
+//! ```text
+//! TAP version 13
+//! 1..2
+//! ok 1 - kernel::log::kernel_tests::parses_filters
+//! not ok 2 - kernel::drivers::rtc::kernel_tests::parses_timezones
+//!   # kernel/src/drivers/rtc.rs:282: parse_timezone("UTC+15") is Some(54000), not None
+//! ```
+//!
+//! With `test` on the command line the kernel runs them once it is up,
+//! then leaves QEMU through its isa-debug-exit device with the result:
+//! `make test-qemu` boots it so, for CI.
+//!
+//! `fuzz` feeds the parsers of untrusted input malformed bytes.
+
+pub mod fuzz;
 
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::cpu;
+use crate::console;
+use crate::drivers::input::outl;
 use crate::println;
 
-/// Test result
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TestResult {
-    Passed,
-    Failed,
-    Skipped,
-}
+pub use webbos_macros::kernel_test;
 
-/// Test status
-#[derive(Debug)]
-pub struct Test {
+/// What `#[kernel_test]` leaves in the `.kernel_tests` section
+pub struct KernelTest {
+    /// Module path and function
     pub name: &'static str,
-    pub result: TestResult,
-    pub message: Option<&'static str>,
+    pub run: fn() -> Result<(), String>,
 }
 
-/// Test suite
-pub struct TestSuite {
-    name: &'static str,
-    tests: alloc::vec::Vec<Test>,
+extern "C" {
+    static __kernel_tests_start: u8;
+    static __kernel_tests_end: u8;
 }
 
-impl TestSuite {
-    /// Create new test suite
-    pub fn new(name: &'static str) -> Self {
-        Self {
-            name,
-            tests: alloc::vec::Vec::new(),
-        }
-    }
-    
-    /// Add a test
-    pub fn add_test(&mut self, name: &'static str, result: TestResult) {
-        self.tests.push(Test {
-            name,
-            result,
-            message: None,
-        });
-    }
-    
-    /// Add test with message
-    pub fn add_test_with_message(&mut self, name: &'static str, result: TestResult, message: &'static str) {
-        self.tests.push(Test {
-            name,
-            result,
-            message: Some(message),
-        });
-    }
-    
-    /// Run all tests and print results
-    pub fn run(&self) {
-        println!("\n╔════════════════════════════════════════════════════════════╗");
-        println!("║              {} Test Suite              ║", self.name);
-        println!("╚════════════════════════════════════════════════════════════╝");
-        
-        let mut passed = 0;
-        let mut failed = 0;
-        let mut skipped = 0;
-        
-        for test in &self.tests {
-            let symbol = match test.result {
-                TestResult::Passed => "✓",
-                TestResult::Failed => "✗",
-                TestResult::Skipped => "⊘",
-            };
-            
-            let color_code = match test.result {
-                TestResult::Passed => "[32m", // Green
-                TestResult::Failed => "[31m", // Red
-                TestResult::Skipped => "[33m", // Yellow
-            };
-            
-            println!("  {} {} {}", 
-                symbol,
-                test.name,
-                if let Some(msg) = test.message {
-                    alloc::format!("({})", msg)
-                } else {
-                    alloc::string::String::new()
-                }
-            );
-            
-            match test.result {
-                TestResult::Passed => passed += 1,
-                TestResult::Failed => failed += 1,
-                TestResult::Skipped => skipped += 1,
-            }
-        }
-        
-        let total = self.tests.len();
-        println!("\n╔════════════════════════════════════════════════════════════╗");
-        println!("║                    Test Summary                            ║");
-        println!("╠════════════════════════════════════════════════════════════╣");
-        println!("║  Total:   {:3}  Passed: {:3}  Failed: {:3}  Skipped: {:3}  ║",
-            total, passed, failed, skipped);
-        println!("╚════════════════════════════════════════════════════════════╝");
-    }
-}
+/// Port of QEMU's isa-debug-exit device, as `scripts/qemu-test.sh` sets it
+const QEMU_EXIT_PORT: u16 = 0xF4;
 
-/// Assert macro for tests
-#[macro_export]
-macro_rules! assert_test {
-    ($cond:expr, $name:expr) => {
-        if $cond {
-            $crate::testing::TestResult::Passed
-        } else {
-            $crate::testing::TestResult::Failed
-        }
-    };
+/// Codes to leave QEMU with; it exits with `code << 1 | 1`, 33 or 35, so
+/// neither is mistaken for QEMU's own 0 or 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
 }
 
-/// Run all tests
-pub fn run_tests() {
-    println!("\n");
-    println!("╔════════════════════════════════════════════════════════════╗");
-    println!("║                 WebbOS Test Suite                          ║");
-    println!("╚════════════════════════════════════════════════════════════╝");
-    
-    run_memory_tests();
-    run_process_tests();
-    run_network_tests();
-    run_crypto_tests();
-    run_vfs_tests();
-}
+/// Running for `test` on the command line, so a panic has to leave QEMU
+static EXIT_WHEN_DONE: AtomicBool = AtomicBool::new(false);
 
-/// Memory management tests
-fn run_memory_tests() {
-    let mut suite = TestSuite::new("Memory Management");
-    
-    // Frame allocator test
-    suite.add_test("Frame allocator basic", TestResult::Passed);
-    suite.add_test("Frame allocator exhausted", TestResult::Passed);
-    
-    // Heap allocator test
-    suite.add_test("Heap allocation", TestResult::Passed);
-    suite.add_test("Heap deallocation", TestResult::Passed);
-    suite.add_test("Heap reallocation", TestResult::Passed);
-    
-    // Paging test
-    suite.add_test("Page table creation", TestResult::Passed);
-    suite.add_test("Virtual to physical mapping", TestResult::Passed);
-    
-    suite.run();
+/// Results of a run
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
 }
 
-/// Process management tests
-fn run_process_tests() {
-    let mut suite = TestSuite::new("Process Management");
-    
-    // PCB tests
-    suite.add_test("Process creation", TestResult::Passed);
-    suite.add_test("Thread creation", TestResult::Passed);
-    suite.add_test("Context switching", TestResult::Passed);
-    
-    // Scheduler tests
-    suite.add_test("Scheduler initialization", TestResult::Passed);
-    suite.add_test("Round-robin scheduling", TestResult::Passed);
-    suite.add_test("Priority queues", TestResult::Passed);
-    
-    suite.run();
+/// Every test in the kernel, in link order
+pub fn tests() -> &'static [KernelTest] {
+    let start = core::ptr::addr_of!(__kernel_tests_start) as *const KernelTest;
+    let end = core::ptr::addr_of!(__kernel_tests_end) as usize;
+    let count = (end - start as usize) / core::mem::size_of::<KernelTest>();
+    unsafe { core::slice::from_raw_parts(start, count) }
 }
 
-/// Network stack tests
-fn run_network_tests() {
-    let mut suite = TestSuite::new("Network Stack");
-    
-    // Socket tests
-    suite.add_test("Socket creation", TestResult::Passed);
-    suite.add_test("Socket bind", TestResult::Passed);
-    suite.add_test("Socket connect", TestResult::Skipped);
-    
-    // Protocol tests
-    suite.add_test("IPv4 packet creation", TestResult::Passed);
-    suite.add_test("TCP segment creation", TestResult::Passed);
-    suite.add_test("UDP datagram creation", TestResult::Passed);
-    
-    // ARP test
-    suite.add_test("ARP cache", TestResult::Passed);
-    
-    // DNS test
-    suite.add_test("DNS parsing", TestResult::Passed);
-    
-    suite.run();
+/// Write a line of TAP to the serial port
+fn tap(args: core::fmt::Arguments) {
+    console::write_log(format_args!("{}\n", args), false, true);
 }
 
-/// Cryptography tests
-fn run_crypto_tests() {
-    let mut suite = TestSuite::new("Cryptography");
-    
-    // Hash tests
-    suite.add_test("SHA-256", TestResult::Passed);
-    suite.add_test("SHA-384", TestResult::Passed);
-    
-    // Cipher tests
-    suite.add_test("ChaCha20", TestResult::Passed);
-    suite.add_test("Poly1305", TestResult::Passed);
-    
-    // Key derivation
-    suite.add_test("HKDF", TestResult::Passed);
-    suite.add_test("X25519", TestResult::Passed);
-    
-    // TLS tests
-    suite.add_test("TLS ClientHello", TestResult::Passed);
-    suite.add_test("TLS key schedule", TestResult::Passed);
-    
-    suite.run();
+/// Run the tests whose names contain `filter`, or all of them
+pub fn run_tests(filter: Option<&str>) -> Summary {
+    let selected = || tests().iter().filter(|test| filter.map_or(true, |f| test.name.contains(f)));
+    let mut summary = Summary::default();
+
+    println!("Running {} kernel tests", selected().count());
+    tap(format_args!("TAP version 13"));
+    tap(format_args!("1..{}", selected().count()));
+    for (number, test) in selected().enumerate() {
+        match (test.run)() {
+            Ok(()) => {
+                summary.passed += 1;
+                println!("  \x1b[32m✓\x1b[0m {}", test.name);
+                tap(format_args!("ok {} - {}", number + 1, test.name));
+            }
+            Err(reason) => {
+                summary.failed += 1;
+                println!("  \x1b[31m✗\x1b[0m {}: {}", test.name, reason);
+                tap(format_args!("not ok {} - {}", number + 1, test.name));
+                tap(format_args!("  # {}", reason));
+            }
+        }
+    }
+    println!("Passed: {}  Failed: {}", summary.passed, summary.failed);
+    tap(format_args!("# passed {}, failed {}", summary.passed, summary.failed));
+    summary
 }
 
-/// VFS tests
-fn run_vfs_tests() {
-    let mut suite = TestSuite::new("Virtual Filesystem");
-    
-    // File system tests
-    suite.add_test("VFS mount", TestResult::Passed);
-    suite.add_test("VFS open", TestResult::Passed);
-    suite.add_test("VFS read", TestResult::Passed);
-    suite.add_test("VFS write", TestResult::Passed);
-    
-    // EXT2 tests
-    suite.add_test("EXT2 superblock", TestResult::Passed);
-    suite.add_test("EXT2 inode", TestResult::Passed);
-    
-    // FAT32 tests
-    suite.add_test("FAT32 boot sector", TestResult::Passed);
-    suite.add_test("FAT32 directory", TestResult::Passed);
-    
-    suite.run();
+/// Leave QEMU through the isa-debug-exit device; returns where there is
+/// none
+pub fn exit_qemu(code: QemuExitCode) {
+    unsafe { outl(QEMU_EXIT_PORT, code as u32) };
 }
 
-/// Test runner for inline tests
-pub struct TestRunner {
-    total: usize,
-    passed: usize,
-    failed: usize,
+/// Run every test and leave QEMU with the result, for `test` on the
+/// command line; powers off without the exit device
+pub fn run_and_exit() -> ! {
+    EXIT_WHEN_DONE.store(true, Ordering::Relaxed);
+    let summary = run_tests(None);
+    exit_qemu(if summary.failed == 0 { QemuExitCode::Success } else { QemuExitCode::Failed });
+    cpu::shutdown()
 }
 
-impl TestRunner {
-    /// Create new test runner
-    pub fn new() -> Self {
-        Self {
-            total: 0,
-            passed: 0,
-            failed: 0,
-        }
+/// For the panic handler: a panic in the tests run for `test` on the
+/// command line ends the TAP stream and leaves QEMU as a failure
+pub fn panicked() {
+    if EXIT_WHEN_DONE.load(Ordering::Relaxed) {
+        console::emergency_write(format_args!("Bail out! Kernel panic\n"));
+        exit_qemu(QemuExitCode::Failed);
     }
-    
-    /// Run a single test
-    pub fn run<F: FnOnce() -> bool>(&mut self, name: &str, test: F) {
-        self.total += 1;
-        if test() {
-            self.passed += 1;
-            println!("  ✓ {}", name);
-        } else {
-            self.failed += 1;
-            println!("  ✗ {}", name);
+}
+
+/// Fail the test unless `cond` holds
+#[macro_export]
+macro_rules! check {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(alloc::format!("{}:{}: {}", file!(), line!(), stringify!($cond)));
         }
-    }
-    
-    /// Print summary
-    pub fn summary(&self) {
-        println!("\n  Total: {}  Passed: {}  Failed: {}", 
-            self.total, self.passed, self.failed);
-    }
+    };
 }
 
-impl Default for TestRunner {
-    fn default() -> Self {
-        Self::new()
-    }
+/// Fail the test unless the two are equal
+#[macro_export]
+macro_rules! check_eq {
+    ($left:expr, $right:expr) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if left != right {
+                    return Err(alloc::format!("{}:{}: {} is {:?}, not {:?}",
+                        file!(), line!(), stringify!($left), left, right));
+                }
+            }
+        }
+    };
 }
@@ -0,0 +1,999 @@
+//! Certificate store
+//!
+//! Trust anchors are PEM files in `/etc/ssl/certs`, one certificate each,
+//! named after the first 16 hex digits of its SHA-256 fingerprint, which
+//! is also the ID `remove` takes. `add` takes PEM text, which may hold
+//! several certificates.
+//!
+//! A lab server with a self-signed certificate can be pinned instead.
+//! `/etc/ssl/pins` has a `host fingerprint` line for each pinned host, and
+//! such a host is trusted with that one certificate, whatever the anchors
+//! say; any other certificate from it fails the handshake.
+//!
+//! `verify_chain` is how the handshake decides. The server's certificate
+//! must name the host, each certificate must be signed by the key of the
+//! next, a CA, and the last must be an anchor or be signed by one. That
+//! the server holds the key of its certificate, pinned or not, is for the
+//! handshake to check, with `PublicKey::verify` on its CertificateVerify.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::keylog::hex;
+use crate::crypto::ecdsa::{self, Curve};
+use crate::crypto::{rsa, sha256, Hash};
+use crate::fs::{self, FsError, FsResult};
+use crate::println;
+use crate::users::audit;
+use crate::info;
+
+/// Where trust anchors are kept
+pub const CERT_DIR: &str = "/etc/ssl/certs";
+/// Pinned certificates, a `host fingerprint` line each
+pub const PINS_PATH: &str = "/etc/ssl/pins";
+
+const SSL_DIR: &str = "/etc/ssl";
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+/// Hex digits of the fingerprint that make an ID
+const ID_LEN: usize = 16;
+
+// DER tags
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_VERSION: u8 = 0xA0;
+const TAG_EXTENSIONS: u8 = 0xA3;
+/// A dNSName among the general names
+const TAG_DNS_NAME: u8 = 0x82;
+
+// Attribute types of a name
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0A];
+
+// Extensions
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1D, 0x13];
+
+// Keys, and the curves of EC keys
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x01];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x2B, 0x81, 0x04, 0x00, 0x22];
+
+// Signature algorithms
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0B];
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0C];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+const OID_ECDSA_WITH_SHA384: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x03];
+
+/// Why a certificate could not be read, added, removed or pinned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertError {
+    /// Not a certificate this parser understands; says what is wrong
+    Invalid(&'static str),
+    /// No anchor or pin by that ID or host
+    NotFound(String),
+    /// The ID is the start of more than one fingerprint
+    Ambiguous(String),
+    /// The chain ends at this issuer, which is not an anchor
+    Untrusted(String),
+    /// The host is pinned to another certificate
+    PinMismatch(String),
+    /// The server's certificate is not for this host
+    WrongHost(String),
+    /// This certificate's signature is not its issuer's
+    BadSignature(String),
+    Fs(FsError),
+}
+
+impl fmt::Display for CertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertError::Invalid(message) => write!(f, "invalid certificate: {}", message),
+            CertError::NotFound(what) => write!(f, "no certificate for '{}'", what),
+            CertError::Ambiguous(id) => write!(f, "'{}' matches more than one certificate", id),
+            CertError::Untrusted(issuer) => write!(f, "issuer '{}' is not trusted", issuer),
+            CertError::PinMismatch(host) => write!(f, "{} is pinned to another certificate", host),
+            CertError::WrongHost(host) => write!(f, "certificate is not for {}", host),
+            CertError::BadSignature(subject) => write!(f, "'{}' is not signed by its issuer", subject),
+            CertError::Fs(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl From<FsError> for CertError {
+    fn from(e: FsError) -> Self {
+        CertError::Fs(e)
+    }
+}
+
+/// An X.509 certificate, with the fields the store shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub der: Vec<u8>,
+    /// Common name of the subject, or its organization
+    pub subject: String,
+    pub issuer: String,
+    /// Last day it is valid, YYYY-MM-DD
+    pub not_after: String,
+    /// SHA-256 of `der`
+    pub fingerprint: [u8; 32],
+    /// Host names it is for: its DNS alternative names, or its common
+    /// name when it has none
+    pub names: Vec<String>,
+    /// Whether it may issue certificates
+    pub ca: bool,
+    pub public_key: PublicKey,
+    /// Encoded names, to match a certificate to its issuer
+    subject_name: Vec<u8>,
+    issuer_name: Vec<u8>,
+    /// The signed part, how it is signed, and the signature
+    tbs: Vec<u8>,
+    signature_algorithm: Option<SignatureAlgorithm>,
+    signature: Vec<u8>,
+}
+
+/// A certificate's key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Rsa(rsa::PublicKey),
+    /// An uncompressed point on the curve
+    Ec(Curve, Vec<u8>),
+    /// A kind of key, or an RSA key too short, that nothing here checks
+    /// signatures with
+    Unsupported,
+}
+
+/// How a signature is made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    RsaPkcs1(Hash),
+    RsaPss(Hash),
+    Ecdsa(Hash),
+}
+
+impl PublicKey {
+    /// Whether `signature` of `message` is by this key
+    pub fn verify(&self, algorithm: SignatureAlgorithm, message: &[u8], signature: &[u8]) -> bool {
+        match (self, algorithm) {
+            (PublicKey::Rsa(key), SignatureAlgorithm::RsaPkcs1(hash)) => {
+                key.verify_pkcs1(hash, &hash.digest(message), signature)
+            }
+            (PublicKey::Rsa(key), SignatureAlgorithm::RsaPss(hash)) => {
+                key.verify_pss(hash, &hash.digest(message), signature)
+            }
+            (PublicKey::Ec(curve, point), SignatureAlgorithm::Ecdsa(hash)) => {
+                // A sequence of r and s
+                let mut outer = Der::new(signature);
+                let mut pair = match outer.expect(TAG_SEQUENCE, "no signature") {
+                    Ok(pair) if outer.peek().is_none() => Der::new(pair),
+                    _ => return false,
+                };
+                match (pair.expect(TAG_INTEGER, "no r"), pair.expect(TAG_INTEGER, "no s")) {
+                    (Ok(r), Ok(s)) => ecdsa::verify(*curve, point, &hash.digest(message), r, s),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Certificate {
+    /// Read a DER-encoded certificate
+    pub fn parse(der: &[u8]) -> Result<Self, CertError> {
+        let mut outer = Der::new(der);
+        let certificate = outer.expect(TAG_SEQUENCE, "not a sequence")?;
+        let mut fields = Der::new(certificate);
+        let tbs_der = fields.whole(TAG_SEQUENCE, "no to-be-signed part")?;
+        let mut algorithm = Der::new(fields.expect(TAG_SEQUENCE, "no signature algorithm")?);
+        let signature_algorithm = signature_algorithm(algorithm.expect(TAG_OID, "no signature algorithm")?);
+        let signature = bit_string(fields.expect(TAG_BIT_STRING, "no signature")?)?;
+
+        let mut tbs = Der::new(Der::new(tbs_der).expect(TAG_SEQUENCE, "no to-be-signed part")?);
+        if tbs.peek() == Some(TAG_VERSION) {
+            tbs.element();
+        }
+        tbs.expect(TAG_INTEGER, "no serial number")?;
+        tbs.expect(TAG_SEQUENCE, "no signature algorithm")?;
+        let issuer_name = tbs.whole(TAG_SEQUENCE, "no issuer")?;
+        let mut validity = Der::new(tbs.expect(TAG_SEQUENCE, "no validity")?);
+        validity.element().ok_or(CertError::Invalid("no start of validity"))?;
+        let not_after = match validity.element() {
+            Some((tag, time)) => date(tag, time).ok_or(CertError::Invalid("bad end of validity"))?,
+            None => return Err(CertError::Invalid("no end of validity")),
+        };
+        let subject_name = tbs.whole(TAG_SEQUENCE, "no subject")?;
+        let public_key = public_key(tbs.expect(TAG_SEQUENCE, "no public key")?)?;
+
+        let mut names = Vec::new();
+        let mut ca = false;
+        while let Some((tag, contents)) = tbs.element() {
+            if tag != TAG_EXTENSIONS {
+                continue;
+            }
+            let mut list = Der::new(Der::new(contents).expect(TAG_SEQUENCE, "bad extensions")?);
+            while let Some((_, extension)) = list.element() {
+                let mut extension = Der::new(extension);
+                let oid = extension.expect(TAG_OID, "bad extension")?;
+                // Critical or not
+                if extension.peek() == Some(TAG_BOOLEAN) {
+                    extension.element();
+                }
+                let value = extension.expect(TAG_OCTET_STRING, "bad extension")?;
+                if oid == OID_SUBJECT_ALT_NAME {
+                    names = dns_names(value)?;
+                } else if oid == OID_BASIC_CONSTRAINTS {
+                    let mut constraints = Der::new(Der::new(value).expect(TAG_SEQUENCE, "bad basic constraints")?);
+                    ca = matches!(constraints.element(), Some((TAG_BOOLEAN, [flag])) if *flag != 0);
+                }
+            }
+        }
+        if names.is_empty() {
+            names.extend(attribute(subject_name, OID_COMMON_NAME).map(|name| name.to_ascii_lowercase()));
+        }
+
+        Ok(Certificate {
+            der: der.to_vec(),
+            subject: display_name(subject_name),
+            issuer: display_name(issuer_name),
+            not_after,
+            fingerprint: sha256::hash(der),
+            names,
+            ca,
+            public_key,
+            subject_name: subject_name.to_vec(),
+            issuer_name: issuer_name.to_vec(),
+            tbs: tbs_der.to_vec(),
+            signature_algorithm,
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// Whether `issuer` has this certificate's issuer name, and its key
+    /// signed it
+    pub fn issued_by(&self, issuer: &Certificate) -> bool {
+        self.issuer_name == issuer.subject_name && match self.signature_algorithm {
+            Some(algorithm) => issuer.public_key.verify(algorithm, &self.tbs, &self.signature),
+            None => false,
+        }
+    }
+
+    /// Whether it is for `host`; a wildcard stands for one whole label,
+    /// the leftmost
+    pub fn is_for(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.names.iter().any(|name| match name.strip_prefix("*.") {
+            Some(parent) => host.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest == parent),
+            None => *name == host,
+        })
+    }
+
+    /// First hex digits of the fingerprint
+    pub fn id(&self) -> String {
+        let mut id = hex(&self.fingerprint);
+        id.truncate(ID_LEN);
+        id
+    }
+
+    pub fn self_signed(&self) -> bool {
+        self.subject_name == self.issuer_name
+    }
+
+    pub fn to_pem(&self) -> String {
+        let encoded = base64_encode(&self.der);
+        let mut pem = String::from(PEM_BEGIN);
+        pem.push('\n');
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(core::str::from_utf8(line).unwrap_or(""));
+            pem.push('\n');
+        }
+        pem.push_str(PEM_END);
+        pem.push('\n');
+        pem
+    }
+}
+
+/// Elements of DER, read one after another
+struct Der<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    /// Next element's tag and contents
+    fn element(&mut self) -> Option<(u8, &'a [u8])> {
+        let (tag, start, end) = self.bounds()?;
+        self.pos = end;
+        Some((tag, &self.data[start..end]))
+    }
+
+    /// Contents of the next element, which must be a `tag`
+    fn expect(&mut self, tag: u8, missing: &'static str) -> Result<&'a [u8], CertError> {
+        match self.element() {
+            Some((t, contents)) if t == tag => Ok(contents),
+            _ => Err(CertError::Invalid(missing)),
+        }
+    }
+
+    /// The next element, header and all, which must be a `tag`
+    fn whole(&mut self, tag: u8, missing: &'static str) -> Result<&'a [u8], CertError> {
+        let begin = self.pos;
+        self.expect(tag, missing)?;
+        Ok(&self.data[begin..self.pos])
+    }
+
+    /// Tag, and where the contents of the next element start and end
+    fn bounds(&self) -> Option<(u8, usize, usize)> {
+        let tag = *self.data.get(self.pos)?;
+        let first = *self.data.get(self.pos + 1)? as usize;
+        let mut start = self.pos + 2;
+        let len = if first < 0x80 {
+            first
+        } else {
+            // Long form; more than three bytes of length is never a
+            // certificate
+            let count = first & 0x7F;
+            if count == 0 || count > 3 {
+                return None;
+            }
+            let bytes = self.data.get(start..start + count)?;
+            start += count;
+            bytes.iter().fold(0, |len, &b| (len << 8) | b as usize)
+        };
+        let end = start.checked_add(len)?;
+        if end > self.data.len() {
+            return None;
+        }
+        Some((tag, start, end))
+    }
+}
+
+/// Common name of an encoded name, or its organization
+fn display_name(name: &[u8]) -> String {
+    attribute(name, OID_COMMON_NAME)
+        .or_else(|| attribute(name, OID_ORGANIZATION))
+        .unwrap_or_else(|| String::from("(unnamed)"))
+}
+
+/// The last value of the attribute `wanted` in an encoded name
+fn attribute(name: &[u8], wanted: &[u8]) -> Option<String> {
+    let mut found = None;
+    let mut outer = Der::new(name);
+    let mut rdns = match outer.element() {
+        Some((TAG_SEQUENCE, contents)) => Der::new(contents),
+        _ => return None,
+    };
+    while let Some((tag, set)) = rdns.element() {
+        if tag != TAG_SET {
+            continue;
+        }
+        let mut attributes = Der::new(set);
+        while let Some((_, attribute)) = attributes.element() {
+            let mut attribute = Der::new(attribute);
+            let oid = match attribute.element() {
+                Some((TAG_OID, oid)) => oid,
+                _ => continue,
+            };
+            match attribute.element() {
+                Some((_, value)) if oid == wanted => found = Some(String::from_utf8_lossy(value).into_owned()),
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+/// The bits of a BIT STRING, which for a key or signature are whole bytes
+fn bit_string(contents: &[u8]) -> Result<&[u8], CertError> {
+    match contents.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(CertError::Invalid("bad bit string")),
+    }
+}
+
+/// The key in a SubjectPublicKeyInfo
+fn public_key(info: &[u8]) -> Result<PublicKey, CertError> {
+    let mut info = Der::new(info);
+    let mut algorithm = Der::new(info.expect(TAG_SEQUENCE, "no key algorithm")?);
+    let kind = algorithm.expect(TAG_OID, "no key algorithm")?;
+    let key = bit_string(info.expect(TAG_BIT_STRING, "no public key")?)?;
+    if kind == OID_RSA_ENCRYPTION {
+        let mut outer = Der::new(key);
+        let mut numbers = Der::new(outer.expect(TAG_SEQUENCE, "bad RSA key")?);
+        let modulus = numbers.expect(TAG_INTEGER, "bad RSA key")?;
+        let exponent = numbers.expect(TAG_INTEGER, "bad RSA key")?;
+        Ok(rsa::PublicKey::new(modulus, exponent).map_or(PublicKey::Unsupported, PublicKey::Rsa))
+    } else if kind == OID_EC_PUBLIC_KEY {
+        let curve = algorithm.expect(TAG_OID, "no curve")?;
+        if curve == OID_P256 {
+            Ok(PublicKey::Ec(Curve::P256, key.to_vec()))
+        } else if curve == OID_P384 {
+            Ok(PublicKey::Ec(Curve::P384, key.to_vec()))
+        } else {
+            Ok(PublicKey::Unsupported)
+        }
+    } else {
+        Ok(PublicKey::Unsupported)
+    }
+}
+
+/// How a certificate is signed, if it is signed in a way checked here
+fn signature_algorithm(oid: &[u8]) -> Option<SignatureAlgorithm> {
+    if oid == OID_SHA256_WITH_RSA {
+        Some(SignatureAlgorithm::RsaPkcs1(Hash::Sha256))
+    } else if oid == OID_SHA384_WITH_RSA {
+        Some(SignatureAlgorithm::RsaPkcs1(Hash::Sha384))
+    } else if oid == OID_ECDSA_WITH_SHA256 {
+        Some(SignatureAlgorithm::Ecdsa(Hash::Sha256))
+    } else if oid == OID_ECDSA_WITH_SHA384 {
+        Some(SignatureAlgorithm::Ecdsa(Hash::Sha384))
+    } else {
+        None
+    }
+}
+
+/// The DNS names among the general names of a subject alternative name
+fn dns_names(value: &[u8]) -> Result<Vec<String>, CertError> {
+    let mut outer = Der::new(value);
+    let mut general_names = Der::new(outer.expect(TAG_SEQUENCE, "bad alternative names")?);
+    let mut names = Vec::new();
+    while let Some((tag, name)) = general_names.element() {
+        if tag == TAG_DNS_NAME {
+            names.push(String::from_utf8_lossy(name).to_ascii_lowercase());
+        }
+    }
+    Ok(names)
+}
+
+/// A UTCTime or GeneralizedTime as YYYY-MM-DD
+fn date(tag: u8, time: &[u8]) -> Option<String> {
+    let text = core::str::from_utf8(time).ok()?;
+    let (year, rest) = match tag {
+        TAG_UTC_TIME => {
+            let yy: u32 = text.get(..2)?.parse().ok()?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, text.get(2..)?)
+        }
+        TAG_GENERALIZED_TIME => (text.get(..4)?.parse().ok()?, text.get(4..)?),
+        _ => return None,
+    };
+    let month: u32 = rest.get(..2)?.parse().ok()?;
+    let day: u32 = rest.get(2..4)?.parse().ok()?;
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode base64, skipping whitespace
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    let mut padding = false;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            padding = true;
+            continue;
+        }
+        if padding {
+            return None;
+        }
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Every certificate in PEM text, DER-encoded
+pub fn parse_pem(text: &str) -> Result<Vec<Vec<u8>>, CertError> {
+    let mut certificates = Vec::new();
+    let mut rest = text;
+    while let Some(begin) = rest.find(PEM_BEGIN) {
+        let body = &rest[begin + PEM_BEGIN.len()..];
+        let end = body.find(PEM_END).ok_or(CertError::Invalid("no END CERTIFICATE line"))?;
+        certificates.push(base64_decode(&body[..end]).ok_or(CertError::Invalid("bad base64"))?);
+        rest = &body[end + PEM_END.len()..];
+    }
+    if certificates.is_empty() {
+        return Err(CertError::Invalid("no BEGIN CERTIFICATE line"));
+    }
+    Ok(certificates)
+}
+
+/// A 64-digit hex fingerprint
+fn parse_fingerprint(text: &str) -> Option<[u8; 32]> {
+    let digits: Vec<u8> = text.bytes().filter(|&c| c != b':').collect();
+    if digits.len() != 64 {
+        return None;
+    }
+    let mut fingerprint = [0u8; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+fn ensure_dir(path: &str) -> FsResult<()> {
+    match fs::create_dir(path) {
+        Ok(()) | Err(FsError::AlreadyExists) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn anchor_path(id: &str) -> String {
+    format!("{}/{}.pem", CERT_DIR, id)
+}
+
+/// The trust anchors
+pub fn list() -> Vec<Certificate> {
+    let entries = fs::read_dir(CERT_DIR).unwrap_or_default();
+    entries.iter()
+        .filter(|e| e.name.ends_with(".pem"))
+        .filter_map(|e| fs::read_file(&format!("{}/{}", CERT_DIR, e.name)).ok())
+        .filter_map(|data| parse_pem(&String::from_utf8_lossy(&data)).ok())
+        .flat_map(|ders| ders.into_iter().filter_map(|der| Certificate::parse(&der).ok()))
+        .collect()
+}
+
+/// Trust the certificates in PEM text; returns those added
+pub fn add(pem: &str) -> Result<Vec<Certificate>, CertError> {
+    let certificates = parse_pem(pem)?
+        .iter()
+        .map(|der| Certificate::parse(der))
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure_dir(SSL_DIR)?;
+    ensure_dir(CERT_DIR)?;
+    for certificate in &certificates {
+        fs::write_file(&anchor_path(&certificate.id()), certificate.to_pem().as_bytes())?;
+        info!("tls", "Trusting {} ({})", certificate.subject, certificate.id());
+        audit::record(audit::Kind::Settings, &format!("added trust anchor {} {}", certificate.subject, certificate.id()));
+    }
+    Ok(certificates)
+}
+
+/// Stop trusting the anchor whose fingerprint starts with `id`
+pub fn remove(id: &str) -> Result<Certificate, CertError> {
+    let id = id.to_ascii_lowercase();
+    let mut matches = list().into_iter().filter(|c| !id.is_empty() && hex(&c.fingerprint).starts_with(&id));
+    let certificate = matches.next().ok_or_else(|| CertError::NotFound(id.clone()))?;
+    if matches.next().is_some() {
+        return Err(CertError::Ambiguous(id));
+    }
+    fs::remove(&anchor_path(&certificate.id()))?;
+    info!("tls", "No longer trusting {} ({})", certificate.subject, certificate.id());
+    audit::record(audit::Kind::Settings, &format!("removed trust anchor {} {}", certificate.subject, certificate.id()));
+    Ok(certificate)
+}
+
+/// The anchor among `anchors` that signed `certificate`, if any
+fn issuer_anchor<'a>(certificate: &Certificate, anchors: &'a [Certificate]) -> Option<&'a Certificate> {
+    anchors.iter().find(|anchor| certificate.issued_by(anchor))
+}
+
+/// Pinned hosts and the fingerprints they are pinned to
+pub fn pins() -> Vec<(String, [u8; 32])> {
+    let data = fs::read_file(PINS_PATH).unwrap_or_default();
+    String::from_utf8_lossy(&data)
+        .lines()
+        .filter_map(|line| {
+            let (host, fingerprint) = line.trim().split_once(' ')?;
+            Some((String::from(host), parse_fingerprint(fingerprint.trim())?))
+        })
+        .collect()
+}
+
+fn save_pins(pins: &[(String, [u8; 32])]) -> FsResult<()> {
+    let mut text = String::new();
+    for (host, fingerprint) in pins {
+        text.push_str(&format!("{} {}\n", host, hex(fingerprint)));
+    }
+    ensure_dir(SSL_DIR)?;
+    fs::write_file(PINS_PATH, text.as_bytes())
+}
+
+fn valid_host(host: &str) -> bool {
+    !host.is_empty() && host.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'.' || c == b'-')
+}
+
+/// Trust `host` with one certificate, given as PEM or as the hex of its
+/// SHA-256 fingerprint; a host pinned already is pinned again
+pub fn pin(host: &str, certificate: &str) -> Result<[u8; 32], CertError> {
+    let host = host.to_ascii_lowercase();
+    if !valid_host(&host) {
+        return Err(CertError::Invalid("not a host name"));
+    }
+    let fingerprint = match parse_fingerprint(certificate.trim()) {
+        Some(fingerprint) => fingerprint,
+        None => {
+            let der = parse_pem(certificate)?.remove(0);
+            Certificate::parse(&der)?.fingerprint
+        }
+    };
+    let mut pins = pins();
+    pins.retain(|(pinned, _)| *pinned != host);
+    pins.push((host.clone(), fingerprint));
+    save_pins(&pins)?;
+    info!("tls", "Pinned {} to {}", host, hex(&fingerprint));
+    audit::record(audit::Kind::Settings, &format!("pinned {} to {}", host, hex(&fingerprint)));
+    Ok(fingerprint)
+}
+
+pub fn unpin(host: &str) -> Result<(), CertError> {
+    let host = host.to_ascii_lowercase();
+    let mut pins = pins();
+    let before = pins.len();
+    pins.retain(|(pinned, _)| *pinned != host);
+    if pins.len() == before {
+        return Err(CertError::NotFound(host));
+    }
+    save_pins(&pins)?;
+    info!("tls", "Unpinned {}", host);
+    audit::record(audit::Kind::Settings, &format!("unpinned {}", host));
+    Ok(())
+}
+
+/// Whether `host`'s certificate, DER-encoded, is the one `pins` pin it
+/// to; `None` if it is not pinned
+fn check_pin(host: &str, der: &[u8], pins: &[(String, [u8; 32])]) -> Option<bool> {
+    let host = host.to_ascii_lowercase();
+    let (_, fingerprint) = pins.iter().find(|(pinned, _)| *pinned == host)?;
+    Some(crate::crypto::ct::eq(fingerprint, &sha256::hash(der)))
+}
+
+/// Whether `host` may be trusted with `chain`, the DER certificates of a
+/// TLS Certificate message, the server's own first; returns the server's
+/// certificate, whose key must yet sign the handshake
+pub fn verify_chain(host: &str, chain: &[Vec<u8>]) -> Result<Certificate, CertError> {
+    check_chain(host, chain, &list(), &pins())
+}
+
+fn check_chain(host: &str, chain: &[Vec<u8>], anchors: &[Certificate], pins: &[(String, [u8; 32])]) -> Result<Certificate, CertError> {
+    let leaf = chain.first().ok_or(CertError::Invalid("no certificate"))?;
+    let certificates = chain.iter()
+        .map(|der| Certificate::parse(der))
+        .collect::<Result<Vec<_>, _>>()?;
+    // A pin names its host itself, and stands for the rest of the chain
+    match check_pin(host, leaf, pins) {
+        Some(true) => return Ok(certificates[0].clone()),
+        Some(false) => return Err(CertError::PinMismatch(host.to_ascii_lowercase())),
+        None => {}
+    }
+    if !certificates[0].is_for(host) {
+        return Err(CertError::WrongHost(host.to_ascii_lowercase()));
+    }
+    for pair in certificates.windows(2) {
+        if pair[0].issuer_name != pair[1].subject_name {
+            return Err(CertError::Invalid("chain is out of order"));
+        }
+        if !pair[1].ca {
+            return Err(CertError::Invalid("issuer is not a CA"));
+        }
+        if !pair[0].issued_by(&pair[1]) {
+            return Err(CertError::BadSignature(pair[0].subject.clone()));
+        }
+    }
+    let last = &certificates[certificates.len() - 1];
+    if anchors.iter().any(|anchor| anchor.fingerprint == last.fingerprint) || issuer_anchor(last, anchors).is_some() {
+        return Ok(certificates[0].clone());
+    }
+    if anchors.iter().any(|anchor| anchor.subject_name == last.issuer_name) {
+        Err(CertError::BadSignature(last.subject.clone()))
+    } else {
+        Err(CertError::Untrusted(last.issuer.clone()))
+    }
+}
+
+/// Text of a PEM file, relative to the shell's working directory
+fn read_pem(path: &str) -> Result<String, String> {
+    fs::read_file(&crate::shell::env::path(path))
+        .map(|data| String::from_utf8_lossy(&data).into_owned())
+        .map_err(|e| format!("{}: {:?}", path, e))
+}
+
+/// The `certs` shell command:
+/// `certs [add FILE | remove ID | pin HOST FILE|SHA256 | unpin HOST]`
+pub fn command(args: &[&str]) {
+    let result = match args {
+        [] => {
+            print_info();
+            Ok(())
+        }
+        ["add", path] => read_pem(path)
+            .and_then(|pem| add(&pem).map_err(|e| e.to_string()))
+            .map(|added| added.iter().for_each(|c| println!("Trusting {} ({})", c.subject, c.id()))),
+        ["remove", id] => remove(id)
+            .map(|c| println!("Removed {} ({})", c.subject, c.id()))
+            .map_err(|e| e.to_string()),
+        ["pin", host, certificate] => {
+            let certificate = match parse_fingerprint(certificate) {
+                Some(_) => Ok(String::from(*certificate)),
+                None => read_pem(certificate),
+            };
+            certificate
+                .and_then(|c| pin(host, &c).map_err(|e| e.to_string()))
+                .map(|fingerprint| println!("Pinned {} to {}", host, hex(&fingerprint)))
+        }
+        ["unpin", host] => unpin(host)
+            .map(|()| println!("Unpinned {}", host))
+            .map_err(|e| e.to_string()),
+        _ => Err(String::from("Usage: certs [add <pem file> | remove <id> | pin <host> <pem file or sha256> | unpin <host>]")),
+    };
+    if let Err(e) = result {
+        println!("certs: {}", e);
+    }
+}
+
+/// Print the trust anchors and pins
+pub fn print_info() {
+    let anchors = list();
+    println!("Trust anchors ({}):", CERT_DIR);
+    if anchors.is_empty() {
+        println!("  (none)");
+    }
+    for c in anchors {
+        println!("  {}  {:<32} until {}{}", c.id(), c.subject, c.not_after,
+            if c.self_signed() { "" } else { "  (issued by another)" });
+    }
+    let pins = pins();
+    println!("Pinned hosts ({}):", PINS_PATH);
+    if pins.is_empty() {
+        println!("  (none)");
+    }
+    for (host, fingerprint) in pins {
+        println!("  {:<32} {}", host, hex(&fingerprint));
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// Self-signed Ed25519 certificate for O=WebbOS Lab, CN=lab.webbos.test
+    const LAB_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBYTCCAROgAwIBAgICEjQwBQYDK2VwMC8xEzARBgNVBAoMCldlYmJPUyBMYWIx
+GDAWBgNVBAMMD2xhYi53ZWJib3MudGVzdDAeFw0yNjEwMTgwODE4NTRaFw0zNjEw
+MTUwODE4NTRaMC8xEzARBgNVBAoMCldlYmJPUyBMYWIxGDAWBgNVBAMMD2xhYi53
+ZWJib3MudGVzdDAqMAUGAytlcAMhAMXS1CKT5dlDHqrXePxB2tjPEHH+NSxLbPpV
+04bF0RYJo1MwUTAdBgNVHQ4EFgQUBWQtWnYcJ7xLFpvEnwTRAccf0t8wHwYDVR0j
+BBgwFoAUBWQtWnYcJ7xLFpvEnwTRAccf0t8wDwYDVR0TAQH/BAUwAwEB/zAFBgMr
+ZXADQQCF05KTvw/wayDajozTlYewDstpc4iXdnv4p6z8D9kef7Ax3nR7DectffjY
+u/afUdY1Y5oTc9yZMdCQxFi9UtgI
+-----END CERTIFICATE-----
+";
+    const LAB_FINGERPRINT: &str = "7EF083F4A258B3193FDEDF0B8501E4AAEBFE17AF6FA23AE2045CDAD8023634F4";
+    /// RSA-2048 root, CN=WebbOS Test Root
+    const ROOT_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIDATCCAemgAwIBAgIUZVl7BvZ+DOct5fR9W5pBAAFYhswwDQYJKoZIhvcNAQEL
+BQAwMDETMBEGA1UECgwKV2ViYk9TIExhYjEZMBcGA1UEAwwQV2ViYk9TIFRlc3Qg
+Um9vdDAeFw0yNjEwMTgwMDAwMDBaFw0zNjEwMTUwMDAwMDBaMDAxEzARBgNVBAoM
+CldlYmJPUyBMYWIxGTAXBgNVBAMMEFdlYmJPUyBUZXN0IFJvb3QwggEiMA0GCSqG
+SIb3DQEBAQUAA4IBDwAwggEKAoIBAQC9zY3sGUKDJPQYbbrzzYhx8lwBIcN9mX/C
+bsi9nKSxMDn3sP0fZq/YrRaWHXHOj+n/DUA6sC3Wvi2R6/sohj+i3X2qm3boevxF
+40uQyzPZpY0CifJQf6jcM/SDChqrCwJxLb469fyO5f1xQPNaaNQO1YjaEwhDWP8V
+9pQS0uCtFfhRtVN2oE8yT+26kRN6phqaF59+6gBEWCOEybpj03DulduNV4qSq4/1
+MQQHqeVCaySew27FAu8fSMMhifof/y70fGfTPA695D7WizE3bgHXuKJkcfzUHLPv
+rY5ZrsnXUSUcBojPNNNkhKVRlO0bOVFJPeJBrPmc/MeDSPmiiiKxAgMBAAGjEzAR
+MA8GA1UdEwEB/wQFMAMBAf8wDQYJKoZIhvcNAQELBQADggEBAGl3enV7Z2gqpr9L
+zvhuHYcpOrN7DXn38Ff2XOW3N9nCNT3KZ4mid0rqqYQa2rPFT+R8kMYpZbHZCVtW
+2yJQYFpvOqSqZQ7jeE3D7i/hhREpcuaTigVY1H7QhLoUeCUBvhh1czkfkj4DJ+XM
+LLfcpeUteBc+HeeqC6SrcMjw/AEqCSr7OZwH1Pcf/+ZfZ+yLcFQghxUIRtubIC21
+Wiko/N2AYQ0DlNuq/4Q4M1/mk/St5sJS+XtmEcb354GVwRdZ4WcdrIkatgE9qs97
+4NZ3QFVl0ox0VwGiijv/5EDALl2sX1A8Isi8POemt0AGYNX3EHPb6aoo0Te0OuYZ
+w4HI+pk=
+-----END CERTIFICATE-----
+";
+    /// P-384 CA under the root
+    const CA_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIICUTCCATmgAwIBAgIUcbUa7d7pA7mF/EuUzLva4i0a7cswDQYJKoZIhvcNAQEL
+BQAwMDETMBEGA1UECgwKV2ViYk9TIExhYjEZMBcGA1UEAwwQV2ViYk9TIFRlc3Qg
+Um9vdDAeFw0yNjEwMTgwMDAwMDBaFw0zNjEwMTUwMDAwMDBaMC4xEzARBgNVBAoM
+CldlYmJPUyBMYWIxFzAVBgNVBAMMDldlYmJPUyBUZXN0IENBMHYwEAYHKoZIzj0C
+AQYFK4EEACIDYgAELb41Fg1kHkrtA+/iGJ9HGJdSff1eYHZzBWFbzaEWsV7Tplaf
+SxkAEr8yn2xueNErU71zSr4ijBe45ovMMPIgP0Er/M3y6bu6P/2Fki45QCf1Yrri
+piaghdubtSSmbPbeoxMwETAPBgNVHRMBAf8EBTADAQH/MA0GCSqGSIb3DQEBCwUA
+A4IBAQAcKsyILqrhnxlabF1wsIjhvviEtU4uIP1h66y4k9x+zSkxKkg15mOcUGuf
+n/GS6m+RjBE1yuFpV5DnjwwelkWg1cNVYF0PmFBWygnQ3BpuAa67VAYt1va7wtfW
+7nob2oZpQ7sDGwvm9rYGfDOY1auHb0uK9ALivAUS96WOFEvgERFNJMY3coLE3yuK
+7jYFNFwVbZwHKF+lLltSB98QQXnF3I62Z1E6S29rq+sS5OFufxtw0Z460KuuDioP
+P0tqhofvXhKITtcblH+egsdFOKlOV4MojjZx8tKDc1OCC/8Os5JUErYOVYNY2fc+
+p9Xxb11Y8+xrGY3QpTVwSkJqidQq
+-----END CERTIFICATE-----
+";
+    /// P-256 certificate from the CA for www.webbos.test and *.lab.webbos.test
+    const LEAF_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBvjCCAUSgAwIBAgIUNyMSGWOFhqvyRaUy14/qLeRW9CUwCgYIKoZIzj0EAwMw
+LjETMBEGA1UECgwKV2ViYk9TIExhYjEXMBUGA1UEAwwOV2ViYk9TIFRlc3QgQ0Ew
+HhcNMjYxMDE4MDAwMDAwWhcNMzYxMDE1MDAwMDAwWjAvMRMwEQYDVQQKDApXZWJi
+T1MgTGFiMRgwFgYDVQQDDA93d3cud2ViYm9zLnRlc3QwWTATBgcqhkjOPQIBBggq
+hkjOPQMBBwNCAASo0bBG3Xc0T+GJkIAHN7pIOaAq4lwl/7voAIsFu0XlzCkizOKn
+KzVgeTl7ae/vKNOD2vFCygmzg2II2I1+Wbf/oz8wPTAMBgNVHRMBAf8EAjAAMC0G
+A1UdEQQmMCSCD3d3dy53ZWJib3MudGVzdIIRKi5sYWIud2ViYm9zLnRlc3QwCgYI
+KoZIzj0EAwMDaAAwZQIwJEFmPCkn94N7k/vL3meuxPpQvoEFk+j4kWLsxOTGt+Iu
+9bORHH7c8DjO+LjiKCjaAjEAnms0c+007qBz1z9qjDq7f1ag6cZ/eyuSZabuEpIq
+kOh029Lilq4uLPdAZ+Ixgpjp
+-----END CERTIFICATE-----
+";
+    /// The same names, with the CA as issuer, but signed by another key
+    const FORGED_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBvzCCAUSgAwIBAgIUdX8GFlGQLUxvbbDUsOrDrKNmHMAwCgYIKoZIzj0EAwMw
+LjETMBEGA1UECgwKV2ViYk9TIExhYjEXMBUGA1UEAwwOV2ViYk9TIFRlc3QgQ0Ew
+HhcNMjYxMDE4MDAwMDAwWhcNMzYxMDE1MDAwMDAwWjAvMRMwEQYDVQQKDApXZWJi
+T1MgTGFiMRgwFgYDVQQDDA93d3cud2ViYm9zLnRlc3QwWTATBgcqhkjOPQIBBggq
+hkjOPQMBBwNCAAToQ5D2zyEQ7jzRliiWIiz/IaxnLz1xgz5ABz2VcgzdFuzF0Phg
+Jj5Dvsdo8ffRqvA8EiYOE9UWpWnC7uBFD/rooz8wPTAMBgNVHRMBAf8EAjAAMC0G
+A1UdEQQmMCSCD3d3dy53ZWJib3MudGVzdIIRKi5sYWIud2ViYm9zLnRlc3QwCgYI
+KoZIzj0EAwMDaQAwZgIxANZ65nB6mpKgP3tFuxc3430PXPOa2FrecfUGN0vOQ1A7
+3Be/tKDYu0vIiLOemrY/UAIxAIfWjd1JciY4pVReY1Eg9fcEiguEdna1QukLoa6E
+8XvrzNn0QNzrEGUqTgyQptlqeg==
+-----END CERTIFICATE-----
+";
+    /// Signed by the key of LEAF_PEM, which is not a CA
+    const UNDER_LEAF_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBjjCCATSgAwIBAgIUaW95Zf22xDbYtseLhnL55IwqVlswCgYIKoZIzj0EAwIw
+LzETMBEGA1UECgwKV2ViYk9TIExhYjEYMBYGA1UEAwwPd3d3LndlYmJvcy50ZXN0
+MB4XDTI2MTAxODAwMDAwMFoXDTM2MTAxNTAwMDAwMFowMDETMBEGA1UECgwKV2Vi
+Yk9TIExhYjEZMBcGA1UEAwwQZXZpbC53ZWJib3MudGVzdDBZMBMGByqGSM49AgEG
+CCqGSM49AwEHA0IABGoGlmPCkMhs9woAkLS+oJpYOhdMPRqAchU9couA7QaDMr70
+moCL4/OKGrnIP+XQfyvZ9wqam/IUVhMzLsxbYw+jLTArMAwGA1UdEwEB/wQCMAAw
+GwYDVR0RBBQwEoIQZXZpbC53ZWJib3MudGVzdDAKBggqhkjOPQQDAgNIADBFAiAs
+b4x8Z2t5ho+WX/Mwa+3XsZ9Rfv9E3hyfawxFRLom2QIhAMwkTMkkyxaw8LbSf7Tq
+0MbIQDk8V0lLutQiNf/cDMMM
+-----END CERTIFICATE-----
+";
+
+    fn der(pem: &str) -> Result<Vec<u8>, String> {
+        parse_pem(pem).map(|mut ders| ders.remove(0)).map_err(|e| format!("{}", e))
+    }
+
+    #[kernel_test]
+    fn pem_certificates_are_read() -> Result<(), String> {
+        let ders = parse_pem(LAB_PEM).map_err(|e| format!("{}", e))?;
+        check_eq!(ders.len(), 1);
+        check_eq!(ders[0].len(), 357);
+        let certificate = Certificate::parse(&ders[0]).map_err(|e| format!("{}", e))?;
+        check_eq!(certificate.subject.as_str(), "lab.webbos.test");
+        check_eq!(certificate.issuer.as_str(), "lab.webbos.test");
+        check_eq!(certificate.not_after.as_str(), "2036-10-15");
+        check!(certificate.self_signed());
+        check_eq!(Some(certificate.fingerprint), parse_fingerprint(LAB_FINGERPRINT));
+        check_eq!(certificate.id().as_str(), "7ef083f4a258b319");
+        // Written out again, it reads back the same
+        check_eq!(parse_pem(&certificate.to_pem()).map_err(|e| format!("{}", e))?, ders);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn chains_need_a_pin_or_an_anchor() -> Result<(), String> {
+        let der = parse_pem(LAB_PEM).map_err(|e| format!("{}", e))?.remove(0);
+        let lab = Certificate::parse(&der).map_err(|e| format!("{}", e))?;
+        let chain = alloc::vec![der.clone()];
+        let host = "lab.webbos.test";
+
+        check_eq!(check_chain(host, &chain, &[], &[]), Err(CertError::Untrusted(String::from(host))));
+        check_eq!(check_chain(host, &chain, &[lab.clone()], &[]), Ok(lab.clone()));
+        check_eq!(check_chain(host, &[], &[lab.clone()], &[]), Err(CertError::Invalid("no certificate")));
+        check_eq!(check_chain("other.test", &chain, &[lab.clone()], &[]), Err(CertError::WrongHost(String::from("other.test"))));
+
+        // A pin stands without anchors, and overrides them when it differs
+        let pinned = [(String::from(host), lab.fingerprint)];
+        check_eq!(check_chain("LAB.webbos.test", &chain, &[], &pinned), Ok(lab.clone()));
+        let other = [(String::from(host), [0x11; 32])];
+        check_eq!(check_chain(host, &chain, &[lab.clone()], &other), Err(CertError::PinMismatch(String::from(host))));
+        let elsewhere = [(String::from("other.test"), [0x11; 32])];
+        check_eq!(check_chain(host, &chain, &[lab.clone()], &elsewhere), Ok(lab));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn chains_are_signed_by_their_issuers() -> Result<(), String> {
+        let anchors = [Certificate::parse(&der(ROOT_PEM)?).map_err(|e| format!("{}", e))?];
+        let (ca, leaf) = (der(CA_PEM)?, der(LEAF_PEM)?);
+        let chain = alloc::vec![leaf.clone(), ca];
+
+        // RSA signs the CA, P-384 the server's P-256 certificate
+        let trusted = check_chain("www.webbos.test", &chain, &anchors, &[]).map_err(|e| format!("{}", e))?;
+        check_eq!(trusted.der, leaf);
+        check_eq!(trusted.names, alloc::vec![String::from("www.webbos.test"), String::from("*.lab.webbos.test")]);
+        check!(matches!(trusted.public_key, PublicKey::Ec(Curve::P256, _)));
+        check!(matches!(anchors[0].public_key, PublicKey::Rsa(_)));
+        check!(anchors[0].ca && !trusted.ca);
+
+        // A wildcard is one label
+        check!(check_chain("WWW.webbos.test", &chain, &anchors, &[]).is_ok());
+        check!(check_chain("a.lab.webbos.test", &chain, &anchors, &[]).is_ok());
+        for host in ["lab.webbos.test", "a.b.lab.webbos.test", "webbos.test"] {
+            check_eq!(check_chain(host, &chain, &anchors, &[]), Err(CertError::WrongHost(String::from(host))));
+        }
+        // Without the CA, nothing the anchors signed
+        check_eq!(check_chain("www.webbos.test", &chain[..1], &anchors, &[]),
+            Err(CertError::Untrusted(String::from("WebbOS Test CA"))));
+        Ok(())
+    }
+
+    /// Issuer names can be copied; the issuer's signature cannot
+    #[kernel_test]
+    fn forged_issuers_are_refused() -> Result<(), String> {
+        let anchors = [Certificate::parse(&der(ROOT_PEM)?).map_err(|e| format!("{}", e))?];
+        let (ca, leaf, forged) = (der(CA_PEM)?, der(LEAF_PEM)?, der(FORGED_PEM)?);
+        let host = "www.webbos.test";
+
+        check_eq!(check_chain(host, &[forged.clone(), ca.clone()], &anchors, &[]),
+            Err(CertError::BadSignature(String::from(host))));
+        // Nor does making the CA an anchor let it vouch for what it did not sign
+        let ca_anchor = [Certificate::parse(&ca).map_err(|e| format!("{}", e))?];
+        check!(check_chain(host, &[leaf.clone()], &ca_anchor, &[]).is_ok());
+        check_eq!(check_chain(host, &[forged], &ca_anchor, &[]), Err(CertError::BadSignature(String::from(host))));
+        // A server's certificate, properly signed itself, cannot issue
+        check_eq!(check_chain("evil.webbos.test", &[der(UNDER_LEAF_PEM)?, leaf, ca], &anchors, &[]),
+            Err(CertError::Invalid("issuer is not a CA")));
+        Ok(())
+    }
+
+    /// The handshake takes the chain from a Certificate message and stops
+    /// when it is not trusted
+    #[kernel_test]
+    fn handshake_refuses_an_untrusted_chain() -> Result<(), String> {
+        use crate::tls::{TlsConnection, TlsError};
+        let der = parse_pem(LAB_PEM).map_err(|e| format!("{}", e))?.remove(0);
+        let entry = der.len() + 3 + 2;
+        let mut body = alloc::vec![0u8, 0, (entry >> 8) as u8, entry as u8];
+        body.extend_from_slice(&[0, (der.len() >> 8) as u8, der.len() as u8]);
+        body.extend_from_slice(&der);
+        body.extend_from_slice(&[0, 0]);
+
+        let mut tls = TlsConnection::for_host("untrusted.webbos.test");
+        check_eq!(tls.process_certificate(&body), Err(TlsError::CertificateError));
+        check_eq!(tls.process_certificate(&body[..body.len() - 3]), Err(TlsError::InvalidMessage));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn broken_certificates_are_refused() -> Result<(), String> {
+        check!(parse_pem("no certificate here").is_err());
+        check!(parse_pem("-----BEGIN CERTIFICATE-----\nMIIB").is_err());
+        check!(parse_pem("-----BEGIN CERTIFICATE-----\nM!IB\n-----END CERTIFICATE-----").is_err());
+        let der = parse_pem(LAB_PEM).map_err(|e| format!("{}", e))?.remove(0);
+        check!(Certificate::parse(&der[..100]).is_err());
+        check!(Certificate::parse(&[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! Key log and handshake trace
+//!
+//! With `tls.keylog` set, every traffic secret a connection derives is
+//! written out as the ServerHello is taken, as a line of the NSS key log format, the one browsers write
+//! to `SSLKEYLOGFILE`: `LABEL <client random> <secret>`, both in hex.
+//! Given the file, Wireshark decrypts a capture of the connection. The
+//! setting is a file to append to, made readable by its owner alone, or
+//! `serial` for the log port.
+//!
+//! With `tls.trace` on, each step of a handshake is logged at info level,
+//! the messages sent and received in hex, and the reason one is refused.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use crate::config;
+use crate::fs::{self, Permissions};
+use crate::{info, warn};
+
+/// Labels Wireshark knows secrets by
+pub const CLIENT_HANDSHAKE: &str = "CLIENT_HANDSHAKE_TRAFFIC_SECRET";
+pub const SERVER_HANDSHAKE: &str = "SERVER_HANDSHAKE_TRAFFIC_SECRET";
+
+/// Bytes of a message shown on each trace line
+const TRACE_WIDTH: usize = 32;
+
+/// Where key log lines go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    File(String),
+    Serial,
+}
+
+impl Sink {
+    /// `serial` or an absolute path
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "serial" => Some(Sink::Serial),
+            path if path.starts_with('/') && path.len() > 1 => Some(Sink::File(String::from(path))),
+            _ => None,
+        }
+    }
+}
+
+fn sink() -> Option<Sink> {
+    Sink::parse(&config::get("tls.keylog")?)
+}
+
+/// Whether handshakes are traced
+pub fn tracing() -> bool {
+    config::get("tls.trace").as_deref() == Some("on")
+}
+
+/// `bytes` as lowercase hex
+pub fn hex(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(text, "{:02x}", byte);
+    }
+    text
+}
+
+/// One key log line, without its newline
+pub fn line(label: &str, client_random: &[u8; 32], secret: &[u8]) -> String {
+    format!("{} {} {}", label, hex(client_random), hex(secret))
+}
+
+/// Record a secret of the connection that sent `client_random`
+pub fn log_secret(label: &str, client_random: &[u8; 32], secret: &[u8]) {
+    let sink = match sink() {
+        Some(sink) => sink,
+        None => return,
+    };
+    let line = line(label, client_random, secret);
+    match sink {
+        Sink::Serial => crate::console::write_log(format_args!("{}\n", line), false, true),
+        Sink::File(path) => {
+            if let Err(e) = append(&path, &line) {
+                warn!("tls", "Cannot write key log {}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+fn append(path: &str, line: &str) -> fs::FsResult<()> {
+    if fs::metadata(path).is_err() {
+        fs::write_file(path, b"")?;
+        fs::chmod(path, Permissions::from_mode(0o600))?;
+    }
+    fs::append_file(path, format!("{}\n", line).as_bytes())
+}
+
+/// Log a handshake step if tracing is on
+pub fn trace(args: fmt::Arguments) {
+    if tracing() {
+        info!("tls", "{}", args);
+    }
+}
+
+/// Log a message going `direction`, "sent" or "received", in hex
+pub fn trace_message(direction: &str, message: &[u8]) {
+    if !tracing() {
+        return;
+    }
+    let kind = match message.first() {
+        Some(&kind) => kind,
+        None => {
+            info!("tls", "{} an empty message", direction);
+            return;
+        }
+    };
+    info!("tls", "{} {} ({} bytes)", direction, message_name(kind), message.len());
+    for (n, row) in message.chunks(TRACE_WIDTH).enumerate() {
+        info!("tls", "  {:04x}: {}", n * TRACE_WIDTH, hex(row));
+    }
+}
+
+/// Log the header of a record going `direction`, and what an alert says
+pub fn trace_record(direction: &str, record: &[u8]) {
+    if !tracing() {
+        return;
+    }
+    if record.len() < 5 {
+        info!("tls", "{} {} bytes, too short for a record", direction, record.len());
+        return;
+    }
+    let length = u16::from_be_bytes([record[3], record[4]]);
+    info!("tls", "{} {} record, version {:02x}{:02x}, {} bytes",
+        direction, record_name(record[0]), record[1], record[2], length);
+    if record[0] == super::ContentType::Alert as u8 && record.len() >= 7 {
+        let level = if record[5] == 2 { "fatal" } else { "warning" };
+        info!("tls", "  {} alert: {}", level, alert_name(record[6]));
+    }
+}
+
+fn message_name(kind: u8) -> &'static str {
+    match kind {
+        1 => "ClientHello",
+        2 => "ServerHello",
+        4 => "NewSessionTicket",
+        8 => "EncryptedExtensions",
+        11 => "Certificate",
+        13 => "CertificateRequest",
+        15 => "CertificateVerify",
+        20 => "Finished",
+        24 => "KeyUpdate",
+        _ => "unknown message",
+    }
+}
+
+fn record_name(kind: u8) -> &'static str {
+    match kind {
+        20 => "ChangeCipherSpec",
+        21 => "Alert",
+        22 => "Handshake",
+        23 => "ApplicationData",
+        _ => "unknown",
+    }
+}
+
+fn alert_name(description: u8) -> &'static str {
+    match description {
+        0 => "close_notify",
+        10 => "unexpected_message",
+        20 => "bad_record_mac",
+        22 => "record_overflow",
+        40 => "handshake_failure",
+        42 => "bad_certificate",
+        47 => "illegal_parameter",
+        50 => "decode_error",
+        51 => "decrypt_error",
+        70 => "protocol_version",
+        80 => "internal_error",
+        109 => "missing_extension",
+        112 => "unrecognized_name",
+        120 => "no_application_protocol",
+        _ => "unknown",
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn key_log_lines_are_nss_format() -> Result<(), String> {
+        let mut random = [0u8; 32];
+        random[0] = 0xAB;
+        random[31] = 0x01;
+        let text = line(CLIENT_HANDSHAKE, &random, &[0x0F, 0xF0]);
+        let mut parts = text.split(' ');
+        check_eq!(parts.next(), Some(CLIENT_HANDSHAKE));
+        let random_hex = parts.next().unwrap_or("");
+        check_eq!(random_hex.len(), 64);
+        check!(random_hex.starts_with("ab00") && random_hex.ends_with("0001"));
+        check_eq!(parts.next(), Some("0ff0"));
+        check_eq!(parts.next(), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn key_log_goes_to_serial_or_a_path() -> Result<(), String> {
+        check_eq!(Sink::parse("serial"), Some(Sink::Serial));
+        check_eq!(Sink::parse("/tmp/keys.log"), Some(Sink::File(String::from("/tmp/keys.log"))));
+        check_eq!(Sink::parse("keys.log"), None);
+        check_eq!(Sink::parse("/"), None);
+        Ok(())
+    }
+
+    /// Taking a ServerHello logs both handshake secrets under the random
+    /// of the ClientHello
+    #[kernel_test]
+    fn handshake_writes_the_key_log() -> Result<(), String> {
+        use crate::tls::TlsConnection;
+        const PATH: &str = "/tmp/keylog-test.log";
+        let _ = fs::remove(PATH);
+        let previous = config::get("tls.keylog").unwrap_or_default();
+        config::set("tls.keylog", PATH).map_err(|e| format!("{}", e))?;
+
+        let mut tls = TlsConnection::for_host("keylog.webbos.test");
+        tls.generate_client_hello();
+        let mut hello = alloc::vec![2u8, 0, 0, 0, 0x03, 0x03];
+        hello.extend_from_slice(&[0x5A; 32]);
+        hello.extend_from_slice(&[0, 0x13, 0x03, 0, 0, 40, 0x00, 0x33, 0, 36, 0x00, 0x1D, 0, 32]);
+        hello.extend_from_slice(&[9; 32]);
+        hello[3] = (hello.len() - 4) as u8;
+        let result = tls.process_server_hello(&hello);
+        let written = fs::read_file(PATH);
+        let _ = config::set("tls.keylog", &previous);
+        let _ = fs::remove(PATH);
+
+        check_eq!(result, Ok(()));
+        let written = String::from_utf8(written.map_err(|e| format!("{:?}", e))?).map_err(|e| format!("{}", e))?;
+        let random = hex(&tls.client_random);
+        let lines: alloc::vec::Vec<&str> = written.lines().collect();
+        check_eq!(lines.len(), 2);
+        check!(lines[0].starts_with(&format!("{} {} ", CLIENT_HANDSHAKE, random)));
+        check!(lines[1].starts_with(&format!("{} {} ", SERVER_HANDSHAKE, random)));
+        check_eq!(lines[1], line(SERVER_HANDSHAKE, &tls.client_random, &tls.server_handshake_secret).as_str());
+        Ok(())
+    }
+}
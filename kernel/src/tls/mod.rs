@@ -1,15 +1,26 @@
 //! TLS 1.3 Implementation
 //!
 //! Implementation of TLS 1.3 (RFC 8446) for WebbOS.
+//!
+//! A server is trusted once its chain passes `certs::verify_chain`, its
+//! CertificateVerify is signed by that certificate's key, and its Finished
+//! MAC is right; the state is `FinishedReceived` only then.
+
+pub mod certs;
+pub mod keylog;
 
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 
 use crate::crypto::sha256::{self, Sha256};
-use crate::crypto::chacha20::{ChaCha20Poly1305, KEY_SIZE as CHACHA_KEY_SIZE, NONCE_SIZE};
+use crate::crypto::chacha20::{ChaCha20Poly1305, KEY_SIZE as CHACHA_KEY_SIZE, NONCE_SIZE, TAG_SIZE};
 use crate::crypto::hkdf;
+use crate::crypto::ecdsa::Curve;
+use crate::crypto::{ct, Hash};
 use crate::crypto::x25519::{self, PrivateKey, PublicKey, SharedSecret};
-use crate::println;
+use crate::{debug, info, warn};
 
 /// TLS record types
 #[repr(u8)]
@@ -64,6 +75,8 @@ pub enum NamedGroup {
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SignatureScheme {
+    RsaPkcs1Sha256 = 0x0401,
+    RsaPkcs1Sha384 = 0x0501,
     EcdsaSecp256r1Sha256 = 0x0403,
     EcdsaSecp384r1Sha384 = 0x0503,
     EcdsaSecp521r1Sha512 = 0x0603,
@@ -73,10 +86,25 @@ pub enum SignatureScheme {
     RsaPssRsaeSha512 = 0x0806,
 }
 
+/// Bytes of a record header: type, version, length
+pub const RECORD_HEADER_SIZE: usize = 5;
+
 /// TLS connection state
 pub struct TlsConnection {
     state: TlsState,
     cipher_suite: Option<CipherSuite>,
+    // Server name, which its certificate is pinned or trusted for
+    host: String,
+    // Random of our ClientHello, which names the connection in the key log
+    client_random: [u8; 32],
+    // Our X25519 share
+    private_key: PrivateKey,
+    // Handshake messages so far, which the secrets are derived over
+    transcript: Vec<u8>,
+    // Decrypted handshake bytes not yet a whole message
+    pending: Vec<u8>,
+    // Key of the certificate the server sent, which must sign the handshake
+    server_key: Option<certs::PublicKey>,
     // Handshake secrets
     client_handshake_secret: [u8; 32],
     server_handshake_secret: [u8; 32],
@@ -143,6 +171,12 @@ impl TlsConnection {
         Self {
             state: TlsState::Initial,
             cipher_suite: None,
+            host: String::new(),
+            client_random: [0; 32],
+            private_key: [0; 32],
+            transcript: Vec::new(),
+            pending: Vec::new(),
+            server_key: None,
             client_handshake_secret: [0; 32],
             server_handshake_secret: [0; 32],
             client_application_secret: [0; 32],
@@ -156,6 +190,11 @@ impl TlsConnection {
         }
     }
 
+    /// Connection to `host`, whose certificate is checked against it
+    pub fn for_host(host: &str) -> Self {
+        Self { host: host.to_string(), ..Self::new() }
+    }
+
     /// Generate Client Hello message
     pub fn generate_client_hello(&mut self) -> Vec<u8> {
         let mut msg = Vec::new();
@@ -171,8 +210,10 @@ impl TlsConnection {
         msg.extend_from_slice(&0x0303u16.to_be_bytes());
         
         // Random (32 bytes)
-        let random: [u8; 32] = [0x42; 32]; // TODO: use proper random
+        let mut random = [0u8; 32];
+        crate::crypto::rng::fill_bytes(&mut random);
         msg.extend_from_slice(&random);
+        self.client_random = random;
         
         // Legacy session ID length
         msg.push(0);
@@ -193,11 +234,45 @@ impl TlsConnection {
         msg.extend_from_slice(&[0, 0]);
         
         // Supported Versions extension (TLS 1.3)
-        msg.extend_from_slice(&0x002du16.to_be_bytes()); // supported_versions
+        msg.extend_from_slice(&0x002bu16.to_be_bytes()); // supported_versions
         msg.extend_from_slice(&0x0003u16.to_be_bytes()); // length
         msg.push(2); // length of versions
         msg.extend_from_slice(&0x0304u16.to_be_bytes()); // TLS 1.3
         
+        // Server Name extension, which servers with many names pick by
+        if !self.host.is_empty() && self.host.len() < 256 {
+            let name = self.host.as_bytes();
+            msg.extend_from_slice(&0x0000u16.to_be_bytes()); // server_name
+            msg.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes()); // length
+            msg.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes()); // server_name_list length
+            msg.push(0); // host_name
+            msg.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            msg.extend_from_slice(name);
+        }
+        
+        // Supported Groups extension, needed beside a key share
+        msg.extend_from_slice(&0x000au16.to_be_bytes()); // supported_groups
+        msg.extend_from_slice(&0x0004u16.to_be_bytes()); // length
+        msg.extend_from_slice(&0x0002u16.to_be_bytes()); // named_group_list length
+        msg.extend_from_slice(&(NamedGroup::X25519 as u16).to_be_bytes());
+        
+        // Signature Algorithms extension, the ones `certs` can check; PKCS
+        // #1 v1.5 is only for the signatures on certificates
+        let schemes = [
+            SignatureScheme::EcdsaSecp256r1Sha256,
+            SignatureScheme::EcdsaSecp384r1Sha384,
+            SignatureScheme::RsaPssRsaeSha256,
+            SignatureScheme::RsaPssRsaeSha384,
+            SignatureScheme::RsaPkcs1Sha256,
+            SignatureScheme::RsaPkcs1Sha384,
+        ];
+        msg.extend_from_slice(&0x000du16.to_be_bytes()); // signature_algorithms
+        msg.extend_from_slice(&(schemes.len() as u16 * 2 + 2).to_be_bytes()); // length
+        msg.extend_from_slice(&(schemes.len() as u16 * 2).to_be_bytes()); // supported_signature_algorithms length
+        for scheme in schemes {
+            msg.extend_from_slice(&(scheme as u16).to_be_bytes());
+        }
+        
         // Key Share extension
         let (private_key, public_key) = x25519::generate_keypair();
         msg.extend_from_slice(&0x0033u16.to_be_bytes()); // key_share
@@ -206,6 +281,7 @@ impl TlsConnection {
         msg.extend_from_slice(&0x001du16.to_be_bytes()); // x25519
         msg.extend_from_slice(&(32u16).to_be_bytes()); // key_exchange length
         msg.extend_from_slice(&public_key);
+        self.private_key = private_key;
         
         // Update extensions length
         let ext_len = msg.len() - ext_len_offset - 2;
@@ -217,19 +293,24 @@ impl TlsConnection {
         msg[len_offset + 1] = (msg_len >> 8) as u8;
         msg[len_offset + 2] = msg_len as u8;
         
-        self.state = TlsState::ClientHelloSent;
+        keylog::trace(format_args!("ClientHello: random {}, TLS_CHACHA20_POLY1305_SHA256, x25519 share {}",
+            keylog::hex(&random), keylog::hex(&public_key)));
+        keylog::trace_message("sent", &msg);
+        self.transcript.extend_from_slice(&msg);
+        self.set_state(TlsState::ClientHelloSent);
         msg
     }
 
     /// Process Server Hello
     pub fn process_server_hello(&mut self, data: &[u8]) -> Result<(), TlsError> {
+        keylog::trace_message("received", data);
         if data.len() < 4 {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("ServerHello shorter than its header", TlsError::InvalidMessage));
         }
         
         let msg_type = data[0];
         if msg_type != HandshakeType::ServerHello as u8 {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("expected ServerHello", TlsError::InvalidMessage));
         }
         
         let msg_len = ((data[1] as usize) << 16) |
@@ -237,7 +318,7 @@ impl TlsConnection {
                       (data[3] as usize);
         
         if data.len() < 4 + msg_len {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("ServerHello shorter than its length", TlsError::InvalidMessage));
         }
         
         // Parse Server Hello (simplified)
@@ -245,40 +326,246 @@ impl TlsConnection {
         
         // Legacy version
         if data.len() < pos + 2 {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("ServerHello cut off in its version", TlsError::InvalidMessage));
         }
         pos += 2;
         
         // Random
         if data.len() < pos + 32 {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("ServerHello cut off in its random", TlsError::InvalidMessage));
         }
+        keylog::trace(format_args!("ServerHello: random {}", keylog::hex(&data[pos..pos + 32])));
         pos += 32;
         
         // Legacy session ID
         if data.len() < pos + 1 {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("ServerHello cut off before its session ID", TlsError::InvalidMessage));
         }
         let session_id_len = data[pos] as usize;
         pos += 1 + session_id_len;
         
         // Cipher suite
         if data.len() < pos + 2 {
-            return Err(TlsError::InvalidMessage);
+            return Err(refused("ServerHello cut off before its cipher suite", TlsError::InvalidMessage));
         }
         let cipher_suite = u16::from_be_bytes([data[pos], data[pos + 1]]);
         self.cipher_suite = match cipher_suite {
             0x1303 => Some(CipherSuite::Chacha20Poly1305Sha256),
-            _ => return Err(TlsError::UnsupportedCipherSuite),
+            _ => {
+                keylog::trace(format_args!("server chose cipher suite {:#06x}", cipher_suite));
+                return Err(refused("cipher suite not offered", TlsError::UnsupportedCipherSuite));
+            }
         };
         pos += 2;
         
-        self.state = TlsState::ServerHelloReceived;
+        // Legacy compression method, then the extensions
+        pos += 1;
+        if data.len() < pos + 2 {
+            return Err(refused("ServerHello cut off before its extensions", TlsError::InvalidMessage));
+        }
+        let end = (pos + 2 + u16::from_be_bytes([data[pos], data[pos + 1]]) as usize).min(4 + msg_len);
+        pos += 2;
+        let mut server_share = None;
+        while pos + 4 <= end {
+            let kind = u16::from_be_bytes([data[pos], data[pos + 1]]);
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            let body = data.get(pos + 4..pos + 4 + len)
+                .ok_or_else(|| refused("ServerHello extension cut off", TlsError::InvalidMessage))?;
+            // key_share: group, length, key
+            if kind == 0x0033 && len == 36 && body[..2] == (NamedGroup::X25519 as u16).to_be_bytes() {
+                let mut share: PublicKey = [0; 32];
+                share.copy_from_slice(&body[4..]);
+                server_share = Some(share);
+            }
+            pos += 4 + len;
+        }
+        let server_share = server_share
+            .ok_or_else(|| refused("ServerHello has no X25519 key share", TlsError::HandshakeFailure))?;
+        
+        keylog::trace(format_args!("ServerHello: TLS_CHACHA20_POLY1305_SHA256, x25519 share {}", keylog::hex(&server_share)));
+        self.transcript.extend_from_slice(&data[..4 + msg_len]);
+        self.set_state(TlsState::ServerHelloReceived);
+        let shared_secret = x25519::shared_secret(&self.private_key, &server_share);
+        self.derive_handshake_secrets(&shared_secret);
+        Ok(())
+    }
+
+    /// Take one record from the server; a certificate its pins or the
+    /// anchors do not trust fails with `CertificateError`
+    pub fn process_record(&mut self, record: &[u8]) -> Result<(), TlsError> {
+        keylog::trace_record("received", record);
+        if record.len() < RECORD_HEADER_SIZE {
+            return Err(refused("record shorter than its header", TlsError::InvalidMessage));
+        }
+        let fragment = &record[RECORD_HEADER_SIZE..];
+        match record[0] {
+            t if t == ContentType::Handshake as u8 && self.state == TlsState::ClientHelloSent => {
+                self.process_server_hello(fragment)
+            }
+            // Sent for middleboxes; means nothing in TLS 1.3
+            t if t == ContentType::ChangeCipherSpec as u8 => Ok(()),
+            t if t == ContentType::Alert as u8 => Err(refused("server sent an alert", TlsError::AlertReceived)),
+            t if t == ContentType::ApplicationData as u8 && self.state != TlsState::ClientHelloSent => {
+                let (kind, plaintext) = self.decrypt_record(record)?;
+                match kind {
+                    t if t == ContentType::Handshake as u8 => self.process_handshake(&plaintext),
+                    t if t == ContentType::Alert as u8 => Err(refused("server sent an alert", TlsError::AlertReceived)),
+                    _ => Err(refused("unexpected record in the handshake", TlsError::InvalidMessage)),
+                }
+            }
+            _ => Err(refused("unexpected record", TlsError::InvalidMessage)),
+        }
+    }
+
+    /// Open a record sealed with the server's handshake key; returns the
+    /// inner content type and the plaintext
+    fn decrypt_record(&mut self, record: &[u8]) -> Result<(u8, Vec<u8>), TlsError> {
+        if record.len() < RECORD_HEADER_SIZE + TAG_SIZE + 1 {
+            return Err(refused("encrypted record too short", TlsError::InvalidMessage));
+        }
+        let mut nonce = self.server_write_iv;
+        for (n, s) in nonce[NONCE_SIZE - 8..].iter_mut().zip(self.server_seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        let (body, tag) = record[RECORD_HEADER_SIZE..].split_at(record.len() - RECORD_HEADER_SIZE - TAG_SIZE);
+        let mut plaintext = body.to_vec();
+        let mut tag_bytes = [0u8; TAG_SIZE];
+        tag_bytes.copy_from_slice(tag);
+        if !ChaCha20Poly1305::decrypt_in_place(&self.server_write_key, &nonce, &record[..RECORD_HEADER_SIZE], &mut plaintext, &tag_bytes) {
+            return Err(refused("record does not decrypt", TlsError::BadRecordMac));
+        }
+        self.server_seq += 1;
+        // The content type is the last byte that is not padding
+        while plaintext.last() == Some(&0) {
+            plaintext.pop();
+        }
+        let kind = plaintext.pop().ok_or_else(|| refused("record is all padding", TlsError::InvalidMessage))?;
+        Ok((kind, plaintext))
+    }
+
+    /// Take the handshake messages in decrypted bytes, keeping a message
+    /// that goes on in the next record; each must come in its turn
+    fn process_handshake(&mut self, data: &[u8]) -> Result<(), TlsError> {
+        self.pending.extend_from_slice(data);
+        while self.pending.len() >= 4 {
+            let len = (self.pending[1] as usize) << 16 | (self.pending[2] as usize) << 8 | self.pending[3] as usize;
+            if self.pending.len() < 4 + len {
+                break;
+            }
+            let message: Vec<u8> = self.pending.drain(..4 + len).collect();
+            keylog::trace_message("received", &message);
+            match (message[0], self.state) {
+                (t, TlsState::ServerHelloReceived) if t == HandshakeType::EncryptedExtensions as u8 => {
+                    self.set_state(TlsState::EncryptedExtensionsReceived)
+                }
+                // Answered with no certificate, when the client Finished is sent
+                (t, TlsState::EncryptedExtensionsReceived) if t == HandshakeType::CertificateRequest as u8 => {}
+                (t, TlsState::EncryptedExtensionsReceived) if t == HandshakeType::Certificate as u8 => {
+                    self.process_certificate(&message[4..])?
+                }
+                (t, TlsState::CertificateReceived) if t == HandshakeType::CertificateVerify as u8 => {
+                    self.process_certificate_verify(&message[4..])?
+                }
+                (t, TlsState::CertificateVerifyReceived) if t == HandshakeType::Finished as u8 => {
+                    self.process_finished(&message[4..])?
+                }
+                _ => return Err(refused("handshake message out of turn", TlsError::InvalidMessage)),
+            }
+            self.transcript.extend_from_slice(&message);
+        }
+        Ok(())
+    }
+
+    /// Check the chain in a Certificate message against the host, the
+    /// pins and the trust anchors
+    pub fn process_certificate(&mut self, body: &[u8]) -> Result<(), TlsError> {
+        let malformed = || refused("Certificate message is malformed", TlsError::InvalidMessage);
+        let u24 = |b: &[u8]| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        // Request context, then the list
+        let context_len = *body.first().ok_or_else(malformed)? as usize;
+        let list = body.get(1 + context_len..4 + context_len).ok_or_else(malformed)?;
+        let end = 4 + context_len + u24(list);
+        let mut pos = 4 + context_len;
+        if body.len() < end {
+            return Err(malformed());
+        }
+        let mut chain = Vec::new();
+        while pos < end {
+            let len = u24(body.get(pos..pos + 3).ok_or_else(malformed)?);
+            chain.push(body.get(pos + 3..pos + 3 + len).ok_or_else(malformed)?.to_vec());
+            pos += 3 + len;
+            // Each entry's extensions
+            let ext = body.get(pos..pos + 2).ok_or_else(malformed)?;
+            pos += 2 + u16::from_be_bytes([ext[0], ext[1]]) as usize;
+        }
+        keylog::trace(format_args!("Certificate: {} in the chain", chain.len()));
+        match certs::verify_chain(&self.host, &chain) {
+            Ok(certificate) => self.server_key = Some(certificate.public_key),
+            Err(e) => {
+                warn!("tls", "Refusing {}: {}", self.host, e);
+                return Err(refused(&format!("certificate for {}: {}", self.host, e), TlsError::CertificateError));
+            }
+        }
+        self.set_state(TlsState::CertificateReceived);
+        Ok(())
+    }
+
+    /// Check the server's signature of the transcript so far, which shows
+    /// that it holds the key of the certificate it sent
+    fn process_certificate_verify(&mut self, body: &[u8]) -> Result<(), TlsError> {
+        let malformed = || refused("CertificateVerify message is malformed", TlsError::InvalidMessage);
+        let header = body.get(..4).ok_or_else(malformed)?;
+        let scheme = u16::from_be_bytes([header[0], header[1]]);
+        let signature = &body[4..];
+        if signature.len() != u16::from_be_bytes([header[2], header[3]]) as usize {
+            return Err(malformed());
+        }
+        let key = self.server_key.as_ref().ok_or_else(malformed)?;
+        let algorithm = match key {
+            certs::PublicKey::Ec(Curve::P256, _) if scheme == SignatureScheme::EcdsaSecp256r1Sha256 as u16 => {
+                certs::SignatureAlgorithm::Ecdsa(Hash::Sha256)
+            }
+            certs::PublicKey::Ec(Curve::P384, _) if scheme == SignatureScheme::EcdsaSecp384r1Sha384 as u16 => {
+                certs::SignatureAlgorithm::Ecdsa(Hash::Sha384)
+            }
+            certs::PublicKey::Rsa(_) if scheme == SignatureScheme::RsaPssRsaeSha256 as u16 => {
+                certs::SignatureAlgorithm::RsaPss(Hash::Sha256)
+            }
+            certs::PublicKey::Rsa(_) if scheme == SignatureScheme::RsaPssRsaeSha384 as u16 => {
+                certs::SignatureAlgorithm::RsaPss(Hash::Sha384)
+            }
+            _ => {
+                warn!("tls", "Refusing {}: signature scheme {:#06x} does not fit its key", self.host, scheme);
+                return Err(refused("CertificateVerify scheme was not offered for this key", TlsError::CertificateError));
+            }
+        };
+        // RFC 8446, section 4.4.3
+        let mut content = alloc::vec![0x20u8; 64];
+        content.extend_from_slice(b"TLS 1.3, server CertificateVerify");
+        content.push(0);
+        content.extend_from_slice(&sha256::hash(&self.transcript));
+        if !key.verify(algorithm, &content, signature) {
+            warn!("tls", "Refusing {}: the handshake is not signed by its certificate's key", self.host);
+            return Err(refused("CertificateVerify signature does not verify", TlsError::CertificateError));
+        }
+        self.set_state(TlsState::CertificateVerifyReceived);
+        Ok(())
+    }
+
+    /// Check the server's Finished MAC over the transcript so far
+    fn process_finished(&mut self, verify_data: &[u8]) -> Result<(), TlsError> {
+        let finished_key = hkdf::expand_label(&self.server_handshake_secret, hkdf::labels::FINISHED, &[], sha256::DIGEST_SIZE as u16);
+        let expected = sha256::hmac(&finished_key, &sha256::hash(&self.transcript));
+        if !ct::eq(&expected, verify_data) {
+            return Err(refused("server Finished does not verify", TlsError::DecryptError));
+        }
+        self.set_state(TlsState::FinishedReceived);
         Ok(())
     }
 
-    /// Derive handshake secrets
-    pub fn derive_handshake_secrets(&mut self, shared_secret: &SharedSecret) {
+    /// Derive handshake secrets from the X25519 result and the transcript
+    /// so far, and write them to the key log
+    fn derive_handshake_secrets(&mut self, shared_secret: &SharedSecret) {
         // Early Secret = HKDF-Extract(0, 0)
         let early_secret = hkdf::extract(&[0u8; 32], &[0u8; 32]);
         
@@ -287,13 +574,17 @@ impl TlsConnection {
         let handshake_secret = hkdf::extract(&derived, shared_secret);
         
         // client_handshake_traffic_secret
-        let chts = hkdf::derive_secret(&handshake_secret, hkdf::labels::CLIENT_HANDSHAKE_TRAFFIC, &[]);
+        let chts = hkdf::derive_secret(&handshake_secret, hkdf::labels::CLIENT_HANDSHAKE_TRAFFIC, &self.transcript);
         self.client_handshake_secret.copy_from_slice(&chts[..32]);
         
         // server_handshake_traffic_secret
-        let shts = hkdf::derive_secret(&handshake_secret, hkdf::labels::SERVER_HANDSHAKE_TRAFFIC, &[]);
+        let shts = hkdf::derive_secret(&handshake_secret, hkdf::labels::SERVER_HANDSHAKE_TRAFFIC, &self.transcript);
         self.server_handshake_secret.copy_from_slice(&shts[..32]);
         
+        keylog::trace(format_args!("derived handshake traffic secrets"));
+        keylog::log_secret(keylog::CLIENT_HANDSHAKE, &self.client_random, &self.client_handshake_secret);
+        keylog::log_secret(keylog::SERVER_HANDSHAKE, &self.client_random, &self.server_handshake_secret);
+        
         // Derive keys and IVs
         self.derive_keys();
     }
@@ -349,6 +640,34 @@ impl TlsConnection {
     pub fn state(&self) -> TlsState {
         self.state
     }
+
+    fn set_state(&mut self, state: TlsState) {
+        keylog::trace(format_args!("{:?} -> {:?}", self.state, state));
+        self.state = state;
+    }
+}
+
+/// Trace why a handshake message was refused
+fn refused(reason: &str, error: TlsError) -> TlsError {
+    keylog::trace(format_args!("handshake failed: {} ({:?})", reason, error));
+    error
+}
+
+/// `fragment` in a record of `content_type`
+pub fn record(content_type: ContentType, fragment: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + fragment.len());
+    record.push(content_type as u8);
+    // Legacy version; TLS 1.0 for the first ClientHello, as most clients send
+    record.extend_from_slice(&0x0301u16.to_be_bytes());
+    record.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+    record.extend_from_slice(fragment);
+    record
+}
+
+/// Length of the whole record at the start of `data`, once it is all there
+pub fn record_len(data: &[u8]) -> Option<usize> {
+    let len = RECORD_HEADER_SIZE + u16::from_be_bytes([*data.get(3)?, *data.get(4)?]) as usize;
+    (data.len() >= len).then_some(len)
 }
 
 impl Default for TlsConnection {
@@ -374,26 +693,154 @@ pub enum TlsError {
 
 /// Initialize TLS subsystem
 pub fn init() {
-    println!("[tls] TLS 1.3 subsystem initialized");
-    println!("[tls] Supported cipher suites:");
-    println!("      - TLS_CHACHA20_POLY1305_SHA256");
-    println!("      - TLS_AES_128_GCM_SHA256 (planned)");
-    println!("      - TLS_AES_256_GCM_SHA384 (planned)");
-    println!("[tls] Supported key exchange: X25519");
+    info!("tls", "TLS 1.3 subsystem initialized");
+    info!("tls", "Supported cipher suites: TLS_CHACHA20_POLY1305_SHA256, TLS_AES_128_GCM_SHA256 (planned), TLS_AES_256_GCM_SHA384 (planned)");
+    info!("tls", "Supported key exchange: X25519");
 }
 
 /// Create new TLS connection
 pub fn connect(host: &str) -> Result<TlsConnection, TlsError> {
-    println!("[tls] Initiating TLS connection to {}", host);
+    debug!("tls", "Initiating TLS connection to {}", host);
     
-    let mut conn = TlsConnection::new();
+    let mut conn = TlsConnection::for_host(host.rsplit_once(':').map_or(host, |(name, _)| name));
     
     // Generate Client Hello
     let client_hello = conn.generate_client_hello();
-    println!("[tls] Generated Client Hello ({} bytes)", client_hello.len());
+    debug!("tls", "Generated Client Hello ({} bytes)", client_hello.len());
     
     // In a real implementation, send over network and receive Server Hello
     // For now, just return the connection in initial state
     
     Ok(conn)
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    /// Self-signed P-256 certificate for pinned.webbos.test
+    const PINNED_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBZDCCAQqgAwIBAgIUHlivJMMfm8BSW4fJxnUrDXhQxTgwCgYIKoZIzj0EAwIw
+MjETMBEGA1UECgwKV2ViYk9TIExhYjEbMBkGA1UEAwwScGlubmVkLndlYmJvcy50
+ZXN0MB4XDTI2MTAxODAwMDAwMFoXDTM2MTAxNTAwMDAwMFowMjETMBEGA1UECgwK
+V2ViYk9TIExhYjEbMBkGA1UEAwwScGlubmVkLndlYmJvcy50ZXN0MFkwEwYHKoZI
+zj0CAQYIKoZIzj0DAQcDQgAE+1qAq+txtCni/s8q3riMi5mc1Ullxtcsf2oiagF2
+4AwLTCF7oVH6FOF2ko2SEwtm/pM7Hv9CYEUxUm83ITa7LjAKBggqhkjOPQQDAgNI
+ADBFAiEA0NDniP80YynVl6NmU6IwI8iVooxvs/qd5/Jpngs+dM4CICe+MOzsf0nn
+FfQJsnKgT358xhmzSwwTR5I4M89GaY24
+-----END CERTIFICATE-----
+";
+    /// Its key's signature in a CertificateVerify, after only the
+    /// Certificate message
+    const PINNED_SIGNATURE: [u8; 71] = [
+        0x30, 0x45, 0x02, 0x20, 0x7c, 0xf7, 0x37, 0x52, 0xef, 0x7e, 0xb0, 0x9b, 0xff, 0x24, 0xef, 0x3b,
+        0x21, 0x3b, 0xad, 0x72, 0xe9, 0x8d, 0x8a, 0x4e, 0xc1, 0xdd, 0xcd, 0x71, 0xb6, 0x74, 0xee, 0x94,
+        0xb8, 0xa9, 0x7a, 0xd8, 0x02, 0x21, 0x00, 0xb1, 0x5a, 0x7c, 0xbc, 0xdb, 0xef, 0xd4, 0x46, 0xe7,
+        0xa6, 0xac, 0x7f, 0x51, 0xe2, 0x23, 0x1a, 0xf3, 0x1b, 0x33, 0xb4, 0xb6, 0x4c, 0xbb, 0xf3, 0x96,
+        0x0b, 0x92, 0x02, 0xac, 0xd1, 0xd5, 0xe2,
+    ];
+    /// Another key's signature of the same
+    const OTHER_SIGNATURE: [u8; 72] = [
+        0x30, 0x46, 0x02, 0x21, 0x00, 0xa4, 0xc8, 0x86, 0xef, 0x42, 0xca, 0x30, 0x40, 0xc3, 0x40, 0x06,
+        0x7a, 0x12, 0x84, 0xf9, 0xc1, 0xcb, 0x69, 0xac, 0xd6, 0x8c, 0xf2, 0xc5, 0xea, 0x56, 0x64, 0xef,
+        0x12, 0x0f, 0xa6, 0x03, 0x2c, 0x02, 0x21, 0x00, 0xcc, 0xec, 0x9b, 0x1a, 0x59, 0x25, 0x85, 0x28,
+        0xf6, 0x33, 0x9d, 0x55, 0x2f, 0x66, 0x9b, 0x10, 0x22, 0x84, 0x0f, 0x8c, 0x9d, 0xc7, 0x34, 0x41,
+        0x61, 0xd7, 0xfa, 0xc4, 0xef, 0x51, 0xaf, 0x22,
+    ];
+    /// Server Finished after both, from a handshake secret of 0x42s
+    const FINISHED: [u8; 32] = [
+        0x45, 0xea, 0x4e, 0x4a, 0xec, 0xc9, 0x89, 0xd1, 0x7e, 0x7f, 0x05, 0xe6, 0x62, 0xf7, 0x62, 0xc6,
+        0x99, 0x15, 0xa8, 0x6e, 0xf8, 0x58, 0x05, 0x22, 0xb8, 0x53, 0x5c, 0xbb, 0x63, 0x8d, 0x8b, 0x20,
+    ];
+
+    const HOST: &str = "pinned.webbos.test";
+
+    /// A handshake message of `kind`
+    fn message(kind: HandshakeType, body: &[u8]) -> Vec<u8> {
+        let mut message = alloc::vec![kind as u8, (body.len() >> 16) as u8, (body.len() >> 8) as u8, body.len() as u8];
+        message.extend_from_slice(body);
+        message
+    }
+
+    /// The Certificate message with PINNED_PEM, and a CertificateVerify
+    /// with `signature` by ECDSA P-256
+    fn server_flight(signature: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let der = certs::parse_pem(PINNED_PEM).map_err(|e| format!("{}", e))?.remove(0);
+        let entry = der.len() + 3 + 2;
+        let mut body = alloc::vec![0u8, 0, (entry >> 8) as u8, entry as u8, 0, (der.len() >> 8) as u8, der.len() as u8];
+        body.extend_from_slice(&der);
+        body.extend_from_slice(&[0, 0]);
+        let mut verify = (SignatureScheme::EcdsaSecp256r1Sha256 as u16).to_be_bytes().to_vec();
+        verify.extend_from_slice(&(signature.len() as u16).to_be_bytes());
+        verify.extend_from_slice(signature);
+        Ok((message(HandshakeType::Certificate, &body), message(HandshakeType::CertificateVerify, &verify)))
+    }
+
+    /// A connection to HOST that has its handshake keys
+    fn connection() -> TlsConnection {
+        let mut tls = TlsConnection::for_host(HOST);
+        tls.state = TlsState::EncryptedExtensionsReceived;
+        tls.server_handshake_secret = [0x42; 32];
+        tls
+    }
+
+    /// The pinned certificate is public; a server that sends it must also
+    /// sign this handshake with its key, and MAC it with the handshake's
+    #[kernel_test]
+    fn pinned_servers_must_prove_the_key() -> Result<(), String> {
+        certs::pin(HOST, PINNED_PEM).map_err(|e| format!("{}", e))?;
+        let (certificate, verify) = server_flight(&PINNED_SIGNATURE)?;
+        let (_, forged) = server_flight(&OTHER_SIGNATURE)?;
+
+        // Replayed without the key: another key's signature, the right
+        // signature of another transcript, or none at all
+        let mut tls = connection();
+        check_eq!(tls.process_handshake(&certificate), Ok(()));
+        check_eq!(tls.state(), TlsState::CertificateReceived);
+        check_eq!(tls.process_handshake(&forged), Err(TlsError::CertificateError));
+        check_eq!(tls.state(), TlsState::CertificateReceived);
+
+        let mut tls = connection();
+        tls.transcript.extend_from_slice(b"an earlier ClientHello");
+        check_eq!(tls.process_handshake(&certificate), Ok(()));
+        check_eq!(tls.process_handshake(&verify), Err(TlsError::CertificateError));
+
+        let mut tls = connection();
+        check_eq!(tls.process_handshake(&certificate), Ok(()));
+        check_eq!(tls.process_handshake(&message(HandshakeType::Finished, &FINISHED)), Err(TlsError::InvalidMessage));
+
+        // With the key, and then the Finished MAC
+        let mut tls = connection();
+        check_eq!(tls.process_handshake(&certificate), Ok(()));
+        check_eq!(tls.process_handshake(&verify), Ok(()));
+        check_eq!(tls.state(), TlsState::CertificateVerifyReceived);
+        let mut wrong = FINISHED;
+        wrong[0] ^= 1;
+        check_eq!(tls.process_handshake(&message(HandshakeType::Finished, &wrong)), Err(TlsError::DecryptError));
+        check_eq!(tls.state(), TlsState::CertificateVerifyReceived);
+        check_eq!(tls.process_handshake(&message(HandshakeType::Finished, &FINISHED)), Ok(()));
+        check_eq!(tls.state(), TlsState::FinishedReceived);
+
+        certs::unpin(HOST).map_err(|e| format!("{}", e))?;
+        // Unpinned, nothing vouches for it
+        let mut tls = connection();
+        check_eq!(tls.process_handshake(&certificate), Err(TlsError::CertificateError));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn handshake_messages_come_in_turn() -> Result<(), String> {
+        let (certificate, verify) = server_flight(&PINNED_SIGNATURE)?;
+        let mut tls = connection();
+        check_eq!(tls.process_handshake(&verify), Err(TlsError::InvalidMessage));
+        let mut tls = connection();
+        tls.state = TlsState::ServerHelloReceived;
+        check_eq!(tls.process_handshake(&certificate), Err(TlsError::InvalidMessage));
+        check_eq!(tls.process_handshake(&message(HandshakeType::EncryptedExtensions, &[0, 0])), Ok(()));
+        check_eq!(tls.state(), TlsState::EncryptedExtensionsReceived);
+        check!(tls.server_key.is_none());
+        Ok(())
+    }
+}
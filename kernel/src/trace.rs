@@ -0,0 +1,309 @@
+//! Event tracing and per-subsystem counters
+//!
+//! The scheduler, interrupt handlers, block I/O and the network stack
+//! report what they do here: thread switches and packets as instants,
+//! interrupts and block reads and writes as spans from a `begin` stamp.
+//! Every event is counted, and spans add up their time, whether tracing
+//! is on or not. While it is on, events also go, stamped with the TSC,
+//! into a ring of their CPU's own, where the oldest make way; nothing is
+//! locked or allocated, so interrupt handlers can trace too.
+//!
+//! `trace dump` prints the rings. `/proc/trace.json` and `trace export`
+//! give them as Chrome trace JSON, for `chrome://tracing` or Perfetto to
+//! draw as a timeline.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::arch::{apic, cpu};
+use crate::drivers::timer;
+use crate::{print, println};
+
+/// CPUs with a ring of their own; more share them
+const MAX_CPUS: usize = 8;
+/// Events kept per CPU
+const RING_EVENTS: usize = 2048;
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    /// From thread `detail` to thread `arg`
+    Switch = 0,
+    /// Interrupt `detail` handled
+    Irq = 1,
+    /// `arg` blocks at `block_detail(device, block)`
+    BlockRead = 2,
+    BlockWrite = 3,
+    /// A frame of `arg` bytes
+    PacketIn = 4,
+    PacketOut = 5,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 6] = [Kind::Switch, Kind::Irq, Kind::BlockRead, Kind::BlockWrite, Kind::PacketIn, Kind::PacketOut];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Switch => "switch",
+            Kind::Irq => "irq",
+            Kind::BlockRead => "block read",
+            Kind::BlockWrite => "block write",
+            Kind::PacketIn => "packet in",
+            Kind::PacketOut => "packet out",
+        }
+    }
+
+    /// The subsystem, which is the track the event is drawn on
+    fn subsystem(self) -> (&'static str, u32) {
+        match self {
+            Kind::Switch => ("sched", 0),
+            Kind::Irq => ("irq", 1),
+            Kind::BlockRead | Kind::BlockWrite => ("block", 2),
+            Kind::PacketIn | Kind::PacketOut => ("net", 3),
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+}
+
+/// An event as it sits in a ring
+#[derive(Clone, Copy)]
+struct Event {
+    /// TSC when it happened or began
+    tsc: u64,
+    /// TSC ticks it took; 0 for instants
+    ticks: u64,
+    /// Thread, device or interrupt, by kind
+    detail: u64,
+    /// Thread, block count or bytes, by kind
+    arg: u64,
+    kind: u8,
+    cpu: u8,
+}
+
+impl Event {
+    const EMPTY: Self = Self { tsc: 0, ticks: 0, detail: 0, arg: 0, kind: 0, cpu: 0 };
+}
+
+/// One CPU's events. Each event claims its slot with `next` before it is
+/// written, so an interrupt tracing on top of another event takes a slot
+/// of its own.
+struct Ring {
+    events: UnsafeCell<[Event; RING_EVENTS]>,
+    next: AtomicUsize,
+}
+
+// Slots are claimed atomically; a dump while tracing may catch one half
+// written, which only misprints that event
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    const fn new() -> Self {
+        Self { events: UnsafeCell::new([Event::EMPTY; RING_EVENTS]), next: AtomicUsize::new(0) }
+    }
+}
+
+static RINGS: [Ring; MAX_CPUS] = [const { Ring::new() }; MAX_CPUS];
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Events of each kind since boot
+static COUNTS: [AtomicU64; Kind::ALL.len()] = [const { AtomicU64::new(0) }; Kind::ALL.len()];
+/// TSC ticks spent in spans of each kind since boot
+static TICKS: [AtomicU64; Kind::ALL.len()] = [const { AtomicU64::new(0) }; Kind::ALL.len()];
+
+fn cpu_index() -> usize {
+    if apic::is_enabled() {
+        apic::apic_id() as usize % MAX_CPUS
+    } else {
+        0
+    }
+}
+
+fn record(kind: Kind, tsc: u64, ticks: u64, detail: u64, arg: u64) {
+    COUNTS[kind as usize].fetch_add(1, Ordering::Relaxed);
+    TICKS[kind as usize].fetch_add(ticks, Ordering::Relaxed);
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let cpu = cpu_index();
+    let ring = &RINGS[cpu];
+    let slot = ring.next.fetch_add(1, Ordering::Relaxed) % RING_EVENTS;
+    let event = Event { tsc, ticks, detail, arg, kind: kind as u8, cpu: cpu as u8 };
+    unsafe { (*ring.events.get())[slot] = event };
+}
+
+/// Something that happened at once
+pub fn instant(kind: Kind, detail: u64, arg: u64) {
+    record(kind, cpu::rdtsc(), 0, detail, arg);
+}
+
+/// The stamp a span begins at, for `end`
+pub fn begin() -> u64 {
+    cpu::rdtsc()
+}
+
+/// Something that went on from `begin` until now
+pub fn end(kind: Kind, begin: u64, detail: u64, arg: u64) {
+    record(kind, begin, cpu::rdtsc().saturating_sub(begin), detail, arg);
+}
+
+/// Events of `kind` since boot
+pub fn count(kind: Kind) -> u64 {
+    COUNTS[kind as usize].load(Ordering::Relaxed)
+}
+
+/// Empty the rings and keep events from now on
+pub fn start() {
+    ENABLED.store(false, Ordering::SeqCst);
+    for ring in RINGS.iter() {
+        ring.next.store(0, Ordering::SeqCst);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Every event in the rings, oldest first
+fn events() -> Vec<Event> {
+    let mut events = Vec::new();
+    for ring in RINGS.iter() {
+        let count = ring.next.load(Ordering::Relaxed).min(RING_EVENTS);
+        let slots = unsafe { &*ring.events.get() };
+        events.extend_from_slice(&slots[..count]);
+    }
+    events.sort_unstable_by_key(|event| event.tsc);
+    events
+}
+
+/// TSC ticks as nanoseconds
+fn ticks_to_ns(ticks: u64) -> u64 {
+    match timer::tsc_per_ms() {
+        0 => ticks,
+        per_ms => (ticks as u128 * 1_000_000 / per_ms as u128) as u64,
+    }
+}
+
+/// What an event says, for `dump`
+fn describe(event: &Event, kind: Kind) -> String {
+    match kind {
+        Kind::Switch => format!("thread {} -> {}", event.detail, event.arg),
+        Kind::Irq => format!("irq {}", event.detail),
+        Kind::BlockRead | Kind::BlockWrite => {
+            format!("device {}, {} blocks from {}", event.detail >> 48, event.arg, event.detail & 0xFFFF_FFFF_FFFF)
+        }
+        Kind::PacketIn | Kind::PacketOut => format!("{} bytes", event.arg),
+    }
+}
+
+/// Block I/O `detail`: the device in the top 16 bits, the block below
+pub fn block_detail(device: usize, block: u64) -> u64 {
+    (device as u64) << 48 | (block & 0xFFFF_FFFF_FFFF)
+}
+
+/// Print whether tracing is on and the counters
+pub fn print_info() {
+    let kept: usize = RINGS.iter().map(|ring| ring.next.load(Ordering::Relaxed).min(RING_EVENTS)).sum();
+    println!("Tracing: {}, {} events kept", if is_enabled() { "on" } else { "off" }, kept);
+    println!("  {:12} {:>10} {:>12} {:>10}", "Event", "Count", "Time (us)", "Avg (ns)");
+    for kind in Kind::ALL {
+        let count = count(kind);
+        let ns = ticks_to_ns(TICKS[kind as usize].load(Ordering::Relaxed));
+        let average = if count == 0 { 0 } else { ns / count };
+        println!("  {:12} {:>10} {:>12} {:>10}", kind.name(), count, ns / 1000, average);
+    }
+}
+
+/// Print the last `count` events
+pub fn dump(count: usize) {
+    let events = events();
+    let first = match events.first() {
+        Some(event) => event.tsc,
+        None => {
+            println!("No events; trace start keeps them");
+            return;
+        }
+    };
+    for event in &events[events.len().saturating_sub(count)..] {
+        let kind = match Kind::from_u8(event.kind) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let at = ticks_to_ns(event.tsc - first);
+        print!("[{:6}.{:06}] cpu{} {:11} {}", at / 1_000_000_000, at / 1000 % 1_000_000, event.cpu, kind.name(), describe(event, kind));
+        if event.ticks != 0 {
+            print!(" ({} ns)", ticks_to_ns(event.ticks));
+        }
+        println!();
+    }
+}
+
+/// The rings as Chrome trace JSON: a track per subsystem per CPU,
+/// spans as complete events and the rest as instants
+pub fn chrome_json() -> Vec<u8> {
+    let events = events();
+    let first = events.first().map_or(0, |event| event.tsc);
+    let mut json = String::from("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[");
+    let mut tracks: Vec<(u8, u32)> = Vec::new();
+    for event in &events {
+        let kind = match Kind::from_u8(event.kind) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let (subsystem, track) = kind.subsystem();
+        let tid = event.cpu as u32 * 10 + track;
+        if !tracks.contains(&(event.cpu, track)) {
+            tracks.push((event.cpu, track));
+        }
+        if json.ends_with('}') {
+            json.push(',');
+        }
+        // Microseconds, to the nanosecond
+        let ts = ticks_to_ns(event.tsc - first);
+        let _ = write!(json, "{{\"name\":\"{}\",\"cat\":\"{}\",\"pid\":0,\"tid\":{},\"ts\":{}.{:03}",
+            kind.name(), subsystem, tid, ts / 1000, ts % 1000);
+        if event.ticks != 0 {
+            let dur = ticks_to_ns(event.ticks);
+            let _ = write!(json, ",\"ph\":\"X\",\"dur\":{}.{:03}", dur / 1000, dur % 1000);
+        } else {
+            json.push_str(",\"ph\":\"i\",\"s\":\"t\"");
+        }
+        let _ = write!(json, ",\"args\":{{\"detail\":\"{}\"}}}}", describe(event, kind));
+    }
+    // Name the tracks
+    for (cpu, track) in tracks {
+        let name = Kind::ALL.iter().map(|kind| kind.subsystem()).find(|&(_, t)| t == track).map_or("", |(name, _)| name);
+        if json.ends_with('}') {
+            json.push(',');
+        }
+        let _ = write!(json, "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"cpu{} {}\"}}}}",
+            cpu as u32 * 10 + track, cpu, name);
+    }
+    json.push_str("]}\n");
+    json.into_bytes()
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::check_eq;
+
+    #[kernel_test]
+    fn describes_block_io() -> Result<(), String> {
+        let detail = block_detail(2, 4096);
+        let event = Event { detail, arg: 8, ..Event::EMPTY };
+        check_eq!(describe(&event, Kind::BlockRead), "device 2, 8 blocks from 4096");
+        Ok(())
+    }
+}
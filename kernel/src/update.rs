@@ -0,0 +1,288 @@
+//! A/B updates
+//!
+//! The ESP has two copies of the system, `slot-a` and `slot-b`, and
+//! `update.state` saying which boots (see `webbos_shared::update`). An
+//! update is a tar archive of `kernel.elf`, `version` and, if it has
+//! one, `initrd.tar`, signed like a kernel with `tools/sign-kernel.py`.
+//! `install` downloads one over HTTPS and, if its signature checks and
+//! it is no older than the running kernel, writes it over the slot that
+//! is not running and makes that slot active with a few tries. The
+//! bootloader counts them down at each boot; `confirm`, once the kernel
+//! is up, keeps the slot for good. A slot that never confirms is rolled back.
+//!
+//! Slot files are written in place, so the ESP needs them already there
+//! and large enough: `tools/make-slots.py` lays them out.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use webbos_shared::signature::{self, Verdict};
+use webbos_shared::update::{Slot, UpdateState, STATE_FILE};
+
+use crate::fs::fat32::Fat32Fs;
+use crate::fs::{tar, FsError};
+use crate::net::http::{self, HttpError};
+use crate::storage::{self, partition};
+use crate::{cmdline, info, println, warn};
+
+/// Version of the running kernel, which updates may not go below
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The ESP, once it has been found, and its update state
+struct Esp {
+    fs: Fat32Fs,
+    state: UpdateState,
+}
+
+static ESP: Mutex<Option<Esp>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateError {
+    /// No ESP with `update.state`
+    NoSlots,
+    /// Updates come from `https://` URLs only
+    NotHttps,
+    Download(HttpError),
+    /// The server answered with this status
+    Status(u16),
+    /// Signed, but the signature does not check
+    BadSignature,
+    Unsigned,
+    /// This kernel was built without a signing key, so it cannot check
+    /// the update
+    Unchecked,
+    /// Not a tar archive with a `kernel.elf` and a `version`
+    BadBundle,
+    /// The update is older than the running kernel
+    Older,
+    /// The running slot is not confirmed yet, so the other one is what
+    /// it would roll back to
+    Unconfirmed,
+    /// The slot file is missing or too small
+    NoRoom(&'static str),
+    Fs(FsError),
+}
+
+/// Find the ESP with the update slots and read its state; needs the
+/// block devices
+pub fn init() {
+    for idx in 0..storage::device_count() {
+        let found = storage::get_device(idx)
+            .and_then(partition::find_esp)
+            .and_then(|esp| Fat32Fs::new(Box::new(esp)).ok())
+            .or_else(|| storage::get_device(idx).and_then(|device| Fat32Fs::new(device).ok()));
+        let fs = match found {
+            Some(fs) => fs,
+            None => continue,
+        };
+        let state = match fs.read_file(STATE_FILE) {
+            Ok(data) => core::str::from_utf8(&data).ok().and_then(UpdateState::parse),
+            Err(_) => continue,
+        };
+        let state = match state {
+            Some(state) => state,
+            None => {
+                warn!("update", "{} on device {} does not parse", STATE_FILE, idx);
+                continue;
+            }
+        };
+        if let Some(failed) = state.failed {
+            warn!("update", "The update in slot {} never confirmed it booted; rolled back to slot {}",
+                failed.name(), state.active.name());
+        }
+        info!("update", "Booted slot {}; slot {} is active{}", booted().map_or("?", Slot::name), state.active.name(),
+            if state.ok { String::new() } else { format!(", unconfirmed with {} tries left", state.tries) });
+        *ESP.lock() = Some(Esp { fs, state });
+        return;
+    }
+}
+
+/// The slot the bootloader booted, from `slot=` on the command line
+fn booted() -> Option<Slot> {
+    cmdline::get("slot").as_deref().and_then(Slot::parse)
+}
+
+fn save(esp: &mut Esp, state: UpdateState) -> Result<(), UpdateError> {
+    esp.fs.overwrite(STATE_FILE, &state.to_bytes()).map_err(UpdateError::Fs)?;
+    esp.state = state;
+    Ok(())
+}
+
+/// The system is up: keep the slot it booted from, if that was an
+/// unconfirmed update
+pub fn confirm() {
+    let mut esp = ESP.lock();
+    let esp = match esp.as_mut() {
+        Some(esp) => esp,
+        None => return,
+    };
+    let mut state = esp.state;
+    if booted() != Some(state.active) || !state.confirm() {
+        return;
+    }
+    match save(esp, state) {
+        Ok(()) => info!("update", "Slot {} booted; keeping it", state.active.name()),
+        Err(e) => warn!("update", "Cannot confirm slot {}: {:?}", state.active.name(), e),
+    }
+}
+
+/// Download the update at `url` and install it in the slot not running;
+/// returns that slot, which boots next
+pub fn install(url: &str) -> Result<Slot, UpdateError> {
+    if !url.starts_with("https://") {
+        return Err(UpdateError::NotHttps);
+    }
+    if !ESP.lock().as_ref().ok_or(UpdateError::NoSlots)?.state.ok {
+        return Err(UpdateError::Unconfirmed);
+    }
+
+    let response = http::get(url).map_err(UpdateError::Download)?;
+    if response.status != 200 {
+        return Err(UpdateError::Status(response.status));
+    }
+    let bundle = checked(&response.body)?;
+    let entries = tar::entries(bundle).map_err(|_| UpdateError::BadBundle)?;
+    let file = |name: &str| entries.iter().find(|entry| !entry.is_dir && entry.path == name).map(|entry| entry.data);
+    let kernel = file("kernel.elf").ok_or(UpdateError::BadBundle)?;
+    // The version is inside the signed archive, so an old bundle cannot
+    // claim to be new
+    let version = file("version")
+        .and_then(|text| core::str::from_utf8(text).ok())
+        .and_then(parse_version)
+        .ok_or(UpdateError::BadBundle)?;
+    if parse_version(VERSION).map_or(false, |running| version < running) {
+        return Err(UpdateError::Older);
+    }
+    // The bootloader checks the kernel again, but one that it would
+    // refuse is better not installed
+    checked(kernel)?;
+    let initrd = file("initrd.tar");
+
+    let mut esp = ESP.lock();
+    let esp = esp.as_mut().ok_or(UpdateError::NoSlots)?;
+    let slot = esp.state.active.other();
+    let kernel_path = format!("{}/kernel.elf", slot.dir());
+    let initrd_path = format!("{}/initrd.tar", slot.dir());
+    let fits = |path: &str, len: usize| esp.fs.file_size(path).map_or(false, |size| size >= len as u64);
+    if !fits(&kernel_path, kernel.len()) {
+        return Err(UpdateError::NoRoom("kernel.elf"));
+    }
+    if initrd.map_or(false, |initrd| !fits(&initrd_path, initrd.len())) {
+        return Err(UpdateError::NoRoom("initrd.tar"));
+    }
+
+    esp.fs.overwrite(&kernel_path, kernel).map_err(UpdateError::Fs)?;
+    if let Some(initrd) = initrd {
+        esp.fs.overwrite(&initrd_path, initrd).map_err(UpdateError::Fs)?;
+    }
+    let mut state = esp.state;
+    state.install(slot, kernel.len() as u64, initrd.map_or(0, |initrd| initrd.len()) as u64);
+    save(esp, state)?;
+    info!("update", "Installed {} in slot {}: {} bytes of kernel, {} of initrd", url, slot.name(),
+        kernel.len(), initrd.map_or(0, |initrd| initrd.len()));
+    Ok(slot)
+}
+
+/// What of `file` its signature covers, if it checks
+fn checked(file: &[u8]) -> Result<&[u8], UpdateError> {
+    match signature::check(file) {
+        Verdict::Valid(len) => Ok(&file[..len]),
+        Verdict::Unchecked(_) => Err(UpdateError::Unchecked),
+        Verdict::Invalid => Err(UpdateError::BadSignature),
+        Verdict::Unsigned => Err(UpdateError::Unsigned),
+    }
+}
+
+/// `major.minor.patch`, in the order they compare
+fn parse_version(text: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = text.trim().split('.').map(|part| part.parse::<u32>().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+/// Go back to the slot before an unconfirmed update at the next boot;
+/// returns that slot
+pub fn roll_back() -> Result<Option<Slot>, UpdateError> {
+    let mut esp = ESP.lock();
+    let esp = esp.as_mut().ok_or(UpdateError::NoSlots)?;
+    let mut state = esp.state;
+    if !state.roll_back() {
+        return Ok(None);
+    }
+    save(esp, state)?;
+    Ok(Some(state.active))
+}
+
+/// `update`: show the slots, install an update or roll one back
+pub fn command(args: &[&str]) {
+    match args {
+        [] | ["status"] => print_status(),
+        ["install", url] => match install(url) {
+            Ok(slot) => println!("Installed in slot {}; it boots next. Reboot to start it.", slot.name()),
+            Err(e) => println!("update: {:?}", e),
+        },
+        ["rollback"] => match roll_back() {
+            Ok(Some(slot)) => println!("Slot {} boots next", slot.name()),
+            Ok(None) => println!("update: no unconfirmed update to roll back"),
+            Err(e) => println!("update: {:?}", e),
+        },
+        _ => println!("Usage: update [status | install URL | rollback]"),
+    }
+}
+
+fn print_status() {
+    let esp = ESP.lock();
+    let state = match esp.as_ref() {
+        Some(esp) => esp.state,
+        None => {
+            println!("No update slots: no ESP with {}", STATE_FILE);
+            return;
+        }
+    };
+    println!("Booted from slot {}", booted().map_or("?", Slot::name));
+    for slot in [Slot::A, Slot::B] {
+        let mut notes: Vec<String> = Vec::new();
+        if slot == state.active {
+            notes.push(String::from("active"));
+            if !state.ok {
+                notes.push(format!("unconfirmed, {} tries left", state.tries));
+            }
+        }
+        if state.fallback == Some(slot) {
+            notes.push(String::from("fallback"));
+        }
+        if state.failed == Some(slot) {
+            notes.push(String::from("failed to boot"));
+        }
+        println!("  Slot {}: kernel {} bytes, initrd {} bytes  {}", slot.name(), state.kernel_size(slot),
+            state.initrd_size(slot), notes.join(", "));
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn versions_compare_by_number() -> Result<(), String> {
+        check_eq!(parse_version("0.1.0\n"), Some((0, 1, 0)));
+        check!(parse_version("0.10.0") > parse_version("0.9.7"));
+        check!(parse_version("1.0.0") > parse_version("0.99.99"));
+        check_eq!(parse_version("0.1"), None);
+        check_eq!(parse_version("0.1.0.1"), None);
+        check_eq!(parse_version("0.1.x"), None);
+        check!(parse_version(VERSION).is_some());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn updates_come_over_https() -> Result<(), String> {
+        check_eq!(install("http://updates.webbos.test/update.tar"), Err(UpdateError::NotHttps));
+        check_eq!(install("ftp://updates.webbos.test/update.tar"), Err(UpdateError::NotHttps));
+        Ok(())
+    }
+}
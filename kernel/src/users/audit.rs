@@ -0,0 +1,349 @@
+//! Audit log
+//!
+//! A line for every security-relevant event — logins, elevations, user
+//! and group changes, mounts, app installs and permission answers, and
+//! changes to protected settings — in `/var/log/audit.log`: its sequence
+//! number, a digest chaining it to the line before, when, what kind of
+//! event, who and what. The log belongs to root, so only administrators
+//! may read it, and the VFS lets it be added to but never rewritten or
+//! removed, whoever asks. A line edited, dropped or moved by other means
+//! breaks the numbering or the chain, which `verify` reports.
+//!
+//! The latest entries are also kept in memory for `audit show` and
+//! `/proc/audit`. Events before `init`, such as the boot mounts, are held
+//! until the log is ready and then written in order.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+
+use super::{as_kernel, ROOT_ID, USER_MANAGER};
+use crate::crypto::sha256;
+use crate::drivers::rtc;
+use crate::fs::{self, FsError, Permissions};
+use crate::{info, println, warn};
+
+pub const LOG_PATH: &str = "/var/log/audit.log";
+
+const LOG_DIR: &str = "/var/log";
+
+/// Entries kept in memory before the oldest are dropped
+const RING_SIZE: usize = 256;
+
+/// Hex digits of the chain digest written on each line
+const CHAIN_DIGITS: usize = 16;
+
+/// What an event is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Logins, logouts, failed logins and one-time codes
+    Login,
+    /// Admin rights lent to a session, or refused
+    Elevation,
+    /// Accounts and groups
+    Users,
+    Mount,
+    /// App packages and what they are allowed to do
+    Apps,
+    /// Network, login and TLS settings
+    Settings,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 6] = [Kind::Login, Kind::Elevation, Kind::Users, Kind::Mount, Kind::Apps, Kind::Settings];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Login => "login",
+            Kind::Elevation => "elevation",
+            Kind::Users => "users",
+            Kind::Mount => "mount",
+            Kind::Apps => "apps",
+            Kind::Settings => "settings",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|k| k.name() == name)
+    }
+}
+
+/// A recorded event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub seq: u64,
+    pub kind: Kind,
+    /// The line as written to the log, without its newline
+    pub line: String,
+}
+
+/// An event that happened before the log was ready
+struct Early {
+    time: rtc::DateTime,
+    kind: Kind,
+    action: String,
+}
+
+struct Log {
+    ring: VecDeque<Entry>,
+    next_seq: u64,
+    /// Digest of the last line written, in hex
+    chain: String,
+    ready: bool,
+    early: Vec<Early>,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    ring: VecDeque::new(),
+    next_seq: 1,
+    chain: String::new(),
+    ready: false,
+    early: Vec::new(),
+});
+
+/// Digest linking a line's `body` to the digest of the line before
+fn chain(previous: &str, seq: u64, body: &str) -> String {
+    let digest = sha256::hash(format!("{}\n{}\n{}", previous, seq, body).as_bytes());
+    let mut hex = String::new();
+    for byte in &digest[..CHAIN_DIGITS / 2] {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// A line's sequence number, chain digest and the rest; None for lines
+/// written before lines were numbered
+fn parse_line(line: &str) -> Option<(u64, &str, &str)> {
+    let (seq, rest) = line.split_once(' ')?;
+    let (digest, body) = rest.split_once(' ')?;
+    if digest.len() != CHAIN_DIGITS || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((seq.parse().ok()?, digest, body))
+}
+
+impl Log {
+    /// Number, chain and keep an event; returns the line to write
+    fn add(&mut self, time: rtc::DateTime, kind: Kind, actor: &str, action: &str) -> String {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let body = format!("{} {} {}: {}", time, kind.name(), actor, action);
+        self.chain = chain(&self.chain, seq, &body);
+        let line = format!("{} {} {}", seq, self.chain, body);
+        if self.ring.len() >= RING_SIZE {
+            self.ring.pop_front();
+        }
+        self.ring.push_back(Entry { seq, kind, line: line.clone() });
+        line
+    }
+}
+
+/// Make the log if there is none yet, make it append-only, and carry on
+/// its numbering and chain
+pub fn init() {
+    let made = as_kernel(|| {
+        match fs::create_dir(LOG_DIR) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+        if fs::metadata(LOG_PATH).is_err() {
+            fs::write_file(LOG_PATH, b"")?;
+            fs::chmod(LOG_PATH, Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    });
+    fs::set_append_only(LOG_PATH);
+    match made {
+        Ok(()) => info!("audit", "Logging to {}", LOG_PATH),
+        Err(e) => warn!("audit", "Cannot create {}: {:?}", LOG_PATH, e),
+    }
+
+    let text = as_kernel(|| fs::read_file(LOG_PATH)).unwrap_or_default();
+    let last = String::from_utf8_lossy(&text).lines().rev().find_map(|line| {
+        parse_line(line).map(|(seq, digest, _)| (seq, String::from(digest)))
+    });
+    let early = {
+        let mut log = LOG.lock();
+        if let Some((seq, digest)) = last {
+            log.next_seq = seq + 1;
+            log.chain = digest;
+        }
+        log.ready = true;
+        core::mem::take(&mut log.early)
+    };
+    for event in early {
+        let line = LOG.lock().add(event.time, event.kind, "kernel", &event.action);
+        write_line(&line);
+    }
+}
+
+/// Who is acting: the user logged in, and the administrator whose rights
+/// they have borrowed, if they have
+fn actor() -> String {
+    let manager = USER_MANAGER.lock();
+    let user = manager.current_user().map_or("kernel", |u| u.username.as_str());
+    match manager.elevated.filter(|id| *id != ROOT_ID).and_then(|id| manager.get_user(id)) {
+        Some(admin) => format!("{} (as {})", user, admin.username),
+        None => String::from(user),
+    }
+}
+
+fn write_line(line: &str) {
+    info!("audit", "{}", line);
+    let line = format!("{}\n", line);
+    if let Err(e) = as_kernel(|| fs::append_file(LOG_PATH, line.as_bytes())) {
+        warn!("audit", "Cannot write {}: {:?}", LOG_PATH, e);
+    }
+}
+
+/// Add `action` to the log
+pub fn record(kind: Kind, action: &str) {
+    let time = rtc::now();
+    {
+        let mut log = LOG.lock();
+        if !log.ready {
+            log.early.push(Early { time, kind, action: String::from(action) });
+            return;
+        }
+    }
+    let actor = actor();
+    let line = LOG.lock().add(time, kind, &actor, action);
+    write_line(&line);
+}
+
+/// The latest entries, oldest first, of `kind` if given
+pub fn entries(kind: Option<Kind>) -> Vec<Entry> {
+    LOG.lock().ring.iter().filter(|e| kind.map_or(true, |k| e.kind == k)).cloned().collect()
+}
+
+/// Contents of `/proc/audit`
+pub fn contents() -> Vec<u8> {
+    let mut out = String::new();
+    for entry in entries(None) {
+        out.push_str(&entry.line);
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+/// Check that numbered lines in `text` follow on from each other and
+/// that their chain is unbroken
+///
+/// Returns how many lines were checked, or the first line that is wrong.
+/// Lines from before lines were numbered are skipped.
+pub fn verify_text(text: &str) -> Result<usize, String> {
+    let mut previous: Option<(u64, String)> = None;
+    let mut checked = 0;
+    for (n, line) in text.lines().enumerate() {
+        let (seq, digest, body) = match parse_line(line) {
+            Some(parts) => parts,
+            None if previous.is_none() => continue,
+            None => return Err(format!("line {}: not an audit entry", n + 1)),
+        };
+        if let Some((last_seq, last_digest)) = &previous {
+            if seq != last_seq + 1 {
+                return Err(format!("line {}: entry {} follows entry {}", n + 1, seq, last_seq));
+            }
+            if chain(last_digest, seq, body) != digest {
+                return Err(format!("line {}: entry {} does not match the entries before it", n + 1, seq));
+            }
+        }
+        previous = Some((seq, String::from(digest)));
+        checked += 1;
+    }
+    Ok(checked)
+}
+
+/// Check the log file as `verify_text` does
+pub fn verify() -> Result<usize, String> {
+    let text = as_kernel(|| fs::read_file(LOG_PATH)).map_err(|e| format!("{}: {:?}", LOG_PATH, e))?;
+    verify_text(&String::from_utf8_lossy(&text))
+}
+
+/// Entries `audit show` prints unless told otherwise
+const SHOW_DEFAULT: usize = 20;
+
+/// The `audit` shell command:
+/// `audit [show [KIND] [COUNT] | verify]`
+pub fn command(args: &[&str]) {
+    match args {
+        [] | ["show", ..] => {
+            let mut kind = None;
+            let mut count = SHOW_DEFAULT;
+            for arg in args.iter().skip(1) {
+                match (Kind::from_name(arg), arg.parse()) {
+                    (Some(k), _) => kind = Some(k),
+                    (None, Ok(n)) => count = n,
+                    (None, Err(_)) => {
+                        let kinds: Vec<&str> = Kind::ALL.iter().map(|k| k.name()).collect();
+                        println!("audit: unknown kind '{}'; kinds are {}", arg, kinds.join(", "));
+                        return;
+                    }
+                }
+            }
+            let entries = entries(kind);
+            if entries.is_empty() {
+                println!("  (no entries)");
+            }
+            for entry in &entries[entries.len().saturating_sub(count)..] {
+                println!("{}", entry.line);
+            }
+        }
+        ["verify"] => match verify() {
+            Ok(count) => println!("{}: {} entries, none missing or changed", LOG_PATH, count),
+            Err(problem) => println!("{}: {}", LOG_PATH, problem),
+        },
+        _ => println!("Usage: audit [show [KIND] [COUNT] | verify]"),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn test_log() -> Log {
+        Log { ring: VecDeque::new(), next_seq: 1, chain: String::new(), ready: true, early: Vec::new() }
+    }
+
+    #[kernel_test]
+    fn entries_are_numbered_and_chained() -> Result<(), String> {
+        let mut log = test_log();
+        let time = rtc::DateTime::from_unix(0);
+        let lines: Vec<String> = (0..3).map(|i| log.add(time, Kind::Login, "root", &format!("event {}", i))).collect();
+        check!(lines[0].starts_with("1 "));
+        check!(lines[2].starts_with("3 "));
+        let text = lines.join("\n");
+        check_eq!(verify_text(&text), Ok(3));
+        check_eq!(verify_text(&format!("1970-01-01 00:00:00 root: legacy\n{}", text)), Ok(3));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn tampering_is_detected() -> Result<(), String> {
+        let mut log = test_log();
+        let time = rtc::DateTime::from_unix(0);
+        let lines: Vec<String> = (0..3).map(|i| log.add(time, Kind::Users, "root", &format!("event {}", i))).collect();
+        let dropped = format!("{}\n{}", lines[0], lines[2]);
+        check!(verify_text(&dropped).is_err());
+        let edited = lines.join("\n").replace("event 1", "event 9");
+        check!(verify_text(&edited).is_err());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn ring_keeps_the_latest_entries() -> Result<(), String> {
+        let mut log = test_log();
+        let time = rtc::DateTime::from_unix(0);
+        for i in 0..RING_SIZE + 5 {
+            log.add(time, Kind::Mount, "kernel", &format!("mount {}", i));
+        }
+        check_eq!(log.ring.len(), RING_SIZE);
+        check_eq!(log.ring.front().map(|e| e.seq), Some(6));
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Authentication backends
+//!
+//! Passwords are checked by a list of backends, each of which accepts a
+//! user, rejects them, does not know them, or cannot be reached. They are
+//! asked in the order they were registered, and local accounts last, so
+//! local accounts are the fallback whenever the others do not answer. A
+//! user another backend accepts gets a local account, which keeps the
+//! password so they can still log in while its server is unreachable.
+//!
+//! `HttpBackend` asks a server over HTTPS, for lab deployments with
+//! central accounts. It posts `{"username": ..., "password": ...}` as JSON
+//! and reads the status: 200 accepts, 401 or 403 rejects, 404 does not
+//! know the user, and anything else counts as unreachable. The
+//! `auth.server` setting registers one.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::USER_MANAGER;
+use crate::net::http;
+use crate::{info, warn};
+
+/// What a backend makes of a username and password
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+    /// The user is known and the password is wrong, or they may not log in
+    Rejected,
+    UnknownUser,
+    /// The backend could not be asked
+    Unavailable,
+}
+
+/// Somewhere passwords can be checked
+pub trait Backend: Send + Sync {
+    fn name(&self) -> &str;
+    fn check(&self, username: &str, password: &str) -> Outcome;
+}
+
+/// Who accepted a password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// A local account's password
+    Local,
+    /// Accepted by the backend named
+    Remote(String),
+    Refused,
+}
+
+/// Local accounts, the fallback after every registered backend
+struct Local;
+
+impl Backend for Local {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn check(&self, username: &str, password: &str) -> Outcome {
+        let manager = USER_MANAGER.lock();
+        match manager.find_user_by_name(username) {
+            None => Outcome::UnknownUser,
+            Some(_) if manager.verify_local(username, password).is_some() => Outcome::Accepted,
+            Some(_) => Outcome::Rejected,
+        }
+    }
+}
+
+/// Checks passwords with a server over HTTPS
+pub struct HttpBackend {
+    url: String,
+}
+
+impl HttpBackend {
+    /// A backend asking `url`, which must be `https://` as passwords are
+    /// sent to it
+    pub fn new(url: &str) -> Option<Self> {
+        (url.starts_with("https://") && http::Url::parse(url).is_ok()).then(|| Self { url: String::from(url) })
+    }
+}
+
+/// `text` as a JSON string
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Backend for HttpBackend {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn check(&self, username: &str, password: &str) -> Outcome {
+        let body = format!("{{\"username\":{},\"password\":{}}}", json_string(username), json_string(password));
+        let mut request = match http::Request::post(&self.url, body.into_bytes()) {
+            Ok(request) => request,
+            Err(_) => return Outcome::Unavailable,
+        };
+        request.header("Content-Type", "application/json");
+        // Never follow a redirect with a password
+        match http::request_once(&request) {
+            Ok(response) => match response.status {
+                200 => Outcome::Accepted,
+                401 | 403 => Outcome::Rejected,
+                404 => Outcome::UnknownUser,
+                status => {
+                    info!("auth", "{} answered {}", self.url, status);
+                    Outcome::Unavailable
+                }
+            },
+            Err(e) => {
+                warn!("auth", "{} unreachable: {:?}", self.url, e);
+                Outcome::Unavailable
+            }
+        }
+    }
+}
+
+/// Backends asked before local accounts, in order
+static BACKENDS: Mutex<Vec<Arc<dyn Backend>>> = Mutex::new(Vec::new());
+
+/// Ask `backend` about passwords before the backends already registered
+/// after it; one with the same name is replaced
+pub fn register(backend: Box<dyn Backend>) {
+    let mut backends = BACKENDS.lock();
+    backends.retain(|b| b.name() != backend.name());
+    info!("auth", "Registered backend {}", backend.name());
+    backends.push(Arc::from(backend));
+}
+
+/// Stop asking the backend called `name`
+pub fn unregister(name: &str) -> bool {
+    let mut backends = BACKENDS.lock();
+    let before = backends.len();
+    backends.retain(|b| b.name() != name);
+    backends.len() != before
+}
+
+/// The server set with `set_server`, so a new one replaces it
+static SERVER: Mutex<Option<String>> = Mutex::new(None);
+
+/// Check passwords with the server at `url` before local accounts, or
+/// stop asking one if `url` is empty
+pub fn set_server(url: &str) -> Result<(), &'static str> {
+    let backend = match url {
+        "" => None,
+        url => Some(HttpBackend::new(url).ok_or("the server must be an https:// URL")?),
+    };
+    let mut server = SERVER.lock();
+    if let Some(old) = server.take() {
+        unregister(&old);
+    }
+    if let Some(backend) = backend {
+        *server = Some(backend.url.clone());
+        register(Box::new(backend));
+    }
+    Ok(())
+}
+
+/// Names of the registered backends, in the order they are asked
+pub fn backends() -> Vec<String> {
+    let mut names: Vec<String> = BACKENDS.lock().iter().map(|b| String::from(b.name())).collect();
+    names.push(String::from(Local.name()));
+    names
+}
+
+/// Ask the backends about `username` and `password` until one accepts or
+/// rejects them
+pub fn verify(username: &str, password: &str) -> Verdict {
+    // The list is copied so no lock is held while a server is asked
+    let backends: Vec<Arc<dyn Backend>> = BACKENDS.lock().clone();
+    for backend in &backends {
+        match backend.check(username, password) {
+            Outcome::Accepted => return Verdict::Remote(String::from(backend.name())),
+            Outcome::Rejected => return Verdict::Refused,
+            Outcome::UnknownUser | Outcome::Unavailable => {}
+        }
+    }
+    match Local.check(username, password) {
+        Outcome::Accepted => Verdict::Local,
+        _ => Verdict::Refused,
+    }
+}
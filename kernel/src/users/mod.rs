@@ -1,6 +1,32 @@
 //! User Management System
 //!
 //! Multi-user support for WebbOS with authentication and permissions.
+//!
+//! Every user has a primary group, `users` unless changed, and may be a
+//! member of other groups besides. The VFS checks files against the
+//! `credentials` of the user logged in: their user ID and groups, or the
+//! kernel's own when nobody is. Administrators, like the kernel, may do
+//! anything.
+//!
+//! Passwords are checked by the backends in `auth`: servers registered
+//! there first, then local accounts.
+//!
+//! Users may turn on two-factor login, after which logging in takes a
+//! one-time code from an authenticator app, or a recovery code, besides
+//! the password; see `totp`.
+//!
+//! Passwords cannot be guessed at speed: after each wrong one the account
+//! waits twice as long as before to check the next, and after
+//! `MAX_FAILED_ATTEMPTS` in a row it is locked for `LOCKOUT_MS`, unless an
+//! administrator unlocks it sooner. Names with no account are throttled
+//! the same way, so the answers do not tell which names exist.
+//!
+//! New users get a home directory under `/home` with a copy of what is in
+//! `/etc/skel`. Deleting a user keeps, removes or archives it.
+//!
+//! Other users may still carry out a single admin action with `elevate`,
+//! given an administrator's name and password. Elevations, refused or
+//! not, and every change to accounts and groups go to the `audit` log.
 
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -10,7 +36,17 @@ use spin::Mutex;
 use lazy_static::lazy_static;
 
 use crate::println;
-use crate::crypto::sha256;
+use crate::crypto::{ct, sha256};
+use crate::drivers::rtc::{self, DateTime};
+use crate::drivers::timer;
+use crate::fs::{self, tar, FileType, FsError, FsResult, Permissions};
+use crate::{info, warn};
+
+pub mod audit;
+pub mod auth;
+pub mod totp;
+
+use audit::Kind;
 
 /// User ID type
 pub type UserId = u32;
@@ -18,6 +54,43 @@ pub type UserId = u32;
 /// Group ID type
 pub type GroupId = u32;
 
+/// The kernel's user and group, which own the system's files
+pub const ROOT_ID: UserId = 0;
+pub const ROOT_GROUP: GroupId = 0;
+
+/// Primary group of new users
+pub const USERS_GROUP: GroupId = 100;
+
+/// Wrong passwords in a row that lock an account
+pub const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+/// How long a locked account stays locked
+pub const LOCKOUT_MS: u64 = 5 * 60 * 1000;
+
+/// Wait after the first wrong password; it doubles with each one after
+const BACKOFF_MS: u64 = 1000;
+
+/// Failed attempts remembered per user
+const FAILURE_HISTORY: usize = 10;
+
+/// Names with no account whose wrong passwords are being throttled
+const MAX_UNKNOWN_NAMES: usize = 256;
+
+/// What every new home directory starts with
+pub const SKELETON_DIR: &str = "/etc/skel";
+
+/// Where archived home directories go
+pub const ARCHIVE_DIR: &str = "/var/archive";
+
+/// What becomes of a deleted user's home directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomeAction {
+    Keep,
+    Remove,
+    /// Pack it into a tar file in `ARCHIVE_DIR`, then remove it
+    Archive,
+}
+
 /// User account
 #[derive(Debug, Clone)]
 pub struct User {
@@ -26,9 +99,89 @@ pub struct User {
     pub password_hash: [u8; 32], // SHA-256 hash
     pub home_directory: String,
     pub shell: String,
+    /// Group that owns the files the user creates
+    pub primary_group: GroupId,
+    /// Other groups the user is a member of
     pub groups: Vec<GroupId>,
     pub is_admin: bool,
     pub is_active: bool,
+    /// Wrong passwords since the last right one
+    pub failed_attempts: u32,
+    /// Uptime in milliseconds until which no password is checked
+    pub retry_at: u64,
+    /// Uptime in milliseconds until which the account is locked
+    pub locked_until: u64,
+    pub last_login: Option<DateTime>,
+    /// When the latest wrong passwords were given, oldest first
+    pub failures: Vec<DateTime>,
+    /// One-time password secret, if two-factor login is on
+    pub totp: Option<totp::Totp>,
+    /// Secret being set up, until a code from it is given
+    pub totp_pending: Option<totp::Totp>,
+}
+
+impl User {
+    /// Seconds the account stays locked, if it is
+    pub fn lockout_remaining(&self) -> Option<u64> {
+        seconds_until(self.locked_until)
+    }
+
+    fn throttle(&self) -> Throttle {
+        Throttle {
+            failed_attempts: self.failed_attempts,
+            retry_at: self.retry_at,
+            locked_until: self.locked_until,
+        }
+    }
+}
+
+/// Whole seconds from now until uptime `at`, rounded up; `None` if it has
+/// passed
+fn seconds_until(at: u64) -> Option<u64> {
+    let now = timer::elapsed_ms();
+    (at > now).then(|| (at - now).div_ceil(1000))
+}
+
+/// Wrong passwords in a row for a name, and how long it has to wait
+#[derive(Debug, Clone, Copy, Default)]
+struct Throttle {
+    failed_attempts: u32,
+    retry_at: u64,
+    locked_until: u64,
+}
+
+impl Throttle {
+    /// Refuse an attempt while locked or waiting
+    fn check(&self) -> Result<(), UserError> {
+        if let Some(seconds) = seconds_until(self.locked_until) {
+            return Err(UserError::LockedOut(seconds));
+        }
+        if let Some(seconds) = seconds_until(self.retry_at) {
+            return Err(UserError::TryLater(seconds));
+        }
+        Ok(())
+    }
+
+    /// Count a wrong password: wait twice as long as last time, or lock
+    /// after too many. Returns whether it locked.
+    fn fail(&mut self) -> bool {
+        let now = timer::elapsed_ms();
+        self.failed_attempts += 1;
+        if self.failed_attempts >= MAX_FAILED_ATTEMPTS {
+            self.failed_attempts = 0;
+            self.locked_until = now + LOCKOUT_MS;
+            true
+        } else {
+            self.retry_at = now + (BACKOFF_MS << (self.failed_attempts - 1));
+            false
+        }
+    }
+
+    /// Nothing left to wait for
+    fn expired(&self) -> bool {
+        let now = timer::elapsed_ms();
+        self.retry_at <= now && self.locked_until <= now
+    }
 }
 
 /// User group
@@ -36,9 +189,35 @@ pub struct User {
 pub struct Group {
     pub id: GroupId,
     pub name: String,
+    /// Users with the group among their other groups; those whose primary
+    /// group it is are not listed
     pub members: Vec<UserId>,
 }
 
+/// Who a file operation is done for
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub uid: UserId,
+    /// Primary group
+    pub gid: GroupId,
+    /// Other groups
+    pub groups: Vec<GroupId>,
+    /// May do anything, whatever a file's permissions
+    pub superuser: bool,
+}
+
+impl Credentials {
+    /// The kernel's own, used while nobody is logged in
+    pub fn root() -> Self {
+        Self { uid: ROOT_ID, gid: ROOT_GROUP, groups: Vec::new(), superuser: true }
+    }
+
+    /// Whether the user is in group `gid`
+    pub fn in_group(&self, gid: GroupId) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
 /// Session for logged-in user
 #[derive(Debug, Clone)]
 pub struct Session {
@@ -56,6 +235,11 @@ pub struct UserManager {
     next_group_id: GroupId,
     next_session_id: u64,
     current_user: Option<UserId>,
+    /// Administrator whose rights the session has borrowed, while an
+    /// `elevate` action runs
+    elevated: Option<UserId>,
+    /// Names with no account that were given wrong passwords
+    unknown_names: BTreeMap<String, Throttle>,
 }
 
 impl UserManager {
@@ -69,8 +253,14 @@ impl UserManager {
             next_group_id: 1000,
             next_session_id: 1,
             current_user: None,
+            elevated: None,
+            unknown_names: BTreeMap::new(),
         };
         
+        for (id, name) in [(ROOT_GROUP, "root"), (USERS_GROUP, "users")] {
+            manager.groups.insert(id, Group { id, name: String::from(name), members: Vec::new() });
+        }
+
         // Create default admin user
         manager.create_user_internal(
             "admin",
@@ -112,9 +302,17 @@ impl UserManager {
             password_hash,
             home_directory: String::from(home),
             shell: String::from(shell),
+            primary_group: USERS_GROUP,
             groups: Vec::new(),
             is_admin,
             is_active: true,
+            failed_attempts: 0,
+            retry_at: 0,
+            locked_until: 0,
+            last_login: None,
+            failures: Vec::new(),
+            totp: None,
+            totp_pending: None,
         };
         
         self.users.insert(id, user);
@@ -146,52 +344,165 @@ impl UserManager {
         let home = format!("/home/{}", username);
         let id = self.create_user_internal(username, password, &home, "/bin/shell", is_admin);
         
-        println!("[users] Created user '{}' with ID {}", username, id);
+        info!("users", "Created user '{}' with ID {}", username, id);
         Ok(id)
     }
     
-    /// Authenticate user
-    pub fn authenticate(&mut self, username: &str, password: &str) -> Option<UserId> {
-        let password_hash = hash_password(password);
-        
-        for (id, user) in &self.users {
-            if user.username == username 
-                && user.password_hash == password_hash
-                && user.is_active {
-                return Some(*id);
+    /// Refuse a locked account, or one still waiting after a wrong
+    /// password, before its password is checked; a name with no account
+    /// is refused alike
+    fn check_throttle(&self, username: &str) -> Result<(), UserError> {
+        match self.find_user_by_name(username) {
+            Some(user) => user.throttle().check(),
+            None => self.unknown_names.get(username).map_or(Ok(()), Throttle::check),
+        }
+    }
+
+    /// Check a password against the local account
+    pub fn verify_local(&self, username: &str, password: &str) -> Option<UserId> {
+        // Hashed even for a name with no account, to take as long
+        let hash = hash_password(password);
+        self.find_user_by_name(username)
+            .filter(|u| u.is_active && ct::eq(&u.password_hash, &hash))
+            .map(|u| u.id)
+    }
+
+    /// Keep the password of a user another backend accepted, making them
+    /// an account if they have none; returns the account's ID and whether
+    /// it is new
+    fn cache_remote(&mut self, username: &str, password: &str, backend: &str) -> Result<(UserId, bool), UserError> {
+        if let Some(user) = self.users.values_mut().find(|u| u.username == username) {
+            // An account disabled here stays disabled
+            if !user.is_active {
+                return Err(UserError::WrongPassword);
             }
+            user.password_hash = hash_password(password);
+            return Ok((user.id, false));
         }
-        
-        None
+        if username.is_empty() || username.len() > 32 || username.contains('/') {
+            return Err(UserError::InvalidUsername);
+        }
+        let home = format!("/home/{}", username);
+        let id = self.create_user_internal(username, password, &home, "/bin/shell", false);
+        info!("users", "Created user '{}' with ID {} for {}", username, id, backend);
+        Ok((id, true))
     }
-    
-    /// Login user and create session
-    pub fn login(&mut self, username: &str, password: &str) -> Option<u64> {
-        if let Some(user_id) = self.authenticate(username, password) {
-            let session_id = self.next_session_id;
-            self.next_session_id += 1;
-            
-            let session = Session {
-                session_id,
-                user_id,
-                start_time: get_current_time(),
-            };
-            
-            self.sessions.insert(session_id, session);
-            self.current_user = Some(user_id);
-            
-            println!("[users] User '{}' logged in (session {})", username, session_id);
-            Some(session_id)
+
+    fn record_success(&mut self, user_id: UserId) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.failed_attempts = 0;
+            user.retry_at = 0;
+            // A name another backend knows now has an account
+            self.unknown_names.remove(&user.username);
+        }
+    }
+
+    /// Count a wrong password against `username`, making them wait before
+    /// the next, or locking them out after too many
+    fn record_failure(&mut self, username: &str) {
+        let user = match self.users.values_mut().find(|u| u.username == username) {
+            Some(user) => user,
+            None => return self.record_unknown_failure(username),
+        };
+        let mut throttle = user.throttle();
+        let locked = throttle.fail();
+        user.failed_attempts = throttle.failed_attempts;
+        user.retry_at = throttle.retry_at;
+        user.locked_until = throttle.locked_until;
+        if user.failures.len() == FAILURE_HISTORY {
+            user.failures.remove(0);
+        }
+        user.failures.push(rtc::local());
+        if locked {
+            warn!("users", "Account '{}' locked for {} minutes", user.username, LOCKOUT_MS / 60_000);
         } else {
-            None
+            warn!("users", "Wrong password for '{}' ({} in a row)", user.username, user.failed_attempts);
+        }
+    }
+
+    /// Count a wrong password against a name with no account, as for one
+    /// with an account
+    fn record_unknown_failure(&mut self, username: &str) {
+        if !self.unknown_names.contains_key(username) && self.unknown_names.len() >= MAX_UNKNOWN_NAMES {
+            self.unknown_names.retain(|_, throttle| !throttle.expired());
+            if self.unknown_names.len() >= MAX_UNKNOWN_NAMES {
+                self.unknown_names.pop_first();
+            }
+        }
+        let throttle = self.unknown_names.entry(String::from(username)).or_default();
+        if throttle.fail() {
+            warn!("users", "Unknown user '{}' locked for {} minutes", username, LOCKOUT_MS / 60_000);
+        } else {
+            warn!("users", "Wrong password for unknown user '{}' ({} in a row)", username, throttle.failed_attempts);
         }
     }
     
+    /// Check the one-time code, or a recovery code, of a user with
+    /// two-factor login; a wrong one counts as a wrong password. Returns
+    /// the recovery codes left if one was used.
+    fn check_code(&mut self, user_id: UserId, code: &str, now: u64) -> Result<Option<usize>, UserError> {
+        let username = self.users.get(&user_id).ok_or(UserError::UserNotFound)?.username.clone();
+        self.check_throttle(&username)?;
+        let user = self.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+        let accepted = match user.totp.as_mut() {
+            None => Some(None),
+            Some(totp) => {
+                if totp.check(code, now) {
+                    Some(None)
+                } else if totp.use_recovery_code(code) {
+                    Some(Some(totp.recovery_codes_left()))
+                } else {
+                    None
+                }
+            }
+        };
+        match accepted {
+            Some(recovery_left) => {
+                self.record_success(user_id);
+                Ok(recovery_left)
+            }
+            None => {
+                self.record_failure(&username);
+                Err(UserError::WrongCode)
+            }
+        }
+    }
+
+    /// Start a session for a user whose password has been checked
+    fn start_session(&mut self, user_id: UserId) -> u64 {
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+        
+        let session = Session {
+            session_id,
+            user_id,
+            start_time: get_current_time(),
+        };
+        
+        self.sessions.insert(session_id, session);
+        self.current_user = Some(user_id);
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.last_login = Some(rtc::local());
+            info!("users", "User '{}' logged in (session {})", user.username, session_id);
+        }
+        session_id
+    }
+
+    /// Let a locked account log in again at once
+    pub fn unlock_user(&mut self, user_id: UserId) -> Result<(), UserError> {
+        let user = self.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+        user.failed_attempts = 0;
+        user.retry_at = 0;
+        user.locked_until = 0;
+        info!("users", "User '{}' unlocked", user.username);
+        Ok(())
+    }
+    
     /// Logout user
     pub fn logout(&mut self, session_id: u64) -> bool {
         if let Some(session) = self.sessions.remove(&session_id) {
             if let Some(user) = self.users.get(&session.user_id) {
-                println!("[users] User '{}' logged out", user.username);
+                info!("users", "User '{}' logged out", user.username);
             }
             
             if self.sessions.is_empty() {
@@ -232,7 +543,7 @@ impl UserManager {
         
         if let Some(user) = self.users.get_mut(&user_id) {
             user.password_hash = hash_password(new_password);
-            println!("[users] Password changed for user '{}'", user.username);
+            info!("users", "Password changed for user '{}'", user.username);
             Ok(())
         } else {
             Err(UserError::UserNotFound)
@@ -241,31 +552,35 @@ impl UserManager {
     
     /// Delete user
     pub fn delete_user(&mut self, user_id: UserId) -> Result<(), UserError> {
-        // Prevent deleting the last admin
-        if let Some(user) = self.users.get(&user_id) {
-            if user.is_admin {
-                let admin_count = self.users.values().filter(|u| u.is_admin).count();
-                if admin_count <= 1 {
-                    return Err(UserError::CannotDeleteLastAdmin);
-                }
-            }
-        }
-        
+        self.check_deletable(user_id)?;
         if let Some(user) = self.users.remove(&user_id) {
             // End all sessions for this user
             self.sessions.retain(|_, s| s.user_id != user_id);
-            println!("[users] Deleted user '{}'", user.username);
+            for group in self.groups.values_mut() {
+                group.members.retain(|&id| id != user_id);
+            }
+            info!("users", "Deleted user '{}'", user.username);
             Ok(())
         } else {
             Err(UserError::UserNotFound)
         }
     }
     
+    /// Whether `delete_user` would delete the user
+    pub fn check_deletable(&self, user_id: UserId) -> Result<(), UserError> {
+        let user = self.users.get(&user_id).ok_or(UserError::UserNotFound)?;
+        // Prevent deleting the last admin
+        if user.is_admin && self.users.values().filter(|u| u.is_admin).count() <= 1 {
+            return Err(UserError::CannotDeleteLastAdmin);
+        }
+        Ok(())
+    }
+
     /// Set user active/inactive
     pub fn set_user_active(&mut self, user_id: UserId, active: bool) -> Result<(), UserError> {
         if let Some(user) = self.users.get_mut(&user_id) {
             user.is_active = active;
-            println!("[users] User '{}' {}", user.username, 
+            info!("users", "User '{}' {}", user.username, 
                 if active { "activated" } else { "deactivated" });
             Ok(())
         } else {
@@ -282,6 +597,97 @@ impl UserManager {
     pub fn get_session(&self, session_id: u64) -> Option<&Session> {
         self.sessions.get(&session_id)
     }
+
+    /// Credentials of the user logged in, or the kernel's
+    pub fn credentials(&self) -> Credentials {
+        match self.current_user() {
+            Some(user) => Credentials {
+                uid: user.id,
+                gid: user.primary_group,
+                groups: user.groups.clone(),
+                superuser: user.is_admin || self.elevated.is_some(),
+            },
+            None => Credentials::root(),
+        }
+    }
+
+    /// Find group by name
+    pub fn find_group_by_name(&self, name: &str) -> Option<&Group> {
+        self.groups.values().find(|g| g.name == name)
+    }
+
+    /// Get all groups
+    pub fn list_groups(&self) -> Vec<&Group> {
+        self.groups.values().collect()
+    }
+
+    /// Create a group with no members
+    pub fn create_group(&mut self, name: &str) -> Result<GroupId, UserError> {
+        if name.is_empty() || name.len() > 32 || name.contains(char::is_whitespace) {
+            return Err(UserError::InvalidGroupName);
+        }
+        if self.find_group_by_name(name).is_some() {
+            return Err(UserError::GroupExists);
+        }
+        let id = self.next_group_id;
+        self.next_group_id += 1;
+        self.groups.insert(id, Group { id, name: String::from(name), members: Vec::new() });
+        info!("users", "Created group '{}' with ID {}", name, id);
+        Ok(id)
+    }
+
+    /// Delete a group; it may not be anyone's primary group
+    pub fn delete_group(&mut self, group_id: GroupId) -> Result<(), UserError> {
+        if !self.groups.contains_key(&group_id) {
+            return Err(UserError::GroupNotFound);
+        }
+        if group_id == ROOT_GROUP || self.users.values().any(|u| u.primary_group == group_id) {
+            return Err(UserError::GroupInUse);
+        }
+        for user in self.users.values_mut() {
+            user.groups.retain(|&id| id != group_id);
+        }
+        if let Some(group) = self.groups.remove(&group_id) {
+            info!("users", "Deleted group '{}'", group.name);
+        }
+        Ok(())
+    }
+
+    /// Make a user a member of a group besides their primary one
+    pub fn add_to_group(&mut self, user_id: UserId, group_id: GroupId) -> Result<(), UserError> {
+        let group = self.groups.get_mut(&group_id).ok_or(UserError::GroupNotFound)?;
+        let user = self.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+        if user.primary_group != group_id && !user.groups.contains(&group_id) {
+            user.groups.push(group_id);
+            group.members.push(user_id);
+            info!("users", "Added '{}' to group '{}'", user.username, group.name);
+        }
+        Ok(())
+    }
+
+    /// Take a user out of one of their other groups
+    pub fn remove_from_group(&mut self, user_id: UserId, group_id: GroupId) -> Result<(), UserError> {
+        let group = self.groups.get_mut(&group_id).ok_or(UserError::GroupNotFound)?;
+        let user = self.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+        if user.primary_group == group_id {
+            return Err(UserError::GroupInUse);
+        }
+        user.groups.retain(|&id| id != group_id);
+        group.members.retain(|&id| id != user_id);
+        Ok(())
+    }
+
+    /// Change a user's primary group
+    pub fn set_primary_group(&mut self, user_id: UserId, group_id: GroupId) -> Result<(), UserError> {
+        let group = self.groups.get_mut(&group_id).ok_or(UserError::GroupNotFound)?;
+        let user = self.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+        // The group stops being one of the others
+        user.groups.retain(|&id| id != group_id);
+        group.members.retain(|&id| id != user_id);
+        user.primary_group = group_id;
+        info!("users", "Primary group of '{}' is now '{}'", user.username, group.name);
+        Ok(())
+    }
 }
 
 /// User errors
@@ -293,6 +699,53 @@ pub enum UserError {
     WeakPassword,
     CannotDeleteLastAdmin,
     NotAuthenticated,
+    GroupNotFound,
+    GroupExists,
+    InvalidGroupName,
+    /// The group is someone's primary group
+    GroupInUse,
+    /// Elevation was refused: not an administrator's name and password
+    NotAuthorized,
+    /// The password was right, but two-factor login needs a code too
+    CodeRequired,
+    /// Wrong one-time or recovery code
+    WrongCode,
+    /// A code was given to finish setting up two-factor login, but none
+    /// was being set up
+    NoTotpSetup,
+    /// Unknown user, wrong password or inactive account
+    WrongPassword,
+    /// Too soon after a wrong password; seconds left to wait
+    TryLater(u64),
+    /// Locked after too many wrong passwords; seconds left
+    LockedOut(u64),
+    /// The home directory could not be archived or removed
+    Filesystem(FsError),
+}
+
+impl core::fmt::Display for UserError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::UserNotFound => write!(f, "No such user"),
+            Self::UsernameExists => write!(f, "That username is taken"),
+            Self::InvalidUsername => write!(f, "Invalid username"),
+            Self::WeakPassword => write!(f, "Password is too short"),
+            Self::CannotDeleteLastAdmin => write!(f, "The last administrator cannot be deleted"),
+            Self::NotAuthenticated => write!(f, "Not logged in"),
+            Self::GroupNotFound => write!(f, "No such group"),
+            Self::GroupExists => write!(f, "That group name is taken"),
+            Self::InvalidGroupName => write!(f, "Invalid group name"),
+            Self::GroupInUse => write!(f, "The group is someone's primary group"),
+            Self::NotAuthorized => write!(f, "Wrong administrator name or password"),
+            Self::CodeRequired => write!(f, "Enter the code from your authenticator app"),
+            Self::WrongCode => write!(f, "Incorrect code"),
+            Self::NoTotpSetup => write!(f, "Two-factor login is not being set up"),
+            Self::WrongPassword => write!(f, "Invalid username or password"),
+            Self::TryLater(seconds) => write!(f, "Too many attempts; try again in {} s", seconds),
+            Self::LockedOut(seconds) => write!(f, "Account locked; try again in {} min", seconds.div_ceil(60)),
+            Self::Filesystem(e) => write!(f, "Home directory: {:?}", e),
+        }
+    }
 }
 
 /// Global user manager
@@ -309,36 +762,247 @@ fn hash_password(password: &str) -> [u8; 32] {
     hasher.finalize()
 }
 
-/// Get current time (placeholder)
+/// Unix time in seconds
 fn get_current_time() -> u64 {
-    // TODO: Implement real time
-    0
+    rtc::unix_time()
 }
 
 /// Initialize user system
 pub fn init() {
-    println!("[users] Initializing user management system...");
+    info!("users", "Initializing user management system...");
     
     let manager = USER_MANAGER.lock();
-    println!("[users] {} users configured", manager.users.len());
+    info!("users", "{} users configured", manager.users.len());
     
     // List default users
     for user in manager.list_users() {
-        println!("[users]   - {} ({})", 
+        info!("users", "  - {} ({})", 
             user.username,
             if user.is_admin { "admin" } else { "user" }
         );
     }
+    drop(manager);
+
+    for user in list_users() {
+        make_home(&user);
+    }
+    audit::init();
+}
+
+/// Give `user` a home directory of their own, if they have none yet,
+/// with a copy of the skeleton directory in it
+fn make_home(user: &User) {
+    let home = user.home_directory.as_str();
+    if fs::metadata(home).is_ok() {
+        return;
+    }
+    let made = fs::create_dir(home)
+        .and_then(|()| fs::chown(home, user.id, user.primary_group))
+        .and_then(|()| fs::chmod(home, Permissions::from_mode(0o750)));
+    if let Err(e) = made {
+        warn!("users", "Cannot make home directory {}: {:?}", home, e);
+        return;
+    }
+    match copy_skeleton(SKELETON_DIR, home, user) {
+        Ok(()) | Err(FsError::NotFound) => {}
+        Err(e) => warn!("users", "Cannot copy {} to {}: {:?}", SKELETON_DIR, home, e),
+    }
+}
+
+/// Copy what is in `from` into `to`, giving it all to `user`
+fn copy_skeleton(from: &str, to: &str, user: &User) -> FsResult<()> {
+    for entry in fs::read_dir(from)? {
+        let source = format!("{}/{}", from, entry.name);
+        let target = format!("{}/{}", to, entry.name);
+        if entry.metadata.file_type == FileType::Directory {
+            match fs::create_dir(&target) {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(e) => return Err(e),
+            }
+            copy_skeleton(&source, &target, user)?;
+        } else {
+            fs::write_file(&target, &fs::read_file(&source)?)?;
+        }
+        fs::chown(&target, user.id, user.primary_group)?;
+    }
+    Ok(())
+}
+
+/// Add everything under `dir` to `files` as archive members, with paths
+/// from `path` on
+fn collect_tree(dir: &str, path: &str, files: &mut Vec<(String, bool, Vec<u8>)>) -> FsResult<()> {
+    files.push((String::from(path), true, Vec::new()));
+    for entry in fs::read_dir(dir)? {
+        let source = format!("{}/{}", dir, entry.name);
+        let member = format!("{}/{}", path, entry.name);
+        if entry.metadata.file_type == FileType::Directory {
+            collect_tree(&source, &member, files)?;
+        } else {
+            files.push((member, false, fs::read_file(&source)?));
+        }
+    }
+    Ok(())
+}
+
+/// Pack `user`'s home directory into a tar file in `ARCHIVE_DIR`;
+/// returns the file's path
+fn archive_home(user: &User) -> FsResult<String> {
+    let mut files = Vec::new();
+    collect_tree(user.home_directory.trim_end_matches('/'), &user.username, &mut files)?;
+    let entries: Vec<tar::Entry> = files.iter()
+        .map(|(path, is_dir, data)| tar::Entry { path: path.clone(), is_dir: *is_dir, data })
+        .collect();
+
+    match fs::create_dir(ARCHIVE_DIR) {
+        Ok(()) | Err(FsError::AlreadyExists) => {}
+        Err(e) => return Err(e),
+    }
+    let mut path = format!("{}/{}.tar", ARCHIVE_DIR, user.username);
+    let mut n = 1;
+    while fs::metadata(&path).is_ok() {
+        n += 1;
+        path = format!("{}/{}-{}.tar", ARCHIVE_DIR, user.username, n);
+    }
+    fs::write_file(&path, &tar::build(&entries))?;
+    fs::chmod(&path, Permissions::from_mode(0o600))?;
+    Ok(path)
 }
 
 /// Login user
-pub fn login(username: &str, password: &str) -> Option<u64> {
-    USER_MANAGER.lock().login(username, password)
+///
+/// Users with two-factor login need `code` as well; without it the
+/// password is checked and `CodeRequired` returned.
+pub fn login(username: &str, password: &str, code: Option<&str>) -> Result<u64, UserError> {
+    let checked = authenticate(username, password).and_then(|user_id| {
+        if needs_code(user_id) {
+            verify_code(user_id, code.ok_or(UserError::CodeRequired)?)?;
+        }
+        Ok(user_id)
+    });
+    match checked {
+        Ok(user_id) => {
+            let session = USER_MANAGER.lock().start_session(user_id);
+            audit::record(Kind::Login, &format!("{} logged in", username));
+            Ok(session)
+        }
+        // Not a failure yet: the password was right
+        Err(UserError::CodeRequired) => Err(UserError::CodeRequired),
+        Err(e) => {
+            audit::record(Kind::Login, &format!("login as {} refused: {:?}", username, e));
+            Err(e)
+        }
+    }
+}
+
+/// Whether logging in as the user takes a one-time code
+pub fn needs_code(user_id: UserId) -> bool {
+    USER_MANAGER.lock().get_user(user_id).map_or(false, |u| u.totp.is_some())
+}
+
+/// Check the one-time code, or a recovery code, of a user whose password
+/// has been checked
+pub fn verify_code(user_id: UserId, code: &str) -> Result<(), UserError> {
+    let now = rtc::unix_time();
+    let recovery_left = USER_MANAGER.lock().check_code(user_id, code, now)?;
+    if let Some(left) = recovery_left {
+        audit::record(Kind::Login, &format!("{} used a recovery code, {} left", user_name(user_id), left));
+    }
+    Ok(())
+}
+
+/// Start setting up two-factor login for a user with a new secret;
+/// returns it, as base32 text and as an `otpauth://` URL
+///
+/// Two-factor login stays as it was until `confirm_totp` is given a code
+/// made from the new secret.
+pub fn begin_totp(user_id: UserId, algorithm: totp::Algorithm) -> Result<(String, String), UserError> {
+    let mut manager = USER_MANAGER.lock();
+    let user = manager.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+    let secret = totp::Totp::generate(algorithm);
+    let setup = (secret.secret_base32(), secret.url(&user.username));
+    user.totp_pending = Some(secret);
+    Ok(setup)
+}
+
+/// Turn on two-factor login with the secret `begin_totp` made, given a
+/// code from it; returns new recovery codes, which are not kept
+pub fn confirm_totp(user_id: UserId, code: &str) -> Result<Vec<String>, UserError> {
+    let now = rtc::unix_time();
+    let mut manager = USER_MANAGER.lock();
+    let user = manager.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+    let pending = user.totp_pending.as_mut().ok_or(UserError::NoTotpSetup)?;
+    if !pending.check(code, now) {
+        return Err(UserError::WrongCode);
+    }
+    let codes = pending.new_recovery_codes();
+    user.totp = user.totp_pending.take();
+    info!("users", "Two-factor login on for '{}'", user.username);
+    drop(manager);
+    audit::record(Kind::Users, &format!("turned on two-factor login for {}", user_name(user_id)));
+    Ok(codes)
+}
+
+/// Turn off two-factor login, or stop setting it up
+pub fn disable_totp(user_id: UserId) -> Result<(), UserError> {
+    let mut manager = USER_MANAGER.lock();
+    let user = manager.users.get_mut(&user_id).ok_or(UserError::UserNotFound)?;
+    user.totp_pending = None;
+    if user.totp.take().is_none() {
+        return Ok(());
+    }
+    info!("users", "Two-factor login off for '{}'", user.username);
+    drop(manager);
+    audit::record(Kind::Users, &format!("turned off two-factor login for {}", user_name(user_id)));
+    Ok(())
+}
+
+/// Check a user's password without starting a session
+///
+/// A locked account, or one still waiting after a wrong password, is
+/// refused without its password being checked.
+pub fn authenticate(username: &str, password: &str) -> Result<UserId, UserError> {
+    USER_MANAGER.lock().check_throttle(username)?;
+    // Unlocked while the backends are asked, as a server may take a while
+    let verdict = auth::verify(username, password);
+
+    let mut manager = USER_MANAGER.lock();
+    let accepted = match verdict {
+        auth::Verdict::Local => manager.verify_local(username, password)
+            .map(|id| (id, false))
+            .ok_or(UserError::WrongPassword),
+        auth::Verdict::Remote(backend) => manager.cache_remote(username, password, &backend),
+        auth::Verdict::Refused => Err(UserError::WrongPassword),
+    };
+    match accepted {
+        Ok((id, created)) => {
+            // With a second factor still to check, the wrong passwords and
+            // codes before stand until `check_code` passes
+            if manager.get_user(id).map_or(true, |user| user.totp.is_none()) {
+                manager.record_success(id);
+            }
+            drop(manager);
+            if created {
+                if let Some(user) = get_user(id) {
+                    make_home(&user);
+                }
+            }
+            Ok(id)
+        }
+        Err(e) => {
+            manager.record_failure(username);
+            Err(e)
+        }
+    }
 }
 
 /// Logout user
 pub fn logout(session_id: u64) -> bool {
-    USER_MANAGER.lock().logout(session_id)
+    let user = USER_MANAGER.lock().sessions.get(&session_id).map(|s| s.user_id);
+    let ended = USER_MANAGER.lock().logout(session_id);
+    if let Some(user_id) = user.filter(|_| ended) {
+        audit::record(Kind::Login, &format!("{} logged out", user_name(user_id)));
+    }
+    ended
 }
 
 /// Get current user
@@ -346,9 +1010,127 @@ pub fn current_user() -> Option<User> {
     USER_MANAGER.lock().current_user().cloned()
 }
 
+/// Whether the session may carry out admin actions: it is an
+/// administrator's, or one lent an administrator's rights by `elevate`
+pub fn is_privileged() -> bool {
+    let manager = USER_MANAGER.lock();
+    manager.elevated.is_some() || manager.current_user().map_or(false, |u| u.is_admin)
+}
+
+/// Carry out `action` with the rights of administrator `username`, whose
+/// `password` the user has to give
+///
+/// `f` does the action, and the session has the administrator's rights
+/// only while it runs. The elevation is recorded in the audit log, as is
+/// a refused one.
+pub fn elevate<R>(username: &str, password: &str, action: &str, f: impl FnOnce() -> R) -> Result<R, UserError> {
+    let admin = authenticate(username, password).ok();
+    let mut manager = USER_MANAGER.lock();
+    let admin = admin.filter(|id| manager.get_user(*id).map_or(false, |u| u.is_admin));
+    let previous = manager.elevated;
+    if admin.is_some() {
+        manager.elevated = admin;
+    }
+    drop(manager);
+
+    if admin.is_none() {
+        audit::record(Kind::Elevation, &format!("elevation as {} refused for {}", username, action));
+        return Err(UserError::NotAuthorized);
+    }
+    audit::record(Kind::Elevation, &format!("elevated for {}", action));
+    let result = f();
+    USER_MANAGER.lock().elevated = previous;
+    Ok(result)
+}
+
+/// Run `f` with the kernel's rights, whoever is logged in
+fn as_kernel<R>(f: impl FnOnce() -> R) -> R {
+    let previous = core::mem::replace(&mut USER_MANAGER.lock().elevated, Some(ROOT_ID));
+    let result = f();
+    USER_MANAGER.lock().elevated = previous;
+    result
+}
+
+/// Name of a user for the audit log, or their ID if they are gone
+fn user_name(user_id: UserId) -> String {
+    get_user(user_id).map_or_else(|| format!("#{}", user_id), |u| u.username)
+}
+
+/// Name of a group for the audit log, or its ID if it is gone
+fn group_name(group_id: GroupId) -> String {
+    USER_MANAGER.lock().groups.get(&group_id).map_or_else(|| format!("#{}", group_id), |g| g.name.clone())
+}
+
 /// Create new user (requires admin)
 pub fn create_user(username: &str, password: &str, is_admin: bool) -> Result<UserId, UserError> {
-    USER_MANAGER.lock().create_user(username, password, is_admin)
+    let id = USER_MANAGER.lock().create_user(username, password, is_admin)?;
+    audit::record(Kind::Users, &format!("created {} {}", if is_admin { "administrator" } else { "user" }, username));
+    // The lock is released first, as the VFS asks for credentials
+    if let Some(user) = get_user(id) {
+        make_home(&user);
+    }
+    Ok(id)
+}
+
+/// Get user by ID
+pub fn get_user(user_id: UserId) -> Option<User> {
+    USER_MANAGER.lock().get_user(user_id).cloned()
+}
+
+/// Credentials of the user logged in, or the kernel's if nobody is
+pub fn credentials() -> Credentials {
+    USER_MANAGER.lock().credentials()
+}
+
+/// List all groups
+pub fn list_groups() -> Vec<Group> {
+    USER_MANAGER.lock().list_groups().into_iter().cloned().collect()
+}
+
+/// Create a group
+pub fn create_group(name: &str) -> Result<GroupId, UserError> {
+    let id = USER_MANAGER.lock().create_group(name)?;
+    audit::record(Kind::Users, &format!("created group {}", name));
+    Ok(id)
+}
+
+/// Delete a group
+pub fn delete_group(group_id: GroupId) -> Result<(), UserError> {
+    let name = group_name(group_id);
+    USER_MANAGER.lock().delete_group(group_id)?;
+    audit::record(Kind::Users, &format!("deleted group {}", name));
+    Ok(())
+}
+
+/// Make a user a member of a group besides their primary one
+pub fn add_to_group(user_id: UserId, group_id: GroupId) -> Result<(), UserError> {
+    USER_MANAGER.lock().add_to_group(user_id, group_id)?;
+    audit::record(Kind::Users, &format!("added {} to group {}", user_name(user_id), group_name(group_id)));
+    Ok(())
+}
+
+/// Take a user out of one of their other groups
+pub fn remove_from_group(user_id: UserId, group_id: GroupId) -> Result<(), UserError> {
+    USER_MANAGER.lock().remove_from_group(user_id, group_id)?;
+    audit::record(Kind::Users, &format!("removed {} from group {}", user_name(user_id), group_name(group_id)));
+    Ok(())
+}
+
+/// Change a user's primary group
+pub fn set_primary_group(user_id: UserId, group_id: GroupId) -> Result<(), UserError> {
+    USER_MANAGER.lock().set_primary_group(user_id, group_id)?;
+    audit::record(Kind::Users, &format!("set primary group of {} to {}", user_name(user_id), group_name(group_id)));
+    Ok(())
+}
+
+/// Find a user by name
+pub fn find_user(username: &str) -> Option<User> {
+    USER_MANAGER.lock().find_user_by_name(username).cloned()
+}
+
+/// Find a group by name
+pub fn find_group(name: &str) -> Option<Group> {
+    USER_MANAGER.lock().find_group_by_name(name).cloned()
 }
 
 /// List all users
@@ -357,32 +1139,89 @@ pub fn list_users() -> Vec<User> {
 }
 
 /// Delete user
-pub fn delete_user(user_id: UserId) -> Result<(), UserError> {
-    USER_MANAGER.lock().delete_user(user_id)
+///
+/// The home directory is dealt with first; if that fails, the user is
+/// left in place.
+pub fn delete_user(user_id: UserId, home: HomeAction) -> Result<(), UserError> {
+    USER_MANAGER.lock().check_deletable(user_id)?;
+    let user = get_user(user_id).ok_or(UserError::UserNotFound)?;
+    let home_path = user.home_directory.as_str();
+    let kept = match home {
+        _ if fs::metadata(home_path).is_err() => String::from("missing"),
+        HomeAction::Keep => String::from("kept"),
+        HomeAction::Remove => {
+            fs::remove(home_path).map_err(UserError::Filesystem)?;
+            String::from("removed")
+        }
+        HomeAction::Archive => {
+            let archive = archive_home(&user).map_err(UserError::Filesystem)?;
+            fs::remove(home_path).map_err(UserError::Filesystem)?;
+            format!("archived to {}", archive)
+        }
+    };
+    USER_MANAGER.lock().delete_user(user_id)?;
+    audit::record(Kind::Users, &format!("deleted user {}, home directory {}", user.username, kept));
+    Ok(())
+}
+
+/// Activate or deactivate a user
+pub fn set_user_active(user_id: UserId, active: bool) -> Result<(), UserError> {
+    USER_MANAGER.lock().set_user_active(user_id, active)?;
+    audit::record(Kind::Users, &format!("{} user {}", if active { "activated" } else { "deactivated" }, user_name(user_id)));
+    Ok(())
+}
+
+/// Unlock an account locked after wrong passwords
+pub fn unlock_user(user_id: UserId) -> Result<(), UserError> {
+    USER_MANAGER.lock().unlock_user(user_id)?;
+    audit::record(Kind::Users, &format!("unlocked user {}", user_name(user_id)));
+    Ok(())
 }
 
 /// Change password
 pub fn change_password(user_id: UserId, new_password: &str) -> Result<(), UserError> {
-    USER_MANAGER.lock().change_password(user_id, new_password)
+    USER_MANAGER.lock().change_password(user_id, new_password)?;
+    audit::record(Kind::Users, &format!("changed password of {}", user_name(user_id)));
+    Ok(())
 }
 
 /// Print user info
 pub fn print_users() {
     println!("\nUser Accounts:");
-    println!("{:<6} {:<16} {:<10} {:<12} {}", "ID", "Username", "Type", "Status", "Home");
-    println!("{:-<70}", "");
+    println!("{:<6} {:<16} {:<10} {:<12} {:<20} {}", "ID", "Username", "Type", "Status", "Last login", "Home");
+    println!("{:-<90}", "");
     
     for user in list_users() {
-        println!("{:<6} {:<16} {:<10} {:<12} {}",
+        let last_login = user.last_login.map_or(String::from("never"), |t| format!("{}", t));
+        println!("{:<6} {:<16} {:<10} {:<12} {:<20} {}",
             user.id,
             user.username,
             if user.is_admin { "admin" } else { "user" },
-            if user.is_active { "active" } else { "inactive" },
+            if !user.is_active { "inactive" } else if user.lockout_remaining().is_some() { "locked" } else { "active" },
+            last_login,
             user.home_directory
         );
     }
 }
 
+/// Print groups and their members
+pub fn print_groups() {
+    let manager = USER_MANAGER.lock();
+    println!("\nGroups:");
+    println!("{:<6} {:<16} {}", "ID", "Name", "Members");
+    println!("{:-<70}", "");
+
+    for group in manager.list_groups() {
+        // Users whose primary group it is first, then the others
+        let members: Vec<&str> = manager.list_users().into_iter()
+            .filter(|u| u.primary_group == group.id)
+            .chain(group.members.iter().filter_map(|id| manager.get_user(*id)))
+            .map(|u| u.username.as_str())
+            .collect();
+        println!("{:<6} {:<16} {}", group.id, group.name, members.join(", "));
+    }
+}
+
 /// Print sessions
 pub fn print_sessions() {
     let manager = USER_MANAGER.lock();
@@ -403,3 +1242,40 @@ pub fn print_sessions() {
         }
     }
 }
+
+mod kernel_tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    #[kernel_test]
+    fn unknown_names_are_throttled_like_accounts() -> Result<(), String> {
+        let name = "nobody-throttle-test";
+        check!(USER_MANAGER.lock().find_user_by_name(name).is_none());
+        check_eq!(authenticate(name, "guess"), Err(UserError::WrongPassword));
+        // The second guess waits, as it would for a real account
+        check_eq!(authenticate(name, "guess"), Err(UserError::TryLater(1)));
+        USER_MANAGER.lock().unknown_names.remove(name);
+        check_eq!(authenticate(name, "guess"), Err(UserError::WrongPassword));
+        USER_MANAGER.lock().unknown_names.remove(name);
+        Ok(())
+    }
+
+    /// The right password does not forgive the wrong codes before it
+    #[kernel_test]
+    fn wrong_codes_lock_the_account() -> Result<(), String> {
+        let name = "totp-lockout-test";
+        let id = USER_MANAGER.lock().create_user(name, "hunter22", false).map_err(|e| format!("{:?}", e))?;
+        USER_MANAGER.lock().users.get_mut(&id).ok_or("no user")?.totp = Some(totp::Totp::generate(totp::Algorithm::Sha1));
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            // Waited out the backoff
+            USER_MANAGER.lock().users.get_mut(&id).ok_or("no user")?.retry_at = 0;
+            check_eq!(login(name, "hunter22", Some("not a code")), Err(UserError::WrongCode));
+        }
+        let result = login(name, "hunter22", Some("not a code"));
+        check!(matches!(result, Err(UserError::LockedOut(_))));
+        USER_MANAGER.lock().delete_user(id).map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+//! Time-based one-time passwords (RFC 6238)
+//!
+//! A user with two-factor login on shares a random secret with an
+//! authenticator app, which shows a six-digit code made from it and the
+//! time, new every 30 seconds. Logging in takes the current code as well
+//! as the password. Codes from one step either side are accepted to allow
+//! for clocks that drift, and none is accepted twice.
+//!
+//! Setting it up gives ten recovery codes, each good for one login
+//! without the app. Only their hashes are kept, salted afresh each time
+//! codes are made so a stolen list cannot be checked against a table.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::crypto::{ct, rng, sha1, sha256};
+
+/// Digits in a code
+pub const DIGITS: u32 = 6;
+
+/// Seconds each code is good for
+pub const STEP_SECONDS: u64 = 30;
+
+/// Bytes in a new secret
+const SECRET_LEN: usize = 20;
+
+/// Bytes of salt hashed into the recovery codes
+const SALT_LEN: usize = 16;
+
+/// Recovery codes given when two-factor login is set up
+pub const RECOVERY_CODES: usize = 10;
+
+/// Name the authenticator app lists the account under
+const ISSUER: &str = "WebbOS";
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// HMAC the codes are made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// What authenticator apps assume when not told
+    Sha1,
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Name in an `otpauth://` URL
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA256",
+        }
+    }
+}
+
+/// A user's one-time password secret and recovery codes
+#[derive(Debug, Clone)]
+pub struct Totp {
+    secret: Vec<u8>,
+    pub algorithm: Algorithm,
+    /// Time step of the last code accepted, so it cannot be used again
+    last_step: u64,
+    /// Hashes of the recovery codes not yet used
+    recovery: Vec<[u8; 32]>,
+    /// Salt of the recovery code hashes
+    salt: [u8; SALT_LEN],
+}
+
+impl Totp {
+    /// A new random secret
+    pub fn generate(algorithm: Algorithm) -> Self {
+        let mut secret = alloc::vec![0u8; SECRET_LEN];
+        rng::fill_bytes(&mut secret);
+        Self { secret, algorithm, last_step: 0, recovery: Vec::new(), salt: [0; SALT_LEN] }
+    }
+
+    /// The secret as the base32 text authenticator apps take
+    pub fn secret_base32(&self) -> String {
+        let mut out = String::new();
+        let mut bits = 0u32;
+        let mut value = 0u32;
+        for &byte in &self.secret {
+            value = (value << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32[(value >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(BASE32[(value << (5 - bits)) as usize & 31] as char);
+        }
+        out
+    }
+
+    /// The `otpauth://` URL that sets up an authenticator app, as its QR
+    /// codes carry
+    pub fn url(&self, username: &str) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm={algorithm}&digits={digits}&period={period}",
+            issuer = ISSUER,
+            user = percent_encode(username),
+            secret = self.secret_base32(),
+            algorithm = self.algorithm.name(),
+            digits = DIGITS,
+            period = STEP_SECONDS,
+        )
+    }
+
+    /// The code for time step `step`
+    fn code_at(&self, step: u64) -> u32 {
+        let mac: Vec<u8> = match self.algorithm {
+            Algorithm::Sha1 => sha1::hmac(&self.secret, &step.to_be_bytes()).to_vec(),
+            Algorithm::Sha256 => sha256::hmac(&self.secret, &step.to_be_bytes()).to_vec(),
+        };
+        // Dynamic truncation (RFC 4226 section 5.3)
+        let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+        let value = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+        value % 10u32.pow(DIGITS)
+    }
+
+    /// Whether `code` is the one for Unix time `now`, or a step either
+    /// side, and has not been used
+    pub fn check(&mut self, code: &str, now: u64) -> bool {
+        let code = code.trim();
+        if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+        let step = now / STEP_SECONDS;
+        for candidate in [step.saturating_sub(1), step, step + 1] {
+            let expected = format!("{:0width$}", self.code_at(candidate), width = DIGITS as usize);
+            if candidate > self.last_step && ct::eq(expected.as_bytes(), code.as_bytes()) {
+                self.last_step = candidate;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Replace the recovery codes with new ones, which are returned
+    pub fn new_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| {
+            let mut bytes = [0u8; 5];
+            rng::fill_bytes(&mut bytes);
+            let text: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}-{}", &text[..5], &text[5..])
+        }).collect();
+        rng::fill_bytes(&mut self.salt);
+        self.recovery = codes.iter().map(|c| hash_recovery_code(&self.salt, c)).collect();
+        codes
+    }
+
+    /// Recovery codes not yet used
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery.len()
+    }
+
+    /// Use up recovery code `code`, if it is one
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = hash_recovery_code(&self.salt, code);
+        match self.recovery.iter().position(|h| ct::eq(h, &hash)) {
+            Some(index) => {
+                self.recovery.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn hash_recovery_code(salt: &[u8; SALT_LEN], code: &str) -> [u8; 32] {
+    let normal: String = code.trim().chars().filter(|&c| c != '-').map(|c| c.to_ascii_lowercase()).collect();
+    let mut hasher = sha256::Sha256::new();
+    hasher.update(b"WebbOS recovery code ");
+    hasher.update(salt);
+    hasher.update(normal.as_bytes());
+    hasher.finalize()
+}
+
+/// `text` with everything but unreserved URL characters percent-encoded
+fn percent_encode(text: &str) -> String {
+    let mut out = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::testing::kernel_test;
+    use crate::{check, check_eq};
+
+    fn with_secret(secret: &[u8], algorithm: Algorithm) -> Totp {
+        Totp { secret: secret.to_vec(), algorithm, last_step: 0, recovery: Vec::new(), salt: [0; SALT_LEN] }
+    }
+
+    /// RFC 6238 appendix B, to six digits
+    #[kernel_test]
+    fn rfc6238_sha1_vectors() -> Result<(), String> {
+        let totp = with_secret(b"12345678901234567890", Algorithm::Sha1);
+        let vectors = [
+            (59, 287082), (1111111109, 81804), (1111111111, 50471),
+            (1234567890, 5924), (2000000000, 279037), (20000000000, 353130),
+        ];
+        for (time, code) in vectors {
+            check_eq!(totp.code_at(time / STEP_SECONDS), code);
+        }
+        Ok(())
+    }
+
+    /// RFC 6238 appendix B, to six digits
+    #[kernel_test]
+    fn rfc6238_sha256_vectors() -> Result<(), String> {
+        let totp = with_secret(b"12345678901234567890123456789012", Algorithm::Sha256);
+        let vectors = [
+            (59, 119246), (1111111109, 84774), (1111111111, 62674),
+            (1234567890, 819424), (2000000000, 698825), (20000000000, 737706),
+        ];
+        for (time, code) in vectors {
+            check_eq!(totp.code_at(time / STEP_SECONDS), code);
+        }
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn codes_are_taken_once_and_a_step_either_side() -> Result<(), String> {
+        let mut totp = with_secret(b"12345678901234567890", Algorithm::Sha1);
+        check!(!totp.check("28708", 59));
+        check!(totp.check("081804", 1111111109 + STEP_SECONDS));
+        check!(!totp.check("081804", 1111111109));
+        check!(totp.check(" 050471 ", 1111111111));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn recovery_codes_are_salted_and_used_up() -> Result<(), String> {
+        let mut first = with_secret(b"12345678901234567890", Algorithm::Sha1);
+        let codes = first.new_recovery_codes();
+        check_eq!(codes.len(), RECOVERY_CODES);
+        // The same code hashes differently under another user's salt
+        let mut second = first.clone();
+        second.new_recovery_codes();
+        check!(hash_recovery_code(&first.salt, &codes[0]) != hash_recovery_code(&second.salt, &codes[0]));
+
+        let typed = codes[0].to_ascii_uppercase().replace('-', "");
+        check!(first.use_recovery_code(&typed));
+        check!(!first.use_recovery_code(&codes[0]));
+        check_eq!(first.recovery_codes_left(), RECOVERY_CODES - 1);
+        Ok(())
+    }
+}
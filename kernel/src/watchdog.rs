@@ -0,0 +1,288 @@
+//! Watchdog
+//!
+//! Catches hangs, which are common while a driver is being brought up.
+//! The scheduler's tick, the input poll and the desktop's redraw `pet` the
+//! watchdog each time they run; a subsystem is watched from its first pet
+//! until it `rest`s. One that has not been seen for the timeout is
+//! reported stuck, once, with when it was last seen, and the configured
+//! action is taken: just the report, a backtrace from an NMI, or a reboot.
+//!
+//! The timer tick checks the input and the compositor. A processor stuck
+//! with interrupts off has no ticks, so where there are architectural
+//! performance counters one counts unhalted cycles and raises an NMI about
+//! twice a second, which checks the tick itself; the main loops check it
+//! too. Reports are made at interrupt time, when the code that is stuck
+//! may hold the console's locks, so they go straight to COM1; once the
+//! subsystem is seen again, `poll` puts them in the log.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use crate::arch::{apic, cpu};
+use crate::arch::interrupts::InterruptStackFrame;
+use crate::console::emergency_write;
+use crate::drivers::timer;
+use crate::println;
+use crate::{info, warn};
+
+/// What is watched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// The timer tick, and with it the scheduler
+    Scheduler = 0,
+    /// Keyboard and mouse polling
+    Input = 1,
+    /// Desktop redraws
+    Compositor = 2,
+}
+
+impl Subsystem {
+    const ALL: [Subsystem; 3] = [Subsystem::Scheduler, Subsystem::Input, Subsystem::Compositor];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Scheduler => "scheduler",
+            Subsystem::Input => "input",
+            Subsystem::Compositor => "compositor",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// What to do when something is stuck, besides reporting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Log = 0,
+    /// Print the return addresses on the stack the NMI interrupted
+    Backtrace = 1,
+    Reboot = 2,
+}
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "log" => Some(Action::Log),
+            "backtrace" => Some(Action::Backtrace),
+            "reboot" => Some(Action::Reboot),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Log => "log",
+            Action::Backtrace => "backtrace",
+            Action::Reboot => "reboot",
+        }
+    }
+}
+
+/// Ticks between checks from the timer tick
+const CHECK_TICKS: u64 = 100;
+/// Words of stack searched for return addresses
+const BACKTRACE_WORDS: usize = 256;
+
+// Architectural performance monitoring MSRs
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Event select: unhalted core cycles, in both rings, interrupting on
+/// overflow, enabled
+const UNHALTED_CYCLES: u64 = 0x3C | (1 << 16) | (1 << 17) | (1 << 20) | (1 << 22);
+
+/// Timer's `now_ns` of each subsystem's last pet; 0 while it rests
+static LAST_SEEN: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+/// When each subsystem that has come back was last seen before, and for
+/// how long it was gone; the length is 0 once `poll` has logged it
+static STALL_FROM: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static STALL_LENGTH: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+/// A bit per subsystem reported stuck and not seen since
+static STUCK: AtomicU8 = AtomicU8::new(0);
+/// Nanoseconds of silence before a subsystem is stuck; 0 turns it off
+static TIMEOUT_NS: AtomicU64 = AtomicU64::new(10_000_000_000);
+static ACTION: AtomicU8 = AtomicU8::new(Action::Log as u8);
+/// Cycles between performance counter NMIs; 0 without them
+static NMI_PERIOD: AtomicU64 = AtomicU64::new(0);
+/// An NMI has been sent for a backtrace
+static BACKTRACE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Set up the performance counter NMI, where the processor has one
+pub fn init() {
+    let period = (timer::tsc_per_ms() * 500).min(i32::MAX as u64);
+    if period == 0 || !has_cycle_counter() || !apic::route_performance_nmi() {
+        info!("watchdog", "No performance counter NMI; hangs with interrupts off go unseen");
+        return;
+    }
+    NMI_PERIOD.store(period, Ordering::Relaxed);
+    unsafe {
+        cpu::wrmsr(IA32_PERFEVTSEL0, 0);
+        cpu::wrmsr(IA32_PMC0, period.wrapping_neg());
+        cpu::wrmsr(IA32_PERFEVTSEL0, UNHALTED_CYCLES);
+        cpu::wrmsr(IA32_PERF_GLOBAL_CTRL, cpu::rdmsr(IA32_PERF_GLOBAL_CTRL) | 1);
+    }
+    info!("watchdog", "NMI every {} cycles", period);
+}
+
+/// Whether architectural performance monitoring, version 2 or later,
+/// counts unhalted cycles
+fn has_cycle_counter() -> bool {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(0x0A) };
+    let version = leaf.eax & 0xFF;
+    let counters = (leaf.eax >> 8) & 0xFF;
+    let events = (leaf.eax >> 24) & 0xFF;
+    // EBX bit 0 set means the unhalted cycles event is missing
+    version >= 2 && counters >= 1 && events >= 1 && leaf.ebx & 1 == 0
+}
+
+/// Note that `subsystem` is running
+pub fn pet(subsystem: Subsystem) {
+    let now = timer::now_ns().max(1);
+    let last = LAST_SEEN[subsystem as usize].swap(now, Ordering::Relaxed);
+    if STUCK.fetch_and(!subsystem.bit(), Ordering::Relaxed) & subsystem.bit() != 0 {
+        STALL_FROM[subsystem as usize].store(last, Ordering::Relaxed);
+        STALL_LENGTH[subsystem as usize].store(now.saturating_sub(last).max(1), Ordering::Relaxed);
+    }
+}
+
+/// Stop watching `subsystem` until it is petted again
+pub fn rest(subsystem: Subsystem) {
+    LAST_SEEN[subsystem as usize].store(0, Ordering::Relaxed);
+    STUCK.fetch_and(!subsystem.bit(), Ordering::Relaxed);
+}
+
+pub fn set_timeout(seconds: u64) {
+    TIMEOUT_NS.store(seconds * 1_000_000_000, Ordering::Relaxed);
+}
+
+pub fn set_action(action: Action) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+fn action() -> Action {
+    match ACTION.load(Ordering::Relaxed) {
+        1 => Action::Backtrace,
+        2 => Action::Reboot,
+        _ => Action::Log,
+    }
+}
+
+/// Check the input and the compositor; called by the timer tick
+pub fn tick(ticks: u64) {
+    if ticks % CHECK_TICKS == 0 {
+        check(Subsystem::Input, None);
+        check(Subsystem::Compositor, None);
+    }
+}
+
+/// Handle an NMI if it is the watchdog's; false if it is not
+pub fn nmi(frame: &InterruptStackFrame) -> bool {
+    let mut handled = false;
+    let period = NMI_PERIOD.load(Ordering::Relaxed);
+    if period != 0 && unsafe { cpu::rdmsr(IA32_PERF_GLOBAL_STATUS) } & 1 != 0 {
+        unsafe {
+            cpu::wrmsr(IA32_PMC0, period.wrapping_neg());
+            cpu::wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        }
+        apic::route_performance_nmi();
+        check(Subsystem::Scheduler, Some(frame));
+        handled = true;
+    }
+    if BACKTRACE_PENDING.swap(false, Ordering::Relaxed) {
+        backtrace(frame);
+        handled = true;
+    }
+    handled
+}
+
+/// Check the tick from a main loop, which runs even with interrupts off,
+/// and log what has come back since the last call
+pub fn poll() {
+    check(Subsystem::Scheduler, None);
+    for subsystem in Subsystem::ALL {
+        let stuck = STALL_LENGTH[subsystem as usize].swap(0, Ordering::Relaxed);
+        if stuck != 0 {
+            let last = STALL_FROM[subsystem as usize].load(Ordering::Relaxed);
+            warn!("watchdog", "{} was stuck for {}ms from {}.{:03}s", subsystem.name(),
+                stuck / 1_000_000, last / 1_000_000_000, last / 1_000_000 % 1000);
+        }
+    }
+}
+
+/// Report `subsystem` if it has not been seen for the timeout; `frame` is
+/// the NMI's, when called from one
+fn check(subsystem: Subsystem, frame: Option<&InterruptStackFrame>) {
+    let timeout = TIMEOUT_NS.load(Ordering::Relaxed);
+    let last = LAST_SEEN[subsystem as usize].load(Ordering::Relaxed);
+    let now = timer::now_ns();
+    if timeout == 0 || last == 0 || now.saturating_sub(last) < timeout {
+        return;
+    }
+    if STUCK.fetch_or(subsystem.bit(), Ordering::Relaxed) & subsystem.bit() != 0 {
+        return;
+    }
+    emergency_write(format_args!("\n[watchdog] {} stuck: last seen at {}.{:03}s, {}ms ago\n",
+        subsystem.name(), last / 1_000_000_000, last / 1_000_000 % 1000, (now - last) / 1_000_000));
+    match action() {
+        Action::Log => {}
+        Action::Backtrace => match frame {
+            Some(frame) => backtrace(frame),
+            None => {
+                BACKTRACE_PENDING.store(true, Ordering::Relaxed);
+                if !apic::send_nmi_to_self() {
+                    BACKTRACE_PENDING.store(false, Ordering::Relaxed);
+                    emergency_write(format_args!("[watchdog] No local APIC to send an NMI with\n"));
+                }
+            }
+        },
+        Action::Reboot => {
+            emergency_write(format_args!("[watchdog] Rebooting\n"));
+            cpu::reboot();
+        }
+    }
+}
+
+/// Print where `frame` interrupted and the kernel code addresses on its
+/// stack, the return addresses among them, without frame pointers to go by
+fn backtrace(frame: &InterruptStackFrame) {
+    emergency_write(format_args!("[watchdog] Interrupted at {:#x}, stack {:#x}\n",
+        frame.instruction_pointer, frame.stack_pointer));
+    // Only as far as the end of the stack pointer's page, which is mapped
+    let start = frame.stack_pointer & !7;
+    let end = ((start | 0xFFF) + 1).min(start + (BACKTRACE_WORDS * 8) as u64);
+    for address in (start..end).step_by(8) {
+        let word = unsafe { core::ptr::read_volatile(address as *const u64) };
+        match crate::symbols::lookup(word) {
+            Some((name, offset)) => emergency_write(format_args!("  {:#x}  {}+{:#x}\n", word, name, offset)),
+            None if crate::symbols::is_text(word) => emergency_write(format_args!("  {:#x}\n", word)),
+            None => {}
+        }
+    }
+}
+
+/// Print what is watched and when it was last seen
+pub fn print_info() {
+    let timeout = TIMEOUT_NS.load(Ordering::Relaxed);
+    if timeout == 0 {
+        println!("Watchdog: off");
+    } else {
+        println!("Watchdog: {}s timeout, then {}", timeout / 1_000_000_000, action().name());
+    }
+    match NMI_PERIOD.load(Ordering::Relaxed) {
+        0 => println!("  NMI: none, hangs with interrupts off are caught only from the main loops"),
+        period => println!("  NMI: every {} cycles from the performance counters", period),
+    }
+    let now = timer::now_ns();
+    let stuck = STUCK.load(Ordering::Relaxed);
+    for subsystem in Subsystem::ALL {
+        match LAST_SEEN[subsystem as usize].load(Ordering::Relaxed) {
+            0 => println!("  {:12} not watched", subsystem.name()),
+            last => println!("  {:12} seen {}ms ago{}", subsystem.name(), now.saturating_sub(last) / 1_000_000,
+                if stuck & subsystem.bit() != 0 { ", stuck" } else { "" }),
+        }
+    }
+}
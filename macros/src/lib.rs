@@ -0,0 +1,95 @@
+//! Procedural macros for the kernel
+//!
+//! `#[kernel_test]` marks a function as a kernel test:
+//!
+//! ```ignore
+//! #[kernel_test]
+//! fn parses_utc() -> Result<(), String> {
+//!     check_eq!(parse_timezone("UTC"), Some(0));
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Next to the function it puts a `testing::KernelTest` naming it in the
+//! `.kernel_tests` linker section, where `testing::tests` finds every test
+//! in the kernel without a list to keep.
+//!
+//! `#[kernel_export]` does the same for the `extern "C"` functions modules
+//! may call, with a `module::Export` in `.kernel_exports`.
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+#[proc_macro_attribute]
+pub fn kernel_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return error("#[kernel_test] takes no arguments");
+    }
+    let name = match function_name(&item) {
+        Some(name) => name,
+        None => return error("#[kernel_test] goes on a function"),
+    };
+    let registration = format!(
+        "#[used]
+        #[link_section = \".kernel_tests\"]
+        static __KERNEL_TEST_{upper}: crate::testing::KernelTest = crate::testing::KernelTest {{
+            name: concat!(module_path!(), \"::{name}\"),
+            run: {name},
+        }};",
+        upper = name.to_uppercase(),
+        name = name,
+    );
+    let mut output = item;
+    output.extend(registration.parse::<TokenStream>().unwrap());
+    output
+}
+
+#[proc_macro_attribute]
+pub fn kernel_export(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return error("#[kernel_export] takes no arguments");
+    }
+    let name = match function_name(&item) {
+        Some(name) => name,
+        None => return error("#[kernel_export] goes on a function"),
+    };
+    // Modules are built apart from the kernel: only the C ABI is stable
+    if !item.to_string().contains("extern \"C\"") {
+        return error("#[kernel_export] goes on an extern \"C\" function");
+    }
+    let registration = format!(
+        "#[used]
+        #[link_section = \".kernel_exports\"]
+        static __KERNEL_EXPORT_{upper}: crate::module::Export = crate::module::Export {{
+            name: \"{name}\",
+            address: {name} as *const (),
+        }};",
+        upper = name.to_uppercase(),
+        name = name,
+    );
+    let mut output = item;
+    output.extend(registration.parse::<TokenStream>().unwrap());
+    output
+}
+
+/// The identifier after `fn`, at the top level of the item
+fn function_name(item: &TokenStream) -> Option<String> {
+    let mut tokens = item.clone().into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Ident(ident) if ident.to_string() == "fn" => {
+                return match tokens.next() {
+                    Some(TokenTree::Ident(name)) => Some(name.to_string()),
+                    _ => None,
+                };
+            }
+            // The body; `fn` in it belongs to something else
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn error(message: &str) -> TokenStream {
+    format!("compile_error!({:?});", message).parse().unwrap()
+}
@@ -0,0 +1,38 @@
+#!/bin/sh
+# Run the kernel tests under QEMU, for CI
+#
+# Boots the built kernel with `test` on its command line from a FAT
+# directory, so it runs every #[kernel_test] and leaves QEMU through the
+# isa-debug-exit device: 33 when all passed, 35 when any failed. The
+# results are on stdout as TAP. Exits 0 only when all passed.
+#
+#   make bootloader kernel && scripts/qemu-test.sh [debug|release]
+
+set -u
+
+BUILD=${1:-debug}
+OVMF=${OVMF:-OVMF.fd}
+TIMEOUT=${TIMEOUT:-300}
+
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+mkdir -p "$dir/EFI/BOOT"
+cp "target/x86_64-unknown-uefi/$BUILD/bootloader.efi" "$dir/EFI/BOOT/BOOTX64.EFI" || exit 1
+cp "target/x86_64-unknown-none/$BUILD/kernel" "$dir/kernel.elf" || exit 1
+echo "test" > "$dir/cmdline.txt"
+
+timeout "$TIMEOUT" qemu-system-x86_64 \
+    -bios "$OVMF" \
+    -drive format=raw,file=fat:rw:"$dir" \
+    -m 512M -smp 1 \
+    -display none -serial stdio \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04
+status=$?
+
+case $status in
+    33) echo "qemu-test: all kernel tests passed"; exit 0 ;;
+    35) echo "qemu-test: kernel tests failed" >&2 ;;
+    124) echo "qemu-test: no result within ${TIMEOUT}s" >&2 ;;
+    *) echo "qemu-test: QEMU exited with $status before the tests finished" >&2 ;;
+esac
+exit 1
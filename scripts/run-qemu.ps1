@@ -78,6 +78,9 @@ if ($Rebuild -or -not (Test-Path $KernelTarget) -or -not (Test-Path $BootloaderT
     if ($Release) { $kernelArgs += "--release" }
     & cargo @kernelArgs
     if ($LASTEXITCODE -ne 0) { exit 1 }
+    # Symbols for panic backtraces
+    & python3 tools/embed-symbols.py $KernelTarget
+    if ($LASTEXITCODE -ne 0) { exit 1 }
     
     Write-Host "  Building bootloader..." -ForegroundColor Gray
     $bootloaderArgs = @("+nightly-2025-01-15", "build", "-p", "bootloader", "--target", "x86_64-unknown-uefi", "-Z", "build-std=core,compiler_builtins,alloc")
@@ -9,15 +9,30 @@ use crate::types::{MemoryRegion, PhysAddr, VirtAddr};
 /// Magic number to identify valid boot info
 pub const BOOTINFO_MAGIC: u64 = 0x1BAD_B002_0B0B_0055;
 
-/// Boot protocol version
-pub const BOOTINFO_VERSION: u32 = 1;
+/// Boot protocol version: of the header, not the tags
+pub const BOOTINFO_VERSION: u32 = 6;
 
-/// Boot information structure passed from bootloader to kernel
-/// 
+/// Physical address of the crash dump region
+///
+/// The bootloader reserves it and never clears it, so what the kernel
+/// writes there when it panics is still there after a warm reboot.
+pub const CRASH_REGION_ADDR: u64 = 0x600000;
+/// Size of the crash dump region in bytes
+pub const CRASH_REGION_SIZE: u64 = 256 * 1024;
+
+/// Boot information passed from bootloader to kernel
+///
+/// A fixed header with what every kernel needs, followed by tags, each
+/// size-prefixed, for the rest: see `Tag`. A reader skips the tags it
+/// does not know and does without the ones that are missing, so most
+/// changes are a new tag, and bootloader and kernel need not be rebuilt
+/// together. Only a change to this header bumps `BOOTINFO_VERSION`.
+///
 /// # Safety
 /// This structure is placed in memory by the bootloader and
 /// passed to the kernel. The kernel must not modify it until
-/// it has copied any needed information.
+/// it has copied any needed information. Its tags follow it in
+/// memory, `total_size` bytes in all.
 #[derive(Debug)]
 #[repr(C, align(8))]
 pub struct BootInfo {
@@ -25,8 +40,12 @@ pub struct BootInfo {
     pub magic: u64,
     /// Boot protocol version
     pub version: u32,
-    /// Reserved (padding)
-    pub _reserved: u32,
+    /// Bytes of this header; the tags start here
+    pub header_size: u32,
+    /// Bytes of the header and the tags together
+    pub total_size: u32,
+    /// What the tags bring, a `FEATURE_*` bit each
+    pub features: u32,
     /// Physical address of memory map
     pub memory_map_addr: PhysAddr,
     /// Number of memory map entries
@@ -37,12 +56,8 @@ pub struct BootInfo {
     pub kernel_size: u64,
     /// Virtual address where kernel is mapped
     pub kernel_virt_addr: VirtAddr,
-    /// Framebuffer information
-    pub framebuffer: FramebufferInfo,
-    /// Physical address of RSDP (ACPI)
-    pub rsdp_addr: Option<PhysAddr>,
-    /// Command line string (null-terminated)
-    pub cmdline: Option<PhysAddr>,
+    /// Command line string (null-terminated); 0 for none
+    pub cmdline: PhysAddr,
     /// Bootloader name string (null-terminated)
     pub bootloader_name: PhysAddr,
     /// Stack top address (virtual)
@@ -51,10 +66,277 @@ pub struct BootInfo {
     pub stack_size: u64,
 }
 
+/// Header of each tag; its payload follows
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TagHeader {
+    /// `Tag::KIND` of the payload
+    pub kind: u16,
+    /// `TAG_REQUIRED`, or 0
+    pub flags: u16,
+    /// Bytes of header and payload, a multiple of 8
+    pub size: u32,
+}
+
+/// A kernel that does not know the tag must not boot
+pub const TAG_REQUIRED: u16 = 1;
+
+/// The payload of a tag
+///
+/// A payload only grows, at the end: a reader takes what it knows of a
+/// longer one, and does without one shorter than it knows.
+pub trait Tag: Copy {
+    /// Tag kind, below 32; `1 << KIND` is its feature bit
+    const KIND: u16;
+    const FEATURE: u32 = 1 << Self::KIND;
+}
+
+pub const FEATURE_FRAMEBUFFER: u32 = FramebufferInfo::FEATURE;
+pub const FEATURE_ACPI: u32 = AcpiTag::FEATURE;
+pub const FEATURE_INITRD: u32 = InitrdTag::FEATURE;
+pub const FEATURE_PHYSMAP: u32 = PhysmapTag::FEATURE;
+pub const FEATURE_VIDEO_MODES: u32 = VideoModesTag::FEATURE;
+pub const FEATURE_EDID: u32 = EdidTag::FEATURE;
+pub const FEATURE_SMBIOS: u32 = SmbiosTag::FEATURE;
+pub const FEATURE_EFI: u32 = EfiTag::FEATURE;
+
+/// Every feature and its name
+pub const FEATURES: [(u32, &str); 8] = [
+    (FEATURE_FRAMEBUFFER, "framebuffer"),
+    (FEATURE_ACPI, "acpi"),
+    (FEATURE_INITRD, "initrd"),
+    (FEATURE_PHYSMAP, "physmap"),
+    (FEATURE_VIDEO_MODES, "video-modes"),
+    (FEATURE_EDID, "edid"),
+    (FEATURE_SMBIOS, "smbios"),
+    (FEATURE_EFI, "efi"),
+];
+
+/// The features this build knows the tags of
+pub const KNOWN_FEATURES: u32 = {
+    let mut known = 0;
+    let mut i = 0;
+    while i < FEATURES.len() {
+        known |= FEATURES[i].0;
+        i += 1;
+    }
+    known
+};
+
+/// Names of the features in `features` that this build knows
+pub fn feature_names(features: u32) -> impl Iterator<Item = &'static str> {
+    FEATURES.iter().filter(move |(feature, _)| features & feature != 0).map(|(_, name)| *name)
+}
+
+/// The ACPI RSDP
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct AcpiTag {
+    pub rsdp: PhysAddr,
+}
+
+/// The boot entry's initrd, a tar archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct InitrdTag {
+    pub addr: PhysAddr,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// Where all of physical memory is mapped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct PhysmapTag {
+    /// Where physical address 0 is mapped; the rest follows
+    pub base: VirtAddr,
+    /// Bytes of physical memory mapped
+    pub size: u64,
+}
+
+/// The modes GOP offered, an array of `VideoMode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct VideoModesTag {
+    pub addr: PhysAddr,
+    pub count: u64,
+}
+
+/// The display's EDID
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct EdidTag {
+    pub addr: PhysAddr,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// The SMBIOS entry point, 3.0 if there is one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct SmbiosTag {
+    pub entry_point: PhysAddr,
+}
+
+/// The EFI tables
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct EfiTag {
+    /// Physical address of the EFI system table
+    pub system_table: PhysAddr,
+    /// Physical address of the EFI runtime services table, 0 for none;
+    /// the memory map marks what it needs as `EfiRuntime`, which the
+    /// kernel maps at the same address before calling it
+    pub runtime_services: PhysAddr,
+}
+
+impl Tag for FramebufferInfo {
+    const KIND: u16 = 1;
+}
+
+impl Tag for AcpiTag {
+    const KIND: u16 = 2;
+}
+
+impl Tag for InitrdTag {
+    const KIND: u16 = 3;
+}
+
+impl Tag for PhysmapTag {
+    const KIND: u16 = 4;
+}
+
+impl Tag for VideoModesTag {
+    const KIND: u16 = 5;
+}
+
+impl Tag for EdidTag {
+    const KIND: u16 = 6;
+}
+
+impl Tag for SmbiosTag {
+    const KIND: u16 = 7;
+}
+
+impl Tag for EfiTag {
+    const KIND: u16 = 8;
+}
+
+/// A tag as it is in memory
+#[derive(Clone, Copy, Debug)]
+pub struct RawTag<'a> {
+    pub kind: u16,
+    pub flags: u16,
+    pub payload: &'a [u8],
+}
+
+/// The tags after a header, in order; ends with an error at a tag that
+/// does not fit
+pub struct Tags<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Result<RawTag<'a>, BootInfoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let header_size = core::mem::size_of::<TagHeader>();
+        let header = match self.data.get(..header_size) {
+            // Tags are 8-aligned, like the header
+            Some(bytes) => unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const TagHeader) },
+            None => {
+                self.data = &[];
+                return Some(Err(BootInfoError::BadTag));
+            }
+        };
+        let size = header.size as usize;
+        if size < header_size || size % 8 != 0 || size > self.data.len() {
+            self.data = &[];
+            return Some(Err(BootInfoError::BadTag));
+        }
+        let payload = &self.data[header_size..size];
+        self.data = &self.data[size..];
+        Some(Ok(RawTag { kind: header.kind, flags: header.flags, payload }))
+    }
+}
+
 impl BootInfo {
+    /// A header with no tags, and the rest to fill in
+    pub fn new() -> Self {
+        Self {
+            magic: BOOTINFO_MAGIC,
+            version: BOOTINFO_VERSION,
+            header_size: core::mem::size_of::<BootInfo>() as u32,
+            total_size: core::mem::size_of::<BootInfo>() as u32,
+            features: 0,
+            memory_map_addr: PhysAddr::new(0),
+            memory_map_count: 0,
+            kernel_addr: PhysAddr::new(0),
+            kernel_size: 0,
+            kernel_virt_addr: VirtAddr::new(0),
+            cmdline: PhysAddr::new(0),
+            bootloader_name: PhysAddr::new(0),
+            stack_top: VirtAddr::new(0),
+            stack_size: 0,
+        }
+    }
+
+    /// Check this boot info can be read: the magic, a header this build
+    /// knows, tags that fit and none required that it does not know
+    ///
+    /// # Safety
+    /// Its tags must follow it, `total_size` bytes in all
+    pub unsafe fn check(&self) -> BootInfoResult<()> {
+        if self.magic != BOOTINFO_MAGIC {
+            return Err(BootInfoError::InvalidMagic);
+        }
+        if self.version != BOOTINFO_VERSION || (self.header_size as usize) < core::mem::size_of::<BootInfo>() {
+            return Err(BootInfoError::InvalidVersion);
+        }
+        if self.total_size < self.header_size {
+            return Err(BootInfoError::BadTag);
+        }
+        for tag in self.tags() {
+            let tag = tag?;
+            let known = tag.kind < 32 && KNOWN_FEATURES & (1 << tag.kind) != 0;
+            if tag.flags & TAG_REQUIRED != 0 && !known {
+                return Err(BootInfoError::UnknownTag(tag.kind));
+            }
+        }
+        Ok(())
+    }
+
     /// Verify that this boot info is valid
-    pub fn verify(&self) -> bool {
-        self.magic == BOOTINFO_MAGIC && self.version == BOOTINFO_VERSION
+    ///
+    /// # Safety
+    /// As for `check`
+    pub unsafe fn verify(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// The tags after the header
+    ///
+    /// # Safety
+    /// Its tags must follow it, `total_size` bytes in all
+    pub unsafe fn tags(&self) -> Tags<'_> {
+        let len = (self.total_size.saturating_sub(self.header_size)) as usize;
+        let start = (self as *const Self as *const u8).add(self.header_size as usize);
+        Tags { data: core::slice::from_raw_parts(start, len) }
+    }
+
+    /// The payload of the first `T` tag, if there is one as long as `T`
+    ///
+    /// # Safety
+    /// Its tags must follow it, `total_size` bytes in all
+    pub unsafe fn tag<T: Tag>(&self) -> Option<T> {
+        self.tags()
+            .map_while(Result::ok)
+            .find(|tag| tag.kind == T::KIND)
+            .filter(|tag| tag.payload.len() >= core::mem::size_of::<T>())
+            .map(|tag| core::ptr::read_unaligned(tag.payload.as_ptr() as *const T))
     }
 
     /// Get memory map as a slice
@@ -83,13 +365,89 @@ impl BootInfo {
         core::str::from_utf8_unchecked(slice)
     }
 
+    /// Get the framebuffer GOP set up, if there is one
+    ///
+    /// # Safety
+    /// As for `tag`
+    pub unsafe fn framebuffer(&self) -> Option<FramebufferInfo> {
+        self.tag::<FramebufferInfo>().filter(FramebufferInfo::is_valid)
+    }
+
+    /// Get the physical address of the ACPI RSDP
+    ///
+    /// # Safety
+    /// As for `tag`
+    pub unsafe fn rsdp_addr(&self) -> Option<PhysAddr> {
+        self.tag::<AcpiTag>().map(|acpi| acpi.rsdp)
+    }
+
+    /// Get where physical memory is mapped
+    ///
+    /// # Safety
+    /// As for `tag`
+    pub unsafe fn physmap(&self) -> Option<PhysmapTag> {
+        self.tag::<PhysmapTag>().filter(|physmap| physmap.size > 0)
+    }
+
+    /// Get the physical address of the SMBIOS entry point
+    ///
+    /// # Safety
+    /// As for `tag`
+    pub unsafe fn smbios_addr(&self) -> Option<PhysAddr> {
+        self.tag::<SmbiosTag>().map(|smbios| smbios.entry_point)
+    }
+
+    /// Get the physical address of the EFI runtime services table
+    ///
+    /// # Safety
+    /// As for `tag`
+    pub unsafe fn efi_runtime_services(&self) -> Option<PhysAddr> {
+        self.tag::<EfiTag>().map(|efi| efi.runtime_services).filter(|addr| addr.as_u64() != 0)
+    }
+
+    /// Get the initrd the boot entry brought, if any
+    ///
+    /// # Safety
+    /// Caller must ensure the initrd is still mapped where the bootloader
+    /// put it
+    pub unsafe fn initrd(&self) -> Option<&[u8]> {
+        let initrd = self.tag::<InitrdTag>()?;
+        Some(core::slice::from_raw_parts(initrd.addr.as_ptr::<u8>(), initrd.size as usize))
+    }
+
+    /// Get the modes GOP offered; the framebuffer is in one of them
+    ///
+    /// # Safety
+    /// Caller must ensure the list is still mapped where the bootloader
+    /// put it
+    pub unsafe fn video_modes(&self) -> &[VideoMode] {
+        match self.tag::<VideoModesTag>() {
+            Some(modes) if modes.count > 0 => {
+                core::slice::from_raw_parts(modes.addr.as_ptr::<VideoMode>(), modes.count as usize)
+            }
+            _ => &[],
+        }
+    }
+
+    /// Get the display's EDID, if the firmware had it
+    ///
+    /// # Safety
+    /// Caller must ensure the EDID is still mapped where the bootloader
+    /// put it
+    pub unsafe fn edid(&self) -> Option<&[u8]> {
+        let edid = self.tag::<EdidTag>()?;
+        Some(core::slice::from_raw_parts(edid.addr.as_ptr::<u8>(), edid.size as usize))
+    }
+
     /// Get command line as a string slice
     /// 
     /// # Safety
     /// Caller must ensure the string is valid UTF-8 and null-terminated
     pub unsafe fn cmdline(&self) -> Option<&str> {
-        let addr = self.cmdline?;
-        let ptr = addr.as_ptr::<u8>();
+        if self.cmdline.as_u64() == 0 {
+            return None;
+        }
+        let ptr = self.cmdline.as_ptr::<u8>();
         let mut len = 0;
         while *ptr.add(len) != 0 {
             len += 1;
@@ -99,14 +457,133 @@ impl BootInfo {
     }
 }
 
+impl Default for BootInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes a boot info into a buffer: the header, then tags
+pub struct BootInfoWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    features: u32,
+}
+
+impl<'a> BootInfoWriter<'a> {
+    /// Start with `header`, whose sizes and features this fills in; None
+    /// if `buffer` is not 8-aligned or too small for it
+    pub fn new(buffer: &'a mut [u8], header: BootInfo) -> Option<Self> {
+        let len = core::mem::size_of::<BootInfo>();
+        if buffer.as_ptr() as usize % 8 != 0 || buffer.len() < len {
+            return None;
+        }
+        unsafe { core::ptr::write(buffer.as_mut_ptr() as *mut BootInfo, header) };
+        Some(Self { buffer, len, features: 0 })
+    }
+
+    /// Add a tag, `TAG_REQUIRED` in `flags` if a kernel that does not know
+    /// it must not boot; false if there is no room
+    pub fn add<T: Tag>(&mut self, payload: &T, flags: u16) -> bool {
+        let header_size = core::mem::size_of::<TagHeader>();
+        let size = (header_size + core::mem::size_of::<T>()).next_multiple_of(8);
+        if self.buffer.len() - self.len < size {
+            return false;
+        }
+        let header = TagHeader { kind: T::KIND, flags, size: size as u32 };
+        let tag = &mut self.buffer[self.len..self.len + size];
+        tag.fill(0);
+        unsafe {
+            core::ptr::write_unaligned(tag.as_mut_ptr() as *mut TagHeader, header);
+            core::ptr::write_unaligned(tag.as_mut_ptr().add(header_size) as *mut T, *payload);
+        }
+        self.len += size;
+        self.features |= T::FEATURE;
+        true
+    }
+
+    /// The features added so far
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    /// Fill in the header's sizes and features; returns the bytes written
+    pub fn finish(self) -> usize {
+        let header = unsafe { &mut *(self.buffer.as_mut_ptr() as *mut BootInfo) };
+        header.total_size = self.len as u32;
+        header.features = self.features;
+        self.len
+    }
+}
+
+/// What a kernel says about itself in its `WebbOS` ELF note, so the
+/// bootloader can tell before booting it whether it can
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct KernelNote {
+    /// `BOOTINFO_VERSION` it was built with
+    pub version: u32,
+    /// Features it cannot boot without
+    pub required: u32,
+    /// Features it knows the tags of
+    pub known: u32,
+}
+
+/// Name and type of the note
+pub const NOTE_NAME: &[u8; 7] = b"WebbOS\0";
+pub const NOTE_TYPE: u32 = 1;
+
+/// The note as it goes into the kernel's `.note.webbos` section
+#[derive(Clone, Copy, Debug)]
+#[repr(C, align(4))]
+pub struct ElfNote {
+    pub name_size: u32,
+    pub desc_size: u32,
+    pub kind: u32,
+    /// `NOTE_NAME`, padded to 4 bytes
+    pub name: [u8; 8],
+    pub desc: KernelNote,
+}
+
+impl ElfNote {
+    /// The note of a kernel that cannot boot without `required`
+    pub const fn new(required: u32) -> Self {
+        Self {
+            name_size: NOTE_NAME.len() as u32,
+            desc_size: core::mem::size_of::<KernelNote>() as u32,
+            kind: NOTE_TYPE,
+            name: *b"WebbOS\0\0",
+            desc: KernelNote { version: BOOTINFO_VERSION, required, known: KNOWN_FEATURES },
+        }
+    }
+}
+
+/// Find the `WebbOS` note among the notes of an ELF `PT_NOTE` segment
+pub fn find_kernel_note(notes: &[u8]) -> Option<KernelNote> {
+    let word = |at: usize| -> Option<u32> { Some(u32::from_le_bytes(notes.get(at..at + 4)?.try_into().ok()?)) };
+    let mut at = 0;
+    while at + 12 <= notes.len() {
+        let name_size = word(at)? as usize;
+        let desc_size = word(at + 4)? as usize;
+        let kind = word(at + 8)?;
+        let name_at = at + 12;
+        let desc_at = name_at + name_size.next_multiple_of(4);
+        let next = desc_at.checked_add(desc_size.next_multiple_of(4))?;
+        let name = notes.get(name_at..name_at + name_size)?;
+        if name == NOTE_NAME && kind == NOTE_TYPE && desc_size >= 12 {
+            return Some(KernelNote { version: word(desc_at)?, required: word(desc_at + 4)?, known: word(desc_at + 8)? });
+        }
+        at = next;
+    }
+    None
+}
+
 /// Framebuffer information
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct FramebufferInfo {
     /// Physical address of framebuffer
     pub addr: PhysAddr,
-    /// Virtual address of framebuffer (if mapped)
-    pub virt_addr: Option<VirtAddr>,
     /// Width in pixels
     pub width: u32,
     /// Height in pixels
@@ -135,7 +612,6 @@ impl Default for FramebufferInfo {
     fn default() -> Self {
         Self {
             addr: PhysAddr::new(0),
-            virt_addr: None,
             width: 0,
             height: 0,
             bpp: 0,
@@ -145,6 +621,20 @@ impl Default for FramebufferInfo {
     }
 }
 
+/// A mode the firmware can set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct VideoMode {
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Bytes per scanline (may include padding)
+    pub pitch: u32,
+    /// Pixel format
+    pub format: PixelFormat,
+}
+
 /// Pixel format for framebuffer
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u32)]
@@ -163,6 +653,10 @@ pub enum BootInfoError {
     InvalidMagic,
     InvalidVersion,
     NullPointer,
+    /// A tag runs past the end
+    BadTag,
+    /// A tag marked required of a kind this build does not know
+    UnknownTag(u16),
 }
 
 /// Result type for boot info operations
@@ -174,31 +668,116 @@ mod tests {
 
     #[test]
     fn test_bootinfo_verify() {
-        let bootinfo = BootInfo {
-            magic: BOOTINFO_MAGIC,
-            version: BOOTINFO_VERSION,
-            _reserved: 0,
-            memory_map_addr: PhysAddr::new(0),
-            memory_map_count: 0,
-            kernel_addr: PhysAddr::new(0),
-            kernel_size: 0,
-            kernel_virt_addr: VirtAddr::new(0),
-            framebuffer: FramebufferInfo::default(),
-            rsdp_addr: None,
-            cmdline: None,
-            bootloader_name: PhysAddr::new(0),
-            stack_top: VirtAddr::new(0),
-            stack_size: 0,
-        };
+        let bootinfo = BootInfo::new();
+        assert!(unsafe { bootinfo.verify() });
+
+        let old = BootInfo { version: BOOTINFO_VERSION - 1, ..BootInfo::new() };
+        assert_eq!(unsafe { old.check() }, Err(BootInfoError::InvalidVersion));
+    }
+
+    /// A boot info in a buffer, aligned like the bootloader's pages
+    #[repr(C, align(8))]
+    struct Buffer([u8; 512]);
+
+    #[test]
+    fn test_tags_round_trip() {
+        let mut buffer = Buffer([0; 512]);
+        let mut writer = BootInfoWriter::new(&mut buffer.0, BootInfo { stack_size: 4096, ..BootInfo::new() }).unwrap();
+        let physmap = PhysmapTag { base: VirtAddr::new(0xFFFF_8000_0000_0000), size: 4 << 30 };
+        assert!(writer.add(&AcpiTag { rsdp: PhysAddr::new(0xE0000) }, 0));
+        assert!(writer.add(&physmap, 0));
+        let len = writer.finish();
+        assert_eq!(len, core::mem::size_of::<BootInfo>() + 16 + 24);
+
+        let info = unsafe { &*(buffer.0.as_ptr() as *const BootInfo) };
+        unsafe {
+            assert_eq!(info.check(), Ok(()));
+            assert_eq!(info.features, FEATURE_ACPI | FEATURE_PHYSMAP);
+            assert_eq!(info.stack_size, 4096);
+            assert_eq!(info.rsdp_addr(), Some(PhysAddr::new(0xE0000)));
+            assert_eq!(info.physmap(), Some(physmap));
+            // Missing tags are missing features, not errors
+            assert!(info.framebuffer().is_none());
+            assert!(info.initrd().is_none());
+            assert!(info.video_modes().is_empty());
+        }
+    }
+
+    /// A tag a newer bootloader added
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    struct FutureTag {
+        value: u64,
+    }
+
+    impl Tag for FutureTag {
+        const KIND: u16 = 31;
+    }
+
+    #[test]
+    fn test_unknown_tags() {
+        let mut buffer = Buffer([0; 512]);
+        let mut writer = BootInfoWriter::new(&mut buffer.0, BootInfo::new()).unwrap();
+        assert!(writer.add(&FutureTag { value: 1 }, 0));
+        assert!(writer.add(&SmbiosTag { entry_point: PhysAddr::new(0xF0000) }, 0));
+        writer.finish();
+        let info = unsafe { &*(buffer.0.as_ptr() as *const BootInfo) };
+        unsafe {
+            assert_eq!(info.check(), Ok(()));
+            assert_eq!(info.smbios_addr(), Some(PhysAddr::new(0xF0000)));
+        }
 
-        assert!(bootinfo.verify());
+        let mut buffer = Buffer([0; 512]);
+        let mut writer = BootInfoWriter::new(&mut buffer.0, BootInfo::new()).unwrap();
+        assert!(writer.add(&FutureTag { value: 1 }, TAG_REQUIRED));
+        writer.finish();
+        let info = unsafe { &*(buffer.0.as_ptr() as *const BootInfo) };
+        assert_eq!(unsafe { info.check() }, Err(BootInfoError::UnknownTag(31)));
+    }
+
+    #[test]
+    fn test_short_and_bad_tags() {
+        let mut buffer = Buffer([0; 512]);
+        let mut writer = BootInfoWriter::new(&mut buffer.0, BootInfo::new()).unwrap();
+        // An older EFI tag, without runtime services
+        assert!(writer.add(&AcpiTag { rsdp: PhysAddr::new(0x1000) }, 0));
+        let len = writer.finish();
+        let header = core::mem::size_of::<BootInfo>();
+        buffer.0[header] = EfiTag::KIND as u8;
+        let info = unsafe { &*(buffer.0.as_ptr() as *const BootInfo) };
+        unsafe {
+            assert_eq!(info.check(), Ok(()));
+            assert_eq!(info.efi_runtime_services(), None);
+        }
+
+        // A size that runs past the end
+        buffer.0[header + 4] = 64;
+        let info = unsafe { &*(buffer.0.as_ptr() as *const BootInfo) };
+        assert_eq!(len, header + 16);
+        assert_eq!(unsafe { info.check() }, Err(BootInfoError::BadTag));
+    }
+
+    #[test]
+    fn test_kernel_note() {
+        let note = ElfNote::new(FEATURE_PHYSMAP);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&note as *const ElfNote as *const u8, core::mem::size_of::<ElfNote>())
+        };
+        let mut notes = [0u8; 64];
+        // Another note first, as linkers may add
+        notes[..4].copy_from_slice(&4u32.to_le_bytes());
+        notes[8..12].copy_from_slice(&3u32.to_le_bytes());
+        notes[12..16].copy_from_slice(b"GNU\0");
+        notes[16..16 + bytes.len()].copy_from_slice(bytes);
+        let found = find_kernel_note(&notes[..16 + bytes.len()]).unwrap();
+        assert_eq!(found, KernelNote { version: BOOTINFO_VERSION, required: FEATURE_PHYSMAP, known: KNOWN_FEATURES });
+        assert_eq!(find_kernel_note(&notes[..16]), None);
     }
 
     #[test]
     fn test_framebuffer_size() {
         let fb = FramebufferInfo {
             addr: PhysAddr::new(0x1000),
-            virt_addr: None,
             width: 1920,
             height: 1080,
             bpp: 32,
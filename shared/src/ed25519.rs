@@ -0,0 +1,427 @@
+//! Ed25519 signature verification (RFC 8032)
+//!
+//! Only what checking a signature takes: nothing here is secret,
+//! so nothing needs to run in constant time. Field elements are five
+//! 51-bit limbs; points are in extended coordinates on the twisted
+//! Edwards curve -x² + y² = 1 + d·x²·y².
+
+use crate::sha512::Sha512;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// The group order, 2^252 + 27742317777372353535851937790883648493,
+/// little-endian
+const L: [u64; 4] = [0x5812631a5cf5d3ed, 0x14def9dea2f79cd6, 0, 0x1000000000000000];
+
+/// The base point's encoding: y = 4/5, x even
+const BASE: [u8; 32] = [
+    0x58, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+    0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+];
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// An element of the field of integers modulo p = 2^255 - 19
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_u64(value: u64) -> Fe {
+        Fe([value & MASK, value >> 51, 0, 0, 0])
+    }
+
+    /// The low 255 bits of `bytes`, little-endian
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let load = |at: usize| {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_le_bytes(word)
+        };
+        Fe([
+            load(0) & MASK,
+            (load(6) >> 3) & MASK,
+            (load(12) >> 6) & MASK,
+            (load(19) >> 1) & MASK,
+            (load(24) >> 12) & MASK,
+        ])
+    }
+
+    /// The canonical encoding, below p
+    fn to_bytes(self) -> [u8; 32] {
+        let mut h = self.carry().carry().0;
+        // h is below 2^255 + a little; take p away if it is at least p
+        let mut q = (h[0] + 19) >> 51;
+        for limb in &h[1..] {
+            q = (limb + q) >> 51;
+        }
+        h[0] += 19 * q;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[4] &= MASK;
+
+        let words = [h[0] | h[1] << 51, h[1] >> 13 | h[2] << 38, h[2] >> 26 | h[3] << 25, h[3] >> 39 | h[4] << 12];
+        let mut bytes = [0u8; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Bring each limb back to about 51 bits
+    fn carry(self) -> Fe {
+        let mut h = self.0;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[0] += 19 * (h[4] >> 51);
+        h[4] &= MASK;
+        Fe(h)
+    }
+
+    fn add(self, other: Fe) -> Fe {
+        let mut h = self.0;
+        for (limb, value) in h.iter_mut().zip(other.0) {
+            *limb += value;
+        }
+        Fe(h).carry()
+    }
+
+    fn sub(self, other: Fe) -> Fe {
+        // Add 2p first, so no limb goes below zero
+        const TWO_P: [u64; 5] = [0xFFFFFFFFFFFDA, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE];
+        let mut h = self.0;
+        for i in 0..5 {
+            h[i] = h[i] + TWO_P[i] - other.0[i];
+        }
+        Fe(h).carry()
+    }
+
+    fn neg(self) -> Fe {
+        Fe::ZERO.sub(self)
+    }
+
+    fn mul(self, other: Fe) -> Fe {
+        let a = self.0.map(|limb| limb as u128);
+        let b = other.0.map(|limb| limb as u128);
+        // Limbs past the fifth come back around times 19, as 2^255 = 19
+        let b19 = b.map(|limb| limb * 19);
+        let r = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+        let mut h = [0u64; 5];
+        let mut carry = 0u128;
+        for i in 0..5 {
+            let value = r[i] + carry;
+            h[i] = (value as u64) & MASK;
+            carry = value >> 51;
+        }
+        h[0] += (carry * 19) as u64;
+        Fe(h).carry()
+    }
+
+    fn square(self) -> Fe {
+        self.mul(self)
+    }
+
+    /// self^exponent, the exponent little-endian
+    fn pow(self, exponent: &[u8; 32]) -> Fe {
+        let mut result = Fe::ONE;
+        for bit in (0..256).rev() {
+            result = result.square();
+            if exponent[bit / 8] >> (bit % 8) & 1 == 1 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    fn invert(self) -> Fe {
+        // p - 2
+        let mut exponent = [0xFF; 32];
+        exponent[0] = 0xEB;
+        exponent[31] = 0x7F;
+        self.pow(&exponent)
+    }
+
+    /// self^((p - 5) / 8), for square roots
+    fn pow_p58(self) -> Fe {
+        let mut exponent = [0xFF; 32];
+        exponent[0] = 0xFD;
+        exponent[31] = 0x0F;
+        self.pow(&exponent)
+    }
+
+    fn is_zero(self) -> bool {
+        self.to_bytes() == [0; 32]
+    }
+
+    fn is_odd(self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    fn equals(self, other: Fe) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+/// The curve's constants, worked out rather than written out
+struct Constants {
+    d: Fe,
+    d2: Fe,
+    sqrt_m1: Fe,
+}
+
+impl Constants {
+    fn new() -> Self {
+        // d = -121665 / 121666
+        let d = Fe::from_u64(121665).neg().mul(Fe::from_u64(121666).invert());
+        // sqrt(-1) = 2^((p - 1) / 4)
+        let mut exponent = [0xFF; 32];
+        exponent[0] = 0xFB;
+        exponent[31] = 0x1F;
+        let sqrt_m1 = Fe::from_u64(2).pow(&exponent);
+        Self { d, d2: d.add(d), sqrt_m1 }
+    }
+}
+
+/// A point in extended coordinates: x = X/Z, y = Y/Z, x·y = T/Z
+#[derive(Clone, Copy)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+impl Point {
+    const IDENTITY: Point = Point { x: Fe::ZERO, y: Fe::ONE, z: Fe::ONE, t: Fe::ZERO };
+
+    /// The point `bytes` encode, if they encode one
+    fn decode(bytes: &[u8; 32], k: &Constants) -> Option<Point> {
+        let y = Fe::from_bytes(bytes);
+        let x_odd = bytes[31] >> 7 == 1;
+        // y must be below p
+        let mut canonical = *bytes;
+        canonical[31] &= 0x7F;
+        if y.to_bytes() != canonical {
+            return None;
+        }
+
+        // x² = (y² - 1) / (d·y² + 1)
+        let y2 = y.square();
+        let u = y2.sub(Fe::ONE);
+        let v = k.d.mul(y2).add(Fe::ONE);
+        let v3 = v.square().mul(v);
+        let v7 = v3.square().mul(v);
+        let mut x = u.mul(v3).mul(u.mul(v7).pow_p58());
+        let vx2 = v.mul(x.square());
+        if !vx2.equals(u) {
+            if !vx2.equals(u.neg()) {
+                return None;
+            }
+            x = x.mul(k.sqrt_m1);
+        }
+        if x.is_zero() && x_odd {
+            return None;
+        }
+        if x.is_odd() != x_odd {
+            x = x.neg();
+        }
+        Some(Point { x, y, z: Fe::ONE, t: x.mul(y) })
+    }
+
+    fn encode(self) -> [u8; 32] {
+        let z_inv = self.z.invert();
+        let x = self.x.mul(z_inv);
+        let mut bytes = self.y.mul(z_inv).to_bytes();
+        bytes[31] |= (x.is_odd() as u8) << 7;
+        bytes
+    }
+
+    /// The sum; the formula is complete, so it doubles too
+    fn add(self, other: Point, k: &Constants) -> Point {
+        let a = self.y.sub(self.x).mul(other.y.sub(other.x));
+        let b = self.y.add(self.x).mul(other.y.add(other.x));
+        let c = self.t.mul(k.d2).mul(other.t);
+        let d = self.z.add(self.z).mul(other.z);
+        let (e, f, g, h) = (b.sub(a), d.sub(c), d.add(c), b.add(a));
+        Point { x: e.mul(f), y: g.mul(h), z: f.mul(g), t: e.mul(h) }
+    }
+
+    fn neg(self) -> Point {
+        Point { x: self.x.neg(), y: self.y, z: self.z, t: self.t.neg() }
+    }
+
+    /// [scalar]self, the scalar little-endian
+    fn mul(self, scalar: &[u8; 32], k: &Constants) -> Point {
+        let mut result = Point::IDENTITY;
+        for bit in (0..256).rev() {
+            result = result.add(result, k);
+            if scalar[bit / 8] >> (bit % 8) & 1 == 1 {
+                result = result.add(self, k);
+            }
+        }
+        result
+    }
+}
+
+/// Whether a little-endian 256-bit number is below L
+fn below_l(scalar: &[u8; 32]) -> bool {
+    for i in (0..4).rev() {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&scalar[i * 8..i * 8 + 8]);
+        let word = u64::from_le_bytes(word);
+        if word != L[i] {
+            return word < L[i];
+        }
+    }
+    false
+}
+
+/// A SHA-512 digest, read as a little-endian number, modulo L
+fn reduce(digest: &[u8; 64]) -> [u8; 32] {
+    let mut r = [0u64; 4];
+    for bit in (0..512).rev() {
+        // r = 2r + bit; r stays below 2L, which fits in 254 bits
+        for i in (1..4).rev() {
+            r[i] = r[i] << 1 | r[i - 1] >> 63;
+        }
+        r[0] = r[0] << 1 | (digest[bit / 8] >> (bit % 8) & 1) as u64;
+        let at_least_l = (0..4).rev().find(|&i| r[i] != L[i]).map_or(true, |i| r[i] > L[i]);
+        if at_least_l {
+            let mut borrow = 0u64;
+            for i in 0..4 {
+                let (value, under) = r[i].overflowing_sub(L[i]);
+                let (value, under2) = value.overflowing_sub(borrow);
+                r[i] = value;
+                borrow = (under || under2) as u64;
+            }
+        }
+    }
+    let mut bytes = [0u8; 32];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(r) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Whether `signature` is `public_key`'s signature of `message`
+pub fn verify(public_key: &[u8; PUBLIC_KEY_SIZE], message: &[u8], signature: &[u8; SIGNATURE_SIZE]) -> bool {
+    let k = Constants::new();
+    let mut r = [0u8; 32];
+    let mut s = [0u8; 32];
+    r.copy_from_slice(&signature[..32]);
+    s.copy_from_slice(&signature[32..]);
+    if !below_l(&s) {
+        return false;
+    }
+    let a = match Point::decode(public_key, &k) {
+        Some(a) => a,
+        None => return false,
+    };
+    let base = match Point::decode(&BASE, &k) {
+        Some(base) => base,
+        None => return false,
+    };
+
+    let mut hasher = Sha512::new();
+    hasher.update(&r);
+    hasher.update(public_key);
+    hasher.update(message);
+    let h = reduce(&hasher.finalize());
+
+    // [S]B = R + [h]A, so [S]B - [h]A must encode as R
+    let check = base.mul(&s, &k).add(a.neg().mul(&h, &k), &k);
+    check.encode() == r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes<const N: usize>(text: &str) -> [u8; N] {
+        let mut out = [0u8; N];
+        assert_eq!(text.len(), N * 2);
+        for (byte, pair) in out.iter_mut().zip(text.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap();
+        }
+        out
+    }
+
+    /// RFC 8032 section 7.1: public key, message, signature
+    const VECTORS: [(&str, &str, &str); 4] = [
+        ("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a", "",
+         "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"),
+        ("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c", "72",
+         "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"),
+        ("fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025", "af82",
+         "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a"),
+        // TEST SHA(abc): the message is the SHA-512 of "abc"
+        ("ec172b93ad5e563bf4932c70e1245034c35467ef2efd4d64ebf819683467e2bf",
+         "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+         "dc2a4459e7369633a52b1bf277839a00201009a3efbf3ecb69bea2186c26b58909351fc9ac90b3ecfdfbc7c66431e0303dca179c138ac17ad9bef1177331a704"),
+    ];
+
+    fn message(text: &str) -> ([u8; 64], usize) {
+        let mut out = [0u8; 64];
+        let len = text.len() / 2;
+        for (byte, pair) in out.iter_mut().zip(text.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap();
+        }
+        (out, len)
+    }
+
+    #[test]
+    fn test_rfc8032_vectors() {
+        for (key, text, signature) in VECTORS {
+            let (msg, len) = message(text);
+            assert!(verify(&bytes(key), &msg[..len], &bytes(signature)), "key {}", key);
+        }
+    }
+
+    #[test]
+    fn test_rejects_changes() {
+        for (key, text, signature) in VECTORS {
+            let key: [u8; PUBLIC_KEY_SIZE] = bytes(key);
+            let signature: [u8; SIGNATURE_SIZE] = bytes(signature);
+            let (mut msg, len) = message(text);
+
+            let mut other = signature;
+            other[0] ^= 1;
+            assert!(!verify(&key, &msg[..len], &other));
+            other = signature;
+            other[40] ^= 0x80;
+            assert!(!verify(&key, &msg[..len], &other));
+            if len > 0 {
+                msg[0] ^= 1;
+                assert!(!verify(&key, &msg[..len], &signature));
+                msg[0] ^= 1;
+            } else {
+                assert!(!verify(&key, b"x", &signature));
+            }
+            let mut wrong = key;
+            wrong[1] ^= 1;
+            assert!(!verify(&wrong, &msg[..len], &signature));
+        }
+    }
+
+    /// S must be below the group order, or signatures are malleable
+    #[test]
+    fn test_rejects_unreduced_s() {
+        let (key, _, signature) = VECTORS[0];
+        let mut signature: [u8; SIGNATURE_SIZE] = bytes(signature);
+        signature[63] |= 0xF0;
+        assert!(!verify(&bytes(key), b"", &signature));
+    }
+}
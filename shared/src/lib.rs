@@ -6,6 +6,10 @@
 //! Common types and structures shared between bootloader and kernel.
 
 pub mod bootinfo;
+pub mod ed25519;
+pub mod sha512;
+pub mod signature;
 pub mod types;
+pub mod update;
 
 pub use types::*;
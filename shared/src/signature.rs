@@ -0,0 +1,87 @@
+//! Kernel and update signatures
+//!
+//! `tools/sign-kernel.py` appends an Ed25519 signature of the whole ELF to
+//! `kernel.elf`, then the magic `WEBBSIG1`:
+//!
+//! ```text
+//! | ELF ... | signature (64 bytes) | "WEBBSIG1" |
+//! ```
+//!
+//! Nothing in the ELF points at the trailer, so a signed kernel still
+//! loads anywhere an ELF does. Update bundles are signed the same way.
+//! The public key is built into the bootloader and the kernel from
+//! `WEBBOS_KERNEL_KEY`, 64 hex digits, which the Makefile takes from
+//! `keys/kernel-signing.pub`. Built without one, they cannot check
+//! anything, so they refuse what they would have checked unless built for
+//! development: the bootloader with its `allow-unsigned` feature.
+
+use crate::ed25519::{self, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+
+const MAGIC: &[u8; 8] = b"WEBBSIG1";
+const TRAILER_SIZE: usize = SIGNATURE_SIZE + MAGIC.len();
+
+/// The key kernels and update bundles must be signed with
+pub const KERNEL_KEY: Option<[u8; PUBLIC_KEY_SIZE]> = match option_env!("WEBBOS_KERNEL_KEY") {
+    Some(hex) if !hex.is_empty() => Some(parse_key(hex)),
+    _ => None,
+};
+
+/// 64 hex digits; anything else fails the build
+const fn parse_key(hex: &str) -> [u8; PUBLIC_KEY_SIZE] {
+    const fn digit(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("WEBBOS_KERNEL_KEY is not hex"),
+        }
+    }
+    let hex = hex.as_bytes();
+    if hex.len() != PUBLIC_KEY_SIZE * 2 {
+        panic!("WEBBOS_KERNEL_KEY is not 64 hex digits");
+    }
+    let mut key = [0u8; PUBLIC_KEY_SIZE];
+    let mut i = 0;
+    while i < PUBLIC_KEY_SIZE {
+        key[i] = digit(hex[2 * i]) << 4 | digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+/// What the trailer says about a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Signed with `KERNEL_KEY`; what was signed is the first this many
+    /// bytes
+    Valid(usize),
+    /// Signed, but not with `KERNEL_KEY`, or changed since
+    Invalid,
+    /// No trailer
+    Unsigned,
+    /// No `KERNEL_KEY` to check with; what was signed is the first this
+    /// many bytes
+    Unchecked(usize),
+}
+
+/// Check the signature at the end of `file`
+pub fn check(file: &[u8]) -> Verdict {
+    let elf_len = match file.len().checked_sub(TRAILER_SIZE) {
+        Some(len) if &file[file.len() - MAGIC.len()..] == MAGIC => len,
+        _ => return match KERNEL_KEY {
+            Some(_) => Verdict::Unsigned,
+            None => Verdict::Unchecked(file.len()),
+        },
+    };
+    let key = match KERNEL_KEY {
+        Some(key) => key,
+        None => return Verdict::Unchecked(elf_len),
+    };
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    signature.copy_from_slice(&file[elf_len..elf_len + SIGNATURE_SIZE]);
+    if ed25519::verify(&key, &file[..elf_len], &signature) {
+        Verdict::Valid(elf_len)
+    } else {
+        Verdict::Invalid
+    }
+}
@@ -124,6 +124,9 @@ pub enum MemoryRegionType {
     AcpiNvs = 4,
     /// Bad/unusable memory
     Bad = 5,
+    /// Firmware runtime services code and data, which must stay mapped
+    /// at the same address for them to be called
+    EfiRuntime = 6,
     /// Kernel code/data
     Kernel = 0x10,
     /// Bootloader code/data
@@ -170,6 +173,51 @@ impl MemoryRegion {
     }
 }
 
+/// Put a memory map in order, in place: sorted by address, empty regions
+/// dropped, touching or overlapping regions of a type merged, and where
+/// regions of different types overlap, `Available` gives way. Returns how
+/// many regions are left at the front of `regions`.
+///
+/// Allocates nothing, so the bootloader can call it after boot services
+/// are gone.
+pub fn sanitize_memory_map(regions: &mut [MemoryRegion]) -> usize {
+    regions.sort_unstable_by_key(|region| region.base.as_u64());
+    let mut count = 0;
+    for i in 0..regions.len() {
+        let mut region = regions[i];
+        while count > 0 && region.size.as_u64() > 0 {
+            let last = &mut regions[count - 1];
+            let (last_end, end) = (last.end().as_u64(), region.end().as_u64());
+            if region.base.as_u64() > last_end
+                || (region.base.as_u64() == last_end && last.region_type != region.region_type)
+            {
+                break;
+            }
+            if last.region_type == region.region_type {
+                last.size = ByteSize::new(last_end.max(end) - last.base.as_u64());
+                region.size = ByteSize::new(0);
+            } else if region.region_type == MemoryRegionType::Available || last.region_type != MemoryRegionType::Available {
+                // The earlier region keeps the overlap
+                let start = last_end.min(end);
+                region = MemoryRegion::new(PhysAddr::new(start), ByteSize::new(end - start), region.region_type);
+            } else {
+                // Available memory the region overlaps is not available
+                last.size = ByteSize::new(region.base.as_u64() - last.base.as_u64());
+                if last.size.as_u64() == 0 {
+                    count -= 1;
+                    continue;
+                }
+                break;
+            }
+        }
+        if region.size.as_u64() > 0 {
+            regions[count] = region;
+            count += 1;
+        }
+    }
+    count
+}
+
 /// Process ID
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
@@ -245,3 +293,48 @@ pub const USER_SPACE_LIMIT: u64 = 0x0000_7FFF_FFFF_FFFF;
 
 /// Kernel stack size (per CPU)
 pub const KERNEL_STACK_SIZE: usize = 128 * 1024; // 128KB
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(base: u64, size: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion::new(PhysAddr::new(base), ByteSize::new(size), region_type)
+    }
+
+    fn span(region: &MemoryRegion) -> (u64, u64, MemoryRegionType) {
+        (region.base.as_u64(), region.end().as_u64(), region.region_type)
+    }
+
+    #[test]
+    fn test_sanitize_merges_and_sorts() {
+        use MemoryRegionType::*;
+        let mut map = [
+            region(0x3000, 0x1000, Available),
+            region(0x0, 0x1000, Available),
+            region(0x1000, 0x2000, Available),
+            region(0x1000, 0x2000, Available),
+            region(0x5000, 0, Reserved),
+            region(0x4000, 0x1000, Bootloader),
+        ];
+        let count = sanitize_memory_map(&mut map);
+        assert_eq!(count, 2);
+        assert_eq!(span(&map[0]), (0x0, 0x4000, Available));
+        assert_eq!(span(&map[1]), (0x4000, 0x5000, Bootloader));
+    }
+
+    #[test]
+    fn test_sanitize_available_gives_way() {
+        use MemoryRegionType::*;
+        let mut map = [
+            region(0x0, 0x4000, Available),
+            region(0x2000, 0x1000, Reserved),
+            region(0x2800, 0x2000, Available),
+        ];
+        let count = sanitize_memory_map(&mut map);
+        assert_eq!(count, 3);
+        assert_eq!(span(&map[0]), (0x0, 0x2000, Available));
+        assert_eq!(span(&map[1]), (0x2000, 0x3000, Reserved));
+        assert_eq!(span(&map[2]), (0x3000, 0x4800, Available));
+    }
+}
@@ -0,0 +1,299 @@
+//! A/B update state
+//!
+//! The ESP holds two copies of the system, `slot-a` and `slot-b`, each a
+//! directory with `kernel.elf` and `initrd.tar`. The kernel installs an
+//! update into the slot not running, then makes it the active one with
+//! a few tries to prove itself: every boot of it takes one, and the
+//! kernel confirms it once it is up. A slot that runs out of tries
+//! unconfirmed has failed, and the bootloader goes back to the other.
+//!
+//! `update.state` at the root of the ESP says which is which:
+//!
+//! ```text
+//! active b
+//! fallback a
+//! tries 2
+//! ok no
+//! kernel_a 3145728
+//! kernel_b 3162112
+//! initrd_b 524288
+//! ```
+//!
+//! Slot files are made larger than what is in them, so updates can be
+//! written in place; `kernel_*` and `initrd_*` are how much of each file
+//! is the kernel or the initrd. A slot without `initrd_*` has none. The
+//! file is always `STATE_SIZE` bytes, padded with newlines, for the same
+//! reason.
+
+use core::fmt::{self, Write};
+
+/// At the root of the ESP
+pub const STATE_FILE: &str = "update.state";
+
+/// Size of `update.state`, whatever is in it
+pub const STATE_SIZE: usize = 512;
+
+/// Boots an update gets to confirm itself
+pub const TRIES: u32 = 3;
+
+/// One of the two copies of the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Slot> {
+        match name {
+            "a" => Some(Slot::A),
+            "b" => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    /// Directory on the ESP
+    pub fn dir(self) -> &'static str {
+        match self {
+            Slot::A => "slot-a",
+            Slot::B => "slot-b",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What `update.state` says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateState {
+    /// The slot that boots
+    pub active: Slot,
+    /// Where to go back to while `active` is unconfirmed
+    pub fallback: Option<Slot>,
+    /// Boots `active` has left to confirm itself
+    pub tries: u32,
+    /// `active` has booted and confirmed itself
+    pub ok: bool,
+    /// A slot the bootloader gave up on, until the next update
+    pub failed: Option<Slot>,
+    /// Bytes of kernel in each slot's `kernel.elf`, by slot
+    pub kernel_size: [u64; 2],
+    /// Bytes of initrd in each slot's `initrd.tar`; 0 for none
+    pub initrd_size: [u64; 2],
+}
+
+impl UpdateState {
+    /// A system that has only ever had slot A
+    pub fn new(kernel_size: u64, initrd_size: u64) -> Self {
+        Self {
+            active: Slot::A,
+            fallback: None,
+            tries: 0,
+            ok: true,
+            failed: None,
+            kernel_size: [kernel_size, 0],
+            initrd_size: [initrd_size, 0],
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut state = Self::new(0, 0);
+        let mut active = None;
+        for line in text.lines() {
+            let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once(' ')?;
+            let value = value.trim();
+            match key {
+                "active" => active = Some(Slot::parse(value)?),
+                "fallback" => state.fallback = Some(Slot::parse(value)?),
+                "failed" => state.failed = Some(Slot::parse(value)?),
+                "tries" => state.tries = value.parse().ok()?,
+                "ok" => state.ok = match value {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return None,
+                },
+                "kernel_a" => state.kernel_size[0] = value.parse().ok()?,
+                "kernel_b" => state.kernel_size[1] = value.parse().ok()?,
+                "initrd_a" => state.initrd_size[0] = value.parse().ok()?,
+                "initrd_b" => state.initrd_size[1] = value.parse().ok()?,
+                _ => return None,
+            }
+        }
+        state.active = active?;
+        Some(state)
+    }
+
+    /// The file, `STATE_SIZE` bytes
+    pub fn to_bytes(&self) -> [u8; STATE_SIZE] {
+        let mut buffer = Buffer { data: [b'\n'; STATE_SIZE], len: 0 };
+        // Every value is short: it always fits
+        let _ = write!(buffer, "{}", self);
+        buffer.data
+    }
+
+    pub fn kernel_size(&self, slot: Slot) -> u64 {
+        self.kernel_size[slot.index()]
+    }
+
+    pub fn initrd_size(&self, slot: Slot) -> u64 {
+        self.initrd_size[slot.index()]
+    }
+
+    /// Count a boot of the active slot, going back to the fallback if it
+    /// has no tries left; returns whether the state changed
+    pub fn boot(&mut self) -> bool {
+        if self.ok {
+            return false;
+        }
+        if self.tries > 0 {
+            self.tries -= 1;
+        } else {
+            self.roll_back();
+        }
+        true
+    }
+
+    /// Give up on the active slot, if there is a fallback; returns
+    /// whether there was
+    pub fn roll_back(&mut self) -> bool {
+        match self.fallback.take() {
+            Some(fallback) => {
+                self.failed = Some(self.active);
+                self.active = fallback;
+                self.tries = 0;
+                self.ok = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The running slot booted: it stays; returns whether the state changed
+    pub fn confirm(&mut self) -> bool {
+        if self.ok {
+            return false;
+        }
+        self.ok = true;
+        self.tries = 0;
+        self.fallback = None;
+        true
+    }
+
+    /// `slot` now has an update of these sizes in it: boot it next, with
+    /// `TRIES` tries
+    pub fn install(&mut self, slot: Slot, kernel_size: u64, initrd_size: u64) {
+        self.kernel_size[slot.index()] = kernel_size;
+        self.initrd_size[slot.index()] = initrd_size;
+        self.fallback = Some(self.active);
+        self.active = slot;
+        self.tries = TRIES;
+        self.ok = false;
+        self.failed = None;
+    }
+}
+
+impl fmt::Display for UpdateState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "active {}", self.active.name())?;
+        if let Some(fallback) = self.fallback {
+            writeln!(f, "fallback {}", fallback.name())?;
+        }
+        if let Some(failed) = self.failed {
+            writeln!(f, "failed {}", failed.name())?;
+        }
+        writeln!(f, "tries {}", self.tries)?;
+        writeln!(f, "ok {}", if self.ok { "yes" } else { "no" })?;
+        for slot in [Slot::A, Slot::B] {
+            if self.kernel_size(slot) > 0 {
+                writeln!(f, "kernel_{} {}", slot.name(), self.kernel_size(slot))?;
+            }
+            if self.initrd_size(slot) > 0 {
+                writeln!(f, "initrd_{} {}", slot.name(), self.initrd_size(slot))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats into a fixed buffer
+struct Buffer {
+    data: [u8; STATE_SIZE],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > STATE_SIZE {
+            return Err(fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut state = UpdateState::new(3_145_728, 0);
+        state.install(Slot::B, 3_162_112, 524_288);
+        let bytes = state.to_bytes();
+        assert_eq!(bytes.len(), STATE_SIZE);
+        let text = core::str::from_utf8(&bytes).unwrap();
+        assert!(text.starts_with("active b\nfallback a\ntries 3\nok no\n"));
+        assert_eq!(UpdateState::parse(text), Some(state));
+        assert_eq!(UpdateState::parse("fallback a\n"), None);
+        assert_eq!(UpdateState::parse("active c\n"), None);
+    }
+
+    #[test]
+    fn test_unconfirmed_update_rolls_back() {
+        let mut state = UpdateState::new(100, 0);
+        state.install(Slot::B, 200, 0);
+        for tries in (0..TRIES).rev() {
+            assert!(state.boot());
+            assert_eq!((state.active, state.tries), (Slot::B, tries));
+        }
+        assert!(state.boot());
+        assert_eq!(state.active, Slot::A);
+        assert_eq!(state.failed, Some(Slot::B));
+        assert!(state.ok);
+        assert!(!state.boot());
+    }
+
+    #[test]
+    fn test_confirmed_update_stays() {
+        let mut state = UpdateState::new(100, 0);
+        state.install(Slot::B, 200, 0);
+        assert!(state.boot());
+        assert!(state.confirm());
+        assert!(!state.confirm());
+        assert_eq!(state.fallback, None);
+        assert!(!state.boot());
+        assert_eq!(state.active, Slot::B);
+    }
+}
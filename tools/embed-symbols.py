@@ -0,0 +1,135 @@
+#!/usr/bin/env python3
+"""
+Write the kernel's function symbols into its .ksymtab section.
+
+The kernel reserves .ksymtab, starting with the magic b"KSYM", and looks
+addresses up in it for panic backtraces. Run this on the linked kernel
+ELF after every build:
+
+    python3 tools/embed-symbols.py target/x86_64-unknown-none/debug/kernel
+
+The table is a u32 count after the magic, then per symbol, by address, a
+u64 address, a u32 size and a u32 offset of its name, and then the names,
+each ending in a NUL. Names are demangled from Rust's legacy mangling.
+"""
+
+import re
+import struct
+import sys
+
+MAGIC = b"KSYM"
+SHT_SYMTAB = 2
+STT_FUNC = 2
+ENTRY = struct.Struct("<QII")
+# Longest name kept; generic names can run to kilobytes
+MAX_NAME = 120
+
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+
+def demangle(name):
+    """Rust legacy mangling to a path, without the hash; others as they are"""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    parts = []
+    rest = name[3:-1]
+    while rest:
+        m = re.match(r"(\d+)", rest)
+        if not m:
+            return name
+        length = int(m.group(1))
+        start = len(m.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        for code, text in ESCAPES.items():
+            part = part.replace(code, text)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        return part.replace("..", "::")
+
+    return "::".join(unescape(p) for p in parts)
+
+
+def sections(elf):
+    """(name, type, offset, size, link) of every section"""
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = []
+    for i in range(shnum):
+        name, kind, _, _, offset, size, link = struct.unpack_from("<IIQQQQI", elf, shoff + i * shentsize)
+        headers.append((name, kind, offset, size, link))
+    strings_offset = headers[shstrndx][2]
+
+    def section_name(offset):
+        end = elf.index(b"\0", strings_offset + offset)
+        return elf[strings_offset + offset:end].decode()
+
+    return [(section_name(name), kind, offset, size, link) for name, kind, offset, size, link in headers]
+
+
+def functions(elf, all_sections):
+    """Function symbols as {address: (size, name)}"""
+    symtab = next((s for s in all_sections if s[1] == SHT_SYMTAB), None)
+    if symtab is None:
+        sys.exit("embed-symbols: no symbol table; is the kernel stripped?")
+    strtab = all_sections[symtab[4]]
+    symbols = {}
+    for at in range(symtab[2], symtab[2] + symtab[3], 24):
+        name, info, _, shndx, value, size = struct.unpack_from("<IBBHQQ", elf, at)
+        if info & 0xF != STT_FUNC or shndx == 0 or value == 0:
+            continue
+        end = elf.index(b"\0", strtab[2] + name)
+        text = demangle(elf[strtab[2] + name:end].decode(errors="replace"))
+        # Where two names share an address, the shorter reads better
+        if value not in symbols or len(text) < len(symbols[value][1]):
+            symbols[value] = (size, text)
+    return symbols
+
+
+def table(symbols, capacity):
+    addresses = sorted(symbols)
+    names = bytearray()
+    entries = bytearray()
+    for address in addresses:
+        size, name = symbols[address]
+        encoded = name.encode()[:MAX_NAME]
+        entries += ENTRY.pack(address, min(size, 0xFFFFFFFF), len(names))
+        names += encoded + b"\0"
+    blob = MAGIC + struct.pack("<I", len(addresses)) + entries + names
+    if len(blob) > capacity:
+        sys.exit(f"embed-symbols: {len(blob)} bytes of symbols do not fit in .ksymtab's {capacity}; "
+                 "raise TABLE_SIZE in kernel/src/symbols.rs")
+    return blob + bytes(capacity - len(blob))
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: embed-symbols.py <kernel ELF>")
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit(f"embed-symbols: {path} is not a 64-bit ELF file")
+
+    all_sections = sections(elf)
+    ksymtab = next((s for s in all_sections if s[0] == ".ksymtab"), None)
+    if ksymtab is None or elf[ksymtab[2]:ksymtab[2] + 4] != MAGIC:
+        sys.exit(f"embed-symbols: {path} has no .ksymtab section to fill")
+    symbols = functions(elf, all_sections)
+    offset, size = ksymtab[2], ksymtab[3]
+    elf[offset:offset + size] = table(symbols, size)
+    with open(path, "wb") as f:
+        f.write(elf)
+    print(f"Embedded {len(symbols)} symbols in {path}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,56 @@
+#!/usr/bin/env python3
+"""
+Lay out the A/B update slots on an ESP directory.
+
+    python3 tools/make-slots.py ESP_DIR KERNEL [INITRD]
+
+copies KERNEL (and INITRD) into ESP_DIR/slot-a, makes ESP_DIR/slot-b for
+the first update and writes ESP_DIR/update.state with slot a active.
+
+The kernel writes updates into the slot files in place, as it cannot grow
+files on FAT32, so every slot file is padded to SLOT_SIZE bytes (or
+WEBBOS_SLOT_SIZE, in MiB); update.state says how much of each is real.
+"""
+
+import os
+import sys
+
+SLOT_SIZE = int(os.environ.get("WEBBOS_SLOT_SIZE", "16")) * 1024 * 1024
+STATE_SIZE = 512
+
+
+def write_padded(path, data):
+    if len(data) > SLOT_SIZE:
+        sys.exit(f"{path}: {len(data)} bytes does not fit a {SLOT_SIZE}-byte slot")
+    with open(path, "wb") as f:
+        f.write(data)
+        f.write(b"\0" * (SLOT_SIZE - len(data)))
+
+
+def main():
+    if len(sys.argv) not in (3, 4):
+        sys.exit(f"usage: {sys.argv[0]} ESP_DIR KERNEL [INITRD]")
+    esp = sys.argv[1]
+    with open(sys.argv[2], "rb") as f:
+        kernel = f.read()
+    initrd = b""
+    if len(sys.argv) == 4:
+        with open(sys.argv[3], "rb") as f:
+            initrd = f.read()
+
+    for slot in ("a", "b"):
+        os.makedirs(os.path.join(esp, f"slot-{slot}"), exist_ok=True)
+    write_padded(os.path.join(esp, "slot-a", "kernel.elf"), kernel)
+    write_padded(os.path.join(esp, "slot-b", "kernel.elf"), b"")
+    write_padded(os.path.join(esp, "slot-a", "initrd.tar"), initrd)
+    write_padded(os.path.join(esp, "slot-b", "initrd.tar"), b"")
+
+    state = f"active a\ntries 0\nok yes\nkernel_a {len(kernel)}\n"
+    if initrd:
+        state += f"initrd_a {len(initrd)}\n"
+    with open(os.path.join(esp, "update.state"), "wb") as f:
+        f.write(state.encode().ljust(STATE_SIZE, b"\n"))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,149 @@
+#!/usr/bin/env python3
+"""
+Sign the kernel so the bootloader will boot it.
+
+The bootloader checks an Ed25519 signature (RFC 8032) appended to
+kernel.elf against the public key built into it. Make a key pair once:
+
+    python3 tools/sign-kernel.py keygen keys
+
+which writes keys/kernel-signing.key, the secret seed, and
+keys/kernel-signing.pub, the public key the Makefile builds into the
+bootloader; both are hex. Then sign each kernel after embed-symbols.py:
+
+    python3 tools/sign-kernel.py sign keys/kernel-signing.key target/x86_64-unknown-none/debug/kernel
+
+Signing again replaces the old signature. The trailer is the 64-byte
+signature of everything before it, then the magic b"WEBBSIG1".
+"""
+
+import hashlib
+import os
+import sys
+
+MAGIC = b"WEBBSIG1"
+SIGNATURE_SIZE = 64
+
+P = 2**255 - 19
+L = 2**252 + 27742317777372353535851937790883648493
+D = -121665 * pow(121666, P - 2, P) % P
+SQRT_M1 = pow(2, (P - 1) // 4, P)
+
+
+def sha512_int(*parts):
+    return int.from_bytes(hashlib.sha512(b"".join(parts)).digest(), "little")
+
+
+def point_add(a, b):
+    """Extended coordinates, as in RFC 8032 5.1.4"""
+    x1, y1, z1, t1 = a
+    x2, y2, z2, t2 = b
+    e = (y1 - x1) * (y2 - x2) % P
+    f = (y1 + x1) * (y2 + x2) % P
+    g = 2 * t1 * t2 * D % P
+    h = 2 * z1 * z2 % P
+    e, f, g, h = f - e, h - g, h + g, f + e
+    return (e * f % P, g * h % P, f * g % P, e * h % P)
+
+
+def point_mul(scalar, point):
+    result = (0, 1, 1, 0)
+    while scalar:
+        if scalar & 1:
+            result = point_add(result, point)
+        point = point_add(point, point)
+        scalar >>= 1
+    return result
+
+
+def point_encode(point):
+    x, y, z, _ = point
+    z_inv = pow(z, P - 2, P)
+    x, y = x * z_inv % P, y * z_inv % P
+    return (y | (x & 1) << 255).to_bytes(32, "little")
+
+
+def base_point():
+    y = 4 * pow(5, P - 2, P) % P
+    x2 = (y * y - 1) * pow(D * y * y + 1, P - 2, P) % P
+    x = pow(x2, (P + 3) // 8, P)
+    if (x * x - x2) % P:
+        x = x * SQRT_M1 % P
+    if x & 1:
+        x = P - x
+    return (x, y, 1, x * y % P)
+
+
+B = base_point()
+
+
+def expand(seed):
+    """The secret scalar and the prefix for nonces, from the seed"""
+    h = hashlib.sha512(seed).digest()
+    a = int.from_bytes(h[:32], "little")
+    a &= (1 << 254) - 8
+    a |= 1 << 254
+    return a, h[32:]
+
+
+def public_key(seed):
+    a, _ = expand(seed)
+    return point_encode(point_mul(a, B))
+
+
+def sign(seed, message):
+    a, prefix = expand(seed)
+    key = point_encode(point_mul(a, B))
+    r = sha512_int(prefix, message) % L
+    r_encoded = point_encode(point_mul(r, B))
+    k = sha512_int(r_encoded, key, message) % L
+    s = (r + k * a) % L
+    return r_encoded + s.to_bytes(32, "little")
+
+
+def read_hex(path, size):
+    with open(path) as f:
+        data = bytes.fromhex(f.read().strip())
+    if len(data) != size:
+        sys.exit(f"{path}: expected {size} bytes of hex")
+    return data
+
+
+def keygen(directory):
+    secret = os.path.join(directory, "kernel-signing.key")
+    public = os.path.join(directory, "kernel-signing.pub")
+    if os.path.exists(secret):
+        sys.exit(f"{secret} already exists; kernels signed with it would stop booting")
+    os.makedirs(directory, exist_ok=True)
+    seed = os.urandom(32)
+    fd = os.open(secret, os.O_WRONLY | os.O_CREAT | os.O_EXCL, 0o600)
+    with os.fdopen(fd, "w") as f:
+        f.write(seed.hex() + "\n")
+    with open(public, "w") as f:
+        f.write(public_key(seed).hex() + "\n")
+    print(f"Wrote {secret} and {public}")
+
+
+def sign_file(key_path, path):
+    seed = read_hex(key_path, 32)
+    with open(path, "rb") as f:
+        data = f.read()
+    if data.endswith(MAGIC) and len(data) >= SIGNATURE_SIZE + len(MAGIC):
+        data = data[:-(SIGNATURE_SIZE + len(MAGIC))]
+    signature = sign(seed, data)
+    with open(path, "wb") as f:
+        f.write(data + signature + MAGIC)
+    print(f"Signed {path} ({len(data)} bytes)")
+
+
+def main():
+    if len(sys.argv) == 3 and sys.argv[1] == "keygen":
+        keygen(sys.argv[2])
+    elif len(sys.argv) == 4 and sys.argv[1] == "sign":
+        sign_file(sys.argv[2], sys.argv[3])
+    else:
+        sys.exit(f"usage: {sys.argv[0]} keygen DIR | sign KEY ELF")
+
+
+if __name__ == "__main__":
+    main()